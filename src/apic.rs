@@ -0,0 +1,1559 @@
+/*
+ * Local APIC emulation
+ *
+ * Register page sits at 0xFEE00000 and is accessed with aligned 32 bit loads and stores, registers are
+ * 16 bytes apart. Other access sizes and misaligned offsets read as 0 and writes to them are dropped.
+ *
+ * Interrupts accepted with fixed delivery go to IRR. Highest IRR vector whose priority class is above
+ * processor priority is raised in vm external interrupt queue, same as PIC vectors, and moves to ISR
+ * when it is injected. Processor priority is the higher of TPR and class of highest ISR vector, so raising
+ * TPR or a nested interrupt in service takes a queued vector back out until EOI or lower TPR lets it through.
+ * EOI retires highest ISR vector, if that one was level triggered IOAPIC hears about it through EOI handler
+ * so it can clear remote IRR of the entries that sent it.
+ *
+ * Software disabled APIC accepts no fixed interrupts, senders keep them pending and get told through enable
+ * handler when guest enables APIC. Acknowledge that finds the raised vector no longer deliverable gets the
+ * spurious vector from SVR, which sets no ISR bit and takes no EOI. All 8 bits of spurious vector are
+ * writable like on integrated APICs, older ones forcing low nibble to 0xF are not emulated.
+ *
+ * Timer counts bus clock ticks of virtual time divided by divide configuration, which is latched when initial
+ * count is written. Current count is computed from the time count started whenever it is read. Expiry is a
+ * timer event, periodic mode reloads on whole periods from the start so deadlines don't drift, and timer
+ * vector is accepted unless timer LVT entry is masked, which software disabled APIC forces.
+ *
+ * Message signaled interrupts from devices are accepted the same way as fixed interrupts. Only fixed and lowest
+ * priority delivery to this APIC or broadcast goes anywhere.
+ *
+ * IMCR at ports 0x22 and 0x23 switches between PIC mode, where PIC output goes straight to vcpu, and
+ * symmetric APIC mode, where PIC output goes to LINT0 and IOAPIC takes device IRQ lines. Write 0x70 to
+ * 0x22 to select it and bit 0 of 0x23 is the mode. In APIC mode PIC output reaches vcpu only while LINT0 is
+ * unmasked with ExtINT delivery, which is virtual wire mode. PIC vectors go around IRR, ISR and TPR then, and
+ * guest acknowledges and EOIs them in PIC like in PIC mode. PIC IRR pending while its output is disconnected
+ * stays latched and is raised again when it is connected back.
+ *
+ * ICR sends fixed, NMI, INIT and startup IPIs. Fixed IPIs to self go to IRR and NMI to self is raised in vm.
+ * INIT and startup IPIs to self are ignored like hardware does. There are no other vcpus yet, so INIT and startup
+ * IPIs sent to other APICs go nowhere, but they complete normally and are recorded in startup log for host to see
+ * which MP startup sequence guest went through. INIT level de-assert does nothing on integrated APICs and is not
+ * recorded. Delivery status is busy from the ICR write until the IPI is sent, which is done before guest runs again.
+ *
+ * Current limitations:
+ * - Single vcpu, IPIs to other APICs are dropped, logical destinations only in flat model
+ * - ISA IRQ lines go to PIC unless IOAPIC takes them, LINT0 only takes PIC output and LINT1 is never signalled
+ * - TSC deadline timer mode is not supported, timer doesn't count in it
+ */
+
+use vm;
+use pic;
+use event;
+use config;
+use clock::{self, virtual_clock, VcpuClock};
+use hypervisor_framework::*;
+
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+
+const APIC_BASE: hv_gpaddr_t    = 0xFEE00000;
+const APIC_PAGE_SIZE: u64       = 0x1000;
+
+// Register offsets
+const APIC_ID: u64              = 0x020;
+const APIC_VERSION: u64         = 0x030;
+const APIC_TPR: u64             = 0x080;
+const APIC_APR: u64             = 0x090;
+const APIC_PPR: u64             = 0x0A0;
+const APIC_EOI: u64             = 0x0B0;
+const APIC_LDR: u64             = 0x0D0;
+const APIC_DFR: u64             = 0x0E0;
+const APIC_SVR: u64             = 0x0F0;
+const APIC_ISR: u64             = 0x100; // 8 registers of 32 vectors each
+const APIC_TMR: u64             = 0x180;
+const APIC_IRR: u64             = 0x200;
+const APIC_ESR: u64             = 0x280;
+const APIC_ICR_LO: u64          = 0x300;
+const APIC_ICR_HI: u64          = 0x310;
+const APIC_LVT_TIMER: u64       = 0x320;
+const APIC_LVT_ERROR: u64       = 0x370;
+const APIC_TIMER_INITIAL: u64   = 0x380;
+const APIC_TIMER_CURRENT: u64   = 0x390;
+const APIC_TIMER_DIVIDE: u64    = 0x3E0;
+
+// Integrated APIC, 6 LVT entries
+const APIC_VERSION_VALUE: u32   = 0x00050014;
+
+// Spurious interrupt vector register
+const APIC_SVR_ENABLE: u32      = 0x100; // Software enable
+const APIC_SVR_WRITABLE: u32    = 0x1FF;
+
+// LVT entries: timer, thermal, performance counter, LINT0, LINT1, error
+const LVT_ENTRIES: usize        = 6;
+
+// LVT entry of PIC output
+const LVT_LINT0: usize          = 3;
+
+// LVT bits
+const LVT_DELIVERY_MODE: u32    = 0x700;
+const LVT_EXTINT: u32           = 0x700;
+const LVT_MASKED: u32           = 0x10000;
+const LVT_TIMER_MODE: u32       = 0x60000;
+const LVT_TIMER_PERIODIC: u32   = 0x20000;
+const LVT_TIMER_TSC_DEADLINE: u32 = 0x40000;
+
+// Bus clock timer counts divide down from, a tick per ns
+const APIC_TIMER_FREQ_HZ: u64   = 1000000000;
+
+// Writable LVT bits: vector, delivery mode, polarity, trigger mode, mask and timer mode as each entry has them
+const LVT_WRITABLE: [u32; LVT_ENTRIES] = [0x000700FF, 0x000107FF, 0x000107FF, 0x0001A7FF, 0x0001A7FF, 0x000100FF];
+
+// Interrupt command register bits
+const ICR_VECTOR: u32           = 0xFF;
+const ICR_DELIVERY_MODE: u32    = 0x700;
+const ICR_DEST_LOGICAL: u32     = 0x800;
+const ICR_DELIVERY_STATUS: u32  = 0x1000; // Send pending
+const ICR_LEVEL_ASSERT: u32     = 0x4000;
+const ICR_TRIGGER_LEVEL: u32    = 0x8000;
+const ICR_SHORTHAND: u32        = 0xC0000;
+const ICR_SHORTHAND_SELF: u32   = 0x40000;
+const ICR_SHORTHAND_ALL: u32    = 0x80000;
+const ICR_SHORTHAND_OTHERS: u32 = 0xC0000;
+const ICR_WRITABLE: u32         = 0x000CCFFF;
+
+// IPI delivery modes
+const ICR_FIXED: u32            = 0x000;
+const ICR_NMI: u32              = 0x400;
+const ICR_INIT: u32             = 0x500;
+const ICR_STARTUP: u32          = 0x600;
+
+// Destination field of broadcast in physical mode and flat logical model
+const APIC_BROADCAST: u8        = 0xFF;
+
+// MSI address and data bits
+const MSI_ADDR_MASK: u64        = 0xFFF00000;
+const MSI_ADDR_DEST_SHIFT: u64  = 12;
+const MSI_DATA_VECTOR: u32      = 0xFF;
+const MSI_DATA_DELIVERY_MODE: u32 = 0x700;
+const MSI_DELIVERY_LOWEST: u32  = 0x100;
+
+// Vectors below this are reserved for exceptions and are never accepted
+const APIC_MIN_VECTOR: u8       = 16;
+
+/* Bit of a vector in 256 bit register array */
+fn vec_test(regs: &[u32; 8], vec: u8) -> bool
+{
+    (regs[(vec >> 5) as usize] & (1 << (vec & 31))) != 0
+}
+
+fn vec_set(regs: &mut [u32; 8], vec: u8)
+{
+    regs[(vec >> 5) as usize] |= 1 << (vec & 31);
+}
+
+fn vec_clear(regs: &mut [u32; 8], vec: u8)
+{
+    regs[(vec >> 5) as usize] &= !(1 << (vec & 31));
+}
+
+/* Highest vector set in register array */
+fn vec_highest(regs: &[u32; 8]) -> Option<u8>
+{
+    for i in (0..8).rev() {
+        if regs[i] != 0 {
+            return Some((i as u32 * 32 + 31 - regs[i].leading_zeros()) as u8);
+        }
+    }
+
+    None
+}
+
+/* Priority class of a vector */
+fn class(vec: u8) -> u8
+{
+    vec >> 4
+}
+
+/**
+ * INIT or startup IPI sent to other APICs, startup vector is the page AP starts executing at
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum StartupIpi
+{
+    Init { dest: u8 },
+    Startup { dest: u8, vector: u8 },
+}
+
+/* IPI that goes outside local APIC */
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Ipi
+{
+    Nmi,
+    Startup(StartupIpi),
+}
+
+/**
+ * Local APIC of the only vcpu
+ */
+struct LocalApic
+{
+    id: u8,
+    tpr: u8,
+    ldr: u32,
+    dfr: u32,
+    svr: u32,
+    isr: [u32; 8],          // Vectors in service
+    tmr: [u32; 8],          // Level triggered vectors
+    irr: [u32; 8],          // Vectors accepted and not yet injected
+    esr: u32,
+    icr: (u32, u32),        // Low and high half
+    lvt: [u32; LVT_ENTRIES],
+    timer_initial: u32,
+    timer_divide: u32,
+    timer_start: u64,       // Bus tick initial count was written at
+    timer_shift: u32,       // Divide configuration latched with initial count, as a shift
+    clock: Rc<virtual_clock>,
+    injected: Option<u8>,   // Vector raised in vm queue, not injected yet
+    level_eoi: Option<u8>,  // Level triggered vector retired by last EOI
+    ipi: Option<Ipi>,       // IPI waiting to be sent, delivery status is busy until then
+}
+
+impl LocalApic
+{
+    fn new(clock: Rc<virtual_clock>) -> LocalApic {
+        LocalApic {
+            id: 0,
+            tpr: 0,
+            ldr: 0,
+            dfr: 0xFFFFFFFF,
+            svr: 0xFF,
+            isr: [0; 8],
+            tmr: [0; 8],
+            irr: [0; 8],
+            esr: 0,
+            icr: (0, 0),
+            lvt: [LVT_MASKED; LVT_ENTRIES],
+            timer_initial: 0,
+            timer_divide: 0,
+            timer_start: 0,
+            timer_shift: 1,
+            clock: clock,
+            injected: None,
+            level_eoi: None,
+            ipi: None,
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = LocalApic::new(self.clock.clone());
+    }
+
+    fn enabled(&self) -> bool {
+        (self.svr & APIC_SVR_ENABLE) != 0
+    }
+
+    /*
+     * Accept an interrupt with fixed delivery, false if it is dropped
+     * Software disabled APIC accepts nothing, vector already in IRR merges.
+     */
+    fn accept(&mut self, vec: u8, level: bool) -> bool {
+        if !self.enabled() || vec < APIC_MIN_VECTOR {
+            return false;
+        }
+
+        vec_set(&mut self.irr, vec);
+        if level {
+            vec_set(&mut self.tmr, vec);
+        } else {
+            vec_clear(&mut self.tmr, vec);
+        }
+
+        true
+    }
+
+    /* Processor priority: TPR or class of highest vector in service, whichever is higher */
+    fn ppr(&self) -> u8 {
+        let isrv = vec_highest(&self.isr).unwrap_or(0);
+        if class(self.tpr) >= class(isrv) {
+            self.tpr
+        } else {
+            isrv & 0xF0
+        }
+    }
+
+    /* Highest pending vector processor priority lets through */
+    fn deliverable(&self) -> Option<u8> {
+        match vec_highest(&self.irr) {
+            Some(vec) if class(vec) > class(self.ppr()) => Some(vec),
+            _ => None,
+        }
+    }
+
+    /* Vector APIC hands out when the one it raised is gone by acknowledge */
+    fn spurious_vector(&self) -> u8 {
+        self.svr as u8
+    }
+
+    /* Vector got injected in guest */
+    fn ack(&mut self, vec: u8) {
+        assert!(vec_test(&self.irr, vec));
+
+        vec_clear(&mut self.irr, vec);
+        vec_set(&mut self.isr, vec);
+        self.injected = None;
+    }
+
+    /* Current time in bus clock ticks */
+    fn now(&self) -> u64 {
+        clock::ns_to_ticks(self.clock.now_ns(), APIC_TIMER_FREQ_HZ)
+    }
+
+    /* Divide configuration as a shift: bits 0, 1 and 3 give 2, 4, ... 128, and 7 means divide by 1 */
+    fn divide_shift(divide: u32) -> u32 {
+        let val = (divide & 3) | ((divide >> 1) & 4);
+        (val + 1) & 7
+    }
+
+    fn timer_running(&self) -> bool {
+        self.timer_initial != 0 && (self.lvt[0] & LVT_TIMER_MODE) != LVT_TIMER_TSC_DEADLINE
+    }
+
+    fn timer_periodic(&self) -> bool {
+        (self.lvt[0] & LVT_TIMER_MODE) == LVT_TIMER_PERIODIC
+    }
+
+    /* Count down from initial count, reloaded every period in periodic mode and stopped at 0 in one-shot */
+    fn timer_current(&self) -> u32 {
+        if !self.timer_running() {
+            return 0;
+        }
+
+        let initial = self.timer_initial as u64;
+        let elapsed = (self.now() - self.timer_start) >> self.timer_shift;
+        if self.timer_periodic() {
+            (initial - elapsed % initial) as u32
+        } else if elapsed < initial {
+            (initial - elapsed) as u32
+        } else {
+            0
+        }
+    }
+
+    /*
+     * Bus tick of timer expiry and reload period, 0 for one-shot
+     * Periodic timer expires at the first reload ahead of current time, one-shot at its only one even if
+     * that has already gone by.
+     */
+    fn timer_expiry(&self) -> Option<(u64, u64)> {
+        if !self.timer_running() {
+            return None;
+        }
+
+        let period = (self.timer_initial as u64) << self.timer_shift;
+        if self.timer_periodic() {
+            let elapsed = self.now() - self.timer_start;
+            Some((self.timer_start + (elapsed / period + 1) * period, period))
+        } else {
+            Some((self.timer_start + period, 0))
+        }
+    }
+
+    /* LINT0 passes PIC output through to vcpu */
+    fn extint(&self) -> bool {
+        (self.lvt[LVT_LINT0] & (LVT_MASKED | LVT_DELIVERY_MODE)) == LVT_EXTINT
+    }
+
+    /* Timer expired, timer vector goes to IRR unless masked */
+    fn timer_interrupt(&mut self) {
+        let lvt = self.lvt[0];
+        if (lvt & LVT_MASKED) == 0 {
+            self.accept((lvt & 0xFF) as u8, false);
+        }
+    }
+
+    /* End of interrupt retires highest vector in service */
+    fn eoi(&mut self) {
+        if let Some(vec) = vec_highest(&self.isr) {
+            vec_clear(&mut self.isr, vec);
+            if vec_test(&self.tmr, vec) {
+                self.level_eoi = Some(vec);
+            }
+        }
+    }
+
+    fn read(&self, offset: u64) -> u32 {
+        let index = ((offset >> 4) & 7) as usize;
+
+        match offset {
+            APIC_ID => (self.id as u32) << 24,
+            APIC_VERSION => APIC_VERSION_VALUE,
+            APIC_TPR => self.tpr as u32,
+            APIC_APR => 0,
+            APIC_PPR => self.ppr() as u32,
+            APIC_LDR => self.ldr,
+            APIC_DFR => self.dfr,
+            APIC_SVR => self.svr,
+            APIC_ISR ... 0x170 => self.isr[index],
+            APIC_TMR ... 0x1F0 => self.tmr[index],
+            APIC_IRR ... 0x270 => self.irr[index],
+            APIC_ESR => self.esr,
+            APIC_ICR_LO => self.icr.0,
+            APIC_ICR_HI => self.icr.1,
+            APIC_LVT_TIMER ... APIC_LVT_ERROR => self.lvt[((offset - APIC_LVT_TIMER) >> 4) as usize],
+            APIC_TIMER_INITIAL => self.timer_initial,
+            APIC_TIMER_CURRENT => self.timer_current(),
+            APIC_TIMER_DIVIDE => self.timer_divide,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u64, val: u32) {
+        match offset {
+            APIC_ID => self.id = (val >> 24) as u8,
+            APIC_TPR => self.tpr = val as u8,
+            APIC_EOI => self.eoi(),
+            APIC_LDR => self.ldr = val & 0xFF000000,
+            APIC_DFR => self.dfr = val | 0x0FFFFFFF,
+            APIC_SVR => {
+                self.svr = val & APIC_SVR_WRITABLE;
+                if !self.enabled() {
+                    for lvt in self.lvt.iter_mut() {
+                        *lvt |= LVT_MASKED;
+                    }
+                }
+            },
+            APIC_ESR => self.esr = 0,
+            APIC_ICR_LO => {
+                self.icr.0 = (val & ICR_WRITABLE) | ICR_DELIVERY_STATUS;
+                self.send_ipi();
+            },
+            APIC_ICR_HI => self.icr.1 = val & 0xFF000000,
+            APIC_LVT_TIMER ... APIC_LVT_ERROR => {
+                let lvt = ((offset - APIC_LVT_TIMER) >> 4) as usize;
+                let mut val = val & LVT_WRITABLE[lvt];
+                if !self.enabled() {
+                    val |= LVT_MASKED;
+                }
+                self.lvt[lvt] = val;
+            },
+            APIC_TIMER_INITIAL => {
+                self.timer_initial = val;
+                self.timer_start = self.now();
+                self.timer_shift = LocalApic::divide_shift(self.timer_divide);
+            },
+            APIC_TIMER_DIVIDE => self.timer_divide = val & 0xB,
+            _ => debug!("apic: write {:x} to read only or reserved register {:x}", val, offset),
+        }
+    }
+
+    /* Whether IPI destination is this APIC and whether it is some other APIC */
+    fn ipi_destination(&self) -> (bool, bool) {
+        let dest = (self.icr.1 >> 24) as u8;
+        match self.icr.0 & ICR_SHORTHAND {
+            ICR_SHORTHAND_SELF => (true, false),
+            ICR_SHORTHAND_ALL => (true, true),
+            ICR_SHORTHAND_OTHERS => (false, true),
+            _ if (self.icr.0 & ICR_DEST_LOGICAL) != 0 => {
+                let ours = (self.ldr >> 24) as u8;
+                ((dest & ours) != 0, (dest & !ours) != 0)
+            },
+            _ => (dest == self.id || dest == APIC_BROADCAST, dest != self.id),
+        }
+    }
+
+    /*
+     * Interrupt command, fixed interrupts to ourselves go to IRR right away
+     * Anything else that goes somewhere waits in ipi for the device to send it.
+     */
+    fn send_ipi(&mut self) {
+        let icr = self.icr.0;
+        let dest = (self.icr.1 >> 24) as u8;
+        let vec = (icr & ICR_VECTOR) as u8;
+        let (to_self, to_others) = self.ipi_destination();
+
+        let startup = if (icr & ICR_SHORTHAND) != 0 { APIC_BROADCAST } else { dest };
+        self.ipi = match icr & ICR_DELIVERY_MODE {
+            ICR_FIXED if to_self => {
+                self.accept(vec, (icr & ICR_TRIGGER_LEVEL) != 0);
+                None
+            },
+            ICR_NMI if to_self => Some(Ipi::Nmi),
+            ICR_INIT if (icr & (ICR_TRIGGER_LEVEL | ICR_LEVEL_ASSERT)) == ICR_TRIGGER_LEVEL => None,
+            ICR_INIT if to_others => Some(Ipi::Startup(StartupIpi::Init { dest: startup })),
+            ICR_STARTUP if to_others => Some(Ipi::Startup(StartupIpi::Startup { dest: startup, vector: vec })),
+            ICR_INIT | ICR_STARTUP if to_self => None,
+            _ => {
+                debug!("apic: dropping IPI {:x}:{:x}", self.icr.1, icr);
+                None
+            },
+        };
+
+        if self.ipi.is_none() {
+            self.icr.0 &= !ICR_DELIVERY_STATUS;
+        }
+    }
+
+    /* Take IPI waiting to be sent, delivery status goes idle */
+    fn take_ipi(&mut self) -> Option<Ipi> {
+        self.icr.0 &= !ICR_DELIVERY_STATUS;
+        self.ipi.take()
+    }
+}
+
+#[cfg(test)]
+mod apic_test
+{
+    use super::*;
+    use clock::MockClock;
+
+    const APIC_LVT_LINT0: u64 = 0x350;
+    const APIC_LVT_LINT1: u64 = 0x360;
+
+    fn new_apic() -> LocalApic {
+        LocalApic::new(Rc::new(MockClock::new()))
+    }
+
+    fn enabled() -> LocalApic {
+        let mut apic = new_apic();
+        apic.write(APIC_SVR, APIC_SVR_ENABLE | 0xFF);
+        apic
+    }
+
+    /* Inject what APIC offers, vectors in injection order */
+    fn inject_all(apic: &mut LocalApic) -> Vec<u8> {
+        let mut injected = Vec::new();
+        while let Some(vec) = apic.deliverable() {
+            apic.ack(vec);
+            injected.push(vec);
+        }
+        injected
+    }
+
+    #[test] fn reset_state() {
+        let mut apic = new_apic();
+        assert!(apic.read(APIC_VERSION) == 0x00050014);
+        assert!(apic.read(APIC_SVR) == 0xFF && !apic.enabled());
+        assert!(apic.read(APIC_DFR) == 0xFFFFFFFF);
+        assert!((0..LVT_ENTRIES).all(|i| apic.read(APIC_LVT_TIMER + 0x10 * i as u64) == LVT_MASKED));
+
+        /* Software disabled APIC drops interrupts and keeps LVT entries masked */
+        assert!(!apic.accept(0x40, false));
+        apic.write(APIC_LVT_LINT0, 0x700);
+        assert!(apic.read(APIC_LVT_LINT0) == LVT_MASKED | 0x700);
+
+        apic.write(APIC_SVR, APIC_SVR_ENABLE | 0x3FF);
+        assert!(apic.read(APIC_SVR) == 0x1FF);
+        assert!(!apic.accept(0x0F, false) && apic.accept(0x40, false));
+        apic.write(APIC_LVT_LINT1, 0x400);
+        assert!(apic.read(APIC_LVT_LINT1) == 0x400);
+
+        /* Disabling masks everything again */
+        apic.write(APIC_SVR, 0xFF);
+        assert!(apic.read(APIC_LVT_LINT1) == LVT_MASKED | 0x400);
+    }
+
+    #[test] fn highest_first() {
+        let mut apic = enabled();
+        for vec in [0x45, 0x80, 0x32, 0x81].iter() {
+            assert!(apic.accept(*vec, false));
+        }
+        assert!(apic.read(APIC_IRR + 0x20) == 1 << 5);
+        assert!(apic.read(APIC_IRR + 0x40) == 0x3);
+
+        /* Nothing below the class in service gets through until EOI */
+        assert!(inject_all(&mut apic) == vec![0x81]);
+        assert!(apic.ppr() == 0x80);
+        apic.eoi();
+        assert!(inject_all(&mut apic) == vec![0x80]);
+        apic.eoi();
+        assert!(inject_all(&mut apic) == vec![0x45]);
+
+        /* Higher class nests over the one in service */
+        assert!(apic.accept(0x91, false) && apic.accept(0x44, false));
+        assert!(inject_all(&mut apic) == vec![0x91]);
+        assert!(apic.read(APIC_ISR + 0x40) == 1 << 0x11 && apic.read(APIC_ISR + 0x20) == 1 << 5);
+        assert!(apic.read(APIC_PPR) == 0x90);
+
+        /* EOI clears the nested vector first, then the interrupted one */
+        apic.eoi();
+        assert!(apic.read(APIC_ISR + 0x40) == 0 && vec_test(&apic.isr, 0x45));
+        assert!(apic.read(APIC_PPR) == 0x40 && apic.deliverable().is_none());
+        apic.write(APIC_EOI, 0);
+        assert!(apic.read(APIC_PPR) == 0 && vec_highest(&apic.isr).is_none());
+        assert!(inject_all(&mut apic) == vec![0x44]);
+    }
+
+    #[test] fn task_priority() {
+        let mut apic = enabled();
+        apic.write(APIC_TPR, 0x50);
+        assert!(apic.read(APIC_TPR) == 0x50 && apic.read(APIC_PPR) == 0x50);
+
+        /* Class at or below TPR waits, higher gets through */
+        assert!(apic.accept(0x5F, false) && apic.accept(0x41, false));
+        assert!(apic.deliverable().is_none());
+        assert!(apic.accept(0x60, true));
+        assert!(inject_all(&mut apic) == vec![0x60]);
+        assert!(vec_test(&apic.tmr, 0x60));
+
+        /* TPR above class in service decides priority */
+        apic.write(APIC_TPR, 0x75);
+        assert!(apic.read(APIC_PPR) == 0x75);
+        apic.eoi();
+        apic.write(APIC_TPR, 0x4F);
+        assert!(apic.read(APIC_PPR) == 0x4F);
+        assert!(inject_all(&mut apic) == vec![0x5F]);
+        apic.write(APIC_TPR, 0);
+        assert!(apic.read(APIC_PPR) == 0x50);
+        apic.eoi();
+        assert!(inject_all(&mut apic) == vec![0x41]);
+    }
+
+    #[test] fn self_ipi() {
+        let mut apic = enabled();
+
+        apic.write(APIC_ICR_LO, ICR_SHORTHAND_SELF | 0x55);
+        apic.write(APIC_ICR_HI, 0x01000000);
+        apic.write(APIC_ICR_LO, 0x56);
+        apic.write(APIC_ICR_HI, 0);
+        apic.write(APIC_ICR_LO, 0x57);
+        apic.write(APIC_ICR_LO, ICR_SHORTHAND_SELF | 0x400 | 0x58);
+        assert!(inject_all(&mut apic) == vec![0x57]);
+        apic.eoi();
+        assert!(inject_all(&mut apic) == vec![0x55]);
+
+        /* NMI waits to be sent with delivery status busy, vector doesn't matter */
+        assert!(apic.read(APIC_ICR_LO) == ICR_SHORTHAND_SELF | ICR_DELIVERY_STATUS | 0x458);
+        assert!(apic.take_ipi() == Some(Ipi::Nmi) && apic.read(APIC_ICR_LO) == ICR_SHORTHAND_SELF | 0x458);
+        assert!(apic.take_ipi().is_none());
+
+        /* Guest can't set delivery status, fixed IPI to self is sent right away */
+        apic.write(APIC_ICR_LO, ICR_SHORTHAND_SELF | ICR_DELIVERY_STATUS | 0x59);
+        assert!(apic.read(APIC_ICR_LO) == ICR_SHORTHAND_SELF | 0x59 && apic.ipi.is_none());
+    }
+
+    #[test] fn ipi_destinations() {
+        let mut apic = enabled();
+        apic.write(APIC_ID, 0x02000000);
+        apic.write(APIC_LDR, 0x04000000);
+
+        /* Destination field: this APIC, some other one, broadcast */
+        let sent = |apic: &mut LocalApic, hi: u32, lo: u32| {
+            apic.write(APIC_ICR_HI, hi);
+            apic.write(APIC_ICR_LO, lo);
+            apic.ipi_destination()
+        };
+        assert!(sent(&mut apic, 0x02000000, 0) == (true, false));
+        assert!(sent(&mut apic, 0x01000000, 0) == (false, true));
+        assert!(sent(&mut apic, 0xFF000000, 0) == (true, true));
+        assert!(sent(&mut apic, 0x04000000, ICR_DEST_LOGICAL) == (true, false));
+        assert!(sent(&mut apic, 0x03000000, ICR_DEST_LOGICAL) == (false, true));
+        assert!(sent(&mut apic, 0x0C000000, ICR_DEST_LOGICAL) == (true, true));
+
+        /* Shorthand ignores destination field */
+        assert!(sent(&mut apic, 0x01000000, ICR_SHORTHAND_SELF) == (true, false));
+        assert!(sent(&mut apic, 0x02000000, ICR_SHORTHAND_ALL) == (true, true));
+        assert!(sent(&mut apic, 0x02000000, ICR_SHORTHAND_OTHERS) == (false, true));
+        assert!(inject_all(&mut apic).is_empty());
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/*
+ * Timer event, periodic in periodic mode
+ * One-shot timer stays recorded after it fires, so the same count isn't armed again.
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+struct APICTimer
+{
+    handle: event::TimerHandle,
+    first: u64,                 // Bus tick of first expiry
+    period: u64,                // Ticks between expiries, 0 for one-shot
+}
+
+impl APICTimer
+{
+    /* Timer already fires at a tick and then every period after it */
+    fn covers(&self, first: u64, period: u64) -> bool {
+        if period != self.period {
+            return false;
+        }
+
+        match period {
+            0 => first == self.first,
+            _ => first >= self.first && (first - self.first) % period == 0,
+        }
+    }
+}
+
+struct APICDev
+{
+    apic: RefCell<LocalApic>,
+    legacy: Rc<vm::interrupt_controller>,   // PIC ISA IRQ lines stay wired to
+    raise: fn(u8),                          // Raises vector in vm external interrupt queue
+    cancel: fn(u8),                         // Takes vector back out of the queue
+    eoi_handler: Cell<Option<fn(u8)>>,      // Called with level triggered vectors guest EOIs
+    enable_handler: Cell<Option<fn()>>,     // Called when guest software enables APIC
+    nmi: fn(),                              // Raises NMI in vcpu
+    pic_output: fn(bool),                   // Connects PIC output to vcpu
+    pic_bypass: Cell<bool>,                 // PIC mode, PIC output goes around APIC
+    pic_connected: Cell<bool>,
+    pic_handler: Cell<Option<fn(bool)>>,    // Told when PIC output gets connected or disconnected
+    timer: Cell<Option<APICTimer>>,         // Timer event while timer counts
+    startup_log: RefCell<Vec<StartupIpi>>,  // INIT and startup IPIs guest sent to other APICs, in order
+}
+
+impl APICDev
+{
+    /*
+     * Keep timer event armed for timer expiry. Timer that already fires at the right ticks is left alone,
+     * one-shot expiry that went by without an event is not armed at all.
+     */
+    fn arm_timer(&self, apic: &LocalApic) {
+        let wanted = apic.timer_expiry();
+
+        let timer = self.timer.get();
+        match (timer, wanted) {
+            (Some(timer), Some((first, period))) if timer.covers(first, period) => return,
+            (Some(timer), _) => { event::cancel_event(timer.handle); },
+            (None, _) => {},
+        }
+
+        let wanted = match wanted {
+            Some((first, 0)) if first <= apic.now() => None,
+            wanted => wanted,
+        };
+
+        self.timer.set(wanted.map(|(first, period)| APICTimer {
+            handle: event::schedule_periodic(first, period, APIC_TIMER_FREQ_HZ, event::create_event(timer_event)),
+            first: first,
+            period: period,
+        }));
+    }
+
+    /* Timer event fired, missed periods went by while it was late */
+    fn timer_expired(&self, missed: u64) {
+        let mut apic = self.apic.borrow_mut();
+
+        if missed != 0 {
+            debug!("apic: {} timer interrupts coalesced", missed);
+        }
+
+        apic.timer_interrupt();
+        self.update(&mut apic);
+    }
+
+    /* Fixed interrupt from IOAPIC or another source outside the APIC */
+    fn deliver(&self, vec: u8, level: bool) -> bool {
+        let mut apic = self.apic.borrow_mut();
+        let accepted = apic.accept(vec, level);
+        self.update(&mut apic);
+        accepted
+    }
+
+    /* PIC output reaches vcpu in PIC mode or through LINT0 with ExtINT delivery */
+    fn connect_pic(&self, extint: bool) {
+        let connected = self.pic_bypass.get() || extint;
+        if connected == self.pic_connected.get() {
+            return;
+        }
+
+        self.pic_connected.set(connected);
+        (self.pic_output)(connected);
+        if let Some(handler) = self.pic_handler.get() {
+            handler(connected);
+        }
+    }
+
+    /* IMCR switched PIC output to go around APIC or to LINT0 */
+    fn set_pic_bypass(&self, bypass: bool) {
+        self.pic_bypass.set(bypass);
+        let extint = self.apic.borrow().extint();
+        self.connect_pic(extint);
+    }
+
+    /* Send IPI ICR write left waiting */
+    fn send_ipi(&self, ipi: Ipi) {
+        match ipi {
+            Ipi::Nmi => (self.nmi)(),
+            Ipi::Startup(startup) => {
+                debug!("apic: {:?} to nonexistent vcpu", startup);
+                self.startup_log.borrow_mut().push(startup);
+            },
+        }
+    }
+
+    /* Keep highest deliverable vector, and only that one, in vm queue */
+    fn update(&self, apic: &mut LocalApic) {
+        let wanted = apic.deliverable();
+        if wanted == apic.injected {
+            return;
+        }
+
+        if let Some(vec) = apic.injected {
+            (self.cancel)(vec);
+        }
+
+        if let Some(vec) = wanted {
+            (self.raise)(vec);
+        }
+
+        apic.injected = wanted;
+    }
+}
+
+impl vm::mmio_handler for APICDev
+{
+    fn mmio_read(&self, addr: hv_gpaddr_t, size: u8) -> u64
+    {
+        let offset = addr - APIC_BASE;
+        if size != 4 || (offset & 0xF) != 0 {
+            debug!("apic: {} byte read at {:x}", size, offset);
+            return 0;
+        }
+
+        self.apic.borrow().read(offset) as u64
+    }
+
+    fn mmio_write(&self, addr: hv_gpaddr_t, size: u8, data: u64)
+    {
+        let offset = addr - APIC_BASE;
+        if size != 4 || (offset & 0xF) != 0 {
+            debug!("apic: {} byte write at {:x}", size, offset);
+            return;
+        }
+
+        let (eoi, enabled, ipi, extint) = {
+            let mut apic = self.apic.borrow_mut();
+            let was_enabled = apic.enabled();
+            apic.write(offset, data as u32);
+            self.update(&mut apic);
+            self.arm_timer(&apic);
+            (apic.level_eoi.take(), !was_enabled && apic.enabled(), apic.take_ipi(), apic.extint())
+        };
+
+        if let Some(ipi) = ipi {
+            self.send_ipi(ipi);
+        }
+
+        self.connect_pic(extint);
+
+        /* Handlers may deliver again right away, so APIC is not borrowed any more */
+        if let (Some(vec), Some(handler)) = (eoi, self.eoi_handler.get()) {
+            handler(vec);
+        }
+
+        if let (true, Some(handler)) = (enabled, self.enable_handler.get()) {
+            handler();
+        }
+    }
+}
+
+impl vm::interrupt_controller for APICDev
+{
+    fn assert_irq(&self, irq: u8, id: Option<u64>)
+    {
+        self.legacy.assert_irq(irq, id);
+    }
+
+    fn ack(&self, vec: u8) -> u8
+    {
+        let mut apic = self.apic.borrow_mut();
+        if apic.injected != Some(vec) {
+            return self.legacy.ack(vec);
+        }
+
+        /* Raised vector stays deliverable while it is queued, unless state changed behind update */
+        let vec = if apic.deliverable() == Some(vec) {
+            apic.ack(vec);
+            vec
+        } else {
+            apic.injected = None;
+            apic.spurious_vector()
+        };
+
+        self.update(&mut apic);
+        vec
+    }
+}
+
+impl vm::msi_controller for APICDev
+{
+    fn deliver_msi(&self, addr: u64, data: u32) -> bool
+    {
+        let dest = ((addr >> MSI_ADDR_DEST_SHIFT) & 0xFF) as u8;
+        if (addr & MSI_ADDR_MASK) != vm::MSI_ADDR_BASE || (dest != self.apic.borrow().id && dest != 0xFF) {
+            debug!("apic: dropping MSI {:x} to {:x}", data, addr);
+            return false;
+        }
+
+        match data & MSI_DATA_DELIVERY_MODE {
+            0 | MSI_DELIVERY_LOWEST => self.deliver((data & MSI_DATA_VECTOR) as u8, false),
+            mode => {
+                debug!("apic: dropping MSI {:x} with delivery mode {:x}", data, mode >> 8);
+                false
+            },
+        }
+    }
+}
+
+impl vm::reset_handler for APICDev
+{
+    fn reset(&self)
+    {
+        /* Reset drops queued vectors before it gets here */
+        {
+            let mut apic = self.apic.borrow_mut();
+            apic.reset();
+            self.arm_timer(&apic);
+        }
+
+        self.connect_pic(false);
+    }
+}
+
+#[cfg(test)]
+mod apic_dev_test
+{
+    use super::*;
+    use clock::MockClock;
+
+    thread_local! {
+        static QUEUE: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+        static EOIS: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+        static ENABLES: Cell<u32> = Cell::new(0);
+        static NMIS: Cell<u32> = Cell::new(0);
+        static PIC_CONNECTED: Cell<bool> = Cell::new(false);
+        static PIC_LATCHED: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+    }
+
+    /* PIC stand-in programmed with vector base 0x20, raises latched IRQs when connected back */
+    fn pic_output(connected: bool) {
+        PIC_CONNECTED.with(|c| c.set(connected));
+        for irq in PIC_LATCHED.with(|l| l.borrow_mut().drain(..).collect::<Vec<u8>>()) {
+            pic_irq(irq);
+        }
+    }
+
+    fn pic_irq(irq: u8) {
+        if PIC_CONNECTED.with(|c| c.get()) {
+            raise(0x20 + irq);
+        } else {
+            PIC_LATCHED.with(|l| l.borrow_mut().push(irq));
+        }
+    }
+
+    fn record_nmi() {
+        NMIS.with(|n| n.set(n.get() + 1));
+    }
+
+    fn record_enable() {
+        ENABLES.with(|e| e.set(e.get() + 1));
+    }
+
+    fn raise(vec: u8) {
+        QUEUE.with(|q| q.borrow_mut().push(vec));
+    }
+
+    fn cancel(vec: u8) {
+        QUEUE.with(|q| q.borrow_mut().retain(|v| *v != vec));
+    }
+
+    fn record_eoi(vec: u8) {
+        EOIS.with(|e| e.borrow_mut().push(vec));
+    }
+
+    fn queue() -> Vec<u8> {
+        QUEUE.with(|q| q.borrow().clone())
+    }
+
+    /* Stands in for PIC, records what reaches it */
+    struct Legacy {
+        irqs: RefCell<Vec<u8>>,
+        acks: RefCell<Vec<u8>>,
+    }
+
+    impl vm::interrupt_controller for Legacy {
+        fn assert_irq(&self, irq: u8, _id: Option<u64>) {
+            self.irqs.borrow_mut().push(irq);
+            pic_irq(irq);
+        }
+
+        fn ack(&self, vec: u8) -> u8 {
+            self.acks.borrow_mut().push(vec);
+            vec
+        }
+    }
+
+    fn write(dev: &APICDev, offset: u64, val: u32) {
+        vm::mmio_handler::mmio_write(dev, APIC_BASE + offset, 4, val as u64);
+    }
+
+    fn read(dev: &APICDev, offset: u64) -> u32 {
+        vm::mmio_handler::mmio_read(dev, APIC_BASE + offset, 4) as u32
+    }
+
+    fn make_dev(clock: &Rc<MockClock>) -> (APICDev, Rc<Legacy>) {
+        let legacy = Rc::new(Legacy { irqs: RefCell::new(Vec::new()), acks: RefCell::new(Vec::new()) });
+        let dev = APICDev {
+            apic: RefCell::new(LocalApic::new(clock.clone())),
+            legacy: legacy.clone(),
+            raise: raise,
+            cancel: cancel,
+            eoi_handler: Cell::new(Some(record_eoi)),
+            enable_handler: Cell::new(Some(record_enable)),
+            nmi: record_nmi,
+            pic_output: pic_output,
+            pic_bypass: Cell::new(false),
+            pic_connected: Cell::new(false),
+            pic_handler: Cell::new(None),
+            timer: Cell::new(None),
+            startup_log: RefCell::new(Vec::new()),
+        };
+        (dev, legacy)
+    }
+
+    #[test] fn queue_follows_priority() {
+        let (dev, legacy) = make_dev(&Rc::new(MockClock::new()));
+
+        write(&dev, APIC_SVR, 0x1FF);
+        assert!(read(&dev, APIC_SVR) == 0x1FF);
+        assert!(vm::mmio_handler::mmio_read(&dev, APIC_BASE + APIC_SVR, 1) == 0);
+        assert!(vm::mmio_handler::mmio_read(&dev, APIC_BASE + APIC_SVR + 4, 4) == 0);
+
+        /* Higher vector replaces the queued one */
+        write(&dev, APIC_ICR_LO, ICR_SHORTHAND_SELF | 0x41);
+        assert!(queue() == vec![0x41]);
+        write(&dev, APIC_ICR_LO, ICR_SHORTHAND_SELF | 0x62);
+        assert!(queue() == vec![0x62]);
+
+        /* Raising TPR takes it back, lowering lets it through again */
+        write(&dev, APIC_TPR, 0x60);
+        assert!(queue().is_empty());
+        write(&dev, APIC_TPR, 0x50);
+        assert!(queue() == vec![0x62]);
+
+        /* Injection queues nothing until EOI, then the lower one */
+        cancel(0x62);
+        vm::interrupt_controller::ack(&dev, 0x62);
+        assert!(queue().is_empty());
+        write(&dev, APIC_TPR, 0);
+        assert!(queue().is_empty());
+        write(&dev, APIC_EOI, 0);
+        assert!(queue() == vec![0x41]);
+
+        /* Level triggered vector delivered from outside tells about its EOI */
+        cancel(0x41);
+        vm::interrupt_controller::ack(&dev, 0x41);
+        assert!(dev.deliver(0x71, true) && queue() == vec![0x71]);
+        cancel(0x71);
+        vm::interrupt_controller::ack(&dev, 0x71);
+        write(&dev, APIC_EOI, 0);
+        write(&dev, APIC_EOI, 0);
+        assert!(EOIS.with(|e| e.borrow().clone()) == vec![0x71]);
+
+        /* ISA lines and vectors APIC didn't raise belong to PIC */
+        vm::interrupt_controller::assert_irq(&dev, 1, None);
+        vm::interrupt_controller::ack(&dev, 0x09);
+        assert!(*legacy.irqs.borrow() == vec![1] && *legacy.acks.borrow() == vec![0x09]);
+
+        /* Reset forgets everything */
+        vm::reset_handler::reset(&dev);
+        assert!(read(&dev, APIC_IRR + 0x20) == 0 && read(&dev, APIC_SVR) == 0xFF);
+    }
+
+    #[test] fn spurious() {
+        let (dev, _) = make_dev(&Rc::new(MockClock::new()));
+
+        /* Enabling tells senders once */
+        write(&dev, APIC_SVR, 0x1F0);
+        write(&dev, APIC_SVR, 0x1F0);
+        assert!(ENABLES.with(|e| e.get()) == 1);
+
+        /* Raised vector taken out of IRR behind queue's back is gone by acknowledge */
+        assert!(dev.deliver(0x61, false) && queue() == vec![0x61]);
+        cancel(0x61);
+        vec_clear(&mut dev.apic.borrow_mut().irr, 0x61);
+        assert!(vm::interrupt_controller::ack(&dev, 0x61) == 0xF0);
+        assert!(dev.apic.borrow().injected.is_none() && queue().is_empty());
+
+        /* Spurious vector is not in service and takes no EOI */
+        assert!(vec_highest(&dev.apic.borrow().isr).is_none() && read(&dev, APIC_PPR) == 0);
+        assert!(dev.deliver(0x52, false) && queue() == vec![0x52]);
+        cancel(0x52);
+        assert!(vm::interrupt_controller::ack(&dev, 0x52) == 0x52);
+        assert!(read(&dev, APIC_PPR) == 0x50);
+        write(&dev, APIC_EOI, 0);
+        assert!(read(&dev, APIC_PPR) == 0);
+
+        /* Disabled again, fixed interrupts are turned down */
+        write(&dev, APIC_SVR, 0xF0);
+        assert!(!dev.deliver(0x53, false) && queue().is_empty());
+        write(&dev, APIC_SVR, 0x1F0);
+        assert!(ENABLES.with(|e| e.get()) == 2);
+    }
+
+    #[test] fn extint() {
+        const APIC_LVT_LINT0: u64 = 0x350;
+        let (dev, legacy) = make_dev(&Rc::new(MockClock::new()));
+
+        /* APIC mode, LINT0 masked at reset holds PIC back */
+        write(&dev, APIC_SVR, 0x1FF);
+        vm::interrupt_controller::assert_irq(&dev, 0, None);
+        assert!(queue().is_empty());
+
+        /* Virtual wire: timer tick comes through with PIC vector, acknowledged by PIC alone */
+        write(&dev, APIC_LVT_LINT0, LVT_EXTINT);
+        assert!(queue() == vec![0x20]);
+        cancel(0x20);
+        assert!(vm::interrupt_controller::ack(&dev, 0x20) == 0x20 && *legacy.acks.borrow() == vec![0x20]);
+        assert!((0..8).all(|i| read(&dev, APIC_ISR + 0x10 * i) == 0) && read(&dev, APIC_PPR) == 0);
+        vm::interrupt_controller::assert_irq(&dev, 0, None);
+        assert!(queue() == vec![0x20]);
+        cancel(0x20);
+
+        /* Masking LINT0 or another delivery mode hold it until it is unmasked */
+        write(&dev, APIC_LVT_LINT0, LVT_MASKED | LVT_EXTINT);
+        vm::interrupt_controller::assert_irq(&dev, 0, None);
+        write(&dev, APIC_LVT_LINT0, 0x400);
+        assert!(queue().is_empty());
+        write(&dev, APIC_LVT_LINT0, LVT_EXTINT);
+        assert!(queue() == vec![0x20]);
+        cancel(0x20);
+
+        /* Software disable masks LINT0 */
+        write(&dev, APIC_SVR, 0xFF);
+        vm::interrupt_controller::assert_irq(&dev, 0, None);
+        assert!(queue().is_empty());
+
+        /* PIC mode goes around LINT0 */
+        dev.set_pic_bypass(true);
+        assert!(queue() == vec![0x20]);
+        dev.set_pic_bypass(false);
+        assert!(!PIC_CONNECTED.with(|c| c.get()));
+    }
+
+    /* Send IPI the way guests do, waiting for delivery status to go idle */
+    fn send_ipi(dev: &APICDev, hi: u32, lo: u32) {
+        write(dev, APIC_ICR_HI, hi);
+        write(dev, APIC_ICR_LO, lo);
+        assert!((read(dev, APIC_ICR_LO) & ICR_DELIVERY_STATUS) == 0);
+        assert!(read(dev, APIC_ICR_LO) == lo & ICR_WRITABLE && read(dev, APIC_ICR_HI) == hi);
+    }
+
+    #[test] fn init_sipi_sipi() {
+        let (dev, _) = make_dev(&Rc::new(MockClock::new()));
+        write(&dev, APIC_SVR, 0x1FF);
+
+        /* MP spec sequence to APIC 1: INIT assert, INIT de-assert, two startup IPIs */
+        send_ipi(&dev, 0x01000000, ICR_TRIGGER_LEVEL | ICR_LEVEL_ASSERT | ICR_INIT);
+        send_ipi(&dev, 0x01000000, ICR_TRIGGER_LEVEL | ICR_INIT);
+        send_ipi(&dev, 0x01000000, ICR_STARTUP | 0x9A);
+        send_ipi(&dev, 0x01000000, ICR_STARTUP | 0x9A);
+
+        /* BIOS style broadcast to everyone else */
+        send_ipi(&dev, 0, ICR_SHORTHAND_OTHERS | ICR_LEVEL_ASSERT | ICR_INIT);
+        send_ipi(&dev, 0, ICR_SHORTHAND_OTHERS | ICR_LEVEL_ASSERT | ICR_STARTUP | 0x10);
+
+        assert!(*dev.startup_log.borrow() == vec![
+            StartupIpi::Init { dest: 1 },
+            StartupIpi::Startup { dest: 1, vector: 0x9A },
+            StartupIpi::Startup { dest: 1, vector: 0x9A },
+            StartupIpi::Init { dest: 0xFF },
+            StartupIpi::Startup { dest: 0xFF, vector: 0x10 },
+        ]);
+
+        /* INIT and startup to self are ignored, nothing gets to IRR */
+        dev.startup_log.borrow_mut().clear();
+        send_ipi(&dev, 0, ICR_LEVEL_ASSERT | ICR_INIT);
+        send_ipi(&dev, 0, ICR_SHORTHAND_SELF | ICR_STARTUP | 0x9A);
+        assert!(dev.startup_log.borrow().is_empty() && queue().is_empty());
+        assert!((0..8).all(|i| read(&dev, APIC_IRR + 0x10 * i) == 0));
+    }
+
+    #[test] fn self_ipi_delivery() {
+        let (dev, _) = make_dev(&Rc::new(MockClock::new()));
+        write(&dev, APIC_SVR, 0x1FF);
+
+        /* Fixed IPI to own ID or broadcast goes to local queue, to others nowhere */
+        send_ipi(&dev, 0, 0x44);
+        assert!(queue() == vec![0x44]);
+        cancel(0x44);
+        send_ipi(&dev, 0xFF000000, 0x45);
+        assert!(queue() == vec![0x45]);
+        cancel(0x45);
+        send_ipi(&dev, 0x03000000, 0x46);
+        send_ipi(&dev, 0, ICR_SHORTHAND_OTHERS | 0x47);
+        assert!(queue().is_empty() && read(&dev, APIC_IRR + 0x20) == 0x30);
+
+        /* NMI to self gets raised, to others dropped */
+        send_ipi(&dev, 0, ICR_NMI);
+        send_ipi(&dev, 0x03000000, ICR_NMI);
+        assert!(NMIS.with(|n| n.get()) == 1 && dev.startup_log.borrow().is_empty());
+    }
+
+    #[test] fn msi() {
+        let (dev, legacy) = make_dev(&Rc::new(MockClock::new()));
+        let msi = |addr: u64, data: u32| vm::msi_controller::deliver_msi(&dev, addr, data);
+
+        /* Software disabled APIC rejects messages */
+        assert!(!msi(vm::MSI_ADDR_BASE, 0x50) && queue().is_empty());
+
+        write(&dev, APIC_SVR, 0x1FF);
+        assert!(msi(vm::MSI_ADDR_BASE, 0x50) && queue() == vec![0x50]);
+
+        /* Reserved vectors, other address windows, other APICs and other delivery modes go nowhere */
+        assert!(!msi(vm::MSI_ADDR_BASE, 0x0F));
+        assert!(!msi(0xFED00000, 0x51));
+        assert!(!msi(vm::MSI_ADDR_BASE | 0x1000, 0x51));
+        assert!(!msi(vm::MSI_ADDR_BASE, 0x400 | 0x51));
+        assert!(msi(vm::MSI_ADDR_BASE | 0xFF000, 0x100 | 0x40));
+        assert!(queue() == vec![0x50] && read(&dev, APIC_IRR + 0x20) == 0x00010001);
+
+        /* PIC vector pending along gets injected first, MSI vector stays queued */
+        vm::interrupt_controller::assert_irq(&dev, 0, None);
+        vm::interrupt_controller::ack(&dev, 0x08);
+        assert!(*legacy.irqs.borrow() == vec![0] && *legacy.acks.borrow() == vec![0x08]);
+        assert!(queue() == vec![0x50]);
+
+        cancel(0x50);
+        vm::interrupt_controller::ack(&dev, 0x50);
+        write(&dev, APIC_EOI, 0);
+        assert!(queue() == vec![0x40]);
+    }
+
+    /* Advance clock to next timer expiry and fire timer event there, vector it queued if any */
+    fn fire_next(dev: &APICDev, clock: &MockClock) -> Option<u8> {
+        let (deadline, period) = dev.apic.borrow().timer_expiry().unwrap();
+        let timer = dev.timer.get().unwrap();
+        assert!(timer.covers(deadline, period));
+
+        clock.advance_ns(clock::ticks_to_ns(deadline, APIC_TIMER_FREQ_HZ) - clock.now_ns());
+        dev.timer_expired(0);
+
+        /* Inject and retire it */
+        let queued = queue().pop();
+        if let Some(vec) = queued {
+            cancel(vec);
+            vm::interrupt_controller::ack(dev, vec);
+            write(dev, APIC_EOI, 0);
+        }
+        queued
+    }
+
+    #[test] fn timer() {
+        let clock = Rc::new(MockClock::new());
+        clock.advance_ns(1000000);
+        let (dev, _) = make_dev(&clock);
+        write(&dev, APIC_SVR, 0x1FF);
+
+        /* Divide by 16 periodic timer, 1000 counts take 16 us */
+        write(&dev, APIC_TIMER_DIVIDE, 0x3);
+        write(&dev, APIC_LVT_TIMER, LVT_TIMER_PERIODIC | 0x40);
+        write(&dev, APIC_TIMER_INITIAL, 1000);
+        assert!(dev.timer.get().map(|t| (t.first, t.period)) == Some((1016000, 16000)));
+        assert!(read(&dev, APIC_TIMER_CURRENT) == 1000);
+        clock.advance_ns(5000);
+        assert!(read(&dev, APIC_TIMER_CURRENT) == 688);
+
+        let mut irqs = Vec::new();
+        for _ in 0..10 {
+            assert!(fire_next(&dev, &clock) == Some(0x40));
+            irqs.push(clock.now_ns());
+
+            /* Reloaded right at expiry, one count left a tick before the next */
+            assert!(read(&dev, APIC_TIMER_CURRENT) == 1000);
+            clock.advance_ns(15984);
+            assert!(read(&dev, APIC_TIMER_CURRENT) == 1);
+        }
+        assert!(irqs == (1..11).map(|n| 1000000 + n * 16000).collect::<Vec<u64>>());
+
+        /* Masked timer keeps counting in the same phase without interrupts */
+        write(&dev, APIC_LVT_TIMER, LVT_MASKED | LVT_TIMER_PERIODIC | 0x40);
+        assert!(fire_next(&dev, &clock).is_none() && clock.now_ns() == 1176000);
+        write(&dev, APIC_LVT_TIMER, LVT_TIMER_PERIODIC | 0x40);
+        assert!(fire_next(&dev, &clock) == Some(0x40) && clock.now_ns() == 1192000);
+
+        /* One-shot counts down once with divide by 1 and stays at 0 */
+        write(&dev, APIC_TIMER_DIVIDE, 0xB);
+        write(&dev, APIC_LVT_TIMER, 0x41);
+        write(&dev, APIC_TIMER_INITIAL, 100);
+        assert!(dev.timer.get().map(|t| (t.first, t.period)) == Some((1192100, 0)));
+        assert!(fire_next(&dev, &clock) == Some(0x41));
+        clock.advance_ns(1000);
+        write(&dev, APIC_TPR, 0);
+        assert!(read(&dev, APIC_TIMER_CURRENT) == 0);
+        assert!(dev.timer.get().map(|t| t.first) == Some(1192100));
+
+        /* Software disabled APIC masks timer */
+        write(&dev, APIC_LVT_TIMER, LVT_TIMER_PERIODIC | 0x42);
+        write(&dev, APIC_TIMER_INITIAL, 500);
+        write(&dev, APIC_SVR, 0xFF);
+        assert!(fire_next(&dev, &clock).is_none());
+
+        /* Zero initial count stops it */
+        write(&dev, APIC_TIMER_INITIAL, 0);
+        assert!(dev.timer.get().is_none() && read(&dev, APIC_TIMER_CURRENT) == 0);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+// Interrupt mode configuration register, MultiProcessor spec 3.6.2.1
+const IMCR_SELECT_PORT: u16     = 0x22;
+const IMCR_DATA_PORT: u16       = 0x23;
+const IMCR_SELECT: u8           = 0x70;
+const IMCR_APIC_MODE: u8        = 0x01;
+
+/**
+ * IMCR latch, switches PIC output and device IRQ lines between PIC and APIC mode
+ */
+struct IMCRDev
+{
+    select: Cell<u8>,
+    apic_mode: Cell<bool>,
+    pic_bypass: fn(bool),                   // Sends PIC output around APIC, or to LINT0
+}
+
+impl IMCRDev
+{
+    fn set_mode(&self, apic_mode: bool) {
+        if self.apic_mode.get() == apic_mode {
+            return;
+        }
+
+        self.apic_mode.set(apic_mode);
+        (self.pic_bypass)(!apic_mode);
+    }
+}
+
+impl vm::io_handler for IMCRDev
+{
+    fn io_read(&self, port: u16, size: u8) -> vm::IoOperandType
+    {
+        assert!(size == 1);
+
+        vm::IoOperandType::byte(
+            match port {
+                IMCR_SELECT_PORT => self.select.get(),
+                IMCR_DATA_PORT if self.select.get() == IMCR_SELECT => self.apic_mode.get() as u8,
+                _ => 0xFF,
+            }
+        )
+    }
+
+    fn io_write(&self, port: u16, data: vm::IoOperandType)
+    {
+        let data8 = data.unwrap_byte();
+
+        match port {
+            IMCR_SELECT_PORT => self.select.set(data8),
+            IMCR_DATA_PORT if self.select.get() == IMCR_SELECT => self.set_mode((data8 & IMCR_APIC_MODE) != 0),
+            _ => debug!("imcr: write {:x} to register {:x}", data8, self.select.get()),
+        }
+    }
+}
+
+impl vm::reset_handler for IMCRDev
+{
+    fn reset(&self)
+    {
+        self.select.set(0);
+        self.set_mode(false);
+    }
+}
+
+#[cfg(test)]
+mod imcr_test
+{
+    use super::*;
+
+    thread_local! {
+        static SWITCHES: RefCell<Vec<bool>> = RefCell::new(Vec::new());
+    }
+
+    fn pic_bypass(bypass: bool) {
+        SWITCHES.with(|s| s.borrow_mut().push(bypass));
+    }
+
+    fn switches() -> Vec<bool> {
+        SWITCHES.with(|s| s.borrow_mut().drain(..).collect())
+    }
+
+    fn outb(dev: &IMCRDev, port: u16, val: u8) {
+        vm::io_handler::io_write(dev, port, vm::IoOperandType::byte(val));
+    }
+
+    fn inb(dev: &IMCRDev, port: u16) -> u8 {
+        vm::io_handler::io_read(dev, port, 1).unwrap_byte()
+    }
+
+    #[test] fn mode_switch() {
+        let dev = IMCRDev {
+            select: Cell::new(0),
+            apic_mode: Cell::new(false),
+            pic_bypass: pic_bypass,
+        };
+
+        /* Data port means nothing until IMCR is selected */
+        outb(&dev, IMCR_DATA_PORT, 0x01);
+        assert!(inb(&dev, IMCR_DATA_PORT) == 0xFF && switches().is_empty());
+
+        outb(&dev, IMCR_SELECT_PORT, 0x70);
+        assert!(inb(&dev, IMCR_SELECT_PORT) == 0x70 && inb(&dev, IMCR_DATA_PORT) == 0);
+        outb(&dev, IMCR_DATA_PORT, 0x01);
+        assert!(inb(&dev, IMCR_DATA_PORT) == 1);
+        assert!(switches() == vec![false]);
+
+        /* Same mode again changes nothing */
+        outb(&dev, IMCR_DATA_PORT, 0x01);
+        assert!(switches().is_empty());
+
+        outb(&dev, IMCR_DATA_PORT, 0x00);
+        assert!(switches() == vec![true]);
+
+        /* Reset goes back to PIC mode */
+        outb(&dev, IMCR_DATA_PORT, 0x01);
+        switches();
+        vm::reset_handler::reset(&dev);
+        assert!(inb(&dev, IMCR_SELECT_PORT) == 0 && !dev.apic_mode.get());
+        assert!(switches() == vec![true]);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+fn raise_interrupt(vec: u8)
+{
+    vm::raise_external_interrupt(vec, None);
+    vm::interrupt_guest();
+}
+
+static mut APIC_DEV: Option<*const APICDev> = None;
+
+/* Timer event, stays armed while periodic timer counts */
+fn timer_event(ev: event::Event)
+{
+    unsafe {
+        if let Some(dev) = APIC_DEV {
+            (*dev).timer_expired(ev.missed());
+        }
+    }
+}
+
+/**
+ * Deliver fixed interrupt to local APIC, false if there is none or it doesn't accept it
+ *
+ * \vec    Interrupt vector
+ * \level  Level triggered, EOI handler gets called when guest retires it
+ */
+pub fn deliver_interrupt(vec: u8, level: bool) -> bool
+{
+    unsafe {
+        match APIC_DEV {
+            Some(dev) => (*dev).deliver(vec, level),
+            None => false,
+        }
+    }
+}
+
+/**
+ * INIT and startup IPIs guest sent to other vcpus so far, in the order it sent them
+ */
+pub fn startup_log() -> Vec<StartupIpi>
+{
+    unsafe {
+        match APIC_DEV {
+            Some(dev) => (*dev).startup_log.borrow().clone(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/**
+ * Install a function to be called when guest software enables APIC, so interrupts it turned down can be sent again
+ */
+pub fn set_enable_handler(handler: fn())
+{
+    unsafe {
+        if let Some(dev) = APIC_DEV {
+            (*dev).enable_handler.set(Some(handler));
+        }
+    }
+}
+
+/**
+ * Install a function to be called when guest EOIs a level triggered vector
+ */
+pub fn set_eoi_handler(handler: fn(u8))
+{
+    unsafe {
+        if let Some(dev) = APIC_DEV {
+            (*dev).eoi_handler.set(Some(handler));
+        }
+    }
+}
+
+/**
+ * Install a function to be called when PIC output gets connected to vcpu or disconnected, by IMCR or LINT0
+ */
+pub fn set_pic_output_handler(handler: fn(bool))
+{
+    unsafe {
+        if let Some(dev) = APIC_DEV {
+            (*dev).pic_handler.set(Some(handler));
+        }
+    }
+}
+
+/* IMCR switched between PIC and APIC mode */
+fn pic_bypass(bypass: bool)
+{
+    unsafe {
+        if let Some(dev) = APIC_DEV {
+            (*dev).set_pic_bypass(bypass);
+        }
+    }
+}
+
+/**
+ * Put local APIC in front of PIC as vm interrupt controller and add IMCR
+ */
+pub fn init(config: &config::VmConfig)
+{
+    if !config.apic {
+        return;
+    }
+
+    let dev = Rc::new(APICDev {
+        apic: RefCell::new(LocalApic::new(Rc::new(VcpuClock))),
+        legacy: vm::get_interrupt_controller(),
+        raise: raise_interrupt,
+        cancel: vm::cancel_external_interrupt,
+        eoi_handler: Cell::new(None),
+        enable_handler: Cell::new(None),
+        nmi: vm::raise_nmi,
+        pic_output: pic::set_output_connected,
+        pic_bypass: Cell::new(true),
+        pic_connected: Cell::new(true),
+        pic_handler: Cell::new(None),
+        timer: Cell::new(None),
+        startup_log: RefCell::new(Vec::new()),
+    });
+
+    unsafe {
+        APIC_DEV = Some(&*dev as *const APICDev);
+    }
+
+    vm::register_interrupt_controller(dev.clone());
+    vm::register_msi_controller(dev.clone());
+    vm::register_mmio_region(dev.clone(), APIC_BASE, APIC_PAGE_SIZE);
+    vm::register_reset_handler(dev.clone());
+
+    let imcr = Rc::new(IMCRDev {
+        select: Cell::new(0),
+        apic_mode: Cell::new(false),
+        pic_bypass: pic_bypass,
+    });
+
+    vm::register_io_region(imcr.clone(), IMCR_SELECT_PORT, 1);
+    vm::register_io_region(imcr.clone(), IMCR_DATA_PORT, 1);
+    vm::register_reset_handler(imcr.clone());
+}
@@ -0,0 +1,2003 @@
+/*
+ * IDE/ATA controller emulation
+ *
+ * Primary channel at 0x1F0-0x1F7/0x3F6 (IRQ14) with a PIO-only hard disk as master.
+ * Secondary channel at 0x170-0x177/0x376 (IRQ15) with an ATAPI CD-ROM as master.
+ * Commands execute instantly, so BSY is never observed by the guest.
+ *
+ * Written sectors go straight to the image, FLUSH CACHE makes them durable on host storage.
+ * Multi-sector write interrupted by reset or another command keeps sectors whose DRQ block completed,
+ * partially filled block is dropped. Read-only disks abort writes before any data is taken.
+ */
+
+use vm;
+use config;
+use summary;
+use devstate;
+use disk::{self, disk_image};
+
+use std::rc::Rc;
+use std::cell::RefCell;
+
+// Primary channel
+const ATA_PRIMARY_BASE: u16     = 0x1F0;
+const ATA_PRIMARY_CTRL: u16     = 0x3F6;
+const ATA_PRIMARY_IRQ: u8       = 14;
+
+// Secondary channel
+const ATA_SECONDARY_BASE: u16   = 0x170;
+const ATA_SECONDARY_CTRL: u16   = 0x376;
+const ATA_SECONDARY_IRQ: u8     = 15;
+
+// Task file register offsets from channel base
+const ATA_REG_DATA: u16         = 0;
+const ATA_REG_ERROR: u16        = 1;    // Error (read) / features (write)
+const ATA_REG_COUNT: u16        = 2;
+const ATA_REG_LBA_LOW: u16      = 3;    // LBA 0-7 / sector number
+const ATA_REG_LBA_MID: u16      = 4;    // LBA 8-15 / cylinder low
+const ATA_REG_LBA_HIGH: u16     = 5;    // LBA 16-23 / cylinder high
+const ATA_REG_DEVICE: u16       = 6;    // Drive/head
+const ATA_REG_STATUS: u16       = 7;    // Status (read) / command (write)
+
+// Status bits
+const ATA_SR_BSY: u8            = 0x80;
+const ATA_SR_DRDY: u8           = 0x40;
+const ATA_SR_DSC: u8            = 0x10;
+const ATA_SR_DRQ: u8            = 0x08;
+const ATA_SR_ERR: u8            = 0x01;
+
+// Error bits
+const ATA_ER_ABRT: u8           = 0x04;
+const ATA_ER_IDNF: u8           = 0x10;
+const ATA_ER_UNC: u8            = 0x40;
+
+// Device register bits
+const ATA_DEV_LBA: u8           = 0x40;
+const ATA_DEV_DRV: u8           = 0x10;
+const ATA_DEV_HEAD_MASK: u8     = 0x0F;
+const ATA_DEV_OBSOLETE: u8      = 0xA0; // Bits 7 and 5 always read as 1
+
+// Device control bits
+const ATA_CTRL_NIEN: u8         = 0x02;
+const ATA_CTRL_SRST: u8         = 0x04;
+
+// Commands
+const ATA_CMD_DEVICE_RESET: u8      = 0x08;
+const ATA_CMD_READ_SECTORS: u8      = 0x20;
+const ATA_CMD_READ_SECTORS_NR: u8   = 0x21;
+const ATA_CMD_WRITE_SECTORS: u8     = 0x30;
+const ATA_CMD_WRITE_SECTORS_NR: u8  = 0x31;
+const ATA_CMD_INIT_PARAMS: u8       = 0x91;
+const ATA_CMD_PACKET: u8            = 0xA0;
+const ATA_CMD_IDENTIFY_PACKET: u8   = 0xA1;
+const ATA_CMD_FLUSH_CACHE: u8       = 0xE7;
+const ATA_CMD_IDENTIFY: u8          = 0xEC;
+const ATA_CMD_SET_FEATURES: u8      = 0xEF;
+
+// SET FEATURES subcommands
+const ATA_FEAT_WCACHE_ON: u8    = 0x02;
+const ATA_FEAT_XFER_MODE: u8    = 0x03;
+const ATA_FEAT_WCACHE_OFF: u8   = 0x82;
+
+pub const ATA_SECTOR_SIZE: usize = 512;
+
+// Largest number of sectors LBA28 can address
+const ATA_LBA28_MAX: u64        = 0x0FFFFFFF;
+
+// IDENTIFY strings
+const ATA_SERIAL: &'static str  = "XVM00000000000000001";
+const ATA_FIRMWARE: &'static str = "1.0";
+const ATA_MODEL: &'static str   = "XVM HARDDISK";
+const ATAPI_MODEL: &'static str = "XVM CD-ROM";
+
+// ATAPI signature in cylinder registers after reset
+const ATAPI_SIG_MID: u8         = 0x14;
+const ATAPI_SIG_HIGH: u8        = 0xEB;
+
+// ATAPI interrupt reason bits in sector count register
+const ATAPI_IR_COD: u8          = 0x01; // Command packet or status
+const ATAPI_IR_IO: u8           = 0x02; // Transfer to host
+
+const ATAPI_FEAT_DMA: u8        = 0x01;
+const ATAPI_PACKET_SIZE: usize  = 12;
+pub const ATAPI_SECTOR_SIZE: usize = 2048;
+
+// SCSI packet commands
+const SCSI_TEST_UNIT_READY: u8  = 0x00;
+const SCSI_REQUEST_SENSE: u8    = 0x03;
+const SCSI_INQUIRY: u8          = 0x12;
+const SCSI_READ_CAPACITY: u8    = 0x25;
+const SCSI_READ_10: u8          = 0x28;
+const SCSI_READ_TOC: u8         = 0x43;
+
+// Sense keys
+const SENSE_NONE: u8            = 0x00;
+const SENSE_NOT_READY: u8       = 0x02;
+const SENSE_MEDIUM_ERROR: u8    = 0x03;
+const SENSE_ILLEGAL_REQUEST: u8 = 0x05;
+
+// Additional sense codes
+const ASC_NONE: u8              = 0x00;
+const ASC_READ_ERROR: u8        = 0x11;
+const ASC_INVALID_OPCODE: u8    = 0x20;
+const ASC_LBA_OUT_OF_RANGE: u8  = 0x21;
+const ASC_INVALID_FIELD: u8     = 0x24;
+const ASC_MEDIUM_NOT_PRESENT: u8 = 0x3A;
+
+/**
+ * Disk CHS geometry
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Geometry
+{
+    pub cylinders: u16,
+    pub heads: u8,
+    pub sectors: u8,
+}
+
+impl Geometry
+{
+    /**
+     * Default translation for a disk of given number of sectors: 16 heads, 63 sectors per track
+     */
+    pub fn from_sectors(total: u64) -> Geometry {
+        let heads = 16;
+        let sectors = 63;
+        let cylinders = ::std::cmp::min(total / (heads * sectors), 16383);
+
+        Geometry {
+            cylinders: cylinders as u16,
+            heads: heads as u8,
+            sectors: sectors as u8,
+        }
+    }
+
+    /**
+     * Logical geometry set by INITIALIZE DEVICE PARAMETERS, cylinders cover as much of the disk as possible
+     */
+    pub fn with_heads_sectors(total: u64, heads: u8, sectors: u8) -> Geometry {
+        let cylinders = ::std::cmp::min(total / (heads as u64 * sectors as u64), 65535);
+
+        Geometry {
+            cylinders: cylinders as u16,
+            heads: heads,
+            sectors: sectors,
+        }
+    }
+
+    pub fn total_sectors(&self) -> u64 {
+        self.cylinders as u64 * self.heads as u64 * self.sectors as u64
+    }
+
+    /** LBA for CHS address, None if address is outside of geometry */
+    pub fn to_lba(&self, c: u16, h: u8, s: u8) -> Option<u64> {
+        if c >= self.cylinders || h >= self.heads || s == 0 || s > self.sectors {
+            return None;
+        }
+
+        Some((c as u64 * self.heads as u64 + h as u64) * self.sectors as u64 + (s - 1) as u64)
+    }
+
+    fn from_lba(&self, lba: u64) -> (u16, u8, u8) {
+        let track = lba / self.sectors as u64;
+        ((track / self.heads as u64) as u16,
+         (track % self.heads as u64) as u8,
+         (lba % self.sectors as u64 + 1) as u8)
+    }
+}
+
+/*
+ * Store string in IDENTIFY words padded with spaces.
+ * ATA strings have 2 characters per word with the first one in the high byte.
+ */
+fn ata_string(words: &mut [u16], s: &str)
+{
+    let mut bytes: Vec<u8> = s.bytes().collect();
+    bytes.resize(words.len() * 2, b' ');
+
+    for (i, word) in words.iter_mut().enumerate() {
+        *word = ((bytes[i * 2] as u16) << 8) | bytes[i * 2 + 1] as u16;
+    }
+}
+
+/**
+ * ATA hard disk
+ */
+struct AtaDisk
+{
+    image: Box<disk_image>,
+    sectors: u64,
+    geometry: Geometry,     // Default geometry
+    logical: Geometry,      // Current CHS translation
+}
+
+impl AtaDisk
+{
+    /**
+     * Attach disk image, default geometry is derived from image size unless given explicitly
+     */
+    fn new(image: Box<disk_image>, geometry: Option<Geometry>) -> AtaDisk {
+        let sectors = ::std::cmp::min(image.size() / ATA_SECTOR_SIZE as u64, ATA_LBA28_MAX);
+        let geometry = geometry.unwrap_or(Geometry::from_sectors(sectors));
+
+        AtaDisk {
+            image: image,
+            sectors: sectors,
+            geometry: geometry,
+            logical: geometry,
+        }
+    }
+
+    fn identify(&self) -> [u16; 256] {
+        let mut id = [0u16; 256];
+        let current = ::std::cmp::min(self.logical.total_sectors(), self.sectors);
+
+        id[0] = 0x0040;                             // Fixed device
+        id[1] = self.geometry.cylinders;
+        id[3] = self.geometry.heads as u16;
+        id[6] = self.geometry.sectors as u16;
+        ata_string(&mut id[10..20], ATA_SERIAL);
+        ata_string(&mut id[23..27], ATA_FIRMWARE);
+        ata_string(&mut id[27..47], ATA_MODEL);
+        id[47] = 0x8000;                            // READ/WRITE MULTIPLE not supported
+        id[49] = 0x0200;                            // LBA supported
+        id[51] = 0x0200;                            // PIO mode 2 timing
+        id[53] = 0x0003;                            // Words 54-58 and 64-70 are valid
+        id[54] = self.logical.cylinders;
+        id[55] = self.logical.heads as u16;
+        id[56] = self.logical.sectors as u16;
+        id[57] = current as u16;
+        id[58] = (current >> 16) as u16;
+        id[60] = self.sectors as u16;
+        id[61] = (self.sectors >> 16) as u16;
+        id[64] = 0x0003;                            // PIO modes 3 and 4
+        id[65] = 120;                               // Cycle times in ns
+        id[66] = 120;
+        id[67] = 120;
+        id[68] = 120;
+        id[80] = 0x003E;                            // ATA-1 through ATA-5
+        id[83] = 0x5000;                            // Command set words are valid, FLUSH CACHE
+        id[84] = 0x4000;
+        id[86] = 0x1000;                            // FLUSH CACHE enabled
+        id[87] = 0x4000;
+
+        id
+    }
+
+    /* INITIALIZE DEVICE PARAMETERS */
+    fn set_logical_geometry(&mut self, heads: u8, sectors: u8) -> bool {
+        if heads == 0 || heads > 16 || sectors == 0 {
+            return false;
+        }
+
+        self.logical = Geometry::with_heads_sectors(self.sectors, heads, sectors);
+        true
+    }
+}
+
+/*
+ * Failed packet command: sense key and additional sense code
+ */
+type SenseError = (u8, u8);
+
+fn be16(b: &[u8]) -> u32
+{
+    (b[0] as u32) << 8 | b[1] as u32
+}
+
+fn be32(b: &[u8]) -> u32
+{
+    (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32
+}
+
+fn push_be32(data: &mut Vec<u8>, val: u32)
+{
+    data.extend_from_slice(&[(val >> 24) as u8, (val >> 16) as u8, (val >> 8) as u8, val as u8]);
+}
+
+/* TOC track address as LBA or as MSF with the 2 second lead-in */
+fn push_toc_address(data: &mut Vec<u8>, lba: u64, msf: bool)
+{
+    if msf {
+        let frames = lba + 150;
+        data.extend_from_slice(&[0, (frames / (60 * 75)) as u8, (frames / 75 % 60) as u8, (frames % 75) as u8]);
+    } else {
+        push_be32(data, lba as u32);
+    }
+}
+
+/**
+ * ATAPI CD-ROM drive with optional medium
+ */
+struct AtapiCdrom
+{
+    image: Option<Box<disk_image>>,
+    sectors: u64,
+    sense: SenseError,      // Sense data of last failed command
+
+    data: Vec<u8>,          // Response bytes not yet handed to the guest
+    read_lba: u64,          // Next sector of READ(10) to fetch into data
+    read_remaining: u32,
+}
+
+impl AtapiCdrom
+{
+    fn new(image: Option<Box<disk_image>>) -> AtapiCdrom {
+        let sectors = image.as_ref().map_or(0, |img| img.size() / ATAPI_SECTOR_SIZE as u64);
+
+        AtapiCdrom {
+            image: image,
+            sectors: sectors,
+            sense: (SENSE_NONE, ASC_NONE),
+            data: Vec::new(),
+            read_lba: 0,
+            read_remaining: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.sense = (SENSE_NONE, ASC_NONE);
+        self.data.clear();
+        self.read_remaining = 0;
+    }
+
+    fn identify(&self) -> [u16; 256] {
+        let mut id = [0u16; 256];
+
+        id[0] = 0x85C0;                             // ATAPI, CD-ROM, removable, 12 byte packets
+        ata_string(&mut id[10..20], ATA_SERIAL);
+        ata_string(&mut id[23..27], ATA_FIRMWARE);
+        ata_string(&mut id[27..47], ATAPI_MODEL);
+        id[49] = 0x0200;                            // LBA supported
+        id[51] = 0x0200;                            // PIO mode 2 timing
+        id[53] = 0x0002;                            // Words 64-70 are valid
+        id[64] = 0x0003;                            // PIO modes 3 and 4
+        id[65] = 120;
+        id[66] = 120;
+        id[67] = 120;
+        id[68] = 120;
+        id[80] = 0x001E;                            // ATA-1 through ATA-4
+
+        id
+    }
+
+    fn check_medium(&self) -> Result<(), SenseError> {
+        match self.image {
+            Some(_) => Ok(()),
+            None => Err((SENSE_NOT_READY, ASC_MEDIUM_NOT_PRESENT)),
+        }
+    }
+
+    fn respond(&mut self, mut data: Vec<u8>, alloc_len: u32) {
+        data.truncate(alloc_len as usize);
+        self.data = data;
+    }
+
+    /**
+     * Execute packet command, response data is fetched with next_chunk
+     */
+    fn command(&mut self, cdb: &[u8]) -> Result<(), SenseError> {
+        debug!("atapi: packet command {:x}", cdb[0]);
+
+        self.data.clear();
+        self.read_remaining = 0;
+
+        if cdb[0] == SCSI_REQUEST_SENSE {
+            let (key, asc) = self.sense;
+            let mut data = vec![0u8; 18];
+            data[0] = 0x70;                         // Current error, fixed format
+            data[2] = key;
+            data[7] = 10;                           // Additional sense length
+            data[12] = asc;
+
+            self.sense = (SENSE_NONE, ASC_NONE);
+            self.respond(data, cdb[4] as u32);
+            return Ok(());
+        }
+
+        self.sense = (SENSE_NONE, ASC_NONE);
+
+        match cdb[0] {
+            SCSI_TEST_UNIT_READY => self.check_medium(),
+
+            SCSI_INQUIRY => {
+                let mut data = vec![0u8; 36];
+                data[0] = 0x05;                     // CD-ROM device
+                data[1] = 0x80;                     // Removable medium
+                data[3] = 0x21;                     // ATAPI response format
+                data[4] = 31;                       // Additional length
+                data[8..16].copy_from_slice(b"XVM     ");
+                data[16..32].copy_from_slice(b"XVM CD-ROM      ");
+                data[32..36].copy_from_slice(b"1.0 ");
+
+                self.respond(data, be16(&cdb[3..5]));
+                Ok(())
+            },
+
+            SCSI_READ_CAPACITY => {
+                try!(self.check_medium());
+
+                let mut data = Vec::new();
+                push_be32(&mut data, self.sectors.saturating_sub(1) as u32);
+                push_be32(&mut data, ATAPI_SECTOR_SIZE as u32);
+                self.data = data;
+                Ok(())
+            },
+
+            SCSI_READ_10 => {
+                try!(self.check_medium());
+
+                let lba = be32(&cdb[2..6]) as u64;
+                let count = be16(&cdb[7..9]);
+                if lba + count as u64 > self.sectors {
+                    return Err((SENSE_ILLEGAL_REQUEST, ASC_LBA_OUT_OF_RANGE));
+                }
+
+                self.read_lba = lba;
+                self.read_remaining = count;
+                Ok(())
+            },
+
+            SCSI_READ_TOC => {
+                try!(self.check_medium());
+
+                let msf = cdb[1] & 0x02 != 0;
+                let format = match cdb[2] & 0x0F {
+                    0 => cdb[9] >> 6,                 // Older drives took format from control byte
+                    f => f,
+                };
+
+                /* Single data track followed by lead-out */
+                let mut data = vec![0, 0, 1, 1];
+                match format {
+                    0 => {
+                        data.extend_from_slice(&[0, 0x14, 1, 0]);
+                        push_toc_address(&mut data, 0, msf);
+                        data.extend_from_slice(&[0, 0x14, 0xAA, 0]);
+                        push_toc_address(&mut data, self.sectors, msf);
+                    },
+                    1 => {
+                        /* Multisession info: first session starts at track 1 */
+                        data.extend_from_slice(&[0, 0x14, 1, 0]);
+                        push_toc_address(&mut data, 0, msf);
+                    },
+                    _ => return Err((SENSE_ILLEGAL_REQUEST, ASC_INVALID_FIELD)),
+                }
+
+                let len = data.len() - 2;
+                data[0] = (len >> 8) as u8;
+                data[1] = len as u8;
+
+                self.respond(data, be16(&cdb[7..9]));
+                Ok(())
+            },
+
+            _ => {
+                debug!("atapi: unsupported packet command {:x}", cdb[0]);
+                Err((SENSE_ILLEGAL_REQUEST, ASC_INVALID_OPCODE))
+            },
+        }
+    }
+
+    /**
+     * Next piece of response data no longer than limit, empty when command is done
+     */
+    fn next_chunk(&mut self, limit: usize) -> Result<Vec<u8>, SenseError> {
+        while self.data.len() < limit && self.read_remaining > 0 {
+            let mut sector = vec![0u8; ATAPI_SECTOR_SIZE];
+            let res = self.image.as_mut().unwrap().read_at(self.read_lba * ATAPI_SECTOR_SIZE as u64, &mut sector);
+            if let Err(err) = res {
+                error!("atapi: read of sector {} failed: {}", self.read_lba, err);
+                self.read_remaining = 0;
+                return Err((SENSE_MEDIUM_ERROR, ASC_READ_ERROR));
+            }
+
+            self.data.extend_from_slice(&sector);
+            self.read_lba += 1;
+            self.read_remaining -= 1;
+        }
+
+        let len = ::std::cmp::min(limit, self.data.len());
+        Ok(self.data.drain(..len).collect())
+    }
+}
+
+/*
+ * Device attached to a channel
+ */
+enum AtaDevice
+{
+    Disk(AtaDisk),
+    Cdrom(AtapiCdrom),
+}
+
+/*
+ * Current PIO data transfer
+ */
+#[derive(PartialEq, Debug)]
+enum Transfer
+{
+    None,
+    Identify,
+    Read { lba: u64, remaining: u32 },
+    Write { lba: u64, remaining: u32 },
+    Packet { limit: usize },        // Receiving command packet
+    PacketIn { limit: usize },      // Packet command response, limit is byte count per DRQ block
+}
+
+/**
+ * Single ATA channel with master and slave devices
+ * Task file registers are shared, writes go to both devices.
+ */
+/* Kind of drive attached, as device state shows it */
+fn drive_kind(drive: &Option<AtaDevice>) -> &'static str
+{
+    match *drive {
+        Some(AtaDevice::Disk(_)) => "disk",
+        Some(AtaDevice::Cdrom(_)) => "cdrom",
+        None => "none",
+    }
+}
+
+struct ATAChannel
+{
+    features: u8,
+    count: u8,
+    lba_low: u8,
+    lba_mid: u8,
+    lba_high: u8,
+    device: u8,
+    status: u8,
+    error: u8,
+    control: u8,
+
+    buf: Vec<u8>,           // Current DRQ data block
+    buf_pos: usize,
+    transfer: Transfer,
+    irq: bool,
+
+    drives: [Option<AtaDevice>; 2],
+
+    /* Bytes guest moved through data register, for run summary */
+    bytes_read: u64,
+    bytes_written: u64,
+}
+
+impl ATAChannel
+{
+    fn new(master: Option<AtaDevice>, slave: Option<AtaDevice>) -> ATAChannel {
+        let mut ch = ATAChannel {
+            features: 0,
+            count: 0,
+            lba_low: 0,
+            lba_mid: 0,
+            lba_high: 0,
+            device: 0,
+            status: 0,
+            error: 0,
+            control: 0,
+            buf: Vec::new(),
+            buf_pos: 0,
+            transfer: Transfer::None,
+            irq: false,
+            drives: [master, slave],
+            bytes_read: 0,
+            bytes_written: 0,
+        };
+
+        ch.reset();
+        ch
+    }
+
+    /*
+     * Device reset puts signature in task file and runs diagnostics
+     */
+    fn reset(&mut self) {
+        self.count = 1;
+        self.lba_low = 1;
+        self.lba_mid = 0;
+        self.lba_high = 0;
+        self.device = 0;
+        self.error = 0x01;  // No error detected
+        self.status = ATA_SR_DRDY | ATA_SR_DSC;
+        self.buf.clear();
+        self.buf_pos = 0;
+        self.transfer = Transfer::None;
+        self.irq = false;
+
+        for drive in self.drives.iter_mut() {
+            if let Some(AtaDevice::Cdrom(ref mut cdrom)) = *drive {
+                cdrom.reset();
+            }
+        }
+
+        self.set_signature();
+    }
+
+    /*
+     * Packet devices identify themselves by cylinder registers and don't report ready
+     * until host issues IDENTIFY PACKET DEVICE.
+     */
+    fn set_signature(&mut self) {
+        if let Some(AtaDevice::Cdrom(_)) = self.drives[self.selected()] {
+            self.lba_mid = ATAPI_SIG_MID;
+            self.lba_high = ATAPI_SIG_HIGH;
+            self.status = 0;
+        }
+    }
+
+    fn selected(&self) -> usize {
+        if self.device & ATA_DEV_DRV != 0 { 1 } else { 0 }
+    }
+
+    fn is_selected_present(&self) -> bool {
+        self.drives[self.selected()].is_some()
+    }
+
+    fn disk(&self) -> &AtaDisk {
+        match self.drives[self.selected()] {
+            Some(AtaDevice::Disk(ref disk)) => disk,
+            _ => panic!(),
+        }
+    }
+
+    fn disk_mut(&mut self) -> &mut AtaDisk {
+        match self.drives[self.selected()] {
+            Some(AtaDevice::Disk(ref mut disk)) => disk,
+            _ => panic!(),
+        }
+    }
+
+    fn cdrom_mut(&mut self) -> &mut AtapiCdrom {
+        match self.drives[self.selected()] {
+            Some(AtaDevice::Cdrom(ref mut cdrom)) => cdrom,
+            _ => panic!(),
+        }
+    }
+
+    fn raise_irq(&mut self) {
+        if self.control & ATA_CTRL_NIEN == 0 {
+            self.irq = true;
+        }
+    }
+
+    /**
+     * Take pending interrupt request
+     */
+    fn take_irq(&mut self) -> bool {
+        let irq = self.irq;
+        self.irq = false;
+        irq
+    }
+
+    fn sector_count(&self) -> u32 {
+        if self.count == 0 { 256 } else { self.count as u32 }
+    }
+
+    /* Starting sector from task file in LBA28 or CHS mode */
+    fn task_lba(&self) -> Option<u64> {
+        let disk = self.disk();
+
+        let lba = if self.device & ATA_DEV_LBA != 0 {
+            ((self.device & ATA_DEV_HEAD_MASK) as u64) << 24 |
+            (self.lba_high as u64) << 16 |
+            (self.lba_mid as u64) << 8 |
+            self.lba_low as u64
+        } else {
+            let c = ((self.lba_high as u16) << 8) | self.lba_mid as u16;
+            match disk.logical.to_lba(c, self.device & ATA_DEV_HEAD_MASK, self.lba_low) {
+                Some(lba) => lba,
+                None => return None,
+            }
+        };
+
+        if lba + self.sector_count() as u64 > disk.sectors {
+            return None;
+        }
+
+        Some(lba)
+    }
+
+    /* Reflect sector address in task file using current addressing mode */
+    fn set_task_lba(&mut self, lba: u64) {
+        if self.device & ATA_DEV_LBA != 0 {
+            self.lba_low = lba as u8;
+            self.lba_mid = (lba >> 8) as u8;
+            self.lba_high = (lba >> 16) as u8;
+            self.device = (self.device & !ATA_DEV_HEAD_MASK) | ((lba >> 24) as u8 & ATA_DEV_HEAD_MASK);
+        } else {
+            let (c, h, s) = self.disk().logical.from_lba(lba);
+            self.lba_low = s;
+            self.lba_mid = c as u8;
+            self.lba_high = (c >> 8) as u8;
+            self.device = (self.device & !ATA_DEV_HEAD_MASK) | (h & ATA_DEV_HEAD_MASK);
+        }
+    }
+
+    fn abort(&mut self, error: u8) {
+        self.error = error;
+        self.status = ATA_SR_DRDY | ATA_SR_DSC | ATA_SR_ERR;
+        self.transfer = Transfer::None;
+        self.raise_irq();
+    }
+
+    /* Start PIO-in data block and signal guest to read it */
+    fn start_data_in(&mut self, data: Vec<u8>) {
+        self.buf = data;
+        self.buf_pos = 0;
+        self.status = ATA_SR_DRDY | ATA_SR_DSC | ATA_SR_DRQ;
+        self.raise_irq();
+    }
+
+    /* Start PIO-out data block, guest fills it before we process it */
+    fn start_data_out(&mut self) {
+        self.buf = vec![0; ATA_SECTOR_SIZE];
+        self.buf_pos = 0;
+        self.status = ATA_SR_DRDY | ATA_SR_DSC | ATA_SR_DRQ;
+    }
+
+    fn complete(&mut self) {
+        self.transfer = Transfer::None;
+        self.buf.clear();
+        self.buf_pos = 0;
+        self.status = ATA_SR_DRDY | ATA_SR_DSC;
+    }
+
+    fn read_sector(&mut self, lba: u64) -> Option<Vec<u8>> {
+        let mut data = vec![0; ATA_SECTOR_SIZE];
+        match self.disk_mut().image.read_at(lba * ATA_SECTOR_SIZE as u64, &mut data) {
+            Ok(_) => Some(data),
+            Err(err) => {
+                error!("ata: read of sector {} failed: {}", lba, err);
+                None
+            }
+        }
+    }
+
+    fn start_identify(&mut self, id: [u16; 256]) {
+        let mut data = Vec::with_capacity(ATA_SECTOR_SIZE);
+        for word in id.iter() {
+            data.push(*word as u8);
+            data.push((*word >> 8) as u8);
+        }
+
+        self.transfer = Transfer::Identify;
+        self.start_data_in(data);
+    }
+
+    fn exec_command(&mut self, cmd: u8) {
+        match self.drives[self.selected()] {
+            None => return,
+            Some(AtaDevice::Cdrom(_)) => return self.exec_packet_device_command(cmd),
+            Some(AtaDevice::Disk(_)) => (),
+        }
+
+        debug!("ata: command {:x}", cmd);
+        self.error = 0;
+
+        match cmd {
+            ATA_CMD_IDENTIFY => {
+                let id = self.disk().identify();
+                self.start_identify(id);
+            },
+
+            ATA_CMD_READ_SECTORS | ATA_CMD_READ_SECTORS_NR => {
+                let lba = match self.task_lba() {
+                    Some(lba) => lba,
+                    None => return self.abort(ATA_ER_IDNF),
+                };
+
+                match self.read_sector(lba) {
+                    Some(data) => {
+                        self.transfer = Transfer::Read { lba: lba, remaining: self.sector_count() };
+                        self.set_task_lba(lba);
+                        self.start_data_in(data);
+                    },
+                    None => self.abort(ATA_ER_UNC),
+                }
+            },
+
+            ATA_CMD_WRITE_SECTORS | ATA_CMD_WRITE_SECTORS_NR => {
+                if self.disk().image.is_read_only() {
+                    return self.abort(ATA_ER_ABRT);
+                }
+
+                let lba = match self.task_lba() {
+                    Some(lba) => lba,
+                    None => return self.abort(ATA_ER_IDNF),
+                };
+
+                self.transfer = Transfer::Write { lba: lba, remaining: self.sector_count() };
+                self.start_data_out();
+            },
+
+            ATA_CMD_FLUSH_CACHE => {
+                if let Err(err) = self.disk_mut().image.flush() {
+                    error!("ata: flush failed: {}", err);
+                    return self.abort(ATA_ER_ABRT);
+                }
+
+                self.complete();
+                self.raise_irq();
+            },
+
+            ATA_CMD_INIT_PARAMS => {
+                /* Sector count has sectors per track, head field has max head number */
+                let heads = (self.device & ATA_DEV_HEAD_MASK) + 1;
+                let sectors = self.count;
+                if self.disk_mut().set_logical_geometry(heads, sectors) {
+                    self.complete();
+                    self.raise_irq();
+                } else {
+                    self.abort(ATA_ER_ABRT);
+                }
+            },
+
+            ATA_CMD_SET_FEATURES => {
+                match self.features {
+                    ATA_FEAT_XFER_MODE | ATA_FEAT_WCACHE_ON | ATA_FEAT_WCACHE_OFF => {
+                        self.complete();
+                        self.raise_irq();
+                    },
+                    _ => self.abort(ATA_ER_ABRT),
+                }
+            },
+
+            _ => {
+                debug!("ata: unsupported command {:x}", cmd);
+                self.abort(ATA_ER_ABRT);
+            }
+        }
+    }
+
+    /*
+     * Commands accepted by a packet device, everything else goes through PACKET
+     */
+    fn exec_packet_device_command(&mut self, cmd: u8) {
+        debug!("atapi: command {:x}", cmd);
+        self.error = 0;
+
+        match cmd {
+            ATA_CMD_IDENTIFY_PACKET => {
+                let id = match self.drives[self.selected()] {
+                    Some(AtaDevice::Cdrom(ref cdrom)) => cdrom.identify(),
+                    _ => panic!(),
+                };
+                self.start_identify(id);
+            },
+
+            ATA_CMD_PACKET => {
+                if self.features & ATAPI_FEAT_DMA != 0 {
+                    return self.abort(ATA_ER_ABRT);
+                }
+
+                /* Byte count limit must be even, zero means maximum */
+                let limit = match ((self.lba_high as usize) << 8 | self.lba_mid as usize) & !1 {
+                    0 => 0xFFFE,
+                    limit => limit,
+                };
+
+                self.transfer = Transfer::Packet { limit: limit };
+                self.buf = vec![0; ATAPI_PACKET_SIZE];
+                self.buf_pos = 0;
+                self.count = ATAPI_IR_COD;
+                self.status = ATA_SR_DRDY | ATA_SR_DSC | ATA_SR_DRQ;
+            },
+
+            ATA_CMD_DEVICE_RESET => {
+                self.cdrom_mut().reset();
+                self.transfer = Transfer::None;
+                self.buf.clear();
+                self.buf_pos = 0;
+                self.count = 1;
+                self.lba_low = 1;
+                self.error = 0x01;
+                self.set_signature();
+            },
+
+            ATA_CMD_SET_FEATURES => {
+                match self.features {
+                    ATA_FEAT_XFER_MODE => {
+                        self.complete();
+                        self.raise_irq();
+                    },
+                    _ => self.abort(ATA_ER_ABRT),
+                }
+            },
+
+            ATA_CMD_IDENTIFY => {
+                /* Abort leaving signature in place so that host knows to use IDENTIFY PACKET DEVICE */
+                self.count = 1;
+                self.lba_low = 1;
+                self.set_signature();
+                self.abort(ATA_ER_ABRT);
+            },
+
+            _ => {
+                debug!("atapi: unsupported command {:x}", cmd);
+                self.abort(ATA_ER_ABRT);
+            }
+        }
+    }
+
+    /* Host sent the whole command packet */
+    fn packet_received(&mut self, limit: usize) {
+        let cdb = self.buf.clone();
+        match self.cdrom_mut().command(&cdb) {
+            Ok(_) => self.packet_data_in(limit),
+            Err(sense) => self.packet_complete(Some(sense)),
+        }
+    }
+
+    /* Hand next block of response data to host or finish command */
+    fn packet_data_in(&mut self, limit: usize) {
+        match self.cdrom_mut().next_chunk(limit) {
+            Ok(ref chunk) if chunk.is_empty() => self.packet_complete(None),
+            Ok(chunk) => {
+                self.lba_mid = chunk.len() as u8;
+                self.lba_high = (chunk.len() >> 8) as u8;
+                self.count = ATAPI_IR_IO;
+                self.transfer = Transfer::PacketIn { limit: limit };
+                self.start_data_in(chunk);
+            },
+            Err(sense) => self.packet_complete(Some(sense)),
+        }
+    }
+
+    /* Status phase, failed command reports sense key in error register */
+    fn packet_complete(&mut self, sense: Option<SenseError>) {
+        self.complete();
+        self.count = ATAPI_IR_IO | ATAPI_IR_COD;
+
+        if let Some((key, asc)) = sense {
+            self.cdrom_mut().sense = (key, asc);
+            self.error = key << 4;
+            self.status |= ATA_SR_ERR;
+        }
+
+        self.raise_irq();
+    }
+
+    /*
+     * Guest drained current PIO-in block
+     * Each next sector of a multi-sector read gets its own DRQ block and interrupt.
+     */
+    fn data_in_done(&mut self) {
+        match self.transfer {
+            Transfer::Read { lba, remaining } if remaining > 1 => {
+                let next = lba + 1;
+                match self.read_sector(next) {
+                    Some(data) => {
+                        self.transfer = Transfer::Read { lba: next, remaining: remaining - 1 };
+                        self.set_task_lba(next);
+                        self.start_data_in(data);
+                    },
+                    None => self.abort(ATA_ER_UNC),
+                }
+            },
+            Transfer::PacketIn { limit } => self.packet_data_in(limit),
+            _ => self.complete(),
+        }
+    }
+
+    /* Guest filled current PIO-out block */
+    fn data_out_done(&mut self) {
+        if let Transfer::Packet { limit } = self.transfer {
+            return self.packet_received(limit);
+        }
+
+        if let Transfer::Write { lba, remaining } = self.transfer {
+            let res = {
+                let buf = &self.buf;
+                match self.drives[self.selected()] {
+                    Some(AtaDevice::Disk(ref mut disk)) => disk.image.write_at(lba * ATA_SECTOR_SIZE as u64, buf),
+                    _ => panic!(),
+                }
+            };
+
+            if let Err(err) = res {
+                error!("ata: write of sector {} failed: {}", lba, err);
+                return self.abort(ATA_ER_UNC);
+            }
+
+            self.set_task_lba(lba);
+            if remaining > 1 {
+                self.transfer = Transfer::Write { lba: lba + 1, remaining: remaining - 1 };
+                self.start_data_out();
+            } else {
+                self.complete();
+            }
+
+            self.raise_irq();
+        }
+    }
+
+    fn read_data(&mut self, size: usize) -> u32 {
+        if self.status & ATA_SR_DRQ == 0 || self.buf.is_empty() {
+            return 0xFFFFFFFF;
+        }
+
+        let mut val: u32 = 0;
+        for i in 0..size {
+            if self.buf_pos < self.buf.len() {
+                val |= (self.buf[self.buf_pos] as u32) << (i * 8);
+                self.buf_pos += 1;
+                self.bytes_read += 1;
+            }
+        }
+
+        if self.buf_pos == self.buf.len() && self.transfer != Transfer::None && !self.is_data_out() {
+            self.data_in_done();
+        }
+
+        val
+    }
+
+    fn is_data_out(&self) -> bool {
+        match self.transfer {
+            Transfer::Write { .. } | Transfer::Packet { .. } => true,
+            _ => false,
+        }
+    }
+
+    fn write_data(&mut self, val: u32, size: usize) {
+        if self.status & ATA_SR_DRQ == 0 || !self.is_data_out() {
+            return;
+        }
+
+        for i in 0..size {
+            if self.buf_pos < self.buf.len() {
+                self.buf[self.buf_pos] = (val >> (i * 8)) as u8;
+                self.buf_pos += 1;
+                self.bytes_written += 1;
+            }
+        }
+
+        if self.buf_pos == self.buf.len() {
+            self.data_out_done();
+        }
+    }
+
+    /* Task file and transfer in progress for inspection */
+    fn device_state(&self) -> devstate::DeviceState {
+        let transfer = match self.transfer {
+            Transfer::None => "none",
+            Transfer::Identify => "identify",
+            Transfer::Read { .. } => "read",
+            Transfer::Write { .. } => "write",
+            Transfer::Packet { .. } => "packet",
+            Transfer::PacketIn { .. } => "packet in",
+        };
+        devstate::DeviceState::new()
+            .hex("status", self.status as u64, 2)
+            .hex("error", self.error as u64, 2)
+            .hex("features", self.features as u64, 2)
+            .number("count", self.count as u64)
+            .hex("lba_low", self.lba_low as u64, 2)
+            .hex("lba_mid", self.lba_mid as u64, 2)
+            .hex("lba_high", self.lba_high as u64, 2)
+            .hex("device", self.device as u64, 2)
+            .hex("control", self.control as u64, 2)
+            .text("transfer", transfer)
+            .number("buf_pos", self.buf_pos as u64)
+            .number("buf_len", self.buf.len() as u64)
+            .text("master", drive_kind(&self.drives[0]))
+            .text("slave", drive_kind(&self.drives[1]))
+    }
+
+    /* Task file device_state() gave, only between commands since transfer buffer isn't in it */
+    fn restore(&mut self, state: &devstate::DeviceState) -> Result<(), String> {
+        if try!(state.get_text("transfer")) != "none" {
+            return Err(String::from("Transfer in progress can't be restored"));
+        }
+        for &(name, i) in &[("master", 0), ("slave", 1)] {
+            let saved = try!(state.get_text(name));
+            if saved != drive_kind(&self.drives[i]) {
+                return Err(format!("Snapshot has {} as {}, VM has {}", saved, name, drive_kind(&self.drives[i])));
+            }
+        }
+
+        self.status = try!(state.get_number("status")) as u8;
+        self.error = try!(state.get_number("error")) as u8;
+        self.features = try!(state.get_number("features")) as u8;
+        self.count = try!(state.get_number("count")) as u8;
+        self.lba_low = try!(state.get_number("lba_low")) as u8;
+        self.lba_mid = try!(state.get_number("lba_mid")) as u8;
+        self.lba_high = try!(state.get_number("lba_high")) as u8;
+        self.device = try!(state.get_number("device")) as u8;
+        self.control = try!(state.get_number("control")) as u8;
+        self.transfer = Transfer::None;
+        self.buf.clear();
+        self.buf_pos = 0;
+        Ok(())
+    }
+
+    fn read_status(&self) -> u8 {
+        if !self.is_selected_present() {
+            return 0;
+        }
+
+        self.status
+    }
+
+    fn read_reg(&mut self, reg: u16) -> u8 {
+        /* Absent slave doesn't drive the bus */
+        if !self.is_selected_present() && reg != ATA_REG_DEVICE {
+            return 0;
+        }
+
+        match reg {
+            ATA_REG_ERROR => self.error,
+            ATA_REG_COUNT => self.count,
+            ATA_REG_LBA_LOW => self.lba_low,
+            ATA_REG_LBA_MID => self.lba_mid,
+            ATA_REG_LBA_HIGH => self.lba_high,
+            ATA_REG_DEVICE => self.device | ATA_DEV_OBSOLETE,
+            ATA_REG_STATUS => self.read_status(),
+            _ => panic!(),
+        }
+    }
+
+    fn write_reg(&mut self, reg: u16, val: u8) {
+        match reg {
+            ATA_REG_ERROR => self.features = val,
+            ATA_REG_COUNT => self.count = val,
+            ATA_REG_LBA_LOW => self.lba_low = val,
+            ATA_REG_LBA_MID => self.lba_mid = val,
+            ATA_REG_LBA_HIGH => self.lba_high = val,
+            ATA_REG_DEVICE => self.device = val & !ATA_DEV_OBSOLETE,
+            ATA_REG_STATUS => self.exec_command(val),
+            _ => panic!(),
+        }
+    }
+
+    /* Software reset happens when SRST bit is cleared after being set */
+    fn write_control(&mut self, val: u8) {
+        let srst_done = (self.control & ATA_CTRL_SRST) != 0 && (val & ATA_CTRL_SRST) == 0;
+
+        if val & ATA_CTRL_SRST != 0 {
+            self.status = ATA_SR_BSY;
+        }
+
+        self.control = val;
+
+        if srst_done {
+            self.reset();
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct ATADev
+{
+    channel: RefCell<ATAChannel>,
+    base: u16,
+    ctrl: u16,
+    irq: u8,
+    assert_irq: fn(u8),
+}
+
+impl ATADev
+{
+    fn service(&self) {
+        if self.channel.borrow_mut().take_irq() {
+            (self.assert_irq)(self.irq);
+        }
+    }
+}
+
+impl vm::io_handler for ATADev
+{
+    fn io_read(&self, port: u16, size: u8) -> vm::IoOperandType
+    {
+        let mut ch = self.channel.borrow_mut();
+
+        if port == self.ctrl {
+            /* Alternate status, doesn't acknowledge interrupt */
+            return vm::IoOperandType::byte(ch.read_status());
+        }
+
+        let reg = port - self.base;
+        if reg == ATA_REG_DATA {
+            let val = ch.read_data(size as usize);
+            drop(ch);
+            self.service();
+
+            return match size {
+                1 => vm::IoOperandType::byte(val as u8),
+                2 => vm::IoOperandType::word(val as u16),
+                4 => vm::IoOperandType::dword(val),
+                _ => panic!(),
+            };
+        }
+
+        vm::IoOperandType::byte(ch.read_reg(reg))
+    }
+
+    fn io_write(&self, port: u16, data: vm::IoOperandType)
+    {
+        {
+            let mut ch = self.channel.borrow_mut();
+
+            if port == self.ctrl {
+                ch.write_control(data.unwrap_byte());
+            } else {
+                let reg = port - self.base;
+                if reg == ATA_REG_DATA {
+                    match data {
+                        vm::IoOperandType::byte(v) => ch.write_data(v as u32, 1),
+                        vm::IoOperandType::word(v) => ch.write_data(v as u32, 2),
+                        vm::IoOperandType::dword(v) => ch.write_data(v, 4),
+                    }
+                } else {
+                    ch.write_reg(reg, data.unwrap_byte());
+                }
+            }
+        }
+
+        self.service();
+    }
+}
+
+impl vm::stats_handler for ATADev
+{
+    fn stats(&self) -> summary::DeviceStats
+    {
+        let ch = self.channel.borrow();
+        summary::DeviceStats {
+            device: if self.base == ATA_PRIMARY_BASE { "ata0" } else { "ata1" },
+            bytes_read: ch.bytes_read,
+            bytes_written: ch.bytes_written,
+        }
+    }
+}
+
+/* Channel as monitor shows it, with the image file behind its master drive */
+struct ATAChannelState
+{
+    dev: Rc<ATADev>,
+    image: Option<String>,
+}
+
+impl vm::state_handler for ATAChannelState
+{
+    fn device_state(&self) -> Option<devstate::DeviceState>
+    {
+        let state = match self.dev.channel.try_borrow() {
+            Ok(ch) => ch.device_state(),
+            Err(_) => return None,
+        };
+
+        Some(match self.image {
+            Some(ref path) => state.annotate("image", path),
+            None => state,
+        })
+    }
+
+    fn restore_state(&self, state: &devstate::DeviceState) -> Result<(), String>
+    {
+        self.dev.channel.borrow_mut().restore(state)
+    }
+}
+
+impl vm::reset_handler for ATADev
+{
+    fn reset(&self)
+    {
+        let mut ch = self.channel.borrow_mut();
+        ch.control = 0;
+        ch.reset();
+
+        /* Translation set by INITIALIZE DEVICE PARAMETERS doesn't survive power cycle */
+        for drive in ch.drives.iter_mut() {
+            if let Some(AtaDevice::Disk(ref mut disk)) = *drive {
+                disk.logical = disk.geometry;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod ata_test
+{
+    use super::*;
+    use std::io::Write;
+    use std::cell::Cell;
+
+    thread_local! {
+        static IRQ_COUNT: Cell<u32> = Cell::new(0);
+    }
+
+    fn count_irq(irq: u8) {
+        assert!(irq == ATA_PRIMARY_IRQ);
+        IRQ_COUNT.with(|c| c.set(c.get() + 1));
+    }
+
+    fn count_cdrom_irq(irq: u8) {
+        assert!(irq == ATA_SECONDARY_IRQ);
+        IRQ_COUNT.with(|c| c.set(c.get() + 1));
+    }
+
+    fn irq_count() -> u32 {
+        IRQ_COUNT.with(|c| c.get())
+    }
+
+    fn sector_byte(lba: usize, i: usize) -> u8 {
+        (lba * 3 + i) as u8
+    }
+
+    /* Temp file named after the test process, so concurrent runs don't share images */
+    fn image_path(name: &str) -> ::std::path::PathBuf {
+        let (stem, ext) = name.split_at(name.rfind('.').unwrap_or(name.len()));
+        ::std::env::temp_dir().join(format!("{}_{}{}", stem, ::std::process::id(), ext))
+    }
+
+    /* Temp image file with a distinct pattern in each sector */
+    fn make_image(name: &str, sectors: usize) -> disk::FileImage {
+        let path = image_path(name);
+        {
+            let mut file = ::std::fs::File::create(&path).unwrap();
+            let data: Vec<u8> = (0..sectors * ATA_SECTOR_SIZE)
+                .map(|i| sector_byte(i / ATA_SECTOR_SIZE, i % ATA_SECTOR_SIZE))
+                .collect();
+            file.write_all(&data).unwrap();
+        }
+
+        disk::FileImage::open(path.to_str().unwrap()).unwrap()
+    }
+
+    fn dev_on(image: Box<disk_image>, geometry: Option<Geometry>) -> ATADev {
+        ATADev {
+            channel: RefCell::new(ATAChannel::new(Some(AtaDevice::Disk(AtaDisk::new(image, geometry))), None)),
+            base: ATA_PRIMARY_BASE,
+            ctrl: ATA_PRIMARY_CTRL,
+            irq: ATA_PRIMARY_IRQ,
+            assert_irq: count_irq,
+        }
+    }
+
+    /* Disk on a temp image, which the device keeps open and nothing else looks at, so the file goes right away */
+    fn make_dev_geometry(name: &str, sectors: usize, geometry: Option<Geometry>) -> ATADev {
+        let dev = dev_on(Box::new(make_image(name, sectors)), geometry);
+        ::std::fs::remove_file(image_path(name)).unwrap();
+        dev
+    }
+
+    fn make_dev(name: &str, sectors: usize) -> ATADev {
+        make_dev_geometry(name, sectors, None)
+    }
+
+    fn outb(dev: &ATADev, reg: u16, val: u8) {
+        vm::io_handler::io_write(dev, dev.base + reg, vm::IoOperandType::byte(val));
+    }
+
+    fn inb(dev: &ATADev, reg: u16) -> u8 {
+        vm::io_handler::io_read(dev, dev.base + reg, 1).unwrap_byte()
+    }
+
+    fn inw(dev: &ATADev) -> u16 {
+        vm::io_handler::io_read(dev, dev.base + ATA_REG_DATA, 2).unwrap_word()
+    }
+
+    fn identify_words(dev: &ATADev) -> [u16; 256] {
+        outb(dev, ATA_REG_STATUS, ATA_CMD_IDENTIFY);
+
+        let mut id = [0u16; 256];
+        for i in 0..256 {
+            id[i] = inw(dev);
+        }
+        id
+    }
+
+    /* Decode ATA string from IDENTIFY words */
+    fn id_string(words: &[u16]) -> String {
+        let mut s = String::new();
+        for w in words {
+            s.push((*w >> 8) as u8 as char);
+            s.push(*w as u8 as char);
+        }
+        String::from(s.trim_right())
+    }
+
+    fn read_block(dev: &ATADev) -> Vec<u8> {
+        let mut data = Vec::new();
+        for _ in 0..ATA_SECTOR_SIZE / 2 {
+            let w = inw(dev);
+            data.push(w as u8);
+            data.push((w >> 8) as u8);
+        }
+        data
+    }
+
+    #[test] fn identify() {
+        let dev = make_dev("xvm_ata_identify.img", 2048);
+        let irqs = irq_count();
+
+        outb(&dev, ATA_REG_DEVICE, 0xA0);
+        assert!(inb(&dev, ATA_REG_STATUS) == ATA_SR_DRDY | ATA_SR_DSC);
+        outb(&dev, ATA_REG_STATUS, ATA_CMD_IDENTIFY);
+        assert!(irq_count() == irqs + 1);
+        assert!(inb(&dev, ATA_REG_STATUS) & (ATA_SR_BSY | ATA_SR_DRQ) == ATA_SR_DRQ);
+
+        let mut id = [0u16; 256];
+        for i in 0..256 {
+            id[i] = inw(&dev);
+        }
+
+        assert!(inb(&dev, ATA_REG_STATUS) == ATA_SR_DRDY | ATA_SR_DSC);
+        assert!(id[0] == 0x0040);
+        assert!(id[1] == 2 && id[3] == 16 && id[6] == 63);
+        assert!(id[27] == ((b'X' as u16) << 8 | b'V' as u16));
+        assert!(id[49] & 0x0200 != 0);
+        assert!(id[60] == 2048 && id[61] == 0);
+    }
+
+    /*
+     * 20M disk: IDENTIFY contents, then switch to 4 heads/32 sectors translation
+     */
+    #[test] fn identify_20m_and_translation() {
+        let sectors = 20 * 1024 * 1024 / ATA_SECTOR_SIZE;
+        let dev = make_dev("xvm_ata_20m.img", sectors);
+
+        outb(&dev, ATA_REG_DEVICE, 0xA0);
+        let id = identify_words(&dev);
+        assert!(id[0] == 0x0040);
+        assert!(id[1] == 40 && id[3] == 16 && id[6] == 63);
+        assert!(id_string(&id[10..20]) == ATA_SERIAL);
+        assert!(id_string(&id[23..27]) == ATA_FIRMWARE);
+        assert!(id_string(&id[27..47]) == ATA_MODEL);
+        assert!(id[47] == 0x8000);
+        assert!(id[49] == 0x0200);
+        assert!(id[53] == 0x0003);
+        assert!(id[54] == 40 && id[55] == 16 && id[56] == 63);
+        assert!(id[57] as u32 | (id[58] as u32) << 16 == 40 * 16 * 63);
+        assert!(id[60] as usize | (id[61] as usize) << 16 == sectors);
+        assert!(id[64] == 0x0003);
+        assert!(id[80] == 0x003E && id[83] == 0x5000 && id[86] == 0x1000);
+
+        /* INITIALIZE DEVICE PARAMETERS: 4 heads, 32 sectors per track */
+        let irqs = irq_count();
+        outb(&dev, ATA_REG_COUNT, 32);
+        outb(&dev, ATA_REG_DEVICE, 0xA3);
+        outb(&dev, ATA_REG_STATUS, ATA_CMD_INIT_PARAMS);
+        assert!(irq_count() == irqs + 1);
+        assert!(inb(&dev, ATA_REG_STATUS) == ATA_SR_DRDY | ATA_SR_DSC);
+
+        /* Default geometry stays, current geometry follows translation */
+        let id = identify_words(&dev);
+        assert!(id[1] == 40 && id[3] == 16 && id[6] == 63);
+        assert!(id[54] == 320 && id[55] == 4 && id[56] == 32);
+        assert!(id[57] as u32 | (id[58] as u32) << 16 == 320 * 4 * 32);
+
+        /* C/H/S 2/1/5 translates to LBA (2 * 4 + 1) * 32 + 4 */
+        outb(&dev, ATA_REG_COUNT, 1);
+        outb(&dev, ATA_REG_LBA_LOW, 5);
+        outb(&dev, ATA_REG_LBA_MID, 2);
+        outb(&dev, ATA_REG_LBA_HIGH, 0);
+        outb(&dev, ATA_REG_DEVICE, 0xA1);
+        outb(&dev, ATA_REG_STATUS, ATA_CMD_READ_SECTORS);
+        let data = read_block(&dev);
+        let expected: Vec<u8> = (0..ATA_SECTOR_SIZE).map(|i| sector_byte(292, i)).collect();
+        assert!(data == expected);
+
+        /* Head beyond translation is an addressing error */
+        outb(&dev, ATA_REG_DEVICE, 0xA5);
+        outb(&dev, ATA_REG_STATUS, ATA_CMD_READ_SECTORS);
+        assert!(inb(&dev, ATA_REG_ERROR) == ATA_ER_IDNF);
+
+        /* Zero sectors per track is rejected */
+        outb(&dev, ATA_REG_COUNT, 0);
+        outb(&dev, ATA_REG_STATUS, ATA_CMD_INIT_PARAMS);
+        assert!(inb(&dev, ATA_REG_ERROR) == ATA_ER_ABRT);
+    }
+
+    #[test] fn geometry_override() {
+        let geometry = Geometry { cylinders: 100, heads: 8, sectors: 17 };
+        let dev = make_dev_geometry("xvm_ata_override.img", 100 * 8 * 17, Some(geometry));
+
+        let id = identify_words(&dev);
+        assert!(id[1] == 100 && id[3] == 8 && id[6] == 17);
+        assert!(id[54] == 100 && id[55] == 8 && id[56] == 17);
+
+        /* C/H/S 0/1/1 is LBA 17 */
+        outb(&dev, ATA_REG_COUNT, 1);
+        outb(&dev, ATA_REG_LBA_LOW, 1);
+        outb(&dev, ATA_REG_LBA_MID, 0);
+        outb(&dev, ATA_REG_DEVICE, 0xA1);
+        outb(&dev, ATA_REG_STATUS, ATA_CMD_READ_SECTORS);
+        assert!(read_block(&dev)[0] == sector_byte(17, 0));
+    }
+
+    #[test] fn read_two_sectors() {
+        let dev = make_dev("xvm_ata_read.img", 64);
+        let irqs = irq_count();
+
+        outb(&dev, ATA_REG_COUNT, 2);
+        outb(&dev, ATA_REG_LBA_LOW, 10);
+        outb(&dev, ATA_REG_LBA_MID, 0);
+        outb(&dev, ATA_REG_LBA_HIGH, 0);
+        outb(&dev, ATA_REG_DEVICE, 0xE0);
+        outb(&dev, ATA_REG_STATUS, ATA_CMD_READ_SECTORS);
+
+        /* Every sector gets an interrupt and DRQ block */
+        for sector in 10..12 {
+            assert!(inb(&dev, ATA_REG_STATUS) == ATA_SR_DRDY | ATA_SR_DSC | ATA_SR_DRQ);
+            assert!(irq_count() == irqs + sector - 9);
+
+            let data = read_block(&dev);
+            let expected: Vec<u8> = (0..ATA_SECTOR_SIZE).map(|i| sector_byte(sector as usize, i)).collect();
+            assert!(data == expected);
+        }
+
+        assert!(irq_count() == irqs + 2);
+        assert!(inb(&dev, ATA_REG_STATUS) == ATA_SR_DRDY | ATA_SR_DSC);
+        assert!(inb(&dev, ATA_REG_LBA_LOW) == 11);
+    }
+
+    #[test] fn state_mid_read() {
+        let dev = Rc::new(make_dev("xvm_ata_state.img", 64));
+        let view = ATAChannelState { dev: dev.clone(), image: Some(String::from("/tmp/xvm_ata_state.img")) };
+
+        outb(&dev, ATA_REG_COUNT, 2);
+        outb(&dev, ATA_REG_LBA_LOW, 10);
+        outb(&dev, ATA_REG_DEVICE, 0xE0);
+        outb(&dev, ATA_REG_STATUS, ATA_CMD_READ_SECTORS);
+        inw(&dev);
+
+        let state = vm::state_handler::device_state(&view).unwrap();
+        assert!(state.get("transfer") == Some(&devstate::StateValue::Text(String::from("read"))));
+        assert!(state.get("status") == Some(&devstate::StateValue::Hex((ATA_SR_DRDY | ATA_SR_DSC | ATA_SR_DRQ) as u64, 2)));
+        assert!(state.get("buf_pos") == Some(&devstate::StateValue::Number(2)));
+        assert!(state.get("master") == Some(&devstate::StateValue::Text(String::from("disk"))));
+        assert!(state.annotations == vec![(String::from("image"), String::from("/tmp/xvm_ata_state.img"))]);
+
+        let _busy = dev.channel.borrow_mut();
+        assert!(vm::state_handler::device_state(&view).is_none());
+    }
+
+    /* Task file comes back between commands, not in the middle of one */
+    #[test] fn restore() {
+        let dev = Rc::new(make_dev("xvm_ata_restore.img", 64));
+        let view = ATAChannelState { dev: dev.clone(), image: None };
+
+        outb(&dev, ATA_REG_COUNT, 2);
+        outb(&dev, ATA_REG_LBA_LOW, 10);
+        outb(&dev, ATA_REG_DEVICE, 0xE0);
+        let idle = vm::state_handler::device_state(&view).unwrap();
+        outb(&dev, ATA_REG_STATUS, ATA_CMD_READ_SECTORS);
+        let busy = vm::state_handler::device_state(&view).unwrap();
+
+        let restored = make_dev("xvm_ata_restored.img", 64);
+        let restored = ATAChannelState { dev: Rc::new(restored), image: None };
+        let json = idle.to_json();
+        vm::state_handler::restore_state(&restored, &devstate::DeviceState::from_json(&json).unwrap()).unwrap();
+        assert!(vm::state_handler::device_state(&restored).unwrap().to_json() == json);
+
+        let err = vm::state_handler::restore_state(&restored, &busy).unwrap_err();
+        assert!(err == "Transfer in progress can't be restored", "{}", err);
+        let mut cdrom = idle.clone();
+        for field in cdrom.fields.iter_mut().filter(|field| field.0 == "slave") {
+            field.1 = devstate::StateValue::Text(String::from("cdrom"));
+        }
+        let err = vm::state_handler::restore_state(&restored, &cdrom).unwrap_err();
+        assert!(err == "Snapshot has cdrom as slave, VM has none", "{}", err);
+    }
+
+    /* Task file with annotations goes through either codec whole, fields it doesn't know are dropped */
+    #[test] fn codecs() {
+        let dev = Rc::new(make_dev("xvm_ata_codecs.img", 64));
+        let view = ATAChannelState { dev: dev.clone(), image: Some(String::from("/tmp/xvm_ata_codecs.img")) };
+        outb(&dev, ATA_REG_COUNT, 200);
+        outb(&dev, ATA_REG_LBA_LOW, 10);
+        outb(&dev, ATA_REG_DEVICE, 0xE0);
+        let state = vm::state_handler::device_state(&view).unwrap();
+
+        for codec in &[&devstate::JsonCodec as &devstate::state_codec, &devstate::BinaryCodec] {
+            let decoded = codec.decode(&codec.encode(&state)).unwrap();
+            assert!(decoded == state);
+
+            let restored = ATAChannelState { dev: Rc::new(make_dev("xvm_ata_codecs_restored.img", 64)), image: None };
+            vm::state_handler::restore_state(&restored, &decoded).unwrap();
+            assert!(vm::state_handler::device_state(&restored).unwrap().fields == state.fields);
+        }
+
+        assert!(vm::state_handler::unknown_fields(&view) == devstate::UnknownFields::Ignore);
+        let newer = state.clone().number("sector_size", 512);
+        assert!(newer.known_fields(&state, vm::state_handler::unknown_fields(&view)) == Ok(state));
+    }
+
+    #[test] fn chs_read_and_write() {
+        let dev = make_dev("xvm_ata_write.img", 16 * 63 * 2);
+
+        /* C/H/S 1/2/3 is LBA (1 * 16 + 2) * 63 + 2 */
+        let lba = (1 * 16 + 2) * 63 + 2;
+        outb(&dev, ATA_REG_COUNT, 1);
+        outb(&dev, ATA_REG_LBA_LOW, 3);
+        outb(&dev, ATA_REG_LBA_MID, 1);
+        outb(&dev, ATA_REG_LBA_HIGH, 0);
+        outb(&dev, ATA_REG_DEVICE, 0xA2);
+        outb(&dev, ATA_REG_STATUS, ATA_CMD_WRITE_SECTORS);
+        assert!(inb(&dev, ATA_REG_STATUS) & ATA_SR_DRQ != 0);
+
+        let irqs = irq_count();
+        for _ in 0..ATA_SECTOR_SIZE / 2 {
+            vm::io_handler::io_write(&dev, ATA_PRIMARY_BASE, vm::IoOperandType::word(0xBEEF));
+        }
+        assert!(irq_count() == irqs + 1);
+        assert!(inb(&dev, ATA_REG_STATUS) == ATA_SR_DRDY | ATA_SR_DSC);
+
+        outb(&dev, ATA_REG_DEVICE, 0xE0);
+        outb(&dev, ATA_REG_LBA_LOW, lba as u8);
+        outb(&dev, ATA_REG_LBA_MID, (lba >> 8) as u8);
+        outb(&dev, ATA_REG_STATUS, ATA_CMD_READ_SECTORS);
+        let data = read_block(&dev);
+        assert!(data[0] == 0xEF && data[1] == 0xBE && data[511] == 0xBE);
+    }
+
+    fn write_block(dev: &ATADev, val: u16) {
+        for _ in 0..ATA_SECTOR_SIZE / 2 {
+            vm::io_handler::io_write(dev, dev.base + ATA_REG_DATA, vm::IoOperandType::word(val));
+        }
+    }
+
+    fn start_lba_command(dev: &ATADev, cmd: u8, lba: u32, count: u8) {
+        outb(dev, ATA_REG_COUNT, count);
+        outb(dev, ATA_REG_LBA_LOW, lba as u8);
+        outb(dev, ATA_REG_LBA_MID, (lba >> 8) as u8);
+        outb(dev, ATA_REG_LBA_HIGH, (lba >> 16) as u8);
+        outb(dev, ATA_REG_DEVICE, 0xE0 | (lba >> 24) as u8);
+        outb(dev, ATA_REG_STATUS, cmd);
+    }
+
+    fn file_contents(name: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        let path = image_path(name);
+        ::std::io::Read::read_to_end(&mut ::std::fs::File::open(&path).unwrap(), &mut data).unwrap();
+        data
+    }
+
+    /*
+     * Multi-sector write lands at the right offset, reads back, and reaches the file once flushed
+     */
+    #[test] fn write_readback_and_flush() {
+        let dev = dev_on(Box::new(make_image("xvm_ata_flush.img", 64)), None);
+
+        let irqs = irq_count();
+        start_lba_command(&dev, ATA_CMD_WRITE_SECTORS, 20, 3);
+        for sector in 0..3 {
+            assert!(inb(&dev, ATA_REG_STATUS) == ATA_SR_DRDY | ATA_SR_DSC | ATA_SR_DRQ);
+            write_block(&dev, 0x1100 + sector);
+            assert!(irq_count() == irqs + sector as u32 + 1);
+        }
+        assert!(inb(&dev, ATA_REG_STATUS) == ATA_SR_DRDY | ATA_SR_DSC);
+        assert!(inb(&dev, ATA_REG_LBA_LOW) == 22);
+
+        start_lba_command(&dev, ATA_CMD_READ_SECTORS, 19, 5);
+        assert!(read_block(&dev)[0] == sector_byte(19, 0));
+        for sector in 0..3 {
+            let expected: Vec<u8> = (0..ATA_SECTOR_SIZE).map(|i| if i % 2 == 0 { sector as u8 } else { 0x11 }).collect();
+            assert!(read_block(&dev) == expected);
+        }
+        assert!(read_block(&dev)[0] == sector_byte(23, 0));
+
+        let irqs = irq_count();
+        outb(&dev, ATA_REG_STATUS, ATA_CMD_FLUSH_CACHE);
+        assert!(irq_count() == irqs + 1);
+        assert!(inb(&dev, ATA_REG_STATUS) == ATA_SR_DRDY | ATA_SR_DSC);
+
+        /* Reopened file has the data */
+        let data = file_contents("xvm_ata_flush.img");
+        assert!(data.len() == 64 * ATA_SECTOR_SIZE);
+        assert!(data[20 * ATA_SECTOR_SIZE] == 0x00 && data[20 * ATA_SECTOR_SIZE + 1] == 0x11);
+        assert!(data[23 * ATA_SECTOR_SIZE - 2] == 0x02);
+        assert!(data[23 * ATA_SECTOR_SIZE] == sector_byte(23, 0));
+        ::std::fs::remove_file(image_path("xvm_ata_flush.img")).unwrap();
+    }
+
+    #[test] fn read_only_disk() {
+        make_image("xvm_ata_ro.img", 64);
+        let path = image_path("xvm_ata_ro.img");
+        let image = disk::FileImage::open_read_only(path.to_str().unwrap()).unwrap();
+        let dev = dev_on(Box::new(image), None);
+
+        /* Write is aborted without asking for data */
+        let irqs = irq_count();
+        start_lba_command(&dev, ATA_CMD_WRITE_SECTORS, 5, 1);
+        assert!(irq_count() == irqs + 1);
+        assert!(inb(&dev, ATA_REG_STATUS) == ATA_SR_DRDY | ATA_SR_DSC | ATA_SR_ERR);
+        assert!(inb(&dev, ATA_REG_ERROR) == ATA_ER_ABRT);
+        write_block(&dev, 0xFFFF);
+
+        /* Reads and flush still work, file is untouched */
+        start_lba_command(&dev, ATA_CMD_READ_SECTORS, 5, 1);
+        assert!(read_block(&dev)[0] == sector_byte(5, 0));
+        outb(&dev, ATA_REG_STATUS, ATA_CMD_FLUSH_CACHE);
+        assert!(inb(&dev, ATA_REG_STATUS) == ATA_SR_DRDY | ATA_SR_DSC);
+
+        let data = file_contents("xvm_ata_ro.img");
+        assert!(data[5 * ATA_SECTOR_SIZE..6 * ATA_SECTOR_SIZE].iter().enumerate().all(|(i, b)| *b == sector_byte(5, i)));
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    /*
+     * Reset in the middle of a 3 sector write: first sector is in, half filled second one is dropped
+     */
+    #[test] fn interrupted_write() {
+        let dev = make_dev("xvm_ata_interrupted.img", 64);
+
+        start_lba_command(&dev, ATA_CMD_WRITE_SECTORS, 8, 3);
+        write_block(&dev, 0x7777);
+        for _ in 0..ATA_SECTOR_SIZE / 4 {
+            vm::io_handler::io_write(&dev, ATA_PRIMARY_BASE, vm::IoOperandType::word(0x6666));
+        }
+        assert!(inb(&dev, ATA_REG_STATUS) & ATA_SR_DRQ != 0);
+
+        vm::io_handler::io_write(&dev, ATA_PRIMARY_CTRL, vm::IoOperandType::byte(ATA_CTRL_SRST));
+        vm::io_handler::io_write(&dev, ATA_PRIMARY_CTRL, vm::IoOperandType::byte(0));
+        assert!(inb(&dev, ATA_REG_STATUS) == ATA_SR_DRDY | ATA_SR_DSC);
+
+        /* Data port no longer takes data */
+        write_block(&dev, 0x5555);
+
+        start_lba_command(&dev, ATA_CMD_READ_SECTORS, 8, 3);
+        assert!(read_block(&dev) == vec![0x77; ATA_SECTOR_SIZE]);
+        assert!(read_block(&dev)[0] == sector_byte(9, 0));
+        assert!(read_block(&dev)[0] == sector_byte(10, 0));
+    }
+
+    #[test] fn errors_and_nien() {
+        let dev = make_dev("xvm_ata_errors.img", 64);
+
+        /* Out of range LBA */
+        outb(&dev, ATA_REG_COUNT, 1);
+        outb(&dev, ATA_REG_LBA_LOW, 64);
+        outb(&dev, ATA_REG_DEVICE, 0xE0);
+        outb(&dev, ATA_REG_STATUS, ATA_CMD_READ_SECTORS);
+        assert!(inb(&dev, ATA_REG_STATUS) & ATA_SR_ERR != 0);
+        assert!(inb(&dev, ATA_REG_ERROR) == ATA_ER_IDNF);
+
+        /* Unknown command aborts, no interrupt with nIEN */
+        let irqs = irq_count();
+        vm::io_handler::io_write(&dev, ATA_PRIMARY_CTRL, vm::IoOperandType::byte(ATA_CTRL_NIEN));
+        outb(&dev, ATA_REG_STATUS, 0xFF);
+        assert!(inb(&dev, ATA_REG_ERROR) == ATA_ER_ABRT);
+        assert!(irq_count() == irqs);
+
+        /* Absent slave floats */
+        outb(&dev, ATA_REG_DEVICE, 0xB0);
+        assert!(inb(&dev, ATA_REG_STATUS) == 0);
+    }
+
+    #[test] fn software_reset() {
+        let dev = make_dev("xvm_ata_reset.img", 64);
+
+        outb(&dev, ATA_REG_LBA_LOW, 0x55);
+        vm::io_handler::io_write(&dev, ATA_PRIMARY_CTRL, vm::IoOperandType::byte(ATA_CTRL_SRST));
+        assert!(vm::io_handler::io_read(&dev, ATA_PRIMARY_CTRL, 1).unwrap_byte() & ATA_SR_BSY != 0);
+        vm::io_handler::io_write(&dev, ATA_PRIMARY_CTRL, vm::IoOperandType::byte(0));
+
+        assert!(inb(&dev, ATA_REG_STATUS) == ATA_SR_DRDY | ATA_SR_DSC);
+        assert!(inb(&dev, ATA_REG_COUNT) == 1 && inb(&dev, ATA_REG_LBA_LOW) == 1);
+        assert!(inb(&dev, ATA_REG_LBA_MID) == 0 && inb(&dev, ATA_REG_LBA_HIGH) == 0);
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+
+    fn iso_byte(lba: usize, i: usize) -> u8 {
+        (lba * 5 + i / 7) as u8
+    }
+
+    /* Generated ISO with a distinct pattern in each 2048 byte sector */
+    fn make_iso(sectors: usize) -> disk::MemImage {
+        let mut image = disk::MemImage::new(sectors * ATAPI_SECTOR_SIZE);
+        for (i, b) in image.data.iter_mut().enumerate() {
+            *b = iso_byte(i / ATAPI_SECTOR_SIZE, i % ATAPI_SECTOR_SIZE);
+        }
+        image
+    }
+
+    fn make_cdrom_dev(image: Option<disk::MemImage>) -> ATADev {
+        let cdrom = AtapiCdrom::new(image.map(|img| Box::new(img) as Box<disk_image>));
+        ATADev {
+            channel: RefCell::new(ATAChannel::new(Some(AtaDevice::Cdrom(cdrom)), None)),
+            base: ATA_SECONDARY_BASE,
+            ctrl: ATA_SECONDARY_CTRL,
+            irq: ATA_SECONDARY_IRQ,
+            assert_irq: count_cdrom_irq,
+        }
+    }
+
+    /* Issue PACKET and send command block, limit goes to byte count registers */
+    fn packet(dev: &ATADev, cdb: &[u8], limit: u16) {
+        let mut block = [0u8; ATAPI_PACKET_SIZE];
+        block[..cdb.len()].copy_from_slice(cdb);
+
+        outb(dev, ATA_REG_ERROR, 0);
+        outb(dev, ATA_REG_LBA_MID, limit as u8);
+        outb(dev, ATA_REG_LBA_HIGH, (limit >> 8) as u8);
+        outb(dev, ATA_REG_STATUS, ATA_CMD_PACKET);
+        assert!(inb(dev, ATA_REG_STATUS) & ATA_SR_DRQ != 0);
+        assert!(inb(dev, ATA_REG_COUNT) == ATAPI_IR_COD);
+
+        for i in 0..ATAPI_PACKET_SIZE / 2 {
+            let w = block[i * 2] as u16 | (block[i * 2 + 1] as u16) << 8;
+            vm::io_handler::io_write(dev, dev.base + ATA_REG_DATA, vm::IoOperandType::word(w));
+        }
+    }
+
+    /* Drain data-in DRQ blocks until status phase, returns data and block sizes */
+    fn packet_response(dev: &ATADev) -> (Vec<u8>, Vec<usize>) {
+        let mut data = Vec::new();
+        let mut blocks = Vec::new();
+
+        while inb(dev, ATA_REG_STATUS) & ATA_SR_DRQ != 0 {
+            assert!(inb(dev, ATA_REG_COUNT) == ATAPI_IR_IO);
+            let len = inb(dev, ATA_REG_LBA_MID) as usize | (inb(dev, ATA_REG_LBA_HIGH) as usize) << 8;
+            for _ in 0..len / 2 {
+                let w = inw(dev);
+                data.push(w as u8);
+                data.push((w >> 8) as u8);
+            }
+            blocks.push(len);
+        }
+
+        assert!(inb(dev, ATA_REG_COUNT) == ATAPI_IR_IO | ATAPI_IR_COD);
+        (data, blocks)
+    }
+
+    #[test] fn cdrom_signature() {
+        let dev = make_cdrom_dev(Some(make_iso(16)));
+        assert!(inb(&dev, ATA_REG_COUNT) == 1 && inb(&dev, ATA_REG_LBA_LOW) == 1);
+        assert!(inb(&dev, ATA_REG_LBA_MID) == 0x14 && inb(&dev, ATA_REG_LBA_HIGH) == 0xEB);
+
+        /* ATA IDENTIFY is aborted, signature survives */
+        outb(&dev, ATA_REG_DEVICE, 0xA0);
+        outb(&dev, ATA_REG_STATUS, ATA_CMD_IDENTIFY);
+        assert!(inb(&dev, ATA_REG_STATUS) & ATA_SR_ERR != 0);
+        assert!(inb(&dev, ATA_REG_ERROR) == ATA_ER_ABRT);
+        assert!(inb(&dev, ATA_REG_LBA_MID) == 0x14 && inb(&dev, ATA_REG_LBA_HIGH) == 0xEB);
+
+        outb(&dev, ATA_REG_STATUS, ATA_CMD_IDENTIFY_PACKET);
+        let mut id = [0u16; 256];
+        for i in 0..256 {
+            id[i] = inw(&dev);
+        }
+        assert!(id[0] == 0x85C0);
+        assert!(id_string(&id[27..47]) == ATAPI_MODEL);
+        assert!(inb(&dev, ATA_REG_STATUS) == ATA_SR_DRDY | ATA_SR_DSC);
+
+        /* Software reset restores signature */
+        vm::io_handler::io_write(&dev, ATA_SECONDARY_CTRL, vm::IoOperandType::byte(ATA_CTRL_SRST));
+        vm::io_handler::io_write(&dev, ATA_SECONDARY_CTRL, vm::IoOperandType::byte(0));
+        assert!(inb(&dev, ATA_REG_LBA_MID) == 0x14 && inb(&dev, ATA_REG_LBA_HIGH) == 0xEB);
+    }
+
+    #[test] fn cdrom_inquiry_and_read() {
+        let dev = make_cdrom_dev(Some(make_iso(32)));
+
+        let irqs = irq_count();
+        packet(&dev, &[SCSI_INQUIRY, 0, 0, 0, 36, 0], 0xFFFE);
+        assert!(irq_count() == irqs + 1);
+        let (data, _) = packet_response(&dev);
+        assert!(irq_count() == irqs + 2);
+        assert!(inb(&dev, ATA_REG_STATUS) == ATA_SR_DRDY | ATA_SR_DSC);
+        assert!(data.len() == 36);
+        assert!(data[0] == 0x05 && data[1] == 0x80);
+        assert!(&data[16..26] == b"XVM CD-ROM");
+
+        packet(&dev, &[SCSI_READ_CAPACITY], 0xFFFE);
+        let (data, _) = packet_response(&dev);
+        assert!(data == [0, 0, 0, 31, 0, 0, 0x08, 0]);
+
+        /* READ(10) of sectors 16 and 17, one DRQ block per sector */
+        let irqs = irq_count();
+        packet(&dev, &[SCSI_READ_10, 0, 0, 0, 0, 16, 0, 0, 2, 0], ATAPI_SECTOR_SIZE as u16);
+        let (data, blocks) = packet_response(&dev);
+        assert!(blocks == [2048, 2048]);
+        assert!(irq_count() == irqs + 3);
+        let expected: Vec<u8> = (0..2 * ATAPI_SECTOR_SIZE)
+            .map(|i| iso_byte(16 + i / ATAPI_SECTOR_SIZE, i % ATAPI_SECTOR_SIZE))
+            .collect();
+        assert!(data == expected);
+
+        /* Byte count limit splits sector across blocks */
+        packet(&dev, &[SCSI_READ_10, 0, 0, 0, 0, 16, 0, 0, 1, 0], 1001);
+        let (data, blocks) = packet_response(&dev);
+        assert!(blocks == [1000, 1000, 48]);
+        assert!(&data[..] == &expected[..ATAPI_SECTOR_SIZE]);
+
+        /* Beyond end of medium */
+        packet(&dev, &[SCSI_READ_10, 0, 0, 0, 0, 31, 0, 0, 2, 0], 0xFFFE);
+        assert!(inb(&dev, ATA_REG_STATUS) & ATA_SR_ERR != 0);
+        assert!(inb(&dev, ATA_REG_ERROR) == SENSE_ILLEGAL_REQUEST << 4);
+
+        /* TOC in MSF: track 1 at 00:02:00, lead-out at 32 + 150 frames */
+        packet(&dev, &[SCSI_READ_TOC, 0x02, 0, 0, 0, 0, 0, 0, 100, 0], 0xFFFE);
+        let (data, _) = packet_response(&dev);
+        assert!(data.len() == 20);
+        assert!(data[0..4] == [0, 18, 1, 1]);
+        assert!(data[6] == 1 && data[8..12] == [0, 0, 2, 0]);
+        assert!(data[14] == 0xAA && data[16..20] == [0, 0, 2, 32]);
+    }
+
+    #[test] fn cdrom_no_medium() {
+        let dev = make_cdrom_dev(None);
+
+        packet(&dev, &[SCSI_TEST_UNIT_READY], 0);
+        assert!(inb(&dev, ATA_REG_STATUS) == ATA_SR_DRDY | ATA_SR_DSC | ATA_SR_ERR);
+        assert!(inb(&dev, ATA_REG_ERROR) == SENSE_NOT_READY << 4);
+        assert!(inb(&dev, ATA_REG_COUNT) == ATAPI_IR_IO | ATAPI_IR_COD);
+
+        packet(&dev, &[SCSI_REQUEST_SENSE, 0, 0, 0, 18, 0], 0);
+        let (data, _) = packet_response(&dev);
+        assert!(data.len() == 18);
+        assert!(data[0] == 0x70 && data[2] == SENSE_NOT_READY && data[7] == 10);
+        assert!(data[12] == ASC_MEDIUM_NOT_PRESENT && data[13] == 0);
+        assert!(inb(&dev, ATA_REG_STATUS) == ATA_SR_DRDY | ATA_SR_DSC);
+
+        /* Sense is cleared once reported */
+        packet(&dev, &[SCSI_REQUEST_SENSE, 0, 0, 0, 18, 0], 0);
+        assert!(packet_response(&dev).0[2] == SENSE_NONE);
+
+        packet(&dev, &[SCSI_READ_10, 0, 0, 0, 0, 0, 0, 0, 1, 0], 0);
+        assert!(inb(&dev, ATA_REG_ERROR) == SENSE_NOT_READY << 4);
+
+        packet(&dev, &[0xFF], 0);
+        assert!(inb(&dev, ATA_REG_ERROR) == SENSE_ILLEGAL_REQUEST << 4);
+        packet(&dev, &[SCSI_REQUEST_SENSE, 0, 0, 0, 18, 0], 0);
+        let (data, _) = packet_response(&dev);
+        assert!(data[2] == SENSE_ILLEGAL_REQUEST && data[12] == ASC_INVALID_OPCODE);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+fn open_image(path: &str) -> Box<disk_image>
+{
+    match disk::FileImage::open(path) {
+        Ok(image) => Box::new(image),
+        Err(err) => panic!("ata: failed to open {}: {}", path, err),
+    }
+}
+
+fn open_hda(config: &config::VmConfig, path: &str) -> Box<disk_image>
+{
+    match disk::open_hda(config, path) {
+        Ok(image) => image,
+        Err(err) => panic!("ata: failed to open {}: {}", path, err),
+    }
+}
+
+fn register_channel(name: &'static str, channel: ATAChannel, base: u16, ctrl: u16, irq: u8, image: Option<String>)
+{
+    let dev = Rc::new(ATADev {
+        channel: RefCell::new(channel),
+        base: base,
+        ctrl: ctrl,
+        irq: irq,
+        assert_irq: vm::assert_irq,
+    });
+
+    for reg in ATA_REG_DATA..(ATA_REG_STATUS + 1) {
+        vm::register_io_region(dev.clone(), base + reg, 1);
+    }
+    vm::register_io_region(dev.clone(), ctrl, 1);
+
+    vm::register_reset_handler(dev.clone());
+    vm::register_stats_handler(dev.clone());
+    vm::register_device_state(name, Rc::new(ATAChannelState { dev: dev.clone(), image: image }));
+}
+
+pub fn init(config: &config::VmConfig)
+{
+    let geometry = config.hda_chs.map(|(c, h, s)| Geometry { cylinders: c, heads: h, sectors: s });
+    let master = config.hda.as_ref().map(|path| AtaDevice::Disk(AtaDisk::new(open_hda(config, path), geometry)));
+    register_channel("ata0", ATAChannel::new(master, None), ATA_PRIMARY_BASE, ATA_PRIMARY_CTRL, ATA_PRIMARY_IRQ,
+                     config.hda.clone());
+
+    /* CD-ROM drive is always there, medium is optional */
+    let cdrom = AtapiCdrom::new(config.cdrom.as_ref().map(|path| open_image(path)));
+    register_channel("ata1", ATAChannel::new(Some(AtaDevice::Cdrom(cdrom)), None),
+                     ATA_SECONDARY_BASE, ATA_SECONDARY_CTRL, ATA_SECONDARY_IRQ, config.cdrom.clone());
+}
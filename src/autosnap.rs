@@ -0,0 +1,543 @@
+/*
+ * Automatic snapshots
+ *
+ * --auto-snapshot saves a snapshot every so many seconds of guest time, or of host time, to a directory and keeps
+ * the last few. Files are numbered in the order they are saved, auto-000001.snap for a whole snapshot and
+ * auto-000002.inc.snap for an increment over the file before it, see vm::save_snapshot_incremental(). Numbers go
+ * on after the highest one in the directory, so files an earlier run left there are neither overwritten nor
+ * deleted.
+ *
+ * Newest snapshot is restored from its chain, the last whole snapshot and increments after it, and a chain grows
+ * to as many files as are kept: a snapshot that would make it longer is saved whole and starts the next one.
+ * Files past the ones kept are deleted, along with increments that lost their parent that way, so the chain of
+ * the newest snapshot is always there and a new chain leaves only files of its own. An increment that can't be
+ * saved, e.g. after "savevm" saved another snapshot over the dirty page log, is saved whole instead.
+ *
+ * A timer event checks the schedule and kicks vcpu out, the snapshot is saved at its next exit through the code
+ * monitor "snapshot now" uses too. Guest is stopped for as long as saving takes, that pause is logged and goes
+ * to the event log with the snapshot. A snapshot that fails, e.g. on a full disk, is logged and goes to the event
+ * log too, and is tried again after twice as long as the last wait, up to MAX_BACKOFF intervals. VM goes on
+ * either way.
+ *
+ * Timer events run on guest time, with host seconds they only check the schedule: a VM that is stopped takes
+ * no snapshots.
+ */
+
+use vm;
+use config;
+use clock;
+use event;
+use eventlog;
+use monitor;
+
+use std::cmp;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+const NS_PER_SEC: u64 = 1000000000;
+
+/* Guest time between checks of the schedule */
+const CHECK_US: u64 = 100000;
+
+/* Longest wait after failed snapshots, in intervals */
+const MAX_BACKOFF: u64 = 16;
+
+/**
+ * Saves snapshots, vm::save_snapshot() and vm::save_snapshot_incremental() for the VM
+ */
+pub trait snapshot_saver
+{
+    fn save(&mut self, path: &Path) -> io::Result<()>;
+
+    fn save_incremental(&mut self, path: &Path, base: &Path) -> io::Result<()>;
+}
+
+/**
+ * Snapshots of the VM
+ */
+pub struct VmSaver;
+
+impl snapshot_saver for VmSaver
+{
+    fn save(&mut self, path: &Path) -> io::Result<()> {
+        vm::save_snapshot(path)
+    }
+
+    fn save_incremental(&mut self, path: &Path, base: &Path) -> io::Result<()> {
+        vm::save_snapshot_incremental(path, base)
+    }
+}
+
+/* Snapshot file kept */
+struct SavedFile
+{
+    path: PathBuf,
+    incremental: bool,
+}
+
+/**
+ * Snapshot saved on schedule or asked for
+ */
+#[derive(Debug)]
+pub struct Saved
+{
+    pub path: PathBuf,
+    pub incremental: bool,
+    pub pause_us: u64,          // Host time saving took
+    pub chain: Vec<PathBuf>,    // Files snapshot is restored from, whole one first
+    pub deleted: Vec<PathBuf>,  // Files no longer kept
+}
+
+/**
+ * Snapshot that failed
+ */
+#[derive(Debug)]
+pub struct Failed
+{
+    pub path: PathBuf,
+    pub error: String,
+    pub retry_ns: u64,          // Time until the next try
+}
+
+/**
+ * Snapshot schedule and the files kept, times are ns of whichever clock drives it
+ */
+pub struct AutoSnapshot
+{
+    dir: PathBuf,
+    every_ns: u64,
+    keep: usize,
+    next_ns: u64,               // Time next snapshot is due
+    seq: u64,                   // Number of the next file
+    files: Vec<SavedFile>,      // Files kept, oldest first
+    failures: u32,              // Snapshots failed in a row
+}
+
+impl AutoSnapshot
+{
+    /**
+     * Schedule saving to dir every_ns from now on, numbering files after the ones in dir
+     */
+    pub fn new(dir: &Path, every_ns: u64, keep: usize, now: u64) -> AutoSnapshot {
+        assert!(every_ns > 0 && keep > 0);
+        AutoSnapshot {
+            dir: dir.to_path_buf(),
+            every_ns: every_ns,
+            keep: keep,
+            next_ns: now + every_ns,
+            seq: last_seq(dir) + 1,
+            files: Vec::new(),
+            failures: 0,
+        }
+    }
+
+    /**
+     * Next snapshot is due
+     */
+    pub fn due(&self, now: u64) -> bool {
+        now >= self.next_ns
+    }
+
+    /**
+     * Files newest snapshot is restored from, whole one first, none before the first snapshot
+     */
+    pub fn chain(&self) -> Vec<PathBuf> {
+        let start = self.files.iter().rposition(|file| !file.incremental).unwrap_or(self.files.len());
+        self.files[start..].iter().map(|file| file.path.clone()).collect()
+    }
+
+    fn path(&self, incremental: bool) -> PathBuf {
+        self.dir.join(format!("auto-{:06}{}.snap", self.seq, if incremental { ".inc" } else { "" }))
+    }
+
+    /**
+     * Save the next snapshot now, as an increment if the chain has room for it, and schedule the one after it
+     */
+    pub fn save(&mut self, now: u64, saver: &mut snapshot_saver) -> Result<Saved, Failed> {
+        let start = Instant::now();
+        let (full, inc) = (self.path(false), self.path(true));
+        let base = match self.files.last() {
+            Some(last) if self.chain().len() < self.keep => Some(last.path.clone()),
+            _ => None,
+        };
+
+        let res = fs::create_dir_all(&self.dir).and_then(|()| {
+            if let Some(ref base) = base {
+                match saver.save_incremental(&inc, base) {
+                    Ok(()) => return Ok(true),
+                    Err(err) => debug!("autosnap: can't save increment over {}, saving whole snapshot: {}", base.display(), err),
+                }
+            }
+            saver.save(&full).map(|()| false)
+        });
+
+        let incremental = match res {
+            Ok(incremental) => incremental,
+            Err(err) => {
+                self.failures += 1;
+                let retry_ns = self.every_ns * cmp::min(1u64 << cmp::min(self.failures, 32), MAX_BACKOFF);
+                self.next_ns = now + retry_ns;
+                return Err(Failed { path: full, error: err.to_string(), retry_ns: retry_ns });
+            },
+        };
+
+        let path = if incremental { inc } else { full };
+        self.files.push(SavedFile { path: path.clone(), incremental: incremental });
+        self.seq += 1;
+        self.failures = 0;
+        self.next_ns = now + self.every_ns;
+
+        let deleted = self.rotate();
+        Ok(Saved { path: path, incremental: incremental, pause_us: micros(start.elapsed()), chain: self.chain(), deleted: deleted })
+    }
+
+    /* Delete files past the ones kept and increments left without their parent */
+    fn rotate(&mut self) -> Vec<PathBuf> {
+        let mut drop = self.files.len().saturating_sub(self.keep);
+        while drop < self.files.len() && self.files[drop].incremental {
+            drop += 1;
+        }
+
+        self.files.drain(..drop).map(|file| {
+            if let Err(err) = fs::remove_file(&file.path) {
+                warn!("autosnap: can't delete {}: {}", file.path.display(), err);
+            }
+            file.path
+        }).collect()
+    }
+}
+
+/* Number of an automatic snapshot file by its name */
+fn seq_of(name: &str) -> Option<u64>
+{
+    if !name.starts_with("auto-") || !name.ends_with(".snap") {
+        return None;
+    }
+    name["auto-".len()..].split('.').next().and_then(|seq| seq.parse::<u64>().ok())
+}
+
+/* Highest number of automatic snapshot files in dir, 0 if there are none */
+fn last_seq(dir: &Path) -> u64
+{
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries.filter_map(|entry| entry.ok())
+           .filter_map(|entry| entry.file_name().to_str().and_then(seq_of))
+           .max()
+           .unwrap_or(0)
+}
+
+fn micros(duration: Duration) -> u64
+{
+    duration.as_secs() * 1000000 + duration.subsec_nanos() as u64 / 1000
+}
+
+#[cfg(test)]
+mod autosnap_test
+{
+    use super::*;
+    use clock::{virtual_clock, MockClock};
+    use snapshot::{self, Snapshot, SnapshotMemory};
+    use std::env;
+    use std::fs::File;
+    use std::io::Read;
+
+    const PAGE: usize = vm::DIRTY_PAGE_SIZE;
+
+    /* Saves guest memory of a page per guest second, page n holding n, like vm saves it */
+    struct FakeSaver
+    {
+        clock: ::std::rc::Rc<MockClock>,
+        last: Option<(PathBuf, u64)>,   // Snapshot last saved and its content hash
+        written: Vec<usize>,            // Pages written since
+        full_disk: bool,
+    }
+
+    impl FakeSaver
+    {
+        fn memory(&self) -> Vec<u8> {
+            let mut data = vec![0u8; 16 * PAGE];
+            for page in 0..cmp::min(self.clock.now_ns() / NS_PER_SEC, 16) as usize {
+                data[page * PAGE] = page as u8 + 1;
+            }
+            data
+        }
+
+        fn write(&mut self, path: &Path, pages: Option<Vec<usize>>, parent: Option<u64>) -> io::Result<()> {
+            if self.full_disk {
+                return Err(io::Error::new(io::ErrorKind::Other, "No space left on device"));
+            }
+            let snapshot = Snapshot {
+                created: 1500000000,
+                memory: vec![SnapshotMemory { base: 0, flags: 7, data: self.memory(), pages: pages }],
+                vcpu_state: vm::VcpuState { rip: 0x7C00, ..Default::default() },
+                clock_ns: self.clock.now_ns(),
+                dilation: 1.0,
+                devices: Vec::new(),
+                parent: parent,
+            };
+            let hash = try!(snapshot::save(&snapshot, path));
+            self.last = Some((path.to_path_buf(), hash));
+            self.written.clear();
+            Ok(())
+        }
+
+        /* Guest runs for a second writing the next page */
+        fn run_second(&mut self) {
+            self.clock.advance_ns(NS_PER_SEC);
+            let page = (self.clock.now_ns() / NS_PER_SEC - 1) as usize;
+            if page < 16 {
+                self.written.push(page);
+            }
+        }
+    }
+
+    impl snapshot_saver for FakeSaver
+    {
+        fn save(&mut self, path: &Path) -> io::Result<()> {
+            self.write(path, None, None)
+        }
+
+        fn save_incremental(&mut self, path: &Path, base: &Path) -> io::Result<()> {
+            let hash = match self.last {
+                Some((ref last, hash)) if last == base => hash,
+                _ => return Err(io::Error::new(io::ErrorKind::Other, "Base isn't the snapshot last saved")),
+            };
+            let pages = self.written.clone();
+            self.write(path, Some(pages), Some(hash))
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("xvm-autosnap-{}-{}", name, ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+        names.sort();
+        names
+    }
+
+    /* Run guest for seconds, saving snapshots as they come due */
+    fn run(auto: &mut AutoSnapshot, saver: &mut FakeSaver, seconds: u64) -> Vec<Result<Saved, Failed>> {
+        let mut results = Vec::new();
+        for _ in 0..seconds {
+            saver.run_second();
+            let now = saver.clock.now_ns();
+            if auto.due(now) {
+                results.push(auto.save(now, saver));
+            }
+        }
+        results
+    }
+
+    fn new_saver() -> FakeSaver {
+        FakeSaver { clock: ::std::rc::Rc::new(MockClock::new()), last: None, written: Vec::new(), full_disk: false }
+    }
+
+    #[test] fn rotation() {
+        let dir = temp_dir("rotation");
+        let mut saver = new_saver();
+        let mut auto = AutoSnapshot::new(&dir, 2 * NS_PER_SEC, 3, saver.clock.now_ns());
+
+        /* Whole snapshot and increments over it while the chain has room */
+        let saved: Vec<Saved> = run(&mut auto, &mut saver, 6).into_iter().map(Result::unwrap).collect();
+        assert!(saved.iter().map(|saved| saved.incremental).collect::<Vec<bool>>() == vec![false, true, true]);
+        assert!(saved.iter().all(|saved| saved.path.parent() == Some(&dir) && saved.deleted.is_empty()));
+        assert!(names(&dir) == vec!["auto-000001.snap", "auto-000002.inc.snap", "auto-000003.inc.snap"]);
+
+        /* Next chain, the oldest file goes and increments over it with it */
+        let saved: Vec<Saved> = run(&mut auto, &mut saver, 6).into_iter().map(Result::unwrap).collect();
+        assert!(saved.iter().map(|saved| saved.incremental).collect::<Vec<bool>>() == vec![false, true, true]);
+        assert!(saved[0].deleted == vec![dir.join("auto-000001.snap"), dir.join("auto-000002.inc.snap"), dir.join("auto-000003.inc.snap")]);
+        assert!(names(&dir) == vec!["auto-000004.snap", "auto-000005.inc.snap", "auto-000006.inc.snap"]);
+
+        /* Newest restores to guest memory as it was saved */
+        let chain = saved[2].chain.clone();
+        assert!(chain == vec![dir.join("auto-000004.snap"), dir.join("auto-000005.inc.snap"), dir.join("auto-000006.inc.snap")]);
+        assert!(auto.chain() == chain);
+        let paths: Vec<&Path> = chain.iter().map(PathBuf::as_path).collect();
+        let (restored, hash) = snapshot::load_chain(&paths).unwrap();
+        assert!(restored.clock_ns == 12 * NS_PER_SEC);
+        assert!(restored.memory[0].data == saver.memory());
+
+        let mut data = Vec::new();
+        File::open(&chain[2]).unwrap().read_to_end(&mut data).unwrap();
+        assert!(hash == snapshot::content_hash(&data));
+
+        /* Numbering goes on after files already there */
+        let next = AutoSnapshot::new(&dir, NS_PER_SEC, 3, 0);
+        assert!(next.path(false) == dir.join("auto-000007.snap") && next.chain().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test] fn keep_one() {
+        let dir = temp_dir("keep-one");
+        let mut saver = new_saver();
+        let mut auto = AutoSnapshot::new(&dir, NS_PER_SEC, 1, 0);
+
+        let saved: Vec<Saved> = run(&mut auto, &mut saver, 3).into_iter().map(Result::unwrap).collect();
+        assert!(saved.iter().all(|saved| !saved.incremental));
+        assert!(names(&dir) == vec!["auto-000003.snap"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test] fn failures() {
+        let dir = temp_dir("failures");
+        let mut saver = new_saver();
+        let mut auto = AutoSnapshot::new(&dir, NS_PER_SEC, 4, 0);
+        run(&mut auto, &mut saver, 1).pop().unwrap().unwrap();
+
+        /* Tries come twice as far apart each time, up to MAX_BACKOFF intervals */
+        saver.full_disk = true;
+        let failed: Vec<Failed> = run(&mut auto, &mut saver, 64).into_iter().map(|res| res.unwrap_err()).collect();
+        let waits: Vec<u64> = failed.iter().map(|failed| failed.retry_ns / NS_PER_SEC).collect();
+        assert!(waits == vec![2, 4, 8, 16, 16, 16, 16]);
+        assert!(failed[0].path == dir.join("auto-000002.snap") && failed[0].error == "No space left on device");
+
+        /* Next snapshot is on time again, and over the base still there */
+        saver.full_disk = false;
+        let saved = run(&mut auto, &mut saver, 16).into_iter().map(Result::unwrap).collect::<Vec<Saved>>();
+        assert!(saved.len() == 2 && saved[0].incremental && saved[0].path == dir.join("auto-000002.inc.snap"));
+        assert!(names(&dir) == vec!["auto-000001.snap", "auto-000002.inc.snap", "auto-000003.inc.snap"]);
+
+        /* Base that isn't the snapshot last saved gets a whole one instead of an increment */
+        saver.last = None;
+        let saved = run(&mut auto, &mut saver, 1).pop().unwrap().unwrap();
+        assert!(!saved.incremental && names(&dir) == vec!["auto-000001.snap", "auto-000002.inc.snap", "auto-000003.inc.snap", "auto-000004.snap"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/* Schedule and the clock it runs on */
+struct AutoState
+{
+    schedule: AutoSnapshot,
+    host_start: Option<Instant>,    // Host time counts from here, guest time drives schedule if none
+}
+
+impl AutoState
+{
+    fn now_ns(&self) -> u64 {
+        match self.host_start {
+            Some(start) => {
+                let elapsed = start.elapsed();
+                elapsed.as_secs() * NS_PER_SEC + elapsed.subsec_nanos() as u64
+            },
+            None => clock::guest_time_ns(),
+        }
+    }
+}
+
+lazy_static! {
+    static ref DUE: AtomicBool = AtomicBool::new(false);
+    static ref AUTO: Mutex<Option<AutoState>> = Mutex::new(None);
+}
+
+/* Save snapshot and tell how it went, vcpu thread only */
+fn save(auto: &mut AutoState) -> Result<String, String>
+{
+    let now = auto.now_ns();
+    match auto.schedule.save(now, &mut VmSaver) {
+        Ok(saved) => {
+            for path in &saved.deleted {
+                debug!("autosnap: deleted {}", path.display());
+            }
+            eventlog::emit(|| eventlog::Event::Snapshot {
+                path: saved.path.display().to_string(),
+                incremental: saved.incremental,
+                pause_us: saved.pause_us,
+            });
+            let chain: Vec<String> = saved.chain.iter().map(|path| path.display().to_string()).collect();
+            Ok(format!("Snapshot saved to {} in {} us, restore with --restore {}", saved.path.display(), saved.pause_us, chain.join(",")))
+        },
+        Err(failed) => {
+            let retry_s = failed.retry_ns / NS_PER_SEC;
+            eventlog::emit(|| eventlog::Event::SnapshotFailed {
+                path: failed.path.display().to_string(),
+                error: failed.error.clone(),
+                retry_s: retry_s,
+            });
+            Err(format!("Can't save snapshot to {}: {}, trying again in {} s", failed.path.display(), failed.error, retry_s))
+        },
+    }
+}
+
+/**
+ * Save snapshot if one came due, vcpu thread only, at every exit
+ */
+pub fn poll()
+{
+    if !DUE.swap(false, Ordering::SeqCst) {
+        return;
+    }
+
+    if let Some(ref mut auto) = *AUTO.lock().unwrap() {
+        if auto.schedule.due(auto.now_ns()) {
+            match save(auto) {
+                Ok(text) => info!("{}", text),
+                Err(text) => error!("{}", text),
+            }
+        }
+    }
+}
+
+/* Kick vcpu out to save snapshot that is due */
+fn check_event(ev: event::Event)
+{
+    let due = match *AUTO.lock().unwrap() {
+        Some(ref auto) => auto.schedule.due(auto.now_ns()),
+        None => false,
+    };
+
+    if due && !DUE.swap(true, Ordering::SeqCst) {
+        vm::interrupt_guest();
+    }
+    event::schedule_event(CHECK_US, ev);
+}
+
+fn cmd_snapshot_now(_: &mut monitor::MonitorContext, _: &[&str]) -> Result<String, String>
+{
+    match *AUTO.lock().unwrap() {
+        Some(ref mut auto) => save(auto),
+        None => Err(String::from("Automatic snapshots are off, see --auto-snapshot")),
+    }
+}
+
+/**
+ * Start saving snapshots on schedule, time counts from here
+ */
+pub fn init(config: &config::VmConfig)
+{
+    monitor::register_command(monitor::MonitorCommand {
+        name: "snapshot now",
+        args: "",
+        help: "save the next automatic snapshot now, older ones go as they would on schedule",
+        handler: cmd_snapshot_now,
+    });
+
+    let auto = match config.auto_snapshot {
+        Some(ref auto) => auto,
+        None => return,
+    };
+
+    let host_start = if auto.host { Some(Instant::now()) } else { None };
+    let now = if auto.host { 0 } else { clock::guest_time_ns() };
+
+    *AUTO.lock().unwrap() = Some(AutoState {
+        schedule: AutoSnapshot::new(Path::new(&auto.dir), auto.seconds as u64 * NS_PER_SEC, auto.keep, now),
+        host_start: host_start,
+    });
+    event::schedule_event(CHECK_US, event::create_event(check_event));
+}
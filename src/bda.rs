@@ -0,0 +1,248 @@
+/*
+ * BIOS data area at 0x400 for guests started without firmware
+ *
+ * Filled from VM configuration the way BIOS POST would leave it. Timer tick count at 0x46C is
+ * advanced by us on every IRQ0 since there is no BIOS INT 8 handler. With real firmware loaded
+ * BDA belongs to firmware and none of this is done.
+ */
+
+use vm;
+use config;
+use pit;
+use uart;
+use lpt;
+use time;
+
+use std::sync::Arc;
+
+const BDA_BASE: usize           = 0x400;
+const BDA_SIZE: usize           = 0x100;
+
+// Field offsets from BDA base
+const BDA_COM_PORTS: usize      = 0x00;     // 4 words
+const BDA_LPT_PORTS: usize      = 0x08;     // 3 words
+const BDA_EBDA_SEGMENT: usize   = 0x0E;
+const BDA_EQUIPMENT: usize      = 0x10;
+const BDA_MEMORY_SIZE: usize    = 0x13;     // Base memory in KB, up to EBDA
+const BDA_KBD_HEAD: usize       = 0x1A;
+const BDA_KBD_TAIL: usize       = 0x1C;
+const BDA_KBD_BUFFER: u16       = 0x1E;     // Offsets within segment 40h
+const BDA_KBD_BUFFER_END: u16   = 0x3E;
+const BDA_TIMER_TICKS: usize    = 0x6C;
+const BDA_TIMER_MIDNIGHT: usize = 0x70;
+const BDA_KBD_START: usize      = 0x80;
+const BDA_KBD_END: usize        = 0x82;
+
+// Equipment word bits
+const EQUIP_FLOPPY: u16         = 0x0001;
+const EQUIP_FPU: u16            = 0x0002;
+const EQUIP_MOUSE: u16          = 0x0004;
+const EQUIP_VIDEO_80X25: u16    = 0x0020;
+const EQUIP_FLOPPIES_SHIFT: u16 = 6;
+const EQUIP_SERIAL_SHIFT: u16   = 9;
+const EQUIP_PARALLEL_SHIFT: u16 = 14;
+
+// Top of conventional memory is reserved for extended BDA
+const CONVENTIONAL_LIMIT: usize = 0xA0000;
+const EBDA_SIZE: usize          = 0x400;
+
+// Timer ticks at 18.2 Hz roll over after a day
+const TICKS_PER_DAY: u32        = 0x1800B0;
+
+/**
+ * Devices and memory layout described by BDA
+ */
+pub struct BdaInfo
+{
+    pub com_ports: Vec<u16>,
+    pub lpt_ports: Vec<u16>,
+    pub floppies: u8,
+    pub mouse: bool,
+    pub conventional_kb: u16,   // Including EBDA
+    pub ticks: u32,             // Timer ticks since midnight
+}
+
+/**
+ * Segment of extended BDA at the top of conventional memory
+ */
+pub fn ebda_segment(conventional_kb: u16) -> u16
+{
+    ((((conventional_kb as usize) << 10) - EBDA_SIZE) >> 4) as u16
+}
+
+fn put_word(bda: &mut [u8], offset: usize, val: u16)
+{
+    bda[offset] = val as u8;
+    bda[offset + 1] = (val >> 8) as u8;
+}
+
+/**
+ * Build BDA contents
+ */
+pub fn build(info: &BdaInfo) -> Vec<u8>
+{
+    let mut bda = vec![0u8; BDA_SIZE];
+
+    for (i, port) in info.com_ports.iter().take(4).enumerate() {
+        put_word(&mut bda, BDA_COM_PORTS + i * 2, *port);
+    }
+    for (i, port) in info.lpt_ports.iter().take(3).enumerate() {
+        put_word(&mut bda, BDA_LPT_PORTS + i * 2, *port);
+    }
+
+    let mut equipment = EQUIP_FPU | EQUIP_VIDEO_80X25;
+    if info.floppies > 0 {
+        equipment |= EQUIP_FLOPPY | ((info.floppies as u16 - 1) & 0x3) << EQUIP_FLOPPIES_SHIFT;
+    }
+    if info.mouse {
+        equipment |= EQUIP_MOUSE;
+    }
+    equipment |= (::std::cmp::min(info.com_ports.len(), 4) as u16) << EQUIP_SERIAL_SHIFT;
+    equipment |= (::std::cmp::min(info.lpt_ports.len(), 3) as u16) << EQUIP_PARALLEL_SHIFT;
+    put_word(&mut bda, BDA_EQUIPMENT, equipment);
+
+    put_word(&mut bda, BDA_EBDA_SEGMENT, ebda_segment(info.conventional_kb));
+    put_word(&mut bda, BDA_MEMORY_SIZE, info.conventional_kb - (EBDA_SIZE >> 10) as u16);
+
+    /* Empty keyboard ring buffer */
+    put_word(&mut bda, BDA_KBD_HEAD, BDA_KBD_BUFFER);
+    put_word(&mut bda, BDA_KBD_TAIL, BDA_KBD_BUFFER);
+    put_word(&mut bda, BDA_KBD_START, BDA_KBD_BUFFER);
+    put_word(&mut bda, BDA_KBD_END, BDA_KBD_BUFFER_END);
+
+    put_word(&mut bda, BDA_TIMER_TICKS, info.ticks as u16);
+    put_word(&mut bda, BDA_TIMER_TICKS + 2, (info.ticks >> 16) as u16);
+
+    bda
+}
+
+/* Advance tick count in RAM mapped at guest physical 0 */
+fn advance_ticks(ram: &vm::memory_region)
+{
+    let mut buf = [0u8; 5];
+    ram.read_bytes(BDA_BASE + BDA_TIMER_TICKS, &mut buf);
+
+    let mut ticks = (buf[0] as u32) | (buf[1] as u32) << 8 | (buf[2] as u32) << 16 | (buf[3] as u32) << 24;
+    ticks += 1;
+    if ticks >= TICKS_PER_DAY {
+        ticks = 0;
+        buf[BDA_TIMER_MIDNIGHT - BDA_TIMER_TICKS] = 1;
+    }
+
+    buf[0] = ticks as u8;
+    buf[1] = (ticks >> 8) as u8;
+    buf[2] = (ticks >> 16) as u8;
+    buf[3] = (ticks >> 24) as u8;
+    ram.write_bytes(BDA_BASE + BDA_TIMER_TICKS, &buf);
+}
+
+#[cfg(test)]
+mod bda_test
+{
+    use super::*;
+
+    fn read_word(ram: &vm::memory_region, addr: usize) -> u16 {
+        let mut buf = [0u8; 2];
+        ram.read_bytes(addr, &mut buf);
+        buf[0] as u16 | (buf[1] as u16) << 8
+    }
+
+    #[test] fn com1_640k() {
+        let ram = vm::alloc_memory_region(0x100000);
+        ram.write_bytes(0, &vec![0xCCu8; 0x100000]);
+
+        let info = BdaInfo {
+            com_ports: vec![0x3F8],
+            lpt_ports: vec![0x378],
+            floppies: 0,
+            mouse: true,
+            conventional_kb: 640,
+            ticks: 0x1800AF,
+        };
+        ram.write_bytes(BDA_BASE, &build(&info));
+
+        assert!(read_word(&ram, 0x400) == 0x3F8);
+        assert!(read_word(&ram, 0x402) == 0);
+        assert!(read_word(&ram, 0x408) == 0x378);
+        assert!(read_word(&ram, 0x40A) == 0);
+        assert!(read_word(&ram, 0x40E) == 0x9FC0);
+        assert!(read_word(&ram, 0x410) == 0x4226);
+        assert!(read_word(&ram, 0x413) == 639);
+        assert!(read_word(&ram, 0x41A) == 0x1E && read_word(&ram, 0x41C) == 0x1E);
+        assert!(read_word(&ram, 0x480) == 0x1E && read_word(&ram, 0x482) == 0x3E);
+        assert!(read_word(&ram, 0x46C) == 0x00AF && read_word(&ram, 0x46E) == 0x18);
+
+        /* EBDA starts right where base memory ends */
+        assert!((read_word(&ram, 0x40E) as usize) << 4 == (read_word(&ram, 0x413) as usize) << 10);
+
+        /* Tick count rolls over at midnight and sets the flag */
+        let mut flag = [0u8; 1];
+        ram.read_bytes(0x470, &mut flag);
+        assert!(flag[0] == 0);
+        advance_ticks(&ram);
+        assert!(read_word(&ram, 0x46C) == 0 && read_word(&ram, 0x46E) == 0);
+        ram.read_bytes(0x470, &mut flag);
+        assert!(flag[0] == 1);
+        advance_ticks(&ram);
+        assert!(read_word(&ram, 0x46C) == 1);
+    }
+
+    #[test] fn equipment() {
+        let info = BdaInfo {
+            com_ports: vec![0x3F8, 0x2F8],
+            lpt_ports: vec![],
+            floppies: 2,
+            mouse: false,
+            conventional_kb: 512,
+            ticks: 0,
+        };
+        let bda = build(&info);
+        assert!(bda[0x10] as u16 | (bda[0x11] as u16) << 8 == 0x0463);
+        assert!(bda[0x13] as u16 | (bda[0x14] as u16) << 8 == 511);
+        assert!(bda[0x0E] as u16 | (bda[0x0F] as u16) << 8 == 0x7FC0);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+static mut LOW_RAM: Option<*const vm::memory_region> = None;
+
+/* IRQ0 raised by PIT, do what BIOS INT 8 handler would */
+fn timer_tick()
+{
+    unsafe {
+        if let Some(ram) = LOW_RAM {
+            advance_ticks(&*ram);
+        }
+    }
+}
+
+/* Timer ticks since local midnight, as BIOS initializes them from RTC */
+fn ticks_since_midnight() -> u32
+{
+    let now = time::now();
+    let ms = ((now.tm_hour * 60 + now.tm_min) * 60 + now.tm_sec) as u64 * 1000 + (now.tm_nsec / 1000000) as u64;
+    (ms * TICKS_PER_DAY as u64 / (24 * 3600 * 1000)) as u32
+}
+
+/**
+ * Fill BDA in RAM mapped at guest physical 0 and start counting timer ticks
+ */
+pub fn init(ram: &Arc<vm::memory_region>, config: &config::VmConfig)
+{
+    let info = BdaInfo {
+        com_ports: vec![uart::COM1_BASE],
+        lpt_ports: vec![lpt::LPT1_BASE],
+        floppies: if config.floppy_b.is_some() { 2 } else if config.floppy.is_some() { 1 } else { 0 },
+        mouse: true,
+        conventional_kb: (::std::cmp::min(ram.size, CONVENTIONAL_LIMIT) >> 10) as u16,
+        ticks: ticks_since_midnight(),
+    };
+
+    ram.write_bytes(BDA_BASE, &build(&info));
+
+    unsafe {
+        LOW_RAM = Some(&**ram as *const vm::memory_region);
+    }
+    pit::set_tick_handler(timer_tick);
+}
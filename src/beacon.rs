@@ -0,0 +1,380 @@
+/*
+ * Guest panic beacon
+ *
+ * Bare metal test kernels report assertion failures by writing to the beacon port, see --panic-port. VMM
+ * captures the message with guest registers and stack around SS:SP, logs a guest_panic event and stops VM
+ * with VmExit::GuestPanic, or only reports it and lets guest go on. It is the guest initiated sibling of
+ * crash reports and saved the same way, see --crash-dir.
+ *
+ * Protocol, all of it writes to the beacon port:
+ *
+ *   dword BEACON_INLINE    length, then that many byte writes of message text
+ *   dword BEACON_POINTER   dword guest physical address, then length of message text there
+ *
+ * Lengths may be written as byte, word or dword. Writes that don't fit protocol are dropped with a warning
+ * and the next one has to start over with a magic dword. Reads find no device.
+ */
+
+use vm;
+use crash;
+use config;
+use eventlog;
+
+use std::cell::RefCell;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+
+// Magic dwords starting a message, "PANC" and "PANP" in guest memory
+pub const BEACON_INLINE: u32 = 0x434E4150;
+pub const BEACON_POINTER: u32 = 0x504E4150;
+
+// Longer messages are cut, inline ones are refused
+pub const MAX_MESSAGE: usize = 1024;
+
+// Stack bytes shown below and from SS:SP on
+const STACK_BELOW: u64 = 16;
+const STACK_ABOVE: u64 = 48;
+
+// Big flag of stack segment access rights, 32 bit SP
+const SEGMENT_ATTR_DB: u32 = 0x4000;
+
+/* Where a message is in the protocol */
+#[derive(Clone, PartialEq, Debug)]
+enum Protocol
+{
+    Idle,
+    InlineLength,
+    InlineText { len: usize, text: Vec<u8> },
+    PointerAddress,
+    PointerLength { addr: u64 },
+}
+
+/**
+ * Guest panic message with guest state at the time
+ */
+pub struct PanicReport
+{
+    pub message: String,
+    pub vcpu_state: vm::VcpuState,
+    pub stack_addr: u64,        // Linear address of first stack byte
+    pub stack: Vec<u8>,         // Empty if SS:SP isn't in guest memory
+}
+
+impl PanicReport
+{
+    /* Linear address of SS:SP */
+    fn stack_pointer(&self) -> u64 {
+        let state = &self.vcpu_state;
+        let sp = if state.ss.attributes & SEGMENT_ATTR_DB != 0 { state.rsp & 0xFFFFFFFF } else { state.rsp & 0xFFFF };
+        state.ss.base + sp
+    }
+}
+
+impl fmt::Display for PanicReport
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "Guest panic: {}", self.message));
+
+        try!(writeln!(f, "Registers:"));
+        try!(writeln!(f, "{}", self.vcpu_state));
+
+        let state = &self.vcpu_state;
+        try!(writeln!(f, "Stack around {:04x}:{:04x}:", state.ss.selector, state.rsp));
+        if self.stack.is_empty() {
+            return writeln!(f, "  (not in guest memory)");
+        }
+        crash::write_hex_lines(f, self.stack_addr, &self.stack)
+    }
+}
+
+/**
+ * Beacon port device, message ends up with panic along with whether it stops VM
+ */
+struct PanicBeacon
+{
+    stop: bool,
+    protocol: RefCell<Protocol>,
+    read_memory: fn(u64, &mut [u8]) -> usize,
+    panic: fn(String, bool),
+}
+
+impl PanicBeacon
+{
+    fn new(stop: bool, read_memory: fn(u64, &mut [u8]) -> usize, panic: fn(String, bool)) -> PanicBeacon {
+        PanicBeacon {
+            stop: stop,
+            protocol: RefCell::new(Protocol::Idle),
+            read_memory: read_memory,
+            panic: panic,
+        }
+    }
+
+    /* Text guest gave, bytes that aren't UTF-8 are replaced */
+    fn finish(&self, text: &[u8]) {
+        (self.panic)(String::from_utf8_lossy(text).into_owned(), self.stop);
+    }
+
+    /* Next protocol step for a write, Idle also after a message is complete */
+    fn step(&self, protocol: Protocol, data: vm::IoOperandType) -> Protocol {
+        let val = match data {
+            vm::IoOperandType::byte(v) => v as u32,
+            vm::IoOperandType::word(v) => v as u32,
+            vm::IoOperandType::dword(v) => v,
+        };
+
+        match (protocol, data) {
+            (Protocol::Idle, vm::IoOperandType::dword(BEACON_INLINE)) => Protocol::InlineLength,
+            (Protocol::Idle, vm::IoOperandType::dword(BEACON_POINTER)) => Protocol::PointerAddress,
+            (Protocol::InlineLength, _) if val as usize > MAX_MESSAGE => {
+                warn!("beacon: panic message of {} bytes is over {}, dropped", val, MAX_MESSAGE);
+                Protocol::Idle
+            },
+            (Protocol::InlineLength, _) if val == 0 => {
+                self.finish(&[]);
+                Protocol::Idle
+            },
+            (Protocol::InlineLength, _) => Protocol::InlineText { len: val as usize, text: Vec::with_capacity(val as usize) },
+            (Protocol::InlineText { len, mut text }, vm::IoOperandType::byte(c)) => {
+                text.push(c);
+                if text.len() < len {
+                    return Protocol::InlineText { len: len, text: text };
+                }
+                self.finish(&text);
+                Protocol::Idle
+            },
+            (Protocol::PointerAddress, vm::IoOperandType::dword(addr)) => Protocol::PointerLength { addr: addr as u64 },
+            (Protocol::PointerLength { addr }, _) => {
+                let len = val as usize;
+                if len > MAX_MESSAGE {
+                    warn!("beacon: panic message of {} bytes at 0x{:x} cut to {}", len, addr, MAX_MESSAGE);
+                }
+
+                /* Message running into unmapped memory ends there */
+                let mut text = vec![0u8; ::std::cmp::min(len, MAX_MESSAGE)];
+                let read = (self.read_memory)(addr, &mut text);
+                text.truncate(read);
+                self.finish(&text);
+                Protocol::Idle
+            },
+            (protocol, _) => {
+                warn!("beacon: unexpected write 0x{:x} in state {:?}, waiting for magic", val, protocol);
+                Protocol::Idle
+            },
+        }
+    }
+}
+
+impl vm::io_handler for PanicBeacon
+{
+    fn io_read(&self, _: u16, size: u8) -> vm::IoOperandType
+    {
+        vm::IoOperandType::make_unhandled(size)
+    }
+
+    fn io_write(&self, _: u16, data: vm::IoOperandType)
+    {
+        let protocol = mem::replace(&mut *self.protocol.borrow_mut(), Protocol::Idle);
+        let next = self.step(protocol, data);
+        *self.protocol.borrow_mut() = next;
+    }
+}
+
+impl vm::reset_handler for PanicBeacon
+{
+    fn reset(&self)
+    {
+        *self.protocol.borrow_mut() = Protocol::Idle;
+    }
+}
+
+#[cfg(test)]
+mod beacon_test
+{
+    use super::*;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static PANICS: RefCell<Vec<(String, bool)>> = RefCell::new(Vec::new());
+    }
+
+    /* Guest memory has a message at 0x1000 and ends at 0x1010 */
+    fn read_memory(addr: u64, buf: &mut [u8]) -> usize {
+        let memory = b"ptr: bad magic!!";
+        if addr < 0x1000 || addr >= 0x1000 + memory.len() as u64 {
+            return 0;
+        }
+
+        let start = (addr - 0x1000) as usize;
+        let len = ::std::cmp::min(buf.len(), memory.len() - start);
+        buf[..len].copy_from_slice(&memory[start..start + len]);
+        len
+    }
+
+    fn record_panic(message: String, stop: bool) {
+        PANICS.with(|panics| panics.borrow_mut().push((message, stop)));
+    }
+
+    fn panics() -> Vec<(String, bool)> {
+        PANICS.with(|panics| panics.borrow_mut().split_off(0))
+    }
+
+    fn write(dev: &PanicBeacon, data: vm::IoOperandType) {
+        vm::io_handler::io_write(dev, 0x510, data);
+    }
+
+    fn write_inline(dev: &PanicBeacon, text: &str) {
+        write(dev, vm::IoOperandType::dword(BEACON_INLINE));
+        write(dev, vm::IoOperandType::word(text.len() as u16));
+        for &c in text.as_bytes() {
+            write(dev, vm::IoOperandType::byte(c));
+        }
+    }
+
+    #[test] fn inline_message() {
+        let dev = PanicBeacon::new(true, read_memory, record_panic);
+
+        write_inline(&dev, "assert failed: x == 1");
+        assert!(panics() == vec![(String::from("assert failed: x == 1"), true)]);
+
+        /* Message isn't reported before its last byte */
+        write(&dev, vm::IoOperandType::dword(BEACON_INLINE));
+        write(&dev, vm::IoOperandType::byte(3));
+        write(&dev, vm::IoOperandType::byte(b'a'));
+        write(&dev, vm::IoOperandType::byte(b'b'));
+        assert!(panics().is_empty());
+        write(&dev, vm::IoOperandType::byte(b'c'));
+        assert!(panics() == vec![(String::from("abc"), true)]);
+
+        write(&dev, vm::IoOperandType::dword(BEACON_INLINE));
+        write(&dev, vm::IoOperandType::dword(0));
+        assert!(panics() == vec![(String::new(), true)]);
+    }
+
+    #[test] fn pointer_message() {
+        let dev = PanicBeacon::new(false, read_memory, record_panic);
+
+        write(&dev, vm::IoOperandType::dword(BEACON_POINTER));
+        write(&dev, vm::IoOperandType::dword(0x1000));
+        write(&dev, vm::IoOperandType::word(14));
+        assert!(panics() == vec![(String::from("ptr: bad magic"), false)]);
+
+        /* Text running off guest memory is cut there */
+        write(&dev, vm::IoOperandType::dword(BEACON_POINTER));
+        write(&dev, vm::IoOperandType::dword(0x100A));
+        write(&dev, vm::IoOperandType::dword(100));
+        assert!(panics() == vec![(String::from("agic!!"), false)]);
+    }
+
+    #[test] fn protocol_errors() {
+        let dev = PanicBeacon::new(true, read_memory, record_panic);
+
+        /* Stray bytes and magic written as words don't start a message */
+        write(&dev, vm::IoOperandType::byte(b'x'));
+        write(&dev, vm::IoOperandType::word(BEACON_INLINE as u16));
+        write(&dev, vm::IoOperandType::word((BEACON_INLINE >> 16) as u16));
+        assert!(*dev.protocol.borrow() == Protocol::Idle);
+
+        /* Oversized message is refused, word in the middle of text starts over */
+        write(&dev, vm::IoOperandType::dword(BEACON_INLINE));
+        write(&dev, vm::IoOperandType::dword(MAX_MESSAGE as u32 + 1));
+        assert!(*dev.protocol.borrow() == Protocol::Idle);
+        write(&dev, vm::IoOperandType::dword(BEACON_INLINE));
+        write(&dev, vm::IoOperandType::byte(4));
+        write(&dev, vm::IoOperandType::word(0x4141));
+        assert!(*dev.protocol.borrow() == Protocol::Idle);
+        assert!(panics().is_empty());
+
+        /* Reset drops a message half way */
+        write(&dev, vm::IoOperandType::dword(BEACON_POINTER));
+        vm::reset_handler::reset(&dev);
+        write(&dev, vm::IoOperandType::dword(0x1000));
+        assert!(*dev.protocol.borrow() == Protocol::Idle);
+
+        write_inline(&dev, "ok");
+        assert!(panics() == vec![(String::from("ok"), true)]);
+        assert!(vm::io_handler::io_read(&dev, 0x510, 1).unwrap_byte() == 0xFF);
+    }
+
+    #[test] fn report_format() {
+        let real_mode = vm::SegmentState { selector: 0, base: 0, limit: 0xffff, attributes: 0x93 };
+        let mut report = PanicReport {
+            message: String::from("assert failed: x == 1"),
+            vcpu_state: vm::VcpuState {
+                rsp: 0x7bfe,
+                rip: 0x7c20,
+                es: real_mode,
+                cs: vm::SegmentState { attributes: 0x9b, ..real_mode },
+                ss: real_mode,
+                ds: real_mode,
+                fs: real_mode,
+                gs: real_mode,
+                ..Default::default()
+            },
+            stack_addr: 0x7bee,
+            stack: (0..64).collect(),
+        };
+        assert!(report.stack_pointer() == 0x7bfe);
+
+        let text = report.to_string();
+        assert!(text.starts_with("Guest panic: assert failed: x == 1\nRegisters:\nEAX=00000000"));
+        assert!(text.contains("EIP=00007c20"));
+        assert!(text.contains("Stack around 0000:7bfe:\n  00007bee  00 01 02"));
+        assert!(text.ends_with("  00007c1e  30 31 32 33 34 35 36 37 38 39 3a 3b 3c 3d 3e 3f\n"));
+
+        report.stack.clear();
+        assert!(report.to_string().ends_with("Stack around 0000:7bfe:\n  (not in guest memory)\n"));
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/**
+ * Report guest panic message with guest state, stop VM if asked to, vcpu thread only
+ */
+pub fn guest_panic(message: String, stop: bool)
+{
+    let mut report = PanicReport {
+        message: message,
+        vcpu_state: vm::vcpu_state(),
+        stack_addr: 0,
+        stack: Vec::new(),
+    };
+
+    /* Window may run into unmapped memory, stack is only kept up to there */
+    let sp = report.stack_pointer();
+    report.stack_addr = sp.saturating_sub(STACK_BELOW);
+    report.stack = vec![0u8; (sp - report.stack_addr + STACK_ABOVE) as usize];
+    let len = vm::read_guest_memory(report.stack_addr, &mut report.stack);
+    report.stack.truncate(len);
+
+    let text = report.to_string();
+    eventlog::emit(|| {
+        let state = &report.vcpu_state;
+        eventlog::Event::GuestPanic {
+            message: report.message.clone(),
+            cs: state.cs.selector,
+            ip: state.rip,
+            ss: state.ss.selector,
+            sp: state.rsp,
+        }
+    });
+
+    if stop {
+        vm::request_vm_exit(vm::VmExit::GuestPanic { message: report.message, report: text });
+    } else {
+        error!("{}", text);
+    }
+}
+
+pub fn init(config: &config::VmConfig)
+{
+    let beacon = match config.panic_beacon {
+        Some(ref beacon) => beacon,
+        None => return,
+    };
+
+    let dev = Rc::new(PanicBeacon::new(beacon.stop, vm::read_guest_memory, guest_panic));
+    vm::register_io_region(dev.clone(), beacon.port, 1);
+    vm::register_reset_handler(dev);
+}
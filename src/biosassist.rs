@@ -0,0 +1,684 @@
+/*
+ * BIOS services handled by VMM for test images running without firmware
+ *
+ * Assisted vectors point at stubs doing HLT followed by IRET. HLT exits to VMM which recognizes the stub
+ * address, performs the call on guest registers and resumes guest at the IRET. Flags returned to caller
+ * are the ones IRET pops, so VMM edits that stack image to report status in CF.
+ *
+ * Int 10h supports only 80x25 color text mode: teletype output, cursor position and current mode queries.
+ * Cursor positions live in BDA as a video BIOS would keep them, so guest may read them directly.
+ * Int 13h disk services are in biosdisk module, int 15h system services in biossys and int 16h keyboard
+ * services in bioskbd. A call that can't complete yet leaves guest at the HLT, so it is retried.
+ */
+
+use vm;
+use config;
+use dma;
+use biosdisk;
+use bioskbd;
+use biossys;
+use event;
+use clock;
+
+use std::sync::Arc;
+use std::cell::RefCell;
+use std::thread;
+use std::time;
+
+// Trap stubs at the entry points used by PC BIOSes
+const INT10_VECTOR: usize       = 0x10;
+const INT10_STUB_OFF: u16       = 0xF065;
+const INT13_VECTOR: usize       = 0x13;
+const INT13_STUB_OFF: u16       = 0xE3FE;
+const INT15_VECTOR: usize       = 0x15;
+const INT15_STUB_OFF: u16       = 0xF859;
+const INT16_VECTOR: usize       = 0x16;
+const INT16_STUB_OFF: u16       = 0xE82E;
+const STUB_SEG: u16             = 0xF000;
+const HLT_OPCODE: u8            = 0xF4;
+const IRET_OPCODE: u8           = 0xCF;
+
+const FLAGS_CF: u16             = 0x0001;
+const FLAGS_ZF: u16             = 0x0040;
+
+// Keyboard controller polled for int 16h
+const I8042_DATA_PORT: u16      = 0x60;
+const I8042_STATUS_PORT: u16    = 0x64;
+const I8042_STR_OBF: u8         = 0x01;
+const I8042_STR_AUXB: u8        = 0x20;
+const INPUT_POLL_MS: u64        = 10;
+
+// BDA video fields
+const BDA_VIDEO_MODE: usize     = 0x449;
+const BDA_VIDEO_COLUMNS: usize  = 0x44A;
+const BDA_VIDEO_PAGE_SIZE: usize = 0x44C;
+const BDA_VIDEO_PAGE_OFFSET: usize = 0x44E;
+const BDA_CURSOR_POS: usize     = 0x450;    // 8 words, column in low byte, row in high byte
+const BDA_CURSOR_SHAPE: usize   = 0x460;
+const BDA_ACTIVE_PAGE: usize    = 0x462;
+const BDA_CRTC_BASE: usize      = 0x463;
+const BDA_VIDEO_ROWS: usize     = 0x484;    // Rows minus one
+
+// Mode 3 text screen
+const VIDEO_MODE_TEXT: u8       = 0x03;
+const TEXT_BASE: usize          = 0xB8000;
+const TEXT_COLS: u8             = 80;
+const TEXT_ROWS: u8             = 25;
+const TEXT_PAGES: u8            = 8;
+const TEXT_PAGE_SIZE: usize     = 0x1000;
+const TEXT_ATTR: u8             = 0x07;     // Light grey on black
+const CURSOR_SHAPE: u16         = 0x0607;   // Underline
+const CRTC_COLOR_BASE: u16      = 0x3D4;
+
+// Teletype control characters
+const CHAR_BELL: u8             = 0x07;
+const CHAR_BS: u8               = 0x08;
+const CHAR_LF: u8               = 0x0A;
+const CHAR_CR: u8               = 0x0D;
+
+// Int 10h functions in AH
+const INT10_SET_CURSOR: u8      = 0x02;
+const INT10_GET_CURSOR: u8      = 0x03;
+const INT10_TELETYPE: u8        = 0x0E;
+const INT10_GET_MODE: u8        = 0x0F;
+
+/**
+ * Guest registers passed to and returned from an assisted BIOS call
+ */
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct CallRegs
+{
+    pub ax: u16,
+    pub bx: u16,
+    pub cx: u16,
+    pub dx: u16,
+    pub si: u16,
+    pub di: u16,
+    pub ds: u16,
+    pub es: u16,
+    pub flags: u16,     // Restored by IRET on return to caller
+}
+
+#[allow(dead_code)]
+impl CallRegs
+{
+    pub fn ah(&self) -> u8 { hi(self.ax) }
+    pub fn al(&self) -> u8 { lo(self.ax) }
+    pub fn bh(&self) -> u8 { hi(self.bx) }
+    pub fn bl(&self) -> u8 { lo(self.bx) }
+    pub fn ch(&self) -> u8 { hi(self.cx) }
+    pub fn cl(&self) -> u8 { lo(self.cx) }
+    pub fn dh(&self) -> u8 { hi(self.dx) }
+    pub fn dl(&self) -> u8 { lo(self.dx) }
+
+    pub fn set_ah(&mut self, val: u8) { self.ax = make_word(val, self.al()) }
+    pub fn set_al(&mut self, val: u8) { self.ax = make_word(self.ah(), val) }
+    pub fn set_bh(&mut self, val: u8) { self.bx = make_word(val, self.bl()) }
+
+    pub fn set_carry(&mut self, carry: bool) {
+        if carry {
+            self.flags |= FLAGS_CF;
+        } else {
+            self.flags &= !FLAGS_CF;
+        }
+    }
+
+    pub fn carry(&self) -> bool {
+        (self.flags & FLAGS_CF) != 0
+    }
+
+    pub fn set_zero(&mut self, zero: bool) {
+        if zero {
+            self.flags |= FLAGS_ZF;
+        } else {
+            self.flags &= !FLAGS_ZF;
+        }
+    }
+
+    pub fn zero(&self) -> bool {
+        (self.flags & FLAGS_ZF) != 0
+    }
+}
+
+/**
+ * Outcome of guest HLT
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Trap
+{
+    None,       // Not a trap stub
+    Done,       // Call performed, resume after the HLT
+    Wait,       // Call blocks for input, run HLT again later
+    Delay,      // Call waits for guest time to pass, run HLT again right away
+}
+
+fn hi(val: u16) -> u8
+{
+    (val >> 8) as u8
+}
+
+fn lo(val: u16) -> u8
+{
+    val as u8
+}
+
+fn make_word(hi: u8, lo: u8) -> u16
+{
+    ((hi as u16) << 8) | lo as u16
+}
+
+fn read_word(ram: &vm::memory_region, addr: usize) -> u16
+{
+    let mut buf = [0u8; 2];
+    ram.read_bytes(addr, &mut buf);
+    make_word(buf[1], buf[0])
+}
+
+fn write_word(ram: &vm::memory_region, addr: usize, val: u16)
+{
+    ram.write_bytes(addr, &[lo(val), hi(val)]);
+}
+
+/* Cursor of text page as (row, column) */
+fn get_cursor(ram: &vm::memory_region, page: u8) -> (u8, u8)
+{
+    let pos = read_word(ram, BDA_CURSOR_POS + (page as usize) * 2);
+    (hi(pos), lo(pos))
+}
+
+fn set_cursor(ram: &vm::memory_region, page: u8, row: u8, col: u8)
+{
+    write_word(ram, BDA_CURSOR_POS + (page as usize) * 2, make_word(row, col));
+}
+
+fn cell_addr(page: u8, row: u8, col: u8) -> usize
+{
+    TEXT_BASE + (page as usize) * TEXT_PAGE_SIZE + ((row as usize) * (TEXT_COLS as usize) + col as usize) * 2
+}
+
+/* Move page contents up one row and blank the bottom row */
+fn scroll_up(ram: &vm::memory_region, page: u8)
+{
+    let row_bytes = (TEXT_COLS as usize) * 2;
+    let mut text = vec![0u8; row_bytes * (TEXT_ROWS as usize - 1)];
+    ram.read_bytes(cell_addr(page, 1, 0), &mut text);
+    ram.write_bytes(cell_addr(page, 0, 0), &text);
+
+    let blank: Vec<u8> = (0..TEXT_COLS).flat_map(|_| vec![b' ', TEXT_ATTR]).collect();
+    ram.write_bytes(cell_addr(page, TEXT_ROWS - 1, 0), &blank);
+}
+
+/* AH=0Eh: write character in AL at cursor of page BH and advance cursor */
+fn teletype(ram: &vm::memory_region, page: u8, ch: u8)
+{
+    let (mut row, mut col) = get_cursor(ram, page);
+
+    match ch {
+        CHAR_BELL => debug!("biosassist: bell"),
+        CHAR_BS => col = col.saturating_sub(1),
+        CHAR_CR => col = 0,
+        CHAR_LF => row += 1,
+        _ => {
+            ram.write_bytes(cell_addr(page, row, col), &[ch, TEXT_ATTR]);
+            col += 1;
+            if col >= TEXT_COLS {
+                col = 0;
+                row += 1;
+            }
+        }
+    }
+
+    if row >= TEXT_ROWS {
+        scroll_up(ram, page);
+        row = TEXT_ROWS - 1;
+    }
+
+    set_cursor(ram, page, row, col);
+}
+
+/**
+ * Perform int 10h call on guest registers.
+ * RAM is mapped at guest physical 0 and covers BDA and text buffer.
+ */
+pub fn int10(ram: &vm::memory_region, regs: &mut CallRegs)
+{
+    let page = regs.bh() & (TEXT_PAGES - 1);
+
+    match regs.ah() {
+        INT10_SET_CURSOR => {
+            let (row, col) = (regs.dh(), regs.dl());
+            if row < TEXT_ROWS && col < TEXT_COLS {
+                set_cursor(ram, page, row, col);
+            }
+        },
+
+        INT10_GET_CURSOR => {
+            let (row, col) = get_cursor(ram, page);
+            regs.dx = make_word(row, col);
+            regs.cx = read_word(ram, BDA_CURSOR_SHAPE);
+        },
+
+        INT10_TELETYPE => teletype(ram, page, regs.al()),
+
+        INT10_GET_MODE => {
+            let mut mode = [0u8; 1];
+            let mut active = [0u8; 1];
+            ram.read_bytes(BDA_VIDEO_MODE, &mut mode);
+            ram.read_bytes(BDA_ACTIVE_PAGE, &mut active);
+            regs.ax = make_word(lo(read_word(ram, BDA_VIDEO_COLUMNS)), mode[0]);
+            regs.set_bh(active[0]);
+        },
+
+        func => debug!("biosassist: unsupported int 10h function {:x}", func),
+    }
+}
+
+/* Point vector at a trap stub in BIOS segment */
+fn install_stub(ram: &vm::memory_region, vector: usize, off: u16)
+{
+    write_word(ram, vector * 4, off);
+    write_word(ram, vector * 4 + 2, STUB_SEG);
+    ram.write_bytes(stub_addr(off) as usize, &[HLT_OPCODE, IRET_OPCODE]);
+}
+
+fn stub_addr(off: u16) -> u64
+{
+    ((STUB_SEG as u64) << 4) + off as u64
+}
+
+/**
+ * Point assisted vectors at trap stubs, set up BDA video fields and clear the screen.
+ * Disk specific tables and BDA fields are set up by biosdisk, system configuration table by biossys.
+ */
+pub fn install(ram: &vm::memory_region, disks: &biosdisk::BiosDisks)
+{
+    install_stub(ram, INT10_VECTOR, INT10_STUB_OFF);
+    install_stub(ram, INT13_VECTOR, INT13_STUB_OFF);
+    install_stub(ram, INT15_VECTOR, INT15_STUB_OFF);
+    install_stub(ram, INT16_VECTOR, INT16_STUB_OFF);
+
+    ram.write_bytes(BDA_VIDEO_MODE, &[VIDEO_MODE_TEXT]);
+    write_word(ram, BDA_VIDEO_COLUMNS, TEXT_COLS as u16);
+    write_word(ram, BDA_VIDEO_PAGE_SIZE, TEXT_PAGE_SIZE as u16);
+    write_word(ram, BDA_VIDEO_PAGE_OFFSET, 0);
+    for page in 0..TEXT_PAGES {
+        set_cursor(ram, page, 0, 0);
+    }
+    write_word(ram, BDA_CURSOR_SHAPE, CURSOR_SHAPE);
+    ram.write_bytes(BDA_ACTIVE_PAGE, &[0]);
+    write_word(ram, BDA_CRTC_BASE, CRTC_COLOR_BASE);
+    ram.write_bytes(BDA_VIDEO_ROWS, &[TEXT_ROWS - 1]);
+
+    let blank: Vec<u8> = (0..TEXT_PAGE_SIZE / 2 * TEXT_PAGES as usize).flat_map(|_| vec![b' ', TEXT_ATTR]).collect();
+    ram.write_bytes(TEXT_BASE, &blank);
+
+    biosdisk::install(ram, disks);
+    biossys::install(ram);
+}
+
+/* Host side of assisted calls */
+struct Host
+{
+    read_scancode: fn() -> Option<u8>,  // Keyboard controller data that arrived since last keyboard call
+    clock: fn() -> u64,                 // Guest time in nanoseconds
+}
+
+/* Assisted call state kept between traps */
+struct Services
+{
+    disks: biosdisk::BiosDisks,
+    delay_end: Option<u64>,             // Deadline of pending int 15h wait
+}
+
+/* Handle guest HLT at linear address */
+fn trap(ram: &vm::memory_region, services: &mut Services, mem: &dma::dma_memory, host: &Host, addr: u64, regs: &mut CallRegs) -> Trap
+{
+    if addr == stub_addr(INT10_STUB_OFF) {
+        int10(ram, regs);
+    } else if addr == stub_addr(INT13_STUB_OFF) {
+        biosdisk::int13(&mut services.disks, mem, regs);
+    } else if addr == stub_addr(INT15_STUB_OFF) {
+        if !biossys::int15(mem, regs, (host.clock)(), &mut services.delay_end) {
+            return Trap::Delay;
+        }
+    } else if addr == stub_addr(INT16_STUB_OFF) {
+        while let Some(code) = (host.read_scancode)() {
+            bioskbd::scancode(ram, code);
+        }
+
+        if !bioskbd::int16(ram, regs) {
+            return Trap::Wait;
+        }
+    } else {
+        return Trap::None;
+    }
+
+    Trap::Done
+}
+
+#[cfg(test)]
+mod biosassist_test
+{
+    use super::*;
+
+    /* Guest memory is RAM mapped at 0 */
+    struct RamMemory<'a>
+    {
+        ram: &'a vm::memory_region,
+    }
+
+    impl<'a> dma::dma_memory for RamMemory<'a>
+    {
+        fn read(&self, addr: u64, buf: &mut [u8]) -> usize {
+            self.ram.read_bytes(addr as usize, buf)
+        }
+
+        fn write(&self, addr: u64, buf: &[u8]) -> usize {
+            self.ram.write_bytes(addr as usize, buf)
+        }
+    }
+
+    fn make_ram() -> Arc<vm::memory_region> {
+        let ram = vm::alloc_memory_region(0x100000);
+        ram.write_bytes(0, &vec![0xCCu8; 0x100000]);
+        install(&ram, &biosdisk::BiosDisks::new(None, None, None));
+        ram
+    }
+
+    fn vector_addr(ram: &vm::memory_region, vector: usize) -> u64 {
+        ((read_word(ram, vector * 4 + 2) as u64) << 4) + read_word(ram, vector * 4) as u64
+    }
+
+    /* Call int 10h through its vector the way guest would get there */
+    fn call(ram: &vm::memory_region, ax: u16, bx: u16, cx: u16, dx: u16) -> CallRegs {
+        let mut regs = CallRegs { ax: ax, bx: bx, cx: cx, dx: dx, ..Default::default() };
+        assert!(trap(ram, &mut services(), &RamMemory { ram: ram }, &host(no_scancode), vector_addr(ram, 0x10), &mut regs) == Trap::Done);
+        regs
+    }
+
+    fn services() -> Services {
+        Services { disks: biosdisk::BiosDisks::new(None, None, None), delay_end: None }
+    }
+
+    fn host(read_scancode: fn() -> Option<u8>) -> Host {
+        Host { read_scancode: read_scancode, clock: test_clock }
+    }
+
+    /* Guest time moves by 1 ms on every look */
+    fn test_clock() -> u64 {
+        thread_local!(static NOW: ::std::cell::Cell<u64> = ::std::cell::Cell::new(0));
+        NOW.with(|now| { now.set(now.get() + 1000000); now.get() })
+    }
+
+    fn no_scancode() -> Option<u8> {
+        None
+    }
+
+    /* Keyboard controller holding "A" press and release */
+    fn key_a_scancode() -> Option<u8> {
+        thread_local!(static CODES: RefCell<Vec<u8>> = RefCell::new(vec![0x9E, 0x1E]));
+        CODES.with(|codes| codes.borrow_mut().pop())
+    }
+
+    fn print(ram: &vm::memory_region, s: &[u8]) {
+        for ch in s {
+            call(ram, 0x0E00 | *ch as u16, 0, 0, 0);
+        }
+    }
+
+    fn row_text(ram: &vm::memory_region, row: u8) -> String {
+        let mut buf = vec![0u8; TEXT_COLS as usize * 2];
+        ram.read_bytes(cell_addr(0, row, 0), &mut buf);
+        buf.chunks(2).map(|cell| cell[0] as char).collect::<String>().trim_right().to_string()
+    }
+
+    fn cursor(ram: &vm::memory_region) -> (u8, u8) {
+        let mut buf = [0u8; 2];
+        ram.read_bytes(0x450, &mut buf);
+        (buf[1], buf[0])
+    }
+
+    #[test] fn stub() {
+        let ram = make_ram();
+        assert!(read_word(&ram, 0x40) == 0xF065 && read_word(&ram, 0x42) == 0xF000);
+
+        let mut code = [0u8; 2];
+        ram.read_bytes(0xFF065, &mut code);
+        assert!(code == [HLT_OPCODE, IRET_OPCODE]);
+
+        assert!(vector_addr(&ram, 0x13) == 0xFE3FE);
+        ram.read_bytes(0xFE3FE, &mut code);
+        assert!(code == [HLT_OPCODE, IRET_OPCODE]);
+
+        /* Disk calls land in int 13h handler, which has no drives here */
+        let mut services = services();
+        let mut regs = CallRegs { ax: 0x0000, dx: 0x0080, ..Default::default() };
+        assert!(trap(&ram, &mut services, &RamMemory { ram: &ram }, &host(no_scancode), 0xFE3FE, &mut regs) == Trap::Done);
+        assert!(regs.carry());
+
+        /* HLT anywhere else is not ours */
+        let mut regs = CallRegs { ax: 0x0E41, ..Default::default() };
+        assert!(trap(&ram, &mut services, &RamMemory { ram: &ram }, &host(no_scancode), 0xFF066, &mut regs) == Trap::None);
+        assert!(row_text(&ram, 0) == "");
+    }
+
+    #[test] fn teletype_output() {
+        let ram = make_ram();
+
+        print(&ram, b"Hello\r\nworld");
+        assert!(row_text(&ram, 0) == "Hello");
+        assert!(row_text(&ram, 1) == "world");
+        assert!(cursor(&ram) == (1, 5));
+
+        let mut cell = [0u8; 2];
+        ram.read_bytes(0xB8000 + 80 * 2, &mut cell);
+        assert!(cell == [b'w', 0x07]);
+
+        /* Backspace only moves cursor, bell prints nothing */
+        print(&ram, b"\x08\x08\x07X");
+        assert!(row_text(&ram, 1) == "worXd");
+        assert!(cursor(&ram) == (1, 4));
+        print(&ram, b"\r\x08");
+        assert!(cursor(&ram) == (1, 0));
+
+        /* Long lines wrap */
+        print(&ram, &[b'-'; 81]);
+        assert!(cursor(&ram) == (2, 1));
+        assert!(row_text(&ram, 2) == "-");
+    }
+
+    #[test] fn scroll() {
+        let ram = make_ram();
+
+        for i in 0..25 {
+            print(&ram, format!("line {}\r\n", i).as_bytes());
+        }
+
+        /* Line feed past row 24 scrolls, cursor stays on the bottom row */
+        assert!(cursor(&ram) == (24, 0));
+        assert!(row_text(&ram, 0) == "line 1");
+        assert!(row_text(&ram, 23) == "line 24");
+        assert!(row_text(&ram, 24) == "");
+
+        let mut cell = [0u8; 2];
+        ram.read_bytes(0xB8000 + (24 * 80 + 79) * 2, &mut cell);
+        assert!(cell == [b' ', 0x07]);
+    }
+
+    #[test] fn cursor_and_mode() {
+        let ram = make_ram();
+
+        /* AH=02h sets, AH=03h reads back position and shape */
+        call(&ram, 0x0200, 0, 0, 0x0C28);
+        assert!(cursor(&ram) == (12, 40));
+        let regs = call(&ram, 0x0300, 0, 0, 0);
+        assert!(regs.dx == 0x0C28 && regs.cx == 0x0607);
+
+        print(&ram, b"*");
+        let mut cell = [0u8; 1];
+        ram.read_bytes(0xB8000 + (12 * 80 + 40) * 2, &mut cell);
+        assert!(cell[0] == b'*');
+
+        /* Off screen positions are ignored, other pages have own cursors */
+        call(&ram, 0x0200, 0, 0, 0x1900);
+        assert!(cursor(&ram) == (12, 41));
+        call(&ram, 0x0200, 0x0100, 0, 0x0203);
+        assert!(read_word(&ram, 0x452) == 0x0203);
+        assert!(call(&ram, 0x0300, 0x0100, 0, 0).dx == 0x0203);
+
+        /* AH=0Fh: 80 columns, mode 3, page 0 */
+        let regs = call(&ram, 0x0F00, 0x0512, 0, 0);
+        assert!(regs.ax == 0x5003 && regs.bx == 0x0012);
+    }
+
+    #[test] fn keyboard() {
+        let ram = make_ram();
+        let info = ::bda::BdaInfo {
+            com_ports: vec![],
+            lpt_ports: vec![],
+            floppies: 0,
+            mouse: false,
+            conventional_kb: 640,
+            ticks: 0,
+        };
+        ram.write_bytes(0x400, &::bda::build(&info));
+        let mut services = services();
+        let addr = vector_addr(&ram, 0x16);
+        assert!(addr == 0xFE82E);
+
+        /* Blocking read waits while controller has nothing */
+        let mut regs = CallRegs { ax: 0x0000, ..Default::default() };
+        assert!(trap(&ram, &mut services, &RamMemory { ram: &ram }, &host(no_scancode), addr, &mut regs) == Trap::Wait);
+        assert!(regs.ax == 0);
+
+        /* Scancodes are taken from controller on the retry */
+        assert!(trap(&ram, &mut services, &RamMemory { ram: &ram }, &host(key_a_scancode), addr, &mut regs) == Trap::Done);
+        assert!(regs.ax == 0x1E61);
+
+        let mut regs = CallRegs { ax: 0x0100, ..Default::default() };
+        assert!(trap(&ram, &mut services, &RamMemory { ram: &ram }, &host(key_a_scancode), addr, &mut regs) == Trap::Done);
+        assert!(regs.zero());
+    }
+
+    #[test] fn system_services() {
+        let ram = make_ram();
+        let mut services = services();
+        let addr = vector_addr(&ram, 0x15);
+        assert!(addr == 0xFF859);
+
+        /* 2.5 ms wait with clock ticking 1 ms per trap */
+        let mut regs = CallRegs { ax: 0x8600, cx: 0x0000, dx: 0x09C4, ..Default::default() };
+        assert!(trap(&ram, &mut services, &RamMemory { ram: &ram }, &host(no_scancode), addr, &mut regs) == Trap::Delay);
+        assert!(trap(&ram, &mut services, &RamMemory { ram: &ram }, &host(no_scancode), addr, &mut regs) == Trap::Delay);
+        assert!(trap(&ram, &mut services, &RamMemory { ram: &ram }, &host(no_scancode), addr, &mut regs) == Trap::Delay);
+        assert!(trap(&ram, &mut services, &RamMemory { ram: &ram }, &host(no_scancode), addr, &mut regs) == Trap::Done);
+        assert!(regs.ah() == 0 && !regs.carry());
+
+        /* Configuration table is in place */
+        let mut regs = CallRegs { ax: 0xC000, ..Default::default() };
+        assert!(trap(&ram, &mut services, &RamMemory { ram: &ram }, &host(no_scancode), addr, &mut regs) == Trap::Done);
+        let mut table = [0u8; 5];
+        ram.read_bytes(((regs.es as usize) << 4) + regs.bx as usize, &mut table);
+        assert!(table == [0x08, 0x00, 0xFC, 0x01, 0x00]);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/* State of assisted BIOS services, lives as long as VM */
+struct Assist
+{
+    ram: Arc<vm::memory_region>,
+    services: RefCell<Services>,
+}
+
+const HOST: Host = Host {
+    read_scancode: read_i8042,
+    clock: clock::device_time_ns,
+};
+
+static mut ASSIST: Option<*const Assist> = None;
+
+/* Keyboard data pending in i8042, mouse data is read and dropped */
+fn read_i8042() -> Option<u8>
+{
+    loop {
+        let status = vm::handle_io_read(I8042_STATUS_PORT, 1).unwrap_byte();
+        if (status & I8042_STR_OBF) == 0 {
+            return None;
+        }
+
+        let data = vm::handle_io_read(I8042_DATA_PORT, 1).unwrap_byte();
+        if (status & I8042_STR_AUXB) == 0 {
+            return Some(data);
+        }
+    }
+}
+
+/**
+ * Handle guest HLT at linear address.
+ * Guest resumes after the HLT if the call is done and stays at it while the call waits.
+ */
+pub fn handle_trap(addr: u64, regs: &mut CallRegs) -> Trap
+{
+    let assist = unsafe {
+        match ASSIST {
+            Some(assist) => &*assist,
+            None => return Trap::None,
+        }
+    };
+
+    /* Block move reaches above 1M, BIOS opens A20 for it and restores the gate afterwards */
+    let a20 = vm::is_a20_enabled();
+    if addr == stub_addr(INT15_STUB_OFF) {
+        vm::set_a20_enabled(true);
+    }
+
+    let res = trap(&assist.ram, &mut assist.services.borrow_mut(), &dma::GuestMemory, &HOST, addr, regs);
+    vm::set_a20_enabled(a20);
+    res
+}
+
+/**
+ * Give host time to deliver input before a waiting call is retried.
+ * Event loop runs meanwhile, so device events are not held up by the guest spinning on HLT.
+ */
+pub fn wait_for_input()
+{
+    event::unlock_event_loop();
+    thread::sleep(time::Duration::from_millis(INPUT_POLL_MS));
+    event::lock_event_loop();
+}
+
+/**
+ * Install BIOS assist if enabled in config.
+ * Needs test image loaded as loader sets up IVT and BDA. Firmware handles int 10h, 13h, 15h and 16h itself.
+ */
+pub fn init(config: &config::VmConfig)
+{
+    if !config.bios_assist {
+        return;
+    }
+
+    if config.has_bios() {
+        warn!("biosassist: firmware provides BIOS services, assist disabled");
+        return;
+    }
+
+    let ram = match vm::find_memory_mapping(0) {
+        Some(mapping) => mapping.region.clone(),
+        None => panic!("biosassist: no guest RAM"),
+    };
+
+    let disks = biosdisk::BiosDisks::open(config);
+    install(&ram, &disks);
+
+    let assist = Box::new(Assist {
+        ram: ram,
+        services: RefCell::new(Services { disks: disks, delay_end: None }),
+    });
+
+    unsafe {
+        ASSIST = Some(Box::into_raw(assist) as *const Assist);
+    }
+}
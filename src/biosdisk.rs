@@ -0,0 +1,600 @@
+/*
+ * Int 13h disk services handled by VMM for test images running without a BIOS
+ *
+ * Drive 00h is the floppy image and drive 80h is the primary ATA master image, each opened again next to
+ * the emulated controller. When booting a floppy emulation El Torito CD, drive 00h is the boot image on CD. Hard disk CHS geometry matches the one ATA disk reports.
+ * Supported functions: reset, CHS read/write, drive parameters and EDD 1.1 presence check, extended read and
+ * extended drive parameters. Classic transfers fail when buffer crosses a 64K boundary, like DMA driven ones do.
+ */
+
+use vm;
+use dma::dma_memory;
+use disk::{self, disk_image};
+use eltorito::{self, BootMedia};
+use ata::Geometry;
+use fdc;
+use config::{self, LoadConfig};
+use biosassist::CallRegs;
+
+use std::io;
+
+const SECTOR_SIZE: usize        = 512;
+
+// Drive numbers in DL
+const FLOPPY_DRIVE: u8          = 0x00;
+const HARD_DISK_DRIVE: u8       = 0x80;
+
+// Int 13h functions in AH
+const INT13_RESET: u8           = 0x00;
+const INT13_READ: u8            = 0x02;
+const INT13_WRITE: u8           = 0x03;
+const INT13_GET_PARAMS: u8      = 0x08;
+const INT13_EDD_CHECK: u8       = 0x41;
+const INT13_EXT_READ: u8        = 0x42;
+const INT13_EXT_PARAMS: u8      = 0x48;
+
+// Status codes returned in AH
+const STATUS_OK: u8             = 0x00;
+const STATUS_BAD_COMMAND: u8    = 0x01;
+const STATUS_SECTOR_NOT_FOUND: u8 = 0x04;
+const STATUS_DMA_BOUNDARY: u8   = 0x09;
+const STATUS_CONTROLLER_FAILURE: u8 = 0x20;
+
+// Classic calls move at most 128 sectors, EDD 1.1 at most 127
+const MAX_CHS_SECTORS: u8       = 128;
+const MAX_EXT_SECTORS: u16      = 127;
+
+// EDD presence check
+const EDD_CHECK_MAGIC: u16      = 0x55AA;
+const EDD_CHECK_REPLY: u16      = 0xAA55;
+const EDD_VERSION: u8           = 0x21;     // EDD 1.1
+const EDD_SUPPORT_EXT_ACCESS: u16 = 0x0001; // Functions 42h-44h, 47h, 48h
+
+// Disk address packet
+const DAP_MIN_SIZE: usize       = 0x10;
+const DAP_COUNT: usize          = 0x02;
+
+// Extended drive parameters buffer
+const EDD_PARAMS_SIZE: usize    = 0x1A;
+const EDD_PARAMS_CHS_VALID: u16 = 0x0002;
+
+// BDA fields
+const BDA_EQUIPMENT: usize      = 0x410;
+const BDA_FLOPPY_STATUS: u64    = 0x441;
+const BDA_DISK_STATUS: u64      = 0x474;
+const BDA_HARD_DISKS: usize     = 0x475;
+
+// Buffers have to be in real mode addressable memory
+const REAL_MODE_LIMIT: u64      = 0x110000;
+
+// Drive types returned by AH=08h, diskette parameter table pointed to by vector 1Eh is for 1.44M
+const FLOPPY_TYPE_120M: u8      = 0x02;
+const FLOPPY_TYPE_144M: u8      = 0x04;
+const FLOPPY_TYPE_288M: u8      = 0x05;
+const DPT_VECTOR: usize         = 0x1E;
+const DPT_SEG: u16              = 0xF000;
+const DPT_OFF: u16              = 0xEFC7;
+const DPT_144M: [u8; 11]        = [0xAF, 0x02, 0x25, 0x02, 0x12, 0x1B, 0xFF, 0x6C, 0xF6, 0x0F, 0x08];
+
+/**
+ * Disk image backing a BIOS drive
+ */
+pub struct BiosDrive
+{
+    image: Box<disk_image>,
+    geometry: Geometry,
+}
+
+impl BiosDrive
+{
+    /* Sectors reachable through LBA */
+    fn total_sectors(&self) -> u64 {
+        self.image.size() / SECTOR_SIZE as u64
+    }
+}
+
+/**
+ * Drives visible through int 13h
+ */
+pub struct BiosDisks
+{
+    floppy: Option<BiosDrive>,
+    hard_disk: Option<BiosDrive>,
+}
+
+impl BiosDisks
+{
+    /**
+     * Floppy is a 1.44M image unless it has the size of a 1.2M or 2.88M one,
+     * hard disk geometry defaults to the one ATA disk reports
+     */
+    pub fn new(floppy: Option<Box<disk_image>>, hard_disk: Option<Box<disk_image>>, hd_geometry: Option<Geometry>) -> BiosDisks {
+        BiosDisks {
+            floppy: floppy.map(|image| {
+                let geometry = floppy_geometry(image.size());
+                BiosDrive {
+                    image: image,
+                    geometry: geometry,
+                }
+            }),
+            hard_disk: hard_disk.map(|image| {
+                let geometry = hd_geometry.unwrap_or(Geometry::from_sectors(image.size() / SECTOR_SIZE as u64));
+                BiosDrive {
+                    image: image,
+                    geometry: geometry,
+                }
+            }),
+        }
+    }
+
+    /**
+     * Open images of configured floppy and hard disk
+     */
+    pub fn open(config: &config::VmConfig) -> BiosDisks {
+        let opened = |path: &String, res: io::Result<Box<disk_image>>| -> Box<disk_image> {
+            match res {
+                Ok(image) => image,
+                Err(err) => panic!("biosdisk: failed to open {}: {}", path, err),
+            }
+        };
+
+        let mut floppy = config.floppy.as_ref().map(|path| opened(path, disk::open_floppy(config, path)));
+        if let LoadConfig::Cdrom { .. } = config.load {
+            let path = config.cdrom.as_ref().unwrap();
+            let mut iso = opened(path, disk::FileImage::open_read_only(path).map(|image| Box::new(image) as Box<disk_image>));
+            let boot = match eltorito::find_boot_entry(&mut *iso) {
+                Ok(boot) => boot,
+                Err(err) => panic!("biosdisk: {}: {}", path, err),
+            };
+
+            if let BootMedia::Floppy(_) = boot.media {
+                if floppy.is_some() {
+                    warn!("biosdisk: floppy image is replaced by CD boot image as drive 00h");
+                }
+                floppy = match eltorito::floppy_image(iso, &boot) {
+                    Ok(image) => Some(Box::new(image)),
+                    Err(err) => panic!("biosdisk: {}: {}", path, err),
+                };
+            }
+        }
+
+        let hard_disk = config.hda.as_ref().map(|path| opened(path, disk::open_hda(config, path)));
+        let geometry = config.hda_chs.map(|(c, h, s)| Geometry { cylinders: c, heads: h, sectors: s });
+        BiosDisks::new(floppy, hard_disk, geometry)
+    }
+
+    fn drive(&mut self, drive: u8) -> Option<&mut BiosDrive> {
+        match drive {
+            FLOPPY_DRIVE => self.floppy.as_mut(),
+            HARD_DISK_DRIVE => self.hard_disk.as_mut(),
+            _ => None,
+        }
+    }
+}
+
+/* Standard diskette geometry by image size */
+fn floppy_geometry(size: u64) -> Geometry
+{
+    let sectors = match size / (fdc::FLOPPY_CYLINDERS as u64 * fdc::FLOPPY_HEADS as u64 * SECTOR_SIZE as u64) {
+        15 => 15,
+        36 => 36,
+        _ => fdc::FLOPPY_SECTORS,
+    };
+
+    Geometry {
+        cylinders: fdc::FLOPPY_CYLINDERS as u16,
+        heads: fdc::FLOPPY_HEADS,
+        sectors: sectors,
+    }
+}
+
+fn linear(seg: u16, off: u16) -> u64
+{
+    ((seg as u64) << 4) + off as u64
+}
+
+fn get_word(buf: &[u8], offset: usize) -> u16
+{
+    buf[offset] as u16 | (buf[offset + 1] as u16) << 8
+}
+
+fn put_word(buf: &mut [u8], offset: usize, val: u16)
+{
+    buf[offset] = val as u8;
+    buf[offset + 1] = (val >> 8) as u8;
+}
+
+fn put_dword(buf: &mut [u8], offset: usize, val: u32)
+{
+    put_word(buf, offset, val as u16);
+    put_word(buf, offset + 2, (val >> 16) as u16);
+}
+
+fn put_qword(buf: &mut [u8], offset: usize, val: u64)
+{
+    put_dword(buf, offset, val as u32);
+    put_dword(buf, offset + 4, (val >> 32) as u32);
+}
+
+/* Guest memory accesses are checked to stay within real mode reach and to complete */
+fn read_guest(mem: &dma_memory, addr: u64, buf: &mut [u8]) -> Result<(), u8>
+{
+    if addr + buf.len() as u64 > REAL_MODE_LIMIT || mem.read(addr, buf) != buf.len() {
+        return Err(STATUS_BAD_COMMAND);
+    }
+    Ok(())
+}
+
+fn write_guest(mem: &dma_memory, addr: u64, buf: &[u8]) -> Result<(), u8>
+{
+    if addr + buf.len() as u64 > REAL_MODE_LIMIT || mem.write(addr, buf) != buf.len() {
+        return Err(STATUS_BAD_COMMAND);
+    }
+    Ok(())
+}
+
+/* Move sectors between image and guest buffer */
+fn transfer(drive: &mut BiosDrive, mem: &dma_memory, lba: u64, count: usize, addr: u64, write: bool) -> Result<(), u8>
+{
+    let mut buf = vec![0u8; count * SECTOR_SIZE];
+    let offset = lba * SECTOR_SIZE as u64;
+
+    if write {
+        try!(read_guest(mem, addr, &mut buf));
+        try!(drive.image.write_at(offset, &buf).map_err(|err| {
+            warn!("biosdisk: write at LBA {} failed: {}", lba, err);
+            STATUS_CONTROLLER_FAILURE
+        }));
+    } else {
+        try!(drive.image.read_at(offset, &mut buf).map_err(|err| {
+            warn!("biosdisk: read at LBA {} failed: {}", lba, err);
+            STATUS_CONTROLLER_FAILURE
+        }));
+        try!(write_guest(mem, addr, &buf));
+    }
+
+    Ok(())
+}
+
+/* AH=02h/03h: AL sectors at CHS in CX/DH to or from ES:BX, AL returns sectors moved */
+fn chs_transfer(drive: &mut BiosDrive, mem: &dma_memory, regs: &mut CallRegs, write: bool) -> Result<u8, u8>
+{
+    let count = regs.al();
+    regs.set_al(0);
+
+    if count == 0 || count > MAX_CHS_SECTORS {
+        return Err(STATUS_BAD_COMMAND);
+    }
+
+    let cylinder = regs.ch() as u16 | ((regs.cl() as u16 & 0xC0) << 2);
+    let lba = match drive.geometry.to_lba(cylinder, regs.dh(), regs.cl() & 0x3F) {
+        Some(lba) => lba,
+        None => return Err(STATUS_SECTOR_NOT_FOUND),
+    };
+
+    if lba + count as u64 > drive.total_sectors() {
+        return Err(STATUS_SECTOR_NOT_FOUND);
+    }
+
+    /* Transfer can't wrap DMA address counter */
+    let addr = linear(regs.es, regs.bx);
+    if (addr & 0xFFFF) + (count as u64) * SECTOR_SIZE as u64 > 0x10000 {
+        return Err(STATUS_DMA_BOUNDARY);
+    }
+
+    try!(transfer(drive, mem, lba, count as usize, addr, write));
+    regs.set_al(count);
+    Ok(STATUS_OK)
+}
+
+/* AH=08h: maximum cylinder, head and sector numbers */
+fn get_params(drive: &BiosDrive, drive_num: u8, regs: &mut CallRegs) -> Result<u8, u8>
+{
+    let geometry = drive.geometry;
+    let max_cylinder = ::std::cmp::min(geometry.cylinders, 1024) - 1;
+
+    regs.ax = 0;
+    regs.cx = (max_cylinder & 0xFF) << 8 | (max_cylinder >> 2) & 0xC0 | (geometry.sectors & 0x3F) as u16;
+    regs.dx = ((geometry.heads - 1) as u16) << 8 | 1;
+
+    if drive_num == FLOPPY_DRIVE {
+        regs.bx = match geometry.sectors {
+            15 => FLOPPY_TYPE_120M,
+            36 => FLOPPY_TYPE_288M,
+            _ => FLOPPY_TYPE_144M,
+        } as u16;
+        regs.es = DPT_SEG;
+        regs.di = DPT_OFF;
+    }
+
+    Ok(STATUS_OK)
+}
+
+/* AH=42h: read sectors described by disk address packet at DS:SI */
+fn ext_read(drive: &mut BiosDrive, mem: &dma_memory, regs: &CallRegs) -> Result<u8, u8>
+{
+    let dap_addr = linear(regs.ds, regs.si);
+    let mut dap = [0u8; DAP_MIN_SIZE];
+    try!(read_guest(mem, dap_addr, &mut dap));
+
+    if (dap[0] as usize) < DAP_MIN_SIZE {
+        return Err(STATUS_BAD_COMMAND);
+    }
+
+    let count = get_word(&dap, DAP_COUNT);
+    let addr = linear(get_word(&dap, 6), get_word(&dap, 4));
+    let lba = (0..8).fold(0u64, |lba, i| lba | (dap[8 + i] as u64) << (i * 8));
+
+    /* Nothing is transferred on error */
+    let result = if count > MAX_EXT_SECTORS {
+        Err(STATUS_BAD_COMMAND)
+    } else if lba.checked_add(count as u64).map_or(true, |end| end > drive.total_sectors()) {
+        Err(STATUS_SECTOR_NOT_FOUND)
+    } else {
+        transfer(drive, mem, lba, count as usize, addr, false)
+    };
+
+    if result.is_err() {
+        try!(write_guest(mem, dap_addr + DAP_COUNT as u64, &[0, 0]));
+    }
+
+    result.map(|_| STATUS_OK)
+}
+
+/* AH=48h: fill drive parameters buffer at DS:SI */
+fn ext_params(drive: &BiosDrive, mem: &dma_memory, regs: &CallRegs) -> Result<u8, u8>
+{
+    let addr = linear(regs.ds, regs.si);
+    let mut size = [0u8; 2];
+    try!(read_guest(mem, addr, &mut size));
+    if (get_word(&size, 0) as usize) < EDD_PARAMS_SIZE {
+        return Err(STATUS_BAD_COMMAND);
+    }
+
+    let mut params = [0u8; EDD_PARAMS_SIZE];
+    put_word(&mut params, 0x00, EDD_PARAMS_SIZE as u16);
+    put_word(&mut params, 0x02, EDD_PARAMS_CHS_VALID);
+    put_dword(&mut params, 0x04, drive.geometry.cylinders as u32);
+    put_dword(&mut params, 0x08, drive.geometry.heads as u32);
+    put_dword(&mut params, 0x0C, drive.geometry.sectors as u32);
+    put_qword(&mut params, 0x10, drive.total_sectors());
+    put_word(&mut params, 0x18, SECTOR_SIZE as u16);
+
+    try!(write_guest(mem, addr, &params));
+    Ok(STATUS_OK)
+}
+
+/**
+ * Perform int 13h call on guest registers.
+ * Status goes to AH and CF, and to BDA as last operation status of the drive type.
+ */
+pub fn int13(disks: &mut BiosDisks, mem: &dma_memory, regs: &mut CallRegs)
+{
+    let func = regs.ah();
+    let drive_num = regs.dl();
+    let is_hard_disk = (drive_num & HARD_DISK_DRIVE) != 0;
+
+    let result = match disks.drive(drive_num) {
+        None => Err(STATUS_BAD_COMMAND),
+        Some(drive) => match func {
+            INT13_RESET => Ok(STATUS_OK),
+            INT13_READ => chs_transfer(drive, mem, regs, false),
+            INT13_WRITE => chs_transfer(drive, mem, regs, true),
+            INT13_GET_PARAMS => get_params(drive, drive_num, regs),
+
+            /* Extensions are only there for hard disks */
+            INT13_EDD_CHECK if is_hard_disk && regs.bx == EDD_CHECK_MAGIC => {
+                regs.bx = EDD_CHECK_REPLY;
+                regs.cx = EDD_SUPPORT_EXT_ACCESS;
+                Ok(EDD_VERSION)
+            },
+            INT13_EXT_READ if is_hard_disk => ext_read(drive, mem, regs),
+            INT13_EXT_PARAMS if is_hard_disk => ext_params(drive, mem, regs),
+
+            _ => {
+                debug!("biosdisk: unsupported int 13h function {:x} for drive {:x}", func, drive_num);
+                Err(STATUS_BAD_COMMAND)
+            },
+        },
+    };
+
+    let status = match result {
+        Ok(ah) => {
+            regs.set_ah(ah);
+            regs.set_carry(false);
+            STATUS_OK
+        },
+        Err(status) => {
+            regs.set_ah(status);
+            regs.set_carry(true);
+            status
+        },
+    };
+
+    let bda_status = if is_hard_disk { BDA_DISK_STATUS } else { BDA_FLOPPY_STATUS };
+    mem.write(bda_status, &[status]);
+}
+
+/**
+ * Set up diskette parameter table and hard disk count in BDA
+ */
+pub fn install(ram: &vm::memory_region, disks: &BiosDisks)
+{
+    ram.write_bytes(linear(DPT_SEG, DPT_OFF) as usize, &DPT_144M);
+    ram.write_bytes(DPT_VECTOR * 4, &[DPT_OFF as u8, (DPT_OFF >> 8) as u8, DPT_SEG as u8, (DPT_SEG >> 8) as u8]);
+    ram.write_bytes(BDA_HARD_DISKS, &[if disks.hard_disk.is_some() { 1 } else { 0 }]);
+
+    /* Emulated boot diskette may be the only floppy drive */
+    if disks.floppy.is_some() {
+        let mut equipment = [0u8; 1];
+        ram.read_bytes(BDA_EQUIPMENT, &mut equipment);
+        ram.write_bytes(BDA_EQUIPMENT, &[equipment[0] | 0x01]);
+    }
+}
+
+#[cfg(test)]
+mod biosdisk_test
+{
+    use super::*;
+    use dma::TestMemory;
+
+    /* First megabyte of guest memory */
+    fn make_mem() -> TestMemory {
+        TestMemory::new(0x100000, 0xCC)
+    }
+
+    /* Every sector is filled with its LBA */
+    fn make_image(sectors: usize) -> disk::MemImage {
+        let mut image = disk::MemImage::new(sectors * SECTOR_SIZE);
+        for (i, sector) in image.data.chunks_mut(SECTOR_SIZE).enumerate() {
+            for (j, b) in sector.iter_mut().enumerate() {
+                *b = if j % 2 == 0 { i as u8 } else { (i >> 8) as u8 };
+            }
+        }
+        image
+    }
+
+    fn make_disks() -> BiosDisks {
+        let floppy = make_image(fdc::FLOPPY_IMAGE_SIZE as usize / SECTOR_SIZE);
+        BiosDisks::new(Some(Box::new(floppy)), Some(Box::new(make_image(16 * 63 * 20))), None)
+    }
+
+    fn sector_at(mem: &TestMemory, addr: usize) -> Vec<u8> {
+        mem.mem.borrow()[addr..addr + SECTOR_SIZE].to_vec()
+    }
+
+    fn image_sector(disks: &mut BiosDisks, drive: u8, lba: u64) -> Vec<u8> {
+        let mut buf = vec![0u8; SECTOR_SIZE];
+        disks.drive(drive).unwrap().image.read_at(lba * SECTOR_SIZE as u64, &mut buf).unwrap();
+        buf
+    }
+
+    #[test] fn ext_read_dap() {
+        let mut disks = make_disks();
+        let mem = make_mem();
+
+        /* EDD is there for hard disk */
+        let mut regs = CallRegs { ax: 0x4100, bx: 0x55AA, dx: 0x0080, ..Default::default() };
+        int13(&mut disks, &mem, &mut regs);
+        assert!(!regs.carry() && regs.ah() == 0x21 && regs.bx == 0xAA55 && regs.cx & 1 == 1);
+
+        /* Read 3 sectors from LBA 1000 to 2000:0100 with packet at 0000:0600 */
+        let dap = [0x10, 0, 3, 0, 0x00, 0x01, 0x00, 0x20, 0xE8, 0x03, 0, 0, 0, 0, 0, 0];
+        mem.write(0x600, &dap);
+        let mut regs = CallRegs { ax: 0x4200, dx: 0x0080, si: 0x600, flags: 0x0203, ..Default::default() };
+        int13(&mut disks, &mem, &mut regs);
+        assert!(!regs.carry() && regs.ah() == 0);
+        assert!(regs.flags == 0x0202);
+
+        for i in 0..3 {
+            assert!(sector_at(&mem, 0x20100 + i * SECTOR_SIZE) == image_sector(&mut disks, 0x80, 1000 + i as u64));
+        }
+        assert!(mem.mem.borrow()[0x20100 + 3 * SECTOR_SIZE] == 0xCC);
+
+        /* Past the end of disk fails with nothing transferred */
+        let dap = [0x10, 0, 2, 0, 0x00, 0x00, 0x00, 0x30, 0xBF, 0x4E, 0, 0, 0, 0, 0, 0];
+        mem.write(0x600, &dap);
+        let mut regs = CallRegs { ax: 0x4200, dx: 0x0080, si: 0x600, ..Default::default() };
+        int13(&mut disks, &mem, &mut regs);
+        assert!(regs.carry() && regs.ah() == 0x04);
+        assert!(mem.mem.borrow()[0x602] == 0 && mem.mem.borrow()[0x30000] == 0xCC);
+        assert!(mem.mem.borrow()[0x474] == 0x04);
+
+        /* Buffer beyond real mode memory */
+        let dap = [0x10, 0, 1, 0, 0xF0, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0, 0, 0, 0, 0];
+        mem.write(0x600, &dap);
+        let mut regs = CallRegs { ax: 0x4200, dx: 0x0080, si: 0x600, ..Default::default() };
+        int13(&mut disks, &mem, &mut regs);
+        assert!(regs.carry());
+
+        /* No extensions for floppies */
+        let mut regs = CallRegs { ax: 0x4100, bx: 0x55AA, dx: 0x0000, ..Default::default() };
+        int13(&mut disks, &mem, &mut regs);
+        assert!(regs.carry() && regs.ah() == 0x01);
+    }
+
+    #[test] fn chs_read_write() {
+        let mut disks = make_disks();
+        let mem = make_mem();
+
+        /* Floppy C=1 H=1 S=3 is LBA 56, two sectors to 0000:7E00 */
+        let mut regs = CallRegs { ax: 0x0202, cx: 0x0103, dx: 0x0100, bx: 0x7E00, ..Default::default() };
+        int13(&mut disks, &mem, &mut regs);
+        assert!(!regs.carry() && regs.ax == 0x0002);
+        assert!(sector_at(&mem, 0x7E00) == image_sector(&mut disks, 0, 56));
+        assert!(sector_at(&mem, 0x8000) == image_sector(&mut disks, 0, 57));
+
+        /* Buffer crossing 64K boundary is refused */
+        let mut regs = CallRegs { ax: 0x0202, cx: 0x0001, bx: 0xFE00, es: 0x1000, ..Default::default() };
+        int13(&mut disks, &mem, &mut regs);
+        assert!(regs.carry() && regs.ax == 0x0900);
+        assert!(mem.mem.borrow()[0x1FE00] == 0xCC);
+        assert!(mem.mem.borrow()[0x441] == 0x09);
+
+        /* Sectors outside geometry */
+        let mut regs = CallRegs { ax: 0x0201, cx: 0x0013, ..Default::default() };
+        int13(&mut disks, &mem, &mut regs);
+        assert!(regs.carry() && regs.ah() == 0x04);
+        let mut regs = CallRegs { ax: 0x0201, cx: 0x5001, ..Default::default() };
+        int13(&mut disks, &mem, &mut regs);
+        assert!(regs.carry() && regs.ah() == 0x04);
+        let mut regs = CallRegs { ax: 0x0200, cx: 0x0001, ..Default::default() };
+        int13(&mut disks, &mem, &mut regs);
+        assert!(regs.carry() && regs.ah() == 0x01);
+
+        /* Hard disk write at C=2 H=3 S=4 lands at LBA (2 * 16 + 3) * 63 + 3 */
+        mem.write(0x9000, &[0x5A; SECTOR_SIZE]);
+        let mut regs = CallRegs { ax: 0x0301, cx: 0x0204, dx: 0x0380, bx: 0x9000, ..Default::default() };
+        int13(&mut disks, &mem, &mut regs);
+        assert!(!regs.carry() && regs.ax == 0x0001);
+        assert!(image_sector(&mut disks, 0x80, 2208) == vec![0x5A; SECTOR_SIZE]);
+
+        /* No drive 81h */
+        let mut regs = CallRegs { ax: 0x0000, dx: 0x0081, ..Default::default() };
+        int13(&mut disks, &mem, &mut regs);
+        assert!(regs.carry() && regs.ah() == 0x01);
+    }
+
+    #[test] fn drive_params() {
+        let mut disks = make_disks();
+        let mem = make_mem();
+
+        let mut regs = CallRegs { ax: 0x0800, dx: 0x0080, ..Default::default() };
+        int13(&mut disks, &mem, &mut regs);
+        assert!(!regs.carry() && regs.ah() == 0);
+        assert!(regs.cx == 0x133F && regs.dx == 0x0F01);
+
+        let mut regs = CallRegs { ax: 0x0800, dx: 0x0000, ..Default::default() };
+        int13(&mut disks, &mem, &mut regs);
+        assert!(!regs.carry() && regs.bl() == 0x04);
+        assert!(regs.cx == 0x4F12 && regs.dx == 0x0101);
+        assert!(regs.es == 0xF000 && regs.di == 0xEFC7);
+
+        /* Large disk reports at most 1024 cylinders through CHS */
+        let mut big = BiosDisks::new(None, Some(Box::new(disk::MemImage::new(16 * 63 * 2000 * SECTOR_SIZE))), None);
+        let mut regs = CallRegs { ax: 0x0800, dx: 0x0080, ..Default::default() };
+        int13(&mut big, &mem, &mut regs);
+        assert!(regs.cx == 0xFFFF);
+
+        /* El Torito 2.88M boot diskette */
+        let mut cd = BiosDisks::new(Some(Box::new(disk::MemImage::new(2949120))), None, None);
+        let mut regs = CallRegs { ax: 0x0800, dx: 0x0000, ..Default::default() };
+        int13(&mut cd, &mem, &mut regs);
+        assert!(regs.bl() == 0x05 && regs.cx == 0x4F24 && regs.dx == 0x0101);
+
+        /* Extended parameters need a big enough buffer */
+        mem.write(0x700, &[0x1A, 0]);
+        let mut regs = CallRegs { ax: 0x4800, dx: 0x0080, si: 0x700, ..Default::default() };
+        int13(&mut disks, &mem, &mut regs);
+        assert!(!regs.carry());
+        let params = mem.mem.borrow()[0x700..0x71A].to_vec();
+        assert!(params[0x04] == 20 && params[0x08] == 16 && params[0x0C] == 63);
+        assert!(get_word(&params, 0x10) == 20160 && params[0x12] == 0);
+        assert!(get_word(&params, 0x18) == 512);
+
+        mem.write(0x700, &[0x10, 0]);
+        let mut regs = CallRegs { ax: 0x4800, dx: 0x0080, si: 0x700, ..Default::default() };
+        int13(&mut disks, &mem, &mut regs);
+        assert!(regs.carry() && regs.ah() == 0x01);
+    }
+}
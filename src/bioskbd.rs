@@ -0,0 +1,586 @@
+/*
+ * Int 16h keyboard services handled by VMM for test images running without a BIOS
+ *
+ * There is no INT 9 handler, so scancodes waiting in i8042 are taken by VMM on every int 16h call and
+ * processed the way INT 9 would: shift state is tracked in BDA at 0x417 and keystrokes go to the BDA
+ * circular buffer at 0x41E as scancode and ASCII pairs. Services read that buffer through its head and
+ * tail pointers, so guests stuffing or flushing it directly stay in sync.
+ * Scancodes are set 1, i.e. with i8042 translation enabled as it is by default.
+ */
+
+use vm;
+
+// BDA keyboard fields
+const BDA_SEGMENT_BASE: usize   = 0x400;
+const BDA_KBD_FLAGS1: usize     = 0x417;
+const BDA_KBD_FLAGS2: usize     = 0x418;
+const BDA_KBD_HEAD: usize       = 0x41A;
+const BDA_KBD_TAIL: usize       = 0x41C;
+const BDA_KBD_START: usize      = 0x480;
+const BDA_KBD_END: usize        = 0x482;
+const BDA_KBD_FLAGS3: usize     = 0x496;
+
+// Default buffer bounds, offsets in segment 40h
+const KBD_BUFFER_START: u16     = 0x1E;
+const KBD_BUFFER_END: u16       = 0x3E;
+
+// Flags at 0x417
+const FLAGS1_RSHIFT: u8         = 0x01;
+const FLAGS1_LSHIFT: u8         = 0x02;
+const FLAGS1_CTRL: u8           = 0x04;
+const FLAGS1_ALT: u8            = 0x08;
+const FLAGS1_SCROLL_LOCK: u8    = 0x10;
+const FLAGS1_NUM_LOCK: u8       = 0x20;
+const FLAGS1_CAPS_LOCK: u8      = 0x40;
+const FLAGS1_INSERT: u8         = 0x80;
+
+// Flags at 0x418, lock bits are set while the key is held
+const FLAGS2_LCTRL: u8          = 0x01;
+const FLAGS2_LALT: u8           = 0x02;
+const FLAGS2_SCROLL_DOWN: u8    = 0x10;
+const FLAGS2_NUM_DOWN: u8       = 0x20;
+const FLAGS2_CAPS_DOWN: u8      = 0x40;
+const FLAGS2_INSERT_DOWN: u8    = 0x80;
+
+// Flags at 0x496
+const FLAGS3_LAST_E0: u8        = 0x02;
+const FLAGS3_RCTRL: u8          = 0x04;
+const FLAGS3_RALT: u8           = 0x08;
+
+// Set 1 scancodes
+const SC_EXTENDED: u8           = 0xE0;
+const SC_BREAK: u8              = 0x80;
+const SC_CTRL: u8               = 0x1D;
+const SC_LSHIFT: u8             = 0x2A;
+const SC_RSHIFT: u8             = 0x36;
+const SC_ALT: u8                = 0x38;
+const SC_CAPS_LOCK: u8          = 0x3A;
+const SC_NUM_LOCK: u8           = 0x45;
+const SC_SCROLL_LOCK: u8        = 0x46;
+const SC_KEYPAD_HOME: u8        = 0x47;
+const SC_KEYPAD_DEL: u8         = 0x53;
+const SC_KEYPAD_MINUS: u8       = 0x4A;
+const SC_KEYPAD_5: u8           = 0x4C;
+const SC_KEYPAD_PLUS: u8        = 0x4E;
+const SC_INSERT: u8             = 0x52;
+const SC_ENTER: u8              = 0x1C;
+const SC_SLASH: u8              = 0x35;
+
+// Keys from 101-key keyboard with no 84-key equivalent have scancodes above this
+const COMPAT_MAX_SCAN: u8       = 0x84;
+const EXTENDED_ASCII: u8        = 0xE0;
+
+// Int 16h functions in AH
+const INT16_READ: u8            = 0x00;
+const INT16_CHECK: u8           = 0x01;
+const INT16_SHIFT_FLAGS: u8     = 0x02;
+const INT16_EXT_READ: u8        = 0x10;
+const INT16_EXT_CHECK: u8       = 0x11;
+
+/*
+ * Keystrokes for set 1 make codes: normal, shift, ctrl and alt, 0 if there is none.
+ * Keypad 47h-53h produces shifted column with Num Lock on.
+ */
+const KEY_TABLE: [[u16; 4]; 0x59] = [
+    [0x0000, 0x0000, 0x0000, 0x0000],   // 00
+    [0x011B, 0x011B, 0x011B, 0x0100],   // 01 Esc
+    [0x0231, 0x0221, 0x0000, 0x7800],   // 02 1
+    [0x0332, 0x0340, 0x0300, 0x7900],   // 03 2
+    [0x0433, 0x0423, 0x0000, 0x7A00],   // 04 3
+    [0x0534, 0x0524, 0x0000, 0x7B00],   // 05 4
+    [0x0635, 0x0625, 0x0000, 0x7C00],   // 06 5
+    [0x0736, 0x075E, 0x071E, 0x7D00],   // 07 6
+    [0x0837, 0x0826, 0x0000, 0x7E00],   // 08 7
+    [0x0938, 0x092A, 0x0000, 0x7F00],   // 09 8
+    [0x0A39, 0x0A28, 0x0000, 0x8000],   // 0A 9
+    [0x0B30, 0x0B29, 0x0000, 0x8100],   // 0B 0
+    [0x0C2D, 0x0C5F, 0x0C1F, 0x8200],   // 0C -
+    [0x0D3D, 0x0D2B, 0x0000, 0x8300],   // 0D =
+    [0x0E08, 0x0E08, 0x0E7F, 0x0E00],   // 0E Backspace
+    [0x0F09, 0x0F00, 0x9400, 0xA500],   // 0F Tab
+    [0x1071, 0x1051, 0x1011, 0x1000],   // 10 q
+    [0x1177, 0x1157, 0x1117, 0x1100],   // 11 w
+    [0x1265, 0x1245, 0x1205, 0x1200],   // 12 e
+    [0x1372, 0x1352, 0x1312, 0x1300],   // 13 r
+    [0x1474, 0x1454, 0x1414, 0x1400],   // 14 t
+    [0x1579, 0x1559, 0x1519, 0x1500],   // 15 y
+    [0x1675, 0x1655, 0x1615, 0x1600],   // 16 u
+    [0x1769, 0x1749, 0x1709, 0x1700],   // 17 i
+    [0x186F, 0x184F, 0x180F, 0x1800],   // 18 o
+    [0x1970, 0x1950, 0x1910, 0x1900],   // 19 p
+    [0x1A5B, 0x1A7B, 0x1A1B, 0x1A00],   // 1A [
+    [0x1B5D, 0x1B7D, 0x1B1D, 0x1B00],   // 1B ]
+    [0x1C0D, 0x1C0D, 0x1C0A, 0x1C00],   // 1C Enter
+    [0x0000, 0x0000, 0x0000, 0x0000],   // 1D Ctrl
+    [0x1E61, 0x1E41, 0x1E01, 0x1E00],   // 1E a
+    [0x1F73, 0x1F53, 0x1F13, 0x1F00],   // 1F s
+    [0x2064, 0x2044, 0x2004, 0x2000],   // 20 d
+    [0x2166, 0x2146, 0x2106, 0x2100],   // 21 f
+    [0x2267, 0x2247, 0x2207, 0x2200],   // 22 g
+    [0x2368, 0x2348, 0x2308, 0x2300],   // 23 h
+    [0x246A, 0x244A, 0x240A, 0x2400],   // 24 j
+    [0x256B, 0x254B, 0x250B, 0x2500],   // 25 k
+    [0x266C, 0x264C, 0x260C, 0x2600],   // 26 l
+    [0x273B, 0x273A, 0x0000, 0x2700],   // 27 ;
+    [0x2827, 0x2822, 0x0000, 0x2800],   // 28 '
+    [0x2960, 0x297E, 0x0000, 0x2900],   // 29 `
+    [0x0000, 0x0000, 0x0000, 0x0000],   // 2A Left Shift
+    [0x2B5C, 0x2B7C, 0x2B1C, 0x2B00],   // 2B backslash
+    [0x2C7A, 0x2C5A, 0x2C1A, 0x2C00],   // 2C z
+    [0x2D78, 0x2D58, 0x2D18, 0x2D00],   // 2D x
+    [0x2E63, 0x2E43, 0x2E03, 0x2E00],   // 2E c
+    [0x2F76, 0x2F56, 0x2F16, 0x2F00],   // 2F v
+    [0x3062, 0x3042, 0x3002, 0x3000],   // 30 b
+    [0x316E, 0x314E, 0x310E, 0x3100],   // 31 n
+    [0x326D, 0x324D, 0x320D, 0x3200],   // 32 m
+    [0x332C, 0x333C, 0x0000, 0x3300],   // 33 ,
+    [0x342E, 0x343E, 0x0000, 0x3400],   // 34 .
+    [0x352F, 0x353F, 0x0000, 0x3500],   // 35 /
+    [0x0000, 0x0000, 0x0000, 0x0000],   // 36 Right Shift
+    [0x372A, 0x372A, 0x9600, 0x3700],   // 37 Keypad *
+    [0x0000, 0x0000, 0x0000, 0x0000],   // 38 Alt
+    [0x3920, 0x3920, 0x3920, 0x3920],   // 39 Space
+    [0x0000, 0x0000, 0x0000, 0x0000],   // 3A Caps Lock
+    [0x3B00, 0x5400, 0x5E00, 0x6800],   // 3B F1
+    [0x3C00, 0x5500, 0x5F00, 0x6900],   // 3C F2
+    [0x3D00, 0x5600, 0x6000, 0x6A00],   // 3D F3
+    [0x3E00, 0x5700, 0x6100, 0x6B00],   // 3E F4
+    [0x3F00, 0x5800, 0x6200, 0x6C00],   // 3F F5
+    [0x4000, 0x5900, 0x6300, 0x6D00],   // 40 F6
+    [0x4100, 0x5A00, 0x6400, 0x6E00],   // 41 F7
+    [0x4200, 0x5B00, 0x6500, 0x6F00],   // 42 F8
+    [0x4300, 0x5C00, 0x6600, 0x7000],   // 43 F9
+    [0x4400, 0x5D00, 0x6700, 0x7100],   // 44 F10
+    [0x0000, 0x0000, 0x0000, 0x0000],   // 45 Num Lock
+    [0x0000, 0x0000, 0x0000, 0x0000],   // 46 Scroll Lock
+    [0x4700, 0x4737, 0x7700, 0x0000],   // 47 Keypad Home
+    [0x4800, 0x4838, 0x8D00, 0x0000],   // 48 Keypad Up
+    [0x4900, 0x4939, 0x8400, 0x0000],   // 49 Keypad PgUp
+    [0x4A2D, 0x4A2D, 0x8E00, 0x4A00],   // 4A Keypad -
+    [0x4B00, 0x4B34, 0x7300, 0x0000],   // 4B Keypad Left
+    [0x4C00, 0x4C35, 0x8F00, 0x0000],   // 4C Keypad 5
+    [0x4D00, 0x4D36, 0x7400, 0x0000],   // 4D Keypad Right
+    [0x4E2B, 0x4E2B, 0x9000, 0x4E00],   // 4E Keypad +
+    [0x4F00, 0x4F31, 0x7500, 0x0000],   // 4F Keypad End
+    [0x5000, 0x5032, 0x9100, 0x0000],   // 50 Keypad Down
+    [0x5100, 0x5133, 0x7600, 0x0000],   // 51 Keypad PgDn
+    [0x5200, 0x5230, 0x9200, 0x0000],   // 52 Keypad Ins
+    [0x5300, 0x532E, 0x9300, 0x0000],   // 53 Keypad Del
+    [0x0000, 0x0000, 0x0000, 0x0000],   // 54 SysRq
+    [0x0000, 0x0000, 0x0000, 0x0000],   // 55
+    [0x565C, 0x567C, 0x0000, 0x0000],   // 56 102nd key
+    [0x8500, 0x8700, 0x8900, 0x8B00],   // 57 F11
+    [0x8600, 0x8800, 0x8A00, 0x8C00],   // 58 F12
+];
+
+fn read_byte(ram: &vm::memory_region, addr: usize) -> u8
+{
+    let mut buf = [0u8; 1];
+    ram.read_bytes(addr, &mut buf);
+    buf[0]
+}
+
+fn write_byte(ram: &vm::memory_region, addr: usize, val: u8)
+{
+    ram.write_bytes(addr, &[val]);
+}
+
+fn read_word(ram: &vm::memory_region, addr: usize) -> u16
+{
+    let mut buf = [0u8; 2];
+    ram.read_bytes(addr, &mut buf);
+    buf[0] as u16 | (buf[1] as u16) << 8
+}
+
+fn write_word(ram: &vm::memory_region, addr: usize, val: u16)
+{
+    ram.write_bytes(addr, &[val as u8, (val >> 8) as u8]);
+}
+
+/* Buffer bounds set in BDA, defaults if they make no sense */
+fn buffer_bounds(ram: &vm::memory_region) -> (u16, u16)
+{
+    let start = read_word(ram, BDA_KBD_START);
+    let end = read_word(ram, BDA_KBD_END);
+    if start < end && (end - start) % 2 == 0 {
+        (start, end)
+    } else {
+        (KBD_BUFFER_START, KBD_BUFFER_END)
+    }
+}
+
+fn next_slot(ptr: u16, start: u16, end: u16) -> u16
+{
+    if ptr + 2 >= end { start } else { ptr + 2 }
+}
+
+/* Append keystroke to BDA buffer, dropped if buffer is full */
+fn push_key(ram: &vm::memory_region, key: u16)
+{
+    let (start, end) = buffer_bounds(ram);
+    let head = read_word(ram, BDA_KBD_HEAD);
+    let tail = read_word(ram, BDA_KBD_TAIL);
+    let next = next_slot(tail, start, end);
+
+    if next == head {
+        debug!("bioskbd: buffer full, dropping key {:04x}", key);
+        return;
+    }
+
+    write_word(ram, BDA_SEGMENT_BASE + tail as usize, key);
+    write_word(ram, BDA_KBD_TAIL, next);
+}
+
+fn peek_key(ram: &vm::memory_region) -> Option<u16>
+{
+    let head = read_word(ram, BDA_KBD_HEAD);
+    if head == read_word(ram, BDA_KBD_TAIL) {
+        return None;
+    }
+
+    Some(read_word(ram, BDA_SEGMENT_BASE + head as usize))
+}
+
+fn pop_key(ram: &vm::memory_region) -> Option<u16>
+{
+    let key = peek_key(ram);
+    if key.is_some() {
+        let (start, end) = buffer_bounds(ram);
+        let head = read_word(ram, BDA_KBD_HEAD);
+        write_word(ram, BDA_KBD_HEAD, next_slot(head, start, end));
+    }
+    key
+}
+
+/* Keystroke for extended (E0 prefixed) make code */
+fn extended_key(code: u8, flags1: u8) -> Option<u16>
+{
+    let ctrl = (flags1 & FLAGS1_CTRL) != 0;
+    let alt = (flags1 & FLAGS1_ALT) != 0;
+
+    match code {
+        SC_ENTER => Some(if alt { 0xA600 } else if ctrl { 0xE00A } else { 0xE00D }),
+        SC_SLASH => Some(if alt { 0xA400 } else if ctrl { 0x9500 } else { 0xE02F }),
+
+        /* Grey navigation keys report E0 instead of ASCII so software can tell them from keypad */
+        SC_KEYPAD_HOME...SC_KEYPAD_DEL if code != SC_KEYPAD_MINUS && code != SC_KEYPAD_PLUS && code != SC_KEYPAD_5 => {
+            Some(if alt {
+                (code as u16 + 0x50) << 8
+            } else if ctrl {
+                (KEY_TABLE[code as usize][2] & 0xFF00) | EXTENDED_ASCII as u16
+            } else {
+                ((code as u16) << 8) | EXTENDED_ASCII as u16
+            })
+        },
+
+        _ => None,
+    }
+}
+
+/* Keystroke for make code given shift state */
+fn key(code: u8, flags1: u8) -> Option<u16>
+{
+    let entry = match KEY_TABLE.get(code as usize) {
+        Some(entry) => entry,
+        None => return None,
+    };
+
+    let shift = (flags1 & (FLAGS1_LSHIFT | FLAGS1_RSHIFT)) != 0;
+    let key = if (flags1 & FLAGS1_ALT) != 0 {
+        entry[3]
+    } else if (flags1 & FLAGS1_CTRL) != 0 {
+        entry[2]
+    } else if code >= SC_KEYPAD_HOME && code <= SC_KEYPAD_DEL {
+        entry[if shift != ((flags1 & FLAGS1_NUM_LOCK) != 0) { 1 } else { 0 }]
+    } else {
+        let ascii = entry[0] as u8;
+        let letter = ascii >= b'a' && ascii <= b'z';
+        entry[if shift != (letter && (flags1 & FLAGS1_CAPS_LOCK) != 0) { 1 } else { 0 }]
+    };
+
+    if key == 0 { None } else { Some(key) }
+}
+
+/* Update modifier state for Shift, Ctrl, Alt and lock keys, false if code is not one of them */
+fn modifier(ram: &vm::memory_region, code: u8, extended: bool, pressed: bool) -> bool
+{
+    let mut flags1 = read_byte(ram, BDA_KBD_FLAGS1);
+    let mut flags2 = read_byte(ram, BDA_KBD_FLAGS2);
+    let mut flags3 = read_byte(ram, BDA_KBD_FLAGS3);
+
+    let set = |flags: &mut u8, bit: u8| if pressed { *flags |= bit } else { *flags &= !bit };
+
+    /* Lock keys toggle once per press, not on typematic repeats */
+    let toggle = |flags1: &mut u8, flags2: &mut u8, lock: u8, down: u8| {
+        if pressed && (*flags2 & down) == 0 {
+            *flags1 ^= lock;
+        }
+        if pressed { *flags2 |= down } else { *flags2 &= !down }
+    };
+
+    match (code, extended) {
+        /* Extended shifts are sent around grey keys to undo shift state, they mean nothing */
+        (SC_LSHIFT, true) | (SC_RSHIFT, true) => {},
+        (SC_LSHIFT, false) => set(&mut flags1, FLAGS1_LSHIFT),
+        (SC_RSHIFT, false) => set(&mut flags1, FLAGS1_RSHIFT),
+        (SC_CTRL, false) => set(&mut flags2, FLAGS2_LCTRL),
+        (SC_CTRL, true) => set(&mut flags3, FLAGS3_RCTRL),
+        (SC_ALT, false) => set(&mut flags2, FLAGS2_LALT),
+        (SC_ALT, true) => set(&mut flags3, FLAGS3_RALT),
+        (SC_CAPS_LOCK, false) => toggle(&mut flags1, &mut flags2, FLAGS1_CAPS_LOCK, FLAGS2_CAPS_DOWN),
+        (SC_NUM_LOCK, false) => toggle(&mut flags1, &mut flags2, FLAGS1_NUM_LOCK, FLAGS2_NUM_DOWN),
+        (SC_SCROLL_LOCK, false) => toggle(&mut flags1, &mut flags2, FLAGS1_SCROLL_LOCK, FLAGS2_SCROLL_DOWN),
+        _ => return false,
+    }
+
+    flags1 &= !(FLAGS1_CTRL | FLAGS1_ALT);
+    if (flags2 & FLAGS2_LCTRL) != 0 || (flags3 & FLAGS3_RCTRL) != 0 {
+        flags1 |= FLAGS1_CTRL;
+    }
+    if (flags2 & FLAGS2_LALT) != 0 || (flags3 & FLAGS3_RALT) != 0 {
+        flags1 |= FLAGS1_ALT;
+    }
+
+    write_byte(ram, BDA_KBD_FLAGS1, flags1);
+    write_byte(ram, BDA_KBD_FLAGS2, flags2);
+    write_byte(ram, BDA_KBD_FLAGS3, flags3);
+    true
+}
+
+/**
+ * Process set 1 scancode byte the way INT 9 handler does.
+ * RAM is mapped at guest physical 0 and covers BDA.
+ */
+pub fn scancode(ram: &vm::memory_region, code: u8)
+{
+    let flags3 = read_byte(ram, BDA_KBD_FLAGS3);
+
+    if code == SC_EXTENDED {
+        write_byte(ram, BDA_KBD_FLAGS3, flags3 | FLAGS3_LAST_E0);
+        return;
+    }
+
+    let extended = (flags3 & FLAGS3_LAST_E0) != 0;
+    write_byte(ram, BDA_KBD_FLAGS3, flags3 & !FLAGS3_LAST_E0);
+
+    let pressed = (code & SC_BREAK) == 0;
+    let make = code & !SC_BREAK;
+
+    if modifier(ram, make, extended, pressed) {
+        return;
+    }
+
+    if make == SC_INSERT {
+        let mut flags2 = read_byte(ram, BDA_KBD_FLAGS2);
+        let mut flags1 = read_byte(ram, BDA_KBD_FLAGS1);
+        if pressed && (flags2 & FLAGS2_INSERT_DOWN) == 0 {
+            flags1 ^= FLAGS1_INSERT;
+        }
+        if pressed { flags2 |= FLAGS2_INSERT_DOWN } else { flags2 &= !FLAGS2_INSERT_DOWN }
+        write_byte(ram, BDA_KBD_FLAGS1, flags1);
+        write_byte(ram, BDA_KBD_FLAGS2, flags2);
+    }
+
+    if !pressed {
+        return;
+    }
+
+    let flags1 = read_byte(ram, BDA_KBD_FLAGS1);
+    let keystroke = if extended { extended_key(make, flags1) } else { key(make, flags1) };
+    match keystroke {
+        Some(keystroke) => push_key(ram, keystroke),
+        None => debug!("bioskbd: no keystroke for scancode {}{:02x}", if extended { "e0 " } else { "" }, make),
+    }
+}
+
+/*
+ * Keystroke as reported by functions for 84-key keyboards.
+ * Grey keys look like their keypad counterparts, keys that 84-key keyboard doesn't have are None.
+ */
+fn compat_key(key: u16) -> Option<u16>
+{
+    let (scan, ascii) = ((key >> 8) as u8, key as u8);
+
+    if scan == EXTENDED_ASCII {
+        let scan = if ascii == b'/' { SC_SLASH } else { SC_ENTER };
+        return Some(((scan as u16) << 8) | ascii as u16);
+    }
+
+    if scan > COMPAT_MAX_SCAN {
+        return None;
+    }
+
+    if ascii == EXTENDED_ASCII && scan != 0 {
+        return Some(key & 0xFF00);
+    }
+
+    Some(key)
+}
+
+/* Next keystroke for check functions, keys unknown to 84-key functions are thrown away */
+fn next_key(ram: &vm::memory_region, compat: bool) -> Option<u16>
+{
+    loop {
+        let key = match peek_key(ram) {
+            Some(key) => key,
+            None => return None,
+        };
+
+        if !compat {
+            return Some(key);
+        }
+
+        match compat_key(key) {
+            Some(key) => return Some(key),
+            None => { pop_key(ram); },
+        }
+    }
+}
+
+/**
+ * Perform int 16h call on guest registers.
+ * Returns false if a blocking read found no keystroke, guest should then retry the call later.
+ */
+pub fn int16(ram: &vm::memory_region, regs: &mut ::biosassist::CallRegs) -> bool
+{
+    match regs.ah() {
+        INT16_READ | INT16_EXT_READ => {
+            match next_key(ram, regs.ah() == INT16_READ) {
+                Some(key) => {
+                    pop_key(ram);
+                    regs.ax = key;
+                },
+                None => return false,
+            }
+        },
+
+        INT16_CHECK | INT16_EXT_CHECK => {
+            match next_key(ram, regs.ah() == INT16_CHECK) {
+                Some(key) => {
+                    regs.ax = key;
+                    regs.set_zero(false);
+                },
+                None => regs.set_zero(true),
+            }
+        },
+
+        INT16_SHIFT_FLAGS => regs.set_al(read_byte(ram, BDA_KBD_FLAGS1)),
+
+        func => debug!("bioskbd: unsupported int 16h function {:x}", func),
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod bioskbd_test
+{
+    use super::*;
+    use biosassist::CallRegs;
+
+    fn make_ram() -> ::std::sync::Arc<vm::memory_region> {
+        let ram = vm::alloc_memory_region(0x100000);
+        ram.write_bytes(0, &vec![0u8; 0x100000]);
+        write_word(&ram, BDA_KBD_HEAD, KBD_BUFFER_START);
+        write_word(&ram, BDA_KBD_TAIL, KBD_BUFFER_START);
+        write_word(&ram, BDA_KBD_START, KBD_BUFFER_START);
+        write_word(&ram, BDA_KBD_END, KBD_BUFFER_END);
+        ram
+    }
+
+    fn type_codes(ram: &vm::memory_region, codes: &[u8]) {
+        for code in codes {
+            scancode(ram, *code);
+        }
+    }
+
+    fn call(ram: &vm::memory_region, ah: u8) -> Option<CallRegs> {
+        let mut regs = CallRegs { ax: (ah as u16) << 8, flags: 0x0202, ..Default::default() };
+        if int16(ram, &mut regs) { Some(regs) } else { None }
+    }
+
+    #[test] fn letters() {
+        let ram = make_ram();
+
+        /* A, then shift-A, then ctrl-A */
+        type_codes(&ram, &[0x1E, 0x9E]);
+        type_codes(&ram, &[0x2A, 0x1E, 0x9E, 0xAA]);
+        type_codes(&ram, &[0x1D, 0x1E, 0x9E, 0x9D]);
+        assert!(read_byte(&ram, BDA_KBD_FLAGS1) == 0);
+
+        let regs = call(&ram, 0x01).unwrap();
+        assert!(regs.ax == 0x1E61 && !regs.zero());
+        assert!(call(&ram, 0x00).unwrap().ax == 0x1E61);
+        assert!(call(&ram, 0x11).unwrap().ax == 0x1E41);
+        assert!(call(&ram, 0x10).unwrap().ax == 0x1E41);
+        assert!(call(&ram, 0x00).unwrap().ax == 0x1E01);
+
+        /* Nothing left: check sets ZF, blocking read has to wait */
+        let regs = call(&ram, 0x01).unwrap();
+        assert!(regs.ax == 0x0100 && regs.zero());
+        assert!(call(&ram, 0x00).is_none());
+
+        /* Caps Lock inverts shift for letters only */
+        type_codes(&ram, &[0x3A, 0xBA, 0x1E, 0x2A, 0x1E, 0x02, 0xAA]);
+        assert!(call(&ram, 0x02).unwrap().al() == FLAGS1_CAPS_LOCK);
+        assert!(call(&ram, 0x00).unwrap().ax == 0x1E41);
+        assert!(call(&ram, 0x00).unwrap().ax == 0x1E61);
+        assert!(call(&ram, 0x00).unwrap().ax == 0x0221);
+    }
+
+    #[test] fn extended_keys() {
+        let ram = make_ram();
+
+        /* Grey Up arrow, keypad Up, then F11 */
+        type_codes(&ram, &[0xE0, 0x48, 0xE0, 0xC8, 0x48, 0xC8, 0x57, 0xD7]);
+        assert!(read_byte(&ram, BDA_KBD_FLAGS3) == 0);
+
+        /* Extended functions see the difference */
+        assert!(call(&ram, 0x11).unwrap().ax == 0x48E0);
+        assert!(call(&ram, 0x10).unwrap().ax == 0x48E0);
+        assert!(call(&ram, 0x10).unwrap().ax == 0x4800);
+        assert!(call(&ram, 0x10).unwrap().ax == 0x8500);
+
+        /* Older ones get keypad code and never see F11 */
+        type_codes(&ram, &[0xE0, 0x48, 0xE0, 0xC8, 0x57, 0xD7, 0xE0, 0x1C, 0xE0, 0x9C]);
+        assert!(call(&ram, 0x00).unwrap().ax == 0x4800);
+        assert!(call(&ram, 0x01).unwrap().ax == 0x1C0D);
+        assert!(call(&ram, 0x00).unwrap().ax == 0x1C0D);
+        assert!(call(&ram, 0x00).is_none());
+
+        /* Shift around grey keys does not stick, right Ctrl counts as Ctrl */
+        type_codes(&ram, &[0xE0, 0x2A, 0xE0, 0x4B, 0xE0, 0xCB, 0xE0, 0xAA, 0xE0, 0x1D, 0xE0, 0x4B]);
+        assert!(call(&ram, 0x02).unwrap().al() == FLAGS1_CTRL);
+        assert!(call(&ram, 0x10).unwrap().ax == 0x4BE0);
+        assert!(call(&ram, 0x10).unwrap().ax == 0x73E0);
+        type_codes(&ram, &[0xE0, 0x9D]);
+        assert!(call(&ram, 0x02).unwrap().al() == 0);
+    }
+
+    #[test] fn shared_buffer() {
+        let ram = make_ram();
+
+        /* Keystroke stuffed by guest is read back */
+        write_word(&ram, 0x41E, 0x3B00);
+        write_word(&ram, BDA_KBD_TAIL, 0x20);
+        assert!(call(&ram, 0x00).unwrap().ax == 0x3B00);
+        assert!(read_word(&ram, BDA_KBD_HEAD) == 0x20);
+
+        /* Buffer holds 15 keystrokes and wraps around */
+        for _ in 0..20 {
+            type_codes(&ram, &[0x39, 0xB9]);
+        }
+        let mut count = 0;
+        while let Some(regs) = call(&ram, 0x00) {
+            assert!(regs.ax == 0x3920);
+            count += 1;
+        }
+        assert!(count == 15);
+        assert!(read_word(&ram, BDA_KBD_HEAD) == read_word(&ram, BDA_KBD_TAIL));
+
+        /* Guest flushing buffer is seen as no keystroke */
+        type_codes(&ram, &[0x1E, 0x9E]);
+        write_word(&ram, BDA_KBD_HEAD, read_word(&ram, BDA_KBD_TAIL));
+        assert!(call(&ram, 0x01).unwrap().zero());
+    }
+}
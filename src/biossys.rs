@@ -0,0 +1,298 @@
+/*
+ * Int 15h system services handled by VMM for test images running without a BIOS
+ *
+ * Supported functions: wait, extended memory block move and system configuration table.
+ * Block move reads its descriptor table from guest memory and copies with A20 open, as BIOS does when it
+ * switches to protected mode for the copy. Wait counts guest execution time, so HLT in the stub is retried
+ * until enough virtual time passed. Interrupts stay masked while waiting, timer ticks are still counted in BDA.
+ */
+
+use vm;
+use dma::dma_memory;
+use biosassist::CallRegs;
+
+// Int 15h functions in AH
+const INT15_WAIT: u8            = 0x86;
+const INT15_BLOCK_MOVE: u8      = 0x87;
+const INT15_SYSTEM_CONFIG: u8   = 0xC0;
+
+// Status codes returned in AH
+const STATUS_OK: u8             = 0x00;
+const STATUS_PARITY_ERROR: u8   = 0x01;
+const STATUS_EXCEPTION: u8      = 0x02;
+const STATUS_UNSUPPORTED: u8    = 0x86;
+
+// Block move descriptor table at ES:SI, source and destination are entries 2 and 3
+const MOVE_GDT_SIZE: usize      = 0x30;
+const MOVE_SOURCE_DESC: usize   = 0x10;
+const MOVE_DEST_DESC: usize     = 0x18;
+const MOVE_MAX_WORDS: u16       = 0x8000;
+
+// Descriptor access byte and flags
+const DESC_PRESENT: u8          = 0x80;
+const DESC_SEGMENT: u8          = 0x10;
+const DESC_CODE: u8             = 0x08;
+const DESC_GRANULARITY: u8      = 0x80;
+
+// System configuration table, model FCh is AT class
+const CONFIG_SEG: u16           = 0xF000;
+const CONFIG_OFF: u16           = 0xE6F5;
+const CONFIG_TABLE: [u8; 10]    = [
+    0x08, 0x00,                 // Bytes following
+    0xFC,                       // Model
+    0x01,                       // Submodel
+    0x00,                       // BIOS revision
+    0x64,                       // Second 8259, RTC, extended BDA allocated
+    0x00, 0x00, 0x00, 0x00,     // Feature bytes 2-5
+];
+
+fn linear(seg: u16, off: u16) -> u64
+{
+    ((seg as u64) << 4) + off as u64
+}
+
+/* Base and limit of a block move descriptor, segments it can't copy through are rejected */
+fn descriptor(gdt: &[u8], offset: usize) -> Result<(u64, u64), u8>
+{
+    let desc = &gdt[offset..offset + 8];
+    let access = desc[5];
+    let flags = desc[6];
+
+    if (access & (DESC_PRESENT | DESC_SEGMENT | DESC_CODE)) != (DESC_PRESENT | DESC_SEGMENT) {
+        return Err(STATUS_EXCEPTION);
+    }
+
+    let base = desc[2] as u64 | (desc[3] as u64) << 8 | (desc[4] as u64) << 16 | (desc[7] as u64) << 24;
+    let mut limit = desc[0] as u64 | (desc[1] as u64) << 8 | ((flags & 0x0F) as u64) << 16;
+    if (flags & DESC_GRANULARITY) != 0 {
+        limit = (limit << 12) | 0xFFF;
+    }
+
+    Ok((base, limit))
+}
+
+/* AH=87h: copy CX words between descriptors of the table at ES:SI */
+fn block_move(mem: &dma_memory, regs: &CallRegs) -> Result<(), u8>
+{
+    let words = regs.cx;
+    if words > MOVE_MAX_WORDS {
+        return Err(STATUS_EXCEPTION);
+    }
+
+    let mut gdt = [0u8; MOVE_GDT_SIZE];
+    if mem.read(linear(regs.es, regs.si), &mut gdt) != gdt.len() {
+        return Err(STATUS_PARITY_ERROR);
+    }
+
+    let (src, src_limit) = try!(descriptor(&gdt, MOVE_SOURCE_DESC));
+    let (dst, dst_limit) = try!(descriptor(&gdt, MOVE_DEST_DESC));
+
+    /* Copy touching bytes past segment limit would fault in protected mode */
+    let len = words as usize * 2;
+    if len == 0 {
+        return Ok(());
+    }
+    if len as u64 - 1 > src_limit || len as u64 - 1 > dst_limit {
+        return Err(STATUS_EXCEPTION);
+    }
+
+    let mut buf = vec![0u8; len];
+    if mem.read(src, &mut buf) != len || mem.write(dst, &buf) != len {
+        return Err(STATUS_PARITY_ERROR);
+    }
+
+    Ok(())
+}
+
+/*
+ * AH=86h: wait CX:DX microseconds of guest time.
+ * First call sets the deadline, false until it passes.
+ */
+fn wait(regs: &CallRegs, now: u64, delay_end: &mut Option<u64>) -> bool
+{
+    let end = match *delay_end {
+        Some(end) => end,
+        None => {
+            let us = (regs.cx as u64) << 16 | regs.dx as u64;
+            now + us * 1000
+        },
+    };
+
+    if now < end {
+        *delay_end = Some(end);
+        return false;
+    }
+
+    *delay_end = None;
+    true
+}
+
+/**
+ * Perform int 15h call on guest registers.
+ * Time is guest execution time in nanoseconds, delay_end keeps deadline of a pending wait between calls.
+ * Returns false if the call has to be retried later.
+ */
+pub fn int15(mem: &dma_memory, regs: &mut CallRegs, now: u64, delay_end: &mut Option<u64>) -> bool
+{
+    let func = regs.ah();
+
+    let result = match func {
+        INT15_WAIT => {
+            if !wait(regs, now, delay_end) {
+                return false;
+            }
+            Ok(())
+        },
+
+        INT15_BLOCK_MOVE => block_move(mem, regs),
+
+        INT15_SYSTEM_CONFIG => {
+            regs.es = CONFIG_SEG;
+            regs.bx = CONFIG_OFF;
+            Ok(())
+        },
+
+        _ => {
+            debug!("biossys: unsupported int 15h function {:x}", func);
+            Err(STATUS_UNSUPPORTED)
+        },
+    };
+
+    match result {
+        Ok(()) => {
+            regs.set_ah(STATUS_OK);
+            regs.set_carry(false);
+        },
+        Err(status) => {
+            regs.set_ah(status);
+            regs.set_carry(true);
+        },
+    }
+
+    true
+}
+
+/**
+ * Place system configuration table in BIOS segment
+ */
+pub fn install(ram: &vm::memory_region)
+{
+    ram.write_bytes(linear(CONFIG_SEG, CONFIG_OFF) as usize, &CONFIG_TABLE);
+}
+
+#[cfg(test)]
+mod biossys_test
+{
+    use super::*;
+    use dma::TestMemory;
+
+    /* Guest memory with A20 open */
+    fn make_mem() -> TestMemory {
+        TestMemory::new(0x300000, 0)
+    }
+
+    /* Data segment descriptor with byte granular limit */
+    fn data_desc(base: u32, limit: u16) -> [u8; 8] {
+        [limit as u8, (limit >> 8) as u8, base as u8, (base >> 8) as u8, (base >> 16) as u8, 0x93, 0x00, (base >> 24) as u8]
+    }
+
+    /* Block move table at 0000:7000 */
+    fn put_gdt(mem: &TestMemory, src: [u8; 8], dst: [u8; 8]) {
+        let mut gdt = [0u8; MOVE_GDT_SIZE];
+        gdt[MOVE_SOURCE_DESC..MOVE_SOURCE_DESC + 8].copy_from_slice(&src);
+        gdt[MOVE_DEST_DESC..MOVE_DEST_DESC + 8].copy_from_slice(&dst);
+        mem.write(0x7000, &gdt);
+    }
+
+    fn call(mem: &TestMemory, ax: u16, cx: u16) -> CallRegs {
+        let mut regs = CallRegs { ax: ax, cx: cx, si: 0x7000, ..Default::default() };
+        assert!(int15(mem, &mut regs, 0, &mut None));
+        regs
+    }
+
+    #[test] fn block_move_above_1m() {
+        let mem = make_mem();
+        let data: Vec<u8> = (0..0x1000).map(|i| (i * 7) as u8).collect();
+        mem.write(0xFF800, &data);
+
+        /* Source straddles 1M, destination is at 2M */
+        put_gdt(&mem, data_desc(0xFF800, 0xFFFF), data_desc(0x200000, 0xFFFF));
+        let regs = call(&mem, 0x8700, 0x800);
+        assert!(regs.ah() == 0 && !regs.carry());
+
+        let mut copy = vec![0u8; 0x1001];
+        mem.read(0x200000, &mut copy);
+        assert!(&copy[..0x1000] == &data[..] && copy[0x1000] == 0);
+
+        /* And back below 1M across the boundary again */
+        put_gdt(&mem, data_desc(0x200000, 0xFFFF), data_desc(0xFFC00, 0x0FFF));
+        let regs = call(&mem, 0x8700, 0x800);
+        assert!(regs.ah() == 0 && !regs.carry());
+        mem.read(0xFFC00, &mut copy[..0x1000]);
+        assert!(&copy[..0x1000] == &data[..]);
+    }
+
+    #[test] fn block_move_errors() {
+        let mem = make_mem();
+        mem.write(0x100000, &[0x55; 4]);
+
+        /* Limit shorter than the copy */
+        put_gdt(&mem, data_desc(0x100000, 0x0002), data_desc(0x8000, 0xFFFF));
+        let regs = call(&mem, 0x8700, 2);
+        assert!(regs.ah() == 0x02 && regs.carry());
+
+        /* Not present and code segments */
+        let mut code = data_desc(0x100000, 0xFFFF);
+        code[5] = 0x9B;
+        put_gdt(&mem, code, data_desc(0x8000, 0xFFFF));
+        assert!(call(&mem, 0x8700, 2).ah() == 0x02);
+        let mut absent = data_desc(0x8000, 0xFFFF);
+        absent[5] = 0x13;
+        put_gdt(&mem, data_desc(0x100000, 0xFFFF), absent);
+        assert!(call(&mem, 0x8700, 2).ah() == 0x02);
+
+        /* Past the end of memory */
+        put_gdt(&mem, data_desc(0x2FFFFE, 0xFFFF), data_desc(0x8000, 0xFFFF));
+        assert!(call(&mem, 0x8700, 2).ah() == 0x01);
+
+        /* Nothing was copied, page granular limit allows large copies */
+        let mut buf = [0u8; 4];
+        mem.read(0x8000, &mut buf);
+        assert!(buf == [0; 4]);
+
+        let mut big = data_desc(0x100000, 0x000F);
+        big[6] = 0x80;
+        put_gdt(&mem, big, data_desc(0x8000, 0xFFFF));
+        assert!(!call(&mem, 0x8700, 0x8000).carry());
+        mem.read(0x8000, &mut buf);
+        assert!(buf == [0x55; 4]);
+        assert!(call(&mem, 0x8700, 0x8001).carry());
+    }
+
+    #[test] fn wait_and_config() {
+        let mem = make_mem();
+
+        /* 1.5 ms wait retried until guest ran for that long */
+        let mut delay_end = None;
+        let mut regs = CallRegs { ax: 0x8600, cx: 0x0000, dx: 0x05DC, ..Default::default() };
+        assert!(!int15(&mem, &mut regs, 1000000, &mut delay_end));
+        assert!(!int15(&mem, &mut regs, 2000000, &mut delay_end));
+        assert!(int15(&mem, &mut regs, 2500000, &mut delay_end));
+        assert!(regs.ah() == 0 && !regs.carry() && delay_end.is_none());
+
+        /* Zero wait returns right away */
+        regs.ax = 0x8600;
+        regs.dx = 0;
+        assert!(int15(&mem, &mut regs, 0, &mut delay_end));
+
+        let regs = call(&mem, 0xC000, 0);
+        assert!(regs.ah() == 0 && !regs.carry());
+        assert!(regs.es == 0xF000 && regs.bx == 0xE6F5);
+
+        /* Anything else is refused */
+        for ax in &[0x8800, 0xE820, 0x2401] {
+            let regs = call(&mem, *ax, 0);
+            assert!(regs.ah() == 0x86 && regs.carry());
+        }
+    }
+}
@@ -0,0 +1,404 @@
+/*
+ * Guest execution breakpoints
+ *
+ * Breakpoints stop guest before it executes the instruction at a guest physical address, see vm::add_breakpoint().
+ *
+ * INT3 breakpoints replace first instruction byte with 0xCC. They are cheap, but guest code reading its own bytes,
+ * e.g. checksumming its image, sees the 0xCC, and they can't go in ROM where guest may never see the byte change.
+ * Execute protection breakpoints leave memory alone: their page loses its EPT execute right, every fetch from it
+ * exits and instructions other than the breakpoint run one single step at a time with execute right back on.
+ * That is slow for code sharing the page, but invisible to guest and works in ROM.
+ *
+ * Resuming from a hit steps over the breakpoint: original byte or execute right is back for one single step
+ * and the breakpoint is put back after it.
+ *
+ * I/O breakpoints stop guest at port accesses before they reach the device, see vm::add_io_breakpoint(). Every
+ * port access exits anyway, so they only cost a look at the I/O breakpoint list, which is empty most of the time.
+ *
+ * Vector breakpoints stop guest when an external interrupt vector is about to be injected, see vm::break_on_vector().
+ * Interrupt controller isn't acknowledged at that point, so it shows the request still pending, and resuming
+ * either delivers the vector or takes it back from vcpu.
+ */
+
+use std::ops::Range;
+
+// EPT protection granularity
+pub const PAGE_SIZE: u64 = 0x1000;
+
+/**
+ * How a breakpoint stops guest
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum BreakpointKind
+{
+    Int3,           // 0xCC patched into guest memory
+    ExecProtect,    // Page has no execute right
+}
+
+/**
+ * Breakpoint identity returned when it is added, used to remove it
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct BreakpointHandle(pub u32);
+
+/**
+ * Breakpoint set in guest
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Breakpoint
+{
+    pub handle: BreakpointHandle,
+    pub gpa: u64,
+    pub kind: BreakpointKind,
+    pub orig: u8,           // Instruction byte INT3 replaced
+}
+
+/**
+ * Port access direction, Any only makes sense for I/O breakpoints
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum IoDirection
+{
+    Read,
+    Write,
+    Any,
+}
+
+/**
+ * I/O breakpoint set in guest, stops accesses overlapping its ports
+ */
+#[derive(PartialEq, Debug, Clone)]
+pub struct IoBreakpoint
+{
+    pub handle: BreakpointHandle,
+    pub ports: Range<u16>,
+    pub direction: IoDirection,
+}
+
+impl IoBreakpoint
+{
+    /**
+     * Access of size bytes at port in direction is stopped by this breakpoint
+     */
+    pub fn matches(&self, port: u16, size: u8, direction: IoDirection) -> bool {
+        let end = port as u32 + size as u32;
+        (self.direction == IoDirection::Any || self.direction == direction) &&
+            (port as u32) < self.ports.end as u32 && end > self.ports.start as u32
+    }
+}
+
+/**
+ * Vector breakpoint set in guest
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct VectorBreakpoint
+{
+    pub handle: BreakpointHandle,
+    pub vec: u8,
+}
+
+/* Port as hex with 0x prefix or decimal */
+fn parse_port(val: &str) -> Result<u16, String>
+{
+    let res = if val.starts_with("0x") {
+        u16::from_str_radix(&val[2..], 16)
+    } else {
+        val.parse::<u16>()
+    };
+
+    res.map_err(|_| format!("Bad port {}", val))
+}
+
+/**
+ * Parse I/O breakpoint as "port[-last][,r|w]", e.g. "0x20-0x21,w", it stops reads and writes by default
+ */
+pub fn parse_io_breakpoint(val: &str) -> Result<(Range<u16>, IoDirection), String>
+{
+    let mut parts = val.splitn(2, ',');
+    let ports = parts.next().unwrap();
+    let direction = match parts.next() {
+        None => IoDirection::Any,
+        Some("r") => IoDirection::Read,
+        Some("w") => IoDirection::Write,
+        Some(dir) => return Err(format!("Bad I/O breakpoint direction {}, expected r or w", dir)),
+    };
+
+    let mut range = ports.splitn(2, '-');
+    let first = try!(parse_port(range.next().unwrap()));
+    let last = match range.next() {
+        Some(last) => try!(parse_port(last)),
+        None => first,
+    };
+
+    if last < first || last == 0xFFFF {
+        return Err(format!("Bad port range {}", ports));
+    }
+    Ok((first..last + 1, direction))
+}
+
+/**
+ * Parse interrupt vector as hex with 0x prefix or decimal
+ */
+pub fn parse_vector(val: &str) -> Result<u8, String>
+{
+    let res = if val.starts_with("0x") {
+        u8::from_str_radix(&val[2..], 16)
+    } else {
+        val.parse::<u8>()
+    };
+
+    res.map_err(|_| format!("Bad interrupt vector {}", val))
+}
+
+/**
+ * Page a guest physical address is in
+ */
+pub fn page_of(gpa: u64) -> u64
+{
+    gpa & !(PAGE_SIZE - 1)
+}
+
+/**
+ * Breakpoints by address, I/O and vector breakpoints, handles are shared and never reused
+ */
+pub struct BreakpointTable
+{
+    breakpoints: Vec<Breakpoint>,
+    io: Vec<IoBreakpoint>,
+    vectors: Vec<VectorBreakpoint>,
+    next_handle: u32,
+}
+
+impl BreakpointTable
+{
+    pub fn new() -> BreakpointTable {
+        BreakpointTable {
+            breakpoints: Vec::new(),
+            io: Vec::new(),
+            vectors: Vec::new(),
+            next_handle: 1,
+        }
+    }
+
+    pub fn list(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn find(&self, gpa: u64) -> Option<&Breakpoint> {
+        self.breakpoints.iter().find(|bp| bp.gpa == gpa)
+    }
+
+    /**
+     * Add breakpoint, there can be only one at an address
+     */
+    pub fn add(&mut self, gpa: u64, kind: BreakpointKind, orig: u8) -> Result<BreakpointHandle, String> {
+        if self.find(gpa).is_some() {
+            return Err(format!("Breakpoint already set at 0x{:x}", gpa));
+        }
+
+        let handle = self.alloc_handle();
+        self.breakpoints.push(Breakpoint { handle: handle, gpa: gpa, kind: kind, orig: orig });
+        Ok(handle)
+    }
+
+    /**
+     * Add I/O breakpoint, ranges of different breakpoints may overlap
+     */
+    pub fn add_io(&mut self, ports: Range<u16>, direction: IoDirection) -> Result<BreakpointHandle, String> {
+        if ports.start >= ports.end {
+            return Err(format!("Empty port range {:x}-{:x}", ports.start, ports.end));
+        }
+
+        let handle = self.alloc_handle();
+        self.io.push(IoBreakpoint { handle: handle, ports: ports, direction: direction });
+        Ok(handle)
+    }
+
+    pub fn io_list(&self) -> &[IoBreakpoint] {
+        &self.io
+    }
+
+    /**
+     * I/O breakpoint stopping port access, first one added wins
+     */
+    pub fn find_io(&self, port: u16, size: u8, direction: IoDirection) -> Option<&IoBreakpoint> {
+        self.io.iter().find(|bp| bp.matches(port, size, direction))
+    }
+
+    pub fn remove_io(&mut self, handle: BreakpointHandle) -> Option<IoBreakpoint> {
+        match self.io.iter().position(|bp| bp.handle == handle) {
+            Some(i) => Some(self.io.remove(i)),
+            None => None,
+        }
+    }
+
+    /**
+     * Add vector breakpoint, there can be only one for a vector
+     */
+    pub fn add_vector(&mut self, vec: u8) -> Result<BreakpointHandle, String> {
+        if self.find_vector(vec).is_some() {
+            return Err(format!("Breakpoint already set at vector 0x{:02x}", vec));
+        }
+
+        let handle = self.alloc_handle();
+        self.vectors.push(VectorBreakpoint { handle: handle, vec: vec });
+        Ok(handle)
+    }
+
+    pub fn vector_list(&self) -> &[VectorBreakpoint] {
+        &self.vectors
+    }
+
+    pub fn find_vector(&self, vec: u8) -> Option<&VectorBreakpoint> {
+        self.vectors.iter().find(|bp| bp.vec == vec)
+    }
+
+    pub fn remove_vector(&mut self, handle: BreakpointHandle) -> Option<VectorBreakpoint> {
+        match self.vectors.iter().position(|bp| bp.handle == handle) {
+            Some(i) => Some(self.vectors.remove(i)),
+            None => None,
+        }
+    }
+
+    fn alloc_handle(&mut self) -> BreakpointHandle {
+        let handle = BreakpointHandle(self.next_handle);
+        self.next_handle += 1;
+        handle
+    }
+
+    pub fn remove(&mut self, handle: BreakpointHandle) -> Option<Breakpoint> {
+        match self.breakpoints.iter().position(|bp| bp.handle == handle) {
+            Some(i) => Some(self.breakpoints.remove(i)),
+            None => None,
+        }
+    }
+
+    /**
+     * Note instruction byte INT3 replaces at gpa anew, e.g. after memory was restored from snapshot
+     */
+    pub fn set_orig(&mut self, gpa: u64, orig: u8) {
+        if let Some(bp) = self.breakpoints.iter_mut().find(|bp| bp.gpa == gpa) {
+            bp.orig = orig;
+        }
+    }
+
+    /**
+     * Page has execute protection breakpoints and must stay non-executable
+     */
+    pub fn is_page_protected(&self, page: u64) -> bool {
+        self.breakpoints.iter().any(|bp| bp.kind == BreakpointKind::ExecProtect && page_of(bp.gpa) == page)
+    }
+}
+
+#[cfg(test)]
+mod breakpoint_test
+{
+    use super::*;
+
+    #[test] fn add_and_remove() {
+        let mut table = BreakpointTable::new();
+        let first = table.add(0x7C05, BreakpointKind::Int3, 0xEE).unwrap();
+        let second = table.add(0xF0010, BreakpointKind::ExecProtect, 0).unwrap();
+        assert!(first != second);
+        assert!(table.list().len() == 2);
+
+        /* One breakpoint per address */
+        assert!(table.add(0x7C05, BreakpointKind::ExecProtect, 0).is_err());
+
+        assert!(table.find(0x7C05).unwrap().orig == 0xEE);
+        assert!(table.find(0x7C06).is_none());
+        table.set_orig(0x7C05, 0x90);
+        assert!(table.find(0x7C05).unwrap().orig == 0x90);
+
+        assert!(table.remove(first).unwrap().gpa == 0x7C05);
+        assert!(table.remove(first).is_none());
+        assert!(table.find(0x7C05).is_none());
+
+        /* Handles of removed breakpoints don't come back */
+        let third = table.add(0x7C05, BreakpointKind::Int3, 0xEE).unwrap();
+        assert!(third != first && third != second);
+    }
+
+    #[test] fn page_protection() {
+        let mut table = BreakpointTable::new();
+        let int3 = table.add(0x7C05, BreakpointKind::Int3, 0xEE).unwrap();
+        assert!(!table.is_page_protected(0x7000));
+
+        let first = table.add(0xF0010, BreakpointKind::ExecProtect, 0).unwrap();
+        let second = table.add(0xF0FFF, BreakpointKind::ExecProtect, 0).unwrap();
+        assert!(table.is_page_protected(page_of(0xF0010)));
+        assert!(!table.is_page_protected(0xF1000));
+
+        /* Page stays protected until its last breakpoint is gone */
+        table.remove(first);
+        assert!(table.is_page_protected(0xF0000));
+        table.remove(second);
+        assert!(!table.is_page_protected(0xF0000));
+
+        table.remove(int3);
+        assert!(table.list().is_empty());
+    }
+
+    #[test] fn io_breakpoints() {
+        let mut table = BreakpointTable::new();
+        assert!(table.find_io(0x21, 1, IoDirection::Write).is_none());
+
+        let pic = table.add_io(0x20..0x22, IoDirection::Any).unwrap();
+        let reads = table.add_io(0x21..0x22, IoDirection::Read).unwrap();
+        let int3 = table.add(0x7C05, BreakpointKind::Int3, 0xEE).unwrap();
+        assert!(pic != reads && reads != int3);
+        assert!(table.add_io(0x21..0x21, IoDirection::Read).is_err());
+
+        assert!(table.find_io(0x21, 1, IoDirection::Write).unwrap().handle == pic);
+        assert!(table.find_io(0x22, 1, IoDirection::Write).is_none());
+
+        /* Word access at 0x1F touches 0x20 */
+        assert!(table.find_io(0x1F, 2, IoDirection::Read).unwrap().handle == pic);
+        assert!(table.find_io(0x1E, 2, IoDirection::Read).is_none());
+
+        /* Each kind of breakpoint is only removed by its own call */
+        assert!(table.remove_io(int3).is_none());
+        assert!(table.remove(pic).is_none());
+        assert!(table.remove_io(pic).unwrap().ports == (0x20..0x22));
+
+        assert!(table.find_io(0x21, 1, IoDirection::Write).is_none());
+        assert!(table.find_io(0x21, 1, IoDirection::Read).unwrap().handle == reads);
+        assert!(table.io_list().len() == 1);
+    }
+
+    #[test] fn vector_breakpoints() {
+        let mut table = BreakpointTable::new();
+        let int3 = table.add(0x7C05, BreakpointKind::Int3, 0xEE).unwrap();
+        let timer = table.add_vector(0x08).unwrap();
+        let keyboard = table.add_vector(0x09).unwrap();
+        assert!(timer != int3 && keyboard != timer);
+        assert!(table.add_vector(0x08).is_err());
+
+        assert!(table.find_vector(0x08).unwrap().handle == timer);
+        assert!(table.find_vector(0x70).is_none());
+
+        assert!(table.remove(timer).is_none() && table.remove_io(timer).is_none());
+        assert!(table.remove_vector(timer).unwrap().vec == 0x08);
+        assert!(table.remove_vector(timer).is_none());
+        assert!(table.find_vector(0x08).is_none());
+        assert!(table.vector_list() == &[VectorBreakpoint { handle: keyboard, vec: 0x09 }]);
+    }
+
+    #[test] fn parse_io() {
+        assert!(parse_io_breakpoint("0x21") == Ok((0x21..0x22, IoDirection::Any)));
+        assert!(parse_io_breakpoint("0x20-0x21,w") == Ok((0x20..0x22, IoDirection::Write)));
+        assert!(parse_io_breakpoint("96,r") == Ok((0x60..0x61, IoDirection::Read)));
+
+        assert!(parse_io_breakpoint("21") == Ok((21..22, IoDirection::Any)));
+        assert!(parse_io_breakpoint("0x21h").is_err());
+        assert!(parse_io_breakpoint("0x21,x").is_err());
+        assert!(parse_io_breakpoint("0x21-0x20").is_err());
+        assert!(parse_io_breakpoint("0x10000").is_err());
+        assert!(parse_io_breakpoint("").is_err());
+
+        assert!(parse_vector("0x08") == Ok(8));
+        assert!(parse_vector("112") == Ok(0x70));
+        assert!(parse_vector("0x100").is_err());
+        assert!(parse_vector("08h").is_err());
+    }
+}
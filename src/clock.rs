@@ -0,0 +1,513 @@
+/*
+ * Virtual time sources for device models
+ *
+ * Guest time is vcpu execution time scaled by a dilation ratio, so guest can be run slower or faster than
+ * host. All devices and the event loop read it through guest_time_ns(), so one ratio applies to every
+ * timer at once. Ratio changes take effect from the current point, guest time never jumps.
+ *
+ * Guest time is the only time device timers run on and it stands still while VM is paused, so nothing
+ * comes due during a pause and timers don't catch up in a burst after it.
+ *
+ * Host time can still leap forward under a running VM, e.g. when host sleeps. A forward step of source
+ * time beyond a threshold between two reads is taken for such a jump. Guest time either absorbs it, moving
+ * by no more than the threshold, or follows host and takes all of it.
+ */
+
+use vm;
+use record;
+use replay;
+
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::sync::Mutex;
+
+pub const NS_PER_SEC: u64 = 1000000000;
+
+/* Dilation ratio bounds */
+pub const MIN_DILATION: f64 = 0.01;
+pub const MAX_DILATION: f64 = 100.0;
+
+/**
+ * Monotonic virtual time
+ *
+ * Virtual time only runs while guest vcpu runs, so devices driven by it pause with the VM.
+ */
+pub trait virtual_clock
+{
+    /** Virtual time in nanoseconds */
+    fn now_ns(&self) -> u64;
+
+    /** Number of host time jumps seen so far, e.g. for wall clock devices to resync after one */
+    fn jumps(&self) -> u64 {
+        0
+    }
+}
+
+/**
+ * Virtual time from vcpu execution time
+ */
+pub struct VcpuClock;
+
+impl virtual_clock for VcpuClock
+{
+    fn now_ns(&self) -> u64 {
+        device_time_ns()
+    }
+
+    /* Replay has the jumps RTC read host wall clock after */
+    fn jumps(&self) -> u64 {
+        let jumps = VCPU_TIME_SCALE.lock().unwrap().jumps;
+        if replay::enabled() { jumps + replay::clock_jumps() } else { jumps }
+    }
+}
+
+/**
+ * Piecewise linear mapping of source time to guest time
+ *
+ * Rate is kept in guest nanoseconds per source second, so unity ratio maps time exactly.
+ */
+pub struct TimeScale
+{
+    rate: u64,                  // Guest ns per source second
+    source_base: u64,           // Source time where current rate took effect
+    guest_base: u64,            // Guest time at source_base
+    frozen: Option<u64>,        // Guest time while paused
+    fixed: bool,                // Something follows source time directly, rate can't change
+    jump_threshold: u64,        // Source time step taken for a host time jump
+    absorb_jumps: bool,         // Guest time moves by jump_threshold at most on a jump
+    last_source: u64,           // Source time of the last read
+    jumps: u64,                 // Jumps seen
+}
+
+impl TimeScale
+{
+    pub fn new() -> TimeScale {
+        TimeScale {
+            rate: NS_PER_SEC,
+            source_base: 0,
+            guest_base: 0,
+            frozen: None,
+            fixed: false,
+            jump_threshold: u64::max_value(),
+            absorb_jumps: false,
+            last_source: 0,
+            jumps: 0,
+        }
+    }
+
+    /**
+     * Watch for source time steps over threshold_ns, absorb limits guest time step to threshold_ns
+     */
+    pub fn set_jump_policy(&mut self, threshold_ns: u64, absorb: bool) {
+        assert!(threshold_ns != 0);
+        self.jump_threshold = threshold_ns;
+        self.absorb_jumps = absorb;
+    }
+
+    /**
+     * Guest time at source time source_ns, source time jumps since last read are dealt with first
+     */
+    pub fn update(&mut self, source_ns: u64) -> u64 {
+        if self.frozen.is_none() && source_ns > self.last_source {
+            let step = source_ns - self.last_source;
+            if step > self.jump_threshold {
+                let kept = if self.absorb_jumps { self.jump_threshold } else { step };
+                warn!("clock: host time jumped {} ms ahead, guest time moves {} ms", step / 1000000, kept / 1000000);
+
+                self.guest_base = self.guest_ns(self.last_source + kept);
+                self.source_base = source_ns;
+                self.jumps += 1;
+            }
+            self.last_source = source_ns;
+        }
+
+        self.guest_ns(source_ns)
+    }
+
+    pub fn guest_ns(&self, source_ns: u64) -> u64 {
+        if let Some(frozen) = self.frozen {
+            return frozen;
+        }
+
+        self.guest_base + ns_to_ticks(source_ns.saturating_sub(self.source_base), self.rate)
+    }
+
+    /**
+     * Stop guest time at source time source_ns
+     */
+    pub fn pause(&mut self, source_ns: u64) {
+        if self.frozen.is_none() {
+            self.frozen = Some(self.update(source_ns));
+        }
+    }
+
+    /**
+     * Continue guest time from where it stopped, source time that passed meanwhile is left out
+     */
+    pub fn resume(&mut self, source_ns: u64) {
+        if let Some(frozen) = self.frozen.take() {
+            self.guest_base = frozen;
+            self.source_base = source_ns;
+            self.last_source = source_ns;
+        }
+    }
+
+    pub fn ratio(&self) -> f64 {
+        self.rate as f64 / NS_PER_SEC as f64
+    }
+
+    /**
+     * Put guest time at guest_ns as of source time source_ns, e.g. to continue a restored snapshot
+     */
+    pub fn set_guest_ns(&mut self, guest_ns: u64, source_ns: u64) {
+        if self.frozen.is_some() {
+            self.frozen = Some(guest_ns);
+        }
+        self.guest_base = guest_ns;
+        self.source_base = source_ns;
+        self.last_source = source_ns;
+    }
+
+    /**
+     * Change ratio at source time source_ns, guest time continues from where it is
+     */
+    pub fn set_ratio(&mut self, ratio: f64, source_ns: u64) -> Result<(), String> {
+        if !(ratio >= MIN_DILATION && ratio <= MAX_DILATION) {
+            return Err(format!("Time dilation {} out of range {}-{}", ratio, MIN_DILATION, MAX_DILATION));
+        }
+
+        let rate = (ratio * NS_PER_SEC as f64).round() as u64;
+        if rate == self.rate {
+            return Ok(());
+        }
+
+        if self.fixed {
+            return Err("Time dilation can't change while guest TSC runs on host TSC".to_string());
+        }
+
+        if self.frozen.is_none() {
+            self.guest_base = self.update(source_ns);
+            self.source_base = source_ns;
+        }
+        self.rate = rate;
+        Ok(())
+    }
+}
+
+lazy_static! {
+    static ref VCPU_TIME_SCALE: Mutex<TimeScale> = Mutex::new(TimeScale::new());
+}
+
+/**
+ * Guest time in nanoseconds, vcpu execution time scaled by dilation ratio
+ * Replay has guest time as of the last recorded reading.
+ */
+pub fn guest_time_ns() -> u64
+{
+    if let Some(ns) = replay::time_ns() {
+        return ns;
+    }
+
+    let mut scale = VCPU_TIME_SCALE.lock().unwrap();
+    scale.update(vm::get_guest_exec_time())
+}
+
+/**
+ * Guest time devices and timers go by, an input to record and to take from recording on replay
+ */
+pub fn device_time_ns() -> u64
+{
+    if replay::enabled() {
+        return replay::clock_ns();
+    }
+
+    let ns = guest_time_ns();
+    record::emit(|| record::Input::Clock { ns: ns });
+    ns
+}
+
+/* Execution time doesn't run while vcpu is parked, pausing guest time as well keeps that an invariant */
+impl vm::pause_handler for VcpuClock
+{
+    fn paused(&self)
+    {
+        VCPU_TIME_SCALE.lock().unwrap().pause(vm::get_guest_exec_time());
+    }
+
+    fn resumed(&self)
+    {
+        VCPU_TIME_SCALE.lock().unwrap().resume(vm::get_guest_exec_time());
+    }
+}
+
+/**
+ * Stop guest time while VM is paused
+ */
+pub fn init()
+{
+    vm::register_pause_handler(Rc::new(VcpuClock));
+}
+
+/**
+ * Set guest time dilation, 0.5 runs guest time at half host rate. Callable from any thread.
+ */
+pub fn set_time_dilation(ratio: f64) -> Result<(), String>
+{
+    let mut scale = VCPU_TIME_SCALE.lock().unwrap();
+    try!(scale.set_ratio(ratio, vm::get_guest_exec_time()));
+    debug!("clock: time dilation {}", ratio);
+    Ok(())
+}
+
+/**
+ * Set how guest time deals with host time jumps over threshold_ms, see TimeScale::set_jump_policy
+ */
+pub fn set_jump_policy(threshold_ms: u64, absorb: bool)
+{
+    VCPU_TIME_SCALE.lock().unwrap().set_jump_policy(threshold_ms * 1000000, absorb);
+    debug!("clock: host time jumps over {} ms {}", threshold_ms, if absorb { "absorbed" } else { "followed" });
+}
+
+/**
+ * Current guest time dilation ratio, e.g. for saving VM state
+ */
+pub fn time_dilation() -> f64
+{
+    VCPU_TIME_SCALE.lock().unwrap().ratio()
+}
+
+/**
+ * Continue guest time from a snapshot taken at guest_ns with dilation ratio, vcpu thread only
+ */
+pub fn restore_guest_time(guest_ns: u64, ratio: f64) -> Result<(), String>
+{
+    let mut scale = VCPU_TIME_SCALE.lock().unwrap();
+    let source_ns = vm::get_guest_exec_time();
+    try!(scale.set_ratio(ratio, source_ns));
+    scale.set_guest_ns(guest_ns, source_ns);
+    debug!("clock: guest time restored to {} ns", guest_ns);
+    Ok(())
+}
+
+/**
+ * Keep guest time at unity rate for good, for guest visible counters that run on host time
+ */
+pub fn fix_time_dilation() -> Result<(), String>
+{
+    let mut scale = VCPU_TIME_SCALE.lock().unwrap();
+    if scale.rate != NS_PER_SEC {
+        return Err(format!("Time dilation {} can't be kept at unity", scale.ratio()));
+    }
+
+    scale.fixed = true;
+    Ok(())
+}
+
+/**
+ * Manually advanced clock for tests
+ */
+pub struct MockClock
+{
+    ns: Cell<u64>,
+}
+
+impl MockClock
+{
+    pub fn new() -> MockClock {
+        MockClock {
+            ns: Cell::new(0),
+        }
+    }
+
+    pub fn advance_ns(&self, ns: u64) {
+        self.ns.set(self.ns.get() + ns);
+    }
+}
+
+impl virtual_clock for MockClock
+{
+    fn now_ns(&self) -> u64 {
+        self.ns.get()
+    }
+}
+
+/**
+ * Clock running at a ratio of another one, for tests of dilated time
+ */
+pub struct ScaledClock
+{
+    source: Rc<virtual_clock>,
+    scale: RefCell<TimeScale>,
+}
+
+impl ScaledClock
+{
+    pub fn new(source: Rc<virtual_clock>) -> ScaledClock {
+        ScaledClock {
+            source: source,
+            scale: RefCell::new(TimeScale::new()),
+        }
+    }
+
+    pub fn set_ratio(&self, ratio: f64) -> Result<(), String> {
+        self.scale.borrow_mut().set_ratio(ratio, self.source.now_ns())
+    }
+
+    pub fn set_jump_policy(&self, threshold_ns: u64, absorb: bool) {
+        self.scale.borrow_mut().set_jump_policy(threshold_ns, absorb);
+    }
+}
+
+impl virtual_clock for ScaledClock
+{
+    fn now_ns(&self) -> u64 {
+        self.scale.borrow_mut().update(self.source.now_ns())
+    }
+
+    fn jumps(&self) -> u64 {
+        self.scale.borrow().jumps
+    }
+}
+
+impl vm::pause_handler for ScaledClock
+{
+    fn paused(&self)
+    {
+        self.scale.borrow_mut().pause(self.source.now_ns());
+    }
+
+    fn resumed(&self)
+    {
+        self.scale.borrow_mut().resume(self.source.now_ns());
+    }
+}
+
+/**
+ * Number of ticks of a frequency that fit in a time span, without intermediate overflow
+ */
+pub fn ns_to_ticks(ns: u64, freq_hz: u64) -> u64
+{
+    (ns / NS_PER_SEC) * freq_hz + (ns % NS_PER_SEC) * freq_hz / NS_PER_SEC
+}
+
+/**
+ * Time span in ns until a number of ticks of a frequency have passed, rounded up so the last tick is in
+ */
+pub fn ticks_to_ns(ticks: u64, freq_hz: u64) -> u64
+{
+    (ticks / freq_hz) * NS_PER_SEC + ((ticks % freq_hz) * NS_PER_SEC + freq_hz - 1) / freq_hz
+}
+
+#[cfg(test)]
+mod clock_test
+{
+    use super::*;
+    use vm::pause_handler;
+
+    #[test] fn ticks() {
+        assert!(ns_to_ticks(0, 3579545) == 0);
+        assert!(ns_to_ticks(1000000000, 3579545) == 3579545);
+        assert!(ns_to_ticks(1000, 3579545) == 3);
+        assert!(ns_to_ticks(3600 * 1000000000 + 500000000, 1000) == 3600500);
+        assert!(ticks_to_ns(3, 3579545) == 839);
+        assert!(ns_to_ticks(ticks_to_ns(3, 3579545), 3579545) == 3);
+        assert!(ticks_to_ns(3579545 * 3600 + 1, 3579545) == 3600 * 1000000000 + 280);
+
+        let clock = MockClock::new();
+        clock.advance_ns(10);
+        clock.advance_ns(5);
+        assert!(clock.now_ns() == 15);
+    }
+
+    #[test] fn dilation() {
+        let host = Rc::new(MockClock::new());
+        let clock = ScaledClock::new(host.clone());
+
+        host.advance_ns(1000);
+        assert!(clock.now_ns() == 1000);
+
+        /* Slowing down continues from current guest time */
+        assert!(clock.set_ratio(0.5).is_ok());
+        assert!(clock.now_ns() == 1000);
+        host.advance_ns(4000);
+        assert!(clock.now_ns() == 3000);
+
+        /* Speeding up never goes back either */
+        assert!(clock.set_ratio(3.0).is_ok());
+        assert!(clock.now_ns() == 3000);
+        host.advance_ns(1);
+        assert!(clock.now_ns() == 3003);
+        assert!(clock.scale.borrow().ratio() == 3.0);
+
+        assert!(clock.set_ratio(0.0).is_err() && clock.set_ratio(1000.0).is_err());
+        assert!(clock.now_ns() == 3003);
+
+        /* Host time passing during pause is left out, ratio changed during pause applies after it */
+        clock.paused();
+        host.advance_ns(1000);
+        assert!(clock.now_ns() == 3003);
+        assert!(clock.set_ratio(1.0).is_ok());
+        host.advance_ns(1000);
+        clock.resumed();
+        assert!(clock.now_ns() == 3003);
+        host.advance_ns(7);
+        assert!(clock.now_ns() == 3010);
+
+        /* Fixed scale keeps unity rate */
+        let mut scale = TimeScale::new();
+        scale.fixed = true;
+        assert!(scale.set_ratio(1.0, 0).is_ok());
+        assert!(scale.set_ratio(2.0, 0).is_err());
+    }
+
+    #[test] fn restored_time() {
+        let mut scale = TimeScale::new();
+        assert!(scale.set_ratio(0.5, 0).is_ok());
+        assert!(scale.update(1000) == 500);
+
+        /* Guest time goes on from the restored point at the same rate */
+        scale.set_guest_ns(70000, 2000);
+        assert!(scale.update(2000) == 70000);
+        assert!(scale.update(3000) == 70500);
+
+        /* Restored while paused stays there until resumed */
+        scale.pause(3000);
+        scale.set_guest_ns(90000, 3500);
+        assert!(scale.update(9000) == 90000);
+        scale.resume(9000);
+        assert!(scale.update(9100) == 90050);
+    }
+
+    #[test] fn host_jumps() {
+        let host = Rc::new(MockClock::new());
+        let clock = ScaledClock::new(host.clone());
+        clock.set_jump_policy(1000, true);
+
+        /* Steps up to threshold are regular time */
+        host.advance_ns(1000);
+        assert!(clock.now_ns() == 1000 && clock.jumps() == 0);
+
+        /* Longer one is cut to threshold, guest time goes on from there */
+        host.advance_ns(50000);
+        assert!(clock.now_ns() == 2000 && clock.jumps() == 1);
+        host.advance_ns(10);
+        assert!(clock.now_ns() == 2010);
+
+        /* Threshold is in host time, dilated guest gets its share of it */
+        assert!(clock.set_ratio(2.0).is_ok());
+        host.advance_ns(5000);
+        assert!(clock.now_ns() == 4010 && clock.jumps() == 2);
+
+        /* Pause is not a jump */
+        clock.paused();
+        host.advance_ns(50000);
+        clock.resumed();
+        host.advance_ns(10);
+        assert!(clock.now_ns() == 4030 && clock.jumps() == 2);
+
+        /* Following host takes the whole jump but still counts it */
+        clock.set_jump_policy(1000, false);
+        host.advance_ns(5000);
+        assert!(clock.now_ns() == 14030 && clock.jumps() == 3);
+    }
+}
@@ -3,9 +3,14 @@
  */
 
 use vm;
+use event;
+use record;
+use replay;
+use clock::{self, virtual_clock, VcpuClock};
 
 use std::rc::Rc;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::mem;
 use time;
 
 const CMOS_SELECT_PORT: u16     = 0x70;
@@ -13,9 +18,36 @@ const CMOS_DATA_PORT: u16       = 0x71;
 const CMOS_TOTAL_REGS: u8       = 128;  // Total number of byte registers we emulate
 const CMOS_DEFAULT_SELECTOR: u8 = 0xD;  // Default selected register
 const CMOS_STA_DEFAULT: u8      = 0b00100110;
-const CMOS_STA_SUPPORTED: u8    = 0b00000000;
+const CMOS_STA_SUPPORTED: u8    = 0b00001111;
 const CMOS_STB_DEFAULT: u8      = 0b00000110;
-const CMOS_STB_SUPPORTED: u8    = 0b00000100;
+const CMOS_STB_SUPPORTED: u8    = 0b11010110;
+
+// Status register A bits
+const CMOS_STA_RATE: u8         = 0x0F; // Periodic interrupt rate select, 0 is off
+const CMOS_STA_UIP: u8          = 0x80; // Update in progress, read only
+
+// Status register B bits
+const CMOS_STB_24H: u8          = 0x02; // 24 hour mode, otherwise 12 hour with PM flag in hours
+const CMOS_STB_BINARY: u8       = 0x04; // Binary data mode, otherwise BCD
+const CMOS_STB_UIE: u8          = 0x10; // Update ended interrupt enable
+const CMOS_STB_PIE: u8          = 0x40; // Periodic interrupt enable
+const CMOS_STB_SET: u8          = 0x80; // Clock updates inhibited while guest sets time
+
+// Status register C bits, cleared by reading
+const CMOS_STC_UF: u8           = 0x10; // Update ended
+const CMOS_STC_PF: u8           = 0x40; // Periodic interrupt
+const CMOS_STC_IRQF: u8         = 0x80; // Interrupt requested
+
+const CMOS_IRQ: u8              = 8;
+
+// Time base oscillator, periodic interrupt divides it down
+const CMOS_RTC_FREQ_HZ: u64     = 32768;
+
+// UIP is set this long before every seconds update, clock registers are stable for as long after UIP reads clear
+const CMOS_UIP_WINDOW_NS: u64   = 244000;
+const NS_PER_SEC: u64           = 1000000000;
+
+const CMOS_RTC_HOURS_PM: u8     = 0x80; // PM flag in hours register in 12 hour mode
 
 const CMOS_RTC_SECONDS: u8      = 0x00;
 const CMOS_RTC_MINUTES: u8      = 0x02;
@@ -25,36 +57,76 @@ const CMOS_RTC_MDAY: u8         = 0x07;
 const CMOS_RTC_MONTH: u8        = 0x08;
 const CMOS_RTC_YEAR: u8         = 0x09;
 const CMOS_RTC_CENTURY: u8      = 0x32;
+const CMOS_RTC_CENTURY_PS2: u8  = 0x37; // Alternative century location used by PS/2 machines
 const CMOS_STA: u8              = 0x0A;
 const CMOS_STB: u8              = 0x0B;
+const CMOS_STC: u8              = 0x0C;
 
 /* 
  * Current limitations:
- * - No 12 hour support, only 24
- * - No interrupt generation
- * - RTC updates on every register access which can lead to unstable time readings in guests
+ * - No alarm interrupt
+ * - Divider bits in register A are fixed at normal 32.768 kHz operation
+ *
+ * Time is kept in binary and encoded on every read according to current register B mode bits,
+ * so mode changes apply to subsequent reads immediately.
+ *
+ * Clock starts at host time and then runs in virtual time. Seconds update once a second at a fixed phase,
+ * UIP is set for CMOS_UIP_WINDOW_NS before each update. Reading register A with UIP clear snapshots the time,
+ * clock registers read within the window after that come from the snapshot, so such reads are always coherent.
+ *
+ * Periodic flag is set whenever virtual time crosses a whole period of the selected rate. Periods are
+ * counted from the oscillator tick divider chain started at, so a new rate takes effect at the next multiple
+ * of the new period past current time in the chain's phase, and turning periodic interrupt off and on again
+ * keeps that phase. Divider keeps running while SET bit holds clock updates. With periodic interrupt enabled
+ * a periodic timer event makes sure the interrupt is raised without guest touching CMOS. Periods that passed
+ * before the flag was noticed coalesce into one interrupt.
+ *
+ * Host time jumps cut out of virtual time leave the clock behind host wall clock, with resync enabled
+ * the clock is set to host wall clock again after each one.
  */
 struct CMOS
 {
     selector: u8,
     sta: u8,
     stb: u8,
+    stc: u8,
     nmi_bit: bool,          // TODO: this bit should be owned by vm/vcpu
-    host_time: time::Tm,    // Real host time during last update
+    clock: Rc<virtual_clock>,
+    clock_ns: u64,          // Virtual time during last update
+    subsec_ns: u64,         // Time since last seconds update
+    divider_start: u64,     // Oscillator tick divider chain started counting at
     time: time::Tm,         // Time we are emulating
+    snapshot: Option<(time::Tm, u64)>, // Time and virtual time of the last UIP clear read
+    irq_pending: bool,      // Update ended or periodic interrupt to be raised
+    missed_periodic: u64,   // Periodic interrupts that coalesced into later ones
+    resync_on_jump: bool,   // Set time to host wall clock after host time jumps
+    wall_clock: fn() -> time::Tm,
+    jumps: u64,             // Host time jumps seen by virtual clock so far
 }
 
 impl CMOS
 {
-    fn new() -> CMOS 
+    fn new(clock: Rc<virtual_clock>) -> CMOS 
     {
+        let clock_ns = clock.now_ns();
+        let jumps = clock.jumps();
         CMOS {
             selector: CMOS_DEFAULT_SELECTOR,
             sta: CMOS_STA_DEFAULT,
             stb: CMOS_STB_DEFAULT,
+            stc: 0,
             nmi_bit: false,
-            host_time: time::empty_tm(),
-            time: time::empty_tm(),
+            clock: clock,
+            clock_ns: clock_ns,
+            subsec_ns: 0,
+            divider_start: clock::ns_to_ticks(clock_ns, CMOS_RTC_FREQ_HZ),
+            time: time::now(),
+            snapshot: None,
+            irq_pending: false,
+            missed_periodic: 0,
+            resync_on_jump: false,
+            wall_clock: time::now,
+            jumps: jumps,
         }
     }
 
@@ -88,7 +160,7 @@ impl CMOS
 
     fn to_rtc_format(&self, val: i32) -> u8
     {
-        if (self.stb & 0x04) == 0 {
+        if (self.stb & CMOS_STB_BINARY) == 0 {
             // BCD format needed
             assert!(val < 100);
             let lo = (val % 10) as u8;
@@ -101,40 +173,207 @@ impl CMOS
 
     fn from_rtc_format(&self, val: u8) -> i32
     {
-        if (self.stb & 0x04) == 0 {
+        if (self.stb & CMOS_STB_BINARY) == 0 {
             // BCD format needed
             let lo = (val & 0xF) as i32;
             let hi = (val >> 4) as i32;
-            return lo + hi;
+            return lo + hi * 10;
         } else {
             return val as i32;
         }
     }
 
-    fn read_reg(&mut self) -> u8
+    // Hours register carries PM flag in 12 hour mode, midnight and noon are hour 12
+    fn to_rtc_hours(&self, hour: i32) -> u8
     {
-        // Adjust emulated time by computing elapsed duration since last time 
-        // Then add this duration to time we emulate
-        let now = time::now();
-        let delta = now - self.host_time; // Ok if negative
+        if (self.stb & CMOS_STB_24H) != 0 {
+            return self.to_rtc_format(hour);
+        }
+
+        let pm = if hour >= 12 { CMOS_RTC_HOURS_PM } else { 0 };
+        let hour12 = match hour % 12 {
+            0 => 12,
+            h => h,
+        };
+
+        return self.to_rtc_format(hour12) | pm;
+    }
+
+    fn from_rtc_hours(&self, val: u8) -> i32
+    {
+        if (self.stb & CMOS_STB_24H) != 0 {
+            return self.from_rtc_format(val);
+        }
+
+        let hour = self.from_rtc_format(val & !CMOS_RTC_HOURS_PM) % 12;
+        if (val & CMOS_RTC_HOURS_PM) != 0 {
+            return hour + 12;
+        } else {
+            return hour;
+        }
+    }
+
+    // Full year number, Tm counts from 1900
+    fn year(&self) -> i32
+    {
+        self.time.tm_year + 1900
+    }
+
+    fn set_year(&mut self, year: i32)
+    {
+        self.time.tm_year = year - 1900;
+    }
+
+    // Periodic interrupt period in oscillator ticks, rates 1 and 2 repeat rates 8 and 9
+    fn periodic_ticks(&self) -> Option<u64>
+    {
+        match self.sta & CMOS_STA_RATE {
+            0 => None,
+            1 => Some(128),
+            2 => Some(256),
+            rate => Some(1 << (rate - 1)),
+        }
+    }
+
+    // Oscillator ticks divider chain counted by a virtual time
+    fn divider_ticks(&self, ns: u64) -> u64
+    {
+        clock::ns_to_ticks(ns, CMOS_RTC_FREQ_HZ) - self.divider_start
+    }
+
+    // Periods completed between two virtual times
+    fn periods_between(&self, from_ns: u64, to_ns: u64) -> u64
+    {
+        match self.periodic_ticks() {
+            Some(period) => self.divider_ticks(to_ns) / period - self.divider_ticks(from_ns) / period,
+            None => 0,
+        }
+    }
+
+    // Oscillator tick of first periodic interrupt after a virtual time
+    fn next_periodic(&self, ns: u64, period: u64) -> u64
+    {
+        self.divider_start + (self.divider_ticks(ns) / period + 1) * period
+    }
+
+    // Add virtual time elapsed since last update to time we emulate
+    fn update_time(&mut self)
+    {
+        let now = self.clock.now_ns();
+        let delta = now - self.clock_ns;
+
+        let periods = self.periods_between(self.clock_ns, now);
+        if periods != 0 {
+            self.stc |= CMOS_STC_PF;
+            if (self.stb & CMOS_STB_PIE) != 0 {
+                self.stc |= CMOS_STC_IRQF;
+                self.irq_pending = true;
+            }
+        }
+
+        self.clock_ns = now;
+        self.advance(delta);
+
+        let jumps = self.clock.jumps();
+        if jumps != self.jumps {
+            self.jumps = jumps;
+            if self.resync_on_jump {
+                self.resync();
+            }
+        }
+    }
+
+    // Catch up with host wall clock, unless guest is setting time
+    fn resync(&mut self)
+    {
+        if (self.stb & CMOS_STB_SET) != 0 {
+            return;
+        }
+
+        self.time = match replay::wall_clock() {
+            Some(secs) => time::at(time::Timespec::new(secs, 0)),
+            None => (self.wall_clock)(),
+        };
+        let secs = self.time.to_timespec().sec;
+        record::emit(|| record::Input::WallClock { secs: secs });
+        self.snapshot = None;
+        debug!("cmos: clock set to host time after host time jump");
+    }
+
+    // Clock doesn't run while guest holds updates with SET bit
+    fn advance(&mut self, delta_ns: u64)
+    {
+        if (self.stb & CMOS_STB_SET) != 0 {
+            return;
+        }
+
+        let total_ns = self.subsec_ns + delta_ns;
+        let seconds = total_ns / NS_PER_SEC;
+        self.subsec_ns = total_ns % NS_PER_SEC;
+
+        if seconds != 0 {
+            self.time = self.time + time::Duration::seconds(seconds as i64);
+            self.stc |= CMOS_STC_UF;
+            if (self.stb & CMOS_STB_UIE) != 0 {
+                self.stc |= CMOS_STC_IRQF;
+                self.irq_pending = true;
+            }
+        }
+    }
+
+    fn uip(&self) -> bool
+    {
+        (self.stb & CMOS_STB_SET) == 0 && self.subsec_ns >= NS_PER_SEC - CMOS_UIP_WINDOW_NS
+    }
 
-        self.time = self.time + delta;
-        self.host_time = now;
+    // Virtual time until next seconds update
+    fn next_update_ns(&self) -> u64
+    {
+        NS_PER_SEC - self.subsec_ns
+    }
+
+    fn take_irq(&mut self) -> bool
+    {
+        mem::replace(&mut self.irq_pending, false)
+    }
+
+    // Time for clock registers, snapshot if guest saw UIP clear recently
+    fn read_time(&self) -> time::Tm
+    {
+        match self.snapshot {
+            Some((time, taken_ns)) if self.clock_ns - taken_ns < CMOS_UIP_WINDOW_NS => time,
+            _ => self.time,
+        }
+    }
+
+    fn read_reg(&mut self) -> u8
+    {
+        self.update_time();
+        let time = self.read_time();
 
         return match self.reset_selector() {
             // RTC
-            CMOS_RTC_SECONDS => self.to_rtc_format(self.time.tm_sec),
-            CMOS_RTC_MINUTES => self.to_rtc_format(self.time.tm_min),
-            CMOS_RTC_HOURS   => self.to_rtc_format(self.time.tm_hour),
-            CMOS_RTC_WDAY    => self.to_rtc_format(self.time.tm_wday + 1), // CMOS wday starts from 1
-            CMOS_RTC_MDAY    => self.to_rtc_format(self.time.tm_mday),
-            CMOS_RTC_MONTH   => self.to_rtc_format(self.time.tm_mon),
-            CMOS_RTC_YEAR    => self.to_rtc_format(self.time.tm_year % 100),
-            CMOS_RTC_CENTURY => self.to_rtc_format(self.time.tm_year / 100),
+            CMOS_RTC_SECONDS => self.to_rtc_format(time.tm_sec),
+            CMOS_RTC_MINUTES => self.to_rtc_format(time.tm_min),
+            CMOS_RTC_HOURS   => self.to_rtc_hours(time.tm_hour),
+            CMOS_RTC_WDAY    => self.to_rtc_format(time.tm_wday + 1), // CMOS wday starts from 1
+            CMOS_RTC_MDAY    => self.to_rtc_format(time.tm_mday),
+            CMOS_RTC_MONTH   => self.to_rtc_format(time.tm_mon + 1),  // CMOS month starts from 1
+            CMOS_RTC_YEAR    => self.to_rtc_format((time.tm_year + 1900) % 100),
+            CMOS_RTC_CENTURY | CMOS_RTC_CENTURY_PS2 => self.to_rtc_format((time.tm_year + 1900) / 100),
 
             // Status
-            CMOS_STA => self.sta,
+            CMOS_STA => {
+                if self.uip() {
+                    self.snapshot = None;
+                    self.sta | CMOS_STA_UIP
+                } else {
+                    self.snapshot = Some((self.time, self.clock_ns));
+                    self.sta
+                }
+            },
             CMOS_STB => self.stb,
+            CMOS_STC => mem::replace(&mut self.stc, 0),
 
             // Unsupported
             _ => 0,
@@ -143,19 +382,30 @@ impl CMOS
 
     fn write_reg(&mut self, val: u8)
     {
+        // Account time passed so far in the mode it passed in
+        self.update_time();
+        self.snapshot = None;
+
         match self.reset_selector() {
             // RTC
             CMOS_RTC_SECONDS => self.time.tm_sec    = self.from_rtc_format(val),
             CMOS_RTC_MINUTES => self.time.tm_min    = self.from_rtc_format(val),
-            CMOS_RTC_HOURS   => self.time.tm_hour   = self.from_rtc_format(val),
+            CMOS_RTC_HOURS   => self.time.tm_hour   = self.from_rtc_hours(val),
             CMOS_RTC_WDAY    => self.time.tm_wday   = self.from_rtc_format(val) - 1, // CMOS wday starts from 1
             CMOS_RTC_MDAY    => self.time.tm_mday   = self.from_rtc_format(val),
-            CMOS_RTC_MONTH   => self.time.tm_mon    = self.from_rtc_format(val),
-            CMOS_RTC_YEAR    => self.time.tm_year   = self.from_rtc_format(val) + self.time.tm_year / 100 * 100,
-            CMOS_RTC_CENTURY => self.time.tm_year   = self.from_rtc_format(val) * 100 + self.time.tm_year % 100,
+            CMOS_RTC_MONTH   => self.time.tm_mon    = self.from_rtc_format(val) - 1, // CMOS month starts from 1
+            CMOS_RTC_YEAR    => {
+                let year = self.year() / 100 * 100 + self.from_rtc_format(val);
+                self.set_year(year);
+            },
+            CMOS_RTC_CENTURY | CMOS_RTC_CENTURY_PS2 => {
+                let year = self.from_rtc_format(val) * 100 + self.year() % 100;
+                self.set_year(year);
+            },
 
             // Status
             CMOS_STA => {
+                let val = val & !CMOS_STA_UIP;
                 let diff = self.sta ^ val;
                 if (diff & !CMOS_STA_SUPPORTED) != 0 {
                     panic!("CMOS: setting unsupported STA bits");
@@ -180,9 +430,21 @@ impl CMOS
 #[cfg(test)]
 mod cmos_test {
 
-    use super::CMOS;
+    use super::{CMOS, CMOSDev};
+    use vm;
+    use clock::{self, virtual_clock, MockClock, ScaledClock};
+    use std::rc::Rc;
+    use std::cell::{Cell, RefCell};
     use time;
 
+    const NS_PER_SEC: u64 = super::NS_PER_SEC;
+
+    fn make_cmos() -> (CMOS, Rc<MockClock>)
+    {
+        let clock = Rc::new(MockClock::new());
+        (CMOS::new(clock.clone()), clock)
+    }
+
     fn read_reg(cmos: &mut CMOS, reg: u8) -> u8
     {
         let sel = (cmos.read_selector() & 0x80) | reg;
@@ -226,9 +488,9 @@ mod cmos_test {
     {
         let stb = read_reg(cmos, super::CMOS_STB);
         if is_bcd {
-            write_reg(cmos, super::CMOS_STB, stb | 0x04);
-        } else {
             write_reg(cmos, super::CMOS_STB, stb & !0x04);
+        } else {
+            write_reg(cmos, super::CMOS_STB, stb | 0x04);
         }
     }
 
@@ -242,9 +504,9 @@ mod cmos_test {
         tm.tm_hour = read_rtc_reg(cmos, is_bcd, super::CMOS_RTC_HOURS) as i32;
         tm.tm_wday = read_rtc_reg(cmos, is_bcd, super::CMOS_RTC_WDAY) as i32;
         tm.tm_mday = read_rtc_reg(cmos, is_bcd, super::CMOS_RTC_MDAY) as i32;
-        tm.tm_mon = read_rtc_reg(cmos, is_bcd, super::CMOS_RTC_MONTH) as i32;
+        tm.tm_mon = read_rtc_reg(cmos, is_bcd, super::CMOS_RTC_MONTH) as i32 - 1;
         tm.tm_year = (read_rtc_reg(cmos, is_bcd, super::CMOS_RTC_CENTURY) as i32) * 100
-                     + (read_rtc_reg(cmos, is_bcd, super::CMOS_RTC_YEAR) as i32);
+                     + (read_rtc_reg(cmos, is_bcd, super::CMOS_RTC_YEAR) as i32) - 1900;
 
         return tm;
     }
@@ -258,9 +520,9 @@ mod cmos_test {
         write_rtc_reg(cmos, is_bcd, super::CMOS_RTC_HOURS, tm.tm_hour as u8);
         write_rtc_reg(cmos, is_bcd, super::CMOS_RTC_WDAY, tm.tm_wday as u8);
         write_rtc_reg(cmos, is_bcd, super::CMOS_RTC_MDAY, tm.tm_mday as u8);
-        write_rtc_reg(cmos, is_bcd, super::CMOS_RTC_MONTH, tm.tm_mon as u8);
-        write_rtc_reg(cmos, is_bcd, super::CMOS_RTC_YEAR, (tm.tm_year % 100) as u8);
-        write_rtc_reg(cmos, is_bcd, super::CMOS_RTC_CENTURY, (tm.tm_year / 100) as u8);
+        write_rtc_reg(cmos, is_bcd, super::CMOS_RTC_MONTH, (tm.tm_mon + 1) as u8);
+        write_rtc_reg(cmos, is_bcd, super::CMOS_RTC_YEAR, ((tm.tm_year + 1900) % 100) as u8);
+        write_rtc_reg(cmos, is_bcd, super::CMOS_RTC_CENTURY, ((tm.tm_year + 1900) / 100) as u8);
     }
 
     fn checktime(t1: time::Tm, t2: time::Tm)
@@ -280,19 +542,20 @@ mod cmos_test {
     // Test initial state
     #[test] fn default()
     {
-        let cmos = CMOS::new();
+        let (cmos, _) = make_cmos();
 
         assert!(cmos.selector == super::CMOS_DEFAULT_SELECTOR);
         assert!(cmos.nmi_bit == false);
-        assert!(cmos.host_time == time::empty_tm());
-        assert!(cmos.time == time::empty_tm());
+        assert!(cmos.stc == 0);
+        assert!(cmos.subsec_ns == 0);
+        assert!((cmos.time - time::now()).num_seconds().abs() <= 1);
     }
 
 
     // Check that NMI bit is propogated to selector value
     #[test] fn nmi_bit()
     {
-        let mut cmos = CMOS::new();
+        let (mut cmos, _) = make_cmos();
 
         let mut sel = cmos.read_selector();
         assert!(sel & 0x80 == 0);
@@ -308,7 +571,7 @@ mod cmos_test {
 
     #[test] fn rtc()
     {
-        let mut cmos = CMOS::new();
+        let (mut cmos, clock) = make_cmos();
 
         // Test sane rtc values in BCD
         set_bcd(&mut cmos, true);
@@ -318,14 +581,11 @@ mod cmos_test {
         set_bcd(&mut cmos, false);
         rtc_test_common(&mut cmos);
 
-        // Check that eventually rtc will report a monotonic increase in time
-        loop {
-            let tm1 = gettime(&mut cmos);
-            let tm2 = gettime(&mut cmos);
-            if tm2 > tm1 {
-                break;
-            }
-        }
+        // Check that rtc reports a monotonic increase in time
+        let tm1 = gettime(&mut cmos);
+        clock.advance_ns(NS_PER_SEC);
+        let tm2 = gettime(&mut cmos);
+        assert!(tm2 > tm1);
 
         // Set rtc fields and read back
         set_bcd(&mut cmos, true);
@@ -336,6 +596,420 @@ mod cmos_test {
         settime(&mut cmos, time::empty_tm());
         checktime(gettime(&mut cmos), time::empty_tm());
     }
+
+    // Reference register encoding, independent of CMOS implementation
+    fn ref_encode(val: i32, binary: bool) -> u8
+    {
+        if binary {
+            val as u8
+        } else {
+            (((val / 10) << 4) | (val % 10)) as u8
+        }
+    }
+
+    fn ref_encode_hours(hour: i32, binary: bool, is_24h: bool) -> u8
+    {
+        if is_24h {
+            return ref_encode(hour, binary);
+        }
+
+        // 0 -> 12 AM, 1..11 -> AM, 12 -> 12 PM, 13..23 -> 1..11 PM
+        let table = [12, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+        let pm = if hour >= 12 { 0x80 } else { 0 };
+        ref_encode(table[(hour % 12) as usize], binary) | pm
+    }
+
+    fn set_mode(cmos: &mut CMOS, binary: bool, is_24h: bool)
+    {
+        let mut stb = read_reg(cmos, super::CMOS_STB) & !(super::CMOS_STB_BINARY | super::CMOS_STB_24H);
+        if binary {
+            stb |= super::CMOS_STB_BINARY;
+        }
+        if is_24h {
+            stb |= super::CMOS_STB_24H;
+        }
+        write_reg(cmos, super::CMOS_STB, stb);
+    }
+
+    // Check every clock register against reference encoding of given date
+    fn check_regs(cmos: &mut CMOS, date: (i32, i32, i32, i32, i32, i32), binary: bool, is_24h: bool)
+    {
+        let (year, month, mday, hour, min, sec) = date;
+
+        assert!(read_reg(cmos, super::CMOS_RTC_SECONDS) == ref_encode(sec, binary));
+        assert!(read_reg(cmos, super::CMOS_RTC_MINUTES) == ref_encode(min, binary));
+        assert!(read_reg(cmos, super::CMOS_RTC_HOURS) == ref_encode_hours(hour, binary, is_24h));
+        assert!(read_reg(cmos, super::CMOS_RTC_MDAY) == ref_encode(mday, binary));
+        assert!(read_reg(cmos, super::CMOS_RTC_MONTH) == ref_encode(month, binary));
+        assert!(read_reg(cmos, super::CMOS_RTC_YEAR) == ref_encode(year % 100, binary));
+        assert!(read_reg(cmos, super::CMOS_RTC_CENTURY) == ref_encode(year / 100, binary));
+        assert!(read_reg(cmos, super::CMOS_RTC_CENTURY_PS2) == ref_encode(year / 100, binary));
+    }
+
+    // Guest style clock set: hold SET, program registers in current mode, release SET
+    fn set_date(cmos: &mut CMOS, date: (i32, i32, i32, i32, i32, i32), binary: bool, is_24h: bool)
+    {
+        let (year, month, mday, hour, min, sec) = date;
+        let stb = read_reg(cmos, super::CMOS_STB);
+
+        write_reg(cmos, super::CMOS_STB, stb | super::CMOS_STB_SET);
+        write_reg(cmos, super::CMOS_RTC_SECONDS, ref_encode(sec, binary));
+        write_reg(cmos, super::CMOS_RTC_MINUTES, ref_encode(min, binary));
+        write_reg(cmos, super::CMOS_RTC_HOURS, ref_encode_hours(hour, binary, is_24h));
+        write_reg(cmos, super::CMOS_RTC_MDAY, ref_encode(mday, binary));
+        write_reg(cmos, super::CMOS_RTC_MONTH, ref_encode(month, binary));
+        write_reg(cmos, super::CMOS_RTC_YEAR, ref_encode(year % 100, binary));
+        write_reg(cmos, super::CMOS_RTC_CENTURY, ref_encode(year / 100, binary));
+    }
+
+    // Every combination of BCD/binary and 12/24 hour modes, flipped at runtime
+    #[test] fn mode_matrix()
+    {
+        let modes = [(false, false), (false, true), (true, false), (true, true)];
+        let hours = [0, 1, 11, 12, 13, 23];
+
+        for &(set_binary, set_24h) in modes.iter() {
+            for hour in hours.iter() {
+                let (mut cmos, _) = make_cmos();
+                let date = (2099, 12, 31, *hour, 59, 58);
+
+                // Time stays frozen while SET is held so every read is exact
+                set_mode(&mut cmos, set_binary, set_24h);
+                set_date(&mut cmos, date, set_binary, set_24h);
+
+                for &(binary, is_24h) in modes.iter() {
+                    set_mode(&mut cmos, binary, is_24h);
+                    check_regs(&mut cmos, date, binary, is_24h);
+                }
+            }
+        }
+    }
+
+    // Date rolls over to the next century and century registers follow
+    #[test] fn century_rollover()
+    {
+        let (mut cmos, clock) = make_cmos();
+
+        set_mode(&mut cmos, false, true);
+        set_date(&mut cmos, (1999, 12, 31, 23, 59, 50), false, true);
+        check_regs(&mut cmos, (1999, 12, 31, 23, 59, 50), false, true);
+
+        // Clock does not run while SET is held
+        clock.advance_ns(20 * NS_PER_SEC);
+        check_regs(&mut cmos, (1999, 12, 31, 23, 59, 50), false, true);
+
+        // Release SET and let the clock run past midnight
+        let stb = read_reg(&mut cmos, super::CMOS_STB);
+        write_reg(&mut cmos, super::CMOS_STB, stb & !super::CMOS_STB_SET);
+        clock.advance_ns(20 * NS_PER_SEC);
+        check_regs(&mut cmos, (2000, 1, 1, 0, 0, 10), false, true);
+
+        // Writing century alone keeps year within century
+        write_reg(&mut cmos, super::CMOS_RTC_CENTURY_PS2, 0x21);
+        assert!(read_reg(&mut cmos, super::CMOS_RTC_YEAR) == 0x00);
+        assert!(read_reg(&mut cmos, super::CMOS_RTC_CENTURY) == 0x21);
+
+        // 12 hour mode writes with PM flag
+        set_mode(&mut cmos, false, false);
+        write_reg(&mut cmos, super::CMOS_RTC_HOURS, 0x80 | 0x12);
+        assert!(cmos.time.tm_hour == 12);
+        write_reg(&mut cmos, super::CMOS_RTC_HOURS, 0x12);
+        assert!(cmos.time.tm_hour == 0);
+        write_reg(&mut cmos, super::CMOS_RTC_HOURS, 0x80 | 0x07);
+        assert!(cmos.time.tm_hour == 19);
+    }
+
+    // Guest polls UIP across second boundaries in small steps
+    #[test] fn update_in_progress()
+    {
+        let (mut cmos, clock) = make_cmos();
+        let step_ns = 7000;
+        let mut uip = false;
+        let mut pulses = 0;
+        let mut pulse_ns = 0;
+
+        while clock.now_ns() < 3 * NS_PER_SEC {
+            let sta = read_reg(&mut cmos, super::CMOS_STA);
+            let now = clock.now_ns();
+
+            if (sta & super::CMOS_STA_UIP) != 0 {
+                if !uip {
+                    pulses += 1;
+                    pulse_ns = now;
+                }
+                uip = true;
+                assert!(now % NS_PER_SEC >= NS_PER_SEC - super::CMOS_UIP_WINDOW_NS);
+            } else {
+                if uip {
+                    // Pulse lasted about 244 us and ended with the update
+                    assert!(now - pulse_ns <= super::CMOS_UIP_WINDOW_NS + step_ns);
+                    assert!(now % NS_PER_SEC < step_ns);
+                }
+                uip = false;
+            }
+
+            clock.advance_ns(step_ns);
+        }
+
+        assert!(pulses == 3);
+
+        // UIP seen clear just before the window, reads until the window ends all see the same time
+        for gap_ns in [1, 1000, step_ns].iter() {
+            let (mut cmos, clock) = make_cmos();
+            clock.advance_ns(NS_PER_SEC - super::CMOS_UIP_WINDOW_NS - gap_ns);
+            assert!((read_reg(&mut cmos, super::CMOS_STA) & super::CMOS_STA_UIP) == 0);
+
+            let tm = gettime(&mut cmos);
+            clock.advance_ns(super::CMOS_UIP_WINDOW_NS - 1);
+            assert!(gettime(&mut cmos) == tm);
+
+            // And the update is there right after
+            clock.advance_ns(*gap_ns + 1);
+            assert!(gettime(&mut cmos) > tm);
+        }
+    }
+
+    // Update ended flag is set on every update, interrupt only with UIE
+    #[test] fn update_ended()
+    {
+        let (mut cmos, clock) = make_cmos();
+
+        // Default rate sets periodic flag along, without interrupt
+        clock.advance_ns(NS_PER_SEC);
+        assert!(read_reg(&mut cmos, super::CMOS_STC) == super::CMOS_STC_UF | super::CMOS_STC_PF);
+        assert!(read_reg(&mut cmos, super::CMOS_STC) == 0);
+        assert!(!cmos.take_irq());
+
+        let stb = read_reg(&mut cmos, super::CMOS_STB);
+        write_reg(&mut cmos, super::CMOS_STB, stb | super::CMOS_STB_UIE);
+        clock.advance_ns(NS_PER_SEC / 2);
+        assert!(read_reg(&mut cmos, super::CMOS_STC) == super::CMOS_STC_PF);
+        assert!(cmos.next_update_ns() == NS_PER_SEC / 2);
+
+        clock.advance_ns(NS_PER_SEC / 2);
+        cmos.update_time();
+        assert!(cmos.take_irq());
+        assert!(!cmos.take_irq());
+        assert!(read_reg(&mut cmos, super::CMOS_STC) == super::CMOS_STC_UF | super::CMOS_STC_PF | super::CMOS_STC_IRQF);
+        assert!(read_reg(&mut cmos, super::CMOS_STC) == 0);
+
+        // Writing back register A as read keeps UIP out of it
+        clock.advance_ns(NS_PER_SEC - 1000);
+        let sta = read_reg(&mut cmos, super::CMOS_STA);
+        assert!((sta & super::CMOS_STA_UIP) != 0);
+        write_reg(&mut cmos, super::CMOS_STA, sta);
+        assert!(cmos.sta == super::CMOS_STA_DEFAULT);
+    }
+
+    // Rate select to period in 32.768 kHz ticks
+    #[test] fn periodic_rate()
+    {
+        let (mut cmos, _) = make_cmos();
+        assert!(cmos.periodic_ticks() == Some(32));
+
+        let rates = [(0, None), (1, Some(128)), (2, Some(256)), (3, Some(4)), (9, Some(256)), (15, Some(16384))];
+        for &(rate, ticks) in rates.iter() {
+            write_reg(&mut cmos, super::CMOS_STA, (super::CMOS_STA_DEFAULT & !super::CMOS_STA_RATE) | rate);
+            assert!(cmos.periodic_ticks() == ticks);
+        }
+
+        // Rate 0 never sets the flag
+        read_reg(&mut cmos, super::CMOS_STC);
+        cmos.clock_ns = 0;
+        cmos.update_time();
+        assert!(read_reg(&mut cmos, super::CMOS_STC) == 0);
+    }
+
+    // 1024 Hz periodic interrupt noticed late by varying amounts, one interrupt per period all the same
+    #[test] fn periodic_interrupt()
+    {
+        let (mut cmos, clock) = make_cmos();
+        let stb = read_reg(&mut cmos, super::CMOS_STB);
+        write_reg(&mut cmos, super::CMOS_STB, stb | super::CMOS_STB_PIE);
+
+        let mut irqs = 0;
+        for n in 1..1025 {
+            let deadline = clock::ticks_to_ns(n * 32, super::CMOS_RTC_FREQ_HZ);
+            clock.advance_ns(deadline + n * 7919 % 900000 - clock.now_ns());
+            cmos.update_time();
+            if cmos.take_irq() {
+                irqs += 1;
+            }
+            assert!(read_reg(&mut cmos, super::CMOS_STC) & !super::CMOS_STC_UF == super::CMOS_STC_PF | super::CMOS_STC_IRQF);
+        }
+        assert!(irqs == 1024);
+
+        // Three and a half periods late, one interrupt for all of them
+        clock.advance_ns(3 * NS_PER_SEC / 1024 + NS_PER_SEC / 2048);
+        cmos.update_time();
+        assert!(cmos.take_irq() && !cmos.take_irq());
+        read_reg(&mut cmos, super::CMOS_STC);
+
+        // Flag without interrupt once disabled
+        write_reg(&mut cmos, super::CMOS_STB, stb);
+        clock.advance_ns(NS_PER_SEC / 1024);
+        cmos.update_time();
+        assert!(!cmos.take_irq());
+        assert!(read_reg(&mut cmos, super::CMOS_STC) == super::CMOS_STC_PF);
+    }
+
+    thread_local! {
+        static IRQS: Cell<u32> = Cell::new(0);
+    }
+
+    fn count_irq(irq: u8)
+    {
+        assert!(irq == super::CMOS_IRQ);
+        IRQS.with(|irqs| irqs.set(irqs.get() + 1));
+    }
+
+    fn outb(dev: &CMOSDev, port: u16, val: u8)
+    {
+        vm::io_handler::io_write(dev, port, vm::IoOperandType::byte(val));
+    }
+
+    fn inb(dev: &CMOSDev, port: u16) -> u8
+    {
+        vm::io_handler::io_read(dev, port, 1).unwrap_byte()
+    }
+
+    // Reference divider chain: periodic interrupt times after a time, in whole periods from chain start
+    fn ref_irqs(start_ns: u64, period: u64, after_ns: u64, until_ns: u64) -> Vec<u64>
+    {
+        let period_ns = period as f64 * NS_PER_SEC as f64 / 32768.0;
+        let mut n = ((after_ns - start_ns) as f64 / period_ns) as u64;
+        let mut irqs = Vec::new();
+        loop {
+            let at = start_ns + (n as f64 * period_ns).ceil() as u64;
+            if at > until_ns {
+                return irqs;
+            }
+            if at > after_ns {
+                irqs.push(at);
+            }
+            n += 1;
+        }
+    }
+
+    // Guest changes periodic rate and enable bit mid-stream, interrupt service routine polls register C
+    #[test] fn periodic_rate_change()
+    {
+        const STEP_NS: u64 = 5000;
+        const END_NS: u64 = 110000000;
+
+        // Divider starts at oscillator tick 448
+        let start_ns = 13671875;
+        let clock = Rc::new(MockClock::new());
+        clock.advance_ns(start_ns);
+        let dev = CMOSDev {
+            cmos: RefCell::new(CMOS::new(clock.clone())),
+            assert_irq: count_irq,
+            periodic: Cell::new(None),
+        };
+
+        // (time, rate, periodic interrupt enable)
+        let changes = [(0, 6, true), (10300000, 3, true), (12000000, 10, true),
+                       (50000000, 10, false), (57700000, 10, true), (100000000, 6, true)];
+
+        let mut irqs = Vec::new();
+        let mut expected = Vec::new();
+        let mut t = 0;
+        while t <= END_NS {
+            clock.advance_ns(start_ns + t - clock.now_ns());
+
+            for (i, &(at, rate, pie)) in changes.iter().enumerate() {
+                if at != t {
+                    continue;
+                }
+
+                outb(&dev, 0x70, super::CMOS_STA);
+                outb(&dev, 0x71, (super::CMOS_STA_DEFAULT & !super::CMOS_STA_RATE) | rate);
+                outb(&dev, 0x70, super::CMOS_STB);
+                outb(&dev, 0x71, super::CMOS_STB_DEFAULT | if pie { super::CMOS_STB_PIE } else { 0 });
+
+                if pie {
+                    let period = dev.cmos.borrow().periodic_ticks().unwrap();
+                    let until = changes.get(i + 1).map_or(END_NS, |c| c.0);
+                    let reference = ref_irqs(start_ns, period, start_ns + at, start_ns + until);
+                    let (_, first, _) = dev.periodic.get().unwrap();
+                    assert!(clock::ticks_to_ns(first, super::CMOS_RTC_FREQ_HZ) == reference[0]);
+                    expected.extend(reference.iter().map(|at| (at - start_ns + STEP_NS - 1) / STEP_NS * STEP_NS));
+                } else {
+                    assert!(dev.periodic.get().is_none());
+                }
+            }
+
+            let before = IRQS.with(|irqs| irqs.get());
+            outb(&dev, 0x70, super::CMOS_STC);
+            inb(&dev, 0x71);
+            if IRQS.with(|irqs| irqs.get()) != before {
+                irqs.push(t);
+            }
+
+            t += STEP_NS;
+        }
+
+        assert!(irqs == expected);
+    }
+
+    // Clock follows dilated guest time, seconds at half rate take twice as long in host time
+    #[test] fn dilated_time()
+    {
+        let host = Rc::new(MockClock::new());
+        let clock = Rc::new(ScaledClock::new(host.clone()));
+        let mut cmos = CMOS::new(clock.clone());
+        let start = cmos.time;
+
+        host.advance_ns(4 * NS_PER_SEC);
+        cmos.update_time();
+        assert!((cmos.time - start).num_seconds() == 4);
+
+        assert!(clock.set_ratio(0.5).is_ok());
+        host.advance_ns(NS_PER_SEC);
+        assert!(cmos.next_update_ns() == NS_PER_SEC);
+        cmos.update_time();
+        assert!((cmos.time - start).num_seconds() == 4 && cmos.next_update_ns() == NS_PER_SEC / 2);
+
+        host.advance_ns(9 * NS_PER_SEC);
+        cmos.update_time();
+        assert!((cmos.time - start).num_seconds() == 9);
+        assert!(clock.now_ns() == 9 * NS_PER_SEC);
+    }
+
+    // Host wall clock an hour after real time
+    fn hour_later() -> time::Tm
+    {
+        time::now() + time::Duration::hours(1)
+    }
+
+    // Host sleeps for an hour under a guest reading the clock, returns how far RTC moved
+    fn host_sleep(resync: bool) -> i64
+    {
+        let host = Rc::new(MockClock::new());
+        let clock = Rc::new(ScaledClock::new(host.clone()));
+        clock.set_jump_policy(2 * NS_PER_SEC, true);
+        let mut cmos = CMOS::new(clock.clone());
+        cmos.resync_on_jump = resync;
+        cmos.wall_clock = hour_later;
+
+        host.advance_ns(NS_PER_SEC);
+        let before = gettime(&mut cmos);
+        host.advance_ns(3600 * NS_PER_SEC);
+        let after = gettime(&mut cmos);
+
+        assert!(clock.jumps() == 1 && clock.now_ns() == 3 * NS_PER_SEC);
+        (after - before).num_seconds()
+    }
+
+    // Guest time absorbs the jump, RTC either counts guest time or registers host time
+    #[test] fn host_jump()
+    {
+        let moved = host_sleep(false);
+        assert!(moved >= 1 && moved <= 3);
+
+        let moved = host_sleep(true);
+        assert!(moved >= 3599 && moved <= 3601);
+    }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -343,6 +1017,69 @@ mod cmos_test {
 struct CMOSDev
 {
     cmos: RefCell<CMOS>,
+    assert_irq: fn(u8),
+    periodic: Cell<Option<(event::TimerHandle, u64, u64)>>, // Periodic interrupt event, its first tick and period
+}
+
+impl CMOSDev
+{
+    /* Raise update ended interrupt if CMOS has one pending */
+    fn raise_irq(&self, cmos: &mut CMOS)
+    {
+        if cmos.take_irq() {
+            (self.assert_irq)(CMOS_IRQ);
+        }
+    }
+
+    /* Account seconds update, returns delay until the next one in microseconds */
+    fn update(&self) -> u64
+    {
+        let mut cmos = self.cmos.borrow_mut();
+
+        cmos.update_time();
+        self.raise_irq(&mut cmos);
+
+        cmos.next_update_ns() / 1000 + 1
+    }
+
+    /* Periodic event fired, missed periods went by while it was late */
+    fn periodic_expired(&self, missed: u64)
+    {
+        let mut cmos = self.cmos.borrow_mut();
+
+        if missed != 0 {
+            cmos.missed_periodic += missed;
+            debug!("cmos: {} periodic interrupts coalesced", missed);
+        }
+
+        cmos.update_time();
+        self.raise_irq(&mut cmos);
+    }
+
+    /* Keep periodic event armed at selected rate while periodic interrupt is enabled */
+    fn arm_periodic(&self, cmos: &CMOS)
+    {
+        let period = match cmos.periodic_ticks() {
+            Some(period) if (cmos.stb & CMOS_STB_PIE) != 0 => Some(period),
+            _ => None,
+        };
+
+        let armed = self.periodic.get();
+        if armed.map(|(_, _, p)| p) == period {
+            return;
+        }
+
+        if let Some((handle, _, _)) = armed {
+            event::cancel_event(handle);
+        }
+
+        /* New rate starts at its next period in divider phase, not a full period from now */
+        self.periodic.set(period.map(|period| {
+            let first = cmos.next_periodic(cmos.clock_ns, period);
+            let ev = event::create_event(periodic_event);
+            (event::schedule_periodic(first, period, CMOS_RTC_FREQ_HZ, ev), first, period)
+        }));
+    }
 }
 
 impl vm::io_handler for CMOSDev
@@ -360,7 +1097,9 @@ impl vm::io_handler for CMOSDev
             },
 
             CMOS_DATA_PORT => {
-                return vm::IoOperandType::byte(cmos.read_reg());
+                let val = cmos.read_reg();
+                self.raise_irq(&mut cmos);
+                return vm::IoOperandType::byte(val);
             }
 
             _ => {
@@ -384,6 +1123,8 @@ impl vm::io_handler for CMOSDev
 
             CMOS_DATA_PORT => {
                 cmos.write_reg(val);
+                self.raise_irq(&mut cmos);
+                self.arm_periodic(&cmos);
             }
 
             _ => {
@@ -393,13 +1134,72 @@ impl vm::io_handler for CMOSDev
     }
 }
 
+static mut CMOS_DEV: Option<*const CMOSDev> = None;
+
+/*
+ * Fires right after every seconds update, so update ended interrupts come in time
+ * even if guest does not touch CMOS
+ */
+fn update_event(ev: event::Event)
+{
+    let delay = unsafe {
+        match CMOS_DEV {
+            Some(dev) => {
+                let dev: &CMOSDev = mem::transmute(dev);
+                dev.update()
+            },
+            None => return,
+        }
+    };
+
+    event::schedule_event(delay, ev);
+}
+
+/* Periodic interrupt event, stays armed until rate or enable bit changes */
+fn periodic_event(ev: event::Event)
+{
+    unsafe {
+        if let Some(dev) = CMOS_DEV {
+            let dev: &CMOSDev = mem::transmute(dev);
+            dev.periodic_expired(ev.missed());
+        }
+    }
+}
+
+/**
+ * Set RTC to host wall clock after host time jumps that guest time doesn't follow
+ */
+pub fn set_rtc_resync(resync: bool)
+{
+    unsafe {
+        if let Some(dev) = CMOS_DEV {
+            let dev: &CMOSDev = mem::transmute(dev);
+            dev.cmos.borrow_mut().resync_on_jump = resync;
+        }
+    }
+}
+
+fn raise_irq(irq: u8)
+{
+    vm::assert_irq(irq);
+    vm::interrupt_guest();
+}
+
 pub fn init()
 { 
 	let dev = Rc::new(CMOSDev {
-        cmos: RefCell::new(CMOS::new()),
+        cmos: RefCell::new(CMOS::new(Rc::new(VcpuClock))),
+        assert_irq: raise_irq,
+        periodic: Cell::new(None),
     });
 
+    unsafe {
+        CMOS_DEV = Some(&*dev as *const CMOSDev);
+    }
+
     vm::register_io_region(dev.clone(), CMOS_SELECT_PORT, 1);
     vm::register_io_region(dev.clone(), CMOS_DATA_PORT, 1);
-}
 
+    let delay = dev.update();
+    event::schedule_event(delay, event::create_event(update_event));
+}
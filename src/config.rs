@@ -0,0 +1,1360 @@
+/*
+ * VM configuration
+ *
+ * Parsed from command line:
+ *   xvm [options] [test image]
+ *
+ * Options:
+ *   --headless             Don't render guest display on host terminal
+ *   --frame-dump <dir>     Dump guest graphics frames to directory as PPM files
+ *   --floppy <image>       Raw 1.44M floppy image for drive A:
+ *   --floppy-overlay <f>   Keep floppy image pristine, writes go to copy-on-write overlay file
+ *   --floppy-b <image>     Install drive B: with raw floppy image, 1.44M, 1.2M and 720K images work for both drives
+ *   --hda <image>          Raw hard disk image for primary ATA master
+ *   --hda-chs <c,h,s>      Override hard disk geometry derived from image size
+ *   --hda-ro               Attach hard disk image read-only, guest writes fail
+ *   --hda-grow <MiB>       Hard disk has given size, image file is created if needed and grows as guest writes
+ *   --hda-overlay <file>   Keep hard disk image pristine, writes go to copy-on-write overlay file
+ *   --flash <image>        Attach SST39SF0x0 style flash backed by image (size multiple of 4K), programs go to image
+ *   --flash-ro             Keep flash image pristine, guest programs and erases are discarded on exit
+ *   --flash-base <addr>    Guest physical address of flash (default 0xFF000000)
+ *   --cdrom <image>        ISO image to insert in secondary ATAPI CD-ROM drive
+ *   --lpt <file>           Capture LPT1 printer output to file
+ *   --net <backend>        Attach NE2000 card: pcap:<file> captures transmitted frames,
+ *                          tap:<ifname> connects to Linux tap interface (needs "tap" feature),
+ *                          user is NAT to host network through a built-in IP stack
+ *   --pm-timer <port>[,32] Add ACPI PM timer at I/O port (0x608 usual), 24 bit unless ",32" given
+ *   --vbe-lfb <addr>       Guest physical address of VBE linear framebuffer (default 0xE0000000)
+ *   --smbios               Place SMBIOS tables in BIOS segment (test images only)
+ *   --uuid <uuid>          System UUID reported in SMBIOS, e.g. 12345678-9abc-def0-0123-456789abcdef
+ *   --serial <backend>     Connect COM1: stdio, tcp:<port> listens on localhost, file:<path> captures output
+ *   --pvcon <backend>      Attach paravirtual console driven by hypercalls, backends as for --serial
+ *   --watchdog <s>[,<act>] Add watchdog device armed for s seconds (0 leaves it to guest), on expiry
+ *                          act is stop (default), reset or nmi
+ *   --pit-policy <p>       How late timer interrupts catch up: coalesce (default) repays them gradually,
+ *                          drop loses them, inject-all delivers them in a short burst
+ *   --tsc <mode>           Guest TSC source: offset (default) runs on host TSC with an offset, exiting
+ *                          computes every read from virtual time for deterministic runs
+ *   --time-dilation <r>    Run guest time at r times host rate, e.g. 0.5 for half speed (needs --tsc exiting)
+ *   --clock-jump <policy>  Host time leaping ahead, e.g. after host sleep: absorb (default) moves guest time
+ *                          by the threshold at most, rtc does too but sets RTC to new host time, follow
+ *                          lets guest time follow host and timers catch up all of it
+ *   --clock-jump-threshold <ms>  Host time step taken for a jump (default 2000)
+ *   --timer <mode>         Timer event delivery: host (default) fires events on a host thread that kicks vcpu,
+ *                          preemption uses VMX preemption timer to exit guest right at the next deadline
+ *   --apic                 Add local APIC at 0xFEE00000, PIC keeps ISA IRQ lines
+ *   --ioapic               Add IOAPIC at 0xFEC00000 and local APIC, device IRQ lines go to IOAPIC in APIC mode
+ *                          and in PIC mode when PIC masks them
+ *   --bios-assist          Handle int 10h text output in VMM when there is no video BIOS (test images only)
+ *   --gdb <port>           Listen for GDB remote protocol connections on local TCP port
+ *   --gdb-wait             Keep guest stopped before its first instruction until GDB attaches
+ *   --gdb-cs-relative      GDB addresses are offsets from CS base instead of guest physical addresses
+ *   --monitor <backend>    Monitor console on stdio or tcp:<port> listening on localhost
+ *   --break <addr>         Stop guest before it executes instruction at guest physical address, can be repeated
+ *   --io-break <port>[-<last>][,r|w]  Stop guest before it reads or writes ports, can be repeated
+ *   --vector-break <vec>   Stop guest before external interrupt vector is injected, can be repeated
+ *   --trace <file>         Log every guest instruction with its disassembly to file, needs monitor trap flag
+ *   --trace-range <first>-<last>  Trace only instructions at linear addresses in range
+ *   --crash-dir <dir>      Save a report of guest state to a timestamped file there when VM stops on a fatal error
+ *   --coredump <file>[,sparse]  Write guest memory, registers, device states and crash report to file when VM
+ *                          stops on a fatal error, sparse leaves out pages of zeros
+ *   --ram-image <file>[,<addr>]  Load flat file into RAM at guest physical address (default 0) before guest
+ *                          starts, over a restored snapshot too, can be repeated
+ *   --restore <file>[,<file>...]  Start guest from a snapshot "savevm" saved, VM needs the options it was saved
+ *                          with. Incremental snapshots follow their base in the order they were saved
+ *   --record <file>        Record guest inputs with the VM exit they came at to file for replay, starting
+ *                          from a snapshot saved to <file>.snap
+ *   --replay <file>        Run guest from the snapshot a recording starts from on inputs recorded there, VM
+ *                          stops at the first difference to the recorded run
+ *   --reverse <ms>[,<k>]   Save a checkpoint every ms of guest time while recording and keep the last k (default
+ *                          8), for reverse-step and reverse-continue from monitor or GDB (needs --record)
+ *   --event-log <file>     Log VM and device events to file as JSON lines
+ *   --event-filter <expr>  Log only events matching filter, e.g. "device == pic and vector == 8"
+ *   --io-filter <expr>     Keep only port accesses matching filter in I/O history of crash reports, e.g.
+ *                          "port in 0x1f0..0x1f7 and dir == write"
+ *   --summary <file>       Write exit, I/O and interrupt counts to file when VM stops, - prints them
+ *   --metrics <file>[,<s>] Write VM counters to file in Prometheus text format every s seconds (default 10)
+ *   --auto-snapshot <dir>,<s>[,host][,<k>]  Save a snapshot to dir every s seconds of guest time, or of host time
+ *                          with host, and keep the last k (default 3)
+ *   --panic-port <port>[,<act>]  Take guest panic messages at I/O port, act is stop (default) or log
+ *   --hang-detect <s>[,<act>]    Watch for guest spinning without progress for s seconds of guest time, act is
+ *                          log (default), report to log a crash report or stop VM with one
+ *   --symbols <map>[,<base>]  Load guest symbol map, nm or objdump -t output, with base added to its addresses
+ *                          to make them linear, can be repeated
+ *
+ *   --load <seg:off>       Load test image at real mode address (hex, default 0000:8000)
+ *   --entry <seg:off>      Start test image at real mode address, defaults to load address
+ *   --boot-sector          Test image is a boot sector to run at 0000:7C00
+ *   --boot-drive <n>       Drive number passed to boot sector in DL (default 0x80, 0xE0 for CD boot)
+ *   --boot-cd              Boot --cdrom image through its El Torito boot catalog instead of a test image
+ *
+ * Without a test image or --boot-cd VM boots firmware from bios/bios.bin
+ */
+
+use clock::{MIN_DILATION, MAX_DILATION};
+use breakpoint;
+use trace;
+use tracefilter::TraceFilter;
+
+use std::ops::Range;
+
+// Seconds between metrics writes when --metrics doesn't say
+const DEFAULT_METRICS_INTERVAL: u32 = 10;
+
+// Automatic snapshots kept when --auto-snapshot doesn't say
+const DEFAULT_AUTO_SNAPSHOT_KEEP: usize = 3;
+
+// Reverse debugging checkpoints kept when --reverse doesn't say
+const DEFAULT_REVERSE_KEEP: usize = 8;
+
+/**
+ * Network backend for the NIC
+ */
+#[derive(PartialEq, Debug)]
+pub enum NetConfig
+{
+    Pcap(String),       // Capture file
+    Tap(String),        // Host interface name
+    User,               // User mode NAT
+}
+
+/**
+ * Host end of COM1
+ */
+#[derive(PartialEq, Debug)]
+pub enum SerialConfig
+{
+    Stdio,              // Host terminal
+    Tcp(u16),           // Local TCP port to listen on
+    File(String),       // Output capture file
+}
+
+/**
+ * Placement of test image in guest memory
+ */
+#[derive(PartialEq, Debug)]
+pub enum LoadConfig
+{
+    Flat { load: (u16, u16), entry: (u16, u16) },   // Raw binary at segment:offset
+    BootSector { drive: u8 },                       // Boot sector at 0000:7C00
+    Cdrom { drive: u8 },                            // El Torito boot image of CD-ROM medium
+}
+
+/**
+ * ACPI PM timer placement
+ */
+#[derive(PartialEq, Debug)]
+pub struct PmTimerConfig
+{
+    pub port: u16,
+    pub wide: bool,     // 32 bit counter instead of 24 bit
+}
+
+/**
+ * What watchdog does when guest stops kicking it
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum WatchdogAction
+{
+    Reset,
+    Nmi,
+    Stop,
+}
+
+/**
+ * How PIT makes up for channel 0 interrupts missed while its timer fired late
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum TickPolicy
+{
+    Coalesce,           // One interrupt now, missed ones repaid over slightly shorter periods
+    Drop,               // One interrupt now, missed ones are lost
+    InjectAll,          // Missed ones delivered in a short burst of bounded length
+}
+
+/**
+ * How guest TSC reads are served
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum TscMode
+{
+    Offset,             // Guest reads host TSC directly, VMCS offset hides time VM spent paused
+    Exiting,            // RDTSC and RDTSCP exit, VMM returns ticks of virtual time
+}
+
+/**
+ * What large forward jumps of host time do to guest
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ClockJumpPolicy
+{
+    Absorb,             // Guest time moves by threshold at most, RTC keeps counting guest time
+    ResyncRtc,          // As Absorb, RTC registers jump to host wall clock
+    Follow,             // Guest time follows host, timers catch up the whole jump
+}
+
+/**
+ * How GDB addresses map to guest memory
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum GdbAddressing
+{
+    Physical,           // Guest physical, EIP is reported as CS base + EIP
+    CsRelative,         // Offset from CS base, EIP is reported as is
+}
+
+/**
+ * Where monitor console reads commands
+ */
+#[derive(PartialEq, Debug)]
+pub enum MonitorConfig
+{
+    Stdio,              // Host terminal
+    Tcp(u16),           // Local TCP port
+}
+
+/**
+ * GDB stub placement
+ */
+#[derive(PartialEq, Debug)]
+pub struct GdbConfig
+{
+    pub port: u16,
+    pub wait: bool,     // Guest doesn't start until GDB attaches
+    pub addressing: GdbAddressing,
+}
+
+/**
+ * How timer events get delivered while guest runs
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum TimerMode
+{
+    Host,               // Event loop thread sleeps until deadline and interrupts vcpu
+    Preemption,         // VMX preemption timer exits guest at deadline, vcpu thread fires events
+}
+
+#[derive(PartialEq, Debug)]
+pub struct WatchdogConfig
+{
+    pub timeout: u8,    // Seconds, 0 if not armed by host
+    pub action: WatchdogAction,
+}
+
+/**
+ * Guest panic beacon placement
+ */
+#[derive(PartialEq, Debug)]
+pub struct PanicBeaconConfig
+{
+    pub port: u16,
+    pub stop: bool,     // Guest panic stops VM, it is only reported otherwise
+}
+
+/**
+ * What hang detector does about a guest making no progress
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum HangAction
+{
+    Log,                // Warning only
+    Report,             // Crash report of guest state, VM goes on
+    Stop,               // Stop VM with crash report
+}
+
+#[derive(PartialEq, Debug)]
+pub struct HangConfig
+{
+    pub seconds: u32,   // Guest time without progress taken for a hang
+    pub action: HangAction,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct CoredumpConfig
+{
+    pub path: String,
+    pub sparse: bool,   // Leave out pages of zeros
+}
+
+#[derive(PartialEq, Debug)]
+pub struct MetricsConfig
+{
+    pub path: String,
+    pub interval: u32,  // Host seconds between writes
+}
+
+#[derive(PartialEq, Debug)]
+pub struct AutoSnapshotConfig
+{
+    pub dir: String,
+    pub seconds: u32,   // Time between snapshots
+    pub host: bool,     // Seconds are host time, guest time otherwise
+    pub keep: usize,    // Snapshot files kept
+}
+
+#[derive(PartialEq, Debug)]
+pub struct ReverseConfig
+{
+    pub interval_ms: u32,   // Guest time between checkpoints
+    pub keep: usize,        // Checkpoint files kept
+}
+
+/**
+ * VM configuration options
+ */
+pub struct VmConfig
+{
+    pub image: Option<String>,  // Test image to run without firmware
+    pub load: LoadConfig,       // How test image is loaded
+    pub headless: bool,         // Don't render guest display on host
+    pub frame_dump: Option<String>, // Directory to dump graphics frames to
+    pub floppy: Option<String>, // Floppy drive image
+    pub floppy_overlay: Option<String>, // Copy-on-write overlay over floppy image
+    pub floppy_b: Option<String>, // Floppy drive B image, no drive B if not set
+    pub hda: Option<String>,    // Primary master hard disk image
+    pub hda_chs: Option<(u16, u8, u8)>, // Hard disk geometry override
+    pub hda_read_only: bool,    // Hard disk image is not written
+    pub hda_grow: Option<u64>,  // Growable hard disk image size in bytes, image is fixed size if not set
+    pub hda_overlay: Option<String>, // Copy-on-write overlay over hard disk image
+    pub cdrom: Option<String>,  // CD-ROM medium image
+    pub flash: Option<String>,  // Flash memory image
+    pub flash_read_only: bool,  // Flash image is not written
+    pub flash_base: u64,        // Flash guest physical address
+    pub lpt: Option<String>,    // LPT1 printer capture file
+    pub net: Option<NetConfig>, // NIC backend, no NIC if not set
+    pub pm_timer: Option<PmTimerConfig>, // ACPI PM timer, none if not set
+    pub serial: Option<SerialConfig>, // COM1 backend, line is disconnected if not set
+    pub pvcon: Option<SerialConfig>, // Paravirtual console backend, no console if not set
+    pub watchdog: Option<WatchdogConfig>, // Watchdog device, none if not set
+    pub pit_policy: TickPolicy, // Late PIT interrupt catch-up policy
+    pub tsc_mode: TscMode,      // Guest TSC virtualization
+    pub time_dilation: f64,     // Guest time rate relative to host
+    pub timer_mode: TimerMode,  // Timer event delivery
+    pub clock_jump: ClockJumpPolicy, // Host time jump handling
+    pub clock_jump_threshold_ms: u64, // Host time step taken for a jump
+    pub vbe_lfb: u64,           // VBE linear framebuffer base
+    pub smbios: bool,           // Place SMBIOS tables in guest memory
+    pub uuid: Option<[u8; 16]>, // System UUID, big endian
+    pub bios_assist: bool,      // Emulate int 10h teletype services without video BIOS
+    pub apic: bool,             // Local APIC in front of PIC
+    pub ioapic: bool,           // IOAPIC takes device IRQ lines, needs local APIC
+    pub gdb: Option<GdbConfig>, // GDB stub, none if not set
+    pub monitor: Option<MonitorConfig>, // Monitor console, none if not set
+    pub breakpoints: Vec<u64>,  // INT3 breakpoints set before guest starts
+    pub io_breakpoints: Vec<(Range<u16>, breakpoint::IoDirection)>, // I/O breakpoints set before guest starts
+    pub vector_breakpoints: Vec<u8>, // Vector breakpoints set before guest starts
+    pub trace: Option<String>,  // Execution trace file, none if not set
+    pub trace_range: Option<Range<u64>>, // Linear addresses to trace, all if none
+    pub crash_dir: Option<String>, // Directory for crash reports, they are only printed if none
+    pub coredump: Option<CoredumpConfig>, // Core dump file written on fatal errors, none if not set
+    pub restore: Vec<String>,   // Snapshot guest starts from and increments over it, none to boot
+    pub record: Option<String>, // Input recording file, none if not set
+    pub replay: Option<String>, // Recording to replay, none if not set
+    pub reverse: Option<ReverseConfig>, // Checkpoints of recorded run for reverse debugging, none if not set
+    pub event_log: Option<String>, // JSON lines event log file, none if not set
+    pub event_filter: Option<TraceFilter>, // Events logged, all if none
+    pub io_filter: Option<TraceFilter>, // Port accesses kept for crash reports, all if none
+    pub summary: Option<String>, // Run summary file or - for stdout, none if not set
+    pub metrics: Option<MetricsConfig>, // Metrics file, none if not set
+    pub auto_snapshot: Option<AutoSnapshotConfig>, // Periodic snapshots, none if not set
+    pub panic_beacon: Option<PanicBeaconConfig>, // Guest panic port, none if not set
+    pub hang: Option<HangConfig>, // Hang detector, none if not set
+    pub symbols: Vec<(String, u64)>, // Guest symbol maps with base of their addresses
+    pub ram_images: Vec<(String, u64)>, // Flat RAM images with guest physical address they load at
+}
+
+impl VmConfig
+{
+    pub fn default() -> VmConfig {
+        VmConfig {
+            image: None,
+            load: LoadConfig::Flat { load: (0, 0x8000), entry: (0, 0x8000) },
+            headless: false,
+            frame_dump: None,
+            floppy: None,
+            floppy_overlay: None,
+            floppy_b: None,
+            hda: None,
+            hda_chs: None,
+            hda_read_only: false,
+            hda_grow: None,
+            hda_overlay: None,
+            cdrom: None,
+            flash: None,
+            flash_read_only: false,
+            flash_base: 0xFF000000,
+            lpt: None,
+            net: None,
+            pm_timer: None,
+            serial: None,
+            pvcon: None,
+            watchdog: None,
+            pit_policy: TickPolicy::Coalesce,
+            tsc_mode: TscMode::Offset,
+            time_dilation: 1.0,
+            timer_mode: TimerMode::Host,
+            clock_jump: ClockJumpPolicy::Absorb,
+            clock_jump_threshold_ms: 2000,
+            vbe_lfb: 0xE0000000,
+            smbios: false,
+            uuid: None,
+            bios_assist: false,
+            apic: false,
+            ioapic: false,
+            gdb: None,
+            monitor: None,
+            breakpoints: Vec::new(),
+            io_breakpoints: Vec::new(),
+            vector_breakpoints: Vec::new(),
+            trace: None,
+            trace_range: None,
+            crash_dir: None,
+            event_log: None,
+            event_filter: None,
+            io_filter: None,
+            summary: None,
+            metrics: None,
+            auto_snapshot: None,
+            coredump: None,
+            restore: Vec::new(),
+            record: None,
+            replay: None,
+            reverse: None,
+            panic_beacon: None,
+            hang: None,
+            symbols: Vec::new(),
+            ram_images: Vec::new(),
+        }
+    }
+
+    /**
+     * Should we boot firmware or a test image
+     */
+    pub fn has_bios(&self) -> bool {
+        match self.load {
+            LoadConfig::Cdrom { .. } => false,
+            _ => self.image.is_none(),
+        }
+    }
+}
+
+/* Take value of an option from the following argument */
+fn option_value<'a, I: Iterator<Item = &'a String>>(iter: &mut I, option: &str) -> Result<String, String>
+{
+    match iter.next() {
+        Some(val) => Ok(val.clone()),
+        None => Err(format!("{} requires a value", option)),
+    }
+}
+
+/* Parse "c,h,s" disk geometry within ATA limits */
+fn parse_chs(val: &str) -> Result<(u16, u8, u8), String>
+{
+    let err = format!("Bad geometry {}, expected cylinders,heads,sectors", val);
+    let parts: Vec<&str> = val.split(',').collect();
+    if parts.len() != 3 {
+        return Err(err);
+    }
+
+    match (parts[0].parse::<u16>(), parts[1].parse::<u8>(), parts[2].parse::<u8>()) {
+        (Ok(c), Ok(h), Ok(s)) if c > 0 && h > 0 && h <= 16 && s > 0 && s <= 63 => Ok((c, h, s)),
+        _ => Err(err),
+    }
+}
+
+/* Parse growable disk size in MiB, up to what LBA28 addresses */
+fn parse_disk_size(val: &str) -> Result<u64, String>
+{
+    match val.parse::<u64>() {
+        Ok(mib) if mib > 0 && mib <= 128 * 1024 => Ok(mib << 20),
+        _ => Err(format!("Bad disk size {}, expected 1 to 131072 MiB", val)),
+    }
+}
+
+/* Parse "kind:value" network backend */
+fn parse_net(val: &str) -> Result<NetConfig, String>
+{
+    let mut parts = val.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some("pcap"), Some(path)) if !path.is_empty() => Ok(NetConfig::Pcap(String::from(path))),
+        (Some("tap"), Some(ifname)) if !ifname.is_empty() => Ok(NetConfig::Tap(String::from(ifname))),
+        (Some("user"), None) => Ok(NetConfig::User),
+        _ => Err(format!("Bad network backend {}, expected pcap:<file>, tap:<ifname> or user", val)),
+    }
+}
+
+/* Parse "port[,32]" PM timer placement, port is decimal or 0x prefixed hex */
+fn parse_pm_timer(val: &str) -> Result<PmTimerConfig, String>
+{
+    let err = format!("Bad PM timer {}, expected <port>[,32]", val);
+    let mut parts = val.splitn(2, ',');
+
+    let port = match parts.next() {
+        Some(p) if p.starts_with("0x") => u16::from_str_radix(&p[2..], 16),
+        Some(p) => p.parse::<u16>(),
+        None => return Err(err),
+    };
+
+    match (port, parts.next()) {
+        (Ok(port), None) => Ok(PmTimerConfig { port: port, wide: false }),
+        (Ok(port), Some("32")) => Ok(PmTimerConfig { port: port, wide: true }),
+        _ => Err(err),
+    }
+}
+
+/* Parse "seconds[,action]" watchdog setting */
+fn parse_watchdog(val: &str) -> Result<WatchdogConfig, String>
+{
+    let err = format!("Bad watchdog {}, expected <seconds>[,stop|reset|nmi]", val);
+    let mut parts = val.splitn(2, ',');
+
+    let timeout = match parts.next().map(|t| t.parse::<u8>()) {
+        Some(Ok(timeout)) => timeout,
+        _ => return Err(err),
+    };
+
+    let action = match parts.next() {
+        None | Some("stop") => WatchdogAction::Stop,
+        Some("reset") => WatchdogAction::Reset,
+        Some("nmi") => WatchdogAction::Nmi,
+        _ => return Err(err),
+    };
+
+    Ok(WatchdogConfig { timeout: timeout, action: action })
+}
+
+/* Parse "port[,action]" panic beacon placement, port is decimal or 0x prefixed hex */
+fn parse_panic_beacon(val: &str) -> Result<PanicBeaconConfig, String>
+{
+    let err = format!("Bad panic port {}, expected <port>[,stop|log]", val);
+    let mut parts = val.splitn(2, ',');
+
+    let port = match parts.next() {
+        Some(p) if p.starts_with("0x") => u16::from_str_radix(&p[2..], 16),
+        Some(p) => p.parse::<u16>(),
+        None => return Err(err),
+    };
+
+    match (port, parts.next()) {
+        (Ok(port), None) | (Ok(port), Some("stop")) => Ok(PanicBeaconConfig { port: port, stop: true }),
+        (Ok(port), Some("log")) => Ok(PanicBeaconConfig { port: port, stop: false }),
+        _ => Err(err),
+    }
+}
+
+/* Parse "seconds[,action]" hang detector setting */
+fn parse_hang(val: &str) -> Result<HangConfig, String>
+{
+    let err = format!("Bad hang detector {}, expected <seconds>[,log|report|stop]", val);
+    let mut parts = val.splitn(2, ',');
+
+    let seconds = match parts.next().map(|s| s.parse::<u32>()) {
+        Some(Ok(seconds)) if seconds > 0 => seconds,
+        _ => return Err(err),
+    };
+
+    let action = match parts.next() {
+        None | Some("log") => HangAction::Log,
+        Some("report") => HangAction::Report,
+        Some("stop") => HangAction::Stop,
+        _ => return Err(err),
+    };
+
+    Ok(HangConfig { seconds: seconds, action: action })
+}
+
+/* Parse "file[,sparse]" core dump */
+fn parse_coredump(val: &str) -> Result<CoredumpConfig, String>
+{
+    let sparse = val.ends_with(",sparse");
+    let path = if sparse { &val[..val.len() - ",sparse".len()] } else { val };
+    if path.is_empty() {
+        return Err(format!("Bad core dump file {}, expected <file>[,sparse]", val));
+    }
+    Ok(CoredumpConfig { path: String::from(path), sparse: sparse })
+}
+
+/* Parse "file[,seconds]" metrics export */
+fn parse_metrics(val: &str) -> Result<MetricsConfig, String>
+{
+    let mut parts = val.rsplitn(2, ',');
+    let (last, first) = (parts.next().unwrap_or(""), parts.next());
+
+    let (path, interval) = match first {
+        Some(path) => match last.parse::<u32>() {
+            Ok(interval) if interval > 0 => (path, interval),
+            _ => return Err(format!("Bad metrics interval {}, expected seconds", last)),
+        },
+        None => (last, DEFAULT_METRICS_INTERVAL),
+    };
+
+    if path.is_empty() {
+        return Err(format!("Bad metrics file {}, expected <file>[,<seconds>]", val));
+    }
+    Ok(MetricsConfig { path: String::from(path), interval: interval })
+}
+
+/* Parse "dir,seconds[,host][,keep]" automatic snapshots */
+fn parse_auto_snapshot(val: &str) -> Result<AutoSnapshotConfig, String>
+{
+    let err = format!("Bad automatic snapshots {}, expected <dir>,<seconds>[,host][,<keep>]", val);
+    let parts: Vec<&str> = val.split(',').collect();
+    if parts.len() < 2 || parts.len() > 4 || parts[0].is_empty() {
+        return Err(err);
+    }
+
+    let seconds = match parts[1].parse::<u32>() {
+        Ok(seconds) if seconds > 0 => seconds,
+        _ => return Err(err),
+    };
+
+    let mut config = AutoSnapshotConfig { dir: String::from(parts[0]), seconds: seconds, host: false, keep: DEFAULT_AUTO_SNAPSHOT_KEEP };
+    let mut rest = &parts[2..];
+    if rest.first() == Some(&"host") {
+        config.host = true;
+        rest = &rest[1..];
+    }
+    match rest.first().map(|keep| keep.parse::<usize>()) {
+        None if rest.is_empty() => {},
+        Some(Ok(keep)) if keep > 0 && rest.len() == 1 => config.keep = keep,
+        _ => return Err(err),
+    }
+    Ok(config)
+}
+
+/* Parse "ms[,keep]" reverse debugging checkpoints */
+fn parse_reverse(val: &str) -> Result<ReverseConfig, String>
+{
+    let err = format!("Bad reverse checkpoints {}, expected <ms>[,<keep>]", val);
+    let parts: Vec<&str> = val.split(',').collect();
+    if parts.len() > 2 {
+        return Err(err);
+    }
+
+    let interval_ms = match parts[0].parse::<u32>() {
+        Ok(ms) if ms > 0 => ms,
+        _ => return Err(err),
+    };
+    let keep = match parts.get(1).map(|keep| keep.parse::<usize>()) {
+        None => DEFAULT_REVERSE_KEEP,
+        Some(Ok(keep)) if keep > 0 => keep,
+        _ => return Err(err),
+    };
+    Ok(ReverseConfig { interval_ms: interval_ms, keep: keep })
+}
+
+/* Parse "file[,addr]" RAM image, addr is decimal or 0x prefixed hex */
+fn parse_ram_image(val: &str) -> Result<(String, u64), String>
+{
+    let err = format!("Bad RAM image {}, expected <file>[,<addr>]", val);
+    let mut parts = val.rsplitn(2, ',');
+    let (last, first) = (parts.next().unwrap_or(""), parts.next());
+
+    let (path, base) = match first {
+        Some(path) => {
+            let base = if last.starts_with("0x") { u64::from_str_radix(&last[2..], 16) } else { last.parse::<u64>() };
+            match base {
+                Ok(base) => (path, base),
+                Err(_) => return Err(err),
+            }
+        },
+        None => (last, 0),
+    };
+
+    if path.is_empty() {
+        return Err(err);
+    }
+    Ok((String::from(path), base))
+}
+
+/* Parse "map[,base]" symbol map, base is decimal or 0x prefixed hex */
+fn parse_symbols(val: &str) -> Result<(String, u64), String>
+{
+    let mut parts = val.rsplitn(2, ',');
+    let (last, first) = (parts.next().unwrap_or(""), parts.next());
+
+    let (path, base) = match first {
+        Some(path) => {
+            let base = if last.starts_with("0x") { u64::from_str_radix(&last[2..], 16) } else { last.parse::<u64>() };
+            match base {
+                Ok(base) => (path, base),
+                Err(_) => return Err(format!("Bad symbol map {}, expected <map>[,<base>]", val)),
+            }
+        },
+        None => (last, 0),
+    };
+
+    if path.is_empty() {
+        return Err(format!("Bad symbol map {}, expected <map>[,<base>]", val));
+    }
+    Ok((String::from(path), base))
+}
+
+/* Parse PIT catch-up policy name */
+fn parse_tick_policy(val: &str) -> Result<TickPolicy, String>
+{
+    match val {
+        "coalesce" => Ok(TickPolicy::Coalesce),
+        "drop" => Ok(TickPolicy::Drop),
+        "inject-all" => Ok(TickPolicy::InjectAll),
+        _ => Err(format!("Bad PIT policy {}, expected coalesce, drop or inject-all", val)),
+    }
+}
+
+/* Parse TSC mode name */
+fn parse_tsc_mode(val: &str) -> Result<TscMode, String>
+{
+    match val {
+        "offset" => Ok(TscMode::Offset),
+        "exiting" => Ok(TscMode::Exiting),
+        _ => Err(format!("Bad TSC mode {}, expected offset or exiting", val)),
+    }
+}
+
+/* Parse timer delivery mode name */
+fn parse_timer_mode(val: &str) -> Result<TimerMode, String>
+{
+    match val {
+        "host" => Ok(TimerMode::Host),
+        "preemption" => Ok(TimerMode::Preemption),
+        _ => Err(format!("Bad timer mode {}, expected host or preemption", val)),
+    }
+}
+
+/* Parse host time jump policy name */
+fn parse_clock_jump(val: &str) -> Result<ClockJumpPolicy, String>
+{
+    match val {
+        "absorb" => Ok(ClockJumpPolicy::Absorb),
+        "rtc" => Ok(ClockJumpPolicy::ResyncRtc),
+        "follow" => Ok(ClockJumpPolicy::Follow),
+        _ => Err(format!("Bad clock jump policy {}, expected absorb, rtc or follow", val)),
+    }
+}
+
+/* Parse host time jump threshold in milliseconds */
+fn parse_jump_threshold(val: &str) -> Result<u64, String>
+{
+    match val.parse::<u64>() {
+        Ok(ms) if ms != 0 => Ok(ms),
+        _ => Err(format!("Bad clock jump threshold {}, expected milliseconds", val)),
+    }
+}
+
+/* Parse time dilation ratio */
+fn parse_dilation(val: &str) -> Result<f64, String>
+{
+    match val.parse::<f64>() {
+        Ok(ratio) if ratio >= MIN_DILATION && ratio <= MAX_DILATION => Ok(ratio),
+        _ => Err(format!("Bad time dilation {}, expected ratio from {} to {}", val, MIN_DILATION, MAX_DILATION)),
+    }
+}
+
+/* Parse page aligned 32 bit guest physical address, decimal or 0x prefixed hex */
+fn parse_address(val: &str) -> Result<u64, String>
+{
+    let addr = if val.starts_with("0x") {
+        u64::from_str_radix(&val[2..], 16)
+    } else {
+        val.parse::<u64>()
+    };
+
+    match addr {
+        Ok(addr) if addr & 0xFFF == 0 && addr < 0x100000000 => Ok(addr),
+        _ => Err(format!("Bad address {}, expected page aligned 32 bit address", val)),
+    }
+}
+
+/* Parse UUID in canonical 8-4-4-4-12 hex digit form */
+fn parse_uuid(val: &str) -> Result<[u8; 16], String>
+{
+    let err = format!("Bad UUID {}, expected xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx", val);
+    let groups: Vec<&str> = val.split('-').collect();
+    if groups.iter().map(|g| g.len()).collect::<Vec<usize>>() != vec![8, 4, 4, 4, 12] {
+        return Err(err);
+    }
+
+    let digits: String = groups.concat();
+    let mut uuid = [0u8; 16];
+    for i in 0..16 {
+        uuid[i] = match u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16) {
+            Ok(b) => b,
+            Err(_) => return Err(err),
+        };
+    }
+
+    Ok(uuid)
+}
+
+/* Parse hex "segment:offset" real mode address */
+fn parse_segoff(val: &str) -> Result<(u16, u16), String>
+{
+    let mut parts = val.splitn(2, ':');
+    match (parts.next().map(|p| u16::from_str_radix(p, 16)), parts.next().map(|p| u16::from_str_radix(p, 16))) {
+        (Some(Ok(seg)), Some(Ok(off))) => Ok((seg, off)),
+        _ => Err(format!("Bad address {}, expected hex segment:offset", val)),
+    }
+}
+
+/* Parse drive number, decimal or 0x prefixed hex */
+fn parse_drive(val: &str) -> Result<u8, String>
+{
+    let drive = if val.starts_with("0x") {
+        u8::from_str_radix(&val[2..], 16)
+    } else {
+        val.parse::<u8>()
+    };
+
+    drive.map_err(|_| format!("Bad drive number {}", val))
+}
+
+/* Parse "stdio", "tcp:port" or "file:path" serial backend */
+fn parse_serial(val: &str) -> Result<SerialConfig, String>
+{
+    let mut parts = val.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some("stdio"), None) => Ok(SerialConfig::Stdio),
+        (Some("tcp"), Some(port)) if port.parse::<u16>().is_ok() => Ok(SerialConfig::Tcp(port.parse().unwrap())),
+        (Some("file"), Some(path)) if !path.is_empty() => Ok(SerialConfig::File(String::from(path))),
+        _ => Err(format!("Bad serial backend {}, expected stdio, tcp:<port> or file:<path>", val)),
+    }
+}
+
+/* Parse 32 bit guest physical breakpoint address, decimal or 0x prefixed hex */
+fn parse_breakpoint(val: &str) -> Result<u64, String>
+{
+    let addr = if val.starts_with("0x") {
+        u64::from_str_radix(&val[2..], 16)
+    } else {
+        val.parse::<u64>()
+    };
+
+    match addr {
+        Ok(addr) if addr < 0x100000000 => Ok(addr),
+        _ => Err(format!("Bad breakpoint address {}, expected 32 bit address", val)),
+    }
+}
+
+/* Parse "stdio" or "tcp:port" monitor backend */
+fn parse_monitor(val: &str) -> Result<MonitorConfig, String>
+{
+    match try!(parse_serial(val).map_err(|_| format!("Bad monitor backend {}, expected stdio or tcp:<port>", val))) {
+        SerialConfig::Stdio => Ok(MonitorConfig::Stdio),
+        SerialConfig::Tcp(port) => Ok(MonitorConfig::Tcp(port)),
+        _ => Err(format!("Bad monitor backend {}, expected stdio or tcp:<port>", val)),
+    }
+}
+
+/* Parse GDB stub TCP port */
+fn parse_gdb_port(val: &str) -> Result<u16, String>
+{
+    match val.parse::<u16>() {
+        Ok(port) if port != 0 => Ok(port),
+        _ => Err(format!("Bad GDB port {}", val)),
+    }
+}
+
+/**
+ * Parse command line arguments (not including program name)
+ */
+pub fn parse(args: &[String]) -> Result<VmConfig, String>
+{
+    let mut config = VmConfig::default();
+    let mut iter = args.iter();
+
+    let mut load = None;
+    let mut entry = None;
+    let mut boot_sector = false;
+    let mut boot_drive = None;
+    let mut boot_cd = false;
+    let mut gdb_wait = false;
+    let mut gdb_cs_relative = false;
+
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--headless" => config.headless = true,
+            "--frame-dump" => config.frame_dump = Some(try!(option_value(&mut iter, arg))),
+            "--floppy" => config.floppy = Some(try!(option_value(&mut iter, arg))),
+            "--floppy-overlay" => config.floppy_overlay = Some(try!(option_value(&mut iter, arg))),
+            "--floppy-b" => config.floppy_b = Some(try!(option_value(&mut iter, arg))),
+            "--hda" => config.hda = Some(try!(option_value(&mut iter, arg))),
+            "--hda-chs" => config.hda_chs = Some(try!(parse_chs(&try!(option_value(&mut iter, arg))))),
+            "--hda-ro" => config.hda_read_only = true,
+            "--hda-grow" => config.hda_grow = Some(try!(parse_disk_size(&try!(option_value(&mut iter, arg))))),
+            "--hda-overlay" => config.hda_overlay = Some(try!(option_value(&mut iter, arg))),
+            "--cdrom" => config.cdrom = Some(try!(option_value(&mut iter, arg))),
+            "--flash" => config.flash = Some(try!(option_value(&mut iter, arg))),
+            "--flash-ro" => config.flash_read_only = true,
+            "--flash-base" => config.flash_base = try!(parse_address(&try!(option_value(&mut iter, arg)))),
+            "--lpt" => config.lpt = Some(try!(option_value(&mut iter, arg))),
+            "--net" => config.net = Some(try!(parse_net(&try!(option_value(&mut iter, arg))))),
+            "--pm-timer" => config.pm_timer = Some(try!(parse_pm_timer(&try!(option_value(&mut iter, arg))))),
+            "--vbe-lfb" => config.vbe_lfb = try!(parse_address(&try!(option_value(&mut iter, arg)))),
+            "--smbios" => config.smbios = true,
+            "--uuid" => config.uuid = Some(try!(parse_uuid(&try!(option_value(&mut iter, arg))))),
+            "--load" => load = Some(try!(parse_segoff(&try!(option_value(&mut iter, arg))))),
+            "--entry" => entry = Some(try!(parse_segoff(&try!(option_value(&mut iter, arg))))),
+            "--boot-sector" => boot_sector = true,
+            "--boot-drive" => boot_drive = Some(try!(parse_drive(&try!(option_value(&mut iter, arg))))),
+            "--boot-cd" => boot_cd = true,
+            "--serial" => config.serial = Some(try!(parse_serial(&try!(option_value(&mut iter, arg))))),
+            "--pvcon" => config.pvcon = Some(try!(parse_serial(&try!(option_value(&mut iter, arg))))),
+            "--watchdog" => config.watchdog = Some(try!(parse_watchdog(&try!(option_value(&mut iter, arg))))),
+            "--pit-policy" => config.pit_policy = try!(parse_tick_policy(&try!(option_value(&mut iter, arg)))),
+            "--tsc" => config.tsc_mode = try!(parse_tsc_mode(&try!(option_value(&mut iter, arg)))),
+            "--time-dilation" => config.time_dilation = try!(parse_dilation(&try!(option_value(&mut iter, arg)))),
+            "--clock-jump" => config.clock_jump = try!(parse_clock_jump(&try!(option_value(&mut iter, arg)))),
+            "--clock-jump-threshold" => config.clock_jump_threshold_ms = try!(parse_jump_threshold(&try!(option_value(&mut iter, arg)))),
+            "--timer" => config.timer_mode = try!(parse_timer_mode(&try!(option_value(&mut iter, arg)))),
+            "--bios-assist" => config.bios_assist = true,
+            "--apic" => config.apic = true,
+            "--ioapic" => {
+                config.apic = true;
+                config.ioapic = true;
+            },
+            "--gdb" => config.gdb = Some(GdbConfig {
+                port: try!(parse_gdb_port(&try!(option_value(&mut iter, arg)))),
+                wait: false,
+                addressing: GdbAddressing::Physical,
+            }),
+            "--gdb-wait" => gdb_wait = true,
+            "--gdb-cs-relative" => gdb_cs_relative = true,
+            "--monitor" => config.monitor = Some(try!(parse_monitor(&try!(option_value(&mut iter, arg))))),
+            "--break" => config.breakpoints.push(try!(parse_breakpoint(&try!(option_value(&mut iter, arg))))),
+            "--io-break" => config.io_breakpoints.push(try!(breakpoint::parse_io_breakpoint(&try!(option_value(&mut iter, arg))))),
+            "--vector-break" => config.vector_breakpoints.push(try!(breakpoint::parse_vector(&try!(option_value(&mut iter, arg))))),
+            "--trace" => config.trace = Some(try!(option_value(&mut iter, arg))),
+            "--trace-range" => config.trace_range = Some(try!(trace::parse_range(&try!(option_value(&mut iter, arg))))),
+            "--crash-dir" => config.crash_dir = Some(try!(option_value(&mut iter, arg))),
+            "--event-log" => config.event_log = Some(try!(option_value(&mut iter, arg))),
+            "--event-filter" => config.event_filter = Some(try!(TraceFilter::parse(&try!(option_value(&mut iter, arg))))),
+            "--io-filter" => config.io_filter = Some(try!(TraceFilter::parse(&try!(option_value(&mut iter, arg))))),
+            "--summary" => config.summary = Some(try!(option_value(&mut iter, arg))),
+            "--metrics" => config.metrics = Some(try!(parse_metrics(&try!(option_value(&mut iter, arg))))),
+            "--auto-snapshot" => config.auto_snapshot = Some(try!(parse_auto_snapshot(&try!(option_value(&mut iter, arg))))),
+            "--coredump" => config.coredump = Some(try!(parse_coredump(&try!(option_value(&mut iter, arg))))),
+            "--restore" => config.restore = try!(option_value(&mut iter, arg)).split(',').map(String::from).collect(),
+            "--record" => config.record = Some(try!(option_value(&mut iter, arg))),
+            "--replay" => config.replay = Some(try!(option_value(&mut iter, arg))),
+            "--reverse" => config.reverse = Some(try!(parse_reverse(&try!(option_value(&mut iter, arg))))),
+            "--panic-port" => config.panic_beacon = Some(try!(parse_panic_beacon(&try!(option_value(&mut iter, arg))))),
+            "--hang-detect" => config.hang = Some(try!(parse_hang(&try!(option_value(&mut iter, arg))))),
+            "--symbols" => config.symbols.push(try!(parse_symbols(&try!(option_value(&mut iter, arg))))),
+            "--ram-image" => config.ram_images.push(try!(parse_ram_image(&try!(option_value(&mut iter, arg))))),
+
+            _ => {
+                if arg.starts_with("--") {
+                    return Err(format!("Unknown option {}", arg));
+                }
+
+                if config.image.is_some() {
+                    return Err(format!("Unexpected argument {}", arg));
+                }
+
+                config.image = Some(arg.clone());
+            }
+        }
+    }
+
+    if config.event_filter.is_some() && config.event_log.is_none() {
+        return Err(String::from("Event filter needs --event-log file"));
+    }
+
+    if config.time_dilation != 1.0 && config.tsc_mode == TscMode::Offset {
+        return Err(String::from("Time dilation needs --tsc exiting, host TSC can't be slowed down"));
+    }
+
+    match config.gdb {
+        Some(ref mut gdb) => {
+            gdb.wait = gdb_wait;
+            gdb.addressing = if gdb_cs_relative { GdbAddressing::CsRelative } else { GdbAddressing::Physical };
+        },
+        None if gdb_wait || gdb_cs_relative => return Err(String::from("GDB options need --gdb port")),
+        None => {},
+    }
+
+    if config.monitor == Some(MonitorConfig::Stdio) &&
+       (config.serial == Some(SerialConfig::Stdio) || config.pvcon == Some(SerialConfig::Stdio)) {
+        return Err(String::from("Monitor and serial console can't both use stdio"));
+    }
+
+    if config.replay.is_some() && (config.record.is_some() || !config.restore.is_empty()) {
+        return Err(String::from("Replay starts from the snapshot of its recording, it can't record or restore another"));
+    }
+
+    if config.reverse.is_some() && config.record.is_none() {
+        return Err(String::from("Reverse debugging replays a recorded run, it needs --record"));
+    }
+
+    if config.auto_snapshot.is_some() && (config.record.is_some() || config.replay.is_some()) {
+        return Err(String::from("Automatic snapshots are taken at host timed exits, they can't be recorded or replayed"));
+    }
+
+    if config.hda_read_only && config.hda_grow.is_some() {
+        return Err(String::from("Read-only hard disk can't grow"));
+    }
+
+    if config.hda_overlay.is_some() && (config.hda_read_only || config.hda_grow.is_some()) {
+        return Err(String::from("Hard disk with overlay is neither read-only nor growable"));
+    }
+
+    if boot_cd {
+        if config.image.is_some() {
+            return Err(String::from("CD boot doesn't take a test image"));
+        }
+        if config.cdrom.is_none() {
+            return Err(String::from("CD boot needs a --cdrom image"));
+        }
+        if load.is_some() || entry.is_some() || boot_sector {
+            return Err(String::from("CD boot image is loaded where its boot catalog entry says"));
+        }
+        config.load = LoadConfig::Cdrom { drive: boot_drive.unwrap_or(0xE0) };
+        return Ok(config);
+    }
+
+    if config.image.is_none() && (load.is_some() || entry.is_some() || boot_sector || boot_drive.is_some()) {
+        return Err(String::from("Load options need a test image"));
+    }
+
+    if boot_sector {
+        if load.is_some() || entry.is_some() {
+            return Err(String::from("Boot sector is always loaded and started at 0000:7C00"));
+        }
+        config.load = LoadConfig::BootSector { drive: boot_drive.unwrap_or(0x80) };
+    } else {
+        if boot_drive.is_some() {
+            return Err(String::from("Boot drive is only passed to boot sectors"));
+        }
+        let load = load.unwrap_or((0, 0x8000));
+        config.load = LoadConfig::Flat { load: load, entry: entry.unwrap_or(load) };
+    }
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod config_test
+{
+    use super::{parse, LoadConfig, NetConfig, PmTimerConfig, SerialConfig, WatchdogConfig, WatchdogAction, TickPolicy, TscMode, TimerMode, ClockJumpPolicy};
+    use super::{GdbConfig, GdbAddressing, MonitorConfig, PanicBeaconConfig, HangConfig, HangAction, MetricsConfig, CoredumpConfig};
+    use super::{AutoSnapshotConfig, ReverseConfig};
+    use breakpoint::IoDirection;
+
+    fn args(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test] fn defaults() {
+        let config = parse(&args(&[])).unwrap();
+        assert!(config.image.is_none());
+        assert!(config.has_bios());
+        assert!(config.load == LoadConfig::Flat { load: (0, 0x8000), entry: (0, 0x8000) });
+        assert!(!config.headless);
+        assert!(config.frame_dump.is_none());
+        assert!(config.floppy.is_none());
+        assert!(config.hda.is_none());
+        assert!(config.hda_chs.is_none());
+        assert!(!config.hda_read_only);
+        assert!(config.hda_grow.is_none());
+        assert!(config.hda_overlay.is_none());
+        assert!(config.floppy_overlay.is_none());
+        assert!(config.floppy_b.is_none());
+        assert!(config.cdrom.is_none());
+        assert!(config.flash.is_none() && !config.flash_read_only && config.flash_base == 0xFF000000);
+        assert!(config.lpt.is_none());
+        assert!(config.net.is_none());
+        assert!(config.pm_timer.is_none());
+        assert!(config.serial.is_none());
+        assert!(config.pvcon.is_none());
+        assert!(config.watchdog.is_none());
+        assert!(config.pit_policy == TickPolicy::Coalesce);
+        assert!(config.tsc_mode == TscMode::Offset);
+        assert!(config.time_dilation == 1.0);
+        assert!(config.timer_mode == TimerMode::Host);
+        assert!(config.clock_jump == ClockJumpPolicy::Absorb && config.clock_jump_threshold_ms == 2000);
+        assert!(config.vbe_lfb == 0xE0000000);
+        assert!(!config.smbios);
+        assert!(config.uuid.is_none());
+        assert!(!config.bios_assist);
+        assert!(!config.apic && !config.ioapic);
+        assert!(config.gdb.is_none());
+        assert!(config.monitor.is_none());
+        assert!(config.breakpoints.is_empty() && config.io_breakpoints.is_empty() && config.vector_breakpoints.is_empty());
+        assert!(config.trace.is_none() && config.trace_range.is_none());
+        assert!(config.crash_dir.is_none() && config.event_log.is_none() && config.summary.is_none());
+        assert!(config.event_filter.is_none() && config.io_filter.is_none() && config.metrics.is_none());
+        assert!(config.auto_snapshot.is_none() && config.ram_images.is_empty());
+        assert!(config.coredump.is_none() && config.restore.is_empty() && config.record.is_none() && config.replay.is_none());
+        assert!(config.panic_beacon.is_none() && config.hang.is_none() && config.symbols.is_empty());
+    }
+
+    #[test] fn image_and_options() {
+        let config = parse(&args(&["--headless", "test/payload/dummy.bin", "--frame-dump", "/tmp/frames"])).unwrap();
+        assert!(config.image == Some(String::from("test/payload/dummy.bin")));
+        assert!(!config.has_bios());
+        assert!(config.headless);
+        assert!(config.frame_dump == Some(String::from("/tmp/frames")));
+
+        let config = parse(&args(&["boot.bin", "--boot-sector"])).unwrap();
+        assert!(config.load == LoadConfig::BootSector { drive: 0x80 });
+        let config = parse(&args(&["--boot-sector", "--boot-drive", "0", "boot.bin"])).unwrap();
+        assert!(config.load == LoadConfig::BootSector { drive: 0 });
+        let config = parse(&args(&["--bios-assist", "boot.bin"])).unwrap();
+        assert!(config.bios_assist);
+        let config = parse(&args(&["--apic", "boot.bin"])).unwrap();
+        assert!(config.apic && !config.ioapic);
+        let config = parse(&args(&["--ioapic", "boot.bin"])).unwrap();
+        assert!(config.apic && config.ioapic);
+        let config = parse(&args(&["--gdb", "1234", "boot.bin"])).unwrap();
+        assert!(config.gdb == Some(GdbConfig { port: 1234, wait: false, addressing: GdbAddressing::Physical }));
+        let config = parse(&args(&["--gdb-wait", "--gdb-cs-relative", "--gdb", "1234", "boot.bin"])).unwrap();
+        assert!(config.gdb == Some(GdbConfig { port: 1234, wait: true, addressing: GdbAddressing::CsRelative }));
+        let config = parse(&args(&["--load", "1000:0100", "prog.com"])).unwrap();
+        assert!(config.load == LoadConfig::Flat { load: (0x1000, 0x100), entry: (0x1000, 0x100) });
+        let config = parse(&args(&["--load", "2000:0", "--entry", "2000:1F0", "prog.bin"])).unwrap();
+        assert!(config.load == LoadConfig::Flat { load: (0x2000, 0), entry: (0x2000, 0x1F0) });
+
+        let config = parse(&args(&["--lpt", "printer.txt"])).unwrap();
+        assert!(config.lpt == Some(String::from("printer.txt")));
+
+        let config = parse(&args(&["--net", "pcap:out.pcap"])).unwrap();
+        assert!(config.net == Some(NetConfig::Pcap(String::from("out.pcap"))));
+        let config = parse(&args(&["--net", "tap:tap0"])).unwrap();
+        assert!(config.net == Some(NetConfig::Tap(String::from("tap0"))));
+        let config = parse(&args(&["--net", "user"])).unwrap();
+        assert!(config.net == Some(NetConfig::User));
+
+        let config = parse(&args(&["--pm-timer", "0x608"])).unwrap();
+        assert!(config.pm_timer == Some(PmTimerConfig { port: 0x608, wide: false }));
+        let config = parse(&args(&["--pm-timer", "45064,32"])).unwrap();
+        assert!(config.pm_timer == Some(PmTimerConfig { port: 0xB008, wide: true }));
+
+        let config = parse(&args(&["--vbe-lfb", "0xFD000000"])).unwrap();
+        assert!(config.vbe_lfb == 0xFD000000);
+
+        let config = parse(&args(&["--smbios", "--uuid", "12345678-9abc-DEF0-0123-456789abcdef"])).unwrap();
+        assert!(config.smbios);
+        assert!(config.uuid == Some([0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0, 0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF]));
+
+        let config = parse(&args(&["--serial", "stdio"])).unwrap();
+        assert!(config.serial == Some(SerialConfig::Stdio));
+        let config = parse(&args(&["--serial", "tcp:4555"])).unwrap();
+        assert!(config.serial == Some(SerialConfig::Tcp(4555)));
+        let config = parse(&args(&["--serial", "file:com1.log"])).unwrap();
+        assert!(config.serial == Some(SerialConfig::File(String::from("com1.log"))));
+        let config = parse(&args(&["--pvcon", "file:console.log"])).unwrap();
+        assert!(config.pvcon == Some(SerialConfig::File(String::from("console.log"))));
+        assert!(config.serial.is_none());
+        let config = parse(&args(&["--monitor", "stdio", "--serial", "tcp:4555"])).unwrap();
+        assert!(config.monitor == Some(MonitorConfig::Stdio));
+        let config = parse(&args(&["--monitor", "tcp:4444", "--serial", "stdio"])).unwrap();
+        assert!(config.monitor == Some(MonitorConfig::Tcp(4444)));
+        let config = parse(&args(&["--break", "0x7c05", "--break", "1048560", "boot.bin"])).unwrap();
+        assert!(config.breakpoints == vec![0x7C05, 0xFFFF0]);
+        let config = parse(&args(&["--io-break", "0x21,r", "--io-break", "0x20-0x21", "boot.bin"])).unwrap();
+        assert!(config.io_breakpoints == vec![(0x21..0x22, IoDirection::Read), (0x20..0x22, IoDirection::Any)]);
+        let config = parse(&args(&["--vector-break", "0x08", "--vector-break", "9", "boot.bin"])).unwrap();
+        assert!(config.vector_breakpoints == vec![0x08, 0x09]);
+        let config = parse(&args(&["--trace", "trace.log", "--trace-range", "0x7c00-0x7dff", "boot.bin"])).unwrap();
+        assert!(config.trace == Some(String::from("trace.log")) && config.trace_range == Some(0x7C00..0x7E00));
+        let config = parse(&args(&["--crash-dir", "/tmp/crashes", "--event-log", "events.jsonl", "boot.bin"])).unwrap();
+        assert!(config.crash_dir == Some(String::from("/tmp/crashes")));
+        assert!(config.event_log == Some(String::from("events.jsonl")));
+        let config = parse(&args(&["--event-log", "events.jsonl", "--event-filter", "event == pic_raise",
+                                   "--io-filter", "port in 0x1f0..0x1f7", "boot.bin"])).unwrap();
+        assert!(config.event_filter.unwrap().to_string() == "event == pic_raise");
+        assert!(config.io_filter.unwrap().to_string() == "port in 0x1f0..0x1f7");
+        let config = parse(&args(&["--summary", "-", "boot.bin"])).unwrap();
+        assert!(config.summary == Some(String::from("-")));
+        let config = parse(&args(&["--metrics", "/var/lib/node/xvm.prom", "boot.bin"])).unwrap();
+        assert!(config.metrics == Some(MetricsConfig { path: String::from("/var/lib/node/xvm.prom"), interval: 10 }));
+        let config = parse(&args(&["--metrics", "xvm.prom,30", "boot.bin"])).unwrap();
+        assert!(config.metrics == Some(MetricsConfig { path: String::from("xvm.prom"), interval: 30 }));
+        let config = parse(&args(&["--auto-snapshot", "/var/xvm/auto,60", "boot.bin"])).unwrap();
+        assert!(config.auto_snapshot == Some(AutoSnapshotConfig { dir: String::from("/var/xvm/auto"), seconds: 60, host: false, keep: 3 }));
+        let config = parse(&args(&["--auto-snapshot", "auto,600,host,10", "boot.bin"])).unwrap();
+        assert!(config.auto_snapshot == Some(AutoSnapshotConfig { dir: String::from("auto"), seconds: 600, host: true, keep: 10 }));
+        let config = parse(&args(&["--auto-snapshot", "auto,5,1", "boot.bin"])).unwrap();
+        assert!(config.auto_snapshot == Some(AutoSnapshotConfig { dir: String::from("auto"), seconds: 5, host: false, keep: 1 }));
+        let config = parse(&args(&["--coredump", "ci/xvm.core", "boot.bin"])).unwrap();
+        assert!(config.coredump == Some(CoredumpConfig { path: String::from("ci/xvm.core"), sparse: false }));
+        let config = parse(&args(&["--coredump", "xvm.core,sparse", "boot.bin"])).unwrap();
+        assert!(config.coredump == Some(CoredumpConfig { path: String::from("xvm.core"), sparse: true }));
+        let config = parse(&args(&["--restore", "boot.snap", "boot.bin"])).unwrap();
+        assert!(config.restore == vec![String::from("boot.snap")]);
+        let config = parse(&args(&["--restore", "base.snap,1.snap,2.snap", "boot.bin"])).unwrap();
+        assert!(config.restore == vec![String::from("base.snap"), String::from("1.snap"), String::from("2.snap")]);
+        let config = parse(&args(&["--record", "run.rec", "boot.bin"])).unwrap();
+        assert!(config.record == Some(String::from("run.rec")));
+        let config = parse(&args(&["--replay", "run.rec", "boot.bin"])).unwrap();
+        assert!(config.replay == Some(String::from("run.rec")));
+        let config = parse(&args(&["--record", "run.rec", "--reverse", "100", "boot.bin"])).unwrap();
+        assert!(config.reverse == Some(ReverseConfig { interval_ms: 100, keep: 8 }));
+        let config = parse(&args(&["--record", "run.rec", "--reverse", "5,2", "boot.bin"])).unwrap();
+        assert!(config.reverse == Some(ReverseConfig { interval_ms: 5, keep: 2 }));
+        let config = parse(&args(&["--symbols", "kernel.map,0x8000", "--symbols", "boot.map", "boot.bin"])).unwrap();
+        assert!(config.symbols == vec![(String::from("kernel.map"), 0x8000), (String::from("boot.map"), 0)]);
+        let config = parse(&args(&["--ram-image", "low.bin", "--ram-image", "heap.bin,0x10000", "boot.bin"])).unwrap();
+        assert!(config.ram_images == vec![(String::from("low.bin"), 0), (String::from("heap.bin"), 0x10000)]);
+
+        let config = parse(&args(&["--watchdog", "30"])).unwrap();
+        assert!(config.watchdog == Some(WatchdogConfig { timeout: 30, action: WatchdogAction::Stop }));
+        let config = parse(&args(&["--watchdog", "0,nmi"])).unwrap();
+        assert!(config.watchdog == Some(WatchdogConfig { timeout: 0, action: WatchdogAction::Nmi }));
+        let config = parse(&args(&["--watchdog", "5,reset"])).unwrap();
+        assert!(config.watchdog == Some(WatchdogConfig { timeout: 5, action: WatchdogAction::Reset }));
+        let config = parse(&args(&["--panic-port", "0x510"])).unwrap();
+        assert!(config.panic_beacon == Some(PanicBeaconConfig { port: 0x510, stop: true }));
+        let config = parse(&args(&["--panic-port", "1296,log"])).unwrap();
+        assert!(config.panic_beacon == Some(PanicBeaconConfig { port: 0x510, stop: false }));
+        let config = parse(&args(&["--hang-detect", "5"])).unwrap();
+        assert!(config.hang == Some(HangConfig { seconds: 5, action: HangAction::Log }));
+        let config = parse(&args(&["--hang-detect", "2,stop"])).unwrap();
+        assert!(config.hang == Some(HangConfig { seconds: 2, action: HangAction::Stop }));
+
+        let config = parse(&args(&["--pit-policy", "drop"])).unwrap();
+        assert!(config.pit_policy == TickPolicy::Drop);
+        let config = parse(&args(&["--pit-policy", "inject-all"])).unwrap();
+        assert!(config.pit_policy == TickPolicy::InjectAll);
+
+        let config = parse(&args(&["--tsc", "exiting"])).unwrap();
+        assert!(config.tsc_mode == TscMode::Exiting);
+        let config = parse(&args(&["--tsc", "exiting", "--time-dilation", "0.5"])).unwrap();
+        assert!(config.time_dilation == 0.5);
+        let config = parse(&args(&["--timer", "preemption"])).unwrap();
+        assert!(config.timer_mode == TimerMode::Preemption);
+        let config = parse(&args(&["--clock-jump", "rtc", "--clock-jump-threshold", "500"])).unwrap();
+        assert!(config.clock_jump == ClockJumpPolicy::ResyncRtc && config.clock_jump_threshold_ms == 500);
+        let config = parse(&args(&["--clock-jump", "follow"])).unwrap();
+        assert!(config.clock_jump == ClockJumpPolicy::Follow);
+
+        let config = parse(&args(&["--floppy", "dos.img", "--hda", "c.img", "--cdrom", "boot.iso"])).unwrap();
+        assert!(config.floppy == Some(String::from("dos.img")));
+        assert!(config.hda == Some(String::from("c.img")));
+        assert!(config.cdrom == Some(String::from("boot.iso")));
+        let config = parse(&args(&["--floppy-b", "b.img"])).unwrap();
+        assert!(config.floppy_b == Some(String::from("b.img")));
+
+        let config = parse(&args(&["--hda-chs", "615,4,17"])).unwrap();
+        assert!(config.hda_chs == Some((615, 4, 17)));
+        assert!(config.has_bios());
+
+        let config = parse(&args(&["--hda", "c.img", "--hda-ro"])).unwrap();
+        assert!(config.hda_read_only && config.hda_grow.is_none());
+        let config = parse(&args(&["--hda", "c.img", "--hda-grow", "504"])).unwrap();
+        assert!(!config.hda_read_only && config.hda_grow == Some(504 * 1024 * 1024));
+        let config = parse(&args(&["--hda", "c.img", "--hda-overlay", "c.cow", "--floppy-overlay", "a.cow"])).unwrap();
+        assert!(config.hda_overlay == Some(String::from("c.cow")));
+        assert!(config.floppy_overlay == Some(String::from("a.cow")));
+
+        let config = parse(&args(&["--flash", "nvram.bin", "--flash-ro", "--flash-base", "0xFFF80000"])).unwrap();
+        assert!(config.flash == Some(String::from("nvram.bin")));
+        assert!(config.flash_read_only && config.flash_base == 0xFFF80000);
+
+        let config = parse(&args(&["--cdrom", "boot.iso", "--boot-cd"])).unwrap();
+        assert!(config.load == LoadConfig::Cdrom { drive: 0xE0 });
+        assert!(config.image.is_none() && !config.has_bios());
+        let config = parse(&args(&["--boot-cd", "--boot-drive", "0x9F", "--cdrom", "boot.iso"])).unwrap();
+        assert!(config.load == LoadConfig::Cdrom { drive: 0x9F });
+    }
+
+    #[test] fn bad_args() {
+        assert!(parse(&args(&["--bogus"])).is_err());
+        assert!(parse(&args(&["a.bin", "b.bin"])).is_err());
+        assert!(parse(&args(&["--frame-dump"])).is_err());
+        assert!(parse(&args(&["--floppy"])).is_err());
+        assert!(parse(&args(&["--floppy-b"])).is_err());
+        assert!(parse(&args(&["--cdrom"])).is_err());
+        assert!(parse(&args(&["--lpt"])).is_err());
+        assert!(parse(&args(&["--net", "slip:foo"])).is_err());
+        assert!(parse(&args(&["--net", "pcap:"])).is_err());
+        assert!(parse(&args(&["--net", "user:foo"])).is_err());
+        assert!(parse(&args(&["--pm-timer"])).is_err());
+        assert!(parse(&args(&["--pm-timer", "0x10000"])).is_err());
+        assert!(parse(&args(&["--pm-timer", "0x608,16"])).is_err());
+        assert!(parse(&args(&["--vbe-lfb", "0xFD000800"])).is_err());
+        assert!(parse(&args(&["--vbe-lfb", "0x100000000"])).is_err());
+        assert!(parse(&args(&["--uuid", "12345678-9abc-def0-0123-456789abcde"])).is_err());
+        assert!(parse(&args(&["--uuid", "12345678-9abc-def0-0123-456789abcdeg"])).is_err());
+        assert!(parse(&args(&["--uuid", "123456789abcdef00123456789abcdef"])).is_err());
+        assert!(parse(&args(&["--boot-sector"])).is_err());
+        assert!(parse(&args(&["--replay", "run.rec", "--record", "again.rec", "boot.bin"])).is_err());
+        assert!(parse(&args(&["--replay", "run.rec", "--restore", "boot.snap", "boot.bin"])).is_err());
+        assert!(parse(&args(&["--reverse", "100", "boot.bin"])).is_err());
+        assert!(parse(&args(&["--record", "run.rec", "--reverse", "0", "boot.bin"])).is_err());
+        assert!(parse(&args(&["--record", "run.rec", "--reverse", "100,0", "boot.bin"])).is_err());
+        assert!(parse(&args(&["--record", "run.rec", "--reverse", "100,2,3", "boot.bin"])).is_err());
+        assert!(parse(&args(&["--record", "run.rec", "--reverse", ",2", "boot.bin"])).is_err());
+        assert!(parse(&args(&["--load", "1000:0100"])).is_err());
+        assert!(parse(&args(&["--load", "10000:0", "a.bin"])).is_err());
+        assert!(parse(&args(&["--load", "1000", "a.bin"])).is_err());
+        assert!(parse(&args(&["--boot-sector", "--load", "0:7C00", "a.bin"])).is_err());
+        assert!(parse(&args(&["--boot-drive", "0x80", "a.bin"])).is_err());
+        assert!(parse(&args(&["--boot-sector", "--boot-drive", "256", "a.bin"])).is_err());
+        assert!(parse(&args(&["--serial"])).is_err());
+        assert!(parse(&args(&["--serial", "tcp:70000"])).is_err());
+        assert!(parse(&args(&["--serial", "file:"])).is_err());
+        assert!(parse(&args(&["--serial", "stdio:x"])).is_err());
+        assert!(parse(&args(&["--pvcon", "tcp:"])).is_err());
+        assert!(parse(&args(&["--monitor", "file:mon.log"])).is_err());
+        assert!(parse(&args(&["--monitor", "tcp:x"])).is_err());
+        assert!(parse(&args(&["--monitor", "stdio", "--serial", "stdio"])).is_err());
+        assert!(parse(&args(&["--monitor", "stdio", "--pvcon", "stdio"])).is_err());
+        assert!(parse(&args(&["--break"])).is_err());
+        assert!(parse(&args(&["--break", "7c05", "a.bin"])).is_err());
+        assert!(parse(&args(&["--break", "0x100000000", "a.bin"])).is_err());
+        assert!(parse(&args(&["--io-break", "0x21,x", "a.bin"])).is_err());
+        assert!(parse(&args(&["--vector-break", "0x100", "a.bin"])).is_err());
+        assert!(parse(&args(&["--trace-range", "0x7c00", "a.bin"])).is_err());
+        assert!(parse(&args(&["--io-filter", "port = 5", "a.bin"])).is_err());
+        assert!(parse(&args(&["--event-filter", "event == vm_start", "a.bin"])).is_err());
+        assert!(parse(&args(&["--watchdog", "300"])).is_err());
+        assert!(parse(&args(&["--watchdog", "30,halt"])).is_err());
+        assert!(parse(&args(&["--watchdog", ",stop"])).is_err());
+        assert!(parse(&args(&["--panic-port", "0x510,halt"])).is_err());
+        assert!(parse(&args(&["--panic-port", "70000"])).is_err());
+        assert!(parse(&args(&["--hang-detect", "0"])).is_err());
+        assert!(parse(&args(&["--metrics", "xvm.prom,0"])).is_err());
+        assert!(parse(&args(&["--metrics", ",10"])).is_err());
+        assert!(parse(&args(&["--auto-snapshot", "auto"])).is_err());
+        assert!(parse(&args(&["--auto-snapshot", "auto,0"])).is_err());
+        assert!(parse(&args(&["--auto-snapshot", "auto,60,0"])).is_err());
+        assert!(parse(&args(&["--auto-snapshot", "auto,60,3,host"])).is_err());
+        assert!(parse(&args(&["--auto-snapshot", ",60"])).is_err());
+        assert!(parse(&args(&["--auto-snapshot", "auto,60", "--record", "run.rec", "a.bin"])).is_err());
+        assert!(parse(&args(&["--coredump", ",sparse"])).is_err());
+        assert!(parse(&args(&["--hang-detect", "3,reset"])).is_err());
+        assert!(parse(&args(&["--symbols", "kernel.map,seg"])).is_err());
+        assert!(parse(&args(&["--symbols", ",0x8000"])).is_err());
+        assert!(parse(&args(&["--ram-image", "heap.bin,seg"])).is_err());
+        assert!(parse(&args(&["--ram-image", ",0x10000"])).is_err());
+        assert!(parse(&args(&["--pit-policy", "burst"])).is_err());
+        assert!(parse(&args(&["--tsc", "native"])).is_err());
+        assert!(parse(&args(&["--time-dilation", "0.5"])).is_err());
+        assert!(parse(&args(&["--tsc", "exiting", "--time-dilation", "0"])).is_err());
+        assert!(parse(&args(&["--tsc", "exiting", "--time-dilation", "half"])).is_err());
+        assert!(parse(&args(&["--timer", "signal"])).is_err());
+        assert!(parse(&args(&["--clock-jump", "ignore"])).is_err());
+        assert!(parse(&args(&["--clock-jump-threshold", "0"])).is_err());
+        assert!(parse(&args(&["--clock-jump-threshold", "2s"])).is_err());
+        assert!(parse(&args(&["--hda-chs", "615,4"])).is_err());
+        assert!(parse(&args(&["--hda-chs", "615,17,17"])).is_err());
+        assert!(parse(&args(&["--hda-chs", "0,4,17"])).is_err());
+        assert!(parse(&args(&["--hda-grow", "0"])).is_err());
+        assert!(parse(&args(&["--hda-grow", "200000"])).is_err());
+        assert!(parse(&args(&["--hda-ro", "--hda-grow", "10"])).is_err());
+        assert!(parse(&args(&["--hda-overlay", "c.cow", "--hda-ro"])).is_err());
+        assert!(parse(&args(&["--hda-overlay"])).is_err());
+        assert!(parse(&args(&["--flash"])).is_err());
+        assert!(parse(&args(&["--flash-base", "0xFFF80100"])).is_err());
+        assert!(parse(&args(&["--boot-cd"])).is_err());
+        assert!(parse(&args(&["--gdb"])).is_err());
+        assert!(parse(&args(&["--gdb", "0"])).is_err());
+        assert!(parse(&args(&["--gdb", "tcp:1234"])).is_err());
+        assert!(parse(&args(&["--gdb-wait", "a.bin"])).is_err());
+        assert!(parse(&args(&["--gdb-cs-relative", "a.bin"])).is_err());
+        assert!(parse(&args(&["--boot-cd", "--cdrom", "boot.iso", "a.bin"])).is_err());
+        assert!(parse(&args(&["--boot-cd", "--cdrom", "boot.iso", "--boot-sector"])).is_err());
+        assert!(parse(&args(&["--boot-cd", "--cdrom", "boot.iso", "--load", "1000:0"])).is_err());
+    }
+}
@@ -0,0 +1,583 @@
+/*
+ * Guest core dumps
+ *
+ * A core dump bundles what there is to know about a stopped VM into one file, to look at after the fact when
+ * there was no debugger to attach, e.g. in CI: guest memory, vcpu registers, device states and a crash report.
+ * --coredump writes one as VM stops on a fatal error, guest panic or hang, monitor "coredump" and SIGQUIT write
+ * one at the next exit and let guest go on.
+ *
+ * File is a header and a section table followed by section data, numbers are little endian:
+ *
+ *   header     "XVMCORE\0", version u32, section count u32                          16 bytes
+ *   entry      kind u32, flags u32, base u64, data offset u64, data size u64       32 bytes, one per section
+ *
+ * Sections are
+ *
+ *   memory     guest physical memory from base, one section per memory mapping, RAM and ROM alike
+ *   vcpu       registers as "name=0x..." lines, segment registers as "cs.selector=0x..." and so on
+ *   devices    device states as a JSON object by device name, see devstate.rs
+ *   report     crash report text
+ *
+ * Sparse memory sections leave out pages of zeros: their data is the memory size u64, a page count u64 and the
+ * offset of each page kept as u64, followed by those pages. Pages left out read back as zeros.
+ */
+
+use vm;
+use crash;
+use config;
+use monitor;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const CORE_MAGIC: &'static [u8; 8]  = b"XVMCORE\0";
+const CORE_VERSION: u32             = 1;
+const HEADER_SIZE: usize            = 16;
+const ENTRY_SIZE: usize             = 32;
+
+// Section flags
+const SECTION_SPARSE: u32           = 1 << 0;
+
+// Granularity sparse memory sections leave zeros out at
+pub const CORE_PAGE_SIZE: usize     = 4096;
+
+/**
+ * What a section holds
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SectionKind
+{
+    Memory = 1,
+    Vcpu = 2,
+    Devices = 3,
+    Report = 4,
+}
+
+#[allow(dead_code)]
+impl SectionKind
+{
+    fn from_u32(val: u32) -> Option<SectionKind> {
+        match val {
+            1 => Some(SectionKind::Memory),
+            2 => Some(SectionKind::Vcpu),
+            3 => Some(SectionKind::Devices),
+            4 => Some(SectionKind::Report),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Section table entry
+ */
+#[allow(dead_code)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Section
+{
+    pub kind: SectionKind,
+    pub sparse: bool,
+    pub base: u64,              // Guest physical address of memory sections, 0 for others
+    pub offset: u64,            // Data position in file
+    pub size: u64,              // Data bytes in file
+}
+
+/**
+ * Guest state going into a core dump
+ */
+pub struct CoreDump
+{
+    pub memory: Vec<(u64, Vec<u8>)>,    // Guest physical base and bytes of each mapping
+    pub vcpu_state: vm::VcpuState,
+    pub devices: String,                // JSON object
+    pub report: String,
+}
+
+pub fn put_u32(out: &mut Vec<u8>, val: u32)
+{
+    for i in 0..4 {
+        out.push((val >> (i * 8)) as u8);
+    }
+}
+
+pub fn put_u64(out: &mut Vec<u8>, val: u64)
+{
+    for i in 0..8 {
+        out.push((val >> (i * 8)) as u8);
+    }
+}
+
+pub fn get_u32(data: &[u8], pos: usize) -> u32
+{
+    (0..4).fold(0, |val, i| val | (data[pos + i] as u32) << (i * 8))
+}
+
+pub fn get_u64(data: &[u8], pos: usize) -> u64
+{
+    (0..8).fold(0, |val, i| val | (data[pos + i] as u64) << (i * 8))
+}
+
+/**
+ * Memory as sparse section data: size, page count, offsets of pages with anything but zeros and the pages
+ */
+pub fn sparse_memory(data: &[u8]) -> Vec<u8>
+{
+    let pages: Vec<usize> = data.chunks(CORE_PAGE_SIZE).enumerate()
+        .filter(|&(_, page)| page.iter().any(|&byte| byte != 0))
+        .map(|(i, _)| i)
+        .collect();
+    sparse_pages(data, &pages)
+}
+
+/**
+ * Sparse section data with just the listed pages of memory, whatever they hold
+ */
+pub fn sparse_pages(data: &[u8], pages: &[usize]) -> Vec<u8>
+{
+    let mut out = Vec::new();
+    put_u64(&mut out, data.len() as u64);
+    put_u64(&mut out, pages.len() as u64);
+    for &i in pages {
+        put_u64(&mut out, (i * CORE_PAGE_SIZE) as u64);
+    }
+    for &i in pages {
+        let end = ::std::cmp::min(data.len(), (i + 1) * CORE_PAGE_SIZE);
+        out.extend_from_slice(&data[i * CORE_PAGE_SIZE..end]);
+    }
+    out
+}
+
+/**
+ * Memory of sparse section data, pages left out are zeros, None if data doesn't add up
+ */
+pub fn expand_sparse_memory(data: &[u8]) -> Option<Vec<u8>>
+{
+    if data.len() < 16 {
+        return None;
+    }
+
+    let mut mem = vec![0u8; get_u64(data, 0) as usize];
+    if apply_sparse_memory(&mut mem, data) { Some(mem) } else { None }
+}
+
+/**
+ * Copy pages sparse section data holds over memory of the size it was saved from, false if data doesn't add up
+ */
+pub fn apply_sparse_memory(mem: &mut [u8], data: &[u8]) -> bool
+{
+    if data.len() < 16 {
+        return false;
+    }
+    let (len, count) = (get_u64(data, 0) as usize, get_u64(data, 8) as usize);
+    if len != mem.len() || count > data.len() / 8 || data.len() < 16 + count * 8 {
+        return false;
+    }
+
+    let pages = 16 + count * 8;
+    for i in 0..count {
+        let offset = get_u64(data, 16 + i * 8) as usize;
+        let start = pages + i * CORE_PAGE_SIZE;
+        let size = ::std::cmp::min(CORE_PAGE_SIZE, len.saturating_sub(offset));
+        if size == 0 || start + size > data.len() {
+            return false;
+        }
+        mem[offset..offset + size].copy_from_slice(&data[start..start + size]);
+    }
+    true
+}
+
+/**
+ * Registers as "name=0x..." lines
+ */
+pub fn vcpu_text(state: &vm::VcpuState) -> String
+{
+    let mut fields = vec![
+        ("rax", state.rax), ("rbx", state.rbx), ("rcx", state.rcx), ("rdx", state.rdx),
+        ("rsi", state.rsi), ("rdi", state.rdi), ("rbp", state.rbp), ("rsp", state.rsp),
+        ("rip", state.rip), ("rflags", state.rflags),
+        ("cr0", state.cr0), ("cr3", state.cr3), ("cr4", state.cr4),
+        ("interruptibility", state.interruptibility as u64), ("activity", state.activity as u64),
+    ].iter().map(|&(name, val)| format!("{}=0x{:x}", name, val)).collect::<Vec<String>>();
+
+    for &(name, seg) in &[("es", state.es), ("cs", state.cs), ("ss", state.ss), ("ds", state.ds), ("fs", state.fs), ("gs", state.gs)] {
+        fields.push(format!("{}.selector=0x{:x}", name, seg.selector));
+        fields.push(format!("{}.base=0x{:x}", name, seg.base));
+        fields.push(format!("{}.limit=0x{:x}", name, seg.limit));
+        fields.push(format!("{}.attributes=0x{:x}", name, seg.attributes));
+    }
+
+    fields.join("\n") + "\n"
+}
+
+/**
+ * Registers of vcpu section text
+ */
+pub fn parse_vcpu_text(text: &str) -> Result<vm::VcpuState, String>
+{
+    let mut fields = HashMap::new();
+    for line in text.lines() {
+        let mut parts = line.splitn(2, '=');
+        let (name, val) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+        if !val.starts_with("0x") {
+            return Err(format!("Bad register line \"{}\"", line));
+        }
+        let val = try!(u64::from_str_radix(&val[2..], 16).map_err(|_| format!("Bad register line \"{}\"", line)));
+        fields.insert(name, val);
+    }
+
+    let get = |name: &str| fields.get(name).cloned().ok_or(format!("No register {}", name));
+    let seg = |name: &str| -> Result<vm::SegmentState, String> {
+        Ok(vm::SegmentState {
+            selector: try!(get(&format!("{}.selector", name))) as u16,
+            base: try!(get(&format!("{}.base", name))),
+            limit: try!(get(&format!("{}.limit", name))) as u32,
+            attributes: try!(get(&format!("{}.attributes", name))) as u32,
+        })
+    };
+
+    Ok(vm::VcpuState {
+        rax: try!(get("rax")),
+        rbx: try!(get("rbx")),
+        rcx: try!(get("rcx")),
+        rdx: try!(get("rdx")),
+        rsi: try!(get("rsi")),
+        rdi: try!(get("rdi")),
+        rbp: try!(get("rbp")),
+        rsp: try!(get("rsp")),
+        rip: try!(get("rip")),
+        rflags: try!(get("rflags")),
+        es: try!(seg("es")),
+        cs: try!(seg("cs")),
+        ss: try!(seg("ss")),
+        ds: try!(seg("ds")),
+        fs: try!(seg("fs")),
+        gs: try!(seg("gs")),
+        cr0: try!(get("cr0")),
+        cr3: try!(get("cr3")),
+        cr4: try!(get("cr4")),
+        interruptibility: try!(get("interruptibility")) as u32,
+        activity: try!(get("activity")) as u32,
+    })
+}
+
+/**
+ * Write core dump container, memory sections sparse if asked for
+ */
+pub fn write<W: Write>(dump: &CoreDump, out: &mut W, sparse: bool) -> io::Result<()>
+{
+    /* Kind, flags, base and data of each section */
+    let mut sections: Vec<(SectionKind, u32, u64, Vec<u8>)> = dump.memory.iter().map(|&(base, ref data)| {
+        if sparse {
+            (SectionKind::Memory, SECTION_SPARSE, base, sparse_memory(data))
+        } else {
+            (SectionKind::Memory, 0, base, data.clone())
+        }
+    }).collect();
+    sections.push((SectionKind::Vcpu, 0, 0, vcpu_text(&dump.vcpu_state).into_bytes()));
+    sections.push((SectionKind::Devices, 0, 0, dump.devices.clone().into_bytes()));
+    sections.push((SectionKind::Report, 0, 0, dump.report.clone().into_bytes()));
+
+    let mut header = Vec::new();
+    header.extend_from_slice(CORE_MAGIC);
+    put_u32(&mut header, CORE_VERSION);
+    put_u32(&mut header, sections.len() as u32);
+
+    let mut offset = (HEADER_SIZE + sections.len() * ENTRY_SIZE) as u64;
+    for &(kind, flags, base, ref data) in &sections {
+        put_u32(&mut header, kind as u32);
+        put_u32(&mut header, flags);
+        put_u64(&mut header, base);
+        put_u64(&mut header, offset);
+        put_u64(&mut header, data.len() as u64);
+        offset += data.len() as u64;
+    }
+
+    try!(out.write_all(&header));
+    for &(_, _, _, ref data) in &sections {
+        try!(out.write_all(data));
+    }
+    out.flush()
+}
+
+/**
+ * Core dump read back from a file, the VM itself only writes them
+ */
+#[allow(dead_code)]
+pub struct CoreFile
+{
+    data: Vec<u8>,
+    sections: Vec<Section>,
+}
+
+#[allow(dead_code)]
+impl CoreFile
+{
+    /**
+     * Check container and its section table, sections are read as asked for
+     */
+    pub fn parse(data: Vec<u8>) -> Result<CoreFile, String> {
+        if data.len() < HEADER_SIZE || &data[0..8] != CORE_MAGIC {
+            return Err(String::from("Not a core dump"));
+        }
+        if get_u32(&data, 8) != CORE_VERSION {
+            return Err(format!("Unknown core dump version {}", get_u32(&data, 8)));
+        }
+
+        let count = get_u32(&data, 12) as usize;
+        if data.len() < HEADER_SIZE + count * ENTRY_SIZE {
+            return Err(String::from("Core dump section table is cut short"));
+        }
+
+        let mut sections = Vec::new();
+        for i in 0..count {
+            let pos = HEADER_SIZE + i * ENTRY_SIZE;
+            let kind = try!(SectionKind::from_u32(get_u32(&data, pos)).ok_or(format!("Unknown section kind in entry {}", i)));
+            let section = Section {
+                kind: kind,
+                sparse: get_u32(&data, pos + 4) & SECTION_SPARSE != 0,
+                base: get_u64(&data, pos + 8),
+                offset: get_u64(&data, pos + 16),
+                size: get_u64(&data, pos + 24),
+            };
+            if section.offset.checked_add(section.size).map_or(true, |end| end > data.len() as u64) {
+                return Err(format!("Section {} runs past end of core dump", i));
+            }
+            sections.push(section);
+        }
+
+        Ok(CoreFile { data: data, sections: sections })
+    }
+
+    pub fn open(path: &Path) -> Result<CoreFile, String> {
+        let mut data = Vec::new();
+        try!(File::open(path).and_then(|mut file| file.read_to_end(&mut data))
+             .map_err(|err| format!("Can't read core dump {}: {}", path.display(), err)));
+        CoreFile::parse(data)
+    }
+
+    pub fn sections(&self) -> &[Section] {
+        &self.sections
+    }
+
+    fn section_data(&self, section: &Section) -> &[u8] {
+        &self.data[section.offset as usize..(section.offset + section.size) as usize]
+    }
+
+    /**
+     * Bytes of memory section, pages sparse section left out are zeros
+     */
+    pub fn memory(&self, section: &Section) -> Result<Vec<u8>, String> {
+        let data = self.section_data(section);
+        if !section.sparse {
+            return Ok(data.to_vec());
+        }
+
+        expand_sparse_memory(data).ok_or(format!("Bad sparse memory section at 0x{:x}", section.base))
+    }
+
+    /**
+     * Bytes of the memory section from guest physical base
+     */
+    pub fn memory_at(&self, base: u64) -> Result<Vec<u8>, String> {
+        match self.sections.iter().find(|section| section.kind == SectionKind::Memory && section.base == base) {
+            Some(section) => self.memory(section),
+            None => Err(format!("No memory section at 0x{:x}", base)),
+        }
+    }
+
+    fn text(&self, kind: SectionKind) -> Result<String, String> {
+        match self.sections.iter().find(|section| section.kind == kind) {
+            Some(section) => String::from_utf8(self.section_data(section).to_vec()).map_err(|_| format!("{:?} section isn't text", kind)),
+            None => Err(format!("No {:?} section in core dump", kind)),
+        }
+    }
+
+    pub fn vcpu_state(&self) -> Result<vm::VcpuState, String> {
+        parse_vcpu_text(&try!(self.text(SectionKind::Vcpu)))
+    }
+
+    pub fn devices(&self) -> Result<String, String> {
+        self.text(SectionKind::Devices)
+    }
+
+    pub fn report(&self) -> Result<String, String> {
+        self.text(SectionKind::Report)
+    }
+}
+
+#[cfg(test)]
+mod coredump_test
+{
+    use super::*;
+
+    /* Guest stopped at a fatal error with 64K of RAM, a pattern in one page and zeros elsewhere */
+    fn fatal_dump() -> CoreDump {
+        let mut ram = vec![0u8; 0x10000];
+        for i in 0..0x200 {
+            ram[0x7C00 + i] = (i * 7) as u8;
+        }
+
+        let real_mode = vm::SegmentState { selector: 0, base: 0, limit: 0xffff, attributes: 0x93 };
+        CoreDump {
+            memory: vec![(0, ram), (0xF0000, vec![0xEA; 0x10000])],
+            vcpu_state: vm::VcpuState {
+                rax: 0x1242,
+                rbx: 0x5678,
+                rip: 0x7C0B,
+                rflags: 0x2,
+                cs: vm::SegmentState { attributes: 0x9b, ..real_mode },
+                ds: vm::SegmentState { selector: 0x40, base: 0x400, ..real_mode },
+                ..Default::default()
+            },
+            devices: String::from("{\n  \"pic\": {}\n}"),
+            report: String::from("VM crashed: Unhandled I/O write to port 0x99\n"),
+        }
+    }
+
+    fn round_trip(sparse: bool) -> (usize, CoreFile) {
+        let path = ::std::env::temp_dir().join(format!("xvm_coredump_test_{}.core", sparse));
+        write(&fatal_dump(), &mut File::create(&path).unwrap(), sparse).unwrap();
+        let size = ::std::fs::metadata(&path).unwrap().len() as usize;
+        (size, CoreFile::open(&path).unwrap())
+    }
+
+    #[test] fn full() {
+        let (size, core) = round_trip(false);
+        let dump = fatal_dump();
+        assert!(size > 0x20000);
+
+        let kinds: Vec<SectionKind> = core.sections().iter().map(|section| section.kind).collect();
+        assert!(kinds == vec![SectionKind::Memory, SectionKind::Memory, SectionKind::Vcpu, SectionKind::Devices, SectionKind::Report]);
+        assert!(core.sections()[1].base == 0xF0000 && !core.sections()[1].sparse);
+
+        assert!(core.memory_at(0).unwrap() == dump.memory[0].1);
+        assert!(core.vcpu_state().unwrap() == dump.vcpu_state);
+        assert!(core.devices().unwrap() == dump.devices);
+        assert!(core.report().unwrap() == dump.report);
+    }
+
+    #[test] fn sparse() {
+        let (size, core) = round_trip(true);
+        let dump = fatal_dump();
+
+        /* RAM keeps its one page with the pattern, ROM is all there */
+        assert!(size < 0x10000 + 0x2000);
+        assert!(core.sections()[0].sparse && core.sections()[0].size == 16 + 8 + CORE_PAGE_SIZE as u64);
+        let ram = core.memory_at(0).unwrap();
+        assert!(ram == dump.memory[0].1 && ram[0x7C01] == 7);
+        assert!(core.memory_at(0xF0000).unwrap() == dump.memory[1].1);
+        assert!(core.memory_at(0x100000).is_err());
+
+        assert!(core.vcpu_state().unwrap().rax == 0x1242);
+    }
+
+    #[test] fn bad_files() {
+        assert!(CoreFile::parse(b"XVMDUMP\0".to_vec()).is_err());
+
+        let mut data = Vec::new();
+        write(&fatal_dump(), &mut data, true).unwrap();
+        assert!(CoreFile::parse(data[..data.len() - 1].to_vec()).is_err());
+        assert!(CoreFile::parse(data[..HEADER_SIZE + ENTRY_SIZE].to_vec()).is_err());
+        data[8] = 2;
+        assert!(CoreFile::parse(data).is_err());
+
+        assert!(parse_vcpu_text("rax=0x1\n").is_err());
+        assert!(parse_vcpu_text("rax=12\n").is_err());
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+// SIGQUIT, same number on macOS and Linux
+const SIGQUIT: i32 = 3;
+
+extern "C" {
+    fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+}
+
+lazy_static! {
+    static ref REQUESTED: AtomicBool = AtomicBool::new(false);   // SIGQUIT asked for a dump
+    static ref COREDUMP: Mutex<Option<config::CoredumpConfig>> = Mutex::new(None);
+}
+
+/* Dump at the next exit, kick vcpu out of guest for one */
+extern "C" fn sigquit(_: i32)
+{
+    REQUESTED.store(true, Ordering::Relaxed);
+    vm::interrupt_guest();
+}
+
+/* Core dump of guest as of the current exit with a crash report for reason, vcpu thread only */
+fn write_current(path: &str, sparse: bool, reason: &str) -> io::Result<()>
+{
+    let report = crash::capture("Core dump", String::from(reason)).to_string();
+    vm::write_coredump(Path::new(path), &report, sparse)
+}
+
+/**
+ * Write core dump with crash report of a fatal exit if --coredump asked for one, vcpu thread only
+ */
+pub fn fatal(report: &str)
+{
+    let coredump = match *COREDUMP.lock().unwrap() {
+        Some(ref coredump) => coredump.clone(),
+        None => return,
+    };
+
+    match vm::write_coredump(Path::new(&coredump.path), report, coredump.sparse) {
+        Ok(()) => error!("Core dump written to {}", coredump.path),
+        Err(err) => error!("Can't write core dump to {}: {}", coredump.path, err),
+    }
+}
+
+/**
+ * Write core dump SIGQUIT asked for, vcpu thread only, at every exit
+ * Dump goes where --coredump says, or to xvm-<pid>.core without it.
+ */
+pub fn poll()
+{
+    if !REQUESTED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    REQUESTED.store(false, Ordering::Relaxed);
+    let (path, sparse) = match *COREDUMP.lock().unwrap() {
+        Some(ref coredump) => (coredump.path.clone(), coredump.sparse),
+        None => (format!("xvm-{}.core", ::std::process::id()), false),
+    };
+
+    match write_current(&path, sparse, "SIGQUIT") {
+        Ok(()) => warn!("Core dump written to {}", path),
+        Err(err) => error!("Can't write core dump to {}: {}", path, err),
+    }
+}
+
+fn cmd_coredump(_: &mut monitor::MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    let sparse = args.len() == 2 && args[1] == "sparse";
+    if args.len() != 1 && !sparse {
+        return Err(String::from("Usage: coredump <file> [sparse]"));
+    }
+
+    try!(write_current(args[0], sparse, "Requested from monitor").map_err(|err| format!("Can't write {}: {}", args[0], err)));
+    Ok(format!("Core dump written to {}", args[0]))
+}
+
+pub fn init(config: &config::VmConfig)
+{
+    *COREDUMP.lock().unwrap() = config.coredump.clone();
+
+    unsafe {
+        signal(SIGQUIT, sigquit);
+    }
+
+    monitor::register_command(monitor::MonitorCommand {
+        name: "coredump",
+        args: "file [sparse]",
+        help: "write guest memory, registers and device states to file",
+        handler: cmd_coredump,
+    });
+}
@@ -0,0 +1,372 @@
+/*
+ * Crash reports
+ *
+ * When guest does something VMM can't go on from, e.g. takes an exit VMM doesn't handle, accesses MMIO with an
+ * instruction the decoder doesn't know or touches a port no device claims, fatal() collects what there is to
+ * know about guest at that point into one report and stops VM with VmExit::Fatal carrying it:
+ *
+ *   - why VM stopped, with VMX exit reason and qualification
+ *   - vcpu registers
+ *   - events waiting for injection and PIC registers
+ *   - latest port accesses, oldest first
+ *   - 32 code bytes around CS:IP and disassembly from CS:IP on, with the guest symbol CS:IP is in if any
+ *
+ * Main loop prints the report and saves it to a timestamped file, see --crash-dir. Parts that can't be
+ * collected, like code in unmapped memory or PIC being in the middle of an access, say so instead of taking
+ * the rest of the report with them.
+ */
+
+use vm;
+use pic;
+use inject;
+use trace;
+use disasm;
+use breakpoint::IoDirection;
+use tracefilter::TraceFilter;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Port accesses kept for crash reports
+pub const IO_HISTORY_LEN: usize = 16;
+
+// Code bytes shown on each side of CS:IP
+const CODE_CONTEXT: u64 = 16;
+const CODE_LINE_BYTES: usize = 16;
+
+/**
+ * Port access guest made, value is the one written or the one read returned
+ */
+#[derive(Clone, Copy)]
+pub struct IoAccess
+{
+    pub port: u16,
+    pub direction: IoDirection,
+    pub value: vm::IoOperandType,
+}
+
+impl fmt::Display for IoAccess
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = if self.direction == IoDirection::Read { "in " } else { "out" };
+        match self.value {
+            vm::IoOperandType::byte(v) => write!(f, "{} 0x{:04x} byte  0x{:02x}", op, self.port, v),
+            vm::IoOperandType::word(v) => write!(f, "{} 0x{:04x} word  0x{:04x}", op, self.port, v),
+            vm::IoOperandType::dword(v) => write!(f, "{} 0x{:04x} dword 0x{:08x}", op, self.port, v),
+        }
+    }
+}
+
+/**
+ * Latest port accesses, older ones drop out
+ */
+pub struct IoHistory
+{
+    accesses: VecDeque<IoAccess>,
+    filter: Option<TraceFilter>,    // Accesses kept, all if none
+}
+
+impl IoHistory
+{
+    pub fn new() -> IoHistory {
+        IoHistory {
+            accesses: VecDeque::with_capacity(IO_HISTORY_LEN),
+            filter: None,
+        }
+    }
+
+    /**
+     * Keep only accesses matching filter from now on, accesses kept already stay
+     */
+    pub fn set_filter(&mut self, filter: Option<TraceFilter>) {
+        self.filter = filter;
+    }
+
+    pub fn filter(&self) -> Option<&TraceFilter> {
+        self.filter.as_ref()
+    }
+
+    pub fn push(&mut self, access: IoAccess) {
+        if !self.filter.as_ref().map_or(true, |filter| filter.matches(&access)) {
+            return;
+        }
+
+        if self.accesses.len() == IO_HISTORY_LEN {
+            self.accesses.pop_front();
+        }
+        self.accesses.push_back(access);
+    }
+
+    /**
+     * Accesses kept, oldest first
+     */
+    pub fn list(&self) -> Vec<IoAccess> {
+        self.accesses.iter().cloned().collect()
+    }
+}
+
+/**
+ * Guest memory bytes as hex lines, each with linear address of its first byte
+ */
+pub fn write_hex_lines(f: &mut fmt::Formatter, addr: u64, bytes: &[u8]) -> fmt::Result
+{
+    for (i, line) in bytes.chunks(CODE_LINE_BYTES).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        try!(writeln!(f, "  {:08x}  {}", addr + (i * CODE_LINE_BYTES) as u64, hex.join(" ")));
+    }
+    Ok(())
+}
+
+/**
+ * Guest state at a fatal error
+ */
+pub struct CrashReport
+{
+    pub headline: &'static str,             // What became of VM, e.g. "VM crashed"
+    pub reason: String,
+    pub exit_reason: u32,
+    pub exit_qualification: u64,
+    pub vcpu_state: vm::VcpuState,
+    pub pending: inject::PendingEvents,
+    pub pic: Option<[pic::I8259State; 2]>,   // None without PIC or while it is accessed
+    pub io_history: Vec<IoAccess>,
+    pub code_addr: u64,                     // Linear address of first code byte
+    pub code: Vec<u8>,                      // Empty if CS:IP isn't in guest memory
+    pub symbol: Option<String>,             // Guest symbol at CS:IP, see --symbols
+}
+
+impl CrashReport
+{
+    fn write_code(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = &self.vcpu_state;
+        match self.symbol {
+            Some(ref symbol) => try!(writeln!(f, "Code around {:04x}:{:04x} <{}>:", state.cs.selector, state.rip, symbol)),
+            None => try!(writeln!(f, "Code around {:04x}:{:04x}:", state.cs.selector, state.rip)),
+        }
+        if self.code.is_empty() {
+            return writeln!(f, "  (not in guest memory)");
+        }
+
+        try!(write_hex_lines(f, self.code_addr, &self.code));
+
+        /* Instructions before CS:IP can't be told apart from the bytes, disassembly starts at it */
+        let linear = state.cs.base + state.rip;
+        if linear < self.code_addr || linear >= self.code_addr + self.code.len() as u64 {
+            return Ok(());
+        }
+
+        let mut offset = (linear - self.code_addr) as usize;
+        let code16 = trace::is_16bit_code(state);
+        while offset < self.code.len() {
+            let ip = state.rip + (offset as u64 - (linear - self.code_addr));
+            match disasm::decode(&self.code[offset..], ip, code16) {
+                Some((size, mnemonic, operands)) => {
+                    let text = disasm::instruction_text(&mnemonic, &operands);
+                    try!(writeln!(f, "  {}", trace::format_instruction(state.cs.selector, ip,
+                                                                       &self.code[offset..offset + size], &text)));
+                    offset += size;
+                },
+                None => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for CrashReport
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "{}: {}", self.headline, self.reason));
+        try!(writeln!(f, "Exit reason {}, qualification 0x{:x}", self.exit_reason & 0xFFFF, self.exit_qualification));
+
+        try!(writeln!(f, "Registers:"));
+        try!(writeln!(f, "{}", self.vcpu_state));
+
+        let exception = match self.pending.exception {
+            Some(exc) => format!("0x{:02x}", exc.vector),
+            None => String::from("none"),
+        };
+        try!(writeln!(f, "Pending events: exception {}, NMI {}, external interrupt {}",
+                      exception, self.pending.nmi as u8, self.pending.external as u8));
+        match self.pic {
+            Some(chips) => for (i, chip) in chips.iter().enumerate() {
+                try!(writeln!(f, "pic{}: {}", i, chip));
+            },
+            None => try!(writeln!(f, "pic: (unavailable)")),
+        }
+
+        try!(writeln!(f, "Port accesses, oldest first:"));
+        if self.io_history.is_empty() {
+            try!(writeln!(f, "  (none)"));
+        }
+        for access in &self.io_history {
+            try!(writeln!(f, "  {}", access));
+        }
+
+        self.write_code(f)
+    }
+}
+
+/**
+ * Crash report file name for a time in seconds since epoch
+ */
+pub fn report_file_name(secs: u64) -> String
+{
+    format!("xvm-crash-{}.txt", secs)
+}
+
+/**
+ * Save report to a new timestamped file in directory, path it went to
+ */
+pub fn save(report: &str, dir: &str) -> io::Result<PathBuf>
+{
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let path = Path::new(dir).join(report_file_name(secs));
+    let mut file = try!(File::create(&path));
+    try!(file.write_all(report.as_bytes()));
+    Ok(path)
+}
+
+#[cfg(test)]
+mod crash_test
+{
+    use super::*;
+
+    fn report() -> CrashReport {
+        let real_mode = vm::SegmentState { selector: 0, base: 0, limit: 0xffff, attributes: 0x93 };
+        CrashReport {
+            headline: "VM crashed",
+            reason: String::from("Unhandled I/O write to port 0x99"),
+            exit_reason: 30,
+            exit_qualification: 0x990000,
+            vcpu_state: vm::VcpuState {
+                rax: 0x1242,
+                rip: 0x7c0b,
+                rflags: 0x2,
+                es: real_mode,
+                cs: vm::SegmentState { attributes: 0x9b, ..real_mode },
+                ss: real_mode,
+                ds: real_mode,
+                fs: real_mode,
+                gs: real_mode,
+                ..Default::default()
+            },
+            pending: inject::PendingEvents { exception: None, nmi: false, external: true },
+            pic: Some([
+                pic::I8259State { irr: 0x01, isr: 0x00, imr: 0xB8, offset: 0x08, initialized: true, output: true },
+                pic::I8259State { irr: 0x00, isr: 0x00, imr: 0xFF, offset: 0x70, initialized: false, output: true },
+            ]),
+            io_history: vec![
+                IoAccess { port: 0x21, direction: IoDirection::Read, value: vm::IoOperandType::byte(0xB8) },
+                IoAccess { port: 0xCFC, direction: IoDirection::Write, value: vm::IoOperandType::dword(0x80000000) },
+            ],
+            code_addr: 0x7bfb,
+            code: (0..32).collect(),
+            symbol: None,
+        }
+    }
+
+    #[test] fn format() {
+        let text = report().to_string();
+        assert!(text.starts_with("VM crashed: Unhandled I/O write to port 0x99\nExit reason 30, qualification 0x990000\n"));
+        assert!(text.contains("EAX=00001242"));
+        assert!(text.contains("Pending events: exception none, NMI 0, external interrupt 1\n"));
+        assert!(text.contains("pic0: irr=01 imr=b8 isr=00 vec=08 init=1 out=1\n"));
+        assert!(text.contains("  in  0x0021 byte  0xb8\n  out 0x0cfc dword 0x80000000\n"));
+        assert!(text.contains("  00007bfb  00 01 02"));
+        assert!(text.contains("  00007c0b  10 11 12"));
+
+        /* Disassembly covers bytes from CS:IP to the end */
+        assert!(text.contains("  0000:7c0b  10"));
+        assert!(!text.contains("  0000:7c0a"));
+    }
+
+    #[test] fn symbol() {
+        let mut report = report();
+        report.symbol = Some(String::from("timer_isr+0x12"));
+        assert!(report.to_string().contains("\nCode around 0000:7c0b <timer_isr+0x12>:\n  00007bfb  00 01 02"));
+    }
+
+    #[test] fn unavailable_parts() {
+        let mut report = report();
+        report.pic = None;
+        report.io_history.clear();
+        report.code.clear();
+
+        let text = report.to_string();
+        assert!(text.contains("EAX=00001242"));
+        assert!(text.contains("pic: (unavailable)\n"));
+        assert!(text.contains("Port accesses, oldest first:\n  (none)\n"));
+        assert!(text.ends_with("Code around 0000:7c0b:\n  (not in guest memory)\n"));
+    }
+
+    #[test] fn io_history() {
+        let mut history = IoHistory::new();
+        for port in 0..IO_HISTORY_LEN as u16 + 3 {
+            history.push(IoAccess { port: port, direction: IoDirection::Write, value: vm::IoOperandType::byte(0) });
+        }
+
+        let list = history.list();
+        assert!(list.len() == IO_HISTORY_LEN);
+        assert!(list[0].port == 3 && list[IO_HISTORY_LEN - 1].port == IO_HISTORY_LEN as u16 + 2);
+
+        /* Filter drops accesses as they come */
+        let mut history = IoHistory::new();
+        history.set_filter(Some(TraceFilter::parse("port in 0x1f0..0x1f7 and dir == write").unwrap()));
+        for &(port, direction) in &[(0x1F2, IoDirection::Write), (0x1F7, IoDirection::Read), (0x3F6, IoDirection::Write),
+                                    (0x1F7, IoDirection::Write), (0x1EF, IoDirection::Write)] {
+            history.push(IoAccess { port: port, direction: direction, value: vm::IoOperandType::byte(0) });
+        }
+        let ports: Vec<u16> = history.list().iter().map(|access| access.port).collect();
+        assert!(ports == vec![0x1F2, 0x1F7]);
+    }
+
+    #[test] fn file_name() {
+        assert!(report_file_name(1760000000) == "xvm-crash-1760000000.txt");
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/**
+ * Report of guest state as of the current exit, vcpu thread only
+ */
+pub fn capture(headline: &'static str, reason: String) -> CrashReport
+{
+    let vcpu_state = vm::vcpu_state();
+    let (exit_reason, exit_qualification) = vm::exit_info();
+
+    /* Range around CS:IP may run into unmapped memory, code is only kept up to there */
+    let linear = vcpu_state.cs.base + vcpu_state.rip;
+    let code_addr = linear.saturating_sub(CODE_CONTEXT);
+    let mut code = vec![0u8; (linear - code_addr + CODE_CONTEXT) as usize];
+    let len = vm::read_guest_memory(code_addr, &mut code);
+    code.truncate(len);
+
+    CrashReport {
+        headline: headline,
+        reason: reason,
+        exit_reason: exit_reason,
+        exit_qualification: exit_qualification,
+        vcpu_state: vcpu_state,
+        pending: vm::pending_events(),
+        pic: pic::state(),
+        io_history: vm::io_history(),
+        code_addr: code_addr,
+        code: code,
+        symbol: vm::resolve_symbol(linear),
+    }
+}
+
+/**
+ * Stop VM with a crash report of guest state, vcpu thread only
+ * Handler that found the error returns as if it completed, VM loop stops when current exit is handled.
+ */
+pub fn fatal(reason: String)
+{
+    vm::request_vm_exit(vm::VmExit::Fatal(capture("VM crashed", reason).to_string()));
+}
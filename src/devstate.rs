@@ -0,0 +1,697 @@
+/*
+ * Device state inspection
+ *
+ * Devices registered with vm::register_device_state describe their state as named fields, which monitor shows
+ * as JSON with "info device <name>". Devices pick the fields worth showing and leave out host side ones, like
+ * backend handles, and can annotate state with things guest doesn't see, like the image file behind a disk.
+ *
+ * Monitor commands run on vcpu thread between exits, so devices are never in the middle of a port access
+ * then. A device that is busy anyway, e.g. because a command ran from a device handler, says so rather than
+ * showing half updated state.
+ *
+ * States go through a codec, see state_codec: snapshots save them in a compact binary encoding, monitor and
+ * tests read them as JSON. Both carry field names and kinds, so either one reads back what it wrote without
+ * knowing the device. Restoring a state from another VMM version may meet fields the device doesn't give any
+ * more, each device says whether those are dropped or fail the restore, see known_fields().
+ */
+
+use vm;
+use monitor;
+use eventlog::quote;
+
+/**
+ * Value of a state field
+ */
+#[derive(Clone, PartialEq, Debug)]
+pub enum StateValue
+{
+    Bool(bool),
+    Number(u64),
+    Hex(u64, usize),            // Value and digits, shown as a "0x" string
+    Text(String),
+    Object(DeviceState),
+}
+
+impl StateValue
+{
+    /* JSON of value in an object at indent level */
+    fn json(&self, indent: usize) -> String {
+        match *self {
+            StateValue::Bool(val) => val.to_string(),
+            StateValue::Number(val) => val.to_string(),
+            StateValue::Hex(val, digits) => format!("\"0x{:01$x}\"", val, digits),
+            StateValue::Text(ref val) => quote(val),
+            StateValue::Object(ref obj) => {
+                let mut text = String::new();
+                obj.write_json(indent, &mut text);
+                text
+            },
+        }
+    }
+
+    /**
+     * Value as DeviceState::to_json() has it in a state
+     */
+    pub fn to_json(&self) -> String {
+        self.json(0)
+    }
+}
+
+/**
+ * Device state as named fields in the order device gave them
+ */
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct DeviceState
+{
+    pub fields: Vec<(String, StateValue)>,
+    pub annotations: Vec<(String, String)>,  // Host side facts about device, e.g. backing file
+}
+
+impl DeviceState
+{
+    pub fn new() -> DeviceState {
+        DeviceState::default()
+    }
+
+    pub fn bool(mut self, name: &str, val: bool) -> DeviceState {
+        self.fields.push((String::from(name), StateValue::Bool(val)));
+        self
+    }
+
+    pub fn number(mut self, name: &str, val: u64) -> DeviceState {
+        self.fields.push((String::from(name), StateValue::Number(val)));
+        self
+    }
+
+    pub fn hex(mut self, name: &str, val: u64, digits: usize) -> DeviceState {
+        self.fields.push((String::from(name), StateValue::Hex(val, digits)));
+        self
+    }
+
+    pub fn text(mut self, name: &str, val: &str) -> DeviceState {
+        self.fields.push((String::from(name), StateValue::Text(String::from(val))));
+        self
+    }
+
+    pub fn object(mut self, name: &str, val: DeviceState) -> DeviceState {
+        self.fields.push((String::from(name), StateValue::Object(val)));
+        self
+    }
+
+    pub fn annotate(mut self, name: &str, note: &str) -> DeviceState {
+        self.annotations.push((String::from(name), String::from(note)));
+        self
+    }
+
+    /**
+     * Field value by name
+     */
+    pub fn get(&self, name: &str) -> Option<&StateValue> {
+        self.fields.iter().find(|&&(ref field, _)| field == name).map(|&(_, ref val)| val)
+    }
+
+    /* Members of JSON object at indent level, annotations go last under their own key */
+    fn write_json(&self, indent: usize, out: &mut String) {
+        let mut members: Vec<(&str, String)> = self.fields.iter().map(|&(ref name, ref val)| (name.as_str(), val.json(indent + 1))).collect();
+
+        if !self.annotations.is_empty() {
+            let notes = DeviceState {
+                fields: self.annotations.iter().map(|&(ref name, ref note)| (name.clone(), StateValue::Text(note.clone()))).collect(),
+                annotations: Vec::new(),
+            };
+            let mut text = String::new();
+            notes.write_json(indent + 1, &mut text);
+            members.push(("annotations", text));
+        }
+
+        if members.is_empty() {
+            out.push_str("{}");
+            return;
+        }
+
+        let pad = "  ".repeat(indent + 1);
+        let lines: Vec<String> = members.iter().map(|&(name, ref text)| format!("{}{}: {}", pad, quote(name), text)).collect();
+        out.push_str(&format!("{{\n{}\n{}}}", lines.join(",\n"), "  ".repeat(indent)));
+    }
+
+    /**
+     * State as indented JSON object
+     */
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        self.write_json(0, &mut out);
+        out
+    }
+
+    /**
+     * State to_json() gave, "0x" strings read back as hex fields
+     */
+    pub fn from_json(text: &str) -> Result<DeviceState, String> {
+        let mut parser = JsonParser { text: text.as_bytes(), pos: 0 };
+        let state = try!(parser.object());
+        parser.skip_space();
+        if parser.pos != text.len() {
+            return Err(parser.error("end of state"));
+        }
+        Ok(state)
+    }
+
+    /* Field by name for restoring from it */
+    fn field(&self, name: &str) -> Result<&StateValue, String> {
+        self.get(name).ok_or(format!("No {} in device state", name))
+    }
+
+    /**
+     * Number or hex field value
+     */
+    pub fn get_number(&self, name: &str) -> Result<u64, String> {
+        match *try!(self.field(name)) {
+            StateValue::Number(val) | StateValue::Hex(val, _) => Ok(val),
+            _ => Err(format!("Device state {} isn't a number", name)),
+        }
+    }
+
+    pub fn get_bool(&self, name: &str) -> Result<bool, String> {
+        match *try!(self.field(name)) {
+            StateValue::Bool(val) => Ok(val),
+            _ => Err(format!("Device state {} isn't a bool", name)),
+        }
+    }
+
+    pub fn get_text(&self, name: &str) -> Result<&str, String> {
+        match *try!(self.field(name)) {
+            StateValue::Text(ref val) => Ok(val),
+            _ => Err(format!("Device state {} isn't text", name)),
+        }
+    }
+
+    pub fn get_object(&self, name: &str) -> Result<&DeviceState, String> {
+        match *try!(self.field(name)) {
+            StateValue::Object(ref val) => Ok(val),
+            _ => Err(format!("Device state {} isn't an object", name)),
+        }
+    }
+}
+
+/**
+ * What restoring a device does with saved fields its state doesn't have
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum UnknownFields
+{
+    Ignore,     // Dropped, device takes the fields it knows
+    Reject,     // Restore fails naming the field
+}
+
+impl DeviceState
+{
+    /**
+     * State with only the fields known has, in objects nested in it too, fields known doesn't have are dropped
+     * or fail it as policy says. Annotations are host side and kept as they are.
+     */
+    pub fn known_fields(self, known: &DeviceState, policy: UnknownFields) -> Result<DeviceState, String> {
+        self.keep_known(known, policy, "")
+    }
+
+    fn keep_known(self, known: &DeviceState, policy: UnknownFields, path: &str) -> Result<DeviceState, String> {
+        let mut fields = Vec::new();
+        for (name, val) in self.fields {
+            let full = if path.is_empty() { name.clone() } else { format!("{}.{}", path, name) };
+            match (known.get(&name), val) {
+                (None, _) => if policy == UnknownFields::Reject {
+                    return Err(format!("Unknown field {} in device state", full));
+                },
+                (Some(&StateValue::Object(ref known)), StateValue::Object(obj)) => {
+                    let obj = try!(obj.keep_known(known, policy, &full));
+                    fields.push((name, StateValue::Object(obj)));
+                },
+                (Some(_), val) => fields.push((name, val)),
+            }
+        }
+        Ok(DeviceState { fields: fields, annotations: self.annotations })
+    }
+}
+
+/**
+ * Encoding of device states as bytes, decode() takes back what encode() gave
+ */
+pub trait state_codec
+{
+    fn encode(&self, state: &DeviceState) -> Vec<u8>;
+
+    fn decode(&self, data: &[u8]) -> Result<DeviceState, String>;
+}
+
+/**
+ * Indented JSON as to_json() writes it, for people to read
+ */
+pub struct JsonCodec;
+
+impl state_codec for JsonCodec
+{
+    fn encode(&self, state: &DeviceState) -> Vec<u8> {
+        state.to_json().into_bytes()
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<DeviceState, String> {
+        let text = try!(::std::str::from_utf8(data).map_err(|_| String::from("Device state JSON isn't UTF-8")));
+        DeviceState::from_json(text)
+    }
+}
+
+/*
+ * Binary encoding: field count, name and tagged value of each field, then annotation count, name and text of
+ * each. Counts, lengths and numbers are LEB128, names and texts are length prefixed UTF-8. Tags are
+ *
+ *   0  false             3  hex: digit count byte and number
+ *   1  true              4  text
+ *   2  number            5  object: a state as above
+ */
+const TAG_FALSE: u8     = 0;
+const TAG_TRUE: u8      = 1;
+const TAG_NUMBER: u8    = 2;
+const TAG_HEX: u8       = 3;
+const TAG_TEXT: u8      = 4;
+const TAG_OBJECT: u8    = 5;
+
+/**
+ * Compact binary encoding above, for snapshots
+ */
+pub struct BinaryCodec;
+
+fn put_varint(out: &mut Vec<u8>, mut val: u64)
+{
+    while val >= 0x80 {
+        out.push(val as u8 | 0x80);
+        val >>= 7;
+    }
+    out.push(val as u8);
+}
+
+fn put_string(out: &mut Vec<u8>, val: &str)
+{
+    put_varint(out, val.len() as u64);
+    out.extend_from_slice(val.as_bytes());
+}
+
+fn put_state(out: &mut Vec<u8>, state: &DeviceState)
+{
+    put_varint(out, state.fields.len() as u64);
+    for &(ref name, ref val) in &state.fields {
+        put_string(out, name);
+        match *val {
+            StateValue::Bool(val) => out.push(if val { TAG_TRUE } else { TAG_FALSE }),
+            StateValue::Number(val) => {
+                out.push(TAG_NUMBER);
+                put_varint(out, val);
+            },
+            StateValue::Hex(val, digits) => {
+                out.push(TAG_HEX);
+                out.push(digits as u8);
+                put_varint(out, val);
+            },
+            StateValue::Text(ref val) => {
+                out.push(TAG_TEXT);
+                put_string(out, val);
+            },
+            StateValue::Object(ref obj) => {
+                out.push(TAG_OBJECT);
+                put_state(out, obj);
+            },
+        }
+    }
+
+    put_varint(out, state.annotations.len() as u64);
+    for &(ref name, ref note) in &state.annotations {
+        put_string(out, name);
+        put_string(out, note);
+    }
+}
+
+/* Reads back states put_state() writes */
+struct BinaryReader<'a>
+{
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BinaryReader<'a>
+{
+    fn error(&self, what: &str) -> String {
+        format!("Bad binary device state at offset {}, expected {}", self.pos, what)
+    }
+
+    fn byte(&mut self, what: &str) -> Result<u8, String> {
+        match self.data.get(self.pos) {
+            Some(&byte) => {
+                self.pos += 1;
+                Ok(byte)
+            },
+            None => Err(self.error(what)),
+        }
+    }
+
+    fn varint(&mut self, what: &str) -> Result<u64, String> {
+        let mut val = 0u64;
+        for shift in (0..10).map(|i| i * 7) {
+            let byte = try!(self.byte(what));
+            if shift == 63 && byte > 1 {
+                return Err(self.error(what));
+            }
+            val |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(val);
+            }
+        }
+        Err(self.error(what))
+    }
+
+    fn string(&mut self, what: &str) -> Result<String, String> {
+        let len = try!(self.varint(what));
+        if len > (self.data.len() - self.pos) as u64 {
+            return Err(self.error(what));
+        }
+        let bytes = &self.data[self.pos..self.pos + len as usize];
+        let text = try!(String::from_utf8(bytes.to_vec()).map_err(|_| self.error("UTF-8 string")));
+        self.pos += len as usize;
+        Ok(text)
+    }
+
+    fn state(&mut self) -> Result<DeviceState, String> {
+        let mut state = DeviceState::new();
+        for _ in 0..try!(self.varint("field count")) {
+            let name = try!(self.string("field name"));
+            let val = match try!(self.byte("value tag")) {
+                TAG_FALSE => StateValue::Bool(false),
+                TAG_TRUE => StateValue::Bool(true),
+                TAG_NUMBER => StateValue::Number(try!(self.varint("number"))),
+                TAG_HEX => {
+                    let digits = try!(self.byte("hex digits")) as usize;
+                    StateValue::Hex(try!(self.varint("number")), digits)
+                },
+                TAG_TEXT => StateValue::Text(try!(self.string("text"))),
+                TAG_OBJECT => StateValue::Object(try!(self.state())),
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.error("value tag"));
+                },
+            };
+            state.fields.push((name, val));
+        }
+
+        for _ in 0..try!(self.varint("annotation count")) {
+            let name = try!(self.string("annotation name"));
+            let note = try!(self.string("annotation"));
+            state.annotations.push((name, note));
+        }
+        Ok(state)
+    }
+}
+
+impl state_codec for BinaryCodec
+{
+    fn encode(&self, state: &DeviceState) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_state(&mut out, state);
+        out
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<DeviceState, String> {
+        let mut reader = BinaryReader { data: data, pos: 0 };
+        let state = try!(reader.state());
+        if reader.pos != data.len() {
+            return Err(reader.error("end of state"));
+        }
+        Ok(state)
+    }
+}
+
+/* Reads back JSON to_json() writes: objects of numbers, bools and strings escaped by eventlog::quote */
+struct JsonParser<'a>
+{
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> JsonParser<'a>
+{
+    fn error(&self, what: &str) -> String {
+        format!("Bad device state JSON at offset {}, expected {}", self.pos, what)
+    }
+
+    fn skip_space(&mut self) {
+        while self.pos < self.text.len() && (self.text[self.pos] as char).is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    /* Next character past white space, consumed if it's c */
+    fn accept(&mut self, c: u8) -> bool {
+        self.skip_space();
+        if self.pos < self.text.len() && self.text[self.pos] == c {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), String> {
+        if self.accept(c) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("'{}'", c as char)))
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        try!(self.expect(b'"'));
+        let mut out = Vec::new();
+        loop {
+            let c = match self.text.get(self.pos) {
+                Some(&c) => c,
+                None => return Err(self.error("end of string")),
+            };
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let esc = self.text.get(self.pos).cloned();
+                    self.pos += 1;
+                    match esc {
+                        Some(b'"') => out.push(b'"'),
+                        Some(b'\\') => out.push(b'\\'),
+                        Some(b'n') => out.push(b'\n'),
+                        Some(b'u') if self.pos + 4 <= self.text.len() => {
+                            let code = ::std::str::from_utf8(&self.text[self.pos..self.pos + 4]).ok()
+                                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                            match code {
+                                Some(code) if code < 0x20 => out.push(code),
+                                _ => return Err(self.error("control character escape")),
+                            }
+                            self.pos += 4;
+                        },
+                        _ => return Err(self.error("string escape")),
+                    }
+                },
+                c => out.push(c),
+            }
+        }
+        String::from_utf8(out).map_err(|_| self.error("UTF-8 string"))
+    }
+
+    fn value(&mut self) -> Result<StateValue, String> {
+        self.skip_space();
+        let rest = &self.text[self.pos..];
+        if rest.starts_with(b"{") {
+            return self.object().map(StateValue::Object);
+        }
+        if rest.starts_with(b"\"") {
+            let text = try!(self.string());
+            if text.starts_with("0x") && text.len() > 2 {
+                if let Ok(val) = u64::from_str_radix(&text[2..], 16) {
+                    return Ok(StateValue::Hex(val, text.len() - 2));
+                }
+            }
+            return Ok(StateValue::Text(text));
+        }
+        if rest.starts_with(b"true") || rest.starts_with(b"false") {
+            let val = rest.starts_with(b"true");
+            self.pos += if val { 4 } else { 5 };
+            return Ok(StateValue::Bool(val));
+        }
+
+        let digits = rest.iter().take_while(|c| c.is_ascii_digit()).count();
+        let val = ::std::str::from_utf8(&rest[..digits]).ok().and_then(|digits| digits.parse().ok());
+        match val {
+            Some(val) => {
+                self.pos += digits;
+                Ok(StateValue::Number(val))
+            },
+            None => Err(self.error("value")),
+        }
+    }
+
+    fn object(&mut self) -> Result<DeviceState, String> {
+        let mut state = DeviceState::new();
+        try!(self.expect(b'{'));
+        if self.accept(b'}') {
+            return Ok(state);
+        }
+
+        loop {
+            let name = try!(self.string());
+            try!(self.expect(b':'));
+            match try!(self.value()) {
+                StateValue::Object(notes) if name == "annotations" => {
+                    for (name, note) in notes.fields {
+                        match note {
+                            StateValue::Text(note) => state.annotations.push((name, note)),
+                            _ => return Err(self.error("annotation text")),
+                        }
+                    }
+                },
+                val => state.fields.push((name, val)),
+            }
+
+            if !self.accept(b',') {
+                break;
+            }
+        }
+        try!(self.expect(b'}'));
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod devstate_test
+{
+    use super::*;
+
+    #[test] fn json() {
+        let chip = DeviceState::new().hex("irr", 0x01, 2).bool("initialized", false);
+        let state = DeviceState::new()
+            .number("count", 3)
+            .text("mode", "rate \"generator\"")
+            .object("chip", chip)
+            .object("empty", DeviceState::new())
+            .annotate("image", "/tmp/disk.img");
+
+        assert!(state.to_json() == "{\n  \"count\": 3,\n  \"mode\": \"rate \\\"generator\\\"\",\n  \"chip\": {\n    \
+                                    \"irr\": \"0x01\",\n    \"initialized\": false\n  },\n  \"empty\": {},\n  \
+                                    \"annotations\": {\n    \"image\": \"/tmp/disk.img\"\n  }\n}");
+        assert!(state.get("count") == Some(&StateValue::Number(3)) && state.get("image").is_none());
+    }
+
+    #[test] fn from_json() {
+        let chip = DeviceState::new().hex("irr", 0x01, 2).bool("initialized", false);
+        let state = DeviceState::new()
+            .number("count", 3)
+            .text("mode", "rate \"generator\"\n\u{1}")
+            .object("chip", chip)
+            .object("empty", DeviceState::new())
+            .annotate("image", "/tmp/disk.img");
+
+        let parsed = DeviceState::from_json(&state.to_json()).unwrap();
+        assert!(parsed == state);
+        assert!(parsed.get_number("count") == Ok(3) && parsed.get_text("mode") == Ok("rate \"generator\"\n\u{1}"));
+        assert!(parsed.get_object("chip").and_then(|chip| chip.get_number("irr")) == Ok(1));
+        assert!(parsed.get_bool("count").is_err() && parsed.get_number("missing").is_err());
+
+        assert!(DeviceState::from_json("{\"count\": 3").is_err());
+        assert!(DeviceState::from_json("{\"count\": 3} {}").is_err());
+        assert!(DeviceState::from_json("{\"count\": -3}").is_err());
+        assert!(DeviceState::from_json(" {} ") == Ok(DeviceState::new()));
+    }
+
+    fn sample() -> DeviceState {
+        let chip = DeviceState::new().hex("irr", 0x01, 2).hex("base", 0xFFFFFFFFFFFFFFFF, 16).bool("initialized", false);
+        DeviceState::new()
+            .number("count", 300)
+            .text("mode", "rate \"generator\"\n\u{1}")
+            .object("chip", chip)
+            .object("empty", DeviceState::new())
+            .bool("ready", true)
+            .annotate("image", "/tmp/disk.img")
+    }
+
+    #[test] fn codecs() {
+        let state = sample();
+        for codec in &[&JsonCodec as &state_codec, &BinaryCodec] {
+            assert!(codec.decode(&codec.encode(&state)) == Ok(state.clone()));
+            assert!(codec.decode(&codec.encode(&DeviceState::new())) == Ok(DeviceState::new()));
+        }
+        assert!(JsonCodec.encode(&state) == state.to_json().into_bytes());
+
+        /* Binary is the compact one, and a cut or padded one doesn't read */
+        let binary = BinaryCodec.encode(&state);
+        assert!(binary.len() * 2 < JsonCodec.encode(&state).len());
+        assert!(&binary[..8] == &[5, 5, b'c', b'o', b'u', b'n', b't', 2]);
+        assert!(&binary[8..10] == &[0xAC, 0x02]);
+        for len in 0..binary.len() {
+            assert!(BinaryCodec.decode(&binary[..len]).is_err(), "{}", len);
+        }
+        let mut padded = binary.clone();
+        padded.push(0);
+        assert!(BinaryCodec.decode(&padded).unwrap_err().contains("end of state"));
+
+        let mut bad_tag = binary.clone();
+        bad_tag[7] = 6;
+        assert!(BinaryCodec.decode(&bad_tag).unwrap_err() == "Bad binary device state at offset 7, expected value tag");
+        assert!(BinaryCodec.decode(&[1, 1, b'n', TAG_NUMBER, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x02, 0]).is_err());
+        assert!(BinaryCodec.decode(&[1, 1, b'n', TAG_NUMBER, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01, 0])
+                == Ok(DeviceState::new().number("n", !0)));
+    }
+
+    #[test] fn unknown_fields() {
+        let known = DeviceState::new().number("count", 0).object("chip", DeviceState::new().hex("irr", 0, 2)).text("mode", "");
+        let saved = DeviceState::new()
+            .number("count", 3)
+            .object("chip", DeviceState::new().hex("irr", 0x01, 2).bool("latched", true))
+            .number("spare", 7)
+            .annotate("image", "/tmp/disk.img");
+
+        /* Fields device doesn't have go, the ones it has stay whether or not state has them */
+        let kept = saved.clone().known_fields(&known, UnknownFields::Ignore).unwrap();
+        assert!(kept == DeviceState::new().number("count", 3).object("chip", DeviceState::new().hex("irr", 0x01, 2)).annotate("image", "/tmp/disk.img"));
+
+        assert!(saved.clone().known_fields(&known, UnknownFields::Reject).unwrap_err() == "Unknown field chip.latched in device state");
+        let flat = DeviceState::new().number("count", 3).number("spare", 7);
+        assert!(flat.known_fields(&known, UnknownFields::Reject).unwrap_err() == "Unknown field spare in device state");
+        assert!(kept.clone().known_fields(&known, UnknownFields::Reject) == Ok(kept));
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+fn cmd_info_devices(_: &mut monitor::MonitorContext, _: &[&str]) -> Result<String, String>
+{
+    Ok(vm::device_names().join("\n"))
+}
+
+fn cmd_info_device(_: &mut monitor::MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    if args.len() != 1 {
+        return Err(String::from("Usage: info device <name>"));
+    }
+
+    vm::device_state(args[0]).map(|state| state.to_json())
+}
+
+/**
+ * Add device state monitor commands
+ */
+pub fn init()
+{
+    monitor::register_command(monitor::MonitorCommand {
+        name: "info devices",
+        args: "",
+        help: "list devices with inspectable state",
+        handler: cmd_info_devices,
+    });
+    monitor::register_command(monitor::MonitorCommand {
+        name: "info device",
+        args: "name",
+        help: "show device state as JSON",
+        handler: cmd_info_device,
+    });
+}
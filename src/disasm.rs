@@ -0,0 +1,265 @@
+/*
+ * Guest code disassembly
+ *
+ * Instructions are decoded by capstone. vm::disasm() decodes a window of 16 bit code in guest memory at a guest
+ * physical or real mode seg:off address and returns instructions with their address, bytes and mnemonic, for
+ * callers to format the way they need.
+ *
+ * Real mode code is fetched like the CPU fetches it: offset wraps around within its segment, and with A20 gate
+ * disabled addresses above 1M wrap around to 0. A window running into unmapped memory ends at the last
+ * instruction guest memory holds in full and says where memory ended.
+ */
+
+use capstone;
+
+use std::fmt;
+
+// Longest x86 instruction
+pub const MAX_INSN_BYTES: usize = 15;
+
+// Address bit A20 gate masks
+const A20_BIT: u64 = 1 << 20;
+
+/**
+ * Where guest code is
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum CodeAddress
+{
+    Physical(u64),
+    RealMode(u16, u16),     // Segment and offset
+}
+
+impl CodeAddress
+{
+    /**
+     * Address delta bytes further, real mode offsets wrap within their segment
+     */
+    pub fn advance(&self, delta: u64) -> CodeAddress {
+        match *self {
+            CodeAddress::Physical(addr) => CodeAddress::Physical(addr + delta),
+            CodeAddress::RealMode(seg, off) => CodeAddress::RealMode(seg, off.wrapping_add(delta as u16)),
+        }
+    }
+
+    /**
+     * Guest physical address CPU would access with A20 gate state
+     */
+    pub fn resolve(&self, a20_enabled: bool) -> u64 {
+        let addr = match *self {
+            CodeAddress::Physical(addr) => addr,
+            CodeAddress::RealMode(seg, off) => ((seg as u64) << 4) + off as u64,
+        };
+
+        if a20_enabled { addr } else { addr & !A20_BIT }
+    }
+
+    /* Offset capstone shows in jump targets */
+    fn ip(&self) -> u64 {
+        match *self {
+            CodeAddress::Physical(addr) => addr,
+            CodeAddress::RealMode(_, off) => off as u64,
+        }
+    }
+}
+
+impl fmt::Display for CodeAddress
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CodeAddress::Physical(addr) => write!(f, "0x{:x}", addr),
+            CodeAddress::RealMode(seg, off) => write!(f, "{:04x}:{:04x}", seg, off),
+        }
+    }
+}
+
+/**
+ * Decoded instruction, mnemonic is "(bad)" for bytes that don't decode
+ */
+#[derive(PartialEq, Debug, Clone)]
+pub struct DisasmInsn
+{
+    pub addr: CodeAddress,
+    pub bytes: Vec<u8>,
+    pub mnemonic: String,
+    pub operands: String,
+}
+
+impl DisasmInsn
+{
+    /**
+     * Instruction as assembly text
+     */
+    pub fn text(&self) -> String {
+        instruction_text(&self.mnemonic, &self.operands)
+    }
+}
+
+/**
+ * Instructions in a window of guest code
+ */
+#[derive(PartialEq, Debug, Clone)]
+pub struct Disassembly
+{
+    pub insns: Vec<DisasmInsn>,
+    pub truncated: Option<CodeAddress>,     // Where guest memory ended before the window did
+}
+
+/**
+ * Mnemonic and operands as one line of assembly
+ */
+pub fn instruction_text(mnemonic: &str, operands: &str) -> String
+{
+    if operands.is_empty() {
+        String::from(mnemonic)
+    } else {
+        format!("{} {}", mnemonic, operands)
+    }
+}
+
+/**
+ * Decode instruction at the start of code, its size, mnemonic and operands
+ */
+pub fn decode(code: &[u8], ip: u64, code16: bool) -> Option<(usize, String, String)>
+{
+    let mode = if code16 { capstone::CsMode::MODE_16 } else { capstone::CsMode::MODE_32 };
+    let cs = match capstone::Capstone::new(capstone::CsArch::ARCH_X86, mode) {
+        Ok(cs) => cs,
+        Err(_) => return None,
+    };
+
+    let insns = match cs.disasm(code, ip, 1) {
+        Ok(insns) => insns,
+        Err(_) => return None,
+    };
+
+    insns.iter().next().map(|insn| {
+        let mnemonic = insn.mnemonic().unwrap_or("").to_string();
+        let operands = insn.op_str().unwrap_or("").to_string();
+        (insn.size, mnemonic, operands)
+    })
+}
+
+/**
+ * Fetch up to len code bytes at addr the way CPU does, stops at the first byte read_byte can't get
+ */
+pub fn fetch<F>(addr: CodeAddress, len: usize, a20_enabled: bool, mut read_byte: F) -> Vec<u8>
+    where F: FnMut(u64) -> Option<u8>
+{
+    let mut code = Vec::with_capacity(len);
+    while code.len() < len {
+        match read_byte(addr.advance(code.len() as u64).resolve(a20_enabled)) {
+            Some(byte) => code.push(byte),
+            None => break,
+        }
+    }
+    code
+}
+
+/**
+ * Decode up to count 16 bit instructions from code fetched at addr
+ * Code is complete if it wasn't cut short by unmapped memory, a cut off last instruction is left out then.
+ */
+pub fn decode_window(code: &[u8], addr: CodeAddress, count: usize, complete: bool) -> Disassembly
+{
+    let mut insns = Vec::new();
+    let mut offset = 0;
+    while insns.len() < count && offset < code.len() {
+        let at = addr.advance(offset as u64);
+        let (size, mnemonic, operands) = match decode(&code[offset..], at.ip(), true) {
+            Some(decoded) => decoded,
+            None if !complete && code.len() - offset < MAX_INSN_BYTES => break,
+            None => (1, String::from("(bad)"), String::new()),
+        };
+
+        insns.push(DisasmInsn {
+            addr: at,
+            bytes: code[offset..offset + size].to_vec(),
+            mnemonic: mnemonic,
+            operands: operands,
+        });
+        offset += size;
+    }
+
+    let truncated = if !complete && insns.len() < count { Some(addr.advance(code.len() as u64)) } else { None };
+    Disassembly { insns: insns, truncated: truncated }
+}
+
+#[cfg(test)]
+mod disasm_test
+{
+    use super::*;
+
+    /*
+     * operand size prefix    66 b8 78 56 34 12   mov eax, 0x12345678
+     * segment override       26 8a 07            mov al, byte ptr es:[bx]
+     * rep prefix             f3 a4               rep movsb
+     * far jump               ea 00 7c 00 00      ljmp 0:0x7c00
+     * invalid opcode         fe                  FE /7 is undefined, decoding goes on with the next byte
+     *                        f8                  clc
+     *                        f4                  hlt
+     */
+    const FIXTURE: [u8; 19] = [0x66, 0xb8, 0x78, 0x56, 0x34, 0x12, 0x26, 0x8a, 0x07, 0xf3, 0xa4,
+                               0xea, 0x00, 0x7c, 0x00, 0x00, 0xfe, 0xf8, 0xf4];
+
+    fn mnemonics(disasm: &Disassembly) -> Vec<&str> {
+        disasm.insns.iter().map(|insn| insn.mnemonic.as_str()).collect()
+    }
+
+    #[test] fn decode_fixture() {
+        let disasm = decode_window(&FIXTURE, CodeAddress::RealMode(0, 0x7c00), 10, true);
+        assert!(mnemonics(&disasm) == vec!["mov", "mov", "rep movsb", "ljmp", "(bad)", "clc", "hlt"]);
+        assert!(disasm.truncated.is_none());
+
+        let insns = &disasm.insns;
+        assert!(insns[0].text() == "mov eax, 0x12345678");
+        assert!(insns[0].bytes == vec![0x66, 0xb8, 0x78, 0x56, 0x34, 0x12]);
+        assert!(insns[1].addr == CodeAddress::RealMode(0, 0x7c06));
+        assert!(insns[3].bytes.len() == 5 && insns[4].bytes == vec![0xfe]);
+        assert!(insns[6].addr == CodeAddress::RealMode(0, 0x7c12) && insns[6].text() == "hlt");
+
+        /* Count limits instructions */
+        assert!(mnemonics(&decode_window(&FIXTURE, CodeAddress::Physical(0x7c00), 2, true)) == vec!["mov", "mov"]);
+    }
+
+    #[test] fn truncated_window() {
+        /* Memory ends in the middle of the far jump */
+        let disasm = decode_window(&FIXTURE[..13], CodeAddress::Physical(0x9fff3), 10, false);
+        assert!(mnemonics(&disasm) == vec!["mov", "mov", "rep movsb"]);
+        assert!(disasm.truncated == Some(CodeAddress::Physical(0xa0000)));
+
+        /* All instructions asked for fit before the end */
+        let disasm = decode_window(&FIXTURE[..13], CodeAddress::Physical(0x9fff3), 2, false);
+        assert!(disasm.truncated.is_none());
+
+        let disasm = decode_window(&[], CodeAddress::RealMode(0xa000, 0), 1, false);
+        assert!(disasm.insns.is_empty() && disasm.truncated == Some(CodeAddress::RealMode(0xa000, 0)));
+    }
+
+    #[test] fn real_mode_fetch() {
+        /* Offset wraps within segment */
+        let mut addrs = Vec::new();
+        let code = fetch(CodeAddress::RealMode(0x1000, 0xfffe), 4, true, |addr| { addrs.push(addr); Some(addr as u8) });
+        assert!(addrs == vec![0x1fffe, 0x1ffff, 0x10000, 0x10001]);
+        assert!(code == vec![0xfe, 0xff, 0x00, 0x01]);
+
+        /* High memory is only there with A20 enabled */
+        assert!(CodeAddress::RealMode(0xffff, 0x0010).resolve(true) == 0x100000);
+        assert!(CodeAddress::RealMode(0xffff, 0x0010).resolve(false) == 0);
+        assert!(CodeAddress::RealMode(0xf000, 0xfff0).resolve(false) == 0xffff0);
+
+        let mut addrs = Vec::new();
+        fetch(CodeAddress::RealMode(0xffff, 0x000e), 4, false, |addr| { addrs.push(addr); Some(0) });
+        assert!(addrs == vec![0xffffe, 0xfffff, 0x0, 0x1]);
+
+        /* Fetch stops at unmapped memory */
+        let code = fetch(CodeAddress::Physical(0x9fffe), 4, true, |addr| if addr < 0xa0000 { Some(0x90) } else { None });
+        assert!(code == vec![0x90, 0x90]);
+    }
+
+    #[test] fn address_format() {
+        assert!(CodeAddress::RealMode(0xf000, 0xfff0).to_string() == "f000:fff0");
+        assert!(CodeAddress::Physical(0x7c00).to_string() == "0x7c00");
+        assert!(instruction_text("hlt", "") == "hlt");
+    }
+}
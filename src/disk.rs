@@ -0,0 +1,707 @@
+/*
+ * Disk image backends for storage devices
+ */
+
+use config;
+use record;
+use replay;
+
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+
+/**
+ * Random access storage behind an emulated drive
+ */
+pub trait disk_image
+{
+    /** Image size in bytes */
+    fn size(&self) -> u64;
+
+    /** Read exactly buf.len() bytes at offset, fails if range is out of image bounds */
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    /** Write exactly buf.len() bytes at offset, fails if range is out of image bounds or image is read-only */
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()>;
+
+    /** Make completed writes durable on host storage */
+    fn flush(&mut self) -> io::Result<()>;
+
+    /** Writes are rejected */
+    fn is_read_only(&self) -> bool;
+}
+
+fn check_bounds(size: u64, offset: u64, len: usize) -> io::Result<()>
+{
+    match offset.checked_add(len as u64) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "access out of image bounds")),
+    }
+}
+
+fn read_only_error() -> io::Error
+{
+    io::Error::new(io::ErrorKind::PermissionDenied, "image is read-only")
+}
+
+/**
+ * Raw image file
+ *
+ * Image size is the file size, writes past it fail instead of growing the file.
+ * Growable images have a fixed size of their own: file may be shorter, it reads as zeroes past its end
+ * and grows as data gets written there.
+ */
+pub struct FileImage
+{
+    file: File,
+    size: u64,
+    file_size: u64,
+    read_only: bool,
+}
+
+impl FileImage
+{
+    pub fn open(path: &str) -> io::Result<FileImage> {
+        let file = try!(OpenOptions::new().read(true).write(true).open(path));
+        FileImage::from_file(file, false)
+    }
+
+    /** Open image without write access, file is never modified */
+    pub fn open_read_only(path: &str) -> io::Result<FileImage> {
+        let file = try!(OpenOptions::new().read(true).open(path));
+        FileImage::from_file(file, true)
+    }
+
+    /** Open image of given size which file grows into, file is created if missing */
+    pub fn open_growable(path: &str, size: u64) -> io::Result<FileImage> {
+        let file = try!(OpenOptions::new().read(true).write(true).create(true).open(path));
+        let mut image = try!(FileImage::from_file(file, false));
+        if image.file_size > size {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "image file is larger than its size"));
+        }
+
+        image.size = size;
+        Ok(image)
+    }
+
+    fn from_file(file: File, read_only: bool) -> io::Result<FileImage> {
+        let size = try!(file.metadata()).len();
+
+        Ok(FileImage {
+            file: file,
+            size: size,
+            file_size: size,
+            read_only: read_only,
+        })
+    }
+}
+
+impl disk_image for FileImage
+{
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        try!(check_bounds(self.size, offset, buf.len()));
+
+        /* Part of growable image file hasn't reached yet */
+        let in_file = if offset < self.file_size {
+            ::std::cmp::min(buf.len() as u64, self.file_size - offset) as usize
+        } else {
+            0
+        };
+
+        for b in buf[in_file..].iter_mut() {
+            *b = 0;
+        }
+
+        if in_file != 0 {
+            try!(self.file.seek(SeekFrom::Start(offset)));
+            try!(self.file.read_exact(&mut buf[..in_file]));
+        }
+
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        if self.read_only {
+            return Err(read_only_error());
+        }
+
+        try!(check_bounds(self.size, offset, buf.len()));
+        try!(self.file.seek(SeekFrom::Start(offset)));
+        try!(self.file.write_all(buf));
+
+        self.file_size = ::std::cmp::max(self.file_size, offset + buf.len() as u64);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        self.file.sync_all()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+/**
+ * Image kept in host memory
+ */
+pub struct MemImage
+{
+    pub data: Vec<u8>,
+}
+
+impl MemImage
+{
+    pub fn new(size: usize) -> MemImage {
+        MemImage {
+            data: vec![0; size],
+        }
+    }
+}
+
+impl disk_image for MemImage
+{
+    fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        try!(check_bounds(self.size(), offset, buf.len()));
+        let offset = offset as usize;
+        buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        try!(check_bounds(self.size(), offset, buf.len()));
+        let offset = offset as usize;
+        self.data[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+}
+
+// Copy-on-write overlay file format, integers are little endian
+const COW_MAGIC: &'static [u8; 8] = b"XVMCOW01";
+const COW_HEADER_SIZE: u64      = 512;
+const COW_CLUSTER_SIZE: u64     = 0x10000;
+
+fn get_le64(b: &[u8]) -> u64
+{
+    b.iter().rev().fold(0, |val, byte| (val << 8) | *byte as u64)
+}
+
+fn put_le64(b: &mut [u8], val: u64)
+{
+    for (i, byte) in b.iter_mut().enumerate() {
+        *byte = (val >> (i * 8)) as u8;
+    }
+}
+
+fn bad_overlay(msg: &str) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/**
+ * Copy-on-write overlay over a read-only base image
+ *
+ * Overlay file layout:
+ * - Header: magic, cluster size (u64), image size (u64), padded to COW_HEADER_SIZE
+ * - Allocation table: overlay file offset (u64) of every cluster of the image, 0 while cluster is in base
+ * - Cluster data, appended as clusters get first written
+ *
+ * First write to a cluster copies it from base, so base file is never written.
+ * Allocation table is read from file on every access, several instances over one overlay stay coherent.
+ */
+pub struct CowImage
+{
+    base: FileImage,
+    overlay: File,
+    size: u64,
+}
+
+impl CowImage
+{
+    /** Open overlay over base, new overlay is created if file is missing or empty */
+    pub fn open(base_path: &str, overlay_path: &str) -> io::Result<CowImage> {
+        let base = try!(FileImage::open_read_only(base_path));
+        let overlay = try!(OpenOptions::new().read(true).write(true).create(true).open(overlay_path));
+
+        let mut image = CowImage {
+            size: base.size(),
+            base: base,
+            overlay: overlay,
+        };
+
+        if try!(image.overlay.metadata()).len() == 0 {
+            try!(image.format());
+        } else {
+            try!(image.check_header());
+        }
+
+        Ok(image)
+    }
+
+    fn clusters(&self) -> u64 {
+        (self.size + COW_CLUSTER_SIZE - 1) / COW_CLUSTER_SIZE
+    }
+
+    /* Write header and empty allocation table */
+    fn format(&mut self) -> io::Result<()> {
+        let mut header = vec![0u8; COW_HEADER_SIZE as usize];
+        header[0..8].copy_from_slice(COW_MAGIC);
+        put_le64(&mut header[8..16], COW_CLUSTER_SIZE);
+        put_le64(&mut header[16..24], self.size);
+
+        try!(self.overlay.seek(SeekFrom::Start(0)));
+        try!(self.overlay.write_all(&header));
+        try!(self.overlay.write_all(&vec![0u8; self.clusters() as usize * 8]));
+        self.overlay.sync_all()
+    }
+
+    fn check_header(&mut self) -> io::Result<()> {
+        let mut header = vec![0u8; COW_HEADER_SIZE as usize];
+        try!(self.overlay.seek(SeekFrom::Start(0)));
+        try!(self.overlay.read_exact(&mut header));
+
+        if &header[0..8] != COW_MAGIC || get_le64(&header[8..16]) != COW_CLUSTER_SIZE {
+            return Err(bad_overlay("not an overlay file"));
+        }
+
+        if get_le64(&header[16..24]) != self.size {
+            return Err(bad_overlay("overlay was made for a base image of different size"));
+        }
+
+        Ok(())
+    }
+
+    fn table_offset(cluster: u64) -> u64 {
+        COW_HEADER_SIZE + cluster * 8
+    }
+
+    /* Overlay offset of cluster data, None if cluster is in base */
+    fn lookup(&mut self, cluster: u64) -> io::Result<Option<u64>> {
+        let mut entry = [0u8; 8];
+        try!(self.overlay.seek(SeekFrom::Start(CowImage::table_offset(cluster))));
+        try!(self.overlay.read_exact(&mut entry));
+
+        match get_le64(&entry) {
+            0 => Ok(None),
+            offset => Ok(Some(offset)),
+        }
+    }
+
+    /*
+     * Copy cluster from base to the end of overlay and point table at it.
+     * Table is updated after data is in place, so interrupted allocation leaves cluster in base.
+     */
+    fn allocate(&mut self, cluster: u64) -> io::Result<u64> {
+        let start = cluster * COW_CLUSTER_SIZE;
+        let mut data = vec![0u8; COW_CLUSTER_SIZE as usize];
+        let len = cmp::min(COW_CLUSTER_SIZE, self.size - start) as usize;
+        try!(self.base.read_at(start, &mut data[..len]));
+
+        let offset = try!(self.overlay.seek(SeekFrom::End(0)));
+        try!(self.overlay.write_all(&data));
+
+        let mut entry = [0u8; 8];
+        put_le64(&mut entry, offset);
+        try!(self.overlay.seek(SeekFrom::Start(CowImage::table_offset(cluster))));
+        try!(self.overlay.write_all(&entry));
+
+        Ok(offset)
+    }
+}
+
+impl disk_image for CowImage
+{
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        try!(check_bounds(self.size, offset, buf.len()));
+
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let within = pos % COW_CLUSTER_SIZE;
+            let len = cmp::min((COW_CLUSTER_SIZE - within) as usize, buf.len() - done);
+            let chunk = &mut buf[done..done + len];
+
+            match try!(self.lookup(pos / COW_CLUSTER_SIZE)) {
+                Some(data) => {
+                    try!(self.overlay.seek(SeekFrom::Start(data + within)));
+                    try!(self.overlay.read_exact(chunk));
+                },
+                None => try!(self.base.read_at(pos, chunk)),
+            }
+
+            done += len;
+        }
+
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        try!(check_bounds(self.size, offset, buf.len()));
+
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let cluster = pos / COW_CLUSTER_SIZE;
+            let within = pos % COW_CLUSTER_SIZE;
+            let len = cmp::min((COW_CLUSTER_SIZE - within) as usize, buf.len() - done);
+
+            let data = match try!(self.lookup(cluster)) {
+                Some(data) => data,
+                None => try!(self.allocate(cluster)),
+            };
+
+            try!(self.overlay.seek(SeekFrom::Start(data + within)));
+            try!(self.overlay.write_all(&buf[done..done + len]));
+            done += len;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.overlay.sync_all()
+    }
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+}
+
+/**
+ * Read-only window into part of another image, e.g. El Torito floppy image inside an ISO
+ */
+pub struct SliceImage
+{
+    image: Box<disk_image>,
+    offset: u64,
+    size: u64,
+}
+
+impl SliceImage
+{
+    pub fn new(image: Box<disk_image>, offset: u64, size: u64) -> io::Result<SliceImage> {
+        match offset.checked_add(size) {
+            Some(end) if end <= image.size() => {},
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "slice is out of image bounds")),
+        }
+
+        Ok(SliceImage {
+            image: image,
+            offset: offset,
+            size: size,
+        })
+    }
+}
+
+impl disk_image for SliceImage
+{
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        try!(check_bounds(self.size, offset, buf.len()));
+        self.image.read_at(self.offset + offset, buf)
+    }
+
+    fn write_at(&mut self, _offset: u64, _buf: &[u8]) -> io::Result<()> {
+        Err(read_only_error())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+}
+
+/**
+ * Image passing data read from another one to input recording, see record.rs
+ * Writable images end up changed by the run, so replay can't read them again.
+ */
+pub struct RecordedImage
+{
+    inner: Box<disk_image>,
+    device: &'static str,
+}
+
+impl RecordedImage
+{
+    pub fn new(inner: Box<disk_image>, device: &'static str) -> RecordedImage {
+        RecordedImage {
+            inner: inner,
+            device: device,
+        }
+    }
+}
+
+impl disk_image for RecordedImage
+{
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    /* Replay reads what recording did, images may have been written since */
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if replay::enabled() {
+            try!(check_bounds(self.size(), offset, buf.len()));
+            if replay::disk_read(self.device, offset, buf) {
+                return Ok(());
+            }
+        }
+
+        try!(self.inner.read_at(offset, buf));
+        let device = self.device;
+        record::emit(|| record::Input::DiskRead { device: device, offset: offset, data: buf.to_vec() });
+        Ok(())
+    }
+
+    /* Replay leaves images as they are */
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        if replay::enabled() {
+            return if self.is_read_only() { Err(read_only_error()) } else { Ok(()) };
+        }
+        self.inner.write_at(offset, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+}
+
+/**
+ * Open hard disk image the way configuration attaches it
+ */
+pub fn open_hda(config: &config::VmConfig, path: &str) -> io::Result<Box<disk_image>>
+{
+    let image: Box<disk_image> = if let Some(ref overlay) = config.hda_overlay {
+        Box::new(try!(CowImage::open(path, overlay)))
+    } else if config.hda_read_only {
+        return Ok(Box::new(try!(FileImage::open_read_only(path))));
+    } else if let Some(size) = config.hda_grow {
+        Box::new(try!(FileImage::open_growable(path, size)))
+    } else {
+        Box::new(try!(FileImage::open(path)))
+    };
+    Ok(Box::new(RecordedImage::new(image, "hda")))
+}
+
+/**
+ * Open floppy image the way configuration attaches it
+ */
+pub fn open_floppy(config: &config::VmConfig, path: &str) -> io::Result<Box<disk_image>>
+{
+    let image: Box<disk_image> = match config.floppy_overlay {
+        Some(ref overlay) => Box::new(try!(CowImage::open(path, overlay))),
+        None => Box::new(try!(FileImage::open(path))),
+    };
+    Ok(Box::new(RecordedImage::new(image, "fda")))
+}
+
+#[cfg(test)]
+mod disk_test
+{
+    use super::*;
+
+    #[test] fn mem_image() {
+        let mut img = MemImage::new(1024);
+        assert!(img.write_at(512, &[1, 2, 3]).is_ok());
+
+        let mut buf = [0u8; 4];
+        assert!(img.read_at(511, &mut buf).is_ok());
+        assert!(buf == [0, 1, 2, 3]);
+
+        assert!(img.read_at(1022, &mut buf).is_err());
+        assert!(img.write_at(1024, &[0]).is_err());
+        assert!(img.read_at(u64::max_value(), &mut buf).is_err());
+    }
+
+    #[test] fn slice_image() {
+        let mut img = MemImage::new(4096);
+        assert!(img.write_at(1024, &[1, 2, 3, 4]).is_ok());
+
+        let mut slice = SliceImage::new(Box::new(img), 1025, 2048).unwrap();
+        assert!(slice.size() == 2048 && slice.is_read_only());
+
+        let mut buf = [0u8; 4];
+        assert!(slice.read_at(0, &mut buf).is_ok());
+        assert!(buf == [2, 3, 4, 0]);
+        assert!(slice.read_at(2045, &mut buf).is_err());
+        assert!(slice.write_at(0, &[0]).is_err());
+
+        assert!(SliceImage::new(Box::new(MemImage::new(4096)), 2048, 2049).is_err());
+    }
+
+    #[test] fn file_image() {
+        let path = ::std::env::temp_dir().join(format!("xvm_disk_test_{}.img", ::std::process::id()));
+        {
+            let mut file = File::create(&path).unwrap();
+            file.write_all(&[0xAA; 2048]).unwrap();
+        }
+
+        let mut img = FileImage::open(path.to_str().unwrap()).unwrap();
+        assert!(img.size() == 2048);
+        assert!(img.write_at(1024, &[0x55; 4]).is_ok());
+
+        let mut buf = [0u8; 6];
+        assert!(img.read_at(1023, &mut buf).is_ok());
+        assert!(buf == [0xAA, 0x55, 0x55, 0x55, 0x55, 0xAA]);
+        assert!(img.read_at(2044, &mut buf).is_err());
+
+        /* Never grows past end of file */
+        assert!(img.write_at(2046, &[0x55; 4]).is_err());
+        assert!(img.flush().is_ok());
+        assert!(::std::fs::metadata(&path).unwrap().len() == 2048);
+
+        ::std::fs::remove_file(&path).ok();
+    }
+
+    #[test] fn read_only_image() {
+        let path = ::std::env::temp_dir().join(format!("xvm_disk_ro_test_{}.img", ::std::process::id()));
+        {
+            let mut file = File::create(&path).unwrap();
+            file.write_all(&[0xAA; 1024]).unwrap();
+        }
+
+        let mut img = FileImage::open_read_only(path.to_str().unwrap()).unwrap();
+        assert!(img.is_read_only());
+        assert!(img.write_at(0, &[0x55; 4]).unwrap_err().kind() == io::ErrorKind::PermissionDenied);
+        assert!(img.flush().is_ok());
+
+        let mut buf = [0u8; 4];
+        assert!(img.read_at(0, &mut buf).is_ok());
+        assert!(buf == [0xAA; 4]);
+
+        ::std::fs::remove_file(&path).ok();
+    }
+
+    #[test] fn growable_image() {
+        let path = ::std::env::temp_dir().join(format!("xvm_disk_grow_test_{}.img", ::std::process::id()));
+        ::std::fs::remove_file(&path).ok();
+
+        let mut img = FileImage::open_growable(path.to_str().unwrap(), 4096).unwrap();
+        assert!(img.size() == 4096);
+        assert!(::std::fs::metadata(&path).unwrap().len() == 0);
+
+        /* Reads past end of file are zeroes, writes extend it up to image size */
+        let mut buf = [0xFFu8; 4];
+        assert!(img.read_at(1024, &mut buf).is_ok());
+        assert!(buf == [0; 4]);
+        assert!(img.write_at(1024, &[1, 2]).is_ok());
+        assert!(::std::fs::metadata(&path).unwrap().len() == 1026);
+        assert!(img.read_at(1024, &mut buf).is_ok());
+        assert!(buf == [1, 2, 0, 0]);
+        assert!(img.write_at(4095, &[1, 2]).is_err());
+
+        /* File larger than image size */
+        assert!(FileImage::open_growable(path.to_str().unwrap(), 1000).is_err());
+
+        ::std::fs::remove_file(&path).ok();
+    }
+
+    fn base_byte(i: usize) -> u8 {
+        (i / 512 * 13 + i % 251) as u8
+    }
+
+    fn file_contents(path: &::std::path::Path) -> Vec<u8> {
+        let mut data = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    /*
+     * Writes through overlay over a base that ends mid-cluster, base stays pristine,
+     * overlay persists across reopening and a fresh one shows the base again
+     */
+    #[test] fn cow_image() {
+        let base_path = ::std::env::temp_dir().join(format!("xvm_disk_cow_base_{}.img", ::std::process::id()));
+        let overlay_path = ::std::env::temp_dir().join(format!("xvm_disk_cow_overlay_{}.img", ::std::process::id()));
+        let (base_str, overlay_str) = (base_path.to_str().unwrap(), overlay_path.to_str().unwrap());
+
+        let base: Vec<u8> = (0..300 * 512).map(base_byte).collect();
+        File::create(&base_path).unwrap().write_all(&base).unwrap();
+        ::std::fs::remove_file(&overlay_path).ok();
+
+        let mut expected = base.clone();
+        {
+            let mut img = CowImage::open(base_str, overlay_str).unwrap();
+            assert!(img.size() == base.len() as u64);
+            assert!(!img.is_read_only());
+
+            /* Sector in first cluster, sectors across cluster boundary, last sector of partial last cluster */
+            for &(offset, len, val) in &[(512, 512, 0x11u8), (0xFE00, 1024, 0x22), (299 * 512, 512, 0x33)] {
+                assert!(img.write_at(offset, &vec![val; len]).is_ok());
+                for b in expected[offset as usize..offset as usize + len].iter_mut() {
+                    *b = val;
+                }
+            }
+            assert!(img.write_at(300 * 512, &[0]).is_err());
+            assert!(img.flush().is_ok());
+
+            let mut buf = vec![0u8; base.len()];
+            assert!(img.read_at(0, &mut buf).is_ok());
+            assert!(buf == expected);
+
+            /* Second instance over the same overlay sees the writes */
+            let mut other = CowImage::open(base_str, overlay_str).unwrap();
+            let mut sector = [0u8; 512];
+            assert!(other.read_at(0xFE00, &mut sector).is_ok());
+            assert!(sector[0] == 0x22 && sector[511] == 0x22);
+        }
+
+        assert!(file_contents(&base_path) == base);
+
+        /* Reopened overlay keeps data */
+        let mut img = CowImage::open(base_str, overlay_str).unwrap();
+        let mut buf = vec![0u8; base.len()];
+        assert!(img.read_at(0, &mut buf).is_ok());
+        assert!(buf == expected);
+
+        /* Overlay doesn't fit a base of different size */
+        let short_path = ::std::env::temp_dir().join(format!("xvm_disk_cow_short_{}.img", ::std::process::id()));
+        File::create(&short_path).unwrap().write_all(&base[..1024]).unwrap();
+        assert!(CowImage::open(short_path.to_str().unwrap(), overlay_str).is_err());
+        ::std::fs::remove_file(&short_path).unwrap();
+        assert!(CowImage::open(base_str, base_str).is_err());
+
+        /* Fresh overlay reproduces base */
+        ::std::fs::remove_file(&overlay_path).unwrap();
+        let mut img = CowImage::open(base_str, overlay_str).unwrap();
+        assert!(img.read_at(0, &mut buf).is_ok());
+        assert!(buf == base);
+
+        for path in &[base_path, overlay_path] {
+            ::std::fs::remove_file(path).unwrap();
+        }
+    }
+}
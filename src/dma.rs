@@ -0,0 +1,693 @@
+/*
+ * Intel 8237 DMA controller emulation
+ *
+ * PC has 2 cascaded controllers:
+ * - Controller 1 at ports 0x00-0x0F serves 8-bit channels 0-3
+ * - Controller 2 at ports 0xC0-0xDF serves 16-bit channels 4-7, channel 4 is a cascade for controller 1
+ * Upper address bits come from page registers at 0x80-0x8F.
+ *
+ * We don't run transfers on our own. Devices call transfer() to move data
+ * once they are ready, which is the only way ISA devices use DMA in practice.
+ */
+
+use vm;
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::mem;
+
+// Per controller register indexes
+const DMA_REG_STATUS: u8        = 0x08;     // Status read / command write
+const DMA_REG_REQUEST: u8       = 0x09;
+const DMA_REG_SINGLE_MASK: u8   = 0x0A;
+const DMA_REG_MODE: u8          = 0x0B;
+const DMA_REG_CLEAR_FF: u8      = 0x0C;
+const DMA_REG_MASTER_CLEAR: u8  = 0x0D;     // Temp register read / master clear write
+const DMA_REG_CLEAR_MASK: u8    = 0x0E;
+const DMA_REG_WRITE_MASK: u8    = 0x0F;
+
+// IO ports
+const DMA1_BASE: u16            = 0x00;
+const DMA2_BASE: u16            = 0xC0;
+const DMA_PAGE_BASE: u16        = 0x80;
+const DMA_PAGE_PORTS: u16       = 0x10;
+
+// Page register port offsets for channels 0-7
+const DMA_PAGE_OFFSETS: [u16; 8] = [0x07, 0x03, 0x01, 0x02, 0x0F, 0x0B, 0x09, 0x0A];
+
+// Mode register bits
+const DMA_MODE_CHANNEL_MASK: u8 = 0x03;
+const DMA_MODE_TYPE_MASK: u8    = 0x0C;
+const DMA_MODE_TYPE_VERIFY: u8  = 0x00;
+const DMA_MODE_TYPE_WRITE: u8   = 0x04;     // Device to memory
+const DMA_MODE_TYPE_READ: u8    = 0x08;     // Memory to device
+const DMA_MODE_AUTOINIT: u8     = 0x10;
+const DMA_MODE_DECREMENT: u8    = 0x20;
+const DMA_MODE_MODE_MASK: u8    = 0xC0;
+const DMA_MODE_CASCADE: u8      = 0xC0;
+
+// Single mask register bits
+const DMA_MASK_SET: u8          = 0x04;
+
+pub const DMA_CHANNELS: usize   = 8;
+const DMA_CASCADE_CHANNEL: usize = 4;
+
+/**
+ * Transfer direction from device point of view
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DmaDirection {
+    ToMemory,       // Device writes guest memory (8237 "write" transfer)
+    FromMemory,     // Device reads guest memory (8237 "read" transfer)
+}
+
+/**
+ * Guest memory accessor used by transfers
+ */
+pub trait dma_memory
+{
+    fn read(&self, addr: u64, buf: &mut [u8]) -> usize;
+    fn write(&self, addr: u64, buf: &[u8]) -> usize;
+}
+
+/**
+ * Outcome of a device initiated transfer
+ */
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct DmaTransfer
+{
+    pub count: usize,   // Bytes transferred
+    pub tc: bool,       // Terminal count reached, device should end its operation
+    pub verify: bool,   // Channel is in verify mode, no data moved to or from memory
+}
+
+/**
+ * DMA channel as seen by a device
+ */
+pub trait dma_channel
+{
+    /** See transfer() */
+    fn transfer(&self, dir: DmaDirection, buf: &mut [u8]) -> DmaTransfer;
+}
+
+#[derive(Clone, Copy, Default)]
+struct DMAChannel
+{
+    base_addr: u16,
+    base_count: u16,
+    cur_addr: u16,
+    cur_count: u16,
+    mode: u8,
+    page: u8,
+}
+
+/**
+ * Single 8237 controller
+ */
+#[derive(Default)]
+struct I8237
+{
+    channels: [DMAChannel; 4],
+    flipflop: bool,     // Next address/count access is high byte
+    mask: u8,           // Channel mask bits
+    status: u8,         // TC bits in low nibble, request bits in high nibble
+    command: u8,
+    temp: u8,
+}
+
+impl I8237
+{
+    fn new() -> I8237 {
+        let mut dma = I8237::default();
+        dma.mask = 0xF;
+        dma
+    }
+
+    /* Set low or high byte of address/count register */
+    fn set_byte(reg: &mut u16, high: bool, val: u8) {
+        if !high {
+            *reg = (*reg & 0xFF00) | val as u16;
+        } else {
+            *reg = (*reg & 0x00FF) | ((val as u16) << 8);
+        }
+    }
+
+    fn read_latched(&mut self, val: u16) -> u8 {
+        let res = if !self.flipflop { val as u8 } else { (val >> 8) as u8 };
+        self.flipflop = !self.flipflop;
+        res
+    }
+
+    fn read(&mut self, reg: u8) -> u8 {
+        match reg {
+            0...7 => {
+                let ch = self.channels[(reg >> 1) as usize];
+                if reg & 1 == 0 {
+                    self.read_latched(ch.cur_addr)
+                } else {
+                    self.read_latched(ch.cur_count)
+                }
+            },
+
+            DMA_REG_STATUS => {
+                /* TC bits are cleared on read */
+                let status = self.status;
+                self.status &= 0xF0;
+                status
+            },
+
+            DMA_REG_MASTER_CLEAR => self.temp,
+            DMA_REG_WRITE_MASK => self.mask | 0xF0,
+
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, reg: u8, val: u8) {
+        match reg {
+            0...7 => {
+                /* Flip-flop selects low or high byte, writes go to both base and current registers */
+                let high = self.flipflop;
+                self.flipflop = !self.flipflop;
+
+                let ch = &mut self.channels[(reg >> 1) as usize];
+                if reg & 1 == 0 {
+                    I8237::set_byte(&mut ch.base_addr, high, val);
+                    I8237::set_byte(&mut ch.cur_addr, high, val);
+                } else {
+                    I8237::set_byte(&mut ch.base_count, high, val);
+                    I8237::set_byte(&mut ch.cur_count, high, val);
+                }
+            },
+
+            DMA_REG_STATUS => self.command = val,
+
+            DMA_REG_REQUEST => {
+                /* Software requests are recorded but we don't run transfers without a device */
+                let bit = 1 << (4 + (val & DMA_MODE_CHANNEL_MASK));
+                if val & DMA_MASK_SET != 0 {
+                    self.status |= bit;
+                } else {
+                    self.status &= !bit;
+                }
+            },
+
+            DMA_REG_SINGLE_MASK => {
+                let bit = 1 << (val & DMA_MODE_CHANNEL_MASK);
+                if val & DMA_MASK_SET != 0 {
+                    self.mask |= bit;
+                } else {
+                    self.mask &= !bit;
+                }
+            },
+
+            DMA_REG_MODE => {
+                self.channels[(val & DMA_MODE_CHANNEL_MASK) as usize].mode = val;
+            },
+
+            DMA_REG_CLEAR_FF => self.flipflop = false,
+
+            DMA_REG_MASTER_CLEAR => {
+                self.flipflop = false;
+                self.status = 0;
+                self.command = 0;
+                self.temp = 0;
+                self.mask = 0xF;
+            },
+
+            DMA_REG_CLEAR_MASK => self.mask = 0,
+            DMA_REG_WRITE_MASK => self.mask = val & 0xF,
+
+            _ => panic!(),
+        }
+    }
+}
+
+/**
+ * Both controllers with page registers
+ */
+struct DMA
+{
+    ctrl: [I8237; 2],
+    pages: [u8; DMA_PAGE_PORTS as usize],   // Page register file including unused scratch registers
+}
+
+impl DMA
+{
+    fn new() -> DMA {
+        DMA {
+            ctrl: [I8237::new(), I8237::new()],
+            pages: [0; DMA_PAGE_PORTS as usize],
+        }
+    }
+
+    fn channel(&self, channel: usize) -> &DMAChannel {
+        &self.ctrl[channel / 4].channels[channel % 4]
+    }
+
+    fn is_masked(&self, channel: usize) -> bool {
+        let ctrl = &self.ctrl[channel / 4];
+        if ctrl.mask & (1 << (channel % 4)) != 0 {
+            return true;
+        }
+
+        /* Controller 1 reaches the bus only through cascade channel on controller 2 */
+        channel < 4 && self.is_masked(DMA_CASCADE_CHANNEL)
+    }
+
+    fn read_page(&self, offset: u16) -> u8 {
+        self.pages[offset as usize]
+    }
+
+    fn write_page(&mut self, offset: u16, val: u8) {
+        self.pages[offset as usize] = val;
+
+        for (ch, off) in DMA_PAGE_OFFSETS.iter().enumerate() {
+            if *off == offset {
+                self.ctrl[ch / 4].channels[ch % 4].page = val;
+            }
+        }
+    }
+
+    /*
+     * Physical address of the current transfer unit
+     * 16-bit channels address words, so address register is shifted and page bit 0 is ignored
+     */
+    fn phys_addr(channel: usize, ch: &DMAChannel) -> u64 {
+        if channel < 4 {
+            ((ch.page as u64) << 16) | ch.cur_addr as u64
+        } else {
+            (((ch.page & 0xFE) as u64) << 16) | ((ch.cur_addr as u64) << 1)
+        }
+    }
+
+    fn read_port(&mut self, port: u16) -> u8 {
+        match port {
+            0x00...0x0F => self.ctrl[0].read(port as u8),
+            0x80...0x8F => self.read_page(port - DMA_PAGE_BASE),
+            0xC0...0xDF => self.ctrl[1].read(((port - DMA2_BASE) >> 1) as u8),
+            _ => panic!(),
+        }
+    }
+
+    fn write_port(&mut self, port: u16, val: u8) {
+        match port {
+            0x00...0x0F => self.ctrl[0].write(port as u8, val),
+            0x80...0x8F => self.write_page(port - DMA_PAGE_BASE, val),
+            0xC0...0xDF => self.ctrl[1].write(((port - DMA2_BASE) >> 1) as u8, val),
+            _ => panic!(),
+        }
+    }
+
+    /**
+     * Perform device initiated transfer on channel using guest programmed address and count.
+     * Transfer stops at the end of device buffer or at terminal count, whichever comes first.
+     */
+    fn transfer(&mut self, channel: usize, dir: DmaDirection, buf: &mut [u8], mem: &dma_memory) -> DmaTransfer {
+        assert!(channel < DMA_CHANNELS);
+
+        if channel == DMA_CASCADE_CHANNEL {
+            error!("dma: transfer on cascade channel");
+            return DmaTransfer::default();
+        }
+
+        if self.is_masked(channel) {
+            debug!("dma: transfer on masked channel {}", channel);
+            return DmaTransfer::default();
+        }
+
+        let mut ch = *self.channel(channel);
+        let mode_type = ch.mode & DMA_MODE_TYPE_MASK;
+
+        if ch.mode & DMA_MODE_MODE_MASK == DMA_MODE_CASCADE {
+            error!("dma: transfer on channel {} in cascade mode", channel);
+            return DmaTransfer::default();
+        }
+
+        match (mode_type, dir) {
+            (DMA_MODE_TYPE_VERIFY, _) |
+            (DMA_MODE_TYPE_WRITE, DmaDirection::ToMemory) |
+            (DMA_MODE_TYPE_READ, DmaDirection::FromMemory) => {},
+            _ => {
+                error!("dma: channel {} mode {:x} does not match {:?} transfer", channel, ch.mode, dir);
+                return DmaTransfer::default();
+            }
+        }
+
+        let unit = if channel < 4 { 1 } else { 2 };
+        let mut done = 0;
+        let mut tc = false;
+
+        while done + unit <= buf.len() {
+            let addr = DMA::phys_addr(channel, &ch);
+
+            match mode_type {
+                DMA_MODE_TYPE_WRITE => { mem.write(addr, &buf[done..done + unit]); },
+                DMA_MODE_TYPE_READ => { mem.read(addr, &mut buf[done..done + unit]); },
+                _ => {},
+            }
+
+            done += unit;
+
+            if ch.mode & DMA_MODE_DECREMENT != 0 {
+                ch.cur_addr = ch.cur_addr.wrapping_sub(1);
+            } else {
+                ch.cur_addr = ch.cur_addr.wrapping_add(1);
+            }
+
+            /* Terminal count is reached when counter rolls over from 0 */
+            ch.cur_count = ch.cur_count.wrapping_sub(1);
+            if ch.cur_count == 0xFFFF {
+                tc = true;
+                break;
+            }
+        }
+
+        let ctrl = &mut self.ctrl[channel / 4];
+        if tc {
+            ctrl.status |= 1 << (channel % 4);
+            ctrl.status &= !(1 << (4 + channel % 4));
+
+            if ch.mode & DMA_MODE_AUTOINIT != 0 {
+                ch.cur_addr = ch.base_addr;
+                ch.cur_count = ch.base_count;
+            } else {
+                ctrl.mask |= 1 << (channel % 4);
+            }
+        }
+
+        ctrl.channels[channel % 4] = ch;
+        DmaTransfer { count: done, tc: tc, verify: mode_type == DMA_MODE_TYPE_VERIFY }
+    }
+}
+
+#[cfg(test)]
+mod dma_test
+{
+    use super::*;
+
+    fn make_memory() -> TestMemory {
+        TestMemory::new(0x40000, 0)
+    }
+
+    /* Program channel 2 the way floppy drivers do */
+    fn setup_channel2(dma: &mut DMA, addr: u32, count: u16, mode: u8) {
+        dma.ctrl[0].write(DMA_REG_SINGLE_MASK, DMA_MASK_SET | 2);
+        dma.ctrl[0].write(DMA_REG_CLEAR_FF, 0);
+        dma.ctrl[0].write(DMA_REG_MODE, mode | 2);
+        dma.ctrl[0].write(4, addr as u8);
+        dma.ctrl[0].write(4, (addr >> 8) as u8);
+        dma.write_page(DMA_PAGE_OFFSETS[2], (addr >> 16) as u8);
+        dma.ctrl[0].write(DMA_REG_CLEAR_FF, 0);
+        dma.ctrl[0].write(5, count as u8);
+        dma.ctrl[0].write(5, (count >> 8) as u8);
+        dma.ctrl[0].write(DMA_REG_SINGLE_MASK, 2);
+    }
+
+    fn make_dma() -> DMA {
+        let mut dma = DMA::new();
+
+        /* BIOS sets up cascade channel */
+        dma.ctrl[1].write(DMA_REG_MODE, DMA_MODE_CASCADE);
+        dma.ctrl[1].write(DMA_REG_SINGLE_MASK, 0);
+        dma
+    }
+
+    #[test] fn flipflop() {
+        let mut dma = make_dma();
+        dma.ctrl[0].write(DMA_REG_CLEAR_FF, 0);
+        dma.ctrl[0].write(2, 0x34);
+        dma.ctrl[0].write(2, 0x12);
+        assert!(dma.channel(1).base_addr == 0x1234);
+        assert!(dma.channel(1).cur_addr == 0x1234);
+
+        dma.ctrl[0].write(DMA_REG_CLEAR_FF, 0);
+        assert!(dma.ctrl[0].read(2) == 0x34);
+        assert!(dma.ctrl[0].read(2) == 0x12);
+    }
+
+    #[test] fn transfer_to_memory() {
+        let mut dma = make_dma();
+        let mem = make_memory();
+
+        setup_channel2(&mut dma, 0x21000, 511, DMA_MODE_TYPE_WRITE | 0x40);
+
+        let mut buf: Vec<u8> = (0..512).map(|i| i as u8).collect();
+        assert!(dma.transfer(2, DmaDirection::ToMemory, &mut buf, &mem) == DmaTransfer { count: 512, tc: true, verify: false });
+        assert!(&mem.mem.borrow()[0x21000..0x21200] == &buf[..]);
+        assert!(mem.mem.borrow()[0x1000] == 0);
+
+        /* Terminal count reached, channel is masked and status has TC bit */
+        assert!(dma.channel(2).cur_count == 0xFFFF);
+        assert!(dma.channel(2).cur_addr == 0x1200);
+        assert!(dma.is_masked(2));
+        assert!(dma.ctrl[0].read(DMA_REG_STATUS) & 0x04 != 0);
+        assert!(dma.ctrl[0].read(DMA_REG_STATUS) & 0x04 == 0);
+    }
+
+    #[test] fn transfer_from_memory() {
+        let mut dma = make_dma();
+        let mem = make_memory();
+        mem.write(0x3000, &[1, 2, 3, 4]);
+
+        setup_channel2(&mut dma, 0x3000, 3, DMA_MODE_TYPE_READ | DMA_MODE_AUTOINIT);
+
+        let mut buf = [0u8; 8];
+        assert!(dma.transfer(2, DmaDirection::FromMemory, &mut buf, &mem).count == 4);
+        assert!(buf[0..4] == [1, 2, 3, 4]);
+
+        /* Autoinit reloads base registers and keeps channel unmasked */
+        assert!(dma.channel(2).cur_addr == 0x3000);
+        assert!(dma.channel(2).cur_count == 3);
+        assert!(!dma.is_masked(2));
+    }
+
+    #[test] fn partial_transfer() {
+        let mut dma = make_dma();
+        let mem = make_memory();
+
+        setup_channel2(&mut dma, 0x1000, 0x3FF, DMA_MODE_TYPE_WRITE);
+
+        let mut buf = [0xAAu8; 0x100];
+        assert!(dma.transfer(2, DmaDirection::ToMemory, &mut buf, &mem) == DmaTransfer { count: 0x100, tc: false, verify: false });
+        assert!(dma.channel(2).cur_count == 0x2FF);
+        assert!(dma.ctrl[0].read(DMA_REG_STATUS) & 0x04 == 0);
+        assert!(!dma.is_masked(2));
+
+        /* Verify transfer counts down without touching memory or device buffer */
+        setup_channel2(&mut dma, 0x1000, 0xFF, DMA_MODE_TYPE_VERIFY);
+        let mut buf = [0x55u8; 0x100];
+        assert!(dma.transfer(2, DmaDirection::FromMemory, &mut buf, &mem) == DmaTransfer { count: 0x100, tc: true, verify: true });
+        assert!(buf[0] == 0x55 && mem.mem.borrow()[0x1000] == 0xAA);
+    }
+
+    #[test] fn masked_and_mismatched() {
+        let mut dma = make_dma();
+        let mem = make_memory();
+        let mut buf = [0u8; 16];
+
+        setup_channel2(&mut dma, 0x1000, 15, DMA_MODE_TYPE_READ);
+        assert!(dma.transfer(2, DmaDirection::ToMemory, &mut buf, &mem).count == 0);
+
+        /* Masking cascade channel blocks controller 1 */
+        dma.ctrl[1].write(DMA_REG_SINGLE_MASK, DMA_MASK_SET);
+        assert!(dma.transfer(2, DmaDirection::FromMemory, &mut buf, &mem).count == 0);
+        assert!(dma.transfer(DMA_CASCADE_CHANNEL, DmaDirection::FromMemory, &mut buf, &mem).count == 0);
+    }
+
+    #[test] fn transfer_16bit() {
+        let mut dma = make_dma();
+        let mem = make_memory();
+
+        /* Channel 5: word address 0x800 in page 0x03 is physical 0x21000 */
+        dma.ctrl[1].write(DMA_REG_MODE, DMA_MODE_TYPE_WRITE | 1);
+        dma.ctrl[1].write(DMA_REG_CLEAR_FF, 0);
+        dma.ctrl[1].write(2, 0x00);
+        dma.ctrl[1].write(2, 0x08);
+        dma.ctrl[1].write(3, 1);
+        dma.ctrl[1].write(3, 0);
+        dma.write_page(DMA_PAGE_OFFSETS[5], 0x03);
+        dma.ctrl[1].write(DMA_REG_SINGLE_MASK, 1);
+
+        let mut buf = [0x55u8; 8];
+        assert!(dma.transfer(5, DmaDirection::ToMemory, &mut buf, &mem).count == 4);
+        assert!(&mem.mem.borrow()[0x21000..0x21005] == &[0x55, 0x55, 0x55, 0x55, 0x00]);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/**
+ * Guest physical memory as seen by transfers
+ */
+pub struct GuestMemory;
+
+impl dma_memory for GuestMemory
+{
+    fn read(&self, addr: u64, buf: &mut [u8]) -> usize {
+        vm::read_guest_memory(addr, buf)
+    }
+
+    fn write(&self, addr: u64, buf: &[u8]) -> usize {
+        vm::write_guest_memory(addr, buf)
+    }
+}
+
+struct DMADev
+{
+    dma: Rc<RefCell<DMA>>,
+}
+
+#[allow(unused_variables)]
+impl vm::io_handler for DMADev
+{
+    fn io_read(&self, port: u16, size: u8) -> vm::IoOperandType
+    {
+        vm::IoOperandType::byte(self.dma.borrow_mut().read_port(port))
+    }
+
+    fn io_write(&self, port: u16, data: vm::IoOperandType)
+    {
+        self.dma.borrow_mut().write_port(port, data.unwrap_byte());
+    }
+}
+
+impl vm::reset_handler for DMADev
+{
+    fn reset(&self)
+    {
+        *self.dma.borrow_mut() = DMA::new();
+    }
+}
+
+/*
+ * DMA device instance for device initiated transfers
+ */
+static mut DMA_DEV: Option<*const DMADev> = None;
+
+fn get_dma() -> &'static DMADev
+{
+    unsafe {
+        mem::transmute(DMA_DEV.expect("dma: not initialized"))
+    }
+}
+
+/*
+ * Channel on a DMA controller pair, devices hold it from init.
+ * For ToMemory transfers buf holds data to write to guest, for FromMemory it receives guest data.
+ * Count is 0 if channel is not ready for transfer.
+ */
+struct Channel
+{
+    dma: Rc<RefCell<DMA>>,
+    mem: Rc<dma_memory>,
+    channel: usize,
+}
+
+impl dma_channel for Channel
+{
+    fn transfer(&self, dir: DmaDirection, buf: &mut [u8]) -> DmaTransfer {
+        self.dma.borrow_mut().transfer(self.channel, dir, buf, &*self.mem)
+    }
+}
+
+fn make_channel(dma: &Rc<RefCell<DMA>>, mem: Rc<dma_memory>, channel: usize) -> Box<dma_channel>
+{
+    assert!(channel < DMA_CHANNELS && channel != DMA_CASCADE_CHANNEL);
+    Box::new(Channel { dma: dma.clone(), mem: mem, channel: channel })
+}
+
+/**
+ * Get channel handle for a device wired to DMA channel
+ */
+pub fn get_channel(channel: usize) -> Box<dma_channel>
+{
+    make_channel(&get_dma().dma, Rc::new(GuestMemory), channel)
+}
+
+/**
+ * Controller pair detached from the VM, lets device tests run against the 8237 model.
+ * Cascade channel is set up as BIOS does.
+ */
+#[cfg(test)]
+pub struct TestController
+{
+    dma: Rc<RefCell<DMA>>,
+}
+
+#[cfg(test)]
+impl TestController
+{
+    pub fn new() -> TestController {
+        let dma = TestController { dma: Rc::new(RefCell::new(DMA::new())) };
+        dma.write_port(DMA2_BASE + ((DMA_REG_MODE as u16) << 1), DMA_MODE_CASCADE);
+        dma.write_port(DMA2_BASE + ((DMA_REG_SINGLE_MASK as u16) << 1), 0);
+        dma
+    }
+
+    pub fn read_port(&self, port: u16) -> u8 {
+        self.dma.borrow_mut().read_port(port)
+    }
+
+    pub fn write_port(&self, port: u16, val: u8) {
+        self.dma.borrow_mut().write_port(port, val)
+    }
+
+    pub fn channel(&self, mem: Rc<dma_memory>, channel: usize) -> Box<dma_channel> {
+        make_channel(&self.dma, mem, channel)
+    }
+}
+
+/**
+ * Flat guest memory for device tests, accesses running past the end are cut short.
+ */
+#[cfg(test)]
+pub struct TestMemory
+{
+    pub mem: RefCell<Vec<u8>>,
+}
+
+#[cfg(test)]
+impl TestMemory
+{
+    pub fn new(size: usize, fill: u8) -> TestMemory {
+        TestMemory { mem: RefCell::new(vec![fill; size]) }
+    }
+}
+
+#[cfg(test)]
+impl dma_memory for TestMemory
+{
+    fn read(&self, addr: u64, buf: &mut [u8]) -> usize {
+        let mem = self.mem.borrow();
+        let len = ::std::cmp::min(buf.len(), mem.len().saturating_sub(addr as usize));
+        buf[..len].copy_from_slice(&mem[addr as usize..addr as usize + len]);
+        len
+    }
+
+    fn write(&self, addr: u64, buf: &[u8]) -> usize {
+        let mut mem = self.mem.borrow_mut();
+        let len = ::std::cmp::min(buf.len(), mem.len().saturating_sub(addr as usize));
+        mem[addr as usize..addr as usize + len].copy_from_slice(&buf[..len]);
+        len
+    }
+}
+
+pub fn init()
+{
+    let dev = Rc::new(DMADev {
+        dma: Rc::new(RefCell::new(DMA::new())),
+    });
+
+    unsafe {
+        DMA_DEV = Some(&*dev as *const DMADev);
+    }
+
+    for reg in 0..0x10 {
+        vm::register_io_region(dev.clone(), DMA1_BASE + reg, 1);
+        vm::register_io_region(dev.clone(), DMA2_BASE + (reg << 1), 1);
+        vm::register_io_region(dev.clone(), DMA_PAGE_BASE + reg, 1);
+    }
+
+    vm::register_reset_handler(dev.clone());
+}
@@ -0,0 +1,289 @@
+/*
+ * El Torito bootable CD-ROM images
+ *
+ * ISO9660 volume descriptors start at sector 16. Boot record volume descriptor among them points at
+ * the boot catalog, which begins with a validation entry followed by the initial/default entry that
+ * tells where the boot image is and how BIOS presents it. Only the default entry is used, section
+ * headers for other platforms are ignored.
+ */
+
+use disk::{disk_image, SliceImage};
+use ata::Geometry;
+
+pub const CD_SECTOR_SIZE: usize = 2048;
+pub const VIRTUAL_SECTOR_SIZE: usize = 512;
+
+// Volume descriptors
+const DESCRIPTORS_START: u64    = 16;
+const MAX_DESCRIPTORS: u64      = 64;
+const VD_TYPE_BOOT_RECORD: u8   = 0x00;
+const VD_TYPE_TERMINATOR: u8    = 0xFF;
+const VD_ID: &'static [u8; 5]   = b"CD001";
+const VD_VERSION: u8            = 0x01;
+const EL_TORITO_ID: &'static [u8] = b"EL TORITO SPECIFICATION";
+const BOOT_SYSTEM_ID_LEN: usize = 32;
+const BOOT_CATALOG_PTR: usize   = 0x47;
+
+// Boot catalog validation entry
+const ENTRY_SIZE: usize         = 32;
+const VALIDATION_HEADER_ID: u8  = 0x01;
+const PLATFORM_X86: u8          = 0x00;
+const VALIDATION_KEY: [u8; 2]   = [0x55, 0xAA];
+
+// Boot catalog initial/default entry
+const BOOT_INDICATOR: u8        = 0x88;
+const MEDIA_TYPE_MASK: u8       = 0x0F;
+const MEDIA_NO_EMULATION: u8    = 0x00;
+const MEDIA_FLOPPY_120M: u8     = 0x01;
+const MEDIA_FLOPPY_144M: u8     = 0x02;
+const MEDIA_FLOPPY_288M: u8     = 0x03;
+const MEDIA_HARD_DISK: u8       = 0x04;
+const DEFAULT_LOAD_SEGMENT: u16 = 0x07C0;
+
+/**
+ * How boot image is presented to the guest
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum BootMedia
+{
+    NoEmulation,            // Loaded as is and started at its load segment
+    Floppy(Geometry),       // Image is a diskette of given geometry booting as drive 00h
+}
+
+/**
+ * Initial/default boot catalog entry
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct BootEntry
+{
+    pub media: BootMedia,
+    pub load_segment: u16,
+    pub sectors: u16,       // 512 byte virtual sectors to load, no emulation only
+    pub lba: u32,           // CD sector boot image starts at
+}
+
+impl BootEntry
+{
+    /** Byte offset of boot image in CD image */
+    pub fn offset(&self) -> u64 {
+        self.lba as u64 * CD_SECTOR_SIZE as u64
+    }
+}
+
+fn get_word(buf: &[u8], offset: usize) -> u16
+{
+    buf[offset] as u16 | (buf[offset + 1] as u16) << 8
+}
+
+fn get_dword(buf: &[u8], offset: usize) -> u32
+{
+    get_word(buf, offset) as u32 | (get_word(buf, offset + 2) as u32) << 16
+}
+
+fn read_sector(image: &mut disk_image, lba: u64) -> Result<Vec<u8>, String>
+{
+    let mut buf = vec![0u8; CD_SECTOR_SIZE];
+    match image.read_at(lba * CD_SECTOR_SIZE as u64, &mut buf) {
+        Ok(()) => Ok(buf),
+        Err(err) => Err(format!("Can't read CD sector {}: {}", lba, err)),
+    }
+}
+
+/* Boot system identifier is padded with zeroes */
+fn is_el_torito(vd: &[u8]) -> bool
+{
+    let id = &vd[7..7 + BOOT_SYSTEM_ID_LEN];
+    &id[..EL_TORITO_ID.len()] == EL_TORITO_ID && id[EL_TORITO_ID.len()..].iter().all(|b| *b == 0)
+}
+
+/* Walk volume descriptors up to terminator looking for El Torito boot record */
+fn find_boot_catalog(image: &mut disk_image) -> Result<u32, String>
+{
+    for lba in DESCRIPTORS_START..DESCRIPTORS_START + MAX_DESCRIPTORS {
+        let vd = try!(read_sector(image, lba));
+        if &vd[1..6] != VD_ID || vd[6] != VD_VERSION {
+            return Err(String::from("Not an ISO9660 image"));
+        }
+
+        match vd[0] {
+            VD_TYPE_BOOT_RECORD if is_el_torito(&vd) => return Ok(get_dword(&vd, BOOT_CATALOG_PTR)),
+            VD_TYPE_TERMINATOR => break,
+            _ => {},
+        }
+    }
+
+    Err(String::from("CD image is not bootable, it has no El Torito boot record"))
+}
+
+/* Words of validation entry including its checksum word sum up to zero */
+fn check_validation_entry(entry: &[u8]) -> Result<(), String>
+{
+    if entry[0] != VALIDATION_HEADER_ID || entry[30..32] != VALIDATION_KEY {
+        return Err(String::from("Boot catalog has no validation entry"));
+    }
+
+    let sum = (0..ENTRY_SIZE / 2).fold(0u16, |sum, i| sum.wrapping_add(get_word(entry, i * 2)));
+    if sum != 0 {
+        return Err(format!("Boot catalog validation entry checksum is off by {:04x}", sum));
+    }
+
+    if entry[1] != PLATFORM_X86 {
+        return Err(format!("Boot catalog is for platform {:02x}, not x86", entry[1]));
+    }
+
+    Ok(())
+}
+
+/**
+ * Find boot image described by initial/default entry of boot catalog
+ */
+pub fn find_boot_entry(image: &mut disk_image) -> Result<BootEntry, String>
+{
+    let catalog_lba = try!(find_boot_catalog(image));
+    let catalog = try!(read_sector(image, catalog_lba as u64));
+    try!(check_validation_entry(&catalog[..ENTRY_SIZE]));
+
+    let entry = &catalog[ENTRY_SIZE..ENTRY_SIZE * 2];
+    if entry[0] != BOOT_INDICATOR {
+        return Err(String::from("Default boot catalog entry is not bootable"));
+    }
+
+    let floppy = |cylinders, sectors| BootMedia::Floppy(Geometry { cylinders: cylinders, heads: 2, sectors: sectors });
+    let media = match entry[1] & MEDIA_TYPE_MASK {
+        MEDIA_NO_EMULATION => BootMedia::NoEmulation,
+        MEDIA_FLOPPY_120M => floppy(80, 15),
+        MEDIA_FLOPPY_144M => floppy(80, 18),
+        MEDIA_FLOPPY_288M => floppy(80, 36),
+        MEDIA_HARD_DISK => return Err(String::from("Hard disk emulation boot is not supported")),
+        other => return Err(format!("Unknown boot media type {:02x}", other)),
+    };
+
+    let sectors = get_word(entry, 6);
+    if media == BootMedia::NoEmulation && sectors == 0 {
+        return Err(String::from("Boot catalog entry loads no sectors"));
+    }
+
+    Ok(BootEntry {
+        media: media,
+        load_segment: match get_word(entry, 2) {
+            0 => DEFAULT_LOAD_SEGMENT,
+            seg => seg,
+        },
+        sectors: sectors,
+        lba: get_dword(entry, 8),
+    })
+}
+
+/**
+ * Emulated diskette of floppy emulation boot entry, a read-only part of CD image
+ */
+pub fn floppy_image(iso: Box<disk_image>, boot: &BootEntry) -> Result<SliceImage, String>
+{
+    let geometry = match boot.media {
+        BootMedia::Floppy(geometry) => geometry,
+        BootMedia::NoEmulation => return Err(String::from("Boot image is not a floppy image")),
+    };
+
+    let size = geometry.cylinders as u64 * geometry.heads as u64 * geometry.sectors as u64 * VIRTUAL_SECTOR_SIZE as u64;
+    SliceImage::new(iso, boot.offset(), size).map_err(|_| String::from("Floppy emulation image runs past end of CD image"))
+}
+
+/**
+ * Build ISO with El Torito boot catalog: descriptors at 16-18, catalog at 19 and boot image from 20 on
+ */
+#[cfg(test)]
+pub fn make_boot_iso(media: u8, load_segment: u16, sectors: u16, boot_image: &[u8]) -> ::disk::MemImage
+{
+    let image_sectors = (boot_image.len() + CD_SECTOR_SIZE - 1) / CD_SECTOR_SIZE;
+    let mut iso = ::disk::MemImage::new((20 + image_sectors) * CD_SECTOR_SIZE);
+
+    let descriptor = |vd: &mut [u8], vd_type: u8| {
+        vd[0] = vd_type;
+        vd[1..6].copy_from_slice(VD_ID);
+        vd[6] = VD_VERSION;
+    };
+
+    descriptor(&mut iso.data[16 * CD_SECTOR_SIZE..], 0x01);
+    descriptor(&mut iso.data[17 * CD_SECTOR_SIZE..], VD_TYPE_BOOT_RECORD);
+    descriptor(&mut iso.data[18 * CD_SECTOR_SIZE..], VD_TYPE_TERMINATOR);
+    iso.data[17 * CD_SECTOR_SIZE + 7..17 * CD_SECTOR_SIZE + 7 + EL_TORITO_ID.len()].copy_from_slice(EL_TORITO_ID);
+    iso.data[17 * CD_SECTOR_SIZE + BOOT_CATALOG_PTR] = 19;
+
+    {
+        let catalog = &mut iso.data[19 * CD_SECTOR_SIZE..20 * CD_SECTOR_SIZE];
+        catalog[0] = VALIDATION_HEADER_ID;
+        catalog[4..8].copy_from_slice(b"XVM ");
+        catalog[30..32].copy_from_slice(&VALIDATION_KEY);
+        let sum = (0..ENTRY_SIZE / 2).fold(0u16, |sum, i| sum.wrapping_add(get_word(catalog, i * 2)));
+        catalog[28] = sum.wrapping_neg() as u8;
+        catalog[29] = (sum.wrapping_neg() >> 8) as u8;
+
+        catalog[32] = BOOT_INDICATOR;
+        catalog[33] = media;
+        catalog[34] = load_segment as u8;
+        catalog[35] = (load_segment >> 8) as u8;
+        catalog[38] = sectors as u8;
+        catalog[39] = (sectors >> 8) as u8;
+        catalog[40] = 20;
+    }
+
+    iso.data[20 * CD_SECTOR_SIZE..20 * CD_SECTOR_SIZE + boot_image.len()].copy_from_slice(boot_image);
+    iso
+}
+
+#[cfg(test)]
+mod eltorito_test
+{
+    use super::*;
+
+    #[test] fn no_emulation_entry() {
+        let mut iso = make_boot_iso(MEDIA_NO_EMULATION, 0, 4, &[0x90u8; 2048]);
+        let entry = find_boot_entry(&mut iso).unwrap();
+        assert!(entry == BootEntry { media: BootMedia::NoEmulation, load_segment: 0x07C0, sectors: 4, lba: 20 });
+        assert!(entry.offset() == 20 * 2048);
+
+        let mut iso = make_boot_iso(MEDIA_NO_EMULATION, 0x2000, 1, &[0x90u8; 512]);
+        assert!(find_boot_entry(&mut iso).unwrap().load_segment == 0x2000);
+
+        let mut iso = make_boot_iso(MEDIA_NO_EMULATION, 0, 0, &[0x90u8; 512]);
+        assert!(find_boot_entry(&mut iso).is_err());
+    }
+
+    #[test] fn floppy_emulation_entry() {
+        let mut iso = make_boot_iso(MEDIA_FLOPPY_144M, 0, 1, &[0u8; 512]);
+        let entry = find_boot_entry(&mut iso).unwrap();
+        assert!(entry.media == BootMedia::Floppy(Geometry { cylinders: 80, heads: 2, sectors: 18 }));
+
+        let mut iso = make_boot_iso(MEDIA_FLOPPY_288M, 0, 1, &[0u8; 512]);
+        assert!(find_boot_entry(&mut iso).unwrap().media == BootMedia::Floppy(Geometry { cylinders: 80, heads: 2, sectors: 36 }));
+
+        let mut iso = make_boot_iso(MEDIA_HARD_DISK, 0, 1, &[0u8; 512]);
+        assert!(find_boot_entry(&mut iso).unwrap_err().contains("Hard disk"));
+    }
+
+    #[test] fn validation_entry() {
+        let mut iso = make_boot_iso(MEDIA_NO_EMULATION, 0, 4, &[0x90u8; 2048]);
+        iso.data[19 * 2048 + 4] ^= 1;
+        assert!(find_boot_entry(&mut iso).unwrap_err().contains("checksum"));
+
+        let mut iso = make_boot_iso(MEDIA_NO_EMULATION, 0, 4, &[0x90u8; 2048]);
+        iso.data[19 * 2048 + 31] = 0;
+        assert!(find_boot_entry(&mut iso).unwrap_err().contains("validation"));
+    }
+
+    #[test] fn not_bootable() {
+        let mut iso = make_boot_iso(MEDIA_NO_EMULATION, 0, 4, &[0x90u8; 2048]);
+        iso.data[19 * 2048 + 32] = 0;
+        assert!(find_boot_entry(&mut iso).unwrap_err().contains("not bootable"));
+
+        /* Boot record is gone, terminator follows primary descriptor */
+        let mut iso = make_boot_iso(MEDIA_NO_EMULATION, 0, 4, &[0x90u8; 2048]);
+        iso.data[17 * 2048 + 7] = b'X';
+        assert!(find_boot_entry(&mut iso).unwrap_err().contains("no El Torito"));
+
+        let mut iso = ::disk::MemImage::new(32 * 2048);
+        assert!(find_boot_entry(&mut iso).unwrap_err().contains("ISO9660"));
+        let mut iso = ::disk::MemImage::new(2048);
+        assert!(find_boot_entry(&mut iso).is_err());
+    }
+}
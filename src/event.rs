@@ -1,56 +1,424 @@
 /*
  * Event scheduling
+ *
+ * Events fire at deadlines in guest time. Pending deadlines are kept in a binary heap, each event owns
+ * a slot that identifies it for cancelling. Re-arming and cancelling leave old heap entries behind,
+ * they are told apart by slot generation and arm sequence, skipped when they come up and compacted away
+ * when they pile up. Slot generation changes every time a slot is freed, so a handle to a cancelled
+ * event can't touch whatever event reuses its slot.
+ *
+ * Periodic timers compute every deadline from the first one as a whole number of periods in ticks of their
+ * own frequency, so deadlines don't drift however late handlers run or how ns rounding falls. A periodic
+ * timer is re-armed as it is taken off the heap, at the first period still ahead of current time; periods
+ * already behind are skipped and handed to the handler as a missed count. One-shot and periodic timers
+ * share slots and handles, cancelling either kind frees the slot and stops it for good.
+ *
+ * Expiry is batched: all events due at current time are taken off the heap first and then fired in
+ * deadline order. Events re-armed by handlers go back to the heap and wait for the next batch even if
+ * they are due right away, so a periodic event can't hold back others due in the same batch.
+ *
+ * Events are delivered one of two ways. Event loop thread sleeps on host time until the next deadline and
+ * fires events while vcpu runs guest, device handlers kick vcpu out to inject interrupts. With VMX
+ * preemption timer vcpu thread does it all: timer is set to the next deadline before guest entry, and due
+ * events fire after every exit, which saves host sleep and signal jitter.
  */
 
 use std::{thread, fmt};
 use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::time::Duration;
 use std::sync::{Mutex, MutexGuard, Condvar, atomic};
 use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT};
 
-use vm;
+use clock;
+use record;
+
+const NS_PER_US: u64                = 1000;
+
+/* Slot of an event that was never scheduled */
+const NO_SLOT: usize                = !0;
+
+/* Compact heap when it holds this many stale entries beyond the armed ones */
+const STALE_ENTRIES_MAX: usize      = 64;
+
+/* Longest host sleep of event loop between checks of guest time */
+const EVENT_LOOP_MAX_SLEEP_NS: u64  = 1000000;
+
+/* Largest preemption timer value, VMCS field is 32 bit */
+const PREEMPTION_TIMER_MAX: u64     = 0xFFFFFFFF;
 
-#[derive(Eq)]
+/* Guest time guest gets to run when next deadline already passed, so late events can't exit in a loop */
+const PREEMPTION_MIN_NS: u64        = 1000;
+
+/**
+ * Schedulable event
+ *
+ * Handler gets the event back when it fires and may schedule it again.
+ */
 pub struct Event {
-    delay: u64,         /* Remaining delay time, saved in case we will resched */
     handler: fn(Event), /* Handler func (TODO: closure?) */
+    slot: usize,        /* Queue slot owned while scheduled or firing */
+    gen: u32,           /* Slot generation the event owns */
+    deadline: u64,      /* Deadline it fired for */
+    missed: u64,        /* Periods skipped before that deadline */
+}
+
+impl Event {
+    /**
+     * Guest time deadline the event fired for, not the time it actually fired at
+     */
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+
+    /**
+     * Number of periods of a periodic event after this deadline that were already due too and got skipped
+     */
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+}
+
+impl fmt::Debug for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{ slot: {}, gen: {}, deadline: {}, handler {:?} }}",
+               self.slot, self.gen, self.deadline, self.handler as *const fn(Event))
+    }
+}
+
+/**
+ * Handle of a scheduled event for cancelling it
+ *
+ * Stays valid while event is re-armed by its handler, goes stale once event is cancelled or fires
+ * without being scheduled again.
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct TimerHandle {
+    slot: usize,
+    gen: u32,
+}
+
+struct Slot {
+    gen: u32,           /* Bumped when slot is freed */
+    in_use: bool,
+    armed: Option<u64>, /* Arm sequence of the heap entry that fires it */
+    handler: fn(Event),
+    periodic: Option<Periodic>,
+}
+
+/* Periodic timer, deadline of period n is first + n * period ticks */
+#[derive(Copy, Clone, Debug)]
+struct Periodic {
+    first: u64,
+    period: u64,
+    freq_hz: u64,
+    index: u64,         /* Period armed */
+}
+
+impl Periodic {
+    fn deadline(&self, index: u64) -> u64 {
+        clock::ticks_to_ns(self.first + index * self.period, self.freq_hz)
+    }
+
+    /* First period after the armed one that is still ahead of time now */
+    fn next_index(&self, now: u64) -> u64 {
+        let mut index = self.index + 1;
+        let now_ticks = clock::ns_to_ticks(now, self.freq_hz);
+        if self.first + index * self.period <= now_ticks {
+            index = (now_ticks - self.first) / self.period + 1;
+        }
+
+        while self.deadline(index) <= now {
+            index += 1;
+        }
+
+        index
+    }
 }
 
-impl Ord for Event {
-    fn cmp(&self, other: &Event) -> Ordering {
-        /* We want a descending sorted order so comparison is reversed */
-        other.delay.cmp(&self.delay)
+/* Event taken off the heap to fire */
+#[derive(Copy, Clone)]
+struct Due {
+    handle: TimerHandle,
+    at: u64,
+    missed: u64,
+}
+
+/* Heap entry, ordered so that the earliest deadline is on top and equal deadlines fire in arm order */
+#[derive(PartialEq, Eq)]
+struct Deadline {
+    at: u64,
+    seq: u64,
+    slot: usize,
+    gen: u32,
+}
+
+impl Ord for Deadline {
+    fn cmp(&self, other: &Deadline) -> Ordering {
+        /* BinaryHeap is a max heap, so comparison is reversed */
+        other.at.cmp(&self.at).then(other.seq.cmp(&self.seq))
     }
 }
 
-impl PartialOrd for Event {
-    fn partial_cmp(&self, other: &Event) -> Option<Ordering> {
+impl PartialOrd for Deadline {
+    fn partial_cmp(&self, other: &Deadline) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl PartialEq for Event {
-    fn eq (&self, other: &Event) -> bool {
-        return self.delay == other.delay;
+/**
+ * Deadline ordered timer queue
+ */
+struct TimerQueue {
+    heap: BinaryHeap<Deadline>,
+    slots: Vec<Slot>,
+    free: Vec<usize>,
+    seq: u64,           /* Next arm sequence */
+    armed: usize,       /* Number of armed slots */
+}
+
+impl TimerQueue {
+    fn new() -> TimerQueue {
+        TimerQueue {
+            heap: BinaryHeap::new(),
+            slots: Vec::new(),
+            free: Vec::new(),
+            seq: 0,
+            armed: 0,
+        }
+    }
+
+    fn owns(&self, slot: usize, gen: u32) -> bool {
+        slot < self.slots.len() && self.slots[slot].in_use && self.slots[slot].gen == gen
+    }
+
+    fn is_current(&self, entry: &Deadline) -> bool {
+        self.owns(entry.slot, entry.gen) && self.slots[entry.slot].armed == Some(entry.seq)
+    }
+
+    fn alloc(&mut self, handler: fn(Event)) -> usize {
+        match self.free.pop() {
+            Some(slot) => {
+                self.slots[slot].in_use = true;
+                self.slots[slot].handler = handler;
+                self.slots[slot].periodic = None;
+                slot
+            },
+            None => {
+                self.slots.push(Slot { gen: 0, in_use: true, armed: None, handler: handler, periodic: None });
+                self.slots.len() - 1
+            },
+        }
+    }
+
+    fn release(&mut self, slot: usize) {
+        let s = &mut self.slots[slot];
+        if s.armed.take().is_some() {
+            self.armed -= 1;
+        }
+        s.in_use = false;
+        s.gen = s.gen.wrapping_add(1);
+        self.free.push(slot);
+    }
+
+    /* Schedule event at deadline, event keeps its slot if it still owns one, periodic or not */
+    fn arm(&mut self, at: u64, ev: Event) -> TimerHandle {
+        let slot = self.slot_of(ev);
+        self.slots[slot].periodic = None;
+        self.push(slot, at)
+    }
+
+    /* Schedule event every period ticks of freq_hz from tick first on */
+    fn arm_periodic(&mut self, first: u64, period: u64, freq_hz: u64, ev: Event) -> TimerHandle {
+        assert!(period != 0 && freq_hz != 0);
+
+        let slot = self.slot_of(ev);
+        let periodic = Periodic { first: first, period: period, freq_hz: freq_hz, index: 0 };
+        self.slots[slot].periodic = Some(periodic);
+        self.push(slot, periodic.deadline(0))
+    }
+
+    fn slot_of(&mut self, ev: Event) -> usize {
+        if self.owns(ev.slot, ev.gen) {
+            ev.slot
+        } else {
+            self.alloc(ev.handler)
+        }
+    }
+
+    /* Put slot on the heap at deadline, replacing its earlier entry */
+    fn push(&mut self, slot: usize, at: u64) -> TimerHandle {
+        let seq = self.seq;
+        self.seq += 1;
+
+        let gen = {
+            let s = &mut self.slots[slot];
+            if s.armed.is_none() {
+                self.armed += 1;
+            }
+            s.armed = Some(seq);
+            s.gen
+        };
+
+        self.heap.push(Deadline { at: at, seq: seq, slot: slot, gen: gen });
+        self.compact();
+        TimerHandle { slot: slot, gen: gen }
+    }
+
+    /* Cancel event, false if it already fired or was cancelled */
+    fn cancel(&mut self, handle: TimerHandle) -> bool {
+        if !self.owns(handle.slot, handle.gen) {
+            return false;
+        }
+
+        self.release(handle.slot);
+        true
+    }
+
+    /* Drop stale entries once they outnumber armed ones */
+    fn compact(&mut self) {
+        if self.heap.len() <= 2 * self.armed + STALE_ENTRIES_MAX {
+            return;
+        }
+
+        let heap = ::std::mem::replace(&mut self.heap, BinaryHeap::new());
+        self.heap = heap.into_iter().filter(|entry| self.is_current(entry)).collect();
+    }
+
+    /* Earliest armed deadline */
+    fn next_deadline(&mut self) -> Option<u64> {
+        while let Some(at) = self.heap.peek().map(|entry| if self.is_current(entry) { Some(entry.at) } else { None }) {
+            match at {
+                Some(at) => return Some(at),
+                None => { self.heap.pop(); },
+            }
+        }
+
+        None
+    }
+
+    /* Take all events due at time now off the heap in deadline order, periodic ones go back at their next period */
+    fn take_due(&mut self, now: u64) -> Vec<Due> {
+        let mut due = Vec::new();
+        while let Some(at) = self.next_deadline() {
+            if at > now {
+                break;
+            }
+
+            let entry = self.heap.pop().unwrap();
+            let mut missed = 0;
+            match self.slots[entry.slot].periodic {
+                Some(mut periodic) => {
+                    let index = periodic.next_index(now);
+                    missed = index - periodic.index - 1;
+                    periodic.index = index;
+                    self.slots[entry.slot].periodic = Some(periodic);
+                    self.push(entry.slot, periodic.deadline(index));
+                },
+                None => {
+                    self.slots[entry.slot].armed = None;
+                    self.armed -= 1;
+                },
+            }
+
+            due.push(Due { handle: TimerHandle { slot: entry.slot, gen: entry.gen }, at: at, missed: missed });
+        }
+
+        due
+    }
+
+    /* Event to hand to handler, None if it was cancelled after it was taken */
+    fn start_firing(&self, due: Due) -> Option<Event> {
+        let handle = due.handle;
+        if !self.owns(handle.slot, handle.gen) {
+            return None;
+        }
+
+        Some(Event {
+            handler: self.slots[handle.slot].handler,
+            slot: handle.slot,
+            gen: handle.gen,
+            deadline: due.at,
+            missed: due.missed,
+        })
+    }
+
+    /* Handler returned, free slot unless it scheduled the event again */
+    fn finish_firing(&mut self, handle: TimerHandle) {
+        if self.owns(handle.slot, handle.gen) && self.slots[handle.slot].armed.is_none() {
+            self.release(handle.slot);
+        }
     }
 }
 
-impl fmt::Debug for Event {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{{ delay: {}, handler {:?} }}", self.delay, self.handler as *const fn(Event))
+/*
+ * Fire a batch of events due at time now, handlers run with queue unlocked so they can schedule and cancel.
+ * Returns number of handlers called.
+ */
+fn fire_due(queue: &Mutex<TimerQueue>, now: u64) -> usize
+{
+    let due = queue.lock().unwrap().take_due(now);
+    let mut fired = 0;
+
+    if !due.is_empty() {
+        record::emit(|| record::Input::Timer { now: now });
     }
+
+    for due in due {
+        let ev = match queue.lock().unwrap().start_firing(due) {
+            Some(ev) => ev,
+            None => continue,
+        };
+
+        debug!("Firing event {:?}", ev);
+        (ev.handler)(ev);
+        queue.lock().unwrap().finish_firing(due.handle);
+        fired += 1;
+    }
+
+    fired
 }
 
 /**
- * Event queue
+ * VMX preemption timer setup
  *
- * Events are sorted by remaining delay time in descinding order (i.e. ready to fire events are
- * always at the end of the queue)
- * 
- * Why no BinaryHeap? We will need to modify delay field when traversing queue in event loop.
- * BinaryHeap doesn't give us a mut_iter and i don't what to put more RefCell-s if i can avoid it.
+ * Timer counts down while guest runs at a fixed rate derived from host TSC and exits guest when it hits
+ * zero. Guest time runs at dilation times that, so timer value is guest time to the deadline scaled back
+ * to host time, rounded up so guest never exits short of it. Deadlines farther out than the timer can
+ * count are clamped, the exit finds nothing due and timer is set again.
  */
+pub struct PreemptionTimer
+{
+    freq_hz: u64,   // Timer rate, host TSC rate shifted right by a CPU specific amount
+}
+
+impl PreemptionTimer
+{
+    pub fn new(freq_hz: u64) -> PreemptionTimer {
+        assert!(freq_hz != 0);
+        PreemptionTimer {
+            freq_hz: freq_hz,
+        }
+    }
+
+    /**
+     * Timer value to exit guest at deadline, guest time now and dilation as guest time runs right now
+     */
+    pub fn value(&self, now: u64, deadline: Option<u64>, dilation: f64) -> u32 {
+        let guest_ns = match deadline {
+            Some(at) if at > now => at - now,
+            Some(_) => PREEMPTION_MIN_NS,
+            None => return PREEMPTION_TIMER_MAX as u32,
+        };
+
+        let ticks = (guest_ns as f64 / dilation * self.freq_hz as f64 / clock::NS_PER_SEC as f64).ceil() as u64;
+        ticks.min(PREEMPTION_TIMER_MAX) as u32
+    }
+}
+
 lazy_static! {
-    static ref EVENT_QUEUE: Mutex<Vec<Event>> = Mutex::new(Vec::new());
+    static ref TIMER_QUEUE: Mutex<TimerQueue> = Mutex::new(TimerQueue::new());
+    static ref PREEMPTION_TIMER: Mutex<Option<PreemptionTimer>> = Mutex::new(None);
 }
 
 /**
@@ -69,17 +437,6 @@ lazy_static! {
     static ref LOOP_IS_LOCKED: AtomicBool = ATOMIC_BOOL_INIT;
 }
 
-fn enqueue_event(ev: Event)
-{
-    let mut q = EVENT_QUEUE.lock().unwrap();
-
-    /* Don't care if it found an element or not - we just need an insert position */
-    match q.binary_search(&ev) {
-        Ok(pos) => q.insert(pos, ev),
-        Err(pos) => q.insert(pos, ev),
-    };
-}
-
 /**
  * Create new event
  * New event is not yet scheduled for execution. Use schedule_event for that.
@@ -88,8 +445,11 @@ fn enqueue_event(ev: Event)
  */
 pub fn create_event(handler: fn(Event)) -> Event {
     Event {
-        delay: 0,
         handler: handler,
+        slot: NO_SLOT,
+        gen: 0,
+        deadline: 0,
+        missed: 0,
     }
 }
 
@@ -99,63 +459,76 @@ pub fn create_event(handler: fn(Event)) -> Event {
  *
  * \delay   Event delay in guest time microseconds
  */
-pub fn schedule_event(delay: u64, ev: Event) {
-    let mut ev = ev;
-    ev.delay = delay;
-    enqueue_event(ev);
+pub fn schedule_event(delay: u64, ev: Event) -> TimerHandle {
+    let at = clock::device_time_ns() + delay * NS_PER_US;
+    TIMER_QUEUE.lock().unwrap().arm(at, ev)
+}
+
+/**
+ * Schedule event every period ticks of a frequency, starting at guest time tick first
+ * Deadlines are whole periods from the first one, so they don't drift with handler latency. Handler must not
+ * schedule the event again, it stays armed until cancelled. Zero period makes it fire once at first.
+ *
+ * \first      First deadline in guest time ticks
+ * \period     Interval in ticks
+ * \freq_hz    Tick frequency
+ */
+pub fn schedule_periodic(first: u64, period: u64, freq_hz: u64, ev: Event) -> TimerHandle {
+    let mut queue = TIMER_QUEUE.lock().unwrap();
+    if period == 0 {
+        queue.arm(clock::ticks_to_ns(first, freq_hz), ev)
+    } else {
+        queue.arm_periodic(first, period, freq_hz, ev)
+    }
+}
+
+/**
+ * Cancel scheduled event, false if it already fired or was cancelled before
+ */
+pub fn cancel_event(handle: TimerHandle) -> bool {
+    TIMER_QUEUE.lock().unwrap().cancel(handle)
+}
+
+/* Wait until vcpu runs guest and event loop is unlocked */
+fn wait_for_unlock() -> MutexGuard<'static, ()>
+{
+    let mut lock = LOOP_LOCK.lock().unwrap();
+    while LOOP_IS_LOCKED.load(atomic::Ordering::Acquire) {
+        debug!("Event loop is waiting");
+        lock = LOOP_COND.wait(lock).unwrap();
+    }
+
+    lock
 }
 
 /**
  * Event loop
- * Check guest execution time and fire events which are due
+ * Check guest time, fire events which are due and sleep until next one might be
  */
 fn event_loop_worker()
 {
     let mut prev_guest_time = 0;
     loop {
+        let sleep_ns = {
+            let _lock = wait_for_unlock();
 
-        /* Check if we need to wait */
-        let mut lock = LOOP_LOCK.lock().unwrap();
-        while LOOP_IS_LOCKED.load(atomic::Ordering::Acquire) {
-            debug!("Event loop is waiting");
-            lock = LOOP_COND.wait(lock).unwrap();
-        }
-
-        /* Count how much guest time has passed */
-        let guest_time = vm::get_guest_exec_time();
-        assert!(guest_time >= prev_guest_time);
-
-        if guest_time > prev_guest_time {
-            /* Guest time is in nanoseconds, event time is in microseconds */
-            let elapsed_time = (guest_time - prev_guest_time) / 1000;
-            let mut q = EVENT_QUEUE.lock().unwrap();
-
-            /* We will now iterate over all stored elements and substract elapsed time from delay
-             * Since we're substracting the same quantity from all elements it will not change
-             * their sorted order */
-            for ev in &mut *q {
-                if ev.delay < elapsed_time {
-                    ev.delay = 0;
-                } else {
-                    ev.delay -= elapsed_time;
-                }
-            }
-
-            /* Do another pass over events popping and firing those that have 0 delay */
-            let len = q.len();
-            for i in 0..len {
-                if q[len - i - 1].delay != 0 {
-                    break;
-                }
+            let guest_time = clock::guest_time_ns();
+            assert!(guest_time >= prev_guest_time);
+            prev_guest_time = guest_time;
 
-                let ev = q.pop().unwrap();
+            fire_due(&TIMER_QUEUE, guest_time);
 
-                debug!("Firing event {:?}", ev);
-                (ev.handler)(ev);
+            /* Guest time doesn't run faster than host time times dilation, so sleep can't oversleep a deadline */
+            match TIMER_QUEUE.lock().unwrap().next_deadline() {
+                Some(at) if at > guest_time => ((at - guest_time) as f64 / clock::time_dilation()) as u64,
+                Some(_) => 0,
+                None => EVENT_LOOP_MAX_SLEEP_NS,
             }
-        }
+        };
 
-        prev_guest_time = guest_time;
+        if sleep_ns != 0 {
+            thread::sleep(Duration::new(0, sleep_ns.min(EVENT_LOOP_MAX_SLEEP_NS) as u32));
+        }
     }
 }
 
@@ -169,6 +542,37 @@ pub fn start_event_loop()
     });
 }
 
+/**
+ * Deliver events by VMX preemption timer instead of event loop thread
+ * Vcpu thread then has to call run_due_events after each exit and program preemption_timer_value before entry.
+ *
+ * \freq_hz    Preemption timer rate
+ */
+pub fn start_preemption_timer(freq_hz: u64)
+{
+    *PREEMPTION_TIMER.lock().unwrap() = Some(PreemptionTimer::new(freq_hz));
+}
+
+/**
+ * Fire events due at current guest time, vcpu thread only
+ */
+pub fn run_due_events() -> usize
+{
+    fire_due(&TIMER_QUEUE, clock::guest_time_ns())
+}
+
+/**
+ * Preemption timer value for next guest entry, vcpu thread only
+ */
+pub fn preemption_timer_value() -> u32
+{
+    let deadline = TIMER_QUEUE.lock().unwrap().next_deadline();
+    match *PREEMPTION_TIMER.lock().unwrap() {
+        Some(ref timer) => timer.value(clock::guest_time_ns(), deadline, clock::time_dilation()),
+        None => panic!("preemption timer is not started"),
+    }
+}
+
 /**
  * Pause event loop execution
  */
@@ -186,3 +590,349 @@ pub fn unlock_event_loop()
     LOOP_IS_LOCKED.store(false, atomic::Ordering::Release);
     LOOP_COND.notify_one();
 }
+
+#[cfg(test)]
+mod event_test
+{
+    use super::*;
+    use vm::pause_handler;
+    use clock::{virtual_clock, MockClock, ScaledClock};
+    use std::rc::Rc;
+    use std::cell::{Cell, RefCell};
+    use std::time::Instant;
+
+    thread_local! {
+        static QUEUE: Mutex<TimerQueue> = Mutex::new(TimerQueue::new());
+        static NOW: Cell<u64> = Cell::new(0);
+        static FIRED: RefCell<Vec<(usize, u64)>> = RefCell::new(Vec::new());
+        static CANCEL: Cell<Option<TimerHandle>> = Cell::new(None);
+    }
+
+    fn now() -> u64 {
+        NOW.with(|now| now.get())
+    }
+
+    fn record(ev: &Event) {
+        FIRED.with(|fired| fired.borrow_mut().push((ev.slot, now())));
+    }
+
+    fn take_fired() -> Vec<usize> {
+        FIRED.with(|fired| fired.borrow_mut().drain(..).map(|(slot, _)| slot).collect())
+    }
+
+    fn arm(at: u64, ev: Event) -> TimerHandle {
+        QUEUE.with(|q| q.lock().unwrap().arm(at, ev))
+    }
+
+    fn run(at: u64) -> usize {
+        NOW.with(|now| now.set(at));
+        QUEUE.with(|q| fire_due(q, at))
+    }
+
+    fn one_shot(ev: Event) {
+        record(&ev);
+    }
+
+    /* Fires every 10 time units */
+    fn periodic(ev: Event) {
+        record(&ev);
+        let at = now() + 10;
+        arm(at, ev);
+    }
+
+    /* Fires again at the same time it fired, must not starve others */
+    fn immediate(ev: Event) {
+        record(&ev);
+        let at = now();
+        arm(at, ev);
+    }
+
+    fn canceller(ev: Event) {
+        record(&ev);
+        if let Some(handle) = CANCEL.with(|c| c.get()) {
+            QUEUE.with(|q| assert!(q.lock().unwrap().cancel(handle)));
+        }
+    }
+
+    fn stats() -> (usize, usize, usize) {
+        QUEUE.with(|q| {
+            let q = q.lock().unwrap();
+            (q.heap.len(), q.armed, q.slots.len())
+        })
+    }
+
+    #[test] fn deadline_order() {
+        let c = arm(30, create_event(one_shot));
+        let a = arm(10, create_event(one_shot));
+        let b = arm(20, create_event(one_shot));
+        let d = arm(20, create_event(one_shot));
+
+        assert!(run(5) == 0);
+        assert!(QUEUE.with(|q| q.lock().unwrap().next_deadline()) == Some(10));
+
+        /* One batch, deadline order, ties in arm order */
+        assert!(run(25) == 3);
+        assert!(take_fired() == vec![a.slot, b.slot, d.slot]);
+        assert!(run(30) == 1 && take_fired() == vec![c.slot]);
+
+        /* Fired one shot events free their slots */
+        assert!(stats() == (0, 0, 4));
+        assert!(QUEUE.with(|q| q.lock().unwrap().next_deadline()).is_none());
+    }
+
+    #[test] fn cancel_and_generation() {
+        let a = arm(10, create_event(one_shot));
+        assert!(QUEUE.with(|q| q.lock().unwrap().cancel(a)));
+        assert!(!QUEUE.with(|q| q.lock().unwrap().cancel(a)));
+
+        /* New event reuses the slot, stale handle can't cancel it and stale heap entry doesn't fire it early */
+        let b = arm(50, create_event(one_shot));
+        assert!(b.slot == a.slot && b.gen != a.gen);
+        assert!(!QUEUE.with(|q| q.lock().unwrap().cancel(a)));
+        assert!(run(10) == 0);
+        assert!(run(50) == 1 && take_fired() == vec![b.slot]);
+        assert!(!QUEUE.with(|q| q.lock().unwrap().cancel(b)));
+
+        /* Handler cancels an event due in the same batch */
+        let c = arm(100, create_event(canceller));
+        let d = arm(101, create_event(one_shot));
+        CANCEL.with(|cancel| cancel.set(Some(d)));
+        assert!(run(200) == 1 && take_fired() == vec![c.slot]);
+        CANCEL.with(|cancel| cancel.set(None));
+        assert!(stats().1 == 0);
+    }
+
+    #[test] fn rearm() {
+        /* Handler re-arming keeps slot and handle */
+        let p = arm(10, create_event(periodic));
+        assert!(run(10) == 1);
+        assert!(run(15) == 0);
+        assert!(run(20) == 1 && take_fired() == vec![p.slot, p.slot]);
+
+        /* Cancelled periodic event stays quiet */
+        assert!(QUEUE.with(|q| q.lock().unwrap().cancel(p)));
+        assert!(run(100) == 0);
+
+        /* Event due right away again doesn't starve later deadlines in the batch */
+        arm(200, create_event(immediate));
+        let b = arm(201, create_event(one_shot));
+        assert!(run(300) == 2);
+        assert!(take_fired()[1] == b.slot);
+        assert!(run(300) == 1);
+    }
+
+    #[test] fn compaction() {
+        let keep = arm(5000, create_event(one_shot));
+        for i in 0..1000 {
+            let handle = arm(1000 + i, create_event(one_shot));
+            QUEUE.with(|q| assert!(q.lock().unwrap().cancel(handle)));
+        }
+
+        /* Cancelled events left one slot to reuse and a bounded number of stale entries */
+        let (heap, armed, slots) = stats();
+        assert!(armed == 1 && slots == 2 && heap <= 2 + STALE_ENTRIES_MAX + 1);
+        assert!(run(4999) == 0);
+        assert!(run(5000) == 1 && take_fired() == vec![keep.slot]);
+    }
+
+    /* Host time passing while paused must not expire timers, neither during pause nor after it */
+    #[test] fn paused_clock() {
+        let host = Rc::new(MockClock::new());
+        let clock = ScaledClock::new(host.clone());
+        for i in 0..3 {
+            arm(i, create_event(periodic));
+        }
+
+        let run_host = |ns: u64| -> usize {
+            (0..ns).map(|_| {
+                host.advance_ns(1);
+                run(clock.now_ns())
+            }).sum()
+        };
+
+        assert!(run_host(100) == 30);
+        clock.paused();
+        assert!(run_host(100000) == 0);
+        clock.resumed();
+
+        /* Same rate as before the pause, no catch up burst */
+        assert!(run_host(100) == 30);
+        let fired_at: Vec<u64> = FIRED.with(|f| f.borrow_mut().drain(..).map(|(_, at)| at).collect());
+        assert!(fired_at.iter().all(|&at| at <= 200) && clock.now_ns() == 200);
+    }
+
+    thread_local! {
+        static PERIODS: RefCell<Vec<(u64, u64)>> = RefCell::new(Vec::new());
+    }
+
+    /* Periodic timer handler, records deadline it fired for and periods it missed */
+    fn counted(ev: Event) {
+        record(&ev);
+        PERIODS.with(|p| p.borrow_mut().push((ev.deadline(), ev.missed())));
+    }
+
+    fn arm_periodic(first: u64, period: u64, freq_hz: u64, ev: Event) -> TimerHandle {
+        QUEUE.with(|q| q.lock().unwrap().arm_periodic(first, period, freq_hz, ev))
+    }
+
+    fn next_deadline() -> Option<u64> {
+        QUEUE.with(|q| q.lock().unwrap().next_deadline())
+    }
+
+    #[test] fn periodic_no_drift() {
+        const FREQ_HZ: u64 = 1193182;
+        const START: u64 = 1000;
+        const INTERVAL: u64 = 11932;    /* About 100 Hz, not a whole number of ns */
+        const N: u64 = 1000;
+
+        let p = arm_periodic(START, INTERVAL, FREQ_HZ, create_event(counted));
+
+        /* Callbacks run late by varying amounts up to most of a period */
+        let mut fired = 0;
+        while fired < N {
+            let at = next_deadline().unwrap();
+            fired += run(at + (fired * 7919) % 9000000) as u64;
+        }
+
+        let periods: Vec<(u64, u64)> = PERIODS.with(|p| p.borrow_mut().drain(..).collect());
+        for (n, &(deadline, missed)) in periods.iter().enumerate() {
+            assert!(deadline == clock::ticks_to_ns(START + n as u64 * INTERVAL, FREQ_HZ) && missed == 0);
+        }
+        assert!(next_deadline() == Some(clock::ticks_to_ns(START + N * INTERVAL, FREQ_HZ)));
+
+        /* Late by three and a half periods, fires once for the oldest and skips the rest */
+        let late = clock::ticks_to_ns(START + (N + 3) * INTERVAL + INTERVAL / 2, FREQ_HZ);
+        assert!(run(late) == 1);
+        assert!(PERIODS.with(|p| p.borrow_mut().pop()) == Some((clock::ticks_to_ns(START + N * INTERVAL, FREQ_HZ), 3)));
+        assert!(next_deadline() == Some(clock::ticks_to_ns(START + (N + 4) * INTERVAL, FREQ_HZ)));
+
+        /* Cancelled like any event, for good */
+        assert!(QUEUE.with(|q| q.lock().unwrap().cancel(p)));
+        assert!(!QUEUE.with(|q| q.lock().unwrap().cancel(p)));
+        assert!(run(late * 2) == 0 && stats().1 == 0);
+        take_fired();
+    }
+
+    #[test] fn periodic_to_one_shot() {
+        let p = arm_periodic(10, 10, clock::NS_PER_SEC, create_event(counted));
+        assert!(run(10) == 1 && run(20) == 1);
+
+        /* Arming the same event with a plain deadline makes it one-shot, handle stays */
+        let ev = Event { handler: counted, slot: p.slot, gen: p.gen, deadline: 0, missed: 0 };
+        assert!(arm(25, ev) == p);
+        assert!(run(30) == 1 && run(100) == 0);
+        assert!(stats().1 == 0 && !QUEUE.with(|q| q.lock().unwrap().cancel(p)));
+
+        let periods: Vec<(u64, u64)> = PERIODS.with(|p| p.borrow_mut().drain(..).collect());
+        assert!(periods == vec![(10, 0), (20, 0), (25, 0)]);
+        take_fired();
+    }
+
+    /* Timer period in guest ns, about a PIT tick at its fastest DOS rate */
+    const TICK_PERIOD_NS: u64 = 54925;
+
+    thread_local! {
+        static TICK_AT: Cell<u64> = Cell::new(0);
+    }
+
+    /* PIT like periodic tick, next deadline follows the ideal one and not the time it fired */
+    fn tick(ev: Event) {
+        record(&ev);
+        let at = TICK_AT.with(|at| { at.set(at.get() + TICK_PERIOD_NS); at.get() });
+        arm(at, ev);
+    }
+
+    #[test] fn preemption_timer_value() {
+        /* 2.4 GHz TSC shifted by 5 */
+        let timer = PreemptionTimer::new(75000000);
+
+        /* Rounds up, never short of deadline */
+        assert!(timer.value(0, Some(1000), 1.0) == 75);
+        assert!(timer.value(0, Some(1001), 1.0) == 76);
+        assert!(timer.value(5000, Some(6000), 0.5) == 150);
+        assert!(timer.value(0, Some(1000), 2.0) == 38);
+
+        /* Late deadline still lets guest run */
+        assert!(timer.value(5000, Some(5000), 1.0) == 75);
+        assert!(timer.value(5000, Some(10), 1.0) == 75);
+
+        /* Far or no deadline is clamped */
+        assert!(timer.value(0, Some(3600 * clock::NS_PER_SEC), 1.0) == 0xFFFFFFFF);
+        assert!(timer.value(0, None, 1.0) == 0xFFFFFFFF);
+    }
+
+    /*
+     * Vcpu loop with preemption timer against virtual time as guest sees it through RDTSC exits. Hardware
+     * counts the timer at its rate while guest runs and exit takes extra host time before events fire.
+     */
+    fn tick_latency(ratio: f64) -> u64 {
+        const FREQ_HZ: u64 = 75000000;
+        const EXIT_COST_NS: u64 = 700;
+        const TICKS: u64 = 200;
+
+        let host = Rc::new(MockClock::new());
+        let clock = ScaledClock::new(host.clone());
+        clock.set_ratio(ratio).unwrap();
+        let timer = PreemptionTimer::new(FREQ_HZ);
+
+        TICK_AT.with(|at| at.set(TICK_PERIOD_NS));
+        let ev = arm(TICK_PERIOD_NS, create_event(tick));
+
+        let mut exits = 0;
+        let mut worst = 0;
+        while FIRED.with(|f| f.borrow().len()) < TICKS as usize {
+            let deadline = QUEUE.with(|q| q.lock().unwrap().next_deadline());
+            let value = timer.value(clock.now_ns(), deadline, ratio) as u64;
+            host.advance_ns((value * clock::NS_PER_SEC + FREQ_HZ - 1) / FREQ_HZ + EXIT_COST_NS);
+            exits += 1;
+
+            /* Guest reads TSC first thing in its tick handler */
+            if run(clock.now_ns()) != 0 {
+                let rdtsc = clock::ns_to_ticks(clock.now_ns(), clock::NS_PER_SEC);
+                let ideal = FIRED.with(|f| f.borrow().len()) as u64 * TICK_PERIOD_NS;
+                worst = worst.max(rdtsc - ideal);
+            }
+        }
+
+        /* One exit per tick, nothing fires early or twice */
+        assert!(exits == TICKS);
+        assert!(QUEUE.with(|q| q.lock().unwrap().cancel(ev)));
+        take_fired();
+        worst
+    }
+
+    #[test] fn preemption_tick_latency() {
+        /* Exit cost is the only delay, a few microseconds of guest time at most */
+        assert!(tick_latency(1.0) <= 750);
+        assert!(tick_latency(0.5) <= 400);
+        assert!(tick_latency(4.0) <= 3000);
+    }
+
+    /* Few hundred periodic timers with different periods, run with --nocapture for timing */
+    #[test] fn many_timers() {
+        const TIMERS: usize = 300;
+        const END: u64 = 10000;
+
+        for i in 0..TIMERS {
+            arm(i as u64 % 10, create_event(periodic));
+        }
+
+        let start = Instant::now();
+        let mut t = 0;
+        let mut fired = 0;
+        while t <= END {
+            fired += run(t);
+            t += 1;
+        }
+        let elapsed = start.elapsed();
+
+        /* Every timer fires at its phase and then every 10 */
+        let expected: u64 = (0..TIMERS as u64).map(|i| (END - i % 10) / 10 + 1).sum();
+        assert!(fired as u64 == expected);
+        let fired_at: Vec<(usize, u64)> = FIRED.with(|f| f.borrow_mut().drain(..).collect());
+        assert!(fired_at.windows(2).all(|w| w[0].1 <= w[1].1));
+        assert!(stats().1 == TIMERS);
+
+        println!("{} timers, {} expiries in {}.{:06} s", TIMERS, fired, elapsed.as_secs(), elapsed.subsec_nanos() / 1000);
+    }
+}
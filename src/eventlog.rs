@@ -0,0 +1,454 @@
+/*
+ * Machine readable event log
+ *
+ * VM core and devices emit typed events, which go to a file as JSON lines when an event log is open, see
+ * --event-log. Each line has a sequence number and guest time in nanoseconds next to the event fields, e.g.
+ *
+ *   {"seq":3,"time_ns":1250,"event":"pic_raise","vector":8}
+ *
+ * Events are built by a closure emit() only calls when there is a log, so an emitter costs one relaxed load
+ * of the enabled flag without one. Emitters can run on any thread.
+ *
+ * With a log open each IRQ line assertion gets an id, which goes along with the interrupt through every stage
+ * of its delivery: assertion, PIC raising its vector to vcpu, PIC ack as the vector is taken from the pending
+ * queue, injection at VM entry and guest EOI. Events of each stage carry the id, so grepping for "id":4132
+ * tells what became of assertion 4132. An assertion merging into an IRQ latched already, or masked, stops at
+ * its irq_assert event. Without a log ids are None and nothing keeps them.
+ *
+ * A filter, see tracefilter.rs, keeps the log to matching events. Events it drops take no sequence number.
+ */
+
+use config;
+use clock;
+use crash::IoAccess;
+use breakpoint::IoDirection;
+use vm::IoOperandType;
+use tracefilter::TraceFilter;
+
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/**
+ * Things that happen to VM
+ */
+#[derive(Clone)]
+pub enum Event
+{
+    VmStart,
+    VmStop { status: i32 },
+    VmReset,
+    Io(IoAccess),                               // Guest port access
+    IrqAssert { irq: u8, id: Option<u64> },     // Device asserted ISA IRQ line at PIC
+    PicRaise { vector: u8, id: Option<u64> },   // PIC latched IRQ and raised its vector to vcpu
+    PicAck { vector: u8, id: Option<u64> },     // Vector is being delivered, IRR bit moves to ISR
+    PicEoi { vector: u8, id: Option<u64> },     // Guest ended the interrupt in service
+    Inject { kind: &'static str, vector: u8, id: Option<u64> },    // Event injected at VM entry: exception, nmi or external
+    Device { device: &'static str, message: String },
+    GuestPanic { message: String, cs: u16, ip: u64, ss: u16, sp: u64 },    // Guest reported panic at beacon port
+    HangDetected { seconds: u64, ips: usize, ports: usize },   // Guest spun that long at few IPs and ports
+    MemoryWrite { addr: u64, old: Vec<u8>, new: Vec<u8> },     // Host user changed guest memory, e.g. from monitor
+    RegisterWrite { reg: String, old: u64, new: u64 },         // Host user changed guest register
+    Snapshot { path: String, incremental: bool, pause_us: u64 },   // Automatic snapshot saved, guest stopped that long
+    SnapshotFailed { path: String, error: String, retry_s: u64 },  // Automatic snapshot failed, next try that much later
+    RamImport { path: String, base: u64, size: u64 },          // Host user loaded flat file into guest RAM
+}
+
+/**
+ * JSON string literal
+ */
+pub fn quote(val: &str) -> String
+{
+    let mut res = String::from("\"");
+    for c in val.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+/**
+ * Bytes as a hex string
+ */
+pub fn hex(bytes: &[u8]) -> String
+{
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl Event
+{
+    /**
+     * Event kind as it appears in the log
+     */
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Event::VmStart => "vm_start",
+            Event::VmStop { .. } => "vm_stop",
+            Event::VmReset => "vm_reset",
+            Event::Io(_) => "io",
+            Event::IrqAssert { .. } => "irq_assert",
+            Event::PicRaise { .. } => "pic_raise",
+            Event::PicAck { .. } => "pic_ack",
+            Event::PicEoi { .. } => "pic_eoi",
+            Event::Inject { .. } => "inject",
+            Event::Device { .. } => "device",
+            Event::GuestPanic { .. } => "guest_panic",
+            Event::HangDetected { .. } => "hang_detected",
+            Event::MemoryWrite { .. } => "memory_write",
+            Event::RegisterWrite { .. } => "register_write",
+            Event::Snapshot { .. } => "snapshot",
+            Event::SnapshotFailed { .. } => "snapshot_failed",
+            Event::RamImport { .. } => "ram_import",
+        }
+    }
+
+    /**
+     * Id of the IRQ assertion event is a stage of
+     */
+    pub fn irq_id(&self) -> Option<u64> {
+        match *self {
+            Event::IrqAssert { id, .. } | Event::PicRaise { id, .. } | Event::PicAck { id, .. } |
+            Event::PicEoi { id, .. } | Event::Inject { id, .. } => id,
+            _ => None,
+        }
+    }
+
+    /* Fields after the event kind, each with a leading comma */
+    fn fields(&self) -> String {
+        let fields = match *self {
+            Event::VmStart | Event::VmReset => String::new(),
+            Event::VmStop { status } => format!(",\"status\":{}", status),
+            Event::Io(ref access) => {
+                let (size, value) = match access.value {
+                    IoOperandType::byte(v) => (1, v as u32),
+                    IoOperandType::word(v) => (2, v as u32),
+                    IoOperandType::dword(v) => (4, v),
+                };
+                let dir = if access.direction == IoDirection::Read { "read" } else { "write" };
+                format!(",\"port\":{},\"dir\":\"{}\",\"size\":{},\"value\":{}", access.port, dir, size, value)
+            },
+            Event::IrqAssert { irq, .. } => format!(",\"irq\":{}", irq),
+            Event::PicRaise { vector, .. } | Event::PicAck { vector, .. } | Event::PicEoi { vector, .. } => {
+                format!(",\"vector\":{}", vector)
+            },
+            Event::Inject { kind, vector, .. } => format!(",\"kind\":{},\"vector\":{}", quote(kind), vector),
+            Event::Device { device, ref message } => format!(",\"device\":{},\"message\":{}", quote(device), quote(message)),
+            Event::GuestPanic { ref message, cs, ip, ss, sp } => {
+                format!(",\"message\":{},\"cs\":{},\"ip\":{},\"ss\":{},\"sp\":{}", quote(message), cs, ip, ss, sp)
+            },
+            Event::HangDetected { seconds, ips, ports } => format!(",\"seconds\":{},\"ips\":{},\"ports\":{}", seconds, ips, ports),
+            Event::MemoryWrite { addr, ref old, ref new } => {
+                format!(",\"addr\":{},\"old\":{},\"new\":{}", addr, quote(&hex(old)), quote(&hex(new)))
+            },
+            Event::RegisterWrite { ref reg, old, new } => format!(",\"reg\":{},\"old\":{},\"new\":{}", quote(reg), old, new),
+            Event::Snapshot { ref path, incremental, pause_us } => {
+                format!(",\"path\":{},\"incremental\":{},\"pause_us\":{}", quote(path), incremental, pause_us)
+            },
+            Event::SnapshotFailed { ref path, ref error, retry_s } => {
+                format!(",\"path\":{},\"error\":{},\"retry_s\":{}", quote(path), quote(error), retry_s)
+            },
+            Event::RamImport { ref path, base, size } => format!(",\"path\":{},\"base\":{},\"size\":{}", quote(path), base, size),
+        };
+
+        match self.irq_id() {
+            Some(id) => format!("{},\"id\":{}", fields, id),
+            None => fields,
+        }
+    }
+
+    /**
+     * Log line for event with sequence number seq at guest time
+     */
+    pub fn to_json(&self, seq: u64, time_ns: u64) -> String {
+        format!("{{\"seq\":{},\"time_ns\":{},\"event\":{}{}}}", seq, time_ns, quote(self.kind()), self.fields())
+    }
+}
+
+/**
+ * Open event log, sequence numbers start at 0
+ */
+pub struct EventLog
+{
+    sink: Box<Write + Send>,
+    clock: Box<Fn() -> u64 + Send>,     // Guest time for timestamps
+    seq: u64,
+    filter: Option<TraceFilter>,        // Events logged, all if none
+}
+
+impl EventLog
+{
+    pub fn new(sink: Box<Write + Send>, clock: Box<Fn() -> u64 + Send>) -> EventLog {
+        EventLog {
+            sink: sink,
+            clock: clock,
+            seq: 0,
+            filter: None,
+        }
+    }
+
+    pub fn set_filter(&mut self, filter: Option<TraceFilter>) {
+        self.filter = filter;
+    }
+
+    /**
+     * Log event unless filter drops it
+     */
+    pub fn record(&mut self, event: &Event) -> io::Result<()> {
+        if !self.filter.as_ref().map_or(true, |filter| filter.matches(event)) {
+            return Ok(());
+        }
+
+        let line = event.to_json(self.seq, (self.clock)());
+        self.seq += 1;
+        writeln!(self.sink, "{}", line)
+    }
+}
+
+#[cfg(test)]
+mod eventlog_test
+{
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /* Sink tests can look into after handing it over */
+    #[derive(Clone)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /* Value of a number or string field, lines are flat objects */
+    fn field<'a>(line: &'a str, name: &str) -> &'a str {
+        let key = format!("\"{}\":", name);
+        let start = line.find(&key).expect(name) + key.len();
+        let rest = &line[start..];
+        let end = rest.find(|c| c == ',' || c == '}').unwrap();
+        rest[..end].trim_matches('"')
+    }
+
+    #[test] fn format() {
+        assert!(Event::VmStart.to_json(0, 0) == "{\"seq\":0,\"time_ns\":0,\"event\":\"vm_start\"}");
+        assert!(Event::VmStop { status: 60 }.to_json(7, 1500) ==
+                "{\"seq\":7,\"time_ns\":1500,\"event\":\"vm_stop\",\"status\":60}");
+
+        let io = IoAccess { port: 0x21, direction: IoDirection::Write, value: IoOperandType::byte(0x3C) };
+        assert!(Event::Io(io).to_json(1, 2) ==
+                "{\"seq\":1,\"time_ns\":2,\"event\":\"io\",\"port\":33,\"dir\":\"write\",\"size\":1,\"value\":60}");
+        assert!(Event::Inject { kind: "external", vector: 8, id: None }.to_json(1, 2).ends_with(",\"kind\":\"external\",\"vector\":8}"));
+        assert!(Event::PicAck { vector: 8, id: Some(4132) }.to_json(1, 2).ends_with(",\"vector\":8,\"id\":4132}"));
+
+        let device = Event::Device { device: "ata", message: String::from("read \"LBA\" 5\n") };
+        assert!(device.to_json(1, 2).ends_with(",\"device\":\"ata\",\"message\":\"read \\\"LBA\\\" 5\\n\"}"));
+
+        let panic = Event::GuestPanic { message: String::from("x == 1"), cs: 0, ip: 0x7c20, ss: 0, sp: 0x7bfe };
+        assert!(panic.to_json(1, 2).ends_with(",\"event\":\"guest_panic\",\"message\":\"x == 1\",\"cs\":0,\"ip\":31776,\"ss\":0,\"sp\":31742}"));
+
+        let patch = Event::MemoryWrite { addr: 0x7c0a, old: vec![0x2a, 0xe6], new: vec![0x55, 0x0f] };
+        assert!(patch.to_json(1, 2).ends_with(",\"event\":\"memory_write\",\"addr\":31754,\"old\":\"2ae6\",\"new\":\"550f\"}"));
+        let reg = Event::RegisterWrite { reg: String::from("EAX"), old: 0x54, new: 0x1234 };
+        assert!(reg.to_json(1, 2).ends_with(",\"event\":\"register_write\",\"reg\":\"EAX\",\"old\":84,\"new\":4660}"));
+
+        let saved = Event::Snapshot { path: String::from("auto/auto-000002.inc.snap"), incremental: true, pause_us: 1830 };
+        assert!(saved.to_json(1, 2).ends_with(",\"event\":\"snapshot\",\"path\":\"auto/auto-000002.inc.snap\",\"incremental\":true,\"pause_us\":1830}"));
+        let failed = Event::SnapshotFailed { path: String::from("auto-000003.snap"), error: String::from("No space left on device"), retry_s: 120 };
+        assert!(failed.to_json(1, 2).ends_with(",\"event\":\"snapshot_failed\",\"path\":\"auto-000003.snap\",\"error\":\"No space left on device\",\"retry_s\":120}"));
+        let import = Event::RamImport { path: String::from("prime.bin"), base: 0x8000, size: 0x200 };
+        assert!(import.to_json(1, 2).ends_with(",\"event\":\"ram_import\",\"path\":\"prime.bin\",\"base\":32768,\"size\":512}"));
+    }
+
+    #[test] fn scripted_sequence() {
+        let buf = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+        let time = Arc::new(Mutex::new(0u64));
+        let clock_time = time.clone();
+        let mut log = EventLog::new(Box::new(buf.clone()), Box::new(move || {
+            let mut now = clock_time.lock().unwrap();
+            *now += 100;
+            *now
+        }));
+
+        /* Timer IRQ goes all the way through PIC to guest */
+        let script = [
+            Event::VmStart,
+            Event::IrqAssert { irq: 0, id: Some(0) },
+            Event::PicRaise { vector: 8, id: Some(0) },
+            Event::PicAck { vector: 8, id: Some(0) },
+            Event::Inject { kind: "external", vector: 8, id: Some(0) },
+            Event::Io(IoAccess { port: 0x20, direction: IoDirection::Write, value: IoOperandType::byte(0x20) }),
+            Event::PicEoi { vector: 8, id: Some(0) },
+            Event::VmStop { status: 0 },
+        ];
+        for event in script.iter() {
+            log.record(event).unwrap();
+        }
+
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines.len() == script.len());
+
+        let kinds: Vec<&str> = lines.iter().map(|line| field(line, "event")).collect();
+        assert!(kinds == vec!["vm_start", "irq_assert", "pic_raise", "pic_ack", "inject", "io", "pic_eoi", "vm_stop"]);
+
+        for (i, line) in lines.iter().enumerate() {
+            assert!(line.starts_with('{') && line.ends_with('}'));
+            assert!(field(line, "seq") == i.to_string());
+            assert!(field(line, "time_ns") == ((i + 1) * 100).to_string());
+        }
+        assert!(field(lines[2], "vector") == "8" && field(lines[6], "vector") == "8");
+        assert!(field(lines[5], "port") == "32" && field(lines[5], "dir") == "write");
+
+        /* Every delivery stage refers to the assertion */
+        let stages: Vec<&str> = lines.iter().filter(|line| line.contains("\"id\":0}")).map(|line| field(line, "event")).collect();
+        assert!(stages == vec!["irq_assert", "pic_raise", "pic_ack", "inject", "pic_eoi"]);
+        assert!(*time.lock().unwrap() == 800);
+    }
+
+    #[test] fn filtered_sequence() {
+        let buf = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+        let mut log = EventLog::new(Box::new(buf.clone()), Box::new(|| 0));
+
+        /* Every predicate kind: word and number equality, inequality and ranges */
+        log.set_filter(Some(TraceFilter::parse("port in 0x1f0..0x1f7 and dir == write or \
+                                                device == pic and vector != 0x0e or event == inject and kind == nmi").unwrap()));
+
+        let script = [
+            Event::VmStart,
+            Event::Io(IoAccess { port: 0x1F2, direction: IoDirection::Write, value: IoOperandType::byte(1) }),
+            Event::Io(IoAccess { port: 0x1F7, direction: IoDirection::Read, value: IoOperandType::byte(0x50) }),
+            Event::Io(IoAccess { port: 0x1F7, direction: IoDirection::Write, value: IoOperandType::byte(0x20) }),
+            Event::Io(IoAccess { port: 0x1F8, direction: IoDirection::Write, value: IoOperandType::byte(0) }),
+            Event::IrqAssert { irq: 14, id: Some(0) },
+            Event::PicRaise { vector: 0x0E, id: Some(0) },
+            Event::PicRaise { vector: 0x08, id: Some(1) },
+            Event::PicAck { vector: 0x08, id: Some(1) },
+            Event::Inject { kind: "external", vector: 0x08, id: Some(1) },
+            Event::Inject { kind: "nmi", vector: 2, id: None },
+            Event::Device { device: "ata", message: String::from("reset") },
+            Event::VmStop { status: 0 },
+        ];
+        for event in script.iter() {
+            log.record(event).unwrap();
+        }
+
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        let kinds: Vec<&str> = lines.iter().map(|line| field(line, "event")).collect();
+        assert!(kinds == vec!["io", "io", "pic_raise", "pic_ack", "inject"]);
+        assert!(field(lines[0], "port") == "498" && field(lines[1], "value") == "32");
+        assert!(field(lines[2], "vector") == "8" && field(lines[4], "kind") == "nmi");
+
+        /* Dropped events take no sequence numbers */
+        for (i, line) in lines.iter().enumerate() {
+            assert!(field(line, "seq") == i.to_string());
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+lazy_static! {
+    static ref ENABLED: AtomicBool = AtomicBool::new(false);
+    static ref EVENT_LOG: Mutex<Option<EventLog>> = Mutex::new(None);
+    static ref NEXT_IRQ_ID: AtomicUsize = AtomicUsize::new(0);
+}
+
+/**
+ * Start logging events to file, replacing the current log
+ */
+pub fn open(path: &str) -> Result<(), String>
+{
+    let file = try!(File::create(path).map_err(|err| format!("Can't create event log {}: {}", path, err)));
+    let log = EventLog::new(Box::new(LineWriter::new(file)), Box::new(clock::guest_time_ns));
+
+    *EVENT_LOG.lock().unwrap() = Some(log);
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/**
+ * There is a log events go to
+ */
+pub fn enabled() -> bool
+{
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/**
+ * Log event built by closure, which is only called if there is a log
+ * Log that can't be written to is closed, VM goes on without it.
+ */
+pub fn emit<F>(event: F) where F: FnOnce() -> Event
+{
+    if !enabled() {
+        return;
+    }
+
+    let mut log = EVENT_LOG.lock().unwrap();
+    let res = match *log {
+        Some(ref mut log) => log.record(&event()),
+        None => return,
+    };
+
+    if let Err(err) = res {
+        error!("Event log write failed, closing it: {}", err);
+        ENABLED.store(false, Ordering::Relaxed);
+        *log = None;
+    }
+}
+
+/**
+ * Log only events matching filter, all without one
+ */
+pub fn set_filter(filter: Option<TraceFilter>) -> Result<(), String>
+{
+    match *EVENT_LOG.lock().unwrap() {
+        Some(ref mut log) => {
+            log.set_filter(filter);
+            Ok(())
+        },
+        None => Err(String::from("No event log open")),
+    }
+}
+
+/**
+ * Filter of the open log, None if there is no log or it takes all events
+ */
+pub fn filter() -> Option<TraceFilter>
+{
+    EVENT_LOG.lock().unwrap().as_ref().and_then(|log| log.filter.clone())
+}
+
+/**
+ * Id for a new IRQ line assertion, None without a log
+ */
+pub fn next_irq_id() -> Option<u64>
+{
+    if !enabled() {
+        return None;
+    }
+
+    Some(NEXT_IRQ_ID.fetch_add(1, Ordering::Relaxed) as u64)
+}
+
+pub fn init(config: &config::VmConfig)
+{
+    if let Some(ref path) = config.event_log {
+        if let Err(err) = open(path) {
+            error!("{}", err);
+            ::std::process::exit(1);
+        }
+    }
+}
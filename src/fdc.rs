@@ -0,0 +1,1121 @@
+/*
+ * 82077AA floppy disk controller emulation
+ *
+ * Drive A and optional drive B backed by raw 1.44M, 1.2M or 720K images, media geometry follows image size.
+ * Host may insert, eject and swap media while guest runs, disk change line in DIR reports it for the drive
+ * selected in DOR until a seek steps the head with media in. Recalibrating a drive that is not installed
+ * fails to find track 0, which is how drivers detect it.
+ * Commands execute instantly when last parameter byte is written, sector data moves over DMA channel 2.
+ * Data commands run until EOT or DMA terminal count, whichever comes first.
+ * FORMAT TRACK and implied seeks are not supported. Data commands in non-DMA mode terminate abnormally.
+ */
+
+use vm;
+use dma;
+use config;
+use disk::{self, disk_image};
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::mem;
+
+// IO ports
+const FDC_BASE: u16             = 0x3F0;
+const FDC_SRA: u16              = 0x0;      // Status register A (read)
+const FDC_SRB: u16              = 0x1;      // Status register B (read)
+const FDC_DOR: u16              = 0x2;      // Digital output register
+const FDC_TDR: u16              = 0x3;      // Tape drive register
+const FDC_MSR: u16              = 0x4;      // Main status (read) / data rate select (write)
+const FDC_FIFO: u16             = 0x5;
+const FDC_DIR: u16              = 0x7;      // Digital input (read) / config control (write)
+
+const FDC_IRQ: u8               = 6;
+const FDC_DMA_CHANNEL: usize    = 2;
+const FDC_DRIVES: usize         = 4;
+const FDC_MAX_FLOPPIES: usize   = 2;        // Drives we install
+
+// DOR bits
+const DOR_DRIVE_MASK: u8        = 0x03;
+const DOR_NRESET: u8            = 0x04;
+const DOR_DMA_GATE: u8          = 0x08;
+const DOR_MOTOR_SHIFT: u8       = 4;
+
+// SRB bits (PS/2 mode)
+const SRB_RESERVED: u8          = 0xC0;
+const SRB_MOTOR_MASK: u8        = 0x03;
+const SRB_DRIVE_SEL0: u8        = 0x20;
+
+// DIR bits
+const DIR_DISK_CHANGE: u8       = 0x80;
+
+// DSR bits
+const DSR_SW_RESET: u8          = 0x80;
+
+// MSR bits
+const MSR_RQM: u8               = 0x80;     // Data register ready
+const MSR_DIO: u8               = 0x40;     // Data direction is controller to host
+const MSR_CB: u8                = 0x10;     // Command in progress
+
+// ST0 bits
+const ST0_DRIVE_HEAD_MASK: u8   = 0x07;
+const ST0_NOT_READY: u8         = 0x08;
+const ST0_EQUIPMENT_CHECK: u8   = 0x10;
+const ST0_SEEK_END: u8          = 0x20;
+const ST0_IC_ABNORMAL: u8       = 0x40;
+const ST0_IC_INVALID: u8        = 0x80;
+const ST0_IC_READY_CHANGE: u8   = 0xC0;
+
+// ST1 bits
+const ST1_NOT_WRITABLE: u8      = 0x02;
+const ST1_NO_DATA: u8           = 0x04;
+const ST1_OVERRUN: u8           = 0x10;
+
+// ST2 bits
+const ST2_WRONG_CYLINDER: u8    = 0x10;
+
+// ST3 bits
+const ST3_TWO_SIDED: u8         = 0x08;
+const ST3_TRACK0: u8            = 0x10;
+const ST3_READY: u8             = 0x20;
+
+// Commands
+const CMD_MASK: u8              = 0x1F;
+const CMD_MT: u8                = 0x80;     // Multitrack flag
+const CMD_SPECIFY: u8           = 0x03;
+const CMD_SENSE_DRIVE: u8       = 0x04;
+const CMD_WRITE_DATA: u8        = 0x05;
+const CMD_READ_DATA: u8         = 0x06;
+const CMD_RECALIBRATE: u8       = 0x07;
+const CMD_SENSE_INT: u8         = 0x08;
+const CMD_READ_ID: u8           = 0x0A;
+const CMD_SEEK: u8              = 0x0F;
+const CMD_VERSION: u8           = 0x10;
+const CMD_CONFIGURE: u8         = 0x13;
+
+const FDC_VERSION_82077: u8     = 0x90;
+
+// SPECIFY non-DMA mode bit
+const SPECIFY_ND: u8            = 0x01;
+
+// 1.44M geometry
+pub const FLOPPY_CYLINDERS: u8  = 80;
+pub const FLOPPY_HEADS: u8      = 2;
+pub const FLOPPY_SECTORS: u8    = 18;
+const FLOPPY_SECTOR_SIZE: usize = 512;
+const FLOPPY_SECTOR_CODE: u8    = 2;        // N value for 512 byte sectors
+pub const FLOPPY_IMAGE_SIZE: u64 =
+    FLOPPY_CYLINDERS as u64 * FLOPPY_HEADS as u64 * FLOPPY_SECTORS as u64 * FLOPPY_SECTOR_SIZE as u64;
+
+/**
+ * Floppy media geometry, 512 byte sectors
+ */
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct FloppyGeometry
+{
+    pub cylinders: u8,
+    pub heads: u8,
+    pub sectors: u8,
+}
+
+pub const FLOPPY_144M: FloppyGeometry = FloppyGeometry { cylinders: FLOPPY_CYLINDERS, heads: FLOPPY_HEADS, sectors: FLOPPY_SECTORS };
+pub const FLOPPY_120M: FloppyGeometry = FloppyGeometry { cylinders: 80, heads: 2, sectors: 15 };
+pub const FLOPPY_720K: FloppyGeometry = FloppyGeometry { cylinders: 80, heads: 2, sectors: 9 };
+
+impl FloppyGeometry
+{
+    pub fn image_size(&self) -> u64 {
+        self.cylinders as u64 * self.heads as u64 * self.sectors as u64 * FLOPPY_SECTOR_SIZE as u64
+    }
+
+    /**
+     * Geometry of a raw image by its size, None for unsupported formats
+     */
+    pub fn from_image_size(size: u64) -> Option<FloppyGeometry> {
+        [FLOPPY_144M, FLOPPY_120M, FLOPPY_720K].iter().find(|g| g.image_size() == size).map(|g| *g)
+    }
+
+    /*
+     * Image offset for sector address, None if address is outside of disk geometry
+     */
+    fn chs_to_offset(&self, c: u8, h: u8, r: u8) -> Option<u64> {
+        if c >= self.cylinders || h >= self.heads || r == 0 || r > self.sectors {
+            return None;
+        }
+
+        let lba = (c as u64 * self.heads as u64 + h as u64) * self.sectors as u64 + (r - 1) as u64;
+        Some(lba * FLOPPY_SECTOR_SIZE as u64)
+    }
+}
+
+/* Total command length with parameters, unknown commands are rejected after the first byte */
+fn command_length(cmd: u8) -> usize
+{
+    match cmd & CMD_MASK {
+        CMD_SPECIFY => 3,
+        CMD_SENSE_DRIVE => 2,
+        CMD_WRITE_DATA => 9,
+        CMD_READ_DATA => 9,
+        CMD_RECALIBRATE => 2,
+        CMD_SENSE_INT => 1,
+        CMD_READ_ID => 2,
+        CMD_SEEK => 3,
+        CMD_VERSION => 1,
+        CMD_CONFIGURE => 4,
+        _ => 1,
+    }
+}
+
+/*
+ * Installed floppy drive
+ */
+struct FloppyDrive
+{
+    image: Option<Box<disk_image>>,
+    geometry: FloppyGeometry,       // Geometry of inserted media
+    disk_changed: bool,             // Change line, media went in or out since head last stepped with media in
+}
+
+impl FloppyDrive
+{
+    fn new() -> FloppyDrive {
+        FloppyDrive {
+            image: None,
+            geometry: FLOPPY_144M,
+            disk_changed: true,
+        }
+    }
+
+    /* Drive with media inserted */
+    fn with_image(image: Box<disk_image>) -> Result<FloppyDrive, String> {
+        let mut drive = FloppyDrive::new();
+        try!(drive.insert(image));
+        Ok(drive)
+    }
+
+    fn has_media(&self) -> bool {
+        self.image.is_some()
+    }
+
+    /* Insert media, replacing what is in the drive */
+    fn insert(&mut self, image: Box<disk_image>) -> Result<(), String> {
+        let geometry = match FloppyGeometry::from_image_size(image.size()) {
+            Some(geometry) => geometry,
+            None => return Err(format!("{} bytes is not a 1.44M, 1.2M or 720K floppy image", image.size())),
+        };
+
+        self.eject();
+        self.image = Some(image);
+        self.geometry = geometry;
+        self.disk_changed = true;
+        Ok(())
+    }
+
+    fn eject(&mut self) -> Option<Box<disk_image>> {
+        let mut image = self.image.take();
+        if let Some(ref mut image) = image {
+            if let Err(err) = image.flush() {
+                error!("fdc: failed to flush ejected image: {}", err);
+            }
+            self.disk_changed = true;
+        }
+        image
+    }
+}
+
+struct FDC
+{
+    dor: u8,
+    cmd: Vec<u8>,                   // Command phase bytes collected so far
+    result: VecDeque<u8>,           // Result phase bytes left to read
+    cylinders: [u8; FDC_DRIVES],    // Present cylinder number for each drive
+    seek_status: Option<u8>,        // ST0 of completed seek, reported by SENSE INTERRUPT STATUS
+    reset_sense: usize,             // Number of drives left to sense after reset
+    irq: bool,                      // Interrupt request to deliver
+    non_dma: bool,                  // SPECIFY selected non-DMA mode
+    drives: Vec<FloppyDrive>,       // Installed drives from drive 0 up
+    dma: Box<dma::dma_channel>,
+}
+
+impl FDC
+{
+    fn new(drives: Vec<FloppyDrive>, dma: Box<dma::dma_channel>) -> FDC {
+        assert!(drives.len() <= FDC_MAX_FLOPPIES);
+        FDC {
+            dor: 0,
+            cmd: Vec::new(),
+            result: VecDeque::new(),
+            cylinders: [0; FDC_DRIVES],
+            seek_status: None,
+            reset_sense: 0,
+            irq: false,
+            non_dma: false,
+            drives: drives,
+            dma: dma,
+        }
+    }
+
+    fn raise_irq(&mut self) {
+        if self.dor & DOR_DMA_GATE != 0 {
+            self.irq = true;
+        }
+    }
+
+    /**
+     * Take pending interrupt request
+     */
+    fn take_irq(&mut self) -> bool {
+        let irq = self.irq;
+        self.irq = false;
+        irq
+    }
+
+    /*
+     * Controller reset aborts current command.
+     * After reset every driver expects an interrupt and then senses interrupt status of each of 4 drives.
+     */
+    fn reset(&mut self) {
+        self.cmd.clear();
+        self.result.clear();
+        self.seek_status = None;
+        self.reset_sense = FDC_DRIVES;
+        self.raise_irq();
+    }
+
+    fn is_in_reset(&self) -> bool {
+        self.dor & DOR_NRESET == 0
+    }
+
+    fn read_msr(&self) -> u8 {
+        if self.is_in_reset() {
+            0
+        } else if !self.result.is_empty() {
+            MSR_RQM | MSR_DIO | MSR_CB
+        } else if !self.cmd.is_empty() {
+            MSR_RQM | MSR_CB
+        } else {
+            MSR_RQM
+        }
+    }
+
+    fn write_dor(&mut self, val: u8) {
+        let leaving_reset = self.is_in_reset() && (val & DOR_NRESET) != 0;
+        self.dor = val;
+
+        if leaving_reset {
+            self.reset();
+        }
+    }
+
+    fn read_fifo(&mut self) -> u8 {
+        match self.result.pop_front() {
+            Some(val) => val,
+            None => {
+                debug!("fdc: FIFO read outside of result phase");
+                0xFF
+            }
+        }
+    }
+
+    fn write_fifo(&mut self, val: u8) {
+        if self.is_in_reset() {
+            return;
+        }
+
+        if !self.result.is_empty() {
+            debug!("fdc: FIFO write in result phase ignored");
+            return;
+        }
+
+        self.cmd.push(val);
+        if self.cmd.len() == command_length(self.cmd[0]) {
+            self.exec_command();
+            self.cmd.clear();
+        }
+    }
+
+    fn has_media(&self, drive: usize) -> bool {
+        self.drives.get(drive).map_or(false, |d| d.has_media())
+    }
+
+    fn selected_drive(&self) -> usize {
+        (self.dor & DOR_DRIVE_MASK) as usize
+    }
+
+    fn read_srb(&self) -> u8 {
+        let drive_sel = if self.selected_drive() & 1 != 0 { SRB_DRIVE_SEL0 } else { 0 };
+        SRB_RESERVED | drive_sel | ((self.dor >> DOR_MOTOR_SHIFT) & SRB_MOTOR_MASK)
+    }
+
+    /* Disk change line of selected drive */
+    fn read_dir(&self) -> u8 {
+        match self.drives.get(self.selected_drive()) {
+            Some(drive) if drive.disk_changed => DIR_DISK_CHANGE,
+            _ => 0,
+        }
+    }
+
+    /* Host media change */
+    fn insert(&mut self, drive: usize, image: Box<disk_image>) -> Result<(), String> {
+        match self.drives.get_mut(drive) {
+            Some(d) => d.insert(image),
+            None => Err(format!("no floppy drive {}", drive)),
+        }
+    }
+
+    fn eject(&mut self, drive: usize) -> Option<Box<disk_image>> {
+        self.drives.get_mut(drive).and_then(|d| d.eject())
+    }
+
+    fn exec_command(&mut self) {
+        let cmd = self.cmd.clone();
+        debug!("fdc: command {:?}", cmd);
+
+        match cmd[0] & CMD_MASK {
+            CMD_SPECIFY => {
+                self.non_dma = cmd[2] & SPECIFY_ND != 0;
+                if self.non_dma {
+                    warn!("fdc: non-DMA mode is not supported");
+                }
+            },
+
+            CMD_SENSE_DRIVE => {
+                /* Drive that is not installed asserts no status lines at all */
+                let drive = (cmd[1] & DOR_DRIVE_MASK) as usize;
+                let mut st3 = cmd[1] & ST0_DRIVE_HEAD_MASK;
+                if drive < self.drives.len() {
+                    st3 |= ST3_TWO_SIDED;
+                    if self.has_media(drive) {
+                        st3 |= ST3_READY;
+                    }
+                    if self.cylinders[drive] == 0 {
+                        st3 |= ST3_TRACK0;
+                    }
+                }
+                self.result.push_back(st3);
+            },
+
+            CMD_READ_DATA => self.transfer_data(&cmd, dma::DmaDirection::ToMemory),
+            CMD_WRITE_DATA => self.transfer_data(&cmd, dma::DmaDirection::FromMemory),
+
+            CMD_RECALIBRATE => self.recalibrate(cmd[1]),
+            CMD_SEEK => self.seek(cmd[1], cmd[2]),
+
+            CMD_SENSE_INT => {
+                if self.reset_sense > 0 {
+                    let drive = FDC_DRIVES - self.reset_sense;
+                    self.reset_sense -= 1;
+                    self.result.push_back(ST0_IC_READY_CHANGE | drive as u8);
+                    self.result.push_back(self.cylinders[drive]);
+                } else if let Some(st0) = self.seek_status.take() {
+                    self.result.push_back(st0);
+                    self.result.push_back(self.cylinders[(st0 & DOR_DRIVE_MASK) as usize]);
+                } else {
+                    self.result.push_back(ST0_IC_INVALID);
+                }
+            },
+
+            CMD_READ_ID => {
+                let drive = (cmd[1] & DOR_DRIVE_MASK) as usize;
+                let mut st0 = cmd[1] & ST0_DRIVE_HEAD_MASK;
+                if !self.has_media(drive) {
+                    st0 |= ST0_IC_ABNORMAL | ST0_NOT_READY;
+                }
+
+                let cylinder = self.cylinders[drive];
+                self.push_rw_result(st0, 0, 0, cylinder, (cmd[1] >> 2) & 1, 1);
+            },
+
+            CMD_VERSION => self.result.push_back(FDC_VERSION_82077),
+
+            CMD_CONFIGURE => {},
+
+            _ => {
+                debug!("fdc: unsupported command {:x}", cmd[0]);
+                self.result.push_back(ST0_IC_INVALID);
+            }
+        }
+    }
+
+    /* Seek and recalibrate complete instantly and report via SENSE INTERRUPT STATUS */
+    fn seek(&mut self, drive_head: u8, cylinder: u8) {
+        let drive = (drive_head & DOR_DRIVE_MASK) as usize;
+        self.cylinders[drive] = cylinder;
+        if let Some(d) = self.drives.get_mut(drive) {
+            if d.has_media() {
+                d.disk_changed = false;
+            }
+        }
+
+        self.seek_status = Some(ST0_SEEK_END | (drive_head & ST0_DRIVE_HEAD_MASK));
+        self.raise_irq();
+    }
+
+    /* Missing drive never signals track 0, controller gives up stepping with equipment check */
+    fn recalibrate(&mut self, drive_head: u8) {
+        let drive = (drive_head & DOR_DRIVE_MASK) as usize;
+        if drive < self.drives.len() {
+            self.seek(drive_head, 0);
+            return;
+        }
+
+        self.seek_status = Some(ST0_IC_ABNORMAL | ST0_SEEK_END | ST0_EQUIPMENT_CHECK | (drive_head & ST0_DRIVE_HEAD_MASK));
+        self.raise_irq();
+    }
+
+    fn push_rw_result(&mut self, st0: u8, st1: u8, st2: u8, c: u8, h: u8, r: u8) {
+        self.result.extend(&[st0, st1, st2, c, h, r, FLOPPY_SECTOR_CODE]);
+        self.raise_irq();
+    }
+
+    /*
+     * READ DATA / WRITE DATA
+     * Transfers sectors from R up to EOT (continuing on head 1 for multitrack commands)
+     * until DMA controller reaches terminal count or stops accepting data.
+     * Sector cut short by terminal count still completes, result reports the sector following it.
+     */
+    fn transfer_data(&mut self, cmd: &[u8], dir: dma::DmaDirection) {
+        let multitrack = cmd[0] & CMD_MT != 0;
+        let drive = (cmd[1] & DOR_DRIVE_MASK) as usize;
+        let (mut c, mut h, mut r) = (cmd[2], cmd[3], cmd[4]);
+        let n = cmd[5];
+        let eot = cmd[6];
+
+        let mut st0 = cmd[1] & ST0_DRIVE_HEAD_MASK;
+        let mut st1 = 0;
+        let mut st2 = 0;
+
+        /* Nobody would move data through FIFO, fail as if host never serviced it */
+        if self.non_dma {
+            self.push_rw_result(st0 | ST0_IC_ABNORMAL, ST1_OVERRUN, 0, c, h, r);
+            return;
+        }
+
+        if !self.has_media(drive) {
+            self.push_rw_result(st0 | ST0_IC_ABNORMAL | ST0_NOT_READY, 0, 0, c, h, r);
+            return;
+        }
+
+        if c != self.cylinders[drive] {
+            self.push_rw_result(st0 | ST0_IC_ABNORMAL, ST1_NO_DATA, ST2_WRONG_CYLINDER, c, h, r);
+            return;
+        }
+
+        let geometry = self.drives[drive].geometry;
+        let mut sectors = 0;
+        loop {
+            let offset = match geometry.chs_to_offset(c, h, r) {
+                Some(offset) if n == FLOPPY_SECTOR_CODE => offset,
+                _ => {
+                    st0 |= ST0_IC_ABNORMAL;
+                    st1 |= ST1_NO_DATA;
+                    break;
+                }
+            };
+
+            let mut buf = [0u8; FLOPPY_SECTOR_SIZE];
+            let image = self.drives[drive].image.as_mut().unwrap();
+            let xfer = if dir == dma::DmaDirection::ToMemory {
+                if let Err(err) = image.read_at(offset, &mut buf) {
+                    error!("fdc: image read failed: {}", err);
+                    st0 |= ST0_IC_ABNORMAL;
+                    st1 |= ST1_NO_DATA;
+                    break;
+                }
+
+                self.dma.transfer(dir, &mut buf)
+            } else {
+                /* Short transfer leaves the rest of the sector zero filled, verify leaves media alone */
+                let xfer = self.dma.transfer(dir, &mut buf);
+                if xfer.count != 0 && !xfer.verify {
+                    if let Err(err) = image.write_at(offset, &buf) {
+                        error!("fdc: image write failed: {}", err);
+                        st0 |= ST0_IC_ABNORMAL;
+                        st1 |= ST1_NOT_WRITABLE;
+                        break;
+                    }
+                }
+
+                xfer
+            };
+
+            if xfer.count == 0 {
+                /* DMA didn't service us at all */
+                if sectors == 0 {
+                    st0 |= ST0_IC_ABNORMAL;
+                    st1 |= ST1_OVERRUN;
+                }
+                break;
+            }
+
+            sectors += 1;
+
+            /* Advance to next sector, result phase reports sector following the last one */
+            let mut end = false;
+            if r == eot {
+                r = 1;
+                if multitrack && h == 0 {
+                    h = 1;
+                } else {
+                    h = 0;
+                    c = c.wrapping_add(1);
+                    end = true;
+                }
+            } else {
+                r += 1;
+            }
+
+            if end || xfer.tc || xfer.count < FLOPPY_SECTOR_SIZE {
+                break;
+            }
+        }
+
+        self.push_rw_result(st0, st1, st2, c, h, r);
+    }
+
+    fn read_port(&mut self, offset: u16) -> u8 {
+        match offset {
+            FDC_SRA | FDC_TDR => 0,
+            FDC_SRB => self.read_srb(),
+            FDC_DOR => self.dor,
+            FDC_MSR => self.read_msr(),
+            FDC_FIFO => self.read_fifo(),
+            FDC_DIR => self.read_dir(),
+            _ => 0xFF,
+        }
+    }
+
+    fn write_port(&mut self, offset: u16, val: u8) {
+        match offset {
+            FDC_DOR => self.write_dor(val),
+            FDC_MSR => {
+                if val & DSR_SW_RESET != 0 {
+                    self.reset();
+                }
+            },
+            FDC_FIFO => self.write_fifo(val),
+            FDC_DIR => {}, // Data rate, we don't care
+            _ => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod fdc_test
+{
+    use super::*;
+
+    /* DMA channel that sinks/sources data to a buffer until terminal count */
+    struct TestDma
+    {
+        mem: Rc<RefCell<Vec<u8>>>,
+        limit: usize,
+    }
+
+    impl dma::dma_channel for TestDma
+    {
+        fn transfer(&self, dir: dma::DmaDirection, buf: &mut [u8]) -> dma::DmaTransfer {
+            let mut mem = self.mem.borrow_mut();
+            match dir {
+                dma::DmaDirection::ToMemory => {
+                    let len = ::std::cmp::min(buf.len(), self.limit - mem.len());
+                    mem.extend_from_slice(&buf[..len]);
+                    dma::DmaTransfer { count: len, tc: mem.len() == self.limit, verify: false }
+                },
+                dma::DmaDirection::FromMemory => {
+                    let len = ::std::cmp::min(buf.len(), mem.len());
+                    buf[..len].copy_from_slice(&mem[..len]);
+                    mem.drain(..len);
+                    dma::DmaTransfer { count: len, tc: mem.is_empty(), verify: false }
+                },
+            }
+        }
+    }
+
+    /* Program channel 2 through controller ports as floppy drivers do, count is bytes - 1 */
+    fn setup_dma(dma: &dma::TestController, addr: u32, count: u16, mode: u8) {
+        dma.write_port(0x0A, 0x04 | 2);
+        dma.write_port(0x0C, 0);
+        dma.write_port(0x0B, mode | 2);
+        dma.write_port(0x04, addr as u8);
+        dma.write_port(0x04, (addr >> 8) as u8);
+        dma.write_port(0x81, (addr >> 16) as u8);
+        dma.write_port(0x0C, 0);
+        dma.write_port(0x05, count as u8);
+        dma.write_port(0x05, (count >> 8) as u8);
+        dma.write_port(0x0A, 2);
+    }
+
+    fn sector_byte(lba: usize, i: usize) -> u8 {
+        (lba * 7 + i) as u8
+    }
+
+    /* Formatted image where every sector has a distinct pattern */
+    fn make_image() -> disk::MemImage {
+        let mut img = disk::MemImage::new(FLOPPY_IMAGE_SIZE as usize);
+        for (i, b) in img.data.iter_mut().enumerate() {
+            *b = sector_byte(i / FLOPPY_SECTOR_SIZE, i % FLOPPY_SECTOR_SIZE);
+        }
+        img
+    }
+
+    /* Drive B media, 720K with inverted pattern */
+    fn make_720k_image() -> disk::MemImage {
+        let mut img = disk::MemImage::new(FLOPPY_720K.image_size() as usize);
+        for (i, b) in img.data.iter_mut().enumerate() {
+            *b = !sector_byte(i / FLOPPY_SECTOR_SIZE, i % FLOPPY_SECTOR_SIZE);
+        }
+        img
+    }
+
+    fn make_fdc_with(drives: Vec<FloppyDrive>, limit: usize) -> (FDC, Rc<RefCell<Vec<u8>>>) {
+        let mem = Rc::new(RefCell::new(Vec::new()));
+        let dma = TestDma { mem: mem.clone(), limit: limit };
+        (FDC::new(drives, Box::new(dma)), mem)
+    }
+
+    fn make_fdc(limit: usize) -> (FDC, Rc<RefCell<Vec<u8>>>) {
+        make_fdc_with(vec![FloppyDrive::with_image(Box::new(make_image())).unwrap()], limit)
+    }
+
+    fn command(fdc: &mut FDC, bytes: &[u8]) -> Vec<u8> {
+        for b in bytes {
+            assert!(fdc.read_port(FDC_MSR) & (MSR_RQM | MSR_DIO) == MSR_RQM);
+            fdc.write_port(FDC_FIFO, *b);
+        }
+
+        let mut result = Vec::new();
+        while fdc.read_port(FDC_MSR) & MSR_DIO != 0 {
+            result.push(fdc.read_port(FDC_FIFO));
+        }
+
+        assert!(fdc.read_port(FDC_MSR) == MSR_RQM);
+        result
+    }
+
+    /* Reset controller and sense all drives as drivers do */
+    fn reset(fdc: &mut FDC) {
+        fdc.write_port(FDC_DOR, 0);
+        assert!(fdc.read_port(FDC_MSR) == 0);
+        fdc.write_port(FDC_DOR, DOR_NRESET | DOR_DMA_GATE);
+        assert!(fdc.take_irq());
+
+        for drive in 0..4 {
+            assert!(command(fdc, &[CMD_SENSE_INT]) == vec![ST0_IC_READY_CHANGE | drive, 0]);
+        }
+    }
+
+    #[test] fn reset_sense() {
+        let (mut fdc, _) = make_fdc(0);
+        reset(&mut fdc);
+
+        /* No more interrupts to sense */
+        assert!(command(&mut fdc, &[CMD_SENSE_INT]) == vec![ST0_IC_INVALID]);
+
+        /* Without DMA gate reset doesn't interrupt */
+        fdc.write_port(FDC_DOR, 0);
+        fdc.write_port(FDC_DOR, DOR_NRESET);
+        assert!(!fdc.take_irq());
+    }
+
+    #[test] fn seek_and_recalibrate() {
+        let (mut fdc, _) = make_fdc(0);
+        reset(&mut fdc);
+
+        assert!(command(&mut fdc, &[CMD_SEEK, 0x04, 10]).is_empty());
+        assert!(fdc.take_irq());
+        assert!(command(&mut fdc, &[CMD_SENSE_INT]) == vec![ST0_SEEK_END | 0x04, 10]);
+
+        assert!(command(&mut fdc, &[CMD_RECALIBRATE, 0]).is_empty());
+        assert!(fdc.take_irq());
+        assert!(command(&mut fdc, &[CMD_SENSE_INT]) == vec![ST0_SEEK_END, 0]);
+        assert!(command(&mut fdc, &[CMD_SENSE_DRIVE, 0]) == vec![ST3_READY | ST3_TWO_SIDED | ST3_TRACK0]);
+    }
+
+    /*
+     * Multitrack read from sector 17 head 0 crosses to head 1, DMA count stops it after 3 sectors
+     */
+    #[test] fn read_data() {
+        let (mut fdc, mem) = make_fdc(3 * FLOPPY_SECTOR_SIZE);
+        reset(&mut fdc);
+
+        assert!(command(&mut fdc, &[CMD_SPECIFY, 0xDF, 0x02]).is_empty());
+        command(&mut fdc, &[CMD_SEEK, 0, 1]);
+        assert!(command(&mut fdc, &[CMD_SENSE_INT]) == vec![ST0_SEEK_END, 1]);
+
+        let result = command(&mut fdc, &[CMD_READ_DATA | CMD_MT | 0x40, 0, 1, 0, 17, 2, 18, 0x1B, 0xFF]);
+        assert!(fdc.take_irq());
+        assert!(result == vec![0, 0, 0, 1, 1, 2, 2]);
+
+        let offset = FLOPPY_144M.chs_to_offset(1, 0, 17).unwrap() as usize;
+        let mut image = make_image();
+        let mut expected = vec![0u8; 3 * FLOPPY_SECTOR_SIZE];
+        image.read_at(offset as u64, &mut expected).unwrap();
+        assert!(*mem.borrow() == expected);
+        assert!(expected[0] == sector_byte(offset / FLOPPY_SECTOR_SIZE, 0));
+    }
+
+    #[test] fn write_data() {
+        let (mut fdc, mem) = make_fdc(0);
+        reset(&mut fdc);
+
+        *mem.borrow_mut() = vec![0x5A; FLOPPY_SECTOR_SIZE];
+        let result = command(&mut fdc, &[CMD_WRITE_DATA | 0x40, 0, 0, 0, 18, 2, 18, 0x1B, 0xFF]);
+        assert!(result == vec![0, 0, 0, 1, 0, 1, 2]);
+
+        let mut buf = vec![0u8; FLOPPY_SECTOR_SIZE];
+        fdc.drives[0].image.as_mut().unwrap().read_at(FLOPPY_144M.chs_to_offset(0, 0, 18).unwrap(), &mut buf).unwrap();
+        assert!(buf == vec![0x5A; FLOPPY_SECTOR_SIZE]);
+    }
+
+    /*
+     * Real 8237 channel 2 programmed for 2.5 sectors while command asks for a whole track.
+     * Terminal count ends the command mid-track, partially transferred sector still counts.
+     */
+    #[test] fn dma_terminal_count() {
+        let dma = dma::TestController::new();
+        let mem = Rc::new(dma::TestMemory::new(0x30000, 0));
+        let drives = vec![FloppyDrive::with_image(Box::new(make_image())).unwrap()];
+        let mut fdc = FDC::new(drives, dma.channel(mem.clone(), FDC_DMA_CHANNEL));
+        reset(&mut fdc);
+
+        /* Single mode write transfer into page 2 */
+        setup_dma(&dma, 0x21000, 0x4FF, 0x44);
+        let result = command(&mut fdc, &[CMD_READ_DATA | CMD_MT | 0x40, 0, 0, 0, 3, 2, 18, 0x1B, 0xFF]);
+        assert!(result == vec![0, 0, 0, 0, 0, 6, 2]);
+        assert!(fdc.take_irq());
+
+        let offset = FLOPPY_144M.chs_to_offset(0, 0, 3).unwrap() as usize;
+        let mut expected = vec![0u8; 0x500];
+        make_image().read_at(offset as u64, &mut expected).unwrap();
+        assert!(&mem.mem.borrow()[0x21000..0x21500] == &expected[..]);
+        assert!(mem.mem.borrow()[0x21500] == 0);
+
+        /* TC bit for channel 2 is set and channel got masked, next command gets no data */
+        assert!(dma.read_port(0x08) & 0x04 != 0);
+        let result = command(&mut fdc, &[CMD_READ_DATA | 0x40, 0, 0, 0, 1, 2, 18, 0x1B, 0xFF]);
+        assert!(result == vec![ST0_IC_ABNORMAL, ST1_OVERRUN, 0, 0, 0, 1, 2]);
+
+        /* Autoinit reloads the channel, TC still ends each command after 1 sector */
+        setup_dma(&dma, 0x22000, 0x1FF, 0x54);
+        for r in 1..3 {
+            let result = command(&mut fdc, &[CMD_READ_DATA | 0x40, 0, 0, 0, r, 2, 18, 0x1B, 0xFF]);
+            assert!(result == vec![0, 0, 0, 0, 0, r + 1, 2]);
+        }
+
+        /* Verify mode runs write command to TC without touching the image */
+        setup_dma(&dma, 0x22000, 0x1FF, 0x40);
+        let result = command(&mut fdc, &[CMD_WRITE_DATA | 0x40, 0, 0, 0, 1, 2, 18, 0x1B, 0xFF]);
+        assert!(result == vec![0, 0, 0, 0, 0, 2, 2]);
+        let mut buf = vec![0u8; FLOPPY_SECTOR_SIZE];
+        fdc.drives[0].image.as_mut().unwrap().read_at(0, &mut buf).unwrap();
+        assert!(buf[1] == sector_byte(0, 1));
+    }
+
+    #[test] fn non_dma_mode() {
+        let (mut fdc, mem) = make_fdc(FLOPPY_SECTOR_SIZE);
+        reset(&mut fdc);
+
+        assert!(command(&mut fdc, &[CMD_SPECIFY, 0xDF, 0x02 | SPECIFY_ND]).is_empty());
+        let result = command(&mut fdc, &[CMD_READ_DATA | 0x40, 0, 0, 0, 1, 2, 18, 0x1B, 0xFF]);
+        assert!(result == vec![ST0_IC_ABNORMAL, ST1_OVERRUN, 0, 0, 0, 1, 2]);
+        assert!(fdc.take_irq());
+        assert!(mem.borrow().is_empty());
+
+        /* Back to DMA mode */
+        assert!(command(&mut fdc, &[CMD_SPECIFY, 0xDF, 0x02]).is_empty());
+        let result = command(&mut fdc, &[CMD_READ_DATA | 0x40, 0, 0, 0, 1, 2, 18, 0x1B, 0xFF]);
+        assert!(result == vec![0, 0, 0, 0, 0, 2, 2]);
+    }
+
+    #[test] fn bad_requests() {
+        let (mut fdc, _) = make_fdc(FLOPPY_SECTOR_SIZE);
+        reset(&mut fdc);
+
+        /* Sector past end of track */
+        let result = command(&mut fdc, &[CMD_READ_DATA, 0, 0, 0, 19, 2, 19, 0x1B, 0xFF]);
+        assert!(result[0] == ST0_IC_ABNORMAL && result[1] == ST1_NO_DATA);
+
+        /* Head is not over requested cylinder */
+        let result = command(&mut fdc, &[CMD_READ_DATA, 0, 5, 0, 1, 2, 18, 0x1B, 0xFF]);
+        assert!(result[0] == ST0_IC_ABNORMAL && result[2] == ST2_WRONG_CYLINDER);
+
+        /* No media in drive 1 */
+        let result = command(&mut fdc, &[CMD_READ_DATA, 1, 0, 0, 1, 2, 18, 0x1B, 0xFF]);
+        assert!(result[0] == ST0_IC_ABNORMAL | ST0_NOT_READY | 1);
+
+        assert!(command(&mut fdc, &[0x1F]) == vec![ST0_IC_INVALID]);
+    }
+
+    /*
+     * 1.44M in drive A and 720K in drive B, each read within its own geometry
+     */
+    #[test] fn two_drives() {
+        let drives = vec![
+            FloppyDrive::with_image(Box::new(make_image())).unwrap(),
+            FloppyDrive::with_image(Box::new(make_720k_image())).unwrap(),
+        ];
+        let (mut fdc, mem) = make_fdc_with(drives, 2 * FLOPPY_SECTOR_SIZE);
+        reset(&mut fdc);
+        assert!(fdc.drives[1].geometry == FLOPPY_720K);
+
+        /* Select B with its motor on */
+        fdc.write_port(FDC_DOR, DOR_NRESET | DOR_DMA_GATE | (0x02 << DOR_MOTOR_SHIFT) | 1);
+        assert!(fdc.read_port(FDC_SRB) == SRB_RESERVED | SRB_DRIVE_SEL0 | 0x02);
+        assert!(command(&mut fdc, &[CMD_SENSE_DRIVE, 1]) == vec![ST3_READY | ST3_TWO_SIDED | ST3_TRACK0 | 1]);
+
+        /* Heads are positioned per drive */
+        command(&mut fdc, &[CMD_SEEK, 1, 2]);
+        assert!(command(&mut fdc, &[CMD_SENSE_INT]) == vec![ST0_SEEK_END | 1, 2]);
+        command(&mut fdc, &[CMD_SEEK, 0, 1]);
+        assert!(command(&mut fdc, &[CMD_SENSE_INT]) == vec![ST0_SEEK_END, 1]);
+
+        /* Sector 9 ends a 720K track, multitrack read goes on to head 1 */
+        let result = command(&mut fdc, &[CMD_READ_DATA | CMD_MT | 0x40, 1, 2, 0, 9, 2, 9, 0x1B, 0xFF]);
+        assert!(result == vec![1, 0, 0, 2, 1, 2, 2]);
+        let offset = FLOPPY_720K.chs_to_offset(2, 0, 9).unwrap() as usize;
+        let mut expected = vec![0u8; 2 * FLOPPY_SECTOR_SIZE];
+        make_720k_image().read_at(offset as u64, &mut expected).unwrap();
+        assert!(*mem.borrow() == expected);
+        assert!(expected[0] == !sector_byte(2 * 18 + 8, 0));
+
+        /* Sector 10 exists only on drive A */
+        mem.borrow_mut().clear();
+        let result = command(&mut fdc, &[CMD_READ_DATA | 0x40, 1, 2, 0, 10, 2, 10, 0x1B, 0xFF]);
+        assert!(result[0] == ST0_IC_ABNORMAL | 1 && result[1] == ST1_NO_DATA);
+        let result = command(&mut fdc, &[CMD_READ_DATA | 0x40, 0, 1, 0, 10, 2, 18, 0x1B, 0xFF]);
+        assert!(result == vec![0, 0, 0, 1, 0, 12, 2]);
+        assert!(mem.borrow()[0] == sector_byte(18 * 2 + 9, 0));
+    }
+
+    #[test] fn disk_change() {
+        let drives = vec![FloppyDrive::with_image(Box::new(make_image())).unwrap(), FloppyDrive::new()];
+        let (mut fdc, _) = make_fdc_with(drives, FLOPPY_SECTOR_SIZE);
+        reset(&mut fdc);
+
+        /* Power on media counts as changed until head steps */
+        assert!(fdc.read_port(FDC_DIR) == DIR_DISK_CHANGE);
+        command(&mut fdc, &[CMD_SEEK, 0, 1]);
+        command(&mut fdc, &[CMD_SENSE_INT]);
+        assert!(fdc.read_port(FDC_DIR) == 0);
+
+        /* Empty drive B keeps its line set, seeking does not help */
+        fdc.write_port(FDC_DOR, DOR_NRESET | DOR_DMA_GATE | 1);
+        assert!(fdc.read_port(FDC_DIR) == DIR_DISK_CHANGE);
+        command(&mut fdc, &[CMD_RECALIBRATE, 1]);
+        assert!(command(&mut fdc, &[CMD_SENSE_INT]) == vec![ST0_SEEK_END | 1, 0]);
+        assert!(fdc.read_port(FDC_DIR) == DIR_DISK_CHANGE);
+
+        /* Swap A: line goes up for A only, data comes from new media */
+        fdc.insert(1, Box::new(make_720k_image())).unwrap();
+        command(&mut fdc, &[CMD_SEEK, 1, 1]);
+        command(&mut fdc, &[CMD_SENSE_INT]);
+        assert!(fdc.read_port(FDC_DIR) == 0);
+        assert!(fdc.insert(0, Box::new(make_720k_image())).is_ok());
+        assert!(fdc.read_port(FDC_DIR) == 0);
+        fdc.write_port(FDC_DOR, DOR_NRESET | DOR_DMA_GATE);
+        assert!(fdc.read_port(FDC_DIR) == DIR_DISK_CHANGE);
+
+        let result = command(&mut fdc, &[CMD_READ_DATA | 0x40, 0, 1, 0, 1, 2, 9, 0x1B, 0xFF]);
+        assert!(result == vec![0, 0, 0, 1, 0, 2, 2]);
+        command(&mut fdc, &[CMD_SEEK, 0, 0]);
+        command(&mut fdc, &[CMD_SENSE_INT]);
+        assert!(fdc.read_port(FDC_DIR) == 0);
+
+        /* Eject leaves drive not ready with line set */
+        assert!(fdc.eject(0).is_some());
+        assert!(fdc.eject(0).is_none());
+        assert!(fdc.read_port(FDC_DIR) == DIR_DISK_CHANGE);
+        let result = command(&mut fdc, &[CMD_READ_DATA | 0x40, 0, 0, 0, 1, 2, 18, 0x1B, 0xFF]);
+        assert!(result[0] == ST0_IC_ABNORMAL | ST0_NOT_READY);
+        assert!(command(&mut fdc, &[CMD_SENSE_DRIVE, 0]) == vec![ST3_TWO_SIDED | ST3_TRACK0]);
+
+        /* Bad media size and missing drive are refused */
+        assert!(fdc.insert(0, Box::new(disk::MemImage::new(1000 * 1024))).is_err());
+        assert!(fdc.insert(2, Box::new(make_image())).is_err());
+        assert!(!fdc.has_media(0));
+    }
+
+    #[test] fn missing_drive() {
+        let (mut fdc, _) = make_fdc(0);
+        reset(&mut fdc);
+
+        /* B is not installed: no track 0 after recalibrate, no status lines */
+        assert!(command(&mut fdc, &[CMD_RECALIBRATE, 1]).is_empty());
+        assert!(fdc.take_irq());
+        assert!(command(&mut fdc, &[CMD_SENSE_INT]) == vec![ST0_IC_ABNORMAL | ST0_SEEK_END | ST0_EQUIPMENT_CHECK | 1, 0]);
+        assert!(command(&mut fdc, &[CMD_SENSE_DRIVE, 1]) == vec![1]);
+        fdc.write_port(FDC_DOR, DOR_NRESET | DOR_DMA_GATE | 1);
+        assert!(fdc.read_port(FDC_DIR) == 0);
+
+        /* A is fine */
+        assert!(command(&mut fdc, &[CMD_RECALIBRATE, 0]).is_empty());
+        assert!(command(&mut fdc, &[CMD_SENSE_INT]) == vec![ST0_SEEK_END, 0]);
+    }
+
+    #[test] fn read_id_and_version() {
+        let (mut fdc, _) = make_fdc(0);
+        reset(&mut fdc);
+
+        assert!(command(&mut fdc, &[CMD_VERSION]) == vec![FDC_VERSION_82077]);
+        assert!(command(&mut fdc, &[CMD_READ_ID | 0x40, 0x04]) == vec![0x04, 0, 0, 0, 1, 1, 2]);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct FDCDev
+{
+    fdc: RefCell<FDC>,
+}
+
+impl FDCDev
+{
+    fn service(&self) {
+        if self.fdc.borrow_mut().take_irq() {
+            vm::assert_irq(FDC_IRQ);
+        }
+    }
+}
+
+#[allow(unused_variables)]
+impl vm::io_handler for FDCDev
+{
+    fn io_read(&self, port: u16, size: u8) -> vm::IoOperandType
+    {
+        let val = self.fdc.borrow_mut().read_port(port - FDC_BASE);
+        vm::IoOperandType::byte(val)
+    }
+
+    fn io_write(&self, port: u16, data: vm::IoOperandType)
+    {
+        self.fdc.borrow_mut().write_port(port - FDC_BASE, data.unwrap_byte());
+        self.service();
+    }
+}
+
+impl vm::reset_handler for FDCDev
+{
+    fn reset(&self)
+    {
+        let mut fdc = self.fdc.borrow_mut();
+        let drives = mem::replace(&mut fdc.drives, Vec::new());
+        *fdc = FDC::new(drives, dma::get_channel(FDC_DMA_CHANNEL));
+    }
+}
+
+static mut FDC_DEV: Option<*const FDCDev> = None;
+
+fn get_fdc() -> Option<&'static FDCDev>
+{
+    unsafe {
+        match FDC_DEV {
+            Some(dev) => Some(mem::transmute(dev)),
+            None => None,
+        }
+    }
+}
+
+/* Change media with VM paused, guest sees it through disk change line */
+fn change_media<F: FnOnce(&mut FDC) -> Result<(), String>>(change: F) -> Result<(), String>
+{
+    let dev = match get_fdc() {
+        Some(dev) => dev,
+        None => return Err(String::from("no floppy controller")),
+    };
+
+    let _pause = vm::pause();
+    let mut fdc = dev.fdc.borrow_mut();
+    change(&mut fdc)
+}
+
+fn open_image(path: &str) -> Result<Box<disk_image>, String>
+{
+    match disk::FileImage::open(path) {
+        Ok(image) => Ok(Box::new(image)),
+        Err(err) => Err(format!("failed to open {}: {}", path, err)),
+    }
+}
+
+/**
+ * Insert floppy image into empty drive, 0 is A: and 1 is B:. Must not be called from vcpu thread.
+ */
+pub fn attach_floppy(drive: usize, path: &str) -> Result<(), String>
+{
+    let image = try!(open_image(path));
+    change_media(|fdc| {
+        if fdc.has_media(drive) {
+            return Err(format!("floppy drive {} is not empty", drive));
+        }
+        fdc.insert(drive, image)
+    })
+}
+
+/**
+ * Eject floppy image from drive, image file is flushed and closed. Must not be called from vcpu thread.
+ */
+pub fn detach_floppy(drive: usize) -> Result<(), String>
+{
+    change_media(|fdc| {
+        match fdc.eject(drive) {
+            Some(_) => Ok(()),
+            None => Err(format!("floppy drive {} is empty", drive)),
+        }
+    })
+}
+
+/**
+ * Replace media in drive with another image, drive may be empty. Must not be called from vcpu thread.
+ */
+pub fn swap_floppy(drive: usize, path: &str) -> Result<(), String>
+{
+    let image = try!(open_image(path));
+    change_media(|fdc| fdc.insert(drive, image))
+}
+
+fn open_drive(config: &config::VmConfig, path: &str, overlay: bool) -> FloppyDrive
+{
+    let image: Box<disk_image> = if overlay {
+        match disk::open_floppy(config, path) {
+            Ok(image) => image,
+            Err(err) => panic!("fdc: failed to open {}: {}", path, err),
+        }
+    } else {
+        match open_image(path) {
+            Ok(image) => image,
+            Err(err) => panic!("fdc: {}", err),
+        }
+    };
+
+    match FloppyDrive::with_image(image) {
+        Ok(drive) => drive,
+        Err(err) => panic!("fdc: {}: {}", path, err),
+    }
+}
+
+pub fn init(config: &config::VmConfig)
+{
+    /* Drive A is always there, overlay applies to it only */
+    let mut drives = vec![match config.floppy {
+        Some(ref path) => open_drive(config, path, true),
+        None => FloppyDrive::new(),
+    }];
+
+    if let Some(ref path) = config.floppy_b {
+        drives.push(open_drive(config, path, false));
+    }
+
+    let dev = Rc::new(FDCDev {
+        fdc: RefCell::new(FDC::new(drives, dma::get_channel(FDC_DMA_CHANNEL))),
+    });
+
+    for port in &[FDC_SRA, FDC_SRB, FDC_DOR, FDC_TDR, FDC_MSR, FDC_FIFO, FDC_DIR] {
+        vm::register_io_region(dev.clone(), FDC_BASE + port, 1);
+    }
+
+    vm::register_reset_handler(dev.clone());
+
+    unsafe {
+        FDC_DEV = Some(&*dev as *const FDCDev);
+    }
+}
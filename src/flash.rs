@@ -0,0 +1,532 @@
+/*
+ * Parallel NOR flash, SST39SF0x0 style
+ *
+ * Flash array is backed by a host file and mapped read-only into guest physical memory, so reads and code
+ * fetches run at memory speed while writes trap into the JEDEC command state machine:
+ *
+ *   AA@5555 55@2AAA A0@5555 data@addr                 Byte program, only clears bits
+ *   AA@5555 55@2AAA 80@5555 AA@5555 55@2AAA 30@sector  4K sector erase
+ *   AA@5555 55@2AAA 80@5555 AA@5555 55@2AAA 10@5555    Chip erase
+ *   AA@5555 55@2AAA 90@5555                           Software ID, reads return manufacturer and device id
+ *   F0                                                Back to reading array
+ *
+ * Command addresses are decoded on A14-A0. Program and erase take a while in virtual time, reads during
+ * that time return status: DQ7 is complement of data being written (0 for erase) and DQ6 toggles on every
+ * read. While status or ID is being read array is unmapped, so guest reads trap here as well.
+ *
+ * Programmed and erased bytes go to the backing file right away, unless it is attached read-only:
+ * then guest still sees its changes but they are discarded when VM exits.
+ */
+
+use vm;
+use config;
+use clock::{virtual_clock, VcpuClock};
+use hypervisor_framework::*;
+
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::sync::Arc;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+const FLASH_SECTOR_SIZE: usize      = 0x1000;
+
+// Command cycles
+const FLASH_CMD_ADDR_MASK: usize    = 0x7FFF;
+const FLASH_UNLOCK_ADDR1: usize     = 0x5555;
+const FLASH_UNLOCK_ADDR2: usize     = 0x2AAA;
+const FLASH_UNLOCK_DATA1: u8        = 0xAA;
+const FLASH_UNLOCK_DATA2: u8        = 0x55;
+const FLASH_CMD_PROGRAM: u8         = 0xA0;
+const FLASH_CMD_ERASE_SETUP: u8     = 0x80;
+const FLASH_CMD_SECTOR_ERASE: u8    = 0x30;
+const FLASH_CMD_CHIP_ERASE: u8      = 0x10;
+const FLASH_CMD_SOFTWARE_ID: u8     = 0x90;
+const FLASH_CMD_RESET: u8           = 0xF0;
+
+// Status bits
+const FLASH_STATUS_DATA: u8         = 0x80;
+const FLASH_STATUS_TOGGLE: u8       = 0x40;
+
+// Software ID
+const FLASH_MANUFACTURER_SST: u8    = 0xBF;
+const FLASH_DEVICE_39SF010A: u8     = 0xB5;
+const FLASH_DEVICE_39SF020A: u8     = 0xB6;
+const FLASH_DEVICE_39SF040: u8      = 0xB7;
+
+// Operation times
+const FLASH_PROGRAM_NS: u64         = 20000;
+const FLASH_SECTOR_ERASE_NS: u64    = 25000000;
+const FLASH_CHIP_ERASE_NS: u64      = 100000000;
+
+/* Command sequence progress */
+#[derive(PartialEq, Debug, Copy, Clone)]
+enum Cycle
+{
+    Idle,
+    Unlock1,
+    Unlock2,
+    Program,
+    EraseSetup,
+    EraseUnlock1,
+    EraseUnlock2,
+}
+
+/* Program or erase in progress */
+struct Operation
+{
+    deadline_ns: u64,
+    data: u8,           // Final data, DQ7 reads as its complement
+    toggle: bool,       // Next DQ6 value
+}
+
+/**
+ * Flash chip model
+ */
+struct Flash
+{
+    array: Arc<vm::memory_region>,
+    file: Option<File>,         // Backing file, None if changes are discarded
+    clock: Rc<virtual_clock>,
+    cycle: Cycle,
+    software_id: bool,
+    busy: Option<Operation>,
+}
+
+impl Flash
+{
+    /* Load flash array from backing file, which is kept open for writing unless read_only */
+    fn open(path: &str, read_only: bool, clock: Rc<virtual_clock>) -> io::Result<Flash> {
+        let mut file = try!(OpenOptions::new().read(true).write(!read_only).open(path));
+        let mut data = Vec::new();
+        try!(file.read_to_end(&mut data));
+
+        if data.is_empty() || data.len() % FLASH_SECTOR_SIZE != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "size is not a multiple of 4K sector"));
+        }
+
+        let array = vm::alloc_memory_region(data.len());
+        array.write_bytes(0, &data);
+
+        Ok(Flash::new(array, if read_only { None } else { Some(file) }, clock))
+    }
+
+    fn new(array: Arc<vm::memory_region>, file: Option<File>, clock: Rc<virtual_clock>) -> Flash {
+        Flash {
+            array: array,
+            file: file,
+            clock: clock,
+            cycle: Cycle::Idle,
+            software_id: false,
+            busy: None,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.cycle = Cycle::Idle;
+        self.software_id = false;
+        self.busy = None;
+    }
+
+    fn device_id(&self) -> u8 {
+        match self.array.size {
+            0x20000 => FLASH_DEVICE_39SF010A,
+            0x40000 => FLASH_DEVICE_39SF020A,
+            _ => FLASH_DEVICE_39SF040,
+        }
+    }
+
+    /* Finish operation whose time has come */
+    fn update(&mut self) {
+        let done = match self.busy {
+            Some(ref op) => self.clock.now_ns() >= op.deadline_ns,
+            None => false,
+        };
+
+        if done {
+            self.busy = None;
+        }
+    }
+
+    /* Guest may map array directly: no status or ID reads */
+    fn is_array_mode(&mut self) -> bool {
+        self.update();
+        self.busy.is_none() && !self.software_id
+    }
+
+    fn read(&mut self, offset: usize) -> u8 {
+        self.update();
+
+        if let Some(ref mut op) = self.busy {
+            let status = (!op.data & FLASH_STATUS_DATA) | if op.toggle { FLASH_STATUS_TOGGLE } else { 0 };
+            op.toggle = !op.toggle;
+            return status;
+        }
+
+        if self.software_id {
+            return if offset & 1 == 0 { FLASH_MANUFACTURER_SST } else { self.device_id() };
+        }
+
+        let mut val = [0u8; 1];
+        self.array.read_bytes(offset, &mut val);
+        val[0]
+    }
+
+    fn write(&mut self, offset: usize, val: u8) {
+        self.update();
+
+        if self.busy.is_some() {
+            debug!("flash: ignoring write {:x} to {:x} while busy", val, offset);
+            return;
+        }
+
+        let addr = offset & FLASH_CMD_ADDR_MASK;
+        self.cycle = match (self.cycle, addr, val) {
+            (Cycle::Program, _, _) => {
+                self.program(offset, val);
+                Cycle::Idle
+            },
+            (_, _, FLASH_CMD_RESET) => {
+                self.software_id = false;
+                Cycle::Idle
+            },
+            (Cycle::Idle, FLASH_UNLOCK_ADDR1, FLASH_UNLOCK_DATA1) => Cycle::Unlock1,
+            (Cycle::Unlock1, FLASH_UNLOCK_ADDR2, FLASH_UNLOCK_DATA2) => Cycle::Unlock2,
+            (Cycle::Unlock2, FLASH_UNLOCK_ADDR1, FLASH_CMD_PROGRAM) => Cycle::Program,
+            (Cycle::Unlock2, FLASH_UNLOCK_ADDR1, FLASH_CMD_ERASE_SETUP) => Cycle::EraseSetup,
+            (Cycle::Unlock2, FLASH_UNLOCK_ADDR1, FLASH_CMD_SOFTWARE_ID) => {
+                self.software_id = true;
+                Cycle::Idle
+            },
+            (Cycle::EraseSetup, FLASH_UNLOCK_ADDR1, FLASH_UNLOCK_DATA1) => Cycle::EraseUnlock1,
+            (Cycle::EraseUnlock1, FLASH_UNLOCK_ADDR2, FLASH_UNLOCK_DATA2) => Cycle::EraseUnlock2,
+            (Cycle::EraseUnlock2, _, FLASH_CMD_SECTOR_ERASE) => {
+                let sector = offset & !(FLASH_SECTOR_SIZE - 1);
+                self.erase(sector, FLASH_SECTOR_SIZE, FLASH_SECTOR_ERASE_NS);
+                Cycle::Idle
+            },
+            (Cycle::EraseUnlock2, FLASH_UNLOCK_ADDR1, FLASH_CMD_CHIP_ERASE) => {
+                let size = self.array.size;
+                self.erase(0, size, FLASH_CHIP_ERASE_NS);
+                Cycle::Idle
+            },
+            (cycle, _, _) => {
+                debug!("flash: unexpected write {:x} to {:x} in {:?}, back to idle", val, offset, cycle);
+                Cycle::Idle
+            },
+        };
+    }
+
+    /* Program byte, bits can only go from 1 to 0 */
+    fn program(&mut self, offset: usize, val: u8) {
+        let mut cur = [0u8; 1];
+        self.array.read_bytes(offset, &mut cur);
+        cur[0] &= val;
+        self.array.write_bytes(offset, &cur);
+
+        self.store(offset, 1);
+        self.start(val, FLASH_PROGRAM_NS);
+    }
+
+    fn erase(&mut self, offset: usize, size: usize, time_ns: u64) {
+        self.array.write_bytes(offset, &vec![0xFF; size]);
+
+        self.store(offset, size);
+        self.start(0xFF, time_ns);
+    }
+
+    fn start(&mut self, data: u8, time_ns: u64) {
+        self.busy = Some(Operation {
+            deadline_ns: self.clock.now_ns() + time_ns,
+            data: data,
+            toggle: false,
+        });
+    }
+
+    /* Write array range through to backing file */
+    fn store(&mut self, offset: usize, size: usize) {
+        let file = match self.file {
+            Some(ref mut file) => file,
+            None => return,
+        };
+
+        let mut data = vec![0u8; size];
+        self.array.read_bytes(offset, &mut data);
+
+        let res = file.seek(SeekFrom::Start(offset as u64)).and_then(|_| file.write_all(&data));
+        if let Err(err) = res {
+            error!("flash: failed writing to file: {}", err);
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct FlashDev
+{
+    flash: RefCell<Flash>,
+    base: hv_gpaddr_t,
+    mapped: Cell<bool>,                             // Array is mapped for guest reads
+    protect: fn(hv_gpaddr_t, hv_memory_flags_t),    // Change guest access to array mapping
+}
+
+impl FlashDev
+{
+    /* Let guest read array directly only when reads are not status */
+    fn update_mapping(&self) {
+        let array_mode = self.flash.borrow_mut().is_array_mode();
+        if array_mode == self.mapped.get() {
+            return;
+        }
+
+        (self.protect)(self.base, if array_mode { HV_MEMORY_READ | HV_MEMORY_EXEC } else { 0 });
+        self.mapped.set(array_mode);
+    }
+}
+
+impl vm::mmio_handler for FlashDev
+{
+    fn mmio_read(&self, addr: hv_gpaddr_t, size: u8) -> u64
+    {
+        let offset = (addr - self.base) as usize;
+        let mut val = 0;
+        {
+            let mut flash = self.flash.borrow_mut();
+            for i in 0..size as usize {
+                val |= (flash.read(offset + i) as u64) << (i * 8);
+            }
+        }
+
+        self.update_mapping();
+        val
+    }
+
+    fn mmio_write(&self, addr: hv_gpaddr_t, size: u8, data: u64)
+    {
+        if size != 1 {
+            debug!("flash: {} byte write to {:x}, using low byte", size, addr);
+        }
+
+        self.flash.borrow_mut().write((addr - self.base) as usize, data as u8);
+        self.update_mapping();
+    }
+}
+
+impl vm::reset_handler for FlashDev
+{
+    fn reset(&self)
+    {
+        self.flash.borrow_mut().reset();
+        self.update_mapping();
+    }
+}
+
+#[cfg(test)]
+mod flash_test
+{
+    use super::*;
+    use clock::MockClock;
+    use vm::mmio_handler;
+
+    const BASE: hv_gpaddr_t = 0xFFF00000;
+    const SIZE: usize = 0x20000;
+
+    thread_local! {
+        static PROTECT: Cell<Option<hv_memory_flags_t>> = Cell::new(None);
+    }
+
+    fn record_protect(base: hv_gpaddr_t, flags: hv_memory_flags_t) {
+        assert!(base == BASE);
+        PROTECT.with(|p| p.set(Some(flags)));
+    }
+
+    fn last_protect() -> Option<hv_memory_flags_t> {
+        PROTECT.with(|p| p.replace(None))
+    }
+
+    fn make_image(name: &str) -> ::std::path::PathBuf {
+        let (stem, ext) = name.split_at(name.rfind('.').unwrap_or(name.len()));
+        let path = ::std::env::temp_dir().join(format!("{}_{}{}", stem, ::std::process::id(), ext));
+        let data: Vec<u8> = (0..SIZE).map(|i| (i >> 8) as u8).collect();
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        path
+    }
+
+    fn make_dev(path: &::std::path::Path, read_only: bool, clock: &Rc<MockClock>) -> FlashDev {
+        FlashDev {
+            flash: RefCell::new(Flash::open(path.to_str().unwrap(), read_only, clock.clone()).unwrap()),
+            base: BASE,
+            mapped: Cell::new(true),
+            protect: record_protect,
+        }
+    }
+
+    fn readb(dev: &FlashDev, offset: usize) -> u8 {
+        dev.mmio_read(BASE + offset as u64, 1) as u8
+    }
+
+    fn writeb(dev: &FlashDev, offset: usize, val: u8) {
+        dev.mmio_write(BASE + offset as u64, 1, val as u64);
+    }
+
+    fn command(dev: &FlashDev, cmd: u8) {
+        writeb(dev, FLASH_UNLOCK_ADDR1, FLASH_UNLOCK_DATA1);
+        writeb(dev, FLASH_UNLOCK_ADDR2, FLASH_UNLOCK_DATA2);
+        writeb(dev, FLASH_UNLOCK_ADDR1, cmd);
+    }
+
+    fn file_byte(path: &::std::path::Path, offset: usize) -> u8 {
+        let mut data = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut data).unwrap();
+        assert!(data.len() == SIZE);
+        data[offset]
+    }
+
+    #[test] fn program() {
+        let clock = Rc::new(MockClock::new());
+        let path = make_image("xvm_flash_program.bin");
+        let dev = make_dev(&path, false, &clock);
+        assert!(readb(&dev, 0x1234) == 0x12);
+        assert!(dev.mmio_read(BASE + 0x1FF, 2) == 0x0201);
+
+        /* Program 0x12 -> 0x10, status polls until done */
+        command(&dev, FLASH_CMD_PROGRAM);
+        assert!(last_protect() == None);
+        writeb(&dev, 0x1234, 0x30);
+        assert!(last_protect() == Some(0));
+        assert!(readb(&dev, 0x1234) == 0x80);
+        assert!(readb(&dev, 0) == 0x80 | FLASH_STATUS_TOGGLE);
+        assert!(readb(&dev, 0x1234) == 0x80);
+        assert!(file_byte(&path, 0x1234) == 0x10);
+
+        /* Writes while busy are ignored */
+        writeb(&dev, FLASH_UNLOCK_ADDR1, FLASH_UNLOCK_DATA1);
+        clock.advance_ns(FLASH_PROGRAM_NS);
+        assert!(readb(&dev, 0x1234) == 0x10);
+        assert!(last_protect() == Some(HV_MEMORY_READ | HV_MEMORY_EXEC));
+        assert!(dev.flash.borrow().cycle == Cycle::Idle);
+
+        /* Bad sequence does not program */
+        writeb(&dev, FLASH_UNLOCK_ADDR1, FLASH_UNLOCK_DATA1);
+        writeb(&dev, FLASH_UNLOCK_ADDR1, FLASH_UNLOCK_DATA2);
+        writeb(&dev, FLASH_UNLOCK_ADDR1, FLASH_CMD_PROGRAM);
+        writeb(&dev, 0x2000, 0);
+        assert!(readb(&dev, 0x2000) == 0x20 && file_byte(&path, 0x2000) == 0x20);
+        assert!(last_protect() == None);
+
+        /* Software ID from any address, command addresses alias above A14 */
+        writeb(&dev, 0x10000 + FLASH_UNLOCK_ADDR1, FLASH_UNLOCK_DATA1);
+        writeb(&dev, 0x18000 + FLASH_UNLOCK_ADDR2, FLASH_UNLOCK_DATA2);
+        writeb(&dev, FLASH_UNLOCK_ADDR1, FLASH_CMD_SOFTWARE_ID);
+        assert!(last_protect() == Some(0));
+        assert!(readb(&dev, 0) == FLASH_MANUFACTURER_SST && readb(&dev, 0x10001) == FLASH_DEVICE_39SF010A);
+        writeb(&dev, 0, FLASH_CMD_RESET);
+        assert!(last_protect() == Some(HV_MEMORY_READ | HV_MEMORY_EXEC));
+        assert!(readb(&dev, 0) == 0x00);
+
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test] fn erase() {
+        let clock = Rc::new(MockClock::new());
+        let path = make_image("xvm_flash_erase.bin");
+        let dev = make_dev(&path, false, &clock);
+
+        /* Sector erase of 0x3000-0x3FFF, DQ7 reads 0 while erasing */
+        command(&dev, FLASH_CMD_ERASE_SETUP);
+        assert!(dev.flash.borrow().cycle == Cycle::EraseSetup);
+        writeb(&dev, FLASH_UNLOCK_ADDR1, FLASH_UNLOCK_DATA1);
+        writeb(&dev, FLASH_UNLOCK_ADDR2, FLASH_UNLOCK_DATA2);
+        writeb(&dev, 0x3456, FLASH_CMD_SECTOR_ERASE);
+        assert!(readb(&dev, 0x3456) == 0);
+        assert!(readb(&dev, 0x3456) == FLASH_STATUS_TOGGLE);
+        clock.advance_ns(FLASH_SECTOR_ERASE_NS - 1);
+        assert!(readb(&dev, 0x3456) == 0);
+        clock.advance_ns(1);
+        assert!(readb(&dev, 0x2FFF) == 0x2F && readb(&dev, 0x3000) == 0xFF && readb(&dev, 0x3FFF) == 0xFF);
+        assert!(readb(&dev, 0x4000) == 0x40);
+        assert!(file_byte(&path, 0x2FFF) == 0x2F && file_byte(&path, 0x3ABC) == 0xFF && file_byte(&path, 0x4000) == 0x40);
+
+        /* Reprogram erased byte */
+        command(&dev, FLASH_CMD_PROGRAM);
+        writeb(&dev, 0x3000, 0x5A);
+        assert!(readb(&dev, 0x3000) == 0x80);
+        clock.advance_ns(FLASH_PROGRAM_NS);
+        assert!(readb(&dev, 0x3000) == 0x5A && file_byte(&path, 0x3000) == 0x5A);
+
+        /* Chip erase, reset drops status mode */
+        command(&dev, FLASH_CMD_ERASE_SETUP);
+        writeb(&dev, FLASH_UNLOCK_ADDR1, FLASH_UNLOCK_DATA1);
+        writeb(&dev, FLASH_UNLOCK_ADDR2, FLASH_UNLOCK_DATA2);
+        writeb(&dev, FLASH_UNLOCK_ADDR1, FLASH_CMD_CHIP_ERASE);
+        assert!(readb(&dev, 0x100) == 0);
+        last_protect();
+        vm::reset_handler::reset(&dev);
+        assert!(last_protect() == Some(HV_MEMORY_READ | HV_MEMORY_EXEC));
+        assert!(readb(&dev, 0) == 0xFF && readb(&dev, SIZE - 1) == 0xFF);
+        assert!(file_byte(&path, 0) == 0xFF && file_byte(&path, SIZE - 1) == 0xFF);
+
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test] fn read_only() {
+        let clock = Rc::new(MockClock::new());
+        let path = make_image("xvm_flash_ro.bin");
+        let dev = make_dev(&path, true, &clock);
+
+        /* Guest sees its program, file does not */
+        command(&dev, FLASH_CMD_PROGRAM);
+        writeb(&dev, 0x100, 0x00);
+        clock.advance_ns(FLASH_PROGRAM_NS);
+        assert!(readb(&dev, 0x100) == 0x00 && file_byte(&path, 0x100) == 0x01);
+
+        ::std::fs::remove_file(&path).unwrap();
+        assert!(Flash::open(path.to_str().unwrap(), true, clock.clone()).is_err());
+
+        /* Size has to be whole sectors */
+        File::create(&path).unwrap().write_all(&[0xFF; FLASH_SECTOR_SIZE + 1]).unwrap();
+        assert!(Flash::open(path.to_str().unwrap(), false, clock.clone()).is_err());
+        ::std::fs::remove_file(&path).unwrap();
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+pub fn init(config: &config::VmConfig)
+{
+    let path = match config.flash {
+        Some(ref path) => path,
+        None => return,
+    };
+
+    let flash = match Flash::open(path, config.flash_read_only, Rc::new(VcpuClock)) {
+        Ok(flash) => flash,
+        Err(err) => panic!("flash: failed to open {}: {}", path, err),
+    };
+
+    let base = config.flash_base;
+    let size = flash.array.size as u64;
+    if base + size > 0x100000000 {
+        panic!("flash: {:x} bytes at {:x} go past 4G", size, base);
+    }
+
+    let mut addr = base;
+    while addr < base + size {
+        if vm::find_memory_mapping(addr).is_some() {
+            panic!("flash: {:x} is already mapped, use --flash-base to move flash", addr);
+        }
+        addr += FLASH_SECTOR_SIZE as u64;
+    }
+
+    vm::map_memory_region(base, HV_MEMORY_READ | HV_MEMORY_EXEC, flash.array.clone());
+
+    let dev = Rc::new(FlashDev {
+        flash: RefCell::new(flash),
+        base: base,
+        mapped: Cell::new(true),
+        protect: vm::protect_memory_region,
+    });
+
+    vm::register_mmio_region(dev.clone(), base, size);
+    vm::register_reset_handler(dev.clone());
+}
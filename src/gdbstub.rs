@@ -0,0 +1,1109 @@
+/*
+ * GDB remote serial protocol stub
+ *
+ * Listens for GDB on a local TCP port. Connection reader threads decode packets and hand them to vcpu thread,
+ * which serves them while guest is stopped, so guest state is only touched from guest context. Ctrl-C and a
+ * new connection kick vcpu out of guest and stop it with SIGINT.
+ *
+ * Addresses are guest physical by default, guest paging is not walked. Real mode code is easier to follow with
+ * CS-relative addressing, where memory and breakpoint addresses are offsets from CS base and EIP is reported as is.
+ * In physical mode EIP is reported as CS base + EIP, so GDB sees the linear address of the next instruction.
+ *
+ * Software breakpoints replace the first instruction byte with INT3, memory reads show original bytes instead.
+ * Single stepping is done by vcpu loop, stub only tells it how to resume. With --reverse, bs and bc packets run
+ * guest backwards through the recording to the previous instruction or breakpoint, see reverse.rs. Going back
+ * past where recording starts stops there with a replaylog:begin reason.
+ */
+
+use vm;
+use config;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+// Largest packet we take and send, advertised in qSupported
+const GDB_PACKET_SIZE: usize    = 0x1000;
+
+const INT3: u8                  = 0xCC;
+
+// GDB i386 register numbers
+pub const GDB_REG_EIP: usize    = 8;
+pub const GDB_NUM_REGS: usize   = 16;
+
+/**
+ * Registers in GDB i386 order: eax ecx edx ebx esp ebp esi edi eip eflags cs ss ds es fs gs
+ */
+pub type GdbRegisters = [u32; GDB_NUM_REGS];
+
+/**
+ * Guest as seen by the stub
+ */
+pub trait gdb_target
+{
+    fn read_registers(&self) -> GdbRegisters;
+
+    /** Segment selectors reload segment bases in real mode only */
+    fn write_registers(&mut self, regs: &GdbRegisters);
+
+    /** Linear address of CS segment */
+    fn cs_base(&self) -> u64;
+
+    /** Guest physical memory access, returns bytes done up to the end of the mapping address is in */
+    fn read_memory(&self, addr: u64, buf: &mut [u8]) -> usize;
+    fn write_memory(&mut self, addr: u64, data: &[u8]) -> usize;
+}
+
+/**
+ * Why guest stopped
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum GdbStop
+{
+    Trap,           // Attach or single step done
+    Breakpoint,     // Hit one of our breakpoints
+    Interrupt,      // Ctrl-C or new connection
+    Vector(u8),     // External interrupt vector about to be injected hit a vector breakpoint
+    ReplayBegin,    // Reverse execution got back to where recording starts
+}
+
+/**
+ * How to run guest when debugger lets it go
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum GdbResume
+{
+    Continue,
+    Step,
+    ReverseStep,
+    ReverseContinue,
+    Kill,           // Debugger asked to terminate VM
+}
+
+/* What to do with a packet */
+#[derive(PartialEq, Debug)]
+enum Action
+{
+    Reply(String),
+    Resume(GdbResume),
+    Detach,
+    Kill,
+}
+
+fn hex_digit(c: u8) -> Option<u8>
+{
+    match c {
+        b'0'...b'9' => Some(c - b'0'),
+        b'a'...b'f' => Some(c - b'a' + 10),
+        b'A'...b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/* Big endian hex number as in addresses and lengths */
+fn parse_hex(s: &[u8]) -> Option<u64>
+{
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+
+    let mut val = 0u64;
+    for c in s {
+        match hex_digit(*c) {
+            Some(digit) => val = (val << 4) | digit as u64,
+            None => return None,
+        }
+    }
+    Some(val)
+}
+
+/* Byte string as pairs of hex digits */
+fn decode_hex(s: &[u8]) -> Option<Vec<u8>>
+{
+    if s.len() % 2 != 0 {
+        return None;
+    }
+
+    let mut data = Vec::with_capacity(s.len() / 2);
+    for pair in s.chunks(2) {
+        match (hex_digit(pair[0]), hex_digit(pair[1])) {
+            (Some(hi), Some(lo)) => data.push((hi << 4) | lo),
+            _ => return None,
+        }
+    }
+    Some(data)
+}
+
+fn encode_hex(data: &[u8]) -> String
+{
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/* Register values go in target byte order */
+fn encode_reg(val: u32) -> String
+{
+    encode_hex(&[val as u8, (val >> 8) as u8, (val >> 16) as u8, (val >> 24) as u8])
+}
+
+fn decode_reg(s: &[u8]) -> Option<u32>
+{
+    match decode_hex(s) {
+        Some(ref data) if data.len() == 4 =>
+            Some(data[0] as u32 | (data[1] as u32) << 8 | (data[2] as u32) << 16 | (data[3] as u32) << 24),
+        _ => None,
+    }
+}
+
+/* Split "a<sep>b" at first separator */
+fn split(s: &[u8], sep: u8) -> Option<(&[u8], &[u8])>
+{
+    s.iter().position(|c| *c == sep).map(|i| (&s[..i], &s[i + 1..]))
+}
+
+/* "addr,len" of memory packets */
+fn parse_addr_len(s: &[u8]) -> Option<(u64, usize)>
+{
+    match split(s, b',') {
+        Some((addr, len)) => match (parse_hex(addr), parse_hex(len)) {
+            (Some(addr), Some(len)) => Some((addr, len as usize)),
+            _ => None,
+        },
+        None => None,
+    }
+}
+
+/**
+ * Frame reply as $data#checksum
+ */
+fn encode_packet(data: &str) -> Vec<u8>
+{
+    let sum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+    format!("${}#{:02x}", data, sum).into_bytes()
+}
+
+/**
+ * What debugger sent
+ */
+#[derive(PartialEq, Debug)]
+enum GdbInput
+{
+    Packet(Vec<u8>),    // Unescaped packet data
+    BadChecksum,        // Debugger resends on NAK
+    Interrupt,          // Ctrl-C
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum DecodeState
+{
+    Idle,
+    Data,
+    Escape,
+    Checksum(u8),       // Number of checksum digits seen
+}
+
+/**
+ * Debugger byte stream to packets, acks between packets are skipped
+ */
+struct PacketDecoder
+{
+    state: DecodeState,
+    data: Vec<u8>,
+    sum: u8,
+    checksum: u8,
+}
+
+impl PacketDecoder
+{
+    fn new() -> PacketDecoder {
+        PacketDecoder {
+            state: DecodeState::Idle,
+            data: Vec::new(),
+            sum: 0,
+            checksum: 0,
+        }
+    }
+
+    fn feed(&mut self, c: u8) -> Option<GdbInput> {
+        match self.state {
+            DecodeState::Idle => {
+                match c {
+                    b'$' => {
+                        self.data.clear();
+                        self.sum = 0;
+                        self.state = DecodeState::Data;
+                    },
+                    0x03 => return Some(GdbInput::Interrupt),
+                    _ => {},
+                }
+            },
+            DecodeState::Data => {
+                match c {
+                    b'#' => {
+                        self.checksum = 0;
+                        self.state = DecodeState::Checksum(0);
+                    },
+                    b'}' => {
+                        self.sum = self.sum.wrapping_add(c);
+                        self.state = DecodeState::Escape;
+                    },
+                    _ => {
+                        self.sum = self.sum.wrapping_add(c);
+                        self.data.push(c);
+                    },
+                }
+            },
+            DecodeState::Escape => {
+                self.sum = self.sum.wrapping_add(c);
+                self.data.push(c ^ 0x20);
+                self.state = DecodeState::Data;
+            },
+            DecodeState::Checksum(digits) => {
+                let digit = match hex_digit(c) {
+                    Some(digit) => digit,
+                    None => {
+                        self.state = DecodeState::Idle;
+                        return Some(GdbInput::BadChecksum);
+                    },
+                };
+
+                self.checksum = (self.checksum << 4) | digit;
+                if digits == 0 {
+                    self.state = DecodeState::Checksum(1);
+                    return None;
+                }
+
+                self.state = DecodeState::Idle;
+                if self.checksum != self.sum {
+                    return Some(GdbInput::BadChecksum);
+                }
+                return Some(GdbInput::Packet(self.data.split_off(0)));
+            },
+        }
+
+        None
+    }
+}
+
+/* Software breakpoint and instruction byte it replaced */
+struct Breakpoint
+{
+    addr: u64,          // Guest physical
+    orig: u8,
+}
+
+/**
+ * Protocol state of a debugger connection, independent of transport
+ */
+struct GdbSession
+{
+    addressing: config::GdbAddressing,
+    can_step: bool,
+    can_reverse: bool,
+    stop: GdbStop,
+    no_ack: bool,
+    breakpoints: Vec<Breakpoint>,
+}
+
+impl GdbSession
+{
+    fn new(addressing: config::GdbAddressing, can_step: bool, can_reverse: bool) -> GdbSession {
+        GdbSession {
+            addressing: addressing,
+            can_step: can_step,
+            can_reverse: can_reverse,
+            stop: GdbStop::Trap,
+            no_ack: false,
+            breakpoints: Vec::new(),
+        }
+    }
+
+    /* Vector stop is a SIGTRAP with the vector as a stop reason, GDB skips reasons it doesn't know */
+    fn stop_reply(&self) -> String {
+        match self.stop {
+            GdbStop::Trap => String::from("S05"),
+            GdbStop::Breakpoint => String::from("T05swbreak:;"),
+            GdbStop::Interrupt => String::from("S02"),
+            GdbStop::Vector(vec) => format!("T05vector:{:02x};", vec),
+            GdbStop::ReplayBegin => String::from("T05replaylog:begin;"),
+        }
+    }
+
+    /* Guest physical address of a debugger address */
+    fn physical(&self, target: &gdb_target, addr: u64) -> u64 {
+        match self.addressing {
+            config::GdbAddressing::Physical => addr,
+            config::GdbAddressing::CsRelative => target.cs_base().wrapping_add(addr),
+        }
+    }
+
+    fn is_breakpoint(&self, addr: u64) -> bool {
+        self.breakpoints.iter().any(|bp| bp.addr == addr)
+    }
+
+    /* Guest registers with EIP as debugger addresses it */
+    fn registers(&self, target: &gdb_target) -> GdbRegisters {
+        let mut regs = target.read_registers();
+        if self.addressing == config::GdbAddressing::Physical {
+            regs[GDB_REG_EIP] = regs[GDB_REG_EIP].wrapping_add(target.cs_base() as u32);
+        }
+        regs
+    }
+
+    /* Segments go first, so physical EIP is made relative to the CS base it ends up with */
+    fn set_registers(&self, target: &mut gdb_target, regs: &GdbRegisters) {
+        let mut regs = *regs;
+        target.write_registers(&regs);
+        if self.addressing == config::GdbAddressing::Physical {
+            regs[GDB_REG_EIP] = regs[GDB_REG_EIP].wrapping_sub(target.cs_base() as u32);
+            target.write_registers(&regs);
+        }
+    }
+
+    /* Read guest memory across mappings with breakpoints hidden, short at a hole */
+    fn read_memory(&self, target: &gdb_target, addr: u64, len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        let mut done = 0;
+        while done < len {
+            let n = target.read_memory(addr + done as u64, &mut data[done..]);
+            if n == 0 {
+                break;
+            }
+            done += n;
+        }
+        data.truncate(done);
+
+        for bp in &self.breakpoints {
+            if bp.addr >= addr && bp.addr < addr + done as u64 {
+                data[(bp.addr - addr) as usize] = bp.orig;
+            }
+        }
+        data
+    }
+
+    /* Write guest memory, bytes under breakpoints become their original bytes */
+    fn write_memory(&mut self, target: &mut gdb_target, addr: u64, data: &[u8]) -> bool {
+        let mut data = data.to_vec();
+        for bp in &mut self.breakpoints {
+            if bp.addr >= addr && bp.addr < addr + data.len() as u64 {
+                let off = (bp.addr - addr) as usize;
+                bp.orig = data[off];
+                data[off] = INT3;
+            }
+        }
+
+        let mut done = 0;
+        while done < data.len() {
+            let n = target.write_memory(addr + done as u64, &data[done..]);
+            if n == 0 {
+                return false;
+            }
+            done += n;
+        }
+        true
+    }
+
+    fn insert_breakpoint(&mut self, target: &mut gdb_target, addr: u64) -> bool {
+        if self.is_breakpoint(addr) {
+            return true;
+        }
+
+        let mut orig = [0u8; 1];
+        if target.read_memory(addr, &mut orig) != 1 || target.write_memory(addr, &[INT3]) != 1 {
+            return false;
+        }
+
+        self.breakpoints.push(Breakpoint { addr: addr, orig: orig[0] });
+        true
+    }
+
+    fn remove_breakpoint(&mut self, target: &mut gdb_target, addr: u64) -> bool {
+        match self.breakpoints.iter().position(|bp| bp.addr == addr) {
+            Some(i) => {
+                let bp = self.breakpoints.remove(i);
+                target.write_memory(bp.addr, &[bp.orig]);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /**
+     * Debugger is gone, guest runs on without our breakpoints
+     */
+    fn detach(&mut self, target: &mut gdb_target) {
+        while let Some(bp) = self.breakpoints.pop() {
+            target.write_memory(bp.addr, &[bp.orig]);
+        }
+        self.no_ack = false;
+    }
+
+    fn handle(&mut self, target: &mut gdb_target, packet: &[u8]) -> Action {
+        if packet.is_empty() {
+            return Action::Reply(String::new());
+        }
+
+        let args = &packet[1..];
+        let reply = match packet[0] {
+            b'?' => Ok(self.stop_reply()),
+            b'g' => Ok(self.registers(target).iter().map(|r| encode_reg(*r)).collect()),
+            b'G' => self.handle_write_registers(target, args),
+            b'p' => self.handle_read_register(target, args),
+            b'P' => self.handle_write_register(target, args),
+            b'm' => self.handle_read_memory(target, args),
+            b'M' => self.handle_write_memory(target, args, false),
+            b'X' => self.handle_write_memory(target, args, true),
+            b'Z' | b'z' => self.handle_breakpoint(target, packet[0] == b'Z', args),
+            b'c' | b's' => {
+                let resume = if packet[0] == b's' { GdbResume::Step } else { GdbResume::Continue };
+                if resume == GdbResume::Step && !self.can_step {
+                    return Action::Reply(String::from("E01"));
+                }
+
+                if !args.is_empty() {
+                    let addr = match parse_hex(args) {
+                        Some(addr) => addr as u32,
+                        None => return Action::Reply(String::from("E01")),
+                    };
+
+                    let mut regs = self.registers(target);
+                    regs[GDB_REG_EIP] = addr;
+                    self.set_registers(target, &regs);
+                }
+                return Action::Resume(resume);
+            },
+            b'b' if self.can_reverse && args == b"s" => return Action::Resume(GdbResume::ReverseStep),
+            b'b' if self.can_reverse && args == b"c" => return Action::Resume(GdbResume::ReverseContinue),
+            b'b' => Err(()),
+            b'q' => self.handle_query(args),
+            b'Q' if args == b"StartNoAckMode" => {
+                self.no_ack = true;
+                Ok(String::from("OK"))
+            },
+            b'H' | b'T' => Ok(String::from("OK")),
+            b'D' => return Action::Detach,
+            b'k' => return Action::Kill,
+            _ => Ok(String::new()),
+        };
+
+        Action::Reply(reply.unwrap_or(String::from("E01")))
+    }
+
+    fn handle_write_registers(&mut self, target: &mut gdb_target, args: &[u8]) -> Result<String, ()> {
+        if args.len() < GDB_NUM_REGS * 8 {
+            return Err(());
+        }
+
+        let mut regs = [0u32; GDB_NUM_REGS];
+        for i in 0..GDB_NUM_REGS {
+            regs[i] = try!(decode_reg(&args[i * 8..(i + 1) * 8]).ok_or(()));
+        }
+        self.set_registers(target, &regs);
+        Ok(String::from("OK"))
+    }
+
+    fn handle_read_register(&mut self, target: &mut gdb_target, args: &[u8]) -> Result<String, ()> {
+        let n = try!(parse_hex(args).ok_or(())) as usize;
+        if n >= GDB_NUM_REGS {
+            return Err(());
+        }
+        Ok(encode_reg(self.registers(target)[n]))
+    }
+
+    fn handle_write_register(&mut self, target: &mut gdb_target, args: &[u8]) -> Result<String, ()> {
+        let (n, val) = try!(split(args, b'=').ok_or(()));
+        let n = try!(parse_hex(n).ok_or(())) as usize;
+        if n >= GDB_NUM_REGS {
+            return Err(());
+        }
+
+        let mut regs = self.registers(target);
+        regs[n] = try!(decode_reg(val).ok_or(()));
+        self.set_registers(target, &regs);
+        Ok(String::from("OK"))
+    }
+
+    fn handle_read_memory(&mut self, target: &mut gdb_target, args: &[u8]) -> Result<String, ()> {
+        let (addr, len) = try!(parse_addr_len(args).ok_or(()));
+        let data = self.read_memory(target, self.physical(target, addr), len.min(GDB_PACKET_SIZE / 2));
+        if data.is_empty() && len != 0 {
+            return Err(());
+        }
+        Ok(encode_hex(&data))
+    }
+
+    /* M carries hex data, X binary */
+    fn handle_write_memory(&mut self, target: &mut gdb_target, args: &[u8], binary: bool) -> Result<String, ()> {
+        let (addr_len, data) = try!(split(args, b':').ok_or(()));
+        let (addr, len) = try!(parse_addr_len(addr_len).ok_or(()));
+        let data = if binary { data.to_vec() } else { try!(decode_hex(data).ok_or(())) };
+        if data.len() != len {
+            return Err(());
+        }
+
+        let addr = self.physical(target, addr);
+        if !self.write_memory(target, addr, &data) {
+            return Err(());
+        }
+        Ok(String::from("OK"))
+    }
+
+    /* Z0/z0 software breakpoints only, other kinds are unsupported */
+    fn handle_breakpoint(&mut self, target: &mut gdb_target, insert: bool, args: &[u8]) -> Result<String, ()> {
+        let (kind, rest) = try!(split(args, b',').ok_or(()));
+        if kind != b"0" {
+            return Ok(String::new());
+        }
+
+        let addr = match split(rest, b',') {
+            Some((addr, _)) => try!(parse_hex(addr).ok_or(())),
+            None => try!(parse_hex(rest).ok_or(())),
+        };
+
+        let addr = self.physical(target, addr);
+        let done = if insert { self.insert_breakpoint(target, addr) } else { self.remove_breakpoint(target, addr) };
+        if !done {
+            return Err(());
+        }
+        Ok(String::from("OK"))
+    }
+
+    fn handle_query(&mut self, args: &[u8]) -> Result<String, ()> {
+        let name = match split(args, b':') {
+            Some((name, _)) => name,
+            None => args,
+        };
+
+        Ok(String::from(match name {
+            b"Supported" => {
+                let reverse = if self.can_reverse { ";ReverseStep+;ReverseContinue+" } else { "" };
+                return Ok(format!("PacketSize={:x};swbreak+;QStartNoAckMode+{}", GDB_PACKET_SIZE, reverse));
+            },
+            b"Attached" => "1",
+            b"C" => "QC1",
+            b"fThreadInfo" => "m1",
+            b"sThreadInfo" => "l",
+            _ => "",
+        }))
+    }
+}
+
+/* Connected debugger, packets come from its reader thread */
+struct GdbClient
+{
+    stream: TcpStream,
+    input: Receiver<GdbInput>,
+}
+
+struct GdbStub
+{
+    session: GdbSession,
+    clients: Receiver<GdbClient>,
+    client: Option<GdbClient>,
+    running: bool,      // Client resumed guest and waits for a stop reply
+}
+
+impl GdbStub
+{
+    /* Take new connection, one debugger at a time so it replaces the current one */
+    fn attach(&mut self, target: &mut gdb_target, client: GdbClient) {
+        if self.client.is_some() {
+            warn!("New GDB connection replaces current one");
+        }
+        self.session.detach(target);
+        self.client = Some(client);
+        self.running = false;
+    }
+
+    fn disconnect(&mut self, target: &mut gdb_target) {
+        self.session.detach(target);
+        self.client = None;
+        self.running = false;
+    }
+
+    /* Raw write, connection is dropped on error */
+    fn write(&mut self, target: &mut gdb_target, data: &[u8]) {
+        let failed = match self.client {
+            Some(ref mut client) => client.stream.write_all(data).is_err(),
+            None => false,
+        };
+
+        if failed {
+            warn!("GDB connection lost");
+            self.disconnect(target);
+        }
+    }
+
+    fn send(&mut self, target: &mut gdb_target, reply: &str) {
+        self.write(target, &encode_packet(reply));
+    }
+
+    /* Serve debugger until it resumes guest */
+    fn serve(&mut self, target: &mut gdb_target, stop: GdbStop) -> GdbResume {
+        while let Ok(client) = self.clients.try_recv() {
+            self.attach(target, client);
+        }
+
+        self.session.stop = stop;
+        if self.running {
+            self.running = false;
+            let reply = self.session.stop_reply();
+            self.send(target, &reply);
+        }
+
+        loop {
+            let input = match self.client {
+                Some(ref client) => client.input.recv(),
+                None => return GdbResume::Continue,
+            };
+
+            let packet = match input {
+                Ok(GdbInput::Packet(packet)) => packet,
+                Ok(GdbInput::BadChecksum) => {
+                    self.write(target, b"-");
+                    continue;
+                },
+                Ok(GdbInput::Interrupt) => continue,
+                Err(_) => {
+                    self.disconnect(target);
+                    continue;
+                },
+            };
+
+            if !self.session.no_ack {
+                self.write(target, b"+");
+            }
+
+            match self.session.handle(target, &packet) {
+                Action::Reply(reply) => self.send(target, &reply),
+                Action::Resume(resume) => {
+                    self.running = true;
+                    return resume;
+                },
+                Action::Detach => {
+                    self.send(target, "OK");
+                    self.disconnect(target);
+                },
+                Action::Kill => {
+                    self.disconnect(target);
+                    return GdbResume::Kill;
+                },
+            }
+        }
+    }
+}
+
+static mut GDB_STUB: Option<*mut GdbStub> = None;
+
+lazy_static! {
+    static ref ATTENTION: AtomicBool = AtomicBool::new(false);
+}
+
+fn get_stub() -> &'static mut GdbStub
+{
+    unsafe { &mut *GDB_STUB.unwrap() }
+}
+
+/* Stop guest at next exit */
+fn request_attention()
+{
+    ATTENTION.store(true, Ordering::SeqCst);
+    vm::interrupt_guest();
+}
+
+/* Decode debugger input, Ctrl-C is acted on here so it gets through while guest runs */
+fn read_packets(mut stream: TcpStream, input: Sender<GdbInput>)
+{
+    let mut decoder = PacketDecoder::new();
+    let mut buf = [0u8; GDB_PACKET_SIZE];
+
+    loop {
+        let n = match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => n,
+        };
+
+        for c in &buf[..n] {
+            match decoder.feed(*c) {
+                Some(GdbInput::Interrupt) => request_attention(),
+                Some(packet) => {
+                    if input.send(packet).is_err() {
+                        return;
+                    }
+                },
+                None => {},
+            }
+        }
+    }
+}
+
+fn accept_clients(listener: TcpListener, clients: Sender<GdbClient>)
+{
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("GDB accept failed: {}", err);
+                continue;
+            },
+        };
+
+        let reader = match stream.try_clone() {
+            Ok(reader) => reader,
+            Err(err) => {
+                warn!("GDB connection setup failed: {}", err);
+                continue;
+            },
+        };
+
+        let _ = stream.set_nodelay(true);
+        let (input_tx, input_rx) = mpsc::channel();
+        thread::spawn(move || read_packets(reader, input_tx));
+
+        if clients.send(GdbClient { stream: stream, input: input_rx }).is_err() {
+            return;
+        }
+        request_attention();
+    }
+}
+
+/**
+ * Start listening for debugger if configured, can_step tells if vcpu loop supports single stepping and
+ * can_reverse if it runs guest backwards
+ */
+pub fn init(config: &config::VmConfig, can_step: bool, can_reverse: bool)
+{
+    let gdb = match config.gdb {
+        Some(ref gdb) => gdb,
+        None => return,
+    };
+
+    let listener = match TcpListener::bind(("127.0.0.1", gdb.port)) {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Can't listen for GDB on port {}: {}", gdb.port, err);
+            ::std::process::exit(1);
+        }
+    };
+
+    let (clients_tx, clients_rx) = mpsc::channel();
+    thread::spawn(move || accept_clients(listener, clients_tx));
+
+    let stub = Box::new(GdbStub {
+        session: GdbSession::new(gdb.addressing, can_step, can_reverse),
+        clients: clients_rx,
+        client: None,
+        running: false,
+    });
+
+    unsafe {
+        GDB_STUB = Some(Box::into_raw(stub));
+    }
+}
+
+pub fn enabled() -> bool
+{
+    unsafe { GDB_STUB.is_some() }
+}
+
+/**
+ * Debugger wants guest stopped, checked by vcpu loop after each exit
+ */
+pub fn attention_requested() -> bool
+{
+    enabled() && ATTENTION.load(Ordering::SeqCst)
+}
+
+/**
+ * Guest stopped for debugger, serve it until it resumes guest
+ * Guest time stands still meanwhile.
+ */
+pub fn stop(target: &mut gdb_target, stop: GdbStop) -> GdbResume
+{
+    ATTENTION.store(false, Ordering::SeqCst);
+    vm::hold(|| get_stub().serve(target, stop))
+}
+
+/**
+ * Block until debugger connects and serve it before guest runs its first instruction
+ */
+pub fn wait_for_attach(target: &mut gdb_target) -> GdbResume
+{
+    let stub = get_stub();
+    println!("Waiting for GDB connection");
+
+    match stub.clients.recv() {
+        Ok(client) => stub.attach(target, client),
+        Err(_) => return GdbResume::Continue,
+    }
+    stop(target, GdbStop::Trap)
+}
+
+/**
+ * Guest hit INT3, None if it isn't one of ours and guest should get its #BP
+ */
+pub fn breakpoint(target: &mut gdb_target) -> Option<GdbResume>
+{
+    if !enabled() {
+        return None;
+    }
+
+    let regs = target.read_registers();
+    let addr = target.cs_base().wrapping_add(regs[GDB_REG_EIP] as u64);
+    if !get_stub().session.is_breakpoint(addr) {
+        return None;
+    }
+    Some(stop(target, GdbStop::Breakpoint))
+}
+
+/**
+ * Our software breakpoints in guest memory and the bytes they replaced
+ */
+pub fn breakpoints() -> Vec<(u64, u8)>
+{
+    if !enabled() {
+        return Vec::new();
+    }
+    get_stub().session.breakpoints.iter().map(|bp| (bp.addr, bp.orig)).collect()
+}
+
+/**
+ * Patch our breakpoints in again after guest memory was restored from snapshot, which has them hidden, ones
+ * still there are left alone
+ */
+pub fn patch_breakpoints(target: &mut gdb_target)
+{
+    if !enabled() {
+        return;
+    }
+    for bp in &mut get_stub().session.breakpoints {
+        let mut orig = [0u8; 1];
+        if target.read_memory(bp.addr, &mut orig) == 1 && orig[0] != INT3 {
+            bp.orig = orig[0];
+            target.write_memory(bp.addr, &[INT3]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod gdbstub_test
+{
+    use super::*;
+    use config::GdbAddressing;
+
+    const GDB_REG_CS: usize = 10;
+
+    /* Real mode guest with 64K of memory and a hole above */
+    struct MockTarget
+    {
+        regs: GdbRegisters,
+        mem: Vec<u8>,
+    }
+
+    impl MockTarget {
+        fn new() -> MockTarget {
+            let mut regs = [0u32; GDB_NUM_REGS];
+            regs[GDB_REG_EIP] = 0x100;
+            regs[GDB_REG_CS] = 0x700;
+            MockTarget { regs: regs, mem: (0..0x10000).map(|i| i as u8).collect() }
+        }
+    }
+
+    impl gdb_target for MockTarget {
+        fn read_registers(&self) -> GdbRegisters {
+            self.regs
+        }
+
+        fn write_registers(&mut self, regs: &GdbRegisters) {
+            self.regs = *regs;
+        }
+
+        fn cs_base(&self) -> u64 {
+            (self.regs[GDB_REG_CS] as u64) << 4
+        }
+
+        fn read_memory(&self, addr: u64, buf: &mut [u8]) -> usize {
+            if addr >= self.mem.len() as u64 {
+                return 0;
+            }
+            let len = buf.len().min(self.mem.len() - addr as usize);
+            buf[..len].copy_from_slice(&self.mem[addr as usize..addr as usize + len]);
+            len
+        }
+
+        fn write_memory(&mut self, addr: u64, data: &[u8]) -> usize {
+            if addr >= self.mem.len() as u64 {
+                return 0;
+            }
+            let len = data.len().min(self.mem.len() - addr as usize);
+            self.mem[addr as usize..addr as usize + len].copy_from_slice(&data[..len]);
+            len
+        }
+    }
+
+    fn reply(session: &mut GdbSession, target: &mut MockTarget, packet: &str) -> String {
+        match session.handle(target, packet.as_bytes()) {
+            Action::Reply(reply) => reply,
+            action => panic!("{} got {:?}", packet, action),
+        }
+    }
+
+    fn decode(input: &[u8]) -> Vec<GdbInput> {
+        let mut decoder = PacketDecoder::new();
+        input.iter().filter_map(|c| decoder.feed(*c)).collect()
+    }
+
+    #[test] fn packets() {
+        assert!(encode_packet("OK") == b"$OK#9a".to_vec());
+        assert!(decode(b"+$g#67") == vec![GdbInput::Packet(b"g".to_vec())]);
+        assert!(decode(b"$g#68") == vec![GdbInput::BadChecksum]);
+        assert!(decode(b"$g#6x") == vec![GdbInput::BadChecksum]);
+        assert!(decode(b"\x03$?#3F") == vec![GdbInput::Interrupt, GdbInput::Packet(b"?".to_vec())]);
+
+        /* Escaped bytes count in checksum as sent */
+        let sum = b"X0,1:}".iter().fold(0u8, |sum, b| sum.wrapping_add(*b)).wrapping_add(b'#' ^ 0x20);
+        let mut input = b"$X0,1:}".to_vec();
+        input.push(b'#' ^ 0x20);
+        input.extend(format!("#{:02x}", sum).bytes());
+        assert!(decode(&input) == vec![GdbInput::Packet(b"X0,1:#".to_vec())]);
+    }
+
+    #[test] fn registers() {
+        let mut target = MockTarget::new();
+        let mut session = GdbSession::new(GdbAddressing::Physical, true, false);
+
+        /* Physical mode reports linear EIP */
+        let regs = reply(&mut session, &mut target, "g");
+        assert!(regs.len() == GDB_NUM_REGS * 8);
+        assert!(&regs[GDB_REG_EIP * 8..(GDB_REG_EIP + 1) * 8] == "00710000");
+        assert!(reply(&mut session, &mut target, "p8") == "00710000");
+        assert!(reply(&mut session, &mut target, "pa") == "00070000");
+        assert!(reply(&mut session, &mut target, "p10") == "E01");
+
+        assert!(reply(&mut session, &mut target, "P8=00720000") == "OK");
+        assert!(target.regs[GDB_REG_EIP] == 0x200);
+        assert!(reply(&mut session, &mut target, "P0=78563412") == "OK");
+        assert!(target.regs[0] == 0x12345678);
+
+        /* EIP is made relative to CS written along */
+        let mut regs = reply(&mut session, &mut target, "g");
+        regs.replace_range(GDB_REG_EIP * 8..(GDB_REG_CS + 1) * 8, "100001000200000000100000");
+        regs.truncate(GDB_NUM_REGS * 8);
+        assert!(reply(&mut session, &mut target, &format!("G{}", regs)) == "OK");
+        assert!(target.regs[GDB_REG_CS] == 0x1000 && target.regs[GDB_REG_EIP] == 0x10);
+
+        /* CS-relative mode leaves EIP alone */
+        let mut session = GdbSession::new(GdbAddressing::CsRelative, true, false);
+        assert!(reply(&mut session, &mut target, "p8") == "10000000");
+        assert!(reply(&mut session, &mut target, "G1234") == "E01");
+    }
+
+    #[test] fn memory() {
+        let mut target = MockTarget::new();
+        let mut session = GdbSession::new(GdbAddressing::Physical, true, false);
+
+        assert!(reply(&mut session, &mut target, "m7100,4") == "00010203");
+        assert!(reply(&mut session, &mut target, "M7100,2:aa55") == "OK");
+        assert!(target.mem[0x7100] == 0xAA && target.mem[0x7101] == 0x55);
+        assert!(reply(&mut session, &mut target, "X7102,2:#}") == "OK");
+        assert!(target.mem[0x7102] == 0x23 && target.mem[0x7103] == 0x7D);
+        assert!(reply(&mut session, &mut target, "X7104,0:") == "OK");
+
+        /* Reads stop at a hole, nothing readable is an error */
+        assert!(reply(&mut session, &mut target, "mfffe,4") == "feff");
+        assert!(reply(&mut session, &mut target, "m10000,4") == "E01");
+        assert!(reply(&mut session, &mut target, "M10000,1:00") == "E01");
+        assert!(reply(&mut session, &mut target, "M0,2:00") == "E01");
+        assert!(reply(&mut session, &mut target, "m0") == "E01");
+
+        /* CS-relative addresses */
+        let mut session = GdbSession::new(GdbAddressing::CsRelative, true, false);
+        assert!(reply(&mut session, &mut target, "m100,2") == "aa55");
+    }
+
+    #[test] fn breakpoints() {
+        let mut target = MockTarget::new();
+        let mut session = GdbSession::new(GdbAddressing::Physical, true, false);
+
+        assert!(reply(&mut session, &mut target, "Z0,7100,1") == "OK");
+        assert!(target.mem[0x7100] == INT3);
+        assert!(session.is_breakpoint(0x7100));
+
+        /* Debugger sees original bytes and writes under breakpoint keep it in place */
+        assert!(reply(&mut session, &mut target, "m70ff,3") == "ff0001");
+        assert!(reply(&mut session, &mut target, "M7100,1:90") == "OK");
+        assert!(target.mem[0x7100] == INT3);
+        assert!(reply(&mut session, &mut target, "m7100,1") == "90");
+
+        assert!(reply(&mut session, &mut target, "z0,7100,1") == "OK");
+        assert!(target.mem[0x7100] == 0x90);
+        assert!(reply(&mut session, &mut target, "z0,7100,1") == "E01");
+        assert!(reply(&mut session, &mut target, "Z0,10000,1") == "E01");
+
+        /* Hardware breakpoints and watchpoints aren't supported */
+        assert!(reply(&mut session, &mut target, "Z1,7100,1") == "");
+
+        /* CS-relative breakpoint lands at CS base + offset, detach restores it */
+        let mut session = GdbSession::new(GdbAddressing::CsRelative, true, false);
+        assert!(reply(&mut session, &mut target, "Z0,200,1") == "OK");
+        assert!(target.mem[0x7200] == INT3);
+        session.detach(&mut target);
+        assert!(target.mem[0x7200] == 0x00);
+        assert!(session.breakpoints.is_empty());
+    }
+
+    #[test] fn resume() {
+        let mut target = MockTarget::new();
+        let mut session = GdbSession::new(GdbAddressing::Physical, false, false);
+
+        assert!(session.handle(&mut target, b"c") == Action::Resume(GdbResume::Continue));
+        assert!(session.handle(&mut target, b"c7200") == Action::Resume(GdbResume::Continue));
+        assert!(target.regs[GDB_REG_EIP] == 0x200);
+
+        /* Step is refused without vcpu support */
+        assert!(reply(&mut session, &mut target, "s") == "E01");
+        let mut session = GdbSession::new(GdbAddressing::Physical, true, false);
+        assert!(session.handle(&mut target, b"s") == Action::Resume(GdbResume::Step));
+
+        /* Reverse execution only with a recording to go back through */
+        assert!(reply(&mut session, &mut target, "bs") == "E01");
+        let mut session = GdbSession::new(GdbAddressing::Physical, true, true);
+        assert!(session.handle(&mut target, b"bs") == Action::Resume(GdbResume::ReverseStep));
+        assert!(session.handle(&mut target, b"bc") == Action::Resume(GdbResume::ReverseContinue));
+        assert!(reply(&mut session, &mut target, "bx") == "E01");
+
+        assert!(session.handle(&mut target, b"D") == Action::Detach);
+        assert!(session.handle(&mut target, b"k") == Action::Kill);
+    }
+
+    #[test] fn queries() {
+        let mut target = MockTarget::new();
+        let mut session = GdbSession::new(GdbAddressing::Physical, true, false);
+
+        assert!(reply(&mut session, &mut target, "?") == "S05");
+        session.stop = GdbStop::Breakpoint;
+        assert!(reply(&mut session, &mut target, "?") == "T05swbreak:;");
+        session.stop = GdbStop::Interrupt;
+        assert!(reply(&mut session, &mut target, "?") == "S02");
+        session.stop = GdbStop::Vector(0x08);
+        assert!(reply(&mut session, &mut target, "?") == "T05vector:08;");
+        session.stop = GdbStop::ReplayBegin;
+        assert!(reply(&mut session, &mut target, "?") == "T05replaylog:begin;");
+
+        assert!(reply(&mut session, &mut target, "qSupported:multiprocess+;swbreak+") == "PacketSize=1000;swbreak+;QStartNoAckMode+");
+        assert!(reply(&mut session, &mut target, "qAttached") == "1");
+        assert!(reply(&mut session, &mut target, "qfThreadInfo") == "m1");
+        assert!(reply(&mut session, &mut target, "qsThreadInfo") == "l");
+        assert!(reply(&mut session, &mut target, "qTStatus") == "");
+        assert!(reply(&mut session, &mut target, "Hg0") == "OK");
+        assert!(reply(&mut session, &mut target, "vMustReplyEmpty") == "");
+
+        assert!(!session.no_ack);
+        assert!(reply(&mut session, &mut target, "QStartNoAckMode") == "OK");
+        assert!(session.no_ack);
+
+        let mut session = GdbSession::new(GdbAddressing::Physical, true, true);
+        assert!(reply(&mut session, &mut target, "qSupported") == "PacketSize=1000;swbreak+;QStartNoAckMode+;ReverseStep+;ReverseContinue+");
+    }
+}
@@ -0,0 +1,354 @@
+/*
+ * Hang detection
+ *
+ * Guest spinning on a port that never changes isn't dead to VMM, it keeps exiting. Hang detector tells it from
+ * a guest getting work done by what it does over windows of guest time:
+ *
+ *   - distinct IPs guest exited at, sampled from exits taken anyway
+ *   - interrupts delivered to it
+ *   - distinct ports it accessed
+ *
+ * A window with a handful of IPs and ports and no interrupts is stuck, a run of them lasting --hang-detect
+ * seconds is a hang. Detector logs it, logs a crash report of guest state or stops VM with one, see
+ * config::HangAction, and holds off until guest makes progress again. Guest spinning without exits at all is
+ * caught by a timer kicking vcpu out once a window.
+ */
+
+use vm;
+use config::{self, HangAction};
+use crash;
+use clock;
+use event;
+use eventlog;
+
+use std::collections::HashSet;
+use std::fmt;
+use std::mem;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Guest time a window covers, detector timer kicks vcpu as often
+const WINDOW_NS: u64 = 1000000000;
+const WINDOW_US: u64 = WINDOW_NS / 1000;
+
+// Window with this many distinct IPs and ports at most and no interrupts is stuck
+const STUCK_IPS: usize = 4;
+const STUCK_PORTS: usize = 2;
+
+// Distinct IPs and ports kept per window, far over what counts as progress
+const MAX_DISTINCT: usize = 64;
+
+/* Progress signals of one window */
+#[derive(Default)]
+struct Window
+{
+    ips: HashSet<u64>,
+    ports: HashSet<u16>,
+    irqs: u64,
+}
+
+impl Window
+{
+    fn stuck(&self) -> bool {
+        self.ips.len() <= STUCK_IPS && self.ports.len() <= STUCK_PORTS && self.irqs == 0
+    }
+
+    fn merge(&mut self, other: Window) {
+        self.ips.extend(other.ips);
+        self.ports.extend(other.ports);
+        self.irqs += other.irqs;
+    }
+}
+
+/**
+ * Guest making no progress, with the IPs and ports it kept to
+ */
+#[derive(Clone, PartialEq, Debug)]
+pub struct Hang
+{
+    pub seconds: u64,
+    pub ips: Vec<u64>,          // Linear addresses, ascending
+    pub ports: Vec<u16>,        // Ascending
+}
+
+impl fmt::Display for Hang
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ips: Vec<String> = self.ips.iter().map(|ip| format!("{:x}", ip)).collect();
+        try!(write!(f, "Guest made no progress for {} s, spinning at {}", self.seconds, ips.join(" ")));
+        if !self.ports.is_empty() {
+            let ports: Vec<String> = self.ports.iter().map(|port| format!("0x{:04x}", port)).collect();
+            try!(write!(f, " on ports {}", ports.join(" ")));
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Progress signals over consecutive windows of guest time
+ */
+pub struct HangDetector
+{
+    windows: u64,               // Stuck windows in a row making a hang
+    window_end: u64,            // Guest time current window ends at
+    current: Window,
+    stuck: Window,              // Signals of stuck windows in a row so far
+    stuck_windows: u64,
+    hung: bool,                 // Hang reported, guest has to make progress for another one
+}
+
+impl HangDetector
+{
+    /**
+     * Detector taking seconds of no progress for a hang, first window starts at guest time now
+     */
+    pub fn new(seconds: u32, now: u64) -> HangDetector {
+        HangDetector {
+            windows: seconds as u64 * 1000000000 / WINDOW_NS,
+            window_end: now + WINDOW_NS,
+            current: Window::default(),
+            stuck: Window::default(),
+            stuck_windows: 0,
+            hung: false,
+        }
+    }
+
+    pub fn sample_ip(&mut self, ip: u64) {
+        if self.current.ips.len() < MAX_DISTINCT {
+            self.current.ips.insert(ip);
+        }
+    }
+
+    pub fn port(&mut self, port: u16) {
+        if self.current.ports.len() < MAX_DISTINCT {
+            self.current.ports.insert(port);
+        }
+    }
+
+    pub fn interrupt(&mut self) {
+        self.current.irqs += 1;
+    }
+
+    /**
+     * Close windows ended by guest time now, hang if they make a new one
+     * Windows nothing happened in, as when vcpu didn't run, count as stuck.
+     */
+    pub fn check(&mut self, now: u64) -> Option<Hang> {
+        let mut hang = None;
+
+        while now >= self.window_end {
+            self.window_end += WINDOW_NS;
+
+            let window = mem::replace(&mut self.current, Window::default());
+            if !window.stuck() {
+                self.stuck = Window::default();
+                self.stuck_windows = 0;
+                self.hung = false;
+                continue;
+            }
+
+            self.stuck.merge(window);
+            self.stuck_windows += 1;
+            if self.stuck_windows >= self.windows && !self.hung {
+                self.hung = true;
+
+                let mut ips: Vec<u64> = self.stuck.ips.iter().cloned().collect();
+                let mut ports: Vec<u16> = self.stuck.ports.iter().cloned().collect();
+                ips.sort();
+                ports.sort();
+                hang = Some(Hang {
+                    seconds: self.stuck_windows * WINDOW_NS / 1000000000,
+                    ips: ips,
+                    ports: ports,
+                });
+            }
+        }
+
+        hang
+    }
+}
+
+#[cfg(test)]
+mod hang_test
+{
+    use super::*;
+
+    /* Guest polling a port that is never ready, with the detector timer kicking it out now and then */
+    fn spin(detector: &mut HangDetector, start: u64, ms: u64) -> Option<Hang> {
+        let mut hang = None;
+        for i in 0..ms {
+            let now = start + i * 1000000;
+            hang = hang.or(detector.check(now));
+            detector.sample_ip(0x7c05);
+            detector.port(0x64);
+            if i % 100 == 0 {
+                detector.sample_ip(0x7c07 + (i / 100) % 2 * 2);
+            }
+        }
+        hang
+    }
+
+    #[test] fn spinning_guest() {
+        let mut detector = HangDetector::new(3, 0);
+
+        assert!(spin(&mut detector, 0, 3000).is_none());
+        let hang = detector.check(3000000000).unwrap();
+        assert!(hang == Hang { seconds: 3, ips: vec![0x7c05, 0x7c07, 0x7c09], ports: vec![0x64] });
+        assert!(hang.to_string() == "Guest made no progress for 3 s, spinning at 7c05 7c07 7c09 on ports 0x0064");
+
+        /* One report for a hang, the next one takes progress in between */
+        assert!(spin(&mut detector, 3000000000, 5000).is_none());
+        detector.interrupt();
+        assert!(detector.check(8000000000).is_none());
+        assert!(spin(&mut detector, 8000000000, 3500).is_some());
+    }
+
+    #[test] fn progressing_guest() {
+        let mut detector = HangDetector::new(2, 0);
+
+        /* Code walking through memory */
+        for i in 0..10000u64 {
+            assert!(detector.check(i * 1000000).is_none());
+            detector.sample_ip(0x8000 + i % 100 * 3);
+            detector.port(0x1F0);
+        }
+
+        /* Polling loop taking timer interrupts */
+        for i in 10000..20000u64 {
+            assert!(detector.check(i * 1000000).is_none());
+            detector.sample_ip(0x7c40);
+            detector.port(0x608);
+            if i % 10 == 0 {
+                detector.interrupt();
+            }
+        }
+
+        /* Port diversity alone is progress as well */
+        for i in 20000..30000u64 {
+            assert!(detector.check(i * 1000000).is_none());
+            detector.sample_ip(0x7c40);
+            detector.port(0x100 + (i % 8) as u16);
+        }
+    }
+
+    #[test] fn idle_windows() {
+        /* Vcpu not running through whole windows is no progress either */
+        let mut detector = HangDetector::new(2, 500);
+        detector.sample_ip(0x7c00);
+        assert!(detector.check(1000000499).is_none());
+        assert!(detector.check(2000000500).unwrap() == Hang { seconds: 2, ips: vec![0x7c00], ports: vec![] });
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct HangState
+{
+    detector: HangDetector,
+    action: HangAction,
+    crash_dir: Option<String>,
+}
+
+lazy_static! {
+    static ref ENABLED: AtomicBool = AtomicBool::new(false);
+    static ref HANG: Mutex<Option<HangState>> = Mutex::new(None);
+}
+
+/* Do what config says about a hang, vcpu thread only */
+fn hang_detected(hang: Hang, action: HangAction, crash_dir: Option<String>)
+{
+    eventlog::emit(|| eventlog::Event::HangDetected { seconds: hang.seconds, ips: hang.ips.len(), ports: hang.ports.len() });
+
+    if action == HangAction::Log {
+        warn!("{}", hang);
+        return;
+    }
+
+    let report = crash::capture("Guest hung", hang.to_string()).to_string();
+    if action == HangAction::Stop {
+        vm::request_vm_exit(vm::VmExit::HangDetected(report));
+        return;
+    }
+
+    error!("{}", report);
+    if let Some(dir) = crash_dir {
+        match crash::save(&report, &dir) {
+            Ok(path) => error!("Crash report saved to {}", path.display()),
+            Err(err) => error!("Can't save crash report to {}: {}", dir, err),
+        }
+    }
+}
+
+/**
+ * Sample guest linear IP at an exit and close windows due, vcpu thread only
+ */
+pub fn sample(ip: u64)
+{
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let res = match *HANG.lock().unwrap() {
+        Some(ref mut state) => {
+            let hang = state.detector.check(clock::guest_time_ns());
+            state.detector.sample_ip(ip);
+            hang.map(|hang| (hang, state.action, state.crash_dir.clone()))
+        },
+        None => None,
+    };
+
+    if let Some((hang, action, crash_dir)) = res {
+        hang_detected(hang, action, crash_dir);
+    }
+}
+
+/**
+ * Count guest port access as progress signal
+ */
+pub fn io_access(port: u16)
+{
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    if let Some(ref mut state) = *HANG.lock().unwrap() {
+        state.detector.port(port);
+    }
+}
+
+/**
+ * Count interrupt delivered to guest as progress signal
+ */
+pub fn interrupt_delivered()
+{
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    if let Some(ref mut state) = *HANG.lock().unwrap() {
+        state.detector.interrupt();
+    }
+}
+
+/* Exit guest once a window so spinning without exits gets sampled */
+fn kick_event(ev: event::Event)
+{
+    vm::interrupt_guest();
+    event::schedule_event(WINDOW_US, ev);
+}
+
+pub fn init(config: &config::VmConfig)
+{
+    let hang = match config.hang {
+        Some(ref hang) => hang,
+        None => return,
+    };
+
+    *HANG.lock().unwrap() = Some(HangState {
+        detector: HangDetector::new(hang.seconds, clock::guest_time_ns()),
+        action: hang.action,
+        crash_dir: config.crash_dir.clone(),
+    });
+    ENABLED.store(true, Ordering::Relaxed);
+    event::schedule_event(WINDOW_US, event::create_event(kick_event));
+}
@@ -0,0 +1,135 @@
+/*
+ * Hypercalls issued by guest with VMCALL
+ *
+ * EAX holds hypercall number, EBX, ECX and EDX are arguments. Result is returned in EAX, HC_ERROR for unknown
+ * hypercalls. Addresses passed in arguments are guest physical.
+ * HC_FEATURES is always there: it returns HC_SIGNATURE in EAX and feature bits of registered devices in EBX,
+ * so a guest can tell it runs on xvm before using anything else.
+ */
+
+use dma;
+
+pub const HC_FEATURES: u32      = 0x0000;
+pub const HC_SIGNATURE: u32     = 0x314D5658;   // "XVM1"
+pub const HC_ERROR: u32         = 0xFFFFFFFF;
+
+/**
+ * Guest registers passed to and returned from hypercall
+ */
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct HypercallRegs
+{
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/**
+ * Hypercall provided by a device
+ */
+#[derive(Clone, Copy)]
+pub struct Hypercall
+{
+    pub nr: u32,
+    pub feature: u32,       // Bit reported by HC_FEATURES
+    pub handler: fn(&dma::dma_memory, &mut HypercallRegs),
+}
+
+/* Perform hypercall in EAX using table of known ones */
+fn dispatch(table: &[Hypercall], mem: &dma::dma_memory, regs: &mut HypercallRegs)
+{
+    if regs.eax == HC_FEATURES {
+        regs.eax = HC_SIGNATURE;
+        regs.ebx = table.iter().fold(0, |features, hc| features | hc.feature);
+        return;
+    }
+
+    match table.iter().find(|hc| hc.nr == regs.eax) {
+        Some(hc) => (hc.handler)(mem, regs),
+        None => {
+            debug!("hypercall: unknown hypercall {:x}", regs.eax);
+            regs.eax = HC_ERROR;
+        }
+    }
+}
+
+#[cfg(test)]
+mod hypercall_test
+{
+    use super::*;
+
+    struct NoMemory;
+
+    impl dma::dma_memory for NoMemory
+    {
+        fn read(&self, _: u64, _: &mut [u8]) -> usize {
+            0
+        }
+
+        fn write(&self, _: u64, _: &[u8]) -> usize {
+            0
+        }
+    }
+
+    fn add(_: &dma::dma_memory, regs: &mut HypercallRegs) {
+        regs.eax = regs.ebx + regs.ecx;
+    }
+
+    #[test] fn features_and_dispatch() {
+        let table = [
+            Hypercall { nr: 0x10, feature: 0x01, handler: add },
+            Hypercall { nr: 0x11, feature: 0x04, handler: add },
+        ];
+
+        let mut regs = HypercallRegs { eax: HC_FEATURES, ..Default::default() };
+        dispatch(&table, &NoMemory, &mut regs);
+        assert!(regs.eax == HC_SIGNATURE && regs.ebx == 0x05);
+
+        let mut regs = HypercallRegs { eax: 0x11, ebx: 2, ecx: 3, edx: 0 };
+        dispatch(&table, &NoMemory, &mut regs);
+        assert!(regs.eax == 5);
+
+        let mut regs = HypercallRegs { eax: 0x12, ebx: 2, ecx: 3, edx: 0 };
+        dispatch(&table, &NoMemory, &mut regs);
+        assert!(regs.eax == HC_ERROR && regs.ebx == 2);
+
+        /* Nothing registered, guest still sees the signature */
+        let mut regs = HypercallRegs { eax: HC_FEATURES, ebx: 0xFF, ..Default::default() };
+        dispatch(&[], &NoMemory, &mut regs);
+        assert!(regs.eax == HC_SIGNATURE && regs.ebx == 0);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+static mut HYPERCALLS: Option<*mut Vec<Hypercall>> = None;
+
+/**
+ * Make hypercall available to guest
+ */
+pub fn register_hypercall(hc: Hypercall)
+{
+    unsafe {
+        if HYPERCALLS.is_none() {
+            HYPERCALLS = Some(Box::into_raw(Box::new(Vec::new())));
+        }
+
+        let table = &mut *HYPERCALLS.unwrap();
+        assert!(hc.nr != HC_FEATURES && table.iter().all(|known| known.nr != hc.nr));
+        table.push(hc);
+    }
+}
+
+/**
+ * Handle guest VMCALL
+ */
+pub fn handle_hypercall(regs: &mut HypercallRegs)
+{
+    unsafe {
+        match HYPERCALLS {
+            Some(table) => dispatch(&*table, &dma::GuestMemory, regs),
+            None => dispatch(&[], &dma::GuestMemory, regs),
+        }
+    }
+}
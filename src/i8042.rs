@@ -0,0 +1,1444 @@
+/*
+ * i8042 keyboard controller emulation
+ *
+ * Controller has two devices attached: a PS/2 keyboard on the first port and a PS/2 mouse on the
+ * auxiliary port. Both devices feed a single output queue which is drained by the guest through
+ * the data port one byte at a time.
+ */
+
+use vm;
+use record;
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+const I8042_DATA_PORT: u16      = 0x60;
+const I8042_CMD_PORT: u16       = 0x64;
+
+const I8042_IRQ_KBD: u8         = 1;
+const I8042_IRQ_AUX: u8         = 12;
+
+// Maximum number of bytes buffered in controller output queue
+const I8042_QUEUE_SIZE: usize   = 16;
+
+// Status register bits
+const I8042_STR_OBF: u8         = 0x01; // Output buffer full
+const I8042_STR_IBF: u8         = 0x02; // Input buffer full
+const I8042_STR_SYS: u8         = 0x04; // System flag
+const I8042_STR_CMD: u8         = 0x08; // Last write was to command port
+const I8042_STR_KEYLOCK: u8     = 0x10; // Keyboard not inhibited
+const I8042_STR_AUXB: u8        = 0x20; // Output buffer holds aux data
+
+// Command byte bits
+const I8042_CTR_KBDINT: u8      = 0x01; // Keyboard interrupt enable
+const I8042_CTR_AUXINT: u8      = 0x02; // Aux interrupt enable
+const I8042_CTR_SYS: u8         = 0x04; // System flag
+const I8042_CTR_KBDDIS: u8      = 0x10; // Keyboard clock disabled
+const I8042_CTR_AUXDIS: u8      = 0x20; // Aux clock disabled
+const I8042_CTR_XLATE: u8       = 0x40; // Set 2 to set 1 translation
+const I8042_CTR_DEFAULT: u8     = I8042_CTR_KBDINT | I8042_CTR_SYS | I8042_CTR_AUXDIS | I8042_CTR_XLATE;
+
+// Controller commands
+const I8042_CMD_READ_CTR: u8    = 0x20;
+const I8042_CMD_WRITE_CTR: u8   = 0x60;
+const I8042_CMD_AUX_DISABLE: u8 = 0xA7;
+const I8042_CMD_AUX_ENABLE: u8  = 0xA8;
+const I8042_CMD_AUX_TEST: u8    = 0xA9;
+const I8042_CMD_SELF_TEST: u8   = 0xAA;
+const I8042_CMD_KBD_TEST: u8    = 0xAB;
+const I8042_CMD_KBD_DISABLE: u8 = 0xAD;
+const I8042_CMD_KBD_ENABLE: u8  = 0xAE;
+const I8042_CMD_READ_OUTPORT: u8 = 0xD0;
+const I8042_CMD_WRITE_OUTPORT: u8 = 0xD1;
+const I8042_CMD_WRITE_AUX: u8   = 0xD4;
+const I8042_CMD_A20_DISABLE: u8 = 0xDD;
+const I8042_CMD_A20_ENABLE: u8  = 0xDF;
+const I8042_CMD_PULSE_BASE: u8  = 0xF0; // 0xF0-0xFF pulse output port bits 0-3 low
+const I8042_CMD_RESET: u8       = 0xFE; // Pulse reset line
+
+// Output port bits
+const I8042_OUT_RESET: u8       = 0x01; // CPU reset line, active low
+const I8042_OUT_A20: u8         = 0x02; // A20 gate
+const I8042_OUT_KBD_OBF: u8     = 0x10; // Keyboard output buffer full
+const I8042_OUT_AUX_OBF: u8     = 0x20; // Aux output buffer full
+
+// Common PS/2 device responses
+const PS2_ACK: u8               = 0xFA;
+const PS2_RESEND: u8            = 0xFE;
+const PS2_SELF_TEST_OK: u8      = 0xAA;
+
+// PS/2 device commands shared by keyboard and mouse
+const PS2_CMD_RESET: u8         = 0xFF;
+const PS2_CMD_SET_DEFAULTS: u8  = 0xF6;
+const PS2_CMD_DISABLE: u8       = 0xF5;
+const PS2_CMD_ENABLE: u8        = 0xF4;
+const PS2_CMD_GET_ID: u8        = 0xF2;
+const PS2_CMD_RESEND: u8        = 0xFE;
+
+// PS/2 keyboard commands
+const KBD_CMD_SET_LEDS: u8      = 0xED;
+const KBD_CMD_ECHO: u8          = 0xEE;
+const KBD_CMD_SCANCODE_SET: u8  = 0xF0; // Parameter 0 reports current set
+const KBD_CMD_SET_TYPEMATIC: u8 = 0xF3;
+const KBD_CMD_SET3_FIRST: u8    = 0xF7; // F7-FD only matter in scancode set 3
+const KBD_CMD_SET3_LAST: u8     = 0xFD;
+const KBD_CMD_FIRST: u8         = KBD_CMD_SET_LEDS; // Lower bytes are parameters
+
+// Keyboard identification, second byte is 0x41 after translation
+const KBD_ID: [u8; 2]           = [0xAB, 0x83];
+
+const KBD_LED_MASK: u8          = vm::KBD_LED_SCROLL_LOCK | vm::KBD_LED_NUM_LOCK | vm::KBD_LED_CAPS_LOCK;
+const KBD_TYPEMATIC_MASK: u8    = 0x7F;
+
+// Keyboard scancode prefixes
+const KBD_EXTENDED_PREFIX: u8   = 0xE0;
+const KBD_BREAK_PREFIX: u8      = 0xF0; // Set 2 break codes
+const KBD_BREAK_BIT: u8         = 0x80; // Set 1 break codes
+
+const KBD_DEFAULT_SET: u8       = 2;
+
+// PS/2 mouse commands
+const MOUSE_CMD_SET_RATE: u8    = 0xF3;
+const MOUSE_CMD_SET_STREAM: u8  = 0xEA;
+const MOUSE_CMD_STATUS: u8      = 0xE9;
+const MOUSE_CMD_SET_RES: u8     = 0xE8;
+const MOUSE_CMD_SCALING_2_1: u8 = 0xE7;
+const MOUSE_CMD_SCALING_1_1: u8 = 0xE6;
+
+const MOUSE_DEFAULT_RATE: u8    = 100;
+const MOUSE_DEFAULT_RES: u8     = 2;    // 4 counts/mm
+
+// Device IDs and sample rate sequences switching to them
+const MOUSE_ID_STANDARD: u8     = 0x00;
+const MOUSE_ID_WHEEL: u8        = 0x03; // IntelliMouse, 4 byte packets with Z delta
+const MOUSE_ID_5BUTTON: u8      = 0x04; // IntelliMouse Explorer, Z delta and buttons 4 and 5
+const MOUSE_WHEEL_MAGIC: [u8; 3] = [200, 100, 80];
+const MOUSE_5BUTTON_MAGIC: [u8; 3] = [200, 200, 80];
+
+/*
+ * Movement packet first byte bits
+ */
+const MOUSE_PKT_ALWAYS_1: u8    = 0x08;
+const MOUSE_PKT_X_SIGN: u8      = 0x10;
+const MOUSE_PKT_Y_SIGN: u8      = 0x20;
+const MOUSE_PKT_X_OVERFLOW: u8  = 0x40;
+const MOUSE_PKT_Y_OVERFLOW: u8  = 0x80;
+
+/*
+ * Fourth packet byte of 5 button mouse, wheel mouse has just Z delta there
+ */
+const MOUSE_PKT_Z_MASK: u8      = 0x0F;
+const MOUSE_PKT_BUTTON_4: u8    = 0x10;
+const MOUSE_PKT_BUTTON_5: u8    = 0x20;
+
+// Z delta range representable in 4 bits
+const MOUSE_Z_MIN: i32          = -8;
+const MOUSE_Z_MAX: i32          = 7;
+
+/**
+ * Convert movement delta into 9-bit two's complement packet encoding
+ * Returns low 8 bits of encoded delta, sign bit and overflow bit
+ */
+fn encode_delta(delta: i32) -> (u8, bool, bool) {
+    let overflow = delta < -256 || delta > 255;
+    let clamped = if delta < -256 {
+        -256
+    } else if delta > 255 {
+        255
+    } else {
+        delta
+    };
+
+    (clamped as u8, clamped < 0, overflow)
+}
+
+/**
+ * Controller translation of set 2 codes into set 1, indexed by set 2 byte
+ */
+const SET2_TO_SET1: [u8; 128] = [
+    0xFF, 0x43, 0x41, 0x3F, 0x3D, 0x3B, 0x3C, 0x58, 0x64, 0x44, 0x42, 0x40, 0x3E, 0x0F, 0x29, 0x59,
+    0x65, 0x38, 0x2A, 0x70, 0x1D, 0x10, 0x02, 0x5A, 0x66, 0x71, 0x2C, 0x1F, 0x1E, 0x11, 0x03, 0x5B,
+    0x67, 0x2E, 0x2D, 0x20, 0x12, 0x05, 0x04, 0x5C, 0x68, 0x39, 0x2F, 0x21, 0x14, 0x13, 0x06, 0x5D,
+    0x69, 0x31, 0x30, 0x23, 0x22, 0x15, 0x07, 0x5E, 0x6A, 0x72, 0x32, 0x24, 0x16, 0x08, 0x09, 0x5F,
+    0x6B, 0x33, 0x25, 0x17, 0x18, 0x0B, 0x0A, 0x60, 0x6C, 0x34, 0x35, 0x26, 0x27, 0x19, 0x0C, 0x61,
+    0x6D, 0x73, 0x28, 0x74, 0x1A, 0x0D, 0x62, 0x6E, 0x3A, 0x36, 0x1C, 0x1B, 0x75, 0x2B, 0x63, 0x76,
+    0x55, 0x56, 0x77, 0x78, 0x79, 0x7A, 0x0E, 0x7B, 0x7C, 0x4F, 0x7D, 0x4B, 0x47, 0x7E, 0x7F, 0x6F,
+    0x52, 0x53, 0x50, 0x4C, 0x4D, 0x48, 0x01, 0x45, 0x57, 0x4E, 0x51, 0x4A, 0x37, 0x49, 0x46, 0x54,
+];
+
+/* Codes above the table pass through, except F7 which sits out of order in set 2 */
+fn translate_set2(val: u8) -> u8 {
+    match val {
+        0x00...0x7F => SET2_TO_SET1[val as usize],
+        0x83 => 0x41,
+        0x84 => 0x54,
+        _ => val,
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/**
+ * PS/2 keyboard on the first i8042 port
+ *
+ * Commands taking a parameter are ACKed and then wait for it. Invalid parameters get RESEND and
+ * the keyboard keeps waiting, a command byte instead of a parameter cancels the pending command.
+ * Scanning is suspended meanwhile: key bytes are held back until the exchange completes.
+ */
+struct PS2Keyboard
+{
+    enabled: bool,          // Scanning enabled
+    scancode_set: u8,       // Scancode set keys are reported in (1 or 2)
+    pending_cmd: Option<u8>,// Command waiting for a parameter byte
+    leds: u8,               // LED state (KBD_LED_*)
+    held: Vec<u8>,          // Key bytes produced while waiting for a parameter
+    last_out: u8,           // Last byte sent, repeated on RESEND
+}
+
+impl PS2Keyboard
+{
+    fn new() -> PS2Keyboard {
+        PS2Keyboard {
+            enabled: true,
+            scancode_set: KBD_DEFAULT_SET,
+            pending_cmd: None,
+            leds: 0,
+            held: Vec::new(),
+            last_out: PS2_ACK,
+        }
+    }
+
+    fn set_defaults(&mut self) {
+        self.enabled = true;
+        self.scancode_set = KBD_DEFAULT_SET;
+        self.pending_cmd = None;
+        self.held.clear();
+    }
+
+    /* Handle a byte sent to keyboard and push response bytes to out */
+    fn write(&mut self, val: u8, out: &mut Vec<u8>) {
+        match self.pending_cmd {
+            Some(cmd) if val < KBD_CMD_FIRST => self.write_param(cmd, val, out),
+            _ => {
+                self.pending_cmd = None;
+                self.write_command(val, out);
+            }
+        }
+
+        if self.pending_cmd.is_none() {
+            out.extend(self.held.drain(..));
+        }
+
+        if let Some(last) = out.last() {
+            self.last_out = *last;
+        }
+    }
+
+    /* Parameter byte for pending command */
+    fn write_param(&mut self, cmd: u8, val: u8, out: &mut Vec<u8>) {
+        let valid = match cmd {
+            KBD_CMD_SET_LEDS => (val & !KBD_LED_MASK) == 0,
+            KBD_CMD_SET_TYPEMATIC => (val & !KBD_TYPEMATIC_MASK) == 0,
+            KBD_CMD_SCANCODE_SET => val <= 3,
+            _ => panic!(),
+        };
+
+        if !valid {
+            debug!("i8042: bad parameter {:x} for keyboard command {:x}", val, cmd);
+            out.push(PS2_RESEND);
+            return;
+        }
+
+        self.pending_cmd = None;
+        out.push(PS2_ACK);
+
+        match cmd {
+            KBD_CMD_SET_LEDS => self.leds = val,
+
+            /* Host provides key repeat, rate is only reported */
+            KBD_CMD_SET_TYPEMATIC => debug!("i8042: typematic delay {}ms, rate {:x}",
+                                            250 * (((val >> 5) & 0x3) as u32 + 1), val & 0x1F),
+
+            KBD_CMD_SCANCODE_SET => match val {
+                0 => out.push(self.scancode_set),
+                1 | 2 => self.scancode_set = val,
+                _ => debug!("i8042: unsupported scancode set {}", val),
+            },
+
+            _ => panic!(),
+        }
+    }
+
+    fn write_command(&mut self, val: u8, out: &mut Vec<u8>) {
+        match val {
+            PS2_CMD_RESET => {
+                self.set_defaults();
+                self.leds = 0;
+                out.push(PS2_ACK);
+                out.push(PS2_SELF_TEST_OK);
+            },
+
+            PS2_CMD_RESEND => {
+                out.push(self.last_out);
+            },
+
+            PS2_CMD_SET_DEFAULTS => {
+                self.set_defaults();
+                out.push(PS2_ACK);
+            },
+
+            PS2_CMD_DISABLE => {
+                self.set_defaults();
+                self.enabled = false;
+                out.push(PS2_ACK);
+            },
+
+            PS2_CMD_ENABLE => {
+                self.enabled = true;
+                out.push(PS2_ACK);
+            },
+
+            PS2_CMD_GET_ID => {
+                out.push(PS2_ACK);
+                out.extend_from_slice(&KBD_ID);
+            },
+
+            KBD_CMD_SCANCODE_SET | KBD_CMD_SET_LEDS | KBD_CMD_SET_TYPEMATIC => {
+                self.pending_cmd = Some(val);
+                out.push(PS2_ACK);
+            },
+
+            KBD_CMD_ECHO => {
+                out.push(KBD_CMD_ECHO);
+            },
+
+            KBD_CMD_SET3_FIRST...KBD_CMD_SET3_LAST => {
+                out.push(PS2_ACK);
+            },
+
+            _ => {
+                debug!("i8042: unsupported keyboard command {:x}", val);
+                out.push(PS2_RESEND);
+            }
+        }
+    }
+
+    /* Encode key event, or hold it back while a parameter exchange is in progress */
+    fn key_event(&mut self, code: u16, pressed: bool, out: &mut Vec<u8>) {
+        if self.pending_cmd.is_none() {
+            self.key_bytes(code, pressed, out);
+            return;
+        }
+
+        let mut bytes = Vec::new();
+        self.key_bytes(code, pressed, &mut bytes);
+        if self.held.len() + bytes.len() <= I8042_QUEUE_SIZE {
+            self.held.extend(bytes);
+        }
+    }
+
+    /*
+     * Encode key event in current scancode set.
+     * Key code is set 2 make code with optional 0xE0 prefix in high byte.
+     */
+    fn key_bytes(&self, code: u16, pressed: bool, out: &mut Vec<u8>) {
+        if !self.enabled {
+            return;
+        }
+
+        if (code >> 8) as u8 == KBD_EXTENDED_PREFIX {
+            out.push(KBD_EXTENDED_PREFIX);
+        }
+
+        let make = code as u8;
+        if self.scancode_set == 1 {
+            let make = translate_set2(make);
+            out.push(if pressed { make } else { make | KBD_BREAK_BIT });
+        } else {
+            if !pressed {
+                out.push(KBD_BREAK_PREFIX);
+            }
+            out.push(make);
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/**
+ * PS/2 mouse on the i8042 auxiliary port
+ *
+ * Standard 3 byte packets until guest unlocks IntelliMouse extensions with sample rate sequences:
+ * 200, 100, 80 adds wheel Z delta as fourth byte, 200, 200, 80 adds buttons 4 and 5 next to it.
+ */
+struct PS2Mouse
+{
+    reporting: bool,        // Data reporting enabled in stream mode
+    rate: u8,               // Sample rate (samples/sec)
+    resolution: u8,         // Resolution (0..3)
+    scaling_2_1: bool,      // 2:1 scaling enabled
+    pending_cmd: Option<u8>,// Command waiting for a parameter byte
+    buttons: u8,            // Last reported button state
+    id: u8,                 // Device ID selecting packet format
+    rate_history: [u8; 3],  // Last sample rates set, oldest first
+}
+
+impl PS2Mouse
+{
+    fn new() -> PS2Mouse {
+        PS2Mouse {
+            reporting: false,
+            rate: MOUSE_DEFAULT_RATE,
+            resolution: MOUSE_DEFAULT_RES,
+            scaling_2_1: false,
+            pending_cmd: None,
+            buttons: 0,
+            id: MOUSE_ID_STANDARD,
+            rate_history: [0; 3],
+        }
+    }
+
+    fn set_defaults(&mut self) {
+        self.reporting = false;
+        self.rate = MOUSE_DEFAULT_RATE;
+        self.resolution = MOUSE_DEFAULT_RES;
+        self.scaling_2_1 = false;
+        self.pending_cmd = None;
+        self.rate_history = [0; 3];
+    }
+
+    fn device_id(&self) -> u8 {
+        self.id
+    }
+
+    /* Buttons the negotiated packet format can report */
+    fn button_mask(&self) -> u8 {
+        if self.id == MOUSE_ID_5BUTTON {
+            vm::MOUSE_BUTTON_LEFT | vm::MOUSE_BUTTON_RIGHT | vm::MOUSE_BUTTON_MIDDLE | vm::MOUSE_BUTTON_4 | vm::MOUSE_BUTTON_5
+        } else {
+            vm::MOUSE_BUTTON_LEFT | vm::MOUSE_BUTTON_RIGHT | vm::MOUSE_BUTTON_MIDDLE
+        }
+    }
+
+    /* Extensions are unlocked by setting magic sequences of sample rates */
+    fn set_rate(&mut self, rate: u8) {
+        self.rate = rate;
+        self.rate_history = [self.rate_history[1], self.rate_history[2], rate];
+
+        if self.rate_history == MOUSE_WHEEL_MAGIC && self.id == MOUSE_ID_STANDARD {
+            self.id = MOUSE_ID_WHEEL;
+        } else if self.rate_history == MOUSE_5BUTTON_MAGIC && self.id != MOUSE_ID_5BUTTON {
+            self.id = MOUSE_ID_5BUTTON;
+        }
+    }
+
+    /* Handle a byte sent to mouse and push response bytes to out */
+    fn write(&mut self, val: u8, out: &mut Vec<u8>) {
+        /* Parameter bytes for previous command */
+        if let Some(cmd) = self.pending_cmd.take() {
+            match cmd {
+                MOUSE_CMD_SET_RATE => self.set_rate(val),
+                MOUSE_CMD_SET_RES => self.resolution = val & 0x3,
+                _ => panic!(),
+            }
+
+            out.push(PS2_ACK);
+            return;
+        }
+
+        match val {
+            PS2_CMD_RESET => {
+                self.set_defaults();
+                self.id = MOUSE_ID_STANDARD;
+                out.push(PS2_ACK);
+                out.push(PS2_SELF_TEST_OK);
+                out.push(self.device_id());
+            },
+
+            PS2_CMD_SET_DEFAULTS => {
+                self.set_defaults();
+                out.push(PS2_ACK);
+            },
+
+            PS2_CMD_DISABLE => {
+                self.reporting = false;
+                out.push(PS2_ACK);
+            },
+
+            PS2_CMD_ENABLE => {
+                self.reporting = true;
+                out.push(PS2_ACK);
+            },
+
+            PS2_CMD_GET_ID => {
+                out.push(PS2_ACK);
+                out.push(self.device_id());
+            },
+
+            MOUSE_CMD_SET_RATE | MOUSE_CMD_SET_RES => {
+                self.pending_cmd = Some(val);
+                out.push(PS2_ACK);
+            },
+
+            MOUSE_CMD_STATUS => {
+                let mut status = self.buttons & 0x7;
+                if self.scaling_2_1 {
+                    status |= 0x10;
+                }
+                if self.reporting {
+                    status |= 0x20;
+                }
+
+                out.push(PS2_ACK);
+                out.push(status);
+                out.push(self.resolution);
+                out.push(self.rate);
+            },
+
+            MOUSE_CMD_SCALING_1_1 => {
+                self.scaling_2_1 = false;
+                out.push(PS2_ACK);
+            },
+
+            MOUSE_CMD_SCALING_2_1 => {
+                self.scaling_2_1 = true;
+                out.push(PS2_ACK);
+            },
+
+            MOUSE_CMD_SET_STREAM => {
+                out.push(PS2_ACK);
+            },
+
+            _ => {
+                debug!("i8042: unsupported mouse command {:x}", val);
+                out.push(PS2_RESEND);
+            }
+        }
+    }
+
+    /*
+     * Build movement packet in format of current device ID.
+     * Returns None if reporting is disabled or event only has wheel or buttons the format lacks.
+     */
+    fn make_packet(&mut self, dx: i32, dy: i32, dz: i32, buttons: u8) -> Option<Vec<u8>> {
+        if !self.reporting {
+            return None;
+        }
+
+        let shown_buttons = buttons & self.button_mask();
+        let shown_dz = if self.id == MOUSE_ID_STANDARD { 0 } else { dz };
+        if dx == 0 && dy == 0 && shown_dz == 0 && shown_buttons == self.buttons && (dz != 0 || buttons != shown_buttons) {
+            return None;
+        }
+
+        let buttons = shown_buttons;
+
+        self.buttons = buttons;
+
+        let (x, x_sign, x_overflow) = encode_delta(dx);
+        let (y, y_sign, y_overflow) = encode_delta(dy);
+
+        let mut b0 = MOUSE_PKT_ALWAYS_1 | (buttons & 0x7);
+        if x_sign {
+            b0 |= MOUSE_PKT_X_SIGN;
+        }
+        if y_sign {
+            b0 |= MOUSE_PKT_Y_SIGN;
+        }
+        if x_overflow {
+            b0 |= MOUSE_PKT_X_OVERFLOW;
+        }
+        if y_overflow {
+            b0 |= MOUSE_PKT_Y_OVERFLOW;
+        }
+
+        let mut packet = vec![b0, x, y];
+
+        /* Wheel moving away from user is negative Z */
+        let z = ::std::cmp::max(MOUSE_Z_MIN, ::std::cmp::min(MOUSE_Z_MAX, -shown_dz)) as u8;
+        match self.id {
+            MOUSE_ID_WHEEL => packet.push(z),
+            MOUSE_ID_5BUTTON => {
+                let mut b3 = z & MOUSE_PKT_Z_MASK;
+                if (buttons & vm::MOUSE_BUTTON_4) != 0 {
+                    b3 |= MOUSE_PKT_BUTTON_4;
+                }
+                if (buttons & vm::MOUSE_BUTTON_5) != 0 {
+                    b3 |= MOUSE_PKT_BUTTON_5;
+                }
+                packet.push(b3);
+            },
+            _ => {},
+        }
+
+        Some(packet)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/**
+ * i8042 controller
+ */
+struct I8042
+{
+    ctr: u8,                        // Controller command byte
+    status: u8,                     // Status register (OBF and AUXB are derived from out)
+    out: Option<(u8, bool)>,        // Output buffer contents and whether it came from aux
+    queue: VecDeque<(u8, bool)>,    // Bytes waiting to be moved to output buffer
+    pending_cmd: Option<u8>,        // Controller command waiting for data port write
+    xlate_break: bool,              // Translation saw a break prefix, next byte gets break bit
+    a20: bool,                      // A20 gate state as seen by output port
+    reset_requested: bool,          // Guest pulsed CPU reset line
+    kbd: PS2Keyboard,
+    mouse: PS2Mouse,
+}
+
+impl I8042
+{
+    fn new() -> I8042 {
+        I8042 {
+            ctr: I8042_CTR_DEFAULT,
+            status: I8042_STR_SYS | I8042_STR_KEYLOCK,
+            out: None,
+            queue: VecDeque::new(),
+            pending_cmd: None,
+            xlate_break: false,
+            a20: true,
+            reset_requested: false,
+            kbd: PS2Keyboard::new(),
+            mouse: PS2Mouse::new(),
+        }
+    }
+
+    fn read_status(&self) -> u8 {
+        let mut status = self.status;
+        match self.out {
+            Some((_, is_aux)) => {
+                status |= I8042_STR_OBF;
+                if is_aux {
+                    status |= I8042_STR_AUXB;
+                }
+            },
+            None => {},
+        }
+
+        status
+    }
+
+    /* Queue bytes produced by keyboard or controller itself */
+    fn push_kbd(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.queue.push_back((*b, false));
+        }
+    }
+
+    /*
+     * Queue bytes produced by keyboard device, passing them through set 2 to set 1
+     * translation if enabled in command byte
+     */
+    fn push_kbd_device(&mut self, bytes: &[u8]) {
+        if (self.ctr & I8042_CTR_XLATE) == 0 {
+            self.push_kbd(bytes);
+            return;
+        }
+
+        for b in bytes {
+            if *b == KBD_BREAK_PREFIX {
+                self.xlate_break = true;
+                continue;
+            }
+
+            let mut val = translate_set2(*b);
+            if self.xlate_break {
+                self.xlate_break = false;
+                val |= KBD_BREAK_BIT;
+            }
+
+            self.queue.push_back((val, false));
+        }
+    }
+
+    /* Queue bytes produced by aux device */
+    fn push_aux(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.queue.push_back((*b, true));
+        }
+    }
+
+    /*
+     * Move next queued byte into output buffer if it is empty.
+     * Returns IRQ line to assert for the newly loaded byte, if any.
+     */
+    fn service(&mut self) -> Option<u8> {
+        if self.out.is_some() {
+            return None;
+        }
+
+        let next = match self.queue.pop_front() {
+            Some(next) => next,
+            None => return None,
+        };
+
+        self.out = Some(next);
+
+        let (_, is_aux) = next;
+        if is_aux && (self.ctr & I8042_CTR_AUXINT) != 0 {
+            Some(I8042_IRQ_AUX)
+        } else if !is_aux && (self.ctr & I8042_CTR_KBDINT) != 0 {
+            Some(I8042_IRQ_KBD)
+        } else {
+            None
+        }
+    }
+
+    /* Compose output port value */
+    fn read_outport(&self) -> u8 {
+        let mut val = I8042_OUT_RESET;
+        if self.a20 {
+            val |= I8042_OUT_A20;
+        }
+
+        match self.out {
+            Some((_, true)) => val |= I8042_OUT_AUX_OBF,
+            Some((_, false)) => val |= I8042_OUT_KBD_OBF,
+            None => {},
+        }
+
+        val
+    }
+
+    fn write_outport(&mut self, val: u8) {
+        self.a20 = (val & I8042_OUT_A20) != 0;
+        if (val & I8042_OUT_RESET) == 0 {
+            self.reset_requested = true;
+        }
+    }
+
+    /* Check and clear pending CPU reset request */
+    fn take_reset_request(&mut self) -> bool {
+        let res = self.reset_requested;
+        self.reset_requested = false;
+        res
+    }
+
+    fn read_data(&mut self) -> u8 {
+        match self.out.take() {
+            Some((val, _)) => val,
+            None => 0,
+        }
+    }
+
+    fn write_command(&mut self, cmd: u8) {
+        self.status |= I8042_STR_CMD;
+        self.pending_cmd = None;
+
+        match cmd {
+            I8042_CMD_READ_CTR => {
+                let ctr = self.ctr;
+                self.push_kbd(&[ctr]);
+            },
+
+            I8042_CMD_WRITE_CTR | I8042_CMD_WRITE_AUX | I8042_CMD_WRITE_OUTPORT => {
+                self.pending_cmd = Some(cmd);
+            },
+
+            I8042_CMD_READ_OUTPORT => {
+                let val = self.read_outport();
+                self.push_kbd(&[val]);
+            },
+
+            I8042_CMD_A20_DISABLE => {
+                self.a20 = false;
+            },
+
+            I8042_CMD_A20_ENABLE => {
+                self.a20 = true;
+            },
+
+            I8042_CMD_PULSE_BASE ... 0xFF => {
+                /* Only the reset line is wired to anything */
+                if (cmd & I8042_OUT_RESET) == 0 {
+                    self.reset_requested = true;
+                }
+            },
+
+            I8042_CMD_AUX_DISABLE => {
+                self.ctr |= I8042_CTR_AUXDIS;
+            },
+
+            I8042_CMD_AUX_ENABLE => {
+                self.ctr &= !I8042_CTR_AUXDIS;
+            },
+
+            I8042_CMD_AUX_TEST => {
+                self.push_kbd(&[0x00]);
+            },
+
+            I8042_CMD_SELF_TEST => {
+                self.push_kbd(&[0x55]);
+            },
+
+            I8042_CMD_KBD_TEST => {
+                self.push_kbd(&[0x00]);
+            },
+
+            I8042_CMD_KBD_DISABLE => {
+                self.ctr |= I8042_CTR_KBDDIS;
+            },
+
+            I8042_CMD_KBD_ENABLE => {
+                self.ctr &= !I8042_CTR_KBDDIS;
+            },
+
+            _ => {
+                debug!("i8042: unsupported controller command {:x}", cmd);
+            }
+        }
+    }
+
+    fn write_data(&mut self, val: u8) {
+        self.status &= !I8042_STR_CMD;
+
+        let mut resp = Vec::new();
+
+        match self.pending_cmd.take() {
+            Some(I8042_CMD_WRITE_CTR) => {
+                self.ctr = val;
+                self.status = (self.status & !I8042_STR_SYS) | (val & I8042_CTR_SYS);
+            },
+
+            Some(I8042_CMD_WRITE_OUTPORT) => {
+                self.write_outport(val);
+            },
+
+            Some(I8042_CMD_WRITE_AUX) => {
+                /* Writing to aux device implicitly enables aux clock */
+                self.ctr &= !I8042_CTR_AUXDIS;
+                self.mouse.write(val, &mut resp);
+                self.push_aux(&resp);
+            },
+
+            Some(_) => panic!(),
+
+            None => {
+                self.ctr &= !I8042_CTR_KBDDIS;
+                self.kbd.write(val, &mut resp);
+                self.push_kbd_device(&resp);
+            }
+        }
+    }
+
+    /*
+     * Feed host key event to keyboard device.
+     * Like mouse packets, whole scancode sequences are dropped if they don't fit the queue.
+     */
+    fn key_event(&mut self, code: u16, pressed: bool) {
+        if (self.ctr & I8042_CTR_KBDDIS) != 0 {
+            return;
+        }
+
+        let mut bytes = Vec::new();
+        self.kbd.key_event(code, pressed, &mut bytes);
+
+        if self.queue.len() + bytes.len() > I8042_QUEUE_SIZE {
+            debug!("i8042: output queue full, dropping key event");
+            return;
+        }
+
+        self.push_kbd_device(&bytes);
+    }
+
+    /*
+     * Feed host mouse movement to aux device.
+     * Packets are queued as a whole or dropped as a whole if controller queue is full, so that
+     * guest never observes a partial packet.
+     */
+    fn mouse_event(&mut self, dx: i32, dy: i32, dz: i32, buttons: u8) {
+        if (self.ctr & I8042_CTR_AUXDIS) != 0 {
+            return;
+        }
+
+        let packet = match self.mouse.make_packet(dx, dy, dz, buttons) {
+            Some(packet) => packet,
+            None => return,
+        };
+
+        if self.queue.len() + packet.len() > I8042_QUEUE_SIZE {
+            debug!("i8042: output queue full, dropping mouse packet");
+            return;
+        }
+
+        self.push_aux(&packet);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod i8042_test
+{
+    use super::*;
+    use std::cell::Cell;
+    use vm::io_handler;
+
+    fn read_all(dev: &mut I8042) -> Vec<u8> {
+        let mut res = Vec::new();
+        loop {
+            dev.service();
+            if (dev.read_status() & I8042_STR_OBF) == 0 {
+                break;
+            }
+            res.push(dev.read_data());
+        }
+        res
+    }
+
+    fn write_aux(dev: &mut I8042, val: u8) -> Vec<u8> {
+        dev.write_command(I8042_CMD_WRITE_AUX);
+        dev.write_data(val);
+        read_all(dev)
+    }
+
+    /* Detection and setup sequence performed by a typical PS/2 mouse driver */
+    fn detect_mouse(dev: &mut I8042) {
+        dev.write_command(I8042_CMD_AUX_ENABLE);
+
+        dev.write_command(I8042_CMD_READ_CTR);
+        let ctr = read_all(dev);
+        assert!(ctr.len() == 1);
+        assert!((ctr[0] & I8042_CTR_AUXDIS) == 0);
+
+        dev.write_command(I8042_CMD_WRITE_CTR);
+        dev.write_data(ctr[0] | I8042_CTR_AUXINT);
+
+        dev.write_command(I8042_CMD_AUX_TEST);
+        assert!(read_all(dev) == vec![0x00]);
+
+        assert!(write_aux(dev, PS2_CMD_RESET) == vec![PS2_ACK, PS2_SELF_TEST_OK, 0x00]);
+        assert!(write_aux(dev, PS2_CMD_GET_ID) == vec![PS2_ACK, 0x00]);
+        assert!(write_aux(dev, MOUSE_CMD_SET_RATE) == vec![PS2_ACK]);
+        assert!(write_aux(dev, 40) == vec![PS2_ACK]);
+        assert!(write_aux(dev, MOUSE_CMD_SET_RES) == vec![PS2_ACK]);
+        assert!(write_aux(dev, 3) == vec![PS2_ACK]);
+        assert!(write_aux(dev, MOUSE_CMD_STATUS) == vec![PS2_ACK, 0x00, 3, 40]);
+        assert!(write_aux(dev, PS2_CMD_ENABLE) == vec![PS2_ACK]);
+    }
+
+    #[test] fn controller_self_test() {
+        let mut dev = I8042::new();
+
+        dev.write_command(I8042_CMD_SELF_TEST);
+        assert!(read_all(&mut dev) == vec![0x55]);
+
+        dev.write_command(I8042_CMD_KBD_TEST);
+        assert!(read_all(&mut dev) == vec![0x00]);
+    }
+
+    /* Output port reflects A20 state, including changes made from outside (port 0x92) */
+    #[test] fn read_outport() {
+        let mut dev = I8042::new();
+
+        dev.write_command(I8042_CMD_READ_OUTPORT);
+        let val = read_all(&mut dev);
+        assert!(val == vec![I8042_OUT_RESET | I8042_OUT_A20]);
+
+        dev.a20 = false;
+        dev.write_command(I8042_CMD_READ_OUTPORT);
+        let val = read_all(&mut dev);
+        assert!(val == vec![I8042_OUT_RESET]);
+    }
+
+    /* Classic A20 enable sequence: D1 command, wait for IBF clear, write output port */
+    #[test] fn a20_enable() {
+        let mut dev = I8042::new();
+        dev.a20 = false;
+
+        dev.write_command(I8042_CMD_WRITE_OUTPORT);
+        assert!((dev.read_status() & I8042_STR_IBF) == 0);
+        dev.write_data(0xDF);
+        assert!((dev.read_status() & I8042_STR_IBF) == 0);
+
+        assert!(dev.a20);
+        assert!(!dev.take_reset_request());
+
+        /* Without A20 1 MiB wraps to 0, with A20 it doesn't */
+        assert!(::vm::a20_mask(0x100000, false) == 0);
+        assert!(::vm::a20_mask(0x100000, dev.a20) == 0x100000);
+
+        dev.write_command(I8042_CMD_A20_DISABLE);
+        assert!(!dev.a20);
+        dev.write_command(I8042_CMD_A20_ENABLE);
+        assert!(dev.a20);
+    }
+
+    #[test] fn cpu_reset() {
+        let mut dev = I8042::new();
+
+        /* Writing output port with reset bit clear */
+        dev.write_command(I8042_CMD_WRITE_OUTPORT);
+        dev.write_data(I8042_OUT_A20);
+        assert!(dev.take_reset_request());
+        assert!(!dev.take_reset_request());
+
+        /* Pulse reset line directly */
+        dev.write_command(I8042_CMD_RESET);
+        assert!(dev.take_reset_request());
+
+        /* Pulsing other lines does not reset */
+        dev.write_command(0xFD);
+        assert!(!dev.take_reset_request());
+    }
+
+    struct ResetProbe
+    {
+        count: Cell<u32>,
+    }
+
+    impl vm::reset_handler for ResetProbe
+    {
+        fn reset(&self)
+        {
+            self.count.set(self.count.get() + 1);
+        }
+    }
+
+    /* Reset pulsed through the command port goes to the VM, whose reset brings controller back to power on */
+    #[test] fn reset_through_vm() {
+        vm::create();
+
+        let dev = Rc::new(I8042Dev {
+            i8042: RefCell::new(I8042::new()),
+        });
+        let probe = Rc::new(ResetProbe { count: Cell::new(0) });
+        vm::register_reset_handler(dev.clone());
+        vm::register_reset_handler(probe.clone());
+
+        /* Guest changes command byte and leaves it unread in output buffer, interrupts off */
+        dev.io_write(I8042_CMD_PORT, vm::IoOperandType::byte(I8042_CMD_WRITE_CTR));
+        dev.io_write(I8042_DATA_PORT, vm::IoOperandType::byte(I8042_CTR_SYS | I8042_CTR_KBDDIS));
+        dev.io_write(I8042_CMD_PORT, vm::IoOperandType::byte(I8042_CMD_READ_CTR));
+        assert!((dev.i8042.borrow().read_status() & I8042_STR_OBF) != 0);
+        assert!(!vm::take_reset_request());
+
+        dev.io_write(I8042_CMD_PORT, vm::IoOperandType::byte(I8042_CMD_RESET));
+        assert!(vm::take_reset_request());
+        assert!(!vm::take_reset_request());
+        vm::reset_devices();
+
+        assert!(probe.count.get() == 1);
+        let i8042 = dev.i8042.borrow();
+        assert!(i8042.ctr == I8042_CTR_DEFAULT);
+        assert!(i8042.out.is_none() && i8042.queue.is_empty());
+        assert!(i8042.read_status() == (I8042_STR_SYS | I8042_STR_KEYLOCK));
+        assert!(!i8042.reset_requested);
+    }
+
+    #[test] fn aux_enable_disable() {
+        let mut dev = I8042::new();
+
+        dev.write_command(I8042_CMD_AUX_ENABLE);
+        assert!((dev.ctr & I8042_CTR_AUXDIS) == 0);
+
+        dev.write_command(I8042_CMD_AUX_DISABLE);
+        assert!((dev.ctr & I8042_CTR_AUXDIS) != 0);
+    }
+
+    #[test] fn mouse_detect_and_move() {
+        let mut dev = I8042::new();
+        detect_mouse(&mut dev);
+
+        dev.mouse_event(5, -3, 0, 0x1);
+
+        /* First packet byte raises IRQ12 and sets AUXB */
+        assert!(dev.service() == Some(I8042_IRQ_AUX));
+        assert!((dev.read_status() & (I8042_STR_OBF | I8042_STR_AUXB)) == (I8042_STR_OBF | I8042_STR_AUXB));
+
+        let b0 = dev.read_data();
+        assert!(b0 == MOUSE_PKT_ALWAYS_1 | MOUSE_PKT_Y_SIGN | 0x1);
+
+        let rest = read_all(&mut dev);
+        assert!(rest == vec![5, (-3i32) as u8]);
+    }
+
+    #[test] fn mouse_overflow() {
+        let mut dev = I8042::new();
+        detect_mouse(&mut dev);
+
+        dev.mouse_event(300, -300, 0, 0x2);
+        let packet = read_all(&mut dev);
+
+        assert!(packet.len() == 3);
+        assert!(packet[0] == MOUSE_PKT_ALWAYS_1 | MOUSE_PKT_Y_SIGN
+                             | MOUSE_PKT_X_OVERFLOW | MOUSE_PKT_Y_OVERFLOW | 0x2);
+        assert!(packet[1] == 255);
+        assert!(packet[2] == 0x00); // -256 in 9-bit two's complement
+    }
+
+    #[test] fn mouse_reporting_disabled() {
+        let mut dev = I8042::new();
+        detect_mouse(&mut dev);
+
+        assert!(write_aux(&mut dev, PS2_CMD_DISABLE) == vec![PS2_ACK]);
+        dev.mouse_event(1, 1, 0, 0);
+        assert!(read_all(&mut dev).is_empty());
+    }
+
+    /* Slow guest: queue fills up and only whole packets are kept */
+    #[test] fn mouse_queue_overflow() {
+        let mut dev = I8042::new();
+        detect_mouse(&mut dev);
+
+        for i in 0..10 {
+            dev.mouse_event(i, 0, 0, 0);
+        }
+
+        let bytes = read_all(&mut dev);
+        assert!(bytes.len() == (I8042_QUEUE_SIZE / 3) * 3);
+
+        for (i, packet) in bytes.chunks(3).enumerate() {
+            assert!((packet[0] & MOUSE_PKT_ALWAYS_1) != 0);
+            assert!(packet[1] == i as u8);
+        }
+    }
+
+    fn set_rates(dev: &mut I8042, rates: &[u8]) {
+        for rate in rates {
+            assert!(write_aux(dev, MOUSE_CMD_SET_RATE) == vec![PS2_ACK]);
+            assert!(write_aux(dev, *rate) == vec![PS2_ACK]);
+        }
+    }
+
+    /* Wheel and extra buttons are dropped when guest didn't ask for them */
+    #[test] fn mouse_standard_extensions_dropped() {
+        let mut dev = I8042::new();
+        detect_mouse(&mut dev);
+        set_rates(&mut dev, &[200, 100, 60]);
+        assert!(write_aux(&mut dev, PS2_CMD_GET_ID) == vec![PS2_ACK, MOUSE_ID_STANDARD]);
+        assert!(write_aux(&mut dev, PS2_CMD_ENABLE) == vec![PS2_ACK]);
+
+        dev.mouse_event(0, 0, 3, 0);
+        dev.mouse_event(0, 0, 0, vm::MOUSE_BUTTON_4);
+        assert!(read_all(&mut dev).is_empty());
+
+        dev.mouse_event(-2, 7, -1, vm::MOUSE_BUTTON_5 | vm::MOUSE_BUTTON_RIGHT);
+        assert!(read_all(&mut dev) == vec![MOUSE_PKT_ALWAYS_1 | MOUSE_PKT_X_SIGN | 0x2, 0xFE, 7]);
+    }
+
+    #[test] fn mouse_wheel() {
+        let mut dev = I8042::new();
+        detect_mouse(&mut dev);
+        set_rates(&mut dev, &MOUSE_WHEEL_MAGIC);
+        assert!(write_aux(&mut dev, PS2_CMD_GET_ID) == vec![PS2_ACK, MOUSE_ID_WHEEL]);
+        assert!(write_aux(&mut dev, PS2_CMD_ENABLE) == vec![PS2_ACK]);
+
+        /* Wheel away from user is negative, button 4 has no place in packet */
+        dev.mouse_event(1, -1, 2, vm::MOUSE_BUTTON_LEFT | vm::MOUSE_BUTTON_4);
+        assert!(read_all(&mut dev) == vec![MOUSE_PKT_ALWAYS_1 | MOUSE_PKT_Y_SIGN | 0x1, 1, 0xFF, 0xFE]);
+
+        /* Z is clamped to what 4 bits hold, X and Y overflow as usual */
+        dev.mouse_event(256, 0, -20, vm::MOUSE_BUTTON_LEFT);
+        assert!(read_all(&mut dev) == vec![MOUSE_PKT_ALWAYS_1 | MOUSE_PKT_X_OVERFLOW | 0x1, 255, 0, 0x07]);
+        dev.mouse_event(0, 0, 100, vm::MOUSE_BUTTON_LEFT);
+        assert!(read_all(&mut dev) == vec![MOUSE_PKT_ALWAYS_1 | 0x1, 0, 0, 0xF8]);
+
+        /* Defaults don't change device ID, reset does */
+        assert!(write_aux(&mut dev, PS2_CMD_SET_DEFAULTS) == vec![PS2_ACK]);
+        assert!(write_aux(&mut dev, PS2_CMD_GET_ID) == vec![PS2_ACK, MOUSE_ID_WHEEL]);
+        assert!(write_aux(&mut dev, PS2_CMD_RESET) == vec![PS2_ACK, PS2_SELF_TEST_OK, MOUSE_ID_STANDARD]);
+        assert!(write_aux(&mut dev, PS2_CMD_GET_ID) == vec![PS2_ACK, MOUSE_ID_STANDARD]);
+    }
+
+    #[test] fn mouse_five_buttons() {
+        let mut dev = I8042::new();
+        detect_mouse(&mut dev);
+        set_rates(&mut dev, &MOUSE_WHEEL_MAGIC);
+        set_rates(&mut dev, &MOUSE_5BUTTON_MAGIC);
+        assert!(write_aux(&mut dev, PS2_CMD_GET_ID) == vec![PS2_ACK, MOUSE_ID_5BUTTON]);
+        assert!(write_aux(&mut dev, PS2_CMD_ENABLE) == vec![PS2_ACK]);
+
+        dev.mouse_event(-300, 300, 1, vm::MOUSE_BUTTON_MIDDLE | vm::MOUSE_BUTTON_5);
+        assert!(read_all(&mut dev) == vec![MOUSE_PKT_ALWAYS_1 | MOUSE_PKT_X_SIGN | MOUSE_PKT_X_OVERFLOW
+                                           | MOUSE_PKT_Y_OVERFLOW | 0x4, 0x00, 255, 0x0F | MOUSE_PKT_BUTTON_5]);
+
+        /* Extra button alone is worth a packet now, Z keeps to low nibble */
+        dev.mouse_event(0, 0, 0, vm::MOUSE_BUTTON_4);
+        assert!(read_all(&mut dev) == vec![MOUSE_PKT_ALWAYS_1, 0, 0, MOUSE_PKT_BUTTON_4]);
+        dev.mouse_event(0, 0, -50, vm::MOUSE_BUTTON_4);
+        assert!(read_all(&mut dev) == vec![MOUSE_PKT_ALWAYS_1, 0, 0, MOUSE_PKT_BUTTON_4 | 0x07]);
+
+        /* Five button mode can be entered directly too */
+        assert!(write_aux(&mut dev, PS2_CMD_RESET) == vec![PS2_ACK, PS2_SELF_TEST_OK, MOUSE_ID_STANDARD]);
+        set_rates(&mut dev, &MOUSE_5BUTTON_MAGIC);
+        assert!(write_aux(&mut dev, PS2_CMD_GET_ID) == vec![PS2_ACK, MOUSE_ID_5BUTTON]);
+    }
+
+    fn write_kbd(dev: &mut I8042, val: u8) -> Vec<u8> {
+        dev.write_data(val);
+        read_all(dev)
+    }
+
+    fn set_xlate(dev: &mut I8042, on: bool) {
+        let ctr = if on { I8042_CTR_DEFAULT } else { I8042_CTR_DEFAULT & !I8042_CTR_XLATE };
+        dev.write_command(I8042_CMD_WRITE_CTR);
+        dev.write_data(ctr);
+    }
+
+    fn key_press_release(dev: &mut I8042, code: u16) -> Vec<u8> {
+        dev.key_event(code, true);
+        dev.key_event(code, false);
+        read_all(dev)
+    }
+
+    /* Same key in every combination of device scancode set and controller translation */
+    #[test] fn scancode_sets() {
+        let mut dev = I8042::new();
+        const KEY_A: u16 = 0x1C;
+        const KEY_RIGHT_CTRL: u16 = 0xE014;
+
+        /* Power on: set 2 translated to set 1 */
+        assert!(key_press_release(&mut dev, KEY_A) == vec![0x1E, 0x9E]);
+        assert!(key_press_release(&mut dev, KEY_RIGHT_CTRL) == vec![0xE0, 0x1D, 0xE0, 0x9D]);
+
+        /* Raw set 2 */
+        set_xlate(&mut dev, false);
+        assert!(key_press_release(&mut dev, KEY_A) == vec![0x1C, 0xF0, 0x1C]);
+        assert!(key_press_release(&mut dev, KEY_RIGHT_CTRL) == vec![0xE0, 0x14, 0xE0, 0xF0, 0x14]);
+
+        /* Raw set 1 */
+        assert!(write_kbd(&mut dev, KBD_CMD_SCANCODE_SET) == vec![PS2_ACK]);
+        assert!(write_kbd(&mut dev, 1) == vec![PS2_ACK]);
+        assert!(write_kbd(&mut dev, KBD_CMD_SCANCODE_SET) == vec![PS2_ACK]);
+        assert!(write_kbd(&mut dev, 0) == vec![PS2_ACK, 1]);
+        assert!(key_press_release(&mut dev, KEY_A) == vec![0x1E, 0x9E]);
+        assert!(key_press_release(&mut dev, KEY_RIGHT_CTRL) == vec![0xE0, 0x1D, 0xE0, 0x9D]);
+
+        /* Set 1 translated again, as real controllers do */
+        set_xlate(&mut dev, true);
+        assert!(key_press_release(&mut dev, KEY_A) == vec![0x03, 0x9E]);
+
+        /* Set query reply goes through translation too */
+        assert!(write_kbd(&mut dev, KBD_CMD_SCANCODE_SET) == vec![PS2_ACK]);
+        assert!(write_kbd(&mut dev, 2) == vec![PS2_ACK]);
+        assert!(write_kbd(&mut dev, KBD_CMD_SCANCODE_SET) == vec![PS2_ACK]);
+        assert!(write_kbd(&mut dev, 0) == vec![PS2_ACK, 0x41]);
+        assert!(key_press_release(&mut dev, KEY_A) == vec![0x1E, 0x9E]);
+
+        /* Reset returns to set 2 */
+        set_xlate(&mut dev, false);
+        assert!(write_kbd(&mut dev, KBD_CMD_SCANCODE_SET) == vec![PS2_ACK]);
+        assert!(write_kbd(&mut dev, 1) == vec![PS2_ACK]);
+        assert!(write_kbd(&mut dev, PS2_CMD_RESET) == vec![PS2_ACK, PS2_SELF_TEST_OK]);
+        assert!(key_press_release(&mut dev, KEY_A) == vec![0x1C, 0xF0, 0x1C]);
+    }
+
+    #[test] fn keyboard_irq_and_disable() {
+        let mut dev = I8042::new();
+
+        dev.key_event(0x1C, true);
+        assert!(dev.service() == Some(I8042_IRQ_KBD));
+        assert!(dev.read_data() == 0x1E);
+
+        /* Interrupt disabled: byte is still delivered by polling */
+        dev.write_command(I8042_CMD_WRITE_CTR);
+        dev.write_data(I8042_CTR_DEFAULT & !I8042_CTR_KBDINT);
+        dev.key_event(0x1C, false);
+        assert!(dev.service() == None);
+        assert!((dev.read_status() & I8042_STR_OBF) != 0);
+        assert!(dev.read_data() == 0x9E);
+
+        /* Keyboard clock disabled: no key bytes at all */
+        dev.write_command(I8042_CMD_KBD_DISABLE);
+        dev.key_event(0x1C, true);
+        assert!(read_all(&mut dev).is_empty());
+
+        /* Scanning disabled in keyboard itself */
+        dev.write_command(I8042_CMD_KBD_ENABLE);
+        assert!(write_kbd(&mut dev, PS2_CMD_DISABLE) == vec![PS2_ACK]);
+        dev.key_event(0x1C, true);
+        assert!(read_all(&mut dev).is_empty());
+    }
+
+    #[test] fn keyboard_commands() {
+        let mut dev = I8042::new();
+
+        /* Identity is translated along with everything else */
+        assert!(write_kbd(&mut dev, PS2_CMD_GET_ID) == vec![PS2_ACK, 0xAB, 0x41]);
+        set_xlate(&mut dev, false);
+        assert!(write_kbd(&mut dev, PS2_CMD_GET_ID) == vec![PS2_ACK, 0xAB, 0x83]);
+
+        assert!(write_kbd(&mut dev, KBD_CMD_SET_LEDS) == vec![PS2_ACK]);
+        assert!(write_kbd(&mut dev, 0x07) == vec![PS2_ACK]);
+        assert!(dev.kbd.leds == 0x07);
+
+        /* Bad parameter is refused and keyboard keeps waiting for a good one */
+        assert!(write_kbd(&mut dev, KBD_CMD_SET_LEDS) == vec![PS2_ACK]);
+        assert!(write_kbd(&mut dev, 0x10) == vec![PS2_RESEND]);
+        assert!(dev.kbd.leds == 0x07);
+        assert!(write_kbd(&mut dev, vm::KBD_LED_NUM_LOCK) == vec![PS2_ACK]);
+        assert!(dev.kbd.leds == vm::KBD_LED_NUM_LOCK);
+
+        assert!(write_kbd(&mut dev, KBD_CMD_SET_TYPEMATIC) == vec![PS2_ACK]);
+        assert!(write_kbd(&mut dev, 0x2B) == vec![PS2_ACK]);
+        assert!(write_kbd(&mut dev, KBD_CMD_SET_TYPEMATIC) == vec![PS2_ACK]);
+        assert!(write_kbd(&mut dev, 0x80) == vec![PS2_RESEND]);
+
+        /* Command in place of parameter cancels pending one */
+        assert!(write_kbd(&mut dev, KBD_CMD_ECHO) == vec![KBD_CMD_ECHO]);
+        assert!(write_kbd(&mut dev, 0x00) == vec![PS2_RESEND]);
+        assert!(write_kbd(&mut dev, PS2_CMD_RESEND) == vec![PS2_RESEND]);
+        assert!(write_kbd(&mut dev, KBD_CMD_ECHO) == vec![KBD_CMD_ECHO]);
+        assert!(write_kbd(&mut dev, PS2_CMD_RESEND) == vec![KBD_CMD_ECHO]);
+        assert!(write_kbd(&mut dev, 0xF8) == vec![PS2_ACK]);
+
+        /* Disable also restores defaults, set defaults keeps scanning on */
+        assert!(write_kbd(&mut dev, KBD_CMD_SCANCODE_SET) == vec![PS2_ACK]);
+        assert!(write_kbd(&mut dev, 1) == vec![PS2_ACK]);
+        assert!(write_kbd(&mut dev, PS2_CMD_DISABLE) == vec![PS2_ACK]);
+        assert!(key_press_release(&mut dev, 0x1C).is_empty());
+        assert!(write_kbd(&mut dev, PS2_CMD_SET_DEFAULTS) == vec![PS2_ACK]);
+        assert!(key_press_release(&mut dev, 0x1C) == vec![0x1C, 0xF0, 0x1C]);
+        assert!(dev.kbd.leds == vm::KBD_LED_NUM_LOCK);
+
+        assert!(write_kbd(&mut dev, PS2_CMD_RESET) == vec![PS2_ACK, PS2_SELF_TEST_OK]);
+        assert!(dev.kbd.leds == 0);
+    }
+
+    /* Keystroke during LED update comes after the exchange instead of in the middle of it */
+    #[test] fn keyboard_param_interleave() {
+        let mut dev = I8042::new();
+
+        assert!(write_kbd(&mut dev, KBD_CMD_SET_LEDS) == vec![PS2_ACK]);
+        dev.key_event(0x1C, true);
+        assert!(read_all(&mut dev).is_empty());
+        assert!(write_kbd(&mut dev, vm::KBD_LED_CAPS_LOCK) == vec![PS2_ACK, 0x1E]);
+        assert!(dev.kbd.leds == vm::KBD_LED_CAPS_LOCK);
+
+        /* Held keys also follow a refused parameter's eventual completion */
+        assert!(write_kbd(&mut dev, KBD_CMD_SET_LEDS) == vec![PS2_ACK]);
+        dev.key_event(0x1C, false);
+        assert!(write_kbd(&mut dev, 0xEC) == vec![PS2_RESEND]);
+        assert!(write_kbd(&mut dev, 0) == vec![PS2_ACK, 0x9E]);
+        assert!(dev.kbd.leds == 0);
+
+        /* Cancelling command releases them too, reset drops them */
+        assert!(write_kbd(&mut dev, KBD_CMD_SET_TYPEMATIC) == vec![PS2_ACK]);
+        dev.key_event(0x1C, true);
+        assert!(write_kbd(&mut dev, PS2_CMD_ENABLE) == vec![PS2_ACK, 0x1E]);
+        assert!(write_kbd(&mut dev, KBD_CMD_SET_TYPEMATIC) == vec![PS2_ACK]);
+        dev.key_event(0x1C, false);
+        assert!(write_kbd(&mut dev, PS2_CMD_RESET) == vec![PS2_ACK, PS2_SELF_TEST_OK]);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct I8042Dev
+{
+    i8042: RefCell<I8042>,
+}
+
+impl I8042Dev
+{
+    /* Load next byte in output buffer and raise an interrupt for it */
+    fn service(&self, dev: &mut I8042) {
+        match dev.service() {
+            Some(irq) => vm::assert_irq(irq),
+            None => {},
+        }
+    }
+
+    /* Propagate output port changes to VM */
+    fn update_outport(&self, dev: &mut I8042) {
+        vm::set_a20_enabled(dev.a20);
+
+        if dev.take_reset_request() {
+            debug!("i8042: CPU reset");
+            vm::request_reset();
+        }
+    }
+}
+
+impl vm::io_handler for I8042Dev
+{
+    fn io_read(&self, port: u16, size: u8) -> vm::IoOperandType
+    {
+        assert!(size == 1);
+
+        let mut dev = self.i8042.borrow_mut();
+        dev.a20 = vm::is_a20_enabled();
+
+        let val = match port {
+            I8042_DATA_PORT => dev.read_data(),
+            I8042_CMD_PORT => dev.read_status(),
+            _ => panic!(),
+        };
+
+        self.service(&mut dev);
+        vm::IoOperandType::byte(val)
+    }
+
+    fn io_write(&self, port: u16, data: vm::IoOperandType)
+    {
+        let mut dev = self.i8042.borrow_mut();
+        let data8 = data.unwrap_byte();
+
+        /* A20 state is shared with port 0x92 */
+        dev.a20 = vm::is_a20_enabled();
+
+        match port {
+            I8042_DATA_PORT => dev.write_data(data8),
+            I8042_CMD_PORT => dev.write_command(data8),
+            _ => panic!(),
+        }
+
+        self.update_outport(&mut dev);
+        self.service(&mut dev);
+    }
+}
+
+impl vm::input_device for I8042Dev
+{
+    fn mouse_event(&self, dx: i32, dy: i32, dz: i32, buttons: u8)
+    {
+        record::emit(|| record::Input::Mouse { dx: dx, dy: dy, dz: dz, buttons: buttons });
+        let mut dev = self.i8042.borrow_mut();
+        dev.mouse_event(dx, dy, dz, buttons);
+        self.service(&mut dev);
+    }
+
+    fn key_event(&self, code: u16, pressed: bool)
+    {
+        record::emit(|| record::Input::Key { code: code, pressed: pressed });
+        let mut dev = self.i8042.borrow_mut();
+        dev.key_event(code, pressed);
+        self.service(&mut dev);
+    }
+
+    fn keyboard_leds(&self) -> u8
+    {
+        self.i8042.borrow().kbd.leds
+    }
+}
+
+impl vm::reset_handler for I8042Dev
+{
+    fn reset(&self)
+    {
+        *self.i8042.borrow_mut() = I8042::new();
+    }
+}
+
+pub fn init()
+{
+    let dev = Rc::new(I8042Dev {
+        i8042: RefCell::new(I8042::new()),
+    });
+
+    vm::register_input_device(dev.clone());
+    vm::register_reset_handler(dev.clone());
+
+    vm::register_io_region(dev.clone(), I8042_DATA_PORT, 1);
+    vm::register_io_region(dev.clone(), I8042_CMD_PORT, 1);
+}
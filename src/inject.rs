@@ -0,0 +1,263 @@
+/*
+ * Event injection arbitration
+ *
+ * Guest can have an exception raised by the VMM, an NMI and external interrupts pending all at once, only one
+ * of them is injected on each vm entry. Exception goes first since it belongs to the instruction VMM just
+ * emulated. NMI comes next and doesn't care about IF, but waits while guest is blocked by a previous NMI until
+ * its IRET, or by STI or MOV SS shadow. External interrupts wait for IF, interrupt shadow and any event injected
+ * ahead of them. Whatever can't be injected now asks for an NMI or interrupt window exit, so it gets its turn
+ * as soon as guest can take it.
+ */
+
+use std::fmt;
+
+// VM entry interruption info
+const EVENT_VALID: u32              = 1 << 31;
+const EVENT_DELIVER_ERROR: u32      = 1 << 11;
+const EVENT_TYPE_EXTERNAL: u32      = 0 << 8;
+const EVENT_TYPE_NMI: u32           = 2 << 8;
+const EVENT_TYPE_EXCEPTION: u32     = 3 << 8;
+
+pub const NMI_VECTOR: u8            = 2;
+
+/**
+ * Hardware exception raised by VMM
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Exception
+{
+    pub vector: u8,
+    pub error_code: Option<u32>,
+}
+
+/**
+ * Events pending for guest
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct PendingEvents
+{
+    pub exception: Option<Exception>,
+    pub nmi: bool,
+    pub external: bool,         // Some external interrupt vector is raised
+}
+
+/**
+ * Guest state deciding which events it can take
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct GuestState
+{
+    pub interrupts_enabled: bool,   // RFLAGS.IF
+    pub interrupt_shadow: bool,     // Blocking by STI or MOV SS
+    pub nmi_blocked: bool,          // NMI handler didn't IRET yet
+}
+
+/**
+ * Event to inject on vm entry
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Injection
+{
+    Exception(Exception),
+    Nmi,
+    External(u8),
+}
+
+impl Injection
+{
+    /**
+     * VM entry interruption info and error code to deliver along
+     */
+    pub fn interruption_info(&self) -> (u32, Option<u32>)
+    {
+        match *self {
+            Injection::Exception(exc) => {
+                let deliver = if exc.error_code.is_some() { EVENT_DELIVER_ERROR } else { 0 };
+                (EVENT_VALID | EVENT_TYPE_EXCEPTION | deliver | exc.vector as u32, exc.error_code)
+            },
+            Injection::Nmi => (EVENT_VALID | EVENT_TYPE_NMI | NMI_VECTOR as u32, None),
+            Injection::External(vec) => (EVENT_VALID | EVENT_TYPE_EXTERNAL | vec as u32, None),
+        }
+    }
+}
+
+/**
+ * Kind of event resolved for injection, external interrupt vector is picked when it gets injected
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum EventKind
+{
+    Exception,
+    Nmi,
+    External,
+}
+
+/**
+ * What to do on vm entry: event to inject and window exits to ask for
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Resolution
+{
+    pub inject: Option<EventKind>,
+    pub interrupt_window: bool,
+    pub nmi_window: bool,
+}
+
+/**
+ * Pick the pending event to inject next and window exits for events that have to wait
+ */
+pub fn resolve_next_event(pending: &PendingEvents, guest: &GuestState) -> Resolution
+{
+    let mut res = Resolution {
+        inject: None,
+        interrupt_window: false,
+        nmi_window: false,
+    };
+
+    if pending.exception.is_some() {
+        res.inject = Some(EventKind::Exception);
+    }
+
+    if pending.nmi {
+        if res.inject.is_none() && !guest.nmi_blocked && !guest.interrupt_shadow {
+            res.inject = Some(EventKind::Nmi);
+        } else {
+            res.nmi_window = true;
+        }
+    }
+
+    if pending.external {
+        if res.inject.is_none() && guest.interrupts_enabled && !guest.interrupt_shadow {
+            res.inject = Some(EventKind::External);
+        } else {
+            res.interrupt_window = true;
+        }
+    }
+
+    res
+}
+
+/**
+ * What became of an event raised by hand, e.g. from monitor
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum InjectOutcome
+{
+    Deliver(u8),    // Vector is injected on the next vm entry
+    Queued(u8),     // Vector waits for guest to take it, behind IF, a shadow or other events
+    Latched,        // Controller took the IRQ but raised no vector yet, e.g. behind one in service
+    Masked,         // Controller dropped the IRQ, line is masked or controller isn't initialized
+}
+
+impl InjectOutcome
+{
+    /**
+     * Vector raised for guest, None if controller holds the event back
+     */
+    pub fn vector(&self) -> Option<u8>
+    {
+        match *self {
+            InjectOutcome::Deliver(vec) | InjectOutcome::Queued(vec) => Some(vec),
+            InjectOutcome::Latched | InjectOutcome::Masked => None,
+        }
+    }
+}
+
+impl fmt::Display for InjectOutcome
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InjectOutcome::Deliver(_) => write!(f, "delivered at next entry"),
+            InjectOutcome::Queued(_) => write!(f, "queued until guest can take it"),
+            InjectOutcome::Latched => write!(f, "latched by interrupt controller, no vector raised yet"),
+            InjectOutcome::Masked => write!(f, "suppressed by interrupt controller mask"),
+        }
+    }
+}
+
+/**
+ * Outcome of raising an event of kind with vector, given what next entry resolves to
+ * \param next_external   Vector an external interrupt injection would take next
+ */
+pub fn raised_outcome(kind: EventKind, vec: u8, res: &Resolution, next_external: Option<u8>) -> InjectOutcome
+{
+    let next = res.inject == Some(kind) && (kind != EventKind::External || next_external == Some(vec));
+    if next { InjectOutcome::Deliver(vec) } else { InjectOutcome::Queued(vec) }
+}
+
+#[cfg(test)]
+mod inject_test
+{
+    use super::*;
+
+    const GP: Exception = Exception { vector: 13, error_code: Some(0) };
+
+    fn pending(exception: Option<Exception>, nmi: bool, external: bool) -> PendingEvents {
+        PendingEvents { exception: exception, nmi: nmi, external: external }
+    }
+
+    fn guest(interrupts_enabled: bool, interrupt_shadow: bool, nmi_blocked: bool) -> GuestState {
+        GuestState { interrupts_enabled: interrupts_enabled, interrupt_shadow: interrupt_shadow, nmi_blocked: nmi_blocked }
+    }
+
+    /* Injected event, interrupt window, NMI window */
+    fn resolve(pending: PendingEvents, guest: GuestState) -> (Option<EventKind>, bool, bool) {
+        let res = resolve_next_event(&pending, &guest);
+        (res.inject, res.interrupt_window, res.nmi_window)
+    }
+
+    #[test] fn nothing_pending() {
+        assert!(resolve(pending(None, false, false), guest(true, false, false)) == (None, false, false));
+        assert!(resolve(pending(None, false, false), guest(false, true, true)) == (None, false, false));
+    }
+
+    #[test] fn external_interrupt() {
+        assert!(resolve(pending(None, false, true), guest(true, false, false)) == (Some(EventKind::External), false, false));
+
+        /* IF clear or interrupt shadow waits for interrupt window, NMI blocking doesn't matter */
+        assert!(resolve(pending(None, false, true), guest(false, false, false)) == (None, true, false));
+        assert!(resolve(pending(None, false, true), guest(true, true, false)) == (None, true, false));
+        assert!(resolve(pending(None, false, true), guest(true, false, true)) == (Some(EventKind::External), false, false));
+    }
+
+    #[test] fn nmi_and_external() {
+        /* NMI doesn't wait for IF, interrupt waits behind it */
+        assert!(resolve(pending(None, true, true), guest(false, false, false)) == (Some(EventKind::Nmi), true, false));
+        assert!(resolve(pending(None, true, true), guest(true, false, false)) == (Some(EventKind::Nmi), true, false));
+
+        /* NMI blocked until IRET lets interrupts through and waits for NMI window */
+        assert!(resolve(pending(None, true, false), guest(true, false, true)) == (None, false, true));
+        assert!(resolve(pending(None, true, true), guest(true, false, true)) == (Some(EventKind::External), false, true));
+        assert!(resolve(pending(None, true, true), guest(false, false, true)) == (None, true, true));
+
+        /* Interrupt shadow holds back both */
+        assert!(resolve(pending(None, true, true), guest(true, true, false)) == (None, true, true));
+    }
+
+    #[test] fn exception_first() {
+        assert!(resolve(pending(Some(GP), false, true), guest(true, false, false)) == (Some(EventKind::Exception), true, false));
+        assert!(resolve(pending(Some(GP), true, false), guest(true, false, false)) == (Some(EventKind::Exception), false, true));
+        assert!(resolve(pending(Some(GP), true, true), guest(false, true, true)) == (Some(EventKind::Exception), true, true));
+    }
+
+    #[test] fn interruption_info() {
+        assert!(Injection::External(0x08).interruption_info() == (0x80000008, None));
+        assert!(Injection::Nmi.interruption_info() == (0x80000202, None));
+        assert!(Injection::Exception(GP).interruption_info() == (0x80000B0D, Some(0)));
+        assert!(Injection::Exception(Exception { vector: 6, error_code: None }).interruption_info() == (0x80000306, None));
+    }
+
+    #[test] fn raised_outcome() {
+        let open = resolve_next_event(&pending(None, true, true), &guest(false, false, false));
+        assert!(super::raised_outcome(EventKind::Nmi, NMI_VECTOR, &open, Some(0x0D)) == InjectOutcome::Deliver(2));
+        assert!(super::raised_outcome(EventKind::External, 0x0D, &open, Some(0x0D)) == InjectOutcome::Queued(0x0D));
+
+        /* Higher priority vector goes ahead of the one raised */
+        let enabled = resolve_next_event(&pending(None, false, true), &guest(true, false, false));
+        assert!(super::raised_outcome(EventKind::External, 0x0D, &enabled, Some(0x0D)) == InjectOutcome::Deliver(0x0D));
+        assert!(super::raised_outcome(EventKind::External, 0x30, &enabled, Some(0x0D)) == InjectOutcome::Queued(0x30));
+
+        assert!(InjectOutcome::Queued(0x30).vector() == Some(0x30) && InjectOutcome::Masked.vector().is_none());
+        assert!(InjectOutcome::Masked.to_string() == "suppressed by interrupt controller mask");
+    }
+}
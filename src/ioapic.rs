@@ -0,0 +1,647 @@
+/*
+ * I/O APIC emulation
+ *
+ * Sits at 0xFEC00000 and is programmed indirectly: register index goes to IOREGSEL at base + 0x00
+ * and register data is read and written through IOWIN at base + 0x10, both with 32 bit accesses.
+ * Besides ID, version and arbitration registers there are 24 redirection entries, each split in
+ * two 32 bit registers starting at index 0x10.
+ *
+ * Device IRQ lines are wired to both PIC and IOAPIC, but each assertion is delivered once. In PIC mode PIC
+ * takes lines it has unmasked and IOAPIC only delivers the ones PIC masks, so guests bringing IOAPIC up
+ * without touching IMCR work. In APIC mode PIC output is disconnected and lines only go to IOAPIC, unless
+ * local APIC takes PIC output through LINT0, which works like PIC mode. A line
+ * that merges into an interrupt still latched in PIC IRR or coalesces into a level entry with remote IRR
+ * set counts as taken by that controller. ISA IRQ0 comes in on pin 2
+ * like on most boards, other IRQs on the pin with the same number. An unmasked entry sends its vector
+ * to local APIC, a level triggered one sets remote IRR and further assertions are coalesced until local
+ * APIC tells us guest EOIed that vector.
+ *
+ * Interrupt local APIC turns down, because guest has it software disabled, stays pending in its entry with
+ * delivery status set and further assertions coalesce into it. Pending entries are sent again when local APIC
+ * gets enabled or guest reprograms a redirection entry, masked ones wait until they are unmasked.
+ *
+ * Current limitations:
+ * - Single vcpu, destination fields are kept but every interrupt goes to the only local APIC
+ * - Fixed, lowest priority and NMI delivery modes only
+ * - Device IRQ lines are edges only, level entries get one interrupt per assertion and EOI
+ */
+
+use vm;
+use apic;
+use pic;
+use config;
+use hypervisor_framework::*;
+
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+
+const IOAPIC_BASE: hv_gpaddr_t  = 0xFEC00000;
+const IOAPIC_PAGE_SIZE: u64     = 0x1000;
+
+// MMIO registers
+const IOAPIC_IOREGSEL: u64      = 0x00;
+const IOAPIC_IOWIN: u64         = 0x10;
+
+// Indirect registers
+const IOAPIC_ID: u32            = 0x00;
+const IOAPIC_VERSION: u32       = 0x01;
+const IOAPIC_ARB: u32           = 0x02;
+const IOAPIC_REDTBL: u32        = 0x10;
+
+const IOAPIC_PINS: usize        = 24;
+
+// Version 0x11, highest redirection entry 23
+const IOAPIC_VERSION_VALUE: u32 = 0x00170011;
+
+// Redirection entry bits
+const REDIR_VECTOR: u64         = 0xFF;
+const REDIR_DELIVERY_MODE: u64  = 0x700;
+const REDIR_DELIVERY_STATUS: u64 = 1 << 12;
+const REDIR_REMOTE_IRR: u64     = 1 << 14;
+const REDIR_TRIGGER_LEVEL: u64  = 1 << 15;
+const REDIR_MASKED: u64         = 1 << 16;
+const REDIR_READ_ONLY: u64      = REDIR_DELIVERY_STATUS | REDIR_REMOTE_IRR;
+const REDIR_WRITABLE: u64       = 0xFF000000_0001AFFF;
+
+// Delivery modes
+const DELIVERY_FIXED: u64       = 0x000;
+const DELIVERY_LOWEST: u64      = 0x100;
+const DELIVERY_NMI: u64         = 0x400;
+
+/* IOAPIC input pin an ISA IRQ line is wired to */
+fn irq_pin(irq: u8) -> usize
+{
+    match irq {
+        0 => 2,
+        _ => irq as usize,
+    }
+}
+
+/**
+ * The only I/O APIC
+ */
+struct IOAPIC
+{
+    id: u32,
+    regsel: u32,
+    redir: [u64; IOAPIC_PINS],
+}
+
+impl IOAPIC
+{
+    fn new() -> IOAPIC {
+        IOAPIC {
+            id: 0,
+            regsel: 0,
+            redir: [REDIR_MASKED; IOAPIC_PINS],
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = IOAPIC::new();
+    }
+
+    /* Redirection entry and which half of it a register index selects */
+    fn redir_index(reg: u32) -> Option<(usize, bool)> {
+        if reg < IOAPIC_REDTBL {
+            return None;
+        }
+
+        let pin = ((reg - IOAPIC_REDTBL) >> 1) as usize;
+        if pin >= IOAPIC_PINS {
+            return None;
+        }
+
+        Some((pin, (reg & 1) != 0))
+    }
+
+    fn read(&self, reg: u32) -> u32 {
+        match reg {
+            IOAPIC_ID => self.id,
+            IOAPIC_VERSION => IOAPIC_VERSION_VALUE,
+            IOAPIC_ARB => self.id,
+            _ => match IOAPIC::redir_index(reg) {
+                Some((pin, true)) => (self.redir[pin] >> 32) as u32,
+                Some((pin, false)) => self.redir[pin] as u32,
+                None => 0,
+            },
+        }
+    }
+
+    fn write(&mut self, reg: u32, val: u32) {
+        match reg {
+            IOAPIC_ID => self.id = val & 0x0F000000,
+            _ => match IOAPIC::redir_index(reg) {
+                Some((pin, high)) => {
+                    let entry = self.redir[pin];
+                    let val = if high {
+                        (entry & 0xFFFFFFFF) | ((val as u64) << 32)
+                    } else {
+                        (entry & !0xFFFFFFFF) | val as u64
+                    };
+                    self.redir[pin] = (entry & REDIR_READ_ONLY) | (val & REDIR_WRITABLE);
+                },
+                None => debug!("ioapic: write {:x} to read only or reserved register {:x}", val, reg),
+            },
+        }
+    }
+
+    /*
+     * Input pin asserted, returns entry to deliver if it isn't masked or coalesced
+     * Entry is pending with delivery status set until delivered() says local APIC took it.
+     */
+    fn assert_pin(&mut self, pin: usize) -> Option<u64> {
+        let entry = self.redir[pin];
+        if (entry & (REDIR_MASKED | REDIR_DELIVERY_STATUS)) != 0 {
+            return None;
+        }
+
+        if (entry & (REDIR_TRIGGER_LEVEL | REDIR_REMOTE_IRR)) == (REDIR_TRIGGER_LEVEL | REDIR_REMOTE_IRR) {
+            return None;
+        }
+
+        self.redir[pin] |= REDIR_DELIVERY_STATUS;
+        Some(self.redir[pin])
+    }
+
+    /*
+     * Pending entry was sent, accepted ones stop pending
+     * Level triggered entries stay remote IRR until EOI of their vector.
+     */
+    fn delivered(&mut self, pin: usize, accepted: bool) {
+        if !accepted {
+            return;
+        }
+
+        self.redir[pin] &= !REDIR_DELIVERY_STATUS;
+        if (self.redir[pin] & REDIR_TRIGGER_LEVEL) != 0 {
+            self.redir[pin] |= REDIR_REMOTE_IRR;
+        }
+    }
+
+    /* Unmasked entries still waiting to be delivered */
+    fn pending_pins(&self) -> Vec<usize> {
+        (0..IOAPIC_PINS)
+            .filter(|&pin| (self.redir[pin] & (REDIR_MASKED | REDIR_DELIVERY_STATUS)) == REDIR_DELIVERY_STATUS)
+            .collect()
+    }
+
+    /* Local APIC EOI of a level triggered vector */
+    fn eoi(&mut self, vec: u8) {
+        for entry in self.redir.iter_mut() {
+            if (*entry & REDIR_VECTOR) as u8 == vec && (*entry & REDIR_TRIGGER_LEVEL) != 0 {
+                *entry &= !REDIR_REMOTE_IRR;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod ioapic_test
+{
+    use super::*;
+
+    #[test] fn reset_state() {
+        let mut ioapic = IOAPIC::new();
+        assert!(ioapic.read(IOAPIC_VERSION) == 0x00170011);
+        assert!(ioapic.read(IOAPIC_REDTBL + 2 * 23) == REDIR_MASKED as u32);
+        assert!(ioapic.read(IOAPIC_REDTBL + 2 * 24) == 0);
+
+        ioapic.write(IOAPIC_ID, 0xFF000000);
+        assert!(ioapic.read(IOAPIC_ID) == 0x0F000000);
+
+        /* Remote IRR and delivery status can't be written */
+        ioapic.write(IOAPIC_REDTBL + 8, 0xFFFFFFFF);
+        ioapic.write(IOAPIC_REDTBL + 9, 0xFFFFFFFF);
+        assert!(ioapic.read(IOAPIC_REDTBL + 8) == 0x0001AFFF);
+        assert!(ioapic.read(IOAPIC_REDTBL + 9) == 0xFF000000);
+
+        ioapic.reset();
+        assert!(ioapic.read(IOAPIC_ID) == 0 && ioapic.read(IOAPIC_REDTBL + 8) == REDIR_MASKED as u32);
+    }
+
+    #[test] fn level_remote_irr() {
+        let mut ioapic = IOAPIC::new();
+        ioapic.write(IOAPIC_REDTBL + 2 * 9, REDIR_TRIGGER_LEVEL as u32 | 0x39);
+
+        assert!(ioapic.assert_pin(9).is_some());
+        ioapic.delivered(9, true);
+        assert!((ioapic.read(IOAPIC_REDTBL + 2 * 9) & REDIR_REMOTE_IRR as u32) != 0);
+        assert!(ioapic.assert_pin(9).is_none());
+
+        /* Other vectors leave it alone */
+        ioapic.eoi(0x38);
+        assert!(ioapic.assert_pin(9).is_none());
+        ioapic.eoi(0x39);
+        assert!(ioapic.assert_pin(9).is_some());
+    }
+
+    #[test] fn delivery_pending() {
+        let mut ioapic = IOAPIC::new();
+        ioapic.write(IOAPIC_REDTBL + 2 * 4, 0x34);
+        ioapic.write(IOAPIC_REDTBL + 2 * 5, REDIR_MASKED as u32 | 0x35);
+
+        /* Turned down entry stays pending, further assertions coalesce */
+        assert!(ioapic.assert_pin(4).is_some());
+        ioapic.delivered(4, false);
+        assert!((ioapic.read(IOAPIC_REDTBL + 2 * 4) & REDIR_DELIVERY_STATUS as u32) != 0);
+        assert!(ioapic.assert_pin(4).is_none());
+        assert!(ioapic.pending_pins() == vec![4]);
+
+        /* Guest can't clear delivery status */
+        ioapic.write(IOAPIC_REDTBL + 2 * 4, 0x34);
+        assert!(ioapic.pending_pins() == vec![4]);
+
+        ioapic.delivered(4, true);
+        assert!(ioapic.read(IOAPIC_REDTBL + 2 * 4) == 0x34 && ioapic.pending_pins().is_empty());
+    }
+}
+
+/**
+ * I/O APIC device, vm interrupt controller in front of local APIC and PIC
+ */
+struct IOAPICDev
+{
+    ioapic: RefCell<IOAPIC>,
+    legacy: Rc<vm::interrupt_controller>,   // Takes IRQ lines in PIC mode, and all acks
+    routed: Cell<bool>,                     // Device IRQ lines only go to IOAPIC, PIC output disconnected
+    pic_accepts: fn(u8) -> bool,            // PIC has IRQ line unmasked
+    deliver: fn(u8, bool) -> bool,          // Fixed interrupt to local APIC, vector and level
+    nmi: fn(),
+}
+
+impl IOAPICDev
+{
+    fn assert_pin(&self, pin: usize) {
+        let entry = self.ioapic.borrow_mut().assert_pin(pin);
+        if let Some(entry) = entry {
+            self.send(pin, entry);
+        }
+    }
+
+    /* Local APIC may call back into eoi() from delivery, so IOAPIC is not borrowed here */
+    fn send(&self, pin: usize, entry: u64) {
+        let vec = (entry & REDIR_VECTOR) as u8;
+        let level = (entry & REDIR_TRIGGER_LEVEL) != 0;
+        let accepted = match entry & REDIR_DELIVERY_MODE {
+            DELIVERY_FIXED | DELIVERY_LOWEST => (self.deliver)(vec, level),
+            DELIVERY_NMI => {
+                (self.nmi)();
+                true
+            },
+            mode => {
+                debug!("ioapic: dropping pin {} interrupt with delivery mode {:x}", pin, mode >> 8);
+                true
+            },
+        };
+
+        self.ioapic.borrow_mut().delivered(pin, accepted);
+    }
+
+    /* Send pending entries again, local APIC may take them now */
+    fn retry_pending(&self) {
+        let pins = self.ioapic.borrow().pending_pins();
+        for pin in pins {
+            let entry = self.ioapic.borrow().redir[pin];
+            self.send(pin, entry);
+        }
+    }
+
+    fn eoi(&self, vec: u8) {
+        self.ioapic.borrow_mut().eoi(vec);
+    }
+}
+
+impl vm::mmio_handler for IOAPICDev
+{
+    fn mmio_read(&self, addr: hv_gpaddr_t, size: u8) -> u64
+    {
+        let ioapic = self.ioapic.borrow();
+        match (addr - IOAPIC_BASE, size) {
+            (IOAPIC_IOREGSEL, 4) => ioapic.regsel as u64,
+            (IOAPIC_IOWIN, 4) => ioapic.read(ioapic.regsel) as u64,
+            (offset, _) => {
+                debug!("ioapic: {} byte read at {:x}", size, offset);
+                0
+            },
+        }
+    }
+
+    fn mmio_write(&self, addr: hv_gpaddr_t, size: u8, data: u64)
+    {
+        let retry = {
+            let mut ioapic = self.ioapic.borrow_mut();
+            match (addr - IOAPIC_BASE, size) {
+                (IOAPIC_IOREGSEL, 4) => {
+                    ioapic.regsel = data as u32 & 0xFF;
+                    false
+                },
+                (IOAPIC_IOWIN, 4) => {
+                    let reg = ioapic.regsel;
+                    ioapic.write(reg, data as u32);
+                    IOAPIC::redir_index(reg).is_some()
+                },
+                (offset, _) => {
+                    debug!("ioapic: {} byte write at {:x}", size, offset);
+                    false
+                },
+            }
+        };
+
+        /* Unmasked entry may have been pending */
+        if retry {
+            self.retry_pending();
+        }
+    }
+}
+
+impl vm::interrupt_controller for IOAPICDev
+{
+    fn assert_irq(&self, irq: u8, id: Option<u64>)
+    {
+        let pin = irq_pin(irq);
+        if !self.routed.get() {
+            let taken = (self.pic_accepts)(irq);
+            self.legacy.assert_irq(irq, id);
+            if taken {
+                return;
+            }
+        }
+
+        if pin < IOAPIC_PINS {
+            self.assert_pin(pin);
+        }
+    }
+
+    fn ack(&self, vec: u8) -> u8
+    {
+        self.legacy.ack(vec)
+    }
+}
+
+impl vm::reset_handler for IOAPICDev
+{
+    fn reset(&self)
+    {
+        self.ioapic.borrow_mut().reset();
+    }
+}
+
+#[cfg(test)]
+mod ioapic_dev_test
+{
+    use super::*;
+
+    thread_local! {
+        static DELIVERED: RefCell<Vec<(u8, bool)>> = RefCell::new(Vec::new());
+        static NMIS: RefCell<u32> = RefCell::new(0);
+        static PIC_MASK: Cell<u16> = Cell::new(0);
+        static APIC_ENABLED: Cell<bool> = Cell::new(true);
+    }
+
+    fn pic_accepts(irq: u8) -> bool {
+        PIC_MASK.with(|m| (m.get() & (1 << irq)) == 0)
+    }
+
+    fn deliver(vec: u8, level: bool) -> bool {
+        if !APIC_ENABLED.with(|e| e.get()) {
+            return false;
+        }
+        DELIVERED.with(|d| d.borrow_mut().push((vec, level)));
+        true
+    }
+
+    fn nmi() {
+        NMIS.with(|n| *n.borrow_mut() += 1);
+    }
+
+    fn delivered() -> Vec<(u8, bool)> {
+        DELIVERED.with(|d| d.borrow_mut().drain(..).collect())
+    }
+
+    /* Stands in for local APIC and PIC behind IOAPIC, records IRQs PIC takes */
+    struct Legacy {
+        irqs: RefCell<Vec<u8>>,
+        acks: RefCell<Vec<u8>>,
+    }
+
+    impl vm::interrupt_controller for Legacy {
+        fn assert_irq(&self, irq: u8, _id: Option<u64>) {
+            if pic_accepts(irq) {
+                self.irqs.borrow_mut().push(irq);
+            }
+        }
+
+        fn ack(&self, vec: u8) -> u8 {
+            self.acks.borrow_mut().push(vec);
+            vec
+        }
+    }
+
+    fn device(routed: bool) -> (IOAPICDev, Rc<Legacy>) {
+        let legacy = Rc::new(Legacy { irqs: RefCell::new(Vec::new()), acks: RefCell::new(Vec::new()) });
+        let dev = IOAPICDev {
+            ioapic: RefCell::new(IOAPIC::new()),
+            legacy: legacy.clone(),
+            routed: Cell::new(routed),
+            pic_accepts: pic_accepts,
+            deliver: deliver,
+            nmi: nmi,
+        };
+        (dev, legacy)
+    }
+
+    fn write(dev: &IOAPICDev, reg: u32, val: u32) {
+        vm::mmio_handler::mmio_write(dev, IOAPIC_BASE + IOAPIC_IOREGSEL, 4, reg as u64);
+        vm::mmio_handler::mmio_write(dev, IOAPIC_BASE + IOAPIC_IOWIN, 4, val as u64);
+    }
+
+    fn read(dev: &IOAPICDev, reg: u32) -> u32 {
+        vm::mmio_handler::mmio_write(dev, IOAPIC_BASE + IOAPIC_IOREGSEL, 4, reg as u64);
+        vm::mmio_handler::mmio_read(dev, IOAPIC_BASE + IOAPIC_IOWIN, 4) as u32
+    }
+
+    #[test] fn route_irq4() {
+        let (dev, legacy) = device(true);
+        assert!(read(&dev, IOAPIC_VERSION) == 0x00170011);
+        assert!(vm::mmio_handler::mmio_read(&dev, IOAPIC_BASE + IOAPIC_IOREGSEL, 4) == IOAPIC_VERSION as u64);
+
+        /* Masked at reset */
+        vm::interrupt_controller::assert_irq(&dev, 4, None);
+        assert!(delivered().is_empty());
+
+        write(&dev, IOAPIC_REDTBL + 2 * 4, 0x34);
+        write(&dev, IOAPIC_REDTBL + 2 * 4 + 1, 0);
+        vm::interrupt_controller::assert_irq(&dev, 4, None);
+        assert!(delivered() == vec![(0x34, false)]);
+
+        /* Masking suppresses it again */
+        write(&dev, IOAPIC_REDTBL + 2 * 4, REDIR_MASKED as u32 | 0x34);
+        vm::interrupt_controller::assert_irq(&dev, 4, None);
+        assert!(delivered().is_empty());
+
+        /* IRQ0 comes in on pin 2, NMI delivery mode goes around local APIC */
+        write(&dev, IOAPIC_REDTBL + 2 * 2, DELIVERY_NMI as u32);
+        vm::interrupt_controller::assert_irq(&dev, 0, None);
+        assert!(delivered().is_empty() && NMIS.with(|n| *n.borrow()) == 1);
+
+        /* Nothing reaches PIC, acks still do */
+        vm::interrupt_controller::ack(&dev, 0x08);
+        assert!(legacy.irqs.borrow().is_empty() && *legacy.acks.borrow() == vec![0x08]);
+    }
+
+    #[test] fn level_coalesced_until_eoi() {
+        let (dev, _) = device(true);
+        write(&dev, IOAPIC_REDTBL + 2 * 4, REDIR_TRIGGER_LEVEL as u32 | 0x34);
+
+        vm::interrupt_controller::assert_irq(&dev, 4, None);
+        vm::interrupt_controller::assert_irq(&dev, 4, None);
+        assert!(delivered() == vec![(0x34, true)]);
+        assert!((read(&dev, IOAPIC_REDTBL + 2 * 4) & REDIR_REMOTE_IRR as u32) != 0);
+
+        dev.eoi(0x34);
+        vm::interrupt_controller::assert_irq(&dev, 4, None);
+        assert!(delivered() == vec![(0x34, true)]);
+    }
+
+    #[test] fn pending_until_apic_enabled() {
+        let (dev, _) = device(true);
+        APIC_ENABLED.with(|e| e.set(false));
+        write(&dev, IOAPIC_REDTBL + 2 * 4, 0x34);
+        write(&dev, IOAPIC_REDTBL + 2 * 9, REDIR_TRIGGER_LEVEL as u32 | 0x39);
+
+        /* Software disabled local APIC leaves them pending, repeated assertions coalesce */
+        vm::interrupt_controller::assert_irq(&dev, 4, None);
+        vm::interrupt_controller::assert_irq(&dev, 4, None);
+        vm::interrupt_controller::assert_irq(&dev, 9, None);
+        assert!(delivered().is_empty());
+        assert!((read(&dev, IOAPIC_REDTBL + 2 * 4) & REDIR_DELIVERY_STATUS as u32) != 0);
+        assert!((read(&dev, IOAPIC_REDTBL + 2 * 9) & REDIR_REMOTE_IRR as u32) == 0);
+        dev.retry_pending();
+        assert!(delivered().is_empty());
+
+        /* Each goes once when it is enabled */
+        APIC_ENABLED.with(|e| e.set(true));
+        dev.retry_pending();
+        assert!(delivered() == vec![(0x34, false), (0x39, true)]);
+        assert!(read(&dev, IOAPIC_REDTBL + 2 * 4) == 0x34);
+        assert!((read(&dev, IOAPIC_REDTBL + 2 * 9) & REDIR_REMOTE_IRR as u32) != 0);
+        dev.retry_pending();
+        assert!(delivered().is_empty());
+
+        /* Masked pending entry goes when guest unmasks it */
+        APIC_ENABLED.with(|e| e.set(false));
+        vm::interrupt_controller::assert_irq(&dev, 4, None);
+        write(&dev, IOAPIC_REDTBL + 2 * 4, REDIR_MASKED as u32 | 0x34);
+        APIC_ENABLED.with(|e| e.set(true));
+        dev.retry_pending();
+        assert!(delivered().is_empty());
+        write(&dev, IOAPIC_REDTBL + 2 * 4, 0x34);
+        assert!(delivered() == vec![(0x34, false)]);
+    }
+
+    #[test] fn not_routed() {
+        let (dev, legacy) = device(false);
+        write(&dev, IOAPIC_REDTBL + 2 * 4, 0x34);
+        vm::interrupt_controller::assert_irq(&dev, 4, None);
+        assert!(delivered().is_empty() && *legacy.irqs.borrow() == vec![4]);
+
+        /* Same line follows IMCR mode switches */
+        dev.routed.set(true);
+        vm::interrupt_controller::assert_irq(&dev, 4, None);
+        assert!(delivered() == vec![(0x34, false)] && legacy.irqs.borrow().len() == 1);
+        dev.routed.set(false);
+        vm::interrupt_controller::assert_irq(&dev, 4, None);
+        assert!(delivered().is_empty() && *legacy.irqs.borrow() == vec![4, 4]);
+    }
+
+    /* Line asserted with PIC and IOAPIC entry masked and unmasked in both IMCR modes, vectors delivered */
+    fn shared_line(apic_mode: bool, pic_unmasked: bool, ioapic_unmasked: bool) -> (Vec<u8>, Vec<u8>) {
+        let (dev, legacy) = device(apic_mode);
+        PIC_MASK.with(|m| m.set(if pic_unmasked { 0 } else { 1 << 4 }));
+        write(&dev, IOAPIC_REDTBL + 2 * 4, if ioapic_unmasked { 0 } else { REDIR_MASKED as u32 } | 0x34);
+
+        vm::interrupt_controller::assert_irq(&dev, 4, None);
+        PIC_MASK.with(|m| m.set(0));
+
+        let pic = legacy.irqs.borrow().iter().map(|irq| irq + 0x08).collect();
+        (pic, delivered().iter().map(|d| d.0).collect())
+    }
+
+    #[test] fn shared_lines() {
+        /* PIC mode: PIC goes first, IOAPIC takes what PIC masks */
+        assert!(shared_line(false, true, false) == (vec![0x0C], vec![]));
+        assert!(shared_line(false, true, true) == (vec![0x0C], vec![]));
+        assert!(shared_line(false, false, true) == (vec![], vec![0x34]));
+        assert!(shared_line(false, false, false) == (vec![], vec![]));
+
+        /* APIC mode: PIC is out of the way */
+        assert!(shared_line(true, true, true) == (vec![], vec![0x34]));
+        assert!(shared_line(true, true, false) == (vec![], vec![]));
+        assert!(shared_line(true, false, true) == (vec![], vec![0x34]));
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+static mut IOAPIC_DEV: Option<*const IOAPICDev> = None;
+
+/* Level triggered vector EOIed in local APIC */
+fn eoi_broadcast(vec: u8)
+{
+    unsafe {
+        if let Some(dev) = IOAPIC_DEV {
+            (*dev).eoi(vec);
+        }
+    }
+}
+
+/* Local APIC software enabled */
+fn apic_enabled()
+{
+    unsafe {
+        if let Some(dev) = IOAPIC_DEV {
+            (*dev).retry_pending();
+        }
+    }
+}
+
+/* PIC output connected to vcpu or disconnected */
+fn pic_output_switched(connected: bool)
+{
+    unsafe {
+        if let Some(dev) = IOAPIC_DEV {
+            (*dev).routed.set(!connected);
+        }
+    }
+}
+
+/**
+ * Put IOAPIC in front of local APIC, device IRQ lines go to it in APIC mode
+ */
+pub fn init(config: &config::VmConfig)
+{
+    if !config.ioapic {
+        return;
+    }
+
+    let dev = Rc::new(IOAPICDev {
+        ioapic: RefCell::new(IOAPIC::new()),
+        legacy: vm::get_interrupt_controller(),
+        routed: Cell::new(false),
+        pic_accepts: pic::accepts_irq,
+        deliver: apic::deliver_interrupt,
+        nmi: vm::raise_nmi,
+    });
+
+    unsafe {
+        IOAPIC_DEV = Some(&*dev as *const IOAPICDev);
+    }
+
+    apic::set_eoi_handler(eoi_broadcast);
+    apic::set_pic_output_handler(pic_output_switched);
+    apic::set_enable_handler(apic_enabled);
+    vm::register_interrupt_controller(dev.clone());
+    vm::register_mmio_region(dev.clone(), IOAPIC_BASE, IOAPIC_PAGE_SIZE);
+    vm::register_reset_handler(dev.clone());
+}
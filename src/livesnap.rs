@@ -0,0 +1,440 @@
+/*
+ * Live snapshots
+ *
+ * Monitor "savevm -live <file>" saves a snapshot as guest keeps running. Guest RAM is copied to host memory in
+ * rounds on a thread of its own: the first round copies every page, each round after it the pages guest or
+ * devices wrote while the one before was copied, as dirty page log has them. Once a round has few enough
+ * pages left to copy, or after MAX_ROUNDS, guest is stopped at the next exit to copy the pages written in the
+ * last round, take vcpu, clock and device states, and goes on. That pause is what guest sees of the snapshot,
+ * it is reported when the file is saved.
+ *
+ * Pages copied in later rounds replace the ones copied before, so host memory ends up holding RAM as it was at
+ * the pause and the file is a whole snapshot like the one vm::save_snapshot() would have saved there, written
+ * after guest has gone on. Device states are taken with guest stopped, after every page they could have
+ * written to RAM by the pause is known to the log.
+ *
+ * Rounds end at an exit vcpu is interrupted for, so live snapshots are not taken while recording or replaying
+ * inputs: host timing would decide where guest exits.
+ */
+
+use vm;
+use monitor;
+use record;
+use replay;
+use snapshot;
+
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/* Guest is stopped once a round leaves no more than this many pages to copy */
+const STOP_PAGES: usize = 64;
+
+/* Rounds before guest is stopped anyway, for guests writing pages faster than they are copied */
+const MAX_ROUNDS: usize = 30;
+
+/**
+ * Guest memory as live snapshot copies it
+ */
+pub trait live_memory
+{
+    /**
+     * Sizes of memory mappings in bytes, in the order snapshot has them
+     */
+    fn sizes(&self) -> Vec<usize>;
+
+    /**
+     * Read page of mapping at index into buf, guest may be writing it meanwhile
+     */
+    fn read_page(&self, mapping: usize, page: usize, buf: &mut [u8]);
+}
+
+/* Guest memory of the VM */
+struct GuestMemory;
+
+impl live_memory for GuestMemory
+{
+    fn sizes(&self) -> Vec<usize> {
+        vm::memory_sizes()
+    }
+
+    fn read_page(&self, mapping: usize, page: usize, buf: &mut [u8]) {
+        vm::read_live_page(mapping, page, buf);
+    }
+}
+
+/**
+ * Copy of guest memory a live snapshot builds, pages copied again replace what was copied of them before
+ */
+pub struct PreCopy
+{
+    memory: Box<live_memory + Send>,
+    contents: Vec<Vec<u8>>,
+    pub rounds: usize,      // Rounds copied as guest ran
+    pub copied: usize,      // Pages copied as guest ran
+    pub stopped: usize,     // Pages copied with guest stopped
+}
+
+impl PreCopy
+{
+    pub fn new(memory: Box<live_memory + Send>) -> PreCopy {
+        let contents = memory.sizes().iter().map(|&size| vec![0u8; size]).collect();
+        PreCopy {
+            memory: memory,
+            contents: contents,
+            rounds: 0,
+            copied: 0,
+            stopped: 0,
+        }
+    }
+
+    /**
+     * Indices of all pages of each mapping, what the first round copies
+     */
+    pub fn all_pages(&self) -> Vec<Vec<usize>> {
+        self.contents.iter().map(|data| (0..(data.len() + vm::DIRTY_PAGE_SIZE - 1) / vm::DIRTY_PAGE_SIZE).collect()).collect()
+    }
+
+    /* Copy pages of each mapping, returns how many */
+    fn copy(&mut self, pages: &[Vec<usize>]) -> usize {
+        for (i, pages) in pages.iter().enumerate() {
+            for &page in pages {
+                let data = &mut self.contents[i];
+                let start = page * vm::DIRTY_PAGE_SIZE;
+                let end = ::std::cmp::min(start + vm::DIRTY_PAGE_SIZE, data.len());
+                self.memory.read_page(i, page, &mut data[start..end]);
+            }
+        }
+        pages.iter().map(Vec::len).sum()
+    }
+
+    /**
+     * Copy a round of pages as guest runs
+     */
+    pub fn round(&mut self, pages: &[Vec<usize>]) {
+        self.copied += self.copy(pages);
+        self.rounds += 1;
+    }
+
+    /**
+     * Whether guest is to be stopped for pages written in the last round rather than copy another one
+     */
+    pub fn should_stop(&self, pages: &[Vec<usize>]) -> bool {
+        pages.iter().map(Vec::len).sum::<usize>() <= STOP_PAGES || self.rounds >= MAX_ROUNDS
+    }
+
+    /**
+     * Copy pages written in the last round with guest stopped and hand memory contents over
+     */
+    pub fn stop(&mut self, pages: &[Vec<usize>]) -> Vec<Vec<u8>> {
+        self.stopped = self.copy(pages);
+        mem::replace(&mut self.contents, Vec::new())
+    }
+}
+
+/* What copy thread is asked to do */
+enum Job
+{
+    Round(Vec<Vec<usize>>),
+    Save(snapshot::Snapshot),
+}
+
+/* Live snapshot being saved */
+struct Live
+{
+    path: PathBuf,
+    precopy: Arc<Mutex<PreCopy>>,
+    jobs: Sender<Job>,
+    saved: Receiver<io::Result<u64>>,
+    started: Instant,
+    pause: Option<Duration>,    // Guest was stopped that long, snapshot is being written
+}
+
+/* Copy rounds and write snapshot as asked, vcpu is interrupted to hear of each one done */
+fn copy_thread(precopy: Arc<Mutex<PreCopy>>, path: PathBuf, jobs: Receiver<Job>, saved: Sender<io::Result<u64>>)
+{
+    for job in jobs {
+        match job {
+            Job::Round(pages) => precopy.lock().unwrap().round(&pages),
+            Job::Save(snapshot) => {
+                let _ = saved.send(snapshot::save(&snapshot, &path));
+            },
+        }
+        JOB_DONE.store(true, Ordering::SeqCst);
+        vm::interrupt_guest();
+    }
+}
+
+fn micros(duration: Duration) -> u64
+{
+    duration.as_secs() * 1000000 + (duration.subsec_nanos() / 1000) as u64
+}
+
+impl Live
+{
+    /* Next step after copy thread did its job, returns report once snapshot is saved or failed */
+    fn step(&mut self) -> Option<Result<String, String>> {
+        if let Some(pause) = self.pause {
+            let precopy = self.precopy.lock().unwrap();
+            return Some(match self.saved.recv().unwrap() {
+                Ok(hash) => {
+                    vm::live_snapshot_saved(hash);
+                    Ok(format!("Live snapshot saved to {} in {} ms: {} rounds copied {} pages as guest ran, guest stopped for {} us to copy {} pages",
+                               self.path.display(), micros(self.started.elapsed()) / 1000, precopy.rounds, precopy.copied, micros(pause), precopy.stopped))
+                },
+                Err(err) => Err(format!("Can't save live snapshot to {}: {}", self.path.display(), err)),
+            });
+        }
+
+        let stopped = Instant::now();
+        let pages = vm::take_dirty_pages();
+        let mut precopy = self.precopy.lock().unwrap();
+        if !precopy.should_stop(&pages) {
+            self.jobs.send(Job::Round(pages)).unwrap();
+            return None;
+        }
+
+        let job = match vm::take_live_snapshot(|| precopy.stop(&pages)) {
+            Ok(snapshot) => {
+                self.pause = Some(stopped.elapsed());
+                Job::Save(snapshot)
+            },
+            Err(err) => {
+                /* A busy device holds guest up for another round */
+                debug!("Live snapshot goes on for another round: {}", err);
+                Job::Round(pages)
+            },
+        };
+        self.jobs.send(job).unwrap();
+        None
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+lazy_static! {
+    static ref JOB_DONE: AtomicBool = AtomicBool::new(false);
+    static ref LIVE: Mutex<Option<Live>> = Mutex::new(None);
+    static ref LAST_REPORT: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/**
+ * Live snapshot is being saved
+ */
+pub fn in_progress() -> bool
+{
+    LIVE.lock().unwrap().is_some()
+}
+
+/**
+ * Start saving live snapshot to path, vcpu thread only
+ */
+pub fn start(path: &Path) -> Result<(), String>
+{
+    if in_progress() {
+        return Err(String::from("Live snapshot is being saved"));
+    }
+    if record::enabled() || replay::enabled() {
+        return Err(String::from("Live snapshot can't be saved while inputs are recorded or replayed"));
+    }
+
+    vm::start_live_snapshot();
+    let precopy = PreCopy::new(Box::new(GuestMemory));
+    let pages = precopy.all_pages();
+    let precopy = Arc::new(Mutex::new(precopy));
+
+    let (jobs_tx, jobs_rx) = channel();
+    let (saved_tx, saved_rx) = channel();
+    let (thread_precopy, thread_path) = (precopy.clone(), path.to_path_buf());
+    thread::spawn(move || copy_thread(thread_precopy, thread_path, jobs_rx, saved_tx));
+    jobs_tx.send(Job::Round(pages)).unwrap();
+
+    *LIVE.lock().unwrap() = Some(Live {
+        path: path.to_path_buf(),
+        precopy: precopy,
+        jobs: jobs_tx,
+        saved: saved_rx,
+        started: Instant::now(),
+        pause: None,
+    });
+    Ok(())
+}
+
+/**
+ * Go on with live snapshot after an exit, vcpu thread calls it after each one
+ */
+pub fn poll()
+{
+    if !JOB_DONE.swap(false, Ordering::SeqCst) {
+        return;
+    }
+
+    let mut live = LIVE.lock().unwrap();
+    let report = match *live {
+        Some(ref mut live) => live.step(),
+        None => None,
+    };
+
+    if let Some(report) = report {
+        *live = None;
+        match report {
+            Ok(ref text) => info!("{}", text),
+            Err(ref text) => error!("{}", text),
+        }
+        *LAST_REPORT.lock().unwrap() = Some(report.unwrap_or_else(|err| err));
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+fn cmd_info_livesnap(_: &mut monitor::MonitorContext, _: &[&str]) -> Result<String, String>
+{
+    if let Some(ref live) = *LIVE.lock().unwrap() {
+        let precopy = live.precopy.lock().unwrap();
+        return Ok(format!("Live snapshot to {} is being saved, {} rounds copied {} pages", live.path.display(), precopy.rounds, precopy.copied));
+    }
+    Ok(LAST_REPORT.lock().unwrap().clone().unwrap_or(String::from("No live snapshot saved")))
+}
+
+/**
+ * Add live snapshot monitor commands
+ */
+pub fn init()
+{
+    monitor::register_command(monitor::MonitorCommand {
+        name: "info livesnap",
+        args: "",
+        help: "show live snapshot being saved or the last one saved",
+        handler: cmd_info_livesnap,
+    });
+}
+
+#[cfg(test)]
+mod livesnap_test
+{
+    use super::*;
+    use snapshot::{Snapshot, SnapshotMemory, SnapshotDevice};
+    use devstate;
+
+    const PAGE: usize = vm::DIRTY_PAGE_SIZE;
+
+    /* Guest memory tests write as "guest runs", with pages read logged */
+    #[derive(Clone)]
+    struct FakeMemory
+    {
+        contents: Arc<Mutex<Vec<Vec<u8>>>>,
+        reads: Arc<Mutex<Vec<(usize, usize)>>>,
+    }
+
+    impl live_memory for FakeMemory
+    {
+        fn sizes(&self) -> Vec<usize> {
+            self.contents.lock().unwrap().iter().map(Vec::len).collect()
+        }
+
+        fn read_page(&self, mapping: usize, page: usize, buf: &mut [u8]) {
+            let contents = self.contents.lock().unwrap();
+            buf.copy_from_slice(&contents[mapping][page * PAGE..page * PAGE + buf.len()]);
+            self.reads.lock().unwrap().push((mapping, page));
+        }
+    }
+
+    impl FakeMemory
+    {
+        /* Guest fills pages of mapping with byte, returns them as dirty page log has them */
+        fn write(&self, mapping: usize, pages: &[usize], val: u8) -> Vec<Vec<usize>> {
+            let mut contents = self.contents.lock().unwrap();
+            let mut dirty = vec![Vec::new(); contents.len()];
+            for &page in pages {
+                let end = ::std::cmp::min((page + 1) * PAGE, contents[mapping].len());
+                for byte in &mut contents[mapping][page * PAGE..end] {
+                    *byte = val;
+                }
+                dirty[mapping].push(page);
+            }
+            dirty
+        }
+
+        fn take_reads(&self) -> Vec<(usize, usize)> {
+            mem::replace(&mut *self.reads.lock().unwrap(), Vec::new())
+        }
+    }
+
+    /* Snapshot of memory contents and the same state otherwise */
+    fn snapshot_of(contents: Vec<Vec<u8>>) -> Snapshot {
+        let flags = [7, 5];
+        Snapshot {
+            created: 1500000000,
+            memory: contents.into_iter().enumerate().map(|(i, data)| SnapshotMemory { base: i as u64 * 0xF0000, flags: flags[i], data: data, pages: None }).collect(),
+            vcpu_state: vm::VcpuState { rip: 0x7C00, ..Default::default() },
+            clock_ns: 5000000,
+            dilation: 1.0,
+            devices: vec![SnapshotDevice { name: String::from("pic"), version: 2, state: devstate::DeviceState::new() }],
+            parent: None,
+        }
+    }
+
+    #[test] fn stop_phase() {
+        /* 320K and a half of RAM, and ROM */
+        let memory = FakeMemory {
+            contents: Arc::new(Mutex::new(vec![vec![0x11; 0x50800], vec![0xEA; 0x10000]])),
+            reads: Arc::new(Mutex::new(Vec::new())),
+        };
+        let mut precopy = PreCopy::new(Box::new(memory.clone()));
+
+        let all = precopy.all_pages();
+        assert!(all[0].len() == 81 && all[1].len() == 16);
+        precopy.round(&all);
+        assert!(memory.take_reads().len() == 97);
+
+        /* Guest writes more pages than guest is stopped for, then a few */
+        let dirty = memory.write(0, &(0..81).collect::<Vec<usize>>(), 0x22);
+        assert!(!precopy.should_stop(&dirty));
+        precopy.round(&dirty);
+        assert!(memory.take_reads().len() == 81);
+
+        let last = memory.write(0, &[3, 80], 0x33);
+        assert!(precopy.should_stop(&last));
+        let contents = precopy.stop(&last);
+
+        /* Stop phase read the pages written in the last round only */
+        assert!(memory.take_reads() == vec![(0, 3), (0, 80)]);
+        assert!(precopy.rounds == 2 && precopy.copied == 178 && precopy.stopped == 2);
+
+        /* File is the one a stop-the-world snapshot at the pause would have been */
+        let stopped = memory.contents.lock().unwrap().clone();
+        assert!(contents == stopped);
+
+        let mut live = Vec::new();
+        snapshot::write(&snapshot_of(contents), &mut live).unwrap();
+        let mut world = Vec::new();
+        snapshot::write(&snapshot_of(stopped.clone()), &mut world).unwrap();
+        assert!(live == world);
+
+        let restored = snapshot::read(&live).unwrap();
+        assert!(restored.memory.iter().map(|mem| mem.data.clone()).collect::<Vec<Vec<u8>>>() == stopped);
+        assert!(restored.memory[0].data[80 * PAGE..] == [0x33; 0x800][..]);
+        assert!(restored.memory[0].data[4 * PAGE] == 0x22);
+    }
+
+    #[test] fn round_limit() {
+        let memory = FakeMemory {
+            contents: Arc::new(Mutex::new(vec![vec![0; 0x100000]])),
+            reads: Arc::new(Mutex::new(Vec::new())),
+        };
+        let mut precopy = PreCopy::new(Box::new(memory.clone()));
+        let busy = vec![(0..STOP_PAGES + 1).collect::<Vec<usize>>()];
+
+        for _ in 0..MAX_ROUNDS {
+            assert!(!precopy.should_stop(&busy));
+            precopy.round(&busy);
+        }
+        assert!(precopy.should_stop(&busy));
+        assert!(precopy.should_stop(&vec![(0..STOP_PAGES).collect::<Vec<usize>>()]));
+    }
+}
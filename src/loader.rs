@@ -0,0 +1,347 @@
+/*
+ * Direct loading of test images without firmware
+ *
+ * Image is either a flat binary placed at a real mode address or a boot sector placed at 0000:7C00
+ * the way BIOS does it. El Torito CD images are booted the same way from their boot catalog: no emulation
+ * images are loaded at their load segment and started there, floppy emulation images have their boot
+ * sector started as drive 00h, int 13h reads of the rest go to biosdisk. Loader also provides what such code expects to find at entry: an IVT with
+ * every vector pointing at an IRET stub, a usable stack and boot drive in DL. BIOS data area is
+ * filled separately by bda module.
+ */
+
+use vm;
+use config::LoadConfig;
+use disk::disk_image;
+use eltorito::{self, BootMedia};
+
+const IVT_ENTRIES: usize        = 256;
+
+// Images may go between BDA and video memory
+const LOAD_AREA_START: u64      = 0x500;
+const ROM_AREA_START: u64       = 0xA0000;
+
+// Unhandled interrupts return right away from dummy handler location used by PC BIOSes
+const IRET_STUB_SEG: u16        = 0xF000;
+const IRET_STUB_OFF: u16        = 0xFF53;
+const IRET_OPCODE: u8           = 0xCF;
+
+const BOOT_SECTOR_SEG: u16      = 0x0000;
+const BOOT_SECTOR_OFF: u16      = 0x7C00;
+const BOOT_SECTOR_SIZE: usize   = 512;
+const BOOT_SIGNATURE: [u8; 2]   = [0x55, 0xAA];
+
+// Floppy emulation boot image is the first floppy drive
+const FLOPPY_DRIVE: u8          = 0x00;
+
+// Flat images get the last 64K of conventional memory as stack, below extended BDA at 9FC00
+const FLAT_STACK_SEG: u16       = 0x9000;
+const FLAT_STACK_TOP: u16       = 0xFC00;
+
+/**
+ * Real mode register state at image entry
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct EntryState
+{
+    pub cs: u16,
+    pub ip: u16,
+    pub ds: u16,        // Also used for ES
+    pub ss: u16,
+    pub sp: u16,
+    pub dl: u8,         // Boot drive
+}
+
+fn linear(seg: u16, off: u16) -> u64
+{
+    ((seg as u64) << 4) + off as u64
+}
+
+/* Point all interrupt vectors at IRET stub */
+fn setup_low_memory(ram: &vm::memory_region)
+{
+    let mut ivt = Vec::with_capacity(IVT_ENTRIES * 4);
+    for _ in 0..IVT_ENTRIES {
+        ivt.push(IRET_STUB_OFF as u8);
+        ivt.push((IRET_STUB_OFF >> 8) as u8);
+        ivt.push(IRET_STUB_SEG as u8);
+        ivt.push((IRET_STUB_SEG >> 8) as u8);
+    }
+    ram.write_bytes(0, &ivt);
+    ram.write_bytes(linear(IRET_STUB_SEG, IRET_STUB_OFF) as usize, &[IRET_OPCODE]);
+}
+
+/* Check that flat image fits load area without touching stack, IVT/BDA or ROM area */
+fn check_load_area(start: u64, len: usize) -> Result<(), String>
+{
+    let stack_base = linear(FLAT_STACK_SEG, 0);
+    let end = start + len as u64;
+
+    if start < LOAD_AREA_START {
+        return Err(format!("Image at {:x}-{:x} overlaps IVT and BIOS data area below {:x}", start, end, LOAD_AREA_START));
+    }
+
+    if end > ROM_AREA_START {
+        return Err(format!("Image at {:x}-{:x} overlaps ROM area at {:x}", start, end, ROM_AREA_START));
+    }
+
+    if end > stack_base {
+        return Err(format!("Image at {:x}-{:x} overlaps stack at {:x}", start, end, stack_base));
+    }
+
+    Ok(())
+}
+
+/* Boot sector stack grows down from its own load address */
+fn boot_sector_entry(drive: u8) -> EntryState
+{
+    EntryState {
+        cs: BOOT_SECTOR_SEG,
+        ip: BOOT_SECTOR_OFF,
+        ds: 0,
+        ss: 0,
+        sp: BOOT_SECTOR_OFF,
+        dl: drive,
+    }
+}
+
+/**
+ * Place image in guest RAM mapped at guest physical 0 and covering first megabyte.
+ * Returns register state to start it with.
+ */
+pub fn load(ram: &vm::memory_region, image: &[u8], config: &LoadConfig) -> Result<EntryState, String>
+{
+    if (ram.size as u64) < 0x100000 {
+        return Err(format!("Loading without firmware needs first megabyte of RAM, have {:x}", ram.size));
+    }
+
+    if image.is_empty() {
+        return Err(String::from("Image is empty"));
+    }
+
+    let (entry, start) = match *config {
+        LoadConfig::BootSector { drive } => {
+            if image.len() != BOOT_SECTOR_SIZE {
+                return Err(format!("Boot sector must be {} bytes, image has {}", BOOT_SECTOR_SIZE, image.len()));
+            }
+
+            if image[BOOT_SECTOR_SIZE - 2..] != BOOT_SIGNATURE {
+                return Err(String::from("Boot sector has no 55AA signature"));
+            }
+
+            (boot_sector_entry(drive), linear(BOOT_SECTOR_SEG, BOOT_SECTOR_OFF))
+        },
+
+        LoadConfig::Flat { load, entry } => {
+            let start = linear(load.0, load.1);
+            try!(check_load_area(start, image.len()));
+
+            let entry_addr = linear(entry.0, entry.1);
+            if entry_addr < LOAD_AREA_START || entry_addr >= ROM_AREA_START {
+                return Err(format!("Entry point {:04x}:{:04x} is outside of load area", entry.0, entry.1));
+            }
+
+            (EntryState {
+                cs: entry.0,
+                ip: entry.1,
+                ds: load.0,
+                ss: FLAT_STACK_SEG,
+                sp: FLAT_STACK_TOP,
+                dl: 0,
+            }, start)
+        },
+
+        LoadConfig::Cdrom { .. } => return Err(String::from("CD boot image is loaded from CD-ROM medium")),
+    };
+
+    setup_low_memory(ram);
+    ram.write_bytes(start as usize, image);
+
+    Ok(entry)
+}
+
+/**
+ * Boot CD image through its El Torito boot catalog, no emulation boot images get CD drive number in DL
+ */
+pub fn load_cd(ram: &vm::memory_region, mut iso: Box<disk_image>, drive: u8) -> Result<EntryState, String>
+{
+    if (ram.size as u64) < 0x100000 {
+        return Err(format!("Loading without firmware needs first megabyte of RAM, have {:x}", ram.size));
+    }
+
+    let boot = try!(eltorito::find_boot_entry(&mut *iso));
+    match boot.media {
+        BootMedia::NoEmulation => {
+            let start = linear(boot.load_segment, 0);
+            let mut image = vec![0u8; boot.sectors as usize * eltorito::VIRTUAL_SECTOR_SIZE];
+            try!(check_load_area(start, image.len()));
+            if let Err(err) = iso.read_at(boot.offset(), &mut image) {
+                return Err(format!("Can't read boot image: {}", err));
+            }
+
+            setup_low_memory(ram);
+            ram.write_bytes(start as usize, &image);
+
+            Ok(EntryState {
+                cs: boot.load_segment,
+                ip: 0,
+                ds: 0,
+                ss: FLAT_STACK_SEG,
+                sp: FLAT_STACK_TOP,
+                dl: drive,
+            })
+        },
+
+        BootMedia::Floppy(_) => {
+            let mut floppy = try!(eltorito::floppy_image(iso, &boot));
+            let mut sector = [0u8; BOOT_SECTOR_SIZE];
+            if let Err(err) = floppy.read_at(0, &mut sector) {
+                return Err(format!("Can't read boot image: {}", err));
+            }
+
+            load(ram, &sector, &LoadConfig::BootSector { drive: FLOPPY_DRIVE })
+        },
+    }
+}
+
+#[cfg(test)]
+mod loader_test
+{
+    use super::*;
+
+    fn make_ram() -> ::std::sync::Arc<vm::memory_region> {
+        let ram = vm::alloc_memory_region(0x100000);
+        ram.write_bytes(0, &vec![0xCCu8; 0x100000]);
+        ram
+    }
+
+    fn read_word(ram: &vm::memory_region, addr: u64) -> u16 {
+        let mut buf = [0u8; 2];
+        ram.read_bytes(addr as usize, &mut buf);
+        buf[0] as u16 | (buf[1] as u16) << 8
+    }
+
+    /* Interrupt vector resolves to an IRET */
+    fn check_ivt(ram: &vm::memory_region, vector: u64) {
+        let off = read_word(ram, vector * 4);
+        let seg = read_word(ram, vector * 4 + 2);
+        let mut op = [0u8; 1];
+        ram.read_bytes(linear(seg, off) as usize, &mut op);
+        assert!(op[0] == IRET_OPCODE);
+    }
+
+    #[test] fn boot_sector() {
+        let ram = make_ram();
+        let image = include_bytes!("../test/boot/exit.bin");
+
+        let entry = load(&ram, image, &LoadConfig::BootSector { drive: 0x80 }).unwrap();
+        assert!(entry == EntryState { cs: 0, ip: 0x7C00, ds: 0, ss: 0, sp: 0x7C00, dl: 0x80 });
+
+        let mut sector = [0u8; 512];
+        ram.read_bytes(0x7C00, &mut sector);
+        assert!(&sector[..] == &image[..]);
+
+        /* Sector does INT 10h before writing its status to debug exit port */
+        assert!(sector[5..7] == [0xCD, 0x10]);
+        check_ivt(&ram, 0x10);
+        check_ivt(&ram, 0xFF);
+
+        let mut bad = image.to_vec();
+        bad[511] = 0;
+        assert!(load(&ram, &bad, &LoadConfig::BootSector { drive: 0x80 }).is_err());
+        assert!(load(&ram, &image[..256], &LoadConfig::BootSector { drive: 0x80 }).is_err());
+    }
+
+    #[test] fn flat_binary() {
+        let ram = make_ram();
+        let image = [0x90u8; 0x1000];
+
+        let entry = load(&ram, &image, &LoadConfig::Flat { load: (0x1000, 0x100), entry: (0x1000, 0x180) }).unwrap();
+        assert!(entry == EntryState { cs: 0x1000, ip: 0x180, ds: 0x1000, ss: FLAT_STACK_SEG, sp: FLAT_STACK_TOP, dl: 0 });
+
+        let mut buf = [0u8; 2];
+        ram.read_bytes(0x10100 + 0xFFF, &mut buf);
+        assert!(buf == [0x90, 0xCC]);
+        check_ivt(&ram, 0x21);
+    }
+
+    #[test] fn load_area() {
+        let ram = make_ram();
+        let image = [0x90u8; 0x1000];
+        let flat = |seg, off| LoadConfig::Flat { load: (seg, off), entry: (seg, off) };
+
+        assert!(load(&ram, &image, &flat(0, 0x500)).is_ok());
+        assert!(load(&ram, &image, &flat(0, 0x400)).is_err());
+        assert!(load(&ram, &image, &flat(0x8F00, 0)).is_ok());
+
+        /* Last 64K is stack, video memory and ROMs follow */
+        assert!(load(&ram, &image, &flat(0x8F00, 1)).unwrap_err().contains("stack"));
+        assert!(load(&ram, &image, &flat(0x9F80, 0)).unwrap_err().contains("ROM"));
+        assert!(load(&ram, &image, &flat(0xF000, 0)).unwrap_err().contains("ROM"));
+        assert!(load(&ram, &image, &flat(0xFFFF, 0xFFFF)).unwrap_err().contains("ROM"));
+        assert!(load(&ram, &[], &flat(0x1000, 0)).is_err());
+
+        let bad_entry = LoadConfig::Flat { load: (0x1000, 0), entry: (0xA000, 0) };
+        assert!(load(&ram, &image, &bad_entry).is_err());
+
+        let small = vm::alloc_memory_region(0xA0000);
+        assert!(load(&small, &image, &flat(0x1000, 0)).is_err());
+    }
+
+    #[test] fn cd_no_emulation() {
+        let ram = make_ram();
+        let boot = include_bytes!("../test/boot/cdboot.bin");
+        let iso = eltorito::make_boot_iso(0x00, 0, 4, boot);
+
+        let entry = load_cd(&ram, Box::new(iso), 0xE0).unwrap();
+        assert!(entry == EntryState { cs: 0x07C0, ip: 0, ds: 0, ss: FLAT_STACK_SEG, sp: FLAT_STACK_TOP, dl: 0xE0 });
+
+        let mut image = [0u8; 2048];
+        ram.read_bytes(0x7C00, &mut image);
+        assert!(&image[..] == &boot[..]);
+        check_ivt(&ram, 0x13);
+
+        /* Boot code checks DL and CS before writing its status to debug exit port */
+        assert!(image[..3] == [0x80, 0xFA, 0xE0]);
+        assert!(image[27..29] == [0xE6, 0xF4]);
+
+        /* Only as many virtual sectors as boot catalog entry says are loaded */
+        let ram = make_ram();
+        let iso = eltorito::make_boot_iso(0x00, 0x2000, 1, boot);
+        assert!(load_cd(&ram, Box::new(iso), 0xE0).unwrap().cs == 0x2000);
+        let mut buf = [0u8; 2];
+        ram.read_bytes(0x20000 + 511, &mut buf);
+        assert!(buf == [0x00, 0xCC]);
+
+        let iso = eltorito::make_boot_iso(0x00, 0x0040, 4, boot);
+        assert!(load_cd(&ram, Box::new(iso), 0xE0).unwrap_err().contains("IVT"));
+    }
+
+    #[test] fn cd_floppy_emulation() {
+        let ram = make_ram();
+        let mut floppy = vec![0u8; 1474560];
+        floppy[..512].copy_from_slice(include_bytes!("../test/boot/exit.bin"));
+        let iso = eltorito::make_boot_iso(0x02, 0, 1, &floppy);
+
+        let entry = load_cd(&ram, Box::new(iso), 0xE0).unwrap();
+        assert!(entry == EntryState { cs: 0, ip: 0x7C00, ds: 0, ss: 0, sp: 0x7C00, dl: 0x00 });
+
+        let mut sector = [0u8; 512];
+        ram.read_bytes(0x7C00, &mut sector);
+        assert!(&sector[..] == &floppy[..512]);
+
+        /* 2.88M diskette doesn't fit CD image */
+        let iso = eltorito::make_boot_iso(0x03, 0, 1, &floppy);
+        assert!(load_cd(&ram, Box::new(iso), 0xE0).unwrap_err().contains("past end"));
+    }
+
+    #[test] fn cd_not_bootable() {
+        let ram = make_ram();
+        let mut iso = eltorito::make_boot_iso(0x00, 0, 4, &[0x90u8; 2048]);
+        iso.data[19 * 2048 + 32] = 0;
+        assert!(load_cd(&ram, Box::new(iso), 0xE0).unwrap_err().contains("not bootable"));
+
+        let iso = ::disk::MemImage::new(64 * 2048);
+        assert!(load_cd(&ram, Box::new(iso), 0xE0).is_err());
+        assert!(load(&ram, &[0x90u8; 512], &LoadConfig::Cdrom { drive: 0xE0 }).is_err());
+    }
+}
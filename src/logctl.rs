@@ -0,0 +1,385 @@
+/*
+ * Per-device log levels
+ *
+ * Each device logs under its module target, e.g. xvm::pic, and has a level of its own that can be changed while
+ * VM runs, see "log" monitor commands. Modules that aren't devices share one more level, shown as "other".
+ * Levels are atomics, a log statement costs relaxed loads: the log crate's max level is kept at the most verbose
+ * level set, so statements nobody asked for stop there, and logger only looks up the level of its target for the
+ * rest.
+ *
+ * "log trace-for <device> <seconds>" turns a device up to trace and back to its level after seconds of guest time,
+ * so a noisy device can be watched for a while without drowning the rest of the run.
+ */
+
+use event;
+use clock;
+use monitor;
+
+use log::{LogLevel, LogLevelFilter, MaxLogLevelFilter};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+const US_PER_SEC: u64 = 1_000_000;
+
+// Devices with a level of their own, named after their modules
+pub const DEVICES: [&'static str; 21] = [
+    "apic", "ata", "cmos", "dma", "fdc", "flash", "i8042", "ioapic", "lpt", "miscdev", "ne2000",
+    "pci", "pic", "pit", "pmtimer", "pvcon", "qemudbg", "serial", "uart", "vga", "watchdog",
+];
+
+// Name of the level everything else logs at
+const OTHER: &'static str = "other";
+
+// Level filters by their numeric value
+const FILTERS: [LogLevelFilter; 6] = [
+    LogLevelFilter::Off, LogLevelFilter::Error, LogLevelFilter::Warn,
+    LogLevelFilter::Info, LogLevelFilter::Debug, LogLevelFilter::Trace,
+];
+
+/* Level a device goes back to at a guest time deadline */
+struct Revert
+{
+    slot: usize,
+    level: LogLevelFilter,
+    deadline: u64,
+}
+
+/**
+ * Log levels of devices, slot DEVICES.len() holds the level of everything else
+ */
+pub struct LogLevels
+{
+    levels: Vec<AtomicUsize>,
+    reverts: Mutex<Vec<Revert>>,
+}
+
+/**
+ * Level filter by name, e.g. "debug"
+ */
+pub fn parse_level(val: &str) -> Result<LogLevelFilter, String>
+{
+    val.parse::<LogLevelFilter>().map_err(|_| format!("Bad log level {}, use off, error, warn, info, debug or trace", val))
+}
+
+/* Device slot of a log target, crate name in front is optional */
+fn target_slot(target: &str) -> usize
+{
+    let mut parts = target.split("::");
+    let first = parts.next().unwrap_or("");
+    let name = parts.next().unwrap_or(first);
+    DEVICES.iter().position(|&device| device == name).unwrap_or(DEVICES.len())
+}
+
+impl LogLevels
+{
+    pub fn new(default: LogLevelFilter) -> LogLevels {
+        LogLevels {
+            levels: (0..DEVICES.len() + 1).map(|_| AtomicUsize::new(default as usize)).collect(),
+            reverts: Mutex::new(Vec::new()),
+        }
+    }
+
+    /* Slots device name stands for, "all" is every device and everything else */
+    fn slots(&self, device: &str) -> Result<Vec<usize>, String> {
+        if device == "all" {
+            return Ok((0..self.levels.len()).collect());
+        }
+        if device == OTHER {
+            return Ok(vec![DEVICES.len()]);
+        }
+
+        match target_slot(device) {
+            slot if slot < DEVICES.len() => Ok(vec![slot]),
+            _ => Err(format!("Unknown device {}, see log list", device)),
+        }
+    }
+
+    fn level(&self, slot: usize) -> LogLevelFilter {
+        FILTERS[self.levels[slot].load(Ordering::Relaxed)]
+    }
+
+    /**
+     * Record at level for target gets logged
+     */
+    pub fn enabled(&self, level: LogLevel, target: &str) -> bool {
+        level as usize <= self.levels[target_slot(target)].load(Ordering::Relaxed)
+    }
+
+    /**
+     * Most verbose level set
+     */
+    pub fn max(&self) -> LogLevelFilter {
+        (0..self.levels.len()).map(|slot| self.level(slot)).max().unwrap_or(LogLevelFilter::Off)
+    }
+
+    /**
+     * Set device level, drops a pending revert of it
+     */
+    pub fn set(&self, device: &str, level: LogLevelFilter) -> Result<(), String> {
+        let slots = try!(self.slots(device));
+        self.reverts.lock().unwrap().retain(|revert| !slots.contains(&revert.slot));
+        for slot in slots {
+            self.levels[slot].store(level as usize, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /**
+     * Set device level until guest time deadline, when it goes back to the level it has now
+     */
+    pub fn set_until(&self, device: &str, level: LogLevelFilter, deadline: u64) -> Result<(), String> {
+        let slots = try!(self.slots(device));
+        let mut reverts = self.reverts.lock().unwrap();
+
+        /* Level before an earlier pending change is the one to go back to */
+        for slot in slots {
+            let previous = match reverts.iter().position(|revert| revert.slot == slot) {
+                Some(pos) => reverts.remove(pos).level,
+                None => self.level(slot),
+            };
+            reverts.push(Revert { slot: slot, level: previous, deadline: deadline });
+            self.levels[slot].store(level as usize, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /**
+     * Revert levels with deadlines up to guest time now, number of devices reverted
+     */
+    pub fn revert_due(&self, now: u64) -> usize {
+        let mut reverts = self.reverts.lock().unwrap();
+        let before = reverts.len();
+        for revert in reverts.iter().filter(|revert| revert.deadline <= now) {
+            self.levels[revert.slot].store(revert.level as usize, Ordering::Relaxed);
+        }
+        reverts.retain(|revert| revert.deadline > now);
+        before - reverts.len()
+    }
+
+    /**
+     * Devices with their levels, everything else last
+     */
+    pub fn list(&self) -> Vec<(&'static str, LogLevelFilter)> {
+        DEVICES.iter().chain(Some(&OTHER)).enumerate().map(|(slot, &name)| (name, self.level(slot))).collect()
+    }
+}
+
+#[cfg(test)]
+mod logctl_test
+{
+    use super::*;
+    use log::{self, LogRecord, LogMetadata};
+    use std::sync::Arc;
+
+    /* Logger keeping the messages levels let through */
+    struct CapturingLogger
+    {
+        levels: Arc<LogLevels>,
+        lines: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, metadata: &LogMetadata) -> bool {
+            self.levels.enabled(metadata.level(), metadata.target())
+        }
+
+        fn log(&self, record: &LogRecord) {
+            /* Other tests may log too, only messages of this one are kept */
+            let line = format!("{} {}", record.target(), record.args());
+            if self.enabled(record.metadata()) && line.contains("logctl-test") {
+                self.lines.lock().unwrap().push(line);
+            }
+        }
+    }
+
+    #[test] fn levels() {
+        let levels = LogLevels::new(LogLevelFilter::Warn);
+        assert!(levels.enabled(LogLevel::Warn, "xvm::pic") && !levels.enabled(LogLevel::Info, "xvm::pic"));
+        assert!(levels.max() == LogLevelFilter::Warn);
+
+        assert!(levels.set("pic", LogLevelFilter::Trace).is_ok());
+        assert!(levels.enabled(LogLevel::Trace, "xvm::pic"));
+        assert!(levels.enabled(LogLevel::Trace, "pic"));
+        assert!(!levels.enabled(LogLevel::Debug, "xvm::pit"));
+        assert!(!levels.enabled(LogLevel::Debug, "xvm::main"));
+        assert!(levels.max() == LogLevelFilter::Trace);
+
+        assert!(levels.set("xvm::pit", LogLevelFilter::Off).is_ok());
+        assert!(!levels.enabled(LogLevel::Error, "xvm::pit"));
+        assert!(levels.set("other", LogLevelFilter::Info).is_ok());
+        assert!(levels.enabled(LogLevel::Info, "xvm::vm"));
+
+        let list = levels.list();
+        assert!(list.len() == DEVICES.len() + 1);
+        assert!(list.contains(&("pic", LogLevelFilter::Trace)) && list.contains(&("pit", LogLevelFilter::Off)));
+        assert!(list[DEVICES.len()] == ("other", LogLevelFilter::Info));
+
+        assert!(levels.set("all", LogLevelFilter::Error).is_ok());
+        assert!(levels.list().iter().all(|&(_, level)| level == LogLevelFilter::Error));
+
+        assert!(levels.set("i8259", LogLevelFilter::Trace).is_err());
+        assert!(parse_level("debug") == Ok(LogLevelFilter::Debug));
+        assert!(parse_level("loud").is_err());
+    }
+
+    #[test] fn revert() {
+        let levels = LogLevels::new(LogLevelFilter::Warn);
+        assert!(levels.set_until("pic", LogLevelFilter::Trace, 10 * NS_PER_SEC).is_ok());
+
+        /* Extending keeps the level from before the first change */
+        assert!(levels.set_until("pic", LogLevelFilter::Debug, 20 * NS_PER_SEC).is_ok());
+        assert!(levels.revert_due(15 * NS_PER_SEC) == 0);
+        assert!(levels.enabled(LogLevel::Debug, "xvm::pic"));
+        assert!(levels.revert_due(20 * NS_PER_SEC) == 1);
+        assert!(!levels.enabled(LogLevel::Info, "xvm::pic"));
+
+        /* Setting a level for good cancels the revert */
+        assert!(levels.set_until("all", LogLevelFilter::Trace, 10 * NS_PER_SEC).is_ok());
+        assert!(levels.set("pit", LogLevelFilter::Info).is_ok());
+        assert!(levels.revert_due(10 * NS_PER_SEC) == DEVICES.len());
+        assert!(levels.enabled(LogLevel::Info, "xvm::pit") && !levels.enabled(LogLevel::Info, "xvm::pic"));
+    }
+
+    #[test] fn capture() {
+        let levels = Arc::new(LogLevels::new(LogLevelFilter::Warn));
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let logger = CapturingLogger { levels: levels.clone(), lines: lines.clone() };
+        log::set_logger(|max_log_level| {
+            max_log_level.set(LogLevelFilter::Trace);
+            Box::new(logger)
+        }).unwrap();
+
+        /* Quiet boot, then PIC alone turned up for a while */
+        debug!(target: "xvm::pic", "logctl-test quiet");
+        assert!(levels.set_until("pic", LogLevelFilter::Trace, 10 * NS_PER_SEC).is_ok());
+        trace!(target: "xvm::pic", "logctl-test irq {}", 0);
+        debug!(target: "xvm::pit", "logctl-test reload");
+        warn!(target: "xvm::pit", "logctl-test bad mode");
+        assert!(levels.revert_due(10 * NS_PER_SEC) == 1);
+        trace!(target: "xvm::pic", "logctl-test after");
+
+        assert!(*lines.lock().unwrap() == vec!["xvm::pic logctl-test irq 0", "xvm::pit logctl-test bad mode"]);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+lazy_static! {
+    static ref LEVELS: LogLevels = LogLevels::new(LogLevelFilter::Warn);
+    static ref MAX_LEVEL: Mutex<Option<MaxLogLevelFilter>> = Mutex::new(None);
+}
+
+/* Let the log crate pass the most verbose level set */
+fn update_max_level()
+{
+    if let Some(ref max_level) = *MAX_LEVEL.lock().unwrap() {
+        max_level.set(LEVELS.max());
+    }
+}
+
+fn revert_event(ev: event::Event)
+{
+    if LEVELS.revert_due(ev.deadline()) != 0 {
+        update_max_level();
+    }
+}
+
+/**
+ * Take over the log crate's max level, logger gets it when it is installed
+ */
+pub fn set_max_level_filter(max_level: MaxLogLevelFilter)
+{
+    max_level.set(LEVELS.max());
+    *MAX_LEVEL.lock().unwrap() = Some(max_level);
+}
+
+/**
+ * Record at level for target gets logged
+ */
+pub fn enabled(level: LogLevel, target: &str) -> bool
+{
+    LEVELS.enabled(level, target)
+}
+
+/**
+ * Set log level of a device, "other" or "all"
+ */
+pub fn set_level(device: &str, level: LogLevelFilter) -> Result<(), String>
+{
+    try!(LEVELS.set(device, level));
+    update_max_level();
+    Ok(())
+}
+
+/**
+ * Log device at trace level for seconds of guest time, then go back to its level
+ */
+pub fn trace_for(device: &str, secs: u64) -> Result<(), String>
+{
+    let deadline = clock::guest_time_ns() + secs * NS_PER_SEC;
+    try!(LEVELS.set_until(device, LogLevelFilter::Trace, deadline));
+    update_max_level();
+    event::schedule_event(secs * US_PER_SEC, event::create_event(revert_event));
+    Ok(())
+}
+
+/**
+ * Devices with their log levels
+ */
+pub fn levels() -> Vec<(&'static str, LogLevelFilter)>
+{
+    LEVELS.list()
+}
+
+fn cmd_log_list(_ctx: &mut monitor::MonitorContext, _args: &[&str]) -> Result<String, String>
+{
+    let lines: Vec<String> = levels().iter().map(|&(device, level)| {
+        format!("{:<10} {}", device, level.to_string().to_lowercase())
+    }).collect();
+    Ok(lines.join("\n"))
+}
+
+fn cmd_log(_ctx: &mut monitor::MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    if args.len() != 2 {
+        return Err(String::from("Usage: log <device>|all <level>"));
+    }
+
+    let level = try!(parse_level(args[1]));
+    try!(set_level(args[0], level));
+    Ok(String::new())
+}
+
+fn cmd_log_trace_for(_ctx: &mut monitor::MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    if args.len() != 2 {
+        return Err(String::from("Usage: log trace-for <device>|all <seconds>"));
+    }
+
+    let secs = try!(args[1].parse::<u64>().map_err(|_| format!("Bad number of seconds {}", args[1])));
+    try!(trace_for(args[0], secs));
+    Ok(String::new())
+}
+
+pub fn init()
+{
+    monitor::register_command(monitor::MonitorCommand {
+        name: "log list",
+        args: "",
+        help: "show log level of each device",
+        handler: cmd_log_list,
+    });
+    monitor::register_command(monitor::MonitorCommand {
+        name: "log",
+        args: "<device>|all <level>",
+        help: "set device log level: off, error, warn, info, debug or trace",
+        handler: cmd_log,
+    });
+    monitor::register_command(monitor::MonitorCommand {
+        name: "log trace-for",
+        args: "<device>|all <seconds>",
+        help: "log device at trace level for seconds of guest time",
+        handler: cmd_log_trace_for,
+    });
+}
@@ -0,0 +1,338 @@
+/*
+ * Standard parallel port (LPT1) at 0x378-0x37A in SPP mode
+ *
+ * Strobe pulse on control port latches data byte into a printer sink.
+ * Printer takes the byte instantly, but the following status read still observes
+ * a single busy/ACK pulse so that polling drivers see the handshake complete.
+ */
+
+use vm;
+use config;
+
+use std::fs::File;
+use std::io::Write;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+pub const LPT1_BASE: u16        = 0x378;
+const LPT1_IRQ: u8              = 7;
+
+// Register offsets from port base
+const LPT_REG_DATA: u16         = 0;
+const LPT_REG_STATUS: u16       = 1;
+const LPT_REG_CONTROL: u16      = 2;
+
+// Status bits, BUSY and ACK are active low
+const LPT_SR_NOT_BUSY: u8       = 0x80;
+const LPT_SR_NOT_ACK: u8        = 0x40;
+const LPT_SR_PAPER_OUT: u8      = 0x20;
+const LPT_SR_SELECT: u8         = 0x10;
+const LPT_SR_NOT_ERROR: u8      = 0x08;
+
+// Control bits, INIT is active low
+const LPT_CR_STROBE: u8         = 0x01;
+const LPT_CR_INIT: u8           = 0x04;
+const LPT_CR_IRQ_EN: u8         = 0x10;
+const LPT_CR_MASK: u8           = 0x3F;
+const LPT_CR_RESERVED: u8       = 0xC0; // Unused bits read as 1
+
+/**
+ * Printer attached to the port, receives every strobed byte
+ */
+pub trait printer_sink
+{
+    fn write_byte(&mut self, val: u8);
+}
+
+/**
+ * Capture printer output to a host file
+ */
+pub struct FileSink
+{
+    file: File,
+}
+
+impl FileSink
+{
+    pub fn create(path: &str) -> ::std::io::Result<FileSink> {
+        Ok(FileSink {
+            file: try!(File::create(path)),
+        })
+    }
+}
+
+impl printer_sink for FileSink
+{
+    fn write_byte(&mut self, val: u8) {
+        self.file.write_all(&[val]).unwrap_or_else(|err| {
+            error!("lpt: failed writing to file: {}", err);
+        });
+    }
+}
+
+/**
+ * Any byte callback works as a sink
+ */
+impl<F: FnMut(u8)> printer_sink for F
+{
+    fn write_byte(&mut self, val: u8) {
+        self(val)
+    }
+}
+
+/*
+ * Parallel port state
+ */
+struct ParallelPort
+{
+    data: u8,
+    control: u8,
+    ack_pulse: bool,        // Next status read observes busy and ACK after a strobe
+    irq: bool,
+    sink: Box<printer_sink>,
+}
+
+impl ParallelPort
+{
+    fn new(sink: Box<printer_sink>) -> ParallelPort {
+        ParallelPort {
+            data: 0,
+            control: LPT_CR_INIT,
+            ack_pulse: false,
+            irq: false,
+            sink: sink,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.data = 0;
+        self.control = LPT_CR_INIT;
+        self.ack_pulse = false;
+        self.irq = false;
+    }
+
+    /**
+     * Take pending interrupt request
+     */
+    fn take_irq(&mut self) -> bool {
+        let irq = self.irq;
+        self.irq = false;
+        irq
+    }
+
+    fn read_status(&mut self) -> u8 {
+        let status = LPT_SR_SELECT | LPT_SR_NOT_ERROR;
+
+        if self.ack_pulse {
+            self.ack_pulse = false;
+            return status;
+        }
+
+        status | LPT_SR_NOT_BUSY | LPT_SR_NOT_ACK
+    }
+
+    fn write_control(&mut self, val: u8) {
+        let val = val & LPT_CR_MASK;
+
+        /* Printer is held in reset while INIT is low and ignores strobes */
+        if val & LPT_CR_INIT == 0 {
+            self.ack_pulse = false;
+        } else if self.control & LPT_CR_STROBE != 0 && val & LPT_CR_STROBE == 0 {
+            /* Byte is latched on the trailing edge of strobe */
+            self.sink.write_byte(self.data);
+            self.ack_pulse = true;
+
+            if val & LPT_CR_IRQ_EN != 0 {
+                self.irq = true;
+            }
+        }
+
+        self.control = val;
+    }
+
+    fn read_port(&mut self, offset: u16) -> u8 {
+        match offset {
+            LPT_REG_DATA => self.data,
+            LPT_REG_STATUS => self.read_status(),
+            LPT_REG_CONTROL => self.control | LPT_CR_RESERVED,
+            _ => panic!(),
+        }
+    }
+
+    fn write_port(&mut self, offset: u16, val: u8) {
+        match offset {
+            LPT_REG_DATA => self.data = val,
+            LPT_REG_STATUS => (),
+            LPT_REG_CONTROL => self.write_control(val),
+            _ => panic!(),
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct LPTDev
+{
+    port: RefCell<ParallelPort>,
+    base: u16,
+    irq: u8,
+    assert_irq: fn(u8),
+}
+
+impl vm::io_handler for LPTDev
+{
+    fn io_read(&self, port: u16, size: u8) -> vm::IoOperandType
+    {
+        assert!(size == 1);
+        vm::IoOperandType::byte(self.port.borrow_mut().read_port(port - self.base))
+    }
+
+    fn io_write(&self, port: u16, data: vm::IoOperandType)
+    {
+        let irq = {
+            let mut lpt = self.port.borrow_mut();
+            lpt.write_port(port - self.base, data.unwrap_byte());
+            lpt.take_irq()
+        };
+
+        if irq {
+            (self.assert_irq)(self.irq);
+        }
+    }
+}
+
+impl vm::reset_handler for LPTDev
+{
+    fn reset(&self)
+    {
+        self.port.borrow_mut().reset();
+    }
+}
+
+#[cfg(test)]
+mod lpt_test
+{
+    use super::*;
+    use std::cell::Cell;
+
+    thread_local! {
+        static IRQ_COUNT: Cell<u32> = Cell::new(0);
+    }
+
+    fn count_irq(irq: u8) {
+        assert!(irq == LPT1_IRQ);
+        IRQ_COUNT.with(|c| c.set(c.get() + 1));
+    }
+
+    fn irq_count() -> u32 {
+        IRQ_COUNT.with(|c| c.get())
+    }
+
+    fn make_dev(output: Rc<RefCell<Vec<u8>>>) -> LPTDev {
+        let sink = move |val: u8| output.borrow_mut().push(val);
+        LPTDev {
+            port: RefCell::new(ParallelPort::new(Box::new(sink))),
+            base: LPT1_BASE,
+            irq: LPT1_IRQ,
+            assert_irq: count_irq,
+        }
+    }
+
+    fn outb(dev: &LPTDev, reg: u16, val: u8) {
+        vm::io_handler::io_write(dev, LPT1_BASE + reg, vm::IoOperandType::byte(val));
+    }
+
+    fn inb(dev: &LPTDev, reg: u16) -> u8 {
+        vm::io_handler::io_read(dev, LPT1_BASE + reg, 1).unwrap_byte()
+    }
+
+    /* Int 17h style: wait for not busy, put data, pulse strobe, check ACK */
+    fn print_byte(dev: &LPTDev, val: u8, control: u8) {
+        assert!(inb(dev, LPT_REG_STATUS) & (LPT_SR_NOT_BUSY | LPT_SR_SELECT | LPT_SR_NOT_ERROR) == 0x98);
+        outb(dev, LPT_REG_DATA, val);
+        outb(dev, LPT_REG_CONTROL, control | LPT_CR_STROBE);
+        outb(dev, LPT_REG_CONTROL, control);
+
+        let status = inb(dev, LPT_REG_STATUS);
+        assert!(status & (LPT_SR_NOT_BUSY | LPT_SR_NOT_ACK) == 0);
+        assert!(status & LPT_SR_PAPER_OUT == 0);
+    }
+
+    #[test] fn strobe_handshake() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let dev = make_dev(output.clone());
+
+        assert!(inb(&dev, LPT_REG_STATUS) == 0xD8);
+        assert!(inb(&dev, LPT_REG_CONTROL) == 0xC4);
+
+        /* Data latch reads back, nothing is printed without strobe */
+        outb(&dev, LPT_REG_DATA, 0x5A);
+        assert!(inb(&dev, LPT_REG_DATA) == 0x5A);
+        assert!(output.borrow().is_empty());
+
+        /* Byte goes out on strobe release only */
+        outb(&dev, LPT_REG_CONTROL, LPT_CR_INIT | LPT_CR_STROBE);
+        assert!(output.borrow().is_empty());
+        outb(&dev, LPT_REG_CONTROL, LPT_CR_INIT);
+        assert!(*output.borrow() == [0x5A]);
+        assert!(inb(&dev, LPT_REG_STATUS) == 0x18);
+        assert!(inb(&dev, LPT_REG_STATUS) == 0xD8);
+
+        output.borrow_mut().clear();
+        for b in b"HELLO\r\n".iter() {
+            print_byte(&dev, *b, LPT_CR_INIT);
+        }
+        assert!(&output.borrow()[..] == b"HELLO\r\n");
+        assert!(irq_count() == 0);
+
+        /* Strobes during INIT are ignored */
+        outb(&dev, LPT_REG_CONTROL, LPT_CR_STROBE);
+        outb(&dev, LPT_REG_CONTROL, 0);
+        assert!(output.borrow().len() == 7);
+    }
+
+    #[test] fn ack_interrupt() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let dev = make_dev(output.clone());
+        let irqs = irq_count();
+
+        print_byte(&dev, b'A', LPT_CR_INIT | LPT_CR_IRQ_EN);
+        assert!(irq_count() == irqs + 1);
+        assert!(inb(&dev, LPT_REG_CONTROL) == 0xD4);
+
+        print_byte(&dev, b'B', LPT_CR_INIT);
+        assert!(irq_count() == irqs + 1);
+        assert!(&output.borrow()[..] == b"AB");
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/* Printer that isn't connected to anything swallows output */
+fn discard(_: u8)
+{
+}
+
+pub fn init(config: &config::VmConfig)
+{
+    let sink: Box<printer_sink> = match config.lpt {
+        Some(ref path) => match FileSink::create(path) {
+            Ok(sink) => Box::new(sink),
+            Err(err) => panic!("lpt: failed to create {}: {}", path, err),
+        },
+        None => Box::new(discard),
+    };
+
+    let dev = Rc::new(LPTDev {
+        port: RefCell::new(ParallelPort::new(sink)),
+        base: LPT1_BASE,
+        irq: LPT1_IRQ,
+        assert_irq: vm::assert_irq,
+    });
+
+    for reg in LPT_REG_DATA..(LPT_REG_CONTROL + 1) {
+        vm::register_io_region(dev.clone(), LPT1_BASE + reg, 1);
+    }
+
+    vm::register_reset_handler(dev.clone());
+}
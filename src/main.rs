@@ -19,6 +19,61 @@ mod vm;
 mod pci;
 mod pic;
 mod event;
+mod i8042;
+mod config;
+mod vga;
+mod dma;
+mod disk;
+mod fdc;
+mod ata;
+mod lpt;
+mod net;
+mod slirp;
+mod ne2000;
+mod clock;
+mod pmtimer;
+mod serial;
+mod uart;
+mod smbios;
+mod loader;
+mod eltorito;
+mod bda;
+mod biosassist;
+mod biosdisk;
+mod bioskbd;
+mod biossys;
+mod hypercall;
+mod pvcon;
+mod watchdog;
+mod flash;
+mod mmio;
+mod tsc;
+mod apic;
+mod inject;
+mod ioapic;
+mod gdbstub;
+mod monitor;
+mod breakpoint;
+mod trace;
+mod crash;
+mod disasm;
+mod eventlog;
+mod logctl;
+mod summary;
+mod devstate;
+mod beacon;
+mod hang;
+mod symbols;
+mod tracefilter;
+mod metrics;
+mod coredump;
+mod snapshot;
+mod record;
+mod replay;
+mod livesnap;
+mod autosnap;
+mod snapdiff;
+mod reverse;
 
 use hypervisor_framework::*;
 use rlibc::*;
@@ -26,14 +81,31 @@ use std::sync::Arc;
 use std::fs::*;
 use std::io::Read;
 use std::env;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use log::*;
 use num::traits::*;
 
+// General purpose registers in instruction encoding order
+const GPREG_MAP: [hv_x86_reg_t; 8] = [
+    hv_x86_reg_t::HV_X86_RAX,
+    hv_x86_reg_t::HV_X86_RCX,
+    hv_x86_reg_t::HV_X86_RDX,
+    hv_x86_reg_t::HV_X86_RBX,
+    hv_x86_reg_t::HV_X86_RSP,
+    hv_x86_reg_t::HV_X86_RBP,
+    hv_x86_reg_t::HV_X86_RSI,
+    hv_x86_reg_t::HV_X86_RDI,
+];
+
+// EPT violation exit qualification
+const EPT_VIOLATION_FETCH: u64 = 1 << 2;
+
 struct SimpleLogger;
 
 impl log::Log for SimpleLogger {
     fn enabled(&self, metadata: &LogMetadata) -> bool {
-        metadata.level() <= LogLevel::Warn
+        logctl::enabled(metadata.level(), metadata.target())
     }
 
     fn log(&self, record: &LogRecord) {
@@ -46,7 +118,7 @@ impl log::Log for SimpleLogger {
 impl SimpleLogger {
     pub fn init() -> Result<(), SetLoggerError> {
         log::set_logger(|max_log_level| {
-            max_log_level.set(LogLevelFilter::Debug);
+            logctl::set_max_level_filter(max_log_level);
             Box::new(SimpleLogger)
         })
     }
@@ -269,6 +341,134 @@ fn next_instruction(vcpu: hv_vcpuid_t)
 }
 
 // TODO: macro
+/*
+ * Complete guest access to unmapped memory by decoding faulting instruction and dispatching it to MMIO handler.
+ * Unclaimed reads return all ones and unclaimed writes are dropped, like on an open bus.
+ */
+fn handle_mmio(vcpu: hv_vcpuid_t, ip: u64, gpa: hv_gpaddr_t)
+{
+    let mut code = [0u8; mmio::MAX_INSN_LEN];
+    let len = vm::read_guest_memory(ip, &mut code);
+
+    let insn = match mmio::decode(&code[..len], !is_in_real_mode(vcpu)) {
+        Some(insn) => insn,
+        None => {
+            crash::fatal(format!("Unsupported MMIO instruction at 0x{:x} accessing 0x{:x}: {:?}", ip, gpa, &code[..len]));
+            return;
+        },
+    };
+
+    if insn.is_write {
+        let data = match insn.operand {
+            mmio::MmioOperand::Reg(reg) => {
+                let regval = read_guest_reg(vcpu, GPREG_MAP[mmio::operand_gpreg(reg, insn.size)]);
+                mmio::operand_value(reg, insn.size, regval)
+            },
+            mmio::MmioOperand::Imm(imm) => imm as u64,
+        };
+
+        if !vm::handle_mmio_write(gpa, insn.size, data) {
+            debug!("Unclaimed MMIO write {:x} to {:x}", data, gpa);
+        }
+    } else {
+        let data = vm::handle_mmio_read(gpa, insn.size).unwrap_or_else(|| {
+            debug!("Unclaimed MMIO read from {:x}", gpa);
+            !0
+        });
+
+        if let mmio::MmioOperand::Reg(reg) = insn.operand {
+            let gpreg = GPREG_MAP[mmio::operand_gpreg(reg, insn.size)];
+            let regval = read_guest_reg(vcpu, gpreg);
+            write_guest_reg(vcpu, gpreg, mmio::load_operand(reg, insn.size, regval, data));
+        }
+    }
+
+    wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_RIP, rvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_RIP) + insn.len as u64);
+}
+
+/* Set EDX:EAX to a 64 bit result, upper halves of RAX and RDX are cleared */
+fn write_edx_eax(vcpu: hv_vcpuid_t, val: u64)
+{
+    write_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RAX, val & 0xFFFFFFFF);
+    write_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RDX, val >> 32);
+}
+
+/*
+ * Complete RDTSC or RDTSCP trapped in exiting TSC mode, RDTSCP also returns IA32_TSC_AUX in ECX
+ */
+fn handle_rdtsc(vcpu: hv_vcpuid_t, rdtscp: bool)
+{
+    write_edx_eax(vcpu, tsc::read_tsc());
+    if rdtscp {
+        write_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RCX, tsc::read_tsc_aux() as u64);
+    }
+    next_instruction(vcpu);
+}
+
+/*
+ * Complete MOV to CR0 by keeping its shadow in sync, only CR0 writes are expected to exit
+ */
+fn handle_mov_cr(vcpu: hv_vcpuid_t, exit_qualif: u64)
+{
+    let crreg = exit_qualif & 0xF;
+    let optype = (exit_qualif >> 4) & 0x3;
+    let gpreg = ((exit_qualif >> 8) & 0xF) as usize;
+
+    if optype != 0 {
+        crash::fatal(format!("Only MOV to CR is supported (got access type {})", optype));
+        return;
+    }
+
+    if crreg != 0 {
+        crash::fatal(format!("MOV to CR{} is not supported", crreg));
+        return;
+    }
+
+    if gpreg >= 8 {
+        crash::fatal(format!("MOV to CR0 from register index {}", gpreg));
+        return;
+    }
+
+    let new_val = read_guest_reg(vcpu, GPREG_MAP[gpreg]);
+    let cur_val = read_guest_reg(vcpu, hv_x86_reg_t::HV_X86_CR0);
+
+    debug!("VMX_REASON_MOV_CR: current value {:x}, new value {:x}", cur_val, new_val);
+
+    // CR0.PE?
+    if is_bit_changed(cur_val, new_val, 0) {
+        debug!("VMX_REASON_MOV_CR: PE {}", new_val & 0x1);
+    }
+
+    // We just keep shadow value in sync
+    wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_CTRL_CR0_SHADOW, new_val);
+    wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_CR0, new_val);
+    next_instruction(vcpu);
+}
+
+/*
+ * Complete MSR access, only TSC MSRs are modelled. Others read as 0 and ignore writes.
+ */
+fn handle_msr(vcpu: hv_vcpuid_t, is_write: bool)
+{
+    let msr = read_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RCX) as u32;
+
+    if is_write {
+        let val = (read_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RDX) << 32) |
+                  (read_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RAX) & 0xFFFFFFFF);
+        if !tsc::write_msr(msr, val) {
+            warn!("Ignoring write {:x} to MSR {:x}", val, msr);
+        }
+    } else {
+        let val = tsc::read_msr(msr).unwrap_or_else(|| {
+            warn!("Unhandled read from MSR {:x}", msr);
+            0
+        });
+        write_edx_eax(vcpu, val);
+    }
+
+    next_instruction(vcpu);
+}
+
 fn is_bit_changed<T: PrimInt>(old_val: T, new_val: T, bit: usize) -> bool {
     ((old_val ^ new_val) & (T::one() << bit)) != T::zero()
 }
@@ -286,78 +486,554 @@ fn wait_any_key()
 
 }
 
-fn is_interruptible(vcpu: hv_vcpuid_t) -> bool
+/*
+ * Perform assisted BIOS call if guest halted in a trap stub at linear address ip.
+ * Stub returns with IRET, so flags for the caller are in the interrupt frame on guest stack.
+ */
+fn handle_bios_call(vcpu: hv_vcpuid_t, ip: u64) -> biosassist::Trap
 {
-    let flags = read_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RFLAGS);
-    let intstate = rvmcs32(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_IGNORE_IRQ);
+    let gpregs = [
+        hv_x86_reg_t::HV_X86_RAX,
+        hv_x86_reg_t::HV_X86_RBX,
+        hv_x86_reg_t::HV_X86_RCX,
+        hv_x86_reg_t::HV_X86_RDX,
+        hv_x86_reg_t::HV_X86_RSI,
+        hv_x86_reg_t::HV_X86_RDI,
+    ];
+    let mut vals: Vec<ia32_reg_t> = gpregs.iter().map(|reg| ia32_reg_t { val: read_guest_reg(vcpu, *reg) as u32 }).collect();
+
+    let frame_flags = rvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_SS_BASE) +
+                      (read_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RSP) & 0xFFFF) + 4;
+    let mut flags = [0u8; 2];
+    vm::read_guest_memory(frame_flags, &mut flags);
+
+    let es = rvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_ES) as u16;
+    let mut regs = biosassist::CallRegs {
+        ax: vals[0].as_u16(),
+        bx: vals[1].as_u16(),
+        cx: vals[2].as_u16(),
+        dx: vals[3].as_u16(),
+        si: vals[4].as_u16(),
+        di: vals[5].as_u16(),
+        ds: rvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_DS) as u16,
+        es: es,
+        flags: flags[0] as u16 | (flags[1] as u16) << 8,
+    };
+
+    let trap = biosassist::handle_trap(ip, &mut regs);
+    if trap != biosassist::Trap::Done {
+        return trap;
+    }
+
+    let results = [regs.ax, regs.bx, regs.cx, regs.dx, regs.si, regs.di];
+    for (i, reg) in gpregs.iter().enumerate() {
+        vals[i].set_u16(results[i]);
+        write_guest_reg(vcpu, *reg, vals[i].val as u64);
+    }
+
+    if regs.es != es {
+        wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_ES, regs.es as u64);
+        wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_ES_BASE, (regs.es as u64) << 4);
+    }
+
+    vm::write_guest_memory(frame_flags, &[regs.flags as u8, (regs.flags >> 8) as u8]);
+    trap
+}
 
-    return (intstate == 0) && (flags & (1 << 9)) != 0;
+/* Set or clear window exiting control */
+fn set_window_exiting(vcpu: hv_vcpuid_t, ctrl: u32, enable: bool)
+{
+    let ctrls = rvmcs32(vcpu, hv_vmx_vmcs_regs::VMCS_CTRL_CPU_BASED);
+    let ctrls = if enable { ctrls | ctrl } else { ctrls & !ctrl };
+    wvmcs32(vcpu, hv_vmx_vmcs_regs::VMCS_CTRL_CPU_BASED,
+            check_capability(hv_vmx_capability_t::HV_VMX_CAP_PROCBASED, ctrls));
 }
 
 fn request_interrupt_window(vcpu: hv_vcpuid_t)
 {
-    let ctrls = check_capability(hv_vmx_capability_t::HV_VMX_CAP_PROCBASED,
-                                 rvmcs32(vcpu, hv_vmx_vmcs_regs::VMCS_CTRL_CPU_BASED) | CPU_BASED_IRQ_WND);
-    wvmcs32(vcpu, hv_vmx_vmcs_regs::VMCS_CTRL_CPU_BASED, ctrls);
+    set_window_exiting(vcpu, CPU_BASED_IRQ_WND, true);
 }
 
 fn complete_interrupt_window(vcpu: hv_vcpuid_t)
 {
-    let ctrls = check_capability(hv_vmx_capability_t::HV_VMX_CAP_PROCBASED,
-                                 rvmcs32(vcpu, hv_vmx_vmcs_regs::VMCS_CTRL_CPU_BASED) & !CPU_BASED_IRQ_WND);
-    wvmcs32(vcpu, hv_vmx_vmcs_regs::VMCS_CTRL_CPU_BASED, ctrls);
+    set_window_exiting(vcpu, CPU_BASED_IRQ_WND, false);
 }
 
-fn init(vcpu: hv_vcpuid_t, bootimg: &String, has_bios: bool)
+fn request_nmi_window(vcpu: hv_vcpuid_t)
 {
-    let kernel_base = 0x8000_u64;
-    let img = load_image(bootimg);
+    set_window_exiting(vcpu, CPU_BASED_VIRTUAL_NMI_WND, true);
+}
+
+fn complete_nmi_window(vcpu: hv_vcpuid_t)
+{
+    set_window_exiting(vcpu, CPU_BASED_VIRTUAL_NMI_WND, false);
+}
+
+/*
+ * Inject the pending event guest can take now and ask for window exits for the rest
+ * Returns false when the external interrupt to inject stopped at a vector breakpoint instead, nothing is
+ * injected then and VM loop has to handle the stop and try again.
+ */
+fn inject_pending_event(vcpu: hv_vcpuid_t) -> bool
+{
+    /* Single step runs with interrupts held pending, so does replay until guest gets where it took the next one,
+     * replay steps guest of its own and lets them in there */
+    let mut pending = vm::pending_events();
+    if (vm::step_in_progress() && !replay::enabled()) || !replay::interrupts_allowed() {
+        pending.nmi = false;
+        pending.external = false;
+    }
+
+    let res = inject::resolve_next_event(&pending, &vm::guest_event_state());
 
-    if has_bios {
-        let ram = make_ram_region(0x0, 0xA0000);
+    /* IRQ assertion external interrupt delivers, for the event log */
+    let mut irq_id = None;
+    let event = match res.inject {
+        Some(inject::EventKind::Exception) => vm::take_exception_request().map(inject::Injection::Exception),
+        Some(inject::EventKind::Nmi) => {
+            vm::take_nmi_request();
+            Some(inject::Injection::Nmi)
+        },
+        Some(inject::EventKind::External) => {
+            if vm::hit_vector_breakpoint() {
+                return false;
+            }
+            vm::next_external_interrupt().map(|(vector, id)| {
+                irq_id = id;
+                hang::interrupt_delivered();
+                inject::Injection::External(vector)
+            })
+        },
+        None => None,
+    };
 
-        assert!((img.len() & 0xFFFF) == 0); // BIOS image should be aligned to real mode segment size
-        let rom = vm::alloc_memory_region(img.len());
-        if rom.write_bytes(0, &img[..]) != img.len() {
-            panic!();
+    if let Some(event) = event {
+        eventlog::emit(|| match event {
+            inject::Injection::Exception(exc) => eventlog::Event::Inject { kind: "exception", vector: exc.vector, id: None },
+            inject::Injection::Nmi => eventlog::Event::Inject { kind: "nmi", vector: 2, id: None },
+            inject::Injection::External(vector) => eventlog::Event::Inject { kind: "external", vector: vector, id: irq_id },
+        });
+
+        /* Replay stops at an injection guest didn't get in the recording */
+        let ip = vm::current_ip();
+        let input = match event {
+            inject::Injection::Exception(exc) => record::Input::Inject { kind: "exception", vector: exc.vector, ip: ip },
+            inject::Injection::Nmi => record::Input::Inject { kind: "nmi", vector: 2, ip: ip },
+            inject::Injection::External(vector) => record::Input::Inject { kind: "external", vector: vector, ip: ip },
+        };
+        record::emit(|| input.clone());
+        if !replay::check(&input) {
+            return false;
         }
 
-        // First rom mapping goes to upper memory
-        vm::map_memory_region(0x100000000u64 - rom.size as u64, HV_MEMORY_READ | HV_MEMORY_EXEC, rom.clone());
+        let (info, error_code) = event.interruption_info();
+        if let Some(error_code) = error_code {
+            wvmcs32(vcpu, hv_vmx_vmcs_regs::VMCS_CTRL_VMENTRY_EXC_ERROR, error_code);
+        }
+        wvmcs32(vcpu, hv_vmx_vmcs_regs::VMCS_CTRL_VMENTRY_IRQ_INFO, info);
+    }
 
-        // Second rom mapping goes right below first megabyte
-        vm::map_memory_region(0x100000u64 - rom.size as u64, HV_MEMORY_READ | HV_MEMORY_WRITE | HV_MEMORY_EXEC, rom.clone());
-    } else {
-        let ram = make_ram_region(0x0, 0x100000);
-        if ram.write_bytes(kernel_base as usize, &img[..]) != img.len() {
-            panic!();
+    if res.interrupt_window {
+        request_interrupt_window(vcpu);
+    }
+
+    if res.nmi_window {
+        request_nmi_window(vcpu);
+    }
+    true
+}
+
+/* Vcpu as GDB sees it */
+struct GdbVcpu(hv_vcpuid_t);
+
+impl gdbstub::gdb_target for GdbVcpu
+{
+    /* GDB i386 register order */
+    fn read_registers(&self) -> gdbstub::GdbRegisters {
+        let s = vm::vcpu_state();
+        let regs = [s.rax, s.rcx, s.rdx, s.rbx, s.rsp, s.rbp, s.rsi, s.rdi, s.rip, s.rflags,
+                    s.cs.selector as u64, s.ss.selector as u64, s.ds.selector as u64,
+                    s.es.selector as u64, s.fs.selector as u64, s.gs.selector as u64];
+
+        let mut res = [0u32; gdbstub::GDB_NUM_REGS];
+        for (i, reg) in regs.iter().enumerate() {
+            res[i] = *reg as u32;
         }
+        res
     }
 
-    wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_CS, 0);
-    wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_CS_LIMIT, 0xffff);
-    wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_CS_AR, 0x9b);
+    fn write_registers(&mut self, regs: &gdbstub::GdbRegisters) {
+        let mut update = vm::VcpuStateUpdate {
+            rax: Some(regs[0] as u64),
+            rcx: Some(regs[1] as u64),
+            rdx: Some(regs[2] as u64),
+            rbx: Some(regs[3] as u64),
+            rsp: Some(regs[4] as u64),
+            rbp: Some(regs[5] as u64),
+            rsi: Some(regs[6] as u64),
+            rdi: Some(regs[7] as u64),
+            rip: Some(regs[8] as u64),
+            rflags: Some(regs[9] as u64),
+            ..Default::default()
+        };
+
+        /* Protected mode selectors would need their descriptors loaded, so only real mode ones change */
+        if is_in_real_mode(self.0) {
+            let s = vm::vcpu_state();
+            let real_mode = |seg: vm::SegmentState, val: u32| {
+                Some(vm::SegmentState { selector: val as u16, base: ((val & 0xFFFF) as u64) << 4, ..seg })
+            };
+            update.cs = real_mode(s.cs, regs[10]);
+            update.ss = real_mode(s.ss, regs[11]);
+            update.ds = real_mode(s.ds, regs[12]);
+            update.es = real_mode(s.es, regs[13]);
+            update.fs = real_mode(s.fs, regs[14]);
+            update.gs = real_mode(s.gs, regs[15]);
+        }
+
+        vm::set_vcpu_state(&update);
+    }
 
-    if has_bios {
-        // Firmware entry at real mode CS:0 with CS.base = 0xFFFFFFF0
-        wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_CS_BASE, 0xfffffff0);
-        write_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RIP, 0x0);
+    fn cs_base(&self) -> u64 {
+        rvmcs(self.0, hv_vmx_vmcs_regs::VMCS_GUEST_CS_BASE)
+    }
 
-    } else {
-        // Kernel entry point at real mode 0h:8000h
-        wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_CS_BASE, 0);
-        write_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RIP, kernel_base);
+    fn read_memory(&self, addr: u64, buf: &mut [u8]) -> usize {
+        vm::read_guest_memory(addr, buf)
+    }
+
+    fn write_memory(&mut self, addr: u64, data: &[u8]) -> usize {
+        vm::write_guest_memory(addr, data)
+    }
+}
+
+/* Vcpu and devices as monitor commands see them */
+struct VcpuMonitor(hv_vcpuid_t);
+
+impl monitor::monitor_target for VcpuMonitor
+{
+    fn registers(&mut self) -> Vec<(&'static str, u64)> {
+        let s = vm::vcpu_state();
+        vec![("EAX", s.rax), ("EBX", s.rbx), ("ECX", s.rcx), ("EDX", s.rdx),
+             ("ESI", s.rsi), ("EDI", s.rdi), ("EBP", s.rbp), ("ESP", s.rsp),
+             ("EIP", s.rip), ("EFL", s.rflags),
+             ("CS", s.cs.selector as u64), ("SS", s.ss.selector as u64), ("DS", s.ds.selector as u64),
+             ("ES", s.es.selector as u64), ("FS", s.fs.selector as u64), ("GS", s.gs.selector as u64),
+             ("CR0", s.cr0), ("CR2", read_guest_reg(self.0, hv_x86_reg_t::HV_X86_CR2)),
+             ("CR3", s.cr3), ("CR4", s.cr4)]
+    }
+
+    fn pic_state(&mut self) -> Option<[pic::I8259State; 2]> {
+        pic::state()
+    }
+
+    fn io_regions(&mut self) -> Vec<(u16, u8)> {
+        vm::io_regions()
+    }
+
+    fn irq_counts(&mut self) -> Vec<u64> {
+        vm::irq_counts().to_vec()
+    }
+
+    fn read_memory(&mut self, addr: u64, buf: &mut [u8]) -> usize {
+        vm::read_guest_memory(addr, buf)
+    }
+
+    fn breakpoints(&mut self) -> Vec<breakpoint::Breakpoint> {
+        vm::breakpoints()
+    }
+
+    fn add_breakpoint(&mut self, addr: u64, kind: breakpoint::BreakpointKind) -> Result<breakpoint::BreakpointHandle, String> {
+        match kind {
+            breakpoint::BreakpointKind::Int3 => vm::add_breakpoint(addr),
+            breakpoint::BreakpointKind::ExecProtect => vm::add_exec_breakpoint(addr),
+        }
+    }
+
+    fn remove_breakpoint(&mut self, handle: breakpoint::BreakpointHandle) -> bool {
+        vm::remove_breakpoint(handle)
+    }
+
+    fn io_breakpoints(&mut self) -> Vec<breakpoint::IoBreakpoint> {
+        vm::io_breakpoints()
+    }
+
+    fn add_io_breakpoint(&mut self, ports: Range<u16>, direction: breakpoint::IoDirection) -> Result<breakpoint::BreakpointHandle, String> {
+        vm::add_io_breakpoint(ports, direction)
+    }
+
+    fn vector_breakpoints(&mut self) -> Vec<breakpoint::VectorBreakpoint> {
+        vm::vector_breakpoints()
+    }
+
+    fn add_vector_breakpoint(&mut self, vec: u8) -> Result<breakpoint::BreakpointHandle, String> {
+        vm::break_on_vector(vec)
+    }
+
+    fn symbols(&mut self) -> &symbols::SymbolTable {
+        vm::symbols()
+    }
+
+    fn inject_irq(&mut self, irq: u8) -> Result<inject::InjectOutcome, String> {
+        vm::inject_irq(irq)
+    }
+
+    fn inject_vector(&mut self, vec: u8) -> inject::InjectOutcome {
+        vm::inject_vector(vec)
+    }
+
+    fn inject_nmi(&mut self) -> inject::InjectOutcome {
+        vm::inject_nmi()
+    }
+
+    fn patch_memory(&mut self, addr: u64, data: &[u8], force: bool) -> Result<Vec<u8>, String> {
+        vm::patch_guest_memory(addr, data, force)
+    }
+
+    fn set_register(&mut self, name: &str, val: u64) -> Result<u64, String> {
+        vm::set_register(name, val)
+    }
+}
+
+/*
+ * Terminate VMM with exit status, event log gets it as VM stop, run summary and metrics are written if asked for
+ */
+fn exit_vm(status: i32) -> !
+{
+    reverse::finish();
+    let status = replay::finish(status);
+    eventlog::emit(|| eventlog::Event::VmStop { status: status });
+    summary::report();
+    metrics::report();
+    record::finish(status);
+    std::process::exit(status);
+}
+
+/*
+ * Guest terminated VM through debug exit port or panicked, watchdog, hang detector or monitor stopped it, it
+ * hit a breakpoint or something fatal happened. Panics and hangs are saved like crash reports. Monitor or
+ * debugger resume from breakpoints, monitor may quit VM from there. Port access stopped at I/O breakpoint is
+ * performed on resume, vector stopped at vector breakpoint is injected or, from monitor, taken back.
+ */
+fn handle_exit_requests(vcpu: hv_vcpuid_t, config: &config::VmConfig)
+{
+    while let Some(exit) = vm::take_exit_request() {
+        match exit {
+            /* Reverse execution looks for breakpoint hits itself and runs past stops on its way */
+            vm::VmExit::Breakpoint { vcpu_state, .. } if reverse::seeking() => reverse::breakpoint_hit(vcpu_state),
+            vm::VmExit::IoBreakpoint { .. } if reverse::seeking() => vm::resume_io_breakpoint(None),
+            vm::VmExit::VectorBreakpoint { .. } if reverse::seeking() => vm::resume_vector_breakpoint(true),
+            vm::VmExit::Guest(code) => {
+                debug!("Guest exit with status {}", code);
+                exit_vm(code);
+            },
+            vm::VmExit::MonitorQuit => {
+                debug!("Quit from monitor");
+                exit_vm(vm::VmExit::MonitorQuit.status());
+            },
+            vm::VmExit::Breakpoint { gpa, .. } if monitor::enabled() => {
+                monitor::breakpoint_hit(&mut VcpuMonitor(vcpu), gpa);
+            },
+            vm::VmExit::Breakpoint { .. } if gdbstub::enabled() => {
+                gdb_resume(gdbstub::stop(&mut GdbVcpu(vcpu), gdbstub::GdbStop::Breakpoint));
+            },
+            vm::VmExit::Breakpoint { gpa, vcpu_state } => {
+                error!("VM stopped at breakpoint {:x}, guest state:\n{}", gpa, vcpu_state);
+                exit_vm(exit.status());
+            },
+            vm::VmExit::IoBreakpoint { port, direction, value, .. } if monitor::enabled() => {
+                let substitute = monitor::io_breakpoint_hit(&mut VcpuMonitor(vcpu), port, direction, value);
+                vm::resume_io_breakpoint(substitute);
+            },
+            vm::VmExit::IoBreakpoint { .. } if gdbstub::enabled() => {
+                let resume = gdbstub::stop(&mut GdbVcpu(vcpu), gdbstub::GdbStop::Trap);
+                vm::resume_io_breakpoint(None);
+                gdb_resume(resume);
+            },
+            vm::VmExit::IoBreakpoint { port, direction, value, vcpu_state } => {
+                error!("VM stopped at I/O breakpoint, {:?} port {:x} value {:x}, guest state:\n{}", direction, port, value, vcpu_state);
+                exit_vm(exit.status());
+            },
+            vm::VmExit::VectorBreakpoint { vec, .. } if monitor::enabled() => {
+                let deliver = monitor::vector_breakpoint_hit(&mut VcpuMonitor(vcpu), vec);
+                vm::resume_vector_breakpoint(deliver);
+            },
+            vm::VmExit::VectorBreakpoint { vec, .. } if gdbstub::enabled() => {
+                let resume = gdbstub::stop(&mut GdbVcpu(vcpu), gdbstub::GdbStop::Vector(vec));
+                vm::resume_vector_breakpoint(true);
+                gdb_resume(resume);
+            },
+            vm::VmExit::VectorBreakpoint { vec, vcpu_state } => {
+                error!("VM stopped at vector breakpoint {:x}, guest state:\n{}", vec, vcpu_state);
+                exit_vm(exit.status());
+            },
+            vm::VmExit::ReplayDiverged(ref report) => {
+                error!("{}", report);
+                exit_vm(exit.status());
+            },
+            vm::VmExit::Fatal(ref report) | vm::VmExit::GuestPanic { ref report, .. } | vm::VmExit::HangDetected(ref report) => {
+                error!("{}", report);
+                match config.crash_dir {
+                    Some(ref dir) => match crash::save(report, dir) {
+                        Ok(path) => error!("Crash report saved to {}", path.display()),
+                        Err(err) => error!("Can't save crash report to {}: {}", dir, err),
+                    },
+                    None => {},
+                }
+                coredump::fatal(report);
+                exit_vm(exit.status());
+            },
+            _ => {
+                error!("VM stopped: {:?}", exit);
+                exit_vm(exit.status());
+            },
+        }
+    }
+}
+
+/*
+ * Run guest the way debugger asked
+ */
+fn gdb_resume(resume: gdbstub::GdbResume)
+{
+    match resume {
+        gdbstub::GdbResume::Kill => {
+            error!("VM stopped: {:?}", vm::VmExit::DebuggerKill);
+            exit_vm(vm::VmExit::DebuggerKill.status());
+        },
+        gdbstub::GdbResume::Step => vm::start_step(vm::StepOwner::Debugger),
+        gdbstub::GdbResume::Continue => {},
+        gdbstub::GdbResume::ReverseStep => reverse::request(reverse::Direction::Step, reverse::Requester::Debugger),
+        gdbstub::GdbResume::ReverseContinue => reverse::request(reverse::Direction::Continue, reverse::Requester::Debugger),
+    }
+}
+
+/* Run reverse execution and tell whoever asked where guest stopped going back */
+fn serve_reverse(vcpu: hv_vcpuid_t)
+{
+    while let Some(arrival) = reverse::poll() {
+        gdbstub::patch_breakpoints(&mut GdbVcpu(vcpu));
+        let ip = vm::current_ip();
+        match arrival.by {
+            reverse::Requester::Debugger => {
+                let stop = match arrival.stop {
+                    reverse::Stop::Step => gdbstub::GdbStop::Trap,
+                    reverse::Stop::Breakpoint => gdbstub::GdbStop::Breakpoint,
+                    reverse::Stop::Begin => gdbstub::GdbStop::ReplayBegin,
+                    reverse::Stop::Failed(ref err) => {
+                        error!("Can't go back: {}", err);
+                        gdbstub::GdbStop::Trap
+                    },
+                };
+                gdb_resume(gdbstub::stop(&mut GdbVcpu(vcpu), stop));
+            },
+            reverse::Requester::Monitor => {
+                let reason = match arrival.stop {
+                    reverse::Stop::Step => format!("0x{:x}, stepped back", ip),
+                    reverse::Stop::Breakpoint => format!("breakpoint at 0x{:x}, continued back", ip),
+                    reverse::Stop::Begin => format!("0x{:x}, start of recording", ip),
+                    reverse::Stop::Failed(ref err) => format!("0x{:x}, can't go back: {}", ip, err),
+                };
+                monitor::stopped(&mut VcpuMonitor(vcpu), reason);
+            },
+        }
     }
+}
+
+/*
+ * Set up guest memory with firmware or test image
+ * Returns test image entry state, None when booting firmware.
+ */
+fn init(vcpu: hv_vcpuid_t, config: &config::VmConfig) -> Option<loader::EntryState>
+{
+    let entry = match config.image {
+        None if config.has_bios() => {
+            let img = load_image("bios/bios.bin");
+            let ram = make_ram_region(0x0, 0xA0000);
+
+            assert!((img.len() & 0xFFFF) == 0); // BIOS image should be aligned to real mode segment size
+            let rom = vm::alloc_memory_region(img.len());
+            if rom.write_bytes(0, &img[..]) != img.len() {
+                panic!();
+            }
+
+            // First rom mapping goes to upper memory
+            vm::map_memory_region(0x100000000u64 - rom.size as u64, HV_MEMORY_READ | HV_MEMORY_EXEC, rom.clone());
+
+            // Second rom mapping goes right below first megabyte
+            vm::map_memory_region(0x100000u64 - rom.size as u64, HV_MEMORY_READ | HV_MEMORY_WRITE | HV_MEMORY_EXEC, rom.clone());
+            None
+        },
+        _ => {
+            let ram = make_ram_region(0x0, 0x100000);
+            let (name, res) = match config.load {
+                config::LoadConfig::Cdrom { drive } => {
+                    let iso = config.cdrom.as_ref().unwrap();
+                    let res = match disk::FileImage::open_read_only(iso) {
+                        Ok(image) => loader::load_cd(&ram, Box::new(image), drive),
+                        Err(err) => Err(err.to_string()),
+                    };
+                    (iso, res)
+                },
+                _ => {
+                    let image = config.image.as_ref().unwrap();
+                    (image, loader::load(&ram, &load_image(image), &config.load))
+                },
+            };
+
+            match res {
+                Ok(entry) => {
+                    bda::init(&ram, config);
+                    Some(entry)
+                },
+                Err(err) => {
+                    error!("Failed to load {}: {}", name, err);
+                    std::process::exit(1);
+                }
+            }
+        },
+    };
+
+    reset_cpu(vcpu, entry.as_ref());
+    entry
+}
+
+/*
+ * Put vcpu in its power-on state
+ * Without firmware we jump straight to test image entry point, image is not reloaded.
+ */
+fn reset_cpu(vcpu: hv_vcpuid_t, entry: Option<&loader::EntryState>)
+{
+    wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_CS_LIMIT, 0xffff);
+    wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_CS_AR, 0x9b);
+
+    let (ds, ss, sp) = match entry {
+        None => {
+            // Firmware entry at real mode CS:0 with CS.base = 0xFFFFFFF0
+            wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_CS, 0);
+            wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_CS_BASE, 0xfffffff0);
+            write_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RIP, 0x0);
+            (0, 0, 0)
+        },
+        Some(entry) => {
+            // Test image entry point, boot drive in DL
+            wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_CS, entry.cs as u64);
+            wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_CS_BASE, (entry.cs as u64) << 4);
+            write_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RIP, entry.ip as u64);
+            write_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RDX, entry.dl as u64);
+            (entry.ds as u64, entry.ss as u64, entry.sp as u64)
+        },
+    };
 
-    wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_DS, 0);
+    wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_DS, ds);
     wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_DS_LIMIT, 0xffff);
     wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_DS_AR, 0x93);
-    wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_DS_BASE, 0);
+    wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_DS_BASE, ds << 4);
 
-    wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_ES, 0);
+    wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_ES, ds);
     wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_ES_LIMIT, 0xffff);
     wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_ES_AR, 0x93);
-    wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_ES_BASE, 0);
+    wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_ES_BASE, ds << 4);
 
     wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_FS, 0);
     wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_FS_LIMIT, 0xffff);
@@ -369,10 +1045,10 @@ fn init(vcpu: hv_vcpuid_t, bootimg: &String, has_bios: bool)
     wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_GS_AR, 0x93);
     wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_GS_BASE, 0);
 
-    wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_SS, 0);
+    wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_SS, ss);
     wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_SS_LIMIT, 0xffff);
     wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_SS_AR, 0x93);
-    wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_SS_BASE, 0);
+    wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_SS_BASE, ss << 4);
 
     wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_LDTR, 0);
     wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_LDTR_LIMIT, 0);
@@ -387,7 +1063,8 @@ fn init(vcpu: hv_vcpuid_t, bootimg: &String, has_bios: bool)
     wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_GDTR_LIMIT, 0);
     wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_GDTR_BASE, 0);
 
-    wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_IDTR_LIMIT, 0);
+    // Real mode IVT at 0, reset value of limit covers all of it
+    wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_IDTR_LIMIT, 0xffff);
     wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_IDTR_BASE, 0);
 
     wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_CR0, 0x20);
@@ -401,7 +1078,10 @@ fn init(vcpu: hv_vcpuid_t, bootimg: &String, has_bios: bool)
     wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_CTRL_CR4_SHADOW, 0);
 
     write_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RFLAGS, 0x2 /*| (1u64 << 8)*/);
-    write_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RSP, 0x0);
+    write_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RSP, sp);
+
+    // Drop any event we were about to inject before reset
+    wvmcs32(vcpu, hv_vmx_vmcs_regs::VMCS_CTRL_VMENTRY_IRQ_INFO, 0);
 
     if cfg!(feature = "guest-tracing") {
         write_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RFLAGS,
@@ -425,6 +1105,8 @@ fn main()
     pic::init();
     pit::init();
     pci::init();
+    dma::init();
+    i8042::init();
 
     // Dump capabilities for debugging
     debug!("HV_VMX_CAP_PINBASED:      {:x}", read_capability(hv_vmx_capability_t::HV_VMX_CAP_PINBASED));
@@ -436,17 +1118,19 @@ fn main()
     // Init vcpu
     wvmcs32(vcpu, hv_vmx_vmcs_regs::VMCS_CTRL_PIN_BASED, check_capability(hv_vmx_capability_t::HV_VMX_CAP_PINBASED, 0
         /*| PIN_BASED_INTR*/
-        /*| PIN_BASED_NMI*/
-        /*| PIN_BASED_VIRTUAL_NMI*/));
+        | PIN_BASED_NMI
+        | PIN_BASED_VIRTUAL_NMI));
 
     wvmcs32(vcpu, hv_vmx_vmcs_regs::VMCS_CTRL_CPU_BASED, check_capability(hv_vmx_capability_t::HV_VMX_CAP_PROCBASED, (0
         | CPU_BASED_HLT
+        | CPU_BASED_TSC_OFFSET
         | CPU_BASED_CR8_LOAD
         | CPU_BASED_CR8_STORE
         | CPU_BASED_SECONDARY_CTLS) as u32));
 
     wvmcs32(vcpu, hv_vmx_vmcs_regs::VMCS_CTRL_CPU_BASED2, check_capability(hv_vmx_capability_t::HV_VMX_CAP_PROCBASED2, 0
         /*| CPU_BASED2_EPT*/
+        | CPU_BASED2_RDTSCP
         | CPU_BASED2_UNRESTRICTED as u32));
 
     wvmcs32(vcpu, hv_vmx_vmcs_regs::VMCS_CTRL_VMENTRY_CONTROLS, check_capability(hv_vmx_capability_t::HV_VMX_CAP_ENTRY, 0));
@@ -460,19 +1144,174 @@ fn main()
     }
 
     let args: Vec<String> = env::args().collect();
-    if args.len() > 1 {
-        debug!("Running test image {}", args[1]);
-        init(vcpu, &args[1], false);
+    let config = match config::parse(&args[1..]) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // Guest time rate has to be set before devices start counting it
+    clock::init();
+    clock::set_time_dilation(config.time_dilation).unwrap();
+    clock::set_jump_policy(config.clock_jump_threshold_ms, config.clock_jump != config::ClockJumpPolicy::Follow);
+    cmos::set_rtc_resync(config.clock_jump == config::ClockJumpPolicy::ResyncRtc);
+
+    match config.image {
+        Some(ref image) => debug!("Running test image {}", image),
+        None if !config.has_bios() => debug!("Booting CD image {}", config.cdrom.as_ref().unwrap()),
+        None => debug!("Running firmware"),
+    }
+    let entry = init(vcpu, &config);
+
+    // Display and firmware tables need guest RAM layout to be set up
+    vga::init(&config);
+    smbios::init(&config);
+    biosassist::init(&config);
+
+    // Storage devices
+    fdc::init(&config);
+    ata::init(&config);
+
+    flash::init(&config);
+
+    lpt::init(&config);
+    ne2000::init(&config);
+    pmtimer::init(&config);
+    uart::init(&config);
+    pvcon::init(&config);
+    watchdog::init(&config);
+    beacon::init(&config);
+    apic::init(&config);
+    ioapic::init(&config);
+    pit::set_tick_policy(config.pit_policy);
+
+    // Guest TSC starts at zero, in exiting mode every read comes from virtual time
+    tsc::init(&config);
+    if config.tsc_mode == config::TscMode::Exiting {
+        let ctrls = check_capability(hv_vmx_capability_t::HV_VMX_CAP_PROCBASED,
+                                     rvmcs32(vcpu, hv_vmx_vmcs_regs::VMCS_CTRL_CPU_BASED) | CPU_BASED_RDTSC);
+        wvmcs32(vcpu, hv_vmx_vmcs_regs::VMCS_CTRL_CPU_BASED, ctrls);
+    }
+
+    // Timer events either run on event loop thread or on this one at preemption timer exits, replay fires them
+    // at recorded exits
+    let preemption_timer = config.timer_mode == config::TimerMode::Preemption && config.replay.is_none();
+    if config.replay.is_some() {
+        debug!("Timer events fire as replayed");
+    } else if preemption_timer {
+        let ctrls = check_capability(hv_vmx_capability_t::HV_VMX_CAP_PINBASED,
+                                     rvmcs32(vcpu, hv_vmx_vmcs_regs::VMCS_CTRL_PIN_BASED) | PIN_BASED_PREEMPTION_TIMER);
+        if ctrls & PIN_BASED_PREEMPTION_TIMER == 0 {
+            error!("VMX preemption timer is not supported, use --timer host");
+            std::process::exit(1);
+        }
+        wvmcs32(vcpu, hv_vmx_vmcs_regs::VMCS_CTRL_PIN_BASED, ctrls);
+
+        let freq = read_capability(hv_vmx_capability_t::HV_VMX_CAP_PREEMPTION_TIMER);
+        debug!("HV_VMX_CAP_PREEMPTION_TIMER: {} Hz", freq);
+        event::start_preemption_timer(freq);
     } else {
-        debug!("Running firmware");
-        init(vcpu, &String::from("bios/bios.bin"), true);
+        event::start_event_loop();
+    }
+
+    // Steps exit on monitor trap flag where vcpu has it, debugger gets INT3 exits
+    vm::set_step_with_mtf(check_capability(hv_vmx_capability_t::HV_VMX_CAP_PROCBASED, CPU_BASED_MTF) & CPU_BASED_MTF != 0);
+    gdbstub::init(&config, true, config.reverse.is_some() && vm::step_with_mtf());
+    if gdbstub::enabled() {
+        wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_CTRL_EXC_BITMAP, rvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_CTRL_EXC_BITMAP) | (1 << 3));
+    }
+
+    monitor::init(&config);
+    trace::init(&config);
+    eventlog::init(&config);
+    tracefilter::init(&config);
+    logctl::init();
+    summary::init(&config);
+    metrics::init(&config);
+    coredump::init(&config);
+    snapshot::init();
+    livesnap::init();
+    snapdiff::init();
+    devstate::init();
+    hang::init(&config);
+
+    // Snapshot goes over guest memory before breakpoints patch it
+    if !config.restore.is_empty() {
+        let paths: Vec<&Path> = config.restore.iter().map(|path| Path::new(path)).collect();
+        if let Err(err) = vm::restore_snapshot(&paths) {
+            error!("Can't restore snapshot: {}", err);
+            std::process::exit(1);
+        }
     }
 
-    // Start event loop thread
-    event::start_event_loop();
+    // RAM images go over what image, firmware or snapshot put in memory, and recording starts from them
+    for &(ref path, base) in &config.ram_images {
+        if let Err(err) = vm::import_ram(Path::new(path), base) {
+            error!("Can't load RAM image {}: {}", path, err);
+            std::process::exit(1);
+        }
+    }
+
+    // Automatic snapshots count time from where guest starts
+    autosnap::init(&config);
+
+    // Recording starts from a snapshot of VM as restored, before breakpoints patch memory, replay from that one
+    record::init(&config);
+    reverse::init(&config);
+    if let Some(ref path) = config.replay {
+        let res = replay::load(path).and_then(|recording| {
+            let snapshot = PathBuf::from(recording.snapshot());
+            vm::replay(&snapshot, recording)
+        });
+        if let Err(err) = res {
+            error!("Can't replay {}: {}", path, err);
+            std::process::exit(1);
+        }
+    }
+
+    for &(ref path, base) in &config.symbols {
+        match vm::load_symbols(path, base) {
+            Ok(count) => debug!("Loaded {} symbols from {}", count, path),
+            Err(err) => {
+                error!("{}", err);
+                std::process::exit(1);
+            },
+        }
+    }
+
+    for &gpa in &config.breakpoints {
+        if let Err(err) = vm::add_breakpoint(gpa) {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    }
+    for &(ref ports, direction) in &config.io_breakpoints {
+        vm::add_io_breakpoint(ports.clone(), direction).unwrap();
+    }
+    for &vec in &config.vector_breakpoints {
+        if let Err(err) = vm::break_on_vector(vec) {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    }
+
+    match config.gdb {
+        Some(ref gdb) if gdb.wait => gdb_resume(gdbstub::wait_for_attach(&mut GdbVcpu(vcpu))),
+        _ => {},
+    }
+    serve_reverse(vcpu);
 
     // Run vm loop
+    eventlog::emit(|| eventlog::Event::VmStart);
     loop {
+        /* Exit guest at the next timer deadline, timer counts down from here on every entry */
+        if preemption_timer {
+            let value = if replay::enabled() { u32::max_value() } else { event::preemption_timer_value() };
+            wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_VMX_TIMER_VALUE, value as u64);
+        }
+
         let err = vm::run();
 
         if err != HV_SUCCESS {
@@ -483,6 +1322,10 @@ fn main()
         let exit_reason = rvmcs32(vcpu, hv_vmx_vmcs_regs::VMCS_RO_EXIT_REASON);
         let exit_qualif = rvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_RO_EXIT_QUALIFIC);
         let ip = rvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_RIP) + rvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_CS_BASE);
+        vm::count_exit(exit_reason);
+        hang::sample(ip);
+        metrics::publish();
+        coredump::poll();
 
         debug!("\n----------");
         debug!("Exit reason {:x} ({})", exit_reason, exit_reason & 0xFFFF);
@@ -492,6 +1335,9 @@ fn main()
         dump_guest_state(vcpu);
         dump_guest_code(ip);
 
+        /* Exit is the trap of a single step */
+        let mut step_trap = false;
+
         match reason {
             hv_vmx_exit_reason::VMX_REASON_EXC_NMI => {
                 debug!("VMX_REASON_EXC_NMI");
@@ -501,6 +1347,24 @@ fn main()
 
                 if irqVec == 1 {
                     debug!("Guest trap @ {:x}", rvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_RO_GUEST_LIN_ADDR));
+                    step_trap = vm::step_in_progress();
+                }
+
+                /* Breakpoint exits are host's, recording doesn't count them */
+                if irqVec == 3 && vm::hit_int3_breakpoint(ip) {
+                    record::host_exit();
+                } else if irqVec == 3 {
+                    match gdbstub::breakpoint(&mut GdbVcpu(vcpu)) {
+                        Some(resume) => {
+                            record::host_exit();
+                            gdb_resume(resume);
+                        },
+                        None => {
+                            /* Guest's own INT3, its handler returns past it */
+                            next_instruction(vcpu);
+                            vm::raise_exception(3, None);
+                        }
+                    }
                 }
             },
 
@@ -523,85 +1387,102 @@ fn main()
                 let port: u16 = ((exit_qualif >> 16) & 0xFFFF) as u16; 
                 let is_read: bool = (exit_qualif & 0x8) != 0;
 
-                if is_read {
-                    let mut eax = ia32_reg_t {
-                        val: read_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RAX) as u32
-                    };
-
-                    match size {
-                        1 => eax.set_u8(vm::handle_io_read(port, size).unwrap_byte()),
-                        2 => eax.set_u16(vm::handle_io_read(port, size).unwrap_word()),
-                        4 => eax.set_u32(vm::handle_io_read(port, size).unwrap_dword()),
-                        _ => panic!(),
+                /* Access stopped at I/O breakpoint is performed when VM loop resumes from it below */
+                let direction = if is_read { breakpoint::IoDirection::Read } else { breakpoint::IoDirection::Write };
+                let access = record::Input::Io { port: port, size: size, write: !is_read };
+                record::emit(|| access.clone());
+                if replay::check(&access) && !vm::hit_io_breakpoint(port, size, direction, read_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RAX) as u32) {
+                    if is_read {
+                        let mut eax = ia32_reg_t {
+                            val: read_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RAX) as u32
+                        };
+
+                        match size {
+                            1 => eax.set_u8(vm::handle_io_read(port, size).unwrap_byte()),
+                            2 => eax.set_u16(vm::handle_io_read(port, size).unwrap_word()),
+                            4 => eax.set_u32(vm::handle_io_read(port, size).unwrap_dword()),
+                            _ => panic!(),
+                        }
+
+                        write_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RAX, eax.val as u64);
+                    } else {
+                        let eax = ia32_reg_t {
+                            val: read_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RAX) as u32
+                        };
+
+                        debug!("Writing {:?} to port {:x} size {}", eax, port, size);
+
+                        match size {
+                            1 => vm::handle_io_write(port, vm::IoOperandType::byte(eax.as_u8())),
+                            2 => vm::handle_io_write(port, vm::IoOperandType::word(eax.as_u16())),
+                            4 => vm::handle_io_write(port, vm::IoOperandType::dword(eax.as_u32())),
+                            _ => panic!(),
+                        }
                     }
 
-                    write_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RAX, eax.val as u64);
-                } else {
-                    let eax = ia32_reg_t {
-                        val: read_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RAX) as u32
-                    };
-
-                    debug!("Writing {:?} to port {:x} size {}", eax, port, size);
-
-                    match size {
-                        1 => vm::handle_io_write(port, vm::IoOperandType::byte(eax.as_u8())),
-                        2 => vm::handle_io_write(port, vm::IoOperandType::word(eax.as_u16())),
-                        4 => vm::handle_io_write(port, vm::IoOperandType::dword(eax.as_u32())),
-                        _ => panic!(),
-                    }
+                    next_instruction(vcpu);
                 }
-
-                next_instruction(vcpu);
             }
 
             hv_vmx_exit_reason::VMX_REASON_MOV_CR => {
                 debug!("VMX_REASON_MOV_CR");
-                
-                let crreg = exit_qualif & 0xF;
-                let optype = (exit_qualif >> 4) & 0x3;
-                let gpreg = ((exit_qualif >> 8) & 0xF) as usize;
-
-                if optype != 0 {
-                    panic!("VMX_REASON_MOV_CR: Only MOV to CR is supported (got {})", optype);
-                }
+                handle_mov_cr(vcpu, exit_qualif);
+            }
 
-                if crreg != 0 {
-                    panic!("VMX_REASON_MOV_CR: Expected CR0, got CR{}", crreg);
-                }
+            hv_vmx_exit_reason::VMX_REASON_VMCALL => {
+                debug!("VMX_REASON_VMCALL");
 
-                if gpreg >= 8 {
-                    panic!("VMX_REASON_MOV_CR: gpreg index out of bounds {}", gpreg);
-                }
+                let mut regs = hypercall::HypercallRegs {
+                    eax: read_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RAX) as u32,
+                    ebx: read_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RBX) as u32,
+                    ecx: read_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RCX) as u32,
+                    edx: read_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RDX) as u32,
+                };
 
-                let gpregmap: [hv_x86_reg_t; 8] = [
-                    hv_x86_reg_t::HV_X86_RAX,
-                    hv_x86_reg_t::HV_X86_RCX,
-                    hv_x86_reg_t::HV_X86_RDX,
-                    hv_x86_reg_t::HV_X86_RBX,
-                    hv_x86_reg_t::HV_X86_RSP,
-                    hv_x86_reg_t::HV_X86_RBP,
-                    hv_x86_reg_t::HV_X86_RSI,
-                    hv_x86_reg_t::HV_X86_RDI,
-                ];
-
-                let new_val = read_guest_reg(vcpu, gpregmap[gpreg]);
-                let cur_val = read_guest_reg(vcpu, hv_x86_reg_t::HV_X86_CR0);
-
-                debug!("VMX_REASON_MOV_CR: current value {:x}, new value {:x}", cur_val, new_val);
-
-                // CR0.PE?
-                if is_bit_changed(cur_val, new_val, 0) {
-                    debug!("VMX_REASON_MOV_CR: PE {}", new_val & 0x1);
-                }
+                hypercall::handle_hypercall(&mut regs);
 
-                // We just keep shadow value in sync
-                wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_CTRL_CR0_SHADOW, new_val);
-                wvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_CR0, new_val);
+                write_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RAX, regs.eax as u64);
+                write_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RBX, regs.ebx as u64);
+                write_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RCX, regs.ecx as u64);
+                write_guest_reg(vcpu, hv_x86_reg_t::HV_X86_RDX, regs.edx as u64);
                 next_instruction(vcpu);
             }
 
+            hv_vmx_exit_reason::VMX_REASON_RDTSC => {
+                debug!("VMX_REASON_RDTSC");
+                handle_rdtsc(vcpu, false);
+            }
+
+            hv_vmx_exit_reason::VMX_REASON_RDTSCP => {
+                debug!("VMX_REASON_RDTSCP");
+                handle_rdtsc(vcpu, true);
+            }
+
+            hv_vmx_exit_reason::VMX_REASON_RDMSR => {
+                debug!("VMX_REASON_RDMSR");
+                handle_msr(vcpu, false);
+            }
+
+            hv_vmx_exit_reason::VMX_REASON_WRMSR => {
+                debug!("VMX_REASON_WRMSR");
+                handle_msr(vcpu, true);
+            }
+
             hv_vmx_exit_reason::VMX_REASON_EPT_VIOLATION => {
-                debug!("VMX_REASON_EPT_VIOLATION");
+                let gpa = rvmcs(vcpu, hv_vmx_vmcs_regs::VMCS_GUEST_PHYSICAL_ADDRESS);
+                debug!("VMX_REASON_EPT_VIOLATION: gpa {:x}", gpa);
+
+                /* Breakpoints and dirty page log protect pages on host's behalf, recording doesn't count their exits */
+                if (exit_qualif & EPT_VIOLATION_FETCH) != 0 {
+                    if !vm::handle_exec_fault(gpa, ip) {
+                        crash::fatal(format!("Executing from MMIO at 0x{:x}", gpa));
+                    }
+                    record::host_exit();
+                } else if vm::handle_write_fault(gpa) {
+                    record::host_exit();
+                } else {
+                    handle_mmio(vcpu, ip, gpa);
+                }
             }
 
 
@@ -612,36 +1493,103 @@ fn main()
                 complete_interrupt_window(vcpu);
             }
 
+            hv_vmx_exit_reason::VMX_REASON_VIRTUAL_NMI_WND => {
+                debug!("VMX_REASON_VIRTUAL_NMI_WND");
+
+                /* Guest returned from NMI handler, pending NMI gets injected below */
+                complete_nmi_window(vcpu);
+            }
+
             hv_vmx_exit_reason::VMX_REASON_HLT => {
                 debug!("VMX_REASON_HLT");
-                std::process::exit(0);
+
+                /* BIOS call trapped by its stub, resume at stub's IRET or retry the HLT while call waits */
+                match handle_bios_call(vcpu, ip) {
+                    biosassist::Trap::Done => next_instruction(vcpu),
+                    biosassist::Trap::Wait => biosassist::wait_for_input(),
+                    biosassist::Trap::Delay => {},
+                    biosassist::Trap::None => {
+                        /* Without a display leave final guest screen and registers in the output */
+                        if config.headless {
+                            match vga::screen() {
+                                Some(screen) => println!("{}", screen.dump()),
+                                None => {},
+                            }
+                            println!("{}", vm::vcpu_state());
+                        }
+
+                        exit_vm(0);
+                    }
+                }
+            }
+
+            hv_vmx_exit_reason::VMX_REASON_VMX_TIMER_EXPIRED => {
+                debug!("VMX_REASON_VMX_TIMER_EXPIRED");
+
+                /* Due events fire below like after any other exit */
+            }
+
+            hv_vmx_exit_reason::VMX_REASON_MTF => {
+                debug!("VMX_REASON_MTF");
+                step_trap = true;
             }
 
             hv_vmx_exit_reason::VMX_REASON_TRIPLE_FAULT => {
                 debug!("VMX_REASON_TRIPLE_FAULT");
-                panic!();
+                crash::fatal(String::from("Triple fault"));
             }
 
             _ => {
-                panic!("Unhandled exit reason");
+                crash::fatal(format!("Unhandled exit reason {}", exit_reason & 0xFFFF));
             }
 
         }
 
-        /* Inject pending external interrupts or request interrupt window if guest is not
-         * interruptible */
-        if vm::has_pending_interrupts() {
-            if !is_interruptible(vcpu) {
-                request_interrupt_window(vcpu);
-            } else {
-                match vm::next_external_interrupt() {
-                    Some(excp) => {
-                        let event = 0x80000000_u32 | excp as u32;
-                        wvmcs32(vcpu, hv_vmx_vmcs_regs::VMCS_CTRL_VMENTRY_IRQ_INFO, event);
-                    },
-                    None => {},
-                }
-            }
+        /* Step ends at its trap or at an instruction emulated here, debugger also stops guest when asked to but
+         * not while reverse execution takes guest back */
+        if vm::finish_step(step_trap) == Some(vm::StepOwner::Debugger) {
+            gdb_resume(gdbstub::stop(&mut GdbVcpu(vcpu), gdbstub::GdbStop::Trap));
+        } else if gdbstub::attention_requested() && !reverse::seeking() {
+            gdb_resume(gdbstub::stop(&mut GdbVcpu(vcpu), gdbstub::GdbStop::Interrupt));
+        }
+
+        /* Monitor commands run here, a stopped VM stays in there */
+        if monitor::attention_requested() && !reverse::seeking() {
+            monitor::serve(&mut VcpuMonitor(vcpu));
+        }
+
+        handle_exit_requests(vcpu, &config);
+
+        /* Live snapshot copies another round of memory or stops guest for the last pages */
+        livesnap::poll();
+
+        /* Automatic snapshot that came due is saved here, guest waits for it */
+        autosnap::poll();
+
+        /* Reverse execution checkpoints the recording and takes guest back, it stops where it got to */
+        serve_reverse(vcpu);
+
+        /* Perform platform reset requested by a device while handling this exit */
+        if vm::take_reset_request() {
+            debug!("Guest reset");
+            eventlog::emit(|| eventlog::Event::VmReset);
+            vm::reset_devices();
+            reset_cpu(vcpu, entry.as_ref());
+            continue;
+        }
+
+        /* Events due by now may raise interrupts to inject right away, replay fires them at recorded exits */
+        if preemption_timer && !replay::enabled() {
+            event::run_due_events();
+        }
+
+        /* Replay delivers inputs recorded by this exit and steps guest up to where it takes the next interrupt */
+        replay::deliver_arrivals();
+        replay::before_entry();
+
+        /* Exception, NMI and external interrupts in architectural order, window exits for the ones that wait */
+        while !inject_pending_event(vcpu) {
+            handle_exit_requests(vcpu, &config);
         }
 
         if cfg!(feature = "guest-tracing") {
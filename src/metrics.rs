@@ -0,0 +1,278 @@
+/*
+ * Metrics export
+ *
+ * Long running VMs can be scraped: --metrics writes VM counters to a file every few seconds in Prometheus text
+ * exposition format, ready for node_exporter textfile collector or anything else reading it. Series are
+ *
+ *   xvm_exits_total{reason="io"}                           exits by basic exit reason, as run summary names them
+ *   xvm_port_accesses_total{port="0x0060",dir="read"}      guest port accesses, ports guest accessed only
+ *   xvm_irq_assertions_total{irq="0"}                      IRQ line assertions, every line
+ *   xvm_device_bytes_total{device="ata0",dir="read"}       bytes devices moved for guest, see vm::stats_handler
+ *   xvm_guest_time_seconds                                 guest virtual time
+ *
+ * Counters belong to vcpu thread, it leaves a copy of them to a writer thread at its first exit after each
+ * write and never waits for the writer. Counters in the file are one interval old at most, guest time is as of
+ * writing it. File is replaced by rename, so readers never see half of it, and written once more as VM stops.
+ */
+
+use vm;
+use config;
+use clock;
+use summary::{self, PortCount, DeviceStats};
+
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::io;
+use std::io::Write as IoWrite;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+const NS_PER_SEC: f64 = 1_000_000_000.0;
+
+/**
+ * Copy of VM counters taken on vcpu thread
+ */
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Sample
+{
+    pub exits: Vec<(u32, u64)>,     // Basic exit reason and count, any order
+    pub ports: Vec<PortCount>,      // Every port accessed, any order
+    pub irqs: Vec<u64>,             // Assertions of each IRQ line
+    pub devices: Vec<DeviceStats>,
+}
+
+/* Label value with backslash, quote and newline escaped */
+fn label(val: &str) -> String
+{
+    val.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/* Help and type lines introducing a metric */
+fn header(out: &mut String, name: &str, kind: &str, help: &str)
+{
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/**
+ * Counters and guest time in Prometheus text format, series in a stable order
+ */
+pub fn gather(sample: &Sample, guest_ns: u64) -> String
+{
+    let mut out = String::new();
+
+    let mut exits = sample.exits.clone();
+    exits.sort();
+    header(&mut out, "xvm_exits_total", "counter", "VM exits by basic exit reason.");
+    for &(reason, count) in &exits {
+        let name = summary::exit_reason_name(reason).replace(' ', "_");
+        let _ = writeln!(out, "xvm_exits_total{{reason=\"{}\"}} {}", label(&name), count);
+    }
+
+    let mut ports = sample.ports.clone();
+    ports.sort_by_key(|count| count.port);
+    header(&mut out, "xvm_port_accesses_total", "counter", "Guest I/O port accesses.");
+    for count in &ports {
+        let _ = writeln!(out, "xvm_port_accesses_total{{port=\"0x{:04x}\",dir=\"read\"}} {}", count.port, count.reads);
+        let _ = writeln!(out, "xvm_port_accesses_total{{port=\"0x{:04x}\",dir=\"write\"}} {}", count.port, count.writes);
+    }
+
+    header(&mut out, "xvm_irq_assertions_total", "counter", "IRQ line assertions.");
+    for (irq, count) in sample.irqs.iter().enumerate() {
+        let _ = writeln!(out, "xvm_irq_assertions_total{{irq=\"{}\"}} {}", irq, count);
+    }
+
+    header(&mut out, "xvm_device_bytes_total", "counter", "Guest data moved by devices in bytes.");
+    for stats in &sample.devices {
+        let device = label(stats.device);
+        let _ = writeln!(out, "xvm_device_bytes_total{{device=\"{}\",dir=\"read\"}} {}", device, stats.bytes_read);
+        let _ = writeln!(out, "xvm_device_bytes_total{{device=\"{}\",dir=\"write\"}} {}", device, stats.bytes_written);
+    }
+
+    header(&mut out, "xvm_guest_time_seconds", "gauge", "Guest virtual time.");
+    let _ = writeln!(out, "xvm_guest_time_seconds {:.9}", guest_ns as f64 / NS_PER_SEC);
+    out
+}
+
+#[cfg(test)]
+mod metrics_test
+{
+    use super::*;
+    use std::collections::HashMap;
+
+    /* Series of text exposition format as name with labels and value, metric types declared before use */
+    fn parse(text: &str) -> Result<HashMap<String, f64>, String> {
+        let mut types = HashMap::new();
+        let mut series = HashMap::new();
+
+        for line in text.lines() {
+            if line.starts_with("# ") {
+                let words: Vec<&str> = line.splitn(4, ' ').collect();
+                match (words.get(1), words.get(2), words.get(3)) {
+                    (Some(&"HELP"), Some(_), Some(_)) => {},
+                    (Some(&"TYPE"), Some(name), Some(&kind)) if kind == "counter" || kind == "gauge" => {
+                        types.insert(name.to_string(), kind);
+                    },
+                    _ => return Err(format!("Bad comment {}", line)),
+                }
+                continue;
+            }
+
+            let split = try!(line.rfind(' ').ok_or(format!("No value in {}", line)));
+            let (key, value) = (&line[..split], &line[split + 1..]);
+            let name = &key[..key.find('{').unwrap_or(key.len())];
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':') {
+                return Err(format!("Bad metric name in {}", line));
+            }
+            if name.len() < key.len() {
+                let labels = &key[name.len()..];
+                if !labels.ends_with('}') || labels[1..labels.len() - 1].split(',').any(|pair| {
+                    let mut parts = pair.splitn(2, '=');
+                    let (label, val) = (parts.next().unwrap(), parts.next().unwrap_or(""));
+                    label.is_empty() || val.len() < 2 || !val.starts_with('"') || !val.ends_with('"')
+                }) {
+                    return Err(format!("Bad labels in {}", line));
+                }
+            }
+            if !types.contains_key(name) {
+                return Err(format!("Metric {} has no type", name));
+            }
+
+            let value = try!(value.parse::<f64>().map_err(|_| format!("Bad value in {}", line)));
+            if series.insert(key.to_string(), value).is_some() {
+                return Err(format!("Duplicate series {}", key));
+            }
+        }
+        Ok(series)
+    }
+
+    /* Counters of a boot sector run: timer interrupts, a disk read and PIC EOIs */
+    fn scripted_run() -> Sample {
+        let mut irqs = vec![0; vm::IRQ_LINES];
+        irqs[0] = 100;
+        irqs[14] = 1;
+
+        Sample {
+            exits: vec![(30, 412), (1, 100), (7, 100), (12, 1)],
+            ports: vec![
+                PortCount { port: 0x20, reads: 0, writes: 101 },
+                PortCount { port: 0x1F7, reads: 3, writes: 1 },
+                PortCount { port: 0x1F0, reads: 256, writes: 0 },
+            ],
+            irqs: irqs,
+            devices: vec![
+                DeviceStats { device: "ata0", bytes_read: 512, bytes_written: 0 },
+                DeviceStats { device: "uart", bytes_read: 0, bytes_written: 14 },
+            ],
+        }
+    }
+
+    #[test] fn exposition() {
+        let text = gather(&scripted_run(), 1_500_000_000);
+        let series = parse(&text).unwrap();
+
+        assert!(series["xvm_exits_total{reason=\"io\"}"] == 412.0);
+        assert!(series["xvm_exits_total{reason=\"external_irq\"}"] == 100.0);
+        assert!(series["xvm_exits_total{reason=\"irq_window\"}"] == 100.0);
+        assert!(series["xvm_exits_total{reason=\"hlt\"}"] == 1.0);
+        assert!(series["xvm_port_accesses_total{port=\"0x01f0\",dir=\"read\"}"] == 256.0);
+        assert!(series["xvm_port_accesses_total{port=\"0x0020\",dir=\"write\"}"] == 101.0);
+        assert!(series["xvm_irq_assertions_total{irq=\"0\"}"] == 100.0);
+        assert!(series["xvm_irq_assertions_total{irq=\"14\"}"] == 1.0);
+        assert!(series["xvm_irq_assertions_total{irq=\"23\"}"] == 0.0);
+        assert!(series["xvm_device_bytes_total{device=\"ata0\",dir=\"read\"}"] == 512.0);
+        assert!(series["xvm_device_bytes_total{device=\"uart\",dir=\"write\"}"] == 14.0);
+        assert!(series["xvm_guest_time_seconds"] == 1.5);
+        assert!(series.len() == 4 + 6 + vm::IRQ_LINES + 4 + 1);
+
+        /* Same counters in any order give the same file */
+        let mut shuffled = scripted_run();
+        shuffled.exits.reverse();
+        shuffled.ports.reverse();
+        assert!(gather(&shuffled, 1_500_000_000) == text);
+        assert!(text.contains("xvm_port_accesses_total{port=\"0x0020\",dir=\"write\"} 101\n\
+                               xvm_port_accesses_total{port=\"0x01f0\",dir=\"read\"} 256\n"));
+    }
+
+    #[test] fn empty() {
+        let series = parse(&gather(&Sample::default(), 0)).unwrap();
+        assert!(series.len() == 1 && series["xvm_guest_time_seconds"] == 0.0);
+        assert!(label("a\"b\\c\n") == "a\\\"b\\\\c\\n");
+        assert!(parse("xvm_exits_total 1\n").is_err());
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+lazy_static! {
+    static ref DUE: AtomicBool = AtomicBool::new(false);     // Writer wants a fresh sample
+    static ref SAMPLE: Mutex<Option<Sample>> = Mutex::new(None);
+    static ref METRICS_PATH: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/* Replace metrics file with text */
+fn write(path: &str, text: &str) -> io::Result<()>
+{
+    let tmp = format!("{}.tmp", path);
+    try!(File::create(&tmp).and_then(|mut file| file.write_all(text.as_bytes())));
+    fs::rename(&tmp, path)
+}
+
+fn writer(path: String, interval: u32)
+{
+    loop {
+        thread::sleep(Duration::from_secs(interval as u64));
+
+        let sample = SAMPLE.lock().unwrap().clone();
+        DUE.store(true, Ordering::Relaxed);
+        if let Some(sample) = sample {
+            if let Err(err) = write(&path, &gather(&sample, clock::guest_time_ns())) {
+                warn!("Can't write metrics to {}: {}", path, err);
+            }
+        }
+    }
+}
+
+/**
+ * Leave a copy of counters to metrics writer if it asked for one, vcpu thread only, at every exit
+ */
+pub fn publish()
+{
+    if !DUE.load(Ordering::Relaxed) {
+        return;
+    }
+
+    DUE.store(false, Ordering::Relaxed);
+    *SAMPLE.lock().unwrap() = Some(vm::metrics_sample());
+}
+
+/**
+ * Write metrics as VM stops, vcpu thread only
+ */
+pub fn report()
+{
+    let path = match *METRICS_PATH.lock().unwrap() {
+        Some(ref path) => path.clone(),
+        None => return,
+    };
+
+    if let Err(err) = write(&path, &gather(&vm::metrics_sample(), clock::guest_time_ns())) {
+        error!("Can't write metrics to {}: {}", path, err);
+    }
+}
+
+pub fn init(config: &config::VmConfig)
+{
+    let metrics = match config.metrics {
+        Some(ref metrics) => metrics,
+        None => return,
+    };
+
+    *METRICS_PATH.lock().unwrap() = Some(metrics.path.clone());
+    DUE.store(true, Ordering::Relaxed);
+
+    let (path, interval) = (metrics.path.clone(), metrics.interval);
+    thread::spawn(move || writer(path, interval));
+}
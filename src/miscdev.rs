@@ -27,12 +27,52 @@ impl vm::io_handler for miscdev
     }
 }
 
+/*
+ * System control port A (0x92)
+ * Bit 0 is a fast CPU reset, bit 1 controls A20 gate shared with i8042 output port.
+ */
+const SYSCTL_PORT_A: u16        = 0x92;
+const SYSCTL_A_RESET: u8        = 0x01;
+const SYSCTL_A_A20: u8          = 0x02;
+
+struct sysctl_port_a
+{
+    val: RefCell<u8>,   // Last written value, except for A20 bit which is owned by vm
+}
+
+#[allow(unused_variables)]
+impl vm::io_handler for sysctl_port_a
+{
+    fn io_read(&self, port: u16, size: u8) -> vm::IoOperandType
+    {
+        let mut val = *self.val.borrow() & !(SYSCTL_A_A20 | SYSCTL_A_RESET);
+        if vm::is_a20_enabled() {
+            val |= SYSCTL_A_A20;
+        }
+
+        vm::IoOperandType::byte(val)
+    }
+
+    fn io_write(&self, port: u16, data: vm::IoOperandType)
+    {
+        let val = data.unwrap_byte();
+        *self.val.borrow_mut() = val;
+
+        vm::set_a20_enabled((val & SYSCTL_A_A20) != 0);
+
+        if (val & SYSCTL_A_RESET) != 0 {
+            debug!("Port 0x92: CPU reset");
+            vm::request_reset();
+        }
+    }
+}
+
 pub fn init()
 {
-    let a20 = Rc::new(miscdev {
-        val: RefCell::new(vm::IoOperandType::byte(0x04)), // A20 enabled
+    let sysctl_a = Rc::new(sysctl_port_a {
+        val: RefCell::new(0),
     });
-    vm::register_io_region(a20, 0x92, 1);
+    vm::register_io_region(sysctl_a, SYSCTL_PORT_A, 1);
 
     let fwcfg1 = Rc::new(miscdev {
         val: RefCell::new(vm::IoOperandType::word(0)),
@@ -43,13 +83,5 @@ pub fn init()
         val: RefCell::new(vm::IoOperandType::byte(0)),
     });
     vm::register_io_region(fwcfg2, 0x511, 1);
-
-    let dma = Rc::new(miscdev {
-        val: RefCell::new(vm::IoOperandType::byte(0)),
-    });
-    vm::register_io_region(dma.clone(), 0xd, 1);
-    vm::register_io_region(dma.clone(), 0xda, 1);
-    vm::register_io_region(dma.clone(), 0xd6, 1);
-    vm::register_io_region(dma.clone(), 0xd4, 1);
 }
 
@@ -0,0 +1,292 @@
+/*
+ * Decoding of guest instructions that fault on MMIO
+ *
+ * EPT violation exits tell us the guest physical address but not the access itself, so the faulting
+ * instruction is fetched and decoded here. Only plain MOV forms are supported, which is what compilers
+ * and hand written code use for device registers:
+ *
+ *   88/89 /r       MOV r/m, reg
+ *   8A/8B /r       MOV reg, r/m
+ *   C6/C7 /0       MOV r/m, imm
+ *   A0-A3          MOV AL/eAX <-> moffs
+ *
+ * with operand size, address size and segment override prefixes. Register numbers are x86 encoding
+ * order (eAX, eCX, eDX, eBX, eSP, eBP, eSI, eDI), for byte accesses 4-7 are AH, CH, DH, BH.
+ */
+
+// Prefixes
+const PREFIX_OPSIZE: u8     = 0x66;
+const PREFIX_ADDRSIZE: u8   = 0x67;
+const PREFIX_ES: u8         = 0x26;
+const PREFIX_CS: u8         = 0x2E;
+const PREFIX_SS: u8         = 0x36;
+const PREFIX_DS: u8         = 0x3E;
+const PREFIX_FS: u8         = 0x64;
+const PREFIX_GS: u8         = 0x65;
+
+// Opcodes
+const OP_MOV_STORE8: u8     = 0x88;
+const OP_MOV_STORE: u8      = 0x89;
+const OP_MOV_LOAD8: u8      = 0x8A;
+const OP_MOV_LOAD: u8       = 0x8B;
+const OP_MOV_IMM8: u8       = 0xC6;
+const OP_MOV_IMM: u8        = 0xC7;
+const OP_MOV_AL_MOFFS: u8   = 0xA0;
+const OP_MOV_AX_MOFFS: u8   = 0xA1;
+const OP_MOV_MOFFS_AL: u8   = 0xA2;
+const OP_MOV_MOFFS_AX: u8   = 0xA3;
+
+/* Longest legal x86 instruction */
+pub const MAX_INSN_LEN: usize = 15;
+
+/**
+ * Source of data for MMIO write or destination of MMIO read
+ */
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum MmioOperand
+{
+    Reg(u8),            // General purpose register number
+    Imm(u32),           // Immediate, stores only
+}
+
+/**
+ * Decoded MMIO access
+ */
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct MmioInsn
+{
+    pub len: usize,             // Instruction length in bytes
+    pub size: u8,               // Access size in bytes
+    pub is_write: bool,
+    pub operand: MmioOperand,
+}
+
+/* Bytes taken by ModRM byte and what follows it up to immediate, None for register operand */
+fn modrm_len(code: &[u8], addr32: bool) -> Option<usize>
+{
+    let modrm = match code.first() {
+        Some(&modrm) => modrm,
+        None => return None,
+    };
+    let md = modrm >> 6;
+    let rm = modrm & 0x7;
+
+    if md == 3 {
+        return None;
+    }
+
+    if !addr32 {
+        return Some(1 + match md {
+            0 if rm == 6 => 2,
+            0 => 0,
+            1 => 1,
+            _ => 2,
+        });
+    }
+
+    let (sib, base) = if rm == 4 {
+        match code.get(1) {
+            Some(&sib) => (1, sib & 0x7),
+            None => return None,
+        }
+    } else {
+        (0, rm)
+    };
+
+    Some(1 + sib + match md {
+        0 if base == 5 => 4,
+        0 => 0,
+        1 => 1,
+        _ => 4,
+    })
+}
+
+/* Little endian immediate of given size */
+fn immediate(code: &[u8], size: u8) -> Option<u32>
+{
+    if code.len() < size as usize {
+        return None;
+    }
+
+    Some(code[..size as usize].iter().rev().fold(0, |val, &b| (val << 8) | b as u32))
+}
+
+/**
+ * Decode MMIO instruction at start of code, None if it is not a supported memory MOV.
+ * code32 is the default operand and address size of the code segment.
+ */
+pub fn decode(code: &[u8], code32: bool) -> Option<MmioInsn>
+{
+    let mut opsize32 = code32;
+    let mut addr32 = code32;
+    let mut pos = 0;
+
+    while pos < code.len() && pos < MAX_INSN_LEN {
+        match code[pos] {
+            PREFIX_OPSIZE => opsize32 = !code32,
+            PREFIX_ADDRSIZE => addr32 = !code32,
+            PREFIX_ES | PREFIX_CS | PREFIX_SS | PREFIX_DS | PREFIX_FS | PREFIX_GS => {},
+            _ => break,
+        }
+        pos += 1;
+    }
+
+    let opcode = match code.get(pos) {
+        Some(&opcode) if pos < MAX_INSN_LEN => opcode,
+        _ => return None,
+    };
+    pos += 1;
+
+    let wide = if opsize32 { 4 } else { 2 };
+    let (size, is_write) = match opcode {
+        OP_MOV_STORE8 | OP_MOV_IMM8 | OP_MOV_MOFFS_AL => (1, true),
+        OP_MOV_LOAD8 | OP_MOV_AL_MOFFS => (1, false),
+        OP_MOV_STORE | OP_MOV_IMM | OP_MOV_MOFFS_AX => (wide, true),
+        OP_MOV_LOAD | OP_MOV_AX_MOFFS => (wide, false),
+        _ => return None,
+    };
+
+    let (len, operand) = match opcode {
+        OP_MOV_AL_MOFFS | OP_MOV_AX_MOFFS | OP_MOV_MOFFS_AL | OP_MOV_MOFFS_AX => {
+            (pos + if addr32 { 4 } else { 2 }, MmioOperand::Reg(0))
+        },
+        _ => {
+            let reg = match code.get(pos) {
+                Some(modrm) => (modrm >> 3) & 0x7,
+                None => return None,
+            };
+            let end = match modrm_len(&code[pos..], addr32) {
+                Some(len) => pos + len,
+                None => return None,
+            };
+
+            if opcode != OP_MOV_IMM8 && opcode != OP_MOV_IMM {
+                (end, MmioOperand::Reg(reg))
+            } else if reg != 0 {
+                return None;
+            } else {
+                match immediate(&code[end.min(code.len())..], size) {
+                    Some(imm) => (end + size as usize, MmioOperand::Imm(imm)),
+                    None => return None,
+                }
+            }
+        },
+    };
+
+    if len > MAX_INSN_LEN || len > code.len() {
+        return None;
+    }
+
+    Some(MmioInsn { len: len, size: size, is_write: is_write, operand: operand })
+}
+
+/* General purpose register number and bit shift of register operand, for AH-BH in byte accesses */
+fn reg_location(reg: u8, size: u8) -> (u8, u32)
+{
+    if size == 1 && reg >= 4 {
+        (reg - 4, 8)
+    } else {
+        (reg, 0)
+    }
+}
+
+fn size_mask(size: u8) -> u64
+{
+    (1u64 << (size as u32 * 8)) - 1
+}
+
+/**
+ * General purpose register number a register operand lives in
+ */
+pub fn operand_gpreg(reg: u8, size: u8) -> usize
+{
+    reg_location(reg, size).0 as usize
+}
+
+/**
+ * Value of register operand taken from full register value
+ */
+pub fn operand_value(reg: u8, size: u8, regval: u64) -> u64
+{
+    let (_, shift) = reg_location(reg, size);
+    (regval >> shift) & size_mask(size)
+}
+
+/**
+ * Full register value after loading data into register operand, other bits are kept
+ */
+pub fn load_operand(reg: u8, size: u8, regval: u64, data: u64) -> u64
+{
+    let (_, shift) = reg_location(reg, size);
+    let mask = size_mask(size) << shift;
+    (regval & !mask) | ((data << shift) & mask)
+}
+
+#[cfg(test)]
+mod mmio_test
+{
+    use super::*;
+
+    fn insn(len: usize, size: u8, is_write: bool, operand: MmioOperand) -> Option<MmioInsn> {
+        Some(MmioInsn { len: len, size: size, is_write: is_write, operand: operand })
+    }
+
+    #[test] fn decode_real_mode() {
+        /* mov [0x5555], al */
+        assert!(decode(&[0xA2, 0x55, 0x55], false) == insn(3, 1, true, MmioOperand::Reg(0)));
+        /* mov ah, [es:di] */
+        assert!(decode(&[0x26, 0x8A, 0x25], false) == insn(3, 1, false, MmioOperand::Reg(4)));
+        /* mov byte [bx+0x2AAA], 0x55 */
+        assert!(decode(&[0xC6, 0x87, 0xAA, 0x2A, 0x55], false) == insn(5, 1, true, MmioOperand::Imm(0x55)));
+        /* mov word [bp+4], 0x1234 */
+        assert!(decode(&[0xC7, 0x46, 0x04, 0x34, 0x12], false) == insn(5, 2, true, MmioOperand::Imm(0x1234)));
+        /* mov eax, [si] */
+        assert!(decode(&[0x66, 0x8B, 0x04], false) == insn(3, 4, false, MmioOperand::Reg(0)));
+        /* mov [ebx+ecx*4+0x10], dx */
+        assert!(decode(&[0x67, 0x89, 0x54, 0x8B, 0x10], false) == insn(5, 2, true, MmioOperand::Reg(2)));
+        /* mov ax, [0x1000] and partial instruction */
+        assert!(decode(&[0xA1, 0x00, 0x10], false) == insn(3, 2, false, MmioOperand::Reg(0)));
+        assert!(decode(&[0xA1, 0x00], false) == None);
+    }
+
+    #[test] fn decode_protected_mode() {
+        /* mov [0xFFFF5555], al */
+        assert!(decode(&[0xA2, 0x55, 0x55, 0xFF, 0xFF], true) == insn(5, 1, true, MmioOperand::Reg(0)));
+        /* mov ecx, [eax] */
+        assert!(decode(&[0x8B, 0x08], true) == insn(2, 4, false, MmioOperand::Reg(1)));
+        /* mov dword [0xFEE000B0], 0 */
+        assert!(decode(&[0xC7, 0x05, 0xB0, 0x00, 0xE0, 0xFE, 0, 0, 0, 0], true) == insn(10, 4, true, MmioOperand::Imm(0)));
+        /* mov [esp+8], bh: SIB without displacement base */
+        assert!(decode(&[0x88, 0x7C, 0x24, 0x08], true) == insn(4, 1, true, MmioOperand::Reg(7)));
+        /* mov eax, [ecx*2+0x1000]: SIB with no base takes disp32 */
+        assert!(decode(&[0x8B, 0x04, 0x4D, 0x00, 0x10, 0, 0], true) == insn(7, 4, false, MmioOperand::Reg(0)));
+        /* mov si, [edi+0x100] */
+        assert!(decode(&[0x66, 0x8B, 0xB7, 0x00, 0x01, 0, 0], true) == insn(7, 2, false, MmioOperand::Reg(6)));
+    }
+
+    #[test] fn decode_unsupported() {
+        /* Register operand, not a memory access */
+        assert!(decode(&[0x89, 0xC8], false) == None);
+        /* C6 with reg field other than 0 */
+        assert!(decode(&[0xC6, 0x0F, 0x00], false) == None);
+        /* movsb, add [bx], al */
+        assert!(decode(&[0xA4], false) == None);
+        assert!(decode(&[0x00, 0x07], false) == None);
+        /* Prefixes only */
+        assert!(decode(&[0x66; MAX_INSN_LEN + 1], false) == None);
+    }
+
+    #[test] fn operands() {
+        assert!(operand_gpreg(4, 1) == 0 && operand_gpreg(4, 2) == 4 && operand_gpreg(3, 1) == 3);
+        assert!(operand_value(0, 1, 0x12345678) == 0x78);
+        assert!(operand_value(4, 1, 0x12345678) == 0x56);
+        assert!(operand_value(7, 1, 0xABCD) == 0xAB);
+        assert!(operand_value(0, 2, 0x12345678) == 0x5678);
+        assert!(operand_value(0, 4, 0xFFFFFFFF12345678) == 0x12345678);
+
+        assert!(load_operand(0, 1, 0x12345678, 0xAA) == 0x123456AA);
+        assert!(load_operand(5, 1, 0x12345678, 0xAA) == 0x1234AA78);
+        assert!(load_operand(1, 2, 0x12345678, 0xFFFFBEEF) == 0x1234BEEF);
+        assert!(load_operand(1, 4, 0x12345678, 0xCAFEF00D) == 0xCAFEF00D);
+    }
+}
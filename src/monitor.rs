@@ -0,0 +1,1296 @@
+/*
+ * Monitor console
+ *
+ * Text commands for poking a running VM, typed on host terminal or over a local TCP connection. Console threads
+ * only pass command lines along: commands run on vcpu thread between exits, where devices are consistent and vcpu
+ * registers can be read, and their output goes back to the console that sent them. A stopped VM keeps serving
+ * commands until it is continued. While a debugger holds VM stopped commands wait until it lets guest run.
+ * Guest hitting a breakpoint stops VM the same way, port access stopped at an I/O breakpoint is performed once
+ * VM continues, with the value given to cont if any. Vector stopped at a vector breakpoint is delivered once VM
+ * continues, "cont skip" takes it back instead.
+ *
+ * Commands are looked up in a registry by their leading words, so "info pic" is a command of its own and devices
+ * can add theirs next to the built-in ones.
+ *
+ * With guest symbols loaded, see --symbols, breakpoints can be set by symbol name, as in "break timer_isr+0x4",
+ * and addresses breakpoints are at show the symbol they are in.
+ *
+ * Interrupts can be injected by hand: "irq" asserts an IRQ line the way a device does, so controller masking and
+ * priority apply, "irq-raw" raises a vector bypassing controllers and "nmi" raises an NMI. Each tells whether
+ * guest gets it at the next entry, it waits for guest to take it or controller holds it back. Events injected
+ * into a stopped VM are delivered as it resumes.
+ *
+ * Guest state can be changed live: "setmem" writes RAM, ROM only with -f, and "setreg" sets a register of a
+ * stopped guest. Both show the values they replaced and go to the event log, so a run can be reproduced.
+ */
+
+use vm;
+use pic;
+use config;
+use breakpoint;
+use symbols;
+use inject;
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+use std::net::TcpListener;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+// Longest memory dump taken by x and xp
+const MAX_DUMP_BYTES: usize = 4096;
+
+const PROMPT: &'static str = "(xvm) ";
+
+/**
+ * VM state commands look at
+ */
+pub trait monitor_target
+{
+    /** Register names and values in dump order */
+    fn registers(&mut self) -> Vec<(&'static str, u64)>;
+
+    /** Master and slave PIC, None without PIC */
+    fn pic_state(&mut self) -> Option<[pic::I8259State; 2]>;
+
+    /** Registered I/O port regions as (base, size) */
+    fn io_regions(&mut self) -> Vec<(u16, u8)>;
+
+    /** Assertions of each IRQ line */
+    fn irq_counts(&mut self) -> Vec<u64>;
+
+    /** Guest physical memory, returns bytes read up to the end of the mapping address is in */
+    fn read_memory(&mut self, addr: u64, buf: &mut [u8]) -> usize;
+
+    /** Breakpoints set in guest */
+    fn breakpoints(&mut self) -> Vec<breakpoint::Breakpoint>;
+
+    /** Stop guest before it executes instruction at guest physical address */
+    fn add_breakpoint(&mut self, addr: u64, kind: breakpoint::BreakpointKind) -> Result<breakpoint::BreakpointHandle, String>;
+
+    /** Remove execution, I/O or vector breakpoint, false if there is no such breakpoint */
+    fn remove_breakpoint(&mut self, handle: breakpoint::BreakpointHandle) -> bool;
+
+    /** I/O breakpoints set in guest */
+    fn io_breakpoints(&mut self) -> Vec<breakpoint::IoBreakpoint>;
+
+    /** Stop guest before it accesses ports */
+    fn add_io_breakpoint(&mut self, ports: Range<u16>, direction: breakpoint::IoDirection) -> Result<breakpoint::BreakpointHandle, String>;
+
+    /** Vector breakpoints set in guest */
+    fn vector_breakpoints(&mut self) -> Vec<breakpoint::VectorBreakpoint>;
+
+    /** Stop guest before external interrupt vector is injected */
+    fn add_vector_breakpoint(&mut self, vec: u8) -> Result<breakpoint::BreakpointHandle, String>;
+
+    /** Guest symbols loaded */
+    fn symbols(&mut self) -> &symbols::SymbolTable;
+
+    /** Assert IRQ line through interrupt controller */
+    fn inject_irq(&mut self, irq: u8) -> Result<inject::InjectOutcome, String>;
+
+    /** Raise external interrupt vector bypassing interrupt controller */
+    fn inject_vector(&mut self, vec: u8) -> inject::InjectOutcome;
+
+    /** Raise NMI */
+    fn inject_nmi(&mut self) -> inject::InjectOutcome;
+
+    /** Write bytes to guest memory, ROM only if forced, returns the bytes replaced */
+    fn patch_memory(&mut self, addr: u64, data: &[u8], force: bool) -> Result<Vec<u8>, String>;
+
+    /** Set register named as registers() names it, returns the value replaced */
+    fn set_register(&mut self, name: &str, val: u64) -> Result<u64, String>;
+}
+
+/**
+ * Whether guest runs after a command
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum RunState
+{
+    Running,
+    Stopped,
+    Quit,           // VM shuts down
+}
+
+/**
+ * What a command works with
+ */
+pub struct MonitorContext<'a>
+{
+    pub target: &'a mut monitor_target,
+    pub run_state: RunState,
+    pub stop_reason: Option<String>,    // Why VM stopped other than by command, e.g. breakpoint hit
+    pub resume_value: Option<u32>,      // Value port access stopped at I/O breakpoint uses instead of guest's
+    pub skip_vector: bool,              // Vector stopped at vector breakpoint is taken back instead of delivered
+}
+
+/**
+ * Command handler, gets arguments after command words and returns output text or error
+ */
+pub type MonitorHandler = fn(&mut MonitorContext, &[&str]) -> Result<String, String>;
+
+/**
+ * Monitor command provided by VMM or a device
+ */
+#[derive(Clone, Copy)]
+pub struct MonitorCommand
+{
+    pub name: &'static str,     // Command words, e.g. "info pic"
+    pub args: &'static str,     // Argument synopsis for help
+    pub help: &'static str,
+    pub handler: MonitorHandler,
+}
+
+/* Number as hex with 0x prefix or decimal */
+pub fn parse_number(val: &str) -> Result<u64, String>
+{
+    let res = if val.starts_with("0x") || val.starts_with("0X") {
+        u64::from_str_radix(&val[2..], 16)
+    } else {
+        val.parse::<u64>()
+    };
+
+    res.map_err(|_| format!("Bad number {}", val))
+}
+
+/* Linear address as number or hex seg:off */
+fn parse_address(val: &str) -> Result<u64, String>
+{
+    let mut parts = val.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(seg), Some(off)) => {
+            match (u16::from_str_radix(seg, 16), u16::from_str_radix(off, 16)) {
+                (Ok(seg), Ok(off)) => Ok(((seg as u64) << 4) + off as u64),
+                _ => Err(format!("Bad address {}", val)),
+            }
+        },
+        _ => parse_number(val),
+    }
+}
+
+/* Hex number with optional 0x prefix */
+fn parse_hex(val: &str) -> Result<u64, String>
+{
+    let digits = if val.starts_with("0x") || val.starts_with("0X") { &val[2..] } else { val };
+    u64::from_str_radix(digits, 16).map_err(|_| format!("Bad hex number {}", val))
+}
+
+/* Linear address as hex number with optional 0x prefix or hex seg:off */
+fn parse_hex_address(val: &str) -> Result<u64, String>
+{
+    if val.contains(':') { parse_address(val) } else { parse_hex(val) }
+}
+
+/* Linear address as number, hex seg:off or symbol name with optional +offset */
+fn parse_location(symbols: &symbols::SymbolTable, val: &str) -> Result<u64, String>
+{
+    if let Ok(addr) = parse_address(val) {
+        return Ok(addr);
+    }
+
+    let mut parts = val.splitn(2, '+');
+    let name = parts.next().unwrap_or("");
+    let offset = match parts.next() {
+        Some(offset) => try!(parse_number(offset)),
+        None => 0,
+    };
+
+    match symbols.lookup(name) {
+        Some(addr) => Ok(addr + offset),
+        None => Err(format!("Bad address or unknown symbol {}", val)),
+    }
+}
+
+/* Address followed by " <symbol+0x12>" if a symbol covers it */
+fn describe_address(symbols: &symbols::SymbolTable, addr: u64) -> String
+{
+    match symbols.describe(addr) {
+        Some(symbol) => format!("0x{:x} <{}>", addr, symbol),
+        None => format!("0x{:x}", addr),
+    }
+}
+
+/**
+ * Memory dump format of x and xp, as in "/16xb"
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+struct DumpFormat
+{
+    count: usize,
+    format: char,       // x hex, d signed or u unsigned decimal
+    size: usize,        // Unit size in bytes
+}
+
+fn parse_dump_format(val: &str) -> Result<DumpFormat, String>
+{
+    let mut fmt = DumpFormat { count: 1, format: 'x', size: 4 };
+    let spec = &val[1..];
+    let digits = spec.chars().take_while(|c| c.is_digit(10)).count();
+    if digits != 0 {
+        fmt.count = try!(spec[..digits].parse::<usize>().map_err(|_| format!("Bad format {}", val)));
+    }
+
+    for c in spec[digits..].chars() {
+        match c {
+            'x' | 'd' | 'u' => fmt.format = c,
+            'b' => fmt.size = 1,
+            'h' => fmt.size = 2,
+            'w' => fmt.size = 4,
+            'g' => fmt.size = 8,
+            _ => return Err(format!("Bad format {}", val)),
+        }
+    }
+
+    Ok(fmt)
+}
+
+/* Dump memory in QEMU style: 8 bytes per line in byte units, 16 bytes otherwise */
+fn dump_memory(target: &mut monitor_target, args: &[&str]) -> Result<String, String>
+{
+    let (fmt, addr) = match args.len() {
+        1 => (DumpFormat { count: 1, format: 'x', size: 4 }, args[0]),
+        2 if args[0].starts_with('/') => (try!(parse_dump_format(args[0])), args[1]),
+        _ => return Err(String::from("Expected [/fmt] address")),
+    };
+
+    let addr = try!(parse_address(addr));
+    let len = fmt.count * fmt.size;
+    if len > MAX_DUMP_BYTES {
+        return Err(format!("Dump is limited to {} bytes", MAX_DUMP_BYTES));
+    }
+
+    let mut data = vec![0u8; len];
+    let mut done = 0;
+    while done < len {
+        let n = target.read_memory(addr + done as u64, &mut data[done..]);
+        if n == 0 {
+            return Err(format!("Cannot access memory at 0x{:x}", addr + done as u64));
+        }
+        done += n;
+    }
+
+    let line_size = if fmt.size == 1 { 8 } else { 16 };
+    let mut out = String::new();
+    for (i, unit) in data.chunks(fmt.size).enumerate() {
+        let off = i * fmt.size;
+        if off % line_size == 0 {
+            if off != 0 {
+                out.push('\n');
+            }
+            out.push_str(&format!("{:016x}:", addr + off as u64));
+        }
+
+        let val = unit.iter().rev().fold(0u64, |val, b| (val << 8) | *b as u64);
+        out.push_str(&match fmt.format {
+            'x' => format!(" 0x{:0width$x}", val, width = fmt.size * 2),
+            'd' => {
+                let shift = 64 - fmt.size * 8;
+                format!(" {}", ((val << shift) as i64) >> shift)
+            },
+            _ => format!(" {}", val),
+        });
+    }
+
+    Ok(out)
+}
+
+fn cmd_info_registers(ctx: &mut MonitorContext, _: &[&str]) -> Result<String, String>
+{
+    let regs = ctx.target.registers();
+    let lines: Vec<String> = regs.chunks(4).map(|line| {
+        let regs: Vec<String> = line.iter().map(|&(name, val)| format!("{:<3}={:08x}", name, val)).collect();
+        regs.join(" ")
+    }).collect();
+    Ok(lines.join("\n"))
+}
+
+fn cmd_info_pic(ctx: &mut MonitorContext, _: &[&str]) -> Result<String, String>
+{
+    let chips = match ctx.target.pic_state() {
+        Some(chips) => chips,
+        None => return Err(String::from("No PIC")),
+    };
+
+    let lines: Vec<String> = chips.iter().enumerate().map(|(i, chip)| format!("pic{}: {}", i, chip)).collect();
+    Ok(lines.join("\n"))
+}
+
+fn cmd_info_ioports(ctx: &mut MonitorContext, _: &[&str]) -> Result<String, String>
+{
+    let mut regions = ctx.target.io_regions();
+    regions.sort();
+
+    let lines: Vec<String> = regions.iter().map(|&(base, size)| {
+        format!("{:04x}-{:04x}", base, base as u32 + size.max(1) as u32 - 1)
+    }).collect();
+    Ok(lines.join("\n"))
+}
+
+fn cmd_info_irq(ctx: &mut MonitorContext, _: &[&str]) -> Result<String, String>
+{
+    let lines: Vec<String> = ctx.target.irq_counts().iter().enumerate()
+        .filter(|&(_, count)| *count != 0)
+        .map(|(irq, count)| format!("IRQ {:>2}: {}", irq, count))
+        .collect();
+
+    if lines.is_empty() {
+        return Ok(String::from("No IRQ asserted"));
+    }
+    Ok(lines.join("\n"))
+}
+
+/* Guest paging is not walked, so virtual addresses are linear ones, which are physical */
+fn cmd_x(ctx: &mut MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    dump_memory(ctx.target, args)
+}
+
+fn cmd_xp(ctx: &mut MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    if args.iter().any(|arg| arg.contains(':')) {
+        return Err(String::from("xp takes a physical address"));
+    }
+    dump_memory(ctx.target, args)
+}
+
+fn cmd_info_breakpoints(ctx: &mut MonitorContext, _: &[&str]) -> Result<String, String>
+{
+    let breakpoints = ctx.target.breakpoints();
+    let symbols = ctx.target.symbols();
+    let mut lines: Vec<(u32, String)> = breakpoints.iter().map(|bp| {
+        let kind = match bp.kind {
+            breakpoint::BreakpointKind::Int3 => "int3",
+            breakpoint::BreakpointKind::ExecProtect => "exec",
+        };
+        (bp.handle.0, format!("{:>3}: {} at {}", bp.handle.0, kind, describe_address(symbols, bp.gpa)))
+    }).collect();
+
+    for bp in ctx.target.io_breakpoints() {
+        let kind = match bp.direction {
+            breakpoint::IoDirection::Read => "io read",
+            breakpoint::IoDirection::Write => "io write",
+            breakpoint::IoDirection::Any => "io",
+        };
+        let ports = if bp.ports.len() == 1 {
+            format!("0x{:x}", bp.ports.start)
+        } else {
+            format!("0x{:x}-0x{:x}", bp.ports.start, bp.ports.end - 1)
+        };
+        lines.push((bp.handle.0, format!("{:>3}: {} at {}", bp.handle.0, kind, ports)));
+    }
+
+    for bp in ctx.target.vector_breakpoints() {
+        lines.push((bp.handle.0, format!("{:>3}: vector 0x{:02x}", bp.handle.0, bp.vec)));
+    }
+
+    if lines.is_empty() {
+        return Ok(String::from("No breakpoints"));
+    }
+    lines.sort();
+    Ok(lines.into_iter().map(|(_, line)| line).collect::<Vec<_>>().join("\n"))
+}
+
+fn cmd_info_status(ctx: &mut MonitorContext, _: &[&str]) -> Result<String, String>
+{
+    Ok(match (ctx.run_state, &ctx.stop_reason) {
+        (RunState::Running, _) => String::from("VM status: running"),
+        (_, &Some(ref reason)) => format!("VM status: paused ({})", reason),
+        (_, &None) => String::from("VM status: paused"),
+    })
+}
+
+fn add_breakpoint(ctx: &mut MonitorContext, args: &[&str], kind: breakpoint::BreakpointKind) -> Result<String, String>
+{
+    if args.len() != 1 {
+        return Err(String::from("Expected address"));
+    }
+
+    let addr = try!(parse_location(ctx.target.symbols(), args[0]));
+    let handle = try!(ctx.target.add_breakpoint(addr, kind));
+    Ok(format!("Breakpoint {} at {}", handle.0, describe_address(ctx.target.symbols(), addr)))
+}
+
+fn cmd_break(ctx: &mut MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    add_breakpoint(ctx, args, breakpoint::BreakpointKind::Int3)
+}
+
+fn cmd_hbreak(ctx: &mut MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    add_breakpoint(ctx, args, breakpoint::BreakpointKind::ExecProtect)
+}
+
+fn cmd_iobreak(ctx: &mut MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    if args.len() != 1 {
+        return Err(String::from("Expected port[-last][,r|w]"));
+    }
+
+    let (ports, direction) = try!(breakpoint::parse_io_breakpoint(args[0]));
+    let handle = try!(ctx.target.add_io_breakpoint(ports, direction));
+    Ok(format!("Breakpoint {} at {}", handle.0, args[0]))
+}
+
+fn cmd_vbreak(ctx: &mut MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    if args.len() != 1 {
+        return Err(String::from("Expected interrupt vector"));
+    }
+
+    let vec = try!(breakpoint::parse_vector(args[0]));
+    let handle = try!(ctx.target.add_vector_breakpoint(vec));
+    Ok(format!("Breakpoint {} at vector 0x{:02x}", handle.0, vec))
+}
+
+fn cmd_delete(ctx: &mut MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    if args.len() != 1 {
+        return Err(String::from("Expected breakpoint number"));
+    }
+
+    let num = try!(args[0].parse::<u32>().map_err(|_| format!("Bad breakpoint number {}", args[0])));
+    if !ctx.target.remove_breakpoint(breakpoint::BreakpointHandle(num)) {
+        return Err(format!("No breakpoint {}", num));
+    }
+    Ok(String::new())
+}
+
+fn cmd_info_symbols(ctx: &mut MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    let symbols = ctx.target.symbols();
+    if symbols.list().is_empty() {
+        return Ok(String::from("No symbols loaded"));
+    }
+
+    match args.len() {
+        0 => Ok(symbols.list().iter().map(|symbol| format!("{:08x}  {}", symbol.addr, symbol.name)).collect::<Vec<_>>().join("\n")),
+        1 => match parse_address(args[0]) {
+            Ok(addr) => symbols.describe(addr).ok_or(format!("No symbol at 0x{:x}", addr)),
+            Err(_) => symbols.lookup(args[0]).map(|addr| format!("0x{:x}", addr)).ok_or(format!("No symbol {}", args[0])),
+        },
+        _ => Err(String::from("Expected optional address or symbol name")),
+    }
+}
+
+fn cmd_irq(ctx: &mut MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    if args.len() != 1 {
+        return Err(String::from("Expected IRQ line"));
+    }
+
+    let irq = try!(args[0].parse::<u8>().map_err(|_| format!("Bad IRQ line {}", args[0])));
+    let outcome = try!(ctx.target.inject_irq(irq));
+    match outcome.vector() {
+        Some(vec) => Ok(format!("IRQ {} as vector 0x{:02x}: {}", irq, vec, outcome)),
+        None => Ok(format!("IRQ {}: {}", irq, outcome)),
+    }
+}
+
+fn cmd_irq_raw(ctx: &mut MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    if args.len() != 1 {
+        return Err(String::from("Expected interrupt vector"));
+    }
+
+    let vec = try!(breakpoint::parse_vector(args[0]));
+    Ok(format!("Vector 0x{:02x}: {}", vec, ctx.target.inject_vector(vec)))
+}
+
+fn cmd_nmi(ctx: &mut MonitorContext, _: &[&str]) -> Result<String, String>
+{
+    Ok(format!("NMI: {}", ctx.target.inject_nmi()))
+}
+
+fn cmd_setmem(ctx: &mut MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    let force = args.first() == Some(&"-f");
+    let args = if force { &args[1..] } else { args };
+    if args.len() < 2 {
+        return Err(String::from("Expected address and bytes"));
+    }
+
+    let addr = try!(parse_hex_address(args[0]));
+    let mut data = Vec::new();
+    for arg in &args[1..] {
+        match parse_hex(arg) {
+            Ok(byte) if byte <= 0xFF => data.push(byte as u8),
+            _ => return Err(format!("Bad byte {}", arg)),
+        }
+    }
+
+    let old = try!(ctx.target.patch_memory(addr, &data, force));
+    let old: Vec<String> = old.iter().map(|byte| format!("{:02x}", byte)).collect();
+    Ok(format!("Replaced at 0x{:x}: {}", addr, old.join(" ")))
+}
+
+fn cmd_setreg(ctx: &mut MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    if args.len() != 2 {
+        return Err(String::from("Expected register name and value"));
+    }
+
+    if ctx.run_state != RunState::Stopped {
+        return Err(String::from("Registers can only be set with guest stopped, stop it first"));
+    }
+
+    let val = try!(parse_hex(args[1]));
+    let old = try!(ctx.target.set_register(args[0], val));
+    Ok(format!("{} was 0x{:x}", args[0].to_uppercase(), old))
+}
+
+fn cmd_stop(ctx: &mut MonitorContext, _: &[&str]) -> Result<String, String>
+{
+    ctx.run_state = RunState::Stopped;
+    Ok(String::new())
+}
+
+fn cmd_cont(ctx: &mut MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    ctx.skip_vector = args == ["skip"];
+    ctx.resume_value = match args.len() {
+        0 => None,
+        1 if ctx.skip_vector => None,
+        1 => Some(try!(parse_number(args[0])) as u32),
+        _ => return Err(String::from("Expected optional value or skip")),
+    };
+    ctx.run_state = RunState::Running;
+    ctx.stop_reason = None;
+    Ok(String::new())
+}
+
+fn cmd_quit(ctx: &mut MonitorContext, _: &[&str]) -> Result<String, String>
+{
+    ctx.run_state = RunState::Quit;
+    Ok(String::new())
+}
+
+const BUILTIN_COMMANDS: [MonitorCommand; 22] = [
+    MonitorCommand { name: "info registers", args: "", help: "show vcpu registers", handler: cmd_info_registers },
+    MonitorCommand { name: "info pic", args: "", help: "show PIC state", handler: cmd_info_pic },
+    MonitorCommand { name: "info ioports", args: "", help: "show I/O port regions", handler: cmd_info_ioports },
+    MonitorCommand { name: "info irq", args: "", help: "show IRQ line assertion counts", handler: cmd_info_irq },
+    MonitorCommand { name: "info breakpoints", args: "", help: "list breakpoints", handler: cmd_info_breakpoints },
+    MonitorCommand { name: "info status", args: "", help: "show whether guest runs", handler: cmd_info_status },
+    MonitorCommand { name: "info symbols", args: "[addr|name]", help: "list guest symbols, or look one up", handler: cmd_info_symbols },
+    MonitorCommand { name: "x", args: "[/fmt] addr", help: "dump memory at linear or seg:off address", handler: cmd_x },
+    MonitorCommand { name: "xp", args: "[/fmt] addr", help: "dump memory at physical address", handler: cmd_xp },
+    MonitorCommand { name: "setmem", args: "[-f] addr byte...", help: "write hex bytes to memory, -f writes ROM too", handler: cmd_setmem },
+    MonitorCommand { name: "setreg", args: "reg value", help: "set register of stopped guest to hex value", handler: cmd_setreg },
+    MonitorCommand { name: "break", args: "addr|symbol", help: "stop guest at instruction address with INT3", handler: cmd_break },
+    MonitorCommand { name: "hbreak", args: "addr|symbol", help: "stop guest at address by execute protection, e.g. in ROM", handler: cmd_hbreak },
+    MonitorCommand { name: "iobreak", args: "port[-last][,r|w]", help: "stop guest at port access", handler: cmd_iobreak },
+    MonitorCommand { name: "vbreak", args: "vec", help: "stop guest before interrupt vector is injected", handler: cmd_vbreak },
+    MonitorCommand { name: "delete", args: "n", help: "remove breakpoint", handler: cmd_delete },
+    MonitorCommand { name: "irq", args: "n", help: "assert IRQ line through interrupt controller", handler: cmd_irq },
+    MonitorCommand { name: "irq-raw", args: "vec", help: "inject interrupt vector bypassing interrupt controller", handler: cmd_irq_raw },
+    MonitorCommand { name: "nmi", args: "", help: "inject NMI", handler: cmd_nmi },
+    MonitorCommand { name: "stop", args: "", help: "stop guest", handler: cmd_stop },
+    MonitorCommand { name: "cont", args: "[value|skip]", help: "resume guest, value replaces port access at I/O breakpoint, skip drops stopped vector", handler: cmd_cont },
+    MonitorCommand { name: "quit", args: "", help: "shut VM down", handler: cmd_quit },
+];
+
+fn help(commands: &[&MonitorCommand]) -> String
+{
+    let lines: Vec<String> = commands.iter().map(|cmd| {
+        let usage = if cmd.args.is_empty() { String::from(cmd.name) } else { format!("{} {}", cmd.name, cmd.args) };
+        format!("{:<24} -- {}", usage, cmd.help)
+    }).collect();
+    lines.join("\n")
+}
+
+/* Run command line using table of known commands, the one with most matching words wins */
+fn dispatch(table: &[MonitorCommand], ctx: &mut MonitorContext, line: &str) -> String
+{
+    let words: Vec<&str> = line.split_whitespace().collect();
+    if words.is_empty() {
+        return String::new();
+    }
+
+    if words[0] == "help" {
+        return help(&table.iter().collect::<Vec<_>>());
+    }
+
+    let mut best: Option<(&MonitorCommand, usize)> = None;
+    for cmd in table {
+        let name: Vec<&str> = cmd.name.split(' ').collect();
+        if words.starts_with(&name) && best.map_or(true, |(_, len)| name.len() > len) {
+            best = Some((cmd, name.len()));
+        }
+    }
+
+    match best {
+        Some((cmd, len)) => match (cmd.handler)(ctx, &words[len..]) {
+            Ok(out) => out,
+            Err(err) => format!("Error: {}", err),
+        },
+        None => {
+            /* A command group like "info" lists what it has */
+            let prefix = format!("{} ", words[0]);
+            let group: Vec<&MonitorCommand> = table.iter().filter(|cmd| cmd.name.starts_with(&prefix)).collect();
+            if group.is_empty() {
+                format!("Error: unknown command {}", words[0])
+            } else {
+                help(&group)
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod monitor_test
+{
+    use super::*;
+
+    /* VM with fixed state and 64K of memory */
+    struct ScriptedVm
+    {
+        mem: Vec<u8>,           // ROM from SCRIPTED_ROM up
+        regs: vm::VcpuState,
+        breakpoints: breakpoint::BreakpointTable,
+        symbols: symbols::SymbolTable,
+    }
+
+    impl monitor_target for ScriptedVm
+    {
+        fn registers(&mut self) -> Vec<(&'static str, u64)> {
+            let s = self.regs;
+            vec![("EAX", s.rax), ("EBX", s.rbx), ("ECX", s.rcx), ("EDX", s.rdx), ("EIP", s.rip), ("CS", s.cs.selector as u64)]
+        }
+
+        fn pic_state(&mut self) -> Option<[pic::I8259State; 2]> {
+            Some([
+                pic::I8259State { irr: 0x01, isr: 0x00, imr: 0xB8, offset: 0x08, initialized: true, output: true },
+                pic::I8259State { irr: 0x00, isr: 0x00, imr: 0xFF, offset: 0x70, initialized: false, output: true },
+            ])
+        }
+
+        fn io_regions(&mut self) -> Vec<(u16, u8)> {
+            vec![(0x60, 1), (0x20, 2), (0x3F8, 8)]
+        }
+
+        fn irq_counts(&mut self) -> Vec<u64> {
+            let mut counts = vec![0; vm::IRQ_LINES];
+            counts[0] = 100;
+            counts[14] = 3;
+            counts
+        }
+
+        fn read_memory(&mut self, addr: u64, buf: &mut [u8]) -> usize {
+            if addr >= self.mem.len() as u64 {
+                return 0;
+            }
+            let len = buf.len().min(self.mem.len() - addr as usize);
+            buf[..len].copy_from_slice(&self.mem[addr as usize..addr as usize + len]);
+            len
+        }
+
+        fn breakpoints(&mut self) -> Vec<breakpoint::Breakpoint> {
+            self.breakpoints.list().to_vec()
+        }
+
+        fn add_breakpoint(&mut self, addr: u64, kind: breakpoint::BreakpointKind) -> Result<breakpoint::BreakpointHandle, String> {
+            if addr >= self.mem.len() as u64 {
+                return Err(format!("No memory at 0x{:x}", addr));
+            }
+            self.breakpoints.add(addr, kind, self.mem[addr as usize])
+        }
+
+        fn remove_breakpoint(&mut self, handle: breakpoint::BreakpointHandle) -> bool {
+            self.breakpoints.remove(handle).is_some() || self.breakpoints.remove_io(handle).is_some() ||
+                self.breakpoints.remove_vector(handle).is_some()
+        }
+
+        fn io_breakpoints(&mut self) -> Vec<breakpoint::IoBreakpoint> {
+            self.breakpoints.io_list().to_vec()
+        }
+
+        fn add_io_breakpoint(&mut self, ports: Range<u16>, direction: breakpoint::IoDirection) -> Result<breakpoint::BreakpointHandle, String> {
+            self.breakpoints.add_io(ports, direction)
+        }
+
+        fn vector_breakpoints(&mut self) -> Vec<breakpoint::VectorBreakpoint> {
+            self.breakpoints.vector_list().to_vec()
+        }
+
+        fn add_vector_breakpoint(&mut self, vec: u8) -> Result<breakpoint::BreakpointHandle, String> {
+            self.breakpoints.add_vector(vec)
+        }
+
+        fn symbols(&mut self) -> &symbols::SymbolTable {
+            &self.symbols
+        }
+
+        /* PIC as pic_state() has it, IRQ 0 already latched, guest with interrupts disabled */
+        fn inject_irq(&mut self, irq: u8) -> Result<inject::InjectOutcome, String> {
+            match irq {
+                0 => Ok(inject::InjectOutcome::Latched),
+                1 | 2 | 6 => Ok(inject::InjectOutcome::Queued(0x08 + irq)),
+                3...15 => Ok(inject::InjectOutcome::Masked),
+                _ => Err(format!("No IRQ line {}", irq)),
+            }
+        }
+
+        fn inject_vector(&mut self, vec: u8) -> inject::InjectOutcome {
+            inject::InjectOutcome::Queued(vec)
+        }
+
+        fn inject_nmi(&mut self) -> inject::InjectOutcome {
+            inject::InjectOutcome::Deliver(inject::NMI_VECTOR)
+        }
+
+        fn patch_memory(&mut self, addr: u64, data: &[u8], force: bool) -> Result<Vec<u8>, String> {
+            let end = addr + data.len() as u64;
+            if end > self.mem.len() as u64 {
+                return Err(format!("No memory at 0x{:x}", addr.max(self.mem.len() as u64)));
+            }
+            if end > SCRIPTED_ROM && !force {
+                return Err(format!("0x{:x} is in ROM, force to write it", addr.max(SCRIPTED_ROM)));
+            }
+
+            let range = addr as usize..end as usize;
+            let old = self.mem[range.clone()].to_vec();
+            self.mem[range].copy_from_slice(data);
+            Ok(old)
+        }
+
+        fn set_register(&mut self, name: &str, val: u64) -> Result<u64, String> {
+            let (old, update) = try!(vm::register_update(name, val, &self.regs));
+            self.regs.rax = update.rax.unwrap_or(self.regs.rax);
+            self.regs.rip = update.rip.unwrap_or(self.regs.rip);
+            self.regs.cs = update.cs.unwrap_or(self.regs.cs);
+            Ok(old)
+        }
+    }
+
+    const SCRIPTED_ROM: u64 = 0xF000;
+
+    fn scripted_vm() -> ScriptedVm {
+        ScriptedVm {
+            mem: (0..0x10000).map(|i| i as u8).collect(),
+            regs: vm::VcpuState { rax: 0x1234, rcx: 0xFFFF, rdx: 0x80, rip: 0x7C00, ..Default::default() },
+            breakpoints: breakpoint::BreakpointTable::new(),
+            symbols: symbols::SymbolTable::new(),
+        }
+    }
+
+    fn run(table: &[MonitorCommand], vm: &mut ScriptedVm, line: &str) -> (String, RunState) {
+        let mut ctx = MonitorContext {
+            target: vm,
+            run_state: RunState::Running,
+            stop_reason: None,
+            resume_value: None,
+            skip_vector: false,
+        };
+        let out = dispatch(table, &mut ctx, line);
+        (out, ctx.run_state)
+    }
+
+    fn output(vm: &mut ScriptedVm, line: &str) -> String {
+        run(&BUILTIN_COMMANDS, vm, line).0
+    }
+
+    #[test] fn parse() {
+        assert!(parse_number("0x7c00") == Ok(0x7C00));
+        assert!(parse_number("31744") == Ok(0x7C00));
+        assert!(parse_number("7c00").is_err());
+        assert!(parse_address("07c0:0010") == Ok(0x7C10));
+        assert!(parse_address("0x100") == Ok(0x100));
+        assert!(parse_address("x:0").is_err());
+
+        assert!(parse_dump_format("/16xb") == Ok(DumpFormat { count: 16, format: 'x', size: 1 }));
+        assert!(parse_dump_format("/2h") == Ok(DumpFormat { count: 2, format: 'x', size: 2 }));
+        assert!(parse_dump_format("/d") == Ok(DumpFormat { count: 1, format: 'd', size: 4 }));
+        assert!(parse_dump_format("/4q").is_err());
+    }
+
+    #[test] fn info() {
+        let mut vm = scripted_vm();
+        assert!(output(&mut vm, "info registers") ==
+                "EAX=00001234 EBX=00000000 ECX=0000ffff EDX=00000080\nEIP=00007c00 CS =00000000");
+        assert!(output(&mut vm, "info pic") ==
+                "pic0: irr=01 imr=b8 isr=00 vec=08 init=1 out=1\npic1: irr=00 imr=ff isr=00 vec=70 init=0 out=1");
+        assert!(output(&mut vm, "info ioports") == "0020-0021\n0060-0060\n03f8-03ff");
+        assert!(output(&mut vm, "info  irq") == "IRQ  0: 100\nIRQ 14: 3");
+
+        /* Group alone lists its commands */
+        let out = output(&mut vm, "info");
+        assert!(out.lines().count() == 7 && out.starts_with("info registers"));
+        assert!(output(&mut vm, "info bogus").lines().count() == 7);
+    }
+
+    #[test] fn memory_dump() {
+        let mut vm = scripted_vm();
+        assert!(output(&mut vm, "xp /4xb 0x7c00") == "0000000000007c00: 0x00 0x01 0x02 0x03");
+        assert!(output(&mut vm, "xp /10xb 0x10") ==
+                "0000000000000010: 0x10 0x11 0x12 0x13 0x14 0x15 0x16 0x17\n0000000000000018: 0x18 0x19");
+        assert!(output(&mut vm, "xp 0x100") == "0000000000000100: 0x03020100");
+        assert!(output(&mut vm, "xp /2xh 0x100") == "0000000000000100: 0x0100 0x0302");
+        assert!(output(&mut vm, "xp /2db 0xFE") == "00000000000000fe: -2 -1");
+        assert!(output(&mut vm, "xp /ug 0x0") == "0000000000000000: 506097522914230528");
+        assert!(output(&mut vm, "x /2xb 0010:0002") == "0000000000000102: 0x02 0x03");
+
+        assert!(output(&mut vm, "xp /4xb 0xfffe") == "Error: Cannot access memory at 0x10000");
+        assert!(output(&mut vm, "xp /2xb 0010:0002") == "Error: xp takes a physical address");
+        assert!(output(&mut vm, "xp /5000xb 0").starts_with("Error: "));
+        assert!(output(&mut vm, "xp").starts_with("Error: "));
+    }
+
+    #[test] fn run_state() {
+        let mut vm = scripted_vm();
+        assert!(run(&BUILTIN_COMMANDS, &mut vm, "stop") == (String::new(), RunState::Stopped));
+        assert!(run(&BUILTIN_COMMANDS, &mut vm, "cont") == (String::new(), RunState::Running));
+        assert!(run(&BUILTIN_COMMANDS, &mut vm, "quit") == (String::new(), RunState::Quit));
+        assert!(run(&BUILTIN_COMMANDS, &mut vm, "info pic").1 == RunState::Running);
+    }
+
+    #[test] fn inject() {
+        let mut vm = scripted_vm();
+        assert!(output(&mut vm, "irq 1") == "IRQ 1 as vector 0x09: queued until guest can take it");
+        assert!(output(&mut vm, "irq 0") == "IRQ 0: latched by interrupt controller, no vector raised yet");
+        assert!(output(&mut vm, "irq 4") == "IRQ 4: suppressed by interrupt controller mask");
+        assert!(output(&mut vm, "irq 16") == "Error: No IRQ line 16");
+        assert!(output(&mut vm, "irq x").starts_with("Error: "));
+
+        /* Longer name wins over "irq" */
+        assert!(output(&mut vm, "irq-raw 0x30") == "Vector 0x30: queued until guest can take it");
+        assert!(output(&mut vm, "irq-raw").starts_with("Error: "));
+        assert!(run(&BUILTIN_COMMANDS, &mut vm, "nmi") == (String::from("NMI: delivered at next entry"), RunState::Running));
+    }
+
+    #[test] fn status() {
+        let mut vm = scripted_vm();
+        assert!(output(&mut vm, "info status") == "VM status: running");
+
+        let mut ctx = MonitorContext {
+            target: &mut vm,
+            run_state: RunState::Stopped,
+            stop_reason: None,
+            resume_value: None,
+            skip_vector: false,
+        };
+        assert!(dispatch(&BUILTIN_COMMANDS, &mut ctx, "info status") == "VM status: paused");
+        ctx.stop_reason = Some(String::from("breakpoint 1 at 0x7c05"));
+        assert!(dispatch(&BUILTIN_COMMANDS, &mut ctx, "info status") == "VM status: paused (breakpoint 1 at 0x7c05)");
+
+        /* Continuing forgets why VM stopped, it may give value for port access */
+        dispatch(&BUILTIN_COMMANDS, &mut ctx, "cont");
+        assert!(ctx.run_state == RunState::Running && ctx.stop_reason.is_none() && ctx.resume_value.is_none());
+        ctx.run_state = RunState::Stopped;
+        assert!(dispatch(&BUILTIN_COMMANDS, &mut ctx, "cont 0x5a") == "");
+        assert!(ctx.run_state == RunState::Running && ctx.resume_value == Some(0x5A));
+        assert!(dispatch(&BUILTIN_COMMANDS, &mut ctx, "cont x").starts_with("Error: "));
+
+        ctx.run_state = RunState::Stopped;
+        assert!(dispatch(&BUILTIN_COMMANDS, &mut ctx, "cont skip") == "");
+        assert!(ctx.run_state == RunState::Running && ctx.skip_vector);
+    }
+
+    #[test] fn setmem() {
+        let mut vm = scripted_vm();
+        assert!(output(&mut vm, "setmem 0x7c0a 55 0x0F") == "Replaced at 0x7c0a: 0a 0b");
+        assert!(output(&mut vm, "xp /2xb 0x7c0a") == "0000000000007c0a: 0x55 0x0f");
+        assert!(output(&mut vm, "setmem 07c0:000a 2a") == "Replaced at 0x7c0a: 55");
+        assert!(output(&mut vm, "setmem 7c0b e6") == "Replaced at 0x7c0b: 0f");
+
+        /* ROM takes force, nothing is written short of it */
+        assert!(output(&mut vm, "setmem 0xeffe 1 2 3") == "Error: 0xf000 is in ROM, force to write it");
+        assert!(output(&mut vm, "xp /1xb 0xeffe") == "000000000000effe: 0xfe");
+        assert!(output(&mut vm, "setmem -f 0xf000 90") == "Replaced at 0xf000: 00");
+        assert!(output(&mut vm, "setmem 0xffff 0 0") == "Error: No memory at 0x10000");
+
+        assert!(output(&mut vm, "setmem 0x7c00 100") == "Error: Bad byte 100");
+        assert!(output(&mut vm, "setmem 0x7c00 zz") == "Error: Bad byte zz");
+        assert!(output(&mut vm, "setmem 0x7c00").starts_with("Error: "));
+        assert!(output(&mut vm, "setmem -f").starts_with("Error: "));
+    }
+
+    #[test] fn setreg() {
+        let mut vm = scripted_vm();
+        assert!(output(&mut vm, "setreg eax 0x55") == "Error: Registers can only be set with guest stopped, stop it first");
+
+        {
+            let mut ctx = MonitorContext {
+                target: &mut vm,
+                run_state: RunState::Stopped,
+                stop_reason: None,
+                resume_value: None,
+                skip_vector: false,
+            };
+            assert!(dispatch(&BUILTIN_COMMANDS, &mut ctx, "setreg eax 55") == "EAX was 0x1234");
+            assert!(dispatch(&BUILTIN_COMMANDS, &mut ctx, "setreg EIP 0x7c09") == "EIP was 0x7c00");
+            assert!(dispatch(&BUILTIN_COMMANDS, &mut ctx, "setreg cs 7c0") == "CS was 0x0");
+            assert!(dispatch(&BUILTIN_COMMANDS, &mut ctx, "setreg cs 10000").starts_with("Error: "));
+            assert!(dispatch(&BUILTIN_COMMANDS, &mut ctx, "setreg cr0 0") == "Error: Can't set register CR0");
+            assert!(dispatch(&BUILTIN_COMMANDS, &mut ctx, "setreg eax").starts_with("Error: "));
+        }
+
+        assert!(output(&mut vm, "info registers") ==
+                "EAX=00000055 EBX=00000000 ECX=0000ffff EDX=00000080\nEIP=00007c09 CS =000007c0");
+        assert!(vm.regs.cs.base == 0x7C00);
+    }
+
+    #[test] fn breakpoints() {
+        let mut vm = scripted_vm();
+        assert!(output(&mut vm, "info breakpoints") == "No breakpoints");
+        assert!(output(&mut vm, "break 0x7c05") == "Breakpoint 1 at 0x7c05");
+        assert!(output(&mut vm, "hbreak f000:fff0") == "Error: No memory at 0xffff0");
+        assert!(output(&mut vm, "hbreak 0100:0010") == "Breakpoint 2 at 0x1010");
+        assert!(output(&mut vm, "break 07c0:0005") == "Error: Breakpoint already set at 0x7c05");
+        assert!(output(&mut vm, "info breakpoints") == "  1: int3 at 0x7c05\n  2: exec at 0x1010");
+        assert!(vm.breakpoints.find(0x7C05).unwrap().orig == 0x05);
+
+        assert!(output(&mut vm, "delete 1") == "");
+        assert!(output(&mut vm, "delete 1") == "Error: No breakpoint 1");
+        assert!(output(&mut vm, "delete x").starts_with("Error: "));
+        assert!(output(&mut vm, "break").starts_with("Error: "));
+        assert!(output(&mut vm, "info breakpoints") == "  2: exec at 0x1010");
+
+        /* I/O breakpoints share numbers and listing */
+        assert!(output(&mut vm, "iobreak 0x21,r") == "Breakpoint 3 at 0x21,r");
+        assert!(output(&mut vm, "iobreak 0x20-0x21") == "Breakpoint 4 at 0x20-0x21");
+        assert!(output(&mut vm, "iobreak 0x21,x").starts_with("Error: "));
+        assert!(output(&mut vm, "info breakpoints") == "  2: exec at 0x1010\n  3: io read at 0x21\n  4: io at 0x20-0x21");
+        assert!(output(&mut vm, "delete 3") == "");
+        assert!(output(&mut vm, "info breakpoints") == "  2: exec at 0x1010\n  4: io at 0x20-0x21");
+
+        /* Vector breakpoints as well */
+        assert!(output(&mut vm, "vbreak 0x08") == "Breakpoint 5 at vector 0x08");
+        assert!(output(&mut vm, "vbreak 8") == "Error: Breakpoint already set at vector 0x08");
+        assert!(output(&mut vm, "vbreak 0x100").starts_with("Error: "));
+        assert!(output(&mut vm, "info breakpoints") == "  2: exec at 0x1010\n  4: io at 0x20-0x21\n  5: vector 0x08");
+        assert!(output(&mut vm, "delete 5") == "");
+        assert!(output(&mut vm, "info breakpoints") == "  2: exec at 0x1010\n  4: io at 0x20-0x21");
+    }
+
+    #[test] fn symbols() {
+        let mut vm = scripted_vm();
+        assert!(output(&mut vm, "info symbols") == "No symbols loaded");
+        assert!(output(&mut vm, "break timer_isr") == "Error: Bad address or unknown symbol timer_isr");
+
+        /* Kernel linked at 0 and loaded at 0800:0000 */
+        vm.symbols.add(symbols::parse_map("00000000 T start\n00000010 00000030 T timer_isr\n", 0x8000).unwrap());
+        assert!(output(&mut vm, "break timer_isr") == "Breakpoint 1 at 0x8010 <timer_isr>");
+        assert!(output(&mut vm, "hbreak timer_isr+0x12") == "Breakpoint 2 at 0x8022 <timer_isr+0x12>");
+        assert!(output(&mut vm, "break 0x7c05") == "Breakpoint 3 at 0x7c05");
+        assert!(vm.breakpoints.find(0x8010).unwrap().orig == 0x10);
+        assert!(vm.breakpoints.find(0x8022).is_some());
+        assert!(output(&mut vm, "info breakpoints") ==
+                "  1: int3 at 0x8010 <timer_isr>\n  2: exec at 0x8022 <timer_isr+0x12>\n  3: int3 at 0x7c05");
+        assert!(output(&mut vm, "break idle").starts_with("Error: "));
+        assert!(output(&mut vm, "break timer_isr+x").starts_with("Error: "));
+
+        assert!(output(&mut vm, "info symbols") == "00008000  start\n00008010  timer_isr");
+        assert!(output(&mut vm, "info symbols 0x8022") == "timer_isr+0x12");
+        assert!(output(&mut vm, "info symbols 0800:0040") == "Error: No symbol at 0x8040");
+        assert!(output(&mut vm, "info symbols timer_isr") == "0x8010");
+        assert!(output(&mut vm, "info symbols idle") == "Error: No symbol idle");
+    }
+
+    fn cmd_echo(_: &mut MonitorContext, args: &[&str]) -> Result<String, String> {
+        Ok(args.join(","))
+    }
+
+    #[test] fn registry() {
+        let mut vm = scripted_vm();
+        let mut table = BUILTIN_COMMANDS.to_vec();
+        table.push(MonitorCommand { name: "info echo", args: "words", help: "echo words", handler: cmd_echo });
+
+        assert!(run(&table, &mut vm, "info echo a b").0 == "a,b");
+        assert!(run(&table, &mut vm, "info").0.lines().count() == 8);
+        assert!(run(&table, &mut vm, "help").0.lines().count() == table.len());
+        assert!(run(&table, &mut vm, "help").0.contains("info echo words"));
+        assert!(run(&table, &mut vm, "bogus").0 == "Error: unknown command bogus");
+        assert!(run(&table, &mut vm, "  ").0 == "");
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+static mut COMMANDS: Option<*mut Vec<MonitorCommand>> = None;
+
+/**
+ * Add monitor command, works whether or not monitor is enabled
+ */
+pub fn register_command(cmd: MonitorCommand)
+{
+    unsafe {
+        if COMMANDS.is_none() {
+            COMMANDS = Some(Box::into_raw(Box::new(BUILTIN_COMMANDS.to_vec())));
+        }
+
+        let table = &mut *COMMANDS.unwrap();
+        assert!(table.iter().all(|known| known.name != cmd.name));
+        table.push(cmd);
+    }
+}
+
+fn commands() -> &'static [MonitorCommand]
+{
+    unsafe {
+        match COMMANDS {
+            Some(table) => &*table,
+            None => &BUILTIN_COMMANDS,
+        }
+    }
+}
+
+/* Command line from a console and where its output goes */
+struct MonitorRequest
+{
+    line: String,
+    reply: Sender<String>,
+}
+
+struct MonitorServer
+{
+    requests: Receiver<MonitorRequest>,
+    run_state: RunState,
+    stop_reason: Option<String>,
+    resume_value: Option<u32>,
+    skip_vector: bool,
+}
+
+static mut MONITOR: Option<*mut MonitorServer> = None;
+
+lazy_static! {
+    static ref ATTENTION: AtomicBool = AtomicBool::new(false);
+}
+
+fn get_server() -> &'static mut MonitorServer
+{
+    unsafe { &mut *MONITOR.unwrap() }
+}
+
+impl MonitorServer
+{
+    fn execute(&mut self, target: &mut monitor_target, req: MonitorRequest) {
+        let mut ctx = MonitorContext {
+            target: target,
+            run_state: self.run_state,
+            stop_reason: self.stop_reason.take(),
+            resume_value: self.resume_value,
+            skip_vector: self.skip_vector,
+        };
+        let out = dispatch(commands(), &mut ctx, &req.line);
+        self.run_state = ctx.run_state;
+        self.stop_reason = ctx.stop_reason;
+        self.resume_value = ctx.resume_value;
+        self.skip_vector = ctx.skip_vector;
+        let _ = req.reply.send(out);
+    }
+
+    /* Keep serving commands while VM is stopped, then shut VM down if asked to */
+    fn run_stopped(&mut self, target: &mut monitor_target) {
+        if self.run_state == RunState::Stopped {
+            vm::hold(|| {
+                while self.run_state == RunState::Stopped {
+                    match self.requests.recv() {
+                        Ok(req) => self.execute(target, req),
+                        Err(_) => self.run_state = RunState::Running,
+                    }
+                }
+            });
+        }
+
+        if self.run_state == RunState::Quit {
+            vm::request_vm_exit(vm::VmExit::MonitorQuit);
+        }
+    }
+}
+
+/* Read command lines and print their output until input ends or VM quits */
+fn console<R: BufRead, W: Write>(input: R, mut output: W, requests: Sender<MonitorRequest>)
+{
+    let (reply_tx, reply_rx) = mpsc::channel();
+    let _ = write!(output, "{}", PROMPT).and_then(|_| output.flush());
+
+    for line in input.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+
+        if requests.send(MonitorRequest { line: line, reply: reply_tx.clone() }).is_err() {
+            return;
+        }
+        ATTENTION.store(true, Ordering::SeqCst);
+        vm::interrupt_guest();
+
+        let out = match reply_rx.recv() {
+            Ok(out) => out,
+            Err(_) => return,
+        };
+
+        let res = if out.is_empty() {
+            write!(output, "{}", PROMPT)
+        } else {
+            write!(output, "{}\n{}", out, PROMPT)
+        };
+
+        if res.and_then(|_| output.flush()).is_err() {
+            return;
+        }
+    }
+}
+
+/**
+ * Start monitor console if configured
+ */
+pub fn init(config: &config::VmConfig)
+{
+    let (requests_tx, requests_rx) = mpsc::channel();
+    match config.monitor {
+        Some(config::MonitorConfig::Stdio) => {
+            thread::spawn(move || {
+                let stdin = io::stdin();
+                let input = stdin.lock();
+                console(input, io::stdout(), requests_tx);
+            });
+        },
+        Some(config::MonitorConfig::Tcp(port)) => {
+            let listener = match TcpListener::bind(("127.0.0.1", port)) {
+                Ok(listener) => listener,
+                Err(err) => {
+                    error!("Can't listen for monitor on port {}: {}", port, err);
+                    ::std::process::exit(1);
+                }
+            };
+
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let (input, output) = match stream.and_then(|s| s.try_clone().map(|clone| (s, clone))) {
+                        Ok(streams) => streams,
+                        Err(err) => {
+                            warn!("Monitor connection failed: {}", err);
+                            continue;
+                        },
+                    };
+
+                    let requests = requests_tx.clone();
+                    thread::spawn(move || console(BufReader::new(input), output, requests));
+                }
+            });
+        },
+        None => return,
+    }
+
+    let server = Box::new(MonitorServer {
+        requests: requests_rx,
+        run_state: RunState::Running,
+        stop_reason: None,
+        resume_value: None,
+        skip_vector: false,
+    });
+    unsafe {
+        MONITOR = Some(Box::into_raw(server));
+    }
+}
+
+/**
+ * Monitor console is configured
+ */
+pub fn enabled() -> bool
+{
+    unsafe { MONITOR.is_some() }
+}
+
+/**
+ * Console sent commands, checked by vcpu loop after each exit
+ */
+pub fn attention_requested() -> bool
+{
+    enabled() && ATTENTION.load(Ordering::SeqCst)
+}
+
+/**
+ * Run queued commands on vcpu thread, VM stays here while a command stopped it
+ */
+pub fn serve(target: &mut monitor_target)
+{
+    ATTENTION.store(false, Ordering::SeqCst);
+    let server = get_server();
+
+    while let Ok(req) = server.requests.try_recv() {
+        server.execute(target, req);
+    }
+    server.run_stopped(target);
+}
+
+/**
+ * Guest hit breakpoint at gpa, VM stays stopped here serving commands until continued
+ */
+pub fn breakpoint_hit(target: &mut monitor_target, gpa: u64)
+{
+    let server = get_server();
+    let addr = describe_address(target.symbols(), gpa);
+    let reason = match target.breakpoints().iter().find(|bp| bp.gpa == gpa) {
+        Some(bp) => format!("breakpoint {} at {}", bp.handle.0, addr),
+        None => format!("breakpoint at {}", addr),
+    };
+    println!("Stopped at {}", reason);
+
+    server.run_state = RunState::Stopped;
+    server.stop_reason = Some(reason);
+    server.run_stopped(target);
+}
+
+/**
+ * VM stopped on its own for reason, e.g. reverse execution got where it was going, VM stays stopped here
+ * serving commands until continued
+ */
+pub fn stopped(target: &mut monitor_target, reason: String)
+{
+    let server = get_server();
+    println!("Stopped at {}", reason);
+
+    server.run_state = RunState::Stopped;
+    server.stop_reason = Some(reason);
+    server.run_stopped(target);
+}
+
+/**
+ * External interrupt vector about to be injected hit vector breakpoint, VM stays stopped here serving commands
+ * until continued. Returns false when cont skipped the vector.
+ */
+pub fn vector_breakpoint_hit(target: &mut monitor_target, vec: u8) -> bool
+{
+    let server = get_server();
+    let reason = match target.vector_breakpoints().iter().find(|bp| bp.vec == vec) {
+        Some(bp) => format!("vector breakpoint {} at 0x{:02x}", bp.handle.0, vec),
+        None => format!("vector breakpoint at 0x{:02x}", vec),
+    };
+    println!("Stopped at {}", reason);
+
+    server.run_state = RunState::Stopped;
+    server.stop_reason = Some(reason);
+    server.skip_vector = false;
+    server.run_stopped(target);
+    !mem::replace(&mut server.skip_vector, false)
+}
+
+/**
+ * Guest port access hit I/O breakpoint, VM stays stopped here serving commands until continued
+ * Returns value cont gave to use for the access instead of guest's.
+ */
+pub fn io_breakpoint_hit(target: &mut monitor_target, port: u16, direction: breakpoint::IoDirection, value: u32) -> Option<u32>
+{
+    let server = get_server();
+    let num = match target.io_breakpoints().iter().find(|bp| bp.matches(port, 1, direction)) {
+        Some(bp) => format!(" {}", bp.handle.0),
+        None => String::new(),
+    };
+    let reason = match direction {
+        breakpoint::IoDirection::Write => format!("I/O breakpoint{}: write 0x{:x} to port 0x{:x}", num, value, port),
+        _ => format!("I/O breakpoint{}: read from port 0x{:x}", num, port),
+    };
+    println!("Stopped at {}", reason);
+
+    server.run_state = RunState::Stopped;
+    server.stop_reason = Some(reason);
+    server.resume_value = None;
+    server.run_stopped(target);
+    server.resume_value.take()
+}
@@ -0,0 +1,800 @@
+/*
+ * NE2000 compatible ISA network card at 0x300 (IRQ3)
+ *
+ * DP8390 core with 16K of onboard ring buffer memory at 0x4000-0x7FFF.
+ * Host CPU moves packet data to and from card memory with remote DMA through the data port,
+ * card moves it to and from the wire with local DMA. Host side of the wire is a net_backend.
+ */
+
+use vm;
+use config;
+use event;
+use net::{self, net_backend};
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::mem;
+
+const NE2000_BASE: u16          = 0x300;
+const NE2000_IRQ: u8            = 3;
+
+// Port offsets from base
+const NE_DATA: u16              = 0x10;     // Remote DMA data port, 0x10-0x17
+const NE_RESET: u16             = 0x1F;     // Reading resets the card, 0x18-0x1F
+const NE_PORTS: u16             = 0x20;
+
+// DP8390 registers, page 0
+const EN0_CLDA0: u16            = 0x01;     // Read: current local DMA address / write: page start
+const EN0_STARTPG: u16          = 0x01;
+const EN0_STOPPG: u16           = 0x02;
+const EN0_BOUNDARY: u16         = 0x03;
+const EN0_TSR: u16              = 0x04;     // Read: transmit status / write: transmit page start
+const EN0_TPSR: u16             = 0x04;
+const EN0_TCNTLO: u16           = 0x05;
+const EN0_TCNTHI: u16           = 0x06;
+const EN0_ISR: u16              = 0x07;
+const EN0_RSARLO: u16           = 0x08;     // Remote start address
+const EN0_RSARHI: u16           = 0x09;
+const EN0_RCNTLO: u16           = 0x0A;     // Remote byte count
+const EN0_RCNTHI: u16           = 0x0B;
+const EN0_RSR: u16              = 0x0C;     // Read: receive status / write: receive config
+const EN0_RXCR: u16             = 0x0C;
+const EN0_TXCR: u16             = 0x0D;
+const EN0_DCFG: u16             = 0x0E;
+const EN0_IMR: u16              = 0x0F;
+
+// Page 1
+const EN1_PHYS: u16             = 0x01;     // Station address, 0x01-0x06
+const EN1_CURPAG: u16           = 0x07;
+const EN1_MULT: u16             = 0x08;     // Multicast filter, 0x08-0x0F
+
+// Command register
+const E8390_STOP: u8            = 0x01;
+const E8390_START: u8           = 0x02;
+const E8390_TRANS: u8           = 0x04;
+const E8390_RREAD: u8           = 0x08;
+const E8390_RWRITE: u8          = 0x10;
+const E8390_NODMA: u8           = 0x20;
+const E8390_DMA_MASK: u8        = 0x38;
+const E8390_PAGE_SHIFT: u8      = 6;
+
+// Interrupt status bits
+const ENISR_RX: u8              = 0x01;
+const ENISR_TX: u8              = 0x02;
+const ENISR_OVER: u8            = 0x10;
+const ENISR_RDC: u8             = 0x40;
+const ENISR_RESET: u8           = 0x80;
+const ENISR_ALL: u8             = 0x7F;     // Reset status doesn't interrupt
+
+// Status bits
+const ENTSR_PTX: u8             = 0x01;
+const ENRSR_RXOK: u8            = 0x01;
+const ENRSR_PHY: u8             = 0x20;     // Multicast or broadcast destination
+
+// Receive configuration bits
+const ENRXCR_BCST: u8           = 0x04;
+const ENRXCR_MULTI: u8          = 0x08;
+const ENRXCR_PROMP: u8          = 0x10;
+
+// Data configuration bits
+const ENDCFG_WTS: u8            = 0x01;     // Word-wide DMA
+
+// Card memory layout
+const NE_PROM_SIZE: usize       = 32;
+const NE_MEM_START: usize       = 0x4000;
+const NE_MEM_END: usize         = 0x8000;
+const NE_PAGE_SIZE: usize       = 256;
+
+const ETH_ALEN: usize           = 6;
+const ETH_ZLEN: usize           = 60;       // Minimum frame length without FCS
+
+// How often host backend is polled for incoming frames
+const NE2000_POLL_PERIOD_US: u64 = 1000;
+
+const NE2000_DEFAULT_MAC: [u8; ETH_ALEN] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+/*
+ * DP8390 with NE2000 memory and remote DMA port
+ */
+struct NE2000
+{
+    cmd: u8,
+    pstart: u8,
+    pstop: u8,
+    boundary: u8,
+    tpsr: u8,
+    tbcr: u16,
+    isr: u8,
+    rsar: u16,
+    rbcr: u16,
+    rsr: u8,
+    tsr: u8,
+    rcr: u8,
+    tcr: u8,
+    dcr: u8,
+    imr: u8,
+    curr: u8,
+    phys: [u8; ETH_ALEN],
+    mult: [u8; 8],
+
+    irq: bool,
+    irq_level: bool,        // Interrupt output last reported to PIC
+
+    prom: [u8; NE_PROM_SIZE],
+    mem: Vec<u8>,
+    backend: Box<net_backend>,
+}
+
+impl NE2000
+{
+    fn new(mac: [u8; ETH_ALEN], backend: Box<net_backend>) -> NE2000 {
+        /* Address PROM is read with word-wide DMA, so every byte is doubled. 0x57 marks NE2000 */
+        let mut prom = [0u8; NE_PROM_SIZE];
+        for i in 0..ETH_ALEN {
+            prom[i * 2] = mac[i];
+            prom[i * 2 + 1] = mac[i];
+        }
+        for b in prom[28..].iter_mut() {
+            *b = 0x57;
+        }
+
+        let mut ne = NE2000 {
+            cmd: 0,
+            pstart: 0,
+            pstop: 0,
+            boundary: 0,
+            tpsr: 0,
+            tbcr: 0,
+            isr: 0,
+            rsar: 0,
+            rbcr: 0,
+            rsr: 0,
+            tsr: 0,
+            rcr: 0,
+            tcr: 0,
+            dcr: 0,
+            imr: 0,
+            curr: 0,
+            phys: [0; ETH_ALEN],
+            mult: [0; 8],
+            irq: false,
+            irq_level: false,
+            prom: prom,
+            mem: vec![0; NE_MEM_END - NE_MEM_START],
+            backend: backend,
+        };
+
+        ne.reset();
+        ne
+    }
+
+    fn reset(&mut self) {
+        self.cmd = E8390_NODMA | E8390_STOP;
+        self.isr = ENISR_RESET;
+        self.imr = 0;
+        self.rbcr = 0;
+        self.update_irq();
+    }
+
+    /* Interrupt output follows ISR & IMR, PIC is edge triggered so only rising edges are reported */
+    fn update_irq(&mut self) {
+        let level = self.isr & self.imr & ENISR_ALL != 0;
+        if level && !self.irq_level {
+            self.irq = true;
+        }
+        self.irq_level = level;
+    }
+
+    /**
+     * Take pending interrupt request
+     */
+    fn take_irq(&mut self) -> bool {
+        let irq = self.irq;
+        self.irq = false;
+        irq
+    }
+
+    fn is_running(&self) -> bool {
+        self.cmd & (E8390_START | E8390_STOP) == E8390_START
+    }
+
+    fn mem_read(&self, addr: usize) -> u8 {
+        match addr {
+            0...0x1F => self.prom[addr],
+            NE_MEM_START...0x7FFF => self.mem[addr - NE_MEM_START],
+            _ => 0xFF,
+        }
+    }
+
+    fn mem_write(&mut self, addr: usize, val: u8) {
+        if addr >= NE_MEM_START && addr < NE_MEM_END {
+            self.mem[addr - NE_MEM_START] = val;
+        }
+    }
+
+    /* Card memory address at start of ring page */
+    fn page_addr(page: u8) -> usize {
+        page as usize * NE_PAGE_SIZE
+    }
+
+    /*
+     * Remote DMA port access, one byte or one word depending on bus width
+     */
+    fn dma_advance(&mut self) {
+        self.rsar = self.rsar.wrapping_add(1);
+        if self.pstop > self.pstart && self.rsar as usize == NE2000::page_addr(self.pstop) {
+            self.rsar = (NE2000::page_addr(self.pstart)) as u16;
+        }
+
+        if self.rbcr > 0 {
+            self.rbcr -= 1;
+            if self.rbcr == 0 {
+                self.isr |= ENISR_RDC;
+                self.update_irq();
+            }
+        }
+    }
+
+    fn read_data(&mut self, size: usize) -> u32 {
+        let mut val = 0;
+        for i in 0..size {
+            val |= (self.mem_read(self.rsar as usize) as u32) << (i * 8);
+            self.dma_advance();
+        }
+        val
+    }
+
+    fn write_data(&mut self, val: u32, size: usize) {
+        for i in 0..size {
+            let addr = self.rsar as usize;
+            self.mem_write(addr, (val >> (i * 8)) as u8);
+            self.dma_advance();
+        }
+    }
+
+    fn transmit(&mut self) {
+        let start = NE2000::page_addr(self.tpsr);
+        let frame: Vec<u8> = (start..start + self.tbcr as usize).map(|addr| self.mem_read(addr)).collect();
+
+        debug!("ne2000: transmit {} bytes", frame.len());
+        self.backend.send(&frame);
+
+        self.tsr = ENTSR_PTX;
+        self.isr |= ENISR_TX;
+        self.cmd &= !E8390_TRANS;
+        self.update_irq();
+    }
+
+    fn write_cmd(&mut self, val: u8) {
+        /* Starting remote DMA with zero byte count completes immediately */
+        if val & E8390_DMA_MASK & (E8390_RREAD | E8390_RWRITE) != 0 && val & E8390_NODMA == 0 && self.rbcr == 0 {
+            self.isr |= ENISR_RDC;
+            self.update_irq();
+        }
+
+        self.cmd = val;
+        if val & E8390_STOP != 0 {
+            self.cmd &= !E8390_START;
+        }
+
+        if val & E8390_TRANS != 0 && self.is_running() {
+            self.transmit();
+        }
+    }
+
+    fn page(&self) -> u8 {
+        self.cmd >> E8390_PAGE_SHIFT
+    }
+
+    fn read_reg(&mut self, reg: u16) -> u8 {
+        if reg == 0 {
+            return self.cmd;
+        }
+
+        match (self.page(), reg) {
+            (0, EN0_CLDA0) => 0,
+            (0, EN0_BOUNDARY) => self.boundary,
+            (0, EN0_TSR) => self.tsr,
+            (0, EN0_ISR) => self.isr,
+            (0, EN0_RSARLO) => self.rsar as u8,     // Current remote DMA address
+            (0, EN0_RSARHI) => (self.rsar >> 8) as u8,
+            (0, EN0_RSR) => self.rsr,
+
+            (1, EN1_PHYS...0x06) => self.phys[(reg - EN1_PHYS) as usize],
+            (1, EN1_CURPAG) => self.curr,
+            (1, EN1_MULT...0x0F) => self.mult[(reg - EN1_MULT) as usize],
+
+            (2, EN0_STARTPG) => self.pstart,
+            (2, EN0_STOPPG) => self.pstop,
+            (2, EN0_TPSR) => self.tpsr,
+            (2, EN0_RXCR) => self.rcr,
+            (2, EN0_TXCR) => self.tcr,
+            (2, EN0_DCFG) => self.dcr,
+            (2, EN0_IMR) => self.imr,
+
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&mut self, reg: u16, val: u8) {
+        if reg == 0 {
+            return self.write_cmd(val);
+        }
+
+        match (self.page(), reg) {
+            (0, EN0_STARTPG) => self.pstart = val,
+            (0, EN0_STOPPG) => self.pstop = val,
+            (0, EN0_BOUNDARY) => self.boundary = val,
+            (0, EN0_TPSR) => self.tpsr = val,
+            (0, EN0_TCNTLO) => self.tbcr = (self.tbcr & 0xFF00) | val as u16,
+            (0, EN0_TCNTHI) => self.tbcr = (self.tbcr & 0x00FF) | (val as u16) << 8,
+            (0, EN0_ISR) => {
+                self.isr &= !val;
+                self.update_irq();
+            },
+            (0, EN0_RSARLO) => self.rsar = (self.rsar & 0xFF00) | val as u16,
+            (0, EN0_RSARHI) => self.rsar = (self.rsar & 0x00FF) | (val as u16) << 8,
+            (0, EN0_RCNTLO) => self.rbcr = (self.rbcr & 0xFF00) | val as u16,
+            (0, EN0_RCNTHI) => self.rbcr = (self.rbcr & 0x00FF) | (val as u16) << 8,
+            (0, EN0_RXCR) => self.rcr = val,
+            (0, EN0_TXCR) => self.tcr = val,
+            (0, EN0_DCFG) => self.dcr = val,
+            (0, EN0_IMR) => {
+                self.imr = val;
+                self.update_irq();
+            },
+
+            (1, EN1_PHYS...0x06) => self.phys[(reg - EN1_PHYS) as usize] = val,
+            (1, EN1_CURPAG) => self.curr = val,
+            (1, EN1_MULT...0x0F) => self.mult[(reg - EN1_MULT) as usize] = val,
+
+            _ => debug!("ne2000: ignored write to page {} register {:x}", self.page(), reg),
+        }
+    }
+
+    /* Destination address filter per receive configuration */
+    fn accepts(&self, frame: &[u8]) -> bool {
+        let dest = &frame[0..ETH_ALEN];
+
+        if self.rcr & ENRXCR_PROMP != 0 {
+            return true;
+        }
+
+        if dest.iter().all(|b| *b == 0xFF) {
+            return self.rcr & ENRXCR_BCST != 0;
+        }
+
+        if dest[0] & 0x01 != 0 {
+            return self.rcr & ENRXCR_MULTI != 0;
+        }
+
+        dest == &self.phys[..]
+    }
+
+    /* Free ring pages between current page and boundary */
+    fn free_pages(&self) -> usize {
+        let ring = self.pstop as usize - self.pstart as usize;
+        if self.curr < self.boundary {
+            self.boundary as usize - self.curr as usize
+        } else {
+            ring - (self.curr as usize - self.boundary as usize)
+        }
+    }
+
+    /**
+     * Frame from the wire
+     * Stored in ring at current page behind a 4 byte header: status, next page, length.
+     * Frame that doesn't fit before boundary is dropped and reported as overflow.
+     */
+    fn receive(&mut self, frame: &[u8]) {
+        if !self.is_running() || frame.len() < ETH_ALEN || self.pstop <= self.pstart ||
+           self.curr < self.pstart || self.curr >= self.pstop {
+            return;
+        }
+
+        if !self.accepts(frame) {
+            return;
+        }
+
+        let mut data = frame.to_vec();
+        if data.len() < ETH_ZLEN {
+            data.resize(ETH_ZLEN, 0);
+        }
+
+        let total = data.len() + 4;
+        let pages = (total + NE_PAGE_SIZE - 1) / NE_PAGE_SIZE;
+
+        /* Ring must never fill up completely, or current page would catch up with boundary */
+        if pages >= self.free_pages() {
+            debug!("ne2000: receive ring overflow, dropping {} bytes", frame.len());
+            self.isr |= ENISR_OVER;
+            self.update_irq();
+            return;
+        }
+
+        let mut next = self.curr as usize + pages;
+        if next >= self.pstop as usize {
+            next -= self.pstop as usize - self.pstart as usize;
+        }
+
+        self.rsr = ENRSR_RXOK;
+        if data[0] & 0x01 != 0 {
+            self.rsr |= ENRSR_PHY;
+        }
+
+        let mut packet = vec![self.rsr, next as u8, total as u8, (total >> 8) as u8];
+        packet.extend_from_slice(&data);
+
+        let ring_start = NE2000::page_addr(self.pstart);
+        let ring_end = NE2000::page_addr(self.pstop);
+        let mut addr = NE2000::page_addr(self.curr);
+        for b in packet {
+            self.mem_write(addr, b);
+            addr += 1;
+            if addr == ring_end {
+                addr = ring_start;
+            }
+        }
+
+        self.curr = next as u8;
+        self.isr |= ENISR_RX;
+        self.update_irq();
+    }
+
+    /* Pull frames that backend has for us */
+    fn poll(&mut self) {
+        while let Some(frame) = self.backend.receive() {
+            self.receive(&frame);
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct NE2000Dev
+{
+    nic: RefCell<NE2000>,
+    base: u16,
+    irq: u8,
+    assert_irq: fn(u8),
+}
+
+impl NE2000Dev
+{
+    fn service(&self) {
+        if self.nic.borrow_mut().take_irq() {
+            (self.assert_irq)(self.irq);
+        }
+    }
+
+    fn poll(&self) {
+        self.nic.borrow_mut().poll();
+        self.service();
+    }
+}
+
+impl vm::io_handler for NE2000Dev
+{
+    fn io_read(&self, port: u16, size: u8) -> vm::IoOperandType
+    {
+        let offset = port - self.base;
+        let val = {
+            let mut nic = self.nic.borrow_mut();
+            match offset {
+                0x00...0x0F => nic.read_reg(offset) as u32,
+                NE_DATA...0x17 => {
+                    /* Byte-wide DMA moves single byte regardless of access size */
+                    let width = if nic.dcr & ENDCFG_WTS != 0 { size as usize } else { 1 };
+                    nic.read_data(width)
+                },
+                0x18...NE_RESET => {
+                    nic.reset();
+                    0
+                },
+                _ => panic!(),
+            }
+        };
+
+        self.service();
+
+        match size {
+            1 => vm::IoOperandType::byte(val as u8),
+            2 => vm::IoOperandType::word(val as u16),
+            4 => vm::IoOperandType::dword(val),
+            _ => panic!(),
+        }
+    }
+
+    fn io_write(&self, port: u16, data: vm::IoOperandType)
+    {
+        let offset = port - self.base;
+        {
+            let mut nic = self.nic.borrow_mut();
+            match offset {
+                0x00...0x0F => nic.write_reg(offset, data.unwrap_byte()),
+                NE_DATA...0x17 => {
+                    let (val, size) = match data {
+                        vm::IoOperandType::byte(v) => (v as u32, 1),
+                        vm::IoOperandType::word(v) => (v as u32, 2),
+                        vm::IoOperandType::dword(v) => (v, 4),
+                    };
+                    let width = if nic.dcr & ENDCFG_WTS != 0 { size } else { 1 };
+                    nic.write_data(val, width);
+                },
+                0x18...NE_RESET => nic.reset(),
+                _ => panic!(),
+            }
+        }
+
+        self.service();
+    }
+}
+
+impl vm::reset_handler for NE2000Dev
+{
+    fn reset(&self)
+    {
+        self.nic.borrow_mut().reset();
+    }
+}
+
+#[cfg(test)]
+mod ne2000_test
+{
+    use super::*;
+    use std::cell::Cell;
+
+    thread_local! {
+        static IRQ_COUNT: Cell<u32> = Cell::new(0);
+    }
+
+    fn count_irq(irq: u8) {
+        assert!(irq == NE2000_IRQ);
+        IRQ_COUNT.with(|c| c.set(c.get() + 1));
+    }
+
+    fn irq_count() -> u32 {
+        IRQ_COUNT.with(|c| c.get())
+    }
+
+    /* pcap output shared with the test */
+    #[derive(Clone)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl ::std::io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> ::std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn make_dev(capture: &SharedBuf) -> NE2000Dev {
+        let pcap = net::PcapWriter::new(capture.clone()).unwrap();
+        NE2000Dev {
+            nic: RefCell::new(NE2000::new(NE2000_DEFAULT_MAC, Box::new(pcap))),
+            base: NE2000_BASE,
+            irq: NE2000_IRQ,
+            assert_irq: count_irq,
+        }
+    }
+
+    fn outb(dev: &NE2000Dev, reg: u16, val: u8) {
+        vm::io_handler::io_write(dev, NE2000_BASE + reg, vm::IoOperandType::byte(val));
+    }
+
+    fn inb(dev: &NE2000Dev, reg: u16) -> u8 {
+        vm::io_handler::io_read(dev, NE2000_BASE + reg, 1).unwrap_byte()
+    }
+
+    fn setup_remote_dma(dev: &NE2000Dev, addr: u16, count: u16, cmd: u8) {
+        outb(dev, EN0_RCNTLO, count as u8);
+        outb(dev, EN0_RCNTHI, (count >> 8) as u8);
+        outb(dev, EN0_RSARLO, addr as u8);
+        outb(dev, EN0_RSARHI, (addr >> 8) as u8);
+        outb(dev, 0, cmd | E8390_START);
+    }
+
+    fn dma_read(dev: &NE2000Dev, addr: u16, count: u16) -> Vec<u8> {
+        setup_remote_dma(dev, addr, count, E8390_RREAD);
+        let mut data = Vec::new();
+        for _ in 0..count / 2 {
+            let w = vm::io_handler::io_read(dev, NE2000_BASE + NE_DATA, 2).unwrap_word();
+            data.push(w as u8);
+            data.push((w >> 8) as u8);
+        }
+        data
+    }
+
+    /* Driver style bring-up: word DMA, ring at 0x46-0x80, receive broadcasts, start */
+    fn init_nic(dev: &NE2000Dev) {
+        inb(dev, NE_RESET);
+        assert!(inb(dev, EN0_ISR) & ENISR_RESET != 0);
+
+        outb(dev, 0, E8390_NODMA | E8390_STOP);
+        outb(dev, EN0_DCFG, 0x49);
+        outb(dev, EN0_RXCR, ENRXCR_BCST);
+        outb(dev, EN0_TPSR, 0x40);
+        outb(dev, EN0_STARTPG, 0x46);
+        outb(dev, EN0_BOUNDARY, 0x46);
+        outb(dev, EN0_STOPPG, 0x80);
+        outb(dev, EN0_ISR, 0xFF);
+        outb(dev, EN0_IMR, ENISR_RX | ENISR_TX | ENISR_OVER);
+
+        outb(dev, 0, E8390_NODMA | E8390_STOP | (1 << E8390_PAGE_SHIFT));
+        for i in 0..ETH_ALEN {
+            outb(dev, EN1_PHYS + i as u16, NE2000_DEFAULT_MAC[i]);
+        }
+        outb(dev, EN1_CURPAG, 0x47);
+
+        outb(dev, 0, E8390_NODMA | E8390_START);
+        outb(dev, EN0_TXCR, 0);
+    }
+
+    fn test_frame(len: usize) -> Vec<u8> {
+        let mut frame = vec![0xFF; ETH_ALEN];
+        frame.extend_from_slice(&NE2000_DEFAULT_MAC);
+        frame.extend_from_slice(&[0x08, 0x06]);
+        while frame.len() < len {
+            let b = frame.len() as u8;
+            frame.push(b);
+        }
+        frame
+    }
+
+    #[test] fn prom_and_registers() {
+        let capture = SharedBuf(Rc::new(RefCell::new(Vec::new())));
+        let dev = make_dev(&capture);
+        init_nic(&dev);
+
+        let prom = dma_read(&dev, 0, 32);
+        assert!(prom[0] == 0x52 && prom[1] == 0x52 && prom[10] == 0x56);
+        assert!(prom[28] == 0x57 && prom[31] == 0x57);
+        assert!(inb(&dev, EN0_ISR) & ENISR_RDC != 0);
+
+        /* Page 2 reads back configuration */
+        outb(&dev, 0, E8390_NODMA | E8390_START | (2 << E8390_PAGE_SHIFT));
+        assert!(inb(&dev, EN0_STARTPG) == 0x46 && inb(&dev, EN0_STOPPG) == 0x80);
+        assert!(inb(&dev, EN0_DCFG) == 0x49 && inb(&dev, EN0_IMR) == 0x13);
+    }
+
+    #[test] fn transmit_to_pcap() {
+        let capture = SharedBuf(Rc::new(RefCell::new(Vec::new())));
+        let dev = make_dev(&capture);
+        init_nic(&dev);
+
+        /* Copy frame into transmit buffer with remote DMA write */
+        let frame = test_frame(98);
+        setup_remote_dma(&dev, 0x4000, frame.len() as u16, E8390_RWRITE);
+        for chunk in frame.chunks(2) {
+            let w = chunk[0] as u16 | (chunk[1] as u16) << 8;
+            vm::io_handler::io_write(&dev, NE2000_BASE + NE_DATA, vm::IoOperandType::word(w));
+        }
+        assert!(inb(&dev, EN0_ISR) & ENISR_RDC != 0);
+        outb(&dev, EN0_ISR, ENISR_RDC);
+
+        let irqs = irq_count();
+        outb(&dev, EN0_TCNTLO, frame.len() as u8);
+        outb(&dev, EN0_TCNTHI, 0);
+        outb(&dev, 0, E8390_NODMA | E8390_START | E8390_TRANS);
+
+        assert!(irq_count() == irqs + 1);
+        assert!(inb(&dev, 0) & E8390_TRANS == 0);
+        assert!(inb(&dev, EN0_TSR) & ENTSR_PTX != 0);
+        assert!(inb(&dev, EN0_ISR) & ENISR_TX != 0);
+
+        /* pcap header, record header, exact frame bytes */
+        let out = capture.0.borrow();
+        assert!(out.len() == 24 + 16 + frame.len());
+        assert!(out[32..36] == [98, 0, 0, 0]);
+        assert!(out[40..] == frame[..]);
+    }
+
+    #[test] fn receive_into_ring() {
+        let capture = SharedBuf(Rc::new(RefCell::new(Vec::new())));
+        let dev = make_dev(&capture);
+        init_nic(&dev);
+
+        let irqs = irq_count();
+        let frame = test_frame(300);
+        dev.nic.borrow_mut().receive(&frame);
+        dev.service();
+        assert!(irq_count() == irqs + 1);
+        assert!(inb(&dev, EN0_ISR) & ENISR_RX != 0);
+
+        /* 304 bytes with header take two pages */
+        outb(&dev, 0, E8390_NODMA | E8390_START | (1 << E8390_PAGE_SHIFT));
+        assert!(inb(&dev, EN1_CURPAG) == 0x49);
+        outb(&dev, 0, E8390_NODMA | E8390_START);
+
+        let header = dma_read(&dev, 0x4700, 4);
+        assert!(header == [ENRSR_RXOK | ENRSR_PHY, 0x49, 0x30, 0x01]);
+        let data = dma_read(&dev, 0x4704, 300);
+        assert!(data == frame);
+
+        /* Short frames are padded, frames for other stations are filtered */
+        dev.nic.borrow_mut().receive(&test_frame(20));
+        assert!(dma_read(&dev, 0x4900, 4) == [ENRSR_RXOK | ENRSR_PHY, 0x4A, 64, 0]);
+        let mut other = test_frame(64);
+        other[0] = 0x02;
+        dev.nic.borrow_mut().receive(&other);
+        assert!(dev.nic.borrow().curr == 0x4A);
+    }
+
+    #[test] fn ring_wrap_and_overflow() {
+        let capture = SharedBuf(Rc::new(RefCell::new(Vec::new())));
+        let dev = make_dev(&capture);
+        init_nic(&dev);
+
+        /* Frame starting at the last ring page wraps to page start */
+        outb(&dev, EN0_BOUNDARY, 0x7E);
+        dev.nic.borrow_mut().curr = 0x7F;
+        let frame = test_frame(400);
+        dev.nic.borrow_mut().receive(&frame);
+        assert!(dev.nic.borrow().curr == 0x47);
+
+        let mut data = dma_read(&dev, 0x7F04, 252);
+        data.extend(dma_read(&dev, 0x4600, 148));
+        assert!(data == frame);
+
+        /* Full ring drops frame and reports overflow */
+        outb(&dev, EN0_ISR, 0xFF);
+        outb(&dev, EN0_BOUNDARY, 0x48);
+        dev.nic.borrow_mut().receive(&frame);
+        assert!(dev.nic.borrow().curr == 0x47);
+        assert!(inb(&dev, EN0_ISR) == ENISR_OVER);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/*
+ * NIC instance for backend poll event
+ */
+static mut NE2000_DEV: Option<*const NE2000Dev> = None;
+
+fn poll_event(ev: event::Event)
+{
+    unsafe {
+        if let Some(dev) = NE2000_DEV {
+            let dev: &NE2000Dev = mem::transmute(dev);
+            dev.poll();
+        }
+    }
+
+    event::schedule_event(NE2000_POLL_PERIOD_US, ev);
+}
+
+pub fn init(config: &config::VmConfig)
+{
+    let netconfig = match config.net {
+        Some(ref netconfig) => netconfig,
+        None => return,
+    };
+
+    let backend = match net::open_backend(netconfig) {
+        Ok(backend) => Box::new(net::RecordedBackend::new(backend, "ne2000")) as Box<net_backend>,
+        Err(err) => panic!("ne2000: failed to open backend {:?}: {}", netconfig, err),
+    };
+
+    let dev = Rc::new(NE2000Dev {
+        nic: RefCell::new(NE2000::new(NE2000_DEFAULT_MAC, backend)),
+        base: NE2000_BASE,
+        irq: NE2000_IRQ,
+        assert_irq: vm::assert_irq,
+    });
+
+    unsafe {
+        NE2000_DEV = Some(&*dev as *const NE2000Dev);
+    }
+
+    for offset in 0..NE_PORTS {
+        vm::register_io_region(dev.clone(), NE2000_BASE + offset, 1);
+    }
+    vm::register_reset_handler(dev.clone());
+
+    event::schedule_event(NE2000_POLL_PERIOD_US, event::create_event(poll_event));
+}
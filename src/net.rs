@@ -0,0 +1,293 @@
+/*
+ * Host side network backends for emulated NICs
+ */
+
+use time;
+use record;
+use replay;
+use reverse;
+
+use std::fs::File;
+use std::io::{self, Write};
+
+/**
+ * Host end of the guest network link
+ */
+pub trait net_backend
+{
+    /** Deliver Ethernet frame transmitted by guest */
+    fn send(&mut self, frame: &[u8]);
+
+    /** Take next Ethernet frame destined to guest, None if nothing is pending */
+    fn receive(&mut self) -> Option<Vec<u8>>;
+}
+
+// pcap file format
+const PCAP_MAGIC: u32           = 0xA1B2C3D4;
+const PCAP_VERSION_MAJOR: u16   = 2;
+const PCAP_VERSION_MINOR: u16   = 4;
+const PCAP_SNAPLEN: u32         = 65535;
+const PCAP_LINKTYPE_ETHERNET: u32 = 1;
+
+/**
+ * Writes guest transmitted frames to a pcap capture, never receives anything
+ */
+pub struct PcapWriter<W: Write>
+{
+    out: W,
+}
+
+impl PcapWriter<File>
+{
+    pub fn create(path: &str) -> io::Result<PcapWriter<File>> {
+        PcapWriter::new(try!(File::create(path)))
+    }
+}
+
+impl<W: Write> PcapWriter<W>
+{
+    pub fn new(mut out: W) -> io::Result<PcapWriter<W>> {
+        let mut header = Vec::with_capacity(24);
+        put_u32(&mut header, PCAP_MAGIC);
+        put_u16(&mut header, PCAP_VERSION_MAJOR);
+        put_u16(&mut header, PCAP_VERSION_MINOR);
+        put_u32(&mut header, 0);                    // GMT offset
+        put_u32(&mut header, 0);                    // Timestamp accuracy
+        put_u32(&mut header, PCAP_SNAPLEN);
+        put_u32(&mut header, PCAP_LINKTYPE_ETHERNET);
+        try!(out.write_all(&header));
+
+        Ok(PcapWriter {
+            out: out,
+        })
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.out
+    }
+
+    fn write_record(&mut self, frame: &[u8]) -> io::Result<()> {
+        let now = time::get_time();
+        let mut record = Vec::with_capacity(16 + frame.len());
+        put_u32(&mut record, now.sec as u32);
+        put_u32(&mut record, (now.nsec / 1000) as u32);
+        put_u32(&mut record, frame.len() as u32);  // Captured length
+        put_u32(&mut record, frame.len() as u32);  // Original length
+        record.extend_from_slice(frame);
+
+        try!(self.out.write_all(&record));
+        self.out.flush()
+    }
+}
+
+impl<W: Write> net_backend for PcapWriter<W>
+{
+    fn send(&mut self, frame: &[u8]) {
+        self.write_record(frame).unwrap_or_else(|err| {
+            error!("net: failed writing pcap record: {}", err);
+        });
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/* pcap files use host byte order, we write little endian */
+fn put_u16(buf: &mut Vec<u8>, val: u16)
+{
+    buf.extend_from_slice(&[val as u8, (val >> 8) as u8]);
+}
+
+fn put_u32(buf: &mut Vec<u8>, val: u32)
+{
+    buf.extend_from_slice(&[val as u8, (val >> 8) as u8, (val >> 16) as u8, (val >> 24) as u8]);
+}
+
+/*
+ * Linux tap interface
+ */
+#[cfg(all(feature = "tap", target_os = "linux"))]
+mod tap
+{
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read, Write};
+    use std::os::unix::io::AsRawFd;
+
+    const TUNSETIFF: u64        = 0x400454CA;
+    const IFF_TAP: u16          = 0x0002;
+    const IFF_NO_PI: u16        = 0x1000;
+    const IFNAMSIZ: usize       = 16;
+    const F_GETFL: i32          = 3;
+    const F_SETFL: i32          = 4;
+    const O_NONBLOCK: i32       = 0x800;
+
+    #[repr(C)]
+    struct ifreq
+    {
+        name: [u8; IFNAMSIZ],
+        flags: u16,
+        pad: [u8; 22],
+    }
+
+    extern "C" {
+        fn ioctl(fd: i32, request: u64, ...) -> i32;
+        fn fcntl(fd: i32, cmd: i32, ...) -> i32;
+    }
+
+    /**
+     * Frames go to and come from an existing tap interface
+     */
+    pub struct TapBackend
+    {
+        file: File,
+    }
+
+    impl TapBackend
+    {
+        pub fn open(ifname: &str) -> io::Result<TapBackend> {
+            if ifname.len() >= IFNAMSIZ {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "interface name is too long"));
+            }
+
+            let file = try!(OpenOptions::new().read(true).write(true).open("/dev/net/tun"));
+            let fd = file.as_raw_fd();
+
+            let mut req = ifreq {
+                name: [0; IFNAMSIZ],
+                flags: IFF_TAP | IFF_NO_PI,
+                pad: [0; 22],
+            };
+            req.name[..ifname.len()].copy_from_slice(ifname.as_bytes());
+
+            unsafe {
+                if ioctl(fd, TUNSETIFF, &mut req as *mut ifreq) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                let flags = fcntl(fd, F_GETFL);
+                if flags < 0 || fcntl(fd, F_SETFL, flags | O_NONBLOCK) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            Ok(TapBackend {
+                file: file,
+            })
+        }
+    }
+
+    impl super::net_backend for TapBackend
+    {
+        fn send(&mut self, frame: &[u8]) {
+            self.file.write_all(frame).unwrap_or_else(|err| {
+                error!("tap: failed to send frame: {}", err);
+            });
+        }
+
+        fn receive(&mut self) -> Option<Vec<u8>> {
+            let mut buf = vec![0u8; 65536];
+            match self.file.read(&mut buf) {
+                Ok(len) => {
+                    buf.truncate(len);
+                    Some(buf)
+                },
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => None,
+                Err(err) => {
+                    error!("tap: failed to receive frame: {}", err);
+                    None
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(feature = "tap", target_os = "linux"))]
+pub use self::tap::TapBackend;
+
+/**
+ * Backend passing frames guest takes from another one to input recording, see record.rs
+ */
+pub struct RecordedBackend
+{
+    inner: Box<net_backend>,
+    device: &'static str,
+}
+
+impl RecordedBackend
+{
+    pub fn new(inner: Box<net_backend>, device: &'static str) -> RecordedBackend {
+        RecordedBackend {
+            inner: inner,
+            device: device,
+        }
+    }
+}
+
+impl net_backend for RecordedBackend
+{
+    fn send(&mut self, frame: &[u8]) {
+        /* Guest sent this before, reverse execution only took it back */
+        if !reverse::replaying() {
+            self.inner.send(frame);
+        }
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        if replay::enabled() {
+            return replay::net_rx(self.device);
+        }
+
+        let frame = self.inner.receive();
+        if let Some(ref frame) = frame {
+            let device = self.device;
+            record::emit(|| record::Input::NetRx { device: device, frame: frame.clone() });
+        }
+        frame
+    }
+}
+
+/**
+ * Open backend described by its configuration
+ */
+pub fn open_backend(config: &::config::NetConfig) -> io::Result<Box<net_backend>>
+{
+    match *config {
+        ::config::NetConfig::Pcap(ref path) => {
+            let writer = try!(PcapWriter::create(path));
+            Ok(Box::new(writer))
+        },
+
+        #[cfg(all(feature = "tap", target_os = "linux"))]
+        ::config::NetConfig::Tap(ref ifname) => {
+            let tap = try!(TapBackend::open(ifname));
+            Ok(Box::new(tap))
+        },
+
+        #[cfg(not(all(feature = "tap", target_os = "linux")))]
+        ::config::NetConfig::Tap(_) => {
+            Err(io::Error::new(io::ErrorKind::Other, "tap backend requires Linux host and \"tap\" feature"))
+        },
+
+        ::config::NetConfig::User => Ok(Box::new(::slirp::SlirpBackend::new())),
+    }
+}
+
+#[cfg(test)]
+mod net_test
+{
+    use super::*;
+
+    #[test] fn pcap_records() {
+        let mut pcap = PcapWriter::new(Vec::new()).unwrap();
+        pcap.send(&[1, 2, 3]);
+        assert!(pcap.receive().is_none());
+
+        let out = pcap.get_ref();
+        assert!(out.len() == 24 + 16 + 3);
+        assert!(out[0..4] == [0xD4, 0xC3, 0xB2, 0xA1]);
+        assert!(out[20..24] == [1, 0, 0, 0]);
+        assert!(out[32..40] == [3, 0, 0, 0, 3, 0, 0, 0]);
+        assert!(out[40..] == [1, 2, 3]);
+    }
+}
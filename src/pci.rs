@@ -1,5 +1,8 @@
 /*
  * PCI root emulation
+ *
+ * Configuration mechanism #1: guest selects bus/device/function/register through 0xCF8
+ * and accesses the selected configuration dword through 0xCFC-0xCFF.
  */
 
 use vm;
@@ -10,27 +13,640 @@ use std::cell::RefCell;
 const PCI_CONFIG_ADDRESS:u16    = 0xCF8;
 const PCI_CONFIG_DATA:u16       = 0xCFC;
 
+// Config address register fields
+const PCI_ADDR_ENABLE: u32      = 0x80000000;
+const PCI_ADDR_MASK: u32        = 0x80FFFFFC;   // Reserved bits and low 2 bits read as 0
+
+// Host bridge identity (440FX)
+const HOST_BRIDGE_VENDOR: u16   = 0x8086;
+const HOST_BRIDGE_DEVICE: u16   = 0x1237;
+const HOST_BRIDGE_REVISION: u8  = 0x02;
+const HOST_BRIDGE_CLASS: u32    = 0x060000;     // Bridge, host bridge
+
 struct PCIRoot
 {
+    address: u32,                   // Config address register
+    bus: Rc<RefCell<vm::PciBus>>,
 }
 
-impl PCIRoot 
+impl PCIRoot
 {
-    fn new() -> PCIRoot {
-        PCIRoot {}
+    fn new(bus: Rc<RefCell<vm::PciBus>>) -> PCIRoot {
+        PCIRoot {
+            address: 0,
+            bus: bus,
+        }
     }
 
+    /* Function and register selected by config address, None if config cycles are disabled */
+    fn selected(&self) -> Option<(vm::PciAddress, u8)> {
+        if self.address & PCI_ADDR_ENABLE == 0 {
+            return None;
+        }
+
+        let addr = vm::PciAddress {
+            bus: (self.address >> 16) as u8,
+            device: ((self.address >> 11) & 0x1F) as u8,
+            function: ((self.address >> 8) & 0x7) as u8,
+        };
+
+        Some((addr, self.address as u8))
+    }
+
+    /* Read returns value shifted down to the accessed port */
     fn read32(&mut self, port: u16) -> u32 {
-        0xFFFFFFFF
+        if port == PCI_CONFIG_ADDRESS {
+            return self.address;
+        }
+
+        let shift = (port - PCI_CONFIG_DATA) * 8;
+        match self.selected() {
+            Some((addr, reg)) => self.bus.borrow().config_read(addr, reg) >> shift,
+            None => 0xFFFFFFFF,
+        }
     }
 
-    fn write32(&mut self, port: u16, data: u32) {
-        /* STUB */
+    fn write32(&mut self, port: u16, data: u32, size: u8) {
+        if port == PCI_CONFIG_ADDRESS {
+            /* Only dword writes hit config address, narrower ones belong to other devices */
+            if size == 4 {
+                self.address = data & PCI_ADDR_MASK;
+            }
+            return;
+        }
+
+        let shift = (port - PCI_CONFIG_DATA) * 8;
+        let mask = match size {
+            1 => 0xFF,
+            2 => 0xFFFF,
+            _ => 0xFFFFFFFF,
+        } << shift;
+
+        if let Some((addr, reg)) = self.selected() {
+            self.bus.borrow().config_write(addr, reg, data << shift, mask);
+        }
     }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
 
+/*
+ * Standard type 0 configuration header registers
+ */
+const PCI_REG_ID: u8            = 0x00;
+const PCI_REG_COMMAND: u8       = 0x04;     // Command and status
+const PCI_REG_CLASS: u8         = 0x08;     // Class code and revision
+const PCI_REG_BAR0: u8          = 0x10;
+const PCI_REG_SUBSYSTEM: u8     = 0x2C;
+const PCI_REG_CAP_PTR: u8       = 0x34;
+const PCI_REG_INTERRUPT: u8     = 0x3C;     // Interrupt line and pin
+const PCI_REG_DEVICE_SPECIFIC: u8 = 0x40;
+
+pub const PCI_NUM_BARS: usize   = 6;
+
+// Command register bits
+pub const PCI_CMD_IO: u16       = 0x0001;
+pub const PCI_CMD_MEMORY: u16   = 0x0002;
+pub const PCI_CMD_MASTER: u16   = 0x0004;
+pub const PCI_CMD_INTX_DISABLE: u16 = 0x0400;
+const PCI_CMD_WRITABLE: u16     = PCI_CMD_IO | PCI_CMD_MEMORY | PCI_CMD_MASTER | PCI_CMD_INTX_DISABLE;
+
+// Status register bits
+pub const PCI_STATUS_CAP_LIST: u16 = 0x0010;
+
+// BAR low bits
+const PCI_BAR_IO: u32           = 0x1;
+const PCI_BAR_IO_MASK: u32      = !0x3;
+const PCI_BAR_MEM_MASK: u32     = !0xF;
+
+/**
+ * Address space decoded by a BAR
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PciBarKind
+{
+    Io,
+    Memory,     // 32-bit non-prefetchable
+}
+
+/**
+ * BAR region as currently placed by guest
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PciBarRegion
+{
+    pub bar: usize,
+    pub kind: PciBarKind,
+    pub base: u32,
+    pub size: u32,
+}
+
+#[derive(Clone, Copy)]
+struct PciBar
+{
+    kind: PciBarKind,
+    size: u32,          // Power of 2
+    addr: u32,          // Programmed base address bits
+}
+
+/**
+ * Device specific part of a PCI function
+ *
+ * Standard header is handled by PciFunction, devices only deal with their BAR regions
+ * and registers past the standard header.
+ */
+#[allow(unused_variables)]
+pub trait pci_device
+{
+    /**
+     * Read from BAR region
+     * \param bar     BAR index
+     * \param offset  Offset from BAR base
+     * \param size    Access size in bytes
+     */
+    fn bar_read(&self, bar: usize, offset: u32, size: u8) -> u32;
+
+    /**
+     * Write to BAR region
+     */
+    fn bar_write(&self, bar: usize, offset: u32, size: u8, val: u32);
+
+    /**
+     * Read device specific configuration dword at 0x40 and above
+     */
+    fn config_read(&self, reg: u8) -> u32 {
+        0
+    }
+
+    /**
+     * Write device specific configuration dword at 0x40 and above
+     */
+    fn config_write(&self, reg: u8, val: u32, mask: u32) {
+    }
+}
+
+/**
+ * Standard configuration header contents
+ */
+pub struct PciHeader
+{
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u32,             // Base class, subclass, programming interface
+    pub revision: u8,
+    pub subsystem_vendor: u16,
+    pub subsystem: u16,
+    pub status: u16,
+    pub interrupt_pin: u8,      // 0 for none, 1-4 for INTA-INTD
+
+    command: u16,
+    interrupt_line: u8,
+    bars: [Option<PciBar>; PCI_NUM_BARS],
+    capabilities: Vec<(u8, u8)>,    // Capability ID and its config space offset
+}
+
+impl PciHeader
+{
+    pub fn new(vendor: u16, device: u16, class: u32, revision: u8) -> PciHeader {
+        PciHeader {
+            vendor: vendor,
+            device: device,
+            class: class,
+            revision: revision,
+            subsystem_vendor: 0,
+            subsystem: 0,
+            status: 0,
+            interrupt_pin: 0,
+            command: 0,
+            interrupt_line: 0,
+            bars: [None; PCI_NUM_BARS],
+            capabilities: Vec::new(),
+        }
+    }
+
+    /**
+     * Declare BAR, size is rounded up to a power of 2
+     */
+    pub fn set_bar(&mut self, bar: usize, kind: PciBarKind, size: u32) {
+        let min = match kind {
+            PciBarKind::Io => 4,
+            PciBarKind::Memory => 16,
+        };
+
+        self.bars[bar] = Some(PciBar {
+            kind: kind,
+            size: ::std::cmp::max(size, min).next_power_of_two(),
+            addr: 0,
+        });
+    }
+
+    /**
+     * Link capability structure at offset into capability list
+     * Device keeps the rest of the structure in its device specific registers.
+     */
+    pub fn add_capability(&mut self, id: u8, offset: u8) {
+        assert!(offset >= PCI_REG_DEVICE_SPECIFIC && offset & 3 == 0);
+        self.capabilities.push((id, offset));
+        self.status |= PCI_STATUS_CAP_LIST;
+    }
+
+    pub fn command(&self) -> u16 {
+        self.command
+    }
+
+    pub fn interrupt_line(&self) -> u8 {
+        self.interrupt_line
+    }
+
+    fn read_bar(&self, bar: usize) -> u32 {
+        match self.bars[bar] {
+            Some(PciBar { kind: PciBarKind::Io, addr, .. }) => addr | PCI_BAR_IO,
+            Some(PciBar { kind: PciBarKind::Memory, addr, .. }) => addr,
+            None => 0,
+        }
+    }
+
+    /* Base address bits below BAR size are hardwired to 0, that's what makes sizing work */
+    fn write_bar(&mut self, bar: usize, val: u32, mask: u32) {
+        let cur = self.read_bar(bar);
+        if let Some(ref mut b) = self.bars[bar] {
+            let val = (cur & !mask) | (val & mask);
+            let kind_mask = match b.kind {
+                PciBarKind::Io => PCI_BAR_IO_MASK,
+                PciBarKind::Memory => PCI_BAR_MEM_MASK,
+            };
+
+            b.addr = val & kind_mask & !(b.size - 1);
+        }
+    }
+
+    /*
+     * Region a BAR currently decodes, if any.
+     * BARs are not decoded while disabled in command register, when left at 0 or while
+     * holding the all-ones sizing pattern.
+     */
+    fn bar_region(&self, bar: usize) -> Option<PciBarRegion> {
+        let b = match self.bars[bar] {
+            Some(b) => b,
+            None => return None,
+        };
+
+        let enable = match b.kind {
+            PciBarKind::Io => PCI_CMD_IO,
+            PciBarKind::Memory => PCI_CMD_MEMORY,
+        };
+
+        let sizing = match b.kind {
+            PciBarKind::Io => PCI_BAR_IO_MASK,
+            PciBarKind::Memory => PCI_BAR_MEM_MASK,
+        } & !(b.size - 1);
+
+        if self.command & enable == 0 || b.addr == 0 || b.addr == sizing {
+            return None;
+        }
+
+        if b.kind == PciBarKind::Io && b.addr as u64 + b.size as u64 > 0x10000 {
+            return None;
+        }
+
+        Some(PciBarRegion {
+            bar: bar,
+            kind: b.kind,
+            base: b.addr,
+            size: b.size,
+        })
+    }
+
+    /* Standard header dword, reg is below 0x40 */
+    fn read(&self, reg: u8) -> u32 {
+        match reg {
+            PCI_REG_ID => (self.device as u32) << 16 | self.vendor as u32,
+            PCI_REG_COMMAND => (self.status as u32) << 16 | self.command as u32,
+            PCI_REG_CLASS => self.class << 8 | self.revision as u32,
+            PCI_REG_BAR0...0x24 => self.read_bar(((reg - PCI_REG_BAR0) / 4) as usize),
+            PCI_REG_SUBSYSTEM => (self.subsystem as u32) << 16 | self.subsystem_vendor as u32,
+            PCI_REG_CAP_PTR => self.capabilities.first().map_or(0, |c| c.1 as u32),
+            PCI_REG_INTERRUPT => (self.interrupt_pin as u32) << 8 | self.interrupt_line as u32,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, reg: u8, val: u32, mask: u32) {
+        match reg {
+            PCI_REG_COMMAND => {
+                let mask = mask as u16 & PCI_CMD_WRITABLE;
+                self.command = (self.command & !mask) | (val as u16 & mask);
+            },
+            PCI_REG_BAR0...0x24 => self.write_bar(((reg - PCI_REG_BAR0) / 4) as usize, val, mask),
+            PCI_REG_INTERRUPT => {
+                if mask & 0xFF != 0 {
+                    self.interrupt_line = val as u8;
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /* Capability ID and next pointer if a capability lives at reg */
+    fn capability_at(&self, reg: u8) -> Option<u32> {
+        self.capabilities.iter().position(|c| c.1 == reg).map(|i| {
+            let next = self.capabilities.get(i + 1).map_or(0, |c| c.1);
+            (next as u32) << 8 | self.capabilities[i].0 as u32
+        })
+    }
+}
+
+/**
+ * Attach (true) or detach (false) a BAR region to guest address space
+ */
+pub type BarMapper = fn(&Rc<pci_device>, &PciBarRegion, bool);
+
+/**
+ * PCI function built from standard header and a device model
+ *
+ * BAR regions follow guest programming: whenever a BAR is moved or its decoding is
+ * toggled in command register, the old region is detached and the new one attached.
+ */
+pub struct PciFunction
+{
+    header: RefCell<PciHeader>,
+    device: Rc<pci_device>,
+    mapped: RefCell<[Option<PciBarRegion>; PCI_NUM_BARS]>,
+    mapper: BarMapper,
+}
+
+impl PciFunction
+{
+    pub fn new(header: PciHeader, device: Rc<pci_device>) -> PciFunction {
+        PciFunction::with_mapper(header, device, map_bar)
+    }
+
+    fn with_mapper(header: PciHeader, device: Rc<pci_device>, mapper: BarMapper) -> PciFunction {
+        PciFunction {
+            header: RefCell::new(header),
+            device: device,
+            mapped: RefCell::new([None; PCI_NUM_BARS]),
+            mapper: mapper,
+        }
+    }
+
+    fn update_mappings(&self) {
+        let header = self.header.borrow();
+        let mut mapped = self.mapped.borrow_mut();
+
+        for bar in 0..PCI_NUM_BARS {
+            let region = header.bar_region(bar);
+            if region == mapped[bar] {
+                continue;
+            }
+
+            if let Some(ref old) = mapped[bar] {
+                debug!("pci: {:04x}:{:04x} BAR{} unmapped from {:x}", header.vendor, header.device, bar, old.base);
+                (self.mapper)(&self.device, old, false);
+            }
+
+            if let Some(ref new) = region {
+                debug!("pci: {:04x}:{:04x} BAR{} mapped at {:x}", header.vendor, header.device, bar, new.base);
+                (self.mapper)(&self.device, new, true);
+            }
+
+            mapped[bar] = region;
+        }
+    }
+
+    /**
+     * Power-on state: decoding disabled and BARs cleared
+     */
+    pub fn reset(&self) {
+        {
+            let mut header = self.header.borrow_mut();
+            header.command = 0;
+            header.interrupt_line = 0;
+            for bar in header.bars.iter_mut() {
+                if let Some(ref mut b) = *bar {
+                    b.addr = 0;
+                }
+            }
+        }
+
+        self.update_mappings();
+    }
+}
+
+impl vm::pci_function for PciFunction
+{
+    fn config_read(&self, reg: u8) -> u32
+    {
+        let header = self.header.borrow();
+        if reg < PCI_REG_DEVICE_SPECIFIC {
+            return header.read(reg);
+        }
+
+        let val = self.device.config_read(reg);
+        match header.capability_at(reg) {
+            Some(cap) => (val & 0xFFFF0000) | cap,
+            None => val,
+        }
+    }
+
+    fn config_write(&self, reg: u8, val: u32, mask: u32)
+    {
+        if reg < PCI_REG_DEVICE_SPECIFIC {
+            self.header.borrow_mut().write(reg, val, mask);
+            self.update_mappings();
+            return;
+        }
+
+        /* Capability ID and next pointer are read-only */
+        let mask = match self.header.borrow().capability_at(reg) {
+            Some(_) => mask & 0xFFFF0000,
+            None => mask,
+        };
+
+        if mask != 0 {
+            self.device.config_write(reg, val, mask);
+        }
+    }
+}
+
+impl vm::reset_handler for PciFunction
+{
+    fn reset(&self)
+    {
+        PciFunction::reset(self);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/*
+ * Forwards guest accesses within a BAR region to device model
+ */
+struct BarHandler
+{
+    device: Rc<pci_device>,
+    region: PciBarRegion,
+}
+
+impl vm::io_handler for BarHandler
+{
+    fn io_read(&self, port: u16, size: u8) -> vm::IoOperandType
+    {
+        let val = self.device.bar_read(self.region.bar, port as u32 - self.region.base, size);
+        match size {
+            1 => vm::IoOperandType::byte(val as u8),
+            2 => vm::IoOperandType::word(val as u16),
+            4 => vm::IoOperandType::dword(val),
+            _ => panic!(),
+        }
+    }
+
+    fn io_write(&self, port: u16, data: vm::IoOperandType)
+    {
+        let (val, size) = match data {
+            vm::IoOperandType::byte(v) => (v as u32, 1),
+            vm::IoOperandType::word(v) => (v as u32, 2),
+            vm::IoOperandType::dword(v) => (v, 4),
+        };
+
+        self.device.bar_write(self.region.bar, port as u32 - self.region.base, size, val);
+    }
+}
+
+impl vm::mmio_handler for BarHandler
+{
+    fn mmio_read(&self, addr: u64, size: u8) -> u64
+    {
+        self.device.bar_read(self.region.bar, (addr - self.region.base as u64) as u32, size) as u64
+    }
+
+    fn mmio_write(&self, addr: u64, size: u8, data: u64)
+    {
+        self.device.bar_write(self.region.bar, (addr - self.region.base as u64) as u32, size, data as u32);
+    }
+}
+
+/* BarMapper for the running VM, IO BARs take one port registration per port */
+fn map_bar(device: &Rc<pci_device>, region: &PciBarRegion, map: bool)
+{
+    match region.kind {
+        PciBarKind::Io => {
+            let ports = region.base as u16..(region.base + region.size) as u16;
+            if map {
+                let handler = Rc::new(BarHandler { device: device.clone(), region: *region });
+                for port in ports {
+                    vm::register_io_region(handler.clone(), port, 1);
+                }
+            } else {
+                for port in ports {
+                    vm::unregister_io_region(port);
+                }
+            }
+        },
+
+        PciBarKind::Memory => {
+            if map {
+                let handler = Rc::new(BarHandler { device: device.clone(), region: *region });
+                vm::register_mmio_region(handler, region.base as u64, region.size as u64);
+            } else {
+                vm::unregister_mmio_region(region.base as u64);
+            }
+        },
+    }
+}
+
+/**
+ * Register PCI function on root bus
+ */
+pub fn register_function(addr: vm::PciAddress, func: Rc<PciFunction>)
+{
+    vm::register_pci_function(addr, func.clone());
+    vm::register_reset_handler(func);
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/*
+ * Host bridge at 00:00.0, has nothing but a header
+ */
+struct HostBridge;
+
+impl pci_device for HostBridge
+{
+    fn bar_read(&self, _: usize, _: u32, _: u8) -> u32 {
+        unreachable!()
+    }
+
+    fn bar_write(&self, _: usize, _: u32, _: u8, _: u32) {
+        unreachable!()
+    }
+}
+
+fn host_bridge() -> PciFunction
+{
+    let mut header = PciHeader::new(HOST_BRIDGE_VENDOR, HOST_BRIDGE_DEVICE, HOST_BRIDGE_CLASS, HOST_BRIDGE_REVISION);
+    header.status = 0x0280;         // Fast back-to-back, medium DEVSEL
+    header.command = PCI_CMD_MEMORY | PCI_CMD_MASTER;
+
+    PciFunction::new(header, Rc::new(HostBridge))
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+// Scratch device identity
+const SCRATCH_VENDOR: u16       = 0x1234;
+const SCRATCH_DEVICE: u16       = 0x0001;
+const SCRATCH_CLASS: u32        = 0xFF0000;     // Unassigned class
+const SCRATCH_REGS: usize       = 4;
+
+/*
+ * Example PCI function: four dword scratch registers visible through
+ * an IO BAR (BAR0) and a memory BAR (BAR1).
+ */
+struct ScratchDevice
+{
+    regs: RefCell<[u32; SCRATCH_REGS]>,
+}
+
+impl pci_device for ScratchDevice
+{
+    fn bar_read(&self, _: usize, offset: u32, size: u8) -> u32 {
+        let offset = offset as usize % (SCRATCH_REGS * 4);
+        let val = self.regs.borrow()[offset / 4] >> ((offset % 4) * 8);
+
+        match size {
+            1 => val & 0xFF,
+            2 => val & 0xFFFF,
+            _ => val,
+        }
+    }
+
+    fn bar_write(&self, _: usize, offset: u32, size: u8, val: u32) {
+        let offset = offset as usize % (SCRATCH_REGS * 4);
+        let shift = (offset % 4) * 8;
+        let mask = match size {
+            1 => 0xFF,
+            2 => 0xFFFF,
+            _ => 0xFFFFFFFF,
+        } << shift;
+
+        let mut regs = self.regs.borrow_mut();
+        regs[offset / 4] = (regs[offset / 4] & !mask) | ((val << shift) & mask);
+    }
+}
+
+fn scratch_device() -> (PciHeader, Rc<pci_device>)
+{
+    let mut header = PciHeader::new(SCRATCH_VENDOR, SCRATCH_DEVICE, SCRATCH_CLASS, 0);
+    header.set_bar(0, PciBarKind::Io, (SCRATCH_REGS * 4) as u32);
+    header.set_bar(1, PciBarKind::Memory, 0x1000);
+    header.interrupt_pin = 1;
+
+    (header, Rc::new(ScratchDevice { regs: RefCell::new([0; SCRATCH_REGS]) }))
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
 struct PCIRootDev
 {
     pci_root: RefCell<PCIRoot>,
@@ -55,17 +671,229 @@ impl vm::io_handler for PCIRootDev
     fn io_write(&self, port: u16, data: vm::IoOperandType)
     {
         let mut dev = self.pci_root.borrow_mut();
-        dev.write32(port, data.unwrap_dword());
+        match data {
+            vm::IoOperandType::byte(v) => dev.write32(port, v as u32, 1),
+            vm::IoOperandType::word(v) => dev.write32(port, v as u32, 2),
+            vm::IoOperandType::dword(v) => dev.write32(port, v, 4),
+        }
+    }
+}
+
+#[cfg(test)]
+mod pci_test
+{
+    use super::*;
+
+    fn make_dev() -> PCIRootDev {
+        let bus = Rc::new(RefCell::new(vm::PciBus::new()));
+        bus.borrow_mut().register(vm::PciAddress { bus: 0, device: 0, function: 0 }, Rc::new(host_bridge()));
+
+        PCIRootDev {
+            pci_root: RefCell::new(PCIRoot::new(bus)),
+        }
+    }
+
+    fn select(dev: &PCIRootDev, bus: u32, device: u32, function: u32, reg: u32) {
+        let addr = PCI_ADDR_ENABLE | bus << 16 | device << 11 | function << 8 | reg;
+        vm::io_handler::io_write(dev, PCI_CONFIG_ADDRESS, vm::IoOperandType::dword(addr));
+    }
+
+    #[test] fn host_bridge_id() {
+        let dev = make_dev();
+        select(&dev, 0, 0, 0, 0);
+
+        assert!(vm::io_handler::io_read(&dev, PCI_CONFIG_DATA, 4).unwrap_dword() == 0x12378086);
+        assert!(vm::io_handler::io_read(&dev, PCI_CONFIG_DATA, 2).unwrap_word() == 0x8086);
+        assert!(vm::io_handler::io_read(&dev, PCI_CONFIG_DATA + 2, 2).unwrap_word() == 0x1237);
+        assert!(vm::io_handler::io_read(&dev, PCI_CONFIG_DATA, 1).unwrap_byte() == 0x86);
+        assert!(vm::io_handler::io_read(&dev, PCI_CONFIG_DATA + 1, 1).unwrap_byte() == 0x80);
+        assert!(vm::io_handler::io_read(&dev, PCI_CONFIG_DATA + 3, 1).unwrap_byte() == 0x12);
+
+        select(&dev, 0, 0, 0, 0x08);
+        assert!(vm::io_handler::io_read(&dev, PCI_CONFIG_DATA, 4).unwrap_dword() == 0x06000002);
+        assert!(vm::io_handler::io_read(&dev, PCI_CONFIG_DATA + 3, 1).unwrap_byte() == 0x06);
+
+        /* Config address reads back without reserved bits */
+        vm::io_handler::io_write(&dev, PCI_CONFIG_ADDRESS, vm::IoOperandType::dword(0xFFFFFFFF));
+        assert!(vm::io_handler::io_read(&dev, PCI_CONFIG_ADDRESS, 4).unwrap_dword() == 0x80FFFFFC);
+    }
+
+    #[test] fn command_write() {
+        let dev = make_dev();
+        select(&dev, 0, 0, 0, 0x04);
+
+        /* Byte write to status half leaves command alone */
+        vm::io_handler::io_write(&dev, PCI_CONFIG_DATA + 2, vm::IoOperandType::byte(0xFF));
+        assert!(vm::io_handler::io_read(&dev, PCI_CONFIG_DATA, 4).unwrap_dword() == 0x02800006);
+
+        vm::io_handler::io_write(&dev, PCI_CONFIG_DATA, vm::IoOperandType::word(0x0007));
+        assert!(vm::io_handler::io_read(&dev, PCI_CONFIG_DATA, 2).unwrap_word() == 0x0007);
+    }
+
+    #[test] fn empty_slots_float() {
+        let dev = make_dev();
+
+        select(&dev, 0, 1, 0, 0);
+        assert!(vm::io_handler::io_read(&dev, PCI_CONFIG_DATA, 4).unwrap_dword() == 0xFFFFFFFF);
+        assert!(vm::io_handler::io_read(&dev, PCI_CONFIG_DATA + 2, 2).unwrap_word() == 0xFFFF);
+
+        select(&dev, 0, 0, 1, 0);
+        assert!(vm::io_handler::io_read(&dev, PCI_CONFIG_DATA, 2).unwrap_word() == 0xFFFF);
+
+        select(&dev, 1, 0, 0, 0);
+        assert!(vm::io_handler::io_read(&dev, PCI_CONFIG_DATA + 1, 1).unwrap_byte() == 0xFF);
+
+        /* Config cycles disabled */
+        vm::io_handler::io_write(&dev, PCI_CONFIG_ADDRESS, vm::IoOperandType::dword(0));
+        assert!(vm::io_handler::io_read(&dev, PCI_CONFIG_DATA, 4).unwrap_dword() == 0xFFFFFFFF);
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+
+    thread_local! {
+        static MAPPED: RefCell<Vec<PciBarRegion>> = RefCell::new(Vec::new());
+    }
+
+    fn record_bar(_: &Rc<pci_device>, region: &PciBarRegion, map: bool) {
+        MAPPED.with(|m| {
+            let mut m = m.borrow_mut();
+            if map {
+                assert!(!m.contains(region));
+                m.push(*region);
+            } else {
+                assert!(m.contains(region));
+                m.retain(|r| r != region);
+            }
+        });
+    }
+
+    fn mapped() -> Vec<PciBarRegion> {
+        MAPPED.with(|m| m.borrow().clone())
+    }
+
+    const SCRATCH_SLOT: u32 = 3;
+
+    fn make_scratch_dev(header: PciHeader, scratch: Rc<pci_device>) -> (PCIRootDev, Rc<PciFunction>) {
+        let dev = make_dev();
+        let func = Rc::new(PciFunction::with_mapper(header, scratch, record_bar));
+        dev.pci_root.borrow().bus.borrow_mut().register(
+            vm::PciAddress { bus: 0, device: SCRATCH_SLOT as u8, function: 0 }, func.clone());
+        (dev, func)
+    }
+
+    fn cfg_read(dev: &PCIRootDev, reg: u32) -> u32 {
+        select(dev, 0, SCRATCH_SLOT, 0, reg);
+        vm::io_handler::io_read(dev, PCI_CONFIG_DATA, 4).unwrap_dword()
+    }
+
+    fn cfg_write(dev: &PCIRootDev, reg: u32, val: u32) {
+        select(dev, 0, SCRATCH_SLOT, 0, reg);
+        vm::io_handler::io_write(dev, PCI_CONFIG_DATA, vm::IoOperandType::dword(val));
+    }
+
+    #[test] fn bar_sizing() {
+        let (header, scratch) = scratch_device();
+        let (dev, _) = make_scratch_dev(header, scratch);
+
+        assert!(cfg_read(&dev, 0x00) == 0x00011234);
+        assert!(cfg_read(&dev, 0x08) == 0xFF000000);
+        assert!(cfg_read(&dev, 0x10) == 0x00000001);
+        assert!(cfg_read(&dev, 0x14) == 0x00000000);
+
+        /* All ones reads back size mask and BAR type */
+        for bar in 0..PCI_NUM_BARS as u32 {
+            cfg_write(&dev, 0x10 + bar * 4, 0xFFFFFFFF);
+        }
+        assert!(cfg_read(&dev, 0x10) == 0xFFFFFFF1);
+        assert!(cfg_read(&dev, 0x14) == 0xFFFFF000);
+        for bar in 2..PCI_NUM_BARS as u32 {
+            assert!(cfg_read(&dev, 0x10 + bar * 4) == 0);
+        }
+
+        /* Address bits below size are hardwired */
+        cfg_write(&dev, 0x10, 0xC00F);
+        assert!(cfg_read(&dev, 0x10) == 0xC001);
+        cfg_write(&dev, 0x14, 0xFEB00FFF);
+        assert!(cfg_read(&dev, 0x14) == 0xFEB00000);
+
+        /* Interrupt line is writable, pin isn't */
+        cfg_write(&dev, 0x3C, 0xFFFF000B);
+        assert!(cfg_read(&dev, 0x3C) == 0x0000010B);
+
+        assert!(mapped().is_empty());
+    }
+
+    #[test] fn bar_relocation() {
+        let (header, scratch) = scratch_device();
+        let (dev, func) = make_scratch_dev(header, scratch.clone());
+        let io = PciBarRegion { bar: 0, kind: PciBarKind::Io, base: 0xC000, size: 16 };
+        let mem = PciBarRegion { bar: 1, kind: PciBarKind::Memory, base: 0xFEB00000, size: 0x1000 };
+
+        /* Nothing is decoded until enabled in command register */
+        cfg_write(&dev, 0x10, 0xC000);
+        cfg_write(&dev, 0x14, 0xFEB00000);
+        assert!(mapped().is_empty());
+
+        cfg_write(&dev, 0x04, PCI_CMD_IO as u32);
+        assert!(mapped() == [io]);
+        cfg_write(&dev, 0x04, (PCI_CMD_IO | PCI_CMD_MEMORY) as u32);
+        assert!(mapped() == [io, mem]);
+
+        /* Both BARs reach the same registers */
+        scratch.bar_write(0, 4, 4, 0xCAFEF00D);
+        assert!(scratch.bar_read(1, 4, 4) == 0xCAFEF00D);
+        assert!(scratch.bar_read(1, 0x1006, 2) == 0xCAFE);
+
+        /* Moving a live BAR remaps it */
+        cfg_write(&dev, 0x10, 0xC100);
+        let moved = PciBarRegion { base: 0xC100, ..io };
+        assert!(mapped() == [mem, moved]);
+
+        /* Sizing while decoding is enabled doesn't claim all-ones address */
+        cfg_write(&dev, 0x14, 0xFFFFFFFF);
+        assert!(mapped() == [moved]);
+        cfg_write(&dev, 0x14, 0xFEC00000);
+        assert!(mapped() == [moved, PciBarRegion { base: 0xFEC00000, ..mem }]);
+
+        /* Disabling decode drops regions, reset clears BARs */
+        cfg_write(&dev, 0x04, PCI_CMD_MEMORY as u32);
+        assert!(mapped() == [PciBarRegion { base: 0xFEC00000, ..mem }]);
+        func.reset();
+        assert!(mapped().is_empty());
+        assert!(cfg_read(&dev, 0x04) & 0xFFFF == 0);
+        assert!(cfg_read(&dev, 0x10) == 0x00000001);
+    }
+
+    #[test] fn capability_list() {
+        let (mut header, scratch) = scratch_device();
+        header.add_capability(0x09, 0x40);
+        header.add_capability(0x05, 0x48);
+        let (dev, _) = make_scratch_dev(header, scratch);
+
+        assert!(cfg_read(&dev, 0x04) >> 16 & PCI_STATUS_CAP_LIST as u32 != 0);
+        assert!(cfg_read(&dev, 0x34) == 0x40);
+        assert!(cfg_read(&dev, 0x40) == 0x4809);
+        assert!(cfg_read(&dev, 0x48) == 0x0005);
+
+        /* Header part of capability is read-only */
+        cfg_write(&dev, 0x40, 0);
+        assert!(cfg_read(&dev, 0x40) == 0x4809);
     }
 }
 
 pub fn init()
 {
+    register_function(vm::PciAddress { bus: 0, device: 0, function: 0 }, Rc::new(host_bridge()));
+
+    let (header, scratch) = scratch_device();
+    register_function(vm::PciAddress { bus: 0, device: 3, function: 0 }, Rc::new(PciFunction::new(header, scratch)));
+
 	let dev = Rc::new(PCIRootDev {
-        pci_root: RefCell::new(PCIRoot::new()),
+        pci_root: RefCell::new(PCIRoot::new(vm::pci_bus())),
     });
 
     vm::register_io_region(dev.clone(), PCI_CONFIG_ADDRESS, 4);
-    vm::register_io_region(dev.clone(), PCI_CONFIG_DATA, 4);
+    for port in PCI_CONFIG_DATA..(PCI_CONFIG_DATA + 4) {
+        vm::register_io_region(dev.clone(), port, 1);
+    }
 }
-
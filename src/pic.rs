@@ -1,11 +1,18 @@
 /*
  * PIC emulation
+ *
+ * INT output of the cascade can be disconnected from vcpu, which IMCR does in APIC mode. Disconnected chips
+ * keep latching IRR but raise nothing, vectors raised and not injected yet are taken back out of vm queue and
+ * everything still in IRR is raised again when output is connected back.
  */
 
 use vm;
+use eventlog;
+use devstate;
 
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::fmt;
 
 const PIC_MASTER_CMD: u16 = 0x20;
 const PIC_MASTER_DATA: u16 = 0x21;
@@ -16,10 +23,39 @@ const ICW1_INIT: u8 = 0x10;
 const ICW1_ICW4: u8 = 0x01;
 const ICW4_8086: u8 = 0x01;
 
+/* Init sequence position by next_icw as state version 2 names it */
+const INIT_STEPS: [&'static str; 5] = ["reset", "done", "icw2", "icw3", "icw4"];
+
+/* Chip state fields version 1 had next_icw number in place of init step name */
+const STATE_VERSION: u32 = 2;
+
 const PIC_READ_IRR: u8 = 0x0A;
 const PIC_READ_ISR: u8 = 0x0B;
 const PIC_EOI: u8 = 0x20;
 
+/**
+ * One PIC chip as seen by inspection
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct I8259State
+{
+    pub irr: u8,
+    pub isr: u8,
+    pub imr: u8,
+    pub offset: u8,         // Interrupt vector base
+    pub initialized: bool,
+    pub output: bool,       // INT output reaches vcpu
+}
+
+/* Registers in one line, the way monitor and crash reports show them */
+impl fmt::Display for I8259State
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "irr={:02x} imr={:02x} isr={:02x} vec={:02x} init={} out={}",
+               self.irr, self.imr, self.isr, self.offset, self.initialized as u8, self.output as u8)
+    }
+}
+
 /**
  * i8259 PIC chip
  */
@@ -32,6 +68,9 @@ struct I8259A
     icw3: u8,   // ICW3 value during initialization (cascade IRQ)
     next_icw: usize,    // During init, next ICW word expected during init
     cmd_latch: u8,      // Latched value to be read next time from command port
+    output: bool,       // INT output reaches vcpu
+    irr_ids: [Option<u64>; 8],  // IRQ assertion each IRR bit latched, for the event log
+    isr_ids: [Option<u64>; 8],  // Same for interrupts in service
 }
 
 impl I8259A 
@@ -45,6 +84,9 @@ impl I8259A
             icw3: 0,
             next_icw: 0,
             cmd_latch: 0,
+            output: true,
+            irr_ids: [None; 8],
+            isr_ids: [None; 8],
         }
     }
 
@@ -56,8 +98,82 @@ impl I8259A
         self.icw3
     }
 
-    /* Assert an IRQ line */
-    fn assert_irq(&mut self, irq: u8) {
+    fn state(&self) -> I8259State {
+        I8259State {
+            irr: self.irr,
+            isr: self.isr,
+            imr: self.imr,
+            offset: self.offset,
+            initialized: self.is_initialized(),
+            output: self.output,
+        }
+    }
+
+    /* Registers and init sequence position for inspection */
+    fn device_state(&self) -> devstate::DeviceState {
+        devstate::DeviceState::new()
+            .hex("irr", self.irr as u64, 2)
+            .hex("isr", self.isr as u64, 2)
+            .hex("imr", self.imr as u64, 2)
+            .hex("offset", self.offset as u64, 2)
+            .hex("icw3", self.icw3 as u64, 2)
+            .bool("initialized", self.is_initialized())
+            .text("init", INIT_STEPS[self.next_icw])
+            .hex("cmd_latch", self.cmd_latch as u64, 2)
+            .bool("output", self.output)
+    }
+
+    /* Registers device_state() gave, assertion ids don't outlive a snapshot */
+    fn restore(&mut self, state: &devstate::DeviceState) -> Result<(), String> {
+        let init = try!(state.get_text("init"));
+        let next_icw = try!(INIT_STEPS.iter().position(|&step| step == init).ok_or(format!("Bad init step {}", init)));
+
+        *self = I8259A {
+            irr: try!(state.get_number("irr")) as u8,
+            isr: try!(state.get_number("isr")) as u8,
+            imr: try!(state.get_number("imr")) as u8,
+            offset: try!(state.get_number("offset")) as u8,
+            icw3: try!(state.get_number("icw3")) as u8,
+            next_icw: next_icw,
+            cmd_latch: try!(state.get_number("cmd_latch")) as u8,
+            output: try!(state.get_bool("output")),
+            ..I8259A::default()
+        };
+        Ok(())
+    }
+
+    /* Version 1 state of a chip as version 2, next_icw number goes over to init step in its place */
+    fn migrate_v1(state: &devstate::DeviceState) -> Result<devstate::DeviceState, String> {
+        let next_icw = try!(state.get_number("next_icw"));
+        let step = try!(INIT_STEPS.get(next_icw as usize).ok_or(format!("Bad next_icw {}", next_icw)));
+
+        let mut state = state.clone();
+        for field in state.fields.iter_mut().filter(|field| field.0 == "next_icw") {
+            *field = (String::from("init"), devstate::StateValue::Text(String::from(*step)));
+        }
+        Ok(state)
+    }
+
+    /* Raise everything latched in IRR, as after a restore */
+    fn raise_pending(&self) {
+        if !self.is_initialized() || !self.output {
+            return;
+        }
+
+        for i in 0..8 {
+            if (self.irr & (1_u8 << i)) != 0 {
+                self.raise(i);
+            }
+        }
+    }
+
+    /* IRQ line assertion would be latched, or merge into one latched already */
+    fn accepts_irq(&self, irq: u8) -> bool {
+        self.is_initialized() && (self.imr & (1u8 << irq)) == 0
+    }
+
+    /* Assert an IRQ line, assertion merging into a latched one leaves its id behind */
+    fn assert_irq(&mut self, irq: u8, id: Option<u64>) {
         assert!(irq < 8);
 
         if !self.is_initialized() {
@@ -72,10 +188,45 @@ impl I8259A
         /* We only update IRR here because we're not sure when
          * interrupt event is going to be injected in guest.
          * That what ack is for. */
+        if (self.irr & mask) == 0 {
+            self.irr_ids[irq as usize] = id;
+        }
         self.irr |= mask;
 
         /* Notify VM state we need to inject this vector */
-        vm::raise_external_interrupt(irq + self.offset);
+        if self.output {
+            self.raise(irq);
+        }
+    }
+
+    /* Raise vector of latched IRQ to vcpu */
+    fn raise(&self, irq: u8) {
+        let vector = irq + self.offset;
+        let id = self.irr_ids[irq as usize];
+        eventlog::emit(|| eventlog::Event::PicRaise { vector: vector, id: id });
+        vm::raise_external_interrupt(vector, id);
+    }
+
+    /* Connect or disconnect INT output, IRR stays latched either way */
+    fn set_output(&mut self, connected: bool) {
+        if self.output == connected {
+            return;
+        }
+
+        self.output = connected;
+        if !self.is_initialized() {
+            return;
+        }
+
+        for i in 0..8 {
+            if (self.irr & (1_u8 << i)) != 0 {
+                if connected {
+                    self.raise(i);
+                } else {
+                    vm::cancel_external_interrupt(i + self.offset);
+                }
+            }
+        }
     }
 
     /* Acknowledge interrupt delivery to guest */
@@ -86,9 +237,13 @@ impl I8259A
         /* Acked bit should be in IRR */
         assert!(0 != (self.irr & (1_u8 << irq)));
 
+        let id = self.irr_ids[irq as usize].take();
+        eventlog::emit(|| eventlog::Event::PicAck { vector: vec, id: id });
+
         /* Move IRR bit to ISR */
         self.isr |= 1_u8 << irq;
         self.irr &= !(1_u8 << irq);
+        self.isr_ids[irq as usize] = id;
     }
 
     /* Write to command port */
@@ -111,7 +266,7 @@ impl I8259A
              *    Don't touch IRR value.
              * 2. Upon completed init reinject all pending IRR interrupts with updated offsets.
              */
-            if self.irr != 0 {
+            if self.irr != 0 && self.output {
                 vm::cancel_all_external_interrupts();
             }
 
@@ -133,6 +288,9 @@ impl I8259A
                 }
 
                 self.isr = self.isr & !(1 << pos);
+                let vector = self.offset + pos;
+                let id = self.isr_ids[pos as usize].take();
+                eventlog::emit(|| eventlog::Event::PicEoi { vector: vector, id: id });
             }
         } else {
             debug!("Unsupported PIC command {:x}", cmd);
@@ -169,8 +327,8 @@ impl I8259A
                 /* Re-inject pre-reset pending interrupts from IRR.
                  * See comments in write_command ICW1 */
                 for i in 0..8 {
-                    if (self.irr & (1_u8 << i)) != 0 {
-                        vm::raise_external_interrupt(i + self.offset);
+                    if self.output && (self.irr & (1_u8 << i)) != 0 {
+                        self.raise(i);
                     }
                 }
             },
@@ -202,17 +360,31 @@ impl PIC
         }
     }
 
-    fn assert_irq(&mut self, irq: u8) {
+    fn assert_irq(&mut self, irq: u8, id: Option<u64>) {
         assert!(irq <= 15);
         if irq < 8 {
-            self.master.assert_irq(irq);
+            self.master.assert_irq(irq, id);
         } else {
+            /* Assertion is delivered by the slave vector, cascade line doesn't take the id */
             let slave_irq = self.master.slave_irq();
-            self.master.assert_irq(slave_irq);
-            self.slave.assert_irq(irq - 8);
+            self.master.assert_irq(slave_irq, None);
+            self.slave.assert_irq(irq - 8, id);
+        }
+    }
+
+    fn accepts_irq(&self, irq: u8) -> bool {
+        if irq < 8 {
+            self.master.accepts_irq(irq)
+        } else {
+            self.master.accepts_irq(self.master.slave_irq()) && self.slave.accepts_irq(irq - 8)
         }
     }
 
+    fn set_output(&mut self, connected: bool) {
+        self.master.set_output(connected);
+        self.slave.set_output(connected);
+    }
+
     fn ack(&mut self, vec: u8) {
         if vec >= self.slave.offset {
             self.slave.ack(vec);
@@ -256,6 +428,49 @@ mod i8259a_test
         let dev = init_common(0x08, 0xAB, 0x02);
         assert!(dev.is_initialized());
     }
+
+    /* Disconnected output keeps latching IRR */
+    #[test] fn output_disconnected() {
+        let mut dev = init_common(0x08, 0xEF, 0x02);
+        dev.set_output(false);
+        dev.assert_irq(4, None);
+        dev.assert_irq(5, None);
+
+        dev.write_command(super::PIC_READ_IRR);
+        assert!(dev.read_command() == 0x10);
+        assert!(dev.accepts_irq(4) && !dev.accepts_irq(5));
+    }
+
+    #[test] fn state() {
+        let dev = I8259A::default();
+        assert!(!dev.state().initialized);
+
+        let mut dev = init_common(0x70, 0xFE, 0x02);
+        dev.set_output(false);
+        dev.assert_irq(0, None);
+        let state = dev.state();
+        assert!(state.initialized && !state.output);
+        assert!(state.irr == 0x01 && state.isr == 0 && state.imr == 0xFE && state.offset == 0x70);
+    }
+
+    /* Assertion id moves along with its IRQ until EOI */
+    #[test] fn assertion_ids() {
+        let mut dev = init_common(0x08, 0x00, 0x02);
+        dev.set_output(false);
+        dev.assert_irq(4, Some(7));
+        dev.assert_irq(4, Some(8));
+        assert!(dev.irr_ids[4] == Some(7));
+
+        dev.ack(0x0C);
+        assert!(dev.irr_ids[4].is_none() && dev.isr_ids[4] == Some(7));
+
+        /* Next assertion latches while the first one is in service */
+        dev.assert_irq(4, Some(9));
+        assert!(dev.irr_ids[4] == Some(9));
+
+        dev.write_command(super::PIC_EOI);
+        assert!(dev.isr == 0 && dev.isr_ids[4].is_none() && dev.irr_ids[4] == Some(9));
+    }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -304,16 +519,233 @@ impl vm::io_handler for PICDev
 
 impl vm::interrupt_controller for PICDev
 {
-    fn assert_irq(&self, irq: u8)
+    fn assert_irq(&self, irq: u8, id: Option<u64>)
     {
         let mut dev = self.pic.borrow_mut();
-        dev.assert_irq(irq)
+        dev.assert_irq(irq, id)
     }
 
-    fn ack(&self, vec: u8)
+    fn ack(&self, vec: u8) -> u8
     {
         let mut dev = self.pic.borrow_mut();
-        dev.ack(vec)
+        dev.ack(vec);
+        vec
+    }
+}
+
+impl vm::state_handler for PICDev
+{
+    fn device_state(&self) -> Option<devstate::DeviceState>
+    {
+        let pic = match self.pic.try_borrow() {
+            Ok(pic) => pic,
+            Err(_) => return None,
+        };
+
+        Some(devstate::DeviceState::new()
+            .object("master", pic.master.device_state())
+            .object("slave", pic.slave.device_state()))
+    }
+
+    fn state_version(&self) -> u32
+    {
+        STATE_VERSION
+    }
+
+    fn migrate_state(&self, version: u32, state: devstate::DeviceState) -> Result<devstate::DeviceState, String>
+    {
+        match version {
+            1 => Ok(devstate::DeviceState::new()
+                    .object("master", try!(I8259A::migrate_v1(try!(state.get_object("master")))))
+                    .object("slave", try!(I8259A::migrate_v1(try!(state.get_object("slave")))))),
+            _ => Err(format!("nothing migrates version {}", version)),
+        }
+    }
+
+    fn unknown_fields(&self) -> devstate::UnknownFields
+    {
+        devstate::UnknownFields::Reject
+    }
+
+    fn restore_state(&self, state: &devstate::DeviceState) -> Result<(), String>
+    {
+        let mut pic = self.pic.borrow_mut();
+        try!(pic.master.restore(try!(state.get_object("master"))));
+        pic.slave.restore(try!(state.get_object("slave")))
+    }
+
+    fn restored(&self)
+    {
+        let pic = self.pic.borrow();
+        pic.master.raise_pending();
+        pic.slave.raise_pending();
+    }
+}
+
+#[cfg(test)]
+mod pic_dev_test
+{
+    use super::*;
+    use vm::{io_handler, state_handler};
+    use devstate::StateValue;
+    use snapshot;
+
+    fn chip_field(state: &devstate::DeviceState, chip: &str, field: &str) -> StateValue {
+        match state.get(chip) {
+            Some(&StateValue::Object(ref chip)) => chip.get(field).unwrap().clone(),
+            _ => panic!("no {}", chip),
+        }
+    }
+
+    /* Master stopped between ICW2 and ICW3 */
+    #[test] fn state_mid_init() {
+        let dev = PICDev { pic: RefCell::new(PIC::new()) };
+        dev.io_write(PIC_MASTER_CMD, vm::IoOperandType::byte(ICW1_INIT | ICW1_ICW4));
+        dev.io_write(PIC_MASTER_DATA, vm::IoOperandType::byte(0x20));
+
+        let state = dev.device_state().unwrap();
+        assert!(chip_field(&state, "master", "offset") == StateValue::Hex(0x20, 2));
+        assert!(chip_field(&state, "master", "initialized") == StateValue::Bool(false));
+        assert!(chip_field(&state, "master", "init") == StateValue::Text(String::from("icw3")));
+        assert!(chip_field(&state, "slave", "init") == StateValue::Text(String::from("reset")));
+
+        let json = state.to_json();
+        assert!(json.contains("\"master\": {\n    \"irr\": \"0x00\",\n"));
+        assert!(json.contains("\"offset\": \"0x20\""));
+
+        /* Device in the middle of an access doesn't show torn state */
+        let _busy = dev.pic.borrow_mut();
+        assert!(dev.device_state().is_none());
+    }
+
+    /* State read back from JSON puts both chips back where they were */
+    #[test] fn restore() {
+        let dev = PICDev { pic: RefCell::new(PIC::new()) };
+        dev.io_write(PIC_MASTER_CMD, vm::IoOperandType::byte(ICW1_INIT | ICW1_ICW4));
+        dev.io_write(PIC_MASTER_DATA, vm::IoOperandType::byte(0x20));
+        dev.io_write(PIC_MASTER_DATA, vm::IoOperandType::byte(0x04));
+        dev.io_write(PIC_MASTER_DATA, vm::IoOperandType::byte(ICW4_8086));
+        dev.io_write(PIC_MASTER_DATA, vm::IoOperandType::byte(0xFB));
+        dev.io_write(PIC_SLAVE_CMD, vm::IoOperandType::byte(ICW1_INIT | ICW1_ICW4));
+        dev.io_write(PIC_SLAVE_CMD, vm::IoOperandType::byte(PIC_READ_ISR));
+        let json = dev.device_state().unwrap().to_json();
+
+        let restored = PICDev { pic: RefCell::new(PIC::new()) };
+        restored.restore_state(&devstate::DeviceState::from_json(&json).unwrap()).unwrap();
+        assert!(restored.device_state().unwrap().to_json() == json);
+        assert!(restored.pic.borrow().master.state() == dev.pic.borrow().master.state());
+        assert!(restored.pic.borrow().slave.next_icw == 2);
+
+        assert!(restored.restore_state(&devstate::DeviceState::new()).is_err());
+        let state = devstate::DeviceState::new().object("master", devstate::DeviceState::new().text("init", "icw5"));
+        assert!(restored.restore_state(&state).is_err());
+    }
+
+    /* State goes through either codec whole, a field PIC doesn't know fails its restore */
+    #[test] fn codecs() {
+        let dev = PICDev { pic: RefCell::new(PIC::new()) };
+        dev.io_write(PIC_MASTER_CMD, vm::IoOperandType::byte(ICW1_INIT | ICW1_ICW4));
+        dev.io_write(PIC_MASTER_DATA, vm::IoOperandType::byte(0x20));
+        dev.io_write(PIC_MASTER_DATA, vm::IoOperandType::byte(0x04));
+        dev.io_write(PIC_MASTER_DATA, vm::IoOperandType::byte(ICW4_8086));
+        dev.io_write(PIC_MASTER_DATA, vm::IoOperandType::byte(0xFB));
+        dev.io_write(PIC_SLAVE_CMD, vm::IoOperandType::byte(ICW1_INIT | ICW1_ICW4));
+        let state = dev.device_state().unwrap();
+
+        for codec in &[&devstate::JsonCodec as &devstate::state_codec, &devstate::BinaryCodec] {
+            let decoded = codec.decode(&codec.encode(&state)).unwrap();
+            assert!(decoded == state);
+
+            let restored = PICDev { pic: RefCell::new(PIC::new()) };
+            restored.restore_state(&decoded).unwrap();
+            assert!(restored.device_state().unwrap() == state);
+        }
+
+        assert!(dev.unknown_fields() == devstate::UnknownFields::Reject);
+        let mut newer = state.clone();
+        for field in newer.fields.iter_mut().filter(|field| field.0 == "slave") {
+            if let StateValue::Object(ref mut chip) = field.1 {
+                chip.fields.push((String::from("elcr"), StateValue::Hex(0, 2)));
+            }
+        }
+        assert!(newer.clone().known_fields(&state, dev.unknown_fields()).unwrap_err() == "Unknown field slave.elcr in device state");
+        assert!(newer.known_fields(&state, devstate::UnknownFields::Ignore) == Ok(state));
+    }
+
+    /* Version 1 state has init sequence position as next_icw number */
+    #[test] fn migrate_v1() {
+        let dev = PICDev { pic: RefCell::new(PIC::new()) };
+        let chip = |next_icw| devstate::DeviceState::new().hex("irr", 0x01, 2).number("next_icw", next_icw).bool("output", true);
+        let v1 = devstate::DeviceState::new().object("master", chip(1)).object("slave", chip(3));
+
+        let v2 = dev.migrate_state(1, v1).unwrap();
+        assert!(chip_field(&v2, "master", "init") == StateValue::Text(String::from("done")));
+        assert!(chip_field(&v2, "slave", "init") == StateValue::Text(String::from("icw3")));
+        assert!(v2.get_object("slave").unwrap().fields.iter().map(|field| field.0.as_str()).collect::<Vec<&str>>() == vec!["irr", "init", "output"]);
+
+        let bad = devstate::DeviceState::new().object("master", chip(5)).object("slave", chip(1));
+        assert!(dev.migrate_state(1, bad).unwrap_err() == "Bad next_icw 5");
+        assert!(dev.migrate_state(2, devstate::DeviceState::new()).is_err());
+    }
+
+    /* Every PIC state snapshots of older versions have restores */
+    #[test] fn fixtures() {
+        let states = snapshot::fixture_states("pic");
+        assert!(!states.is_empty());
+        for (version, state) in states {
+            let dev = PICDev { pic: RefCell::new(PIC::new()) };
+            let state = snapshot::migrate_state(&dev, "pic", version, state).unwrap();
+            dev.restore_state(&state).unwrap();
+            assert!(dev.device_state().unwrap() == state);
+        }
+
+        /* Version 1 base has slave stopped after ICW2 and timer IRQ pending on master */
+        let (_, base) = snapshot::fixture_states("pic").remove(0);
+        let dev = PICDev { pic: RefCell::new(PIC::new()) };
+        dev.restore_state(&snapshot::migrate_state(&dev, "pic", 1, base).unwrap()).unwrap();
+        let pic = dev.pic.borrow();
+        assert!(pic.master.is_initialized() && pic.master.irr == 0x01 && pic.master.imr == 0x3C && pic.master.offset == 0x08);
+        assert!(pic.slave.next_icw == 3 && pic.slave.offset == 0x70);
+    }
+}
+
+static mut PIC_DEV: Option<*const PICDev> = None;
+
+/**
+ * Connect or disconnect PIC output from vcpu, pending IRR stays latched
+ */
+pub fn set_output_connected(connected: bool)
+{
+    unsafe {
+        if let Some(dev) = PIC_DEV {
+            (*dev).pic.borrow_mut().set_output(connected);
+        }
+    }
+}
+
+/**
+ * PIC would take an assertion of IRQ line, it is initialized and has the line unmasked
+ */
+pub fn accepts_irq(irq: u8) -> bool
+{
+    unsafe {
+        match PIC_DEV {
+            Some(dev) => (*dev).pic.borrow().accepts_irq(irq),
+            None => false,
+        }
+    }
+}
+
+/**
+ * Master and slave chip state, None without PIC or while it is being accessed
+ */
+pub fn state() -> Option<[I8259State; 2]>
+{
+    unsafe {
+        match PIC_DEV {
+            Some(dev) => (*dev).pic.try_borrow().ok().map(|pic| [pic.master.state(), pic.slave.state()]),
+            None => None,
+        }
     }
 }
 
@@ -323,7 +755,12 @@ pub fn init()
         pic: RefCell::new(PIC::new()),
     });
 
+    unsafe {
+        PIC_DEV = Some(&*dev as *const PICDev);
+    }
+
     vm::register_interrupt_controller(dev.clone());
+    vm::register_device_state("pic", dev.clone());
 
     vm::register_io_region(dev.clone(), PIC_MASTER_CMD, 1);
     vm::register_io_region(dev.clone(), PIC_MASTER_DATA, 1);
@@ -5,20 +5,50 @@
 use vm;
 
 use std::rc::Rc;
-use std::cell::RefCell;
-use time;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::mem;
+use std::cmp;
 use event;
+use config::TickPolicy;
+use clock::{self, virtual_clock, VcpuClock};
 
 // PIT internal oscilator freq
 const PIT_FREQ_HZ: u64 = 1193182;
 const PIT_FREQ_MHZ: f64 = 1.193182;
 
+const PIT_IRQ: u8 = 0;
+
 // PIT IO ports
 const PIT_CH0:u16 = 0x40;
 const PIT_CH1:u16 = 0x41;
 const PIT_CH2:u16 = 0x42;
 const PIT_CMD:u16 = 0x43;
 
+// System control port B, shares channel 2 gate and output with speaker logic
+const SYSCTL_PORT_B: u16        = 0x61;
+const SYSCTL_B_CH2_GATE: u8     = 0x01;
+const SYSCTL_B_SPEAKER: u8      = 0x02;
+const SYSCTL_B_PARITY_DIS: u8   = 0x04; // Writing 1 clears and disables parity check
+const SYSCTL_B_IOCHK_DIS: u8    = 0x08; // Writing 1 clears and disables channel check
+const SYSCTL_B_REFRESH: u8      = 0x10;
+const SYSCTL_B_CH2_OUT: u8      = 0x20;
+const SYSCTL_B_IOCHK: u8        = 0x40; // Never set, we have no channel check errors
+const SYSCTL_B_PARITY: u8       = 0x80; // Never set, we have no parity errors
+const SYSCTL_B_WRITABLE: u8     = SYSCTL_B_CH2_GATE | SYSCTL_B_SPEAKER | SYSCTL_B_PARITY_DIS | SYSCTL_B_IOCHK_DIS;
+
+// Refresh request line toggles every 15 us
+const REFRESH_HALF_PERIOD_NS: u64 = 15000;
+
+// Speaker tones kept for inspection, oldest are dropped first
+const SPEAKER_MAX_BEEPS: usize = 256;
+
+// Late interrupt catch-up
+const TICK_MAX_DEBT: u64 = 1000;            // Coalesce policy forgets ticks owed beyond this
+const TICK_COALESCE_SHORTEN: u64 = 8;       // Repaying periods are 1/8 shorter
+const TICK_MAX_BURST: u64 = 16;             // Inject-all policy delivers this many missed ticks at most
+const TICK_BURST_GAP: u64 = 60;             // PIT ticks between burst interrupts, about 50 us
+
 // Mode/Command bits 6-7
 const PIT_SELECT_CH0: u8 = 0b00;
 const PIT_SELECT_CH1: u8 = 0b01;
@@ -110,41 +140,57 @@ enum PITChannelAccess
 #[derive(Copy, Clone)]
 struct PITChannel
 {
-    reload: u16,
-    count: u16,
+    reload: u16,            // Programmed reload value
+    period: u64,            // Reload value in effect, in ticks (0 reload means 0x10000)
+    start: u64,             // Tick at which counter holds full period value after loading
+    next_reload: Option<(u64, u64)>, // Periodic modes switch to new period at (tick, period)
+    counting: bool,         // Counter is running since last reload
+    held: u16,              // Count value while counter is not running
+    next_irq: Option<u64>,  // Tick of next output rising edge not yet reported
     latch: u16,
     latch_locked: bool,     // Latch is locked and should not be updated
     gate_state: bool,
-    out_state: bool,
     mode: PITChannelMode,
     state: PITChannelState,
     access: PITChannelAccess,
     read_more: bool,        // There is 1 more byte to read
-    first_update: bool,     // Have we seen an update since last reload?
 }
 
+/*
+ * Channel count and output are not stepped tick by tick.
+ * Instead they are computed on demand from the tick counting started at and current tick.
+ * All channel operations take current time in PIT ticks.
+ */
 impl PITChannel
 {
     fn default() -> PITChannel {
         PITChannel {
             reload: 0,
-            count: 0,
+            period: 0,
+            start: 0,
+            next_reload: None,
+            counting: false,
+            held: 0,
+            next_irq: None,
             latch: 0,
             mode: PITChannelMode::Mode0,
             state: PITChannelState::Initial,
             access: PITChannelAccess::Word,
             latch_locked: false,
             gate_state: false,
-            out_state: false,
             read_more: false,
-            first_update: false,
         }
     }
 
     /*
      * Set new access mode and reset channel state
+     * Counter stops until new reload value is written.
      */
-    fn reset(&mut self, mode: PITChannelMode, access: PITChannelAccess) {
+    fn reset(&mut self, mode: PITChannelMode, access: PITChannelAccess, now: u64) {
+        if self.counting {
+            self.held = self.count(now);
+        }
+
         self.access = access;
         self.mode = mode;
         self.state = match access {
@@ -156,83 +202,179 @@ impl PITChannel
         self.reload = 0; // TODO: does reload reset to 0 actually?
         self.read_more = false;
         self.latch_locked = false;
-        self.gate_state = false;
+        self.counting = false;
+        self.next_reload = None;
+        self.next_irq = None;
     }
 
-    fn set_count(&mut self, val: u16) {
-        self.count = val;
-        if !self.latch_locked {
-            self.latch = self.count;
+    /*
+     * Start and period in effect at a given tick, taking deferred reload into account
+     */
+    fn timebase(&self, now: u64) -> (u64, u64) {
+        match self.next_reload {
+            Some((at, period)) if now >= at => (at, period),
+            _ => (self.start, self.period),
         }
     }
 
-    fn event_handler(ev: event::Event) {
-        vm::assert_irq(0);
-        vm::interrupt_guest();
+    /* Make deferred reload current once its time has come */
+    fn sync(&mut self, now: u64) {
+        let (start, period) = self.timebase(now);
+        if start != self.start || period != self.period {
+            self.start = start;
+            self.period = period;
+            self.next_reload = None;
+        }
     }
 
     /*
-     * Mode specific handling of new reload value
+     * Current counter value
      */
-    fn reload(&mut self) {
+    fn count(&self, now: u64) -> u16 {
+        if !self.counting {
+            return self.held;
+        }
+
+        let (start, n) = self.timebase(now);
+        if now < start {
+            return n as u16;
+        }
+
+        let elapsed = now - start;
         match self.mode {
-            PITChannelMode::Mode0 => {
-                self.first_update = true;
-                self.out_state = false;
+            // Counts down once and wraps around
+            PITChannelMode::Mode0 | PITChannelMode::Mode4 =>
+                n.wrapping_sub(elapsed) as u16,
+
+            // Counts from n to 1 and reloads
+            PITChannelMode::Mode2 =>
+                (n - elapsed % n) as u16,
+
+            // Counts down by 2 twice per period, high half is longer for odd values
+            PITChannelMode::Mode3 => {
+                let phase = elapsed % n;
+                let high = (n + 1) / 2;
+                let step = if phase < high { phase } else { phase - high };
+                ((n & !1) - 2 * step) as u16
             },
 
-            _ => {
-                panic!();
-            }
-        };
+            _ => self.held,
+        }
+    }
 
-        let count = self.reload;
-        self.set_count(count);
+    /*
+     * Current output pin state
+     */
+    fn out(&self, now: u64) -> bool {
+        let idle = self.mode != PITChannelMode::Mode0;
+        if !self.counting {
+            return idle;
+        }
 
-        /* Delay in microseconds */
-        let delay = count as u64 * 1000000 / PIT_FREQ_HZ;
+        let (start, n) = self.timebase(now);
+        if now < start {
+            return idle;
+        }
 
-        let ev = event::create_event(PITChannel::event_handler);
-        event::schedule_event(delay, ev);
+        let elapsed = now - start;
+        match self.mode {
+            PITChannelMode::Mode0 => elapsed >= n,
+            PITChannelMode::Mode2 => elapsed % n != n - 1,
+            PITChannelMode::Mode3 => elapsed % n < (n + 1) / 2,
+            PITChannelMode::Mode4 => elapsed != n,
+            _ => idle,
+        }
     }
 
     /*
-     * Update stored value based on operation mode and elapsed ticks
+     * First output rising edge strictly after a given tick
      */
-    fn update(&mut self, ticks: u64) {
-        if self.state != PITChannelState::Enabled {
-            return;
+    fn edge_after(&self, t: u64) -> Option<u64> {
+        if !self.counting {
+            return None;
+        }
+
+        let (start, n) = self.timebase(t);
+        let edge = match self.mode {
+            PITChannelMode::Mode0 => start + n,
+            PITChannelMode::Mode4 => start + n + 1,
+            PITChannelMode::Mode2 | PITChannelMode::Mode3 => {
+                let periods = if t < start { 1 } else { (t - start) / n + 1 };
+                start + periods * n
+            },
+            _ => return None,
+        };
+
+        if edge > t {
+            Some(edge)
+        } else {
+            None
+        }
+    }
+
+    /*
+     * Spacing of output edges from next_irq on, 0 if they don't repeat
+     */
+    fn irq_period(&self) -> u64 {
+        match (self.mode, self.next_irq) {
+            (PITChannelMode::Mode2, Some(edge)) | (PITChannelMode::Mode3, Some(edge)) => self.timebase(edge).1,
+            _ => 0,
+        }
+    }
+
+    /*
+     * Number of output rising edges since last check, more than one if check came late
+     */
+    fn take_edges(&mut self, now: u64) -> u64 {
+        let mut edges = 0;
+        while let Some(deadline) = self.next_irq {
+            if deadline > now {
+                break;
+            }
+            edges += 1;
+            self.next_irq = self.edge_after(deadline);
         }
 
+        self.sync(now);
+        edges
+    }
+
+    /*
+     * Mode specific handling of new reload value
+     */
+    fn reload(&mut self, now: u64) {
+        let period = if self.reload == 0 { 0x10000 } else { self.reload as u64 };
+
         match self.mode {
-            PITChannelMode::Mode0 => {
-                // Account for one clock tick spent on reloading counter value right after reload
-                let mut ticks = ticks;
-                if self.first_update {
-                    ticks -= 1;
-                    self.first_update = false;
-                }
+            // Running periodic counter picks up new value at the end of current period
+            PITChannelMode::Mode2 | PITChannelMode::Mode3 if self.counting && now >= self.start => {
+                self.sync(now);
+                let at = self.edge_after(now).unwrap();
+                self.next_reload = Some((at, period));
+            },
 
-                // When reaching 0 set out to high and to remain high until next reload value
-                if ticks >= self.count as u64 {
-                    self.set_count(0);
-                    self.out_state = true;
-                } else {
-                    let count = self.count - ticks as u16;
-                    self.set_count(count);
-                }
+            // Counting starts after one tick spent loading the counter
+            PITChannelMode::Mode0 | PITChannelMode::Mode2 | PITChannelMode::Mode3 | PITChannelMode::Mode4 => {
+                self.counting = true;
+                self.start = now + 1;
+                self.period = period;
+                self.next_reload = None;
+                self.next_irq = self.edge_after(now);
             },
 
-            _ => {
-                panic!();
-            }
+            // Gate triggered modes, we never trigger
+            PITChannelMode::Mode1 | PITChannelMode::Mode5 => {
+                debug!("PIT: gate triggered modes are not supported");
+                self.counting = false;
+                self.held = self.reload;
+            },
         };
     }
 
     /*
      * Transition channel state upon new data port write
      */
-    fn next_state(&mut self) {
+    fn next_state(&mut self, now: u64) {
         match self.state {
             PITChannelState::Initial => {
                 panic!("PIT: Bad channel state");
@@ -268,10 +410,9 @@ impl PITChannel
             },
         }
 
-        // When state changes to enabled, update count and time
-        // TODO: Mode specific on what to do after reload is set - refactor
+        // When state changes to enabled, apply new reload value
         if self.state == PITChannelState::Enabled {
-            self.reload();
+            self.reload(now);
         }
     }
 
@@ -279,26 +420,26 @@ impl PITChannel
         self.gate_state = state;
     }
 
-    fn out(&self) -> bool {
-        self.out_state
-    }
-
     /*
      * Store current count value to internal register
+     * Further latch commands are ignored until latched value is read
      */
-    fn latch_count(&mut self) {
-        self.latch_locked = true;
+    fn latch_count(&mut self, now: u64) {
+        if !self.latch_locked {
+            self.latch = self.count(now);
+            self.latch_locked = true;
+        }
     }
 
     /*
      * Write a byte to channel data port.
      * Will change channel state.
      */
-    fn write(&mut self, val: u8) {
+    fn write(&mut self, val: u8, now: u64) {
         // If channel was enabled, put it into one of the wait states first
         // This means that channel state will be changed twice for this write
         if self.state == PITChannelState::Enabled {
-            self.next_state();
+            self.next_state(now);
         }
 
         // Write portion of reload value
@@ -312,29 +453,30 @@ impl PITChannel
         }
 
         // Select next channel state
-        self.next_state();
+        self.next_state(now);
     }
 
     /*
      * Read a byte from channel data port
      */
-    fn read(&mut self) -> u8 {
+    fn read(&mut self, now: u64) -> u8 {
 
         let mut res = 0;
+        let val = if self.latch_locked { self.latch } else { self.count(now) };
 
         if self.read_more {
             assert!(self.access == PITChannelAccess::Word);
             self.read_more = false;
-            res = (self.latch >> 8) as u8;
+            res = (val >> 8) as u8;
         } else {
             res = match self.access {
                 PITChannelAccess::LoByte =>
-                    self.latch as u8,
+                    val as u8,
                 PITChannelAccess::HiByte =>
-                    (self.latch >> 8) as u8,
+                    (val >> 8) as u8,
                 PITChannelAccess::Word => {
                     self.read_more = true;
-                    self.latch as u8
+                    val as u8
                 },
             };
         }
@@ -352,17 +494,16 @@ impl PITChannel
 mod pit_channel_test
 {
     use super::{PITChannel, PITChannelMode, PITChannelAccess, PITChannelState};
-    use time;
 
-    fn read_count(ch: &mut PITChannel) -> u16 {
-        let lo = ch.read();
-        let hi = ch.read();
+    fn read_count(ch: &mut PITChannel, now: u64) -> u16 {
+        let lo = ch.read(now);
+        let hi = ch.read(now);
         return (lo as u16) | ((hi as u16) << 8);
     }
 
-    fn read_latched_count(ch: &mut PITChannel) -> u16 {
-        ch.latch_count();
-        read_count(ch)
+    fn read_latched_count(ch: &mut PITChannel, now: u64) -> u16 {
+        ch.latch_count(now);
+        read_count(ch, now)
     }
 
 /*
@@ -421,18 +562,19 @@ mod pit_channel_test
      */
     #[test] fn reset() {
         let mut ch = PITChannel::default();
+        let mut now = 0;
 
-        ch.reset(PITChannelMode::Mode0, PITChannelAccess::Word);
+        ch.reset(PITChannelMode::Mode0, PITChannelAccess::Word, now);
         assert!(ch.reload == 0);
-        assert!(ch.count == 0);
-
-        // Update does not change count until reload value is set
-        ch.update(100);
-        assert!(read_count(&mut ch) == 0);
-        ch.write(0xFF);
-        ch.write(0xFF);
-        ch.update(100);
-        assert!(read_count(&mut ch) != 0);
+        assert!(ch.count(now) == 0);
+
+        // Time does not change count until reload value is set
+        now += 100;
+        assert!(read_count(&mut ch, now) == 0);
+        ch.write(0xFF, now);
+        ch.write(0xFF, now);
+        now += 100;
+        assert!(read_count(&mut ch, now) != 0);
     }
 
     /*
@@ -442,25 +584,25 @@ mod pit_channel_test
         let mut ch = PITChannel::default();
 
         // Word
-        ch.reset(PITChannelMode::Mode0, PITChannelAccess::Word);
+        ch.reset(PITChannelMode::Mode0, PITChannelAccess::Word, 0);
         assert!(ch.state == PITChannelState::WaitLo);
-        ch.write(0xAB);
+        ch.write(0xAB, 0);
         assert!(ch.state == PITChannelState::WaitHi);
-        ch.write(0xCD);
+        ch.write(0xCD, 0);
         assert!(ch.state == PITChannelState::Enabled);
         assert!(ch.reload == 0xCDAB);
 
         // LoByte
-        ch.reset(PITChannelMode::Mode0, PITChannelAccess::LoByte);
+        ch.reset(PITChannelMode::Mode0, PITChannelAccess::LoByte, 0);
         assert!(ch.state == PITChannelState::WaitLo);
-        ch.write(0xAB);
+        ch.write(0xAB, 0);
         assert!(ch.state == PITChannelState::Enabled);
         assert!(ch.reload == 0xAB);
 
         // HiByte
-        ch.reset(PITChannelMode::Mode0, PITChannelAccess::HiByte);
+        ch.reset(PITChannelMode::Mode0, PITChannelAccess::HiByte, 0);
         assert!(ch.state == PITChannelState::WaitHi);
-        ch.write(0xCD);
+        ch.write(0xCD, 0);
         assert!(ch.state == PITChannelState::Enabled);
         assert!(ch.reload == 0xCD00);
     }
@@ -470,28 +612,29 @@ mod pit_channel_test
      */
     #[test] fn read() {
         let mut ch = PITChannel::default();
+        let mut now = 0;
 
         // Word
-        ch.reset(PITChannelMode::Mode0, PITChannelAccess::Word);
-        ch.write(0xFF);
-        ch.write(0xFF);
-        ch.update(100); // To move ticks forward a bit
-        assert!(ch.read() == (ch.count & 0xFF) as u8);
-        assert!(ch.read() == ((ch.count >> 8) & 0xFF) as u8);
+        ch.reset(PITChannelMode::Mode0, PITChannelAccess::Word, now);
+        ch.write(0xFF, now);
+        ch.write(0xFF, now);
+        now += 100; // To move ticks forward a bit
+        assert!(ch.read(now) == (ch.count(now) & 0xFF) as u8);
+        assert!(ch.read(now) == ((ch.count(now) >> 8) & 0xFF) as u8);
 
         // LoByte
-        ch.reset(PITChannelMode::Mode0, PITChannelAccess::LoByte);
-        ch.write(0xFF);
-        ch.update(100); // To move ticks forward a bit
-        assert!(ch.read() == (ch.count & 0xFF) as u8);
-        assert!(ch.read() == (ch.count & 0xFF) as u8); // Value is repeated
+        ch.reset(PITChannelMode::Mode0, PITChannelAccess::LoByte, now);
+        ch.write(0xFF, now);
+        now += 100; // To move ticks forward a bit
+        assert!(ch.read(now) == (ch.count(now) & 0xFF) as u8);
+        assert!(ch.read(now) == (ch.count(now) & 0xFF) as u8); // Value is repeated
 
         // HiByte
-        ch.reset(PITChannelMode::Mode0, PITChannelAccess::HiByte);
-        ch.write(0xFF);
-        ch.update(100); // To move ticks forward a bit
-        assert!(ch.read() == ((ch.count >> 8) & 0xFF) as u8);
-        assert!(ch.read() == ((ch.count >> 8) & 0xFF) as u8); // Value is repeated
+        ch.reset(PITChannelMode::Mode0, PITChannelAccess::HiByte, now);
+        ch.write(0xFF, now);
+        now += 100; // To move ticks forward a bit
+        assert!(ch.read(now) == ((ch.count(now) >> 8) & 0xFF) as u8);
+        assert!(ch.read(now) == ((ch.count(now) >> 8) & 0xFF) as u8); // Value is repeated
     }
 
     /*
@@ -499,26 +642,28 @@ mod pit_channel_test
      */
     #[test] fn read_latched() {
         let mut ch = PITChannel::default();
+        let mut now = 0;
 
-        ch.reset(PITChannelMode::Mode0, PITChannelAccess::Word);
-        ch.write(0xFF);
-        ch.write(0xFF);
+        ch.reset(PITChannelMode::Mode0, PITChannelAccess::Word, now);
+        ch.write(0xFF, now);
+        ch.write(0xFF, now);
 
         // Read from count pre-latch
-        ch.update(100);
-        let count1 = read_count(&mut ch);
+        now += 100;
+        let count1 = read_count(&mut ch, now);
 
-        ch.latch_count();
-        ch.update(100); // Move ticks again so that latched value differs from count
+        ch.latch_count(now);
+        now += 100; // Move ticks again so that latched value differs from count
 
         // Read from latch
-        let latch = read_count(&mut ch);
+        let latch = read_count(&mut ch, now);
         assert!(latch == count1);
 
         // After reading latch is always unlocked, update count to verify
-        ch.update(100);
-        let count2 = read_count(&mut ch);
+        now += 100;
+        let count2 = read_count(&mut ch, now);
         assert!(latch != count2);
+        assert!(read_latched_count(&mut ch, now) == count2);
     }
 
     /*
@@ -527,30 +672,212 @@ mod pit_channel_test
     #[test] fn mode0() {
         let reload = 0x1000;
         let mut ch = PITChannel::default();
+        let mut now = 0;
 
-        ch.reset(PITChannelMode::Mode0, PITChannelAccess::Word);
-        ch.write(reload as u8);
-        ch.write((reload >> 8) as u8);
-        assert!(read_count(&mut ch) == reload);
+        ch.reset(PITChannelMode::Mode0, PITChannelAccess::Word, now);
+        ch.write(reload as u8, now);
+        ch.write((reload >> 8) as u8, now);
+        assert!(read_count(&mut ch, now) == reload);
 
         // Initial output is low
-        assert!(ch.out() == false);
+        assert!(ch.out(now) == false);
 
         // reload + 1 ticks is required for mode0 output to go high, verify that
-        ch.update(reload as u64);
-        assert!(ch.out() == false);
-        ch.update(1);
-        assert!(ch.out() == true);
+        now += reload as u64;
+        assert!(ch.out(now) == false);
+        now += 1;
+        assert!(ch.out(now) == true);
 
         // After decrementing to 0 out is high and remains high
-        ch.update(100);
-        assert!(ch.out() == true);
+        now += 100;
+        assert!(ch.out(now) == true);
 
         // After writing new reload value out goes low
-        ch.write(reload as u8);
-        ch.write((reload >> 8) as u8);
-        assert!(read_count(&mut ch) == reload);
-        assert!(ch.out() == false);
+        ch.write(reload as u8, now);
+        ch.write((reload >> 8) as u8, now);
+        assert!(read_count(&mut ch, now) == reload);
+        assert!(ch.out(now) == false);
+    }
+
+    /*
+     * Reference 8254 counter stepped one input clock at a time
+     */
+    struct RefCounter
+    {
+        mode: u8,
+        reload: u32,        // Last written reload value
+        period: u32,        // Reload value of current period
+        count: u32,
+        out: bool,
+        loading: bool,      // Next clock loads counter
+        high_half: bool,    // Mode 3 output phase
+        expired: bool,      // Mode 4 strobe already happened
+    }
+
+    impl RefCounter
+    {
+        fn new(mode: u8, reload: u32) -> RefCounter {
+            RefCounter {
+                mode: mode,
+                reload: reload,
+                period: reload,
+                count: reload,
+                out: mode != 0,
+                loading: true,
+                high_half: true,
+                expired: false,
+            }
+        }
+
+        fn tick(&mut self) {
+            if self.loading {
+                self.loading = false;
+                self.period = self.reload;
+                self.count = if self.mode == 3 { self.reload & !1 } else { self.reload };
+                return;
+            }
+
+            match self.mode {
+                0 | 4 => {
+                    self.count = if self.count == 0 { 0xFFFF } else { self.count - 1 };
+                    if self.mode == 0 {
+                        self.out = self.out || self.count == 0;
+                    } else {
+                        // Single strobe, counter keeps wrapping silently
+                        self.out = self.expired || self.count != 0;
+                        self.expired = self.expired || self.count == 0;
+                    }
+                },
+
+                2 => {
+                    self.count -= 1;
+                    if self.count == 1 {
+                        self.out = false;
+                    } else if self.count == 0 {
+                        self.period = self.reload;
+                        self.count = self.period;
+                        self.out = true;
+                    }
+                },
+
+                3 => {
+                    let last = if self.high_half && (self.period & 1) != 0 { 0 } else { 2 };
+                    if self.count == last {
+                        // New reload value is picked up when a new period begins
+                        if !self.high_half {
+                            self.period = self.reload;
+                        }
+                        self.high_half = !self.high_half;
+                        self.out = self.high_half;
+                        self.count = self.period & !1;
+                    } else {
+                        self.count -= 2;
+                    }
+                },
+
+                _ => panic!(),
+            }
+        }
+    }
+
+    fn channel_mode(mode: u8) -> PITChannelMode {
+        match mode {
+            0 => PITChannelMode::Mode0,
+            2 => PITChannelMode::Mode2,
+            3 => PITChannelMode::Mode3,
+            4 => PITChannelMode::Mode4,
+            _ => panic!(),
+        }
+    }
+
+    fn program(ch: &mut PITChannel, mode: u8, reload: u32, now: u64) {
+        ch.reset(channel_mode(mode), PITChannelAccess::Word, now);
+        ch.write(reload as u8, now);
+        ch.write((reload >> 8) as u8, now);
+    }
+
+    /*
+     * Compare computed count, output and interrupt edges with reference counter
+     */
+    fn compare_with_reference(mode: u8, reload: u32, ticks: u64, step: u64) {
+        let mut ch = PITChannel::default();
+        let t0 = 12345;
+        program(&mut ch, mode, reload & 0xFFFF, t0);
+
+        let mut reference = RefCounter::new(mode, if reload == 0 { 0x10000 } else { reload });
+        let mut now = t0;
+        for _ in 0..ticks {
+            let prev_out = reference.out;
+            reference.tick();
+            now += 1;
+
+            let rising = !prev_out && reference.out;
+            if rising || now % step == 0 {
+                assert!(ch.count(now) == reference.count as u16);
+                assert!(ch.out(now) == reference.out);
+                assert!((ch.take_edges(now) != 0) == rising);
+            }
+        }
+    }
+
+    #[test] fn modes_match_reference() {
+        for mode in [0u8, 2, 3, 4].iter() {
+            for reload in [2u32, 3, 5, 8, 99, 100].iter() {
+                compare_with_reference(*mode, *reload, (*reload as u64) * 4 + 10, 1);
+            }
+
+            // Maximum period sampled sparsely
+            compare_with_reference(*mode, 0, 0x30000, 997);
+        }
+    }
+
+    /*
+     * Rewriting reload value without a control word
+     */
+    #[test] fn reprogram_mid_period() {
+        let t0 = 1000;
+
+        // Periodic modes finish current period first
+        for mode in [2u8, 3].iter() {
+            let mut ch = PITChannel::default();
+            let mut reference = RefCounter::new(*mode, 100);
+            program(&mut ch, *mode, 100, t0);
+
+            let mut now = t0;
+            for tick in 0..1000 {
+                if tick == 30 || tick == 515 {
+                    let reload = if tick == 30 { 41 } else { 64 };
+                    ch.write(reload as u8, now);
+                    ch.write((reload >> 8) as u8, now);
+                    reference.reload = reload;
+                }
+
+                let prev_out = reference.out;
+                reference.tick();
+                now += 1;
+
+                assert!(ch.count(now) == reference.count as u16);
+                assert!(ch.out(now) == reference.out);
+                assert!((ch.take_edges(now) != 0) == (!prev_out && reference.out));
+            }
+        }
+
+        // One shot restarts and pending edge moves
+        let mut ch = PITChannel::default();
+        program(&mut ch, 0, 1000, t0);
+        assert!(ch.next_irq == Some(t0 + 1 + 1000));
+        ch.write(0x10, t0 + 500);
+        ch.write(0x00, t0 + 500);
+        assert!(ch.next_irq == Some(t0 + 500 + 1 + 0x10));
+        assert!(ch.take_edges(t0 + 516) == 0);
+        assert!(ch.take_edges(t0 + 517) == 1);
+        assert!(ch.take_edges(t0 + 5000) == 0);
+
+        // Control word stops counting and cancels pending edge
+        program(&mut ch, 2, 100, t0);
+        ch.reset(PITChannelMode::Mode2, PITChannelAccess::Word, t0 + 50);
+        assert!(ch.count(t0 + 1000) == 51);
+        assert!(ch.next_irq.is_none());
     }
 }
 
@@ -568,11 +895,113 @@ impl PITState {
     }
 }
 
+/**
+ * Tone played by PC speaker
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Beep
+{
+    pub frequency: u32,         // Hz, rounded
+    pub start_ns: u64,          // Virtual time tone started
+    pub stop_ns: Option<u64>,   // Virtual time tone stopped, None while still playing
+}
+
+/**
+ * Channel 0 interrupt counters
+ */
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct TickStats
+{
+    pub ideal: u64,             // Output edges by virtual time
+    pub delivered: u64,         // Interrupts raised
+    pub coalesced: u64,         // Edges found already passed when timer fired late
+    pub lost: u64,              // Edges that will never get an interrupt
+}
+
+/*
+ * Late interrupt accounting
+ *
+ * Timer events fire late when host doesn't run us, and then several channel 0 edges have passed.
+ * Guest gets one interrupt right away, policy decides what happens to the rest: drop forgets them,
+ * coalesce keeps them as debt repaid by delivering interrupts at slightly shorter intervals than the
+ * period until delivered count catches up, inject-all delivers a bounded number of them back to back.
+ */
+struct TickAccounting
+{
+    policy: TickPolicy,
+    stats: TickStats,
+    catch_up_at: Option<u64>,   // Tick of next interrupt owed to guest
+}
+
+impl TickAccounting
+{
+    fn new(policy: TickPolicy) -> TickAccounting {
+        TickAccounting {
+            policy: policy,
+            stats: TickStats::default(),
+            catch_up_at: None,
+        }
+    }
+
+    fn owed(&self) -> u64 {
+        self.stats.ideal - self.stats.delivered - self.stats.lost
+    }
+
+    /* Ticks owed to guest beyond the one delivered now */
+    fn max_backlog(&self) -> u64 {
+        match self.policy {
+            TickPolicy::Coalesce => TICK_MAX_DEBT,
+            TickPolicy::Drop => 0,
+            TickPolicy::InjectAll => TICK_MAX_BURST,
+        }
+    }
+
+    /*
+     * Account edges found by a timer event, true if interrupt is to be raised now.
+     * While catching up interrupts only go out on catch-up schedule, stale events don't speed it up.
+     */
+    fn update(&mut self, edges: u64, now: u64, period: u64) -> bool {
+        self.stats.ideal += edges;
+        self.stats.coalesced += edges.saturating_sub(1);
+
+        let owed = self.owed();
+        if owed == 0 {
+            return false;
+        }
+
+        match self.catch_up_at {
+            Some(at) if now < at => return false,
+            _ => {},
+        }
+
+        let backlog = cmp::min(owed - 1, self.max_backlog());
+        self.stats.lost += owed - 1 - backlog;
+        self.stats.delivered += 1;
+
+        self.catch_up_at = if backlog == 0 {
+            None
+        } else if self.policy == TickPolicy::InjectAll {
+            Some(now + TICK_BURST_GAP)
+        } else {
+            Some(now + period - period / TICK_COALESCE_SHORTEN)
+        };
+
+        true
+    }
+
+    /* Channel 0 got reprogrammed, debt in old periods is forgotten */
+    fn resync(&mut self) {
+        self.stats.lost += self.owed();
+        self.catch_up_at = None;
+    }
+}
+
 struct PIT
 {
     channels: [PITChannel; 3],
     state: PITState,
     cur_channel: u8,            // Currently selected channel
+    port_b: u8,                 // Latched writable bits of system control port B
 }
 
 impl PIT
@@ -583,21 +1012,22 @@ impl PIT
             channels: [PITChannel::default(); 3],
             state: PITState::default(),
             cur_channel: 0,
+            port_b: 0,
         }
     }
 
     /*
      * Get current channels counter value
      */
-    fn get_counter(&self, chan: u8) -> u16 {
+    fn get_counter(&self, chan: u8, now: u64) -> u16 {
         assert!(chan <= 3);
-        return self.channels[chan as usize].count;
+        return self.channels[chan as usize].count(now);
     }
 
     /*
      * Write to mode/command register
      */
-    fn write_mode(&mut self, val: u8) {
+    fn write_mode(&mut self, val: u8, now: u64) {
         let cmd = PITModeReg::from(val);
 
         // TODO: readback
@@ -613,7 +1043,7 @@ impl PIT
         let chan = cmd.select as usize;
 
         if cmd.access == PIT_ACCESS_LATCH_COUNT {
-            self.channels[chan].latch_count();
+            self.channels[chan].latch_count(now);
         } else {
             let access = match cmd.access {
                 PIT_ACCESS_LOBYTE => PITChannelAccess::LoByte,
@@ -633,24 +1063,195 @@ impl PIT
             };
 
             // Select channel and reset it
-            self.channels[chan].reset(mode, access);
+            self.channels[chan].reset(mode, access, now);
         }
     }
 
-    fn write_data(&mut self, chan: u8, val: u8) {
-        self.channels[chan as usize].write(val);
+    fn write_data(&mut self, chan: u8, val: u8, now: u64) {
+        self.channels[chan as usize].write(val, now);
     }
 
-    fn read_data(&mut self, chan: u8) -> u8 {
-        self.channels[chan as usize].read()
+    fn read_data(&mut self, chan: u8, now: u64) -> u8 {
+        self.channels[chan as usize].read(now)
+    }
+
+    /*
+     * Read system control port B at a virtual time
+     */
+    fn read_port_b(&self, now_ns: u64) -> u8 {
+        let mut val = self.port_b;
+
+        if (now_ns / REFRESH_HALF_PERIOD_NS) & 1 != 0 {
+            val |= SYSCTL_B_REFRESH;
+        }
+
+        if self.channels[2].out(clock::ns_to_ticks(now_ns, PIT_FREQ_HZ)) {
+            val |= SYSCTL_B_CH2_OUT;
+        }
+
+        val
+    }
+
+    fn write_port_b(&mut self, val: u8) {
+        self.port_b = val & SYSCTL_B_WRITABLE;
+        self.channels[2].gate_set((val & SYSCTL_B_CH2_GATE) != 0);
+    }
+
+    /*
+     * Speaker plays a tone when channel 2 runs as square wave generator with its gate open and
+     * speaker data enabled. Tone follows the last complete reload value, a partly written one
+     * doesn't cut it, and a pending one takes over right away rather than at the next output edge.
+     */
+    fn speaker_frequency(&self) -> Option<u32> {
+        let ch = &self.channels[2];
+        let enabled = SYSCTL_B_CH2_GATE | SYSCTL_B_SPEAKER;
+
+        if self.port_b & enabled != enabled || ch.mode != PITChannelMode::Mode3 || !ch.counting {
+            return None;
+        }
+
+        let period = match ch.next_reload {
+            Some((_, period)) => period,
+            None => ch.period,
+        };
+
+        Some(((PIT_FREQ_HZ + period / 2) / period) as u32)
     }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
 
+/*
+ * Channel 0 timer event, periodic while output edges repeat at a fixed period
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+struct PITTimer
+{
+    handle: event::TimerHandle,
+    first: u64,                 // Tick of first deadline
+    period: u64,                // Ticks between deadlines, 0 for one-shot
+}
+
+impl PITTimer
+{
+    /* Timer already fires at a tick and then every period after it */
+    fn covers(&self, first: u64, period: u64) -> bool {
+        if period != self.period {
+            return false;
+        }
+
+        match period {
+            0 => first == self.first,
+            _ => first >= self.first && (first - self.first) % period == 0,
+        }
+    }
+}
+
 struct PITDev
 {
     pit: RefCell<PIT>,
+    clock: Rc<virtual_clock>,
+    timer: Cell<Option<PITTimer>>,      // Channel 0 timer event while one is armed
+    assert_irq: fn(u8),
+    tick_handler: Cell<Option<fn()>>,  // Called on every IRQ0 before it is raised
+    beeps: RefCell<VecDeque<Beep>>,     // Speaker tones, last one may still be playing
+    ticks: RefCell<TickAccounting>,     // Late IRQ0 catch-up
+}
+
+impl PITDev
+{
+    /* Current time in PIT ticks */
+    fn now(&self) -> u64 {
+        clock::ns_to_ticks(self.clock.now_ns(), PIT_FREQ_HZ)
+    }
+
+    /*
+     * Keep timer event armed for channel 0 output edges, periodic in periodic modes, or one-shot for
+     * next interrupt owed to guest while catching up. Timer that already fires at the right ticks is
+     * left alone, so periodic deadlines keep counting from where they started.
+     */
+    fn arm_timer(&self, pit: &PIT) {
+        let ch = &pit.channels[0];
+        let wanted = match self.ticks.borrow().catch_up_at {
+            Some(at) => Some((at, 0)),
+            None => ch.next_irq.map(|edge| (edge, ch.irq_period())),
+        };
+
+        let timer = self.timer.get();
+        match (timer, wanted) {
+            (Some(timer), Some((first, period))) if timer.covers(first, period) => return,
+            (Some(timer), _) => { event::cancel_event(timer.handle); },
+            (None, _) => {},
+        }
+
+        self.timer.set(wanted.map(|(first, period)| PITTimer {
+            handle: event::schedule_periodic(first, period, PIT_FREQ_HZ, event::create_event(timer_event)),
+            first: first,
+            period: period,
+        }));
+    }
+
+    /*
+     * Timer event fired, raise IRQ0 for output edges that happened and arm for the next one
+     */
+    fn timer_expired(&self) {
+        let now = self.now();
+        let mut pit = self.pit.borrow_mut();
+
+        /* One-shot event is gone once it fires */
+        if self.timer.get().map_or(false, |timer| timer.period == 0) {
+            self.timer.set(None);
+        }
+
+        let edges = pit.channels[0].take_edges(now);
+        let irq = self.ticks.borrow_mut().update(edges, now, pit.channels[0].period);
+        self.arm_timer(&pit);
+
+        if irq {
+            if let Some(handler) = self.tick_handler.get() {
+                handler();
+            }
+            (self.assert_irq)(PIT_IRQ);
+        }
+    }
+
+    /*
+     * Close playing tone and start a new one when speaker frequency changes
+     */
+    fn update_speaker(&self, pit: &PIT) {
+        let freq = pit.speaker_frequency();
+        let now = self.clock.now_ns();
+        let mut beeps = self.beeps.borrow_mut();
+
+        let playing = match beeps.back() {
+            Some(beep) if beep.stop_ns.is_none() => Some(beep.frequency),
+            _ => None,
+        };
+
+        if playing == freq {
+            return;
+        }
+
+        if let Some(beep) = beeps.back_mut() {
+            if beep.stop_ns.is_none() {
+                beep.stop_ns = Some(now);
+                debug!("PIT: speaker stopped {} Hz after {} us", beep.frequency, (now - beep.start_ns) / 1000);
+            }
+        }
+
+        if let Some(freq) = freq {
+            if beeps.len() == SPEAKER_MAX_BEEPS {
+                beeps.pop_front();
+            }
+
+            beeps.push_back(Beep {
+                frequency: freq,
+                start_ns: now,
+                stop_ns: None,
+            });
+            debug!("PIT: speaker playing {} Hz", freq);
+        }
+    }
 }
 
 impl vm::io_handler for PITDev
@@ -658,13 +1259,15 @@ impl vm::io_handler for PITDev
     fn io_read(&self, port: u16, size: u8) -> vm::IoOperandType
     {
         let mut dev = self.pit.borrow_mut();
+        let now = self.now();
 
         vm::IoOperandType::byte(
             match port {
                 PIT_CMD => 0, // Read from CMD is ignored
-                PIT_CH0 => dev.read_data(0),
-                PIT_CH1 => dev.read_data(1),
-                PIT_CH2 => dev.read_data(2),
+                PIT_CH0 => dev.read_data(0, now),
+                PIT_CH1 => dev.read_data(1, now),
+                PIT_CH2 => dev.read_data(2, now),
+                SYSCTL_PORT_B => dev.read_port_b(self.clock.now_ns()),
 
                 _ => panic!(),
             }
@@ -675,26 +1278,547 @@ impl vm::io_handler for PITDev
     {
         let mut dev = self.pit.borrow_mut();
         let data8 = data.unwrap_byte();
+        let now = self.now();
 
         match port {
-            PIT_CMD => dev.write_mode(data8),
-            PIT_CH0 => dev.write_data(0, data8),
-            PIT_CH1 => dev.write_data(1, data8),
-            PIT_CH2 => dev.write_data(2, data8),
+            PIT_CMD => dev.write_mode(data8, now),
+            PIT_CH0 => dev.write_data(0, data8, now),
+            PIT_CH1 => dev.write_data(1, data8, now),
+            PIT_CH2 => dev.write_data(2, data8, now),
+            SYSCTL_PORT_B => dev.write_port_b(data8),
 
             _ => panic!(),
         }
+
+        if port == PIT_CH0 {
+            self.ticks.borrow_mut().resync();
+        }
+
+        // Channel 0 may have been reprogrammed, channel 2 or port B may have changed speaker tone
+        self.arm_timer(&dev);
+        self.update_speaker(&dev);
+    }
+}
+
+#[cfg(test)]
+mod pit_test
+{
+    use super::*;
+    use clock::{MockClock, ScaledClock};
+    use std::cell::Cell;
+
+    thread_local! {
+        static IRQ_COUNT: Cell<u32> = Cell::new(0);
+        static TICK_COUNT: Cell<u32> = Cell::new(0);
+    }
+
+    fn count_tick() {
+        TICK_COUNT.with(|c| c.set(c.get() + 1));
+    }
+
+    fn count_irq(irq: u8) {
+        assert!(irq == PIT_IRQ);
+        IRQ_COUNT.with(|c| c.set(c.get() + 1));
+    }
+
+    fn irq_count() -> u32 {
+        IRQ_COUNT.with(|c| c.get())
+    }
+
+    fn make_dev<C: virtual_clock + 'static>(clock: &Rc<C>) -> PITDev {
+        PITDev {
+            pit: RefCell::new(PIT::new()),
+            clock: clock.clone(),
+            timer: Cell::new(None),
+            assert_irq: count_irq,
+            tick_handler: Cell::new(None),
+            beeps: RefCell::new(VecDeque::new()),
+            ticks: RefCell::new(TickAccounting::new(TickPolicy::Coalesce)),
+        }
+    }
+
+    fn inb(dev: &PITDev, port: u16) -> u8 {
+        vm::io_handler::io_read(dev, port, 1).unwrap_byte()
+    }
+
+    fn outb(dev: &PITDev, port: u16, val: u8) {
+        vm::io_handler::io_write(dev, port, vm::IoOperandType::byte(val));
+    }
+
+    /*
+     * Guest style delay loop: count refresh toggles seen on port B
+     */
+    #[test] fn refresh_toggles() {
+        let clock = Rc::new(MockClock::new());
+        let dev = make_dev(&clock);
+
+        /* Waiting for 100 toggles ends after 1.5 ms, polling every 1 us */
+        let mut toggles = 0;
+        let mut last = inb(&dev, SYSCTL_PORT_B) & SYSCTL_B_REFRESH;
+        let mut elapsed_ns = 0;
+        while toggles < 100 {
+            clock.advance_ns(1000);
+            elapsed_ns += 1000;
+            assert!(elapsed_ns <= 1500000);
+
+            let now = inb(&dev, SYSCTL_PORT_B) & SYSCTL_B_REFRESH;
+            if now != last {
+                toggles += 1;
+                last = now;
+            }
+        }
+        assert!(elapsed_ns == 1500000);
+
+        /* Bit is stable without time passing */
+        let val = inb(&dev, SYSCTL_PORT_B);
+        assert!(inb(&dev, SYSCTL_PORT_B) == val);
+    }
+
+    #[test] fn port_b_bits() {
+        let clock = Rc::new(MockClock::new());
+        let dev = make_dev(&clock);
+        assert!(inb(&dev, SYSCTL_PORT_B) == 0);
+
+        /* Error check disable bits latch, error status always reads clear */
+        outb(&dev, SYSCTL_PORT_B, 0xFF);
+        assert!(inb(&dev, SYSCTL_PORT_B) == SYSCTL_B_WRITABLE);
+        assert!(dev.pit.borrow().channels[2].gate_state);
+
+        outb(&dev, SYSCTL_PORT_B, SYSCTL_B_PARITY_DIS | SYSCTL_B_IOCHK_DIS);
+        assert!(inb(&dev, SYSCTL_PORT_B) == SYSCTL_B_PARITY_DIS | SYSCTL_B_IOCHK_DIS);
+        assert!(!dev.pit.borrow().channels[2].gate_state);
+
+        outb(&dev, SYSCTL_PORT_B, 0);
+        assert!(inb(&dev, SYSCTL_PORT_B) & (SYSCTL_B_IOCHK | SYSCTL_B_PARITY) == 0);
+
+        /* Channel 2 output shows through once mode 0 count expires */
+        outb(&dev, PIT_CMD, 0xB0);
+        outb(&dev, PIT_CH2, 10);
+        outb(&dev, PIT_CH2, 0);
+        assert!(inb(&dev, SYSCTL_PORT_B) == 0);
+        clock.advance_ns(10000);
+        assert!(inb(&dev, SYSCTL_PORT_B) == SYSCTL_B_CH2_OUT);
+    }
+
+    /* Open speaker gate and data for a while, then close them again */
+    fn beep_for(dev: &PITDev, clock: &MockClock, ms: u64) {
+        let val = inb(dev, SYSCTL_PORT_B);
+        outb(dev, SYSCTL_PORT_B, val | SYSCTL_B_CH2_GATE | SYSCTL_B_SPEAKER);
+        clock.advance_ns(ms * 1000000);
+        outb(dev, SYSCTL_PORT_B, val & !(SYSCTL_B_CH2_GATE | SYSCTL_B_SPEAKER));
+    }
+
+    /*
+     * BIOS style beeps: channel 2 square wave at 880 Hz, speaker switched through port B
+     */
+    #[test] fn speaker_beeps() {
+        let clock = Rc::new(MockClock::new());
+        let dev = make_dev(&clock);
+
+        outb(&dev, PIT_CMD, 0xB6);
+        outb(&dev, PIT_CH2, 0x4C);
+        outb(&dev, PIT_CH2, 0x05);
+        assert!(dev.beeps.borrow().is_empty());
+
+        for _ in 0..2 {
+            beep_for(&dev, &clock, 100);
+            clock.advance_ns(50000000);
+        }
+
+        let beeps: Vec<Beep> = dev.beeps.borrow().iter().cloned().collect();
+        assert!(beeps == vec![Beep { frequency: 880, start_ns: 0, stop_ns: Some(100000000) },
+                              Beep { frequency: 880, start_ns: 150000000, stop_ns: Some(250000000) }]);
+
+        /* Gate alone or speaker data alone is silent, and so is mode 2 */
+        outb(&dev, SYSCTL_PORT_B, SYSCTL_B_CH2_GATE);
+        outb(&dev, SYSCTL_PORT_B, SYSCTL_B_SPEAKER);
+        outb(&dev, PIT_CMD, 0xB4);
+        outb(&dev, PIT_CH2, 0x4C);
+        outb(&dev, PIT_CH2, 0x05);
+        beep_for(&dev, &clock, 10);
+        assert!(dev.beeps.borrow().len() == 2);
+
+        /* Tone change while playing splits the beep, rewriting the same value doesn't */
+        outb(&dev, PIT_CMD, 0xB6);
+        outb(&dev, PIT_CH2, 0x4C);
+        outb(&dev, PIT_CH2, 0x05);
+        outb(&dev, SYSCTL_PORT_B, SYSCTL_B_CH2_GATE | SYSCTL_B_SPEAKER);
+        let start = clock.now_ns();
+        clock.advance_ns(20000000);
+        outb(&dev, PIT_CH2, 0x4C);
+        outb(&dev, PIT_CH2, 0x05);
+        clock.advance_ns(20000000);
+        outb(&dev, PIT_CH2, 0x98);
+        assert!(dev.beeps.borrow().len() == 3);
+        outb(&dev, PIT_CH2, 0x0A);
+        clock.advance_ns(2000000);
+        outb(&dev, SYSCTL_PORT_B, 0);
+        let beeps: Vec<Beep> = dev.beeps.borrow().iter().cloned().collect();
+        assert!(beeps.len() == 4);
+        assert!(beeps[2].frequency == 880 && beeps[2].start_ns == start);
+        assert!(beeps[3].frequency == 440 && Some(beeps[3].start_ns) == beeps[2].stop_ns);
+        assert!(beeps[3].start_ns == start + 40000000 && beeps[3].stop_ns == Some(start + 42000000));
+    }
+
+    /*
+     * Tick armed timer event fires at next. Events are not run by event queue in tests, so periodic one
+     * is due at the next output edge, which has to be one of its deadlines.
+     */
+    fn timer_deadline(dev: &PITDev) -> Option<u64> {
+        dev.timer.get().map(|timer| match timer.period {
+            0 => timer.first,
+            period => {
+                let edge = dev.pit.borrow().channels[0].next_irq.unwrap();
+                assert!(edge >= timer.first && (edge - timer.first) % period == 0);
+                edge
+            },
+        })
+    }
+
+    /* Fire armed timer event at its deadline, tick of IRQ0 if it raised one */
+    fn fire_next(dev: &PITDev, clock: &MockClock) -> Option<u64> {
+        let deadline = timer_deadline(dev).unwrap();
+        let ns = clock::ticks_to_ns(deadline, PIT_FREQ_HZ);
+        if ns > clock.now_ns() {
+            clock.advance_ns(ns - clock.now_ns());
+        }
+
+        let irqs = irq_count();
+        dev.timer_expired();
+        if irq_count() != irqs { Some(dev.now()) } else { None }
+    }
+
+    /* Let timer events fire on time for a while, ticks of raised IRQ0s */
+    fn run_timer(dev: &PITDev, clock: &MockClock, ms: u64) -> Vec<u64> {
+        let end_ns = clock.now_ns() + ms * 1000000;
+        let mut irqs = Vec::new();
+        loop {
+            let deadline = timer_deadline(dev).unwrap();
+            if clock::ticks_to_ns(deadline, PIT_FREQ_HZ) >= end_ns {
+                clock.advance_ns(end_ns - clock.now_ns());
+                return irqs;
+            }
+            irqs.extend(fire_next(dev, clock));
+        }
+    }
+
+    /*
+     * 1 kHz rate generator runs for 100 ms, then host doesn't run timer events for 500 ms.
+     * Returns device, ticks of IRQ0s raised by the late event and the following 6 s, and edges found late.
+     */
+    fn stall(policy: TickPolicy) -> (PITDev, Vec<u64>, u64) {
+        let clock = Rc::new(MockClock::new());
+        let dev = make_dev(&clock);
+        dev.ticks.borrow_mut().policy = policy;
+
+        outb(&dev, PIT_CMD, 0x34);
+        outb(&dev, PIT_CH0, (1193 & 0xFF) as u8);
+        outb(&dev, PIT_CH0, (1193 >> 8) as u8);
+        let irqs = run_timer(&dev, &clock, 100);
+        let stats = dev.ticks.borrow().stats;
+        assert!(irqs.len() == 100 && stats == TickStats { ideal: 100, delivered: 100, coalesced: 0, lost: 0 });
+
+        clock.advance_ns(500000000);
+        let late = dev.now();
+        let mut irqs = fire_next(&dev, &clock).into_iter().collect::<Vec<u64>>();
+        assert!(irqs == vec![late]);
+        let edges = dev.ticks.borrow().stats.ideal - 100;
+        assert!(edges == 500 || edges == 501);
+
+        irqs.extend(run_timer(&dev, &clock, 6000));
+        (dev, irqs, edges)
+    }
+
+    fn gaps(irqs: &[u64]) -> Vec<u64> {
+        irqs.windows(2).map(|w| w[1] - w[0]).collect()
+    }
+
+    #[test] fn late_ticks_drop() {
+        let (dev, irqs, edges) = stall(TickPolicy::Drop);
+        let stats = dev.ticks.borrow().stats;
+
+        /* Back on schedule right away, missed edges are gone for good */
+        assert!(gaps(&irqs[1..]).iter().all(|gap| *gap == 1193));
+        assert!(stats.coalesced == edges - 1 && stats.lost == edges - 1);
+        assert!(stats.delivered == stats.ideal - stats.lost);
+        assert!(stats.delivered == irqs.len() as u64 + 100);
+    }
+
+    #[test] fn late_ticks_coalesce() {
+        let (dev, irqs, edges) = stall(TickPolicy::Coalesce);
+        let stats = dev.ticks.borrow().stats;
+
+        /* Debt is repaid over 1/8 shorter periods, then interrupts follow edges again */
+        let gaps = gaps(&irqs);
+        let repaying = gaps.iter().take_while(|gap| **gap == 1193 - 1193 / 8).count() as u64;
+        assert!(repaying >= (edges - 1) * 7 && repaying <= (edges - 1) * 8);
+        assert!(gaps[repaying as usize + 1..].iter().all(|gap| *gap == 1193));
+
+        /* In the long run guest got every tick */
+        assert!(stats.coalesced == edges - 1 && stats.lost == 0);
+        assert!(stats.delivered == stats.ideal);
+        assert!(stats.delivered == irqs.len() as u64 + 100);
+
+        /* Reprogramming forgives the debt */
+        let clock = Rc::new(MockClock::new());
+        let dev = make_dev(&clock);
+        outb(&dev, PIT_CMD, 0x34);
+        outb(&dev, PIT_CH0, 0x00);
+        outb(&dev, PIT_CH0, 0x10);
+        clock.advance_ns(100000000);
+        fire_next(&dev, &clock);
+        let owed = dev.ticks.borrow().owed();
+        assert!(owed > 0);
+        outb(&dev, PIT_CMD, 0x34);
+        outb(&dev, PIT_CH0, 0x00);
+        outb(&dev, PIT_CH0, 0x10);
+        assert!(dev.ticks.borrow().owed() == 0 && dev.ticks.borrow().stats.lost == owed);
+        assert!(dev.ticks.borrow().catch_up_at.is_none());
+    }
+
+    #[test] fn late_ticks_inject_all() {
+        let (dev, irqs, edges) = stall(TickPolicy::InjectAll);
+        let stats = dev.ticks.borrow().stats;
+
+        /* Burst of missed ticks right after the late one, the rest are lost */
+        let gaps = gaps(&irqs);
+        assert!(gaps[..TICK_MAX_BURST as usize].iter().all(|gap| *gap == TICK_BURST_GAP));
+        assert!(gaps[TICK_MAX_BURST as usize + 1..].iter().all(|gap| *gap == 1193));
+        assert!(stats.coalesced == edges - 1 && stats.lost == edges - 1 - TICK_MAX_BURST);
+        assert!(stats.delivered == stats.ideal - stats.lost);
+        assert!(stats.delivered == irqs.len() as u64 + 100);
+    }
+
+    /* Advance host time in 50 us steps firing timer events that are due, number of IRQ0s raised */
+    fn run_host(dev: &PITDev, host: &MockClock, ms: u64) -> u32 {
+        let irqs = irq_count();
+        for _ in 0..ms * 20 {
+            host.advance_ns(50000);
+            while timer_deadline(dev).map_or(false, |t| t <= dev.now()) {
+                dev.timer_expired();
+            }
+        }
+        irq_count() - irqs
+    }
+
+    /*
+     * Guest time dilation: 1 kHz rate generator ticks at half rate in host time after the switch,
+     * nothing is late or lost across it
+     */
+    #[test] fn dilated_time() {
+        let host = Rc::new(MockClock::new());
+        let clock = Rc::new(ScaledClock::new(host.clone()));
+        let dev = make_dev(&clock);
+
+        outb(&dev, PIT_CMD, 0x34);
+        outb(&dev, PIT_CH0, (1193 & 0xFF) as u8);
+        outb(&dev, PIT_CH0, (1193 >> 8) as u8);
+        assert!(run_host(&dev, &host, 1000) == 1000);
+
+        assert!(clock.set_ratio(0.5).is_ok());
+        assert!(run_host(&dev, &host, 2000) == 1000);
+        assert!(run_host(&dev, &host, 10) == 5);
+        assert!(clock.now_ns() == 2005000000);
+
+        let stats = dev.ticks.borrow().stats;
+        assert!(stats == TickStats { ideal: 2005, delivered: 2005, coalesced: 0, lost: 0 });
+    }
+
+    /* Host sleeps for an hour under a 1 kHz rate generator, IRQ0s in 100 ms after it and tick counters */
+    fn host_sleep(absorb: bool) -> (u32, TickStats) {
+        let host = Rc::new(MockClock::new());
+        let clock = Rc::new(ScaledClock::new(host.clone()));
+        clock.set_jump_policy(2000000000, absorb);
+        let dev = make_dev(&clock);
+
+        outb(&dev, PIT_CMD, 0x34);
+        outb(&dev, PIT_CH0, (1193 & 0xFF) as u8);
+        outb(&dev, PIT_CH0, (1193 >> 8) as u8);
+        assert!(run_host(&dev, &host, 100) == 100);
+
+        host.advance_ns(3600 * 1000000000);
+        let irqs = run_host(&dev, &host, 100);
+        assert!(clock.jumps() == 1);
+        let stats = dev.ticks.borrow().stats;
+        (irqs, stats)
+    }
+
+    /*
+     * Absorbed host time jump leaves 2 s of ticks to catch up, following host leaves the whole hour
+     */
+    #[test] fn host_jump() {
+        /* 1193 PIT ticks are 0.99985 ms */
+        let (irqs, stats) = host_sleep(true);
+        assert!(stats.ideal >= 2199 && stats.ideal <= 2201);
+        assert!(irqs > 100 && stats.delivered == 100 + irqs as u64);
+        assert!(stats.lost <= 2000 - TICK_MAX_DEBT);
+
+        let (irqs, stats) = host_sleep(false);
+        assert!(stats.ideal >= 3600748 && stats.ideal <= 3600750);
+        assert!(irqs > 100 && stats.delivered == 100 + irqs as u64);
+        assert!(stats.lost >= 3600000 - TICK_MAX_DEBT);
+    }
+
+    /*
+     * Counter read through ports follows virtual clock without any timer work
+     */
+    #[test] fn live_count() {
+        let clock = Rc::new(MockClock::new());
+        let dev = make_dev(&clock);
+
+        /* Channel 2 in mode 2, nothing gets armed for it */
+        outb(&dev, PIT_CMD, 0xB4);
+        outb(&dev, PIT_CH2, 0x00);
+        outb(&dev, PIT_CH2, 0x10);
+        assert!(dev.timer.get().is_none());
+
+        /* 1 ms is 1193.182 ticks, plus 1 tick to load */
+        let start = dev.now() + 1;
+        clock.advance_ns(1000000);
+        outb(&dev, PIT_CMD, 0x80);
+        let lo = inb(&dev, PIT_CH2);
+        let hi = inb(&dev, PIT_CH2);
+        let count = (lo as u64) | ((hi as u64) << 8);
+        assert!(count == 0x1000 - (dev.now() - start));
+        assert!(count == 0x1000 - 1192);
+
+        /* Channel 0 arms a periodic timer for its edges, reprogramming replaces it */
+        outb(&dev, PIT_CMD, 0x34);
+        outb(&dev, PIT_CH0, 0x00);
+        outb(&dev, PIT_CH0, 0x10);
+        let now = dev.now();
+        let old = dev.timer.get().unwrap();
+        assert!(old.first == now + 1 + 0x1000 && old.period == 0x1000);
+
+        outb(&dev, PIT_CMD, 0x34);
+        outb(&dev, PIT_CH0, 0x00);
+        outb(&dev, PIT_CH0, 0x01);
+        let timer = dev.timer.get().unwrap();
+        assert!(timer.first == now + 1 + 0x100 && timer.period == 0x100);
+        assert!(!event::cancel_event(old.handle));
+
+        /* Event fires and stays armed for the following periods */
+        let irqs = irq_count();
+        dev.tick_handler.set(Some(count_tick));
+        clock.advance_ns(300000);
+        dev.timer_expired();
+        assert!(irq_count() == irqs + 1);
+        assert!(TICK_COUNT.with(|c| c.get()) == 1);
+        assert!(dev.timer.get() == Some(timer) && timer_deadline(&dev) == Some(now + 1 + 0x200));
+
+        /* Late event coalesces missed edges into one interrupt, with drop policy they are gone */
+        dev.ticks.borrow_mut().policy = TickPolicy::Drop;
+        clock.advance_ns(10000000);
+        let late = dev.now();
+        dev.timer_expired();
+        assert!(irq_count() == irqs + 2);
+        assert!(dev.timer.get() == Some(timer));
+        assert!(timer_deadline(&dev) == Some(now + 1 + ((late - now - 1) / 0x100 + 1) * 0x100));
+
+        /* Reload while running takes over at the next edge with a timer of the new period */
+        outb(&dev, PIT_CH0, 0x00);
+        outb(&dev, PIT_CH0, 0x02);
+        let edge = timer_deadline(&dev).unwrap();
+        let reloaded = dev.timer.get().unwrap();
+        assert!(reloaded.first == edge && reloaded.period == 0x200);
+
+        /* Mode 0 gets a one-shot timer that is gone after it fires */
+        outb(&dev, PIT_CMD, 0x30);
+        outb(&dev, PIT_CH0, 0x00);
+        outb(&dev, PIT_CH0, 0x01);
+        assert!(dev.timer.get().unwrap().period == 0);
+        fire_next(&dev, &clock);
+        assert!(dev.timer.get().is_none());
+    }
+}
+
+static mut PIT_DEV: Option<*const PITDev> = None;
+
+fn timer_event(_: event::Event)
+{
+    unsafe {
+        if let Some(dev) = PIT_DEV {
+            let dev: &PITDev = mem::transmute(dev);
+            dev.timer_expired();
+        }
     }
 }
 
+fn raise_irq(irq: u8)
+{
+    vm::assert_irq(irq);
+    vm::interrupt_guest();
+}
+
 pub fn init()
 {
 	let dev = Rc::new(PITDev {
         pit: RefCell::new(PIT::new()),
+        clock: Rc::new(VcpuClock),
+        timer: Cell::new(None),
+        assert_irq: raise_irq,
+        tick_handler: Cell::new(None),
+        beeps: RefCell::new(VecDeque::new()),
+        ticks: RefCell::new(TickAccounting::new(TickPolicy::Coalesce)),
     });
 
+    unsafe {
+        PIT_DEV = Some(&*dev as *const PITDev);
+    }
+
     vm::register_io_region(dev.clone(), PIT_CH0, 1);
     vm::register_io_region(dev.clone(), PIT_CH1, 1);
     vm::register_io_region(dev.clone(), PIT_CH2, 1);
     vm::register_io_region(dev.clone(), PIT_CMD, 1);
+    vm::register_io_region(dev.clone(), SYSCTL_PORT_B, 1);
+}
+
+/**
+ * Install a function to be called on every channel 0 interrupt
+ */
+pub fn set_tick_handler(handler: fn())
+{
+    unsafe {
+        if let Some(dev) = PIT_DEV {
+            (*dev).tick_handler.set(Some(handler));
+        }
+    }
+}
+
+/**
+ * Tones played by PC speaker so far, oldest first
+ */
+pub fn speaker_beeps() -> Vec<Beep>
+{
+    unsafe {
+        match PIT_DEV {
+            Some(dev) => (*dev).beeps.borrow().iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/**
+ * Select how late channel 0 interrupts catch up
+ */
+pub fn set_tick_policy(policy: TickPolicy)
+{
+    unsafe {
+        if let Some(dev) = PIT_DEV {
+            (*dev).ticks.borrow_mut().policy = policy;
+        }
+    }
+}
+
+/**
+ * Channel 0 interrupt counters
+ */
+pub fn tick_stats() -> TickStats
+{
+    unsafe {
+        match PIT_DEV {
+            Some(dev) => (*dev).ticks.borrow().stats,
+            None => TickStats::default(),
+        }
+    }
 }
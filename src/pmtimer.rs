@@ -0,0 +1,166 @@
+/*
+ * ACPI power management timer
+ *
+ * Free running 24 or 32 bit counter at 3.579545 MHz, read as a dword from a single port.
+ * Counter derives from virtual time, so it stops while VM is not running.
+ */
+
+use vm;
+use config;
+use clock::{self, virtual_clock, VcpuClock};
+
+use std::rc::Rc;
+use std::cell::RefCell;
+
+const PM_TIMER_FREQ: u64        = 3579545;
+const PM_TIMER_MASK_24: u32     = 0x00FFFFFF;
+const PM_TIMER_MASK_32: u32     = 0xFFFFFFFF;
+
+struct PMTimer
+{
+    clock: Rc<virtual_clock>,
+    start_ns: u64,          // Virtual time of counter reset
+    mask: u32,
+}
+
+impl PMTimer
+{
+    fn new(clock: Rc<virtual_clock>, wide: bool) -> PMTimer {
+        let start_ns = clock.now_ns();
+        PMTimer {
+            clock: clock,
+            start_ns: start_ns,
+            mask: if wide { PM_TIMER_MASK_32 } else { PM_TIMER_MASK_24 },
+        }
+    }
+
+    fn reset(&mut self) {
+        self.start_ns = self.clock.now_ns();
+    }
+
+    fn read(&self) -> u32 {
+        let ticks = clock::ns_to_ticks(self.clock.now_ns() - self.start_ns, PM_TIMER_FREQ);
+        (ticks as u32) & self.mask
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct PMTimerDev
+{
+    timer: RefCell<PMTimer>,
+    port: u16,
+}
+
+impl vm::io_handler for PMTimerDev
+{
+    fn io_read(&self, port: u16, size: u8) -> vm::IoOperandType
+    {
+        assert!(port == self.port);
+
+        /* Spec only allows dword reads, narrower ones get low bits */
+        let val = self.timer.borrow().read();
+        match size {
+            1 => vm::IoOperandType::byte(val as u8),
+            2 => vm::IoOperandType::word(val as u16),
+            4 => vm::IoOperandType::dword(val),
+            _ => panic!(),
+        }
+    }
+
+    fn io_write(&self, port: u16, _: vm::IoOperandType)
+    {
+        /* Counter is read only */
+        debug!("pmtimer: ignoring write to port {:x}", port);
+    }
+}
+
+impl vm::reset_handler for PMTimerDev
+{
+    fn reset(&self)
+    {
+        self.timer.borrow_mut().reset();
+    }
+}
+
+#[cfg(test)]
+mod pmtimer_test
+{
+    use super::*;
+    use clock::MockClock;
+
+    fn make_dev(clock: &Rc<MockClock>, wide: bool) -> PMTimerDev {
+        PMTimerDev {
+            timer: RefCell::new(PMTimer::new(clock.clone(), wide)),
+            port: 0x608,
+        }
+    }
+
+    fn inl(dev: &PMTimerDev) -> u32 {
+        vm::io_handler::io_read(dev, 0x608, 4).unwrap_dword()
+    }
+
+    #[test] fn ticks_follow_clock() {
+        let clock = Rc::new(MockClock::new());
+        clock.advance_ns(12345);
+        let dev = make_dev(&clock, false);
+        assert!(inl(&dev) == 0);
+
+        /* 1 ms is 3579.545 ticks */
+        clock.advance_ns(1000000);
+        let t1 = inl(&dev);
+        assert!(t1 == 3579);
+        assert!(inl(&dev) == t1);
+
+        clock.advance_ns(1000000);
+        let t2 = inl(&dev);
+        assert!(t2 - t1 == 3580);
+
+        /* Second after reset */
+        vm::reset_handler::reset(&dev);
+        clock.advance_ns(1000000000);
+        assert!(inl(&dev) == 3579545);
+        assert!(vm::io_handler::io_read(&dev, 0x608, 2).unwrap_word() == (3579545 & 0xFFFF) as u16);
+    }
+
+    #[test] fn wraparound() {
+        let clock = Rc::new(MockClock::new());
+        let narrow = make_dev(&clock, false);
+        let wide = make_dev(&clock, true);
+
+        /* 2^24 ticks is 4.687 s, go just past it in 1 s steps */
+        let mut last = inl(&narrow);
+        for _ in 0..5 {
+            clock.advance_ns(1000000000);
+            let now = inl(&narrow);
+            assert!(now <= 0xFFFFFF);
+            assert!(now.wrapping_sub(last) & 0xFFFFFF == 3579545);
+            last = now;
+        }
+        assert!(last == 5 * 3579545 - 0x1000000);
+        assert!(inl(&wide) == 5 * 3579545);
+
+        /* 2^32 ticks is about 20 minutes */
+        clock.advance_ns(1200 * 1000000000);
+        assert!(inl(&wide) == (1205 * 3579545u64 - 0x100000000) as u32);
+        assert!(inl(&narrow) == (1205 * 3579545u64 & 0xFFFFFF) as u32);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+pub fn init(config: &config::VmConfig)
+{
+    let pm_timer = match config.pm_timer {
+        Some(ref pm_timer) => pm_timer,
+        None => return,
+    };
+
+    let dev = Rc::new(PMTimerDev {
+        timer: RefCell::new(PMTimer::new(Rc::new(VcpuClock), pm_timer.wide)),
+        port: pm_timer.port,
+    });
+
+    vm::register_io_region(dev.clone(), pm_timer.port, 4);
+    vm::register_reset_handler(dev.clone());
+}
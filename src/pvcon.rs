@@ -0,0 +1,199 @@
+/*
+ * Paravirtual console driven by hypercalls
+ *
+ * HC_CONSOLE_WRITE: EBX = buffer, ECX = length. Copies buffer to console backend, EAX = bytes written.
+ * HC_CONSOLE_READ: EBX = buffer, ECX = size. Copies pending input to buffer, EAX = bytes read, 0 if none.
+ * Each call moves up to PVCON_MAX_XFER bytes, so guest pays one exit per chunk instead of one per byte
+ * as it would with the UART. Presence is reported as PVCON_FEATURE by HC_FEATURES.
+ * Host end is a serial backend, see serial module.
+ */
+
+use config;
+use dma;
+use hypercall::{self, Hypercall, HypercallRegs};
+use serial::{self, serial_backend};
+
+use std::cell::RefCell;
+use std::cmp;
+
+pub const HC_CONSOLE_WRITE: u32 = 0x0100;
+pub const HC_CONSOLE_READ: u32  = 0x0101;
+pub const PVCON_FEATURE: u32    = 0x00000001;
+
+// Longest transfer in one hypercall
+const PVCON_MAX_XFER: usize     = 0x10000;
+
+/**
+ * Console model
+ */
+pub struct Console
+{
+    backend: Box<serial_backend>,
+}
+
+impl Console
+{
+    pub fn new(mut backend: Box<serial_backend>) -> Console {
+        /* Guest takes input whenever it asks, there is no receive buffer to overflow */
+        backend.set_ready(true);
+        Console { backend: backend }
+    }
+
+    /* Copy guest buffer to backend */
+    fn write(&mut self, mem: &dma::dma_memory, addr: u64, len: usize) -> usize {
+        let mut buf = vec![0u8; cmp::min(len, PVCON_MAX_XFER)];
+        let count = mem.read(addr, &mut buf);
+
+        for val in &buf[..count] {
+            self.backend.write(*val);
+        }
+
+        count
+    }
+
+    /* Copy pending input to guest buffer */
+    fn read(&mut self, mem: &dma::dma_memory, addr: u64, len: usize) -> usize {
+        let mut buf = Vec::new();
+        while buf.len() < cmp::min(len, PVCON_MAX_XFER) {
+            match self.backend.read() {
+                Some(val) => buf.push(val),
+                None => break,
+            }
+        }
+
+        let count = mem.write(addr, &buf);
+        if count != buf.len() {
+            warn!("pvcon: {} input bytes lost past end of guest memory", buf.len() - count);
+        }
+
+        count
+    }
+
+    /* Perform console hypercall on guest registers */
+    fn hypercall(&mut self, mem: &dma::dma_memory, regs: &mut HypercallRegs) {
+        let (addr, len) = (regs.ebx as u64, regs.ecx as usize);
+
+        regs.eax = match regs.eax {
+            HC_CONSOLE_WRITE => self.write(mem, addr, len),
+            HC_CONSOLE_READ => self.read(mem, addr, len),
+            _ => panic!("pvcon: not a console hypercall {:x}", regs.eax),
+        } as u32;
+    }
+}
+
+#[cfg(test)]
+mod pvcon_test
+{
+    use super::*;
+    use dma::{dma_memory, TestMemory};
+    use std::rc::Rc;
+    use std::collections::VecDeque;
+
+    /* Host end keeping output and handing out queued input */
+    struct TestBackend
+    {
+        output: Rc<RefCell<Vec<u8>>>,
+        input: VecDeque<u8>,
+    }
+
+    impl serial_backend for TestBackend
+    {
+        fn write(&mut self, val: u8) {
+            self.output.borrow_mut().push(val);
+        }
+
+        fn read(&mut self) -> Option<u8> {
+            self.input.pop_front()
+        }
+
+        fn set_ready(&mut self, _: bool) {
+        }
+    }
+
+    fn make_console(input: &[u8]) -> (Console, Rc<RefCell<Vec<u8>>>) {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let backend = TestBackend { output: output.clone(), input: input.iter().cloned().collect() };
+        (Console::new(Box::new(backend)), output)
+    }
+
+    fn call(console: &mut Console, mem: &TestMemory, nr: u32, addr: u32, len: u32) -> u32 {
+        let mut regs = HypercallRegs { eax: nr, ebx: addr, ecx: len, edx: 0 };
+        console.hypercall(mem, &mut regs);
+        regs.eax
+    }
+
+    #[test] fn stream_64k() {
+        let (mut console, output) = make_console(b"");
+        let data: Vec<u8> = (0..0x10000).map(|i| (i ^ (i >> 8)) as u8).collect();
+        let mem = TestMemory::new(0x100000, 0);
+        mem.write(0x10000, &data);
+
+        /* Guest sends 4K chunks */
+        let mut exits = 0;
+        let mut sent = 0;
+        while sent < data.len() {
+            let len = cmp::min(0x1000, data.len() - sent);
+            assert!(call(&mut console, &mem, HC_CONSOLE_WRITE, 0x10000 + sent as u32, len as u32) == len as u32);
+            sent += len;
+            exits += 1;
+        }
+
+        assert!(exits == 16);
+        assert!(*output.borrow() == data);
+
+        /* Whole buffer in one go */
+        output.borrow_mut().clear();
+        assert!(call(&mut console, &mem, HC_CONSOLE_WRITE, 0x10000, 0x10000) == 0x10000);
+        assert!(*output.borrow() == data);
+
+        /* Longer requests are cut, nothing is read past end of memory */
+        output.borrow_mut().clear();
+        assert!(call(&mut console, &mem, HC_CONSOLE_WRITE, 0, 0x20000) == 0x10000);
+        assert!(call(&mut console, &mem, HC_CONSOLE_WRITE, 0xFFFF0, 0x100) == 0x10);
+        assert!(output.borrow().len() == 0x10010);
+    }
+
+    #[test] fn input() {
+        let (mut console, output) = make_console(b"hello world");
+        let mem = TestMemory::new(0x1000, 0);
+
+        assert!(call(&mut console, &mem, HC_CONSOLE_READ, 0x100, 5) == 5);
+        assert!(call(&mut console, &mem, HC_CONSOLE_READ, 0x105, 100) == 6);
+        assert!(call(&mut console, &mem, HC_CONSOLE_READ, 0x105, 100) == 0);
+        assert!(&mem.mem.borrow()[0x100..0x10C] == b"hello world\0");
+        assert!(output.borrow().is_empty());
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+static mut PVCON: Option<*const RefCell<Console>> = None;
+
+fn console_hypercall(mem: &dma::dma_memory, regs: &mut HypercallRegs)
+{
+    unsafe {
+        if let Some(console) = PVCON {
+            (*console).borrow_mut().hypercall(mem, regs);
+        }
+    }
+}
+
+pub fn init(config: &config::VmConfig)
+{
+    let backend = match config.pvcon {
+        Some(ref pvconconfig) => match serial::open_backend(pvconconfig) {
+            Ok(backend) => Box::new(serial::RecordedBackend::new(backend, "pvcon")) as Box<serial_backend>,
+            Err(err) => panic!("pvcon: failed to open backend {:?}: {}", pvconconfig, err),
+        },
+        None => return,
+    };
+
+    let console = Box::new(RefCell::new(Console::new(backend)));
+    unsafe {
+        PVCON = Some(Box::into_raw(console) as *const RefCell<Console>);
+    }
+
+    for nr in &[HC_CONSOLE_WRITE, HC_CONSOLE_READ] {
+        hypercall::register_hypercall(Hypercall { nr: *nr, feature: PVCON_FEATURE, handler: console_hypercall });
+    }
+}
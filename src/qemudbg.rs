@@ -1,6 +1,6 @@
 /*
  * qemudbg
- * Implementation of a qemu debug IO port device
+ * Implementation of qemu debug IO port and debug exit devices
  */
 
 use vm;
@@ -41,6 +41,60 @@ impl vm::io_handler for qemudbg
     }
 }
 
+/*
+ * Debug exit port
+ * Guest terminates VM by writing a byte, exit status is (val << 1) | 1 as with qemu isa-debug-exit,
+ * so status never collides with a normal VM exit.
+ */
+const DEBUG_EXIT_PORT: u16 = 0xF4;
+
+struct debug_exit
+{
+    request_exit: fn(i32),
+}
+
+impl vm::io_handler for debug_exit
+{
+    fn io_read(&self, port: u16, size: u8) -> vm::IoOperandType
+    {
+        assert!(port == DEBUG_EXIT_PORT);
+        vm::IoOperandType::make_unhandled(size)
+    }
+
+    fn io_write(&self, port: u16, data: vm::IoOperandType)
+    {
+        assert!(port == DEBUG_EXIT_PORT);
+
+        let code = ((data.unwrap_byte() as i32) << 1) | 1;
+        debug!("qemudbg: guest exit with status {}", code);
+        (self.request_exit)(code);
+    }
+}
+
+#[cfg(test)]
+mod qemudbg_test
+{
+    use super::*;
+    use std::cell::Cell;
+
+    thread_local! {
+        static EXIT_CODE: Cell<Option<i32>> = Cell::new(None);
+    }
+
+    fn record_exit(code: i32) {
+        EXIT_CODE.with(|c| c.set(Some(code)));
+    }
+
+    #[test] fn debug_exit_status() {
+        let dev = debug_exit { request_exit: record_exit };
+
+        vm::io_handler::io_write(&dev, DEBUG_EXIT_PORT, vm::IoOperandType::byte(0x2A));
+        assert!(EXIT_CODE.with(|c| c.get()) == Some(85));
+        vm::io_handler::io_write(&dev, DEBUG_EXIT_PORT, vm::IoOperandType::byte(0));
+        assert!(EXIT_CODE.with(|c| c.get()) == Some(1));
+    }
+}
+
 pub fn init()
 {
     let dev = Rc::new(qemudbg {
@@ -48,5 +102,6 @@ pub fn init()
     });
 
     vm::register_io_region(dev, 0x402, 1);
+    vm::register_io_region(Rc::new(debug_exit { request_exit: vm::request_exit }), DEBUG_EXIT_PORT, 1);
 }
 
@@ -0,0 +1,785 @@
+/*
+ * Recording of guest inputs for deterministic replay
+ *
+ * Guest runs the same way twice when it starts from the same state and gets the same inputs at the same
+ * points of its run. --record <file> saves a snapshot to <file>.snap before guest starts, see snapshot.rs,
+ * and from there logs everything that comes into the VM from outside to file as JSON lines. Each input has
+ * the number of VM exits taken before it was consumed as its position, e.g.
+ *
+ *   {"exit":1042,"input":"serial_rx","device":"uart","value":65}
+ *
+ * First line is a header with the format version, snapshot file and content hashes of disk images guest
+ * can't write, which replay needs unchanged. Inputs are
+ *
+ *   serial_rx      byte serial device took from its host backend
+ *   serial_lines   modem status lines of a serial backend, when device sees them change
+ *   net_rx         Ethernet frame NIC took from its host backend, as hex
+ *   key, mouse     host keyboard and mouse events to input device
+ *   disk_read      data read from a writable disk image, as hex, since guest writes leave the image changed
+ *   clock          guest time in nanoseconds a device read from virtual clock
+ *   wall_clock     host time in seconds since Unix epoch RTC set itself to after host time jumped
+ *   timer          guest time timer events due by then fired at
+ *   inject         event injected at VM entry: exception, nmi or external, its vector and guest IP
+ *   monitor        interrupt host user injected from monitor: irq, vector or nmi and its number
+ *   io             guest port access, port, size and direction, for replay to check guest took the same path
+ *
+ * Last line has the exit count, VM exit status and hash of guest RAM the run ended with.
+ *
+ * Positions count the exits guest causes itself. Exits host forces, for its own interrupts, the preemption
+ * timer, interrupt windows, monitor trap steps, INT3 and execute protection breakpoints and the dirty page log,
+ * don't count, so they may differ between runs. Timer
+ * events firing on event loop thread while guest runs get the count guest got to, it only changes while
+ * the event loop is locked. Guest running without exits in between can be anywhere when an interrupt comes
+ * in, injections note guest IP for that.
+ *
+ * Inputs go through taps that cost an atomic load while nothing is recorded: serial, network and disk
+ * backends are wrapped, devices read guest time through one clock and injections happen in one place.
+ * Snapshot is restored right after it is saved, so recorded run and its replays, see replay.rs, start from
+ * the same state.
+ */
+
+use config;
+use vm;
+use snapshot;
+use eventlog::{quote, hex};
+
+use hypervisor_framework::hv_vmx_exit_reason;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+pub const RECORDING_VERSION: u32 = 2;
+
+/**
+ * Inputs VM takes from outside
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Input
+{
+    SerialRx { device: &'static str, value: u8 },
+    SerialLines { device: &'static str, lines: u8 },           // SERIAL_LINE_* of backend
+    NetRx { device: &'static str, frame: Vec<u8> },
+    Key { code: u16, pressed: bool },
+    Mouse { dx: i32, dy: i32, dz: i32, buttons: u8 },
+    DiskRead { device: &'static str, offset: u64, data: Vec<u8> },
+    Clock { ns: u64 },
+    WallClock { secs: i64 },
+    Timer { now: u64 },
+    Inject { kind: &'static str, vector: u8, ip: u64 },
+    Monitor { kind: &'static str, value: u8 },                 // irq, vector or nmi
+    Io { port: u16, size: u8, write: bool },
+}
+
+impl Input
+{
+    /**
+     * Input kind as it appears in the recording
+     */
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Input::SerialRx { .. } => "serial_rx",
+            Input::SerialLines { .. } => "serial_lines",
+            Input::NetRx { .. } => "net_rx",
+            Input::Key { .. } => "key",
+            Input::Mouse { .. } => "mouse",
+            Input::DiskRead { .. } => "disk_read",
+            Input::Clock { .. } => "clock",
+            Input::WallClock { .. } => "wall_clock",
+            Input::Timer { .. } => "timer",
+            Input::Inject { .. } => "inject",
+            Input::Monitor { .. } => "monitor",
+            Input::Io { .. } => "io",
+        }
+    }
+
+    /* Fields after the input kind, each with a leading comma */
+    fn fields(&self) -> String {
+        match *self {
+            Input::SerialRx { device, value } => format!(",\"device\":{},\"value\":{}", quote(device), value),
+            Input::SerialLines { device, lines } => format!(",\"device\":{},\"lines\":{}", quote(device), lines),
+            Input::NetRx { device, ref frame } => format!(",\"device\":{},\"frame\":{}", quote(device), quote(&hex(frame))),
+            Input::Key { code, pressed } => format!(",\"code\":{},\"pressed\":{}", code, pressed),
+            Input::Mouse { dx, dy, dz, buttons } => format!(",\"dx\":{},\"dy\":{},\"dz\":{},\"buttons\":{}", dx, dy, dz, buttons),
+            Input::DiskRead { device, offset, ref data } => {
+                format!(",\"device\":{},\"offset\":{},\"data\":{}", quote(device), offset, quote(&hex(data)))
+            },
+            Input::Clock { ns } => format!(",\"ns\":{}", ns),
+            Input::WallClock { secs } => format!(",\"secs\":{}", secs),
+            Input::Timer { now } => format!(",\"now\":{}", now),
+            Input::Inject { kind, vector, ip } => format!(",\"kind\":{},\"vector\":{},\"ip\":{}", quote(kind), vector, ip),
+            Input::Monitor { kind, value } => format!(",\"kind\":{},\"value\":{}", quote(kind), value),
+            Input::Io { port, size, write } => {
+                format!(",\"port\":{},\"dir\":\"{}\",\"size\":{}", port, if write { "write" } else { "read" }, size)
+            },
+        }
+    }
+
+    /**
+     * Recording line for input consumed after exit VM exits
+     */
+    pub fn to_json(&self, exit: u64) -> String {
+        format!("{{\"exit\":{},\"input\":{}{}}}", exit, quote(self.kind()), self.fields())
+    }
+
+    /* Input of a recording line and its exit position */
+    fn from_fields(fields: &Fields) -> Result<(u64, Input), String> {
+        let input = match try!(text(fields, "input")) {
+            "serial_rx" => Input::SerialRx { device: try!(name(fields, "device")), value: try!(number(fields, "value")) },
+            "serial_lines" => Input::SerialLines { device: try!(name(fields, "device")), lines: try!(number(fields, "lines")) },
+            "net_rx" => Input::NetRx { device: try!(name(fields, "device")), frame: try!(unhex(try!(text(fields, "frame")))) },
+            "key" => Input::Key { code: try!(number(fields, "code")), pressed: try!(boolean(fields, "pressed")) },
+            "mouse" => Input::Mouse {
+                dx: try!(number(fields, "dx")),
+                dy: try!(number(fields, "dy")),
+                dz: try!(number(fields, "dz")),
+                buttons: try!(number(fields, "buttons")),
+            },
+            "disk_read" => Input::DiskRead {
+                device: try!(name(fields, "device")),
+                offset: try!(number(fields, "offset")),
+                data: try!(unhex(try!(text(fields, "data")))),
+            },
+            "clock" => Input::Clock { ns: try!(number(fields, "ns")) },
+            "wall_clock" => Input::WallClock { secs: try!(number(fields, "secs")) },
+            "timer" => Input::Timer { now: try!(number(fields, "now")) },
+            "inject" => Input::Inject {
+                kind: try!(name(fields, "kind")),
+                vector: try!(number(fields, "vector")),
+                ip: try!(number(fields, "ip")),
+            },
+            "monitor" => Input::Monitor { kind: try!(name(fields, "kind")), value: try!(number(fields, "value")) },
+            "io" => Input::Io {
+                port: try!(number(fields, "port")),
+                size: try!(number(fields, "size")),
+                write: match try!(text(fields, "dir")) {
+                    "read" => false,
+                    "write" => true,
+                    dir => return Err(format!("Bad direction {}", dir)),
+                },
+            },
+            kind => return Err(format!("Unknown input {}", kind)),
+        };
+        Ok((try!(number(fields, "exit")), input))
+    }
+}
+
+/**
+ * Recording end line: exits taken, VM exit status and content hash of guest RAM it ended with
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct End
+{
+    pub exit: u64,
+    pub status: i32,
+    pub ram_hash: u64,
+}
+
+impl End
+{
+    pub fn to_json(&self) -> String {
+        format!("{{\"exit\":{},\"end\":{},\"ram_hash\":\"0x{:016x}\"}}", self.exit, self.status, self.ram_hash)
+    }
+}
+
+/**
+ * Recording line after the header
+ */
+#[derive(Debug, PartialEq)]
+pub enum Line
+{
+    Input(u64, Input),
+    End(End),
+}
+
+/**
+ * Parse recording line after the header
+ */
+pub fn parse_line(line: &str) -> Result<Line, String>
+{
+    let fields = try!(parse_object(line));
+    if field(&fields, "end").is_ok() {
+        return Ok(Line::End(End {
+            exit: try!(number(&fields, "exit")),
+            status: try!(number(&fields, "end")),
+            ram_hash: try!(hash(&fields, "ram_hash")),
+        }));
+    }
+
+    let (exit, input) = try!(Input::from_fields(&fields));
+    Ok(Line::Input(exit, input))
+}
+
+/**
+ * Recording header: snapshot replay starts from and disk images it needs unchanged
+ */
+#[derive(Debug, PartialEq)]
+pub struct Header
+{
+    pub snapshot: String,
+    pub images: Vec<(String, u64)>,
+}
+
+/**
+ * Parse recording header line
+ */
+pub fn parse_header(line: &str) -> Result<Header, String>
+{
+    let fields = try!(parse_object(line));
+    let version: u32 = try!(number(&fields, "recording").map_err(|_| String::from("Not an input recording")));
+    if version != RECORDING_VERSION {
+        return Err(format!("Recording is version {}, VMM reads version {}", version, RECORDING_VERSION));
+    }
+
+    let mut images = Vec::new();
+    match *try!(field(&fields, "images")) {
+        Value::List(ref list) => for image in list {
+            images.push((String::from(try!(text(image, "path"))), try!(hash(image, "hash"))));
+        },
+        _ => return Err(String::from("Field images isn't a list")),
+    }
+
+    Ok(Header {
+        snapshot: String::from(try!(text(&fields, "snapshot"))),
+        images: images,
+    })
+}
+
+/* Field value of a recording line, lines are objects of scalars, header has a list of image objects */
+#[derive(Debug)]
+enum Value
+{
+    Text(String),
+    Number(String),
+    Bool(bool),
+    List(Vec<Fields>),
+}
+
+type Fields = Vec<(String, Value)>;
+
+/* Names devices and injections go by, inputs hold them as static strings */
+const NAMES: &'static [&'static str] = &["uart", "pvcon", "ne2000", "hda", "fda", "exception", "nmi", "external", "irq", "vector"];
+
+/* Recursive descent over the JSON recording lines use */
+struct Parser<'a>
+{
+    line: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a>
+{
+    /* Next character past whitespace */
+    fn peek(&mut self) -> Option<u8> {
+        while self.pos < self.line.len() && self.line.as_bytes()[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+        self.line.as_bytes().get(self.pos).cloned()
+    }
+
+    fn eat(&mut self, c: u8) -> Result<(), String> {
+        if self.peek() != Some(c) {
+            return Err(format!("Expected '{}' at column {}", c as char, self.pos + 1));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        try!(self.eat(b'"'));
+        let mut res = String::new();
+        let mut chars = self.line[self.pos..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(res);
+                },
+                '\\' => match chars.next() {
+                    Some((_, '"')) => res.push('"'),
+                    Some((_, '\\')) => res.push('\\'),
+                    Some((_, 'n')) => res.push('\n'),
+                    Some((_, 'u')) => {
+                        let code: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                        match u32::from_str_radix(&code, 16).ok().and_then(::std::char::from_u32) {
+                            Some(c) => res.push(c),
+                            None => return Err(format!("Bad escape at column {}", self.pos + i + 1)),
+                        }
+                    },
+                    _ => return Err(format!("Bad escape at column {}", self.pos + i + 1)),
+                },
+                c => res.push(c),
+            }
+        }
+        Err(String::from("Unterminated string"))
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some(b'"') => self.string().map(Value::Text),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut list = Vec::new();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Value::List(list));
+                }
+                loop {
+                    list.push(try!(self.object()));
+                    if self.peek() != Some(b',') {
+                        try!(self.eat(b']'));
+                        return Ok(Value::List(list));
+                    }
+                    self.pos += 1;
+                }
+            },
+            _ => {
+                let start = self.pos;
+                while self.pos < self.line.len() && (self.line.as_bytes()[self.pos].is_ascii_alphanumeric() || self.line.as_bytes()[self.pos] == b'-') {
+                    self.pos += 1;
+                }
+                match &self.line[start..self.pos] {
+                    "" => Err(format!("Expected value at column {}", start + 1)),
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    number => Ok(Value::Number(String::from(number))),
+                }
+            },
+        }
+    }
+
+    fn object(&mut self) -> Result<Fields, String> {
+        try!(self.eat(b'{'));
+        let mut fields = Vec::new();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(fields);
+        }
+        loop {
+            let name = try!(self.string());
+            try!(self.eat(b':'));
+            fields.push((name, try!(self.value())));
+            if self.peek() != Some(b',') {
+                try!(self.eat(b'}'));
+                return Ok(fields);
+            }
+            self.pos += 1;
+        }
+    }
+}
+
+fn parse_object(line: &str) -> Result<Fields, String>
+{
+    let mut parser = Parser { line: line, pos: 0 };
+    let fields = try!(parser.object());
+    match parser.peek() {
+        Some(_) => Err(format!("Trailing characters at column {}", parser.pos + 1)),
+        None => Ok(fields),
+    }
+}
+
+fn field<'a>(fields: &'a Fields, name: &str) -> Result<&'a Value, String>
+{
+    fields.iter().find(|&&(ref field, _)| field == name).map(|&(_, ref value)| value).ok_or(format!("No {} field", name))
+}
+
+fn number<T: FromStr>(fields: &Fields, name: &str) -> Result<T, String>
+{
+    match *try!(field(fields, name)) {
+        Value::Number(ref number) => number.parse().map_err(|_| format!("Bad {} {}", name, number)),
+        _ => Err(format!("Field {} isn't a number", name)),
+    }
+}
+
+fn text<'a>(fields: &'a Fields, name: &str) -> Result<&'a str, String>
+{
+    match *try!(field(fields, name)) {
+        Value::Text(ref text) => Ok(text),
+        _ => Err(format!("Field {} isn't a string", name)),
+    }
+}
+
+fn boolean(fields: &Fields, name: &str) -> Result<bool, String>
+{
+    match *try!(field(fields, name)) {
+        Value::Bool(val) => Ok(val),
+        _ => Err(format!("Field {} isn't a boolean", name)),
+    }
+}
+
+/* "0x" prefixed hex content hash */
+fn hash(fields: &Fields, name: &str) -> Result<u64, String>
+{
+    let val = try!(text(fields, name));
+    if !val.starts_with("0x") {
+        return Err(format!("Bad {} {}", name, val));
+    }
+    u64::from_str_radix(&val[2..], 16).map_err(|_| format!("Bad {} {}", name, val))
+}
+
+fn name(fields: &Fields, field: &str) -> Result<&'static str, String>
+{
+    let val = try!(text(fields, field));
+    NAMES.iter().find(|&&name| name == val).map(|&name| name).ok_or(format!("Unknown {} {}", field, val))
+}
+
+/* Bytes from hex string, see eventlog::hex() */
+fn unhex(val: &str) -> Result<Vec<u8>, String>
+{
+    if val.len() % 2 != 0 || !val.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Bad hex data {}", val));
+    }
+    Ok((0..val.len() / 2).map(|i| u8::from_str_radix(&val[i * 2..i * 2 + 2], 16).unwrap()).collect())
+}
+
+/**
+ * Recording header line: snapshot guest starts from and disk images with their content hashes
+ */
+pub fn header(snapshot: &str, images: &[(String, u64)]) -> String
+{
+    let images: Vec<String> = images.iter()
+        .map(|&(ref path, hash)| format!("{{\"path\":{},\"hash\":\"0x{:016x}\"}}", quote(path), hash))
+        .collect();
+    format!("{{\"recording\":{},\"snapshot\":{},\"images\":[{}]}}", RECORDING_VERSION, quote(snapshot), images.join(","))
+}
+
+/**
+ * Content hash of a file, see snapshot::content_hash()
+ */
+pub fn file_hash(path: &str) -> io::Result<u64>
+{
+    let mut file = try!(File::open(path));
+    let mut buf = vec![0u8; 0x10000];
+    let mut hash = snapshot::CONTENT_HASH_SEED;
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => return Ok(hash),
+            Ok(len) => hash = snapshot::hash_more(hash, &buf[..len]),
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/**
+ * Open recording, counts inputs written to it
+ */
+pub struct Recording
+{
+    sink: Box<Write + Send>,
+    inputs: u64,
+}
+
+impl Recording
+{
+    pub fn new(sink: Box<Write + Send>) -> Recording {
+        Recording {
+            sink: sink,
+            inputs: 0,
+        }
+    }
+
+    /**
+     * Write line as it is, e.g. the header
+     */
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self.sink, "{}", line)
+    }
+
+    pub fn record(&mut self, exit: u64, input: &Input) -> io::Result<()> {
+        self.inputs += 1;
+        writeln!(self.sink, "{}", input.to_json(exit))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.sink.flush()
+    }
+}
+
+#[cfg(test)]
+mod record_test
+{
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /* Sink tests can look into after handing it over */
+    #[derive(Clone)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test] fn format() {
+        assert!(Input::SerialRx { device: "uart", value: 0x41 }.to_json(1042) ==
+                "{\"exit\":1042,\"input\":\"serial_rx\",\"device\":\"uart\",\"value\":65}");
+        assert!(Input::SerialLines { device: "uart", lines: 0xB0 }.to_json(0).ends_with(",\"lines\":176}"));
+        assert!(Input::NetRx { device: "ne2000", frame: vec![0xff, 0x00, 0x12] }.to_json(3).ends_with(",\"frame\":\"ff0012\"}"));
+        assert!(Input::Key { code: 0xE075, pressed: true }.to_json(3) ==
+                "{\"exit\":3,\"input\":\"key\",\"code\":57461,\"pressed\":true}");
+        assert!(Input::Mouse { dx: -3, dy: 2, dz: 0, buttons: 1 }.to_json(3).ends_with(",\"dx\":-3,\"dy\":2,\"dz\":0,\"buttons\":1}"));
+        assert!(Input::DiskRead { device: "hda", offset: 512, data: vec![0x55, 0xaa] }.to_json(9) ==
+                "{\"exit\":9,\"input\":\"disk_read\",\"device\":\"hda\",\"offset\":512,\"data\":\"55aa\"}");
+        assert!(Input::Clock { ns: 1500 }.to_json(7) == "{\"exit\":7,\"input\":\"clock\",\"ns\":1500}");
+        assert!(Input::WallClock { secs: 1500000000 }.to_json(7).ends_with(",\"secs\":1500000000}"));
+        assert!(Input::Timer { now: 54925000 }.to_json(7).ends_with("\"input\":\"timer\",\"now\":54925000}"));
+        assert!(Input::Inject { kind: "external", vector: 8, ip: 0x7C12 }.to_json(7).ends_with(",\"kind\":\"external\",\"vector\":8,\"ip\":31762}"));
+        assert!(Input::Io { port: 0x3FD, size: 1, write: false }.to_json(7).ends_with("\"input\":\"io\",\"port\":1021,\"dir\":\"read\",\"size\":1}"));
+        assert!(Input::Monitor { kind: "nmi", value: 2 }.to_json(7).ends_with("\"input\":\"monitor\",\"kind\":\"nmi\",\"value\":2}"));
+
+        assert!(header("run.rec.snap", &[]) == "{\"recording\":2,\"snapshot\":\"run.rec.snap\",\"images\":[]}");
+        assert!(header("a.snap", &[(String::from("cd.iso"), 0xabc)]).ends_with(
+                "\"images\":[{\"path\":\"cd.iso\",\"hash\":\"0x0000000000000abc\"}]}"));
+    }
+
+    #[test] fn stream() {
+        let buf = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+        let mut recording = Recording::new(Box::new(buf.clone()));
+
+        recording.write_line(&header("run.rec.snap", &[])).unwrap();
+        recording.record(0, &Input::Clock { ns: 10 }).unwrap();
+        recording.record(5, &Input::SerialRx { device: "uart", value: b'x' }).unwrap();
+        recording.record(5, &Input::Inject { kind: "external", vector: 0x0C, ip: 0x100 }).unwrap();
+        assert!(recording.inputs == 3);
+
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines.len() == 4 && lines[0].starts_with("{\"recording\":2,"));
+        assert!(lines[2] == "{\"exit\":5,\"input\":\"serial_rx\",\"device\":\"uart\",\"value\":120}");
+    }
+
+    #[test] fn parse() {
+        let inputs = [
+            Input::SerialRx { device: "uart", value: 0x41 },
+            Input::SerialLines { device: "pvcon", lines: 0xB0 },
+            Input::NetRx { device: "ne2000", frame: vec![0xff, 0x00, 0x12] },
+            Input::Key { code: 0xE075, pressed: true },
+            Input::Mouse { dx: -3, dy: 2, dz: -1, buttons: 5 },
+            Input::DiskRead { device: "fda", offset: 512, data: vec![0x55, 0xaa] },
+            Input::Clock { ns: 1500 },
+            Input::WallClock { secs: -5 },
+            Input::Timer { now: 54925000 },
+            Input::Inject { kind: "nmi", vector: 2, ip: 0xF0000 },
+            Input::Monitor { kind: "vector", value: 0x70 },
+            Input::Io { port: 0xF4, size: 4, write: true },
+        ];
+        for input in &inputs {
+            assert!(parse_line(&input.to_json(42)) == Ok(Line::Input(42, input.clone())), "{}", input.to_json(42));
+        }
+
+        let end = End { exit: 99, status: 13, ram_hash: 0x0123456789abcdef };
+        assert!(end.to_json() == "{\"exit\":99,\"end\":13,\"ram_hash\":\"0x0123456789abcdef\"}");
+        assert!(parse_line(&end.to_json()) == Ok(Line::End(end)));
+
+        let header = parse_header(&header("run.rec.snap", &[(String::from("c\\d \"1\".iso"), 0xabc)])).unwrap();
+        assert!(header.snapshot == "run.rec.snap" && header.images == vec![(String::from("c\\d \"1\".iso"), 0xabc)]);
+
+        assert!(parse_line("{\"exit\":1,\"input\":\"serial_rx\",\"device\":\"lpt\",\"value\":1}").is_err());
+        assert!(parse_line("{\"exit\":1,\"input\":\"clock\"}").is_err());
+        assert!(parse_line("{\"exit\":1,\"input\":\"clock\",\"ns\":-1}").is_err());
+        assert!(parse_line("{\"exit\":1,\"input\":\"clock\",\"ns\":1").is_err());
+        assert!(parse_line("{\"exit\":1,\"input\":\"disk_read\",\"device\":\"hda\",\"offset\":0,\"data\":\"5\"}").is_err());
+        assert!(parse_header("{\"recording\":1,\"snapshot\":\"a\",\"images\":[]}").is_err());
+    }
+
+    #[test] fn hash_of_file() {
+        let path = ::std::env::temp_dir().join(format!("xvm_record_hash_{}", ::std::process::id()));
+        let data: Vec<u8> = (0..200000).map(|i| (i * 7) as u8).collect();
+        File::create(&path).unwrap().write_all(&data).unwrap();
+
+        let hash = file_hash(path.to_str().unwrap()).unwrap();
+        ::std::fs::remove_file(&path).unwrap();
+        assert!(hash == snapshot::content_hash(&data));
+        assert!(file_hash(path.to_str().unwrap()).is_err());
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+lazy_static! {
+    static ref ENABLED: AtomicBool = AtomicBool::new(false);
+    static ref RECORDING: Mutex<Option<Recording>> = Mutex::new(None);
+    static ref EXITS: AtomicUsize = AtomicUsize::new(0);
+}
+
+/**
+ * Count VM exit, inputs after it are recorded at the new count
+ * Exits host forces don't count, see above.
+ */
+pub fn count_exit(exit_reason: u32)
+{
+    let forced = [hv_vmx_exit_reason::VMX_REASON_IRQ, hv_vmx_exit_reason::VMX_REASON_IRQ_WND,
+                  hv_vmx_exit_reason::VMX_REASON_VIRTUAL_NMI_WND, hv_vmx_exit_reason::VMX_REASON_MTF,
+                  hv_vmx_exit_reason::VMX_REASON_VMX_TIMER_EXPIRED];
+    if !forced.iter().any(|&reason| reason as u32 == exit_reason) {
+        EXITS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/**
+ * Take back the count of exit just counted, host forced it but that shows only once it is handled, e.g. an
+ * EPT violation of the dirty page log
+ */
+pub fn host_exit()
+{
+    EXITS.fetch_sub(1, Ordering::Relaxed);
+}
+
+/**
+ * VM exits counted so far
+ */
+pub fn exits() -> u64
+{
+    EXITS.load(Ordering::Relaxed) as u64
+}
+
+/**
+ * Inputs are being recorded
+ */
+pub fn enabled() -> bool
+{
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/**
+ * Record input built by closure, which is only called while recording
+ * Recording that can't be written to is closed, VM goes on without it.
+ */
+pub fn emit<F>(input: F) where F: FnOnce() -> Input
+{
+    if !enabled() {
+        return;
+    }
+
+    let mut recording = RECORDING.lock().unwrap();
+    let res = match *recording {
+        Some(ref mut recording) => recording.record(exits(), &input()),
+        None => return,
+    };
+
+    if let Err(err) = res {
+        error!("Recording write failed, closing it: {}", err);
+        ENABLED.store(false, Ordering::Relaxed);
+        *recording = None;
+    }
+}
+
+/**
+ * Start recording inputs to path, after saving snapshot of VM to start replay from next to it
+ * Disk images are noted with their content hashes. Vcpu thread only, before guest runs.
+ */
+pub fn start(path: &str, images: &[&str]) -> Result<(), String>
+{
+    let snapshot = format!("{}.snap", path);
+    try!(vm::save_snapshot(Path::new(&snapshot)).map_err(|err| format!("Can't save snapshot {}: {}", snapshot, err)));
+    try!(vm::restore_snapshot(&[Path::new(&snapshot)]).map_err(|err| format!("Can't restore snapshot {}: {}", snapshot, err)));
+
+    let mut hashes = Vec::new();
+    for image in images {
+        let hash = try!(file_hash(image).map_err(|err| format!("Can't hash disk image {}: {}", image, err)));
+        hashes.push((String::from(*image), hash));
+    }
+
+    let file = try!(File::create(path).map_err(|err| format!("Can't create recording {}: {}", path, err)));
+    let mut recording = Recording::new(Box::new(BufWriter::new(file)));
+    try!(recording.write_line(&header(&snapshot, &hashes)).map_err(|err| format!("Can't write recording {}: {}", path, err)));
+
+    *RECORDING.lock().unwrap() = Some(recording);
+    EXITS.store(0, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/**
+ * End recording with the state VM stops in, vcpu thread only
+ */
+pub fn finish(status: i32)
+{
+    /* VM stopped in a reverse replay, the recording ends where it was suspended */
+    if !enabled() {
+        suspend();
+        return;
+    }
+
+    if let Some(ref mut recording) = *RECORDING.lock().unwrap() {
+        let end = End { exit: exits(), status: status, ram_hash: vm::ram_hash() };
+        if let Err(err) = recording.write_line(&end.to_json()).and_then(|_| recording.flush()) {
+            error!("Recording write failed: {}", err);
+        }
+        debug!("Recorded {} inputs", recording.inputs);
+    }
+}
+
+/**
+ * Stop taking inputs while reverse execution replays what was recorded, see reverse.rs
+ * Recording is flushed, so it can be read back up to here.
+ */
+pub fn suspend()
+{
+    ENABLED.store(false, Ordering::Relaxed);
+    if let Some(ref mut recording) = *RECORDING.lock().unwrap() {
+        if let Err(err) = recording.flush() {
+            error!("Recording write failed: {}", err);
+        }
+    }
+}
+
+/**
+ * Take inputs again once reverse execution got guest back to where recording was suspended
+ */
+pub fn resume()
+{
+    if RECORDING.lock().unwrap().is_some() {
+        ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+/**
+ * Inputs recorded so far, a checkpoint replays the ones after it
+ */
+pub fn inputs() -> u64
+{
+    RECORDING.lock().unwrap().as_ref().map_or(0, |recording| recording.inputs)
+}
+
+/**
+ * Set exit count VM went back to, vcpu thread only
+ */
+pub fn rewind(exit: u64)
+{
+    EXITS.store(exit as usize, Ordering::Relaxed);
+}
+
+/* Images guest can't write, which replay needs as they were */
+fn read_only_images(config: &config::VmConfig) -> Vec<&str>
+{
+    let mut images = Vec::new();
+    if let Some(ref cdrom) = config.cdrom {
+        images.push(cdrom.as_str());
+    }
+    if let Some(ref hda) = config.hda {
+        if config.hda_read_only {
+            images.push(hda.as_str());
+        }
+    }
+    images
+}
+
+pub fn init(config: &config::VmConfig)
+{
+    if let Some(ref path) = config.record {
+        if let Err(err) = start(path, &read_only_images(config)) {
+            error!("{}", err);
+            ::std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,730 @@
+/*
+ * Replay of recorded guest inputs
+ *
+ * --replay <file> restores the snapshot a recording starts from, see record.rs, and runs guest on recorded
+ * inputs instead of host ones. Taps recording inputs take them from here: serial and network backends get
+ * bytes and frames at the exits they were taken at, disk images the data that was read and devices the guest
+ * time they read. Guest time only moves by recorded readings and timers fire at recorded exits for the time
+ * they fired at, nothing runs on event loop thread. Monitor injections and host keyboard and mouse events
+ * arrive at their exits too.
+ *
+ * Interrupts and NMIs are held back until the exit guest took them after, guest is then single stepped with
+ * monitor trap flag up to the IP it took them at. Guest looping without exits may pass that IP more than
+ * once, replay takes the first time.
+ *
+ * Port accesses, injected events and inputs guest asks for are checked against the recording as they happen.
+ * Guest going another way stops VM with a report of the recorded input, what happened instead and guest
+ * state. Run has to end with the exit status and hash of guest RAM recorded, with no inputs left over.
+ */
+
+use vm;
+use event;
+use record::{self, Input, Line, End};
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/* Single steps to an injection point before replay gives up on reaching it */
+const STEP_LIMIT: u64 = 1000000;
+
+/**
+ * Guest went another way than recorded: what recording has next and what happened instead
+ */
+#[derive(Debug, PartialEq)]
+pub struct Divergence
+{
+    pub exit: u64,
+    pub recorded: String,
+    pub actual: String,
+}
+
+impl fmt::Display for Divergence
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Replay diverged at exit {}\nrecorded: {}\nactual:   {}", self.exit, self.recorded, self.actual)
+    }
+}
+
+/* Recording stream input is consumed from, None for inputs that arrive on their own */
+fn stream_of(input: &Input) -> Option<String>
+{
+    match *input {
+        Input::SerialRx { device, .. } | Input::SerialLines { device, .. } | Input::NetRx { device, .. } |
+        Input::DiskRead { device, .. } => Some(format!("{}/{}", input.kind(), device)),
+        Input::Clock { .. } | Input::WallClock { .. } | Input::Inject { .. } | Input::Io { .. } => Some(String::from(input.kind())),
+        Input::Timer { .. } | Input::Monitor { .. } | Input::Key { .. } | Input::Mouse { .. } => None,
+    }
+}
+
+/* Recorded input is what guest asked for, values VMM reads from the recording are left out */
+fn matches(recorded: &Input, actual: &Input) -> bool
+{
+    match (recorded, actual) {
+        (&Input::Clock { .. }, &Input::Clock { .. }) | (&Input::WallClock { .. }, &Input::WallClock { .. }) => true,
+        (&Input::DiskRead { device, offset, ref data }, &Input::DiskRead { device: actual_device, offset: actual_offset, data: ref actual_data }) => {
+            device == actual_device && offset == actual_offset && data.len() == actual_data.len()
+        },
+        _ => recorded == actual,
+    }
+}
+
+/* Input VMM asked for as it appears in a divergence report */
+fn describe(exit: u64, input: &Input) -> String
+{
+    match *input {
+        Input::Clock { .. } | Input::WallClock { .. } => format!("{{\"exit\":{},\"input\":\"{}\"}}", exit, input.kind()),
+        Input::DiskRead { device, offset, ref data } => {
+            format!("{{\"exit\":{},\"input\":\"disk_read\",\"device\":\"{}\",\"offset\":{},\"size\":{}}}", exit, device, offset, data.len())
+        },
+        _ => input.to_json(exit),
+    }
+}
+
+/**
+ * Recording being replayed
+ */
+pub struct Replay
+{
+    snapshot: String,
+    images: Vec<(String, u64)>,
+    streams: HashMap<String, VecDeque<(u64, Input)>>,   // Inputs guest asks for, by stream_of()
+    arrivals: VecDeque<(u64, Input)>,                   // Inputs arriving on their own, in recorded order
+    lines: HashMap<&'static str, u8>,                   // Serial modem lines as of current exit
+    wall_clocks: u64,                                   // Wall clock readings taken so far
+    time_ns: u64,                                       // Guest time as of the last recorded reading
+    steps: u64,                                         // Single steps taken towards next injection point
+    end: Option<End>,
+    diverged: bool,
+}
+
+impl Replay
+{
+    /**
+     * Read recording text, header first
+     */
+    pub fn parse(text: &str) -> Result<Replay, String> {
+        Replay::parse_from(text, 0)
+    }
+
+    /**
+     * Read recording text for replay from a checkpoint taken after the first skip inputs, see reverse.rs
+     * Skipped modem lines and wall clock readings still count, devices restored there have seen them.
+     */
+    pub fn parse_from(text: &str, skip: u64) -> Result<Replay, String> {
+        let mut lines = text.lines();
+        let header = try!(record::parse_header(try!(lines.next().ok_or(String::from("Recording is empty")))));
+
+        let mut replay = Replay {
+            snapshot: header.snapshot,
+            images: header.images,
+            streams: HashMap::new(),
+            arrivals: VecDeque::new(),
+            lines: HashMap::new(),
+            wall_clocks: 0,
+            time_ns: 0,
+            steps: 0,
+            end: None,
+            diverged: false,
+        };
+
+        let mut skipped = 0;
+        for (i, line) in lines.enumerate() {
+            if replay.end.is_some() {
+                return Err(format!("Line {}: recording goes on past its end", i + 2));
+            }
+            match try!(record::parse_line(line).map_err(|err| format!("Line {}: {}", i + 2, err))) {
+                Line::Input(exit, input) if skipped < skip => {
+                    skipped += 1;
+                    match input {
+                        Input::SerialLines { .. } => replay.streams.entry(stream_of(&input).unwrap()).or_insert(VecDeque::new()).push_back((exit, input)),
+                        Input::WallClock { .. } => replay.wall_clocks += 1,
+                        _ => {},
+                    }
+                },
+                Line::Input(exit, input) => match stream_of(&input) {
+                    Some(stream) => replay.streams.entry(stream).or_insert(VecDeque::new()).push_back((exit, input)),
+                    None => replay.arrivals.push_back((exit, input)),
+                },
+                Line::End(end) => replay.end = Some(end),
+            }
+        }
+        Ok(replay)
+    }
+
+    /**
+     * Snapshot recording starts from
+     */
+    pub fn snapshot(&self) -> &str {
+        &self.snapshot
+    }
+
+    /**
+     * Disk images guest reads directly and their content hashes
+     */
+    pub fn images(&self) -> &[(String, u64)] {
+        &self.images
+    }
+
+    /* Recorded input first in stream as a report has it */
+    fn next_recorded(&self, stream: &str) -> String {
+        match self.streams.get(stream).and_then(|inputs| inputs.front()) {
+            Some(&(exit, ref input)) => input.to_json(exit),
+            None => String::from("end of recording"),
+        }
+    }
+
+    /**
+     * Next input arriving by exit, e.g. a timer firing
+     */
+    pub fn next_arrival(&mut self, exit: u64) -> Option<Input> {
+        match self.arrivals.front() {
+            Some(&(at, _)) if at <= exit => {},
+            _ => return None,
+        }
+
+        let (_, input) = self.arrivals.pop_front().unwrap();
+        if let Input::Timer { now } = input {
+            self.time_ns = self.time_ns.max(now);
+        }
+        Some(input)
+    }
+
+    /**
+     * Input device polls for, e.g. a serial byte, which is only there at the exit it was taken at
+     */
+    pub fn poll(&mut self, exit: u64, stream: &str) -> Result<Option<Input>, Divergence> {
+        let at = match self.streams.get(stream).and_then(|inputs| inputs.front()) {
+            Some(&(at, _)) => at,
+            None => return Ok(None),
+        };
+
+        if at > exit {
+            return Ok(None);
+        }
+        if at < exit {
+            return Err(Divergence {
+                exit: exit,
+                recorded: self.next_recorded(stream),
+                actual: format!("not taken by exit {}", exit),
+            });
+        }
+        Ok(self.streams.get_mut(stream).unwrap().pop_front().map(|(_, input)| input))
+    }
+
+    /**
+     * Input guest asks for or causes now, which has to be the next one of its kind in the recording
+     * Returns the recorded one, with the values VMM reads from it.
+     */
+    pub fn expect(&mut self, exit: u64, actual: &Input) -> Result<Input, Divergence> {
+        let stream = stream_of(actual).unwrap();
+        let found = match self.streams.get(&stream).and_then(|inputs| inputs.front()) {
+            Some(&(at, ref recorded)) => at == exit && matches(recorded, actual),
+            None => false,
+        };
+        if !found {
+            return Err(Divergence { exit: exit, recorded: self.next_recorded(&stream), actual: describe(exit, actual) });
+        }
+
+        let (_, input) = self.streams.get_mut(&stream).unwrap().pop_front().unwrap();
+        match input {
+            Input::Clock { ns } => self.time_ns = self.time_ns.max(ns),
+            Input::WallClock { .. } => self.wall_clocks += 1,
+            Input::Inject { .. } => self.steps = 0,
+            _ => {},
+        }
+        Ok(input)
+    }
+
+    /**
+     * Serial modem lines of device as of exit, None before any were recorded
+     */
+    pub fn lines(&mut self, exit: u64, device: &'static str) -> Option<u8> {
+        if let Some(inputs) = self.streams.get_mut(&format!("serial_lines/{}", device)) {
+            while inputs.front().map_or(false, |&(at, _)| at <= exit) {
+                if let Some((_, Input::SerialLines { lines, .. })) = inputs.pop_front() {
+                    self.lines.insert(device, lines);
+                }
+            }
+            /* Lines device sees first are recorded when it first polls them */
+            if let Some(&(_, Input::SerialLines { lines, .. })) = inputs.front() {
+                return Some(*self.lines.entry(device).or_insert(lines));
+            }
+        }
+        self.lines.get(device).cloned()
+    }
+
+    /**
+     * Host time jumps by exit, each one has RTC read host wall clock
+     */
+    pub fn clock_jumps(&self, exit: u64) -> u64 {
+        let pending = self.streams.get("wall_clock").map_or(0, |inputs| inputs.iter().take_while(|&&(at, _)| at <= exit).count());
+        self.wall_clocks + pending as u64
+    }
+
+    /**
+     * Guest IP the next interrupt or NMI goes in at, when it goes in after exit
+     * Guest that went past the exit without taking it diverged.
+     */
+    pub fn injection_point(&self, exit: u64) -> Result<Option<u64>, Divergence> {
+        match self.streams.get("inject").and_then(|inputs| inputs.front()) {
+            Some(&(at, Input::Inject { kind, ip, .. })) if kind != "exception" && at <= exit => {
+                if at < exit || self.steps >= STEP_LIMIT {
+                    return Err(Divergence {
+                        exit: exit,
+                        recorded: self.next_recorded("inject"),
+                        actual: format!("guest didn't take it at IP 0x{:x}", ip),
+                    });
+                }
+                Ok(Some(ip))
+            },
+            _ => Ok(None),
+        }
+    }
+
+    /**
+     * Count single step towards injection point
+     */
+    pub fn step(&mut self) {
+        self.steps += 1;
+    }
+
+    /**
+     * Guest time as of the last recorded reading
+     */
+    pub fn time_ns(&self) -> u64 {
+        self.time_ns
+    }
+
+    /**
+     * Check VM ended the way recorded run did, all inputs taken
+     */
+    pub fn finish(&self, exit: u64, status: i32, ram_hash: u64) -> Result<(), Divergence> {
+        let left = self.streams.values().filter_map(|inputs| inputs.front()).chain(self.arrivals.front()).min_by_key(|&&(at, _)| at);
+        if let Some(&(at, ref input)) = left {
+            return Err(Divergence { exit: exit, recorded: input.to_json(at), actual: format!("VM stopped with status {}", status) });
+        }
+
+        let actual = End { exit: exit, status: status, ram_hash: ram_hash };
+        match self.end {
+            Some(end) if end != actual => Err(Divergence { exit: exit, recorded: end.to_json(), actual: actual.to_json() }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod replay_test
+{
+    use super::*;
+
+    fn replay(inputs: &[(u64, Input)], end: Option<End>) -> Replay {
+        let mut text = record::header("run.rec.snap", &[(String::from("cd.iso"), 0x1234)]);
+        for &(exit, ref input) in inputs {
+            text = text + "\n" + &input.to_json(exit);
+        }
+        if let Some(end) = end {
+            text = text + "\n" + &end.to_json();
+        }
+        Replay::parse(&text).unwrap()
+    }
+
+    #[test] fn parse() {
+        let replay = replay(&[(0, Input::Clock { ns: 5 })], None);
+        assert!(replay.snapshot() == "run.rec.snap");
+        assert!(replay.images() == &[(String::from("cd.iso"), 0x1234)]);
+
+        assert!(Replay::parse("").is_err());
+        assert!(Replay::parse("{\"recording\":1,\"snapshot\":\"a\",\"images\":[]}").err().unwrap().contains("version 1"));
+        let err = Replay::parse("{\"recording\":2,\"snapshot\":\"a\",\"images\":[]}\n{\"exit\":1,\"input\":\"beep\"}").err().unwrap();
+        assert!(err == "Line 2: Unknown input beep", "{}", err);
+    }
+
+    #[test] fn parse_from_checkpoint() {
+        let mut text = record::header("run.rec.snap", &[]);
+        for &(exit, ref input) in &[(0, Input::SerialLines { device: "uart", lines: 0x30 }), (1, Input::WallClock { secs: 5 }),
+                                    (2, Input::Clock { ns: 100 }), (3, Input::Timer { now: 200 }), (4, Input::Clock { ns: 300 })] {
+            text = text + "\n" + &input.to_json(exit);
+        }
+
+        let mut replay = Replay::parse_from(&text, 4).unwrap();
+        assert!(replay.lines(3, "uart") == Some(0x30));
+        assert!(replay.clock_jumps(3) == 1);
+        assert!(replay.next_arrival(9).is_none());
+        assert!(replay.expect(4, &Input::Clock { ns: 0 }) == Ok(Input::Clock { ns: 300 }));
+        assert!(replay.finish(4, 0, 0).is_ok());
+    }
+
+    #[test] fn checked_inputs() {
+        let io = Input::Io { port: 0x3F8, size: 1, write: false };
+        let mut replay = replay(&[(1, Input::Clock { ns: 100 }), (3, io.clone()), (3, Input::Clock { ns: 250 }),
+                                  (4, Input::DiskRead { device: "hda", offset: 512, data: vec![1, 2] })], None);
+
+        assert!(replay.expect(1, &Input::Clock { ns: 0 }) == Ok(Input::Clock { ns: 100 }));
+        assert!(replay.time_ns() == 100);
+
+        /* Port access of another size, then the recorded one */
+        let err = replay.expect(3, &Input::Io { port: 0x3F8, size: 2, write: false }).unwrap_err();
+        assert!(err.recorded == io.to_json(3) && err.actual == "{\"exit\":3,\"input\":\"io\",\"port\":1016,\"dir\":\"read\",\"size\":2}");
+        assert!(replay.expect(3, &io) == Ok(io.clone()));
+
+        /* Clock read at another exit */
+        let err = replay.expect(2, &Input::Clock { ns: 0 }).unwrap_err();
+        assert!(err.actual == "{\"exit\":2,\"input\":\"clock\"}" && err.recorded.ends_with("\"ns\":250}"));
+        assert!(replay.expect(3, &Input::Clock { ns: 0 }).is_ok() && replay.time_ns() == 250);
+
+        let read = replay.expect(4, &Input::DiskRead { device: "hda", offset: 512, data: vec![0; 2] });
+        assert!(read == Ok(Input::DiskRead { device: "hda", offset: 512, data: vec![1, 2] }));
+        assert!(replay.expect(5, &io).unwrap_err().recorded == "end of recording");
+    }
+
+    #[test] fn polled_inputs() {
+        let mut replay = replay(&[(3, Input::SerialRx { device: "uart", value: b'a' }),
+                                  (3, Input::SerialRx { device: "uart", value: b'b' }),
+                                  (7, Input::SerialRx { device: "uart", value: b'c' })], None);
+
+        assert!(replay.poll(2, "serial_rx/uart") == Ok(None));
+        assert!(replay.poll(3, "serial_rx/uart") == Ok(Some(Input::SerialRx { device: "uart", value: b'a' })));
+        assert!(replay.poll(3, "serial_rx/uart") == Ok(Some(Input::SerialRx { device: "uart", value: b'b' })));
+        assert!(replay.poll(3, "serial_rx/uart") == Ok(None));
+        assert!(replay.poll(3, "serial_rx/pvcon") == Ok(None));
+
+        /* Byte guest didn't take when it did in the recording */
+        let err = replay.poll(8, "serial_rx/uart").unwrap_err();
+        assert!(err.exit == 8 && err.actual == "not taken by exit 8");
+    }
+
+    #[test] fn arrivals() {
+        let mut replay = replay(&[(2, Input::Timer { now: 1000 }), (2, Input::Monitor { kind: "nmi", value: 2 }),
+                                  (5, Input::Key { code: 0x1C, pressed: true })], None);
+
+        assert!(replay.next_arrival(1).is_none());
+        assert!(replay.next_arrival(2) == Some(Input::Timer { now: 1000 }) && replay.time_ns() == 1000);
+        assert!(replay.next_arrival(2) == Some(Input::Monitor { kind: "nmi", value: 2 }));
+        assert!(replay.next_arrival(4).is_none());
+        assert!(replay.next_arrival(9) == Some(Input::Key { code: 0x1C, pressed: true }));
+    }
+
+    #[test] fn lines_and_wall_clock() {
+        let mut replay = replay(&[(0, Input::SerialLines { device: "uart", lines: 0x30 }),
+                                  (4, Input::SerialLines { device: "uart", lines: 0xB0 }),
+                                  (6, Input::WallClock { secs: 1500000000 })], None);
+
+        assert!(replay.lines(0, "pvcon").is_none());
+        assert!(replay.lines(0, "uart") == Some(0x30));
+        assert!(replay.lines(3, "uart") == Some(0x30));
+        assert!(replay.lines(4, "uart") == Some(0xB0));
+        assert!(replay.lines(9, "uart") == Some(0xB0));
+
+        assert!(replay.clock_jumps(5) == 0 && replay.clock_jumps(6) == 1);
+        assert!(replay.expect(6, &Input::WallClock { secs: 0 }) == Ok(Input::WallClock { secs: 1500000000 }));
+        assert!(replay.clock_jumps(6) == 1);
+    }
+
+    #[test] fn injection_points() {
+        let mut replay = replay(&[(1, Input::Inject { kind: "exception", vector: 13, ip: 0x7C10 }),
+                                  (4, Input::Inject { kind: "external", vector: 8, ip: 0x7C20 })], None);
+
+        assert!(replay.injection_point(1) == Ok(None));
+        assert!(replay.expect(1, &Input::Inject { kind: "exception", vector: 13, ip: 0x7C10 }).is_ok());
+        assert!(replay.injection_point(3) == Ok(None));
+        assert!(replay.injection_point(4) == Ok(Some(0x7C20)));
+
+        /* Guest took the next exit without getting to the IP */
+        assert!(replay.injection_point(5).unwrap_err().actual == "guest didn't take it at IP 0x7c20");
+
+        for _ in 0..STEP_LIMIT {
+            replay.step();
+        }
+        assert!(replay.injection_point(4).is_err());
+        assert!(replay.expect(4, &Input::Inject { kind: "external", vector: 8, ip: 0x7C20 }).is_ok());
+        assert!(replay.steps == 0);
+    }
+
+    #[test] fn end() {
+        let end = End { exit: 9, status: 13, ram_hash: 0xabcd };
+        let mut replay = replay(&[(9, Input::Io { port: 0xF4, size: 1, write: true })], Some(end));
+
+        let err = replay.finish(9, 13, 0xabcd).unwrap_err();
+        assert!(err.recorded.ends_with("\"port\":244,\"dir\":\"write\",\"size\":1}") && err.actual == "VM stopped with status 13");
+
+        assert!(replay.expect(9, &Input::Io { port: 0xF4, size: 1, write: true }).is_ok());
+        assert!(replay.finish(9, 13, 0xabcd).is_ok());
+        assert!(replay.finish(9, 13, 0xabce).unwrap_err().actual == "{\"exit\":9,\"end\":13,\"ram_hash\":\"0x000000000000abce\"}");
+        assert!(replay.finish(8, 13, 0xabcd).is_err());
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+lazy_static! {
+    static ref ENABLED: AtomicBool = AtomicBool::new(false);
+    static ref REPLAY: Mutex<Option<Replay>> = Mutex::new(None);
+}
+
+/**
+ * Guest runs on recorded inputs
+ */
+pub fn enabled() -> bool
+{
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/* Run closure on replay in progress */
+fn with_replay<T, F>(f: F) -> T where F: FnOnce(&mut Replay) -> T
+{
+    f(REPLAY.lock().unwrap().as_mut().expect("no replay"))
+}
+
+/* Stop VM with divergence report, the first one is what counts */
+fn diverge(divergence: Divergence)
+{
+    let first = with_replay(|replay| !replay.diverged && { replay.diverged = true; true });
+    if first {
+        vm::request_vm_exit(vm::VmExit::ReplayDiverged(format!("{}\n{}", divergence, vm::vcpu_state())));
+    }
+}
+
+/* Recorded input VMM asks for now, None after divergence */
+fn expect(actual: &Input) -> Option<Input>
+{
+    match with_replay(|replay| replay.expect(record::exits(), actual)) {
+        Ok(input) => Some(input),
+        Err(divergence) => {
+            diverge(divergence);
+            None
+        },
+    }
+}
+
+/* Recorded input device polls for now */
+fn poll(stream: &str) -> Option<Input>
+{
+    match with_replay(|replay| replay.poll(record::exits(), stream)) {
+        Ok(input) => input,
+        Err(divergence) => {
+            diverge(divergence);
+            None
+        },
+    }
+}
+
+/**
+ * Read recording and check the disk images guest reads directly are the ones it was recorded with
+ */
+pub fn load(path: &str) -> Result<Replay, String>
+{
+    let mut text = String::new();
+    try!(File::open(path).and_then(|mut file| file.read_to_string(&mut text)).map_err(|err| err.to_string()));
+    let replay = try!(Replay::parse(&text));
+
+    for &(ref image, hash) in replay.images() {
+        let actual = try!(record::file_hash(image).map_err(|err| format!("Can't hash disk image {}: {}", image, err)));
+        if actual != hash {
+            return Err(format!("Disk image {} changed since it was recorded", image));
+        }
+    }
+    Ok(replay)
+}
+
+/**
+ * Run guest on recorded inputs from guest time restored snapshot has, see vm::replay()
+ */
+pub fn start(mut replay: Replay, time_ns: u64)
+{
+    replay.time_ns = time_ns;
+    *REPLAY.lock().unwrap() = Some(replay);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/**
+ * Drop replay in progress, guest runs on host inputs again
+ */
+pub fn stop()
+{
+    ENABLED.store(false, Ordering::Relaxed);
+    *REPLAY.lock().unwrap() = None;
+}
+
+/**
+ * Guest time during replay, None when not replaying
+ */
+pub fn time_ns() -> Option<u64>
+{
+    if !enabled() {
+        return None;
+    }
+    Some(with_replay(|replay| replay.time_ns()))
+}
+
+/**
+ * Guest time device reads now
+ */
+pub fn clock_ns() -> u64
+{
+    match expect(&Input::Clock { ns: 0 }) {
+        Some(Input::Clock { ns }) => ns,
+        _ => with_replay(|replay| replay.time_ns()),
+    }
+}
+
+/**
+ * Host time jumps virtual clock has seen by now
+ */
+pub fn clock_jumps() -> u64
+{
+    with_replay(|replay| replay.clock_jumps(record::exits()))
+}
+
+/**
+ * Host wall clock RTC reads now in seconds since Unix epoch, None when not replaying
+ */
+pub fn wall_clock() -> Option<i64>
+{
+    if !enabled() {
+        return None;
+    }
+    match expect(&Input::WallClock { secs: 0 }) {
+        Some(Input::WallClock { secs }) => Some(secs),
+        _ => None,
+    }
+}
+
+/**
+ * Byte serial device takes now, if one was taken
+ */
+pub fn serial_rx(device: &'static str) -> Option<u8>
+{
+    match poll(&format!("serial_rx/{}", device)) {
+        Some(Input::SerialRx { value, .. }) => Some(value),
+        _ => None,
+    }
+}
+
+/**
+ * Modem lines of serial device, None if none were recorded
+ */
+pub fn serial_lines(device: &'static str) -> Option<u8>
+{
+    if !enabled() {
+        return None;
+    }
+    with_replay(|replay| replay.lines(record::exits(), device))
+}
+
+/**
+ * Frame NIC takes now, if one was taken
+ */
+pub fn net_rx(device: &'static str) -> Option<Vec<u8>>
+{
+    match poll(&format!("net_rx/{}", device)) {
+        Some(Input::NetRx { frame, .. }) => Some(frame),
+        _ => None,
+    }
+}
+
+/**
+ * Fill buf with data read from disk image at offset, false after divergence
+ */
+pub fn disk_read(device: &'static str, offset: u64, buf: &mut [u8]) -> bool
+{
+    match expect(&Input::DiskRead { device: device, offset: offset, data: vec![0; buf.len()] }) {
+        Some(Input::DiskRead { data, .. }) => {
+            buf.copy_from_slice(&data);
+            true
+        },
+        _ => false,
+    }
+}
+
+/**
+ * Check port access or injection guest does is the recorded one, false when it isn't
+ */
+pub fn check(actual: &Input) -> bool
+{
+    !enabled() || expect(actual).is_some()
+}
+
+/**
+ * Deliver inputs arriving by current exit, vcpu thread after an exit is handled
+ */
+pub fn deliver_arrivals()
+{
+    if !enabled() {
+        return;
+    }
+
+    while let Some(input) = with_replay(|replay| replay.next_arrival(record::exits())) {
+        match input {
+            Input::Timer { .. } => {
+                event::run_due_events();
+            },
+            Input::Monitor { kind: "irq", value } => {
+                if let Err(err) = vm::inject_irq(value) {
+                    error!("Replay: {}", err);
+                }
+            },
+            Input::Monitor { kind: "vector", value } => {
+                vm::inject_vector(value);
+            },
+            Input::Monitor { .. } => {
+                vm::inject_nmi();
+            },
+            Input::Key { code, pressed } => vm::send_key_event(code, pressed),
+            Input::Mouse { dx, dy, dz, buttons } => vm::send_mouse_event(dx, dy, dz, buttons),
+            _ => {},
+        }
+    }
+}
+
+/**
+ * Step guest towards the IP it takes next interrupt at, vcpu thread before guest entry
+ */
+pub fn before_entry()
+{
+    if !enabled() || vm::step_in_progress() {
+        return;
+    }
+
+    match with_replay(|replay| replay.injection_point(record::exits())) {
+        Ok(Some(ip)) if ip != vm::current_ip() => {
+            with_replay(|replay| replay.step());
+            vm::start_step(vm::StepOwner::Replay);
+        },
+        Ok(_) => {},
+        Err(divergence) => diverge(divergence),
+    }
+}
+
+/**
+ * Interrupts and NMIs can go in at this guest entry
+ */
+pub fn interrupts_allowed() -> bool
+{
+    !enabled() || with_replay(|replay| replay.injection_point(record::exits())) == Ok(Some(vm::current_ip()))
+}
+
+/**
+ * Check VM stops the way recorded run did, returns status VMM exits with
+ */
+pub fn finish(status: i32) -> i32
+{
+    if !enabled() || with_replay(|replay| replay.diverged) {
+        return status;
+    }
+
+    match with_replay(|replay| replay.finish(record::exits(), status, vm::ram_hash())) {
+        Ok(()) => {
+            debug!("Replay ended as recorded at exit {}", record::exits());
+            status
+        },
+        Err(divergence) => {
+            let exit = vm::VmExit::ReplayDiverged(format!("{}\n{}", divergence, vm::vcpu_state()));
+            error!("{:?}", exit);
+            exit.status()
+        },
+    }
+}
@@ -0,0 +1,724 @@
+/*
+ * Reverse execution
+ *
+ * --reverse <ms>[,<k>] goes with --record and lets debugger and monitor take guest back: "bs" and "bc" from gdb,
+ * "reverse step" and "reverse cont" from monitor. While recording, a checkpoint is saved every so many ms of guest
+ * time to <recording>.rev the way automatic snapshots are, see autosnap.rs, keeping the last k. Snapshot the
+ * recording starts from is the first checkpoint and is always there. Checkpoints note the exit count, inputs
+ * recorded and registers they were taken at.
+ *
+ * Guest can't run backwards, so going back restores the nearest checkpoint before where it is going and replays
+ * the recording from there up to it, see replay.rs. Positions are exit counts with registers, as guest looping
+ * without exits passes the same IP with other registers, and guest is single stepped with monitor trap flag
+ * over the last exit count before the position, where it is looked for.
+ *
+ * Reverse step goes to the instruction before the one guest is at: the last position the segment up to it
+ * passes. Reverse continue goes to the last breakpoint hit before it, checkpoint segments are searched one
+ * after the other back to the first, and guest is taken to the start of the recording when none is hit. Both
+ * then replay the segment again, up to where they found, and stop there.
+ *
+ * Recording is suspended while guest is back in it and guest runs on recorded inputs, forward too, until it
+ * gets to where recording stopped. From there it takes host inputs again and recording goes on. Output guest
+ * sends while it is back in the recording has been sent before and goes nowhere.
+ */
+
+use vm;
+use config;
+use clock;
+use record;
+use replay::{self, Replay};
+use autosnap::{self, AutoSnapshot};
+use breakpoint;
+use gdbstub;
+use monitor;
+
+use std::cmp;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const NS_PER_MS: u64 = 1000000;
+
+/**
+ * Where guest is in a recorded run: exits it caused so far and its registers
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Position
+{
+    pub exit: u64,
+    pub state: vm::VcpuState,
+}
+
+/**
+ * Snapshot taken on the way, replay of the recording goes on from it
+ */
+#[derive(Clone, Debug)]
+pub struct Checkpoint
+{
+    pub chain: Vec<PathBuf>,    // Files snapshot is restored from, whole one first
+    pub position: Position,
+    pub inputs: u64,            // Inputs recorded before it
+}
+
+/**
+ * Checkpoints there are, oldest first, the first one is where recording starts
+ */
+pub struct Checkpoints
+{
+    list: Vec<Checkpoint>,
+}
+
+impl Checkpoints
+{
+    pub fn new(first: Checkpoint) -> Checkpoints {
+        Checkpoints {
+            list: vec![first],
+        }
+    }
+
+    /**
+     * Add checkpoint just saved, dropping the ones deleted files were in the chain of
+     */
+    pub fn add(&mut self, checkpoint: Checkpoint, deleted: &[PathBuf]) {
+        self.list.retain(|old| !old.chain.iter().any(|path| deleted.contains(path)));
+        self.list.push(checkpoint);
+    }
+
+    pub fn get(&self, index: usize) -> &Checkpoint {
+        &self.list[index]
+    }
+
+    /**
+     * Latest checkpoint before position, or at it when inclusive, the first one if there is none
+     */
+    pub fn before(&self, pos: &Position, inclusive: bool) -> usize {
+        self.list.iter().rposition(|checkpoint| {
+            checkpoint.position.exit < pos.exit || (inclusive && checkpoint.position == *pos)
+        }).unwrap_or(0)
+    }
+}
+
+/**
+ * Way to go back
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Direction
+{
+    Step,       // To the instruction before
+    Continue,   // To the last breakpoint hit before
+}
+
+/**
+ * Where guest stopped going back
+ */
+#[derive(Clone, PartialEq, Debug)]
+pub enum Stop
+{
+    Step,           // Instruction before
+    Breakpoint,     // Breakpoint hit before
+    Begin,          // Start of the recording, nothing before
+    Failed(String), // Guest couldn't be taken back, it is wherever that left it
+}
+
+/**
+ * What guest does next in a search
+ */
+#[derive(Clone, PartialEq, Debug)]
+pub enum Next
+{
+    Run,                // Run on, stepping once it gets to the window
+    Rewind(usize),      // Restore checkpoint and replay from it
+    Arrive(Stop),       // Stop here
+}
+
+/* Part of a search guest is in */
+#[derive(Clone, Debug)]
+enum Phase
+{
+    Scan(Position),         // Looking for the position to stop at up to this one
+    Seek(Position, Stop),   // Replaying up to the position found
+}
+
+/**
+ * Search for the position going back gets to, guest replays from checkpoints and tells where it passes
+ */
+pub struct Search
+{
+    direction: Direction,
+    phase: Phase,
+    from: usize,                // Checkpoint guest replays from
+    window: u64,                // Exit count guest is stepped and observed from
+    found: Option<Position>,    // Latest candidate of the scan
+}
+
+impl Search
+{
+    /**
+     * Start going back in direction from here, guest is rewound to checkpoint() first
+     */
+    pub fn new(direction: Direction, here: Position, checkpoints: &Checkpoints) -> Search {
+        let mut search = Search {
+            direction: direction,
+            phase: Phase::Scan(here),
+            from: 0,
+            window: 0,
+            found: None,
+        };
+        search.scan(here, checkpoints);
+        search
+    }
+
+    /**
+     * Checkpoint guest replays from
+     */
+    pub fn checkpoint(&self) -> usize {
+        self.from
+    }
+
+    /**
+     * Exit count guest is stepped from, it runs free up to there
+     */
+    pub fn window(&self) -> u64 {
+        self.window
+    }
+
+    /**
+     * Guest is at position here, in the window after a step or a rewind
+     */
+    pub fn observe(&mut self, here: Position, checkpoints: &Checkpoints) -> Next {
+        match self.phase.clone() {
+            Phase::Scan(end) => {
+                if here != end && here.exit <= end.exit {
+                    if self.direction == Direction::Step {
+                        self.found = Some(here);
+                    }
+                    return Next::Run;
+                }
+                if here != end {
+                    debug!("reverse: replay passed exit {} without getting to {:x}", end.exit, end.state.rip);
+                }
+                self.scanned(checkpoints)
+            },
+            Phase::Seek(target, stop) => {
+                if here == target {
+                    Next::Arrive(stop)
+                } else if here.exit > target.exit {
+                    Next::Arrive(Stop::Failed(format!("replay went past exit {} without getting to 0x{:x}", target.exit, target.state.rip)))
+                } else {
+                    Next::Run
+                }
+            },
+        }
+    }
+
+    /**
+     * Guest hit a breakpoint at position here, before it ran the instruction there
+     */
+    pub fn hit(&mut self, here: Position) {
+        if let Phase::Scan(end) = self.phase {
+            if self.direction == Direction::Continue && here != end && here.exit <= end.exit {
+                self.found = Some(here);
+            }
+        }
+    }
+
+    /* Look for the position to stop at up to end, from the checkpoint before it */
+    fn scan(&mut self, end: Position, checkpoints: &Checkpoints) -> Next {
+        self.from = checkpoints.before(&end, false);
+        let start = checkpoints.get(self.from).position.exit;
+        self.window = match self.direction {
+            Direction::Step => cmp::max(end.exit.saturating_sub(1), start),
+            Direction::Continue => cmp::max(end.exit, start),
+        };
+        self.phase = Phase::Scan(end);
+        self.found = None;
+        Next::Rewind(self.from)
+    }
+
+    /* Replay up to target from the checkpoint before it, or at it */
+    fn seek(&mut self, target: Position, stop: Stop, checkpoints: &Checkpoints) -> Next {
+        self.from = checkpoints.before(&target, true);
+        self.window = target.exit;
+        self.phase = Phase::Seek(target, stop);
+        Next::Rewind(self.from)
+    }
+
+    /* Scan got to its end, go to what it found or look further back */
+    fn scanned(&mut self, checkpoints: &Checkpoints) -> Next {
+        if let Some(found) = self.found {
+            let stop = match self.direction {
+                Direction::Step => Stop::Step,
+                Direction::Continue => Stop::Breakpoint,
+            };
+            return self.seek(found, stop, checkpoints);
+        }
+
+        let start = checkpoints.get(self.from).position;
+        if self.direction == Direction::Step && self.window > start.exit {
+            /* Instruction before caused more than one exit, step all of the segment */
+            self.window = start.exit;
+            return Next::Rewind(self.from);
+        }
+        if self.from > 0 {
+            return self.scan(start, checkpoints);
+        }
+        self.seek(start, Stop::Begin, checkpoints)
+    }
+}
+
+#[cfg(test)]
+mod reverse_test
+{
+    use super::*;
+
+    fn pos(exit: u64, rip: u64) -> Position {
+        Position { exit: exit, state: vm::VcpuState { rip: rip, ..Default::default() } }
+    }
+
+    fn checkpoint(name: &str, exit: u64, rip: u64) -> Checkpoint {
+        Checkpoint { chain: vec![PathBuf::from(name)], position: pos(exit, rip), inputs: exit }
+    }
+
+    /* Guest replaying run, one instruction per position, the search told where it goes and hits, up to where it stops */
+    fn replay(search: &mut Search, checkpoints: &Checkpoints, run: &[Position], hits: &[Position]) -> (Stop, Position) {
+        let mut next = Next::Rewind(search.checkpoint());
+        let mut at = 0;
+        for _ in 0..1000 {
+            at = match next {
+                Next::Rewind(index) => run.iter().position(|here| *here == checkpoints.get(index).position).unwrap(),
+                Next::Run => at + 1,
+                Next::Arrive(stop) => return (stop, run[at]),
+            };
+            if hits.contains(&run[at]) {
+                search.hit(run[at]);
+            }
+            next = if run[at].exit >= search.window() { search.observe(run[at], checkpoints) } else { Next::Run };
+        }
+        panic!("search doesn't end");
+    }
+
+    #[test] fn checkpoint_before() {
+        let mut checkpoints = Checkpoints::new(checkpoint("run.rec.snap", 0, 0x7c00));
+        checkpoints.add(checkpoint("a.snap", 10, 0x100), &[]);
+        checkpoints.add(checkpoint("b.snap", 20, 0x200), &[]);
+
+        assert!(checkpoints.before(&pos(15, 0), false) == 1);
+        assert!(checkpoints.before(&pos(10, 0x100), false) == 0);
+        assert!(checkpoints.before(&pos(10, 0x100), true) == 1);
+        assert!(checkpoints.before(&pos(10, 0x105), true) == 0);
+        assert!(checkpoints.before(&pos(0, 0x7c00), false) == 0);
+        assert!(checkpoints.before(&pos(99, 0), false) == 2);
+
+        /* Checkpoints whose chain lost a file go, the first one stays */
+        let mut inc = checkpoint("c.snap", 30, 0x300);
+        inc.chain.insert(0, PathBuf::from("b.snap"));
+        checkpoints.add(inc, &[]);
+        checkpoints.add(checkpoint("d.snap", 40, 0x400), &[PathBuf::from("b.snap")]);
+        let exits: Vec<u64> = checkpoints.list.iter().map(|checkpoint| checkpoint.position.exit).collect();
+        assert!(exits == vec![0, 10, 40]);
+    }
+
+    #[test] fn step_back() {
+        let run = [pos(0, 0x7c00), pos(0, 0x7c03), pos(1, 0x7c05), pos(1, 0x7c06), pos(2, 0x7c08), pos(3, 0x7c09)];
+        let mut checkpoints = Checkpoints::new(checkpoint("run.rec.snap", 0, 0x7c00));
+
+        /* Instruction before is in the same exit count or in the one before */
+        for i in 1..run.len() {
+            let mut search = Search::new(Direction::Step, run[i], &checkpoints);
+            assert!(replay(&mut search, &checkpoints, &run, &[]) == (Stop::Step, run[i - 1]));
+        }
+
+        /* Segment from the checkpoint before, the checkpoint itself is the instruction before the next one */
+        checkpoints.add(checkpoint("a.snap", 1, 0x7c06), &[]);
+        let mut search = Search::new(Direction::Step, run[4], &checkpoints);
+        assert!(search.checkpoint() == 1);
+        assert!(replay(&mut search, &checkpoints, &run, &[]) == (Stop::Step, run[3]));
+        let mut search = Search::new(Direction::Step, run[3], &checkpoints);
+        assert!(search.checkpoint() == 0);
+        assert!(replay(&mut search, &checkpoints, &run, &[]) == (Stop::Step, run[2]));
+
+        /* Nothing before the first instruction */
+        let mut search = Search::new(Direction::Step, run[0], &checkpoints);
+        assert!(replay(&mut search, &checkpoints, &run, &[]) == (Stop::Begin, run[0]));
+    }
+
+    #[test] fn continue_back() {
+        let run: Vec<Position> = (0..40).map(|i| pos(i / 4, 0x1000 + (i % 8))).collect();
+        let mut checkpoints = Checkpoints::new(checkpoint("run.rec.snap", 0, 0x1000));
+        checkpoints.add(Checkpoint { chain: vec![PathBuf::from("a.snap")], position: run[20], inputs: 5 }, &[]);
+
+        /* Last hit before, not the one guest stopped at, and guest is only stepped over the last exit count */
+        let mut search = Search::new(Direction::Continue, run[33], &checkpoints);
+        assert!(search.checkpoint() == 1 && search.window() == 8);
+        assert!(replay(&mut search, &checkpoints, &run, &[run[9], run[25], run[33]]) == (Stop::Breakpoint, run[25]));
+
+        /* Segment without hits has the search go on in the one before */
+        let mut search = Search::new(Direction::Continue, run[33], &checkpoints);
+        assert!(replay(&mut search, &checkpoints, &run, &[run[9], run[13]]) == (Stop::Breakpoint, run[13]));
+
+        /* No hit takes guest to the start */
+        let mut search = Search::new(Direction::Continue, run[33], &checkpoints);
+        assert!(replay(&mut search, &checkpoints, &run, &[run[33]]) == (Stop::Begin, run[0]));
+    }
+
+    #[test] fn replay_lost() {
+        let checkpoints = Checkpoints::new(checkpoint("run.rec.snap", 0, 0x1000));
+        let mut search = Search::new(Direction::Step, pos(2, 0x1003), &checkpoints);
+        assert!(search.observe(pos(1, 0x1002), &checkpoints) == Next::Run);
+        match search.observe(pos(3, 0x1004), &checkpoints) {
+            Next::Rewind(0) => {},
+            next => panic!("{:?}", next),
+        }
+        match search.observe(pos(2, 0x1005), &checkpoints) {
+            Next::Arrive(Stop::Failed(err)) => assert!(err.contains("past exit 1"), "{}", err),
+            next => panic!("{:?}", next),
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/**
+ * Who asked to go back, and gets told where guest stopped
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Requester
+{
+    Debugger,
+    Monitor,
+}
+
+/**
+ * Guest got where it was going back to
+ */
+pub struct Arrival
+{
+    pub by: Requester,
+    pub stop: Stop,
+}
+
+/* Where recording stopped, guest takes host inputs again once back there */
+struct Live
+{
+    position: Position,
+    time_ns: u64,       // Guest time
+    text: String,       // Recording up to there, checkpoints replay it
+}
+
+struct Reverse
+{
+    schedule: AutoSnapshot,
+    checkpoints: Checkpoints,
+    recording: PathBuf,
+    live: Option<Live>,
+    request: Option<(Direction, Requester)>,
+    search: Option<(Search, Requester)>,
+    temporary: Vec<breakpoint::BreakpointHandle>,   // Breakpoints for debugger's while reverse continue looks for hits
+}
+
+lazy_static! {
+    static ref SEEKING: AtomicBool = AtomicBool::new(false);
+    static ref REPLAYING: AtomicBool = AtomicBool::new(false);
+    static ref REVERSE: Mutex<Option<Reverse>> = Mutex::new(None);
+}
+
+fn position() -> Position
+{
+    Position { exit: record::exits(), state: vm::vcpu_state() }
+}
+
+impl Reverse
+{
+    /* Save checkpoint if one came due while recording */
+    fn checkpoint(&mut self) {
+        let now = clock::guest_time_ns();
+        if !self.schedule.due(now) {
+            return;
+        }
+
+        let here = position();
+        match self.schedule.save(now, &mut autosnap::VmSaver) {
+            Ok(saved) => {
+                debug!("reverse: checkpoint {} at exit {}", saved.path.display(), here.exit);
+                self.checkpoints.add(Checkpoint { chain: saved.chain, position: here, inputs: record::inputs() }, &saved.deleted);
+            },
+            Err(failed) => warn!("reverse: can't save checkpoint {}: {}", failed.path.display(), failed.error),
+        }
+    }
+
+    /* Start going back from where guest is */
+    fn start(&mut self, direction: Direction, by: Requester) -> Option<Arrival> {
+        if !vm::step_with_mtf() {
+            return Some(Arrival { by: by, stop: Stop::Failed(String::from("Reverse execution needs VMX monitor trap flag to step guest")) });
+        }
+
+        let here = position();
+        if self.live.is_none() {
+            if !record::enabled() {
+                return Some(Arrival { by: by, stop: Stop::Failed(String::from("Recording is closed, there is nothing to go back through")) });
+            }
+            record::suspend();
+            let mut text = String::new();
+            if let Err(err) = File::open(&self.recording).and_then(|mut file| file.read_to_string(&mut text)) {
+                record::resume();
+                return Some(Arrival { by: by, stop: Stop::Failed(format!("Can't read recording {}: {}", self.recording.display(), err)) });
+            }
+            self.live = Some(Live { position: here, time_ns: clock::guest_time_ns(), text: text });
+            REPLAYING.store(true, Ordering::Relaxed);
+        }
+
+        if direction == Direction::Continue {
+            for (addr, _) in gdbstub::breakpoints() {
+                if let Ok(handle) = vm::add_breakpoint(addr) {
+                    self.temporary.push(handle);
+                }
+            }
+        }
+
+        let search = Search::new(direction, here, &self.checkpoints);
+        let from = search.checkpoint();
+        self.search = Some((search, by));
+        SEEKING.store(true, Ordering::Relaxed);
+        self.advance(Next::Rewind(from))
+    }
+
+    /* Restore checkpoint and replay recording from there */
+    fn rewind(&mut self, index: usize) -> Result<(), String> {
+        let checkpoint = self.checkpoints.get(index).clone();
+        let text = &self.live.as_ref().unwrap().text;
+        let recording = try!(Replay::parse_from(text, checkpoint.inputs));
+
+        replay::stop();
+        let paths: Vec<&Path> = checkpoint.chain.iter().map(PathBuf::as_path).collect();
+        try!(vm::rewind(&paths));
+        replay::start(recording, clock::guest_time_ns());
+        record::rewind(checkpoint.position.exit);
+        Ok(())
+    }
+
+    /* Follow search until guest has to run on */
+    fn advance(&mut self, mut next: Next) -> Option<Arrival> {
+        loop {
+            next = match next {
+                Next::Rewind(index) => match self.rewind(index) {
+                    Ok(()) => self.observe(),
+                    Err(err) => Next::Arrive(Stop::Failed(err)),
+                },
+                Next::Run => {
+                    let window = self.search.as_ref().unwrap().0.window();
+                    if !vm::step_in_progress() && record::exits() >= window {
+                        vm::start_step(vm::StepOwner::Reverse);
+                    }
+                    return None;
+                },
+                Next::Arrive(stop) => return Some(self.arrive(stop)),
+            };
+        }
+    }
+
+    /* Tell search where guest is, once it is in the window and no step is under way */
+    fn observe(&mut self) -> Next {
+        let search = &mut self.search.as_mut().unwrap().0;
+        if vm::step_in_progress() || record::exits() < search.window() {
+            return Next::Run;
+        }
+        search.observe(position(), &self.checkpoints)
+    }
+
+    /* Search ended, guest stays where it is */
+    fn arrive(&mut self, stop: Stop) -> Arrival {
+        vm::cancel_step();
+        for handle in self.temporary.drain(..) {
+            vm::remove_breakpoint(handle);
+        }
+        vm::resume_at_breakpoint();
+
+        let (_, by) = self.search.take().unwrap();
+        SEEKING.store(false, Ordering::Relaxed);
+        Arrival { by: by, stop: stop }
+    }
+
+    /* Guest replays forward, step it over the last exit count to where recording stopped and go live there */
+    fn catch_up(&mut self) {
+        let live = self.live.as_ref().unwrap().position;
+        let exits = record::exits();
+        if exits < live.exit || vm::step_in_progress() {
+            return;
+        }
+
+        if exits == live.exit && position() != live {
+            vm::start_step(vm::StepOwner::Reverse);
+            return;
+        }
+        if exits > live.exit {
+            warn!("reverse: replay passed exit {} without getting to {:x}, recording goes on from here", live.exit, live.state.rip);
+        }
+        self.go_live();
+    }
+
+    /* Guest is back where recording stopped, it takes host inputs again */
+    fn go_live(&mut self) {
+        let live = self.live.take().unwrap();
+        replay::stop();
+        if let Err(err) = clock::restore_guest_time(live.time_ns, clock::time_dilation()) {
+            warn!("reverse: can't restore guest time: {}", err);
+        }
+        record::rewind(cmp::max(live.position.exit, record::exits()));
+        record::resume();
+        REPLAYING.store(false, Ordering::Relaxed);
+        debug!("reverse: recording goes on at exit {}", live.position.exit);
+    }
+}
+
+fn cmd_reverse(ctx: &mut monitor::MonitorContext, direction: Direction) -> Result<String, String>
+{
+    if !enabled() {
+        return Err(String::from("Reverse execution is off, see --reverse"));
+    }
+    if ctx.run_state != monitor::RunState::Stopped {
+        return Err(String::from("Guest can only go back stopped, stop it first"));
+    }
+
+    request(direction, Requester::Monitor);
+    ctx.run_state = monitor::RunState::Running;
+    ctx.stop_reason = None;
+    Ok(String::new())
+}
+
+fn cmd_reverse_step(ctx: &mut monitor::MonitorContext, _: &[&str]) -> Result<String, String>
+{
+    cmd_reverse(ctx, Direction::Step)
+}
+
+fn cmd_reverse_cont(ctx: &mut monitor::MonitorContext, _: &[&str]) -> Result<String, String>
+{
+    cmd_reverse(ctx, Direction::Continue)
+}
+
+/**
+ * Reverse execution is configured
+ */
+pub fn enabled() -> bool
+{
+    REVERSE.lock().unwrap().is_some()
+}
+
+/**
+ * Guest is being taken back, it replays on its own and debugger and monitor wait until it gets there
+ */
+pub fn seeking() -> bool
+{
+    SEEKING.load(Ordering::Relaxed)
+}
+
+/**
+ * Guest is back in the recording and replays it, output it sends was sent before
+ */
+pub fn replaying() -> bool
+{
+    REPLAYING.load(Ordering::Relaxed)
+}
+
+/**
+ * Take guest back in direction, from the next poll() on
+ */
+pub fn request(direction: Direction, by: Requester)
+{
+    if let Some(ref mut reverse) = *REVERSE.lock().unwrap() {
+        reverse.request = Some((direction, by));
+    }
+}
+
+/**
+ * Guest hit breakpoint while being taken back, it stops there only if that is where it is going
+ */
+pub fn breakpoint_hit(state: vm::VcpuState)
+{
+    if let Some(ref mut reverse) = *REVERSE.lock().unwrap() {
+        if let Some((ref mut search, _)) = reverse.search {
+            search.hit(Position { exit: record::exits(), state: state });
+        }
+    }
+}
+
+/**
+ * Save checkpoint that is due, start going back that was asked for and take guest on to where it is going
+ * Vcpu thread only, at every exit. Returns where guest stopped going back, once it gets there.
+ */
+pub fn poll() -> Option<Arrival>
+{
+    let mut reverse = REVERSE.lock().unwrap();
+    let reverse = match *reverse {
+        Some(ref mut reverse) => reverse,
+        None => return None,
+    };
+
+    if let Some((direction, by)) = reverse.request.take() {
+        return reverse.start(direction, by);
+    }
+    if reverse.search.is_some() {
+        let next = reverse.observe();
+        return reverse.advance(next);
+    }
+    if reverse.live.is_some() {
+        reverse.catch_up();
+    } else if record::enabled() {
+        reverse.checkpoint();
+    }
+    None
+}
+
+/**
+ * Drop replay of a reverse execution as VM stops, recording ends where it was suspended
+ */
+pub fn finish()
+{
+    if replaying() {
+        replay::stop();
+    }
+}
+
+/**
+ * Start saving checkpoints of the recording, vcpu thread only, after recording started
+ */
+pub fn init(config: &config::VmConfig)
+{
+    monitor::register_command(monitor::MonitorCommand {
+        name: "reverse step",
+        args: "",
+        help: "take stopped guest back one instruction in the recording",
+        handler: cmd_reverse_step,
+    });
+    monitor::register_command(monitor::MonitorCommand {
+        name: "reverse cont",
+        args: "",
+        help: "take stopped guest back to the last breakpoint it hit, or to the start of the recording",
+        handler: cmd_reverse_cont,
+    });
+
+    let (reverse, path) = match (config.reverse.as_ref(), config.record.as_ref()) {
+        (Some(reverse), Some(path)) => (reverse, path),
+        _ => return,
+    };
+    if !record::enabled() {
+        return;
+    }
+
+    let first = Checkpoint {
+        chain: vec![PathBuf::from(format!("{}.snap", path))],
+        position: position(),
+        inputs: record::inputs(),
+    };
+    let dir = PathBuf::from(format!("{}.rev", path));
+    *REVERSE.lock().unwrap() = Some(Reverse {
+        schedule: AutoSnapshot::new(&dir, reverse.interval_ms as u64 * NS_PER_MS, reverse.keep, clock::guest_time_ns()),
+        checkpoints: Checkpoints::new(first),
+        recording: PathBuf::from(path),
+        live: None,
+        request: None,
+        search: None,
+        temporary: Vec::new(),
+    });
+}
@@ -0,0 +1,415 @@
+/*
+ * Host side backends for emulated serial ports
+ *
+ * Input backends read host data on their own threads into a shared queue.
+ * Device model drains the queue from guest context, so nothing but the queue is shared between threads.
+ * Input keeps queueing while VM is paused and is delivered once guest runs again.
+ */
+
+use config;
+use record;
+use replay;
+use reverse;
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Condvar};
+use std::thread;
+
+// Reader threads stop taking host input while this much is waiting for the guest
+const SERIAL_INPUT_LIMIT: usize = 4096;
+
+// Modem status lines driven by the far end, laid out as in 16550 MSR
+pub const SERIAL_LINE_CTS: u8   = 0x10;
+pub const SERIAL_LINE_DSR: u8   = 0x20;
+pub const SERIAL_LINE_RI: u8    = 0x40;
+pub const SERIAL_LINE_DCD: u8   = 0x80;
+
+/**
+ * Host end of the guest serial line
+ */
+pub trait serial_backend
+{
+    /** Deliver byte transmitted by guest */
+    fn write(&mut self, val: u8);
+
+    /** Take next byte destined to guest, None if nothing is pending */
+    fn read(&mut self) -> Option<u8>;
+
+    /** Guest is (not) ready to receive, backend should pause input when not */
+    fn set_ready(&mut self, ready: bool);
+
+    /** Modem status lines (SERIAL_LINE_*), a plain host stream looks like an always connected modem */
+    fn modem_lines(&self) -> u8 {
+        SERIAL_LINE_CTS | SERIAL_LINE_DSR | SERIAL_LINE_DCD
+    }
+}
+
+/*
+ * Input queue filled by a reader thread.
+ * Reader blocks while queue is full or guest is not ready, so host side sees backpressure.
+ */
+struct InputState
+{
+    queue: VecDeque<u8>,
+    ready: bool,
+}
+
+#[derive(Clone)]
+struct InputQueue
+{
+    state: Arc<(Mutex<InputState>, Condvar)>,
+}
+
+impl InputQueue
+{
+    fn new() -> InputQueue {
+        InputQueue {
+            state: Arc::new((Mutex::new(InputState { queue: VecDeque::new(), ready: true }), Condvar::new())),
+        }
+    }
+
+    /* Reader side: wait until more input can be accepted */
+    fn wait_for_room(&self) {
+        let &(ref lock, ref cond) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        while !state.ready || state.queue.len() >= SERIAL_INPUT_LIMIT {
+            state = cond.wait(state).unwrap();
+        }
+    }
+
+    fn push(&self, data: &[u8]) {
+        let &(ref lock, _) = &*self.state;
+        lock.lock().unwrap().queue.extend(data.iter().cloned());
+    }
+
+    fn pop(&self) -> Option<u8> {
+        let &(ref lock, ref cond) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        let val = state.queue.pop_front();
+        cond.notify_one();
+        val
+    }
+
+    fn set_ready(&self, ready: bool) {
+        let &(ref lock, ref cond) = &*self.state;
+        lock.lock().unwrap().ready = ready;
+        cond.notify_one();
+    }
+
+    /* Pump a host stream into the queue until it ends */
+    fn read_from<R: Read>(&self, mut input: R) {
+        let mut buf = [0u8; 256];
+        loop {
+            self.wait_for_room();
+            match input.read(&mut buf) {
+                Ok(0) => return,
+                Ok(len) => self.push(&buf[..len]),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => {
+                    error!("serial: input failed: {}", err);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Host process stdin and stdout.
+ * Input arrives as host terminal delivers it, i.e. line buffered unless terminal is put in raw mode.
+ */
+pub struct StdioBackend
+{
+    input: InputQueue,
+}
+
+impl StdioBackend
+{
+    pub fn open() -> StdioBackend {
+        let input = InputQueue::new();
+        let reader = input.clone();
+        thread::spawn(move || {
+            reader.read_from(io::stdin());
+        });
+
+        StdioBackend {
+            input: input,
+        }
+    }
+}
+
+impl serial_backend for StdioBackend
+{
+    fn write(&mut self, val: u8) {
+        let mut out = io::stdout();
+        out.write_all(&[val]).and_then(|_| out.flush()).unwrap_or_else(|err| {
+            error!("serial: failed writing to stdout: {}", err);
+        });
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.input.pop()
+    }
+
+    fn set_ready(&mut self, ready: bool) {
+        self.input.set_ready(ready);
+    }
+}
+
+/**
+ * TCP server accepting one client at a time, e.g. telnet or netcat
+ * Carrier is detected while a client is connected.
+ */
+pub struct TcpBackend
+{
+    input: InputQueue,
+    client: Arc<Mutex<Option<TcpStream>>>,
+    port: u16,
+}
+
+impl TcpBackend
+{
+    /** Listen on local port, 0 picks any free port */
+    pub fn listen(port: u16) -> io::Result<TcpBackend> {
+        let listener = try!(TcpListener::bind(("127.0.0.1", port)));
+        let port = try!(listener.local_addr()).port();
+
+        let input = InputQueue::new();
+        let client = Arc::new(Mutex::new(None));
+
+        let reader = input.clone();
+        let writer = client.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        error!("serial: accept failed: {}", err);
+                        continue;
+                    }
+                };
+
+                match stream.try_clone() {
+                    Ok(clone) => *writer.lock().unwrap() = Some(clone),
+                    Err(err) => {
+                        error!("serial: failed to set up client: {}", err);
+                        continue;
+                    }
+                }
+
+                reader.read_from(stream);
+                *writer.lock().unwrap() = None;
+            }
+        });
+
+        Ok(TcpBackend {
+            input: input,
+            client: client,
+            port: port,
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl serial_backend for TcpBackend
+{
+    /* Output is dropped while nobody is connected */
+    fn write(&mut self, val: u8) {
+        let mut client = self.client.lock().unwrap();
+        let failed = match *client {
+            Some(ref mut stream) => stream.write_all(&[val]).is_err(),
+            None => false,
+        };
+
+        if failed {
+            *client = None;
+        }
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.input.pop()
+    }
+
+    fn set_ready(&mut self, ready: bool) {
+        self.input.set_ready(ready);
+    }
+
+    fn modem_lines(&self) -> u8 {
+        if self.client.lock().unwrap().is_some() {
+            SERIAL_LINE_CTS | SERIAL_LINE_DSR | SERIAL_LINE_DCD
+        } else {
+            SERIAL_LINE_CTS | SERIAL_LINE_DSR
+        }
+    }
+}
+
+/**
+ * Capture guest output to a host file, never receives anything
+ */
+pub struct FileBackend
+{
+    file: File,
+}
+
+impl FileBackend
+{
+    pub fn create(path: &str) -> io::Result<FileBackend> {
+        Ok(FileBackend {
+            file: try!(File::create(path)),
+        })
+    }
+}
+
+impl serial_backend for FileBackend
+{
+    fn write(&mut self, val: u8) {
+        self.file.write_all(&[val]).unwrap_or_else(|err| {
+            error!("serial: failed writing to file: {}", err);
+        });
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn set_ready(&mut self, _: bool) {
+    }
+}
+
+/**
+ * Backend passing what guest takes from another one to input recording, see record.rs
+ */
+pub struct RecordedBackend
+{
+    inner: Box<serial_backend>,
+    device: &'static str,
+    lines: Cell<Option<u8>>,    // Modem lines as device last saw them
+}
+
+impl RecordedBackend
+{
+    pub fn new(inner: Box<serial_backend>, device: &'static str) -> RecordedBackend {
+        RecordedBackend {
+            inner: inner,
+            device: device,
+            lines: Cell::new(None),
+        }
+    }
+}
+
+impl serial_backend for RecordedBackend
+{
+    fn write(&mut self, val: u8) {
+        /* Guest sent this before, reverse execution only took it back */
+        if !reverse::replaying() {
+            self.inner.write(val);
+        }
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        if replay::enabled() {
+            return replay::serial_rx(self.device);
+        }
+
+        let val = self.inner.read();
+        if let Some(val) = val {
+            let device = self.device;
+            record::emit(|| record::Input::SerialRx { device: device, value: val });
+        }
+        val
+    }
+
+    fn set_ready(&mut self, ready: bool) {
+        self.inner.set_ready(ready);
+    }
+
+    fn modem_lines(&self) -> u8 {
+        if let Some(lines) = replay::serial_lines(self.device) {
+            return lines;
+        }
+
+        let lines = self.inner.modem_lines();
+        if self.lines.get() != Some(lines) {
+            self.lines.set(Some(lines));
+            let device = self.device;
+            record::emit(|| record::Input::SerialLines { device: device, lines: lines });
+        }
+        lines
+    }
+}
+
+/**
+ * Open backend described by its configuration
+ */
+pub fn open_backend(config: &config::SerialConfig) -> io::Result<Box<serial_backend>>
+{
+    match *config {
+        config::SerialConfig::Stdio => Ok(Box::new(StdioBackend::open())),
+        config::SerialConfig::Tcp(port) => Ok(Box::new(try!(TcpBackend::listen(port)))),
+        config::SerialConfig::File(ref path) => Ok(Box::new(try!(FileBackend::create(path)))),
+    }
+}
+
+#[cfg(test)]
+mod serial_test
+{
+    use super::*;
+    use std::time::Duration;
+
+    fn wait_read(backend: &mut serial_backend) -> u8 {
+        for _ in 0..1000 {
+            if let Some(val) = backend.read() {
+                return val;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        panic!("no input");
+    }
+
+    #[test] fn tcp_loop() {
+        let mut backend = TcpBackend::listen(0).unwrap();
+        let mut client = TcpStream::connect(("127.0.0.1", backend.port())).unwrap();
+
+        client.write_all(b"hi").unwrap();
+        assert!(wait_read(&mut backend) == b'h');
+        assert!(wait_read(&mut backend) == b'i');
+        assert!(backend.read().is_none());
+
+        /* Output reaches client once it has been accepted */
+        while backend.client.lock().unwrap().is_none() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        backend.write(b'!');
+        let mut buf = [0u8; 1];
+        client.read_exact(&mut buf).unwrap();
+        assert!(buf[0] == b'!');
+    }
+
+    #[test] fn input_flow_control() {
+        let input = InputQueue::new();
+        input.set_ready(false);
+
+        let reader = input.clone();
+        let done = thread::spawn(move || {
+            reader.read_from(&b"abc"[..]);
+        });
+
+        /* Nothing is taken from host while guest is not ready */
+        thread::sleep(Duration::from_millis(20));
+        assert!(input.pop().is_none());
+
+        input.set_ready(true);
+        done.join().unwrap();
+        assert!(input.pop() == Some(b'a'));
+        assert!(input.pop() == Some(b'b'));
+        assert!(input.pop() == Some(b'c'));
+        assert!(input.pop().is_none());
+    }
+}
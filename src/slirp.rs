@@ -0,0 +1,1093 @@
+/*
+ * User mode NAT network backend
+ *
+ * A small IPv4 stack on the host end of the guest link, in the spirit of slirp. Guest sees a
+ * 10.0.2.0/24 network with a gateway at 10.0.2.2 that answers ARP, DHCP and ping. Outgoing TCP and
+ * UDP flows end here and are carried on through host sockets, whatever host sends back is turned
+ * into frames for the guest. Gateway address itself stands for host loopback.
+ *
+ * No port forwarding, no IP fragments, no IPv6. Needs no privileges or host configuration.
+ */
+
+use net::net_backend;
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream, UdpSocket, Shutdown};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Virtual network
+pub const SLIRP_GATEWAY_IP: [u8; 4]     = [10, 0, 2, 2];
+pub const SLIRP_GUEST_IP: [u8; 4]       = [10, 0, 2, 15];
+pub const SLIRP_GATEWAY_MAC: [u8; 6]    = [0x52, 0x55, 0x0A, 0x00, 0x02, 0x02];
+const SLIRP_NETMASK: [u8; 4]            = [255, 255, 255, 0];
+const SLIRP_LEASE_TIME: u32             = 86400;
+
+// Ethernet
+const ETH_ALEN: usize           = 6;
+const ETH_HLEN: usize           = 14;
+const ETH_P_IP: u16             = 0x0800;
+const ETH_P_ARP: u16            = 0x0806;
+const ETH_BROADCAST: [u8; ETH_ALEN] = [0xFF; ETH_ALEN];
+
+// ARP for IPv4 over Ethernet
+const ARP_LEN: usize            = 28;
+const ARPHRD_ETHER: u16         = 1;
+const ARPOP_REQUEST: u16        = 1;
+const ARPOP_REPLY: u16          = 2;
+
+// IPv4
+const IP_HLEN: usize            = 20;
+const IP_VERSION: u8            = 4;
+const IP_MF: u16                = 0x2000;
+const IP_OFFSET_MASK: u16       = 0x1FFF;
+const IP_TTL: u8                = 64;
+const IPPROTO_ICMP: u8          = 1;
+const IPPROTO_TCP: u8           = 6;
+const IPPROTO_UDP: u8           = 17;
+const IP_BROADCAST: [u8; 4]     = [255, 255, 255, 255];
+
+// ICMP
+const ICMP_ECHO_REPLY: u8       = 0;
+const ICMP_ECHO_REQUEST: u8     = 8;
+
+// UDP
+const UDP_HLEN: usize           = 8;
+const UDP_IDLE_SECS: u64        = 60;       // Unused UDP flows are forgotten after this long
+
+// DHCP over BOOTP
+const BOOTP_SERVER_PORT: u16    = 67;
+const BOOTP_CLIENT_PORT: u16    = 68;
+const BOOTP_REQUEST: u8         = 1;
+const BOOTP_REPLY: u8           = 2;
+const BOOTP_LEN: usize          = 236;      // Fixed part up to options
+const DHCP_MAGIC: [u8; 4]       = [0x63, 0x82, 0x53, 0x63];
+
+// DHCP options
+const DHCP_OPT_PAD: u8          = 0;
+const DHCP_OPT_NETMASK: u8      = 1;
+const DHCP_OPT_ROUTER: u8       = 3;
+const DHCP_OPT_LEASE_TIME: u8   = 51;
+const DHCP_OPT_MSG_TYPE: u8     = 53;
+const DHCP_OPT_SERVER_ID: u8    = 54;
+const DHCP_OPT_END: u8          = 255;
+
+// DHCP message types
+const DHCPDISCOVER: u8          = 1;
+const DHCPOFFER: u8             = 2;
+const DHCPREQUEST: u8           = 3;
+const DHCPACK: u8               = 5;
+
+// TCP
+const TCP_HLEN: usize           = 20;
+const TCP_FIN: u8               = 0x01;
+const TCP_SYN: u8               = 0x02;
+const TCP_RST: u8               = 0x04;
+const TCP_PSH: u8               = 0x08;
+const TCP_ACK: u8               = 0x10;
+const TCP_MSS: usize            = 1460;
+const TCP_WINDOW: usize         = 0x4000;   // Receive window offered to guest
+const TCP_SEND_BUFFER: usize    = 0x10000;  // Host data held for guest before reading stops
+const TCP_RTO_MS: u64           = 500;      // Unacknowledged data is sent again after this long
+
+/* Big endian field access */
+fn get_u16(buf: &[u8], offset: usize) -> u16
+{
+    (buf[offset] as u16) << 8 | buf[offset + 1] as u16
+}
+
+fn get_u32(buf: &[u8], offset: usize) -> u32
+{
+    (get_u16(buf, offset) as u32) << 16 | get_u16(buf, offset + 2) as u32
+}
+
+fn put_u16(buf: &mut Vec<u8>, val: u16)
+{
+    buf.extend_from_slice(&[(val >> 8) as u8, val as u8]);
+}
+
+fn put_u32(buf: &mut Vec<u8>, val: u32)
+{
+    put_u16(buf, (val >> 16) as u16);
+    put_u16(buf, val as u16);
+}
+
+fn ip_bytes(buf: &[u8], offset: usize) -> [u8; 4]
+{
+    [buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]]
+}
+
+/* Internet checksum continued from a partial sum */
+fn checksum_add(mut sum: u32, data: &[u8]) -> u32
+{
+    for pair in data.chunks(2) {
+        sum += (pair[0] as u32) << 8 | if pair.len() > 1 { pair[1] as u32 } else { 0 };
+    }
+    sum
+}
+
+fn checksum_fold(mut sum: u32) -> u16
+{
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn checksum(data: &[u8]) -> u16
+{
+    checksum_fold(checksum_add(0, data))
+}
+
+/* TCP and UDP checksum covers IPv4 pseudo header */
+fn transport_checksum(src: [u8; 4], dst: [u8; 4], proto: u8, segment: &[u8]) -> u16
+{
+    let mut pseudo = Vec::with_capacity(12);
+    pseudo.extend_from_slice(&src);
+    pseudo.extend_from_slice(&dst);
+    put_u16(&mut pseudo, proto as u16);
+    put_u16(&mut pseudo, segment.len() as u16);
+    checksum_fold(checksum_add(checksum_add(0, &pseudo), segment))
+}
+
+/* Sequence number comparison modulo 2^32 */
+fn seq_diff(a: u32, b: u32) -> i32
+{
+    a.wrapping_sub(b) as i32
+}
+
+/*
+ * Guest flow is identified by guest port and remote endpoint, guest address is always the lease
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct FlowKey
+{
+    guest_port: u16,
+    addr: [u8; 4],
+    port: u16,
+}
+
+impl FlowKey
+{
+    /* Host socket address, gateway stands for loopback */
+    fn host_addr(&self) -> SocketAddr {
+        let ip = if self.addr == SLIRP_GATEWAY_IP {
+            Ipv4Addr::new(127, 0, 0, 1)
+        } else {
+            Ipv4Addr::new(self.addr[0], self.addr[1], self.addr[2], self.addr[3])
+        };
+
+        SocketAddr::V4(SocketAddrV4::new(ip, self.port))
+    }
+
+    fn name(&self, proto: &str) -> String {
+        format!("{} {}:{} -> {}:{}", proto, Ipv4Addr::from(SLIRP_GUEST_IP), self.guest_port,
+                Ipv4Addr::from(self.addr), self.port)
+    }
+}
+
+/*
+ * NATed TCP connection. Guest talks to us as if we were the remote end.
+ * Data from host is kept until guest acknowledges it, lost segments are sent again from the
+ * oldest unacknowledged byte.
+ */
+struct TcpFlow
+{
+    connect: Option<Receiver<io::Result<TcpStream>>>,   // Host connect still in progress
+    stream: Option<TcpStream>,
+    iss: u32,               // Our initial sequence number
+    rcv_nxt: u32,           // Next sequence number expected from guest
+    snd_una: u32,           // Oldest sequence number guest hasn't acknowledged
+    snd_nxt: u32,           // Next sequence number to send
+    snd_wnd: u32,           // Guest receive window
+    syn_acked: bool,
+    unacked: Vec<u8>,       // Host data from snd_una on (past SYN)
+    to_host: Vec<u8>,       // Guest data host socket didn't take yet
+    host_eof: bool,         // Host closed its end, FIN follows the data
+    fin_acked: bool,
+    guest_fin: bool,        // Guest closed its end
+    host_shutdown: bool,    // Host socket write side was closed after guest FIN
+    last_send: Instant,
+}
+
+impl TcpFlow
+{
+    /* Sequence number right after last data byte */
+    fn data_end(&self) -> u32 {
+        self.snd_una.wrapping_add(self.unacked.len() as u32)
+    }
+
+    fn is_finished(&self) -> bool {
+        self.guest_fin && self.fin_acked && self.to_host.is_empty()
+    }
+
+    fn window(&self) -> u16 {
+        TCP_WINDOW.saturating_sub(self.to_host.len()) as u16
+    }
+}
+
+struct UdpFlow
+{
+    socket: UdpSocket,
+    last_used: Instant,
+}
+
+/**
+ * User mode NAT, see module description
+ */
+pub struct SlirpBackend
+{
+    guest_mac: [u8; ETH_ALEN],  // Learned from guest frames, broadcast until then
+    ip_id: u16,
+    next_iss: u32,
+    out: VecDeque<Vec<u8>>,     // Frames for guest
+    tcp: HashMap<FlowKey, TcpFlow>,
+    udp: HashMap<FlowKey, UdpFlow>,
+}
+
+impl SlirpBackend
+{
+    pub fn new() -> SlirpBackend {
+        SlirpBackend {
+            guest_mac: ETH_BROADCAST,
+            ip_id: 0,
+            next_iss: 0x1000_0000,
+            out: VecDeque::new(),
+            tcp: HashMap::new(),
+            udp: HashMap::new(),
+        }
+    }
+
+    fn send_eth(&mut self, dst: [u8; ETH_ALEN], ethertype: u16, payload: &[u8]) {
+        let mut frame = Vec::with_capacity(ETH_HLEN + payload.len());
+        frame.extend_from_slice(&dst);
+        frame.extend_from_slice(&SLIRP_GATEWAY_MAC);
+        put_u16(&mut frame, ethertype);
+        frame.extend_from_slice(payload);
+        self.out.push_back(frame);
+    }
+
+    fn send_ip(&mut self, src: [u8; 4], dst: [u8; 4], proto: u8, payload: &[u8]) {
+        let mut packet = Vec::with_capacity(IP_HLEN + payload.len());
+        packet.push(IP_VERSION << 4 | (IP_HLEN / 4) as u8);
+        packet.push(0);
+        put_u16(&mut packet, (IP_HLEN + payload.len()) as u16);
+        put_u16(&mut packet, self.ip_id);
+        put_u16(&mut packet, 0);
+        packet.push(IP_TTL);
+        packet.push(proto);
+        put_u16(&mut packet, 0);
+        packet.extend_from_slice(&src);
+        packet.extend_from_slice(&dst);
+        let csum = checksum(&packet);
+        packet[10] = (csum >> 8) as u8;
+        packet[11] = csum as u8;
+        packet.extend_from_slice(payload);
+
+        self.ip_id = self.ip_id.wrapping_add(1);
+
+        let mac = if dst == IP_BROADCAST { ETH_BROADCAST } else { self.guest_mac };
+        self.send_eth(mac, ETH_P_IP, &packet);
+    }
+
+    fn send_udp(&mut self, src: [u8; 4], src_port: u16, dst: [u8; 4], dst_port: u16, data: &[u8]) {
+        let mut dgram = Vec::with_capacity(UDP_HLEN + data.len());
+        put_u16(&mut dgram, src_port);
+        put_u16(&mut dgram, dst_port);
+        put_u16(&mut dgram, (UDP_HLEN + data.len()) as u16);
+        put_u16(&mut dgram, 0);
+        dgram.extend_from_slice(data);
+
+        /* Zero means no checksum, so a computed zero goes out as all ones */
+        let csum = match transport_checksum(src, dst, IPPROTO_UDP, &dgram) {
+            0 => 0xFFFF,
+            csum => csum,
+        };
+        dgram[6] = (csum >> 8) as u8;
+        dgram[7] = csum as u8;
+
+        self.send_ip(src, dst, IPPROTO_UDP, &dgram);
+    }
+
+    fn send_tcp(&mut self, key: &FlowKey, seq: u32, ack: u32, flags: u8, window: u16, data: &[u8]) {
+        let mut seg = Vec::with_capacity(TCP_HLEN + data.len());
+        put_u16(&mut seg, key.port);
+        put_u16(&mut seg, key.guest_port);
+        put_u32(&mut seg, seq);
+        put_u32(&mut seg, ack);
+        seg.push(((TCP_HLEN / 4) as u8) << 4);
+        seg.push(flags);
+        put_u16(&mut seg, window);
+        put_u16(&mut seg, 0);
+        put_u16(&mut seg, 0);
+        seg.extend_from_slice(data);
+
+        let csum = transport_checksum(key.addr, SLIRP_GUEST_IP, IPPROTO_TCP, &seg);
+        seg[16] = (csum >> 8) as u8;
+        seg[17] = csum as u8;
+
+        self.send_ip(key.addr, SLIRP_GUEST_IP, IPPROTO_TCP, &seg);
+    }
+
+    /*
+     * ARP: only gateway lives on the virtual link besides the guest
+     */
+    fn handle_arp(&mut self, arp: &[u8]) {
+        if arp.len() < ARP_LEN || get_u16(arp, 0) != ARPHRD_ETHER || get_u16(arp, 2) != ETH_P_IP ||
+           get_u16(arp, 6) != ARPOP_REQUEST || ip_bytes(arp, 24) != SLIRP_GATEWAY_IP {
+            return;
+        }
+
+        let mut reply = Vec::with_capacity(ARP_LEN);
+        reply.extend_from_slice(&arp[0..6]);
+        put_u16(&mut reply, ARPOP_REPLY);
+        reply.extend_from_slice(&SLIRP_GATEWAY_MAC);
+        reply.extend_from_slice(&SLIRP_GATEWAY_IP);
+        reply.extend_from_slice(&arp[8..18]);
+
+        let mut dst = [0u8; ETH_ALEN];
+        dst.copy_from_slice(&arp[8..14]);
+        self.send_eth(dst, ETH_P_ARP, &reply);
+    }
+
+    fn handle_ip(&mut self, packet: &[u8]) {
+        if packet.len() < IP_HLEN || packet[0] >> 4 != IP_VERSION {
+            return;
+        }
+
+        let hlen = (packet[0] & 0x0F) as usize * 4;
+        let total = get_u16(packet, 2) as usize;
+        if hlen < IP_HLEN || total < hlen || total > packet.len() || checksum(&packet[..hlen]) != 0 {
+            debug!("slirp: dropping malformed IP packet");
+            return;
+        }
+
+        if get_u16(packet, 6) & (IP_MF | IP_OFFSET_MASK) != 0 {
+            debug!("slirp: dropping IP fragment");
+            return;
+        }
+
+        let src = ip_bytes(packet, 12);
+        let dst = ip_bytes(packet, 16);
+        let payload = &packet[hlen..total];
+
+        match packet[9] {
+            IPPROTO_ICMP if dst == SLIRP_GATEWAY_IP => self.handle_icmp(src, payload),
+            IPPROTO_UDP => self.handle_udp(src, dst, payload),
+            IPPROTO_TCP if src == SLIRP_GUEST_IP => self.handle_tcp(dst, payload),
+            proto => debug!("slirp: dropping IP protocol {} packet to {}", proto, Ipv4Addr::from(dst)),
+        }
+    }
+
+    /* Gateway answers ping itself */
+    fn handle_icmp(&mut self, src: [u8; 4], icmp: &[u8]) {
+        if icmp.len() < 8 || icmp[0] != ICMP_ECHO_REQUEST || checksum(icmp) != 0 {
+            return;
+        }
+
+        let mut reply = icmp.to_vec();
+        reply[0] = ICMP_ECHO_REPLY;
+        reply[2] = 0;
+        reply[3] = 0;
+        let csum = checksum(&reply);
+        reply[2] = (csum >> 8) as u8;
+        reply[3] = csum as u8;
+
+        self.send_ip(SLIRP_GATEWAY_IP, src, IPPROTO_ICMP, &reply);
+    }
+
+    fn handle_udp(&mut self, src: [u8; 4], dst: [u8; 4], dgram: &[u8]) {
+        if dgram.len() < UDP_HLEN || (get_u16(dgram, 4) as usize) < UDP_HLEN || get_u16(dgram, 4) as usize > dgram.len() {
+            return;
+        }
+
+        let src_port = get_u16(dgram, 0);
+        let dst_port = get_u16(dgram, 2);
+        let data = &dgram[UDP_HLEN..get_u16(dgram, 4) as usize];
+
+        if dst_port == BOOTP_SERVER_PORT && (dst == IP_BROADCAST || dst == SLIRP_GATEWAY_IP) {
+            self.handle_dhcp(data);
+            return;
+        }
+
+        if src != SLIRP_GUEST_IP || dst == IP_BROADCAST {
+            return;
+        }
+
+        let key = FlowKey { guest_port: src_port, addr: dst, port: dst_port };
+        if !self.udp.contains_key(&key) {
+            let socket = match UdpSocket::bind("0.0.0.0:0").and_then(|socket| {
+                try!(socket.connect(key.host_addr()));
+                try!(socket.set_nonblocking(true));
+                Ok(socket)
+            }) {
+                Ok(socket) => socket,
+                Err(err) => {
+                    warn!("slirp: {} failed: {}", key.name("udp"), err);
+                    return;
+                }
+            };
+
+            debug!("slirp: {} opened", key.name("udp"));
+            self.udp.insert(key, UdpFlow { socket: socket, last_used: Instant::now() });
+        }
+
+        let flow = self.udp.get_mut(&key).unwrap();
+        flow.last_used = Instant::now();
+        match flow.socket.send(data) {
+            Ok(_) => {},
+            Err(err) => debug!("slirp: {} send failed: {}", key.name("udp"), err),
+        }
+    }
+
+    /*
+     * DHCP server handing out the one fixed lease
+     */
+    fn handle_dhcp(&mut self, msg: &[u8]) {
+        if msg.len() < BOOTP_LEN + DHCP_MAGIC.len() || msg[0] != BOOTP_REQUEST || msg[BOOTP_LEN..BOOTP_LEN + 4] != DHCP_MAGIC {
+            return;
+        }
+
+        /* Options are code, length, value triplets except for pad and end */
+        let mut msg_type = None;
+        let mut pos = BOOTP_LEN + 4;
+        while pos < msg.len() && msg[pos] != DHCP_OPT_END {
+            if msg[pos] == DHCP_OPT_PAD {
+                pos += 1;
+                continue;
+            }
+
+            if pos + 2 > msg.len() || pos + 2 + msg[pos + 1] as usize > msg.len() {
+                break;
+            }
+
+            if msg[pos] == DHCP_OPT_MSG_TYPE && msg[pos + 1] == 1 {
+                msg_type = Some(msg[pos + 2]);
+            }
+            pos += 2 + msg[pos + 1] as usize;
+        }
+
+        let reply_type = match msg_type {
+            Some(DHCPDISCOVER) => DHCPOFFER,
+            Some(DHCPREQUEST) => DHCPACK,
+            _ => return,
+        };
+
+        let mut reply = vec![0u8; BOOTP_LEN];
+        reply[0] = BOOTP_REPLY;
+        reply[1..3].copy_from_slice(&msg[1..3]);        // Hardware type and address length
+        reply[4..8].copy_from_slice(&msg[4..8]);        // Transaction ID
+        reply[10..12].copy_from_slice(&msg[10..12]);    // Flags
+        reply[16..20].copy_from_slice(&SLIRP_GUEST_IP);
+        reply[20..24].copy_from_slice(&SLIRP_GATEWAY_IP);
+        reply[28..44].copy_from_slice(&msg[28..44]);    // Client hardware address
+
+        reply.extend_from_slice(&DHCP_MAGIC);
+        reply.extend_from_slice(&[DHCP_OPT_MSG_TYPE, 1, reply_type]);
+        reply.extend_from_slice(&[DHCP_OPT_SERVER_ID, 4]);
+        reply.extend_from_slice(&SLIRP_GATEWAY_IP);
+        reply.extend_from_slice(&[DHCP_OPT_LEASE_TIME, 4]);
+        put_u32(&mut reply, SLIRP_LEASE_TIME);
+        reply.extend_from_slice(&[DHCP_OPT_NETMASK, 4]);
+        reply.extend_from_slice(&SLIRP_NETMASK);
+        reply.extend_from_slice(&[DHCP_OPT_ROUTER, 4]);
+        reply.extend_from_slice(&SLIRP_GATEWAY_IP);
+        reply.push(DHCP_OPT_END);
+
+        debug!("slirp: DHCP {} for {}", if reply_type == DHCPOFFER { "offer" } else { "ack" },
+               Ipv4Addr::from(SLIRP_GUEST_IP));
+        self.send_udp(SLIRP_GATEWAY_IP, BOOTP_SERVER_PORT, IP_BROADCAST, BOOTP_CLIENT_PORT, &reply);
+    }
+
+    fn handle_tcp(&mut self, dst: [u8; 4], seg: &[u8]) {
+        if seg.len() < TCP_HLEN || transport_checksum(SLIRP_GUEST_IP, dst, IPPROTO_TCP, seg) != 0 {
+            debug!("slirp: dropping malformed TCP segment");
+            return;
+        }
+
+        let doff = (seg[12] >> 4) as usize * 4;
+        if doff < TCP_HLEN || doff > seg.len() {
+            return;
+        }
+
+        let key = FlowKey { guest_port: get_u16(seg, 0), addr: dst, port: get_u16(seg, 2) };
+        let seq = get_u32(seg, 4);
+        let ack = get_u32(seg, 8);
+        let flags = seg[13];
+        let window = get_u16(seg, 14) as u32;
+        let data = &seg[doff..];
+
+        if !self.tcp.contains_key(&key) {
+            if flags & TCP_RST != 0 {
+                return;
+            }
+
+            if flags & (TCP_SYN | TCP_ACK) != TCP_SYN {
+                /* Not ours, or forgotten */
+                let ack_seq = seq.wrapping_add(data.len() as u32);
+                self.send_tcp(&key, if flags & TCP_ACK != 0 { ack } else { 0 }, ack_seq, TCP_RST | TCP_ACK, 0, &[]);
+                return;
+            }
+
+            self.tcp_open(key, seq, window);
+            return;
+        }
+
+        if flags & TCP_RST != 0 {
+            debug!("slirp: {} reset by guest", key.name("tcp"));
+            self.tcp.remove(&key);
+            return;
+        }
+
+        let mut reply = false;
+        {
+            let flow = self.tcp.get_mut(&key).unwrap();
+            if flow.connect.is_some() {
+                return;
+            }
+
+            /* Retransmitted SYN, our SYN-ACK got lost */
+            if flags & TCP_SYN != 0 {
+                if !flow.syn_acked {
+                    flow.snd_nxt = flow.iss;
+                }
+                return;
+            }
+
+            flow.snd_wnd = window;
+
+            if flags & TCP_ACK != 0 {
+                if !flow.syn_acked && ack == flow.iss.wrapping_add(1) {
+                    flow.syn_acked = true;
+                    flow.snd_una = ack;
+                }
+
+                /* Retransmission may have moved snd_nxt back, so check against all data ever sent */
+                let acked = seq_diff(ack, flow.snd_una);
+                let limit = flow.data_end().wrapping_add(if flow.host_eof { 1 } else { 0 });
+                if flow.syn_acked && acked > 0 && seq_diff(ack, limit) <= 0 {
+                    let data_acked = ::std::cmp::min(acked as usize, flow.unacked.len());
+                    flow.unacked.drain(..data_acked);
+                    flow.snd_una = flow.snd_una.wrapping_add(data_acked as u32);
+                    if flow.host_eof && ack == limit {
+                        flow.fin_acked = true;
+                    }
+                    if seq_diff(ack, flow.snd_nxt) > 0 {
+                        flow.snd_nxt = ack;
+                    }
+                    flow.last_send = Instant::now();
+                }
+            }
+
+            /* Only in-order data is taken, anything else gets a duplicate ACK */
+            if !data.is_empty() || flags & TCP_FIN != 0 {
+                if seq == flow.rcv_nxt && !flow.guest_fin {
+                    flow.to_host.extend_from_slice(data);
+                    flow.rcv_nxt = flow.rcv_nxt.wrapping_add(data.len() as u32);
+                    if flags & TCP_FIN != 0 {
+                        flow.rcv_nxt = flow.rcv_nxt.wrapping_add(1);
+                        flow.guest_fin = true;
+                        debug!("slirp: {} closed by guest", key.name("tcp"));
+                    }
+                }
+                reply = true;
+            }
+        }
+
+        self.tcp_flush_to_host(&key);
+        if reply {
+            let (snd_nxt, rcv_nxt, window) = {
+                let flow = &self.tcp[&key];
+                (flow.snd_nxt, flow.rcv_nxt, flow.window())
+            };
+            self.send_tcp(&key, snd_nxt, rcv_nxt, TCP_ACK, window, &[]);
+        }
+        self.tcp_output(&key);
+    }
+
+    /* Guest SYN starts host connect in background, SYN-ACK goes out when it completes */
+    fn tcp_open(&mut self, key: FlowKey, seq: u32, window: u32) {
+        let (tx, rx) = mpsc::channel();
+        let addr = key.host_addr();
+        thread::spawn(move || {
+            tx.send(TcpStream::connect(addr)).ok();
+        });
+
+        debug!("slirp: {} connecting", key.name("tcp"));
+
+        let iss = self.next_iss;
+        self.next_iss = self.next_iss.wrapping_add(0x10000);
+
+        self.tcp.insert(key, TcpFlow {
+            connect: Some(rx),
+            stream: None,
+            iss: iss,
+            rcv_nxt: seq.wrapping_add(1),
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_wnd: window,
+            syn_acked: false,
+            unacked: Vec::new(),
+            to_host: Vec::new(),
+            host_eof: false,
+            fin_acked: false,
+            guest_fin: false,
+            host_shutdown: false,
+            last_send: Instant::now(),
+        });
+    }
+
+    /* Hand guest data to host socket as far as it takes it, close write side after guest FIN */
+    fn tcp_flush_to_host(&mut self, key: &FlowKey) {
+        let flow = self.tcp.get_mut(key).unwrap();
+        let stream = match flow.stream {
+            Some(ref mut stream) => stream,
+            None => return,
+        };
+
+        while !flow.to_host.is_empty() {
+            match stream.write(&flow.to_host) {
+                Ok(0) => break,
+                Ok(len) => { flow.to_host.drain(..len); },
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    debug!("slirp: {} host write failed: {}", key.name("tcp"), err);
+                    flow.to_host.clear();
+                    break;
+                }
+            }
+        }
+
+        if flow.guest_fin && flow.to_host.is_empty() && !flow.host_shutdown {
+            stream.shutdown(Shutdown::Write).ok();
+            flow.host_shutdown = true;
+        }
+    }
+
+    /* Send whatever guest window allows from snd_nxt on: SYN-ACK, data and FIN */
+    fn tcp_output(&mut self, key: &FlowKey) {
+        let mut segments = Vec::new();
+        {
+            let flow = self.tcp.get_mut(key).unwrap();
+            if flow.connect.is_some() {
+                return;
+            }
+
+            if !flow.syn_acked {
+                if flow.snd_nxt == flow.iss {
+                    segments.push((flow.iss, TCP_SYN | TCP_ACK, Vec::new()));
+                    flow.snd_nxt = flow.iss.wrapping_add(1);
+                }
+            } else {
+                let window_end = flow.snd_una.wrapping_add(flow.snd_wnd);
+                loop {
+                    let offset = flow.snd_nxt.wrapping_sub(flow.snd_una) as usize;
+                    let room = seq_diff(window_end, flow.snd_nxt);
+                    if offset >= flow.unacked.len() || room <= 0 {
+                        break;
+                    }
+
+                    let len = *[TCP_MSS, flow.unacked.len() - offset, room as usize].iter().min().unwrap();
+                    segments.push((flow.snd_nxt, TCP_ACK | TCP_PSH, flow.unacked[offset..offset + len].to_vec()));
+                    flow.snd_nxt = flow.snd_nxt.wrapping_add(len as u32);
+                }
+
+                if flow.host_eof && !flow.fin_acked && flow.snd_nxt == flow.data_end() {
+                    segments.push((flow.snd_nxt, TCP_ACK | TCP_FIN, Vec::new()));
+                    flow.snd_nxt = flow.snd_nxt.wrapping_add(1);
+                }
+            }
+
+            if !segments.is_empty() {
+                flow.last_send = Instant::now();
+            }
+        }
+
+        let (rcv_nxt, window) = {
+            let flow = &self.tcp[key];
+            (flow.rcv_nxt, flow.window())
+        };
+        for (seq, flags, data) in segments {
+            self.send_tcp(key, seq, rcv_nxt, flags, window, &data);
+        }
+    }
+
+    /*
+     * Move TCP flows along: finish connects, read host data, retransmit and drop closed flows
+     */
+    fn poll_tcp(&mut self) {
+        let keys: Vec<FlowKey> = self.tcp.keys().cloned().collect();
+        for key in keys {
+            let mut reset = false;
+            {
+                let flow = self.tcp.get_mut(&key).unwrap();
+
+                let connected = match flow.connect {
+                    Some(ref rx) => match rx.try_recv() {
+                        Ok(res) => Some(res),
+                        Err(TryRecvError::Empty) => None,
+                        Err(TryRecvError::Disconnected) => Some(Err(io::Error::new(io::ErrorKind::Other, "connect thread died"))),
+                    },
+                    None => None,
+                };
+
+                match connected {
+                    Some(Ok(stream)) => {
+                        stream.set_nonblocking(true).ok();
+                        flow.stream = Some(stream);
+                        flow.connect = None;
+                        debug!("slirp: {} connected", key.name("tcp"));
+                    },
+                    Some(Err(err)) => {
+                        debug!("slirp: {} connect failed: {}", key.name("tcp"), err);
+                        reset = true;
+                    },
+                    None => {},
+                }
+
+                if flow.stream.is_some() && flow.syn_acked && !flow.host_eof {
+                    let mut buf = [0u8; 4096];
+                    while flow.unacked.len() < TCP_SEND_BUFFER {
+                        match flow.stream.as_mut().unwrap().read(&mut buf) {
+                            Ok(0) => {
+                                flow.host_eof = true;
+                                debug!("slirp: {} closed by host", key.name("tcp"));
+                                break;
+                            },
+                            Ok(len) => flow.unacked.extend_from_slice(&buf[..len]),
+                            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(err) => {
+                                debug!("slirp: {} host read failed: {}", key.name("tcp"), err);
+                                reset = true;
+                                break;
+                            }
+                        }
+                    }
+                }
+
+                /* Go back to oldest unacknowledged byte if guest has been quiet for too long */
+                let unacked = flow.snd_nxt != flow.snd_una && !flow.fin_acked;
+                if unacked && flow.last_send.elapsed() >= Duration::from_millis(TCP_RTO_MS) {
+                    flow.snd_nxt = if flow.syn_acked { flow.snd_una } else { flow.iss };
+                }
+            }
+
+            if reset {
+                let (seq, ack) = {
+                    let flow = &self.tcp[&key];
+                    (flow.snd_nxt, flow.rcv_nxt)
+                };
+                self.send_tcp(&key, seq, ack, TCP_RST | TCP_ACK, 0, &[]);
+                self.tcp.remove(&key);
+                continue;
+            }
+
+            self.tcp_flush_to_host(&key);
+            self.tcp_output(&key);
+
+            if self.tcp[&key].is_finished() {
+                debug!("slirp: {} done", key.name("tcp"));
+                self.tcp.remove(&key);
+            }
+        }
+    }
+
+    fn poll_udp(&mut self) {
+        let keys: Vec<FlowKey> = self.udp.keys().cloned().collect();
+        for key in keys {
+            let mut replies = Vec::new();
+            let expired = {
+                let flow = self.udp.get_mut(&key).unwrap();
+                let mut buf = [0u8; 65536];
+                loop {
+                    match flow.socket.recv(&mut buf) {
+                        Ok(len) => replies.push(buf[..len].to_vec()),
+                        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(err) => {
+                            debug!("slirp: {} receive failed: {}", key.name("udp"), err);
+                            break;
+                        }
+                    }
+                }
+
+                if !replies.is_empty() {
+                    flow.last_used = Instant::now();
+                }
+                flow.last_used.elapsed() >= Duration::from_secs(UDP_IDLE_SECS)
+            };
+
+            for data in replies {
+                self.send_udp(key.addr, key.port, SLIRP_GUEST_IP, key.guest_port, &data);
+            }
+
+            if expired {
+                debug!("slirp: {} expired", key.name("udp"));
+                self.udp.remove(&key);
+            }
+        }
+    }
+}
+
+impl net_backend for SlirpBackend
+{
+    fn send(&mut self, frame: &[u8]) {
+        if frame.len() < ETH_HLEN {
+            return;
+        }
+
+        if frame[0..ETH_ALEN] != SLIRP_GATEWAY_MAC && frame[0..ETH_ALEN] != ETH_BROADCAST {
+            return;
+        }
+
+        self.guest_mac.copy_from_slice(&frame[ETH_ALEN..ETH_ALEN * 2]);
+
+        match get_u16(frame, 12) {
+            ETH_P_ARP => self.handle_arp(&frame[ETH_HLEN..]),
+            ETH_P_IP => self.handle_ip(&frame[ETH_HLEN..]),
+            _ => {},
+        }
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        if self.out.is_empty() {
+            self.poll_tcp();
+            self.poll_udp();
+        }
+
+        self.out.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod slirp_test
+{
+    use super::*;
+    use std::net::TcpListener;
+
+    const GUEST_MAC: [u8; ETH_ALEN] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    fn eth(ethertype: u16, dst: [u8; ETH_ALEN], payload: &[u8]) -> Vec<u8> {
+        let mut frame = dst.to_vec();
+        frame.extend_from_slice(&GUEST_MAC);
+        put_u16(&mut frame, ethertype);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn ip(src: [u8; 4], dst: [u8; 4], proto: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0];
+        put_u16(&mut packet, (IP_HLEN + payload.len()) as u16);
+        packet.extend_from_slice(&[0, 1, 0x40, 0, 64, proto, 0, 0]);
+        packet.extend_from_slice(&src);
+        packet.extend_from_slice(&dst);
+        let csum = checksum(&packet);
+        packet[10] = (csum >> 8) as u8;
+        packet[11] = csum as u8;
+        packet.extend_from_slice(payload);
+        eth(ETH_P_IP, SLIRP_GATEWAY_MAC, &packet)
+    }
+
+    fn udp(src: [u8; 4], src_port: u16, dst: [u8; 4], dst_port: u16, data: &[u8]) -> Vec<u8> {
+        let mut dgram = Vec::new();
+        put_u16(&mut dgram, src_port);
+        put_u16(&mut dgram, dst_port);
+        put_u16(&mut dgram, (UDP_HLEN + data.len()) as u16);
+        put_u16(&mut dgram, 0);
+        dgram.extend_from_slice(data);
+        ip(src, dst, IPPROTO_UDP, &dgram)
+    }
+
+    fn tcp(port: u16, dst_port: u16, seq: u32, ack: u32, flags: u8, data: &[u8]) -> Vec<u8> {
+        let mut seg = Vec::new();
+        put_u16(&mut seg, port);
+        put_u16(&mut seg, dst_port);
+        put_u32(&mut seg, seq);
+        put_u32(&mut seg, ack);
+        seg.extend_from_slice(&[0x50, flags]);
+        put_u16(&mut seg, 8192);
+        put_u32(&mut seg, 0);
+        seg.extend_from_slice(data);
+        let csum = transport_checksum(SLIRP_GUEST_IP, SLIRP_GATEWAY_IP, IPPROTO_TCP, &seg);
+        seg[16] = (csum >> 8) as u8;
+        seg[17] = csum as u8;
+        ip(SLIRP_GUEST_IP, SLIRP_GATEWAY_IP, IPPROTO_TCP, &seg)
+    }
+
+    /* Next frame for guest, checks IP framing and returns IP payload */
+    fn recv_ip(slirp: &mut SlirpBackend, proto: u8) -> Vec<u8> {
+        let start = Instant::now();
+        loop {
+            match slirp.receive() {
+                Some(frame) => {
+                    assert!(frame[6..12] == SLIRP_GATEWAY_MAC);
+                    assert!(get_u16(&frame, 12) == ETH_P_IP);
+                    let packet = &frame[ETH_HLEN..];
+                    assert!(checksum(&packet[..IP_HLEN]) == 0);
+                    assert!(packet[9] == proto);
+                    return packet[IP_HLEN..].to_vec();
+                },
+                None => {
+                    assert!(start.elapsed() < Duration::from_secs(5));
+                    thread::sleep(Duration::from_millis(1));
+                },
+            }
+        }
+    }
+
+    #[test] fn arp_gateway() {
+        let mut slirp = SlirpBackend::new();
+
+        let mut arp = Vec::new();
+        put_u16(&mut arp, ARPHRD_ETHER);
+        put_u16(&mut arp, ETH_P_IP);
+        arp.extend_from_slice(&[6, 4]);
+        put_u16(&mut arp, ARPOP_REQUEST);
+        arp.extend_from_slice(&GUEST_MAC);
+        arp.extend_from_slice(&SLIRP_GUEST_IP);
+        arp.extend_from_slice(&[0; 6]);
+        arp.extend_from_slice(&SLIRP_GATEWAY_IP);
+        slirp.send(&eth(ETH_P_ARP, ETH_BROADCAST, &arp));
+
+        let reply = slirp.receive().unwrap();
+        assert!(reply[0..6] == GUEST_MAC && get_u16(&reply, 12) == ETH_P_ARP);
+        assert!(get_u16(&reply, ETH_HLEN + 6) == ARPOP_REPLY);
+        assert!(reply[ETH_HLEN + 8..ETH_HLEN + 14] == SLIRP_GATEWAY_MAC);
+        assert!(reply[ETH_HLEN + 14..ETH_HLEN + 18] == SLIRP_GATEWAY_IP);
+        assert!(reply[ETH_HLEN + 24..ETH_HLEN + 28] == SLIRP_GUEST_IP);
+
+        /* Nobody else is home */
+        let last = arp.len() - 1;
+        arp[last] = 3;
+        slirp.send(&eth(ETH_P_ARP, ETH_BROADCAST, &arp));
+        assert!(slirp.receive().is_none());
+    }
+
+    fn dhcp_exchange(slirp: &mut SlirpBackend, msg_type: u8) -> Vec<u8> {
+        let mut msg = vec![0u8; BOOTP_LEN];
+        msg[0] = BOOTP_REQUEST;
+        msg[1] = 1;
+        msg[2] = 6;
+        msg[4..8].copy_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+        msg[28..34].copy_from_slice(&GUEST_MAC);
+        msg.extend_from_slice(&DHCP_MAGIC);
+        msg.extend_from_slice(&[DHCP_OPT_PAD, DHCP_OPT_MSG_TYPE, 1, msg_type, DHCP_OPT_END]);
+        slirp.send(&udp([0; 4], BOOTP_CLIENT_PORT, IP_BROADCAST, BOOTP_SERVER_PORT, &msg));
+
+        let dgram = recv_ip(slirp, IPPROTO_UDP);
+        assert!(get_u16(&dgram, 0) == BOOTP_SERVER_PORT && get_u16(&dgram, 2) == BOOTP_CLIENT_PORT);
+        assert!(transport_checksum(SLIRP_GATEWAY_IP, IP_BROADCAST, IPPROTO_UDP, &dgram) == 0);
+
+        let reply = dgram[UDP_HLEN..].to_vec();
+        assert!(reply[0] == BOOTP_REPLY && reply[4..8] == [0xDE, 0xAD, 0xBE, 0xEF]);
+        assert!(reply[16..20] == SLIRP_GUEST_IP && reply[28..34] == GUEST_MAC);
+        assert!(reply[BOOTP_LEN..BOOTP_LEN + 4] == DHCP_MAGIC);
+        reply[BOOTP_LEN + 4..].to_vec()
+    }
+
+    #[test] fn dhcp_lease() {
+        let mut slirp = SlirpBackend::new();
+
+        let options = dhcp_exchange(&mut slirp, DHCPDISCOVER);
+        assert!(options[..3] == [DHCP_OPT_MSG_TYPE, 1, DHCPOFFER]);
+        assert!(options.windows(6).any(|opt| opt == [DHCP_OPT_ROUTER, 4, 10, 0, 2, 2]));
+        assert!(options.windows(6).any(|opt| opt == [DHCP_OPT_NETMASK, 4, 255, 255, 255, 0]));
+
+        let options = dhcp_exchange(&mut slirp, DHCPREQUEST);
+        assert!(options[..3] == [DHCP_OPT_MSG_TYPE, 1, DHCPACK]);
+        assert!(*options.last().unwrap() == DHCP_OPT_END);
+    }
+
+    #[test] fn ping_gateway() {
+        let mut slirp = SlirpBackend::new();
+
+        let mut echo = vec![ICMP_ECHO_REQUEST, 0, 0, 0, 0x12, 0x34, 0x00, 0x01];
+        echo.extend_from_slice(b"ping");
+        let csum = checksum(&echo);
+        echo[2] = (csum >> 8) as u8;
+        echo[3] = csum as u8;
+        slirp.send(&ip(SLIRP_GUEST_IP, SLIRP_GATEWAY_IP, IPPROTO_ICMP, &echo));
+
+        let reply = recv_ip(&mut slirp, IPPROTO_ICMP);
+        assert!(reply[0] == ICMP_ECHO_REPLY && checksum(&reply) == 0);
+        assert!(reply[4..] == echo[4..]);
+    }
+
+    #[test] fn udp_nat() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut slirp = SlirpBackend::new();
+
+        slirp.send(&udp(SLIRP_GUEST_IP, 5000, SLIRP_GATEWAY_IP, port, b"query"));
+        let mut buf = [0u8; 16];
+        let (len, from) = server.recv_from(&mut buf).unwrap();
+        assert!(&buf[..len] == b"query");
+        server.send_to(b"answer", from).unwrap();
+
+        let dgram = recv_ip(&mut slirp, IPPROTO_UDP);
+        assert!(get_u16(&dgram, 0) == port && get_u16(&dgram, 2) == 5000);
+        assert!(transport_checksum(SLIRP_GATEWAY_IP, SLIRP_GUEST_IP, IPPROTO_UDP, &dgram) == 0);
+        assert!(&dgram[UDP_HLEN..] == b"answer");
+    }
+
+    /*
+     * Scripted guest connects to host listener through the gateway, sends a request, reads the
+     * answer and both sides close
+     */
+    #[test] fn tcp_nat() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut slirp = SlirpBackend::new();
+        let guest_port = 40000;
+        let mut seq = 1000;
+
+        slirp.send(&tcp(guest_port, port, seq, 0, TCP_SYN, &[]));
+        let (mut conn, _) = listener.accept().unwrap();
+
+        let synack = recv_ip(&mut slirp, IPPROTO_TCP);
+        assert!(transport_checksum(SLIRP_GATEWAY_IP, SLIRP_GUEST_IP, IPPROTO_TCP, &synack) == 0);
+        assert!(get_u16(&synack, 0) == port && get_u16(&synack, 2) == guest_port);
+        assert!(synack[13] == TCP_SYN | TCP_ACK);
+        assert!(get_u32(&synack, 8) == seq + 1);
+        let mut ack = get_u32(&synack, 4).wrapping_add(1);
+        seq += 1;
+
+        slirp.send(&tcp(guest_port, port, seq, ack, TCP_ACK | TCP_PSH, b"GET /"));
+        seq += 5;
+        let seg = recv_ip(&mut slirp, IPPROTO_TCP);
+        assert!(seg[13] == TCP_ACK && get_u32(&seg, 8) == seq);
+
+        let mut buf = [0u8; 5];
+        conn.read_exact(&mut buf).unwrap();
+        assert!(&buf == b"GET /");
+
+        conn.write_all(b"hello").unwrap();
+        drop(conn);
+
+        let seg = recv_ip(&mut slirp, IPPROTO_TCP);
+        assert!(get_u32(&seg, 4) == ack && &seg[TCP_HLEN..] == b"hello");
+        let seg = recv_ip(&mut slirp, IPPROTO_TCP);
+        assert!(seg[13] & TCP_FIN != 0 && get_u32(&seg, 4) == ack + 5);
+
+        /* Guest lost both, NAT goes back to oldest unacknowledged byte */
+        let seg = recv_ip(&mut slirp, IPPROTO_TCP);
+        assert!(get_u32(&seg, 4) == ack && &seg[TCP_HLEN..] == b"hello");
+        let seg = recv_ip(&mut slirp, IPPROTO_TCP);
+        assert!(seg[13] & TCP_FIN != 0);
+        ack += 5;
+
+        slirp.send(&tcp(guest_port, port, seq, ack.wrapping_add(1), TCP_ACK | TCP_FIN, &[]));
+        let seg = recv_ip(&mut slirp, IPPROTO_TCP);
+        assert!(seg[13] & TCP_ACK != 0 && get_u32(&seg, 8) == seq + 1);
+
+        /* Flow is gone once both ends closed */
+        assert!(slirp.receive().is_none());
+        assert!(slirp.tcp.is_empty());
+
+        /* Segment on unknown flow is reset */
+        slirp.send(&tcp(guest_port, port, seq + 1, ack, TCP_ACK, b"late"));
+        let seg = recv_ip(&mut slirp, IPPROTO_TCP);
+        assert!(seg[13] & TCP_RST != 0);
+    }
+}
@@ -0,0 +1,367 @@
+/*
+ * SMBIOS (DMI) tables
+ *
+ * 32-bit entry point and a minimal structure table describing the VM: BIOS, system, one processor
+ * and a single memory device covering conventional RAM. Guests find the entry point by scanning
+ * 0xF0000-0xFFFFF on 16 byte boundaries for "_SM_" anchor.
+ */
+
+use vm;
+use config;
+
+// Entry point and table placement, entry point has to be within BIOS segment
+const SMBIOS_BASE: u64          = 0xF0000;
+const SMBIOS_ENTRY_SIZE: usize  = 0x1F;
+const SMBIOS_TABLE_OFFSET: usize = 0x20;
+
+const SMBIOS_MAJOR: u8          = 2;
+const SMBIOS_MINOR: u8          = 4;
+
+// Structure types
+const SMBIOS_TYPE_BIOS: u8      = 0;
+const SMBIOS_TYPE_SYSTEM: u8    = 1;
+const SMBIOS_TYPE_PROCESSOR: u8 = 4;
+const SMBIOS_TYPE_MEM_ARRAY: u8 = 16;
+const SMBIOS_TYPE_MEM_DEVICE: u8 = 17;
+const SMBIOS_TYPE_MEM_MAPPED: u8 = 19;
+const SMBIOS_TYPE_END: u8       = 127;
+
+const SMBIOS_HANDLE_NONE: u16   = 0xFFFE;
+const SMBIOS_HANDLE_MEM_ARRAY: u16 = 0x1000;
+
+const SMBIOS_VENDOR: &'static str = "xvm16";
+
+/**
+ * VM properties reflected in SMBIOS tables
+ */
+pub struct SmbiosInfo
+{
+    pub uuid: [u8; 16],     // System UUID in canonical (big endian) byte order, zero if not set
+    pub ram_kb: u32,        // Conventional RAM size
+}
+
+/*
+ * Structure with formatted area followed by string set.
+ * String references in formatted area are 1-based indices into string set, 0 is no string.
+ */
+struct Structure
+{
+    data: Vec<u8>,
+    strings: Vec<String>,
+}
+
+impl Structure
+{
+    fn new(kind: u8, len: u8, handle: u16) -> Structure {
+        let mut data = vec![0u8; len as usize];
+        data[0] = kind;
+        data[1] = len;
+        data[2] = handle as u8;
+        data[3] = (handle >> 8) as u8;
+        Structure {
+            data: data,
+            strings: Vec::new(),
+        }
+    }
+
+    fn set_byte(&mut self, offset: usize, val: u8) {
+        self.data[offset] = val;
+    }
+
+    fn set_word(&mut self, offset: usize, val: u16) {
+        self.set_byte(offset, val as u8);
+        self.set_byte(offset + 1, (val >> 8) as u8);
+    }
+
+    fn set_dword(&mut self, offset: usize, val: u32) {
+        self.set_word(offset, val as u16);
+        self.set_word(offset + 2, (val >> 16) as u16);
+    }
+
+    /* Add string and store its index in formatted area */
+    fn set_string(&mut self, offset: usize, s: &str) {
+        self.strings.push(String::from(s));
+        let index = self.strings.len() as u8;
+        self.set_byte(offset, index);
+    }
+
+    /* String set ends with an extra null, empty set is two nulls */
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.data);
+        if self.strings.is_empty() {
+            out.push(0);
+        }
+        for s in &self.strings {
+            out.extend_from_slice(s.as_bytes());
+            out.push(0);
+        }
+        out.push(0);
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)).wrapping_neg()
+}
+
+/* SMBIOS stores first three UUID fields little endian */
+fn encode_uuid(uuid: &[u8; 16]) -> [u8; 16] {
+    let mut res = *uuid;
+    res[0..4].reverse();
+    res[4..6].reverse();
+    res[6..8].reverse();
+    res
+}
+
+fn structures(info: &SmbiosInfo) -> Vec<Structure> {
+    let mut res = Vec::new();
+
+    let mut bios = Structure::new(SMBIOS_TYPE_BIOS, 0x18, 0x0000);
+    bios.set_string(0x04, SMBIOS_VENDOR);
+    bios.set_string(0x05, SMBIOS_VENDOR);
+    bios.set_word(0x06, 0xE800);            // Starting segment
+    bios.set_string(0x08, "01/01/2017");
+    bios.set_byte(0x09, 0);                 // 64K ROM
+    bios.set_dword(0x0A, 0x08);             // Characteristics not supported
+    bios.set_byte(0x14, 0xFF);              // No system BIOS release
+    bios.set_byte(0x15, 0xFF);
+    bios.set_byte(0x16, 0xFF);              // No embedded controller
+    bios.set_byte(0x17, 0xFF);
+    res.push(bios);
+
+    let mut system = Structure::new(SMBIOS_TYPE_SYSTEM, 0x1B, 0x0100);
+    system.set_string(0x04, SMBIOS_VENDOR);
+    system.set_string(0x05, "xvm16 virtual machine");
+    system.set_string(0x06, env!("CARGO_PKG_VERSION"));
+    system.data[0x08..0x18].copy_from_slice(&encode_uuid(&info.uuid));
+    system.set_byte(0x18, 0x06);            // Woken up by power switch
+    system.set_string(0x1A, SMBIOS_VENDOR);
+    res.push(system);
+
+    let mut cpu = Structure::new(SMBIOS_TYPE_PROCESSOR, 0x1A, 0x0400);
+    cpu.set_string(0x04, "CPU 0");
+    cpu.set_byte(0x05, 0x03);               // Central processor
+    cpu.set_byte(0x06, 0x02);               // Unknown family
+    cpu.set_string(0x07, SMBIOS_VENDOR);
+    cpu.set_byte(0x18, 0x41);               // Populated, enabled
+    cpu.set_byte(0x19, 0x01);               // Other upgrade
+    res.push(cpu);
+
+    let mut array = Structure::new(SMBIOS_TYPE_MEM_ARRAY, 0x0F, SMBIOS_HANDLE_MEM_ARRAY);
+    array.set_byte(0x04, 0x03);             // System board
+    array.set_byte(0x05, 0x03);             // System memory
+    array.set_byte(0x06, 0x03);             // No error correction
+    array.set_dword(0x07, info.ram_kb);
+    array.set_word(0x0B, SMBIOS_HANDLE_NONE);
+    array.set_word(0x0D, 1);
+    res.push(array);
+
+    /* Device size is in KB when bit 15 is set */
+    let size = if info.ram_kb < 0x8000 { 0x8000 | info.ram_kb as u16 } else { (info.ram_kb >> 10) as u16 };
+    let mut device = Structure::new(SMBIOS_TYPE_MEM_DEVICE, 0x1B, 0x1100);
+    device.set_word(0x04, SMBIOS_HANDLE_MEM_ARRAY);
+    device.set_word(0x06, SMBIOS_HANDLE_NONE);
+    device.set_word(0x08, 0xFFFF);          // Unknown widths
+    device.set_word(0x0A, 0xFFFF);
+    device.set_word(0x0C, size);
+    device.set_byte(0x0E, 0x09);            // DIMM
+    device.set_string(0x10, "DIMM 0");
+    device.set_byte(0x12, 0x07);            // RAM
+    device.set_word(0x13, 0x0002);          // Unknown type detail
+    res.push(device);
+
+    let mut mapped = Structure::new(SMBIOS_TYPE_MEM_MAPPED, 0x0F, 0x1300);
+    mapped.set_dword(0x04, 0);
+    mapped.set_dword(0x08, info.ram_kb - 1);
+    mapped.set_word(0x0C, SMBIOS_HANDLE_MEM_ARRAY);
+    mapped.set_byte(0x0E, 1);
+    res.push(mapped);
+
+    res.push(Structure::new(SMBIOS_TYPE_END, 0x04, 0x7F00));
+    res
+}
+
+/**
+ * Build entry point immediately followed by structure table for given guest physical address
+ */
+pub fn build(info: &SmbiosInfo, base: u64) -> Vec<u8>
+{
+    let structs = structures(info);
+    let mut table = Vec::new();
+    let mut max_size = 0;
+    for s in &structs {
+        let start = table.len();
+        s.encode(&mut table);
+        max_size = ::std::cmp::max(max_size, table.len() - start);
+    }
+
+    let table_addr = base + SMBIOS_TABLE_OFFSET as u64;
+    assert!(table_addr + (table.len() as u64) < 0x100000000);
+
+    let mut entry = vec![0u8; SMBIOS_ENTRY_SIZE];
+    entry[0x00..0x04].copy_from_slice(b"_SM_");
+    entry[0x05] = SMBIOS_ENTRY_SIZE as u8;
+    entry[0x06] = SMBIOS_MAJOR;
+    entry[0x07] = SMBIOS_MINOR;
+    entry[0x08] = max_size as u8;
+    entry[0x09] = (max_size >> 8) as u8;
+    entry[0x10..0x15].copy_from_slice(b"_DMI_");
+    entry[0x16] = table.len() as u8;
+    entry[0x17] = (table.len() >> 8) as u8;
+    for i in 0..4 {
+        entry[0x18 + i] = (table_addr >> (i * 8)) as u8;
+    }
+    entry[0x1C] = structs.len() as u8;
+    entry[0x1D] = (structs.len() >> 8) as u8;
+    entry[0x1E] = (SMBIOS_MAJOR << 4) | SMBIOS_MINOR;
+
+    /* Intermediate checksum covers DMI part, then entry checksum covers the whole thing */
+    entry[0x15] = checksum(&entry[0x10..]);
+    entry[0x04] = checksum(&entry);
+
+    let mut res = entry;
+    res.resize(SMBIOS_TABLE_OFFSET, 0);
+    res.extend_from_slice(&table);
+    res
+}
+
+/* Copy tables to memory region mapped at region_base */
+fn install(region: &vm::memory_region, region_base: u64, info: &SmbiosInfo)
+{
+    let blob = build(info, SMBIOS_BASE);
+    let offset = (SMBIOS_BASE - region_base) as usize;
+    if region.write_bytes(offset, &blob) != blob.len() {
+        panic!("smbios: tables don't fit memory at {:x}", SMBIOS_BASE);
+    }
+}
+
+#[cfg(test)]
+mod smbios_test
+{
+    use super::*;
+
+    fn word(mem: &[u8], offset: usize) -> usize {
+        mem[offset] as usize | (mem[offset + 1] as usize) << 8
+    }
+
+    fn dword(mem: &[u8], offset: usize) -> usize {
+        word(mem, offset) | word(mem, offset + 2) << 16
+    }
+
+    fn sum(data: &[u8]) -> u8 {
+        data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+    }
+
+    /* Get string n of structure starting at offset, and offset of next structure */
+    fn string(mem: &[u8], offset: usize, n: u8) -> String {
+        let mut pos = offset + mem[offset + 1] as usize;
+        for _ in 1..n {
+            while mem[pos] != 0 {
+                pos += 1;
+            }
+            pos += 1;
+        }
+        let end = pos + mem[pos..].iter().position(|b| *b == 0).unwrap();
+        String::from_utf8(mem[pos..end].to_vec()).unwrap()
+    }
+
+    fn next_structure(mem: &[u8], offset: usize) -> usize {
+        let mut pos = offset + mem[offset + 1] as usize;
+        while mem[pos] != 0 || mem[pos + 1] != 0 {
+            pos += 1;
+        }
+        pos + 2
+    }
+
+    #[test] fn guest_scan() {
+        let mem_size = 0x100000;
+        let region = vm::alloc_memory_region(mem_size);
+        region.write_bytes(0, &vec![0xCCu8; mem_size]);
+
+        let uuid = [0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0, 0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF];
+        install(&region, 0, &SmbiosInfo { uuid: uuid, ram_kb: 640 });
+
+        let mut mem = vec![0u8; mem_size];
+        region.read_bytes(0, &mut mem);
+
+        /* Scan BIOS segment for anchor with valid checksum as DMI decoders do */
+        let entry = (0xF000..0x10000).map(|para| para * 16).find(|addr| {
+            &mem[*addr..*addr + 4] == b"_SM_" && sum(&mem[*addr..*addr + mem[*addr + 5] as usize]) == 0
+        }).unwrap();
+        assert!(mem[entry + 5] == 0x1F);
+        assert!(mem[entry + 6] == 2 && mem[entry + 7] == 4);
+        assert!(&mem[entry + 0x10..entry + 0x15] == b"_DMI_");
+        assert!(sum(&mem[entry + 0x10..entry + 0x1F]) == 0);
+
+        let table = dword(&mem, entry + 0x18);
+        let table_len = word(&mem, entry + 0x16);
+        let count = word(&mem, entry + 0x1C);
+        assert!(count == 7);
+
+        /* Walk structures up to the end marker */
+        let mut pos = table;
+        let mut types = Vec::new();
+        let mut max_size = 0;
+        loop {
+            let next = next_structure(&mem, pos);
+            types.push(mem[pos]);
+            max_size = ::std::cmp::max(max_size, next - pos);
+
+            match mem[pos] {
+                SMBIOS_TYPE_SYSTEM => {
+                    assert!(string(&mem, pos, mem[pos + 4]) == "xvm16");
+                    assert!(string(&mem, pos, mem[pos + 5]) == "xvm16 virtual machine");
+                    assert!(mem[pos + 8..pos + 24] == [0x78, 0x56, 0x34, 0x12, 0xBC, 0x9A, 0xF0, 0xDE,
+                                                       0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF]);
+                },
+                SMBIOS_TYPE_MEM_DEVICE => assert!(word(&mem, pos + 0x0C) == 0x8000 | 640),
+                SMBIOS_TYPE_MEM_MAPPED => assert!(dword(&mem, pos + 0x08) == 639),
+                _ => {},
+            }
+
+            pos = next;
+            if types[types.len() - 1] == SMBIOS_TYPE_END {
+                break;
+            }
+        }
+
+        assert!(types == vec![0, 1, 4, 16, 17, 19, 127]);
+        assert!(pos - table == table_len);
+        assert!(max_size == word(&mem, entry + 0x08));
+
+        /* End marker has no strings and ends with double null */
+        assert!(mem[pos - 6..pos] == [127, 4, 0x00, 0x7F, 0, 0]);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/**
+ * Place SMBIOS tables in guest memory if enabled in config.
+ * Needs guest RAM layout to be set up. Firmware owns the BIOS segment, so this is only done for test images.
+ */
+pub fn init(config: &config::VmConfig)
+{
+    if !config.smbios {
+        return;
+    }
+
+    if config.has_bios() {
+        warn!("smbios: BIOS segment belongs to firmware, not placing SMBIOS tables");
+        return;
+    }
+
+    let ram_kb = match vm::find_memory_mapping(0) {
+        Some(mapping) => (mapping.region.size >> 10) as u32,
+        None => panic!("smbios: no guest RAM"),
+    };
+
+    let mapping = match vm::find_memory_mapping(SMBIOS_BASE) {
+        Some(mapping) => mapping,
+        None => panic!("smbios: nothing mapped at {:x}", SMBIOS_BASE),
+    };
+
+    install(&mapping.region, mapping.base, &SmbiosInfo {
+        uuid: config.uuid.unwrap_or([0; 16]),
+        ram_kb: ram_kb,
+    });
+}
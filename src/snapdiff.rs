@@ -0,0 +1,380 @@
+/*
+ * Snapshot diffs
+ *
+ * diff() tells what differs between two snapshots: device state fields, RAM pages and vcpu registers, for tests
+ * checking an operation changed what it should and nothing else. Monitor "snapdiff" shows the same as text for
+ * snapshot files, or for a snapshot file and the VM as it is.
+ *
+ * Device fields are given by their path in the device state, "master.imr", with values as JSON the way "info
+ * device" shows them. RAM pages are given by guest physical page number and offset of the first byte that
+ * differs in them, registers by their names in snapshot vcpu sections, "rip" or "cs.base".
+ *
+ * Things that change all the time, like tick counters, are left out by an ignore list: a device name leaves out
+ * the whole device, a device name and field path the field and the ones under it, "vcpu" and a register name
+ * the register. Host side annotations and guest time aren't compared.
+ */
+
+use vm;
+use monitor;
+use snapshot;
+use devstate::{DeviceState, StateValue};
+use coredump::vcpu_text;
+
+use std::fmt;
+use std::path::Path;
+
+/**
+ * Field of device state that differs, values are JSON and None where the field isn't there
+ * Empty path is the whole device, which one snapshot doesn't have.
+ */
+#[derive(Clone, PartialEq, Debug)]
+pub struct FieldDiff
+{
+    pub path: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+/**
+ * Device with fields that differ
+ */
+#[derive(Clone, PartialEq, Debug)]
+pub struct DeviceDiff
+{
+    pub name: String,
+    pub fields: Vec<FieldDiff>,
+}
+
+/**
+ * RAM page that differs, by guest physical page number, with offset of the first byte that does
+ */
+#[derive(Clone, PartialEq, Debug)]
+pub struct PageDiff
+{
+    pub page: u64,
+    pub offset: usize,
+}
+
+/**
+ * Vcpu register that differs
+ */
+#[derive(Clone, PartialEq, Debug)]
+pub struct RegisterDiff
+{
+    pub name: String,
+    pub before: u64,
+    pub after: u64,
+}
+
+/**
+ * What differs between two snapshots, in the order they have devices, pages and registers
+ */
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Diff
+{
+    pub devices: Vec<DeviceDiff>,
+    pub pages: Vec<PageDiff>,
+    pub registers: Vec<RegisterDiff>,
+}
+
+impl Diff
+{
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty() && self.pages.is_empty() && self.registers.is_empty()
+    }
+}
+
+impl fmt::Display for Diff
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "Snapshots don't differ");
+        }
+
+        let value = |val: &Option<String>| val.clone().unwrap_or(String::from("none"));
+        let mut lines = Vec::new();
+        for dev in &self.devices {
+            for field in &dev.fields {
+                let name = if field.path.is_empty() { dev.name.clone() } else { format!("{} {}", dev.name, field.path) };
+                lines.push(format!("device {}: {} -> {}", name, value(&field.before), value(&field.after)));
+            }
+        }
+        for page in &self.pages {
+            lines.push(format!("page 0x{:x}: differs from offset 0x{:x}", page.page, page.offset));
+        }
+        for reg in &self.registers {
+            lines.push(format!("register {}: 0x{:x} -> 0x{:x}", reg.name, reg.before, reg.after));
+        }
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+/* Whether path is on ignore list or under something that is */
+fn ignored(path: &str, ignore: &[&str]) -> bool
+{
+    ignore.iter().any(|&entry| path == entry || (path.starts_with(entry) && path[entry.len()..].starts_with('.')))
+}
+
+/* Fields of two states that differ, under path in device */
+fn diff_fields(before: &DeviceState, after: &DeviceState, path: &str, ignore: &[&str], out: &mut Vec<FieldDiff>)
+{
+    let full = |name: &str| if path.is_empty() { String::from(name) } else { format!("{}.{}", path, name) };
+
+    for &(ref name, ref val) in &before.fields {
+        let field = full(name);
+        if ignored(&field, ignore) {
+            continue;
+        }
+        match (val, after.get(name)) {
+            (&StateValue::Object(ref before), Some(&StateValue::Object(ref after))) => diff_fields(before, after, &field, ignore, out),
+            (val, Some(other)) if val == other => {},
+            (val, other) => out.push(FieldDiff {
+                path: field,
+                before: Some(val.to_json()),
+                after: other.map(|other| other.to_json()),
+            }),
+        }
+    }
+    for &(ref name, ref val) in after.fields.iter().filter(|&&(ref name, _)| before.get(name).is_none()) {
+        let field = full(name);
+        if !ignored(&field, ignore) {
+            out.push(FieldDiff { path: field, before: None, after: Some(val.to_json()) });
+        }
+    }
+}
+
+/* Registers of vcpu state by name as snapshots have them */
+fn registers(state: &vm::VcpuState) -> Vec<(String, u64)>
+{
+    vcpu_text(state).lines().filter_map(|line| {
+        let mut parts = line.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        parts.next().and_then(|val| u64::from_str_radix(val.trim_left_matches("0x"), 16).ok()).map(|val| (String::from(name), val))
+    }).collect()
+}
+
+/**
+ * What differs from snapshot before to snapshot after, but for paths on ignore list, see above
+ * Snapshots need the same memory layout.
+ */
+pub fn diff(before: &snapshot::Snapshot, after: &snapshot::Snapshot, ignore: &[&str]) -> Result<Diff, String>
+{
+    let layout = |snapshot: &snapshot::Snapshot| snapshot.memory.iter().map(|mem| (mem.base, mem.data.len())).collect::<Vec<(u64, usize)>>();
+    if layout(before) != layout(after) {
+        return Err(String::from("Snapshots have different memory layouts"));
+    }
+
+    let mut diff = Diff::default();
+
+    for dev in &before.devices {
+        if ignored(&dev.name, ignore) {
+            continue;
+        }
+        let mut fields = Vec::new();
+        match after.devices.iter().find(|other| other.name == dev.name) {
+            Some(other) => {
+                let prefixed: Vec<String> = ignore.iter().filter(|entry| entry.starts_with(&format!("{}.", dev.name)))
+                    .map(|entry| String::from(&entry[dev.name.len() + 1..])).collect();
+                let prefixed: Vec<&str> = prefixed.iter().map(|entry| entry.as_str()).collect();
+                diff_fields(&dev.state, &other.state, "", &prefixed, &mut fields);
+            },
+            None => fields.push(FieldDiff { path: String::new(), before: Some(dev.state.to_json()), after: None }),
+        }
+        if !fields.is_empty() {
+            diff.devices.push(DeviceDiff { name: dev.name.clone(), fields: fields });
+        }
+    }
+    for dev in after.devices.iter().filter(|dev| !before.devices.iter().any(|other| other.name == dev.name)) {
+        if !ignored(&dev.name, ignore) {
+            diff.devices.push(DeviceDiff {
+                name: dev.name.clone(),
+                fields: vec![FieldDiff { path: String::new(), before: None, after: Some(dev.state.to_json()) }],
+            });
+        }
+    }
+
+    for (mem, other) in before.memory.iter().zip(after.memory.iter()) {
+        let pages = mem.data.chunks(vm::DIRTY_PAGE_SIZE).zip(other.data.chunks(vm::DIRTY_PAGE_SIZE));
+        for (i, (page, other)) in pages.enumerate() {
+            if let Some(offset) = page.iter().zip(other.iter()).position(|(a, b)| a != b) {
+                diff.pages.push(PageDiff { page: mem.base / vm::DIRTY_PAGE_SIZE as u64 + i as u64, offset: offset });
+            }
+        }
+    }
+
+    for ((name, val), (_, other)) in registers(&before.vcpu_state).into_iter().zip(registers(&after.vcpu_state)) {
+        if val != other && !ignored(&format!("vcpu.{}", name), ignore) {
+            diff.registers.push(RegisterDiff { name: name, before: val, after: other });
+        }
+    }
+
+    Ok(diff)
+}
+
+/**
+ * Diff of snapshot files, each a base snapshot and the increments over it in the order they were saved
+ */
+pub fn diff_files(before: &[&Path], after: &[&Path], ignore: &[&str]) -> Result<Diff, String>
+{
+    let (before, _) = try!(snapshot::load_chain(before));
+    let (after, _) = try!(snapshot::load_chain(after));
+    diff(&before, &after, ignore)
+}
+
+#[cfg(test)]
+mod snapdiff_test
+{
+    use super::*;
+    use snapshot::{Snapshot, SnapshotMemory, SnapshotDevice};
+
+    fn pic(imr: u64) -> DeviceState {
+        let chip = |imr| DeviceState::new().hex("irr", 0, 2).hex("imr", imr, 2).bool("initialized", true);
+        DeviceState::new().object("master", chip(imr)).object("slave", chip(0xFF))
+    }
+
+    fn pit(ticks: u64) -> DeviceState {
+        DeviceState::new().number("ticks", ticks).text("mode", "rate generator")
+    }
+
+    /* Guest at 7C00h with 64K of RAM and ROM */
+    fn before() -> Snapshot {
+        Snapshot {
+            created: 1500000000,
+            memory: vec![
+                SnapshotMemory { base: 0, flags: 7, data: vec![0; 0x10000], pages: None },
+                SnapshotMemory { base: 0xF0000, flags: 5, data: vec![0xEA; 0x10000], pages: None },
+            ],
+            vcpu_state: vm::VcpuState { rax: 0x1234, rip: 0x7C00, ..Default::default() },
+            clock_ns: 5000000,
+            dilation: 1.0,
+            devices: vec![
+                SnapshotDevice { name: String::from("pic"), version: 2, state: pic(0xFB) },
+                SnapshotDevice { name: String::from("pit"), version: 1, state: pit(100) },
+            ],
+            parent: None,
+        }
+    }
+
+    /* Guest masked IRQ 2 on master and filled three pages, timer ticked meanwhile */
+    fn after() -> Snapshot {
+        let mut snapshot = before();
+        for &(addr, val) in &[(0x1000, 1), (0x2010, 2), (0xFFFF, 3)] {
+            snapshot.memory[0].data[addr] = val;
+        }
+        snapshot.vcpu_state.rip = 0x7C14;
+        snapshot.vcpu_state.ds.base = 0x100;
+        snapshot.clock_ns += 1000000;
+        snapshot.devices[0].state = pic(0xFF);
+        snapshot.devices[1].state = pit(160);
+        snapshot
+    }
+
+    #[test] fn guest_action() {
+        let changes = diff(&before(), &after(), &["pit"]).unwrap();
+        assert!(changes.devices == vec![DeviceDiff {
+            name: String::from("pic"),
+            fields: vec![FieldDiff { path: String::from("master.imr"), before: Some(String::from("\"0xfb\"")), after: Some(String::from("\"0xff\"")) }],
+        }]);
+        assert!(changes.pages == vec![PageDiff { page: 1, offset: 0 }, PageDiff { page: 2, offset: 0x10 }, PageDiff { page: 0xF, offset: 0xFFF }]);
+        assert!(changes.registers == vec![
+            RegisterDiff { name: String::from("rip"), before: 0x7C00, after: 0x7C14 },
+            RegisterDiff { name: String::from("ds.base"), before: 0, after: 0x100 },
+        ]);
+
+        assert!(changes.to_string() == "device pic master.imr: \"0xfb\" -> \"0xff\"\n\
+                                     page 0x1: differs from offset 0x0\n\
+                                     page 0x2: differs from offset 0x10\n\
+                                     page 0xf: differs from offset 0xfff\n\
+                                     register rip: 0x7c00 -> 0x7c14\n\
+                                     register ds.base: 0x0 -> 0x100");
+    }
+
+    #[test] fn ignore() {
+        /* Field paths and registers, the same snapshot twice */
+        let changes = diff(&before(), &after(), &["pit.ticks", "pic.master", "vcpu.rip", "vcpu.ds"]).unwrap();
+        assert!(changes.devices.is_empty());
+        assert!(changes.registers.is_empty() && changes.pages.len() == 3);
+
+        let changes = diff(&before(), &after(), &[]).unwrap();
+        assert!(changes.devices[1] == DeviceDiff {
+            name: String::from("pit"),
+            fields: vec![FieldDiff { path: String::from("ticks"), before: Some(String::from("100")), after: Some(String::from("160")) }],
+        });
+
+        /* Prefix of a name isn't a path above it */
+        let changes = diff(&before(), &after(), &["pi", "vcpu.ri"]).unwrap();
+        assert!(changes.devices.len() == 2 && changes.registers.len() == 2);
+
+        let same = diff(&before(), &before(), &[]).unwrap();
+        assert!(same.is_empty() && same.to_string() == "Snapshots don't differ");
+    }
+
+    #[test] fn fields_and_devices() {
+        let mut other = before();
+        other.devices[1].state = pit(100).number("latch", 5).annotate("backend", "host");
+        other.devices[0].state = DeviceState::new().object("master", DeviceState::new().hex("irr", 0, 2)).text("slave", "none");
+        other.devices.push(SnapshotDevice { name: String::from("uart"), version: 1, state: DeviceState::new().hex("lcr", 3, 2) });
+
+        let changes = diff(&before(), &other, &[]).unwrap();
+        let fields = |name: &str| changes.devices.iter().find(|dev| dev.name == name).unwrap().fields.clone();
+        let field = |path: &str, before: Option<&str>, after: Option<&str>| FieldDiff {
+            path: String::from(path),
+            before: before.map(String::from),
+            after: after.map(String::from),
+        };
+
+        assert!(fields("pic") == vec![
+            field("master.imr", Some("\"0xfb\""), None),
+            field("master.initialized", Some("true"), None),
+            field("slave", Some(&pic(0xFB).get("slave").unwrap().to_json()), Some("\"none\"")),
+        ]);
+        assert!(fields("pit") == vec![field("latch", None, Some("5"))]);
+        assert!(fields("uart") == vec![field("", None, Some("{\n  \"lcr\": \"0x03\"\n}"))]);
+        assert!(changes.to_string().starts_with("device pic master.imr: \"0xfb\" -> none\n"));
+        assert!(changes.to_string().contains("\ndevice uart: none -> {"));
+
+        let mut small = before();
+        small.memory.pop();
+        assert!(diff(&before(), &small, &[]).unwrap_err() == "Snapshots have different memory layouts");
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+fn cmd_snapdiff(_: &mut monitor::MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    let usage = "Usage: snapdiff [-ignore path,...] file[,increment...] [file[,increment...]]";
+    let (ignore, files): (Vec<&str>, &[&str]) = match args.first() {
+        Some(&"-ignore") if args.len() >= 2 => (args[1].split(',').collect(), &args[2..]),
+        _ => (Vec::new(), args),
+    };
+    let chain = |arg: &str| arg.split(',').map(|path| Path::new(path).to_path_buf()).collect::<Vec<_>>();
+
+    match files.len() {
+        1 => {
+            let before = chain(files[0]);
+            let (before, _) = try!(snapshot::load_chain(&before.iter().map(|path| path.as_path()).collect::<Vec<&Path>>()));
+            let now = try!(vm::current_snapshot().map_err(|err| format!("Can't take snapshot of VM: {}", err)));
+            diff(&before, &now, &ignore).map(|diff| diff.to_string())
+        },
+        2 => {
+            let (before, after) = (chain(files[0]), chain(files[1]));
+            diff_files(&before.iter().map(|path| path.as_path()).collect::<Vec<&Path>>(),
+                       &after.iter().map(|path| path.as_path()).collect::<Vec<&Path>>(), &ignore).map(|diff| diff.to_string())
+        },
+        _ => Err(String::from(usage)),
+    }
+}
+
+/**
+ * Add snapshot diff monitor command
+ */
+pub fn init()
+{
+    monitor::register_command(monitor::MonitorCommand {
+        name: "snapdiff",
+        args: "[-ignore path,...] file [file]",
+        help: "show what differs between snapshots, or from a snapshot to VM as it is",
+        handler: cmd_snapdiff,
+    });
+}
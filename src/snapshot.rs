@@ -0,0 +1,846 @@
+/*
+ * Snapshot file format
+ *
+ * A snapshot holds everything needed to bring a VM back to where it was saved: memory layout and contents,
+ * vcpu registers, virtual clock and the state of each device. vm::save_snapshot() writes one as guest runs,
+ * between exits, and monitor "savevm" asks for it. "savevm -live" saves one with guest stopped only for the
+ * memory it wrote last, see livesnap.rs, and --auto-snapshot saves them on a schedule, see autosnap.rs.
+ * vm::restore_snapshot() puts a VM started with --restore back there, provided it has the memory layout and
+ * devices the snapshot was saved with. Monitor "ramsave" and "ramload" take guest RAM alone as a flat file for
+ * tools that don't know this format, see vm::export_ram().
+ *
+ * File is a header and a section table followed by section data, numbers are little endian:
+ *
+ *   header     "XVMSNAP\0", format version u32, section count u32, creation time u64 in seconds since
+ *              Unix epoch, crate version as 16 bytes padded with zeros, checksum u32, zero u32       48 bytes
+ *   entry      kind u32, flags u32, base u64, data offset u64, data size u64, checksum u32, zero u32  40 bytes
+ *
+ * Header checksum covers the header up to it and the whole section table, section checksums cover section
+ * data. Checksums are CRC-32 as in zlib.
+ *
+ * Sections are
+ *
+ *   layout     memory mappings as base u64, size u64, mapping flags u32 and zero u32 each, ROM is mapped
+ *              without write access
+ *   memory     contents of a mapping from base as sparse data, see coredump.rs: pages of zeros are left out
+ *   vcpu       registers as "name=0x..." lines, see coredump.rs
+ *   clock      guest time u64 in nanoseconds and time dilation ratio as f64 bits u64
+ *   device     one per device: state version u32, name length u32, name and the state in binary encoding,
+ *              see devstate::BinaryCodec, format version 1 has the state as JSON
+ *   parent     content hash u64 of the snapshot an incremental one saves changes since, see content_hash()
+ *
+ * An incremental snapshot, vm::save_snapshot_incremental(), has a parent section and delta memory sections:
+ * sparse data holding the pages guest or devices wrote since its parent was saved, zeros or not. Everything
+ * else is saved in full. It is restored by restoring its parent first and the pages over it, so a chain is
+ * given as base snapshot and increments in the order they were saved, each one checked to follow the one
+ * before it.
+ *
+ * File is written next to its final path and renamed over it when complete, so a file under the snapshot
+ * name is always a whole one. Reading it back checks every checksum before anything is restored.
+ *
+ * Snapshots outlive the VMM that saved them. A change to the file layout bumps SNAPSHOT_VERSION and reading
+ * keeps taking the versions before it, converting them on the way in, or fails naming the version when that
+ * can't be done. Device states go the same way with their own versions, see vm::state_handler: restore takes
+ * a state saved at an older version through the device's migrations one version at a time. Files older
+ * versions saved are kept in test/snapshots and tests read them and restore their device states, so a change
+ * that breaks them has to come with a migration, or new fixtures and the reason the old ones can't be read.
+ */
+
+use vm;
+use monitor;
+use devstate::{self, state_codec};
+use livesnap;
+use coredump::{put_u32, put_u64, get_u32, get_u64, sparse_memory, sparse_pages, expand_sparse_memory, apply_sparse_memory,
+               vcpu_text, parse_vcpu_text};
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+const SNAPSHOT_MAGIC: &'static [u8; 8]  = b"XVMSNAP\0";
+pub const SNAPSHOT_VERSION: u32         = 2;
+const HEADER_SIZE: usize                = 48;
+const ENTRY_SIZE: usize                 = 40;
+const CRATE_VERSION_SIZE: usize         = 16;
+
+/* Sparse memory flag of section entry, every memory section has it */
+pub const SECTION_SPARSE: u32 = 0x1;
+
+/* Delta memory flag of section entry, pages are copied over memory of parent snapshot */
+pub const SECTION_DELTA: u32 = 0x2;
+
+/**
+ * Section kinds
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SectionKind
+{
+    Layout = 1,
+    Memory = 2,
+    Vcpu = 3,
+    Clock = 4,
+    Device = 5,
+    Parent = 6,
+}
+
+impl SectionKind
+{
+    fn from_u32(val: u32) -> Option<SectionKind> {
+        match val {
+            1 => Some(SectionKind::Layout),
+            2 => Some(SectionKind::Memory),
+            3 => Some(SectionKind::Vcpu),
+            4 => Some(SectionKind::Clock),
+            5 => Some(SectionKind::Device),
+            6 => Some(SectionKind::Parent),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Guest physical memory mapping and its contents
+ */
+#[derive(Debug)]
+pub struct SnapshotMemory
+{
+    pub base: u64,
+    pub flags: u32,     // Mapping flags (RWX)
+    pub data: Vec<u8>,
+    pub pages: Option<Vec<usize>>,  // Ascending indices of pages an incremental snapshot saves, None for all
+}
+
+/**
+ * Device state under device name
+ */
+#[derive(Debug)]
+pub struct SnapshotDevice
+{
+    pub name: String,
+    pub version: u32,   // Version of device state layout, see vm::state_handler
+    pub state: devstate::DeviceState,
+}
+
+/**
+ * What goes into a snapshot file
+ */
+#[derive(Debug)]
+pub struct Snapshot
+{
+    pub created: u64,   // Seconds since Unix epoch
+    pub memory: Vec<SnapshotMemory>,
+    pub vcpu_state: vm::VcpuState,
+    pub clock_ns: u64,
+    pub dilation: f64,
+    pub devices: Vec<SnapshotDevice>,
+    pub parent: Option<u64>,    // Content hash of snapshot this one saves changes since, None for a full one
+}
+
+/**
+ * CRC-32 of data as zlib computes it
+ */
+pub fn crc32(data: &[u8]) -> u32
+{
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/**
+ * FNV-1a hash of snapshot file contents, incremental snapshots name their parent by it
+ */
+pub fn content_hash(data: &[u8]) -> u64
+{
+    hash_more(CONTENT_HASH_SEED, data)
+}
+
+pub const CONTENT_HASH_SEED: u64 = 0xcbf29ce484222325;
+
+/**
+ * Content hash continued over more data, for contents read in pieces
+ */
+pub fn hash_more(hash: u64, data: &[u8]) -> u64
+{
+    data.iter().fold(hash, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/**
+ * Write snapshot to out in the format above
+ */
+pub fn write<W: Write>(snapshot: &Snapshot, out: &mut W) -> io::Result<()>
+{
+    /* Kind, flags, base and data of each section */
+    let mut layout = Vec::new();
+    for mem in &snapshot.memory {
+        put_u64(&mut layout, mem.base);
+        put_u64(&mut layout, mem.data.len() as u64);
+        put_u32(&mut layout, mem.flags);
+        put_u32(&mut layout, 0);
+    }
+
+    let mut sections = vec![(SectionKind::Layout, 0, 0, layout)];
+    if let Some(parent) = snapshot.parent {
+        let mut data = Vec::new();
+        put_u64(&mut data, parent);
+        sections.push((SectionKind::Parent, 0, 0, data));
+    }
+    for mem in &snapshot.memory {
+        sections.push(match mem.pages {
+            Some(ref pages) => (SectionKind::Memory, SECTION_SPARSE | SECTION_DELTA, mem.base, sparse_pages(&mem.data, pages)),
+            None => (SectionKind::Memory, SECTION_SPARSE, mem.base, sparse_memory(&mem.data)),
+        });
+    }
+    sections.push((SectionKind::Vcpu, 0, 0, vcpu_text(&snapshot.vcpu_state).into_bytes()));
+
+    let mut clock = Vec::new();
+    put_u64(&mut clock, snapshot.clock_ns);
+    put_u64(&mut clock, snapshot.dilation.to_bits());
+    sections.push((SectionKind::Clock, 0, 0, clock));
+
+    for dev in &snapshot.devices {
+        let mut data = Vec::new();
+        put_u32(&mut data, dev.version);
+        put_u32(&mut data, dev.name.len() as u32);
+        data.extend_from_slice(dev.name.as_bytes());
+        data.extend_from_slice(&devstate::BinaryCodec.encode(&dev.state));
+        sections.push((SectionKind::Device, 0, 0, data));
+    }
+
+    let mut crate_version = [0u8; CRATE_VERSION_SIZE];
+    let version = env!("CARGO_PKG_VERSION").as_bytes();
+    let len = ::std::cmp::min(version.len(), CRATE_VERSION_SIZE);
+    crate_version[..len].copy_from_slice(&version[..len]);
+
+    let mut header = Vec::new();
+    header.extend_from_slice(SNAPSHOT_MAGIC);
+    put_u32(&mut header, SNAPSHOT_VERSION);
+    put_u32(&mut header, sections.len() as u32);
+    put_u64(&mut header, snapshot.created);
+    header.extend_from_slice(&crate_version);
+
+    let mut table = Vec::new();
+    let mut offset = (HEADER_SIZE + sections.len() * ENTRY_SIZE) as u64;
+    for &(kind, flags, base, ref data) in &sections {
+        put_u32(&mut table, kind as u32);
+        put_u32(&mut table, flags);
+        put_u64(&mut table, base);
+        put_u64(&mut table, offset);
+        put_u64(&mut table, data.len() as u64);
+        put_u32(&mut table, crc32(data));
+        put_u32(&mut table, 0);
+        offset += data.len() as u64;
+    }
+
+    let mut checked = header.clone();
+    checked.extend_from_slice(&table);
+    put_u32(&mut header, crc32(&checked));
+    put_u32(&mut header, 0);
+
+    try!(out.write_all(&header));
+    try!(out.write_all(&table));
+    for &(_, _, _, ref data) in &sections {
+        try!(out.write_all(data));
+    }
+    out.flush()
+}
+
+/* Where snapshot is written before it's renamed to its path */
+fn temp_path(path: &Path) -> PathBuf
+{
+    let mut name = path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/**
+ * Write snapshot file and return its content hash, path either gets a whole snapshot or is left as it was
+ */
+pub fn save(snapshot: &Snapshot, path: &Path) -> io::Result<u64>
+{
+    let mut data = Vec::new();
+    try!(write(snapshot, &mut data));
+
+    let temp = temp_path(path);
+    let res = File::create(&temp)
+        .and_then(|mut file| file.write_all(&data).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&temp, path));
+    if res.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    res.map(|()| content_hash(&data))
+}
+
+/* Format version and section data by kind, flags and base, checksums and bounds checked */
+fn read_sections(data: &[u8]) -> Result<(u32, Vec<(SectionKind, u32, u64, &[u8])>), String>
+{
+    if data.len() < HEADER_SIZE || &data[0..8] != SNAPSHOT_MAGIC {
+        return Err(String::from("Not a snapshot"));
+    }
+    let version = get_u32(data, 8);
+    if version == 0 {
+        return Err(String::from("Bad snapshot format version 0"));
+    }
+    if version > SNAPSHOT_VERSION {
+        return Err(format!("Snapshot format version {} is newer than version {} this VMM reads", version, SNAPSHOT_VERSION));
+    }
+
+    let count = get_u32(data, 12) as usize;
+    let table_end = HEADER_SIZE + count * ENTRY_SIZE;
+    if data.len() < table_end {
+        return Err(String::from("Snapshot section table is cut short"));
+    }
+
+    let mut checked = data[..40].to_vec();
+    checked.extend_from_slice(&data[HEADER_SIZE..table_end]);
+    if crc32(&checked) != get_u32(data, 40) {
+        return Err(String::from("Snapshot header checksum mismatch"));
+    }
+
+    let mut sections = Vec::new();
+    for i in 0..count {
+        let entry = &data[HEADER_SIZE + i * ENTRY_SIZE..];
+        let kind = try!(SectionKind::from_u32(get_u32(entry, 0)).ok_or(format!("Unknown section kind in entry {}", i)));
+        let (offset, size) = (get_u64(entry, 16), get_u64(entry, 24));
+        if offset.checked_add(size).map_or(true, |end| end > data.len() as u64) {
+            return Err(format!("Section {} runs past end of snapshot", i));
+        }
+
+        let section = &data[offset as usize..(offset + size) as usize];
+        if crc32(section) != get_u32(entry, 32) {
+            return Err(format!("Section {} ({:?}) checksum mismatch", i, kind));
+        }
+        sections.push((kind, get_u32(entry, 4), get_u64(entry, 8), section));
+    }
+    Ok((version, sections))
+}
+
+/* Snapshot from file contents, an incremental one over its parent and the parent's content hash */
+fn read_over(data: &[u8], parent: Option<(&Snapshot, u64)>) -> Result<Snapshot, String>
+{
+    let (version, sections) = try!(read_sections(data));
+    let find = |kind: SectionKind| sections.iter().find(|&&(known, _, _, _)| known == kind).map(|&(_, _, _, data)| data)
+        .ok_or(format!("No {:?} section in snapshot", kind));
+
+    let saved_parent = match sections.iter().find(|&&(kind, _, _, _)| kind == SectionKind::Parent) {
+        Some(&(_, _, _, data)) if data.len() == 8 => Some(get_u64(data, 0)),
+        Some(_) => return Err(String::from("Bad snapshot parent section")),
+        None => None,
+    };
+    match (saved_parent, parent) {
+        (None, None) => {},
+        (Some(_), None) => return Err(String::from("Snapshot is incremental, its parent has to be restored before it")),
+        (None, Some(_)) => return Err(String::from("Snapshot isn't incremental")),
+        (Some(saved), Some((_, hash))) => if saved != hash {
+            return Err(format!("Snapshot was saved over parent 0x{:016x}, not the one before it 0x{:016x}", saved, hash));
+        },
+    }
+
+    let layout = try!(find(SectionKind::Layout));
+    let mut memory = Vec::new();
+    for (i, entry) in layout.chunks(24).enumerate() {
+        if entry.len() != 24 {
+            return Err(String::from("Bad snapshot memory layout"));
+        }
+
+        let (base, size, flags) = (get_u64(entry, 0), get_u64(entry, 8), get_u32(entry, 16));
+        let &(_, section_flags, _, section) = try!(sections.iter().find(|&&(kind, _, known, _)| kind == SectionKind::Memory && known == base)
+                                                   .ok_or(format!("Missing memory section at 0x{:x}", base)));
+        let data = if section_flags & SECTION_DELTA != 0 {
+            /* Parent has the same layout, or there is nothing to put pages over */
+            let mut data = match parent.and_then(|(parent, _)| parent.memory.get(i)) {
+                Some(prev) if prev.base == base && prev.data.len() as u64 == size => prev.data.clone(),
+                _ => return Err(format!("Memory at 0x{:x} isn't laid out as in parent snapshot", base)),
+            };
+            if !apply_sparse_memory(&mut data, section) {
+                return Err(format!("Bad memory section at 0x{:x}", base));
+            }
+            data
+        } else {
+            try!(expand_sparse_memory(section).ok_or(format!("Bad memory section at 0x{:x}", base)))
+        };
+        if data.len() as u64 != size {
+            return Err(format!("Memory section at 0x{:x} has 0x{:x} bytes, layout says 0x{:x}", base, data.len(), size));
+        }
+        memory.push(SnapshotMemory { base: base, flags: flags, data: data, pages: None });
+    }
+
+    let vcpu = try!(find(SectionKind::Vcpu));
+    let vcpu_state = try!(::std::str::from_utf8(vcpu).map_err(|_| String::from("Vcpu section isn't text")).and_then(parse_vcpu_text));
+
+    let clock = try!(find(SectionKind::Clock));
+    if clock.len() != 16 {
+        return Err(String::from("Bad snapshot clock section"));
+    }
+
+    /* Format version 1 saved device states as JSON */
+    let codec: &state_codec = if version == 1 { &devstate::JsonCodec } else { &devstate::BinaryCodec };
+    let mut devices = Vec::new();
+    for &(_, _, _, data) in sections.iter().filter(|&&(kind, _, _, _)| kind == SectionKind::Device) {
+        let len = if data.len() >= 8 { get_u32(data, 4) as usize } else { data.len() };
+        if data.len() < 8 + len {
+            return Err(String::from("Bad snapshot device section"));
+        }
+        let name = try!(String::from_utf8(data[8..8 + len].to_vec()).map_err(|_| String::from("Device name isn't text")));
+        let state = try!(codec.decode(&data[8 + len..]).map_err(|err| format!("Device {}: {}", name, err)));
+        devices.push(SnapshotDevice {
+            name: name,
+            version: get_u32(data, 0),
+            state: state,
+        });
+    }
+
+    Ok(Snapshot {
+        created: get_u64(data, 16),
+        memory: memory,
+        vcpu_state: vcpu_state,
+        clock_ns: get_u64(clock, 0),
+        dilation: f64::from_bits(get_u64(clock, 8)),
+        devices: devices,
+        parent: saved_parent,
+    })
+}
+
+/**
+ * Snapshot from file contents, nothing of a file with a bad checksum anywhere is taken
+ * Incremental snapshots need their parent, see read_chain().
+ */
+pub fn read(data: &[u8]) -> Result<Snapshot, String>
+{
+    read_over(data, None)
+}
+
+/**
+ * Snapshot a base snapshot and its increments in the order they were saved add up to, with content hash of
+ * the last one. Files are given with names for errors.
+ */
+pub fn read_chain(files: &[(String, Vec<u8>)]) -> Result<(Snapshot, u64), String>
+{
+    let mut snapshot: Option<(Snapshot, u64)> = None;
+    for &(ref name, ref data) in files {
+        let next = match snapshot {
+            Some((ref prev, hash)) => read_over(data, Some((prev, hash))),
+            None => read(data),
+        };
+        let next = try!(next.map_err(|err| format!("{}: {}", name, err)));
+        snapshot = Some((next, content_hash(data)));
+    }
+    snapshot.ok_or(String::from("No snapshot to read"))
+}
+
+/**
+ * Read snapshot file and the increments saved after it, see read_chain()
+ */
+pub fn load_chain(paths: &[&Path]) -> Result<(Snapshot, u64), String>
+{
+    let mut files = Vec::new();
+    for path in paths {
+        let mut data = Vec::new();
+        try!(File::open(path).and_then(|mut file| file.read_to_end(&mut data))
+             .map_err(|err| format!("Can't read snapshot {}: {}", path.display(), err)));
+        files.push((path.display().to_string(), data));
+    }
+    read_chain(&files)
+}
+
+/**
+ * Bring device state saved at version up to the version handler has, a migration at a time
+ * Errors name the device and the version it can't get past.
+ */
+pub fn migrate_state(handler: &vm::state_handler, name: &str, version: u32, state: devstate::DeviceState) -> Result<devstate::DeviceState, String>
+{
+    let current = handler.state_version();
+    if version > current {
+        return Err(format!("Device {} state is version {}, newer than version {} VM has", name, version, current));
+    }
+
+    let mut state = state;
+    for from in version..current {
+        state = try!(handler.migrate_state(from, state)
+                     .map_err(|err| format!("Device {} state can't be migrated from version {} to {}: {}", name, from, from + 1, err)));
+    }
+    Ok(state)
+}
+
+/* Files older versions saved, as a base snapshot and increments over it each, see test/snapshots */
+#[cfg(test)]
+const FIXTURES: &'static [&'static [(&'static str, &'static [u8])]] = &[
+    &[("v1-base.snap", include_bytes!("../test/snapshots/v1-base.snap")),
+      ("v1-increment.snap", include_bytes!("../test/snapshots/v1-increment.snap"))],
+];
+
+/**
+ * States of device in snapshot fixtures with the versions they were saved at, for device tests to restore
+ */
+#[cfg(test)]
+pub fn fixture_states(name: &str) -> Vec<(u32, devstate::DeviceState)>
+{
+    let mut states = Vec::new();
+    for chain in FIXTURES {
+        for last in 0..chain.len() {
+            let files: Vec<(String, Vec<u8>)> = chain[..last + 1].iter().map(|&(name, data)| (String::from(name), data.to_vec())).collect();
+            let (snapshot, _) = read_chain(&files).unwrap();
+            for dev in snapshot.devices.iter().filter(|dev| dev.name == name) {
+                states.push((dev.version, dev.state.clone()));
+            }
+        }
+    }
+    states
+}
+
+/**
+ * Check devices saved in snapshot are the ones VM has, error lists the differences
+ */
+pub fn match_devices(saved: &[&str], configured: &[&str]) -> Result<(), String>
+{
+    let missing: Vec<&str> = saved.iter().filter(|name| !configured.contains(name)).cloned().collect();
+    let extra: Vec<&str> = configured.iter().filter(|name| !saved.contains(name)).cloned().collect();
+
+    let mut diffs = Vec::new();
+    if !missing.is_empty() {
+        diffs.push(format!("VM has no {}", missing.join(", ")));
+    }
+    if !extra.is_empty() {
+        diffs.push(format!("snapshot has no {}", extra.join(", ")));
+    }
+
+    if diffs.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Snapshot devices don't match VM: {}", diffs.join("; ")))
+    }
+}
+
+#[cfg(test)]
+mod snapshot_test
+{
+    use super::*;
+
+    /* 64K of RAM with a boot sector, BIOS ROM, and a PIC */
+    fn boot_snapshot() -> Snapshot {
+        let mut ram = vec![0u8; 0x10000];
+        ram[0x7DFE] = 0x55;
+        ram[0x7DFF] = 0xAA;
+
+        Snapshot {
+            created: 1500000000,
+            memory: vec![
+                SnapshotMemory { base: 0, flags: 7, data: ram, pages: None },
+                SnapshotMemory { base: 0xF0000, flags: 5, data: vec![0xEA; 0x10000], pages: None },
+            ],
+            vcpu_state: vm::VcpuState { rax: 0x1234, rip: 0x7C00, ..Default::default() },
+            clock_ns: 5000000,
+            dilation: 0.5,
+            devices: vec![SnapshotDevice { name: String::from("pic"), version: 1, state: devstate::DeviceState::new() }],
+            parent: None,
+        }
+    }
+
+    /* Snapshot over parent file with RAM pages filled with bytes, ROM unchanged */
+    fn increment(parent: &[u8], prev: &Snapshot, writes: &[(usize, u8)], rip: u64) -> Snapshot {
+        let mut ram = prev.memory[0].data.clone();
+        for &(page, val) in writes {
+            for byte in &mut ram[page * 4096..(page + 1) * 4096] {
+                *byte = val;
+            }
+        }
+        let mut pages: Vec<usize> = writes.iter().map(|&(page, _)| page).collect();
+        pages.sort();
+
+        Snapshot {
+            created: prev.created + 1,
+            memory: vec![
+                SnapshotMemory { base: 0, flags: 7, data: ram, pages: Some(pages) },
+                SnapshotMemory { base: 0xF0000, flags: 5, data: vec![0xEA; 0x10000], pages: Some(Vec::new()) },
+            ],
+            vcpu_state: vm::VcpuState { rip: rip, ..prev.vcpu_state },
+            clock_ns: prev.clock_ns + 1000,
+            dilation: prev.dilation,
+            devices: vec![SnapshotDevice { name: String::from("pic"), version: 1, state: devstate::DeviceState::new() }],
+            parent: Some(content_hash(parent)),
+        }
+    }
+
+    /* Section entries as kind, flags, base and data, checksums checked */
+    fn sections(file: &[u8]) -> Vec<(u32, u32, u64, &[u8])> {
+        let count = get_u32(file, 12) as usize;
+        let table = &file[HEADER_SIZE..HEADER_SIZE + count * ENTRY_SIZE];
+        let mut checked = file[..40].to_vec();
+        checked.extend_from_slice(table);
+        assert!(get_u32(file, 40) == crc32(&checked));
+
+        (0..count).map(|i| {
+            let entry = &table[i * ENTRY_SIZE..];
+            let (offset, size) = (get_u64(entry, 16) as usize, get_u64(entry, 24) as usize);
+            let data = &file[offset..offset + size];
+            assert!(get_u32(entry, 32) == crc32(data));
+            (get_u32(entry, 0), get_u32(entry, 4), get_u64(entry, 8), data)
+        }).collect()
+    }
+
+    #[test] fn checksum() {
+        assert!(crc32(b"") == 0);
+        assert!(crc32(b"123456789") == 0xCBF43926);
+    }
+
+    #[test] fn layout() {
+        let mut file = Vec::new();
+        write(&boot_snapshot(), &mut file).unwrap();
+
+        assert!(&file[0..8] == b"XVMSNAP\0");
+        assert!(get_u32(&file, 8) == SNAPSHOT_VERSION && get_u64(&file, 16) == 1500000000);
+        assert!(file[24..40].starts_with(env!("CARGO_PKG_VERSION").as_bytes()));
+
+        let sections = sections(&file);
+        let kinds: Vec<u32> = sections.iter().map(|&(kind, _, _, _)| kind).collect();
+        assert!(kinds == vec![1, 2, 2, 3, 4, 5]);
+
+        let layout = sections[0].3;
+        assert!(layout.len() == 48);
+        assert!(get_u64(layout, 24) == 0xF0000 && get_u64(layout, 32) == 0x10000 && get_u32(layout, 40) == 5);
+
+        /* RAM has one page that isn't zeros */
+        let (_, flags, base, data) = sections[1];
+        assert!(flags == SECTION_SPARSE && base == 0 && data.len() == 16 + 8 + 4096);
+        assert!(expand_sparse_memory(data).unwrap() == boot_snapshot().memory[0].data);
+
+        assert!(String::from_utf8_lossy(sections[3].3).contains("rip=0x7c00\n"));
+        assert!(get_u64(sections[4].3, 0) == 5000000 && f64::from_bits(get_u64(sections[4].3, 8)) == 0.5);
+        assert!(sections[5].3 == b"\x01\0\0\0\x03\0\0\0pic\0\0");
+    }
+
+    #[test] fn read_back() {
+        let mut file = Vec::new();
+        write(&boot_snapshot(), &mut file).unwrap();
+
+        let saved = boot_snapshot();
+        let snapshot = read(&file).unwrap();
+        assert!(snapshot.created == saved.created && snapshot.clock_ns == saved.clock_ns && snapshot.dilation == saved.dilation);
+        assert!(snapshot.vcpu_state == saved.vcpu_state);
+        assert!(snapshot.memory.len() == 2);
+        assert!(snapshot.memory[0].data == saved.memory[0].data && snapshot.memory[1].data == saved.memory[1].data);
+        assert!(snapshot.memory[1].base == 0xF0000 && snapshot.memory[1].flags == 5);
+        assert!(snapshot.devices.len() == 1);
+        assert!(snapshot.devices[0].name == "pic" && snapshot.devices[0].version == 1 && snapshot.devices[0].state == devstate::DeviceState::new());
+
+        /* Any flipped byte fails one checksum or the other */
+        for &pos in &[8, 30, HEADER_SIZE + 8, file.len() - 1, file.len() / 2] {
+            let mut bad = file.clone();
+            bad[pos] ^= 0x10;
+            assert!(read(&bad).is_err(), "byte {}", pos);
+        }
+        assert!(read(&file[..file.len() - 1]).unwrap_err().contains("runs past end"));
+        assert!(read(b"XVMDUMP\0").unwrap_err() == "Not a snapshot");
+
+        /* Format version is checked before checksums, a newer file says so */
+        let mut newer = file.clone();
+        newer[8] += 1;
+        assert!(read(&newer).unwrap_err() == format!("Snapshot format version {} is newer than version {} this VMM reads",
+                                                      SNAPSHOT_VERSION + 1, SNAPSHOT_VERSION));
+    }
+
+    /* Every file older versions saved still reads, base and increments alike */
+    #[test] fn fixtures() {
+        for chain in FIXTURES {
+            let files: Vec<(String, Vec<u8>)> = chain.iter().map(|&(name, data)| (String::from(name), data.to_vec())).collect();
+            for last in 0..files.len() {
+                let (snapshot, _) = read_chain(&files[..last + 1]).unwrap_or_else(|err| panic!("{}", err));
+                assert!(!snapshot.memory.is_empty() && snapshot.parent.is_some() == (last > 0), "{}", files[last].0);
+                assert!(snapshot.devices.iter().any(|dev| dev.name == "pic" && !dev.state.fields.is_empty()), "{}", files[last].0);
+            }
+        }
+        assert!(fixture_states("pic").len() == 2);
+    }
+
+    /* Device at version 3 migrating from 1 renames a field, from 2 adds one */
+    struct Migrating;
+
+    impl vm::state_handler for Migrating {
+        fn device_state(&self) -> Option<devstate::DeviceState> {
+            None
+        }
+
+        fn state_version(&self) -> u32 {
+            3
+        }
+
+        fn migrate_state(&self, version: u32, state: devstate::DeviceState) -> Result<devstate::DeviceState, String> {
+            match version {
+                1 => Ok(devstate::DeviceState::new().number("count", try!(state.get_number("n")))),
+                2 => Ok(state.bool("armed", false)),
+                _ => Err(format!("nothing migrates version {}", version)),
+            }
+        }
+    }
+
+    #[test] fn migration() {
+        let v1 = devstate::DeviceState::new().number("n", 5);
+        let v3 = devstate::DeviceState::new().number("count", 5).bool("armed", false);
+        assert!(migrate_state(&Migrating, "dev", 1, v1) == Ok(v3.clone()));
+        assert!(migrate_state(&Migrating, "dev", 3, v3.clone()) == Ok(v3.clone()));
+        assert!(migrate_state(&Migrating, "dev", 2, devstate::DeviceState::new()) ==
+                Ok(devstate::DeviceState::new().bool("armed", false)));
+
+        assert!(migrate_state(&Migrating, "dev", 4, v3.clone()).unwrap_err() == "Device dev state is version 4, newer than version 3 VM has");
+        assert!(migrate_state(&Migrating, "dev", 0, v3).unwrap_err() ==
+                "Device dev state can't be migrated from version 0 to 1: nothing migrates version 0");
+        assert!(migrate_state(&Migrating, "dev", 1, devstate::DeviceState::new()).unwrap_err() ==
+                "Device dev state can't be migrated from version 1 to 2: No n in device state");
+    }
+
+    #[test] fn chain() {
+        let base = boot_snapshot();
+        let mut base_file = Vec::new();
+        write(&base, &mut base_file).unwrap();
+
+        /* First increment zeroes the boot sector page, second writes over a page the first one did */
+        let inc1 = increment(&base_file, &base, &[(1, 0x11), (7, 0)], 0x7C10);
+        let mut inc1_file = Vec::new();
+        write(&inc1, &mut inc1_file).unwrap();
+        let inc2 = increment(&inc1_file, &inc1, &[(2, 0x22), (1, 0x33)], 0x7C20);
+        let mut inc2_file = Vec::new();
+        write(&inc2, &mut inc2_file).unwrap();
+
+        let kinds: Vec<(u32, u32)> = sections(&inc1_file).iter().map(|&(kind, flags, _, _)| (kind, flags)).collect();
+        assert!(kinds == vec![(1, 0), (6, 0), (2, SECTION_SPARSE | SECTION_DELTA), (2, SECTION_SPARSE | SECTION_DELTA), (3, 0), (4, 0), (5, 0)]);
+        assert!(get_u64(sections(&inc1_file)[1].3, 0) == content_hash(&base_file));
+        assert!(inc2_file.len() < 3 * 4096);
+
+        let files = vec![(String::from("base"), base_file.clone()), (String::from("inc1"), inc1_file.clone()), (String::from("inc2"), inc2_file.clone())];
+        let (snapshot, hash) = read_chain(&files).unwrap();
+        assert!(hash == content_hash(&inc2_file));
+        assert!(snapshot.memory[0].data == inc2.memory[0].data && snapshot.memory[1].data == base.memory[1].data);
+        assert!(snapshot.memory[0].data[0x1000] == 0x33 && snapshot.memory[0].data[0x2000] == 0x22 && snapshot.memory[0].data[0x7DFE] == 0);
+        assert!(snapshot.vcpu_state.rip == 0x7C20 && snapshot.clock_ns == inc2.clock_ns);
+
+        /* Chain stops at any increment */
+        assert!(read_chain(&files[..2]).unwrap().0.memory[0].data == inc1.memory[0].data);
+        assert!(read(&base_file).unwrap().memory[0].data == base.memory[0].data);
+    }
+
+    #[test] fn chain_order() {
+        let base = boot_snapshot();
+        let mut base_file = Vec::new();
+        write(&base, &mut base_file).unwrap();
+        let inc1 = increment(&base_file, &base, &[(1, 0x11)], 0x7C10);
+        let mut inc1_file = Vec::new();
+        write(&inc1, &mut inc1_file).unwrap();
+        let mut inc2_file = Vec::new();
+        write(&increment(&inc1_file, &inc1, &[(2, 0x22)], 0x7C20), &mut inc2_file).unwrap();
+
+        assert!(read(&inc1_file).unwrap_err() == "Snapshot is incremental, its parent has to be restored before it");
+
+        let file = |name: &str, data: &Vec<u8>| (String::from(name), data.clone());
+        let err = read_chain(&[file("base", &base_file), file("inc2", &inc2_file)]).unwrap_err();
+        assert!(err.starts_with("inc2: Snapshot was saved over parent") && err.contains("not the one before it"), "{}", err);
+        assert!(read_chain(&[file("inc1", &inc1_file), file("inc2", &inc2_file)]).unwrap_err().starts_with("inc1: Snapshot is incremental"));
+        assert!(read_chain(&[file("base", &base_file), file("again", &base_file)]).unwrap_err() == "again: Snapshot isn't incremental");
+        assert!(read_chain(&[]).is_err());
+    }
+
+    #[test] fn device_match() {
+        assert!(match_devices(&["pic", "ata0"], &["ata0", "pic"]).is_ok());
+        assert!(match_devices(&["pic", "ata0", "ata1"], &["pic", "uart"]).unwrap_err() ==
+                "Snapshot devices don't match VM: VM has no ata0, ata1; snapshot has no uart");
+        assert!(match_devices(&[], &["pic"]).unwrap_err() == "Snapshot devices don't match VM: snapshot has no pic");
+    }
+
+    #[test] fn atomic_save() {
+        let dir = ::std::env::temp_dir().join(format!("xvm_snapshot_test_{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("boot.snap");
+
+        save(&boot_snapshot(), &path).unwrap();
+        let names: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert!(names == vec![::std::ffi::OsString::from("boot.snap")]);
+
+        /* Snapshot that can't be written leaves neither the file nor its temporary behind */
+        let missing = dir.join("missing").join("boot.snap");
+        assert!(save(&boot_snapshot(), &missing).is_err());
+        assert!(!missing.exists() && !temp_path(&missing).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+fn cmd_savevm(_: &mut monitor::MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    if args.len() == 2 && args[0] == "-live" {
+        return livesnap::start(Path::new(args[1]))
+            .map(|()| format!("Saving live snapshot to {}, see info livesnap", args[1]))
+            .map_err(|err| format!("Can't save snapshot to {}: {}", args[1], err));
+    }
+
+    let res = match args.len() {
+        1 => vm::save_snapshot(Path::new(args[0])),
+        2 => vm::save_snapshot_incremental(Path::new(args[0]), Path::new(args[1])),
+        _ => return Err(String::from("Usage: savevm <file> [base] | savevm -live <file>")),
+    };
+
+    res.map(|()| format!("Snapshot saved to {}", args[0]))
+       .map_err(|err| format!("Can't save snapshot to {}: {}", args[0], err))
+}
+
+fn cmd_ramsave(_: &mut monitor::MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    let range = match args.len() {
+        1 => None,
+        3 => {
+            let addr = try!(monitor::parse_number(args[1]));
+            let size = try!(monitor::parse_number(args[2]));
+            Some(addr..try!(addr.checked_add(size).ok_or(format!("Bad size {}", args[2]))))
+        },
+        _ => return Err(String::from("Usage: ramsave <file> [addr size]")),
+    };
+
+    vm::export_ram(Path::new(args[0]), range)
+        .map(|range| format!("RAM 0x{:x}-0x{:x} saved to {}", range.start, range.end - 1, args[0]))
+        .map_err(|err| format!("Can't save RAM to {}: {}", args[0], err))
+}
+
+fn cmd_ramload(ctx: &mut monitor::MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    if args.len() != 2 {
+        return Err(String::from("Usage: ramload <file> <addr>"));
+    }
+
+    if ctx.run_state != monitor::RunState::Stopped {
+        return Err(String::from("RAM can only be loaded with guest stopped, stop it first"));
+    }
+
+    let addr = try!(monitor::parse_number(args[1]));
+    vm::import_ram(Path::new(args[0]), addr)
+        .map(|range| format!("RAM 0x{:x}-0x{:x} loaded from {}", range.start, range.end - 1, args[0]))
+        .map_err(|err| format!("Can't load RAM from {}: {}", args[0], err))
+}
+
+/**
+ * Add snapshot and RAM image monitor commands
+ */
+pub fn init()
+{
+    monitor::register_command(monitor::MonitorCommand {
+        name: "savevm",
+        args: "file [base] | -live file",
+        help: "save VM snapshot to file, with base only pages changed since that snapshot, -live as guest runs",
+        handler: cmd_savevm,
+    });
+    monitor::register_command(monitor::MonitorCommand {
+        name: "ramsave",
+        args: "file [addr size]",
+        help: "write guest RAM as flat bytes to file, size bytes from addr or the RAM at 0",
+        handler: cmd_ramsave,
+    });
+    monitor::register_command(monitor::MonitorCommand {
+        name: "ramload",
+        args: "file addr",
+        help: "load flat file into guest RAM at addr, guest stopped",
+        handler: cmd_ramload,
+    });
+}
@@ -0,0 +1,245 @@
+/*
+ * Run summary
+ *
+ * Where a run went, put together from VM counters: exits by reason, the hottest I/O ports, assertions of each
+ * IRQ line, guest time against host wall time and bytes devices moved for guest. Devices moving data register
+ * a vm::stats_handler to show up.
+ *
+ * vm::run_summary() returns it for tests holding a guest to an exit budget, --summary writes it as a table
+ * when VM stops.
+ */
+
+use config;
+use vm;
+
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::sync::Mutex;
+
+// Ports listed, most accessed first
+pub const HOT_PORTS: usize = 10;
+
+const NS_PER_SEC: f64 = 1_000_000_000.0;
+
+/**
+ * Guest accesses to one port
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PortCount
+{
+    pub port: u16,
+    pub reads: u64,
+    pub writes: u64,
+}
+
+impl PortCount
+{
+    pub fn total(&self) -> u64 {
+        self.reads + self.writes
+    }
+}
+
+/**
+ * Guest data a device moved
+ */
+#[derive(Clone, PartialEq, Debug)]
+pub struct DeviceStats
+{
+    pub device: &'static str,
+    pub bytes_read: u64,        // Guest took from device
+    pub bytes_written: u64,     // Guest gave to device
+}
+
+/**
+ * Counters of a run
+ */
+#[derive(Clone, PartialEq, Debug)]
+pub struct RunSummary
+{
+    pub exits: Vec<(u32, u64)>,     // Basic exit reason and count, most frequent first
+    pub ports: Vec<PortCount>,      // Hottest ports, most accessed first
+    pub irqs: Vec<u64>,             // Assertions of each IRQ line
+    pub guest_ns: u64,
+    pub host_ns: u64,
+    pub devices: Vec<DeviceStats>,
+}
+
+/**
+ * Exit reason as the summary shows it
+ */
+pub fn exit_reason_name(reason: u32) -> String
+{
+    let name = match reason {
+        0 => "exception",
+        1 => "external irq",
+        2 => "triple fault",
+        7 => "irq window",
+        8 => "nmi window",
+        10 => "cpuid",
+        12 => "hlt",
+        16 => "rdtsc",
+        18 => "vmcall",
+        28 => "mov cr",
+        30 => "io",
+        31 => "rdmsr",
+        32 => "wrmsr",
+        37 => "mtf",
+        48 => "ept violation",
+        51 => "rdtscp",
+        52 => "preemption timer",
+        _ => return format!("reason {}", reason),
+    };
+    String::from(name)
+}
+
+impl RunSummary
+{
+    /**
+     * Summary of counters in any order, ports are cut down to the hottest ones
+     */
+    pub fn new(mut exits: Vec<(u32, u64)>, mut ports: Vec<PortCount>, irqs: Vec<u64>,
+               guest_ns: u64, host_ns: u64, devices: Vec<DeviceStats>) -> RunSummary {
+        /* Ties go by number, so summaries of identical runs read the same */
+        exits.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        ports.sort_by(|a, b| b.total().cmp(&a.total()).then(a.port.cmp(&b.port)));
+        ports.truncate(HOT_PORTS);
+
+        RunSummary {
+            exits: exits,
+            ports: ports,
+            irqs: irqs,
+            guest_ns: guest_ns,
+            host_ns: host_ns,
+            devices: devices,
+        }
+    }
+
+    pub fn total_exits(&self) -> u64 {
+        self.exits.iter().map(|&(_, count)| count).sum()
+    }
+
+    /**
+     * Exits for a basic exit reason
+     */
+    pub fn exit_count(&self, reason: u32) -> u64 {
+        self.exits.iter().find(|&&(r, _)| r == reason).map_or(0, |&(_, count)| count)
+    }
+
+    /**
+     * Accesses to port, None if it isn't among the hottest
+     */
+    pub fn port(&self, port: u16) -> Option<PortCount> {
+        self.ports.iter().find(|count| count.port == port).cloned()
+    }
+
+    pub fn device(&self, device: &str) -> Option<&DeviceStats> {
+        self.devices.iter().find(|stats| stats.device == device)
+    }
+}
+
+impl fmt::Display for RunSummary
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "Exits: {} total", self.total_exits()));
+        for &(reason, count) in &self.exits {
+            try!(writeln!(f, "  {:<18} {:>10}", exit_reason_name(reason), count));
+        }
+
+        try!(writeln!(f, "{:<20} {:>10} {:>10}", "Hottest ports:", "reads", "writes"));
+        for count in &self.ports {
+            try!(writeln!(f, "  {:<18} {:>10} {:>10}", format!("0x{:04x}", count.port), count.reads, count.writes));
+        }
+
+        try!(writeln!(f, "IRQ assertions:"));
+        for (irq, &count) in self.irqs.iter().enumerate().filter(|&(_, &count)| count != 0) {
+            try!(writeln!(f, "  irq {:<14} {:>10}", irq, count));
+        }
+
+        try!(writeln!(f, "Time: guest {:.3} s, host {:.3} s",
+                      self.guest_ns as f64 / NS_PER_SEC, self.host_ns as f64 / NS_PER_SEC));
+
+        try!(writeln!(f, "{:<20} {:>10} {:>10}", "Device bytes:", "read", "written"));
+        for stats in &self.devices {
+            try!(writeln!(f, "  {:<18} {:>10} {:>10}", stats.device, stats.bytes_read, stats.bytes_written));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod summary_test
+{
+    use super::*;
+
+    fn port(port: u16, reads: u64, writes: u64) -> PortCount {
+        PortCount { port: port, reads: reads, writes: writes }
+    }
+
+    fn summary() -> RunSummary {
+        let ports = (0..12).map(|i| port(0x80 + i, i as u64, 1)).chain(Some(port(0x21, 1, 4))).collect();
+        let mut irqs = vec![0; vm::IRQ_LINES];
+        irqs[0] = 100;
+        irqs[14] = 3;
+
+        RunSummary::new(vec![(12, 2), (30, 120), (48, 7), (1, 7)], ports, irqs, 1_000_000_000, 1_250_000_000,
+                        vec![DeviceStats { device: "ata0", bytes_read: 1024, bytes_written: 512 }])
+    }
+
+    #[test] fn counters() {
+        let summary = summary();
+        assert!(summary.total_exits() == 136);
+        assert!(summary.exits == vec![(30, 120), (1, 7), (48, 7), (12, 2)]);
+        assert!(summary.exit_count(48) == 7 && summary.exit_count(10) == 0);
+
+        /* Ten hottest ports survive, ties go by port number */
+        assert!(summary.ports.len() == HOT_PORTS);
+        assert!(summary.ports[0] == port(0x8b, 11, 1));
+        assert!(summary.port(0x21) == Some(port(0x21, 1, 4)));
+        assert!(summary.port(0x81).is_none());
+        assert!(summary.device("ata0").unwrap().bytes_read == 1024 && summary.device("uart").is_none());
+    }
+
+    #[test] fn format() {
+        let text = summary().to_string();
+        assert!(text.starts_with("Exits: 136 total\n  io                        120\n  external irq                7\n"));
+        assert!(text.contains("Hottest ports:            reads     writes\n"));
+        assert!(text.contains("  0x0021                      1          4\n"));
+        assert!(text.contains("  irq 0                     100\n  irq 14                      3\nTime:"));
+        assert!(text.contains("Time: guest 1.000 s, host 1.250 s\n"));
+        assert!(text.ends_with("  ata0                     1024        512\n"));
+        assert!(exit_reason_name(99) == "reason 99");
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+lazy_static! {
+    static ref SUMMARY_PATH: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/**
+ * Write run summary where --summary asked for, vcpu thread only
+ */
+pub fn report()
+{
+    let path = match *SUMMARY_PATH.lock().unwrap() {
+        Some(ref path) => path.clone(),
+        None => return,
+    };
+
+    let text = vm::run_summary().to_string();
+    if path == "-" {
+        print!("{}", text);
+        return;
+    }
+
+    if let Err(err) = File::create(&path).and_then(|mut file| file.write_all(text.as_bytes())) {
+        error!("Can't write run summary to {}: {}", path, err);
+    }
+}
+
+pub fn init(config: &config::VmConfig)
+{
+    *SUMMARY_PATH.lock().unwrap() = config.summary.clone();
+}
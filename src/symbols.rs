@@ -0,0 +1,257 @@
+/*
+ * Guest symbol maps
+ *
+ * Symbol maps of guest builds let traces, crash reports and monitor show "timer_isr+0x12" instead of raw
+ * addresses, and monitor set breakpoints by name. Maps are text, one symbol per line, in any of:
+ *
+ *   00007c10 timer_isr                                 address and name
+ *   00007c10 T timer_isr                               nm
+ *   00007c10 00000012 T timer_isr                      nm -S
+ *   00007c10 g     F .text  00000012 timer_isr         objdump -t
+ *
+ * Addresses are hex, 0x prefix optional. Lines not starting with one, like headers, are skipped, as are file
+ * and section symbols of objdump. Map addresses are offset by a base to make them linear, so maps of code
+ * linked at 0 and loaded at a segment can be used as they are.
+ *
+ * An address resolves to the nearest symbol at or below it, the first one listed when several share an
+ * address. Symbols with a size only cover it, others reach MAX_SYMBOL_REACH at most.
+ */
+
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::Read;
+
+// Furthest an address resolves to a symbol of unknown size
+pub const MAX_SYMBOL_REACH: u64 = 0x10000;
+
+/**
+ * Named guest linear address
+ */
+#[derive(Clone, PartialEq, Debug)]
+pub struct Symbol
+{
+    pub addr: u64,
+    pub size: Option<u64>,      // Bytes covered, None if map doesn't say
+    pub name: String,
+}
+
+/* Hex number with optional 0x prefix */
+fn parse_hex(val: &str) -> Option<u64>
+{
+    let digits = if val.starts_with("0x") || val.starts_with("0X") { &val[2..] } else { val };
+    u64::from_str_radix(digits, 16).ok()
+}
+
+/* Symbol of a map line, Ok(None) for lines that name none */
+fn parse_line(line: &str, base_offset: u64) -> Result<Option<Symbol>, String>
+{
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    let addr = match tokens.first().and_then(|token| parse_hex(token)) {
+        Some(addr) => addr,
+        None => return Ok(None),
+    };
+
+    let name = tokens[tokens.len() - 1];
+    let size = match tokens.len() {
+        2 => None,
+        3 if tokens[1].len() == 1 => None,
+        4 if tokens[2].len() == 1 => parse_hex(tokens[1]),
+        n if n >= 4 => {
+            /* objdump flags are single letters, file and section symbols are no code or data */
+            if tokens.iter().any(|&token| token == "df" || token == "d" || token == "*UND*") {
+                return Ok(None);
+            }
+            parse_hex(tokens[n - 2])
+        },
+        _ => return Err(format!("Bad symbol line \"{}\"", line)),
+    };
+
+    Ok(Some(Symbol {
+        addr: addr + base_offset,
+        size: size.and_then(|size| if size == 0 { None } else { Some(size) }),
+        name: String::from(name),
+    }))
+}
+
+/**
+ * Symbols of a map text, addresses offset by base
+ */
+pub fn parse_map(text: &str, base_offset: u64) -> Result<Vec<Symbol>, String>
+{
+    let mut symbols = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        match parse_line(line, base_offset) {
+            Ok(Some(symbol)) => symbols.push(symbol),
+            Ok(None) => {},
+            Err(err) => return Err(format!("{} at line {}", err, i + 1)),
+        }
+    }
+
+    if symbols.is_empty() {
+        return Err(String::from("No symbols in map"));
+    }
+    Ok(symbols)
+}
+
+/**
+ * Symbols of a map file, addresses offset by base
+ */
+pub fn read_map(path: &str, base_offset: u64) -> Result<Vec<Symbol>, String>
+{
+    let mut text = String::new();
+    try!(File::open(path).and_then(|mut file| file.read_to_string(&mut text))
+         .map_err(|err| format!("Can't read symbol map {}: {}", path, err)));
+    parse_map(&text, base_offset).map_err(|err| format!("{}: {}", path, err))
+}
+
+/**
+ * Symbols sorted by address
+ */
+#[derive(Default)]
+pub struct SymbolTable
+{
+    symbols: Vec<Symbol>,       // Ones sharing an address are in map order
+}
+
+impl SymbolTable
+{
+    pub fn new() -> SymbolTable {
+        SymbolTable::default()
+    }
+
+    /**
+     * Add symbols of a map, ones already there go first at the same address
+     */
+    pub fn add(&mut self, symbols: Vec<Symbol>) {
+        self.symbols.extend(symbols);
+        self.symbols.sort_by_key(|symbol| symbol.addr);
+    }
+
+    pub fn list(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    /**
+     * Symbol address is in and offset into it
+     */
+    pub fn resolve(&self, addr: u64) -> Option<(&str, u64)> {
+        /* First symbol above address, nearest one below is before it along with its aliases */
+        let above = self.symbols.binary_search_by(|symbol| if symbol.addr <= addr { Ordering::Less } else { Ordering::Greater })
+            .unwrap_err();
+        if above == 0 {
+            return None;
+        }
+
+        let mut first = above - 1;
+        while first > 0 && self.symbols[first - 1].addr == self.symbols[first].addr {
+            first -= 1;
+        }
+
+        let symbol = &self.symbols[first];
+        let offset = addr - symbol.addr;
+        if offset >= symbol.size.unwrap_or(MAX_SYMBOL_REACH) {
+            return None;
+        }
+        Some((&symbol.name, offset))
+    }
+
+    /**
+     * Address as "name+0x12", None if it is in no symbol
+     */
+    pub fn describe(&self, addr: u64) -> Option<String> {
+        self.resolve(addr).map(|(name, offset)| {
+            if offset == 0 { String::from(name) } else { format!("{}+0x{:x}", name, offset) }
+        })
+    }
+
+    /**
+     * Address of symbol by name, the first one listed if there are several
+     */
+    pub fn lookup(&self, name: &str) -> Option<u64> {
+        self.symbols.iter().find(|symbol| symbol.name == name).map(|symbol| symbol.addr)
+    }
+}
+
+#[cfg(test)]
+mod symbols_test
+{
+    use super::*;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /* Real mode kernel linked at 0 and loaded at 0800:0000 */
+    const FIXTURE: &'static str = "\
+kernel.elf:     file format elf32-i386
+
+SYMBOL TABLE:
+00000000 l    df *ABS*\t00000000 kernel.asm
+00000000 l    d  .text\t00000000 .text
+00000000 g     F .text\t00000010 start
+00000010 g     F .text\t00000030 timer_isr
+00000010 g     F .text\t00000030 irq0_entry
+00000040 l       .text\t00000000 spin
+00000100 g     O .data\t00000004 ticks
+";
+
+    /* Tests run in parallel, each reads its own copy */
+    static FIXTURES: AtomicUsize = AtomicUsize::new(0);
+
+    fn fixture() -> SymbolTable {
+        let n = FIXTURES.fetch_add(1, Ordering::SeqCst);
+        let path = ::std::env::temp_dir().join(format!("xvm_symbols_fixture_{}_{}.map", ::std::process::id(), n));
+        ::std::fs::File::create(&path).unwrap().write_all(FIXTURE.as_bytes()).unwrap();
+
+        let mut table = SymbolTable::new();
+        table.add(read_map(path.to_str().unwrap(), 0x8000).unwrap());
+        ::std::fs::remove_file(&path).unwrap();
+        table
+    }
+
+    #[test] fn resolve() {
+        let table = fixture();
+        assert!(table.list().len() == 5);
+
+        /* Exact, mid function and aliases sharing an address */
+        assert!(table.resolve(0x8000) == Some(("start", 0)));
+        assert!(table.resolve(0x8022) == Some(("timer_isr", 0x12)));
+        assert!(table.describe(0x8022) == Some(String::from("timer_isr+0x12")));
+        assert!(table.describe(0x8010) == Some(String::from("timer_isr")));
+
+        /* Sized symbols end there, unsized ones reach to the next symbol */
+        assert!(table.resolve(0x803F) == Some(("timer_isr", 0x2F)));
+        assert!(table.resolve(0x8040) == Some(("spin", 0)));
+        assert!(table.resolve(0x80FF) == Some(("spin", 0xBF)));
+        assert!(table.resolve(0x8104).is_none());
+
+        /* Out of range */
+        assert!(table.resolve(0x7FFF).is_none());
+        assert!(table.resolve(0).is_none());
+
+        assert!(table.lookup("irq0_entry") == Some(0x8010));
+        assert!(table.lookup("ticks") == Some(0x8100));
+        assert!(table.lookup("kernel.asm").is_none() && table.lookup(".text").is_none());
+    }
+
+    #[test] fn formats() {
+        let symbols = parse_map("0x7c00 boot\n00007c10 T main\n00007c20 00000008 t helper\n\n# comment\n", 0).unwrap();
+        assert!(symbols == vec![
+            Symbol { addr: 0x7C00, size: None, name: String::from("boot") },
+            Symbol { addr: 0x7C10, size: None, name: String::from("main") },
+            Symbol { addr: 0x7C20, size: Some(8), name: String::from("helper") },
+        ]);
+
+        /* Unsized symbol reaches that far past the last one */
+        let mut table = SymbolTable::new();
+        table.add(parse_map("1000 blob", 0).unwrap());
+        assert!(table.resolve(0x1000 + MAX_SYMBOL_REACH - 1).is_some() && table.resolve(0x1000 + MAX_SYMBOL_REACH).is_none());
+
+        /* Later maps go after symbols at the same address */
+        table.add(parse_map("1000 alias", 0).unwrap());
+        assert!(table.describe(0x1004) == Some(String::from("blob+0x4")));
+
+        assert!(parse_map("00000040         .text\t00000004 spin", 0).unwrap()[0].size == Some(4));
+        assert!(parse_map("7c00 ab cd", 0).is_err());
+        assert!(parse_map("header only\n", 0).is_err());
+        assert!(read_map("/nonexistent/xvm.map", 0).is_err());
+    }
+}
@@ -0,0 +1,394 @@
+/*
+ * Guest execution trace
+ *
+ * While tracing, guest runs one single step per instruction and each instruction is logged with its CS:IP,
+ * raw bytes and disassembly before it executes, e.g.
+ *
+ *   0000:7c0f  66 b8 78 56 34 12     mov eax, 0x12345678
+ *
+ * With guest symbols loaded, see --symbols, instructions a symbol covers end with it:
+ *
+ *   0000:7c22  b0 01                 mov al, 1  <timer_isr+0x12>
+ *
+ * Events injected on the way show up where guest was when it took them, and the next line is the first
+ * instruction of their handler:
+ *
+ *   0000:7c20  -- external interrupt 0x08
+ *
+ * Lines go to a file or to a ring buffer of the latest ones the monitor shows, an address range filter keeps
+ * only instructions at linear addresses in it. Steps need monitor trap flag: a TF step would carry guest into
+ * interrupt handlers without stopping. Tracing is controlled by monitor commands or starts with VM, see --trace.
+ */
+
+use vm;
+use config;
+use monitor;
+use disasm;
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::ops::Range;
+
+// Lines kept by ring buffer trace
+const RING_LINES: usize = 256;
+
+// VM entry interruption info, see inject.rs
+const EVENT_VALID: u32              = 1 << 31;
+const EVENT_TYPE_EXTERNAL: u32      = 0;
+const EVENT_TYPE_NMI: u32           = 2;
+const EVENT_TYPE_EXCEPTION: u32     = 3;
+const EVENT_TYPE_SOFT_INTR: u32     = 4;
+
+const CR0_PE: u64                   = 1 << 0;
+const SEGMENT_ATTR_DB: u32          = 1 << 14;
+
+/**
+ * Trace line for instruction at cs:ip
+ */
+pub fn format_instruction(cs: u16, ip: u64, bytes: &[u8], text: &str) -> String
+{
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{:04x}:{:04x}  {:<20}  {}", cs, ip, hex.join(" "), text)
+}
+
+/* Trace line for event injected at cs:ip with VM entry interruption info */
+fn format_event(cs: u16, ip: u64, info: u32) -> String
+{
+    let vector = info & 0xFF;
+    let event = match (info >> 8) & 0x7 {
+        EVENT_TYPE_EXTERNAL => format!("external interrupt 0x{:02x}", vector),
+        EVENT_TYPE_NMI => String::from("NMI"),
+        EVENT_TYPE_EXCEPTION => format!("exception 0x{:02x}", vector),
+        EVENT_TYPE_SOFT_INTR => format!("software interrupt 0x{:02x}", vector),
+        _ => format!("event 0x{:08x}", info),
+    };
+    format!("{:04x}:{:04x}  -- {}", cs, ip, event)
+}
+
+/**
+ * Real mode and 16 bit protected mode code segments decode as 16 bit code
+ */
+pub fn is_16bit_code(state: &vm::VcpuState) -> bool
+{
+    state.cr0 & CR0_PE == 0 || state.cs.attributes & SEGMENT_ATTR_DB == 0
+}
+
+/**
+ * Where trace lines go
+ */
+pub enum TraceSink
+{
+    File(String, LineWriter<File>), // Lines are written as they come, VM may exit any time
+    Ring(VecDeque<String>),         // Latest lines
+}
+
+/**
+ * Execution trace in progress
+ */
+pub struct Tracer
+{
+    sink: TraceSink,
+    filter: Option<Range<u64>>,     // Linear addresses to trace, all if none
+    active: bool,                   // Ring buffer is kept for the monitor after trace stops
+}
+
+impl Tracer
+{
+    pub fn new(sink: TraceSink, filter: Option<Range<u64>>) -> Tracer {
+        Tracer {
+            sink: sink,
+            filter: filter,
+            active: true,
+        }
+    }
+
+    /**
+     * Address is traced
+     */
+    pub fn wants(&self, linear: u64) -> bool {
+        match self.filter {
+            Some(ref range) => range.start <= linear && linear < range.end,
+            None => true,
+        }
+    }
+
+    fn write(&mut self, line: String) {
+        match self.sink {
+            TraceSink::File(ref path, ref mut file) => {
+                if let Err(err) = writeln!(file, "{}", line) {
+                    warn!("Can't write trace to {}: {}", path, err);
+                }
+            },
+            TraceSink::Ring(ref mut lines) => {
+                if lines.len() == RING_LINES {
+                    lines.pop_front();
+                }
+                lines.push_back(line);
+            },
+        }
+    }
+
+    /**
+     * Log guest state before its next entry: instruction guest is about to run, or event it takes first
+     * when VM entry interruption info is valid. Code holds instruction bytes at CS:IP, symbol is the one
+     * covering CS:IP if any.
+     */
+    pub fn record(&mut self, state: &vm::VcpuState, code: &[u8], event: u32, symbol: Option<String>) {
+        if !self.wants(state.cs.base + state.rip) {
+            return;
+        }
+
+        let cs = state.cs.selector;
+        let line = if event & EVENT_VALID != 0 {
+            format_event(cs, state.rip, event)
+        } else {
+            let line = match disasm::decode(code, state.rip, is_16bit_code(state)) {
+                Some((size, mnemonic, operands)) => {
+                    format_instruction(cs, state.rip, &code[..size], &disasm::instruction_text(&mnemonic, &operands))
+                },
+                None => format_instruction(cs, state.rip, &code[..code.len().min(1)], "(bad)"),
+            };
+            match symbol {
+                Some(symbol) => format!("{}  <{}>", line, symbol),
+                None => line,
+            }
+        };
+        self.write(line);
+    }
+
+    /**
+     * What monitor shows: latest lines of a ring buffer trace, or where a file trace goes
+     */
+    pub fn describe(&self) -> String {
+        match self.sink {
+            TraceSink::File(ref path, _) if self.active => format!("Tracing to {}", path),
+            TraceSink::File(..) => String::from("Not tracing"),
+            TraceSink::Ring(ref lines) if lines.is_empty() => String::from("Trace buffer is empty"),
+            TraceSink::Ring(ref lines) => lines.iter().cloned().collect::<Vec<_>>().join("\n"),
+        }
+    }
+}
+
+/**
+ * Parse trace address filter as "first-last" linear addresses, hex with 0x prefix or decimal
+ */
+pub fn parse_range(val: &str) -> Result<Range<u64>, String>
+{
+    let parse = |addr: &str| if addr.starts_with("0x") {
+        u64::from_str_radix(&addr[2..], 16)
+    } else {
+        addr.parse::<u64>()
+    };
+
+    let mut parts = val.splitn(2, '-');
+    match (parts.next().map(&parse), parts.next().map(&parse)) {
+        (Some(Ok(first)), Some(Ok(last))) if first <= last && last < 0x100000000 => Ok(first..last + 1),
+        _ => Err(format!("Bad address range {}, expected first-last", val)),
+    }
+}
+
+#[cfg(test)]
+mod trace_test
+{
+    use super::*;
+
+    fn state(cs: u16, ip: u64) -> vm::VcpuState {
+        let mut state = vm::VcpuState::default();
+        state.cs.selector = cs;
+        state.cs.base = (cs as u64) << 4;
+        state.rip = ip;
+        state
+    }
+
+    #[test] fn format() {
+        assert!(format_instruction(0, 0x7C0F, &[0x66, 0xB8, 0x78, 0x56, 0x34, 0x12], "mov eax, 0x12345678") ==
+                "0000:7c0f  66 b8 78 56 34 12     mov eax, 0x12345678");
+        assert!(format_instruction(0xF000, 0xFFF0, &[0xFA], "cli") == "f000:fff0  fa                    cli");
+
+        assert!(format_event(0, 0x7C20, 0x80000008) == "0000:7c20  -- external interrupt 0x08");
+        assert!(format_event(0, 0x7C20, 0x80000202) == "0000:7c20  -- NMI");
+        assert!(format_event(0x1000, 0x10, 0x80000B0D) == "1000:0010  -- exception 0x0d");
+        assert!(format_event(0, 0x7C20, 0x80000410) == "0000:7c20  -- software interrupt 0x10");
+    }
+
+    #[test] fn code_size() {
+        let mut s = state(0, 0x7C00);
+        assert!(is_16bit_code(&s));
+
+        /* Protected mode follows CS default operand size */
+        s.cr0 = CR0_PE;
+        assert!(is_16bit_code(&s));
+        s.cs.attributes = 0xC09B;
+        assert!(!is_16bit_code(&s));
+    }
+
+    #[test] fn ring_and_filter() {
+        let mut tracer = Tracer::new(TraceSink::Ring(VecDeque::new()), Some(parse_range("0x7c00-0x7dff").unwrap()));
+        assert!(tracer.describe() == "Trace buffer is empty");
+        assert!(tracer.wants(0x7C00) && tracer.wants(0x7DFF));
+        assert!(!tracer.wants(0x7BFF) && !tracer.wants(0x7E00));
+
+        /* Events are filtered by where guest takes them */
+        tracer.record(&state(0, 0x7C20), &[], 0x80000008, None);
+        tracer.record(&state(0xF000, 0xFEA5), &[], 0x80000008, None);
+        tracer.record(&state(0x07C0, 0x0030), &[], 0x80000202, None);
+        assert!(tracer.describe() == "0000:7c20  -- external interrupt 0x08\n07c0:0030  -- NMI");
+
+        /* Only the latest lines are kept */
+        for _ in 0..RING_LINES {
+            tracer.record(&state(0, 0x7C00), &[], 0x80000202, None);
+        }
+        let lines = tracer.describe();
+        assert!(lines.lines().count() == RING_LINES && !lines.contains("external"));
+    }
+
+    #[test] fn symbols() {
+        let mut tracer = Tracer::new(TraceSink::Ring(VecDeque::new()), None);
+        tracer.record(&state(0, 0x7C22), &[0xF8], 0, Some(String::from("timer_isr+0x12")));
+        tracer.record(&state(0, 0x7C23), &[0xF4], 0, None);
+        tracer.record(&state(0, 0x7C10), &[], 0x80000008, Some(String::from("timer_isr")));
+        assert!(tracer.describe() == "0000:7c22  f8                    clc  <timer_isr+0x12>\n\
+                                      0000:7c23  f4                    hlt\n\
+                                      0000:7c10  -- external interrupt 0x08");
+    }
+
+    #[test] fn range() {
+        assert!(parse_range("0x7c00-0x7dff") == Ok(0x7C00..0x7E00));
+        assert!(parse_range("0-1023") == Ok(0..1024));
+        assert!(parse_range("0x7c00").is_err());
+        assert!(parse_range("0x7dff-0x7c00").is_err());
+        assert!(parse_range("0x7c00-7dff").is_err());
+        assert!(parse_range("0-0x100000000").is_err());
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+static mut TRACER: Option<*mut Tracer> = None;
+
+fn get_tracer() -> Option<&'static mut Tracer>
+{
+    unsafe { TRACER.map(|tracer| &mut *tracer) }
+}
+
+/**
+ * Start tracing to file, or to ring buffer without one, replaces trace in progress
+ */
+pub fn start(file: Option<&str>, filter: Option<Range<u64>>) -> Result<(), String>
+{
+    if !vm::step_with_mtf() {
+        return Err(String::from("Execution trace needs monitor trap flag support"));
+    }
+
+    let sink = match file {
+        Some(path) => match File::create(path) {
+            Ok(file) => TraceSink::File(String::from(path), LineWriter::new(file)),
+            Err(err) => return Err(format!("Can't create trace file {}: {}", path, err)),
+        },
+        None => TraceSink::Ring(VecDeque::with_capacity(RING_LINES)),
+    };
+
+    stop();
+    unsafe {
+        if let Some(old) = TRACER.take() {
+            drop(Box::from_raw(old));
+        }
+        TRACER = Some(Box::into_raw(Box::new(Tracer::new(sink, filter))));
+    }
+    Ok(())
+}
+
+/**
+ * Stop tracing, false if there was no trace going on
+ */
+pub fn stop() -> bool
+{
+    match get_tracer() {
+        Some(ref mut tracer) if tracer.active => {
+            tracer.active = false;
+            if let TraceSink::File(_, ref mut file) = tracer.sink {
+                let _ = file.flush();
+            }
+            true
+        },
+        _ => false,
+    }
+}
+
+/**
+ * Trace is in progress, checked before every guest entry
+ */
+pub fn active() -> bool
+{
+    get_tracer().map_or(false, |tracer| tracer.active)
+}
+
+/**
+ * Log guest instruction or event about to run, see Tracer::record()
+ */
+pub fn record(state: &vm::VcpuState, code: &[u8], event: u32)
+{
+    if let Some(tracer) = get_tracer() {
+        let linear = state.cs.base + state.rip;
+        let symbol = if tracer.wants(linear) { vm::resolve_symbol(linear) } else { None };
+        tracer.record(state, code, event, symbol);
+    }
+}
+
+fn cmd_trace_start(_: &mut monitor::MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    let (file, filter) = match args.len() {
+        1 => (args[0], None),
+        2 => (args[0], Some(try!(parse_range(args[1])))),
+        _ => return Err(String::from("Expected file or ring and optional address range")),
+    };
+
+    try!(start(if file == "ring" { None } else { Some(file) }, filter));
+    Ok(String::new())
+}
+
+fn cmd_trace_stop(_: &mut monitor::MonitorContext, _: &[&str]) -> Result<String, String>
+{
+    if !stop() {
+        return Err(String::from("Not tracing"));
+    }
+    Ok(String::new())
+}
+
+fn cmd_info_trace(_: &mut monitor::MonitorContext, _: &[&str]) -> Result<String, String>
+{
+    Ok(get_tracer().map_or(String::from("Not tracing"), |tracer| tracer.describe()))
+}
+
+/**
+ * Add trace monitor commands and start tracing with VM if configured
+ */
+pub fn init(config: &config::VmConfig)
+{
+    monitor::register_command(monitor::MonitorCommand {
+        name: "trace start",
+        args: "file|ring [first-last]",
+        help: "trace guest instructions, optionally at linear addresses in range only",
+        handler: cmd_trace_start,
+    });
+    monitor::register_command(monitor::MonitorCommand {
+        name: "trace stop",
+        args: "",
+        help: "stop tracing",
+        handler: cmd_trace_stop,
+    });
+    monitor::register_command(monitor::MonitorCommand {
+        name: "info trace",
+        args: "",
+        help: "show trace buffer or trace file",
+        handler: cmd_info_trace,
+    });
+
+    if let Some(ref path) = config.trace {
+        if let Err(err) = start(Some(path), config.trace_range.clone()) {
+            error!("{}", err);
+            ::std::process::exit(1);
+        }
+    }
+}
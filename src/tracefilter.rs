@@ -0,0 +1,409 @@
+/*
+ * Trace filters
+ *
+ * Filters keep the I/O history of crash reports and the event log to what matters. They are checked as port
+ * accesses and events are captured, so what doesn't match takes no room in either, e.g.
+ *
+ *   port in 0x1f0..0x1f7 and dir == write
+ *   event == pic_raise and vector == 8 or event == irq_assert and irq == 0
+ *
+ * A filter is predicates joined by "and" and "or", "and" binding tighter. Predicates compare a field with
+ * "==" or "!=", or take a number field to be "in" a first..last range. Numbers are hex with 0x prefix or
+ * decimal. Fields are
+ *
+ *   event                  event kind as in event log, "io" for port accesses
+ *   port dir size value    port accesses, dir is read or write
+ *   irq                    IRQ line assertions
+ *   vector                 PIC and injection events
+ *   kind                   injected event kind: exception, nmi or external
+ *   device                 device events, "pic" for PIC events
+ *   id                     IRQ assertion id, see eventlog.rs
+ *
+ * Events without a field match no predicate on it, "!=" included.
+ */
+
+use vm;
+use config;
+use monitor;
+use eventlog::{self, Event};
+use crash::IoAccess;
+use breakpoint::IoDirection;
+
+use std::fmt;
+
+/**
+ * What a predicate looks at
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Field
+{
+    Event,
+    Port,
+    Dir,
+    Size,
+    Value,
+    Irq,
+    Vector,
+    Kind,
+    Device,
+    Id,
+}
+
+// Field names and whether fields are words rather than numbers
+const FIELDS: [(&'static str, Field, bool); 10] = [
+    ("event", Field::Event, true),
+    ("port", Field::Port, false),
+    ("dir", Field::Dir, true),
+    ("size", Field::Size, false),
+    ("value", Field::Value, false),
+    ("irq", Field::Irq, false),
+    ("vector", Field::Vector, false),
+    ("kind", Field::Kind, true),
+    ("device", Field::Device, true),
+    ("id", Field::Id, false),
+];
+
+/**
+ * Field value of a port access or event
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FieldValue
+{
+    Number(u64),
+    Word(&'static str),
+}
+
+/**
+ * Port access or event filters look at
+ */
+pub trait filter_target
+{
+    /**
+     * Value of field, None if there is no such field
+     */
+    fn field(&self, field: Field) -> Option<FieldValue>;
+}
+
+impl filter_target for IoAccess
+{
+    fn field(&self, field: Field) -> Option<FieldValue> {
+        match field {
+            Field::Event => Some(FieldValue::Word("io")),
+            Field::Port => Some(FieldValue::Number(self.port as u64)),
+            Field::Dir => Some(FieldValue::Word(if self.direction == IoDirection::Read { "read" } else { "write" })),
+            Field::Size => Some(FieldValue::Number(match self.value {
+                vm::IoOperandType::byte(_) => 1,
+                vm::IoOperandType::word(_) => 2,
+                vm::IoOperandType::dword(_) => 4,
+            })),
+            Field::Value => Some(FieldValue::Number(match self.value {
+                vm::IoOperandType::byte(v) => v as u64,
+                vm::IoOperandType::word(v) => v as u64,
+                vm::IoOperandType::dword(v) => v as u64,
+            })),
+            _ => None,
+        }
+    }
+}
+
+impl filter_target for Event
+{
+    fn field(&self, field: Field) -> Option<FieldValue> {
+        match (self, field) {
+            (&Event::Io(ref access), _) => access.field(field),
+            (_, Field::Event) => Some(FieldValue::Word(self.kind())),
+            (_, Field::Id) => self.irq_id().map(FieldValue::Number),
+            (&Event::IrqAssert { irq, .. }, Field::Irq) => Some(FieldValue::Number(irq as u64)),
+            (&Event::PicRaise { vector, .. }, Field::Vector) | (&Event::PicAck { vector, .. }, Field::Vector) |
+            (&Event::PicEoi { vector, .. }, Field::Vector) | (&Event::Inject { vector, .. }, Field::Vector) => {
+                Some(FieldValue::Number(vector as u64))
+            },
+            (&Event::PicRaise { .. }, Field::Device) | (&Event::PicAck { .. }, Field::Device) |
+            (&Event::PicEoi { .. }, Field::Device) => Some(FieldValue::Word("pic")),
+            (&Event::Inject { kind, .. }, Field::Kind) => Some(FieldValue::Word(kind)),
+            (&Event::Device { device, .. }, Field::Device) => Some(FieldValue::Word(device)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Operand
+{
+    Number(u64),
+    Word(String),
+}
+
+impl Operand
+{
+    fn equals(&self, val: FieldValue) -> bool {
+        match (self, val) {
+            (&Operand::Number(n), FieldValue::Number(val)) => n == val,
+            (&Operand::Word(ref word), FieldValue::Word(val)) => word == val,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Test
+{
+    Equal(Operand),
+    NotEqual(Operand),
+    In(u64, u64),               // First and last
+}
+
+#[derive(Clone, PartialEq, Debug)]
+struct Predicate
+{
+    field: Field,
+    test: Test,
+}
+
+impl Predicate
+{
+    fn matches(&self, target: &filter_target) -> bool {
+        let val = match target.field(self.field) {
+            Some(val) => val,
+            None => return false,
+        };
+
+        match self.test {
+            Test::Equal(ref operand) => operand.equals(val),
+            Test::NotEqual(ref operand) => !operand.equals(val),
+            Test::In(first, last) => match val {
+                FieldValue::Number(n) => first <= n && n <= last,
+                FieldValue::Word(_) => false,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Predicate
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = FIELDS.iter().find(|&&(_, field, _)| field == self.field).unwrap().0;
+        let operand = |operand: &Operand| match *operand {
+            Operand::Number(n) => format!("0x{:x}", n),
+            Operand::Word(ref word) => word.clone(),
+        };
+
+        match self.test {
+            Test::Equal(ref val) => write!(f, "{} == {}", name, operand(val)),
+            Test::NotEqual(ref val) => write!(f, "{} != {}", name, operand(val)),
+            Test::In(first, last) => write!(f, "{} in 0x{:x}..0x{:x}", name, first, last),
+        }
+    }
+}
+
+/* Predicate of field, operator and value tokens */
+fn parse_predicate(name: &str, op: &str, val: &str) -> Result<Predicate, String>
+{
+    let (field, is_word) = match FIELDS.iter().find(|&&(field_name, _, _)| field_name == name) {
+        Some(&(_, field, is_word)) => (field, is_word),
+        None => return Err(format!("Unknown filter field {}", name)),
+    };
+
+    let operand = || if is_word {
+        Ok(Operand::Word(String::from(val)))
+    } else {
+        monitor::parse_number(val).map(Operand::Number)
+    };
+
+    let test = match op {
+        "==" => Test::Equal(try!(operand())),
+        "!=" => Test::NotEqual(try!(operand())),
+        "in" if !is_word => {
+            let mut bounds = val.splitn(2, "..");
+            match (bounds.next().map(monitor::parse_number), bounds.next().map(monitor::parse_number)) {
+                (Some(Ok(first)), Some(Ok(last))) if first <= last => Test::In(first, last),
+                _ => return Err(format!("Bad range {}, expected first..last", val)),
+            }
+        },
+        "in" => return Err(format!("Field {} takes no range", name)),
+        _ => return Err(format!("Expected ==, != or in after {}", name)),
+    };
+
+    Ok(Predicate { field: field, test: test })
+}
+
+/**
+ * Predicates port accesses or events are captured by
+ */
+#[derive(Clone, PartialEq, Debug)]
+pub struct TraceFilter
+{
+    any: Vec<Vec<Predicate>>,   // Alternatives joined by "or", each all predicates joined by "and"
+}
+
+impl TraceFilter
+{
+    /**
+     * Filter of expression, see module doc for its syntax
+     */
+    pub fn parse(text: &str) -> Result<TraceFilter, String> {
+        let spaced = text.replace("==", " == ").replace("!=", " != ");
+        let tokens: Vec<&str> = spaced.split_whitespace().collect();
+        if tokens.is_empty() {
+            return Err(String::from("Empty filter"));
+        }
+
+        let mut any = vec![Vec::new()];
+        let mut pos = 0;
+        loop {
+            if pos + 3 > tokens.len() {
+                return Err(format!("Incomplete filter predicate \"{}\"", tokens[pos..].join(" ")));
+            }
+            any.last_mut().unwrap().push(try!(parse_predicate(tokens[pos], tokens[pos + 1], tokens[pos + 2])));
+            pos += 3;
+
+            match tokens.get(pos) {
+                None => break,
+                Some(&"and") => {},
+                Some(&"or") => any.push(Vec::new()),
+                Some(token) => return Err(format!("Expected and or or instead of {}", token)),
+            }
+            pos += 1;
+        }
+
+        Ok(TraceFilter { any: any })
+    }
+
+    /**
+     * Port access or event is captured
+     */
+    pub fn matches(&self, target: &filter_target) -> bool {
+        self.any.iter().any(|all| all.iter().all(|predicate| predicate.matches(target)))
+    }
+}
+
+impl fmt::Display for TraceFilter
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let any: Vec<String> = self.any.iter().map(|all| {
+            all.iter().map(|predicate| predicate.to_string()).collect::<Vec<_>>().join(" and ")
+        }).collect();
+        write!(f, "{}", any.join(" or "))
+    }
+}
+
+#[cfg(test)]
+mod tracefilter_test
+{
+    use super::*;
+
+    fn io(port: u16, direction: IoDirection, value: vm::IoOperandType) -> IoAccess {
+        IoAccess { port: port, direction: direction, value: value }
+    }
+
+    #[test] fn predicates() {
+        let write = io(0x1F3, IoDirection::Write, vm::IoOperandType::byte(0x10));
+        let read = io(0x1F0, IoDirection::Read, vm::IoOperandType::word(0xBEEF));
+
+        let ata_writes = TraceFilter::parse("port in 0x1f0..0x1f7 and dir == write").unwrap();
+        assert!(ata_writes.matches(&write) && !ata_writes.matches(&read));
+        assert!(!ata_writes.matches(&io(0x1F8, IoDirection::Write, vm::IoOperandType::byte(0))));
+
+        assert!(TraceFilter::parse("size==2").unwrap().matches(&read));
+        assert!(TraceFilter::parse("value != 0xbeef").unwrap().matches(&write));
+        assert!(!TraceFilter::parse("value != 0xbeef").unwrap().matches(&read));
+
+        /* Events lack most fields, which fail any test */
+        let raise = Event::PicRaise { vector: 8, id: Some(3) };
+        assert!(TraceFilter::parse("device == pic and vector == 8 and id == 3").unwrap().matches(&raise));
+        assert!(!TraceFilter::parse("port != 0x20").unwrap().matches(&raise));
+        assert!(!TraceFilter::parse("irq in 0..15").unwrap().matches(&Event::VmStart));
+        assert!(TraceFilter::parse("event == io").unwrap().matches(&Event::Io(write)));
+
+        /* And binds tighter than or */
+        let filter = TraceFilter::parse("event == vm_start or event == inject and kind == nmi").unwrap();
+        assert!(filter.matches(&Event::VmStart));
+        assert!(filter.matches(&Event::Inject { kind: "nmi", vector: 2, id: None }));
+        assert!(!filter.matches(&Event::Inject { kind: "external", vector: 8, id: None }));
+    }
+
+    #[test] fn parse() {
+        let filter = TraceFilter::parse("  port in 496..0x1F7 and dir==write or  vector != 8 ").unwrap();
+        assert!(filter.to_string() == "port in 0x1f0..0x1f7 and dir == write or vector != 0x8");
+        assert!(TraceFilter::parse(&filter.to_string()) == Ok(filter));
+
+        assert!(TraceFilter::parse("") == Err(String::from("Empty filter")));
+        assert!(TraceFilter::parse("address == 5") == Err(String::from("Unknown filter field address")));
+        assert!(TraceFilter::parse("port < 5") == Err(String::from("Expected ==, != or in after port")));
+        assert!(TraceFilter::parse("port == write").is_err());
+        assert!(TraceFilter::parse("dir in 0..1") == Err(String::from("Field dir takes no range")));
+        assert!(TraceFilter::parse("port in 0x1f7..0x1f0") == Err(String::from("Bad range 0x1f7..0x1f0, expected first..last")));
+        assert!(TraceFilter::parse("port in 0x1f0").is_err());
+        assert!(TraceFilter::parse("port == 1 and") == Err(String::from("Incomplete filter predicate \"\"")));
+        assert!(TraceFilter::parse("port == 1 but irq == 0") == Err(String::from("Expected and or or instead of but")));
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+fn describe(filter: Option<TraceFilter>) -> String
+{
+    filter.map_or(String::from("none"), |filter| filter.to_string())
+}
+
+/* Filter of command arguments, None for "off" */
+fn parse_args(args: &[&str]) -> Result<Option<TraceFilter>, String>
+{
+    if args.is_empty() {
+        return Err(String::from("Expected filter or off"));
+    }
+    if args == ["off"] {
+        return Ok(None);
+    }
+    TraceFilter::parse(&args.join(" ")).map(Some)
+}
+
+fn cmd_filter_io(_: &mut monitor::MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    vm::set_io_filter(try!(parse_args(args)));
+    Ok(String::new())
+}
+
+fn cmd_filter_events(_: &mut monitor::MonitorContext, args: &[&str]) -> Result<String, String>
+{
+    try!(eventlog::set_filter(try!(parse_args(args))));
+    Ok(String::new())
+}
+
+fn cmd_info_filters(_: &mut monitor::MonitorContext, _: &[&str]) -> Result<String, String>
+{
+    Ok(format!("io: {}\nevents: {}", describe(vm::io_filter()), describe(eventlog::filter())))
+}
+
+/**
+ * Add filter monitor commands and set filters configured, after event log is open
+ */
+pub fn init(config: &config::VmConfig)
+{
+    monitor::register_command(monitor::MonitorCommand {
+        name: "filter io",
+        args: "expr|off",
+        help: "keep only port accesses matching filter in I/O history",
+        handler: cmd_filter_io,
+    });
+    monitor::register_command(monitor::MonitorCommand {
+        name: "filter events",
+        args: "expr|off",
+        help: "log only events matching filter",
+        handler: cmd_filter_events,
+    });
+    monitor::register_command(monitor::MonitorCommand {
+        name: "info filters",
+        args: "",
+        help: "show I/O history and event log filters",
+        handler: cmd_info_filters,
+    });
+
+    vm::set_io_filter(config.io_filter.clone());
+    if let Some(ref filter) = config.event_filter {
+        if let Err(err) = eventlog::set_filter(Some(filter.clone())) {
+            error!("{}", err);
+            ::std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,389 @@
+/*
+ * Guest time stamp counter
+ *
+ * Guest TSC starts at zero when VM boots and goes back to zero on platform reset. Two modes:
+ *
+ *   offset     Guest RDTSC and RDTSCP run natively and see host TSC plus VMCS TSC offset. Offset is moved
+ *              when vcpu comes back from a host pause, so time spent parked doesn't show up in guest.
+ *   exiting    RDTSC and RDTSCP exit and VMM returns ticks of virtual time at TSC_VIRTUAL_FREQ_HZ, same
+ *              guest execution always sees same values.
+ *
+ * IA32_TSC accesses exit in both modes and read the same counter the instructions do, writes move it.
+ * IA32_TSC_AUX returned by RDTSCP is native in offset mode and kept here in exiting mode.
+ */
+
+use vm;
+use config::{self, TscMode};
+use clock::{self, virtual_clock, VcpuClock};
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::mem;
+
+pub const MSR_IA32_TSC: u32         = 0x10;
+pub const MSR_IA32_TSC_AUX: u32     = 0xC0000103;
+
+/* Exiting mode counter rate, one tick per virtual nanosecond */
+const TSC_VIRTUAL_FREQ_HZ: u64      = 1000000000;
+
+struct Tsc
+{
+    mode: TscMode,
+    clock: Rc<virtual_clock>,
+    host_tsc: fn() -> u64,
+    offset: u64,                // Guest TSC minus its source, wrapping
+    frozen: Option<u64>,        // Guest TSC while vcpu is parked, offset mode only
+    aux: u32,                   // IA32_TSC_AUX, exiting mode only
+}
+
+impl Tsc
+{
+    fn new(mode: TscMode, clock: Rc<virtual_clock>, host_tsc: fn() -> u64) -> Tsc {
+        let mut tsc = Tsc {
+            mode: mode,
+            clock: clock,
+            host_tsc: host_tsc,
+            offset: 0,
+            frozen: None,
+            aux: 0,
+        };
+
+        tsc.write(0);
+        tsc
+    }
+
+    /* Counter guest TSC follows */
+    fn source(&self) -> u64 {
+        match self.mode {
+            TscMode::Offset => (self.host_tsc)(),
+            TscMode::Exiting => clock::ns_to_ticks(self.clock.now_ns(), TSC_VIRTUAL_FREQ_HZ),
+        }
+    }
+
+    fn read(&self) -> u64 {
+        match self.frozen {
+            Some(val) => val,
+            None => self.source().wrapping_add(self.offset),
+        }
+    }
+
+    fn write(&mut self, val: u64) {
+        if self.frozen.is_some() {
+            self.frozen = Some(val);
+        } else {
+            self.offset = val.wrapping_sub(self.source());
+        }
+    }
+
+    /* Stop guest TSC while vcpu is parked, virtual time already stops by itself */
+    fn freeze(&mut self) {
+        if self.mode == TscMode::Offset && self.frozen.is_none() {
+            self.frozen = Some(self.read());
+        }
+    }
+
+    /* Continue from the frozen value, true if offset changed */
+    fn thaw(&mut self) -> bool {
+        match self.frozen.take() {
+            Some(val) => {
+                self.write(val);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.write(0);
+        self.aux = 0;
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct TscDev
+{
+    tsc: RefCell<Tsc>,
+    set_offset: fn(u64),
+}
+
+impl TscDev
+{
+    /* Hand current offset to hardware, exiting mode reads never use it */
+    fn sync_offset(&self, tsc: &Tsc) {
+        if tsc.mode == TscMode::Offset && tsc.frozen.is_none() {
+            (self.set_offset)(tsc.offset);
+        }
+    }
+
+    fn set(&self, val: u64) {
+        let mut tsc = self.tsc.borrow_mut();
+        tsc.write(val);
+        self.sync_offset(&tsc);
+    }
+
+    fn read_msr(&self, msr: u32) -> Option<u64> {
+        let tsc = self.tsc.borrow();
+        match msr {
+            MSR_IA32_TSC => Some(tsc.read()),
+            MSR_IA32_TSC_AUX => Some(tsc.aux as u64),
+            _ => None,
+        }
+    }
+
+    fn write_msr(&self, msr: u32, val: u64) -> bool {
+        match msr {
+            MSR_IA32_TSC => self.set(val),
+            MSR_IA32_TSC_AUX => self.tsc.borrow_mut().aux = val as u32,
+            _ => return false,
+        }
+
+        true
+    }
+}
+
+impl vm::pause_handler for TscDev
+{
+    fn paused(&self)
+    {
+        self.tsc.borrow_mut().freeze();
+    }
+
+    fn resumed(&self)
+    {
+        let mut tsc = self.tsc.borrow_mut();
+        if tsc.thaw() {
+            self.sync_offset(&tsc);
+        }
+    }
+}
+
+impl vm::reset_handler for TscDev
+{
+    fn reset(&self)
+    {
+        let mut tsc = self.tsc.borrow_mut();
+        tsc.reset();
+        self.sync_offset(&tsc);
+    }
+}
+
+#[cfg(test)]
+mod tsc_test
+{
+    use super::*;
+    use vm::{pause_handler, reset_handler};
+    use clock::MockClock;
+    use std::cell::Cell;
+    use std::thread;
+    use std::time::Duration;
+
+    thread_local! {
+        static HOST_TSC: Cell<u64> = Cell::new(0);
+        static OFFSET: Cell<Option<u64>> = Cell::new(None);
+    }
+
+    fn fake_host_tsc() -> u64 {
+        HOST_TSC.with(|tsc| tsc.get())
+    }
+
+    fn advance_host_tsc(ticks: u64) {
+        HOST_TSC.with(|tsc| tsc.set(tsc.get() + ticks));
+    }
+
+    fn no_host_tsc() -> u64 {
+        panic!("exiting mode read host TSC");
+    }
+
+    fn record_offset(offset: u64) {
+        OFFSET.with(|cell| cell.set(Some(offset)));
+    }
+
+    fn take_offset() -> Option<u64> {
+        OFFSET.with(|cell| cell.take())
+    }
+
+    fn make_dev(mode: TscMode, clock: &Rc<MockClock>, host_tsc: fn() -> u64) -> TscDev {
+        let dev = TscDev {
+            tsc: RefCell::new(Tsc::new(mode, clock.clone(), host_tsc)),
+            set_offset: record_offset,
+        };
+
+        take_offset();
+        dev
+    }
+
+    #[test] fn exiting_mode() {
+        let clock = Rc::new(MockClock::new());
+        clock.advance_ns(5000000);
+        let dev = make_dev(TscMode::Exiting, &clock, no_host_tsc);
+
+        /* Two RDTSC exits around 1.5 ms of virtual time */
+        let first = dev.tsc.borrow().read();
+        clock.advance_ns(1500000);
+        let second = dev.tsc.borrow().read();
+        assert!(first == 0 && second - first == 1500000);
+
+        /* Pause doesn't touch it, virtual time doesn't run while parked */
+        dev.paused();
+        dev.resumed();
+        assert!(dev.tsc.borrow().read() == second);
+
+        /* MSR reads agree with RDTSC, writes move the counter */
+        assert!(dev.read_msr(MSR_IA32_TSC) == Some(second));
+        assert!(dev.write_msr(MSR_IA32_TSC, 0x100000000));
+        clock.advance_ns(10);
+        assert!(dev.read_msr(MSR_IA32_TSC) == Some(0x10000000A));
+
+        assert!(dev.write_msr(MSR_IA32_TSC_AUX, 0x1234567800000003));
+        assert!(dev.read_msr(MSR_IA32_TSC_AUX) == Some(3));
+        assert!(dev.read_msr(0x1B).is_none() && !dev.write_msr(0x1B, 0));
+
+        /* Hardware offset is never programmed */
+        assert!(take_offset().is_none());
+
+        dev.reset();
+        assert!(dev.tsc.borrow().read() == 0 && dev.read_msr(MSR_IA32_TSC_AUX) == Some(0));
+    }
+
+    #[test] fn offset_mode() {
+        let clock = Rc::new(MockClock::new());
+        HOST_TSC.with(|tsc| tsc.set(1000000));
+        let dev = make_dev(TscMode::Offset, &clock, fake_host_tsc);
+
+        /* Starts at zero and runs with host TSC */
+        assert!(dev.tsc.borrow().read() == 0);
+        advance_host_tsc(300);
+        assert!(dev.read_msr(MSR_IA32_TSC) == Some(300));
+
+        /* Time spent paused is left out, hardware gets the new offset */
+        dev.paused();
+        advance_host_tsc(5000000);
+        assert!(dev.read_msr(MSR_IA32_TSC) == Some(300));
+        dev.resumed();
+        let offset = take_offset().unwrap();
+        assert!(fake_host_tsc().wrapping_add(offset) == 300);
+        advance_host_tsc(20);
+        assert!(dev.tsc.borrow().read() == 320);
+
+        /* Value set while paused, e.g. restored state, is where guest continues */
+        dev.paused();
+        dev.set(0x5000);
+        assert!(take_offset().is_none());
+        advance_host_tsc(777);
+        dev.resumed();
+        assert!(fake_host_tsc().wrapping_add(take_offset().unwrap()) == 0x5000);
+
+        /* Guest write and reset */
+        assert!(dev.write_msr(MSR_IA32_TSC, 10));
+        assert!(fake_host_tsc().wrapping_add(take_offset().unwrap()) == 10);
+        dev.reset();
+        assert!(fake_host_tsc().wrapping_add(take_offset().unwrap()) == 0);
+    }
+
+    #[test] fn offset_mode_pause_wall_clock() {
+        let clock = Rc::new(MockClock::new());
+        let dev = make_dev(TscMode::Offset, &clock, host_tsc);
+
+        let before = dev.tsc.borrow().read();
+        let host_before = host_tsc();
+        dev.paused();
+        thread::sleep(Duration::from_millis(50));
+        dev.resumed();
+        let host_paused = host_tsc() - host_before;
+        let after = dev.tsc.borrow().read();
+
+        assert!(after >= before && after - before < host_paused / 10);
+        assert!(take_offset().is_some());
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+static mut TSC_DEV: Option<*const TscDev> = None;
+
+fn get_tsc() -> &'static TscDev
+{
+    unsafe {
+        mem::transmute(TSC_DEV.unwrap())
+    }
+}
+
+fn host_tsc() -> u64
+{
+    unsafe {
+        ::std::arch::x86_64::_rdtsc()
+    }
+}
+
+/**
+ * Guest TSC value for RDTSC and RDTSCP exits, vcpu thread only
+ */
+pub fn read_tsc() -> u64
+{
+    get_tsc().tsc.borrow().read()
+}
+
+/**
+ * IA32_TSC_AUX value for RDTSCP exits, vcpu thread only
+ */
+pub fn read_tsc_aux() -> u32
+{
+    get_tsc().tsc.borrow().aux
+}
+
+/**
+ * Handle RDMSR exit, None if MSR is not a TSC one
+ */
+pub fn read_msr(msr: u32) -> Option<u64>
+{
+    get_tsc().read_msr(msr)
+}
+
+/**
+ * Handle WRMSR exit, false if MSR is not a TSC one
+ */
+pub fn write_msr(msr: u32, val: u64) -> bool
+{
+    get_tsc().write_msr(msr, val)
+}
+
+/**
+ * Guest TSC as of now, for saving VM state from a host thread
+ */
+pub fn guest_tsc() -> u64
+{
+    let _pause = vm::pause();
+    read_tsc()
+}
+
+/**
+ * Set guest TSC from a host thread, guest continues counting from val, e.g. after restoring saved state
+ */
+pub fn set_guest_tsc(val: u64)
+{
+    let _pause = vm::pause();
+    get_tsc().set(val);
+}
+
+pub fn init(config: &config::VmConfig)
+{
+    let dev = Rc::new(TscDev {
+        tsc: RefCell::new(Tsc::new(config.tsc_mode, Rc::new(VcpuClock), host_tsc)),
+        set_offset: vm::set_tsc_offset,
+    });
+
+    /* Host TSC can't be scaled, so guest time has to stay at host rate */
+    if config.tsc_mode == TscMode::Offset {
+        clock::fix_time_dilation().unwrap();
+        vm::enable_native_msr(MSR_IA32_TSC_AUX, true);
+    }
+    dev.sync_offset(&dev.tsc.borrow());
+
+    vm::register_pause_handler(dev.clone());
+    vm::register_reset_handler(dev.clone());
+
+    unsafe {
+        TSC_DEV = Some(&*dev as *const TscDev);
+    }
+}
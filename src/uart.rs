@@ -0,0 +1,885 @@
+/*
+ * 16550A UART on COM1 at 0x3F8-0x3FF
+ *
+ * Transmitted bytes go to the serial backend immediately, so transmitter is always empty.
+ * Received bytes are taken from backend by a periodic poll at roughly the programmed line rate,
+ * so a guest that doesn't drain its FIFO sees overruns as it would on real hardware.
+ * Modem status inputs come from the backend, or from modem control outputs in loopback mode. Their changes
+ * are latched in MSR delta bits until MSR is read.
+ */
+
+use vm;
+use config;
+use event;
+use summary;
+use serial::{self, serial_backend};
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::mem;
+
+pub const COM1_BASE: u16        = 0x3F8;
+const COM1_IRQ: u8              = 4;
+
+// Register offsets from port base
+const UART_RBR_THR: u16         = 0;    // Receive buffer / transmit holding, divisor latch low with DLAB
+const UART_IER: u16             = 1;    // Interrupt enable, divisor latch high with DLAB
+const UART_IIR_FCR: u16         = 2;    // Interrupt identification / FIFO control
+const UART_LCR: u16             = 3;
+const UART_MCR: u16             = 4;
+const UART_LSR: u16             = 5;
+const UART_MSR: u16             = 6;
+const UART_SCR: u16             = 7;
+const UART_PORTS: u16           = 8;
+
+// Interrupt enable bits
+const UART_IER_RDA: u8          = 0x01; // Received data available
+const UART_IER_THRE: u8         = 0x02; // Transmit holding register empty
+const UART_IER_RLS: u8          = 0x04; // Receiver line status
+const UART_IER_MSI: u8          = 0x08; // Modem status
+const UART_IER_MASK: u8         = 0x0F;
+
+// Interrupt identification values, highest priority first
+const UART_IIR_NONE: u8         = 0x01;
+const UART_IIR_RLS: u8          = 0x06;
+const UART_IIR_RDA: u8          = 0x04;
+const UART_IIR_TIMEOUT: u8      = 0x0C;
+const UART_IIR_THRE: u8         = 0x02;
+const UART_IIR_MSI: u8          = 0x00;
+const UART_IIR_FIFO: u8         = 0xC0; // FIFOs enabled
+
+// FIFO control bits
+const UART_FCR_ENABLE: u8       = 0x01;
+const UART_FCR_CLEAR_RX: u8     = 0x02;
+
+const UART_FIFO_SIZE: usize     = 16;
+
+// Line control bits
+const UART_LCR_DLAB: u8         = 0x80;
+
+// Modem control bits
+const UART_MCR_DTR: u8          = 0x01;
+const UART_MCR_RTS: u8          = 0x02;
+const UART_MCR_OUT1: u8         = 0x04;
+const UART_MCR_OUT2: u8         = 0x08; // Gates interrupt line on PC
+const UART_MCR_LOOP: u8         = 0x10;
+const UART_MCR_MASK: u8         = 0x1F;
+
+// Line status bits
+const UART_LSR_DR: u8           = 0x01; // Data ready
+const UART_LSR_OE: u8           = 0x02; // Overrun error
+const UART_LSR_THRE: u8         = 0x20;
+const UART_LSR_TEMT: u8         = 0x40;
+
+// Modem status bits
+const UART_MSR_DCTS: u8         = 0x01; // CTS changed
+const UART_MSR_DDSR: u8         = 0x02; // DSR changed
+const UART_MSR_TERI: u8         = 0x04; // RI went inactive
+const UART_MSR_DDCD: u8         = 0x08; // DCD changed
+const UART_MSR_CTS: u8          = 0x10;
+const UART_MSR_DSR: u8          = 0x20;
+const UART_MSR_RI: u8           = 0x40;
+const UART_MSR_DCD: u8          = 0x80;
+const UART_MSR_LINES: u8        = UART_MSR_CTS | UART_MSR_DSR | UART_MSR_RI | UART_MSR_DCD;
+
+const UART_CLOCK_HZ: u32        = 115200;   // Baud rate with divisor of 1
+const UART_DEFAULT_DIVISOR: u16 = 12;       // 9600 baud
+const UART_POLL_PERIOD_US: u64  = 1000;
+
+/*
+ * UART state
+ */
+struct UART
+{
+    divisor: u16,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    lsr_errors: u8,         // Error bits cleared by LSR read
+    msr_lines: u8,          // Modem status inputs as last sampled
+    msr_deltas: u8,         // Delta bits cleared by MSR read
+    fifo_enabled: bool,
+    rx_trigger: usize,      // RX FIFO level that raises data available interrupt
+    rx: VecDeque<u8>,
+    rx_timeout: bool,       // Data sits below trigger level with no new input
+    thre_pending: bool,     // THR empty interrupt not yet acknowledged
+    irq_line: bool,         // Interrupt output level
+    irq: bool,              // Interrupt line had a rising edge
+    backend: Box<serial_backend>,
+    rx_bytes: u64,          // Received bytes guest read, for run summary
+    tx_bytes: u64,          // Bytes guest transmitted
+}
+
+impl UART
+{
+    fn new(backend: Box<serial_backend>) -> UART {
+        UART {
+            divisor: UART_DEFAULT_DIVISOR,
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            lsr_errors: 0,
+            msr_lines: backend.modem_lines() & UART_MSR_LINES,
+            msr_deltas: 0,
+            fifo_enabled: false,
+            rx_trigger: 1,
+            rx: VecDeque::new(),
+            rx_timeout: false,
+            thre_pending: false,
+            irq_line: false,
+            irq: false,
+            backend: backend,
+            rx_bytes: 0,
+            tx_bytes: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.divisor = UART_DEFAULT_DIVISOR;
+        self.ier = 0;
+        self.lcr = 0;
+        self.mcr = 0;
+        self.scr = 0;
+        self.lsr_errors = 0;
+        self.fifo_enabled = false;
+        self.rx_trigger = 1;
+        self.rx.clear();
+        self.rx_timeout = false;
+        self.thre_pending = false;
+        self.irq_line = false;
+        self.irq = false;
+        self.msr_lines = self.backend.modem_lines() & UART_MSR_LINES;
+        self.msr_deltas = 0;
+        self.update_flow_control();
+    }
+
+    /**
+     * Take pending interrupt request
+     */
+    fn take_irq(&mut self) -> bool {
+        let irq = self.irq;
+        self.irq = false;
+        irq
+    }
+
+    fn rx_capacity(&self) -> usize {
+        if self.fifo_enabled { UART_FIFO_SIZE } else { 1 }
+    }
+
+    /* Highest priority pending interrupt */
+    fn interrupt_id(&self) -> u8 {
+        if (self.ier & UART_IER_RLS) != 0 && self.lsr_errors != 0 {
+            UART_IIR_RLS
+        } else if (self.ier & UART_IER_RDA) != 0 && self.rx.len() >= self.rx_trigger {
+            UART_IIR_RDA
+        } else if (self.ier & UART_IER_RDA) != 0 && self.rx_timeout && !self.rx.is_empty() {
+            UART_IIR_TIMEOUT
+        } else if (self.ier & UART_IER_THRE) != 0 && self.thre_pending {
+            UART_IIR_THRE
+        } else if (self.ier & UART_IER_MSI) != 0 && self.msr_deltas != 0 {
+            UART_IIR_MSI
+        } else {
+            UART_IIR_NONE
+        }
+    }
+
+    /* Recompute interrupt output, OUT2 gates it to the interrupt controller */
+    fn update_irq(&mut self) {
+        let line = self.interrupt_id() != UART_IIR_NONE && (self.mcr & UART_MCR_OUT2) != 0;
+        if line && !self.irq_line {
+            self.irq = true;
+        }
+        self.irq_line = line;
+    }
+
+    /* Backend should only send while guest asserts both DTR and RTS */
+    fn update_flow_control(&mut self) {
+        let ready = (self.mcr & (UART_MCR_DTR | UART_MCR_RTS)) == (UART_MCR_DTR | UART_MCR_RTS)
+                    && (self.mcr & UART_MCR_LOOP) == 0;
+        self.backend.set_ready(ready);
+    }
+
+    /* Modem status inputs, loopback wires modem control outputs to them */
+    fn modem_lines(&self) -> u8 {
+        if (self.mcr & UART_MCR_LOOP) == 0 {
+            return self.backend.modem_lines() & UART_MSR_LINES;
+        }
+
+        let mut lines = 0;
+        if (self.mcr & UART_MCR_RTS) != 0 {
+            lines |= UART_MSR_CTS;
+        }
+        if (self.mcr & UART_MCR_DTR) != 0 {
+            lines |= UART_MSR_DSR;
+        }
+        if (self.mcr & UART_MCR_OUT1) != 0 {
+            lines |= UART_MSR_RI;
+        }
+        if (self.mcr & UART_MCR_OUT2) != 0 {
+            lines |= UART_MSR_DCD;
+        }
+        lines
+    }
+
+    /* Sample modem status inputs and latch their changes, RI only counts when it goes inactive */
+    fn update_modem_status(&mut self) {
+        let lines = self.modem_lines();
+        let changed = lines ^ self.msr_lines;
+
+        if (changed & UART_MSR_CTS) != 0 {
+            self.msr_deltas |= UART_MSR_DCTS;
+        }
+        if (changed & UART_MSR_DSR) != 0 {
+            self.msr_deltas |= UART_MSR_DDSR;
+        }
+        if (changed & self.msr_lines & UART_MSR_RI) != 0 {
+            self.msr_deltas |= UART_MSR_TERI;
+        }
+        if (changed & UART_MSR_DCD) != 0 {
+            self.msr_deltas |= UART_MSR_DDCD;
+        }
+
+        self.msr_lines = lines;
+    }
+
+    /* Byte arrives on receiver, lost with overrun if there is no room */
+    fn receive(&mut self, val: u8) {
+        if self.rx.len() >= self.rx_capacity() {
+            self.lsr_errors |= UART_LSR_OE;
+        } else {
+            self.rx.push_back(val);
+        }
+        self.rx_timeout = false;
+    }
+
+    /* Number of characters the line carries in a poll period */
+    fn chars_per_poll(&self) -> usize {
+        let divisor = if self.divisor == 0 { 1 } else { self.divisor as u64 };
+        let baud = UART_CLOCK_HZ as u64 / divisor;
+        let chars = baud * UART_POLL_PERIOD_US / 10000000; // 10 bits per character
+        if chars == 0 { 1 } else { chars as usize }
+    }
+
+    /**
+     * Receive pending backend input, called once per poll period
+     */
+    fn poll(&mut self) {
+        let mut received = false;
+        if (self.mcr & UART_MCR_LOOP) == 0 {
+            for _ in 0..self.chars_per_poll() {
+                match self.backend.read() {
+                    Some(val) => {
+                        self.receive(val);
+                        received = true;
+                    },
+                    None => break,
+                }
+            }
+        }
+
+        /* A whole poll period without input is well past 4 character times */
+        if !received && self.fifo_enabled && !self.rx.is_empty() {
+            self.rx_timeout = true;
+        }
+
+        self.update_modem_status();
+        self.update_irq();
+    }
+
+    fn transmit(&mut self, val: u8) {
+        self.tx_bytes += 1;
+        if (self.mcr & UART_MCR_LOOP) != 0 {
+            self.receive(val);
+        } else {
+            self.backend.write(val);
+        }
+
+        /* Transmitter is done at once */
+        self.thre_pending = true;
+    }
+
+    fn read_lsr(&mut self) -> u8 {
+        let mut lsr = UART_LSR_THRE | UART_LSR_TEMT | self.lsr_errors;
+        if !self.rx.is_empty() {
+            lsr |= UART_LSR_DR;
+        }
+
+        self.lsr_errors = 0;
+        lsr
+    }
+
+    fn read_msr(&mut self) -> u8 {
+        self.update_modem_status();
+        let msr = self.msr_lines | self.msr_deltas;
+        self.msr_deltas = 0;
+        msr
+    }
+
+    fn read_iir(&mut self) -> u8 {
+        let id = self.interrupt_id();
+
+        /* Reading identification acknowledges THR empty interrupt */
+        if id == UART_IIR_THRE {
+            self.thre_pending = false;
+        }
+
+        if self.fifo_enabled { id | UART_IIR_FIFO } else { id }
+    }
+
+    fn write_fcr(&mut self, val: u8) {
+        let enable = (val & UART_FCR_ENABLE) != 0;
+        if enable != self.fifo_enabled || (val & UART_FCR_CLEAR_RX) != 0 {
+            self.rx.clear();
+            self.rx_timeout = false;
+        }
+
+        self.fifo_enabled = enable;
+        self.rx_trigger = if enable {
+            match val >> 6 {
+                0 => 1,
+                1 => 4,
+                2 => 8,
+                _ => 14,
+            }
+        } else {
+            1
+        };
+
+        /* TX FIFO is always empty, so clearing it has nothing to do */
+    }
+
+    fn read_port(&mut self, offset: u16) -> u8 {
+        let dlab = (self.lcr & UART_LCR_DLAB) != 0;
+
+        let val = match offset {
+            UART_RBR_THR if dlab => self.divisor as u8,
+            UART_RBR_THR => {
+                self.rx_timeout = false;
+                match self.rx.pop_front() {
+                    Some(val) => {
+                        self.rx_bytes += 1;
+                        val
+                    },
+                    None => 0,
+                }
+            },
+            UART_IER if dlab => (self.divisor >> 8) as u8,
+            UART_IER => self.ier,
+            UART_IIR_FCR => self.read_iir(),
+            UART_LCR => self.lcr,
+            UART_MCR => self.mcr,
+            UART_LSR => self.read_lsr(),
+            UART_MSR => self.read_msr(),
+            UART_SCR => self.scr,
+            _ => panic!(),
+        };
+
+        self.update_irq();
+        val
+    }
+
+    fn write_port(&mut self, offset: u16, val: u8) {
+        let dlab = (self.lcr & UART_LCR_DLAB) != 0;
+
+        match offset {
+            UART_RBR_THR if dlab => self.divisor = (self.divisor & 0xFF00) | val as u16,
+            UART_RBR_THR => self.transmit(val),
+            UART_IER if dlab => self.divisor = (self.divisor & 0x00FF) | ((val as u16) << 8),
+            UART_IER => {
+                /* Enabling THR empty interrupt with empty THR raises it right away */
+                if (val & UART_IER_THRE) != 0 && (self.ier & UART_IER_THRE) == 0 {
+                    self.thre_pending = true;
+                }
+                self.ier = val & UART_IER_MASK;
+            },
+            UART_IIR_FCR => self.write_fcr(val),
+            UART_LCR => self.lcr = val,
+            UART_MCR => {
+                self.mcr = val & UART_MCR_MASK;
+                self.update_flow_control();
+                self.update_modem_status();
+            },
+            UART_LSR | UART_MSR => (),
+            UART_SCR => self.scr = val,
+            _ => panic!(),
+        }
+
+        self.update_irq();
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct UARTDev
+{
+    uart: RefCell<UART>,
+    base: u16,
+    irq: u8,
+    assert_irq: fn(u8),
+}
+
+impl UARTDev
+{
+    fn poll(&self) {
+        let irq = {
+            let mut uart = self.uart.borrow_mut();
+            uart.poll();
+            uart.take_irq()
+        };
+
+        if irq {
+            (self.assert_irq)(self.irq);
+        }
+    }
+}
+
+impl vm::io_handler for UARTDev
+{
+    fn io_read(&self, port: u16, size: u8) -> vm::IoOperandType
+    {
+        assert!(size == 1);
+
+        let (val, irq) = {
+            let mut uart = self.uart.borrow_mut();
+            let val = uart.read_port(port - self.base);
+            (val, uart.take_irq())
+        };
+
+        if irq {
+            (self.assert_irq)(self.irq);
+        }
+
+        vm::IoOperandType::byte(val)
+    }
+
+    fn io_write(&self, port: u16, data: vm::IoOperandType)
+    {
+        let irq = {
+            let mut uart = self.uart.borrow_mut();
+            uart.write_port(port - self.base, data.unwrap_byte());
+            uart.take_irq()
+        };
+
+        if irq {
+            (self.assert_irq)(self.irq);
+        }
+    }
+}
+
+impl vm::stats_handler for UARTDev
+{
+    fn stats(&self) -> summary::DeviceStats
+    {
+        let uart = self.uart.borrow();
+        summary::DeviceStats { device: "uart", bytes_read: uart.rx_bytes, bytes_written: uart.tx_bytes }
+    }
+}
+
+impl vm::reset_handler for UARTDev
+{
+    fn reset(&self)
+    {
+        self.uart.borrow_mut().reset();
+    }
+}
+
+#[cfg(test)]
+mod uart_test
+{
+    use super::*;
+    use vm::pause_handler;
+    use clock::{virtual_clock, MockClock, ScaledClock};
+    use serial::TcpBackend;
+    use std::cell::Cell;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::Duration;
+
+    thread_local! {
+        static IRQ_COUNT: Cell<u32> = Cell::new(0);
+    }
+
+    fn count_irq(irq: u8) {
+        assert!(irq == COM1_IRQ);
+        IRQ_COUNT.with(|c| c.set(c.get() + 1));
+    }
+
+    fn irq_count() -> u32 {
+        IRQ_COUNT.with(|c| c.get())
+    }
+
+    /* Backend fed by the test directly */
+    struct TestBackend
+    {
+        input: Rc<RefCell<VecDeque<u8>>>,
+        output: Rc<RefCell<Vec<u8>>>,
+        ready: Rc<Cell<bool>>,
+    }
+
+    impl serial_backend for TestBackend
+    {
+        fn write(&mut self, val: u8) {
+            self.output.borrow_mut().push(val);
+        }
+
+        fn read(&mut self) -> Option<u8> {
+            if self.ready.get() { self.input.borrow_mut().pop_front() } else { None }
+        }
+
+        fn set_ready(&mut self, ready: bool) {
+            self.ready.set(ready);
+        }
+    }
+
+    fn make_dev(backend: Box<serial_backend>) -> UARTDev {
+        UARTDev {
+            uart: RefCell::new(UART::new(backend)),
+            base: COM1_BASE,
+            irq: COM1_IRQ,
+            assert_irq: count_irq,
+        }
+    }
+
+    fn outb(dev: &UARTDev, reg: u16, val: u8) {
+        vm::io_handler::io_write(dev, COM1_BASE + reg, vm::IoOperandType::byte(val));
+    }
+
+    fn inb(dev: &UARTDev, reg: u16) -> u8 {
+        vm::io_handler::io_read(dev, COM1_BASE + reg, 1).unwrap_byte()
+    }
+
+    /* Typical driver setup: 115200 8N1, FIFO with trigger at 4, receive interrupts */
+    fn setup(dev: &UARTDev) {
+        outb(dev, UART_LCR, UART_LCR_DLAB);
+        outb(dev, UART_RBR_THR, 1);
+        outb(dev, UART_IER, 0);
+        outb(dev, UART_LCR, 0x03);
+        outb(dev, UART_IIR_FCR, 0x47);
+        outb(dev, UART_MCR, UART_MCR_DTR | UART_MCR_RTS | UART_MCR_OUT2);
+        outb(dev, UART_IER, UART_IER_RDA | UART_IER_RLS);
+    }
+
+    /* Interrupt handler: read everything available, return bytes */
+    fn drain(dev: &UARTDev) -> Vec<u8> {
+        let mut res = Vec::new();
+        while (inb(dev, UART_LSR) & UART_LSR_DR) != 0 {
+            res.push(inb(dev, UART_RBR_THR));
+        }
+        res
+    }
+
+    #[test] fn loopback_detect() {
+        let input = Rc::new(RefCell::new(VecDeque::new()));
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let dev = make_dev(Box::new(TestBackend { input: input, output: output.clone(), ready: Rc::new(Cell::new(false)) }));
+
+        outb(&dev, UART_SCR, 0x5A);
+        assert!(inb(&dev, UART_SCR) == 0x5A);
+        assert!(inb(&dev, UART_IIR_FCR) == UART_IIR_NONE);
+        outb(&dev, UART_IIR_FCR, UART_FCR_ENABLE);
+        assert!(inb(&dev, UART_IIR_FCR) & UART_IIR_FIFO == UART_IIR_FIFO);
+
+        outb(&dev, UART_MCR, UART_MCR_LOOP | UART_MCR_OUT1 | UART_MCR_RTS);
+        assert!(inb(&dev, UART_MSR) == UART_MSR_RI | UART_MSR_CTS | UART_MSR_DDSR | UART_MSR_DDCD);
+        assert!(inb(&dev, UART_MSR) == UART_MSR_RI | UART_MSR_CTS);
+
+        outb(&dev, UART_RBR_THR, 0xA5);
+        assert!(inb(&dev, UART_LSR) == UART_LSR_THRE | UART_LSR_TEMT | UART_LSR_DR);
+        assert!(inb(&dev, UART_RBR_THR) == 0xA5);
+        assert!(output.borrow().is_empty());
+
+        outb(&dev, UART_MCR, 0);
+        outb(&dev, UART_RBR_THR, b'x');
+        assert!(*output.borrow() == vec![b'x']);
+
+        /* Looped back byte counts both ways */
+        let stats = vm::stats_handler::stats(&dev);
+        assert!(stats.device == "uart" && stats.bytes_read == 1 && stats.bytes_written == 2);
+    }
+
+    #[test] fn overrun_and_flow_control() {
+        let input = Rc::new(RefCell::new(VecDeque::new()));
+        let ready = Rc::new(Cell::new(false));
+        let dev = make_dev(Box::new(TestBackend { input: input.clone(), output: Rc::new(RefCell::new(Vec::new())), ready: ready.clone() }));
+
+        /* Nothing is taken from backend until DTR and RTS are asserted */
+        input.borrow_mut().extend(0..40);
+        dev.poll();
+        assert!(!ready.get());
+        assert!(input.borrow().len() == 40);
+
+        setup(&dev);
+        assert!(ready.get());
+
+        /* Guest doesn't drain: 11 chars per ms at 115200, FIFO holds 16, the rest overruns */
+        let irqs = irq_count();
+        dev.poll();
+        assert!(irq_count() == irqs + 1);
+        assert!(inb(&dev, UART_IIR_FCR) == UART_IIR_FIFO | UART_IIR_RDA);
+        dev.poll();
+        assert!(inb(&dev, UART_IIR_FCR) == UART_IIR_FIFO | UART_IIR_RLS);
+        assert!(inb(&dev, UART_LSR) == UART_LSR_THRE | UART_LSR_TEMT | UART_LSR_DR | UART_LSR_OE);
+        assert!(inb(&dev, UART_LSR) & UART_LSR_OE == 0);
+
+        let data = drain(&dev);
+        assert!(data == (0..16).collect::<Vec<u8>>());
+        assert!(input.borrow().len() == 40 - 22);
+
+        /* Dropping RTS pauses reception */
+        outb(&dev, UART_MCR, UART_MCR_DTR | UART_MCR_OUT2);
+        assert!(!ready.get());
+        dev.poll();
+        assert!(inb(&dev, UART_LSR) & UART_LSR_DR == 0);
+    }
+
+    /* Delta bits latch changes of loopback driven inputs until MSR is read */
+    #[test] fn modem_status_deltas() {
+        let input = Rc::new(RefCell::new(VecDeque::new()));
+        let dev = make_dev(Box::new(TestBackend { input: input, output: Rc::new(RefCell::new(Vec::new())), ready: Rc::new(Cell::new(false)) }));
+
+        /* Backend line looks like a connected modem until loopback disconnects it */
+        assert!(inb(&dev, UART_MSR) == UART_MSR_CTS | UART_MSR_DSR | UART_MSR_DCD);
+        outb(&dev, UART_MCR, UART_MCR_LOOP);
+        outb(&dev, UART_IER, UART_IER_MSI);
+        assert!(inb(&dev, UART_IIR_FCR) == UART_IIR_MSI);
+        assert!(inb(&dev, UART_IIR_FCR) == UART_IIR_MSI);
+        assert!(inb(&dev, UART_MSR) == UART_MSR_DCTS | UART_MSR_DDSR | UART_MSR_DDCD);
+        assert!(inb(&dev, UART_MSR) == 0);
+        assert!(inb(&dev, UART_IIR_FCR) == UART_IIR_NONE);
+
+        outb(&dev, UART_MCR, UART_MCR_LOOP | UART_MCR_RTS);
+        assert!(inb(&dev, UART_MSR) == UART_MSR_CTS | UART_MSR_DCTS);
+        assert!(inb(&dev, UART_MSR) == UART_MSR_CTS);
+
+        /* Ring indicator only has a trailing edge delta */
+        outb(&dev, UART_MCR, UART_MCR_LOOP | UART_MCR_RTS | UART_MCR_OUT1);
+        assert!(inb(&dev, UART_IIR_FCR) == UART_IIR_NONE);
+        assert!(inb(&dev, UART_MSR) == UART_MSR_CTS | UART_MSR_RI);
+        outb(&dev, UART_MCR, UART_MCR_LOOP | UART_MCR_RTS);
+        assert!(inb(&dev, UART_MSR) == UART_MSR_CTS | UART_MSR_TERI);
+
+        /* Pulse between reads is still seen */
+        outb(&dev, UART_MCR, UART_MCR_LOOP | UART_MCR_RTS | UART_MCR_DTR);
+        outb(&dev, UART_MCR, UART_MCR_LOOP | UART_MCR_RTS);
+        assert!(inb(&dev, UART_MSR) == UART_MSR_CTS | UART_MSR_DDSR);
+
+        /* Modem status has the lowest priority */
+        outb(&dev, UART_IER, UART_IER_RDA | UART_IER_THRE | UART_IER_MSI);
+        outb(&dev, UART_MCR, UART_MCR_LOOP | UART_MCR_OUT2);
+        outb(&dev, UART_RBR_THR, 0x55);
+        assert!(inb(&dev, UART_IIR_FCR) == UART_IIR_RDA);
+        assert!(inb(&dev, UART_RBR_THR) == 0x55);
+        assert!(inb(&dev, UART_IIR_FCR) == UART_IIR_THRE);
+        assert!(inb(&dev, UART_IIR_FCR) == UART_IIR_MSI);
+        assert!(inb(&dev, UART_MSR) == UART_MSR_DCD | UART_MSR_DCTS | UART_MSR_DDCD);
+        assert!(inb(&dev, UART_IIR_FCR) == UART_IIR_NONE);
+
+        /* Back to backend lines */
+        outb(&dev, UART_MCR, 0);
+        assert!(inb(&dev, UART_MSR) == UART_MSR_CTS | UART_MSR_DSR | UART_MSR_DCD | UART_MSR_DCTS | UART_MSR_DDSR);
+    }
+
+    /* Poll until an interrupt is raised */
+    fn wait_irq(dev: &UARTDev) {
+        let irqs = irq_count();
+        let mut tries = 0;
+        while irq_count() == irqs {
+            tries += 1;
+            assert!(tries < 1000);
+            thread::sleep(Duration::from_millis(1));
+            dev.poll();
+        }
+    }
+
+    /* Carrier follows TCP client connection */
+    #[test] fn tcp_carrier() {
+        let backend = TcpBackend::listen(0).unwrap();
+        let port = backend.port();
+        let dev = make_dev(Box::new(backend));
+        setup(&dev);
+        outb(&dev, UART_IER, UART_IER_RDA | UART_IER_RLS | UART_IER_MSI);
+
+        assert!(inb(&dev, UART_MSR) == UART_MSR_CTS | UART_MSR_DSR);
+        assert!(inb(&dev, UART_IIR_FCR) == UART_IIR_FIFO | UART_IIR_NONE);
+
+        let client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        wait_irq(&dev);
+        assert!(inb(&dev, UART_IIR_FCR) == UART_IIR_FIFO | UART_IIR_MSI);
+        assert!(inb(&dev, UART_MSR) == UART_MSR_CTS | UART_MSR_DSR | UART_MSR_DCD | UART_MSR_DDCD);
+        assert!(inb(&dev, UART_MSR) == UART_MSR_CTS | UART_MSR_DSR | UART_MSR_DCD);
+        assert!(inb(&dev, UART_IIR_FCR) == UART_IIR_FIFO | UART_IIR_NONE);
+
+        drop(client);
+        wait_irq(&dev);
+        assert!(inb(&dev, UART_IIR_FCR) == UART_IIR_FIFO | UART_IIR_MSI);
+        assert!(inb(&dev, UART_MSR) == UART_MSR_CTS | UART_MSR_DSR | UART_MSR_DDCD);
+        assert!(inb(&dev, UART_IIR_FCR) == UART_IIR_FIFO | UART_IIR_NONE);
+    }
+
+    /* End to end: keyboard input through TCP backend to guest interrupt handler */
+    #[test] fn tcp_input() {
+        let backend = TcpBackend::listen(0).unwrap();
+        let mut client = TcpStream::connect(("127.0.0.1", backend.port())).unwrap();
+        let dev = make_dev(Box::new(backend));
+        setup(&dev);
+
+        client.write_all(b"dir\r").unwrap();
+
+        /* Wait until host data reached the backend, then let poll deliver it */
+        let irqs = irq_count();
+        let mut tries = 0;
+        while irq_count() == irqs {
+            tries += 1;
+            assert!(tries < 1000);
+            thread::sleep(Duration::from_millis(1));
+            dev.poll();
+        }
+
+        /* Data sits at or above trigger level of 4, or below it with a timeout */
+        let id = inb(&dev, UART_IIR_FCR) & !UART_IIR_FIFO;
+        assert!(id == UART_IIR_RDA || id == UART_IIR_TIMEOUT);
+
+        let mut line = drain(&dev);
+        while line.len() < 4 {
+            tries += 1;
+            assert!(tries < 1000);
+            thread::sleep(Duration::from_millis(1));
+            dev.poll();
+            line.extend(drain(&dev));
+        }
+        assert!(line == b"dir\r");
+        assert!(inb(&dev, UART_IIR_FCR) == UART_IIR_FIFO | UART_IIR_NONE);
+
+        /* Guest echoes back with THR empty interrupt driven output */
+        let irqs = irq_count();
+        outb(&dev, UART_IER, UART_IER_RDA | UART_IER_THRE);
+        assert!(irq_count() == irqs + 1);
+        assert!(inb(&dev, UART_IIR_FCR) == UART_IIR_FIFO | UART_IIR_THRE);
+        assert!(inb(&dev, UART_IIR_FCR) == UART_IIR_FIFO | UART_IIR_NONE);
+        for b in b"ok\r\n".iter() {
+            outb(&dev, UART_RBR_THR, *b);
+        }
+        assert!(irq_count() == irqs + 2);
+
+        let mut reply = [0u8; 4];
+        client.read_exact(&mut reply).unwrap();
+        assert!(&reply == b"ok\r\n");
+    }
+
+    /* Run poll event every UART_POLL_PERIOD_US of guest time for ms of host time, return polls done */
+    fn run_host(dev: &UARTDev, host: &MockClock, clock: &ScaledClock, next_poll: &mut u64, ms: u64) -> u64 {
+        let mut polls = 0;
+        for _ in 0..ms {
+            thread::sleep(Duration::from_millis(1));
+            host.advance_ns(1000000);
+            while clock.now_ns() >= *next_poll {
+                dev.poll();
+                polls += 1;
+                *next_poll += UART_POLL_PERIOD_US * 1000;
+            }
+        }
+        polls
+    }
+
+    /* Input arriving while VM is paused waits in backend and reaches guest once after resume */
+    #[test] fn paused_input() {
+        let backend = TcpBackend::listen(0).unwrap();
+        let mut client = TcpStream::connect(("127.0.0.1", backend.port())).unwrap();
+        let dev = make_dev(Box::new(backend));
+        setup(&dev);
+
+        let host = Rc::new(MockClock::new());
+        let clock = ScaledClock::new(host.clone());
+        let mut next_poll = UART_POLL_PERIOD_US * 1000;
+        assert!(run_host(&dev, &host, &clock, &mut next_poll, 10) == 10);
+
+        /* Nothing polls and nothing interrupts while paused, however long it takes */
+        let irqs = irq_count();
+        clock.paused();
+        client.write_all(b"dir\r").unwrap();
+        assert!(run_host(&dev, &host, &clock, &mut next_poll, 100) == 0);
+        assert!(irq_count() == irqs);
+        clock.resumed();
+
+        /* Polls continue at guest rate, no burst for the paused time */
+        let mut line = Vec::new();
+        let mut polls = 0;
+        while line.len() < 4 {
+            polls += run_host(&dev, &host, &clock, &mut next_poll, 1);
+            assert!(polls < 1000);
+            line.extend(drain(&dev));
+        }
+        assert!(polls <= 4 && irq_count() > irqs);
+        assert!(line == b"dir\r");
+
+        run_host(&dev, &host, &clock, &mut next_poll, 10);
+        assert!(drain(&dev).is_empty());
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+static mut UART_DEV: Option<*const UARTDev> = None;
+
+fn poll_event(ev: event::Event)
+{
+    unsafe {
+        if let Some(dev) = UART_DEV {
+            let dev: &UARTDev = mem::transmute(dev);
+            dev.poll();
+        }
+    }
+
+    event::schedule_event(UART_POLL_PERIOD_US, ev);
+}
+
+/* Line that isn't connected to anything */
+struct NullBackend;
+
+impl serial_backend for NullBackend
+{
+    fn write(&mut self, _: u8) {
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn set_ready(&mut self, _: bool) {
+    }
+}
+
+pub fn init(config: &config::VmConfig)
+{
+    let backend: Box<serial_backend> = match config.serial {
+        Some(ref serialconfig) => match serial::open_backend(serialconfig) {
+            Ok(backend) => Box::new(serial::RecordedBackend::new(backend, "uart")),
+            Err(err) => panic!("uart: failed to open backend {:?}: {}", serialconfig, err),
+        },
+        None => Box::new(NullBackend),
+    };
+
+    let dev = Rc::new(UARTDev {
+        uart: RefCell::new(UART::new(backend)),
+        base: COM1_BASE,
+        irq: COM1_IRQ,
+        assert_irq: vm::assert_irq,
+    });
+
+    for offset in 0..UART_PORTS {
+        vm::register_io_region(dev.clone(), COM1_BASE + offset, 1);
+    }
+    vm::register_reset_handler(dev.clone());
+    vm::register_stats_handler(dev.clone());
+
+    if config.serial.is_some() {
+        unsafe {
+            UART_DEV = Some(&*dev as *const UARTDev);
+        }
+        event::schedule_event(UART_POLL_PERIOD_US, event::create_event(poll_event));
+    }
+}
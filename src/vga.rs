@@ -0,0 +1,1873 @@
+/*
+ * VGA emulation
+ *
+ * Supported are 80x25 color text mode and 320x200x256 graphics mode (13h).
+ * Video memory window at 0xA0000 is plain guest RAM which is periodically scraped by a renderer.
+ * Font plane 2 is kept aside and swapped into the window while the guest maps it for font access.
+ * Loaded glyphs are used to draw text mode frames for image output.
+ *
+ * High resolution modes go through Bochs VBE display interface (DISPI) with a RAM backed linear
+ * framebuffer. Banked access through the VGA window is not supported, BANK register only reads back.
+ */
+
+use vm;
+use event;
+use config;
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::io::Write;
+use std::mem;
+use std::thread;
+use std::time::{Duration, Instant};
+use hypervisor_framework::*;
+
+// Video memory window
+const VGA_WINDOW_BASE: u64      = 0xA0000;
+const VGA_WINDOW_SIZE: usize    = 0x20000;
+
+// Text mode buffer within video memory window (0xB8000)
+const VGA_TEXT_OFFSET: usize    = 0x18000;
+const VGA_TEXT_SIZE: usize      = 0x8000;
+
+pub const VGA_TEXT_COLS: usize  = 80;
+pub const VGA_TEXT_ROWS: usize  = 25;
+
+pub const VGA_MODE13H_WIDTH: usize  = 320;
+pub const VGA_MODE13H_HEIGHT: usize = 200;
+
+// IO ports
+const VGA_ATTR_INDEX: u16       = 0x3C0;    // Attribute controller index/data write
+const VGA_ATTR_DATA: u16        = 0x3C1;    // Attribute controller data read
+const VGA_MISC_WRITE: u16       = 0x3C2;    // Misc output write / input status 0 read
+const VGA_SEQ_INDEX: u16        = 0x3C4;
+const VGA_SEQ_DATA: u16         = 0x3C5;
+const VGA_DAC_READ_INDEX: u16   = 0x3C7;    // DAC read index write / DAC state read
+const VGA_DAC_WRITE_INDEX: u16  = 0x3C8;
+const VGA_DAC_DATA: u16         = 0x3C9;
+const VGA_MISC_READ: u16        = 0x3CC;
+const VGA_GC_INDEX: u16         = 0x3CE;
+const VGA_GC_DATA: u16          = 0x3CF;
+const VGA_CRTC_INDEX: u16       = 0x3D4;
+const VGA_CRTC_DATA: u16        = 0x3D5;
+const VGA_INPUT_STATUS_1: u16   = 0x3DA;
+
+// CRTC registers
+const CRTC_REGS: usize          = 0x19;
+const CRTC_CURSOR_START: u8     = 0x0A;
+const CRTC_CURSOR_END: u8       = 0x0B;
+const CRTC_START_ADDR_HI: u8    = 0x0C;
+const CRTC_START_ADDR_LO: u8    = 0x0D;
+const CRTC_CURSOR_LOC_HI: u8    = 0x0E;
+const CRTC_CURSOR_LOC_LO: u8    = 0x0F;
+const CRTC_MAX_SCANLINE: u8     = 0x09;
+
+// CRTC register values for mode 3
+const CRTC_MODE3_DEFAULTS: [u8; CRTC_REGS] = [
+    0x5F, 0x4F, 0x50, 0x82, 0x55, 0x81, 0xBF, 0x1F,
+    0x00, 0x4F, 0x0D, 0x0E, 0x00, 0x00, 0x00, 0x00,
+    0x9C, 0x8E, 0x8F, 0x28, 0x1F, 0x96, 0xB9, 0xA3,
+    0xFF,
+];
+
+const VGA_MISC_DEFAULT: u8      = 0x67;
+
+// Cursor start register bits
+const CRTC_CURSOR_DISABLE: u8   = 0x20;
+const CRTC_CURSOR_SCANLINE: u8  = 0x1F;
+
+// Maximum scan line register bits, character height minus one
+const CRTC_MAX_SCANLINE_MASK: u8 = 0x1F;
+
+// Attribute controller registers
+const ATTR_REGS: usize          = 0x15;
+const ATTR_MODE_CONTROL: u8     = 0x10;
+const ATTR_INDEX_MASK: u8       = 0x1F;
+const ATTR_PAS: u8              = 0x20;     // Palette address source, set when display is enabled
+
+// Attribute mode control bits
+const ATTR_MODE_BLINK: u8       = 0x08;
+
+// Attribute controller register values for mode 3
+const ATTR_MODE3_DEFAULTS: [u8; ATTR_REGS] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x14, 0x07,
+    0x38, 0x39, 0x3A, 0x3B, 0x3C, 0x3D, 0x3E, 0x3F,
+    0x0C, 0x00, 0x0F, 0x08, 0x00,
+];
+
+// Sequencer registers
+const SEQ_REGS: usize           = 0x05;
+const SEQ_MAP_MASK: u8          = 0x02;
+const SEQ_MEMORY_MODE: u8       = 0x04;
+const SEQ_MEMORY_SEQUENTIAL: u8 = 0x04;     // Odd/even addressing disabled
+const SEQ_MEMORY_CHAIN4: u8     = 0x08;
+
+const SEQ_MODE3_DEFAULTS: [u8; SEQ_REGS] = [0x03, 0x00, 0x03, 0x00, 0x02];
+
+// Graphics controller registers
+const GC_REGS: usize            = 0x09;
+const GC_READ_MAP: u8           = 0x04;
+const GC_MODE: u8               = 0x05;
+const GC_MISC: u8               = 0x06;
+const GC_MODE_ODD_EVEN: u8      = 0x10;
+const GC_MODE_256COLOR: u8      = 0x40;
+const GC_MISC_GRAPHICS: u8      = 0x01;
+const GC_MISC_MAP_MASK: u8      = 0x0C;
+const GC_MISC_MAP_A0000_64K: u8 = 0x04;     // 0 is 128K at 0xA0000, both start at window base
+
+const GC_MODE3_DEFAULTS: [u8; GC_REGS] = [0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x0E, 0x00, 0xFF];
+
+// Font plane, 256 glyphs of 32 scan lines at the start of plane 2
+pub const VGA_FONT_GLYPHS: usize        = 256;
+pub const VGA_FONT_GLYPH_SIZE: usize    = 32;
+const VGA_FONT_SIZE: usize              = VGA_FONT_GLYPHS * VGA_FONT_GLYPH_SIZE;
+const VGA_FONT_PLANE: u8                = 2;
+const VGA_CHAR_WIDTH: usize             = 8;
+
+// DAC
+const DAC_ENTRIES: usize        = 256;
+const DAC_STATE_READ: u8        = 0x03;
+const DAC_STATE_WRITE: u8       = 0x00;
+
+// Default DAC palette entries for 16 EGA colors, 6 bits per component
+const DAC_EGA_DEFAULTS: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00], [0x00, 0x00, 0x2A], [0x00, 0x2A, 0x00], [0x00, 0x2A, 0x2A],
+    [0x2A, 0x00, 0x00], [0x2A, 0x00, 0x2A], [0x2A, 0x15, 0x00], [0x2A, 0x2A, 0x2A],
+    [0x15, 0x15, 0x15], [0x15, 0x15, 0x3F], [0x15, 0x3F, 0x15], [0x15, 0x3F, 0x3F],
+    [0x3F, 0x15, 0x15], [0x3F, 0x15, 0x3F], [0x3F, 0x3F, 0x15], [0x3F, 0x3F, 0x3F],
+];
+
+// Character attribute bits
+const CHAR_ATTR_BLINK: u8       = 0x80;
+
+// Input status 1 bits
+const VGA_IS1_DISPLAY_DISABLED: u8  = 0x01;
+const VGA_IS1_VRETRACE: u8          = 0x08;
+
+// Guest time between screen refreshes in microseconds
+const VGA_REFRESH_PERIOD_US: u64 = 40000;
+
+// Host time between screen polls in wait_for_text
+const VGA_TEXT_POLL_MS: u64     = 10;
+
+// Bochs VBE display interface ports, 16 bit accesses
+const VBE_DISPI_INDEX_PORT: u16 = 0x1CE;
+const VBE_DISPI_DATA_PORT: u16  = 0x1CF;
+
+// DISPI registers
+const VBE_DISPI_INDEX_ID: u16           = 0x0;
+const VBE_DISPI_INDEX_XRES: u16         = 0x1;
+const VBE_DISPI_INDEX_YRES: u16         = 0x2;
+const VBE_DISPI_INDEX_BPP: u16          = 0x3;
+const VBE_DISPI_INDEX_ENABLE: u16       = 0x4;
+const VBE_DISPI_INDEX_BANK: u16         = 0x5;
+const VBE_DISPI_INDEX_VIRT_WIDTH: u16   = 0x6;
+const VBE_DISPI_INDEX_VIRT_HEIGHT: u16  = 0x7;
+const VBE_DISPI_INDEX_X_OFFSET: u16     = 0x8;
+const VBE_DISPI_INDEX_Y_OFFSET: u16     = 0x9;
+const VBE_DISPI_INDEX_VIDEO_MEMORY_64K: u16 = 0xA;
+
+// Interface versions, guests probe by writing one to ID register and reading it back
+const VBE_DISPI_ID0: u16        = 0xB0C0;
+const VBE_DISPI_ID5: u16        = 0xB0C5;
+
+// ENABLE register bits
+const VBE_DISPI_ENABLED: u16    = 0x01;
+const VBE_DISPI_GETCAPS: u16    = 0x02;     // XRES, YRES and BPP read back maximum values
+const VBE_DISPI_8BIT_DAC: u16   = 0x20;
+const VBE_DISPI_LFB_ENABLED: u16 = 0x40;
+const VBE_DISPI_NOCLEARMEM: u16 = 0x80;
+const VBE_DISPI_ENABLE_MASK: u16 = 0xE3;
+
+const VBE_DISPI_MAX_XRES: u16   = 1920;
+const VBE_DISPI_MAX_YRES: u16   = 1200;
+const VBE_DISPI_MAX_BPP: u16    = 32;
+
+// Linear framebuffer memory
+const VBE_VRAM_SIZE: usize      = 16 << 20;
+const VBE_BANK_SIZE: usize      = 0x10000;
+
+/**
+ * Code page 437 to unicode mapping
+ */
+const CP437: &'static str =
+    " ☺☻♥♦♣♠•◘○◙♂♀♪♫☼►◄↕‼¶§▬↨↑↓→←∟↔▲▼ !\"#$%&'()*+,-./0123456789:;<=>?\
+     @ABCDEFGHIJKLMNOPQRSTUVWXYZ[\\]^_`abcdefghijklmnopqrstuvwxyz{|}~⌂\
+     ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»░▒▓│┤╡╢╖╕╣║╗╝╜╛┐\
+     └┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}";
+
+fn cp437_table() -> Vec<char> {
+    let table: Vec<char> = CP437.chars().collect();
+    assert!(table.len() == 256);
+    table
+}
+
+/**
+ * Snapshot of text mode screen contents
+ */
+pub struct TextScreen
+{
+    pub cells: Vec<u8>,     // Character + attribute pairs, row by row
+    pub cursor: u16,        // Cursor location in cells relative to screen start
+    pub cursor_visible: bool,
+    pub cursor_start: u8,   // Cursor shape as first and last scanline
+    pub cursor_end: u8,
+    pub blink: bool,        // Attribute bit 7 is blink rather than bright background
+}
+
+impl TextScreen
+{
+    pub fn char_at(&self, row: usize, col: usize) -> u8 {
+        self.cells[(row * VGA_TEXT_COLS + col) * 2]
+    }
+
+    pub fn attr_at(&self, row: usize, col: usize) -> u8 {
+        self.cells[(row * VGA_TEXT_COLS + col) * 2 + 1]
+    }
+
+    /**
+     * Cursor row and column if cursor is enabled and within visible screen
+     */
+    pub fn cursor_position(&self) -> Option<(usize, usize)> {
+        let pos = self.cursor as usize;
+        if !self.cursor_visible || pos >= VGA_TEXT_COLS * VGA_TEXT_ROWS {
+            return None;
+        }
+
+        Some((pos / VGA_TEXT_COLS, pos % VGA_TEXT_COLS))
+    }
+
+    /**
+     * Screen contents as text rows, with CP437 characters converted to unicode
+     */
+    pub fn text(&self) -> Vec<String> {
+        let table = cp437_table();
+        let mut rows = Vec::with_capacity(VGA_TEXT_ROWS);
+
+        for row in 0..VGA_TEXT_ROWS {
+            let mut line = String::with_capacity(VGA_TEXT_COLS);
+            for col in 0..VGA_TEXT_COLS {
+                line.push(table[self.char_at(row, col) as usize]);
+            }
+            rows.push(line);
+        }
+
+        rows
+    }
+
+    /**
+     * Attributes as rows parallel to text()
+     */
+    pub fn attrs(&self) -> Vec<Vec<u8>> {
+        (0..VGA_TEXT_ROWS).map(|row| (0..VGA_TEXT_COLS).map(|col| self.attr_at(row, col)).collect())
+                          .collect()
+    }
+
+    /**
+     * Row and column of first occurrence of pattern, patterns don't span rows
+     */
+    pub fn find(&self, pattern: &str) -> Option<(usize, usize)> {
+        for (row, line) in self.text().iter().enumerate() {
+            match line.find(pattern) {
+                Some(pos) => return Some((row, line[..pos].chars().count())),
+                None => {},
+            }
+        }
+
+        None
+    }
+
+    /**
+     * Plain text dump of screen contents with cursor drawn as underscore
+     */
+    pub fn dump(&self) -> String {
+        let mut rows = self.text();
+
+        match self.cursor_position() {
+            Some((row, col)) => {
+                rows[row] = rows[row].chars().enumerate()
+                                     .map(|(i, c)| if i == col { '_' } else { c })
+                                     .collect();
+            },
+            None => {},
+        }
+
+        rows.join("\n")
+    }
+}
+
+/**
+ * Host side display output
+ */
+pub trait TextRenderer
+{
+    fn render(&mut self, screen: &TextScreen);
+}
+
+/**
+ * Graphics mode frame converted to 24-bit RGB
+ */
+#[derive(PartialEq)]
+pub struct Frame
+{
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,    // RGB triplets, row by row
+}
+
+impl Frame
+{
+    /**
+     * Encode frame as binary PPM (P6) image
+     */
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut out = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        out.extend_from_slice(&self.pixels);
+        out
+    }
+
+    pub fn write_ppm(&self, path: &str) -> ::std::io::Result<()> {
+        let mut file = try!(::std::fs::File::create(path));
+        file.write_all(&self.to_ppm())
+    }
+}
+
+/**
+ * Host side graphics output
+ */
+pub trait FrameRenderer
+{
+    fn render_frame(&mut self, frame: &Frame);
+}
+
+/**
+ * Dumps changed frames as numbered PPM files into a directory
+ */
+struct PpmDumper
+{
+    dir: String,
+    count: u32,
+    last: Option<Frame>,
+}
+
+impl PpmDumper
+{
+    fn new(dir: &str) -> PpmDumper {
+        PpmDumper {
+            dir: String::from(dir),
+            count: 0,
+            last: None,
+        }
+    }
+}
+
+impl FrameRenderer for PpmDumper
+{
+    fn render_frame(&mut self, frame: &Frame) {
+        if self.last.as_ref() == Some(frame) {
+            return;
+        }
+
+        let path = format!("{}/frame{:05}.ppm", self.dir, self.count);
+        match frame.write_ppm(&path) {
+            Ok(_) => debug!("vga: dumped frame to {}", path),
+            Err(err) => error!("vga: failed to dump frame to {}: {}", path, err),
+        }
+
+        self.count += 1;
+        self.last = Some(Frame {
+            width: frame.width,
+            height: frame.height,
+            pixels: frame.pixels.clone(),
+        });
+    }
+}
+
+/* Scale 6-bit DAC component to 8 bits */
+fn dac_to_rgb8(val: u8) -> u8 {
+    let val = val & 0x3F;
+    (val << 2) | (val >> 4)
+}
+
+/* Scale low 5 or 6 bits of a packed pixel component to 8 bits */
+fn scale_to_rgb8(val: u16, bits: u32) -> u8 {
+    let val = val & ((1 << bits) - 1);
+    ((val << (8 - bits)) | (val >> (2 * bits - 8))) as u8
+}
+
+/**
+ * Renders text screen to host terminal with ANSI escape sequences
+ */
+struct TerminalRenderer
+{
+    table: Vec<char>,
+    last: Option<TextScreen>,   // Last rendered screen, to skip redundant redraws
+}
+
+impl TerminalRenderer
+{
+    fn new() -> TerminalRenderer {
+        TerminalRenderer {
+            table: cp437_table(),
+            last: None,
+        }
+    }
+
+    /* Map CGA color index to ANSI color code offset */
+    fn ansi_color(color: u8) -> u8 {
+        const CGA_TO_ANSI: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+        CGA_TO_ANSI[(color & 0x7) as usize]
+    }
+
+    /*
+     * ANSI SGR sequence for character attribute
+     * Bit 7 is either blink or background intensity depending on attribute mode control
+     */
+    fn ansi_attr(attr: u8, blink: bool) -> String {
+        let fg = attr & 0xF;
+        let bg = (attr >> 4) & 0xF;
+        let fg_base = if fg & 0x8 != 0 { 90 } else { 30 };
+
+        if blink {
+            format!("\x1b[{};{};{}m", if attr & CHAR_ATTR_BLINK != 0 { 5 } else { 25 },
+                                      fg_base + TerminalRenderer::ansi_color(fg) as u32,
+                                      40 + TerminalRenderer::ansi_color(bg) as u32)
+        } else {
+            let bg_base = if bg & 0x8 != 0 { 100 } else { 40 };
+            format!("\x1b[25;{};{}m", fg_base + TerminalRenderer::ansi_color(fg) as u32,
+                                      bg_base + TerminalRenderer::ansi_color(bg) as u32)
+        }
+    }
+
+    fn is_same_screen(&self, screen: &TextScreen) -> bool {
+        match self.last {
+            Some(ref last) => {
+                last.cells == screen.cells &&
+                last.cursor_position() == screen.cursor_position() &&
+                last.blink == screen.blink
+            },
+            None => false,
+        }
+    }
+}
+
+impl TextRenderer for TerminalRenderer
+{
+    fn render(&mut self, screen: &TextScreen) {
+        if self.is_same_screen(screen) {
+            return;
+        }
+
+        let mut out = String::new();
+        out.push_str("\x1b[H");
+
+        let mut cur_attr: Option<u8> = None;
+        for row in 0..VGA_TEXT_ROWS {
+            for col in 0..VGA_TEXT_COLS {
+                let attr = screen.attr_at(row, col);
+                if cur_attr != Some(attr) {
+                    out.push_str(&TerminalRenderer::ansi_attr(attr, screen.blink));
+                    cur_attr = Some(attr);
+                }
+
+                out.push(self.table[screen.char_at(row, col) as usize]);
+            }
+
+            out.push_str("\x1b[0m\r\n");
+            cur_attr = None;
+        }
+
+        /* Move host cursor to guest cursor position, terminal rows and columns are 1-based */
+        match screen.cursor_position() {
+            Some((row, col)) => out.push_str(&format!("\x1b[{};{}H\x1b[?25h", row + 1, col + 1)),
+            None => out.push_str("\x1b[?25l"),
+        }
+
+        let stdout = ::std::io::stdout();
+        let mut handle = stdout.lock();
+        handle.write_all(out.as_bytes()).unwrap_or_else(|err| {
+            error!("vga: failed writing to terminal: {}", err);
+        });
+        handle.flush().ok();
+
+        self.last = Some(TextScreen {
+            cells: screen.cells.clone(),
+            cursor: screen.cursor,
+            cursor_visible: screen.cursor_visible,
+            cursor_start: screen.cursor_start,
+            cursor_end: screen.cursor_end,
+            blink: screen.blink,
+        });
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/*
+ * Bochs VBE display interface.
+ * Mode registers can only change while display is disabled, enabling checks that the mode fits
+ * video memory and clears it unless asked not to.
+ */
+struct Dispi
+{
+    vram: Arc<vm::memory_region>,   // Linear framebuffer memory
+    index: u16,
+    id: u16,
+    xres: u16,
+    yres: u16,
+    bpp: u16,
+    enable: u16,
+    bank: u16,
+    virt_width: u16,
+    virt_height: u16,
+    x_offset: u16,
+    y_offset: u16,
+}
+
+impl Dispi
+{
+    fn new(vram: Arc<vm::memory_region>) -> Dispi {
+        Dispi {
+            vram: vram,
+            index: 0,
+            id: VBE_DISPI_ID5,
+            xres: 640,
+            yres: 480,
+            bpp: 8,
+            enable: 0,
+            bank: 0,
+            virt_width: 640,
+            virt_height: 480,
+            x_offset: 0,
+            y_offset: 0,
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.enable & VBE_DISPI_ENABLED != 0
+    }
+
+    fn bytes_per_pixel(&self) -> usize {
+        (self.bpp as usize + 7) / 8
+    }
+
+    fn pitch(&self) -> usize {
+        self.virt_width as usize * self.bytes_per_pixel()
+    }
+
+    /* Virtual height is whatever fits video memory at current pitch */
+    fn update_virt_height(&mut self) {
+        let lines = self.vram.size / self.pitch();
+        self.virt_height = ::std::cmp::min(lines, 0xFFFF) as u16;
+    }
+
+    /* Offset of first visible pixel within video memory */
+    fn display_start(&self) -> usize {
+        self.y_offset as usize * self.pitch() + self.x_offset as usize * self.bytes_per_pixel()
+    }
+
+    fn fits_vram(&self, start: usize) -> bool {
+        start + (self.yres as usize - 1) * self.pitch() + self.xres as usize * self.bytes_per_pixel() <= self.vram.size
+    }
+
+    fn set_enable(&mut self, val: u16) {
+        let val = val & VBE_DISPI_ENABLE_MASK;
+
+        if val & VBE_DISPI_ENABLED != 0 && !self.is_enabled() {
+            let size = self.xres as usize * self.yres as usize * self.bytes_per_pixel();
+            if size > self.vram.size {
+                debug!("vga: VBE mode {}x{}x{} doesn't fit video memory", self.xres, self.yres, self.bpp);
+                return;
+            }
+
+            self.virt_width = self.xres;
+            self.update_virt_height();
+            self.x_offset = 0;
+            self.y_offset = 0;
+            self.bank = 0;
+
+            if val & VBE_DISPI_NOCLEARMEM == 0 {
+                let zero = vec![0u8; VBE_BANK_SIZE];
+                let mut offset = 0;
+                while offset < self.vram.size {
+                    offset += self.vram.write_bytes(offset, &zero);
+                }
+            }
+        }
+
+        self.enable = val;
+    }
+
+    fn read_reg(&self) -> u16 {
+        let caps = self.enable & VBE_DISPI_GETCAPS != 0;
+
+        match self.index {
+            VBE_DISPI_INDEX_ID => self.id,
+            VBE_DISPI_INDEX_XRES => if caps { VBE_DISPI_MAX_XRES } else { self.xres },
+            VBE_DISPI_INDEX_YRES => if caps { VBE_DISPI_MAX_YRES } else { self.yres },
+            VBE_DISPI_INDEX_BPP => if caps { VBE_DISPI_MAX_BPP } else { self.bpp },
+            VBE_DISPI_INDEX_ENABLE => self.enable,
+            VBE_DISPI_INDEX_BANK => self.bank,
+            VBE_DISPI_INDEX_VIRT_WIDTH => self.virt_width,
+            VBE_DISPI_INDEX_VIRT_HEIGHT => self.virt_height,
+            VBE_DISPI_INDEX_X_OFFSET => self.x_offset,
+            VBE_DISPI_INDEX_Y_OFFSET => self.y_offset,
+            VBE_DISPI_INDEX_VIDEO_MEMORY_64K => (self.vram.size / VBE_BANK_SIZE) as u16,
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&mut self, val: u16) {
+        match self.index {
+            /* Versions we don't implement are ignored, probe reads back previous one */
+            VBE_DISPI_INDEX_ID => {
+                if val >= VBE_DISPI_ID0 && val <= VBE_DISPI_ID5 {
+                    self.id = val;
+                }
+            },
+            VBE_DISPI_INDEX_XRES | VBE_DISPI_INDEX_YRES | VBE_DISPI_INDEX_BPP if self.is_enabled() => {
+                debug!("vga: ignoring VBE mode change while enabled");
+            },
+            VBE_DISPI_INDEX_XRES => {
+                if val > 0 && val <= VBE_DISPI_MAX_XRES && val % 8 == 0 {
+                    self.xres = val;
+                } else {
+                    debug!("vga: unsupported VBE xres {}", val);
+                }
+            },
+            VBE_DISPI_INDEX_YRES => {
+                if val > 0 && val <= VBE_DISPI_MAX_YRES {
+                    self.yres = val;
+                } else {
+                    debug!("vga: unsupported VBE yres {}", val);
+                }
+            },
+            VBE_DISPI_INDEX_BPP => {
+                match val {
+                    8 | 15 | 16 | 24 | 32 => self.bpp = val,
+                    _ => debug!("vga: unsupported VBE bpp {}", val),
+                }
+            },
+            VBE_DISPI_INDEX_ENABLE => self.set_enable(val),
+            VBE_DISPI_INDEX_BANK => {
+                if (val as usize) < self.vram.size / VBE_BANK_SIZE {
+                    self.bank = val;
+                }
+            },
+            VBE_DISPI_INDEX_VIRT_WIDTH => {
+                let lines = self.vram.size / (val as usize * self.bytes_per_pixel()).max(1);
+                if val >= self.xres && lines >= self.yres as usize {
+                    self.virt_width = val;
+                    self.update_virt_height();
+                }
+            },
+            VBE_DISPI_INDEX_X_OFFSET => {
+                let old = self.x_offset;
+                self.x_offset = val;
+                if !self.fits_vram(self.display_start()) {
+                    self.x_offset = old;
+                }
+            },
+            VBE_DISPI_INDEX_Y_OFFSET => {
+                let old = self.y_offset;
+                self.y_offset = val;
+                if !self.fits_vram(self.display_start()) {
+                    self.y_offset = old;
+                }
+            },
+            _ => debug!("vga: write to unsupported VBE register {:x}", self.index),
+        }
+    }
+
+    /* Convert visible part of linear framebuffer, 8 bit modes go through DAC palette */
+    fn frame(&self, dac: &[[u8; 3]; DAC_ENTRIES]) -> Frame {
+        let width = self.xres as usize;
+        let height = self.yres as usize;
+        let bytes = self.bytes_per_pixel();
+        let dac8 = self.enable & VBE_DISPI_8BIT_DAC != 0;
+
+        let mut row = vec![0u8; width * bytes];
+        let mut pixels = Vec::with_capacity(width * height * 3);
+        let mut offset = self.display_start();
+
+        for _ in 0..height {
+            self.vram.read_bytes(offset, &mut row);
+            offset += self.pitch();
+
+            for px in row.chunks(bytes) {
+                match self.bpp {
+                    8 => {
+                        let color = dac[px[0] as usize];
+                        for c in color.iter() {
+                            pixels.push(if dac8 { *c } else { dac_to_rgb8(*c) });
+                        }
+                    },
+                    15 => {
+                        let val = px[0] as u16 | (px[1] as u16) << 8;
+                        pixels.push(scale_to_rgb8(val >> 10, 5));
+                        pixels.push(scale_to_rgb8(val >> 5, 5));
+                        pixels.push(scale_to_rgb8(val, 5));
+                    },
+                    16 => {
+                        let val = px[0] as u16 | (px[1] as u16) << 8;
+                        pixels.push(scale_to_rgb8(val >> 11, 5));
+                        pixels.push(scale_to_rgb8(val >> 5, 6));
+                        pixels.push(scale_to_rgb8(val, 5));
+                    },
+                    _ => {
+                        /* 24 and 32 bit pixels are stored as BGR(X) */
+                        pixels.push(px[2]);
+                        pixels.push(px[1]);
+                        pixels.push(px[0]);
+                    },
+                }
+            }
+        }
+
+        Frame {
+            width: width,
+            height: height,
+            pixels: pixels,
+        }
+    }
+}
+
+struct VGA
+{
+    vram: Arc<vm::memory_region>,   // Memory region backing video memory window
+    vram_offset: usize,             // Offset of video memory window within vram region
+    misc_output: u8,
+    seq_index: u8,
+    seq: [u8; SEQ_REGS],
+    gc_index: u8,
+    gc: [u8; GC_REGS],
+    dac: [[u8; 3]; DAC_ENTRIES],
+    dac_read_index: u8,
+    dac_write_index: u8,
+    dac_component: usize,           // Next RGB component to access through DAC data port
+    dac_state: u8,
+    crtc_index: u8,
+    crtc: [u8; CRTC_REGS],
+    attr_index: u8,             // Attribute controller index, including PAS bit
+    attr_flipflop: bool,        // Next 0x3C0 write goes to data register
+    attr: [u8; ATTR_REGS],
+    input_status: u8,
+    font: Vec<u8>,                  // Plane 2 glyphs, VGA_FONT_GLYPH_SIZE bytes per character
+    font_loaded: bool,              // Guest has mapped plane 2 at least once
+    window_save: Option<Vec<u8>>,   // Window contents displaced by plane 2 while it is mapped
+    dispi: Dispi,
+}
+
+impl VGA
+{
+    fn new(vram: Arc<vm::memory_region>, vram_offset: usize, lfb: Arc<vm::memory_region>) -> VGA {
+        VGA {
+            vram: vram,
+            vram_offset: vram_offset,
+            misc_output: VGA_MISC_DEFAULT,
+            seq_index: 0,
+            seq: SEQ_MODE3_DEFAULTS,
+            gc_index: 0,
+            gc: GC_MODE3_DEFAULTS,
+            dac: VGA::default_palette(),
+            dac_read_index: 0,
+            dac_write_index: 0,
+            dac_component: 0,
+            dac_state: DAC_STATE_WRITE,
+            crtc_index: 0,
+            crtc: CRTC_MODE3_DEFAULTS,
+            attr_index: 0,
+            attr_flipflop: false,
+            attr: ATTR_MODE3_DEFAULTS,
+            input_status: 0,
+            font: vec![0u8; VGA_FONT_SIZE],
+            font_loaded: false,
+            window_save: None,
+            dispi: Dispi::new(lfb),
+        }
+    }
+
+    fn default_palette() -> [[u8; 3]; DAC_ENTRIES] {
+        let mut dac = [[0u8; 3]; DAC_ENTRIES];
+        dac[..DAC_EGA_DEFAULTS.len()].copy_from_slice(&DAC_EGA_DEFAULTS);
+        dac
+    }
+
+    fn is_graphics_mode(&self) -> bool {
+        self.gc[GC_MISC as usize] & GC_MISC_GRAPHICS != 0
+    }
+
+    /* Mode 13h is the only graphics mode with chained 256 color memory layout */
+    fn is_mode13h(&self) -> bool {
+        self.is_graphics_mode() &&
+        self.seq[SEQ_MEMORY_MODE as usize] & SEQ_MEMORY_CHAIN4 != 0 &&
+        self.gc[GC_MODE as usize] & GC_MODE_256COLOR != 0
+    }
+
+    /*
+     * Standard font access setup: writes go to plane 2 only, reads select plane 2, sequential
+     * addressing and the window mapped at 0xA0000 in text mode.
+     */
+    fn is_font_access(&self) -> bool {
+        self.seq[SEQ_MAP_MASK as usize] == 1 << VGA_FONT_PLANE &&
+        self.seq[SEQ_MEMORY_MODE as usize] & SEQ_MEMORY_SEQUENTIAL != 0 &&
+        self.gc[GC_READ_MAP as usize] == VGA_FONT_PLANE &&
+        self.gc[GC_MODE as usize] & GC_MODE_ODD_EVEN == 0 &&
+        self.gc[GC_MISC as usize] & GC_MISC_MAP_MASK <= GC_MISC_MAP_A0000_64K &&
+        !self.is_graphics_mode()
+    }
+
+    /*
+     * Swap font plane in or out of the window when sequencer or graphics controller setup changes.
+     * Guest accesses the window at memory speed, so glyphs are picked up when plane 2 is unmapped.
+     */
+    fn update_font_mapping(&mut self) {
+        let access = self.is_font_access();
+
+        if access && self.window_save.is_none() {
+            let mut save = vec![0u8; VGA_FONT_SIZE];
+            self.vram.read_bytes(self.vram_offset, &mut save);
+            self.vram.write_bytes(self.vram_offset, &self.font);
+            self.window_save = Some(save);
+        } else if !access && self.window_save.is_some() {
+            self.vram.read_bytes(self.vram_offset, &mut self.font);
+            self.vram.write_bytes(self.vram_offset, &self.window_save.take().unwrap());
+            self.font_loaded = true;
+            debug!("vga: font plane unmapped");
+        }
+    }
+
+    fn write_seq(&mut self, index: u8, val: u8) {
+        VGA::write_indexed(&mut self.seq, index, val);
+        self.update_font_mapping();
+    }
+
+    fn write_gc(&mut self, index: u8, val: u8) {
+        VGA::write_indexed(&mut self.gc, index, val);
+        self.update_font_mapping();
+    }
+
+    /* Current font plane contents, read back from the window while the guest has it mapped */
+    fn font_plane(&self) -> Vec<u8> {
+        if self.window_save.is_some() {
+            let mut font = vec![0u8; VGA_FONT_SIZE];
+            self.vram.read_bytes(self.vram_offset, &mut font);
+            font
+        } else {
+            self.font.clone()
+        }
+    }
+
+    /*
+     * Draw text screen with guest loaded glyphs, 8 dots per character.
+     * Attribute colors index DAC directly, default palette holds the 16 EGA colors in order.
+     */
+    fn text_frame(&self) -> Option<Frame> {
+        if !self.font_loaded {
+            return None;
+        }
+
+        let screen = self.screen();
+        let font = self.font_plane();
+        let char_height = ::std::cmp::min((self.crtc[CRTC_MAX_SCANLINE as usize] & CRTC_MAX_SCANLINE_MASK) as usize + 1,
+                                          VGA_FONT_GLYPH_SIZE);
+        let width = VGA_TEXT_COLS * VGA_CHAR_WIDTH;
+        let height = VGA_TEXT_ROWS * char_height;
+        let cursor = screen.cursor_position();
+
+        let mut pixels = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            let (row, line) = (y / char_height, y % char_height);
+            for col in 0..VGA_TEXT_COLS {
+                let attr = screen.attr_at(row, col);
+                let fg = self.dac[(attr & 0xF) as usize];
+                let bg = self.dac[((attr >> 4) & if screen.blink { 0x7 } else { 0xF }) as usize];
+
+                let in_cursor = cursor == Some((row, col)) &&
+                                line >= screen.cursor_start as usize && line <= screen.cursor_end as usize;
+                let bits = if in_cursor {
+                    0xFF
+                } else {
+                    font[screen.char_at(row, col) as usize * VGA_FONT_GLYPH_SIZE + line]
+                };
+
+                for dot in 0..VGA_CHAR_WIDTH {
+                    let color = if bits & (0x80 >> dot) != 0 { fg } else { bg };
+                    pixels.push(dac_to_rgb8(color[0]));
+                    pixels.push(dac_to_rgb8(color[1]));
+                    pixels.push(dac_to_rgb8(color[2]));
+                }
+            }
+        }
+
+        Some(Frame {
+            width: width,
+            height: height,
+            pixels: pixels,
+        })
+    }
+
+    /* Convert VBE or mode 13h framebuffer through DAC palette, text mode is drawn with loaded font */
+    fn frame(&self) -> Option<Frame> {
+        if self.dispi.is_enabled() {
+            return Some(self.dispi.frame(&self.dac));
+        }
+
+        if !self.is_graphics_mode() {
+            return self.text_frame();
+        }
+
+        if !self.is_mode13h() {
+            return None;
+        }
+
+        let mut fb = vec![0u8; VGA_MODE13H_WIDTH * VGA_MODE13H_HEIGHT];
+        self.vram.read_bytes(self.vram_offset, &mut fb);
+
+        let mut pixels = Vec::with_capacity(fb.len() * 3);
+        for index in fb {
+            let color = self.dac[index as usize];
+            pixels.push(dac_to_rgb8(color[0]));
+            pixels.push(dac_to_rgb8(color[1]));
+            pixels.push(dac_to_rgb8(color[2]));
+        }
+
+        Some(Frame {
+            width: VGA_MODE13H_WIDTH,
+            height: VGA_MODE13H_HEIGHT,
+            pixels: pixels,
+        })
+    }
+
+    fn write_indexed(regs: &mut [u8], index: u8, val: u8) {
+        match regs.get_mut(index as usize) {
+            Some(reg) => *reg = val,
+            None => debug!("vga: write to unsupported register {:x}", index),
+        }
+    }
+
+    fn read_indexed(regs: &[u8], index: u8) -> u8 {
+        match regs.get(index as usize) {
+            Some(val) => *val,
+            None => 0xFF,
+        }
+    }
+
+    /*
+     * DAC data port accesses 3 color components in sequence, index is incremented after the last one
+     */
+    fn write_dac(&mut self, val: u8) {
+        let mask = if self.dispi.enable & VBE_DISPI_8BIT_DAC != 0 { 0xFF } else { 0x3F };
+        self.dac[self.dac_write_index as usize][self.dac_component] = val & mask;
+        self.dac_component += 1;
+        if self.dac_component == 3 {
+            self.dac_component = 0;
+            self.dac_write_index = self.dac_write_index.wrapping_add(1);
+        }
+    }
+
+    fn read_dac(&mut self) -> u8 {
+        let val = self.dac[self.dac_read_index as usize][self.dac_component];
+        self.dac_component += 1;
+        if self.dac_component == 3 {
+            self.dac_component = 0;
+            self.dac_read_index = self.dac_read_index.wrapping_add(1);
+        }
+
+        val
+    }
+
+    fn set_dac_write_index(&mut self, index: u8) {
+        self.dac_write_index = index;
+        self.dac_component = 0;
+        self.dac_state = DAC_STATE_WRITE;
+    }
+
+    fn set_dac_read_index(&mut self, index: u8) {
+        self.dac_read_index = index;
+        self.dac_component = 0;
+        self.dac_state = DAC_STATE_READ;
+    }
+
+    fn start_address(&self) -> u16 {
+        ((self.crtc[CRTC_START_ADDR_HI as usize] as u16) << 8) | self.crtc[CRTC_START_ADDR_LO as usize] as u16
+    }
+
+    fn cursor_location(&self) -> u16 {
+        ((self.crtc[CRTC_CURSOR_LOC_HI as usize] as u16) << 8) | self.crtc[CRTC_CURSOR_LOC_LO as usize] as u16
+    }
+
+    /* Capture visible screen starting from CRTC start address */
+    fn screen(&self) -> TextScreen {
+        let mut cells = vec![0u8; VGA_TEXT_COLS * VGA_TEXT_ROWS * 2];
+
+        /* Start address is in character cells and wraps around text window */
+        let mut offset = (self.start_address() as usize * 2) % VGA_TEXT_SIZE;
+        let mut pos = 0;
+        while pos < cells.len() {
+            let chunk = ::std::cmp::min(cells.len() - pos, VGA_TEXT_SIZE - offset);
+            self.vram.read_bytes(self.vram_offset + VGA_TEXT_OFFSET + offset, &mut cells[pos..pos + chunk]);
+            pos += chunk;
+            offset = 0;
+        }
+
+        TextScreen {
+            cells: cells,
+            cursor: self.cursor_location().wrapping_sub(self.start_address()),
+            cursor_visible: self.crtc[CRTC_CURSOR_START as usize] & CRTC_CURSOR_DISABLE == 0,
+            cursor_start: self.crtc[CRTC_CURSOR_START as usize] & CRTC_CURSOR_SCANLINE,
+            cursor_end: self.crtc[CRTC_CURSOR_END as usize] & CRTC_CURSOR_SCANLINE,
+            blink: self.attr[ATTR_MODE_CONTROL as usize] & ATTR_MODE_BLINK != 0,
+        }
+    }
+
+    fn read_crtc(&self) -> u8 {
+        match self.crtc.get(self.crtc_index as usize) {
+            Some(val) => *val,
+            None => 0xFF,
+        }
+    }
+
+    fn write_crtc(&mut self, val: u8) {
+        let index = self.crtc_index as usize;
+        if index < CRTC_REGS {
+            self.crtc[index] = val;
+        } else {
+            debug!("vga: write to unsupported CRTC register {:x}", index);
+        }
+    }
+
+    /*
+     * Attribute controller has a single port for index and data writes.
+     * An internal flip-flop selects which one the next write goes to, guests reset it to index
+     * state by reading input status 1.
+     */
+    fn write_attr(&mut self, val: u8) {
+        if !self.attr_flipflop {
+            self.attr_index = val & (ATTR_INDEX_MASK | ATTR_PAS);
+        } else {
+            let index = (self.attr_index & ATTR_INDEX_MASK) as usize;
+            if index < ATTR_REGS {
+                self.attr[index] = val;
+            } else {
+                debug!("vga: write to unsupported attribute register {:x}", index);
+            }
+        }
+
+        self.attr_flipflop = !self.attr_flipflop;
+    }
+
+    fn read_attr(&self) -> u8 {
+        match self.attr.get((self.attr_index & ATTR_INDEX_MASK) as usize) {
+            Some(val) => *val,
+            None => 0xFF,
+        }
+    }
+
+    /*
+     * Read input status 1.
+     * We don't have a real refresh cycle so retrace bit toggles on every read. This is enough for
+     * guests that busy-wait for retrace start and end.
+     */
+    fn read_input_status(&mut self) -> u8 {
+        self.input_status ^= VGA_IS1_VRETRACE | VGA_IS1_DISPLAY_DISABLED;
+        self.attr_flipflop = false;
+        self.input_status
+    }
+}
+
+#[cfg(test)]
+mod vga_test
+{
+    use super::*;
+    use std::io::Read;
+
+    fn make_vga() -> VGA {
+        let vram = vm::alloc_memory_region(VGA_WINDOW_SIZE);
+        vram.write_bytes(0, &vec![0u8; VGA_WINDOW_SIZE]);
+        VGA::new(vram, 0, vm::alloc_memory_region(VBE_TEST_VRAM_SIZE))
+    }
+
+    /* Write string at screen position as the guest would */
+    fn guest_write(dev: &VGA, pos: usize, s: &str, attr: u8) {
+        let mut cells = Vec::new();
+        for c in s.bytes() {
+            cells.push(c);
+            cells.push(attr);
+        }
+        dev.vram.write_bytes(VGA_TEXT_OFFSET + pos * 2, &cells);
+    }
+
+    #[test] fn screen_text() {
+        let dev = make_vga();
+        guest_write(&dev, 0, "Hi", 0x1F);
+        guest_write(&dev, VGA_TEXT_COLS * 24 + 78, "!!", 0x07);
+
+        let screen = dev.screen();
+        let text = screen.text();
+        assert!(text.len() == VGA_TEXT_ROWS);
+        assert!(text[0].starts_with("Hi"));
+        assert!(text[24].ends_with("!!"));
+        assert!(screen.attr_at(0, 0) == 0x1F);
+        assert!(screen.attr_at(0, 1) == 0x1F);
+        assert!(screen.attr_at(24, 79) == 0x07);
+    }
+
+    #[test] fn screen_attrs() {
+        let dev = make_vga();
+        guest_write(&dev, VGA_TEXT_COLS * 3 + 10, "Starting MS-DOS...", 0x1E);
+
+        let screen = dev.screen();
+        let attrs = screen.attrs();
+        assert!(attrs.len() == VGA_TEXT_ROWS && attrs[0].len() == VGA_TEXT_COLS);
+        assert!(attrs[3][9] == 0 && attrs[3][10] == 0x1E && attrs[3][27] == 0x1E && attrs[3][28] == 0);
+        assert!(screen.find("MS-DOS") == Some((3, 19)));
+        assert!(screen.find("PC-DOS").is_none());
+    }
+
+    /*
+     * Guest prints a line a character at a time while host waits for it
+     */
+    #[test] fn wait_for_text() {
+        let dev = make_vga();
+        let msg = "Starting MS-DOS...";
+        let mut written = 0;
+
+        let pos = poll_screen(|| {
+            if written < msg.len() {
+                guest_write(&dev, VGA_TEXT_COLS * 5, &msg[..written + 1], 0x07);
+                written += 1;
+            }
+            dev.screen()
+        }, "MS-DOS...", Duration::from_secs(5));
+        assert!(pos == Some((5, 9)));
+        assert!(written == msg.len());
+
+        let pos = poll_screen(|| dev.screen(), "C:\\>", Duration::from_millis(30));
+        assert!(pos.is_none());
+    }
+
+    /* Scrolled guest: row 6 of video memory is top of screen */
+    #[test] fn wait_for_text_scrolled() {
+        let mut dev = make_vga();
+        guest_write(&dev, 0, "Stale", 0x07);
+        guest_write(&dev, VGA_TEXT_COLS * 8 + 2, "C:\\>", 0x07);
+
+        dev.crtc_index = CRTC_START_ADDR_HI;
+        dev.write_crtc(((VGA_TEXT_COLS * 6) >> 8) as u8);
+        dev.crtc_index = CRTC_START_ADDR_LO;
+        dev.write_crtc((VGA_TEXT_COLS * 6) as u8);
+        let pos = poll_screen(|| dev.screen(), "C:\\>", Duration::from_millis(100));
+        assert!(pos == Some((2, 2)));
+        assert!(dev.screen().find("Stale").is_none());
+    }
+
+    #[test] fn crtc_registers() {
+        let mut dev = make_vga();
+
+        dev.crtc_index = CRTC_CURSOR_LOC_HI;
+        dev.write_crtc(0x01);
+        dev.crtc_index = CRTC_CURSOR_LOC_LO;
+        dev.write_crtc(0x40);
+        assert!(dev.read_crtc() == 0x40);
+        assert!(dev.cursor_location() == 0x140);
+        assert!(dev.screen().cursor == 0x140);
+
+        /* Unsupported registers float */
+        dev.crtc_index = 0x30;
+        dev.write_crtc(0x12);
+        assert!(dev.read_crtc() == 0xFF);
+    }
+
+    #[test] fn start_address() {
+        let mut dev = make_vga();
+        guest_write(&dev, VGA_TEXT_COLS, "Second", 0x07);
+
+        dev.crtc[CRTC_START_ADDR_LO as usize] = VGA_TEXT_COLS as u8;
+        let text = dev.screen().text();
+        assert!(text[0].starts_with("Second"));
+    }
+
+    #[test] fn retrace_toggles() {
+        let mut dev = make_vga();
+        let s1 = dev.read_input_status() & VGA_IS1_VRETRACE;
+        let s2 = dev.read_input_status() & VGA_IS1_VRETRACE;
+        assert!(s1 != s2);
+    }
+
+    #[test] fn cursor_shape() {
+        let mut dev = make_vga();
+        let screen = dev.screen();
+        assert!(screen.cursor_visible);
+        assert!(screen.cursor_start == 0x0D && screen.cursor_end == 0x0E);
+        assert!(screen.cursor_position() == Some((0, 0)));
+
+        dev.crtc_index = CRTC_CURSOR_START;
+        dev.write_crtc(CRTC_CURSOR_DISABLE | 0x0D);
+        let screen = dev.screen();
+        assert!(!screen.cursor_visible);
+        assert!(screen.cursor_position() == None);
+        assert!(!screen.dump().contains('_'));
+    }
+
+    #[test] fn attr_flipflop() {
+        let mut dev = make_vga();
+        assert!(dev.screen().blink);
+
+        /* Reading input status 1 puts flip-flop in index state no matter where it was */
+        dev.write_attr(0x05);
+        dev.read_input_status();
+        dev.write_attr(ATTR_PAS | ATTR_MODE_CONTROL);
+        dev.write_attr(0x04);
+        assert!(dev.attr_index == ATTR_PAS | ATTR_MODE_CONTROL);
+        assert!(dev.read_attr() == 0x04);
+        assert!(dev.attr[5] == ATTR_MODE3_DEFAULTS[5]);
+        assert!(!dev.screen().blink);
+
+        /* Next write is an index again */
+        dev.write_attr(0x01);
+        assert!(dev.attr_index == 0x01);
+        assert!(dev.attr[ATTR_MODE_CONTROL as usize] == 0x04);
+    }
+
+    /*
+     * DOS style teletype output: write a character and move hardware cursor after it
+     */
+    #[test] fn cursor_follows_output() {
+        let dev = VGADev {
+            vga: RefCell::new(make_vga()),
+            renderer: RefCell::new(None),
+            frame_renderer: RefCell::new(None),
+        };
+
+        let mut pos: u16 = VGA_TEXT_COLS as u16 * 2;
+        for c in "C:\\>dir".bytes() {
+            dev.vga.borrow().vram.write_bytes(VGA_TEXT_OFFSET + pos as usize * 2, &[c, 0x07]);
+            pos += 1;
+
+            vm::io_handler::io_write(&dev, VGA_CRTC_INDEX, vm::IoOperandType::word(((pos >> 8) << 8) | CRTC_CURSOR_LOC_HI as u16));
+            vm::io_handler::io_write(&dev, VGA_CRTC_INDEX, vm::IoOperandType::byte(CRTC_CURSOR_LOC_LO));
+            vm::io_handler::io_write(&dev, VGA_CRTC_DATA, vm::IoOperandType::byte(pos as u8));
+        }
+
+        let screen = dev.vga.borrow().screen();
+        assert!(screen.cursor == pos);
+        assert!(screen.cursor_position() == Some((2, 7)));
+        assert!(screen.dump().lines().nth(2).unwrap().starts_with("C:\\>dir_"));
+
+        vm::io_handler::io_write(&dev, VGA_CRTC_INDEX, vm::IoOperandType::byte(CRTC_CURSOR_LOC_LO));
+        assert!(vm::io_handler::io_read(&dev, VGA_CRTC_DATA, 1).unwrap_byte() == pos as u8);
+    }
+
+    /* Program mode 13h registers through io ports as BIOS would */
+    fn set_mode13h(dev: &VGADev) {
+        vm::io_handler::io_write(dev, VGA_SEQ_INDEX, vm::IoOperandType::word(((0x0E as u16) << 8) | SEQ_MEMORY_MODE as u16));
+        vm::io_handler::io_write(dev, VGA_GC_INDEX, vm::IoOperandType::word(((0x40 as u16) << 8) | GC_MODE as u16));
+        vm::io_handler::io_write(dev, VGA_GC_INDEX, vm::IoOperandType::byte(GC_MISC));
+        vm::io_handler::io_write(dev, VGA_GC_DATA, vm::IoOperandType::byte(0x05));
+    }
+
+    #[test] fn dac_palette() {
+        let mut dev = make_vga();
+
+        dev.set_dac_write_index(0x10);
+        for val in &[0x3F, 0x20, 0x01, 0x02] {
+            dev.write_dac(*val);
+        }
+        assert!(dev.dac[0x10] == [0x3F, 0x20, 0x01]);
+        assert!(dev.dac[0x11][0] == 0x02);
+        assert!(dev.dac_write_index == 0x11);
+
+        dev.set_dac_read_index(0x0F);
+        assert!(dev.dac_state == DAC_STATE_READ);
+        let rgb: Vec<u8> = (0..6).map(|_| dev.read_dac()).collect();
+        assert!(rgb == vec![0x3F, 0x3F, 0x3F, 0x3F, 0x20, 0x01]);
+    }
+
+    #[test] fn mode13h_detect() {
+        let dev = VGADev {
+            vga: RefCell::new(make_vga()),
+            renderer: RefCell::new(None),
+            frame_renderer: RefCell::new(None),
+        };
+
+        assert!(dev.vga.borrow().frame().is_none());
+        set_mode13h(&dev);
+        assert!(dev.vga.borrow().is_mode13h());
+
+        let frame = dev.vga.borrow().frame().unwrap();
+        assert!(frame.width == VGA_MODE13H_WIDTH && frame.height == VGA_MODE13H_HEIGHT);
+        assert!(frame.pixels.len() == VGA_MODE13H_WIDTH * VGA_MODE13H_HEIGHT * 3);
+    }
+
+    /*
+     * Fill framebuffer with a diagonal gradient and compare against reference image
+     */
+    #[test] fn mode13h_frame() {
+        let dev = VGADev {
+            vga: RefCell::new(make_vga()),
+            renderer: RefCell::new(None),
+            frame_renderer: RefCell::new(None),
+        };
+
+        set_mode13h(&dev);
+
+        vm::io_handler::io_write(&dev, VGA_DAC_WRITE_INDEX, vm::IoOperandType::byte(0));
+        for i in 0..DAC_ENTRIES {
+            vm::io_handler::io_write(&dev, VGA_DAC_DATA, vm::IoOperandType::byte((i & 0x3F) as u8));
+            vm::io_handler::io_write(&dev, VGA_DAC_DATA, vm::IoOperandType::byte(((i >> 2) & 0x3F) as u8));
+            vm::io_handler::io_write(&dev, VGA_DAC_DATA, vm::IoOperandType::byte(0x3F - (i & 0x3F) as u8));
+        }
+
+        let mut fb = Vec::with_capacity(VGA_MODE13H_WIDTH * VGA_MODE13H_HEIGHT);
+        for y in 0..VGA_MODE13H_HEIGHT {
+            for x in 0..VGA_MODE13H_WIDTH {
+                fb.push((x + y) as u8);
+            }
+        }
+        dev.vga.borrow().vram.write_bytes(0, &fb);
+
+        let frame = dev.vga.borrow().frame().unwrap();
+        let reference = include_bytes!("../test/vga/mode13h_gradient.ppm");
+        assert!(&frame.to_ppm()[..] == &reference[..]);
+    }
+
+    const VBE_TEST_VRAM_SIZE: usize = 2 << 20;
+
+    fn dispi_write(dev: &VGADev, index: u16, val: u16) {
+        vm::io_handler::io_write(dev, VBE_DISPI_INDEX_PORT, vm::IoOperandType::word(index));
+        vm::io_handler::io_write(dev, VBE_DISPI_DATA_PORT, vm::IoOperandType::word(val));
+    }
+
+    fn dispi_read(dev: &VGADev, index: u16) -> u16 {
+        vm::io_handler::io_write(dev, VBE_DISPI_INDEX_PORT, vm::IoOperandType::word(index));
+        vm::io_handler::io_read(dev, VBE_DISPI_DATA_PORT, 2).unwrap_word()
+    }
+
+    fn set_vbe_mode(dev: &VGADev, xres: u16, yres: u16, bpp: u16, flags: u16) {
+        dispi_write(dev, VBE_DISPI_INDEX_ENABLE, 0);
+        dispi_write(dev, VBE_DISPI_INDEX_XRES, xres);
+        dispi_write(dev, VBE_DISPI_INDEX_YRES, yres);
+        dispi_write(dev, VBE_DISPI_INDEX_BPP, bpp);
+        dispi_write(dev, VBE_DISPI_INDEX_ENABLE, VBE_DISPI_ENABLED | flags);
+    }
+
+    #[test] fn vbe_registers() {
+        let dev = VGADev {
+            vga: RefCell::new(make_vga()),
+            renderer: RefCell::new(None),
+            frame_renderer: RefCell::new(None),
+        };
+
+        /* Version probe */
+        for id in VBE_DISPI_ID0..VBE_DISPI_ID5 + 1 {
+            dispi_write(&dev, VBE_DISPI_INDEX_ID, id);
+            assert!(dispi_read(&dev, VBE_DISPI_INDEX_ID) == id);
+        }
+        dispi_write(&dev, VBE_DISPI_INDEX_ID, 0xB0C6);
+        assert!(dispi_read(&dev, VBE_DISPI_INDEX_ID) == VBE_DISPI_ID5);
+        assert!(dispi_read(&dev, VBE_DISPI_INDEX_VIDEO_MEMORY_64K) == 32);
+
+        dispi_write(&dev, VBE_DISPI_INDEX_ENABLE, VBE_DISPI_GETCAPS);
+        assert!(dispi_read(&dev, VBE_DISPI_INDEX_XRES) == VBE_DISPI_MAX_XRES);
+        assert!(dispi_read(&dev, VBE_DISPI_INDEX_YRES) == VBE_DISPI_MAX_YRES);
+        assert!(dispi_read(&dev, VBE_DISPI_INDEX_BPP) == VBE_DISPI_MAX_BPP);
+        dispi_write(&dev, VBE_DISPI_INDEX_ENABLE, 0);
+
+        /* Unsupported values are dropped */
+        dispi_write(&dev, VBE_DISPI_INDEX_XRES, 801);
+        dispi_write(&dev, VBE_DISPI_INDEX_YRES, 4000);
+        dispi_write(&dev, VBE_DISPI_INDEX_BPP, 12);
+        assert!(dispi_read(&dev, VBE_DISPI_INDEX_XRES) == 640);
+        assert!(dispi_read(&dev, VBE_DISPI_INDEX_YRES) == 480);
+        assert!(dispi_read(&dev, VBE_DISPI_INDEX_BPP) == 8);
+
+        /* 1024x768x32 needs 3M of video memory */
+        set_vbe_mode(&dev, 1024, 768, 32, VBE_DISPI_LFB_ENABLED);
+        assert!(dispi_read(&dev, VBE_DISPI_INDEX_ENABLE) == 0);
+        assert!(!dev.vga.borrow().dispi.is_enabled());
+
+        set_vbe_mode(&dev, 800, 600, 16, VBE_DISPI_LFB_ENABLED);
+        assert!(dispi_read(&dev, VBE_DISPI_INDEX_ENABLE) == VBE_DISPI_ENABLED | VBE_DISPI_LFB_ENABLED);
+        assert!(dispi_read(&dev, VBE_DISPI_INDEX_VIRT_WIDTH) == 800);
+        assert!(dispi_read(&dev, VBE_DISPI_INDEX_VIRT_HEIGHT) == (VBE_TEST_VRAM_SIZE / 1600) as u16);
+
+        /* Mode is locked while enabled, panning is not */
+        dispi_write(&dev, VBE_DISPI_INDEX_XRES, 640);
+        assert!(dispi_read(&dev, VBE_DISPI_INDEX_XRES) == 800);
+        dispi_write(&dev, VBE_DISPI_INDEX_Y_OFFSET, 10);
+        assert!(dispi_read(&dev, VBE_DISPI_INDEX_Y_OFFSET) == 10);
+        dispi_write(&dev, VBE_DISPI_INDEX_Y_OFFSET, 711);
+        assert!(dispi_read(&dev, VBE_DISPI_INDEX_Y_OFFSET) == 10);
+        assert!(dev.vga.borrow().dispi.display_start() == 10 * 1600);
+
+        dispi_write(&dev, VBE_DISPI_INDEX_VIRT_WIDTH, 1024);
+        assert!(dispi_read(&dev, VBE_DISPI_INDEX_VIRT_HEIGHT) == (VBE_TEST_VRAM_SIZE / 2048) as u16);
+    }
+
+    /*
+     * Set 640x480x32 with LFB, draw a pattern and check the dumped frame
+     */
+    #[test] fn vbe_frame_dump() {
+        let dir = ::std::env::temp_dir().join(format!("xvm-vbe-test-{}", ::std::process::id()));
+        ::std::fs::create_dir_all(&dir).unwrap();
+
+        let dev = VGADev {
+            vga: RefCell::new(make_vga()),
+            renderer: RefCell::new(None),
+            frame_renderer: RefCell::new(Some(Box::new(PpmDumper::new(dir.to_str().unwrap())))),
+        };
+
+        /* Enabling clears video memory unless told otherwise */
+        let lfb = dev.vga.borrow().dispi.vram.clone();
+        lfb.write_bytes(0, &[0xAA; 16]);
+        set_vbe_mode(&dev, 640, 480, 32, VBE_DISPI_LFB_ENABLED | VBE_DISPI_NOCLEARMEM);
+        let mut head = [0u8; 16];
+        lfb.read_bytes(0, &mut head);
+        assert!(head == [0xAA; 16]);
+
+        set_vbe_mode(&dev, 640, 480, 32, VBE_DISPI_LFB_ENABLED);
+        lfb.read_bytes(0, &mut head);
+        assert!(head == [0; 16]);
+
+        let mut fb = Vec::with_capacity(640 * 480 * 4);
+        let mut expected = b"P6\n640 480\n255\n".to_vec();
+        for y in 0..480 {
+            for x in 0..640 {
+                let (r, g, b) = ((x & 0xFF) as u8, (y & 0xFF) as u8, ((x ^ y) & 0xFF) as u8);
+                fb.extend_from_slice(&[b, g, r, 0]);
+                expected.extend_from_slice(&[r, g, b]);
+            }
+        }
+        lfb.write_bytes(0, &fb);
+
+        dev.refresh();
+        let mut dumped = Vec::new();
+        ::std::fs::File::open(dir.join("frame00000.ppm")).unwrap().read_to_end(&mut dumped).unwrap();
+        ::std::fs::remove_dir_all(&dir).unwrap();
+        assert!(dumped == expected);
+    }
+
+    #[test] fn vbe_pixel_formats() {
+        let dev = VGADev {
+            vga: RefCell::new(make_vga()),
+            renderer: RefCell::new(None),
+            frame_renderer: RefCell::new(None),
+        };
+
+        set_vbe_mode(&dev, 8, 1, 16, VBE_DISPI_LFB_ENABLED);
+        dev.vga.borrow().dispi.vram.write_bytes(0, &[0x00, 0xF8, 0xE0, 0x07, 0x1F, 0x00, 0xFF, 0xFF]);
+        let frame = dev.vga.borrow().frame().unwrap();
+        assert!(frame.width == 8 && frame.height == 1);
+        assert!(frame.pixels[..12] == [0xFF, 0, 0, 0, 0xFF, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF]);
+
+        set_vbe_mode(&dev, 8, 1, 15, VBE_DISPI_LFB_ENABLED);
+        dev.vga.borrow().dispi.vram.write_bytes(0, &[0x00, 0x7C, 0xE0, 0x03]);
+        let frame = dev.vga.borrow().frame().unwrap();
+        assert!(frame.pixels[..6] == [0xFF, 0, 0, 0, 0xFF, 0]);
+
+        /* 8 bit DAC keeps full palette values */
+        set_vbe_mode(&dev, 8, 1, 8, VBE_DISPI_LFB_ENABLED | VBE_DISPI_8BIT_DAC);
+        vm::io_handler::io_write(&dev, VGA_DAC_WRITE_INDEX, vm::IoOperandType::byte(1));
+        for val in &[0x80, 0x40, 0xC0] {
+            vm::io_handler::io_write(&dev, VGA_DAC_DATA, vm::IoOperandType::byte(*val));
+        }
+        dev.vga.borrow().dispi.vram.write_bytes(0, &[1]);
+        let frame = dev.vga.borrow().frame().unwrap();
+        assert!(frame.pixels[..3] == [0x80, 0x40, 0xC0]);
+    }
+
+    fn seq_write(dev: &VGADev, index: u8, val: u8) {
+        vm::io_handler::io_write(dev, VGA_SEQ_INDEX, vm::IoOperandType::word(((val as u16) << 8) | index as u16));
+    }
+
+    fn gc_write(dev: &VGADev, index: u8, val: u8) {
+        vm::io_handler::io_write(dev, VGA_GC_INDEX, vm::IoOperandType::byte(index));
+        vm::io_handler::io_write(dev, VGA_GC_DATA, vm::IoOperandType::byte(val));
+    }
+
+    /* Map plane 2 at 0xA0000 the way VGA BIOS does before touching glyphs */
+    fn map_font_plane(dev: &VGADev) {
+        seq_write(dev, 0x00, 0x01);
+        seq_write(dev, SEQ_MAP_MASK, 0x04);
+        seq_write(dev, SEQ_MEMORY_MODE, 0x07);
+        seq_write(dev, 0x00, 0x03);
+        gc_write(dev, GC_READ_MAP, 0x02);
+        gc_write(dev, GC_MODE, 0x00);
+        gc_write(dev, GC_MISC, 0x04);
+    }
+
+    fn unmap_font_plane(dev: &VGADev) {
+        seq_write(dev, 0x00, 0x01);
+        seq_write(dev, SEQ_MAP_MASK, 0x03);
+        seq_write(dev, SEQ_MEMORY_MODE, 0x03);
+        seq_write(dev, 0x00, 0x03);
+        gc_write(dev, GC_READ_MAP, 0x00);
+        gc_write(dev, GC_MODE, 0x10);
+        gc_write(dev, GC_MISC, 0x0E);
+    }
+
+    /*
+     * Upload a font through plane 2, read it back as host and as guest, and draw it
+     */
+    #[test] fn font_upload() {
+        let dev = VGADev {
+            vga: RefCell::new(make_vga()),
+            renderer: RefCell::new(None),
+            frame_renderer: RefCell::new(None),
+        };
+
+        /* No font, no text mode frame */
+        assert!(dev.vga.borrow().frame().is_none());
+
+        let mut font = vec![0u8; VGA_FONT_SIZE];
+        for ch in 0..VGA_FONT_GLYPHS {
+            for line in 0..16 {
+                font[ch * VGA_FONT_GLYPH_SIZE + line] = (ch ^ line) as u8;
+            }
+        }
+        font[b'A' as usize * VGA_FONT_GLYPH_SIZE] = 0x81;
+
+        let vram = dev.vga.borrow().vram.clone();
+        vram.write_bytes(0, &[0x5A; 16]);
+        map_font_plane(&dev);
+        vram.write_bytes(0, &font);
+        assert!(dev.vga.borrow().font_plane() == font);
+        unmap_font_plane(&dev);
+
+        /* Window contents are back, glyphs are kept aside */
+        let mut head = [0u8; 16];
+        vram.read_bytes(0, &mut head);
+        assert!(head == [0x5A; 16]);
+        assert!(dev.vga.borrow().font_plane() == font);
+
+        /* Guest maps plane 2 again to read glyphs back */
+        map_font_plane(&dev);
+        let mut readback = vec![0u8; VGA_FONT_SIZE];
+        vram.read_bytes(0, &mut readback);
+        assert!(readback == font);
+        unmap_font_plane(&dev);
+
+        /* Top left pixels of 'A' with bright white on blue */
+        guest_write(&dev.vga.borrow(), 0, "A", 0x1F);
+        let frame = dev.vga.borrow().frame().unwrap();
+        assert!(frame.width == VGA_TEXT_COLS * 8 && frame.height == VGA_TEXT_ROWS * 16);
+        assert!(frame.pixels[..6] == [0xFF, 0xFF, 0xFF, 0x00, 0x00, 0xAA]);
+        assert!(frame.pixels[7 * 3..8 * 3] == [0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test] fn cp437() {
+        let table = cp437_table();
+        assert!(table['A' as usize] == 'A');
+        assert!(table[0xDB] == '█');
+        assert!(table[0xC4] == '─');
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct VGADev
+{
+    vga: RefCell<VGA>,
+    renderer: RefCell<Option<Box<TextRenderer>>>,
+    frame_renderer: RefCell<Option<Box<FrameRenderer>>>,
+}
+
+impl VGADev
+{
+    fn refresh(&self) {
+        let vga = self.vga.borrow();
+
+        if !vga.is_graphics_mode() && !vga.dispi.is_enabled() {
+            match *self.renderer.borrow_mut() {
+                Some(ref mut renderer) => renderer.render(&vga.screen()),
+                None => {},
+            }
+        }
+
+        /* Text mode frames are only available once guest loaded a font */
+        match &mut *self.frame_renderer.borrow_mut() {
+            &mut Some(ref mut renderer) => match vga.frame() {
+                Some(frame) => renderer.render_frame(&frame),
+                None => {},
+            },
+            &mut None => {},
+        }
+    }
+}
+
+#[allow(unused_variables)]
+impl vm::io_handler for VGADev
+{
+    fn io_read(&self, port: u16, size: u8) -> vm::IoOperandType
+    {
+        let mut dev = self.vga.borrow_mut();
+
+        if port == VBE_DISPI_INDEX_PORT || port == VBE_DISPI_DATA_PORT {
+            let val = if port == VBE_DISPI_INDEX_PORT { dev.dispi.index } else { dev.dispi.read_reg() };
+            return match size {
+                1 => vm::IoOperandType::byte(val as u8),
+                _ => vm::IoOperandType::word(val),
+            };
+        }
+
+        vm::IoOperandType::byte(
+            match port {
+                VGA_ATTR_INDEX => dev.attr_index,
+                VGA_ATTR_DATA => dev.read_attr(),
+                VGA_MISC_WRITE => 0, // Input status 0
+                VGA_SEQ_INDEX => dev.seq_index,
+                VGA_SEQ_DATA => VGA::read_indexed(&dev.seq, dev.seq_index),
+                VGA_DAC_READ_INDEX => dev.dac_state,
+                VGA_DAC_WRITE_INDEX => dev.dac_write_index,
+                VGA_DAC_DATA => dev.read_dac(),
+                VGA_MISC_READ => dev.misc_output,
+                VGA_GC_INDEX => dev.gc_index,
+                VGA_GC_DATA => VGA::read_indexed(&dev.gc, dev.gc_index),
+                VGA_CRTC_INDEX => dev.crtc_index,
+                VGA_CRTC_DATA => dev.read_crtc(),
+                VGA_INPUT_STATUS_1 => dev.read_input_status(),
+                _ => panic!(),
+            }
+        )
+    }
+
+    fn io_write(&self, port: u16, data: vm::IoOperandType)
+    {
+        let mut dev = self.vga.borrow_mut();
+
+        if port == VBE_DISPI_INDEX_PORT || port == VBE_DISPI_DATA_PORT {
+            let val = match data {
+                vm::IoOperandType::byte(val) => val as u16,
+                vm::IoOperandType::word(val) => val,
+                vm::IoOperandType::dword(val) => val as u16,
+            };
+
+            if port == VBE_DISPI_INDEX_PORT {
+                dev.dispi.index = val;
+            } else {
+                dev.dispi.write_reg(val);
+            }
+            return;
+        }
+
+        match data {
+            /* Word write to index port sets index and data in one go */
+            vm::IoOperandType::word(val) if port == VGA_CRTC_INDEX => {
+                dev.crtc_index = val as u8;
+                dev.write_crtc((val >> 8) as u8);
+                return;
+            },
+            vm::IoOperandType::word(val) if port == VGA_SEQ_INDEX => {
+                dev.seq_index = val as u8;
+                dev.write_seq(val as u8, (val >> 8) as u8);
+                return;
+            },
+            vm::IoOperandType::word(val) if port == VGA_GC_INDEX => {
+                dev.gc_index = val as u8;
+                dev.write_gc(val as u8, (val >> 8) as u8);
+                return;
+            },
+            _ => {},
+        }
+
+        let data8 = data.unwrap_byte();
+        match port {
+            VGA_ATTR_INDEX => dev.write_attr(data8),
+            VGA_ATTR_DATA => {}, // Read only
+            VGA_MISC_WRITE => dev.misc_output = data8,
+            VGA_SEQ_INDEX => dev.seq_index = data8,
+            VGA_SEQ_DATA => { let index = dev.seq_index; dev.write_seq(index, data8) },
+            VGA_DAC_READ_INDEX => dev.set_dac_read_index(data8),
+            VGA_DAC_WRITE_INDEX => dev.set_dac_write_index(data8),
+            VGA_DAC_DATA => dev.write_dac(data8),
+            VGA_GC_INDEX => dev.gc_index = data8,
+            VGA_GC_DATA => { let index = dev.gc_index; dev.write_gc(index, data8) },
+            VGA_MISC_READ => {}, // Read only
+            VGA_CRTC_INDEX => dev.crtc_index = data8,
+            VGA_CRTC_DATA => dev.write_crtc(data8),
+            VGA_INPUT_STATUS_1 => {}, // Feature control write, ignored
+            _ => panic!(),
+        }
+    }
+}
+
+/*
+ * VGA device instance for renderer event and host side accessors
+ */
+static mut VGA_DEV: Option<*const VGADev> = None;
+
+fn get_vga() -> Option<&'static VGADev>
+{
+    unsafe {
+        match VGA_DEV {
+            Some(dev) => Some(mem::transmute(dev)),
+            None => None,
+        }
+    }
+}
+
+fn refresh_event(ev: event::Event)
+{
+    match get_vga() {
+        Some(dev) => dev.refresh(),
+        None => return,
+    }
+
+    event::schedule_event(VGA_REFRESH_PERIOD_US, ev);
+}
+
+/**
+ * Get current guest screen contents, see TextScreen
+ */
+pub fn screen() -> Option<TextScreen>
+{
+    get_vga().map(|dev| dev.vga.borrow().screen())
+}
+
+/**
+ * Get current guest screen as text rows
+ */
+pub fn screen_text() -> Option<Vec<String>>
+{
+    screen().map(|screen| screen.text())
+}
+
+/**
+ * Get current guest screen attributes, rows parallel to screen_text
+ */
+pub fn screen_attrs() -> Option<Vec<Vec<u8>>>
+{
+    screen().map(|screen| screen.attrs())
+}
+
+/* Take screen snapshots until pattern shows up or time runs out */
+fn poll_screen<F: FnMut() -> TextScreen>(mut snapshot: F, pattern: &str, timeout: Duration) -> Option<(usize, usize)>
+{
+    let start = Instant::now();
+    loop {
+        match snapshot().find(pattern) {
+            Some(pos) => return Some(pos),
+            None => {},
+        }
+
+        if start.elapsed() >= timeout {
+            return None;
+        }
+
+        thread::sleep(Duration::from_millis(VGA_TEXT_POLL_MS));
+    }
+}
+
+/**
+ * Wait until guest screen shows pattern, return its row and column or None on timeout.
+ * Screen is read with VM paused so rows are never half updated. Must not be called from vcpu thread.
+ */
+pub fn wait_for_text(pattern: &str, timeout: Duration) -> Option<(usize, usize)>
+{
+    let dev = match get_vga() {
+        Some(dev) => dev,
+        None => return None,
+    };
+
+    poll_screen(|| {
+        let _pause = vm::pause();
+        dev.vga.borrow().screen()
+    }, pattern, timeout)
+}
+
+/**
+ * Get current guest graphics frame, None if not in a supported graphics mode.
+ * Text mode is drawn only after the guest loaded a font.
+ */
+pub fn frame() -> Option<Frame>
+{
+    get_vga().and_then(|dev| dev.vga.borrow().frame())
+}
+
+/**
+ * Get guest font plane, VGA_FONT_GLYPH_SIZE bytes per glyph for VGA_FONT_GLYPHS characters
+ */
+pub fn font() -> Option<Vec<u8>>
+{
+    get_vga().map(|dev| dev.vga.borrow().font_plane())
+}
+
+/**
+ * Dump current guest graphics frame to PPM file
+ */
+pub fn dump_frame(path: &str) -> ::std::io::Result<()>
+{
+    match frame() {
+        Some(frame) => frame.write_ppm(path),
+        None => Err(::std::io::Error::new(::std::io::ErrorKind::Other, "no frame in current video mode")),
+    }
+}
+
+/**
+ * Init VGA device
+ * In headless mode nothing is rendered on host, use screen_text to inspect guest screen.
+ * Graphics frames are dumped to config.frame_dump directory if set.
+ * VBE linear framebuffer is mapped at config.vbe_lfb.
+ */
+pub fn init(config: &config::VmConfig)
+{
+    /* Reuse guest RAM if it already covers video memory window */
+    let (vram, offset) = match vm::find_memory_mapping(VGA_WINDOW_BASE) {
+        Some(mapping) => (mapping.region.clone(), (VGA_WINDOW_BASE - mapping.base) as usize),
+        None => {
+            let region = vm::alloc_memory_region(VGA_WINDOW_SIZE);
+            vm::map_memory_region(VGA_WINDOW_BASE, HV_MEMORY_READ | HV_MEMORY_WRITE, region.clone());
+            (region, 0)
+        }
+    };
+
+    let renderer: Option<Box<TextRenderer>> = if config.headless {
+        None
+    } else {
+        Some(Box::new(TerminalRenderer::new()))
+    };
+
+    let frame_renderer: Option<Box<FrameRenderer>> = match config.frame_dump {
+        Some(ref dir) => Some(Box::new(PpmDumper::new(dir))),
+        None => None,
+    };
+
+    let need_refresh = renderer.is_some() || frame_renderer.is_some();
+
+    /* Linear framebuffer is plain RAM, guest draws at memory speed */
+    let lfb = vm::alloc_memory_region(VBE_VRAM_SIZE);
+    vm::map_memory_region(config.vbe_lfb, HV_MEMORY_READ | HV_MEMORY_WRITE, lfb.clone());
+
+    let dev = Rc::new(VGADev {
+        vga: RefCell::new(VGA::new(vram, offset, lfb)),
+        renderer: RefCell::new(renderer),
+        frame_renderer: RefCell::new(frame_renderer),
+    });
+
+    unsafe {
+        VGA_DEV = Some(&*dev as *const VGADev);
+    }
+
+    vm::register_io_region(dev.clone(), VGA_ATTR_INDEX, 1);
+    vm::register_io_region(dev.clone(), VGA_ATTR_DATA, 1);
+    vm::register_io_region(dev.clone(), VGA_MISC_WRITE, 1);
+    vm::register_io_region(dev.clone(), VGA_SEQ_INDEX, 1);
+    vm::register_io_region(dev.clone(), VGA_SEQ_DATA, 1);
+    vm::register_io_region(dev.clone(), VGA_DAC_READ_INDEX, 1);
+    vm::register_io_region(dev.clone(), VGA_DAC_WRITE_INDEX, 1);
+    vm::register_io_region(dev.clone(), VGA_DAC_DATA, 1);
+    vm::register_io_region(dev.clone(), VGA_MISC_READ, 1);
+    vm::register_io_region(dev.clone(), VGA_GC_INDEX, 1);
+    vm::register_io_region(dev.clone(), VGA_GC_DATA, 1);
+    vm::register_io_region(dev.clone(), VGA_CRTC_INDEX, 1);
+    vm::register_io_region(dev.clone(), VGA_CRTC_DATA, 1);
+    vm::register_io_region(dev.clone(), VGA_INPUT_STATUS_1, 1);
+    vm::register_io_region(dev.clone(), VBE_DISPI_INDEX_PORT, 1);
+    vm::register_io_region(dev.clone(), VBE_DISPI_DATA_PORT, 1);
+
+    if need_refresh {
+        event::schedule_event(VGA_REFRESH_PERIOD_US, event::create_event(refresh_event));
+    }
+}
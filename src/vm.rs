@@ -4,13 +4,42 @@
  * TODO: describe locking policy
  */
 
-use std::sync::{Arc, Mutex, atomic};
+use std::sync::{Arc, Mutex, Condvar, atomic};
 use std::rc::Rc;
+use std::cell::RefCell;
 use std::mem;
+use std::fmt;
+use std::thread;
+use std::fs::File;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::collections::HashMap;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use rlibc::*;
 use hypervisor_framework::*;
 use util::bitmap::*;
 use event;
+use inject;
+use breakpoint;
+use trace;
+use crash;
+use disasm;
+use eventlog;
+use summary;
+use devstate;
+use clock;
+use hang;
+use symbols;
+use tracefilter;
+use metrics;
+use pic;
+use coredump;
+use snapshot;
+use record;
+use replay;
+use livesnap;
+use gdbstub;
 
 extern "C" {
     fn valloc(size: usize) -> *mut ::std::os::raw::c_void;
@@ -26,8 +55,12 @@ extern "C" {
 pub struct memory_region {
     pub data: hv_uvaddr_t,  // Host base address
     pub size: usize,        // Region size in bytes
+    dirty: Mutex<Option<Bitmap>>,   // Pages written since dirty page log was started, None when not logging
 }
 
+/* Granularity of dirty page log, the same as sparse snapshot memory */
+pub const DIRTY_PAGE_SIZE: usize = 4096;
+
 impl memory_region {
 
     /**
@@ -69,8 +102,57 @@ impl memory_region {
             memcpy((self.data as *mut u8).offset(offset as isize), buf.as_ptr(), towrite);
         }
 
+        self.mark_dirty(offset, towrite);
         towrite
     }
+
+    /**
+     * Start logging written pages anew, with none written so far
+     * Host writes through write_bytes() are logged here, guest writes are logged by the VM as they fault.
+     */
+    pub fn start_dirty_log(&self) {
+        let pages = (self.size + DIRTY_PAGE_SIZE - 1) / DIRTY_PAGE_SIZE;
+        *self.dirty.lock().unwrap() = Some(Bitmap::new(pages));
+    }
+
+    /**
+     * Indices of pages written since dirty page log was started, None when not logging
+     */
+    pub fn dirty_pages(&self) -> Option<Vec<usize>> {
+        let pages = (self.size + DIRTY_PAGE_SIZE - 1) / DIRTY_PAGE_SIZE;
+        self.dirty.lock().unwrap().as_ref().map(|log| (0..pages).filter(|&page| log.is_set(page)).collect())
+    }
+
+    /**
+     * Indices of pages written since dirty page log was started or last taken, log goes on with none written
+     * None when not logging
+     */
+    pub fn take_dirty_pages(&self) -> Option<Vec<usize>> {
+        let pages = (self.size + DIRTY_PAGE_SIZE - 1) / DIRTY_PAGE_SIZE;
+        self.dirty.lock().unwrap().as_mut().map(|log| {
+            let written = (0..pages).filter(|&page| log.is_set(page)).collect();
+            *log = Bitmap::new(pages);
+            written
+        })
+    }
+
+    /**
+     * Whether page at index could have been written since dirty page log was started, always when not logging
+     */
+    pub fn is_page_dirty(&self, page: usize) -> bool {
+        self.dirty.lock().unwrap().as_ref().map_or(true, |log| log.is_set(page))
+    }
+
+    /**
+     * Log pages in byte range as written
+     */
+    pub fn mark_dirty(&self, offset: usize, len: usize) {
+        if let Some(ref mut log) = *self.dirty.lock().unwrap() {
+            for page in offset / DIRTY_PAGE_SIZE..(offset + len + DIRTY_PAGE_SIZE - 1) / DIRTY_PAGE_SIZE {
+                log.set(page);
+            }
+        }
+    }
 }
 
 /**
@@ -112,6 +194,33 @@ pub struct io_region
     ops: Rc<io_handler>,    // Instance of io_handler for this region
 }
 
+/**
+ * MMIO handler trait
+ * Instances of this trait register as handlers for guest physical address ranges not backed by RAM
+ */
+pub trait mmio_handler
+{
+    /**
+     * Read from MMIO address
+     */
+    fn mmio_read(&self, addr: hv_gpaddr_t, size: u8) -> u64;
+
+    /**
+     * Write to MMIO address
+     */
+    fn mmio_write(&self, addr: hv_gpaddr_t, size: u8, data: u64);
+}
+
+/**
+ * Guest MMIO address space region
+ */
+pub struct mmio_region
+{
+    base: hv_gpaddr_t,
+    size: u64,
+    ops: Rc<mmio_handler>,
+}
+
 /**
  * Interrupt controller trait
  *
@@ -123,16 +232,290 @@ pub trait interrupt_controller
     /**
      * Assert given IRQ line.
      * \param irq   IRQ line to assert
+     * \param id    Assertion id for delivery events, None without event log
      */
-    fn assert_irq(&self, irq: u8);
+    fn assert_irq(&self, irq: u8, id: Option<u64>);
 
     /**
-     * Notify interrupt controller that interrupt vector has been injected in guest
+     * Notify interrupt controller that interrupt vector is being injected in guest
      * \param vec   Interrupt vector that was previously raise with raise_external_interrupt
+     * \return      Vector guest gets, spurious vector if controller no longer has vec to deliver
+     */
+    fn ack(&self, vec: u8) -> u8;
+}
+
+/**
+ * Message signaled interrupt controller trait
+ *
+ * Instances of this trait take interrupt messages straight from devices, bypassing IRQ lines.
+ */
+pub trait msi_controller
+{
+    /**
+     * Deliver interrupt message, false if it is invalid or rejected
+     * \param addr  Message address, selects destination
+     * \param data  Message data, vector and delivery mode
+     */
+    fn deliver_msi(&self, addr: u64, data: u32) -> bool;
+}
+
+/**
+ * Message signaled interrupt of a device
+ *
+ * Devices keep a copy and signal it whenever they need an interrupt, without holding on to any controller.
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct MsiHandle
+{
+    addr: u64,
+    data: u32,
+}
+
+impl MsiHandle
+{
+    /**
+     * Signal interrupt, false if there is no controller to take it or it was rejected
+     */
+    pub fn signal(&self) -> bool
+    {
+        inject_msi(self.addr, self.data)
+    }
+}
+
+/**
+ * Host input device trait
+ *
+ * Instances of this trait accept input events from host side and deliver them to guest.
+ */
+pub trait input_device
+{
+    /**
+     * Report relative mouse movement.
+     * \param dx       Horizontal movement, positive is right
+     * \param dy       Vertical movement, positive is up
+     * \param dz       Wheel movement, positive is away from user
+     * \param buttons  Pressed buttons mask (MOUSE_BUTTON_*)
+     *
+     * Wheel and buttons 4 and 5 are dropped unless guest enabled mouse extensions reporting them.
+     */
+    fn mouse_event(&self, dx: i32, dy: i32, dz: i32, buttons: u8);
+
+    /**
+     * Report key press or release.
+     * \param code     Key scancode in set 2, extended keys as 0xE0xx
+     * \param pressed  Key went down
+     */
+    fn key_event(&self, code: u16, pressed: bool);
+
+    /**
+     * Keyboard LEDs as last set by guest (KBD_LED_*)
+     */
+    fn keyboard_leds(&self) -> u8;
+}
+
+/**
+ * Reset handler trait
+ *
+ * Instances of this trait are notified when guest platform reset happens so that device models
+ * can return to their power-on state.
+ */
+pub trait reset_handler
+{
+    /**
+     * Reset device to power-on state
+     */
+    fn reset(&self);
+}
+
+/**
+ * Pause handler trait
+ *
+ * Instances of this trait are notified on vcpu thread when it parks for host pause requests and when
+ * it is about to return to guest, so state that follows host time can leave the pause out.
+ */
+pub trait pause_handler
+{
+    /**
+     * Vcpu thread is about to park
+     */
+    fn paused(&self);
+
+    /**
+     * Host dropped all pause guards, vcpu thread goes back to guest
+     */
+    fn resumed(&self);
+}
+
+/**
+ * Device statistics trait
+ *
+ * Devices moving guest data, like disks and serial lines, register instances of this trait to have their
+ * totals in run summary.
+ */
+pub trait stats_handler
+{
+    /**
+     * Bytes moved since VM was created, they aren't cleared by reset
+     */
+    fn stats(&self) -> summary::DeviceStats;
+}
+
+/**
+ * Device state trait
+ *
+ * Instances of this trait describe device state for inspection and snapshots, see devstate.
+ */
+pub trait state_handler
+{
+    /**
+     * Device state, None if device is in the middle of an update
+     */
+    fn device_state(&self) -> Option<devstate::DeviceState>;
+
+    /**
+     * Version of state fields, saved with them in snapshots, goes up as fields change meaning
+     */
+    fn state_version(&self) -> u32 {
+        1
+    }
+
+    /**
+     * State saved at version brought to the version after it, restore goes through these up to state_version()
+     * Devices that can't bring a version forward leave this as is, error says why when they can't.
+     */
+    fn migrate_state(&self, version: u32, _state: devstate::DeviceState) -> Result<devstate::DeviceState, String> {
+        Err(format!("nothing migrates version {}", version))
+    }
+
+    /**
+     * What restore does with saved fields device_state() doesn't give, e.g. ones a newer VMM saved: drop them,
+     * or fail for devices that can't be restored without knowing all of their state
+     */
+    fn unknown_fields(&self) -> devstate::UnknownFields {
+        devstate::UnknownFields::Ignore
+    }
+
+    /**
+     * Take state device_state() gave, from a snapshot being restored
+     * Interrupts and timers the state has pending wait for restored(), other devices may not have their
+     * state yet. Devices that can't be restored leave this as is.
+     */
+    fn restore_state(&self, _state: &devstate::DeviceState) -> Result<(), String> {
+        Err(String::from("Device can't be restored from a snapshot"))
+    }
+
+    /**
+     * Every device has its restored state, raise interrupts and arm timers it has pending
+     */
+    fn restored(&self) {
+    }
+}
+
+/**
+ * PCI function trait
+ *
+ * Instances of this trait own configuration space of a single PCI device function.
+ */
+pub trait pci_function
+{
+    /**
+     * Read configuration space dword
+     * \param reg     Dword aligned register offset
+     */
+    fn config_read(&self, reg: u8) -> u32;
+
+    /**
+     * Write configuration space dword
+     * \param reg     Dword aligned register offset
+     * \param val     Value shifted to its position within the dword
+     * \param mask    Bytes of the dword actually written by guest
+     */
+    fn config_write(&self, reg: u8, val: u32, mask: u32);
+}
+
+/**
+ * PCI bus/device/function address
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PciAddress
+{
+    pub bus: u8,
+    pub device: u8,     // 0-31
+    pub function: u8,   // 0-7
+}
+
+/**
+ * Registry of PCI functions present in the system
+ */
+pub struct PciBus
+{
+    functions: Vec<(PciAddress, Rc<pci_function>)>,
+}
+
+impl PciBus
+{
+    pub fn new() -> PciBus {
+        PciBus {
+            functions: Vec::new(),
+        }
+    }
+
+    pub fn register(&mut self, addr: PciAddress, func: Rc<pci_function>) {
+        assert!(addr.device < 32 && addr.function < 8);
+        assert!(self.lookup(addr).is_none());
+        self.functions.push((addr, func));
+    }
+
+    fn lookup(&self, addr: PciAddress) -> Option<Rc<pci_function>> {
+        self.functions.iter().find(|f| f.0 == addr).map(|f| f.1.clone())
+    }
+
+    /**
+     * Configuration read, absent functions float high
+     */
+    pub fn config_read(&self, addr: PciAddress, reg: u8) -> u32 {
+        match self.lookup(addr) {
+            Some(func) => func.config_read(reg & !3),
+            None => 0xFFFFFFFF,
+        }
+    }
+
+    /**
+     * Configuration write, absent functions ignore it
      */
-    fn ack(&self, vec: u8);
+    pub fn config_write(&self, addr: PciAddress, reg: u8, val: u32, mask: u32) {
+        if let Some(func) = self.lookup(addr) {
+            func.config_write(reg & !3, val, mask);
+        }
+    }
 }
 
+pub const MOUSE_BUTTON_LEFT: u8     = 0x1;
+pub const MOUSE_BUTTON_RIGHT: u8    = 0x2;
+pub const MOUSE_BUTTON_MIDDLE: u8   = 0x4;
+pub const MOUSE_BUTTON_4: u8        = 0x8;
+pub const MOUSE_BUTTON_5: u8        = 0x10;
+
+pub const KBD_LED_SCROLL_LOCK: u8   = 0x1;
+pub const KBD_LED_NUM_LOCK: u8      = 0x2;
+pub const KBD_LED_CAPS_LOCK: u8     = 0x4;
+
+/* Message signaled interrupt address window, destination APIC ID in bits 12-19 */
+pub const MSI_ADDR_BASE: u64        = 0xFEE00000;
+
+/* IRQ lines counted for inspection, ISA lines and IOAPIC pins */
+pub const IRQ_LINES: usize          = 24;
+
+/* IRQ lines of the PIC pair */
+pub const ISA_IRQ_LINES: usize      = 16;
+
+// Guest interruptibility state
+const INTERRUPTIBILITY_STI: u64     = 1 << 0;
+const INTERRUPTIBILITY_MOV_SS: u64  = 1 << 1;
+const INTERRUPTIBILITY_NMI: u64     = 1 << 3;
+
+const RFLAGS_IF: u64                = 1 << 9;
+
 /**
  * VM internal state for owning process
  *
@@ -143,18 +526,80 @@ pub trait interrupt_controller
  * TODO: a better lookup for memory mappings
  */
 struct vm {
-    /* HV vcpu id */
+    /* HV vcpu id and the thread that created it, vcpu registers are only accessible from there */
     vcpu: hv_vcpuid_t,
+    vcpu_thread: thread::ThreadId,
 
     /* Interrupt state */
     pic: Option<Rc<interrupt_controller>>,
+    msi: Option<Rc<msi_controller>>,
     pending_ext_ints: Bitmap,
+    pending_ext_ids: Vec<Option<u64>>,      // IRQ assertion each raised vector delivers
+    raw_ext_ints: Bitmap,                   // Raised vectors injected by hand, no controller to ack
+
+    /* Host input */
+    input: Option<Rc<input_device>>,
+
+    /* A20 gate state */
+    a20_enabled: bool,
+
+    /* Guest reset was requested by a device and will be performed on next exit */
+    reset_pending: bool,
+    reset_handlers: Vec<Rc<reset_handler>>,
+
+    /* Notified around host pause requests */
+    pause_handlers: Vec<Rc<pause_handler>>,
+
+    /* Guest or a device asked to terminate VM */
+    exit_pending: Option<VmExit>,
+
+    /* Assertions of each IRQ line, for inspection */
+    irq_counts: [u64; IRQ_LINES],
+
+    /* Exception and NMI to inject on next entries */
+    exception_pending: Option<inject::Exception>,
+    nmi_pending: bool,
+
+    /* Single step in progress and whether it can use monitor trap flag */
+    step: Option<SingleStep>,
+    step_with_mtf: bool,
+
+    /* Guest execution, I/O and vector breakpoints, port access and vector stopped at one */
+    breakpoints: breakpoint::BreakpointTable,
+    pending_io: Option<PendingIo>,
+    pending_vector: Option<u8>,
+    resumed_vector: Option<u8>,         // Vector resumed from its breakpoint, taken without stopping again
+    resumed_breakpoint: Option<hv_gpaddr_t>,    // Breakpoint guest stopped at without hitting it, run without stopping
+
+    /* Latest port accesses for crash reports */
+    io_history: crash::IoHistory,
+
+    /* Run summary counters: exits by basic reason, accesses of each port and devices with totals of their own */
+    created: Instant,
+    exit_counts: HashMap<u32, u64>,
+    port_counts: HashMap<u16, summary::PortCount>,
+    stats_handlers: Vec<Rc<stats_handler>>,
+
+    /* Devices with inspectable state by name */
+    state_handlers: Vec<(&'static str, Rc<state_handler>)>,
+
+    /* Guest symbol maps for address annotation */
+    symbols: symbols::SymbolTable,
 
     /* Mapped memory regions */
     memory: Vec<memory_mapping>,
 
+    /* Content hash of snapshot last saved or restored, dirty page log of RAM runs since then */
+    snapshot_hash: Option<u64>,
+
     /* Registred PIO regions */
     io: Vec<io_region>,
+
+    /* Registered MMIO regions */
+    mmio: Vec<mmio_region>,
+
+    /* PCI functions */
+    pci: Rc<RefCell<PciBus>>,
 }
 
 /*
@@ -189,10 +634,40 @@ pub fn create()
 
         let vm = vm {
                     vcpu: vcpu_create(),
+                    vcpu_thread: thread::current().id(),
                     pic: Option::None,
+                    msi: Option::None,
                     pending_ext_ints: Bitmap::new(256),
+                    raw_ext_ints: Bitmap::new(256),
+                    pending_ext_ids: vec![None; 256],
+                    input: Option::None,
+                    a20_enabled: true,
+                    reset_pending: false,
+                    reset_handlers: Vec::new(),
+                    pause_handlers: Vec::new(),
+                    exit_pending: None,
+                    irq_counts: [0; IRQ_LINES],
+                    exception_pending: None,
+                    nmi_pending: false,
+                    step: None,
+                    step_with_mtf: false,
+                    breakpoints: breakpoint::BreakpointTable::new(),
+                    pending_io: None,
+                    pending_vector: None,
+                    resumed_vector: None,
+                    resumed_breakpoint: None,
+                    io_history: crash::IoHistory::new(),
+                    created: Instant::now(),
+                    exit_counts: HashMap::new(),
+                    port_counts: HashMap::new(),
+                    stats_handlers: Vec::new(),
+                    state_handlers: Vec::new(),
+                    symbols: symbols::SymbolTable::new(),
                     memory: Vec::new(),
-                    io: Vec::new()
+                    snapshot_hash: None,
+                    io: Vec::new(),
+                    mmio: Vec::new(),
+                    pci: Rc::new(RefCell::new(PciBus::new())),
         };
 
         VM = Option::Some(mem::transmute(Box::new(vm)));
@@ -204,132 +679,2688 @@ pub fn register_interrupt_controller(pic: Rc<interrupt_controller>)
     get_vm().pic = Option::Some(pic);
 }
 
-pub fn assert_irq(vec: u8)
+/**
+ * Interrupt controller devices assert IRQs through, for another one to put itself in front of
+ */
+pub fn get_interrupt_controller() -> Rc<interrupt_controller>
 {
-    get_pic().assert_irq(vec);
+    get_pic()
 }
 
-pub fn has_pending_interrupts() -> bool
+pub fn register_msi_controller(msi: Rc<msi_controller>)
 {
-    get_vm().pending_ext_ints.has_any_set()
+    get_vm().msi = Option::Some(msi);
 }
 
-pub fn raise_external_interrupt(vec: u8)
+/**
+ * Get MSI with fixed delivery of a vector to the only vcpu
+ */
+pub fn register_msi(vec: u8) -> MsiHandle
 {
-    get_vm().pending_ext_ints.set(vec as usize);
+    MsiHandle {
+        addr: MSI_ADDR_BASE,
+        data: vec as u32,
+    }
 }
 
-pub fn cancel_all_external_interrupts()
+/**
+ * Inject message signaled interrupt, false if there is no controller to take it or it was rejected
+ */
+pub fn inject_msi(addr: u64, data: u32) -> bool
 {
-    get_vm().pending_ext_ints.clear_all();
+    match get_vm().msi.clone() {
+        Some(msi) => msi.deliver_msi(addr, data),
+        None => false,
+    }
 }
 
-pub fn next_external_interrupt() -> Option<u8>
+pub fn register_input_device(dev: Rc<input_device>)
 {
-    match get_vm().pending_ext_ints.bsf() {
-        Some(vec) => {
-            /* ACK interrupt */
-            get_vm().pending_ext_ints.clear(vec);
-            get_pic().ack(vec as u8);
-            return Option::Some(vec as u8);
-        }
+    get_vm().input = Option::Some(dev);
+}
 
-        None => Option::None,
-    }
+/**
+ * Add PCI function to system bus
+ */
+pub fn register_pci_function(addr: PciAddress, func: Rc<pci_function>)
+{
+    get_vm().pci.borrow_mut().register(addr, func);
 }
 
-pub fn interrupt_guest()
+pub fn pci_bus() -> Rc<RefCell<PciBus>>
 {
-    unsafe {
-        let mut vcpus: [hv_vcpuid_t; 1] = [vcpu(); 1];
-        let res = hv_vcpu_interrupt(vcpus.as_mut_ptr(), vcpus.len() as u32);
-        if res != 0 {
-            panic!("hv_vcpu_interrupt failed with {:x}", res);
-        }
-    }
+    get_vm().pci.clone()
 }
 
-pub fn get_guest_exec_time() -> u64
+/**
+ * Send host mouse event to guest, see input_device::mouse_event
+ */
+pub fn send_mouse_event(dx: i32, dy: i32, dz: i32, buttons: u8)
 {
-    unsafe {
-        let mut time: u64 = 0;
-        let res = hv_vcpu_get_exec_time(vcpu(), &mut time as *mut u64);
-        if res != 0 {
-            panic!("hv_vcpu_get_exec_time failed with {:x}", res);
-        }
+    match get_vm().input {
+        Some(ref dev) => dev.mouse_event(dx, dy, dz, buttons),
+        None => debug!("No input device to handle mouse event"),
+    }
+}
 
-        time
+/**
+ * Send host key event to guest, see input_device::key_event
+ */
+pub fn send_key_event(code: u16, pressed: bool)
+{
+    match get_vm().input {
+        Some(ref dev) => dev.key_event(code, pressed),
+        None => debug!("No input device to handle key event"),
     }
 }
 
-fn alloc_pages(size: usize) -> hv_uvaddr_t 
+/**
+ * Keyboard LED state for host UI, see input_device::keyboard_leds
+ */
+pub fn keyboard_leds() -> u8
 {
-    unsafe {
-        let va = valloc(size);
-        assert!(!va.is_null());
-        return va;
+    match get_vm().input {
+        Some(ref dev) => dev.keyboard_leds(),
+        None => 0,
     }
 }
 
-pub fn vcpu() -> hv_vcpuid_t
+/*
+ * A20 gate
+ *
+ * With A20 disabled, address line 20 is forced low and accesses at 1 MiB wrap around to 0.
+ * Real mode code can only reach first 64 KiB above 1 MiB, so we emulate that by aliasing first
+ * 64 KiB of low RAM at 1 MiB while the gate is closed.
+ */
+const A20_ALIAS_BASE: hv_gpaddr_t = 0x100000;
+const A20_ALIAS_SIZE: usize = 0x10000;
+
+/**
+ * Apply A20 gate to guest physical address
+ */
+pub fn a20_mask(addr: hv_gpaddr_t, a20_enabled: bool) -> hv_gpaddr_t
 {
-    get_vm().vcpu
+    if a20_enabled {
+        addr
+    } else {
+        addr & !(1u64 << 20)
+    }
 }
 
-pub fn alloc_memory_region(size: usize) -> Arc<memory_region>
+pub fn is_a20_enabled() -> bool
 {
-    let va = alloc_pages(size);
-    Arc::new(memory_region { size: size, data: va })
+    get_vm().a20_enabled
 }
 
-pub fn map_memory_region(base: hv_gpaddr_t, flags: hv_memory_flags_t, region: Arc<memory_region>)
+pub fn set_a20_enabled(enabled: bool)
 {
-    unsafe {
-        let res = hv_vm_map(region.data, base, region.size, flags);
+    if get_vm().a20_enabled == enabled {
+        return;
+    }
+
+    get_vm().a20_enabled = enabled;
+    debug!("A20 {}", if enabled { "enabled" } else { "disabled" });
+
+    let low_ram = match find_memory_mapping(0) {
+        Some(mapping) => mapping,
+        None => return,
+    };
+
+    unsafe {
+        let res = if enabled {
+            hv_vm_unmap(A20_ALIAS_BASE, A20_ALIAS_SIZE)
+        } else {
+            hv_vm_map(low_ram.region.data, A20_ALIAS_BASE, A20_ALIAS_SIZE, low_ram.flags)
+        };
+        assert!(res == HV_SUCCESS);
+    }
+
+    /* Guest writes through the alias don't fault, so aliased RAM counts as written all along */
+    if !enabled {
+        low_ram.region.mark_dirty(0, A20_ALIAS_SIZE);
+    }
+}
+
+#[test]
+fn test_a20_mask() {
+    assert!(a20_mask(0x100000, true) == 0x100000);
+    assert!(a20_mask(0x10FFEF, true) == 0x10FFEF);
+    assert!(a20_mask(0x100000, false) == 0x0);
+    assert!(a20_mask(0x10FFEF, false) == 0xFFEF);
+    assert!(a20_mask(0xFFFFF, false) == 0xFFFFF);
+}
+
+pub fn register_reset_handler(handler: Rc<reset_handler>)
+{
+    get_vm().reset_handlers.push(handler);
+}
+
+pub fn register_stats_handler(handler: Rc<stats_handler>)
+{
+    get_vm().stats_handlers.push(handler);
+}
+
+/**
+ * Make device state inspectable under name
+ */
+pub fn register_device_state(name: &'static str, handler: Rc<state_handler>)
+{
+    let vm = get_vm();
+    assert!(vm.state_handlers.iter().all(|&(known, _)| known != name));
+    vm.state_handlers.push((name, handler));
+}
+
+/**
+ * Names of devices with inspectable state
+ */
+pub fn device_names() -> Vec<&'static str>
+{
+    get_vm().state_handlers.iter().map(|&(name, _)| name).collect()
+}
+
+/**
+ * State of device by name, vcpu thread only
+ */
+pub fn device_state(name: &str) -> Result<devstate::DeviceState, String>
+{
+    let handler = match get_vm().state_handlers.iter().find(|&&(known, _)| known == name) {
+        Some(&(_, ref handler)) => handler.clone(),
+        None => return Err(format!("No device {}, see info devices", name)),
+    };
+
+    handler.device_state().ok_or(format!("Device {} is busy", name))
+}
+
+/**
+ * Load guest symbol map with addresses offset by base to make them linear, number of symbols it had
+ */
+pub fn load_symbols(path: &str, base_offset: u64) -> Result<usize, String>
+{
+    let symbols = try!(symbols::read_map(path, base_offset));
+    let count = symbols.len();
+    get_vm().symbols.add(symbols);
+    Ok(count)
+}
+
+/**
+ * Guest symbols of all maps loaded
+ */
+pub fn symbols() -> &'static symbols::SymbolTable
+{
+    &get_vm().symbols
+}
+
+/**
+ * Guest linear address as "name+0x12", None without a symbol covering it
+ */
+pub fn resolve_symbol(addr: u64) -> Option<String>
+{
+    get_vm().symbols.describe(addr)
+}
+
+pub fn register_pause_handler(handler: Rc<pause_handler>)
+{
+    get_vm().pause_handlers.push(handler);
+}
+
+/**
+ * Request guest platform reset
+ * Reset is performed by vcpu loop when current exit is handled.
+ */
+pub fn request_reset()
+{
+    get_vm().reset_pending = true;
+}
+
+/**
+ * Why VM loop stops
+ * All of them terminate VM, except for breakpoint hits monitor or debugger can resume from.
+ * I/O breakpoint value is the one being written, or accumulator contents a read is about to replace.
+ */
+#[derive(PartialEq, Debug, Clone)]
+pub enum VmExit
+{
+    Guest(i32),         // Guest exit with status
+    WatchdogExpired,    // Guest stopped kicking watchdog
+    DebuggerKill,       // Attached debugger killed guest
+    MonitorQuit,        // Shut down from monitor console
+    Breakpoint {        // Guest is about to execute instruction at breakpoint
+        gpa: hv_gpaddr_t,
+        vcpu_state: VcpuState,
+    },
+    IoBreakpoint {      // Guest port access waits for resume_io_breakpoint()
+        port: u16,
+        direction: breakpoint::IoDirection,
+        value: u32,
+        vcpu_state: VcpuState,
+    },
+    Fatal(String),      // VMM can't go on with guest, crash report of its state
+    GuestPanic {        // Guest reported panic at beacon port
+        message: String,
+        report: String, // Message with guest state
+    },
+    HangDetected(String),   // Guest made no progress, crash report of its state
+    VectorBreakpoint {  // External interrupt vector waits for resume_vector_breakpoint()
+        vec: u8,
+        vcpu_state: VcpuState,
+    },
+    ReplayDiverged(String), // Guest went another way than recording replayed, report of it with guest state
+}
+
+impl VmExit
+{
+    /**
+     * Process exit status, VMM stops have even statuses so they never collide with debug exit port ones
+     */
+    pub fn status(&self) -> i32 {
+        match *self {
+            VmExit::Guest(code) => code,
+            VmExit::WatchdogExpired => 2,
+            VmExit::DebuggerKill => 4,
+            VmExit::MonitorQuit => 0,
+            VmExit::Breakpoint { .. } => 6,
+            VmExit::IoBreakpoint { .. } => 7,
+            VmExit::Fatal(_) => 8,
+            VmExit::GuestPanic { .. } => 10,
+            VmExit::HangDetected(_) => 12,
+            VmExit::VectorBreakpoint { .. } => 14,
+            VmExit::ReplayDiverged(_) => 16,
+        }
+    }
+}
+
+/**
+ * Request VM termination with exit status
+ * VM exits when current exit is handled.
+ */
+pub fn request_exit(code: i32)
+{
+    request_vm_exit(VmExit::Guest(code));
+}
+
+/**
+ * Request VM termination for a reason other than guest asking for it
+ */
+pub fn request_vm_exit(exit: VmExit)
+{
+    get_vm().exit_pending = Some(exit);
+}
+
+/**
+ * Check and clear pending exit request
+ */
+pub fn take_exit_request() -> Option<VmExit>
+{
+    get_vm().exit_pending.take()
+}
+
+/**
+ * Request NMI delivery to guest
+ * NMI is injected when vcpu exits next time, so kick it out of guest.
+ */
+pub fn raise_nmi()
+{
+    get_vm().nmi_pending = true;
+    interrupt_guest();
+}
+
+/**
+ * Request exception delivery to guest, for instructions VMM emulates
+ * Exception raised while another one is pending is dropped.
+ */
+pub fn raise_exception(vector: u8, error_code: Option<u32>)
+{
+    let vm = get_vm();
+    if vm.exception_pending.is_some() {
+        debug!("Dropping exception {} raised while another one is pending", vector);
+        return;
+    }
+
+    vm.exception_pending = Some(inject::Exception { vector: vector, error_code: error_code });
+}
+
+/**
+ * Check and clear pending exception
+ */
+pub fn take_exception_request() -> Option<inject::Exception>
+{
+    get_vm().exception_pending.take()
+}
+
+/**
+ * Events waiting for injection
+ */
+pub fn pending_events() -> inject::PendingEvents
+{
+    let vm = get_vm();
+    inject::PendingEvents {
+        exception: vm.exception_pending,
+        nmi: vm.nmi_pending,
+        external: has_pending_interrupts(),
+    }
+}
+
+/**
+ * Check and clear pending NMI
+ */
+pub fn take_nmi_request() -> bool
+{
+    let vm = get_vm();
+    let res = vm.nmi_pending;
+    vm.nmi_pending = false;
+    res
+}
+
+/**
+ * Check and clear pending reset request
+ */
+pub fn take_reset_request() -> bool
+{
+    let vm = get_vm();
+    let res = vm.reset_pending;
+    vm.reset_pending = false;
+    res
+}
+
+/**
+ * Broadcast reset to all registered devices and drop pending interrupts
+ */
+pub fn reset_devices()
+{
+    cancel_all_external_interrupts();
+    get_vm().exception_pending = None;
+
+    for i in &get_vm().reset_handlers {
+        i.reset();
+    }
+}
+
+pub fn assert_irq(vec: u8)
+{
+    if (vec as usize) < IRQ_LINES {
+        get_vm().irq_counts[vec as usize] += 1;
+    }
+
+    let id = eventlog::next_irq_id();
+    eventlog::emit(|| eventlog::Event::IrqAssert { irq: vec, id: id });
+    get_pic().assert_irq(vec, id);
+}
+
+/**
+ * Assertions of each IRQ line since VM was created
+ */
+pub fn irq_counts() -> [u64; IRQ_LINES]
+{
+    get_vm().irq_counts
+}
+
+pub fn has_pending_interrupts() -> bool
+{
+    get_vm().pending_ext_ints.has_any_set()
+}
+
+/**
+ * Queue vector for injection, id is the IRQ assertion it delivers if any
+ */
+pub fn raise_external_interrupt(vec: u8, id: Option<u64>)
+{
+    let vm = get_vm();
+    vm.pending_ext_ints.set(vec as usize);
+    vm.pending_ext_ids[vec as usize] = id;
+}
+
+/**
+ * Take back a raised vector that wasn't injected yet
+ */
+pub fn cancel_external_interrupt(vec: u8)
+{
+    get_vm().pending_ext_ints.clear(vec as usize);
+    get_vm().raw_ext_ints.clear(vec as usize);
+}
+
+pub fn cancel_all_external_interrupts()
+{
+    get_vm().pending_ext_ints.clear_all();
+    get_vm().raw_ext_ints.clear_all();
+}
+
+/**
+ * Take highest priority raised vector for injection, with the IRQ assertion it delivers if any
+ */
+pub fn next_external_interrupt() -> Option<(u8, Option<u64>)>
+{
+    match get_vm().pending_ext_ints.bsf() {
+        Some(vec) => {
+            if get_vm().resumed_vector == Some(vec as u8) {
+                get_vm().resumed_vector = None;
+            }
+
+            /* ACK interrupt, vectors injected by hand have no controller behind them */
+            get_vm().pending_ext_ints.clear(vec);
+            let id = get_vm().pending_ext_ids[vec].take();
+            if get_vm().raw_ext_ints.is_set(vec) {
+                get_vm().raw_ext_ints.clear(vec);
+                return Option::Some((vec as u8, id));
+            }
+            return Option::Some((get_pic().ack(vec as u8), id));
+        }
+
+        None => Option::None,
+    }
+}
+
+/*
+ * Events injected by hand, e.g. from monitor. IRQs take the path device assertions take, so controller masking
+ * and priority apply. Vector stopped VM raises is injected as VM resumes.
+ */
+
+/* Outcome of an event just raised, given what injection picks next */
+fn raised_outcome(kind: inject::EventKind, vec: u8) -> inject::InjectOutcome
+{
+    let res = inject::resolve_next_event(&pending_events(), &guest_event_state());
+    let next_external = get_vm().pending_ext_ints.bsf().map(|vec| vec as u8);
+    inject::raised_outcome(kind, vec, &res, next_external)
+}
+
+/**
+ * Assert ISA IRQ line as a device would, vcpu thread only
+ * IRQ raising no vector is latched or masked as PIC sees it.
+ */
+pub fn inject_irq(irq: u8) -> Result<inject::InjectOutcome, String>
+{
+    assert_vcpu_thread();
+    if irq as usize >= ISA_IRQ_LINES {
+        return Err(format!("No IRQ line {}", irq));
+    }
+    record::emit(|| record::Input::Monitor { kind: "irq", value: irq });
+
+    let raised_before: Vec<usize> = (0..256).filter(|&vec| get_vm().pending_ext_ints.is_set(vec)).collect();
+    assert_irq(irq);
+
+    let raised = (0..256).find(|&vec| get_vm().pending_ext_ints.is_set(vec) && !raised_before.contains(&vec));
+    Ok(match raised {
+        Some(vec) => raised_outcome(inject::EventKind::External, vec as u8),
+        None if pic::accepts_irq(irq) => inject::InjectOutcome::Latched,
+        None => inject::InjectOutcome::Masked,
+    })
+}
+
+/**
+ * Raise external interrupt vector bypassing interrupt controllers, vcpu thread only
+ */
+pub fn inject_vector(vec: u8) -> inject::InjectOutcome
+{
+    assert_vcpu_thread();
+    record::emit(|| record::Input::Monitor { kind: "vector", value: vec });
+    if !get_vm().pending_ext_ints.is_set(vec as usize) {
+        get_vm().raw_ext_ints.set(vec as usize);
+        raise_external_interrupt(vec, None);
+    }
+    raised_outcome(inject::EventKind::External, vec)
+}
+
+/**
+ * Raise NMI, vcpu thread only
+ */
+pub fn inject_nmi() -> inject::InjectOutcome
+{
+    assert_vcpu_thread();
+    record::emit(|| record::Input::Monitor { kind: "nmi", value: inject::NMI_VECTOR });
+    get_vm().nmi_pending = true;
+    raised_outcome(inject::EventKind::Nmi, inject::NMI_VECTOR)
+}
+
+pub fn interrupt_guest()
+{
+    unsafe {
+        let mut vcpus: [hv_vcpuid_t; 1] = [vcpu(); 1];
+        let res = hv_vcpu_interrupt(vcpus.as_mut_ptr(), vcpus.len() as u32);
+        if res != 0 {
+            panic!("hv_vcpu_interrupt failed with {:x}", res);
+        }
+    }
+}
+
+pub fn get_guest_exec_time() -> u64
+{
+    unsafe {
+        let mut time: u64 = 0;
+        let res = hv_vcpu_get_exec_time(vcpu(), &mut time as *mut u64);
+        if res != 0 {
+            panic!("hv_vcpu_get_exec_time failed with {:x}", res);
+        }
+
+        time
+    }
+}
+
+/**
+ * Set value added to host TSC for guest TSC reads that don't exit
+ */
+pub fn set_tsc_offset(offset: u64)
+{
+    unsafe {
+        let res = hv_vmx_vcpu_write_vmcs(vcpu(), hv_vmx_vmcs_regs::VMCS_CTRL_TSC_OFFSET as u32, offset);
+        if res != 0 {
+            panic!("TSC offset write failed with {:x}", res);
+        }
+    }
+}
+
+/**
+ * Let guest access an MSR directly instead of exiting
+ */
+pub fn enable_native_msr(msr: u32, enable: bool)
+{
+    unsafe {
+        let res = hv_vcpu_enable_native_msr(vcpu(), msr, enable);
+        if res != 0 {
+            panic!("hv_vcpu_enable_native_msr failed with {:x}", res);
+        }
+    }
+}
+
+fn alloc_pages(size: usize) -> hv_uvaddr_t 
+{
+    unsafe {
+        let va = valloc(size);
+        assert!(!va.is_null());
+        return va;
+    }
+}
+
+pub fn vcpu() -> hv_vcpuid_t
+{
+    get_vm().vcpu
+}
+
+pub fn alloc_memory_region(size: usize) -> Arc<memory_region>
+{
+    let va = alloc_pages(size);
+    Arc::new(memory_region { size: size, data: va, dirty: Mutex::new(None) })
+}
+
+pub fn map_memory_region(base: hv_gpaddr_t, flags: hv_memory_flags_t, region: Arc<memory_region>)
+{
+    unsafe {
+        let res = hv_vm_map(region.data, base, region.size, flags);
+        assert!(res == HV_SUCCESS);
+    }
+
+    get_vm().memory.push(memory_mapping { region: region, base: base, flags: flags });
+}
+
+/**
+ * Change guest access rights of mapping at base address, e.g. to trap accesses to a ROM device in command mode
+ */
+pub fn protect_memory_region(base: hv_gpaddr_t, flags: hv_memory_flags_t)
+{
+    let mapping = get_vm().memory.iter_mut().find(|m| m.base == base).expect("no mapping at base address");
+
+    unsafe {
+        let res = hv_vm_protect(base, mapping.region.size, flags);
+        assert!(res == HV_SUCCESS);
+    }
+
+    /* Pages guest may write from now on without faulting */
+    if flags & HV_MEMORY_WRITE != 0 {
+        mapping.region.mark_dirty(0, mapping.region.size);
+    }
+
+    mapping.flags = flags;
+}
+
+pub fn find_memory_mapping(addr: hv_gpaddr_t) -> Option<&'static memory_mapping>
+{
+    for i in &get_vm().memory {
+        if addr >= i.base && addr < i.base + i.region.size as u64 {
+            return Some(i);
+        }
+    }
+
+    return None;
+}
+
+pub fn read_guest_memory(addr: hv_gpaddr_t, buf: &mut [u8]) -> usize
+{
+    let addr = a20_mask(addr, is_a20_enabled());
+    let mapping = match find_memory_mapping(addr) {
+        Some(mapping) => mapping,
+        None => return 0,
+    };
+
+    assert!(addr >= mapping.base);
+    mapping.region.read_bytes((addr - mapping.base) as usize, buf)
+}
+
+/**
+ * Disassemble count 16 bit instructions in guest memory at guest physical or real mode address
+ * Real mode addresses resolve with current A20 gate state, window ends early where guest memory does.
+ */
+pub fn disasm(addr: disasm::CodeAddress, count: usize) -> disasm::Disassembly
+{
+    let len = count * disasm::MAX_INSN_BYTES;
+    let code = disasm::fetch(addr, len, is_a20_enabled(), |gpa| {
+        let mut byte = [0u8; 1];
+        if read_guest_memory(gpa, &mut byte) == 1 { Some(byte[0]) } else { None }
+    });
+    disasm::decode_window(&code, addr, count, code.len() == len)
+}
+
+pub fn write_guest_memory(addr: hv_gpaddr_t, buf: &[u8]) -> usize
+{
+    let addr = a20_mask(addr, is_a20_enabled());
+    let mapping = match find_memory_mapping(addr) {
+        Some(mapping) => mapping,
+        None => return 0,
+    };
+
+    assert!(addr >= mapping.base);
+    mapping.region.write_bytes((addr - mapping.base) as usize, buf)
+}
+
+/**
+ * Write bytes to guest memory on behalf of a host user, e.g. from monitor, returns the bytes they replaced
+ * Only RAM is written, and ROM if forced. MMIO is refused rather than have devices see writes guest didn't
+ * make, so are bytes under INT3 breakpoints, which hold the patched opcode. Nothing is written unless all of
+ * it can be. Vcpu thread only, the write goes to the event log.
+ */
+pub fn patch_guest_memory(addr: hv_gpaddr_t, data: &[u8], force: bool) -> Result<Vec<u8>, String>
+{
+    assert_vcpu_thread();
+
+    for gpa in addr..addr + data.len() as u64 {
+        match find_memory_mapping(a20_mask(gpa, is_a20_enabled())) {
+            Some(mapping) if mapping.flags & HV_MEMORY_WRITE == 0 && !force => {
+                return Err(format!("0x{:x} is in ROM, force to write it", gpa));
+            },
+            Some(_) => {},
+            None if find_mmio_region(gpa).is_some() => return Err(format!("0x{:x} is MMIO, only memory can be written", gpa)),
+            None => return Err(format!("No memory at 0x{:x}", gpa)),
+        }
+
+        if let Some(bp) = get_vm().breakpoints.find(gpa) {
+            if bp.kind == breakpoint::BreakpointKind::Int3 {
+                return Err(format!("0x{:x} has breakpoint {}, delete it first", gpa, bp.handle.0));
+            }
+        }
+    }
+
+    /* Range can span mappings, RAM followed by ROM */
+    let mut old = vec![0u8; data.len()];
+    for (i, &byte) in data.iter().enumerate() {
+        read_guest_memory(addr + i as u64, &mut old[i..i + 1]);
+        write_guest_memory(addr + i as u64, &[byte]);
+    }
+
+    eventlog::emit(|| eventlog::Event::MemoryWrite { addr: addr, old: old.clone(), new: data.to_vec() });
+    Ok(old)
+}
+
+/*
+ * Guest memory dumps
+ *
+ * Dumps cover whatever is mapped in a guest physical range, RAM and ROM alike. Bytes with nothing mapped
+ * don't fail the dump: they are written as 0xFF, what guest would read from an empty bus, shown as "??"
+ * in hex dumps and reported back as holes. Memory is read as it is at the time of the call, so guest must
+ * not run meanwhile: dump on vcpu thread (at an exit or while VM is held) or hold a host pause guard.
+ */
+
+// Dump file header: magic, base address and length, little endian
+const DUMP_MAGIC: &'static [u8; 8]  = b"XVMDUMP\0";
+const DUMP_HEADER_SIZE: usize       = 24;
+
+// Hex dump line size
+const HEXDUMP_LINE: usize           = 16;
+
+/* Bytes read at an address, or bytes to skip until memory is mapped again */
+enum DumpChunk {
+    Mapped(usize),
+    Unmapped(usize),
+}
+
+/* Read range through reader, holes filled with 0xFF */
+fn read_dump_range<F: FnMut(hv_gpaddr_t, &mut [u8]) -> DumpChunk>(range: &Range<hv_gpaddr_t>, mut read: F)
+    -> (Vec<u8>, Vec<Range<hv_gpaddr_t>>)
+{
+    let len = if range.end > range.start { (range.end - range.start) as usize } else { 0 };
+    let mut data = vec![0xFFu8; len];
+    let mut holes: Vec<Range<hv_gpaddr_t>> = Vec::new();
+
+    let mut done = 0;
+    while done < len {
+        let addr = range.start + done as u64;
+        match read(addr, &mut data[done..]) {
+            DumpChunk::Mapped(n) => done += n,
+            DumpChunk::Unmapped(n) => {
+                let n = if n == 0 || n > len - done { len - done } else { n };
+
+                /* Adjacent unmapped chunks make one hole */
+                let extends = match holes.last() {
+                    Some(hole) => hole.end == addr,
+                    None => false,
+                };
+                if extends {
+                    holes.last_mut().unwrap().end = addr + n as u64;
+                } else {
+                    holes.push(addr..addr + n as u64);
+                }
+                done += n;
+            },
+        }
+    }
+
+    (data, holes)
+}
+
+/* Read what is mapped at addr, or skip to next mapping */
+fn read_dump_chunk(addr: hv_gpaddr_t, buf: &mut [u8]) -> DumpChunk
+{
+    let n = read_guest_memory(addr, buf);
+    if n != 0 {
+        return DumpChunk::Mapped(n);
+    }
+
+    /* Addresses aliased by A20 gate don't map to mappings linearly, go byte by byte there */
+    let masked = a20_mask(addr, is_a20_enabled());
+    if masked != addr {
+        return DumpChunk::Unmapped(1);
+    }
+
+    let next = get_vm().memory.iter().map(|i| i.base).filter(|&base| base > addr).min();
+    match next {
+        Some(base) if base - addr < buf.len() as u64 => DumpChunk::Unmapped((base - addr) as usize),
+        _ => DumpChunk::Unmapped(buf.len()),
+    }
+}
+
+/* Guest must not run while memory is read */
+fn assert_quiesced()
+{
+    if thread::current().id() != get_vm().vcpu_thread {
+        assert!(PAUSE_STATE.lock().unwrap().paused, "guest memory dumped while VM runs");
+    }
+}
+
+/* Write dump data, with base and length header if asked for */
+fn write_dump<W: Write>(out: &mut W, base: hv_gpaddr_t, data: &[u8], header: bool) -> io::Result<()>
+{
+    if header {
+        let mut hdr = [0u8; DUMP_HEADER_SIZE];
+        hdr[0..8].copy_from_slice(DUMP_MAGIC);
+        for i in 0..8 {
+            hdr[8 + i] = (base >> (i * 8)) as u8;
+            hdr[16 + i] = ((data.len() as u64) >> (i * 8)) as u8;
+        }
+        try!(out.write_all(&hdr));
+    }
+
+    out.write_all(data)
+}
+
+/* hexdump -C style lines, bytes in holes shown as ?? */
+fn format_hexdump(base: hv_gpaddr_t, data: &[u8], holes: &[Range<hv_gpaddr_t>]) -> String
+{
+    let unmapped = |addr: hv_gpaddr_t| holes.iter().any(|hole| addr >= hole.start && addr < hole.end);
+
+    let mut lines = Vec::new();
+    for (i, chunk) in data.chunks(HEXDUMP_LINE).enumerate() {
+        let addr = base + (i * HEXDUMP_LINE) as u64;
+        let mut hex = String::new();
+        let mut text = String::new();
+        for (j, b) in chunk.iter().enumerate() {
+            if j == HEXDUMP_LINE / 2 {
+                hex.push(' ');
+            }
+
+            if unmapped(addr + j as u64) {
+                hex.push_str(" ??");
+                text.push(' ');
+            } else {
+                hex.push_str(&format!(" {:02x}", b));
+                text.push(if *b >= 0x20 && *b < 0x7F { *b as char } else { '.' });
+            }
+        }
+
+        let width = HEXDUMP_LINE * 3 + 1;
+        lines.push(format!("{:08x} {:<width$}  |{}|", addr, hex, text, width = width));
+    }
+
+    lines.join("\n")
+}
+
+/**
+ * Write raw bytes of guest physical range to a file, returns unmapped holes in it
+ */
+pub fn dump_memory(range: Range<hv_gpaddr_t>, path: &Path) -> io::Result<Vec<Range<hv_gpaddr_t>>>
+{
+    save_memory(range, path, false)
+}
+
+/**
+ * Same as dump_memory() with a header recording base address and length ahead of the bytes
+ * Header is 8 bytes of "XVMDUMP\0" magic followed by 64-bit little endian base and length.
+ */
+pub fn dump_memory_with_header(range: Range<hv_gpaddr_t>, path: &Path) -> io::Result<Vec<Range<hv_gpaddr_t>>>
+{
+    save_memory(range, path, true)
+}
+
+fn save_memory(range: Range<hv_gpaddr_t>, path: &Path, header: bool) -> io::Result<Vec<Range<hv_gpaddr_t>>>
+{
+    assert_quiesced();
+
+    let (data, holes) = read_dump_range(&range, read_dump_chunk);
+    let mut file = try!(File::create(path));
+    try!(write_dump(&mut file, range.start, &data, header));
+    Ok(holes)
+}
+
+/**
+ * Guest physical range as text, 16 bytes per line with their characters, unmapped bytes are ??
+ */
+pub fn hexdump(range: Range<hv_gpaddr_t>) -> String
+{
+    assert_quiesced();
+
+    let (data, holes) = read_dump_range(&range, read_dump_chunk);
+    format_hexdump(range.start, &data, &holes)
+}
+
+/*
+ * Flat RAM images
+ *
+ * export_ram() writes RAM bytes as they are, with no header, for tools that don't know snapshot files, and
+ * import_ram() loads such a file, or one another tool prepared, into RAM at a guest physical address. RAM is
+ * memory mapped writable, a whole export is the RAM mapping at address 0. A range has to be RAM all through,
+ * at any byte alignment: one reaching into ROM or past mapped memory fails, as does a file that doesn't fit,
+ * and nothing is written then. ROM isn't skipped, a flat file would have no way of telling what was left out.
+ * Addresses are physical, A20 gate doesn't alias them.
+ *
+ * Bytes under INT3 breakpoints are exported as guest has them, importing over one fails as setmem does.
+ * Imported pages go to the dirty page log, so incremental snapshots have them, and imports to the event log.
+ * Guest must not run meanwhile: vcpu thread before guest starts or at an exit, or a host pause guard.
+ */
+
+/* Mapping, offset in it and length of each piece of range, fails unless range is RAM all through */
+fn ram_spans<'a>(memory: &'a [memory_mapping], range: &Range<hv_gpaddr_t>) -> Result<Vec<(&'a memory_mapping, usize, usize)>, String>
+{
+    if range.end <= range.start {
+        return Err(format!("Empty range 0x{:x}-0x{:x}", range.start, range.end));
+    }
+
+    let mut spans = Vec::new();
+    let mut addr = range.start;
+    while addr < range.end {
+        let mapping = match memory.iter().find(|mapping| addr >= mapping.base && addr < mapping.base + mapping.region.size as u64) {
+            Some(mapping) if mapping.flags & HV_MEMORY_WRITE == 0 => return Err(format!("0x{:x} is in ROM, not RAM", addr)),
+            Some(mapping) => mapping,
+            None => return Err(format!("No RAM at 0x{:x}", addr)),
+        };
+
+        let end = ::std::cmp::min(range.end, mapping.base + mapping.region.size as u64);
+        spans.push((mapping, (addr - mapping.base) as usize, (end - addr) as usize));
+        addr = end;
+    }
+    Ok(spans)
+}
+
+/* RAM mapping at address 0 */
+fn whole_ram(memory: &[memory_mapping]) -> Result<Range<hv_gpaddr_t>, String>
+{
+    match memory.iter().find(|mapping| mapping.base == 0 && mapping.flags & HV_MEMORY_WRITE != 0) {
+        Some(mapping) => Ok(0..mapping.region.size as u64),
+        None => Err(String::from("No RAM at 0x0")),
+    }
+}
+
+/* Bytes of RAM range */
+fn read_ram(memory: &[memory_mapping], range: &Range<hv_gpaddr_t>) -> Result<Vec<u8>, String>
+{
+    let mut data = Vec::new();
+    for (mapping, offset, len) in try!(ram_spans(memory, range)) {
+        let start = data.len();
+        data.resize(start + len, 0);
+        mapping.region.read_bytes(offset, &mut data[start..]);
+    }
+    Ok(data)
+}
+
+/* Write data to RAM from base, returns the range written */
+fn write_ram(memory: &[memory_mapping], base: hv_gpaddr_t, data: &[u8]) -> Result<Range<hv_gpaddr_t>, String>
+{
+    let range = try!(ram_range(base, data.len() as u64));
+    let mut done = 0;
+    for (mapping, offset, len) in try!(ram_spans(memory, &range)) {
+        mapping.region.write_bytes(offset, &data[done..done + len]);
+        done += len;
+    }
+    Ok(range)
+}
+
+/* Range of size bytes from base, if it is in guest physical address space */
+fn ram_range(base: hv_gpaddr_t, size: u64) -> Result<Range<hv_gpaddr_t>, String>
+{
+    match base.checked_add(size) {
+        Some(end) => Ok(base..end),
+        None => Err(format!("0x{:x} bytes from 0x{:x} are past the end of address space", size, base)),
+    }
+}
+
+/**
+ * Write guest RAM as flat bytes to a file, range of it if given or the RAM at address 0, returns the range
+ */
+pub fn export_ram(path: &Path, range: Option<Range<hv_gpaddr_t>>) -> Result<Range<hv_gpaddr_t>, String>
+{
+    assert_quiesced();
+
+    let range = match range {
+        Some(range) => range,
+        None => try!(whole_ram(&get_vm().memory)),
+    };
+    let mut data = try!(read_ram(&get_vm().memory, &range));
+    hide_breakpoints(range.start, &mut data);
+
+    try!(File::create(path).and_then(|mut file| file.write_all(&data)).map_err(|err| format!("Can't write {}: {}", path.display(), err)));
+    Ok(range)
+}
+
+/**
+ * Load flat file into guest RAM from base, returns the range written
+ * File has to fit in RAM and not cover INT3 breakpoints, it is checked before it is read.
+ */
+pub fn import_ram(path: &Path, base: hv_gpaddr_t) -> Result<Range<hv_gpaddr_t>, String>
+{
+    assert_quiesced();
+
+    let size = try!(::std::fs::metadata(path).map(|meta| meta.len()).map_err(|err| format!("Can't read {}: {}", path.display(), err)));
+    let range = try!(ram_range(base, size));
+    try!(ram_spans(&get_vm().memory, &range));
+    for bp in get_vm().breakpoints.list() {
+        if bp.kind == breakpoint::BreakpointKind::Int3 && bp.gpa >= range.start && bp.gpa < range.end {
+            return Err(format!("0x{:x} has breakpoint {}, delete it first", bp.gpa, bp.handle.0));
+        }
+    }
+
+    let mut data = Vec::new();
+    try!(File::open(path).and_then(|mut file| io::Read::read_to_end(&mut file, &mut data)).map_err(|err| format!("Can't read {}: {}", path.display(), err)));
+    if data.len() as u64 != size {
+        return Err(format!("{} changed size while it was read", path.display()));
+    }
+
+    let range = try!(write_ram(&get_vm().memory, base, &data));
+    eventlog::emit(|| eventlog::Event::RamImport { path: path.display().to_string(), base: range.start, size: size });
+    Ok(range)
+}
+
+/**
+ * Write core dump of guest memory, registers and device states with crash report text to a file
+ * See coredump.rs for the format. Vcpu thread only.
+ */
+pub fn write_coredump(path: &Path, report: &str, sparse: bool) -> io::Result<()>
+{
+    assert_vcpu_thread();
+
+    let memory = get_vm().memory.iter().map(|mapping| {
+        let mut data = vec![0u8; mapping.region.size];
+        mapping.region.read_bytes(0, &mut data);
+        (mapping.base, data)
+    }).collect();
+
+    /* Devices busy in the middle of an access, as when a device handler failed, are left out */
+    let mut devices = devstate::DeviceState::new();
+    for name in device_names() {
+        devices = match device_state(name) {
+            Ok(state) => devices.object(name, state),
+            Err(err) => devices.text(name, &err),
+        };
+    }
+
+    let dump = coredump::CoreDump {
+        memory: memory,
+        vcpu_state: vcpu_state(),
+        devices: devices.to_json(),
+        report: String::from(report),
+    };
+    let mut file = try!(File::create(path));
+    coredump::write(&dump, &mut file, sparse)
+}
+
+/* Put guest bytes under INT3 breakpoints, debugger's too, back in contents of memory mapping at base */
+fn hide_breakpoints(base: hv_gpaddr_t, data: &mut [u8])
+{
+    let int3 = get_vm().breakpoints.list().iter().filter(|bp| bp.kind == breakpoint::BreakpointKind::Int3).map(|bp| (bp.gpa, bp.orig));
+    for (gpa, orig) in int3.chain(gdbstub::breakpoints()) {
+        if gpa >= base && gpa < base + data.len() as u64 {
+            data[(gpa - base) as usize] = orig;
+        }
+    }
+}
+
+/* Guest bytes of memory mapping, the ones under INT3 breakpoints and not the breakpoints */
+fn mapping_contents(mapping: &memory_mapping) -> Vec<u8>
+{
+    let mut data = vec![0u8; mapping.region.size];
+    mapping.region.read_bytes(0, &mut data);
+    hide_breakpoints(mapping.base, &mut data);
+    data
+}
+
+/**
+ * Content hash of guest memory, replay checks it ends with the one recording did
+ */
+pub fn ram_hash() -> u64
+{
+    get_vm().memory.iter().fold(snapshot::CONTENT_HASH_SEED, |hash, mapping| snapshot::hash_more(hash, &mapping_contents(mapping)))
+}
+
+/* Saved states of all devices, fails when one is busy */
+fn device_snapshots() -> io::Result<Vec<snapshot::SnapshotDevice>>
+{
+    let mut devices = Vec::new();
+    for &(name, ref handler) in &get_vm().state_handlers {
+        let state = try!(handler.device_state().ok_or(io::Error::new(io::ErrorKind::Other, format!("Device {} is busy", name))));
+        devices.push(snapshot::SnapshotDevice {
+            name: String::from(name),
+            version: handler.state_version(),
+            state: state,
+        });
+    }
+    Ok(devices)
+}
+
+/* Snapshot of VM as it is with memory and device states given */
+fn snapshot_with(memory: Vec<snapshot::SnapshotMemory>, devices: Vec<snapshot::SnapshotDevice>, parent: Option<u64>) -> snapshot::Snapshot
+{
+    snapshot::Snapshot {
+        created: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        memory: memory,
+        vcpu_state: vcpu_state(),
+        clock_ns: clock::guest_time_ns(),
+        dilation: clock::time_dilation(),
+        devices: devices,
+        parent: parent,
+    }
+}
+
+/* Snapshot of VM as it is, memory saved over parent snapshot has just the pages written since it */
+fn take_snapshot(parent: Option<u64>) -> io::Result<snapshot::Snapshot>
+{
+    let devices = try!(device_snapshots());
+    let memory = get_vm().memory.iter().map(|mapping| {
+        let data = mapping_contents(mapping);
+        let pages = if parent.is_some() { mapping.region.dirty_pages() } else { None };
+        snapshot::SnapshotMemory { base: mapping.base, flags: mapping.flags as u32, data: data, pages: pages }
+    }).collect();
+
+    Ok(snapshot_with(memory, devices, parent))
+}
+
+/**
+ * Snapshot of VM as it is without saving it, e.g. to compare with a saved one. Vcpu thread only.
+ */
+pub fn current_snapshot() -> io::Result<snapshot::Snapshot>
+{
+    assert_vcpu_thread();
+    take_snapshot(None)
+}
+
+/* Log guest writes to RAM from here on, for incremental snapshots over the one with content hash if known */
+fn start_dirty_log(hash: Option<u64>)
+{
+    get_vm().snapshot_hash = hash;
+
+    /* Writable RAM is read only to guest until it writes a page, execute protection breakpoints stay */
+    for mapping in &get_vm().memory {
+        mapping.region.start_dirty_log();
+        if mapping.flags & HV_MEMORY_WRITE != 0 {
+            unsafe {
+                let res = hv_vm_protect(mapping.base, mapping.region.size, mapping.flags & !HV_MEMORY_WRITE);
+                assert!(res == HV_SUCCESS);
+            }
+        }
+    }
+    for bp in get_vm().breakpoints.list() {
+        let page = breakpoint::page_of(bp.gpa);
+        if is_exec_protected(page) {
+            protect_page(page, true);
+        }
+    }
+
+    if !is_a20_enabled() {
+        if let Some(low_ram) = find_memory_mapping(0) {
+            low_ram.region.mark_dirty(0, A20_ALIAS_SIZE);
+        }
+    }
+}
+
+/**
+ * Save snapshot of guest memory, registers, virtual clock and device states to a file
+ * See snapshot.rs for the format. Vcpu thread only, devices busy with an access fail it.
+ */
+pub fn save_snapshot(path: &Path) -> io::Result<()>
+{
+    assert_vcpu_thread();
+    try!(check_no_live_snapshot());
+
+    let hash = try!(snapshot::save(&try!(take_snapshot(None)), path));
+    start_dirty_log(Some(hash));
+    Ok(())
+}
+
+/**
+ * Save snapshot with only the memory pages written since base snapshot, which has to be the one last saved
+ * or restored. Vcpu thread only, so guest is stopped from reading dirty page log to starting it anew and
+ * no write is missed between two snapshots. A snapshot that fails leaves the log as it was.
+ */
+pub fn save_snapshot_incremental(path: &Path, base: &Path) -> io::Result<()>
+{
+    assert_vcpu_thread();
+    try!(check_no_live_snapshot());
+
+    let mut data = Vec::new();
+    try!(File::open(base).and_then(|mut file| io::Read::read_to_end(&mut file, &mut data)));
+    let base_hash = snapshot::content_hash(&data);
+    if get_vm().snapshot_hash != Some(base_hash) {
+        return Err(io::Error::new(io::ErrorKind::Other,
+                                  format!("{} isn't the snapshot last saved or restored, pages written are known since that one", base.display())));
+    }
+
+    let hash = try!(snapshot::save(&try!(take_snapshot(Some(base_hash))), path));
+    start_dirty_log(Some(hash));
+    Ok(())
+}
+
+/* Dirty page log belongs to a live snapshot until it is saved */
+fn check_no_live_snapshot() -> io::Result<()>
+{
+    if livesnap::in_progress() {
+        return Err(io::Error::new(io::ErrorKind::Other, "Live snapshot is being saved"));
+    }
+    Ok(())
+}
+
+/**
+ * Log guest writes to RAM for a live snapshot, see livesnap.rs. There is no snapshot to save increments over
+ * until the live one is saved. Vcpu thread only.
+ */
+pub fn start_live_snapshot()
+{
+    assert_vcpu_thread();
+    start_dirty_log(None);
+}
+
+/**
+ * Pages of each memory mapping written since dirty page log was started or last taken. Guest writes them
+ * through a fault again from here on, so a live snapshot copying them as guest runs hears of every later
+ * write. Vcpu thread only.
+ */
+pub fn take_dirty_pages() -> Vec<Vec<usize>>
+{
+    assert_vcpu_thread();
+
+    let pages: Vec<Vec<usize>> = get_vm().memory.iter().map(|mapping| {
+        mapping.region.take_dirty_pages().unwrap_or_else(|| (0..(mapping.region.size + DIRTY_PAGE_SIZE - 1) / DIRTY_PAGE_SIZE).collect())
+    }).collect();
+
+    /* Writes through A20 alias aren't seen, its pages are always written and stay writable */
+    if !is_a20_enabled() {
+        if let Some(low_ram) = find_memory_mapping(0) {
+            low_ram.region.mark_dirty(0, A20_ALIAS_SIZE);
+        }
+    }
+    for (mapping, pages) in get_vm().memory.iter().zip(&pages) {
+        if mapping.flags & HV_MEMORY_WRITE != 0 {
+            for &page in pages {
+                let gpa = mapping.base + (page * DIRTY_PAGE_SIZE) as u64;
+                protect_page(gpa, is_exec_protected(gpa));
+            }
+        }
+    }
+    pages
+}
+
+/**
+ * Snapshot of VM as it is with memory contents a live snapshot copied, see livesnap.rs. Device states are
+ * taken first and contents are asked for once none of them is busy, guest bytes under INT3 breakpoints go in
+ * place of the breakpoints. Dirty page log starts over for increments, which can follow the snapshot once it
+ * is saved, see live_snapshot_saved(). Vcpu thread only.
+ */
+pub fn take_live_snapshot<F: FnOnce() -> Vec<Vec<u8>>>(contents: F) -> io::Result<snapshot::Snapshot>
+{
+    assert_vcpu_thread();
+
+    let devices = try!(device_snapshots());
+    let memory = get_vm().memory.iter().zip(contents()).map(|(mapping, mut data)| {
+        hide_breakpoints(mapping.base, &mut data);
+        snapshot::SnapshotMemory { base: mapping.base, flags: mapping.flags as u32, data: data, pages: None }
+    }).collect();
+
+    let snapshot = snapshot_with(memory, devices, None);
+    start_dirty_log(None);
+    Ok(snapshot)
+}
+
+/**
+ * Live snapshot taken by take_live_snapshot() was saved with content hash, increments can be saved over it
+ */
+pub fn live_snapshot_saved(hash: u64)
+{
+    assert_vcpu_thread();
+    get_vm().snapshot_hash = Some(hash);
+}
+
+/**
+ * Read page of memory mapping at index as guest runs, for live snapshots. Returns bytes read.
+ */
+pub fn read_live_page(mapping: usize, page: usize, buf: &mut [u8]) -> usize
+{
+    get_vm().memory[mapping].region.read_bytes(page * DIRTY_PAGE_SIZE, buf)
+}
+
+/**
+ * Sizes of memory mappings in the order snapshots have them
+ */
+pub fn memory_sizes() -> Vec<usize>
+{
+    get_vm().memory.iter().map(|mapping| mapping.region.size).collect()
+}
+
+/**
+ * Restore VM to a snapshot, or a base snapshot and its increments in the order they were saved, before guest
+ * first runs. VM has to have the same memory layout and devices snapshot was saved with. Memory, registers,
+ * guest time and device states are taken from it, then devices raise what they had pending. Vcpu thread only.
+ */
+pub fn restore_snapshot(paths: &[&Path]) -> Result<(), String>
+{
+    assert_vcpu_thread();
+
+    let (snapshot, hash) = try!(snapshot::load_chain(paths));
+
+    let layout = |memory: Vec<(u64, usize)>| memory.iter().map(|&(base, size)| format!("0x{:x}+0x{:x}", base, size)).collect::<Vec<String>>().join(" ");
+    let saved = layout(snapshot.memory.iter().map(|mem| (mem.base, mem.data.len())).collect());
+    let configured = layout(get_vm().memory.iter().map(|mapping| (mapping.base, mapping.region.size)).collect());
+    if saved != configured {
+        return Err(format!("Snapshot memory layout {} doesn't match VM {}", saved, configured));
+    }
+
+    let saved: Vec<&str> = snapshot.devices.iter().map(|dev| dev.name.as_str()).collect();
+    try!(snapshot::match_devices(&saved, &device_names()));
+
+    /* Device states are all checked before anything changes */
+    let mut states = Vec::new();
+    for dev in &snapshot.devices {
+        let handler = get_vm().state_handlers.iter().find(|&&(name, _)| name == dev.name).map(|&(_, ref handler)| handler.clone()).unwrap();
+        let state = try!(snapshot::migrate_state(&*handler, &dev.name, dev.version, dev.state.clone()));
+        let state = match handler.device_state() {
+            Some(current) => try!(state.known_fields(&current, handler.unknown_fields()).map_err(|err| format!("Device {}: {}", dev.name, err))),
+            None => state,
+        };
+        states.push((dev.name.as_str(), handler, state));
+    }
+
+    for (mem, mapping) in snapshot.memory.iter().zip(get_vm().memory.iter()) {
+        mapping.region.write_bytes(0, &mem.data);
+    }
+    for mem in &snapshot.memory {
+        let flags = mem.flags as hv_memory_flags_t;
+        if get_vm().memory.iter().any(|mapping| mapping.base == mem.base && mapping.flags != flags) {
+            protect_memory_region(mem.base, flags);
+        }
+    }
+
+    for &(name, ref handler, ref state) in &states {
+        try!(handler.restore_state(state).map_err(|err| format!("Device {}: {}", name, err)));
+    }
+
+    restore_vcpu_state(&snapshot.vcpu_state);
+    try!(clock::restore_guest_time(snapshot.clock_ns, snapshot.dilation));
+
+    for &(_, ref handler, _) in &states {
+        handler.restored();
+    }
+
+    /* Increments can be saved over the last one restored */
+    start_dirty_log(Some(hash));
+    Ok(())
+}
+
+/**
+ * Take running VM back to a snapshot saved on the way, for reverse execution, see reverse.rs
+ * Steps, exits, events and breakpoint stops in progress are dropped, restored memory gets INT3 breakpoints
+ * patched in again. Vcpu thread only.
+ */
+pub fn rewind(paths: &[&Path]) -> Result<(), String>
+{
+    assert_vcpu_thread();
+
+    cancel_step();
+    {
+        let vm = get_vm();
+        vm.exit_pending = None;
+        vm.reset_pending = false;
+        vm.exception_pending = None;
+        vm.nmi_pending = false;
+        vm.pending_io = None;
+        vm.pending_vector = None;
+        vm.resumed_vector = None;
+        vm.resumed_breakpoint = None;
+    }
+    cancel_all_external_interrupts();
+    write_vmcs(hv_vmx_vmcs_regs::VMCS_CTRL_VMENTRY_IRQ_INFO, 0);
+
+    try!(restore_snapshot(paths));
+
+    let int3: Vec<hv_gpaddr_t> = get_vm().breakpoints.list().iter().filter(|bp| bp.kind == breakpoint::BreakpointKind::Int3).map(|bp| bp.gpa).collect();
+    for gpa in int3 {
+        let mut orig = [0u8; 1];
+        read_guest_memory(gpa, &mut orig);
+        get_vm().breakpoints.set_orig(gpa, orig[0]);
+        write_breakpoint_byte(gpa, INT3_OPCODE);
+    }
+    Ok(())
+}
+
+/**
+ * Replay recording of guest inputs from the snapshot it starts from, see replay.rs
+ * Guest is stepped up to where it took interrupts, which needs monitor trap flag. Vcpu thread only.
+ */
+pub fn replay(snapshot: &Path, recording: replay::Replay) -> Result<(), String>
+{
+    assert_vcpu_thread();
+
+    if !get_vm().step_with_mtf {
+        return Err(String::from("Replay needs VMX monitor trap flag to step guest to interrupts"));
+    }
+    try!(restore_snapshot(&[snapshot]).map_err(|err| format!("Can't restore snapshot {}: {}", snapshot.display(), err)));
+    replay::start(recording, clock::guest_time_ns());
+    Ok(())
+}
+
+#[cfg(test)]
+fn test_dump_reader(addr: hv_gpaddr_t, buf: &mut [u8]) -> DumpChunk {
+    /* Pattern at 0x1000-0x1010, hole up to 0x1020, text from there to 0x1030 */
+    match addr {
+        0x1000...0x100F => {
+            let n = ::std::cmp::min(buf.len(), (0x1010 - addr) as usize);
+            for i in 0..n {
+                buf[i] = (addr as usize + i) as u8;
+            }
+            DumpChunk::Mapped(n)
+        },
+        0x1020...0x102F => {
+            let text = b"Hello, guest!\0\x01\x7f";
+            let off = (addr - 0x1020) as usize;
+            let n = ::std::cmp::min(buf.len(), text.len() - off);
+            buf[..n].copy_from_slice(&text[off..off + n]);
+            DumpChunk::Mapped(n)
+        },
+        0x1010...0x101F => DumpChunk::Unmapped(::std::cmp::min(buf.len(), 4)),
+        _ => DumpChunk::Unmapped(buf.len()),
+    }
+}
+
+#[test]
+fn test_dump_file() {
+    let (data, holes) = read_dump_range(&(0x1008..0x1028), test_dump_reader);
+    assert!(holes == vec![0x1010..0x1020]);
+    assert!(data.len() == 0x20);
+    assert!(data[0] == 0x08 && data[7] == 0x0F);
+    assert!(data[8..24].iter().all(|b| *b == 0xFF));
+    assert!(&data[24..] == b"Hello, g");
+
+    let path = ::std::env::temp_dir().join(format!("xvm-dump-test-{}.bin", ::std::process::id()));
+    let mut file = File::create(&path).unwrap();
+    write_dump(&mut file, 0x1008, &data, true).unwrap();
+    drop(file);
+
+    let mut saved = Vec::new();
+    io::Read::read_to_end(&mut File::open(&path).unwrap(), &mut saved).unwrap();
+    ::std::fs::remove_file(&path).unwrap();
+
+    assert!(saved.len() == DUMP_HEADER_SIZE + data.len());
+    assert!(&saved[0..8] == b"XVMDUMP\0");
+    assert!(&saved[8..16] == &[0x08, 0x10, 0, 0, 0, 0, 0, 0]);
+    assert!(&saved[16..24] == &[0x20, 0, 0, 0, 0, 0, 0, 0]);
+    assert!(&saved[DUMP_HEADER_SIZE..] == &data[..]);
+
+    /* Raw dump is just the bytes */
+    let mut raw = Vec::new();
+    write_dump(&mut raw, 0x1008, &data, false).unwrap();
+    assert!(raw == data);
+
+    /* Range with nothing mapped at all */
+    let (data, holes) = read_dump_range(&(0x2000..0x2100), test_dump_reader);
+    assert!(data.len() == 0x100 && data.iter().all(|b| *b == 0xFF));
+    assert!(holes == vec![0x2000..0x2100]);
+}
+
+#[test]
+fn test_hexdump() {
+    let (data, holes) = read_dump_range(&(0x1008..0x102C), test_dump_reader);
+    let dump = format_hexdump(0x1008, &data, &holes);
+    let lines: Vec<&str> = dump.lines().collect();
+    assert!(lines.len() == 3);
+    assert!(lines[0] == "00001008  08 09 0a 0b 0c 0d 0e 0f  ?? ?? ?? ?? ?? ?? ?? ??  |........        |");
+    assert!(lines[1] == "00001018  ?? ?? ?? ?? ?? ?? ?? ??  48 65 6c 6c 6f 2c 20 67  |        Hello, g|");
+    assert!(lines[2] == "00001028  75 65 73 74                                       |uest|");
+
+    assert!(format_hexdump(0x1000, &[], &[]) == "");
+}
+
+#[test]
+fn test_ram_image() {
+    /* 64K of RAM, 4K of it mapped above, and ROM after that */
+    let memory = vec![
+        memory_mapping { region: alloc_memory_region(0x10000), base: 0, flags: HV_MEMORY_READ | HV_MEMORY_WRITE },
+        memory_mapping { region: alloc_memory_region(0x1000), base: 0x10000, flags: HV_MEMORY_READ | HV_MEMORY_WRITE },
+        memory_mapping { region: alloc_memory_region(0x1000), base: 0x11000, flags: HV_MEMORY_READ },
+    ];
+    let pattern: Vec<u8> = (0..0x11000).map(|i| (i * 7 + i / 256) as u8).collect();
+    write_ram(&memory, 0, &pattern).unwrap();
+    assert!(whole_ram(&memory) == Ok(0..0x10000));
+
+    /* Ranged export off page boundaries, across two mappings */
+    let range = 0xFF83..0x10421;
+    let path = ::std::env::temp_dir().join(format!("xvm-ram-test-{}.bin", ::std::process::id()));
+    let data = read_ram(&memory, &range).unwrap();
+    File::create(&path).unwrap().write_all(&data).unwrap();
+    assert!(::std::fs::metadata(&path).unwrap().len() == 0x49E);
+    assert!(data[..] == pattern[0xFF83..0x10421]);
+
+    /* Scribble over it and more, import brings back the range and leaves what's around it */
+    write_ram(&memory, 0xF000, &vec![0x5A; 0x2000]).unwrap();
+    let mut saved = Vec::new();
+    io::Read::read_to_end(&mut File::open(&path).unwrap(), &mut saved).unwrap();
+    ::std::fs::remove_file(&path).unwrap();
+    assert!(write_ram(&memory, range.start, &saved) == Ok(range.clone()));
+
+    let after = read_ram(&memory, &(0..0x11000)).unwrap();
+    assert!(after[range.start as usize..range.end as usize] == pattern[range.start as usize..range.end as usize]);
+    assert!(after[0xF000..0xFF83].iter().all(|b| *b == 0x5A) && after[0x10421..0x11000].iter().all(|b| *b == 0x5A));
+    assert!(after[..0xF000] == pattern[..0xF000]);
+
+    /* ROM, nothing mapped and running out of address space fail with nothing written */
+    assert!(read_ram(&memory, &(0x10F00..0x11001)) == Err(String::from("0x11000 is in ROM, not RAM")));
+    assert!(write_ram(&memory, 0x10FFF, &[1, 2]) == Err(String::from("0x11000 is in ROM, not RAM")));
+    assert!(read_ram(&memory, &(0..0x10001)).unwrap().len() == 0x10001);
+    assert!(write_ram(&memory, 0x20000, &[1]) == Err(String::from("No RAM at 0x20000")));
+    assert!(read_ram(&memory, &(0x100..0x100)).is_err());
+    assert!(ram_range(!0 - 1, 4).is_err());
+    assert!(read_ram(&memory, &(0x10FF0..0x11000)) == Ok(vec![0x5A; 0x10]));
+}
+
+pub fn vcpu_create() -> hv_vcpuid_t 
+{
+    unsafe {
+        let mut vcpu: hv_vcpuid_t = 0;  
+        let res = hv_vcpu_create(&mut vcpu, HV_VCPU_DEFAULT);
+        assert!(res == HV_SUCCESS);
+        vcpu
+    }
+}
+
+/**
+ * Guest segment register
+ */
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct SegmentState
+{
+    pub selector: u16,
+    pub base: u64,
+    pub limit: u32,
+    pub attributes: u32,    // VMX access rights format
+}
+
+/**
+ * Guest vcpu registers, see vcpu_state()
+ */
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct VcpuState
+{
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub rip: u64,
+    pub rflags: u64,
+
+    pub es: SegmentState,
+    pub cs: SegmentState,
+    pub ss: SegmentState,
+    pub ds: SegmentState,
+    pub fs: SegmentState,
+    pub gs: SegmentState,
+
+    pub cr0: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+
+    pub interruptibility: u32,  // Blocking by STI, MOV SS, SMI and NMI
+    pub activity: u32,          // Active, HLT, shutdown or wait for SIPI
+}
+
+/* Register dump the way 32-bit guests are usually looked at */
+impl fmt::Display for VcpuState
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "EAX={:08x} EBX={:08x} ECX={:08x} EDX={:08x}", self.rax, self.rbx, self.rcx, self.rdx));
+        try!(writeln!(f, "ESI={:08x} EDI={:08x} EBP={:08x} ESP={:08x}", self.rsi, self.rdi, self.rbp, self.rsp));
+        try!(writeln!(f, "EIP={:08x} EFL={:08x} INT={:08x} ACT={:08x}", self.rip, self.rflags, self.interruptibility, self.activity));
+        for &(name, seg) in &[("ES", self.es), ("CS", self.cs), ("SS", self.ss), ("DS", self.ds), ("FS", self.fs), ("GS", self.gs)] {
+            try!(writeln!(f, "{} ={:04x} {:08x} {:08x} {:08x}", name, seg.selector, seg.base, seg.limit, seg.attributes));
+        }
+        write!(f, "CR0={:08x} CR3={:08x} CR4={:08x}", self.cr0, self.cr3, self.cr4)
+    }
+}
+
+#[test]
+fn test_vcpu_state_display() {
+    let real_mode = SegmentState { selector: 0, base: 0, limit: 0xffff, attributes: 0x93 };
+    let state = VcpuState {
+        rax: 0x1234,
+        rsp: 0x7c00,
+        rip: 0x7c10,
+        rflags: 0x2,
+        es: real_mode,
+        cs: SegmentState { attributes: 0x9b, ..real_mode },
+        ss: real_mode,
+        ds: SegmentState { selector: 0x40, base: 0x400, ..real_mode },
+        fs: real_mode,
+        gs: real_mode,
+        cr0: 0x20,
+        cr4: 0x2200,
+        activity: 1,
+        ..Default::default()
+    };
+
+    let dump = format!("{}", state);
+    let lines: Vec<&str> = dump.lines().collect();
+    assert!(lines.len() == 10);
+    assert!(lines[0] == "EAX=00001234 EBX=00000000 ECX=00000000 EDX=00000000");
+    assert!(lines[1] == "ESI=00000000 EDI=00000000 EBP=00000000 ESP=00007c00");
+    assert!(lines[2] == "EIP=00007c10 EFL=00000002 INT=00000000 ACT=00000001");
+    assert!(lines[4] == "CS =0000 00000000 0000ffff 0000009b");
+    assert!(lines[6] == "DS =0040 00000400 0000ffff 00000093");
+    assert!(lines[9] == "CR0=00000020 CR3=00000000 CR4=00002200");
+}
+
+/**
+ * Registers to change with set_vcpu_state(), the ones left None keep their values
+ */
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct VcpuStateUpdate
+{
+    pub rax: Option<u64>,
+    pub rbx: Option<u64>,
+    pub rcx: Option<u64>,
+    pub rdx: Option<u64>,
+    pub rsi: Option<u64>,
+    pub rdi: Option<u64>,
+    pub rbp: Option<u64>,
+    pub rsp: Option<u64>,
+    pub rip: Option<u64>,
+    pub rflags: Option<u64>,
+
+    pub es: Option<SegmentState>,
+    pub cs: Option<SegmentState>,
+    pub ss: Option<SegmentState>,
+    pub ds: Option<SegmentState>,
+    pub fs: Option<SegmentState>,
+    pub gs: Option<SegmentState>,
+}
+
+// Selector, base, limit and access rights fields of a segment register
+type SegmentFields = (hv_vmx_vmcs_regs, hv_vmx_vmcs_regs, hv_vmx_vmcs_regs, hv_vmx_vmcs_regs);
+
+const SEGMENT_ES: SegmentFields = (hv_vmx_vmcs_regs::VMCS_GUEST_ES, hv_vmx_vmcs_regs::VMCS_GUEST_ES_BASE,
+                                   hv_vmx_vmcs_regs::VMCS_GUEST_ES_LIMIT, hv_vmx_vmcs_regs::VMCS_GUEST_ES_AR);
+const SEGMENT_CS: SegmentFields = (hv_vmx_vmcs_regs::VMCS_GUEST_CS, hv_vmx_vmcs_regs::VMCS_GUEST_CS_BASE,
+                                   hv_vmx_vmcs_regs::VMCS_GUEST_CS_LIMIT, hv_vmx_vmcs_regs::VMCS_GUEST_CS_AR);
+const SEGMENT_SS: SegmentFields = (hv_vmx_vmcs_regs::VMCS_GUEST_SS, hv_vmx_vmcs_regs::VMCS_GUEST_SS_BASE,
+                                   hv_vmx_vmcs_regs::VMCS_GUEST_SS_LIMIT, hv_vmx_vmcs_regs::VMCS_GUEST_SS_AR);
+const SEGMENT_DS: SegmentFields = (hv_vmx_vmcs_regs::VMCS_GUEST_DS, hv_vmx_vmcs_regs::VMCS_GUEST_DS_BASE,
+                                   hv_vmx_vmcs_regs::VMCS_GUEST_DS_LIMIT, hv_vmx_vmcs_regs::VMCS_GUEST_DS_AR);
+const SEGMENT_FS: SegmentFields = (hv_vmx_vmcs_regs::VMCS_GUEST_FS, hv_vmx_vmcs_regs::VMCS_GUEST_FS_BASE,
+                                   hv_vmx_vmcs_regs::VMCS_GUEST_FS_LIMIT, hv_vmx_vmcs_regs::VMCS_GUEST_FS_AR);
+const SEGMENT_GS: SegmentFields = (hv_vmx_vmcs_regs::VMCS_GUEST_GS, hv_vmx_vmcs_regs::VMCS_GUEST_GS_BASE,
+                                   hv_vmx_vmcs_regs::VMCS_GUEST_GS_LIMIT, hv_vmx_vmcs_regs::VMCS_GUEST_GS_AR);
+
+fn read_vmcs(field: hv_vmx_vmcs_regs) -> u64
+{
+    unsafe {
+        let mut v: u64 = 0;
+        let res = hv_vmx_vcpu_read_vmcs(vcpu(), field as u32, &mut v);
+        if res != 0 {
+            panic!("VMCS read of {:x} failed with {:x}", field as u32, res);
+        }
+        v
+    }
+}
+
+fn write_vmcs(field: hv_vmx_vmcs_regs, v: u64)
+{
+    unsafe {
+        let res = hv_vmx_vcpu_write_vmcs(vcpu(), field as u32, v);
+        if res != 0 {
+            panic!("VMCS write of {:x} failed with {:x}", field as u32, res);
+        }
+    }
+}
+
+fn read_register(reg: hv_x86_reg_t) -> u64
+{
+    unsafe {
+        let mut v: u64 = 0;
+        let res = hv_vcpu_read_register(vcpu(), reg, &mut v);
+        if res != 0 {
+            panic!("hv_vcpu_read_register failed with {:x}", res);
+        }
+        v
+    }
+}
+
+fn write_register(reg: hv_x86_reg_t, v: u64)
+{
+    unsafe {
+        let res = hv_vcpu_write_register(vcpu(), reg, v);
+        if res != 0 {
+            panic!("hv_vcpu_write_register failed with {:x}", res);
+        }
+    }
+}
+
+fn read_segment(fields: SegmentFields) -> SegmentState
+{
+    let (selector, base, limit, attributes) = fields;
+    SegmentState {
+        selector: read_vmcs(selector) as u16,
+        base: read_vmcs(base),
+        limit: read_vmcs(limit) as u32,
+        attributes: read_vmcs(attributes) as u32,
+    }
+}
+
+fn write_segment(fields: SegmentFields, seg: &SegmentState)
+{
+    let (selector, base, limit, attributes) = fields;
+    write_vmcs(selector, seg.selector as u64);
+    write_vmcs(base, seg.base);
+    write_vmcs(limit, seg.limit as u64);
+    write_vmcs(attributes, seg.attributes as u64);
+}
+
+/**
+ * Guest state that decides which pending events it can take, vcpu thread only
+ */
+pub fn guest_event_state() -> inject::GuestState
+{
+    let intstate = read_vmcs(hv_vmx_vmcs_regs::VMCS_GUEST_IGNORE_IRQ);
+
+    inject::GuestState {
+        interrupts_enabled: (read_register(hv_x86_reg_t::HV_X86_RFLAGS) & RFLAGS_IF) != 0,
+        interrupt_shadow: (intstate & (INTERRUPTIBILITY_STI | INTERRUPTIBILITY_MOV_SS)) != 0,
+        nmi_blocked: (intstate & INTERRUPTIBILITY_NMI) != 0,
+    }
+}
+
+/* HV framework only lets vcpu owner thread touch its registers */
+fn assert_vcpu_thread()
+{
+    assert!(thread::current().id() == get_vm().vcpu_thread, "vcpu state accessed outside of vcpu thread");
+}
+
+/**
+ * Guest registers as of the last exit
+ * Must be called on vcpu thread, i.e. while an exit is handled or VM is held, host threads ask vcpu thread
+ * for it through its debugger or monitor.
+ */
+pub fn vcpu_state() -> VcpuState
+{
+    assert_vcpu_thread();
+
+    VcpuState {
+        rax: read_register(hv_x86_reg_t::HV_X86_RAX),
+        rbx: read_register(hv_x86_reg_t::HV_X86_RBX),
+        rcx: read_register(hv_x86_reg_t::HV_X86_RCX),
+        rdx: read_register(hv_x86_reg_t::HV_X86_RDX),
+        rsi: read_register(hv_x86_reg_t::HV_X86_RSI),
+        rdi: read_register(hv_x86_reg_t::HV_X86_RDI),
+        rbp: read_register(hv_x86_reg_t::HV_X86_RBP),
+        rsp: read_register(hv_x86_reg_t::HV_X86_RSP),
+        rip: read_register(hv_x86_reg_t::HV_X86_RIP),
+        rflags: read_register(hv_x86_reg_t::HV_X86_RFLAGS),
+
+        es: read_segment(SEGMENT_ES),
+        cs: read_segment(SEGMENT_CS),
+        ss: read_segment(SEGMENT_SS),
+        ds: read_segment(SEGMENT_DS),
+        fs: read_segment(SEGMENT_FS),
+        gs: read_segment(SEGMENT_GS),
+
+        cr0: read_vmcs(hv_vmx_vmcs_regs::VMCS_GUEST_CR0),
+        cr3: read_vmcs(hv_vmx_vmcs_regs::VMCS_GUEST_CR3),
+        cr4: read_vmcs(hv_vmx_vmcs_regs::VMCS_GUEST_CR4),
+
+        interruptibility: read_vmcs(hv_vmx_vmcs_regs::VMCS_GUEST_IGNORE_IRQ) as u32,
+        activity: read_vmcs(hv_vmx_vmcs_regs::VMCS_GUEST_ACTIVITY_STATE) as u32,
+    }
+}
+
+/**
+ * Exit reason and qualification of the last exit, vcpu thread only
+ */
+pub fn exit_info() -> (u32, u64)
+{
+    assert_vcpu_thread();
+    (read_vmcs(hv_vmx_vmcs_regs::VMCS_RO_EXIT_REASON) as u32, read_vmcs(hv_vmx_vmcs_regs::VMCS_RO_EXIT_QUALIFIC))
+}
+
+/**
+ * Change guest registers, takes effect on next entry
+ * Segment registers are loaded as given, descriptor tables are not consulted. Same thread rules as
+ * for vcpu_state() apply.
+ */
+pub fn set_vcpu_state(update: &VcpuStateUpdate)
+{
+    assert_vcpu_thread();
+
+    for &(val, reg) in &[(update.rax, hv_x86_reg_t::HV_X86_RAX), (update.rbx, hv_x86_reg_t::HV_X86_RBX),
+                         (update.rcx, hv_x86_reg_t::HV_X86_RCX), (update.rdx, hv_x86_reg_t::HV_X86_RDX),
+                         (update.rsi, hv_x86_reg_t::HV_X86_RSI), (update.rdi, hv_x86_reg_t::HV_X86_RDI),
+                         (update.rbp, hv_x86_reg_t::HV_X86_RBP), (update.rsp, hv_x86_reg_t::HV_X86_RSP),
+                         (update.rip, hv_x86_reg_t::HV_X86_RIP), (update.rflags, hv_x86_reg_t::HV_X86_RFLAGS)] {
+        if let Some(val) = val {
+            write_register(reg, val);
+        }
+    }
+
+    for &(seg, fields) in &[(update.es, SEGMENT_ES), (update.cs, SEGMENT_CS), (update.ss, SEGMENT_SS),
+                            (update.ds, SEGMENT_DS), (update.fs, SEGMENT_FS), (update.gs, SEGMENT_GS)] {
+        if let Some(ref seg) = seg {
+            write_segment(fields, seg);
+        }
+    }
+}
+
+/**
+ * Load every guest register vcpu_state() reads, e.g. from a snapshot, vcpu thread only
+ */
+pub fn restore_vcpu_state(state: &VcpuState)
+{
+    set_vcpu_state(&VcpuStateUpdate {
+        rax: Some(state.rax),
+        rbx: Some(state.rbx),
+        rcx: Some(state.rcx),
+        rdx: Some(state.rdx),
+        rsi: Some(state.rsi),
+        rdi: Some(state.rdi),
+        rbp: Some(state.rbp),
+        rsp: Some(state.rsp),
+        rip: Some(state.rip),
+        rflags: Some(state.rflags),
+        es: Some(state.es),
+        cs: Some(state.cs),
+        ss: Some(state.ss),
+        ds: Some(state.ds),
+        fs: Some(state.fs),
+        gs: Some(state.gs),
+    });
+
+    /* Guest sees CR0 through its shadow */
+    write_vmcs(hv_vmx_vmcs_regs::VMCS_GUEST_CR0, state.cr0);
+    write_vmcs(hv_vmx_vmcs_regs::VMCS_CTRL_CR0_SHADOW, state.cr0);
+    write_vmcs(hv_vmx_vmcs_regs::VMCS_GUEST_CR3, state.cr3);
+    write_vmcs(hv_vmx_vmcs_regs::VMCS_GUEST_CR4, state.cr4);
+    write_vmcs(hv_vmx_vmcs_regs::VMCS_GUEST_IGNORE_IRQ, state.interruptibility as u64);
+    write_vmcs(hv_vmx_vmcs_regs::VMCS_GUEST_ACTIVITY_STATE, state.activity as u64);
+}
+
+// Registers register_update() sets by name, as monitor shows them
+const GENERAL_REGISTERS: [&'static str; 10] = ["EAX", "EBX", "ECX", "EDX", "ESI", "EDI", "EBP", "ESP", "EIP", "EFL"];
+const SEGMENT_REGISTERS: [&'static str; 6] = ["ES", "CS", "SS", "DS", "FS", "GS"];
+
+/**
+ * Update setting register named the way monitor shows it, e.g. EAX or EFL, and the value it replaces
+ * Segment registers take a selector and get the real mode base along.
+ */
+pub fn register_update(name: &str, val: u64, state: &VcpuState) -> Result<(u64, VcpuStateUpdate), String>
+{
+    let name = name.to_uppercase();
+    let mut update = VcpuStateUpdate::default();
+
+    if let Some(i) = GENERAL_REGISTERS.iter().position(|&reg| reg == name) {
+        if val > 0xFFFFFFFF {
+            return Err(format!("0x{:x} doesn't fit in {}", val, name));
+        }
+
+        let (old, slot) = match i {
+            0 => (state.rax, &mut update.rax),
+            1 => (state.rbx, &mut update.rbx),
+            2 => (state.rcx, &mut update.rcx),
+            3 => (state.rdx, &mut update.rdx),
+            4 => (state.rsi, &mut update.rsi),
+            5 => (state.rdi, &mut update.rdi),
+            6 => (state.rbp, &mut update.rbp),
+            7 => (state.rsp, &mut update.rsp),
+            8 => (state.rip, &mut update.rip),
+            _ => (state.rflags, &mut update.rflags),
+        };
+        *slot = Some(val);
+        return Ok((old, update));
+    }
+
+    if let Some(i) = SEGMENT_REGISTERS.iter().position(|&reg| reg == name) {
+        if val > 0xFFFF {
+            return Err(format!("0x{:x} doesn't fit in {}", val, name));
+        }
+
+        let (seg, slot) = match i {
+            0 => (state.es, &mut update.es),
+            1 => (state.cs, &mut update.cs),
+            2 => (state.ss, &mut update.ss),
+            3 => (state.ds, &mut update.ds),
+            4 => (state.fs, &mut update.fs),
+            _ => (state.gs, &mut update.gs),
+        };
+        *slot = Some(SegmentState { selector: val as u16, base: val << 4, ..seg });
+        return Ok((seg.selector as u64, update));
+    }
+
+    Err(format!("Can't set register {}", name))
+}
+
+#[test]
+fn test_register_update() {
+    let real_mode = SegmentState { selector: 0, base: 0, limit: 0xffff, attributes: 0x93 };
+    let state = VcpuState { rax: 0x1234, rflags: 0x202, ds: real_mode, ..Default::default() };
+
+    assert!(register_update("eax", 0x55, &state) == Ok((0x1234, VcpuStateUpdate { rax: Some(0x55), ..Default::default() })));
+    assert!(register_update("EFL", 0x2, &state) == Ok((0x202, VcpuStateUpdate { rflags: Some(0x2), ..Default::default() })));
+
+    /* Segment keeps limit and rights, base follows selector */
+    let ds = SegmentState { selector: 0x40, base: 0x400, ..real_mode };
+    assert!(register_update("ds", 0x40, &state) == Ok((0, VcpuStateUpdate { ds: Some(ds), ..Default::default() })));
+
+    assert!(register_update("eax", 0x100000000, &state).is_err());
+    assert!(register_update("ds", 0x10000, &state).is_err());
+    assert!(register_update("cr0", 0, &state).is_err());
+}
+
+/**
+ * Set register by name on behalf of a host user, e.g. from monitor, returns the value it replaced
+ * Vcpu thread only, the write goes to the event log.
+ */
+pub fn set_register(name: &str, val: u64) -> Result<u64, String>
+{
+    assert_vcpu_thread();
+
+    let (old, update) = try!(register_update(name, val, &vcpu_state()));
+    set_vcpu_state(&update);
+    eventlog::emit(|| eventlog::Event::RegisterWrite { reg: name.to_uppercase(), old: old, new: val });
+    Ok(old)
+}
+
+/*
+ * Host side pause requests
+ *
+ * Host threads that need a consistent view of guest state (e.g. video memory) ask vcpu thread to
+ * park before its next guest entry. While parked neither guest code nor event loop runs and guest time
+ * stands still, so no device timer comes due. Host input arriving meanwhile stays in backend queues, it
+ * is picked up by device polls in guest context after resume and no interrupt is raised for it before.
+ */
+struct PauseState {
+    requests: usize,                // Outstanding pause guards
+    paused: bool,                   // Vcpu thread is parked
+    handlers_paused: bool,          // Pause handlers were told VM stopped and not told it resumed yet
+    step_requested: bool,           // Host asked parked vcpu for a single step
+    step_result: Option<VcpuState>, // Vcpu state after host requested step
+}
+
+lazy_static! {
+    static ref PAUSE_STATE: Mutex<PauseState> = Mutex::new(PauseState {
+        requests: 0,
+        paused: false,
+        handlers_paused: false,
+        step_requested: false,
+        step_result: None,
+    });
+    static ref PAUSE_COND: Condvar = Condvar::new();
+}
+
+/**
+ * VM stays paused until guard is dropped, see pause()
+ */
+pub struct PauseGuard;
+
+impl Drop for PauseGuard {
+    fn drop(&mut self) {
+        let mut state = PAUSE_STATE.lock().unwrap();
+        state.requests -= 1;
+        PAUSE_COND.notify_all();
+    }
+}
+
+/**
+ * Pause VM from a host thread
+ * Blocks until vcpu thread is out of guest and parked. Must not be called from vcpu thread.
+ */
+pub fn pause() -> PauseGuard
+{
+    let mut state = PAUSE_STATE.lock().unwrap();
+    state.requests += 1;
+    interrupt_guest();
+
+    while !state.paused {
+        state = PAUSE_COND.wait(state).unwrap();
+    }
+
+    PauseGuard
+}
+
+/* Tell pause handlers VM stopped, unless they already know */
+fn notify_paused(state: &mut PauseState)
+{
+    if !state.handlers_paused {
+        for i in &get_vm().pause_handlers {
+            i.paused();
+        }
+        state.handlers_paused = true;
+    }
+}
+
+fn notify_resumed(state: &mut PauseState)
+{
+    if state.handlers_paused {
+        for i in &get_vm().pause_handlers {
+            i.resumed();
+        }
+        state.handlers_paused = false;
+    }
+}
+
+/*
+ * Park vcpu thread while host holds pause guards
+ * Host requested step leaves from here into guest with VM still paused, it parks again once the step ends.
+ */
+fn wait_while_paused()
+{
+    let mut state = PAUSE_STATE.lock().unwrap();
+    if get_vm().step.is_some() {
+        return;
+    }
+
+    while state.requests != 0 {
+        notify_paused(&mut state);
+        state.paused = true;
+        PAUSE_COND.notify_all();
+
+        if state.step_requested {
+            state.step_requested = false;
+            state.paused = false;
+            start_step(StepOwner::Host);
+            return;
+        }
+
+        state = PAUSE_COND.wait(state).unwrap();
+    }
+
+    state.paused = false;
+    notify_resumed(&mut state);
+}
+
+/**
+ * Keep VM stopped on vcpu thread while f runs, e.g. while a debugger looks at guest
+ * Guest time stands still like on a host pause, and host pause requests meanwhile are granted right away.
+ */
+pub fn hold<R, F: FnOnce() -> R>(f: F) -> R
+{
+    {
+        let mut state = PAUSE_STATE.lock().unwrap();
+        notify_paused(&mut state);
+        state.paused = true;
+        PAUSE_COND.notify_all();
+    }
+
+    let res = f();
+
+    /* Host that paused VM meanwhile keeps it paused, a step it asks for is taken before guest entry */
+    {
+        let mut state = PAUSE_STATE.lock().unwrap();
+        while state.requests != 0 && !state.step_requested {
+            state = PAUSE_COND.wait(state).unwrap();
+        }
+
+        if state.requests == 0 {
+            state.paused = false;
+            notify_resumed(&mut state);
+        }
+    }
+
+    res
+}
+
+/*
+ * Single stepping
+ *
+ * A step runs guest for one instruction. It uses monitor trap flag when VMX has it, otherwise it sets guest
+ * TF and intercepts the #DB that follows, guest sees TF in flags it pushes or reads meanwhile. Step ends at
+ * that trap or at the first exit after which guest IP moved, so an instruction VMM emulates (port I/O, MMIO,
+ * hypercall) is dispatched in full and ends the step without running the next one.
+ *
+ * External interrupts and NMIs stay pending while a step is in progress and are delivered on the next normal
+ * run. Only an event that was already set up for injection when a host step starts goes in, with monitor
+ * trap flag the step then ends at the first instruction of its handler. REP string instructions step one
+ * iteration at a time: IP stays on the instruction with count register decremented until the last one.
+ */
+
+// Guest RFLAGS.TF and #DB bit in exception bitmap
+const RFLAGS_TF: u64                = 1 << 8;
+const EXC_BITMAP_DB: u64            = 1 << 1;
+
+/**
+ * Who asked for a single step
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum StepOwner
+{
+    Debugger,   // Debugger stub on vcpu thread, see start_step()
+    Host,       // Host thread through single_step()
+    Breakpoint, // Stepping over a breakpoint, see breakpoint.rs
+    Trace,      // Execution trace, see trace.rs
+    Replay,     // Replay getting guest to where it took an interrupt, see replay.rs
+    Reverse,    // Reverse execution looking for the instruction to stop at, see reverse.rs
+}
+
+struct SingleStep {
+    owner: StepOwner,
+    ip: hv_gpaddr_t,                // Linear address of stepped instruction
+    guest_tf: bool,                 // Guest had TF set itself, restored after a TF step
+    rearm: Option<hv_gpaddr_t>,     // Breakpoint stepped over, put back after the step
+}
+
+/**
+ * Linear address of current guest instruction
+ */
+pub fn current_ip() -> hv_gpaddr_t
+{
+    read_vmcs(hv_vmx_vmcs_regs::VMCS_GUEST_CS_BASE) + read_vmcs(hv_vmx_vmcs_regs::VMCS_GUEST_RIP)
+}
+
+/**
+ * Tell whether VMX can exit on monitor trap flag, steps set guest TF otherwise
+ */
+pub fn set_step_with_mtf(supported: bool)
+{
+    get_vm().step_with_mtf = supported;
+}
+
+/**
+ * Steps exit on monitor trap flag and stop right at interrupt handlers
+ */
+pub fn step_with_mtf() -> bool
+{
+    get_vm().step_with_mtf
+}
+
+/**
+ * Arm single step for next guest entry, must be called on vcpu thread
+ * A step reverse execution armed to get guest back to where recording stopped is taken over as it is.
+ */
+pub fn start_step(owner: StepOwner)
+{
+    assert_vcpu_thread();
+    if let Some(ref mut step) = get_vm().step {
+        if step.owner == StepOwner::Reverse {
+            step.owner = owner;
+            return;
+        }
+    }
+    assert!(get_vm().step.is_none());
+
+    let rflags = read_register(hv_x86_reg_t::HV_X86_RFLAGS);
+    if get_vm().step_with_mtf {
+        let ctrls = read_vmcs(hv_vmx_vmcs_regs::VMCS_CTRL_CPU_BASED);
+        write_vmcs(hv_vmx_vmcs_regs::VMCS_CTRL_CPU_BASED, ctrls | CPU_BASED_MTF as u64);
+    } else {
+        write_register(hv_x86_reg_t::HV_X86_RFLAGS, rflags | RFLAGS_TF);
+        let bitmap = read_vmcs(hv_vmx_vmcs_regs::VMCS_CTRL_EXC_BITMAP);
+        write_vmcs(hv_vmx_vmcs_regs::VMCS_CTRL_EXC_BITMAP, bitmap | EXC_BITMAP_DB);
+    }
+
+    get_vm().step = Some(SingleStep {
+        owner: owner,
+        ip: current_ip(),
+        guest_tf: rflags & RFLAGS_TF != 0,
+        rearm: None,
+    });
+}
+
+/**
+ * Single step is armed and didn't end yet, interrupts wait meanwhile
+ */
+pub fn step_in_progress() -> bool
+{
+    get_vm().step.is_some()
+}
+
+/**
+ * Check if single step ended once an exit is handled, trapped tells exit was the step trap itself
+ * Returns who asked for the ended step, host waiting in single_step() gets its vcpu state from here.
+ */
+pub fn finish_step(trapped: bool) -> Option<StepOwner>
+{
+    let done = match get_vm().step {
+        Some(ref step) => trapped || current_ip() != step.ip,
+        None => false,
+    };
+    if !done {
+        return None;
+    }
+
+    let step = end_step();
+    if step.owner == StepOwner::Host {
+        let mut state = PAUSE_STATE.lock().unwrap();
+        state.step_result = Some(vcpu_state());
+        PAUSE_COND.notify_all();
+    }
+
+    Some(step.owner)
+}
+
+/**
+ * Drop single step in progress without running it, e.g. when VM is rewound
+ */
+pub fn cancel_step()
+{
+    if get_vm().step.is_some() {
+        end_step();
+    }
+}
+
+/* Take single step off guest, breakpoint it stepped over goes back */
+fn end_step() -> SingleStep
+{
+    let step = get_vm().step.take().unwrap();
+    if get_vm().step_with_mtf {
+        let ctrls = read_vmcs(hv_vmx_vmcs_regs::VMCS_CTRL_CPU_BASED);
+        write_vmcs(hv_vmx_vmcs_regs::VMCS_CTRL_CPU_BASED, ctrls & !(CPU_BASED_MTF as u64));
+    } else {
+        let rflags = read_register(hv_x86_reg_t::HV_X86_RFLAGS);
+        let tf = if step.guest_tf { RFLAGS_TF } else { 0 };
+        write_register(hv_x86_reg_t::HV_X86_RFLAGS, (rflags & !RFLAGS_TF) | tf);
+
+        /* Guest tracing build keeps intercepting #DB for itself */
+        if !cfg!(feature = "guest-tracing") {
+            let bitmap = read_vmcs(hv_vmx_vmcs_regs::VMCS_CTRL_EXC_BITMAP);
+            write_vmcs(hv_vmx_vmcs_regs::VMCS_CTRL_EXC_BITMAP, bitmap & !EXC_BITMAP_DB);
+        }
+    }
+
+    if let Some(gpa) = step.rearm {
+        rearm_breakpoint(gpa);
+    }
+    step
+}
+
+/**
+ * Run guest for one instruction while host keeps VM paused, returns vcpu state after the step
+ * Exits the instruction causes are handled by devices as usual, guest time doesn't advance.
+ */
+pub fn single_step(_guard: &PauseGuard) -> VcpuState
+{
+    let mut state = PAUSE_STATE.lock().unwrap();
+    state.step_result = None;
+    state.step_requested = true;
+    PAUSE_COND.notify_all();
+
+    loop {
+        match state.step_result.take() {
+            Some(res) => return res,
+            None => state = PAUSE_COND.wait(state).unwrap(),
+        }
+    }
+}
+
+/*
+ * Execution breakpoints, see breakpoint.rs
+ * They are set and hit on vcpu thread, a hit ends up as VmExit::Breakpoint for whoever runs VM loop. Guest
+ * linear addresses are taken for physical ones, guest paging is not walked.
+ */
+
+const INT3_OPCODE: u8               = 0xCC;
+const EXC_BITMAP_BP: u64            = 1 << 3;
+
+fn write_breakpoint_byte(gpa: hv_gpaddr_t, val: u8)
+{
+    let mapping = find_memory_mapping(gpa).unwrap();
+    mapping.region.write_bytes((gpa - mapping.base) as usize, &[val]);
+}
+
+/* Take execute right of page away or give it back, pages dirty page log hasn't seen written stay read only */
+fn protect_page(page: hv_gpaddr_t, protect: bool)
+{
+    let mapping = find_memory_mapping(page).unwrap();
+    let mut flags = if protect { mapping.flags & !HV_MEMORY_EXEC } else { mapping.flags };
+    if !mapping.region.is_page_dirty((page - mapping.base) as usize / DIRTY_PAGE_SIZE) {
+        flags &= !HV_MEMORY_WRITE;
+    }
+
+    unsafe {
+        let res = hv_vm_protect(page, breakpoint::PAGE_SIZE as usize, flags);
         assert!(res == HV_SUCCESS);
     }
+}
 
-    get_vm().memory.push(memory_mapping { region: region, base: base, flags: flags });
+fn insert_breakpoint(gpa: hv_gpaddr_t, kind: breakpoint::BreakpointKind) -> Result<breakpoint::BreakpointHandle, String>
+{
+    assert_vcpu_thread();
+
+    let mapping = match find_memory_mapping(gpa) {
+        Some(mapping) => mapping,
+        None => return Err(format!("No memory at 0x{:x}", gpa)),
+    };
+
+    if kind == breakpoint::BreakpointKind::Int3 && mapping.flags & HV_MEMORY_WRITE == 0 {
+        return Err(format!("0x{:x} is in ROM, use an execute protection breakpoint there", gpa));
+    }
+
+    let mut orig = [0u8; 1];
+    mapping.region.read_bytes((gpa - mapping.base) as usize, &mut orig);
+    let handle = try!(get_vm().breakpoints.add(gpa, kind, orig[0]));
+
+    match kind {
+        breakpoint::BreakpointKind::Int3 => {
+            write_breakpoint_byte(gpa, INT3_OPCODE);
+
+            /* Guest INT3 exits from now on too, vcpu loop reflects those back to guest */
+            let bitmap = read_vmcs(hv_vmx_vmcs_regs::VMCS_CTRL_EXC_BITMAP);
+            write_vmcs(hv_vmx_vmcs_regs::VMCS_CTRL_EXC_BITMAP, bitmap | EXC_BITMAP_BP);
+        },
+        breakpoint::BreakpointKind::ExecProtect => protect_page(breakpoint::page_of(gpa), true),
+    }
+
+    Ok(handle)
 }
 
-pub fn find_memory_mapping(addr: hv_gpaddr_t) -> Option<&'static memory_mapping>
+/**
+ * Stop guest before it executes instruction at gpa by patching INT3 there, writable memory only
+ * Guest reading the byte sees 0xCC, see breakpoint.rs. Must be called on vcpu thread.
+ */
+pub fn add_breakpoint(gpa: hv_gpaddr_t) -> Result<breakpoint::BreakpointHandle, String>
 {
-    for i in &get_vm().memory {
-        if addr >= i.base && addr < i.base + i.region.size as u64 {
-            return Some(i);
-        }
+    insert_breakpoint(gpa, breakpoint::BreakpointKind::Int3)
+}
+
+/**
+ * Stop guest before it executes instruction at gpa by taking execute right of its page away
+ * Guest memory is left alone, so this works in ROM and under self checking code, but every instruction
+ * on the page exits. Must be called on vcpu thread.
+ */
+pub fn add_exec_breakpoint(gpa: hv_gpaddr_t) -> Result<breakpoint::BreakpointHandle, String>
+{
+    insert_breakpoint(gpa, breakpoint::BreakpointKind::ExecProtect)
+}
+
+/**
+ * Remove execution, I/O or vector breakpoint, false if there is no such breakpoint
+ */
+pub fn remove_breakpoint(handle: breakpoint::BreakpointHandle) -> bool
+{
+    assert_vcpu_thread();
+
+    if get_vm().breakpoints.remove_io(handle).is_some() || get_vm().breakpoints.remove_vector(handle).is_some() {
+        return true;
     }
 
-    return None;
+    let bp = match get_vm().breakpoints.remove(handle) {
+        Some(bp) => bp,
+        None => return false,
+    };
+
+    match bp.kind {
+        breakpoint::BreakpointKind::Int3 => write_breakpoint_byte(bp.gpa, bp.orig),
+        breakpoint::BreakpointKind::ExecProtect => {
+            let page = breakpoint::page_of(bp.gpa);
+            if !get_vm().breakpoints.is_page_protected(page) {
+                protect_page(page, false);
+            }
+        },
+    }
+
+    true
 }
 
-pub fn read_guest_memory(addr: hv_gpaddr_t, buf: &mut [u8]) -> usize
+/**
+ * Breakpoints set in guest
+ */
+pub fn breakpoints() -> Vec<breakpoint::Breakpoint>
 {
-    let mapping = match find_memory_mapping(addr) {
+    get_vm().breakpoints.list().to_vec()
+}
+
+/* Put breakpoint at gpa and execute protection of its page back, if they are still wanted */
+fn rearm_breakpoint(gpa: hv_gpaddr_t)
+{
+    let int3 = match get_vm().breakpoints.find(gpa) {
+        Some(bp) => bp.kind == breakpoint::BreakpointKind::Int3,
+        None => false,
+    };
+    if int3 {
+        write_breakpoint_byte(gpa, INT3_OPCODE);
+    }
+
+    let page = breakpoint::page_of(gpa);
+    if get_vm().breakpoints.is_page_protected(page) {
+        protect_page(page, true);
+    }
+}
+
+/* Run next instruction with breakpoint at gpa or execute protection of its page out of the way */
+fn step_over_breakpoint(gpa: hv_gpaddr_t)
+{
+    let orig = match get_vm().breakpoints.find(gpa) {
+        Some(bp) if bp.kind == breakpoint::BreakpointKind::Int3 => Some(bp.orig),
+        _ => None,
+    };
+    if let Some(orig) = orig {
+        write_breakpoint_byte(gpa, orig);
+    }
+
+    let page = breakpoint::page_of(gpa);
+    if get_vm().breakpoints.is_page_protected(page) {
+        protect_page(page, false);
+    }
+
+    /* A step already in progress, e.g. debugger's, puts breakpoint back when it ends */
+    if get_vm().step.is_none() {
+        start_step(StepOwner::Breakpoint);
+    }
+    get_vm().step.as_mut().unwrap().rearm = Some(gpa);
+}
+
+/* Stop VM loop at breakpoint, guest executes the instruction once resumed */
+fn breakpoint_hit(gpa: hv_gpaddr_t)
+{
+    step_over_breakpoint(gpa);
+    if get_vm().resumed_breakpoint.take() == Some(gpa) {
+        return;
+    }
+    debug!("Breakpoint hit at {:x}", gpa);
+    request_vm_exit(VmExit::Breakpoint { gpa: gpa, vcpu_state: vcpu_state() });
+}
+
+/**
+ * Guest INT3 exit at ip, true when it is an INT3 breakpoint and VM loop is to stop
+ */
+pub fn hit_int3_breakpoint(ip: hv_gpaddr_t) -> bool
+{
+    match get_vm().breakpoints.find(ip) {
+        Some(bp) if bp.kind == breakpoint::BreakpointKind::Int3 => {},
+        _ => return false,
+    }
+
+    breakpoint_hit(ip);
+    true
+}
+
+/**
+ * Guest fetched instruction at ip from gpa in a page execute protection breakpoints keep non-executable
+ * VM loop stops when ip is a breakpoint, any other instruction runs as a single step. Returns false when
+ * breakpoints don't protect the page.
+ */
+pub fn handle_exec_fault(gpa: hv_gpaddr_t, ip: hv_gpaddr_t) -> bool
+{
+    if !get_vm().breakpoints.is_page_protected(breakpoint::page_of(gpa)) {
+        return false;
+    }
+
+    let hit = match get_vm().breakpoints.find(ip) {
+        Some(bp) => bp.kind == breakpoint::BreakpointKind::ExecProtect,
+        None => false,
+    };
+
+    if hit {
+        breakpoint_hit(ip);
+    } else {
+        step_over_breakpoint(gpa);
+    }
+    true
+}
+
+/**
+ * Guest got to a breakpoint without hitting it, e.g. reverse execution stopped there, and runs its instruction
+ * once resumed instead of stopping at it again
+ */
+pub fn resume_at_breakpoint()
+{
+    let ip = current_ip();
+    if get_vm().breakpoints.find(ip).is_some() {
+        get_vm().resumed_breakpoint = Some(ip);
+    }
+}
+
+/* Whether execute protection breakpoints keep page non-executable, they don't while one there is stepped over */
+fn is_exec_protected(page: hv_gpaddr_t) -> bool
+{
+    let stepping = get_vm().step.as_ref().and_then(|step| step.rearm).map_or(false, |gpa| breakpoint::page_of(gpa) == page);
+    get_vm().breakpoints.is_page_protected(page) && !stepping
+}
+
+/**
+ * Guest wrote to gpa in a RAM page dirty page log keeps read only, log it as written and let guest write there
+ * Returns false when gpa isn't such a page, e.g. it is MMIO.
+ */
+pub fn handle_write_fault(gpa: hv_gpaddr_t) -> bool
+{
+    let mapping = match find_memory_mapping(gpa) {
         Some(mapping) => mapping,
-        None => return 0,
+        None => return false,
     };
 
-    assert!(addr >= mapping.base);
-    mapping.region.read_bytes((addr - mapping.base) as usize, buf)
+    let offset = (gpa - mapping.base) as usize;
+    if mapping.flags & HV_MEMORY_WRITE == 0 || mapping.region.is_page_dirty(offset / DIRTY_PAGE_SIZE) {
+        return false;
+    }
+
+    mapping.region.mark_dirty(offset, 1);
+    let page = breakpoint::page_of(gpa);
+    protect_page(page, is_exec_protected(page));
+    true
 }
 
-pub fn vcpu_create() -> hv_vcpuid_t 
+/*
+ * I/O breakpoints stop port accesses before they are dispatched to devices. VM loop gets VmExit::IoBreakpoint
+ * and the access stays pending until resume_io_breakpoint() performs it and moves guest past its instruction.
+ */
+
+struct PendingIo {
+    port: u16,
+    size: u8,
+    direction: breakpoint::IoDirection,
+    value: u32,
+    instr_len: u64,
+}
+
+/* Accumulator bits port access of size bytes uses */
+fn io_mask(size: u8) -> u32
 {
-    unsafe {
-        let mut vcpu: hv_vcpuid_t = 0;  
-        let res = hv_vcpu_create(&mut vcpu, HV_VCPU_DEFAULT);
-        assert!(res == HV_SUCCESS);
-        vcpu
+    match size {
+        1 => 0xFF,
+        2 => 0xFFFF,
+        _ => 0xFFFFFFFF,
+    }
+}
+
+/**
+ * Stop guest at accesses in direction to any port in range, before devices see them
+ */
+pub fn add_io_breakpoint(ports: Range<u16>, direction: breakpoint::IoDirection) -> Result<breakpoint::BreakpointHandle, String>
+{
+    get_vm().breakpoints.add_io(ports, direction)
+}
+
+/**
+ * I/O breakpoints set in guest
+ */
+pub fn io_breakpoints() -> Vec<breakpoint::IoBreakpoint>
+{
+    get_vm().breakpoints.io_list().to_vec()
+}
+
+/**
+ * Check guest port access of size bytes about to be dispatched against I/O breakpoints
+ * Returns true when one stops it, VM loop then has to leave the access to resume_io_breakpoint().
+ */
+pub fn hit_io_breakpoint(port: u16, size: u8, direction: breakpoint::IoDirection, value: u32) -> bool
+{
+    /* Every port access comes through here, keep it to one look at the list */
+    if get_vm().breakpoints.find_io(port, size, direction).is_none() {
+        return false;
+    }
+
+    debug!("I/O breakpoint hit at port {:x}", port);
+    let value = value & io_mask(size);
+    assert!(get_vm().pending_io.is_none());
+    get_vm().pending_io = Some(PendingIo {
+        port: port,
+        size: size,
+        direction: direction,
+        value: value,
+        instr_len: read_vmcs(hv_vmx_vmcs_regs::VMCS_RO_VMEXIT_INSTR_LEN),
+    });
+
+    request_vm_exit(VmExit::IoBreakpoint { port: port, direction: direction, value: value, vcpu_state: vcpu_state() });
+    true
+}
+
+/**
+ * Perform port access stopped at I/O breakpoint and move guest past it
+ * Substituted value is what a read returns to guest without asking device, or what a write sends to device.
+ */
+pub fn resume_io_breakpoint(substitute: Option<u32>)
+{
+    assert_vcpu_thread();
+
+    let io = get_vm().pending_io.take().expect("no port access stopped at I/O breakpoint");
+    let mask = io_mask(io.size);
+
+    if io.direction == breakpoint::IoDirection::Read {
+        let val = match substitute {
+            Some(val) => val,
+            None => match handle_io_read(io.port, io.size) {
+                IoOperandType::byte(val) => val as u32,
+                IoOperandType::word(val) => val as u32,
+                IoOperandType::dword(val) => val,
+            },
+        };
+
+        let rax = read_register(hv_x86_reg_t::HV_X86_RAX);
+        write_register(hv_x86_reg_t::HV_X86_RAX, (rax & !(mask as u64)) | (val & mask) as u64);
+    } else {
+        let val = substitute.unwrap_or(io.value) & mask;
+        handle_io_write(io.port, match io.size {
+            1 => IoOperandType::byte(val as u8),
+            2 => IoOperandType::word(val as u16),
+            _ => IoOperandType::dword(val),
+        });
+    }
+
+    let rip = read_register(hv_x86_reg_t::HV_X86_RIP);
+    write_register(hv_x86_reg_t::HV_X86_RIP, rip + io.instr_len);
+}
+
+/*
+ * Vector breakpoints stop the highest priority raised vector when it is about to be injected, before interrupt
+ * controller is acknowledged. VM loop gets VmExit::VectorBreakpoint and the vector stays raised until
+ * resume_vector_breakpoint() lets it through or takes it back.
+ */
+
+/**
+ * Stop guest before vector is injected into it
+ */
+pub fn break_on_vector(vec: u8) -> Result<breakpoint::BreakpointHandle, String>
+{
+    get_vm().breakpoints.add_vector(vec)
+}
+
+/**
+ * Vector breakpoints set in guest
+ */
+pub fn vector_breakpoints() -> Vec<breakpoint::VectorBreakpoint>
+{
+    get_vm().breakpoints.vector_list().to_vec()
+}
+
+/**
+ * Check vector next_external_interrupt() would take against vector breakpoints, before injecting it
+ * Returns true when one stops it, VM loop then handles the exit before guest runs again.
+ */
+pub fn hit_vector_breakpoint() -> bool
+{
+    let vm = get_vm();
+    let vec = match vm.pending_ext_ints.bsf() {
+        Some(vec) => vec as u8,
+        None => return false,
+    };
+
+    if vm.breakpoints.find_vector(vec).is_none() || vm.resumed_vector == Some(vec) {
+        return false;
+    }
+
+    debug!("Vector breakpoint hit at vector {:x}", vec);
+    assert!(vm.pending_vector.is_none());
+    vm.pending_vector = Some(vec);
+    request_vm_exit(VmExit::VectorBreakpoint { vec: vec, vcpu_state: vcpu_state() });
+    true
+}
+
+/**
+ * Let vector stopped at vector breakpoint be injected next, or take it back from vcpu
+ * Vector taken back stays latched in interrupt controller, which raises it again when its IRQ is asserted again.
+ */
+pub fn resume_vector_breakpoint(deliver: bool)
+{
+    assert_vcpu_thread();
+
+    let vec = get_vm().pending_vector.take().expect("no vector stopped at vector breakpoint");
+    if deliver {
+        get_vm().resumed_vector = Some(vec);
+    } else {
+        cancel_external_interrupt(vec);
     }
 }
 
+fn trace_next_instruction()
+{
+    let state = vcpu_state();
+    let event = read_vmcs(hv_vmx_vmcs_regs::VMCS_CTRL_VMENTRY_IRQ_INFO) as u32;
+    let mut code = [0u8; disasm::MAX_INSN_BYTES];
+    let len = read_guest_memory(state.cs.base + state.rip, &mut code);
+    trace::record(&state, &code[..len], event);
+}
+
 pub fn run() -> hv_return_t
 {
     let res: hv_return_t;
 
-    /* Enable event loop before returning to guest */
-    event::unlock_event_loop();
+    /* Exits are complete here, host may look at VM state */
+    wait_while_paused();
+
+    /* Trace logs each instruction, or event guest takes, before it runs as a step of its own */
+    if trace::active() && get_vm().step.is_none() {
+        trace_next_instruction();
+        start_step(StepOwner::Trace);
+    }
+
+    /* Enable event loop before returning to guest, replay fires timers at recorded exits instead */
+    if !replay::enabled() {
+        event::unlock_event_loop();
+    }
 
     /* Run guest vcpu */
     unsafe {
@@ -351,6 +3382,71 @@ pub fn register_io_region(handler: Rc<io_handler>, base: u16, len: u8)
     });
 }
 
+/**
+ * Registered IO regions as (base port, size)
+ */
+pub fn io_regions() -> Vec<(u16, u8)>
+{
+    get_vm().io.iter().map(|r| (r.base, r.size)).collect()
+}
+
+/**
+ * Remove IO region previously registered at base port
+ */
+pub fn unregister_io_region(base: u16)
+{
+    get_vm().io.retain(|r| r.base != base);
+}
+
+/**
+ * Register MMIO region
+ * Guest accesses are trapped as EPT violations, so range must not be mapped to host memory
+ * or mapped without the access rights that should reach the handler.
+ */
+pub fn register_mmio_region(handler: Rc<mmio_handler>, base: hv_gpaddr_t, size: u64)
+{
+    get_vm().mmio.push(mmio_region {
+        ops: handler,
+        base: base,
+        size: size,
+    });
+}
+
+/**
+ * Remove MMIO region previously registered at base address
+ */
+pub fn unregister_mmio_region(base: hv_gpaddr_t)
+{
+    get_vm().mmio.retain(|r| r.base != base);
+}
+
+fn find_mmio_region(addr: hv_gpaddr_t) -> Option<&'static mmio_region>
+{
+    get_vm().mmio.iter().find(|r| addr >= r.base && addr - r.base < r.size)
+}
+
+/**
+ * Dispatch guest MMIO read, None if address is not claimed by any device
+ */
+pub fn handle_mmio_read(addr: hv_gpaddr_t, size: u8) -> Option<u64>
+{
+    find_mmio_region(addr).map(|r| r.ops.mmio_read(addr, size))
+}
+
+/**
+ * Dispatch guest MMIO write, returns false if address is not claimed by any device
+ */
+pub fn handle_mmio_write(addr: hv_gpaddr_t, size: u8, data: u64) -> bool
+{
+    match find_mmio_region(addr) {
+        Some(r) => {
+            r.ops.mmio_write(addr, size, data);
+            true
+        },
+        None => false,
+    }
+}
+
 #[derive(Clone, Copy)]
 pub enum IoOperandType {
     byte(u8),
@@ -391,26 +3487,111 @@ impl IoOperandType {
     }
 }
 
+/**
+ * Dispatch port read to device claiming the port
+ * Ports no device claims are fatal, guest gets all ones while VM stops with a crash report.
+ */
 pub fn handle_io_read(port: u16, size: u8) -> IoOperandType
 {
-    for i in &get_vm().io {
-        if port == i.base {
-            return i.ops.io_read(port, size);
-        }
-    }
+    let handler = get_vm().io.iter().find(|i| port == i.base).map(|i| i.ops.clone());
+    let data = match handler {
+        Some(ref ops) => ops.io_read(port, size),
+        None => IoOperandType::make_unhandled(size),
+    };
 
-    panic!("Unhandled IO read from port {:x}", port);
+    let access = crash::IoAccess { port: port, direction: breakpoint::IoDirection::Read, value: data };
+    count_port(port).reads += 1;
+    hang::io_access(port);
+    get_vm().io_history.push(access);
+    eventlog::emit(|| eventlog::Event::Io(access));
+    if handler.is_none() {
+        crash::fatal(format!("Unhandled I/O read from port 0x{:x}", port));
+    }
+    data
 }
 
+/**
+ * Dispatch port write to device claiming the port, ports no device claims are fatal like for reads
+ */
 pub fn handle_io_write(port: u16, data: IoOperandType)
 {
-    for i in &get_vm().io {
-        if port == i.base {
-            i.ops.io_write(port, data);
-            return;
+    let access = crash::IoAccess { port: port, direction: breakpoint::IoDirection::Write, value: data };
+    count_port(port).writes += 1;
+    hang::io_access(port);
+    get_vm().io_history.push(access);
+    eventlog::emit(|| eventlog::Event::Io(access));
 
-        }
+    let handler = get_vm().io.iter().find(|i| port == i.base).map(|i| i.ops.clone());
+    match handler {
+        Some(ops) => ops.io_write(port, data),
+        None => crash::fatal(format!("Unhandled I/O write to port 0x{:x}", port)),
     }
+}
 
-    panic!("Unhandled IO write to port {:x}", port);
+/**
+ * Latest port accesses, oldest first
+ */
+pub fn io_history() -> Vec<crash::IoAccess>
+{
+    get_vm().io_history.list()
+}
+
+/**
+ * Keep only port accesses matching filter in I/O history, all without one
+ */
+pub fn set_io_filter(filter: Option<tracefilter::TraceFilter>)
+{
+    get_vm().io_history.set_filter(filter);
+}
+
+/**
+ * Filter of I/O history, None if it keeps all port accesses
+ */
+pub fn io_filter() -> Option<tracefilter::TraceFilter>
+{
+    get_vm().io_history.filter().cloned()
+}
+
+/* Access counts of port */
+fn count_port(port: u16) -> &'static mut summary::PortCount
+{
+    get_vm().port_counts.entry(port).or_insert(summary::PortCount { port: port, reads: 0, writes: 0 })
+}
+
+/**
+ * Count vm exit for run summary
+ */
+pub fn count_exit(exit_reason: u32)
+{
+    *get_vm().exit_counts.entry(exit_reason & 0xFFFF).or_insert(0) += 1;
+    record::count_exit(exit_reason & 0xFFFF);
+}
+
+/**
+ * Exit, I/O, interrupt and device counters since VM was created
+ */
+pub fn run_summary() -> summary::RunSummary
+{
+    let vm = get_vm();
+    let host = vm.created.elapsed();
+    summary::RunSummary::new(vm.exit_counts.iter().map(|(&reason, &count)| (reason, count)).collect(),
+                             vm.port_counts.values().cloned().collect(),
+                             vm.irq_counts.to_vec(),
+                             clock::guest_time_ns(),
+                             host.as_secs() * 1_000_000_000 + host.subsec_nanos() as u64,
+                             vm.stats_handlers.iter().map(|handler| handler.stats()).collect())
+}
+
+/**
+ * Counters for metrics export, every port accessed rather than the hottest ones
+ */
+pub fn metrics_sample() -> metrics::Sample
+{
+    let vm = get_vm();
+    metrics::Sample {
+        exits: vm.exit_counts.iter().map(|(&reason, &count)| (reason, count)).collect(),
+        ports: vm.port_counts.values().cloned().collect(),
+        irqs: vm.irq_counts.to_vec(),
+        devices: vm.stats_handlers.iter().map(|handler| handler.stats()).collect(),
+    }
 }
@@ -0,0 +1,375 @@
+/*
+ * Watchdog timer at 0x5A0-0x5A3
+ *
+ * Guest arms it by writing timeout in seconds and keeps writing the kick register before timeout runs out.
+ * On expiry it resets the platform, raises NMI or stops VM, once per arming. Host may arm it from config,
+ * so guests that know nothing about it are bounded as well. Countdown runs in virtual time and is checked
+ * by a periodic event, so expiry is noticed within WATCHDOG_POLL_US.
+ *
+ * Registers:
+ *   +0 timeout     Seconds, writing restarts countdown, 0 disarms
+ *   +1 kick        Any write restarts countdown
+ *   +2 action      0 reset, 1 NMI, 2 stop VM
+ *   +3 status      Bit 0 armed, bit 1 expired, writing 1 to bit 1 clears it
+ */
+
+use vm;
+use config::{self, WatchdogAction};
+use clock::{virtual_clock, VcpuClock};
+use event;
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::mem;
+
+const WATCHDOG_BASE: u16        = 0x5A0;
+const WATCHDOG_TIMEOUT: u16     = 0;
+const WATCHDOG_KICK: u16        = 1;
+const WATCHDOG_ACTION: u16      = 2;
+const WATCHDOG_STATUS: u16      = 3;
+const WATCHDOG_PORTS: u16       = 4;
+
+const ACTION_RESET: u8          = 0;
+const ACTION_NMI: u8            = 1;
+const ACTION_STOP: u8           = 2;
+
+const STATUS_ARMED: u8          = 0x01;
+const STATUS_EXPIRED: u8        = 0x02;
+
+const NS_PER_SEC: u64           = 1000000000;
+const WATCHDOG_POLL_US: u64     = 100000;
+
+fn action_to_reg(action: WatchdogAction) -> u8
+{
+    match action {
+        WatchdogAction::Reset => ACTION_RESET,
+        WatchdogAction::Nmi => ACTION_NMI,
+        WatchdogAction::Stop => ACTION_STOP,
+    }
+}
+
+fn action_from_reg(val: u8) -> Option<WatchdogAction>
+{
+    match val {
+        ACTION_RESET => Some(WatchdogAction::Reset),
+        ACTION_NMI => Some(WatchdogAction::Nmi),
+        ACTION_STOP => Some(WatchdogAction::Stop),
+        _ => None,
+    }
+}
+
+/**
+ * Watchdog model
+ */
+struct Watchdog
+{
+    clock: Rc<virtual_clock>,
+    default_timeout: u8,        // Host setting restored on reset
+    default_action: WatchdogAction,
+    timeout: u8,
+    action: WatchdogAction,
+    deadline_ns: Option<u64>,   // Virtual time of expiry while armed
+    expired: bool,
+}
+
+impl Watchdog
+{
+    fn new(clock: Rc<virtual_clock>, timeout: u8, action: WatchdogAction) -> Watchdog {
+        let mut watchdog = Watchdog {
+            clock: clock,
+            default_timeout: timeout,
+            default_action: action,
+            timeout: timeout,
+            action: action,
+            deadline_ns: None,
+            expired: false,
+        };
+
+        watchdog.reset();
+        watchdog
+    }
+
+    fn reset(&mut self) {
+        self.timeout = self.default_timeout;
+        self.action = self.default_action;
+        self.expired = false;
+        self.kick();
+    }
+
+    /* Restart countdown, if armed */
+    fn kick(&mut self) {
+        self.deadline_ns = if self.timeout != 0 {
+            Some(self.clock.now_ns() + self.timeout as u64 * NS_PER_SEC)
+        } else {
+            None
+        };
+    }
+
+    fn set_timeout(&mut self, timeout: u8) {
+        self.timeout = timeout;
+        self.kick();
+    }
+
+    fn status(&self) -> u8 {
+        (if self.deadline_ns.is_some() { STATUS_ARMED } else { 0 }) | (if self.expired { STATUS_EXPIRED } else { 0 })
+    }
+
+    /* Action to take if countdown ran out since last check, watchdog stays disarmed until guest rearms it */
+    fn check(&mut self) -> Option<WatchdogAction> {
+        match self.deadline_ns {
+            Some(deadline) if self.clock.now_ns() >= deadline => {
+                self.deadline_ns = None;
+                self.expired = true;
+                Some(self.action)
+            },
+            _ => None,
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct WatchdogDev
+{
+    watchdog: RefCell<Watchdog>,
+    reset: fn(),
+    nmi: fn(),
+    stop: fn(),
+}
+
+impl WatchdogDev
+{
+    /* Take expiry action, with model unborrowed as reset comes back to it */
+    fn poll(&self) {
+        let action = self.watchdog.borrow_mut().check();
+        match action {
+            Some(WatchdogAction::Reset) => {
+                warn!("watchdog: expired, resetting guest");
+                (self.reset)();
+            },
+            Some(WatchdogAction::Nmi) => {
+                warn!("watchdog: expired, raising NMI");
+                (self.nmi)();
+            },
+            Some(WatchdogAction::Stop) => {
+                warn!("watchdog: expired, stopping VM");
+                (self.stop)();
+            },
+            None => {},
+        }
+    }
+}
+
+impl vm::io_handler for WatchdogDev
+{
+    fn io_read(&self, port: u16, size: u8) -> vm::IoOperandType
+    {
+        let watchdog = self.watchdog.borrow();
+        let val = match port - WATCHDOG_BASE {
+            WATCHDOG_TIMEOUT => watchdog.timeout,
+            WATCHDOG_ACTION => action_to_reg(watchdog.action),
+            WATCHDOG_STATUS => watchdog.status(),
+            _ => return vm::IoOperandType::make_unhandled(size),
+        };
+
+        vm::IoOperandType::byte(val)
+    }
+
+    fn io_write(&self, port: u16, data: vm::IoOperandType)
+    {
+        let mut watchdog = self.watchdog.borrow_mut();
+        let val = data.unwrap_byte();
+
+        match port - WATCHDOG_BASE {
+            WATCHDOG_TIMEOUT => watchdog.set_timeout(val),
+            WATCHDOG_KICK => watchdog.kick(),
+            WATCHDOG_ACTION => match action_from_reg(val) {
+                Some(action) => watchdog.action = action,
+                None => debug!("watchdog: ignoring bad action {}", val),
+            },
+            WATCHDOG_STATUS => if (val & STATUS_EXPIRED) != 0 {
+                watchdog.expired = false;
+            },
+            _ => panic!(),
+        }
+    }
+}
+
+impl vm::reset_handler for WatchdogDev
+{
+    fn reset(&self)
+    {
+        self.watchdog.borrow_mut().reset();
+    }
+}
+
+#[cfg(test)]
+mod watchdog_test
+{
+    use super::*;
+    use clock::MockClock;
+    use std::cell::Cell;
+
+    thread_local! {
+        static ACTIONS: Cell<(u32, u32, u32)> = Cell::new((0, 0, 0));
+    }
+
+    fn record_reset() {
+        ACTIONS.with(|a| { let (r, n, s) = a.get(); a.set((r + 1, n, s)) });
+    }
+
+    fn record_nmi() {
+        ACTIONS.with(|a| { let (r, n, s) = a.get(); a.set((r, n + 1, s)) });
+    }
+
+    fn record_stop() {
+        ACTIONS.with(|a| { let (r, n, s) = a.get(); a.set((r, n, s + 1)) });
+    }
+
+    fn actions() -> (u32, u32, u32) {
+        ACTIONS.with(|a| a.get())
+    }
+
+    fn make_dev(clock: &Rc<MockClock>, config: &config::WatchdogConfig) -> WatchdogDev {
+        WatchdogDev {
+            watchdog: RefCell::new(Watchdog::new(clock.clone(), config.timeout, config.action)),
+            reset: record_reset,
+            nmi: record_nmi,
+            stop: record_stop,
+        }
+    }
+
+    fn outb(dev: &WatchdogDev, reg: u16, val: u8) {
+        vm::io_handler::io_write(dev, WATCHDOG_BASE + reg, vm::IoOperandType::byte(val));
+    }
+
+    fn inb(dev: &WatchdogDev, reg: u16) -> u8 {
+        vm::io_handler::io_read(dev, WATCHDOG_BASE + reg, 1).unwrap_byte()
+    }
+
+    /* Let virtual time pass in poll sized steps */
+    fn run(dev: &WatchdogDev, clock: &MockClock, ms: u64) {
+        for _ in 0..ms * 1000 / WATCHDOG_POLL_US {
+            clock.advance_ns(WATCHDOG_POLL_US * 1000);
+            dev.poll();
+        }
+    }
+
+    #[test] fn guest_kicks() {
+        let clock = Rc::new(MockClock::new());
+        let dev = make_dev(&clock, &config::WatchdogConfig { timeout: 0, action: WatchdogAction::Stop });
+        assert!(inb(&dev, WATCHDOG_STATUS) == 0);
+
+        /* Guest arms for 2 s with NMI and kicks every 1.5 s */
+        outb(&dev, WATCHDOG_ACTION, ACTION_NMI);
+        outb(&dev, WATCHDOG_TIMEOUT, 2);
+        assert!(inb(&dev, WATCHDOG_TIMEOUT) == 2 && inb(&dev, WATCHDOG_ACTION) == ACTION_NMI);
+        assert!(inb(&dev, WATCHDOG_STATUS) == STATUS_ARMED);
+        for _ in 0..10 {
+            run(&dev, &clock, 1500);
+            outb(&dev, WATCHDOG_KICK, 0);
+        }
+        assert!(actions() == (0, 0, 0));
+
+        /* Then it hangs */
+        run(&dev, &clock, 1900);
+        assert!(actions() == (0, 0, 0));
+        run(&dev, &clock, 100);
+        assert!(actions() == (0, 1, 0));
+        assert!(inb(&dev, WATCHDOG_STATUS) == STATUS_EXPIRED);
+        run(&dev, &clock, 10000);
+        assert!(actions() == (0, 1, 0));
+
+        /* Rearming and clearing status, 0 disarms */
+        outb(&dev, WATCHDOG_STATUS, STATUS_EXPIRED);
+        outb(&dev, WATCHDOG_TIMEOUT, 1);
+        assert!(inb(&dev, WATCHDOG_STATUS) == STATUS_ARMED);
+        outb(&dev, WATCHDOG_TIMEOUT, 0);
+        assert!(inb(&dev, WATCHDOG_STATUS) == 0);
+        run(&dev, &clock, 5000);
+        assert!(actions() == (0, 1, 0));
+
+        /* Bad action value keeps the old one */
+        outb(&dev, WATCHDOG_ACTION, 7);
+        assert!(inb(&dev, WATCHDOG_ACTION) == ACTION_NMI);
+    }
+
+    #[test] fn host_armed() {
+        let clock = Rc::new(MockClock::new());
+        let dev = make_dev(&clock, &config::WatchdogConfig { timeout: 3, action: WatchdogAction::Reset });
+        assert!(inb(&dev, WATCHDOG_STATUS) == STATUS_ARMED);
+        assert!(inb(&dev, WATCHDOG_ACTION) == ACTION_RESET);
+
+        /* Guest unaware of watchdog gets reset once */
+        run(&dev, &clock, 2900);
+        assert!(actions() == (0, 0, 0));
+        run(&dev, &clock, 100);
+        assert!(actions() == (1, 0, 0));
+        run(&dev, &clock, 5000);
+        assert!(actions() == (1, 0, 0));
+
+        /* Platform reset brings back host setting, guest changes are gone */
+        outb(&dev, WATCHDOG_ACTION, ACTION_STOP);
+        vm::reset_handler::reset(&dev);
+        assert!(inb(&dev, WATCHDOG_STATUS) == STATUS_ARMED && inb(&dev, WATCHDOG_ACTION) == ACTION_RESET);
+        run(&dev, &clock, 3000);
+        assert!(actions() == (2, 0, 0));
+
+        let dev = make_dev(&clock, &config::WatchdogConfig { timeout: 1, action: WatchdogAction::Stop });
+        run(&dev, &clock, 5000);
+        assert!(actions() == (2, 0, 1));
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+static mut WATCHDOG_DEV: Option<*const WatchdogDev> = None;
+
+fn poll_event(ev: event::Event)
+{
+    unsafe {
+        if let Some(dev) = WATCHDOG_DEV {
+            let dev: &WatchdogDev = mem::transmute(dev);
+            dev.poll();
+        }
+    }
+
+    event::schedule_event(WATCHDOG_POLL_US, ev);
+}
+
+fn stop_vm()
+{
+    vm::request_vm_exit(vm::VmExit::WatchdogExpired);
+    vm::interrupt_guest();
+}
+
+fn reset_vm()
+{
+    vm::request_reset();
+    vm::interrupt_guest();
+}
+
+pub fn init(config: &config::VmConfig)
+{
+    let wdconfig = match config.watchdog {
+        Some(ref wdconfig) => wdconfig,
+        None => return,
+    };
+
+    let dev = Rc::new(WatchdogDev {
+        watchdog: RefCell::new(Watchdog::new(Rc::new(VcpuClock), wdconfig.timeout, wdconfig.action)),
+        reset: reset_vm,
+        nmi: vm::raise_nmi,
+        stop: stop_vm,
+    });
+
+    for offset in 0..WATCHDOG_PORTS {
+        vm::register_io_region(dev.clone(), WATCHDOG_BASE + offset, 1);
+    }
+    vm::register_reset_handler(dev.clone());
+
+    unsafe {
+        WATCHDOG_DEV = Some(&*dev as *const WatchdogDev);
+    }
+    event::schedule_event(WATCHDOG_POLL_US, event::create_event(poll_event));
+}
@@ -0,0 +1,37 @@
+;
+;   No emulation El Torito boot image that checks its entry state and reports through debug exit port
+;   Loaded at 07C0h:0 with CD drive number E0h, exits with status 85 on success and 3 on failure
+;
+
+%define DEBUG_EXIT_PORT 0xF4
+
+org 0
+bits 16
+
+_start:
+    cmp     dl, 0xE0
+    jne     .fail
+
+    ; Started at load segment with offset 0
+    mov     ax, cs
+    cmp     ax, 0x07C0
+    jne     .fail
+
+    ; Stack must be usable
+    call    .here
+.here:
+    pop     ax
+    cmp     ax, .here
+    jne     .fail
+
+    mov     al, 0x2A
+    jmp     .done
+
+.fail:
+    mov     al, 0x01
+
+.done:
+    out     DEBUG_EXIT_PORT, al
+    hlt
+
+    times 2048 - ($ - $$) db 0
@@ -0,0 +1,33 @@
+;
+;   Boot sector for incremental snapshots, writing RAM pages between three NOPs to stop at
+;   Loaded at 0h:7C00h, writes page 1000h before 7C09h, pages 2000h and 1000h again before 7C14h and
+;   page 3000h before 7C1Ah, then exits with status 42
+;
+
+%define DEBUG_EXIT_PORT 0xF4
+
+org 0x7C00
+bits 16
+
+_start:
+    xor     ax, ax                      ; 7C00
+    mov     ds, ax                      ; 7C02
+    mov     byte [0x1000], 1            ; 7C04
+
+    nop                                 ; 7C09
+
+    mov     byte [0x2000], 2            ; 7C0A
+    mov     byte [0x1000], 3            ; 7C0F
+
+    nop                                 ; 7C14
+
+    mov     byte [0x3000], 4            ; 7C15
+
+    nop                                 ; 7C1A
+
+    mov     al, 0x2A                    ; 7C1B
+    out     DEBUG_EXIT_PORT, al         ; 7C1D
+    hlt                                 ; 7C1F
+
+    times 510 - ($ - $$) db 0
+    dw      0xAA55
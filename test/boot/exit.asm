@@ -0,0 +1,35 @@
+;
+;   Boot sector that checks its entry state and reports through debug exit port
+;   Loaded at 0h:7C00h with boot drive 80h, exits with status 85 on success and 3 on failure
+;
+
+%define DEBUG_EXIT_PORT 0xF4
+
+org 0x7C00
+bits 16
+
+_start:
+    cmp     dl, 0x80
+    jne     .fail
+
+    ; Unhandled interrupts must return through IVT stub
+    int     0x10
+
+    ; Stack must be usable
+    push    word 0x1234
+    pop     ax
+    cmp     ax, 0x1234
+    jne     .fail
+
+    mov     al, 0x2A
+    jmp     .done
+
+.fail:
+    mov     al, 0x01
+
+.done:
+    out     DEBUG_EXIT_PORT, al
+    hlt
+
+    times 510 - ($ - $$) db 0
+    dw      0xAA55
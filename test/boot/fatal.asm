@@ -0,0 +1,23 @@
+;
+;   Boot sector writing to a port no device claims, for crash reports
+;   Loaded at 0h:7C00h, VM stops with a crash report at the OUT to port 99h
+;
+
+%define PIC_MASTER_DATA 0x21
+%define UNCLAIMED_PORT 0x99
+
+org 0x7C00
+bits 16
+
+_start:
+    cli
+
+    in      al, PIC_MASTER_DATA         ; Shows up in crash report port history
+    mov     ax, 0x1234
+    mov     bx, 0x5678
+    mov     al, 0x42
+    out     UNCLAIMED_PORT, al
+    hlt
+
+    times 510 - ($ - $$) db 0
+    dw      0xAA55
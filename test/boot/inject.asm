@@ -0,0 +1,64 @@
+;
+;   Boot sector waiting for interrupts injected from monitor
+;   Loaded at 0h:7C00h, master PIC delivers IRQ5 only, at vector 0Dh. Handlers for IRQ5, vector 30h and NMI
+;   exit with a value of their own, so exit status tells which one ran.
+;
+
+%define PIC_MASTER_CMD  0x20
+%define PIC_MASTER_DATA 0x21
+%define DEBUG_EXIT_PORT 0xF4
+%define IRQ5_VECTOR     0x0D
+%define RAW_VECTOR      0x30
+%define NMI_VECTOR      0x02
+
+org 0x7C00
+bits 16
+
+_start:
+    cli
+    xor     ax, ax
+    mov     ds, ax
+    mov     ss, ax
+    mov     sp, 0x7C00
+
+    mov     al, 0x11                    ; ICW1: ICW4 follows
+    out     PIC_MASTER_CMD, al
+    mov     al, 0x08                    ; ICW2: vector offset
+    out     PIC_MASTER_DATA, al
+    mov     al, 0x04                    ; ICW3: slave on IRQ 2
+    out     PIC_MASTER_DATA, al
+    mov     al, 0x01                    ; ICW4: 8086 mode
+    out     PIC_MASTER_DATA, al
+    mov     al, 0xDF                    ; OCW1: IRQ5 only
+    out     PIC_MASTER_DATA, al
+
+    mov     word [IRQ5_VECTOR * 4], irq5
+    mov     word [IRQ5_VECTOR * 4 + 2], 0
+    mov     word [RAW_VECTOR * 4], raw
+    mov     word [RAW_VECTOR * 4 + 2], 0
+    mov     word [NMI_VECTOR * 4], nmi
+    mov     word [NMI_VECTOR * 4 + 2], 0
+    sti
+    nop                                 ; Out of STI shadow
+
+    ; Spin rather than halt, HLT ends the VM
+ready:
+    jmp     ready
+
+irq5:
+    mov     al, 0x11
+    out     DEBUG_EXIT_PORT, al
+    hlt
+
+raw:
+    mov     al, 0x22
+    out     DEBUG_EXIT_PORT, al
+    hlt
+
+nmi:
+    mov     al, 0x33
+    out     DEBUG_EXIT_PORT, al
+    hlt
+
+    times 510 - ($ - $$) db 0
+    dw      0xAA55
@@ -0,0 +1,70 @@
+;
+;   Boot sector taking a single timer interrupt, for vector breakpoints
+;   Loaded at 0h:7C00h, master PIC delivers IRQ0 at vector 08h, handler masks it again so it runs once.
+;   Exits with the number of interrupts handled, so exit status is 2 * count + 1.
+;
+
+%define PIC_MASTER_CMD  0x20
+%define PIC_MASTER_DATA 0x21
+%define DEBUG_EXIT_PORT 0xF4
+%define PIT_DIVISOR     11932       ; 1193182 Hz / 100
+%define IRQ0_VECTOR     0x08
+
+org 0x7C00
+bits 16
+
+_start:
+    cli
+    xor     ax, ax
+    mov     ds, ax
+    mov     ss, ax
+    mov     sp, 0x7C00
+
+    mov     al, 0x11                    ; ICW1: ICW4 follows
+    out     PIC_MASTER_CMD, al
+    mov     al, IRQ0_VECTOR             ; ICW2: vector offset
+    out     PIC_MASTER_DATA, al
+    mov     al, 0x04                    ; ICW3: slave on IRQ 2
+    out     PIC_MASTER_DATA, al
+    mov     al, 0x01                    ; ICW4: 8086 mode
+    out     PIC_MASTER_DATA, al
+    mov     al, 0xFE                    ; OCW1: IRQ0 only
+    out     PIC_MASTER_DATA, al
+
+    mov     word [IRQ0_VECTOR * 4], irq0
+    mov     word [IRQ0_VECTOR * 4 + 2], 0
+
+    ; Channel 0 rate generator
+    mov     al, 0x34
+    out     0x43, al
+    mov     al, PIT_DIVISOR & 0xFF
+    out     0x40, al
+    mov     al, PIT_DIVISOR >> 8
+    out     0x40, al
+    sti
+
+    ; Spin rather than halt, HLT ends the VM
+.wait:
+    cmp     byte [ticks], 0
+    je      .wait
+
+    cli
+    mov     al, [ticks]
+    out     DEBUG_EXIT_PORT, al
+    hlt
+
+irq0:
+    inc     byte [cs:ticks]
+    push    ax
+    mov     al, 0xFF                    ; No more timer interrupts
+    out     PIC_MASTER_DATA, al
+    mov     al, 0x20                    ; EOI
+    out     PIC_MASTER_CMD, al
+    pop     ax
+    iret
+
+ticks:
+    db      0
+
+    times 510 - ($ - $$) db 0
+    dw      0xAA55
@@ -0,0 +1,26 @@
+;
+;   Boot sector for live snapshots, writing RAM pages as long as it runs
+;   Loaded at 0h:7C00h, writes a word counter to pages 1000h to 8000h in turn and counts up after each
+;   pass, never stops. Pages up to the one being written hold the counter and the ones after it one less.
+;
+
+org 0x7C00
+bits 16
+
+_start:
+    xor     ax, ax                      ; 7C00
+    mov     ds, ax                      ; 7C02
+    xor     dx, dx                      ; 7C04
+
+.pass:
+    mov     bx, 0x1000                  ; 7C06
+.page:
+    mov     [bx], dx                    ; 7C09
+    add     bx, 0x1000                  ; 7C0B
+    cmp     bx, 0x9000                  ; 7C0F
+    jne     .page                       ; 7C13
+    inc     dx                          ; 7C15
+    jmp     .pass                       ; 7C16
+
+    times 510 - ($ - $$) db 0
+    dw      0xAA55
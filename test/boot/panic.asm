@@ -0,0 +1,44 @@
+;
+;   Boot sector reporting an assertion failure at panic beacon port
+;   Loaded at 0h:7C00h, pushes a marker for the stack window and sends message inline.
+;   Run with --panic-port 0x510, VM stops there or, with panics only logged, guest goes on
+;   to exit with 3 through debug exit port.
+;
+
+%define BEACON_PORT 0x510
+%define BEACON_INLINE 0x434E4150
+%define DEBUG_EXIT_PORT 0xF4
+
+org 0x7C00
+bits 16
+
+_start:
+    cli
+    cld
+    xor     ax, ax
+    mov     ds, ax
+    mov     ss, ax
+    mov     sp, 0x7C00
+    push    word 0xBEEF                 ; Shows up in stack window of panic report
+
+    mov     dx, BEACON_PORT
+    mov     eax, BEACON_INLINE
+    out     dx, eax
+    mov     ax, msg_len
+    out     dx, ax
+    mov     si, msg
+    mov     cx, msg_len
+.next:
+    lodsb
+    out     dx, al
+    loop    .next
+
+    mov     al, 3
+    out     DEBUG_EXIT_PORT, al
+    hlt
+
+msg:    db  "assert failed: x == 1"
+msg_len equ $ - msg
+
+    times 510 - ($ - $$) db 0
+    dw      0xAA55
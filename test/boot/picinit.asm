@@ -0,0 +1,32 @@
+;
+;   Boot sector initializing master PIC, for port access breakpoints
+;   Loaded at 0h:7C00h, exits with interrupt mask it reads back from PIC
+;
+
+%define PIC_MASTER_CMD 0x20
+%define PIC_MASTER_DATA 0x21
+%define DEBUG_EXIT_PORT 0xF4
+
+org 0x7C00
+bits 16
+
+_start:
+    cli
+
+    mov     al, 0x11                    ; ICW1: ICW4 follows
+    out     PIC_MASTER_CMD, al
+    mov     al, 0x08                    ; ICW2: vector offset
+    out     PIC_MASTER_DATA, al
+    mov     al, 0x04                    ; ICW3: slave on IRQ 2
+    out     PIC_MASTER_DATA, al
+    mov     al, 0x01                    ; ICW4: 8086 mode
+    out     PIC_MASTER_DATA, al
+
+    mov     al, 0x3C                    ; OCW1: interrupt mask
+    out     PIC_MASTER_DATA, al
+    in      al, PIC_MASTER_DATA
+    out     DEBUG_EXIT_PORT, al
+    hlt
+
+    times 510 - ($ - $$) db 0
+    dw      0xAA55
@@ -0,0 +1,89 @@
+;
+;   Boot sector counting timer interrupts over one second of guest time
+;   Runs PIT channel 0 at 100 Hz, counts IRQ0s until PM timer at 608h advanced by a second and reports
+;   the count through debug exit port, so exit status is 2 * count + 1. Run with --pm-timer 0x608.
+;
+
+%define DEBUG_EXIT_PORT 0xF4
+%define PM_TIMER_PORT   0x608
+%define PM_TIMER_HZ     3579545
+%define PM_TIMER_MASK   0xFFFFFF    ; 24 bit counter
+%define PIT_DIVISOR     11932       ; 1193182 Hz / 100
+%define IRQ0_VECTOR     0x20
+
+org 0x7C00
+bits 16
+
+_start:
+    cli
+    xor     ax, ax
+    mov     ds, ax
+    mov     ss, ax
+    mov     sp, 0x7C00
+
+    ; Master PIC delivers IRQ0 only, slave is masked
+    mov     al, 0x11
+    out     0x20, al
+    out     0xA0, al
+    mov     al, IRQ0_VECTOR
+    out     0x21, al
+    mov     al, IRQ0_VECTOR + 8
+    out     0xA1, al
+    mov     al, 0x04
+    out     0x21, al
+    mov     al, 0x02
+    out     0xA1, al
+    mov     al, 0x01
+    out     0x21, al
+    out     0xA1, al
+    mov     al, 0xFE
+    out     0x21, al
+    mov     al, 0xFF
+    out     0xA1, al
+
+    mov     word [IRQ0_VECTOR * 4], irq0
+    mov     word [IRQ0_VECTOR * 4 + 2], 0
+
+    ; Channel 0 rate generator
+    mov     al, 0x34
+    out     0x43, al
+    mov     al, PIT_DIVISOR & 0xFF
+    out     0x40, al
+    mov     al, PIT_DIVISOR >> 8
+    out     0x40, al
+
+    ; Sum PM timer deltas until they make a second, ECX holds elapsed ticks
+    mov     dx, PM_TIMER_PORT
+    in      eax, dx
+    mov     ebx, eax
+    xor     ecx, ecx
+    sti
+
+.wait:
+    in      eax, dx
+    mov     esi, eax
+    sub     eax, ebx
+    and     eax, PM_TIMER_MASK
+    add     ecx, eax
+    mov     ebx, esi
+    cmp     ecx, PM_TIMER_HZ
+    jb      .wait
+
+    cli
+    mov     al, [ticks]
+    out     DEBUG_EXIT_PORT, al
+    hlt
+
+irq0:
+    inc     byte [cs:ticks]
+    push    ax
+    mov     al, 0x20
+    out     0x20, al
+    pop     ax
+    iret
+
+ticks:
+    db      0
+
+    times 510 - ($ - $$) db 0
+    dw      0xAA55
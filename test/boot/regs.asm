@@ -0,0 +1,24 @@
+;
+;   Boot sector that halts with known register values for VMM to dump
+;   Loaded at 0h:7C00h, halts at 0h:7C18h with interrupts disabled
+;
+
+org 0x7C00
+bits 16
+
+_start:
+    cli
+
+    mov     ax, 0x1234
+    mov     bx, 0x5678
+    mov     cx, 0x9ABC
+    mov     dx, 0xDEF0
+    mov     si, 0x1111
+    mov     di, 0x2222
+    mov     bp, 0x3333
+    mov     es, di
+
+    hlt
+
+    times 510 - ($ - $$) db 0
+    dw      0xAA55
@@ -0,0 +1,39 @@
+;
+;   Boot sector taking serial input by polling COM1, for input recording and replay
+;   Loaded at 0h:7C00h, raises DTR and RTS so host sends, reads bytes until a '.' echoing them back and
+;   exits with the number of bytes read
+;
+
+%define DEBUG_EXIT_PORT 0xF4
+%define COM1_RBR        0x3F8       ; THR on writes
+%define COM1_MCR        0x3FC
+%define COM1_LSR        0x3FD
+
+org 0x7C00
+bits 16
+
+_start:
+    mov     dx, COM1_MCR
+    mov     al, 0x03                    ; DTR | RTS
+    out     dx, al
+    xor     cx, cx
+
+.poll:
+    mov     dx, COM1_LSR
+    in      al, dx
+    test    al, 0x01                    ; Data ready
+    jz      .poll
+
+    mov     dx, COM1_RBR
+    in      al, dx
+    out     dx, al                      ; Echo to THR
+    inc     cx
+    cmp     al, '.'
+    jne     .poll
+
+    mov     al, cl
+    out     DEBUG_EXIT_PORT, al
+    hlt
+
+    times 510 - ($ - $$) db 0
+    dw      0xAA55
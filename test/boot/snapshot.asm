@@ -0,0 +1,34 @@
+;
+;   Boot sector for snapshots, printing digits to COM1 in two loops with one instruction between them
+;   Loaded at 0h:7C00h, prints 0123 before the NOP at 7C0Ch and 456789 after it, exits with status 42
+;
+
+%define COM1_PORT 0x3F8
+%define DEBUG_EXIT_PORT 0xF4
+
+org 0x7C00
+bits 16
+
+_start:
+    mov     dx, COM1_PORT               ; 7C00
+    mov     al, '0'                     ; 7C03
+.first:
+    out     dx, al                      ; 7C05
+    inc     al                          ; 7C06
+    cmp     al, '4'                     ; 7C08
+    jne     .first                      ; 7C0A
+
+    nop                                 ; 7C0C
+
+.second:
+    out     dx, al                      ; 7C0D
+    inc     al                          ; 7C0E
+    cmp     al, '9' + 1                 ; 7C10
+    jne     .second                     ; 7C12
+
+    mov     al, 0x2A                    ; 7C14
+    out     DEBUG_EXIT_PORT, al         ; 7C16
+    hlt                                 ; 7C18
+
+    times 510 - ($ - $$) db 0
+    dw      0xAA55
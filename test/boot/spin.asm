@@ -0,0 +1,23 @@
+;
+;   Boot sector spinning on a port that never gets ready, for hang detection
+;   Loaded at 0h:7C00h, polls i8042 status for output buffer full with interrupts off. Nothing ever
+;   sends a byte, so only a hang detector stops it.
+;
+
+%define I8042_STATUS_PORT 0x64
+%define I8042_STR_OBF 0x01
+
+org 0x7C00
+bits 16
+
+_start:
+    cli
+
+.poll:
+    in      al, I8042_STATUS_PORT
+    test    al, I8042_STR_OBF
+    jz      .poll
+    hlt
+
+    times 510 - ($ - $$) db 0
+    dw      0xAA55
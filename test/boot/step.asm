@@ -0,0 +1,24 @@
+;
+;   Boot sector for single stepping, every instruction is at a known address
+;   Loaded at 0h:7C00h, prints S to debug console on the way and exits with status 85
+;
+
+%define DEBUG_CONSOLE_PORT 0x402
+%define DEBUG_EXIT_PORT 0xF4
+
+org 0x7C00
+bits 16
+
+_start:
+    mov     dx, DEBUG_CONSOLE_PORT      ; 7C00
+    mov     al, 'S'                     ; 7C03
+    out     dx, al                      ; 7C05
+    inc     al                          ; 7C06
+    nop                                 ; 7C08
+
+    mov     al, 0x2A                    ; 7C09
+    out     DEBUG_EXIT_PORT, al         ; 7C0B
+    hlt
+
+    times 510 - ($ - $$) db 0
+    dw      0xAA55
@@ -0,0 +1,44 @@
+;
+;   Boot sector for execution trace, 20 instructions with operand size and segment override prefixes
+;   Loaded at 0h:7C00h, exits with status 141. Its trace is in trace.golden.
+;
+
+%define DEBUG_EXIT_PORT 0xF4
+
+org 0x7C00
+bits 16
+
+_start:
+    cli
+    xor     ax, ax
+    mov     ds, ax
+    mov     es, ax
+    mov     ss, ax
+    mov     sp, 0x7C00
+
+    mov     bx, 0x7E00
+    mov     eax, 0x12345678
+    mov     [bx], eax
+    mov     cx, [es:bx + 2]             ; CX = 1234h
+    mov     al, [cs:data]               ; AX = 5611h
+
+    push    ax
+    push    cx
+    pop     dx
+    pop     ax
+    add     ax, dx                      ; AX = 6845h
+    call    bump
+
+    out     DEBUG_EXIT_PORT, al
+    hlt
+
+bump:
+    inc     al
+    ret
+
+    times 0x3C - ($ - $$) db 0
+data:
+    db      0x11
+
+    times 510 - ($ - $$) db 0
+    dw      0xAA55
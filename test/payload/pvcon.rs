@@ -0,0 +1,61 @@
+#![feature(asm)]
+#![no_std]
+
+/*
+ * Streams 64K through paravirtual console in 4K chunks.
+ * Run with "--pvcon file:<path>", output file holds bytes i ^ (i >> 8) for i in 0..0x10000.
+ */
+
+#[macro_use]
+extern crate xvmtest;
+
+use xvmtest::dbgprint;
+
+const HC_FEATURES: u32 = 0x0000;
+const HC_SIGNATURE: u32 = 0x314D5658;
+const HC_CONSOLE_WRITE: u32 = 0x0100;
+const PVCON_FEATURE: u32 = 0x00000001;
+
+const STREAM_SIZE: usize = 0x10000;
+const CHUNK_SIZE: usize = 0x1000;
+
+static mut CHUNK: [u8; CHUNK_SIZE] = [0; CHUNK_SIZE];
+
+/* Returns EAX and EBX, paging is off so buffer addresses are physical */
+unsafe fn hypercall(nr: u32, ebx: u32, ecx: u32) -> (u32, u32) {
+    let eax: u32;
+    let ebx_out: u32;
+    asm!("vmcall" : "={eax}"(eax), "={ebx}"(ebx_out) : "{eax}"(nr), "{ebx}"(ebx), "{ecx}"(ecx) : "edx", "memory" : "volatile");
+    (eax, ebx_out)
+}
+
+#[no_mangle]
+pub extern "C" fn test_main() {
+    unsafe {
+        let (signature, features) = hypercall(HC_FEATURES, 0, 0);
+        if signature != HC_SIGNATURE || (features & PVCON_FEATURE) == 0 {
+            dbgprintln!("No paravirtual console");
+            return;
+        }
+
+        let mut exits = 0;
+        let mut sent = 0;
+        while sent < STREAM_SIZE {
+            for (i, b) in CHUNK.iter_mut().enumerate() {
+                let pos = sent + i;
+                *b = (pos ^ (pos >> 8)) as u8;
+            }
+
+            let (written, _) = hypercall(HC_CONSOLE_WRITE, CHUNK.as_ptr() as u32, CHUNK_SIZE as u32);
+            if written as usize != CHUNK_SIZE {
+                dbgprintln!("Short console write {} at {}", written, sent);
+                return;
+            }
+
+            sent += CHUNK_SIZE;
+            exits += 1;
+        }
+
+        dbgprintln!("Sent {} bytes in {} hypercalls", sent, exits);
+    }
+}
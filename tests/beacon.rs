@@ -0,0 +1,46 @@
+/*
+ * Guest panic beacon
+ *
+ * Boot sector reports an assertion failure at the beacon port. VMM stops it with panic status and saves
+ * the message with guest state, or only reports it and lets guest run to its exit.
+ */
+
+mod guest;
+
+use guest::{GuestRun, crash_dir};
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+
+#[test]
+#[ignore]
+fn panic_stops_vm()
+{
+    let dir = crash_dir("panic");
+    let res = GuestRun::boot_sector("panic")
+        .arg("--panic-port").arg("0x510")
+        .arg("--crash-dir").arg(dir.to_str().unwrap())
+        .run();
+    assert!(res == Err(String::from("VM stopped with status 10")), "{:?}", res);
+
+    let files: Vec<PathBuf> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+    assert!(files.len() == 1, "{:?}", files);
+
+    let mut report = String::new();
+    fs::File::open(&files[0]).unwrap().read_to_string(&mut report).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    /* Marker guest pushed is on top of stack, SP is 7bfe after it */
+    assert!(report.starts_with("Guest panic: assert failed: x == 1\n"), "{}", report);
+    assert!(report.contains("ESP=00007bfe"), "{}", report);
+    assert!(report.contains("Stack around 0000:7bfe:\n  00007bee  "), "{}", report);
+    assert!(report.contains("  00007bfe  ef be "), "{}", report);
+}
+
+#[test]
+#[ignore]
+fn panic_logged()
+{
+    let res = GuestRun::boot_sector("panic").arg("--panic-port").arg("0x510,log").run();
+    assert!(res == Ok(3), "{:?}", res);
+}
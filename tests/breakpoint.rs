@@ -0,0 +1,237 @@
+/*
+ * Guest execution, I/O and vector breakpoints
+ *
+ * Boot sector runs into a breakpoint given on the command line: with a monitor console VM waits there until
+ * it is continued, without one VMM stops with breakpoint status. Port accesses wait at I/O breakpoints before
+ * they reach the device and reads can be answered from the monitor. Interrupts wait at vector breakpoints
+ * before they are injected and can be delivered or skipped. Interrupts and NMIs injected from the monitor
+ * while guest waits at a breakpoint are delivered as it continues. Memory and registers changed from the
+ * monitor there are what guest runs on, and the changes are in the event log.
+ */
+
+mod guest;
+
+use guest::{GuestRun, Monitor, free_port};
+use std::env;
+use std::fs;
+use std::io::Read;
+
+#[test]
+#[ignore]
+fn monitor_breakpoint()
+{
+    let port = free_port();
+    let guest = GuestRun::boot_sector("step")
+        .arg("--monitor").arg(&format!("tcp:{}", port))
+        .arg("--break").arg("0x7c05")
+        .start().unwrap();
+    let mut monitor = Monitor::connect(port);
+
+    /* Guest stops before its OUT, with AL already loaded */
+    assert!(monitor.wait_paused() == "VM status: paused (breakpoint 1 at 0x7c05)");
+    let regs = monitor.command("info registers");
+    assert!(regs.contains("EIP=00007c05"), "{}", regs);
+    assert!(regs.contains("53 EBX="), "{}", regs);
+    assert!(monitor.command("info breakpoints") == "  1: int3 at 0x7c05");
+
+    /* Continuing runs the instruction under the breakpoint and the rest of guest */
+    assert!(monitor.command("cont") == "");
+    assert!(guest.wait() == Ok(0x2A));
+}
+
+#[test]
+#[ignore]
+fn breakpoint_without_monitor()
+{
+    let guest = GuestRun::boot_sector("step").arg("--break").arg("0x7c05").start().unwrap();
+    assert!(guest.wait() == Err(String::from("VM stopped with status 6")));
+}
+
+#[test]
+#[ignore]
+fn io_breakpoint_write()
+{
+    let port = free_port();
+    let guest = GuestRun::boot_sector("picinit")
+        .arg("--monitor").arg(&format!("tcp:{}", port))
+        .arg("--io-break").arg("0x21,w")
+        .start().unwrap();
+    let mut monitor = Monitor::connect(port);
+
+    /* ICW2 stops with the value it is about to write and PIC doesn't have it yet */
+    assert!(monitor.wait_paused() == "VM status: paused (I/O breakpoint 1: write 0x8 to port 0x21)");
+    let pic = monitor.command("info pic");
+    assert!(pic.starts_with("pic0: irr=00 imr=00 isr=00 vec=00 init=0"), "{}", pic);
+    let regs = monitor.command("info registers");
+    assert!(regs.contains("EIP=00007c07"), "{}", regs);
+
+    /* Resumed write reaches PIC, rest of init runs freely */
+    assert!(monitor.command("delete 1") == "");
+    assert!(monitor.command("cont") == "");
+    assert!(guest.wait() == Ok(0x3C));
+}
+
+#[test]
+#[ignore]
+fn io_breakpoint_read_substitution()
+{
+    let port = free_port();
+    let guest = GuestRun::boot_sector("picinit")
+        .arg("--monitor").arg(&format!("tcp:{}", port))
+        .arg("--io-break").arg("0x21,r")
+        .start().unwrap();
+    let mut monitor = Monitor::connect(port);
+
+    /* Writes went through, PIC has the mask guest is about to read */
+    assert!(monitor.wait_paused() == "VM status: paused (I/O breakpoint 1: read from port 0x21)");
+    let pic = monitor.command("info pic");
+    assert!(pic.starts_with("pic0: irr=00 imr=3c isr=00 vec=08 init=1"), "{}", pic);
+
+    /* Guest gets the value given to cont instead */
+    assert!(monitor.command("cont 0x5a") == "");
+    assert!(guest.wait() == Ok(0x5A));
+}
+
+#[test]
+#[ignore]
+fn vector_breakpoint()
+{
+    let port = free_port();
+    let guest = GuestRun::boot_sector("irqonce")
+        .arg("--monitor").arg(&format!("tcp:{}", port))
+        .arg("--vector-break").arg("0x08")
+        .start().unwrap();
+    let mut monitor = Monitor::connect(port);
+
+    /* Timer interrupt stops before guest takes it, PIC has it requested but not in service yet */
+    assert!(monitor.wait_paused() == "VM status: paused (vector breakpoint 1 at 0x08)");
+    let pic = monitor.command("info pic");
+    assert!(pic.starts_with("pic0: irr=01 imr=fe isr=00 vec=08 init=1"), "{}", pic);
+
+    /* Continuing delivers it, handler runs once */
+    assert!(monitor.command("cont") == "");
+    assert!(guest.wait() == Ok(1));
+}
+
+#[test]
+#[ignore]
+fn vector_breakpoint_skip()
+{
+    let port = free_port();
+    let guest = GuestRun::boot_sector("irqonce")
+        .arg("--monitor").arg(&format!("tcp:{}", port))
+        .arg("--vector-break").arg("0x08")
+        .start().unwrap();
+    let mut monitor = Monitor::connect(port);
+
+    /* Skipped interrupt stays requested at PIC and comes back with the next timer tick */
+    assert!(monitor.wait_paused() == "VM status: paused (vector breakpoint 1 at 0x08)");
+    assert!(monitor.command("cont skip") == "");
+    assert!(monitor.wait_paused() == "VM status: paused (vector breakpoint 1 at 0x08)");
+
+    assert!(monitor.command("delete 1") == "");
+    assert!(monitor.command("cont") == "");
+    assert!(guest.wait() == Ok(1));
+}
+
+#[test]
+#[ignore]
+fn vector_breakpoint_without_monitor()
+{
+    let guest = GuestRun::boot_sector("irqonce").arg("--vector-break").arg("0x08").start().unwrap();
+    assert!(guest.wait() == Err(String::from("VM stopped with status 14")));
+}
+
+/* Guest stopped at its spin loop with interrupts enabled and IRQ5 unmasked */
+fn inject_guest(port: u16) -> (guest::RunningGuest, Monitor)
+{
+    let guest = GuestRun::boot_sector("inject")
+        .arg("--monitor").arg(&format!("tcp:{}", port))
+        .arg("--break").arg("0x7c44")
+        .start().unwrap();
+    let mut monitor = Monitor::connect(port);
+    assert!(monitor.wait_paused() == "VM status: paused (breakpoint 1 at 0x7c44)");
+    (guest, monitor)
+}
+
+#[test]
+#[ignore]
+fn inject_irq()
+{
+    let (guest, mut monitor) = inject_guest(free_port());
+
+    /* Masked line raises nothing, IRQ5 goes through PIC to its handler */
+    assert!(monitor.command("irq 4") == "IRQ 4: suppressed by interrupt controller mask");
+    assert!(monitor.command("irq 5") == "IRQ 5 as vector 0x0d: delivered at next entry");
+    let pic = monitor.command("info pic");
+    assert!(pic.starts_with("pic0: irr=20 imr=df isr=00 vec=08 init=1"), "{}", pic);
+
+    /* Raw vector waits behind the one PIC raised */
+    assert!(monitor.command("irq-raw 0x30") == "Vector 0x30: queued until guest can take it");
+
+    assert!(monitor.command("cont") == "");
+    assert!(guest.wait() == Ok(0x11));
+}
+
+#[test]
+#[ignore]
+fn inject_raw_vector()
+{
+    let (guest, mut monitor) = inject_guest(free_port());
+
+    /* PIC is left alone */
+    assert!(monitor.command("irq-raw 0x30") == "Vector 0x30: delivered at next entry");
+    let pic = monitor.command("info pic");
+    assert!(pic.starts_with("pic0: irr=00 imr=df isr=00"), "{}", pic);
+
+    assert!(monitor.command("cont") == "");
+    assert!(guest.wait() == Ok(0x22));
+}
+
+#[test]
+#[ignore]
+fn inject_nmi()
+{
+    let (guest, mut monitor) = inject_guest(free_port());
+
+    /* NMI goes ahead of external interrupts */
+    assert!(monitor.command("irq 5") == "IRQ 5 as vector 0x0d: delivered at next entry");
+    assert!(monitor.command("nmi") == "NMI: delivered at next entry");
+
+    assert!(monitor.command("cont") == "");
+    assert!(guest.wait() == Ok(0x33));
+}
+
+#[test]
+#[ignore]
+fn set_memory_and_registers()
+{
+    let port = free_port();
+    let log = env::temp_dir().join(format!("xvm-test-setmem-{}.jsonl", std::process::id()));
+    let guest = GuestRun::boot_sector("step")
+        .arg("--monitor").arg(&format!("tcp:{}", port))
+        .arg("--break").arg("0x7c09")
+        .arg("--event-log").arg(log.to_str().unwrap())
+        .start().unwrap();
+    let mut monitor = Monitor::connect(port);
+    assert!(monitor.wait_paused() == "VM status: paused (breakpoint 1 at 0x7c09)");
+
+    /* Patch the exit value guest is about to load, INT3 under the breakpoint stays */
+    assert!(monitor.command("setmem 0x7c09 90") == "Error: 0x7c09 has breakpoint 1, delete it first");
+    assert!(monitor.command("setmem 07c0:000a 55") == "Replaced at 0x7c0a: 2a");
+    assert!(monitor.command("setmem 0x200000 0").starts_with("Error: No memory at"));
+
+    let old = monitor.command("setreg ebx 1234");
+    assert!(old.starts_with("EBX was 0x"), "{}", old);
+    let regs = monitor.command("info registers");
+    assert!(regs.contains("EBX=00001234"), "{}", regs);
+
+    assert!(monitor.command("cont") == "");
+    assert!(guest.wait() == Ok(0x55));
+
+    let mut text = String::new();
+    fs::File::open(&log).unwrap().read_to_string(&mut text).unwrap();
+    fs::remove_file(&log).unwrap();
+    assert!(text.contains("\"event\":\"memory_write\",\"addr\":31754,\"old\":\"2a\",\"new\":\"55\"}"), "{}", text);
+    assert!(text.contains("\"event\":\"register_write\",\"reg\":\"EBX\","), "{}", text);
+}
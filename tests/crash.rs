@@ -0,0 +1,85 @@
+/*
+ * Crash reports
+ *
+ * Boot sector writes to a port no device claims, VMM stops with fatal status and saves a crash report with
+ * guest registers, the port accesses leading there and the code it stopped at. With --coredump it also writes a
+ * core dump holding guest memory and the same report.
+ */
+
+mod guest;
+
+use guest::{GuestRun, crash_dir};
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+
+#[test]
+#[ignore]
+fn unclaimed_port_report()
+{
+    let dir = crash_dir("crash");
+    let res = GuestRun::boot_sector("fatal").arg("--crash-dir").arg(dir.to_str().unwrap()).run();
+    assert!(res == Err(String::from("VM stopped with status 8")), "{:?}", res);
+
+    let files: Vec<PathBuf> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+    assert!(files.len() == 1, "{:?}", files);
+    let name = files[0].file_name().unwrap().to_str().unwrap().to_string();
+    assert!(name.starts_with("xvm-crash-") && name.ends_with(".txt"), "{}", name);
+
+    let mut report = String::new();
+    fs::File::open(&files[0]).unwrap().read_to_string(&mut report).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    /* Guest stopped at its OUT with registers it loaded */
+    assert!(report.starts_with("VM crashed: Unhandled I/O write to port 0x99\n"), "{}", report);
+    assert!(report.contains("EAX=00001242 EBX=00005678"), "{}", report);
+    assert!(report.contains("EIP=00007c0b"), "{}", report);
+
+    /* PIC read before it and the write itself are in port history */
+    assert!(report.contains("  in  0x0021 byte  0x"), "{}", report);
+    assert!(report.contains("  out 0x0099 byte  0x42\n"), "{}", report);
+    assert!(report.contains("pic0: "), "{}", report);
+    assert!(report.contains("  0000:7c0b  e6 99"), "{}", report);
+}
+
+/* Little endian field of core dump */
+fn get_u64(data: &[u8], pos: usize) -> u64
+{
+    (0..8).fold(0, |val, i| val | (data[pos + i] as u64) << (i * 8))
+}
+
+#[test]
+#[ignore]
+fn unclaimed_port_coredump()
+{
+    let dir = crash_dir("coredump");
+    let path = dir.join("guest.core");
+    let res = GuestRun::boot_sector("fatal").arg("--coredump").arg(path.to_str().unwrap()).run();
+    assert!(res == Err(String::from("VM stopped with status 8")), "{:?}", res);
+
+    let mut core = Vec::new();
+    fs::File::open(&path).unwrap().read_to_end(&mut core).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(&core[0..8] == b"XVMCORE\0", "{:?}", &core[0..8]);
+
+    /* Section table after magic, version and count */
+    let count = get_u64(&core, 8) >> 32;
+    let mut ram = None;
+    let mut report = None;
+    for i in 0..count as usize {
+        let entry = 16 + i * 32;
+        let (kind, base) = (core[entry], get_u64(&core, entry + 8));
+        let (offset, size) = (get_u64(&core, entry + 16) as usize, get_u64(&core, entry + 24) as usize);
+        match kind {
+            1 if base == 0 => ram = Some(&core[offset..offset + size]),
+            4 => report = Some(String::from_utf8_lossy(&core[offset..offset + size]).into_owned()),
+            _ => {},
+        }
+    }
+
+    /* Boot sector is where BIOS loaded it, report is the one crash reports have */
+    let ram = ram.expect("No memory section at 0");
+    assert!(&ram[0x7dfe..0x7e00] == &[0x55, 0xAA]);
+    let report = report.expect("No report section");
+    assert!(report.starts_with("VM crashed: Unhandled I/O write to port 0x99\n"), "{}", report);
+}
@@ -0,0 +1,98 @@
+/*
+ * JSON event log
+ *
+ * Boot sector initializing PIC runs with an event log, which has its port accesses in order between VM start
+ * and stop, with sequence numbers and guest time going up. Timer interrupts guest takes go through every
+ * delivery stage under the id of their IRQ assertion.
+ */
+
+mod guest;
+
+use guest::GuestRun;
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+
+/* Value of a number or string field, log lines are flat objects */
+fn field<'a>(line: &'a str, name: &str) -> Option<&'a str>
+{
+    let key = format!("\"{}\":", name);
+    line.find(&key).map(|start| {
+        let rest = &line[start + key.len()..];
+        let end = rest.find(|c| c == ',' || c == '}').unwrap();
+        rest[..end].trim_matches('"')
+    })
+}
+
+/* Event log of a run, removed once read */
+fn read_log(path: &PathBuf) -> String
+{
+    let mut log = String::new();
+    fs::File::open(path).unwrap().read_to_string(&mut log).unwrap();
+    fs::remove_file(path).unwrap();
+    log
+}
+
+fn log_path(name: &str) -> PathBuf
+{
+    env::temp_dir().join(format!("xvm-test-{}-{}.jsonl", name, std::process::id()))
+}
+
+#[test]
+#[ignore]
+fn pic_init_events()
+{
+    let path = log_path("events");
+    let res = GuestRun::boot_sector("picinit").arg("--event-log").arg(path.to_str().unwrap()).run();
+    assert!(res == Ok(0x3C), "{:?}", res);
+    let log = read_log(&path);
+
+    let lines: Vec<&str> = log.lines().collect();
+    let mut time = 0;
+    for (i, line) in lines.iter().enumerate() {
+        assert!(line.starts_with('{') && line.ends_with('}'), "{}", line);
+        assert!(field(line, "seq") == Some(&i.to_string()[..]), "{}", line);
+
+        let now: u64 = field(line, "time_ns").unwrap().parse().unwrap();
+        assert!(now >= time, "{}", line);
+        time = now;
+    }
+
+    /* Guest ports in program order, debug exit write stops VM with its status */
+    let events: Vec<String> = lines.iter().filter_map(|line| match field(line, "event") {
+        Some("io") => Some(format!("{} {} {}", field(line, "dir").unwrap(), field(line, "port").unwrap(),
+                                   field(line, "value").unwrap())),
+        Some(kind) if kind.starts_with("vm_") => Some(format!("{} {}", kind, field(line, "status").unwrap_or(""))),
+        _ => None,
+    }).collect();
+
+    assert!(events == vec!["vm_start ", "write 32 17", "write 33 8", "write 33 4", "write 33 1", "write 33 60",
+                           "read 33 60", "write 244 60", "vm_stop 121"], "{:?}", events);
+}
+
+#[test]
+#[ignore]
+fn irq_lifecycle()
+{
+    let path = log_path("irq");
+    let res = GuestRun::boot_sector("pittick").arg("--pm-timer").arg("0x608")
+                                              .arg("--event-log").arg(path.to_str().unwrap()).run();
+    assert!(res.is_ok(), "{:?}", res);
+    let log = read_log(&path);
+
+    /* First timer tick guest took, from PIT assertion to its EOI */
+    let lines: Vec<&str> = log.lines().collect();
+    let id = lines.iter().filter(|line| field(line, "event") == Some("pic_eoi"))
+                         .filter_map(|line| field(line, "id")).next().expect("no EOI with an id");
+
+    let stages: Vec<&str> = lines.iter().filter(|line| field(line, "id") == Some(id))
+                                        .map(|line| field(line, "event").unwrap()).collect();
+    assert!(stages == vec!["irq_assert", "pic_raise", "pic_ack", "inject", "pic_eoi"], "{}: {:?}", id, stages);
+
+    let assert = lines.iter().find(|line| field(line, "id") == Some(id)).unwrap();
+    assert!(field(assert, "irq") == Some("0"), "{}", assert);
+    for line in lines.iter().filter(|line| field(line, "id") == Some(id)).skip(1) {
+        assert!(field(line, "vector") == Some("32"), "{}", line);
+    }
+}
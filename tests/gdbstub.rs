@@ -0,0 +1,60 @@
+/*
+ * GDB remote protocol stub
+ *
+ * Speaks raw RSP to a VM started waiting for a debugger: breaks or steps from boot sector entry, checks
+ * where guest stopped and lets it run to completion.
+ */
+
+mod guest;
+
+use guest::{GuestRun, Gdb, free_port};
+
+#[test]
+#[ignore]
+fn breakpoint_at_entry()
+{
+    let port = free_port();
+    let guest = GuestRun::boot_sector("exit").arg("--gdb").arg(&port.to_string()).arg("--gdb-wait").start().unwrap();
+    let mut gdb = Gdb::connect(port);
+
+    /* Guest waits at its entry point, EIP is reported as physical address */
+    assert!(gdb.command("?") == "S05");
+    assert!(gdb.command("p8") == "007c0000");
+
+    /* Breakpoint is hidden from memory reads */
+    assert!(gdb.command("Z0,7c00,1") == "OK");
+    assert!(gdb.command("m7c00,3") == "80fa80");
+
+    /* Guest runs into it right away */
+    assert!(gdb.command("c") == "T05swbreak:;");
+    assert!(gdb.command("p8") == "007c0000");
+
+    /* Without it guest runs to its successful exit */
+    assert!(gdb.command("z0,7c00,1") == "OK");
+    gdb.send("c");
+    assert!(guest.wait() == Ok(0x2A));
+}
+
+#[test]
+#[ignore]
+fn single_step()
+{
+    let port = free_port();
+    let guest = GuestRun::boot_sector("step").arg("--gdb").arg(&port.to_string()).arg("--gdb-wait").start().unwrap();
+    let mut gdb = Gdb::connect(port);
+
+    assert!(gdb.command("?") == "S05");
+    assert!(gdb.command("p8") == "007c0000");
+
+    /* Each step runs one instruction, OUT is dispatched and ends its step */
+    for eip in &["037c0000", "057c0000", "067c0000", "087c0000", "097c0000"] {
+        assert!(gdb.command("s") == "S05");
+        assert!(gdb.command("p8") == *eip);
+    }
+
+    /* OUT didn't disturb AL, INC did */
+    assert!(gdb.command("p0").starts_with("54"));
+
+    gdb.send("c");
+    assert!(guest.wait() == Ok(0x2A));
+}
@@ -0,0 +1,427 @@
+/*
+ * Test guest runner
+ *
+ * Boots test images from test/ in a separate VMM process and collects the value guest reports through
+ * debug exit port. Images are rebuilt by test/Makefile when their sources changed, prebuilt ones are used
+ * when the toolchain is missing. Runs need a host with hypervisor support, so tests using this are
+ * ignored by default and run by "make test".
+ */
+
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/* Guests that don't exit by then are killed */
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/* VMM gets this long to start listening for monitor, answer and hit a breakpoint */
+const MONITOR_TIMEOUT_SECS: u64 = 10;
+
+/* VMM gets this long to start listening for debugger and to answer */
+const GDB_TIMEOUT_SECS: u64 = 10;
+
+const PROMPT: &'static str = "(xvm) ";
+
+/**
+ * Timer event delivery mode, see --timer
+ */
+#[derive(Clone, Copy, Debug)]
+pub enum TimerMode
+{
+    Host,
+    Preemption,
+}
+
+impl TimerMode
+{
+    pub fn all() -> [TimerMode; 2] {
+        [TimerMode::Host, TimerMode::Preemption]
+    }
+
+    fn arg(&self) -> &'static str {
+        match *self {
+            TimerMode::Host => "host",
+            TimerMode::Preemption => "preemption",
+        }
+    }
+}
+
+/* Repository root, tests run from there but don't rely on it */
+fn root_dir() -> PathBuf
+{
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+}
+
+/* VMM binary cargo built next to the test executable in target/<profile>/deps */
+fn vmm_path() -> PathBuf
+{
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    path.join("xvm")
+}
+
+/**
+ * Path to test/boot/<name>.bin, rebuilt from its source if make and assembler are around
+ */
+pub fn boot_image(name: &str) -> PathBuf
+{
+    let target = format!("boot/{}.bin", name);
+    let built = Command::new("make")
+        .arg("-C").arg(root_dir().join("test"))
+        .arg(&target)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false);
+
+    let path = root_dir().join("test").join(&target);
+    if !built {
+        assert!(path.exists(), "no prebuilt {} and it can't be built", path.display());
+    }
+    path
+}
+
+/**
+ * Path to test/payload/<name>.bin, payloads are rebuilt together if the test toolchain is around
+ */
+pub fn payload_image(name: &str) -> PathBuf
+{
+    let built = Command::new("make")
+        .arg("-C").arg(root_dir().join("test"))
+        .arg("payload")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false);
+
+    let path = root_dir().join("test").join("payload").join(format!("{}.bin", name));
+    if !built {
+        assert!(path.exists(), "no prebuilt {} and it can't be built", path.display());
+    }
+    path
+}
+
+/**
+ * One VM run of a test image
+ */
+pub struct GuestRun
+{
+    image: PathBuf,
+    args: Vec<String>,
+    timeout: Duration,
+}
+
+impl GuestRun
+{
+    /**
+     * Boot sector from test/boot, runs headless with virtual TSC so guest sees the same time every run
+     */
+    pub fn boot_sector(name: &str) -> GuestRun {
+        GuestRun {
+            image: boot_image(name),
+            args: vec!["--boot-sector", "--headless", "--tsc", "exiting"].iter().map(|s| s.to_string()).collect(),
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+        }
+    }
+
+    /**
+     * Payload from test/payload on the xvmtest runtime, runs headless with virtual TSC until it halts
+     */
+    pub fn payload(name: &str) -> GuestRun {
+        GuestRun {
+            image: payload_image(name),
+            args: vec!["--headless", "--tsc", "exiting"].iter().map(|s| s.to_string()).collect(),
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+        }
+    }
+
+    /** Add VMM option */
+    pub fn arg(mut self, arg: &str) -> GuestRun {
+        self.args.push(arg.to_string());
+        self
+    }
+
+    pub fn timer(self, mode: TimerMode) -> GuestRun {
+        self.arg("--timer").arg(mode.arg())
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> GuestRun {
+        self.timeout = timeout;
+        self
+    }
+
+    /**
+     * Run VM to completion, value guest wrote to debug exit port
+     */
+    pub fn run(&self) -> Result<u8, String> {
+        try!(self.start()).wait()
+    }
+
+    /**
+     * Run VM until guest halts for good, output VMM leaves behind then ends with guest register dump
+     */
+    pub fn run_to_halt(&self) -> Result<String, String> {
+        let mut child = try!(Command::new(vmm_path())
+            .args(&self.args)
+            .arg(&self.image)
+            .current_dir(root_dir())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|err| format!("Can't start {}: {}", vmm_path().display(), err)));
+
+        /* Output is read on another thread so the pipe never fills up while we watch the clock */
+        let mut stdout = child.stdout.take().unwrap();
+        let reader = thread::spawn(move || {
+            let mut output = String::new();
+            stdout.read_to_string(&mut output).map(|_| output)
+        });
+
+        let mut guest = RunningGuest {
+            child: child,
+            image: self.image.clone(),
+            start: Instant::now(),
+            timeout: self.timeout,
+        };
+        match try!(guest.wait_status()).code() {
+            Some(0) => {},
+            Some(code) => return Err(format!("VM exited with status {} instead of guest halting", code)),
+            None => return Err(String::from("VMM killed by signal")),
+        }
+
+        reader.join().unwrap().map_err(|err| err.to_string())
+    }
+
+    /**
+     * Start VM and leave it running, for tests that talk to it meanwhile
+     */
+    pub fn start(&self) -> Result<RunningGuest, String> {
+        let child = try!(Command::new(vmm_path())
+            .args(&self.args)
+            .arg(&self.image)
+            .current_dir(root_dir())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|err| format!("Can't start {}: {}", vmm_path().display(), err)));
+
+        Ok(RunningGuest {
+            child: child,
+            image: self.image.clone(),
+            start: Instant::now(),
+            timeout: self.timeout,
+        })
+    }
+}
+
+/**
+ * VMM process started by GuestRun::start, killed if it outlives the run timeout
+ */
+pub struct RunningGuest
+{
+    child: Child,
+    image: PathBuf,
+    start: Instant,
+    timeout: Duration,
+}
+
+impl RunningGuest
+{
+    /**
+     * Wait for VM to exit, value guest wrote to debug exit port
+     */
+    pub fn wait(mut self) -> Result<u8, String> {
+        let status = try!(self.wait_status());
+
+        /* Debug exit port statuses are 2 * value + 1, anything else is VMM stopping guest */
+        match status.code() {
+            Some(code) if code & 1 == 1 => Ok((code >> 1) as u8),
+            Some(code) => Err(format!("VM stopped with status {}", code)),
+            None => Err(String::from("VMM killed by signal")),
+        }
+    }
+}
+
+impl RunningGuest
+{
+    /* VMM process exit status, error if it runs past timeout */
+    fn wait_status(&mut self) -> Result<ExitStatus, String> {
+        loop {
+            match try!(self.child.try_wait().map_err(|err| err.to_string())) {
+                Some(status) => return Ok(status),
+                None if self.start.elapsed() > self.timeout => {
+                    return Err(format!("{} didn't exit in {} s", self.image.display(), self.timeout.as_secs()));
+                },
+                None => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+    }
+}
+
+impl Drop for RunningGuest
+{
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+/**
+ * Monitor console over TCP, every command output ends with a prompt
+ */
+pub struct Monitor
+{
+    stream: TcpStream,
+}
+
+impl Monitor
+{
+    pub fn connect(port: u16) -> Monitor {
+        let start = Instant::now();
+        loop {
+            match TcpStream::connect(("127.0.0.1", port)) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(Duration::from_secs(MONITOR_TIMEOUT_SECS))).unwrap();
+                    let mut monitor = Monitor { stream: stream };
+                    monitor.read_to_prompt();
+                    return monitor;
+                },
+                Err(err) => {
+                    assert!(start.elapsed() < Duration::from_secs(MONITOR_TIMEOUT_SECS), "Can't connect to monitor: {}", err);
+                    thread::sleep(Duration::from_millis(100));
+                },
+            }
+        }
+    }
+
+    fn read_to_prompt(&mut self) -> String {
+        let mut out = Vec::new();
+        let mut byte = [0u8; 1];
+        while !out.ends_with(PROMPT.as_bytes()) {
+            self.stream.read_exact(&mut byte).expect("no reply from monitor");
+            out.push(byte[0]);
+        }
+
+        let out = String::from_utf8(out).unwrap();
+        out[..out.len() - PROMPT.len()].trim_matches('\n').to_string()
+    }
+
+    pub fn command(&mut self, line: &str) -> String {
+        self.stream.write_all(format!("{}\n", line).as_bytes()).unwrap();
+        self.read_to_prompt()
+    }
+
+    /* Poll until guest stops, returns the status it stopped with */
+    pub fn wait_paused(&mut self) -> String {
+        let start = Instant::now();
+        loop {
+            let status = self.command("info status");
+            if status.starts_with("VM status: paused") {
+                return status;
+            }
+            assert!(start.elapsed() < Duration::from_secs(MONITOR_TIMEOUT_SECS), "Guest didn't stop: {}", status);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/**
+ * Minimal debugger end of the GDB remote protocol over TCP, packets are acked but never resent
+ */
+pub struct Gdb
+{
+    stream: TcpStream,
+}
+
+impl Gdb
+{
+    pub fn connect(port: u16) -> Gdb {
+        let start = Instant::now();
+        loop {
+            match TcpStream::connect(("127.0.0.1", port)) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(Duration::from_secs(GDB_TIMEOUT_SECS))).unwrap();
+                    return Gdb { stream: stream };
+                },
+                Err(err) => {
+                    assert!(start.elapsed() < Duration::from_secs(GDB_TIMEOUT_SECS), "Can't connect to GDB stub: {}", err);
+                    thread::sleep(Duration::from_millis(100));
+                },
+            }
+        }
+    }
+
+    pub fn send(&mut self, data: &str) {
+        let sum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        self.stream.write_all(format!("${}#{:02x}", data, sum).as_bytes()).unwrap();
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let mut byte = [0u8; 1];
+        self.stream.read_exact(&mut byte).expect("no reply from GDB stub");
+        byte[0]
+    }
+
+    /* Next packet, acks before it are skipped */
+    pub fn recv(&mut self) -> String {
+        while self.read_byte() != b'$' {}
+
+        let mut data = Vec::new();
+        loop {
+            match self.read_byte() {
+                b'#' => break,
+                c => data.push(c),
+            }
+        }
+        self.read_byte();
+        self.read_byte();
+
+        self.stream.write_all(b"+").unwrap();
+        String::from_utf8(data).unwrap()
+    }
+
+    pub fn command(&mut self, data: &str) -> String {
+        self.send(data);
+        self.recv()
+    }
+}
+
+pub fn free_port() -> u16
+{
+    TcpListener::bind(("127.0.0.1", 0)).unwrap().local_addr().unwrap().port()
+}
+
+/**
+ * Empty directory of its own for crash reports, named after the test
+ */
+pub fn crash_dir(name: &str) -> PathBuf
+{
+    let dir = env::temp_dir().join(format!("xvm-test-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/**
+ * Numbers on the run summary line with label, exit reason or port
+ */
+pub fn summary_counts(summary: &str, label: &str) -> Vec<u64>
+{
+    let line = summary.lines().find(|line| line.split_whitespace().next() == Some(label))
+                              .unwrap_or_else(|| panic!("no {} in {}", label, summary));
+    line.split_whitespace().filter_map(|word| word.parse().ok()).collect()
+}
@@ -0,0 +1,46 @@
+/*
+ * Hang detection
+ *
+ * Guest spinning on a port that never gets ready is stopped by the hang detector with a crash report of where
+ * it spins. Guest polling a timer while it takes interrupts is busy, not hung, and runs to its exit.
+ */
+
+mod guest;
+
+use guest::{GuestRun, crash_dir};
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+
+#[test]
+#[ignore]
+fn spinning_guest_stopped()
+{
+    let dir = crash_dir("hang");
+    let res = GuestRun::boot_sector("spin")
+        .arg("--hang-detect").arg("2,stop")
+        .arg("--crash-dir").arg(dir.to_str().unwrap())
+        .run();
+    assert!(res == Err(String::from("VM stopped with status 12")), "{:?}", res);
+
+    let files: Vec<PathBuf> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+    assert!(files.len() == 1, "{:?}", files);
+
+    let mut report = String::new();
+    fs::File::open(&files[0]).unwrap().read_to_string(&mut report).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    /* Every sampled exit is in the polling loop */
+    assert!(report.starts_with("Guest hung: Guest made no progress for 2 s, spinning at 7c01"), "{}", report);
+    assert!(report.contains(" on ports 0x0064\n"), "{}", report);
+    assert!(report.contains("  in  0x0064 byte  0x"), "{}", report);
+}
+
+#[test]
+#[ignore]
+fn busy_guest_runs()
+{
+    /* pittick polls PM timer for a second with timer interrupts coming in */
+    let res = GuestRun::boot_sector("pittick").arg("--pm-timer").arg("0x608").arg("--hang-detect").arg("1,stop").run();
+    assert!(res.is_ok(), "{:?}", res);
+}
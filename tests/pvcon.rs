@@ -0,0 +1,43 @@
+/*
+ * Paravirtual console
+ *
+ * Payload streaming 64K through the console hypercall in 4K chunks. Console file gets every byte in order,
+ * and the stream costs a hypercall exit per chunk rather than a port access per byte.
+ */
+
+mod guest;
+
+use guest::{GuestRun, summary_counts};
+use std::env;
+use std::fs;
+use std::io::Read;
+
+#[test]
+#[ignore]
+fn stream_to_file()
+{
+    let console = env::temp_dir().join(format!("xvm-test-pvcon-{}.bin", std::process::id()));
+    let summary_path = env::temp_dir().join(format!("xvm-test-pvcon-summary-{}.txt", std::process::id()));
+    let res = GuestRun::payload("pvcon")
+        .arg("--pvcon").arg(&format!("file:{}", console.display()))
+        .arg("--summary").arg(summary_path.to_str().unwrap())
+        .run_to_halt();
+    assert!(res.is_ok(), "{:?}", res);
+
+    let mut data = Vec::new();
+    fs::File::open(&console).unwrap().read_to_end(&mut data).unwrap();
+    fs::remove_file(&console).unwrap();
+
+    let mut summary = String::new();
+    fs::File::open(&summary_path).unwrap().read_to_string(&mut summary).unwrap();
+    fs::remove_file(&summary_path).unwrap();
+
+    /* Whole stream, byte for byte */
+    let expected: Vec<u8> = (0..0x10000usize).map(|i| (i ^ (i >> 8)) as u8).collect();
+    assert!(data.len() == expected.len(), "{} bytes on console", data.len());
+    assert!(data == expected);
+
+    /* Feature query and one call per chunk, port exits are only runtime's PIC setup and debug prints */
+    assert!(summary_counts(&summary, "vmcall") == vec![17], "{}", summary);
+    assert!(summary_counts(&summary, "io")[0] < 256, "{}", summary);
+}
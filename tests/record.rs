@@ -0,0 +1,138 @@
+/*
+ * Input recording and replay
+ *
+ * Boot sector polling COM1 gets bytes over TCP at arbitrary times while its inputs are recorded, and echoes
+ * them. Recording starts with a header naming the snapshot saved before guest ran, and has each byte guest
+ * took as serial input in the order it was sent, at VM exit counts that never go down. Replaying it with no
+ * host input gives the same serial output and exit status, replay checks RAM ends with the recorded hash.
+ */
+
+mod guest;
+
+use guest::{GuestRun, free_port};
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/* Value of a number or string field, recording lines are flat objects */
+fn field<'a>(line: &'a str, name: &str) -> Option<&'a str>
+{
+    let key = format!("\"{}\":", name);
+    line.find(&key).map(|start| {
+        let rest = &line[start + key.len()..];
+        let end = rest.find(|c| c == ',' || c == '}' || c == ']').unwrap();
+        rest[..end].trim_matches('"')
+    })
+}
+
+/* Serial port VMM listens on, retried while it starts up */
+fn connect(port: u16) -> TcpStream
+{
+    let start = Instant::now();
+    loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => return stream,
+            Err(err) => {
+                assert!(start.elapsed() < Duration::from_secs(10), "Can't connect to serial port: {}", err);
+                thread::sleep(Duration::from_millis(50));
+            },
+        }
+    }
+}
+
+/* Run guest on bytes sent while its inputs are recorded to path, returns what it echoed */
+fn record(path: &PathBuf, sent: &[u8]) -> Vec<u8>
+{
+    let port = free_port();
+    let guest = GuestRun::boot_sector("serialin")
+        .arg("--serial").arg(&format!("tcp:{}", port))
+        .arg("--record").arg(path.to_str().unwrap())
+        .start().unwrap();
+
+    /* Guest spins between bytes, so they come at whatever exit it has got to */
+    let mut serial = connect(port);
+    for (i, &byte) in sent.iter().enumerate() {
+        thread::sleep(Duration::from_millis(5 + (i as u64 * 37) % 60));
+        serial.write_all(&[byte]).unwrap();
+    }
+    let res = guest.wait();
+    assert!(res == Ok(sent.len() as u8), "{:?}", res);
+
+    /* VMM closed the connection on exit */
+    let mut echo = Vec::new();
+    serial.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    serial.read_to_end(&mut echo).unwrap();
+    echo
+}
+
+#[test]
+#[ignore]
+fn serial_input()
+{
+    let path = env::temp_dir().join(format!("xvm-test-record-{}.jsonl", std::process::id()));
+    let snapshot = PathBuf::from(format!("{}.snap", path.display()));
+    let sent = b"xvm16.";
+    assert!(&record(&path, sent)[..] == &sent[..]);
+
+    let mut text = String::new();
+    fs::File::open(&path).unwrap().read_to_string(&mut text).unwrap();
+    fs::remove_file(&path).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+
+    assert!(field(lines[0], "recording") == Some("2"), "{}", lines[0]);
+    assert!(field(lines[0], "snapshot") == snapshot.to_str(), "{}", lines[0]);
+    assert!(fs::metadata(&snapshot).unwrap().len() > 0);
+    fs::remove_file(&snapshot).unwrap();
+
+    let mut exit = 0;
+    let mut received = Vec::new();
+    for line in &lines[1..] {
+        let now: u64 = field(line, "exit").unwrap().parse().unwrap();
+        assert!(now >= exit, "{}", line);
+        exit = now;
+
+        if field(line, "input") == Some("serial_rx") {
+            assert!(field(line, "device") == Some("uart"), "{}", line);
+            received.push(field(line, "value").unwrap().parse::<u8>().unwrap());
+        }
+    }
+    assert!(&received[..] == &sent[..], "{:?}", received);
+
+    /* Guest polled a while before the first byte came */
+    let first = lines.iter().find(|line| field(line, "input") == Some("serial_rx")).unwrap();
+    assert!(field(first, "exit").unwrap().parse::<u64>().unwrap() > 0, "{}", first);
+}
+
+#[test]
+#[ignore]
+fn replay()
+{
+    let path = env::temp_dir().join(format!("xvm-test-replay-{}.jsonl", std::process::id()));
+    let snapshot = PathBuf::from(format!("{}.snap", path.display()));
+    let output = env::temp_dir().join(format!("xvm-test-replay-{}.out", std::process::id()));
+    let sent = b"replayed input.";
+    let echo = record(&path, sent);
+
+    /* Recording ends with exit status and RAM hash replay has to match, or it stops with status 16 */
+    let mut text = String::new();
+    fs::File::open(&path).unwrap().read_to_string(&mut text).unwrap();
+    let end = text.lines().last().unwrap();
+    assert!(field(end, "end").is_some() && field(end, "ram_hash").unwrap().starts_with("0x"), "{}", end);
+
+    let res = GuestRun::boot_sector("serialin")
+        .arg("--serial").arg(&format!("file:{}", output.display()))
+        .arg("--replay").arg(path.to_str().unwrap())
+        .start().unwrap().wait();
+    assert!(res == Ok(sent.len() as u8), "{:?}", res);
+
+    let mut replayed = Vec::new();
+    fs::File::open(&output).unwrap().read_to_end(&mut replayed).unwrap();
+    for file in &[&path, &snapshot, &output] {
+        fs::remove_file(file).unwrap();
+    }
+    assert!(replayed == echo, "{:?}", String::from_utf8_lossy(&replayed));
+}
@@ -0,0 +1,104 @@
+/*
+ * Reverse execution
+ *
+ * Boot sector stepped from its entry by a debugger while its inputs are recorded with checkpoints. Stepping
+ * back from where it got to gives, instruction by instruction, the registers it had one forward step earlier,
+ * and stops at the start of the recording. Continuing back stops at the breakpoint it hit last.
+ */
+
+mod guest;
+
+use guest::{GuestRun, RunningGuest, Gdb, free_port};
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/* Recording of the run at path with a checkpoint every ms, debugger attaches at guest entry */
+fn recorded_run(path: &Path) -> (RunningGuest, Gdb)
+{
+    let port = free_port();
+    let guest = GuestRun::boot_sector("step")
+        .arg("--record").arg(path.to_str().unwrap())
+        .arg("--reverse").arg("1")
+        .arg("--gdb").arg(&port.to_string()).arg("--gdb-wait")
+        .start().unwrap();
+    let mut gdb = Gdb::connect(port);
+
+    assert!(gdb.command("qSupported").contains("ReverseStep+"));
+    assert!(gdb.command("?") == "S05");
+    (guest, gdb)
+}
+
+/* Recording, its snapshot and checkpoints */
+fn remove_recording(path: &Path)
+{
+    fs::remove_file(path).unwrap();
+    fs::remove_file(format!("{}.snap", path.display())).unwrap();
+    let _ = fs::remove_dir_all(format!("{}.rev", path.display()));
+}
+
+#[test]
+#[ignore]
+fn step_back()
+{
+    let path = env::temp_dir().join(format!("xvm-test-reverse-step-{}.jsonl", std::process::id()));
+    let (guest, mut gdb) = recorded_run(&path);
+
+    /* Registers after each forward step, entry first */
+    let mut regs = vec![gdb.command("g")];
+    for _ in 0..5 {
+        assert!(gdb.command("s") == "S05");
+        regs.push(gdb.command("g"));
+    }
+    assert!(gdb.command("p8") == "097c0000");
+
+    /* Each step back gives the registers of one forward step earlier */
+    for expected in regs[..5].iter().rev() {
+        assert!(gdb.command("bs") == "S05");
+        assert!(gdb.command("g") == *expected);
+    }
+
+    /* Nothing before the entry, guest stays there */
+    assert!(gdb.command("bs") == "T05replaylog:begin;");
+    assert!(gdb.command("g") == regs[0]);
+
+    /* Forward again on what was recorded, then on from where recording stopped */
+    assert!(gdb.command("s") == "S05");
+    assert!(gdb.command("g") == regs[1]);
+    gdb.send("c");
+    assert!(guest.wait() == Ok(0x2A));
+    remove_recording(&path);
+}
+
+#[test]
+#[ignore]
+fn continue_back()
+{
+    let path = env::temp_dir().join(format!("xvm-test-reverse-cont-{}.jsonl", std::process::id()));
+    let (guest, mut gdb) = recorded_run(&path);
+
+    /* Guest runs into the breakpoint, debugger steps over it without */
+    assert!(gdb.command("Z0,7c05,1") == "OK");
+    assert!(gdb.command("c") == "T05swbreak:;");
+    assert!(gdb.command("p8") == "057c0000");
+    let hit = gdb.command("g");
+    assert!(gdb.command("z0,7c05,1") == "OK");
+    assert!(gdb.command("s") == "S05");
+    assert!(gdb.command("s") == "S05");
+    assert!(gdb.command("p8") == "087c0000");
+
+    /* Back to where it hit the breakpoint, and from there to the start */
+    assert!(gdb.command("Z0,7c05,1") == "OK");
+    assert!(gdb.command("bc") == "T05swbreak:;");
+    assert!(gdb.command("g") == hit);
+    assert!(gdb.command("bc") == "T05replaylog:begin;");
+    assert!(gdb.command("p8") == "007c0000");
+
+    /* Breakpoint still stops guest going forward */
+    assert!(gdb.command("c") == "T05swbreak:;");
+    assert!(gdb.command("g") == hit);
+    assert!(gdb.command("z0,7c05,1") == "OK");
+    gdb.send("c");
+    assert!(guest.wait() == Ok(0x2A));
+    remove_recording(&path);
+}
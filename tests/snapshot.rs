@@ -0,0 +1,438 @@
+/*
+ * VM snapshots
+ *
+ * Boot sector stopped at a breakpoint is saved from the monitor. File is checked on its own, without the VMM
+ * reading it back: its header and section checksums add up and it has memory layout, RAM with the boot sector,
+ * registers at the breakpoint, the clock and device states. Guest goes on after saving as if nothing happened.
+ * A VMM started from the snapshot picks up where it was saved, its guest prints what the saved one printed
+ * after that point. Incremental snapshots hold the pages guest wrote since the one before them, and a base
+ * with its increments restores the memory a full snapshot saved at the same point has. A live snapshot of a
+ * guest writing pages as it runs has them as they were at one instruction. Snapshot diff shows the pages and
+ * registers guest changed since a snapshot.
+ */
+
+mod guest;
+
+use guest::{GuestRun, Monitor, free_port};
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+const HEADER_SIZE: usize = 48;
+const ENTRY_SIZE: usize = 40;
+
+fn get_u32(data: &[u8], pos: usize) -> u32
+{
+    (0..4).fold(0, |val, i| val | (data[pos + i] as u32) << (i * 8))
+}
+
+fn get_u64(data: &[u8], pos: usize) -> u64
+{
+    (0..8).fold(0, |val, i| val | (data[pos + i] as u64) << (i * 8))
+}
+
+/* CRC-32 as in zlib, bit by bit */
+fn crc32(data: &[u8]) -> u32
+{
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn read_file(path: &PathBuf) -> String
+{
+    let mut text = String::new();
+    fs::File::open(path).unwrap().read_to_string(&mut text).unwrap();
+    fs::remove_file(path).unwrap();
+    text
+}
+
+/* Byte of sparse memory section data, pages left out are zeros */
+fn sparse_byte(data: &[u8], offset: usize) -> u8
+{
+    let count = get_u64(data, 8) as usize;
+    for i in 0..count {
+        let page = get_u64(data, 16 + i * 8) as usize;
+        if offset >= page && offset < page + 4096 {
+            return data[16 + count * 8 + i * 4096 + offset - page];
+        }
+    }
+    0
+}
+
+/* Sections of snapshot file as kind, flags, base and data, checksums checked */
+fn read_sections(path: &PathBuf) -> Vec<(u32, u32, u64, Vec<u8>)>
+{
+    let mut file = Vec::new();
+    fs::File::open(path).unwrap().read_to_end(&mut file).unwrap();
+
+    let count = get_u32(&file, 12) as usize;
+    let table = &file[HEADER_SIZE..HEADER_SIZE + count * ENTRY_SIZE];
+    let mut checked = file[..40].to_vec();
+    checked.extend_from_slice(table);
+    assert!(get_u32(&file, 40) == crc32(&checked));
+
+    (0..count).map(|i| {
+        let entry = &table[i * ENTRY_SIZE..];
+        let (offset, size) = (get_u64(entry, 16) as usize, get_u64(entry, 24) as usize);
+        let data = file[offset..offset + size].to_vec();
+        assert!(get_u32(entry, 32) == crc32(&data), "Section {} checksum", i);
+        (get_u32(entry, 0), get_u32(entry, 4), get_u64(entry, 8), data)
+    }).collect()
+}
+
+/* Page offsets sparse memory section data of RAM at 0 holds, with section flags */
+fn ram_pages(path: &PathBuf) -> (u32, Vec<u64>)
+{
+    let sections = read_sections(path);
+    let &(_, flags, _, ref data) = sections.iter().find(|&&(kind, _, base, _)| kind == 2 && base == 0).unwrap();
+    (flags, (0..get_u64(data, 8) as usize).map(|i| get_u64(data, 16 + i * 8)).collect())
+}
+
+#[test]
+#[ignore]
+fn save_at_breakpoint()
+{
+    let port = free_port();
+    let path = env::temp_dir().join(format!("xvm-test-{}.snap", std::process::id()));
+    let guest = GuestRun::boot_sector("step")
+        .arg("--monitor").arg(&format!("tcp:{}", port))
+        .arg("--break").arg("0x7c09")
+        .start().unwrap();
+    let mut monitor = Monitor::connect(port);
+    assert!(monitor.wait_paused() == "VM status: paused (breakpoint 1 at 0x7c09)");
+
+    let name = path.to_str().unwrap();
+    assert!(monitor.command(&format!("savevm {}", name)) == format!("Snapshot saved to {}", name));
+    assert!(monitor.command("cont") == "");
+    assert!(guest.wait() == Ok(0x2A));
+
+    let mut file = Vec::new();
+    fs::File::open(&path).unwrap().read_to_end(&mut file).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert!(!env::temp_dir().join(format!("xvm-test-{}.snap.tmp", std::process::id())).exists());
+
+    assert!(&file[0..8] == b"XVMSNAP\0" && get_u32(&file, 8) == 2);
+    let count = get_u32(&file, 12) as usize;
+    let table = &file[HEADER_SIZE..HEADER_SIZE + count * ENTRY_SIZE];
+    let mut checked = file[..40].to_vec();
+    checked.extend_from_slice(table);
+    assert!(get_u32(&file, 40) == crc32(&checked));
+
+    let mut kinds = Vec::new();
+    let mut mappings = 0;
+    let mut devices = Vec::new();
+    for i in 0..count {
+        let entry = &table[i * ENTRY_SIZE..];
+        let (kind, base) = (get_u32(entry, 0), get_u64(entry, 8));
+        let (offset, size) = (get_u64(entry, 16) as usize, get_u64(entry, 24) as usize);
+        let data = &file[offset..offset + size];
+        assert!(get_u32(entry, 32) == crc32(data), "Section {} checksum", i);
+        kinds.push(kind);
+
+        match kind {
+            1 => mappings = size / 24,
+            2 if base == 0 => assert!(sparse_byte(data, 0x7DFE) == 0x55 && sparse_byte(data, 0x7DFF) == 0xAA),
+            3 => assert!(String::from_utf8_lossy(data).contains("rip=0x7c09\n")),
+            5 => {
+                let len = get_u32(data, 4) as usize;
+                devices.push(String::from_utf8_lossy(&data[8..8 + len]).into_owned());
+            },
+            _ => {},
+        }
+    }
+
+    /* Layout first, then memory of each mapping, registers, clock and devices */
+    assert!(kinds[0] == 1 && kinds.iter().filter(|&&kind| kind == 2).count() == mappings);
+    assert!(kinds.contains(&3) && kinds.contains(&4));
+    assert!(devices.contains(&String::from("pic")), "{:?}", devices);
+}
+
+#[test]
+#[ignore]
+fn restore_in_new_process()
+{
+    let port = free_port();
+    let temp = |name: &str| env::temp_dir().join(format!("xvm-test-{}-{}", std::process::id(), name));
+    let (path, saved_com1, restored_com1) = (temp("restore.snap"), temp("saved.com1"), temp("restored.com1"));
+
+    /* Saved between the loops, after 0123 went out */
+    let guest = GuestRun::boot_sector("snapshot")
+        .arg("--monitor").arg(&format!("tcp:{}", port))
+        .arg("--serial").arg(&format!("file:{}", saved_com1.to_str().unwrap()))
+        .arg("--break").arg("0x7c0c")
+        .start().unwrap();
+    let mut monitor = Monitor::connect(port);
+    assert!(monitor.wait_paused() == "VM status: paused (breakpoint 1 at 0x7c0c)");
+    assert!(monitor.command(&format!("savevm {}", path.to_str().unwrap())).starts_with("Snapshot saved"));
+    assert!(monitor.command("cont") == "");
+    assert!(guest.wait() == Ok(0x2A));
+
+    let res = GuestRun::boot_sector("snapshot")
+        .arg("--restore").arg(path.to_str().unwrap())
+        .arg("--serial").arg(&format!("file:{}", restored_com1.to_str().unwrap()))
+        .run();
+    fs::remove_file(&path).unwrap();
+    assert!(res == Ok(0x2A), "{:?}", res);
+
+    assert!(read_file(&saved_com1) == "0123456789");
+    assert!(read_file(&restored_com1) == "456789");
+}
+
+#[test]
+#[ignore]
+fn incremental_chain()
+{
+    let temp = |name: &str| env::temp_dir().join(format!("xvm-test-{}-{}", std::process::id(), name));
+    let (base, inc1, inc2, full, restored) = (temp("base.snap"), temp("inc1.snap"), temp("inc2.snap"), temp("full.snap"), temp("restored.snap"));
+    let name = |path: &PathBuf| String::from(path.to_str().unwrap());
+
+    /* Base at first NOP, an increment at each of the other two and a full snapshot with the last one */
+    let port = free_port();
+    let guest = GuestRun::boot_sector("dirty")
+        .arg("--monitor").arg(&format!("tcp:{}", port))
+        .arg("--break").arg("0x7c09")
+        .arg("--break").arg("0x7c14")
+        .arg("--break").arg("0x7c1a")
+        .start().unwrap();
+    let mut monitor = Monitor::connect(port);
+    assert!(monitor.wait_paused() == "VM status: paused (breakpoint 1 at 0x7c09)");
+    assert!(monitor.command(&format!("savevm {}", name(&base))).starts_with("Snapshot saved"));
+    assert!(monitor.command("cont") == "");
+    assert!(monitor.wait_paused() == "VM status: paused (breakpoint 2 at 0x7c14)");
+    assert!(monitor.command(&format!("savevm {} {}", name(&inc1), name(&base))).starts_with("Snapshot saved"));
+    assert!(monitor.command("cont") == "");
+    assert!(monitor.wait_paused() == "VM status: paused (breakpoint 3 at 0x7c1a)");
+    assert!(monitor.command(&format!("savevm {} {}", name(&inc2), name(&inc1))).starts_with("Snapshot saved"));
+
+    /* Increment goes over the snapshot saved last, nothing else */
+    let res = monitor.command(&format!("savevm {} {}", name(&restored), name(&base)));
+    assert!(res.starts_with("Can't save snapshot") && !restored.exists(), "{}", res);
+
+    assert!(monitor.command(&format!("savevm {}", name(&full))).starts_with("Snapshot saved"));
+    assert!(monitor.command("cont") == "");
+    assert!(guest.wait() == Ok(0x2A));
+
+    /* Each increment has the pages written since the snapshot before it */
+    let (flags, pages) = ram_pages(&inc1);
+    assert!(flags == 3 && pages.contains(&0x1000) && pages.contains(&0x2000) && !pages.contains(&0x3000), "{:?}", pages);
+    let (flags, pages) = ram_pages(&inc2);
+    assert!(flags == 3 && pages.contains(&0x3000) && !pages.contains(&0x1000) && !pages.contains(&0x2000), "{:?}", pages);
+    let size = |path: &PathBuf| fs::metadata(path).unwrap().len();
+    assert!(size(&inc1) < size(&base) && size(&inc2) < size(&base));
+
+    /* Chain restored in a new process and saved at the same point has the reference memory */
+    let port = free_port();
+    let guest = GuestRun::boot_sector("dirty")
+        .arg("--restore").arg(&format!("{},{},{}", name(&base), name(&inc1), name(&inc2)))
+        .arg("--monitor").arg(&format!("tcp:{}", port))
+        .arg("--break").arg("0x7c1a")
+        .start().unwrap();
+    let mut monitor = Monitor::connect(port);
+    assert!(monitor.wait_paused() == "VM status: paused (breakpoint 1 at 0x7c1a)");
+    assert!(monitor.command(&format!("savevm {}", name(&restored))).starts_with("Snapshot saved"));
+    assert!(monitor.command("cont") == "");
+    assert!(guest.wait() == Ok(0x2A));
+
+    let memory = |path: &PathBuf| read_sections(path).into_iter().filter(|&(kind, _, _, _)| kind == 1 || kind == 2).collect::<Vec<_>>();
+    let (expected, actual) = (memory(&full), memory(&restored));
+    for path in &[base, inc1, inc2, full, restored] {
+        fs::remove_file(path).unwrap();
+    }
+    assert!(expected.len() == actual.len() && expected.len() > 1);
+    for (expected, actual) in expected.iter().zip(actual.iter()) {
+        assert!(expected == actual, "Memory at 0x{:x} differs", expected.2);
+    }
+}
+
+#[test]
+#[ignore]
+fn live_while_running()
+{
+    let path = env::temp_dir().join(format!("xvm-test-{}-live.snap", std::process::id()));
+    let name = String::from(path.to_str().unwrap());
+
+    /* Guest writes pages all along, it only stops for the pages written in the last round */
+    let port = free_port();
+    let guest = GuestRun::boot_sector("livewrite")
+        .arg("--monitor").arg(&format!("tcp:{}", port))
+        .start().unwrap();
+    let mut monitor = Monitor::connect(port);
+    let res = monitor.command(&format!("savevm -live {}", name));
+    assert!(res.starts_with("Saving live snapshot"), "{}", res);
+    assert!(monitor.command(&format!("savevm {}", name)).starts_with("Can't save snapshot"));
+
+    let mut report = monitor.command("info livesnap");
+    while report.starts_with("Live snapshot to") {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        report = monitor.command("info livesnap");
+    }
+    assert!(report.starts_with(&format!("Live snapshot saved to {}", name)) && report.contains("guest stopped for"), "{}", report);
+    assert!(monitor.command("info status") == "VM status: running");
+    monitor.command("quit");
+    drop(guest);
+
+    /* Pages hold one pass of the counter, as they were at one instruction */
+    let sections = read_sections(&path);
+    let &(_, _, _, ref ram) = sections.iter().find(|&&(kind, _, base, _)| kind == 2 && base == 0).unwrap();
+    let counts: Vec<u16> = (1..9).map(|page| sparse_byte(ram, page * 0x1000) as u16 | (sparse_byte(ram, page * 0x1000 + 1) as u16) << 8).collect();
+    let steps = counts.windows(2).filter(|pair| pair[0] != pair[1]).count();
+    assert!(steps <= 1 && counts.windows(2).all(|pair| pair[0] == pair[1] || pair[0] == pair[1].wrapping_add(1)), "{:?}", counts);
+
+    /* VMM started from it runs guest on from there */
+    let port = free_port();
+    let guest = GuestRun::boot_sector("livewrite")
+        .arg("--restore").arg(&name)
+        .arg("--monitor").arg(&format!("tcp:{}", port))
+        .arg("--break").arg("0x7c15")
+        .start().unwrap();
+    let mut monitor = Monitor::connect(port);
+    assert!(monitor.wait_paused() == "VM status: paused (breakpoint 1 at 0x7c15)");
+    monitor.command("quit");
+    drop(guest);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+#[ignore]
+fn diff_around_writes()
+{
+    let path = env::temp_dir().join(format!("xvm-test-{}-diff.snap", std::process::id()));
+    let name = String::from(path.to_str().unwrap());
+
+    /* Between the first two NOPs guest writes pages 2000h and 1000h and moves on 11 bytes */
+    let port = free_port();
+    let guest = GuestRun::boot_sector("dirty")
+        .arg("--monitor").arg(&format!("tcp:{}", port))
+        .arg("--break").arg("0x7c09")
+        .arg("--break").arg("0x7c14")
+        .start().unwrap();
+    let mut monitor = Monitor::connect(port);
+    assert!(monitor.wait_paused() == "VM status: paused (breakpoint 1 at 0x7c09)");
+    assert!(monitor.command(&format!("savevm {}", name)).starts_with("Snapshot saved"));
+    assert!(monitor.command(&format!("snapdiff {}", name)) == "Snapshots don't differ");
+    assert!(monitor.command("cont") == "");
+    assert!(monitor.wait_paused() == "VM status: paused (breakpoint 2 at 0x7c14)");
+
+    let diff = monitor.command(&format!("snapdiff {}", name));
+    let lines: Vec<&str> = diff.lines().filter(|line| line.starts_with("page ") || line.starts_with("register rip")).collect();
+    assert!(lines == vec!["page 0x1: differs from offset 0x0", "page 0x2: differs from offset 0x0", "register rip: 0x7c09 -> 0x7c14"], "{}", diff);
+    assert!(!diff.contains("device pic"), "{}", diff);
+
+    /* Ignored registers go */
+    let diff = monitor.command(&format!("snapdiff -ignore vcpu.rip,vcpu.rflags {}", name));
+    assert!(!diff.contains("register rip") && diff.contains("page 0x1:"), "{}", diff);
+
+    assert!(monitor.command("cont") == "");
+    assert!(guest.wait() == Ok(0x2A));
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+#[ignore]
+fn auto_rotation()
+{
+    let dir = env::temp_dir().join(format!("xvm-test-{}-auto", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let names = || {
+        let mut names: Vec<String> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+        names.sort();
+        names
+    };
+
+    /* Every second of guest time, two files kept: whole snapshot, increment over it, next whole one */
+    let port = free_port();
+    let guest = GuestRun::boot_sector("livewrite")
+        .arg("--auto-snapshot").arg(&format!("{},1,2", dir.to_str().unwrap()))
+        .arg("--monitor").arg(&format!("tcp:{}", port))
+        .start().unwrap();
+    let mut monitor = Monitor::connect(port);
+    while !dir.join("auto-000003.snap").exists() {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    monitor.command("stop");
+
+    /* Asked for ones go the same way, one of the next two is an increment */
+    let mut res = monitor.command("snapshot now");
+    if !res.contains(".inc.snap") {
+        res = monitor.command("snapshot now");
+    }
+    let next: Vec<String> = names();
+    assert!(res.starts_with("Snapshot saved to ") && res.contains(".inc.snap") && res.contains("--restore "), "{}", res);
+    assert!(next.len() == 2 && next[1].ends_with(".inc.snap") && !next[0].ends_with(".inc.snap"), "{:?}", next);
+    monitor.command("quit");
+    drop(guest);
+
+    /* Newest restores from the chain "snapshot now" named */
+    let chain = &res[res.find("--restore ").unwrap() + "--restore ".len()..];
+    let port = free_port();
+    let guest = GuestRun::boot_sector("livewrite")
+        .arg("--restore").arg(chain)
+        .arg("--monitor").arg(&format!("tcp:{}", port))
+        .arg("--break").arg("0x7c15")
+        .start().unwrap();
+    let mut monitor = Monitor::connect(port);
+    assert!(monitor.wait_paused() == "VM status: paused (breakpoint 1 at 0x7c15)");
+    assert!(monitor.command("snapshot now") == "Automatic snapshots are off, see --auto-snapshot");
+    monitor.command("quit");
+    drop(guest);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+#[ignore]
+fn ram_image_round_trip()
+{
+    let path = env::temp_dir().join(format!("xvm-test-{}-ram.bin", std::process::id()));
+    let name = String::from(path.to_str().unwrap());
+
+    /* Guest wrote 1 at 1000h, test images fill the rest of RAM with CCh */
+    let port = free_port();
+    let guest = GuestRun::boot_sector("dirty")
+        .arg("--monitor").arg(&format!("tcp:{}", port))
+        .arg("--break").arg("0x7c09")
+        .start().unwrap();
+    let mut monitor = Monitor::connect(port);
+    assert!(monitor.wait_paused() == "VM status: paused (breakpoint 1 at 0x7c09)");
+
+    /* Range off page boundaries, across a page */
+    assert!(monitor.command(&format!("ramsave {} 0xffe 5", name)) == format!("RAM 0xffe-0x1002 saved to {}", name));
+    let mut data = Vec::new();
+    fs::File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+    assert!(data == vec![0xCC, 0xCC, 0x01, 0xCC, 0xCC]);
+
+    /* Scribbled bytes come back, ones around the range stay scribbled */
+    assert!(monitor.command("setmem 0xffd 11 22 33 44 55 66 77").starts_with("Replaced at 0xffd:"));
+    assert!(monitor.command(&format!("ramload {} 0xffe", name)) == format!("RAM 0xffe-0x1002 loaded from {}", name));
+    assert!(monitor.command("xp /7xb 0xffd") == "0000000000000ffd: 0x11 0xcc 0xcc 0x01 0xcc 0xcc 0x77");
+
+    /* Whole RAM, nothing past it */
+    assert!(monitor.command(&format!("ramsave {}", name)) == format!("RAM 0x0-0xfffff saved to {}", name));
+    assert!(fs::metadata(&path).unwrap().len() == 0x100000);
+    assert!(monitor.command(&format!("ramsave {} 0xfff00 0x200", name)).ends_with("No RAM at 0x100000"));
+    assert!(monitor.command(&format!("ramload {} 0xfffff", name)).ends_with("No RAM at 0x100000"));
+    assert!(monitor.command("xp /xb 0xfffff") == "00000000000fffff: 0xcc");
+    monitor.command("quit");
+    drop(guest);
+
+    /* Loaded before guest starts, guest finds the byte it hasn't written yet */
+    fs::File::create(&path).unwrap().write_all(&[0x5A, 0xA5]).unwrap();
+    let port = free_port();
+    let guest = GuestRun::boot_sector("dirty")
+        .arg("--ram-image").arg(&format!("{},0x1000", name))
+        .arg("--monitor").arg(&format!("tcp:{}", port))
+        .arg("--break").arg("0x7c04")
+        .start().unwrap();
+    let mut monitor = Monitor::connect(port);
+    assert!(monitor.wait_paused() == "VM status: paused (breakpoint 1 at 0x7c04)");
+    assert!(monitor.command("xp /3xb 0xfff") == "0000000000000fff: 0xcc 0x5a 0xa5");
+    assert!(monitor.command("cont") == "");
+    assert!(guest.wait() == Ok(0x2A));
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,37 @@
+/*
+ * Run summary
+ *
+ * Boot sector initializing PIC writes a run summary when it stops, which accounts for each of its port
+ * accesses and the exits they took.
+ */
+
+mod guest;
+
+use guest::{GuestRun, summary_counts};
+use std::env;
+use std::fs;
+use std::io::Read;
+
+#[test]
+#[ignore]
+fn pic_init_summary()
+{
+    let path = env::temp_dir().join(format!("xvm-test-summary-{}.txt", std::process::id()));
+    let res = GuestRun::boot_sector("picinit").arg("--summary").arg(path.to_str().unwrap()).run();
+    assert!(res == Ok(0x3C), "{:?}", res);
+
+    let mut summary = String::new();
+    fs::File::open(&path).unwrap().read_to_string(&mut summary).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    /* Five PIC writes, one read and the debug exit write, each an I/O exit of its own */
+    assert!(summary_counts(&summary, "io") == vec![7], "{}", summary);
+    assert!(summary_counts(&summary, "0x0021") == vec![1, 4], "{}", summary);
+    assert!(summary_counts(&summary, "0x0020") == vec![0, 1], "{}", summary);
+    assert!(summary_counts(&summary, "0x00f4") == vec![0, 1], "{}", summary);
+
+    /* Guest didn't touch disk or serial line */
+    assert!(summary_counts(&summary, "ata0") == vec![0, 0], "{}", summary);
+    assert!(summary_counts(&summary, "uart") == vec![0, 0], "{}", summary);
+    assert!(summary.contains("Time: guest "), "{}", summary);
+}
@@ -0,0 +1,38 @@
+/*
+ * Guest observed timer accuracy
+ *
+ * Guest counts timer interrupts over a span of time measured by another guest visible clock and reports
+ * the count. Delivery modes must get it right within a tolerance, lost or spurious ticks show up here.
+ */
+
+mod guest;
+
+use guest::{GuestRun, TimerMode};
+
+/* pittick counts 100 Hz PIT interrupts for one second of PM timer time */
+const PITTICK_EXPECTED: u32 = 100;
+
+/* Allowed difference from expected count in percent */
+const TICK_TOLERANCE_PCT: u32 = 2;
+
+fn pittick(mode: TimerMode) -> u32
+{
+    let run = GuestRun::boot_sector("pittick").arg("--pm-timer").arg("0x608").timer(mode);
+    match run.run() {
+        Ok(count) => count as u32,
+        Err(err) => panic!("pittick in {:?} timer mode: {}", mode, err),
+    }
+}
+
+#[test]
+#[ignore]
+fn pit_tick_ratio()
+{
+    for mode in TimerMode::all().iter() {
+        let ticks = pittick(*mode);
+        let diff = if ticks > PITTICK_EXPECTED { ticks - PITTICK_EXPECTED } else { PITTICK_EXPECTED - ticks };
+        println!("{:?} timer mode: {} of {} ticks", mode, ticks, PITTICK_EXPECTED);
+        assert!(diff * 100 <= PITTICK_EXPECTED * TICK_TOLERANCE_PCT,
+                "{:?} timer mode delivered {} ticks, expected {}", mode, ticks, PITTICK_EXPECTED);
+    }
+}
@@ -0,0 +1,43 @@
+/*
+ * Guest execution trace
+ *
+ * Boot sector runs with its instructions traced to a file, trace must match the golden one line for line:
+ * 16 bit decoding with operand size and segment override prefixes, and control flow through a call.
+ */
+
+mod guest;
+
+use guest::GuestRun;
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::PathBuf;
+use std::process;
+
+fn read_file(path: &PathBuf) -> String
+{
+    let mut text = String::new();
+    File::open(path).and_then(|mut file| file.read_to_string(&mut text)).unwrap();
+    text
+}
+
+#[test]
+#[ignore]
+fn boot_sector_trace()
+{
+    let path = env::temp_dir().join(format!("xvm-trace-{}.log", process::id()));
+    let res = GuestRun::boot_sector("trace")
+        .arg("--trace").arg(path.to_str().unwrap())
+        .arg("--trace-range").arg("0x7c00-0x7dff")
+        .run();
+    assert!(res == Ok(0x46), "trace: {:?}", res);
+
+    let trace = read_file(&path);
+    let golden = read_file(&PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test/boot/trace.golden"));
+    let _ = fs::remove_file(&path);
+
+    for (i, (line, expected)) in trace.lines().zip(golden.lines()).enumerate() {
+        assert!(line == expected, "line {}: got\n{}\nexpected\n{}", i + 1, line, expected);
+    }
+    assert!(trace.lines().count() == golden.lines().count(), "trace:\n{}", trace);
+}
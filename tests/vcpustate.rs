@@ -0,0 +1,60 @@
+/*
+ * Guest register state
+ *
+ * Guest loads known values into its registers and halts, VMM dumps vcpu state when it stops and the dump
+ * must show exactly what guest left there.
+ */
+
+mod guest;
+
+use guest::GuestRun;
+
+/* Value of a register in "NAME=value" dump format, segment registers give their selector */
+fn register(dump: &str, name: &str) -> Option<u32>
+{
+    let prefix = format!("{}=", name);
+    for word in dump.split_whitespace() {
+        if word.starts_with(&prefix) {
+            return u32::from_str_radix(&word[prefix.len()..], 16).ok();
+        }
+    }
+
+    /* Segment lines are "ES =sel base limit attr" */
+    let prefix = format!("{} =", name);
+    for line in dump.lines() {
+        if line.starts_with(&prefix) {
+            return line[prefix.len()..].split_whitespace().next().and_then(|sel| u32::from_str_radix(sel, 16).ok());
+        }
+    }
+    None
+}
+
+#[test]
+fn register_parse()
+{
+    let dump = "EAX=00001234 EBX=00000000\nES =2222 00022220 0000ffff 00000093\n";
+    assert!(register(dump, "EAX") == Some(0x1234));
+    assert!(register(dump, "EBX") == Some(0));
+    assert!(register(dump, "ES") == Some(0x2222));
+    assert!(register(dump, "ECX").is_none());
+}
+
+#[test]
+#[ignore]
+fn halt_registers()
+{
+    let dump = match GuestRun::boot_sector("regs").run_to_halt() {
+        Ok(output) => output,
+        Err(err) => panic!("regs: {}", err),
+    };
+
+    for &(name, val) in &[("EAX", 0x1234), ("EBX", 0x5678), ("ECX", 0x9ABC), ("EDX", 0xDEF0),
+                          ("ESI", 0x1111), ("EDI", 0x2222), ("EBP", 0x3333), ("ES", 0x2222), ("CS", 0),
+                          ("EIP", 0x7C18)] {
+        assert!(register(&dump, name) == Some(val), "{} is {:?}, expected {:x} in:\n{}", name, register(&dump, name), val, dump);
+    }
+
+    /* Guest disabled interrupts before halting */
+    let flags = register(&dump, "EFL").unwrap();
+    assert!(flags & (1 << 9) == 0, "IF set in EFL={:08x}", flags);
+}